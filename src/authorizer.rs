@@ -0,0 +1,514 @@
+/*
+    The authorizer, a callback that is asked whether each thing a statement would do is
+    allowed, as sqlite3_set_authorizer's is, for an embedder to build access control on.
+
+        conn.set_authorizer(|action| match action {
+            Action::Read { table, column } if table == "users" && column == "password" => {
+                Authorization::Deny
+            }
+            Action::Delete { .. } => Authorization::Deny,
+            _ => Authorization::Allow,
+        });
+
+    It is asked as a statement is prepared to run, once for every action, before any of
+    them is done, so a statement it denies anything to fails with "not authorized"
+    (SQLITE_AUTH) having done nothing. A statement whose plan is kept and run again isn't
+    asked about again, but setting an authorizer forgets the plans kept, see prepared.rs.
+
+    The actions are SQLite's, as far as this engine has the statements for them. A query
+    is Select and then Read for each column it reads of each table, its subqueries'
+    included, and the columns of a `*` are every column. The rowid is read as "rowid",
+    whatever it was called. INSERT and DELETE are Insert and Delete on their table and
+    UPDATE is an Update for each column it sets, with Reads for what the WHERE clause and
+    the new values read. The table a column is read from is the one in the FROM clause
+    that has it, innermost first, as name resolution decides, see resolve.rs, named as the
+    table rather than by its alias. A view or CTE is read as the table it is, not as the
+    tables under it.
+
+    The statements triggers run aren't asked about, the statement that fires them is, and
+    VACUUM, which SQLite doesn't ask about either, isn't.
+*/
+use crate::error::SqlError;
+use crate::planner::{is_rowid, Catalog};
+use crate::sql_parser::ast::{Expr, Statement};
+use crate::sql_parser::{parse_aggregate, parse_expr, parse_window_function};
+use anyhow::{bail, Result};
+use std::fmt;
+use std::sync::Arc;
+
+/// Something a statement would do, that the authorizer is asked about.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Action {
+    Select,
+    Read {
+        table: String,
+        column: String,
+    },
+    Insert {
+        table: String,
+    },
+    Update {
+        table: String,
+        column: String,
+    },
+    Delete {
+        table: String,
+    },
+    CreateTable {
+        table: String,
+    },
+    CreateIndex {
+        index: String,
+        table: String,
+    },
+    CreateView {
+        view: String,
+    },
+    CreateTrigger {
+        trigger: String,
+        table: String,
+    },
+    CreateVirtualTable {
+        table: String,
+        module: String,
+    },
+    /// BEGIN, COMMIT or ROLLBACK.
+    Transaction {
+        operation: String,
+    },
+    /// BEGIN for SAVEPOINT, RELEASE or ROLLBACK for ROLLBACK TO.
+    Savepoint {
+        operation: String,
+        name: String,
+    },
+    Attach {
+        file: String,
+    },
+    Detach {
+        database: String,
+    },
+    Pragma {
+        name: String,
+        value: Option<String>,
+    },
+    Analyze {
+        table: Option<String>,
+    },
+    /// The table, index or collation named by REINDEX, None for every index.
+    Reindex {
+        name: Option<String>,
+    },
+}
+
+/// What the authorizer says to an action.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Authorization {
+    Allow,
+    /// Fail the statement with "not authorized".
+    Deny,
+}
+
+#[derive(Clone)]
+pub struct Authorizer(Arc<dyn Fn(&Action) -> Authorization + Send + Sync>);
+
+impl Authorizer {
+    pub fn new(callback: impl Fn(&Action) -> Authorization + Send + Sync + 'static) -> Self {
+        Authorizer(Arc::new(callback))
+    }
+
+    /// Ask about every action of `statement`, failing at the first denied.
+    pub fn authorize(&self, statement: &Statement, catalog: &dyn Catalog) -> Result<()> {
+        for action in actions(statement, catalog) {
+            if (self.0)(&action) == Authorization::Deny {
+                bail!(SqlError::NotAuthorized);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Authorizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Authorizer")
+    }
+}
+
+/// The actions of `statement`, in the order the authorizer is asked about them.
+pub fn actions(statement: &Statement, catalog: &dyn Catalog) -> Vec<Action> {
+    let mut actions = vec![];
+    statement_actions(statement, &mut vec![], catalog, &mut actions);
+    actions
+}
+
+// A table in scope: the name it is known by in the statement, its own name and columns,
+// and whether it has a rowid.
+struct Scope {
+    name: String,
+    table: String,
+    columns: Vec<String>,
+    rowid: bool,
+}
+
+fn statement_actions(
+    statement: &Statement,
+    scopes: &mut Vec<Scope>,
+    catalog: &dyn Catalog,
+    actions: &mut Vec<Action>,
+) {
+    let text = |s: &str| s.to_string();
+    let tables = match statement {
+        Statement::Select {
+            from_table,
+            from_select,
+            alias,
+            joins,
+            ..
+        } => {
+            actions.push(Action::Select);
+            if let Some(select) = from_select {
+                statement_actions(select, scopes, catalog, actions);
+            }
+            // a SELECT without FROM reads no table
+            let mut tables = vec![(from_table, alias.as_ref())];
+            tables.retain(|(table, _)| !table.is_empty());
+            tables.extend(joins.iter().map(|j| (&j.table, j.alias.as_ref())));
+            tables
+        }
+        Statement::Insert { into_table, .. } => {
+            actions.push(Action::Insert {
+                table: text(into_table),
+            });
+            vec![]
+        }
+        Statement::Update {
+            table, assignments, ..
+        } => {
+            for assignment in assignments {
+                actions.push(Action::Update {
+                    table: text(table),
+                    column: assignment.column_name.clone(),
+                });
+            }
+            vec![(table, None)]
+        }
+        Statement::Delete { from_table, .. } => {
+            actions.push(Action::Delete {
+                table: text(from_table),
+            });
+            vec![(from_table, None)]
+        }
+        Statement::Compound { first, rest, .. } => {
+            statement_actions(first, scopes, catalog, actions);
+            for (_, select) in rest {
+                statement_actions(select, scopes, catalog, actions);
+            }
+            return;
+        }
+        Statement::With { ctes, body } => {
+            for cte in ctes {
+                statement_actions(&cte.select, scopes, catalog, actions);
+            }
+            return statement_actions(body, scopes, catalog, actions);
+        }
+        Statement::CreateTable(table) => {
+            actions.push(Action::CreateTable {
+                table: table.name.clone(),
+            });
+            return;
+        }
+        Statement::CreateIndex(index) => {
+            actions.push(Action::CreateIndex {
+                index: index.name.clone(),
+                table: index.table.clone(),
+            });
+            return;
+        }
+        Statement::CreateView(view) => {
+            actions.push(Action::CreateView {
+                view: view.name.clone(),
+            });
+            return statement_actions(&view.select, scopes, catalog, actions);
+        }
+        Statement::CreateTrigger(trigger) => {
+            actions.push(Action::CreateTrigger {
+                trigger: trigger.name.clone(),
+                table: trigger.table.clone(),
+            });
+            return;
+        }
+        Statement::CreateVirtualTable(table) => {
+            actions.push(Action::CreateVirtualTable {
+                table: table.name.clone(),
+                module: table.module.clone(),
+            });
+            return;
+        }
+        Statement::Begin(_) | Statement::Commit | Statement::Rollback => {
+            let operation = match statement {
+                Statement::Begin(_) => "BEGIN",
+                Statement::Commit => "COMMIT",
+                _ => "ROLLBACK",
+            };
+            actions.push(Action::Transaction {
+                operation: text(operation),
+            });
+            return;
+        }
+        Statement::Savepoint(name) | Statement::Release(name) | Statement::RollbackTo(name) => {
+            let operation = match statement {
+                Statement::Savepoint(_) => "BEGIN",
+                Statement::Release(_) => "RELEASE",
+                _ => "ROLLBACK",
+            };
+            actions.push(Action::Savepoint {
+                operation: text(operation),
+                name: name.clone(),
+            });
+            return;
+        }
+        Statement::Attach { path, .. } => {
+            actions.push(Action::Attach { file: path.clone() });
+            return;
+        }
+        Statement::Detach(name) => {
+            actions.push(Action::Detach {
+                database: name.clone(),
+            });
+            return;
+        }
+        Statement::Pragma(pragma) => {
+            actions.push(Action::Pragma {
+                name: pragma.name.clone(),
+                value: pragma.value.clone(),
+            });
+            return;
+        }
+        Statement::Analyze(name) => {
+            actions.push(Action::Analyze {
+                table: name.clone(),
+            });
+            return;
+        }
+        Statement::Reindex(name) => {
+            actions.push(Action::Reindex { name: name.clone() });
+            return;
+        }
+        Statement::Vacuum => return,
+        Statement::Explain(statement)
+        | Statement::ExplainQueryPlan(statement)
+        | Statement::ExplainAnalyze(statement) => {
+            return statement_actions(statement, scopes, catalog, actions)
+        }
+    };
+
+    for (table, alias) in &tables {
+        scopes.push(Scope {
+            name: alias.unwrap_or(table).to_string(),
+            table: table.to_string(),
+            columns: catalog.table_columns(table).unwrap_or_default(),
+            rowid: catalog.has_rowid(table),
+        });
+    }
+    if let Statement::Select { columns, .. } = statement {
+        for column in columns {
+            result_column_reads(column, tables.len(), scopes, catalog, actions);
+        }
+    }
+
+    // the statement's own expressions, then its subqueries, each in a scope of its own
+    let mut own = statement.clone();
+    // a subquery in FROM has had its turn above
+    if let Statement::Select { from_select, .. } = &mut own {
+        *from_select = None;
+    }
+    let mut subqueries = vec![];
+    own.walk_exprs_mut(&mut |e| match e {
+        Expr::InSelect { select, .. } | Expr::Exists { select, .. } | Expr::Subquery(select) => {
+            subqueries.push(std::mem::replace(&mut **select, Statement::Vacuum));
+        }
+        Expr::Column(column) => read(None, column, scopes, actions),
+        Expr::QualifiedColumn { table, column } => read(Some(table), column, scopes, actions),
+        _ => {}
+    });
+    for subquery in &subqueries {
+        statement_actions(subquery, scopes, catalog, actions);
+    }
+    scopes.truncate(scopes.len() - tables.len());
+}
+
+// The reads of a result column, kept as text: `*`, a constant, an aggregate, a window
+// function, or a name or any other expression. The statement's own tables are the last
+// `tables` scopes, which `*` reads all of.
+fn result_column_reads(
+    column: &str,
+    tables: usize,
+    scopes: &mut Vec<Scope>,
+    catalog: &dyn Catalog,
+    actions: &mut Vec<Action>,
+) {
+    if column.parse::<i64>().is_ok() {
+        return;
+    }
+    if let Ok(aggregate) = parse_aggregate(column) {
+        if let Some(arg) = &aggregate.arg {
+            result_column_reads(arg, tables, scopes, catalog, actions);
+        }
+        return;
+    }
+    if let Ok(mut window) = parse_window_function(column) {
+        for column in window.columns_mut() {
+            result_column_reads(column, tables, scopes, catalog, actions);
+        }
+        return;
+    }
+    let table = match column.strip_suffix(".*") {
+        Some(table) => Some(table),
+        None if column == "*" => None,
+        None => {
+            let Ok(expr) = parse_expr(column) else {
+                return;
+            };
+            let mut subqueries = vec![];
+            expr.walk(&mut |e| match e {
+                Expr::InSelect { select, .. }
+                | Expr::Exists { select, .. }
+                | Expr::Subquery(select) => subqueries.push(select.as_ref()),
+                Expr::Column(column) => read(None, column.as_str(), scopes, actions),
+                Expr::QualifiedColumn { table, column } => {
+                    read(Some(table.as_str()), column.as_str(), scopes, actions)
+                }
+                _ => {}
+            });
+            for subquery in subqueries {
+                statement_actions(subquery, scopes, catalog, actions);
+            }
+            return;
+        }
+    };
+    let read: Vec<&Scope> = match table {
+        Some(table) => scopes
+            .iter()
+            .rev()
+            .find(|s| s.name == table)
+            .into_iter()
+            .collect(),
+        None => scopes[scopes.len() - tables..].iter().collect(),
+    };
+    for scope in read {
+        for column in &scope.columns {
+            actions.push(Action::Read {
+                table: scope.table.clone(),
+                column: column.clone(),
+            });
+        }
+    }
+}
+
+// A read of `column` from the table in scope that `table` names, or else the innermost
+// with a column of that name.
+fn read(table: Option<&str>, column: &str, scopes: &[Scope], actions: &mut Vec<Action>) {
+    let scope = scopes.iter().rev().find(|s| match table {
+        Some(table) => s.name == table,
+        None => s.columns.iter().any(|c| c == column) || (s.rowid && is_rowid(column, &s.columns)),
+    });
+    let Some(scope) = scope else {
+        // NEW and OLD in a trigger, say, which aren't tables the statement reads
+        return;
+    };
+    let column = match scope.columns.iter().any(|c| c == column) {
+        true => column.to_string(),
+        false => "rowid".to_string(),
+    };
+    let action = Action::Read {
+        table: scope.table.clone(),
+        column,
+    };
+    if !actions.contains(&action) {
+        actions.push(action);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Schema;
+    use crate::sql_parser::parse;
+
+    fn read(table: &str, column: &str) -> Action {
+        Action::Read {
+            table: table.to_string(),
+            column: column.to_string(),
+        }
+    }
+
+    #[test]
+    fn the_actions_of_a_statement_name_the_tables_and_columns_it_uses() {
+        let mut schema = Schema::default();
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, password TEXT);",
+            "CREATE TABLE orders (user_id INTEGER, total INTEGER);",
+        ] {
+            let Statement::CreateTable(table) = parse(sql).unwrap() else {
+                unreachable!()
+            };
+            schema.create_table(&table).unwrap();
+        }
+        let actions = |sql: &str| actions(&parse(sql).unwrap(), &schema);
+
+        assert_eq!(
+            actions("SELECT name FROM users u WHERE EXISTS (SELECT 1 FROM orders WHERE user_id = u.id AND total > 5);"),
+            [
+                Action::Select,
+                read("users", "name"),
+                Action::Select,
+                read("orders", "user_id"),
+                read("users", "id"),
+                read("orders", "total"),
+            ]
+        );
+        assert_eq!(
+            actions("SELECT * FROM users WHERE rowid > 1;"),
+            [
+                Action::Select,
+                read("users", "id"),
+                read("users", "name"),
+                read("users", "password"),
+                read("users", "rowid"),
+            ]
+        );
+        // `*` reads every table joined
+        assert_eq!(
+            actions("SELECT * FROM users u JOIN orders o ON o.user_id = u.id;"),
+            [
+                Action::Select,
+                read("users", "id"),
+                read("users", "name"),
+                read("users", "password"),
+                read("orders", "user_id"),
+                read("orders", "total"),
+            ]
+        );
+        assert_eq!(
+            actions("SELECT COUNT(*), MAX(total) FROM orders;"),
+            [Action::Select, read("orders", "total")]
+        );
+        assert_eq!(
+            actions("UPDATE users SET password = name WHERE id = 1;"),
+            [
+                Action::Update {
+                    table: "users".to_string(),
+                    column: "password".to_string()
+                },
+                read("users", "name"),
+                read("users", "id"),
+            ]
+        );
+        assert_eq!(
+            actions("PRAGMA cache_size = 10;"),
+            [Action::Pragma {
+                name: "cache_size".to_string(),
+                value: Some("10".to_string())
+            }]
+        );
+    }
+}
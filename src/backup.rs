@@ -0,0 +1,230 @@
+/*
+    Online backup: copying a database to a new file a few pages at a time while the
+    connection goes on using it, as sqlite3_backup_init, _step and _finish do.
+
+        let mut backup = conn.backup("shop-backup.db")?;
+        while !backup.step(&conn, 100)? {
+            conn.execute("INSERT INTO orders (item) VALUES (?);", &["tea".into()])?;
+        }
+
+    The pages copied are those of a new file holding main's rows, see storage/image.rs:
+    the pages of its B+tree as they would be written had every row been inserted into a
+    new one. Each step writes the next few of them into a new file beside the
+    destination, dest-backup, and the last step commits it and renames it over the
+    destination, so the destination is only ever the whole of a backup, never part of
+    one.
+
+    A backup remembers the pages as of the commit it last saw. When a step finds the
+    connection has committed since, it makes the pages anew and copies again just those
+    that changed, rather than starting over. A step while a transaction is
+    open fails with "database is locked", as what the transaction has written isn't
+    committed and mustn't be copied; the backup can carry on once it ends.
+
+    Only main is backed up, never the attached databases, and the destination is in the
+    image format whatever the source's.
+*/
+use crate::connection::Connection;
+use crate::error::SqlError;
+use crate::executor::Executor;
+use crate::storage::compress::Compression;
+use crate::storage::header::DatabaseHeader;
+use crate::storage::image::{self, DatabaseFile};
+use crate::storage::memdb::AccessMode;
+use crate::storage::wal::PageNumber;
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// How many pages Connection::backup_to copies at each step.
+pub const BACKUP_STEP_PAGES: usize = 100;
+
+/// How far a backup has got, as sqlite3_backup_remaining and _pagecount tell it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub remaining: usize,
+    pub page_count: usize,
+}
+
+/// A backup under way, see the module comment.
+#[derive(Debug)]
+pub struct Backup {
+    dest: PathBuf,
+    // the new file being filled, at dest-backup until the backup is done
+    file: Option<DatabaseFile>,
+    temp: PathBuf,
+    // the new file's header, as its pages are laid out
+    header: DatabaseHeader,
+    // the pages from page 2 on as of the commit the backup last saw
+    pages: Vec<Vec<u8>>,
+    commit: u64,
+    // the pages still to be copied, by their place in `pages`
+    remaining: BTreeSet<usize>,
+}
+
+impl Backup {
+    pub(crate) fn new(executor: &Executor, dest: &Path) -> Result<Self> {
+        let temp = with_suffix(dest, "-backup");
+        remove_temp_files(&temp);
+        let mut file = DatabaseFile::open(
+            &temp,
+            AccessMode::Create,
+            Compression::None,
+            executor.config(),
+        )
+        .with_context(|| format!("cannot back up to \"{}\"", dest.display()))?;
+        let header = file.header()?;
+        let pages = image::pages(&executor.image(), &header)?;
+        Ok(Backup {
+            dest: dest.to_path_buf(),
+            file: Some(file),
+            temp,
+            header,
+            remaining: (0..pages.len()).collect(),
+            pages,
+            commit: executor.commit_count(),
+        })
+    }
+
+    /// Copy up to `pages` more pages of `conn`'s database, returning true once the whole
+    /// of it is in the destination.
+    pub fn step(&mut self, conn: &Connection, pages: usize) -> Result<bool> {
+        if self.file.is_none() {
+            return Ok(true);
+        }
+        let executor = conn.executor();
+        if executor.in_transaction() {
+            return Err(SqlError::DatabaseLocked)
+                .context("database is locked: cannot back up while a transaction is open");
+        }
+        if executor.commit_count() != self.commit {
+            let pages = image::pages(&executor.image(), &self.header)?;
+            self.changed(pages);
+            self.commit = executor.commit_count();
+        }
+        let file = self.file.as_mut().expect("a backup under way");
+        for _ in 0..pages {
+            let Some(index) = self.remaining.pop_first() else {
+                break;
+            };
+            file.write_page(image::ROOT_PAGE + index as PageNumber, &self.pages[index])?;
+        }
+        if !self.remaining.is_empty() {
+            return Ok(false);
+        }
+        file.commit_pages(self.pages.len())?;
+        self.file = None;
+        std::fs::rename(&self.temp, &self.dest)
+            .with_context(|| format!("cannot back up to \"{}\"", self.dest.display()))?;
+        remove_temp_files(&self.temp);
+        Ok(true)
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress {
+            remaining: self.remaining.len(),
+            page_count: self.page_count(),
+        }
+    }
+
+    fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    // Take the pages of a commit made since the backup's, to copy again those that
+    // differ from it.
+    fn changed(&mut self, pages: Vec<Vec<u8>>) {
+        for (index, page) in pages.iter().enumerate() {
+            if self.pages.get(index) != Some(page) {
+                self.remaining.insert(index);
+            }
+        }
+        self.remaining.retain(|index| *index < pages.len());
+        self.pages = pages;
+    }
+}
+
+impl Drop for Backup {
+    // a backup given up on leaves nothing behind
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            remove_temp_files(&self.temp);
+        }
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// The backup's new file and the journal its pager kept beside it.
+fn remove_temp_files(temp: &Path) {
+    let _ = std::fs::remove_file(temp);
+    let _ = std::fs::remove_file(with_suffix(temp, "-journal"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::ColVal;
+
+    fn count(conn: &mut Connection) -> i64 {
+        let mut rows = conn.query("SELECT COUNT(*) FROM t;", &[]).unwrap();
+        rows.next().unwrap().unwrap().get::<i64>(0).unwrap()
+    }
+
+    #[test]
+    fn a_backup_carries_on_through_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("backup.db");
+        let mut conn = Connection::open_in_memory();
+        conn.execute("CREATE TABLE t (a INTEGER, b TEXT);", &[])
+            .unwrap();
+        let text = ColVal::String("x".repeat(200));
+        for i in 0..200 {
+            conn.execute(
+                "INSERT INTO t (a, b) VALUES (?, ?);",
+                &[ColVal::Int(i), text.clone()],
+            )
+            .unwrap();
+        }
+
+        let mut backup = conn.backup(&dest).unwrap();
+        let Progress { page_count, .. } = backup.progress();
+        assert!(page_count > 4, "{page_count}");
+        assert!(!backup.step(&conn, 2).unwrap());
+        assert_eq!(backup.progress().remaining, page_count - 2);
+
+        // a write between steps is copied too, and one in a transaction waits for it
+        conn.execute(
+            "INSERT INTO t (a, b) VALUES (?, ?);",
+            &[ColVal::Int(200), text.clone()],
+        )
+        .unwrap();
+        conn.execute("BEGIN;", &[]).unwrap();
+        conn.execute("DELETE FROM t;", &[]).unwrap();
+        assert_eq!(
+            backup.step(&conn, 2).unwrap_err().to_string(),
+            "database is locked: cannot back up while a transaction is open"
+        );
+        conn.execute("ROLLBACK;", &[]).unwrap();
+        while !backup.step(&conn, 2).unwrap() {}
+        assert!(!dir.path().join("backup.db-backup").exists());
+        assert_eq!(
+            count(&mut Connection::open(dest.to_str().unwrap()).unwrap()),
+            201
+        );
+
+        // backup_to reports its progress, the last time with nothing remaining
+        let mut reported = vec![];
+        conn.backup_to(&dest, |progress| reported.push(progress))
+            .unwrap();
+        assert_eq!(reported.last().unwrap().remaining, 0);
+        assert_eq!(
+            count(&mut Connection::open(dest.to_str().unwrap()).unwrap()),
+            201
+        );
+    }
+}
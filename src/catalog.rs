@@ -0,0 +1,301 @@
+/*
+    The system catalog, SQLite's sqlite_master table: a row for every table, index, view
+    and trigger in the database, which is how a database file remembers its own schema.
+
+        type     "table", "index", "view" or "trigger"
+        name     the object's name
+        tbl_name the table an index or trigger is on, and for a table or view its own name
+        rootpage the page its B+tree starts at, 0 for a view or trigger which have none
+        sql      the CREATE statement that made it, NULL for the index of a PRIMARY KEY
+
+    sqlite_master is itself a table, whose B+tree always starts at page 1 so that it can be
+    found without looking it up anywhere. Every other table and index is given the next
+    free page as its root when it is created, in the order SQLite gives them, so a table
+    with a PRIMARY KEY gets one page and its index the next.
+
+    Opening a database reads its schema back out of sqlite_master: each row's SQL is parsed
+    and run again to recreate the object, see Executor::load_catalog. The SQL kept is the
+    statement as it was written, not as the engine would print it, from the name onwards
+    after a CREATE keyword normalised to upper case and without any IF NOT EXISTS, which
+    is what SQLite keeps too. A statement that wasn't written as SQL, such as one built by
+    a program, is kept as the engine prints it.
+
+    sqlite_master can be read like any table but only the schema changes it. B+trees
+    don't live in pages yet, so root pages are only numbers for now, and until there is a
+    database file for sqlite_master to live in, loading a schema means handing its rows
+    over from another connection.
+*/
+use crate::sql_parser::ast::{ColVal, Column, CreateTable, Statement};
+use crate::storage::wal::PageNumber;
+use anyhow::{bail, Result};
+use std::fmt;
+
+pub const MASTER_TABLE: &str = "sqlite_master";
+/// The table of the largest rowid each AUTOINCREMENT table has had, made along with the
+/// first such table as in SQLite.
+pub const SEQUENCE_TABLE: &str = "sqlite_sequence";
+/// Where sqlite_master's own B+tree starts, which never changes.
+pub const MASTER_ROOT_PAGE: PageNumber = 1;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EntryKind {
+    Table,
+    Index,
+    View,
+    Trigger,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EntryKind::Table => "table",
+            EntryKind::Index => "index",
+            EntryKind::View => "view",
+            EntryKind::Trigger => "trigger",
+        })
+    }
+}
+
+impl EntryKind {
+    fn parse(kind: &str) -> Option<EntryKind> {
+        Some(match kind {
+            "table" => EntryKind::Table,
+            "index" => EntryKind::Index,
+            "view" => EntryKind::View,
+            "trigger" => EntryKind::Trigger,
+            _ => return None,
+        })
+    }
+}
+
+/// A row of sqlite_master.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CatalogEntry {
+    pub kind: EntryKind,
+    pub name: String,
+    pub table: String,
+    pub root_page: PageNumber,
+    pub sql: Option<String>,
+}
+
+impl CatalogEntry {
+    pub fn row(&self) -> Vec<ColVal> {
+        vec![
+            ColVal::String(self.kind.to_string()),
+            ColVal::String(self.name.clone()),
+            ColVal::String(self.table.clone()),
+            ColVal::Int(self.root_page.into()),
+            self.sql.clone().map_or(ColVal::Null, ColVal::String),
+        ]
+    }
+
+    pub fn from_row(row: &[ColVal]) -> Result<CatalogEntry> {
+        let text = |v: &ColVal| match v {
+            ColVal::String(s) => Some(s.clone()),
+            _ => None,
+        };
+        let [kind, name, table, ColVal::Int(root_page), sql] = row else {
+            bail!("malformed database schema: {row:?}");
+        };
+        let (Some(kind), Some(name), Some(table)) = (
+            text(kind).as_deref().and_then(EntryKind::parse),
+            text(name),
+            text(table),
+        ) else {
+            bail!("malformed database schema: {row:?}");
+        };
+        Ok(CatalogEntry {
+            kind,
+            name,
+            table,
+            root_page: (*root_page).try_into()?,
+            sql: text(sql),
+        })
+    }
+}
+
+/// The definition of sqlite_master, as SQLite declares it.
+pub fn master_table() -> CreateTable {
+    let column = |name: &str, type_name: &str| Column {
+        name: name.to_string(),
+        type_name: Some(type_name.to_string()),
+        default: None,
+        collation: None,
+        generated: None,
+        not_null: false,
+    };
+    CreateTable {
+        name: MASTER_TABLE.to_string(),
+        columns: vec![
+            column("type", "TEXT"),
+            column("name", "TEXT"),
+            column("tbl_name", "TEXT"),
+            column("rootpage", "INTEGER"),
+            column("sql", "TEXT"),
+        ],
+        primary_key: vec![],
+        foreign_keys: vec![],
+        autoincrement: false,
+        checks: vec![],
+        without_rowid: false,
+        if_not_exists: false,
+    }
+}
+
+/// The SQL sqlite_master keeps for a CREATE statement: `sql` as written if there is any,
+/// from the object's name on, otherwise the statement as the engine prints it. The TEMP of
+/// a temporary table is left out, as the temp database's sqlite_master says as much.
+pub fn stored_sql(statement: &Statement, sql: Option<&str>) -> String {
+    let kind = match statement {
+        Statement::CreateTable(_) => "TABLE",
+        Statement::CreateIndex(index) if index.unique => "UNIQUE INDEX",
+        Statement::CreateIndex(_) => "INDEX",
+        Statement::CreateView(_) => "VIEW",
+        Statement::CreateTrigger(_) => "TRIGGER",
+        other => return other.to_string(),
+    };
+    let written = sql.and_then(|sql| {
+        let sql = sql.trim().trim_end_matches(';').trim_end();
+        let mut words = word_starts(sql).into_iter().peekable();
+        let mut expect = |word: &str| {
+            words
+                .next_if(|(_, w)| w.eq_ignore_ascii_case(word))
+                .is_some()
+        };
+        expect("CREATE").then_some(())?;
+        expect("UNIQUE");
+        let _ = expect("TEMP") || expect("TEMPORARY");
+        let kind_word = kind.rsplit(' ').next().expect("a keyword");
+        expect(kind_word).then_some(())?;
+        if expect("IF") {
+            (expect("NOT") && expect("EXISTS")).then_some(())?;
+        }
+        let (name_at, _) = words.next()?;
+        Some(format!("CREATE {kind} {}", &sql[name_at..]))
+    });
+    written.unwrap_or_else(|| {
+        let printed = statement.to_string();
+        printed.replacen(" IF NOT EXISTS", "", 1)
+    })
+}
+
+/// The SQL an attached database's own sqlite_master keeps for a CREATE statement kept
+/// as `sql`, which has the object's name, and an index's table, in `database`: the same
+/// without `database.` in front of them, since in its own file the database is main.
+pub fn unqualified_sql(sql: &str, database: &str) -> String {
+    let words = word_starts(sql);
+    let is = |i: usize, word: &str| {
+        words
+            .get(i)
+            .is_some_and(|(_, w)| w.eq_ignore_ascii_case(word))
+    };
+    // CREATE [UNIQUE] kind name [ON table]
+    let name = if is(1, "UNIQUE") { 3 } else { 2 };
+    let mut names = vec![name];
+    if is(name + 1, "ON") {
+        names.push(name + 2);
+    }
+    let mut unqualified = sql.to_string();
+    for i in names.into_iter().rev() {
+        let Some((at, word)) = words.get(i) else {
+            continue;
+        };
+        let qualified = word
+            .split_once('.')
+            .is_some_and(|(d, _)| d.eq_ignore_ascii_case(database));
+        if qualified {
+            unqualified.replace_range(*at..at + database.len() + 1, "");
+        }
+    }
+    unqualified
+}
+
+// Each whitespace separated word of `sql` with where it starts. A word ends early at a
+// bracket, so the name in `t(a INTEGER)` is a word of its own.
+fn word_starts(sql: &str) -> Vec<(usize, &str)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (i, c) in sql.char_indices() {
+        let ends = c.is_whitespace() || c == '(';
+        match (start, ends) {
+            (None, false) => start = Some(i),
+            (Some(s), true) => {
+                words.push((s, &sql[s..i]));
+                start = None;
+            }
+            _ => {}
+        }
+        if c == '(' {
+            words.push((i, &sql[i..i + 1]));
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, &sql[s..]));
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::parse;
+
+    #[test]
+    fn sql_is_kept_as_written_from_the_name() {
+        let stored = |sql: &str| stored_sql(&parse(sql).unwrap(), Some(sql));
+        assert_eq!(
+            stored("CREATE TABLE IF NOT EXISTS t (a INTEGER PRIMARY KEY,   b);"),
+            "CREATE TABLE t (a INTEGER PRIMARY KEY,   b)"
+        );
+        assert_eq!(
+            stored("CREATE   UNIQUE INDEX  ti ON t(b);"),
+            "CREATE UNIQUE INDEX ti ON t(b)"
+        );
+        assert_eq!(
+            stored("CREATE VIEW v AS SELECT a FROM t;"),
+            "CREATE VIEW v AS SELECT a FROM t"
+        );
+        assert_eq!(
+            stored("CREATE TEMPORARY TABLE t (a, b);"),
+            "CREATE TABLE t (a, b)"
+        );
+
+        // without the text it is printed from the statement
+        let statement = parse("CREATE TABLE IF NOT EXISTS t (a INTEGER);").unwrap();
+        assert_eq!(stored_sql(&statement, None), "CREATE TABLE t (a INTEGER)");
+    }
+
+    #[test]
+    fn attached_databases_keep_their_names_unqualified() {
+        assert_eq!(
+            unqualified_sql("CREATE TABLE aux.t (a INTEGER)", "aux"),
+            "CREATE TABLE t (a INTEGER)"
+        );
+        assert_eq!(
+            unqualified_sql("CREATE UNIQUE INDEX aux.ti ON aux.t(b)", "aux"),
+            "CREATE UNIQUE INDEX ti ON t(b)"
+        );
+        assert_eq!(
+            unqualified_sql("CREATE INDEX ti ON aux.t (b)", "aux"),
+            "CREATE INDEX ti ON t (b)"
+        );
+    }
+
+    #[test]
+    fn entries_are_rows_of_sqlite_master() {
+        let entry = CatalogEntry {
+            kind: EntryKind::Index,
+            name: "sqlite_autoindex_t_1".to_string(),
+            table: "t".to_string(),
+            root_page: 3,
+            sql: None,
+        };
+        assert_eq!(CatalogEntry::from_row(&entry.row()).unwrap(), entry);
+        assert_eq!(
+            CatalogEntry::from_row(&[ColVal::Int(1)])
+                .unwrap_err()
+                .to_string(),
+            "malformed database schema: [Int(1)]"
+        );
+    }
+}
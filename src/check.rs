@@ -0,0 +1,123 @@
+/*
+    CHECK constraints, conditions every row of a table has to meet.
+
+        CREATE TABLE stock (
+            item TEXT,
+            qty INTEGER CHECK (qty >= 0),
+            price REAL,
+            CHECK (price > 0 OR qty = 0)
+        );
+
+    A CHECK can be written after a column or on its own among the columns, and means the
+    same either way: it may read any of the row's columns, generated ones included, and
+    its rowid. Like a generated column's expression it can't have a subquery or a
+    parameter in it and may only call deterministic functions, see functions.rs, as
+    whether a row passes mustn't change once it is stored.
+
+    Each time a row is written, by INSERT or UPDATE, the table's CHECKs are worked out
+    over it once its generated columns are, in the order they are written. A row is
+    refused only when one comes out FALSE, so a CHECK that comes out NULL passes, as in
+    SQLite: `qty >= 0` lets a NULL qty through, and NOT NULL is for keeping it out. The
+    error names the first CHECK that failed by its expression, `CHECK constraint failed:
+    qty >= 0`.
+
+    A column's NOT NULL is checked along with them, and first, as SQLite does: a NULL in
+    `item TEXT NOT NULL` is refused with `NOT NULL constraint failed: stock.item`.
+*/
+use crate::error::SqlError;
+use crate::eval::truth;
+use crate::planner::ROWID_NAMES;
+use crate::sql_parser::ast::{ColVal, CreateTable, Expr};
+use anyhow::{bail, Result};
+
+/// Check a new table's CHECKs can be worked out over its rows.
+pub fn check(def: &CreateTable) -> Result<()> {
+    for check in &def.checks {
+        let mut unknown = None;
+        let mut refused = None;
+        check.walk(&mut |e| match e {
+            Expr::Column(name) if !def.columns.iter().any(|c| c.name == *name) => {
+                if def.without_rowid || !ROWID_NAMES.contains(&name.as_str()) {
+                    unknown.get_or_insert_with(|| name.clone());
+                }
+            }
+            Expr::InSelect { .. } | Expr::Exists { .. } | Expr::Subquery(_) => {
+                refused = Some("subqueries")
+            }
+            Expr::Placeholder(_) => refused = Some("parameters"),
+            _ => {}
+        });
+        if let Some(what) = refused {
+            bail!("{what} prohibited in CHECK constraints");
+        }
+        if let Some(column) = unknown {
+            bail!(SqlError::NoSuchColumn { column });
+        }
+    }
+    Ok(())
+}
+
+/// Refuse a row of `def` with a NULL in a NOT NULL column, or that one of its CHECKs
+/// comes out FALSE for, where `eval` evaluates an expression over the row.
+pub fn verify(
+    def: &CreateTable,
+    row: &[ColVal],
+    eval: impl Fn(&Expr, &[ColVal]) -> Result<ColVal>,
+) -> Result<()> {
+    for (column, value) in def.columns.iter().zip(row) {
+        if column.not_null && *value == ColVal::Null {
+            // named by the table without its database, as SQLite does
+            let table = def.name.rsplit('.').next().unwrap_or(&def.name);
+            bail!(SqlError::NotNullConstraint {
+                column: format!("{table}.{}", column.name)
+            });
+        }
+    }
+    for check in &def.checks {
+        if truth(&eval(check, row)?) == Some(false) {
+            bail!(SqlError::CheckConstraint {
+                check: check.to_string()
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::Statement;
+    use crate::sql_parser::parse;
+
+    fn table(sql: &str) -> CreateTable {
+        let Statement::CreateTable(table) = parse(sql).unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        table
+    }
+
+    #[test]
+    fn checks_may_only_read_the_row() {
+        assert!(check(&table(
+            "CREATE TABLE t (a CHECK (a > b), b, CHECK (rowid > 0));"
+        ))
+        .is_ok());
+        let refused = |sql: &str| check(&table(sql)).unwrap_err().to_string();
+        assert_eq!(
+            refused("CREATE TABLE t (a, CHECK (a > c));"),
+            "no such column: c"
+        );
+        assert_eq!(
+            refused("CREATE TABLE t (a, CHECK (a IN (SELECT x FROM u)));"),
+            "subqueries prohibited in CHECK constraints"
+        );
+        assert_eq!(
+            refused("CREATE TABLE t (a CHECK (a > ?));"),
+            "parameters prohibited in CHECK constraints"
+        );
+        assert_eq!(
+            refused("CREATE TABLE t (a PRIMARY KEY, CHECK (rowid > 0)) WITHOUT ROWID;"),
+            "no such column: rowid"
+        );
+    }
+}
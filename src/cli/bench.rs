@@ -0,0 +1,213 @@
+/*
+    `bench`, a quick measure of how fast the engine is, to tell whether a change to the
+    pager or the B+tree made it faster or slower.
+
+        sqlite-clone bench [--rows N]
+
+    It fills a table of N rows, 10000 by default, in a database file of its own in a
+    temporary directory, whatever DB is given, then times each of:
+
+        insert      the N rows, one INSERT each, in one transaction
+        lookup      N lookups of a row by its rowid, at most 1000, in random order
+        range       N / 100 scans of 100 rows each, from random starting rowids
+        scan cold   opening the database file again, and every row
+        scan warm   every row again, with whatever the first scan left cached
+
+    For each it prints how long it took, how many rows a second that comes to, and the
+    hits and faults of the file's page cache while it ran, see storage/cache.rs. Rows are
+    read from the file's tree when it is opened and kept in memory from then on, so it is
+    the insert's save and the cold scan's opening that go through the cache, and the rest
+    find their rows without it. The rowids are shuffled the same way every run, so two
+    runs differ only in the engine.
+
+    Last it prints how many levels the file's B+tree has from its root down to the leaves
+    holding the rows, which is how many pages a lookup reads: the more children an inner
+    page has room for, with its separators cut short and sharing their prefixes, the
+    fewer, see storage/btree.rs.
+*/
+use crate::executor::Executor;
+use crate::sql_parser::ast::ColVal;
+use crate::storage::cache::CacheStats;
+use crate::storage::image::DatabaseFile;
+use crate::storage::memdb::OpenTarget;
+use crate::storage::pager::PagerConfig;
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How many rows each range scan reads.
+const RANGE_ROWS: usize = 100;
+/// The most point lookups a run makes, which are slow next to the rest.
+const MAX_LOOKUPS: usize = 1000;
+
+/// One timed part of a run.
+#[derive(Debug)]
+pub struct Measure {
+    pub name: &'static str,
+    pub rows: usize,
+    pub elapsed: Duration,
+    pub cache: CacheStats,
+}
+
+/// Every part of a run, in the order they ran, and the height of the tree it left.
+#[derive(Debug)]
+pub struct Report {
+    pub measures: Vec<Measure>,
+    pub rows: usize,
+    pub tree_height: usize,
+}
+
+/// Run the benchmark over `rows` rows in a new database file.
+pub fn bench(rows: usize) -> Result<Report> {
+    let dir = tempfile::tempdir()?;
+    let target = OpenTarget::parse(&dir.path().join("bench.db").to_string_lossy())?;
+    let mut executor = Executor::open(target.clone())?;
+    executor.execute_sql("CREATE TABLE bench (id INTEGER PRIMARY KEY, n INTEGER, name TEXT);")?;
+    let mut ids: Vec<i64> = (1..=rows as i64).collect();
+    ids.shuffle(&mut StdRng::seed_from_u64(0));
+    let mut measures = vec![];
+
+    let mut insert = executor.prepare("INSERT INTO bench (id, n, name) VALUES (?, ?, ?);")?;
+    measures.push(measure(&mut executor, "insert", |executor| {
+        executor.execute_sql("BEGIN;")?;
+        for id in 1..=rows as i64 {
+            insert.bind(1, ColVal::Int(id))?;
+            insert.bind(2, ColVal::Int(id * 7 % 1000))?;
+            insert.bind(3, ColVal::String(format!("row {id}")))?;
+            executor.execute_prepared(&mut insert)?;
+        }
+        executor.execute_sql("COMMIT;")?;
+        Ok(rows)
+    })?);
+
+    let mut lookup = executor.prepare("SELECT n, name FROM bench WHERE id = ?;")?;
+    measures.push(measure(&mut executor, "lookup", |executor| {
+        let lookups = ids.len().min(MAX_LOOKUPS);
+        for id in &ids[..lookups] {
+            lookup.bind(1, ColVal::Int(*id))?;
+            executor.execute_prepared(&mut lookup)?;
+        }
+        Ok(lookups)
+    })?);
+
+    let mut range = executor.prepare("SELECT n, name FROM bench WHERE id >= ? AND id < ?;")?;
+    measures.push(measure(&mut executor, "range", |executor| {
+        let mut read = 0;
+        for id in ids.iter().take(rows / RANGE_ROWS) {
+            range.bind(1, ColVal::Int(*id))?;
+            range.bind(2, ColVal::Int(id + RANGE_ROWS as i64))?;
+            read += executor.execute_prepared(&mut range)?.rows.len();
+        }
+        Ok(read)
+    })?);
+
+    drop(executor);
+    let tree_height = match DatabaseFile::open_target(&target, &PagerConfig::default())? {
+        Some(mut file) => file.tree_height()?,
+        None => 0,
+    };
+    // a connection just opened has counted nothing else yet
+    let started = Instant::now();
+    let mut executor = Executor::open(target)?;
+    let scanned = executor.execute_sql("SELECT * FROM bench;")?.rows.len();
+    measures.push(Measure {
+        name: "scan cold",
+        rows: scanned,
+        elapsed: started.elapsed(),
+        cache: executor.cache_report().total(),
+    });
+    measures.push(measure(&mut executor, "scan warm", |executor| {
+        Ok(executor.execute_sql("SELECT * FROM bench;")?.rows.len())
+    })?);
+    Ok(Report {
+        measures,
+        rows,
+        tree_height,
+    })
+}
+
+// Time `run`, which returns how many rows it went through, and count the hits and
+// faults of the file's cache while it ran.
+fn measure(
+    executor: &mut Executor,
+    name: &'static str,
+    run: impl FnOnce(&mut Executor) -> Result<usize>,
+) -> Result<Measure> {
+    let before = executor.cache_report().total();
+    let started = Instant::now();
+    let rows = run(executor)?;
+    let elapsed = started.elapsed();
+    let after = executor.cache_report().total();
+    Ok(Measure {
+        name,
+        rows,
+        elapsed,
+        cache: CacheStats {
+            hits: after.hits.saturating_sub(before.hits),
+            faults: after.faults.saturating_sub(before.faults),
+            evictions: after.evictions.saturating_sub(before.evictions),
+            writebacks: after.writebacks.saturating_sub(before.writebacks),
+            read_ahead: after.read_ahead.saturating_sub(before.read_ahead),
+        },
+    })
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<10} {:>8} {:>10} {:>12} {:>8} {:>8}",
+            "", "rows", "seconds", "rows/s", "hits", "faults"
+        )?;
+        for m in &self.measures {
+            let seconds = m.elapsed.as_secs_f64();
+            let rate = if seconds > 0.0 {
+                m.rows as f64 / seconds
+            } else {
+                0.0
+            };
+            writeln!(
+                f,
+                "{:<10} {:>8} {:>10.3} {:>12.0} {:>8} {:>8}",
+                m.name, m.rows, seconds, rate, m.cache.hits, m.cache.faults
+            )?;
+        }
+        writeln!(
+            f,
+            "tree height {} levels over {} rows",
+            self.tree_height, self.rows
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_run_reads_back_every_row_it_wrote() {
+        let Report {
+            measures,
+            tree_height,
+            ..
+        } = bench(300).unwrap();
+        let names: Vec<&str> = measures.iter().map(|m| m.name).collect();
+        assert_eq!(
+            names,
+            ["insert", "lookup", "range", "scan cold", "scan warm"]
+        );
+        assert!(measures.iter().all(|m| m.rows == 300 || m.name == "range"));
+        // three ranges, cut short where they run past the last rowid
+        assert!((1..=3 * RANGE_ROWS).contains(&measures[2].rows));
+        // saving the rows and loading them go through the file's cache, scanning them
+        // once loaded doesn't
+        assert!(measures[0].cache.hits > 0);
+        assert!(measures[3].cache.faults > 0);
+        assert_eq!(measures[4].cache.hits + measures[4].cache.faults, 0);
+        // 300 short rows are more than a leaf holds, and fewer than two levels do
+        assert_eq!(tree_height, 2);
+    }
+}
@@ -0,0 +1,223 @@
+/*
+    `dump` and the shell's `.dump`, the database as the SQL that would make it again, like
+    sqlite3's `.dump`.
+
+    Each table's CREATE TABLE comes first, followed by an INSERT for every row, then the
+    indexes, views and triggers, each in the order sqlite_master lists them and with the
+    SQL it keeps for them. The whole is one transaction. An index is created after the
+    rows are in rather than kept up to date while they go in, and a trigger after them so
+    that it doesn't fire for rows that are only being put back. `.dump TABLE` is the same
+    for that table alone, its indexes and triggers with it.
+
+    Values are written as sqlite3 writes them, a string in single quotes with any single
+    quote in it doubled and a REAL with as many digits as it takes to read back as the
+    same number, though unlike sqlite3's the INSERTs name their columns. sqlite3's own tables, such as sqlite_stat1, aren't
+    dumped; ANALYZE makes them again. sqlite_sequence is made again by the first CREATE
+    TABLE with AUTOINCREMENT, and its rows are put back after the tables' as sqlite3 does,
+    so that rowids the tables had before the dump still aren't used again. A generated
+    column is left out of the INSERTs, which couldn't give it a value.
+*/
+use crate::catalog::{CatalogEntry, EntryKind, MASTER_TABLE, SEQUENCE_TABLE};
+use crate::executor::Executor;
+use crate::planner::Catalog;
+use crate::sql_parser::ast::{literal, ColVal};
+use anyhow::Result;
+use std::fmt::Write;
+
+/// The SQL that makes the database again, or just `table` if given.
+pub fn dump(executor: &mut Executor, table: Option<&str>) -> Result<String> {
+    let entries = executor
+        .execute_sql(&format!("SELECT * FROM {MASTER_TABLE};"))?
+        .rows
+        .iter()
+        .map(|row| CatalogEntry::from_row(row))
+        .collect::<Result<Vec<_>>>()?;
+    let entries: Vec<CatalogEntry> = entries
+        .into_iter()
+        .filter(|e| !e.name.starts_with("sqlite_"))
+        .filter(|e| table.map_or(true, |t| t.eq_ignore_ascii_case(&e.table)))
+        .collect();
+
+    let mut sql = String::from("BEGIN TRANSACTION;\n");
+    for entry in entries.iter().filter(|e| e.kind == EntryKind::Table) {
+        let Some(create) = &entry.sql else { continue };
+        writeln!(sql, "{create};")?;
+        // a generated column is worked out again as its row goes back in
+        let columns = match executor.schema().table(&entry.name) {
+            Some(def) => def
+                .columns
+                .iter()
+                .filter(|c| c.generated.is_none())
+                .map(|c| c.name.clone())
+                .collect(),
+            None => executor.schema().table_columns(&entry.name)?,
+        };
+        let columns = columns.join(", ");
+        let rows = executor
+            .execute_sql(&format!("SELECT {columns} FROM {};", entry.name))?
+            .rows;
+        for row in rows {
+            let values: Vec<String> = row.iter().map(literal).collect();
+            writeln!(
+                sql,
+                "INSERT INTO {} ({columns}) VALUES ({});",
+                entry.name,
+                values.join(", ")
+            )?;
+        }
+    }
+    if executor.schema().table(SEQUENCE_TABLE).is_some() {
+        let rows: Vec<Vec<ColVal>> = executor
+            .execute_sql(&format!("SELECT name, seq FROM {SEQUENCE_TABLE};"))?
+            .rows
+            .into_iter()
+            .filter(|row| match (&row[0], table) {
+                (ColVal::String(name), Some(table)) => name.eq_ignore_ascii_case(table),
+                _ => true,
+            })
+            .collect();
+        match (table, rows.first()) {
+            (None, _) => writeln!(sql, "DELETE FROM {SEQUENCE_TABLE};")?,
+            (Some(_), Some(row)) => writeln!(
+                sql,
+                "DELETE FROM {SEQUENCE_TABLE} WHERE name = {};",
+                literal(&row[0])
+            )?,
+            (Some(_), None) => {}
+        }
+        for row in rows {
+            writeln!(
+                sql,
+                "INSERT INTO {SEQUENCE_TABLE} (name, seq) VALUES ({}, {});",
+                literal(&row[0]),
+                literal(&row[1])
+            )?;
+        }
+    }
+    for entry in entries.iter().filter(|e| e.kind != EntryKind::Table) {
+        if let Some(create) = &entry.sql {
+            writeln!(sql, "{create};")?;
+        }
+    }
+    sql.push_str("COMMIT;\n");
+    Ok(sql)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::run_script;
+
+    #[test]
+    fn a_dump_loads_back_as_the_same_database() {
+        let mut executor = Executor::default();
+        let script = "\
+CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, score REAL);
+CREATE INDEX idx_name ON users (name);
+CREATE TABLE log (name TEXT);
+CREATE TRIGGER log_user AFTER INSERT ON users BEGIN INSERT INTO log (name) VALUES (NEW.name); END;
+INSERT INTO users (name, score) VALUES (\"amy\", 2.5);
+INSERT INTO users (name, score) VALUES (\"bob\", NULL);
+";
+        run_script(&mut executor, script, &mut std::io::sink()).unwrap();
+        let dumped = dump(&mut executor, None).unwrap();
+        assert_eq!(
+            dumped,
+            "\
+BEGIN TRANSACTION;
+CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, score REAL);
+INSERT INTO users (id, name, score) VALUES (1, 'amy', 2.5);
+INSERT INTO users (id, name, score) VALUES (2, 'bob', NULL);
+CREATE TABLE log (name TEXT);
+INSERT INTO log (name) VALUES ('amy');
+INSERT INTO log (name) VALUES ('bob');
+CREATE INDEX idx_name ON users (name);
+CREATE TRIGGER log_user AFTER INSERT ON users BEGIN INSERT INTO log (name) VALUES (NEW.name); END;
+COMMIT;
+"
+        );
+
+        // the trigger isn't there to fire while the rows go back in
+        let mut loaded = Executor::default();
+        run_script(&mut loaded, &dumped, &mut std::io::sink()).unwrap();
+        assert_eq!(dump(&mut loaded, None).unwrap(), dumped);
+
+        // one table is dumped with what belongs to it
+        assert_eq!(
+            dump(&mut executor, Some("USERS")).unwrap(),
+            "\
+BEGIN TRANSACTION;
+CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, score REAL);
+INSERT INTO users (id, name, score) VALUES (1, 'amy', 2.5);
+INSERT INTO users (id, name, score) VALUES (2, 'bob', NULL);
+CREATE INDEX idx_name ON users (name);
+CREATE TRIGGER log_user AFTER INSERT ON users BEGIN INSERT INTO log (name) VALUES (NEW.name); END;
+COMMIT;
+"
+        );
+
+        // an AUTOINCREMENT table's sequence goes back as it was
+        let mut executor = Executor::default();
+        let script = "\
+CREATE TABLE jobs (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT);
+INSERT INTO jobs (name) VALUES (\"a\");
+INSERT INTO jobs (name) VALUES (\"b\");
+DELETE FROM jobs WHERE id = 2;
+";
+        run_script(&mut executor, script, &mut std::io::sink()).unwrap();
+        let dumped = dump(&mut executor, None).unwrap();
+        assert_eq!(
+            dumped,
+            "\
+BEGIN TRANSACTION;
+CREATE TABLE jobs (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT);
+INSERT INTO jobs (id, name) VALUES (1, 'a');
+DELETE FROM sqlite_sequence;
+INSERT INTO sqlite_sequence (name, seq) VALUES ('jobs', 2);
+COMMIT;
+"
+        );
+        let mut loaded = Executor::default();
+        run_script(&mut loaded, &dumped, &mut std::io::sink()).unwrap();
+        assert_eq!(dump(&mut loaded, None).unwrap(), dumped);
+
+        // a generated column is worked out again rather than inserted
+        let mut executor = Executor::default();
+        let script = "\
+CREATE TABLE t (a INTEGER, b AS (a * 2));
+INSERT INTO t (a) VALUES (3);
+";
+        run_script(&mut executor, script, &mut std::io::sink()).unwrap();
+        let dumped = dump(&mut executor, None).unwrap();
+        assert!(dumped.contains("INSERT INTO t (a) VALUES (3);"), "{dumped}");
+        let mut loaded = Executor::default();
+        run_script(&mut loaded, &dumped, &mut std::io::sink()).unwrap();
+        assert_eq!(dump(&mut loaded, None).unwrap(), dumped);
+
+        // any string goes back as it was, and any REAL to the last digit
+        let mut executor = Executor::default();
+        let script = "\
+CREATE TABLE q (s TEXT, r REAL);
+INSERT INTO q (s, r) VALUES ('it''s \"quoted\"', 0.1 + 0.2);
+INSERT INTO q (s, r) VALUES ('', 1e-300);
+";
+        run_script(&mut executor, script, &mut std::io::sink()).unwrap();
+        let dumped = dump(&mut executor, None).unwrap();
+        assert!(
+            dumped
+                .contains("INSERT INTO q (s, r) VALUES ('it''s \"quoted\"', 0.30000000000000004);"),
+            "{dumped}"
+        );
+        let mut loaded = Executor::default();
+        run_script(&mut loaded, &dumped, &mut std::io::sink()).unwrap();
+        let all = "SELECT s, r FROM q;";
+        assert_eq!(
+            loaded.execute_sql(all).unwrap().rows,
+            executor.execute_sql(all).unwrap().rows
+        );
+        assert_eq!(
+            loaded.execute_sql(all).unwrap().rows[0][1],
+            ColVal::Real(0.1 + 0.2)
+        );
+    }
+}
@@ -0,0 +1,247 @@
+/*
+    `import` and the shell's `.import`, adding the rows of a CSV file to a table, like
+    sqlite3's `.import --csv`.
+
+    If the table doesn't exist yet the file's first line names its columns, and it is
+    created with a TEXT column for each. If it does, every line is a row, and each must
+    have a value for every column; --skip N passes over the first N lines, such as a
+    header the table doesn't need. Values are put in as text, and the column's affinity
+    makes numbers of those that look like numbers, so importing into an INTEGER column
+    stores integers.
+
+    Fields are separated by commas, or by the separator given, and may be quoted with
+    double quotes, which lets one hold the separator, a line break or, doubled, a double
+    quote. An empty unquoted field is an empty string, not NULL, as in sqlite3.
+
+    The file is read a record at a time rather than all at once, and its rows are stored
+    BATCH_ROWS at a time with Executor::append_batch rather than an INSERT apiece, so no
+    triggers fire and the first batch into a new table is bulk loaded into its B+tree.
+    The whole import runs in one savepoint, so the file's rows go in all or not at all
+    and a database file is saved once at the end. A table the import creates is created
+    before it, so that a failed import leaves it behind, empty, as sqlite3 leaves it.
+*/
+use crate::executor::Executor;
+use crate::planner::Catalog;
+use crate::sql_parser::ast::ColVal;
+use anyhow::{bail, Result};
+use std::io::BufRead;
+
+// How many rows are stored with each append_batch.
+const BATCH_ROWS: usize = 1000;
+// The savepoint the whole import runs in.
+const IMPORT_SAVEPOINT: &str = "import";
+
+/// How to read the file, see the module comment.
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub separator: char,
+    pub skip: usize,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions {
+            separator: ',',
+            skip: 0,
+        }
+    }
+}
+
+/// Import the rows of `csv` into `table`, returning how many there were.
+pub fn import(
+    executor: &mut Executor,
+    csv: impl BufRead,
+    table: &str,
+    options: &ImportOptions,
+) -> Result<usize> {
+    let mut records = Records {
+        input: csv,
+        separator: options.separator,
+    }
+    .skip(options.skip);
+    let columns = match executor.schema().table_columns(table) {
+        Ok(columns) => columns.len(),
+        Err(_) => {
+            let Some(header) = records.next().transpose()? else {
+                bail!("no header line to create {table} from");
+            };
+            let columns: Vec<String> = header.iter().map(|c| format!("{c} TEXT")).collect();
+            executor.execute_sql(&format!("CREATE TABLE {table} ({});", columns.join(", ")))?;
+            header.len()
+        }
+    };
+    executor.execute_sql(&format!("SAVEPOINT {IMPORT_SAVEPOINT};"))?;
+    let result = import_records(executor, records, table, columns);
+    if result.is_err() {
+        executor.execute_sql(&format!("ROLLBACK TO {IMPORT_SAVEPOINT};"))?;
+    }
+    executor.execute_sql(&format!("RELEASE {IMPORT_SAVEPOINT};"))?;
+    result
+}
+
+fn import_records(
+    executor: &mut Executor,
+    records: impl Iterator<Item = Result<Vec<String>>>,
+    table: &str,
+    columns: usize,
+) -> Result<usize> {
+    let mut count = 0;
+    let mut batch = Vec::with_capacity(BATCH_ROWS);
+    for (line, record) in records.enumerate() {
+        let record = record?;
+        if record.len() != columns {
+            bail!(
+                "row {}: expected {} columns of data but found {}",
+                line + 1,
+                columns,
+                record.len()
+            );
+        }
+        batch.push(record.into_iter().map(ColVal::String).collect());
+        if batch.len() == BATCH_ROWS {
+            count += executor.append_batch(table, batch.drain(..))?;
+        }
+    }
+    Ok(count + executor.append_batch(table, batch)?)
+}
+
+// The records of a CSV file, each a list of its fields, read as they are wanted.
+struct Records<R> {
+    input: R,
+    separator: char,
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = Result<Vec<String>>;
+
+    fn next(&mut self) -> Option<Result<Vec<String>>> {
+        let mut record = vec![];
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut line = String::new();
+        loop {
+            // a quoted field can go on over as many lines as it likes
+            line.clear();
+            match self.input.read_line(&mut line) {
+                Err(err) => return Some(Err(err.into())),
+                Ok(0) if quoted => return Some(Err(anyhow::anyhow!("unterminated quoted field"))),
+                Ok(0) if field.is_empty() && record.is_empty() => return None,
+                Ok(0) => {
+                    record.push(field);
+                    return Some(Ok(record));
+                }
+                Ok(_) => {}
+            }
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (quoted, c) {
+                    (true, '"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    (true, '"') => quoted = false,
+                    (true, c) => field.push(c),
+                    (false, '"') if field.is_empty() => quoted = true,
+                    (false, c) if c == self.separator => record.push(std::mem::take(&mut field)),
+                    (false, '\r') if chars.peek() == Some(&'\n') => {}
+                    (false, '\n') => {
+                        record.push(field);
+                        return Some(Ok(record));
+                    }
+                    (false, c) => field.push(c),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(csv: &str, separator: char) -> Result<Vec<Vec<String>>> {
+        let input = csv.as_bytes();
+        Records { input, separator }.collect()
+    }
+
+    fn import_str(executor: &mut Executor, csv: &str, table: &str) -> Result<usize> {
+        import(executor, csv.as_bytes(), table, &ImportOptions::default())
+    }
+
+    #[test]
+    fn csv_rows_are_imported() {
+        assert_eq!(
+            parse("a,b\r\n\"x, \"\"y\"\"\",\n\"two\nlines\",3", ',').unwrap(),
+            [
+                vec!["a", "b"],
+                vec!["x, \"y\"", ""],
+                vec!["two\nlines", "3"]
+            ]
+        );
+        assert!(parse("\"open", ',').is_err());
+        assert_eq!(parse("a;\"b;c\"\n", ';').unwrap(), [vec!["a", "b;c"]]);
+
+        // a new table takes its columns from the header
+        let mut executor = Executor::default();
+        assert_eq!(
+            import_str(&mut executor, "name,age\namy,34\nbob,27\n", "people").unwrap(),
+            2
+        );
+        assert_eq!(
+            executor
+                .execute_sql("SELECT * FROM people;")
+                .unwrap()
+                .to_string(),
+            "amy|34\nbob|27"
+        );
+
+        // while an existing one takes every line as a row, in its columns' types
+        executor
+            .execute_sql("CREATE TABLE scores (name TEXT, score INTEGER);")
+            .unwrap();
+        import_str(&mut executor, "amy,10\n", "scores").unwrap();
+        assert_eq!(
+            executor
+                .execute_sql("SELECT name FROM scores WHERE score = 10;")
+                .unwrap()
+                .to_string(),
+            "amy"
+        );
+        assert_eq!(
+            import_str(&mut executor, "amy\n", "scores")
+                .unwrap_err()
+                .to_string(),
+            "row 1: expected 2 columns of data but found 1"
+        );
+    }
+
+    #[test]
+    fn an_import_goes_in_batches_all_or_nothing() {
+        let mut executor = Executor::default();
+        let mut csv = String::from("id\tname\n");
+        for i in 0..2500 {
+            csv.push_str(&format!("{i}\tuser {i}\n"));
+        }
+        let tabs = ImportOptions {
+            separator: '\t',
+            skip: 0,
+        };
+        assert_eq!(
+            import(&mut executor, csv.as_bytes(), "users", &tabs).unwrap(),
+            2500
+        );
+        let count = "SELECT COUNT(*) FROM users;";
+        assert_eq!(executor.execute_sql(count).unwrap().to_string(), "2500");
+
+        // a bad row in a later batch undoes the batches before it
+        let skip_header = ImportOptions { skip: 1, ..tabs };
+        csv.push_str("2500\n");
+        assert!(import(&mut executor, csv.as_bytes(), "users", &skip_header).is_err());
+        assert_eq!(executor.execute_sql(count).unwrap().to_string(), "2500");
+
+        // leaving a table the import made empty
+        assert!(import_str(&mut executor, "a,b\n1,2\n1\n", "pairs").is_err());
+        let pairs = executor.execute_sql("SELECT * FROM pairs;").unwrap();
+        assert!(pairs.rows.is_empty());
+    }
+}
@@ -0,0 +1,396 @@
+/*
+    The command line, so the binary can be used from scripts and cron jobs as well as
+    typed at.
+
+        sqlite-clone [DB] [repl] [--init FILE]  the interactive shell, the default
+        sqlite-clone DB SQL                     run SQL, the shell's commands too, and exit
+        sqlite-clone [DB] exec [-f FILE]        run the SQL in FILE, or on stdin
+        sqlite-clone [DB] dump [TABLE]          write the database, or TABLE, out as SQL
+        sqlite-clone [DB] import FILE TABLE     add the rows of a CSV file to TABLE
+        sqlite-clone [DB] serve [--port PORT]   answer SQL sent over HTTP, or --wire
+        sqlite-clone [DB] integrity-check       check the database, "ok" if it is fine
+        sqlite-clone DB recover                 write what can be read of a damaged DB as SQL
+        sqlite-clone bench [--rows N]           time inserts, lookups and scans, see bench.rs
+
+    DB is the database file every command works on, created if it doesn't exist, or
+    anything else Connection::open takes. Without one a command starts from an empty
+    database held in memory, which is gone when it exits, and the shell's .open opens a
+    file later. --load runs SQL scripts on the database first, as many as are given in
+    order, and a script written by dump loads back as the database it was dumped from.
+    import writes the database out as dump would as well, so that what it imported into
+    a database in memory can be loaded again.
+
+    serve --wire answers over TCP connections instead, and --socket PATH over a Unix
+    socket, in the length-prefixed protocol of wire.rs, each connection with a
+    transaction of its own. --replicas PORT ships each commit to the read replicas that
+    connect to PORT, which the shell's .replicate makes of it, see replication.rs.
+
+    With SQL given, or with stdin a pipe or a file rather than a terminal and no command,
+    the shell runs in batch mode: the commands are run as they would be at the prompt,
+    dot commands included, with no prompt and nothing else printed but their output, and
+    the first that fails stops the rest.
+
+    recover reads DB itself rather than opening it, as a file damaged enough to need it
+    won't open, see recover.rs.
+
+    RUST_LOG filters the engine's tracing, which goes to stderr, and the shell's .trace
+    changes the filter as it runs, see trace.rs.
+
+    A command that fails prints its error to stderr and exits with status 1, as does exec
+    at the first statement that fails and integrity-check when it finds a problem, so that
+    a script can tell.
+*/
+mod bench;
+pub(crate) mod dump;
+pub(crate) mod import;
+mod recover;
+mod serve;
+pub(crate) mod trace;
+mod wire;
+
+use crate::error::{self, English};
+use crate::executor::{Executor, RowSet};
+use crate::repl;
+use crate::replication;
+use crate::sql_parser::commands;
+use crate::storage::memdb::OpenTarget;
+use anyhow::{bail, Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::io::{IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+pub fn main() -> ExitCode {
+    trace::init();
+    match run(cli().get_matches()) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {}", error::render(&err, &English));
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn cli() -> Command {
+    Command::new("sqlite-clone")
+        .about("A SQLite clone, as a shell or a command for scripts")
+        .arg(
+            Arg::new("database")
+                .value_name("DB")
+                .help("The database file, created if need be, in memory if not given"),
+        )
+        .arg(
+            Arg::new("sql")
+                .value_name("SQL")
+                .requires("database")
+                .help("Run SQL, or commands of the shell, and exit"),
+        )
+        .arg(
+            Arg::new("load")
+                .long("load")
+                .value_name("SCRIPT")
+                .action(ArgAction::Append)
+                .global(true)
+                .help("Run the SQL in SCRIPT to fill the database first"),
+        )
+        .arg(init_arg())
+        .subcommand(
+            Command::new("repl")
+                .about("Read commands at a prompt, the default")
+                .arg(init_arg()),
+        )
+        .subcommand(
+            Command::new("exec")
+                .about("Run the SQL in a file, or on stdin, stopping at the first error")
+                .arg(
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .value_name("FILE")
+                        .help("The script to run, stdin if not given"),
+                ),
+        )
+        .subcommand(
+            Command::new("dump")
+                .about("Write the database, or TABLE, out as SQL")
+                .arg(Arg::new("table").value_name("TABLE")),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Add the rows of a CSV file to a table, then dump the database")
+                .arg(Arg::new("file").value_name("FILE").required(true))
+                .arg(Arg::new("table").value_name("TABLE").required(true)),
+        )
+        .subcommand(
+            Command::new("serve")
+                .about("Answer SQL POSTed to /query over HTTP")
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .value_parser(clap::value_parser!(u16))
+                        .default_value("8080"),
+                )
+                .arg(
+                    Arg::new("bind")
+                        .long("bind")
+                        .value_name("ADDRESS")
+                        .default_value("127.0.0.1"),
+                )
+                .arg(
+                    Arg::new("replicas")
+                        .long("replicas")
+                        .value_name("PORT")
+                        .value_parser(clap::value_parser!(u16))
+                        .help("Ship every commit to the replicas that connect to PORT"),
+                )
+                .arg(
+                    Arg::new("wire")
+                        .long("wire")
+                        .action(ArgAction::SetTrue)
+                        .help("Speak the length-prefixed protocol of wire.rs over TCP, not HTTP"),
+                )
+                .arg(
+                    Arg::new("socket")
+                        .long("socket")
+                        .value_name("PATH")
+                        .conflicts_with("wire")
+                        .help("Speak the length-prefixed protocol on a Unix socket at PATH"),
+                ),
+        )
+        .subcommand(
+            Command::new("integrity-check").about("Check the database, printing ok if it is"),
+        )
+        .subcommand(
+            Command::new("recover")
+                .about("Write whatever rows can be read out of a damaged database as SQL"),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Time inserts, lookups and scans of a table of generated rows")
+                .arg(
+                    Arg::new("rows")
+                        .long("rows")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("10000"),
+                ),
+        )
+}
+
+fn init_arg() -> Arg {
+    Arg::new("init")
+        .long("init")
+        .value_name("FILE")
+        .help("Run the commands in FILE at startup instead of ~/.sqliteclonerc")
+}
+
+fn run(args: ArgMatches) -> Result<ExitCode> {
+    let (name, matches) = match args.subcommand() {
+        Some((name, matches)) => (name, matches),
+        None => ("repl", &args),
+    };
+    if name == "recover" {
+        let Some(OpenTarget::File { path, .. }) = args
+            .get_one::<String>("database")
+            .map(|database| OpenTarget::parse(database))
+            .transpose()?
+        else {
+            bail!("recover reads a database file, and none was given");
+        };
+        let (sql, lost) = recover::recover(&path)?;
+        for line in lost {
+            eprintln!("{line}");
+        }
+        print!("{sql}");
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut executor = match args.get_one::<String>("database") {
+        Some(database) => Executor::open(OpenTarget::parse(database)?)?,
+        None => Executor::default(),
+    };
+    let scripts = matches.get_many::<String>("load").into_iter().flatten();
+    for script in scripts {
+        let sql =
+            std::fs::read_to_string(script).with_context(|| format!("cannot open \"{script}\""))?;
+        run_script(&mut executor, &sql, &mut std::io::sink())
+            .with_context(|| format!("cannot load \"{script}\""))?;
+    }
+
+    if let Some(sql) = args.get_one::<String>("sql") {
+        repl::run_batch(&mut executor, sql)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut stdout = std::io::stdout().lock();
+    match name {
+        "repl" if args.subcommand().is_none() && !std::io::stdin().is_terminal() => {
+            let mut script = String::new();
+            std::io::stdin().read_to_string(&mut script)?;
+            drop(stdout);
+            repl::run_batch(&mut executor, &script)?;
+        }
+        "repl" => {
+            let init = matches
+                .get_one::<String>("init")
+                .or(args.get_one::<String>("init"))
+                .map(PathBuf::from);
+            repl::repl_loop(executor, init)?;
+        }
+        "exec" => {
+            let sql = match matches.get_one::<String>("file") {
+                Some(path) => std::fs::read_to_string(path)
+                    .with_context(|| format!("cannot open \"{path}\""))?,
+                None => {
+                    let mut sql = String::new();
+                    std::io::stdin().read_to_string(&mut sql)?;
+                    sql
+                }
+            };
+            run_script(&mut executor, &sql, &mut stdout)?;
+        }
+        "dump" => {
+            let table = matches.get_one::<String>("table").map(String::as_str);
+            write!(stdout, "{}", dump::dump(&mut executor, table)?)?;
+        }
+        "import" => {
+            let path = matches.get_one::<String>("file").expect("a required arg");
+            let table = matches.get_one::<String>("table").expect("a required arg");
+            let csv =
+                std::fs::File::open(path).with_context(|| format!("cannot open \"{path}\""))?;
+            let csv = std::io::BufReader::new(csv);
+            let rows = import::import(&mut executor, csv, table, &Default::default())
+                .with_context(|| format!("cannot import \"{path}\""))?;
+            eprintln!("imported {rows} rows into {table}");
+            write!(stdout, "{}", dump::dump(&mut executor, None)?)?;
+        }
+        "serve" => {
+            let port = *matches.get_one::<u16>("port").expect("a default");
+            let bind = matches.get_one::<String>("bind").expect("a default");
+            if let Some(port) = matches.get_one::<u16>("replicas") {
+                let addr = replication::lead(&mut executor, bind, *port)?;
+                eprintln!("shipping commits to replicas on {addr}");
+            }
+            if let Some(path) = matches.get_one::<String>("socket") {
+                wire::serve(executor, wire::Listen::Unix(path.into()))?;
+            } else if matches.get_flag("wire") {
+                wire::serve(executor, wire::Listen::Tcp(bind.clone(), port))?;
+            } else {
+                serve::serve(executor, bind, port)?;
+            }
+        }
+        "integrity-check" => {
+            let result = executor.execute_sql("PRAGMA integrity_check;")?;
+            writeln!(stdout, "{result}")?;
+            if result.to_string() != "ok" {
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        "bench" => {
+            let rows = *matches.get_one::<usize>("rows").expect("a default");
+            write!(stdout, "{}", bench::bench(rows)?)?;
+        }
+        name => unreachable!("no command {name}"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Run each statement of a script, writing the rows of any that return some, and stop at
+/// the first that fails. The shell's own dot commands aren't SQL and can't be run here.
+fn run_script(executor: &mut Executor, script: &str, out: &mut dyn Write) -> Result<()> {
+    run_statements(executor, script, |result| {
+        if !result.rows.is_empty() {
+            writeln!(out, "{result}")?;
+        }
+        Ok(())
+    })
+}
+
+// run_script, handing each statement's result to `each` rather than writing it out.
+fn run_statements(
+    executor: &mut Executor,
+    script: &str,
+    mut each: impl FnMut(RowSet) -> Result<()>,
+) -> Result<()> {
+    for sql in commands(script) {
+        if sql.starts_with('.') {
+            bail!("{sql} is a command of the shell, only SQL can be run here");
+        }
+        let result = executor
+            .execute_sql(&sql)
+            .with_context(|| format!("in \"{sql}\""))?;
+        each(result)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_batch_stops_at_the_line_that_fails() {
+        let mut executor = Executor::default();
+        let script = "\
+CREATE TABLE t (a INTEGER);
+.mode csv
+INSERT INTO t (a)
+  VALUES (1);
+INSERT INTO nope (a) VALUES (2);
+INSERT INTO t (a) VALUES (3);
+";
+        let err = repl::run_batch(&mut executor, script).unwrap_err();
+        assert_eq!(format!("{err:#}"), "near line 5: no such table: nope");
+        let rows = executor.execute_sql("SELECT a FROM t;").unwrap();
+        assert_eq!(rows.to_string(), "1");
+    }
+
+    #[test]
+    fn the_sql_argument_runs_every_statement_in_it() {
+        let mut executor = Executor::default();
+        let sql = "CREATE TABLE t (a INTEGER); INSERT INTO t (a) VALUES (1); \
+            INSERT INTO t (a) VALUES (2);";
+        repl::run_batch(&mut executor, sql).unwrap();
+        let rows = executor.execute_sql("SELECT count(*) FROM t;").unwrap();
+        assert_eq!(rows.to_string(), "2");
+        let err = repl::run_batch(&mut executor, "SELECT 1; SELECT nope FROM t;").unwrap_err();
+        assert_eq!(format!("{err:#}"), "near line 1: no such column: nope");
+    }
+
+    #[test]
+    fn scripts_run_until_a_statement_fails() {
+        let mut executor = Executor::default();
+        let mut out = vec![];
+        let script = "\
+CREATE TABLE t (a INTEGER);
+INSERT INTO t (a) VALUES (1);
+SELECT a FROM t;
+SELECT nope FROM t;
+INSERT INTO t (a) VALUES (2);
+";
+        let err = run_script(&mut executor, script, &mut out).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "in \"SELECT nope FROM t;\": no such column: nope"
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), "1\n");
+        assert!(run_script(&mut executor, ".tables", &mut std::io::sink()).is_err());
+
+        // every command is parsed, with the shell when none is given
+        let args = cli().get_matches_from(["sqlite-clone", "--load", "a.sql", "--init", "rc"]);
+        assert_eq!(args.subcommand_name(), None);
+        assert_eq!(args.get_one::<String>("init").unwrap(), "rc");
+        let args =
+            cli().get_matches_from(["sqlite-clone", "exec", "-f", "x.sql", "--load", "a.sql"]);
+        let (_, exec) = args.subcommand().unwrap();
+        assert_eq!(exec.get_one::<String>("file").unwrap(), "x.sql");
+        assert_eq!(exec.get_many::<String>("load").unwrap().count(), 1);
+        assert!(cli()
+            .try_get_matches_from(["sqlite-clone", "import", "rows.csv"])
+            .is_err());
+        let args = cli().get_matches_from(["sqlite-clone", "x.db", "SELECT 1;"]);
+        assert_eq!(args.subcommand_name(), None);
+        assert_eq!(args.get_one::<String>("sql").unwrap(), "SELECT 1;");
+        let args = cli().get_matches_from(["sqlite-clone", "x.db", "dump"]);
+        assert_eq!(args.subcommand_name(), Some("dump"));
+    }
+}
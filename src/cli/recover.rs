@@ -0,0 +1,146 @@
+/*
+    `recover`, for a database file too damaged to open: whatever rows can still be read
+    out of it, written out as SQL as dump writes a database, to load into a new one.
+
+        sqlite-clone damaged.db recover > recovered.sql
+        sqlite-clone fixed.db exec -f recovered.sql
+
+    storage/recover.rs salvages the rows, page by page. They are put back into a database
+    in memory, each table made first from the CREATE TABLE sqlite_master kept for it, and
+    that database is dumped (see dump.rs), so what comes out loads back. A table whose
+    CREATE TABLE was lost, and lost_and_found for the rows of a file in SQLite's format
+    that no table's B-tree reaches any more, is made with columns c0, c1 and so on.
+
+    Whatever was left out is written to stderr, a line for each damaged page, each entry
+    of sqlite_master that couldn't be made again and each table with rows that couldn't
+    be put back.
+*/
+use super::dump;
+use crate::executor::Executor;
+use crate::storage::recover::{self, Salvage};
+use anyhow::Result;
+use std::path::Path;
+
+/// The SQL that makes again as much as can be salvaged from the file at `path`, and what
+/// was lost on the way.
+pub fn recover(path: &Path) -> Result<(String, Vec<String>)> {
+    let Salvage { rows, mut damage } = recover::salvage(path)?;
+    let mut executor = Executor::recovered(rows, &mut damage);
+    Ok((dump::dump(&mut executor, None)?, damage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memdb::OpenTarget;
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    // Overwrite page `number` of the file with bytes that mean nothing.
+    fn scribble(path: &Path, page_size: u64, number: u64) {
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start((number - 1) * page_size))
+            .unwrap();
+        let noise: Vec<u8> = (0..page_size).map(|i| (i * 7 + 3) as u8).collect();
+        file.write_all(&noise).unwrap();
+    }
+
+    fn database(uri: &str) -> Executor {
+        let mut executor = Executor::open(OpenTarget::parse(uri).unwrap()).unwrap();
+        let script = "\
+CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);
+CREATE INDEX idx_name ON users (name);
+CREATE TABLE notes (body TEXT);
+";
+        super::super::run_script(&mut executor, script, &mut std::io::sink()).unwrap();
+        executor.execute_sql("BEGIN;").unwrap();
+        for id in 1..=2000 {
+            let insert = format!("INSERT INTO users (id, name) VALUES ({id}, \"user {id}\");");
+            executor.execute_sql(&insert).unwrap();
+        }
+        executor
+            .execute_sql("INSERT INTO notes (body) VALUES (\"a note\");")
+            .unwrap();
+        executor.execute_sql("COMMIT;").unwrap();
+        executor
+    }
+
+    fn count(executor: &mut Executor, sql: &str) -> String {
+        executor.execute_sql(sql).unwrap().to_string()
+    }
+
+    #[test]
+    fn rows_either_side_of_a_damaged_page_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut executor = database(&path.to_string_lossy());
+        let (intact, lost) = recover(&path).unwrap();
+        assert_eq!(lost, Vec::<String>::new());
+        assert_eq!(intact, dump::dump(&mut executor, None).unwrap());
+        drop(executor);
+
+        scribble(&path, 4096, 5);
+        assert!(Executor::open(OpenTarget::parse(&path.to_string_lossy()).unwrap()).is_err());
+        let (sql, lost) = recover(&path).unwrap();
+        assert_eq!(
+            lost,
+            ["page 5: database disk image is malformed: bad cell layout"]
+        );
+
+        let mut loaded = Executor::default();
+        super::super::run_script(&mut loaded, &sql, &mut std::io::sink()).unwrap();
+        // the page held a hundred or two of the users, and no more
+        let users: usize = count(&mut loaded, "SELECT COUNT(*) FROM users;")
+            .parse()
+            .unwrap();
+        assert!((1700..2000).contains(&users), "{users}");
+        assert_eq!(
+            count(&mut loaded, "SELECT name FROM users WHERE id = 1;"),
+            "user 1"
+        );
+        assert_eq!(
+            count(&mut loaded, "SELECT name FROM users WHERE id = 2000;"),
+            "user 2000"
+        );
+        assert_eq!(count(&mut loaded, "SELECT body FROM notes;"), "a note");
+        assert!(sql.contains("CREATE INDEX idx_name ON users (name);"));
+    }
+
+    #[test]
+    fn leaves_of_sqlites_format_are_recovered_without_their_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut executor = database(&format!("file:{}?format=sqlite", path.display()));
+        let intact = dump::dump(&mut executor, None).unwrap();
+        drop(executor);
+        assert_eq!(recover(&path).unwrap(), (intact, vec![]));
+
+        // the users' interior page, with the rows of every leaf under it cut off from the
+        // table, and one of those leaves
+        let file = std::fs::read(&path).unwrap();
+        let interior = (2..=file.len() / 4096)
+            .find(|page| file[(page - 1) * 4096] == 0x05)
+            .unwrap() as u64;
+        scribble(&path, 4096, interior);
+        scribble(&path, 4096, interior - 1);
+        let (sql, lost) = recover(&path).unwrap();
+        assert_eq!(lost, Vec::<String>::new());
+        let mut loaded = Executor::default();
+        super::super::run_script(&mut loaded, &sql, &mut std::io::sink()).unwrap();
+        assert_eq!(count(&mut loaded, "SELECT COUNT(*) FROM users;"), "0");
+        let found: usize = count(&mut loaded, "SELECT COUNT(*) FROM lost_and_found;")
+            .parse()
+            .unwrap();
+        assert!((1700..2000).contains(&found), "{found}");
+        assert_eq!(
+            count(
+                &mut loaded,
+                "SELECT c1 FROM lost_and_found WHERE c1 = \"user 1\";"
+            ),
+            "user 1"
+        );
+        assert_eq!(count(&mut loaded, "SELECT body FROM notes;"), "a note");
+
+        assert!(recover(&dir.path().join("none.db")).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+/*
+    `serve`, answering SQL sent over HTTP, so that a database can be kept running and
+    queried by programs that would rather make a request than start the shell.
+
+        curl -d 'SELECT name FROM users;' http://127.0.0.1:8080/query
+
+    The body of a POST to /query is a script, run as `exec` runs one: each statement in
+    turn, stopping at the first that fails. The response is the rows of the statements
+    that returned some, a line per row as the shell prints them, or 400 and the error.
+
+    There is one database, made by --load before the server starts, and every request
+    sees what the ones before it did. The server runs on a thread of its own and hands
+    each request's script to the main thread, which owns the database and runs one script
+    at a time, so scripts never interleave and a transaction begun by one request is still
+    open for the next.
+
+    The requests waiting when the main thread comes to them are answered as a group,
+    their commits saved to the file as one, see transaction.rs, so that many small writes
+    arriving together share a sync rather than each waiting on its own. None of them is
+    answered until the group is saved, and if the save fails each is answered with its
+    error, as though that request's own commit had failed.
+*/
+use super::run_script;
+use crate::error::{self, English};
+use crate::executor::Executor;
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::post;
+use axum::Router;
+use tokio::sync::{mpsc, oneshot};
+
+// A script to run, and where to send its answer.
+type Request = (String, oneshot::Sender<Result<String, String>>);
+
+pub fn serve(executor: Executor, bind: &str, port: u16) -> Result<()> {
+    let (requests, received) = mpsc::channel(64);
+    let bind = bind.to_string();
+    let server = std::thread::spawn(move || listen(requests, &bind, port));
+    // until the server stops, which drops its end of the channel
+    answer_requests(executor, received);
+    server.join().expect("the server not to panic")
+}
+
+fn listen(requests: mpsc::Sender<Request>, bind: &str, port: u16) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let app = Router::new()
+            .route("/query", post(query))
+            .with_state(requests);
+        let listener = tokio::net::TcpListener::bind((bind, port))
+            .await
+            .with_context(|| format!("cannot listen on {bind}:{port}"))?;
+        eprintln!("serving on http://{}/query", listener.local_addr()?);
+        axum::serve(listener, app).await?;
+        Ok(())
+    })
+}
+
+async fn query(State(requests): State<mpsc::Sender<Request>>, sql: String) -> (StatusCode, String) {
+    let (reply, answer) = oneshot::channel();
+    if requests.send((sql, reply)).await.is_err() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "the database has stopped\n".to_string(),
+        );
+    }
+    match answer.await {
+        Ok(Ok(rows)) => (StatusCode::OK, rows),
+        Ok(Err(err)) => (StatusCode::BAD_REQUEST, format!("{err}\n")),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "the database has stopped\n".to_string(),
+        ),
+    }
+}
+
+// Run each script sent until the server stops sending them, those waiting together as
+// a group.
+fn answer_requests(mut executor: Executor, mut received: mpsc::Receiver<Request>) {
+    while let Some(request) = received.blocking_recv() {
+        let mut group = vec![request];
+        while let Ok(request) = received.try_recv() {
+            group.push(request);
+        }
+        let (scripts, replies): (Vec<_>, Vec<_>) = group.into_iter().unzip();
+        for (reply, answer) in replies
+            .into_iter()
+            .zip(answer_group(&mut executor, &scripts))
+        {
+            // a client that has gone away doesn't need its answer
+            let _ = reply.send(answer);
+        }
+    }
+}
+
+// Run the scripts in turn with their commits grouped, answering them once the group is
+// saved.
+fn answer_group(executor: &mut Executor, scripts: &[String]) -> Vec<Result<String, String>> {
+    executor.begin_group_commit();
+    let answers = scripts
+        .iter()
+        .map(|sql| answer(executor, sql))
+        .collect::<Vec<_>>();
+    match executor.end_group_commit() {
+        Ok(()) => answers,
+        Err(err) => {
+            let err = error::render(&err, &English);
+            answers
+                .into_iter()
+                .map(|answer| answer.and(Err(err.clone())))
+                .collect()
+        }
+    }
+}
+
+fn answer(executor: &mut Executor, sql: &str) -> Result<String, String> {
+    let mut out = vec![];
+    run_script(executor, sql, &mut out).map_err(|err| error::render(&err, &English))?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memdb::OpenTarget;
+
+    #[test]
+    fn requests_share_one_database() {
+        let (requests, received) = mpsc::channel(1);
+        let server = std::thread::spawn(move || answer_requests(Executor::default(), received));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let send = |sql: &str| runtime.block_on(query(State(requests.clone()), sql.to_string()));
+
+        assert_eq!(
+            send("CREATE TABLE t (a INTEGER);\nINSERT INTO t (a) VALUES (1);"),
+            (StatusCode::OK, String::new())
+        );
+        assert_eq!(
+            send("SELECT a FROM t;"),
+            (StatusCode::OK, "1\n".to_string())
+        );
+        assert_eq!(
+            send("SELECT b FROM t;"),
+            (
+                StatusCode::BAD_REQUEST,
+                "in \"SELECT b FROM t;\": no such column: b\n".to_string()
+            )
+        );
+        drop(requests);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn requests_waiting_together_are_committed_together() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("served.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut executor = open();
+        executor
+            .execute_sql("CREATE TABLE t (a INTEGER PRIMARY KEY);")
+            .unwrap();
+        let scripts = [
+            "INSERT INTO t (a) VALUES (1);",
+            "INSERT INTO t (a) VALUES (1);",
+            "INSERT INTO t (a) VALUES (2);\nSELECT count(*) FROM t;",
+        ]
+        .map(String::from);
+        let answers = answer_group(&mut executor, &scripts);
+        assert_eq!(answers[0], Ok(String::new()));
+        assert!(answers[1].is_err());
+        assert_eq!(answers[2], Ok("2\n".to_string()));
+        drop(executor);
+
+        let mut reopened = open();
+        let mut out = vec![];
+        run_script(&mut reopened, "SELECT a FROM t;", &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "1\n2\n");
+    }
+}
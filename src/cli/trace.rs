@@ -0,0 +1,65 @@
+/*
+    Where the engine's tracing goes in the shell: to stderr, filtered as RUST_LOG says, or
+    as `.trace` says once the shell is running.
+
+        RUST_LOG=rust_wrapper=debug sqlite-clone shop.db "SELECT * FROM users;"
+
+        $ .trace on
+        $ SELECT name FROM users WHERE id = 1;
+
+    The engine is instrumented with the tracing crate throughout. Each statement is a span
+    with its SQL, and inside it are events for its parse and its plan, and then for what
+    running it did lower down: a page found or not in the cache, read ahead, written back
+    or evicted (cache.rs), a B+tree node split (btree.rs), a commit or rollback of the
+    pager and the locks it took or found busy on the way (pager.rs), and at trace level
+    every page read and written and every fsync of the file (os_interface.rs). The
+    library itself sets up nothing, a program that embeds it adds a subscriber of its own.
+
+    `.trace on` shows the engine's events at debug level, `.trace off` none, and anything
+    else is a filter in RUST_LOG's syntax, `.trace rust_wrapper=trace` for everything.
+*/
+use anyhow::{bail, Context, Result};
+use std::io::IsTerminal;
+use std::sync::OnceLock;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload::Handle;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry};
+
+// What `.trace on` shows.
+const ON: &str = "rust_wrapper=debug";
+
+static FILTER: OnceLock<Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Send tracing to stderr, filtered by RUST_LOG, which shows nothing if it isn't set.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("off"));
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(filter);
+    let output = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .without_time();
+    // a subscriber set already, by a test say, is left as it is
+    if tracing_subscriber::registry()
+        .with(filter)
+        .with(output)
+        .try_init()
+        .is_ok()
+    {
+        let _ = FILTER.set(handle);
+    }
+}
+
+/// `.trace on`, `.trace off` or `.trace FILTER`.
+pub fn set(filter: &str) -> Result<()> {
+    let filter = match filter {
+        "on" => EnvFilter::new(ON),
+        "off" => EnvFilter::new("off"),
+        filter => EnvFilter::try_new(filter).with_context(|| format!("bad filter: {filter}"))?,
+    };
+    let Some(handle) = FILTER.get() else {
+        bail!("tracing isn't set up");
+    };
+    handle.reload(filter)?;
+    Ok(())
+}
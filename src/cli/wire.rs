@@ -0,0 +1,356 @@
+/*
+    `serve --wire` and `serve --socket PATH`, answering SQL over connections held open,
+    TCP or a Unix socket, for clients that would rather keep talking than make a request
+    per script, and that want a transaction of their own.
+
+    Everything sent either way is a frame: a 4-byte big-endian length, then that many
+    bytes. A client sends a script as one frame of UTF-8 and is sent back a frame per row
+    its statements return, each as soon as its statement has run, then one that ends the
+    answer. The first byte of each says which it is:
+
+        R  a row, the rest of the frame the row as the shell prints it
+        K  the end of the answer, every statement having run
+        E  the end of the answer, the rest of the frame the error of the statement that
+           failed, which stopped the rest as exec stops
+
+    and a client can send its next script once it has the end of the answer to the last.
+
+    As with HTTP, the main thread owns the database and runs one script at a time, see
+    serve.rs, but here the transaction a connection begins is its own: while it is open
+    only that connection's scripts are run, and the others' wait until it commits or
+    rolls back, so no connection sees another's uncommitted writes or has its own undone
+    by another's ROLLBACK. A connection that closes with a transaction still open has it
+    rolled back, so a client that goes away never leaves the rest waiting.
+*/
+use super::run_statements;
+use crate::error::{self, English};
+use crate::executor::{Executor, RowSet};
+use anyhow::{bail, Context, Result};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+// Scripts longer than this are refused, and the connection sending one closed, rather
+// than a length read from a client that isn't speaking the protocol being allocated.
+const MAX_SCRIPT: usize = 64 << 20;
+
+/// Where to listen for connections.
+pub enum Listen {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+// Connections are told apart by a number, counting from 0 as they are accepted.
+type Session = u64;
+
+enum Job {
+    Run {
+        session: Session,
+        sql: String,
+        answer: mpsc::UnboundedSender<Frame>,
+    },
+    Close(Session),
+}
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Row(String),
+    Done,
+    Failed(String),
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let (kind, text) = match self {
+            Frame::Row(row) => (b'R', row.as_str()),
+            Frame::Done => (b'K', ""),
+            Frame::Failed(err) => (b'E', err.as_str()),
+        };
+        let mut frame = Vec::with_capacity(5 + text.len());
+        frame.extend((text.len() as u32 + 1).to_be_bytes());
+        frame.push(kind);
+        frame.extend(text.as_bytes());
+        frame
+    }
+}
+
+pub fn serve(executor: Executor, on: Listen) -> Result<()> {
+    let (jobs, received) = mpsc::channel(64);
+    let server = std::thread::spawn(move || listen(jobs, on));
+    // until the server stops, which drops its end of the channel
+    run_sessions(executor, received);
+    server.join().expect("the server not to panic")
+}
+
+fn listen(jobs: mpsc::Sender<Job>, on: Listen) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let mut sessions = 0..;
+        match on {
+            Listen::Tcp(bind, port) => {
+                let listener = tokio::net::TcpListener::bind((bind.as_str(), port))
+                    .await
+                    .with_context(|| format!("cannot listen on {bind}:{port}"))?;
+                eprintln!("serving on {}", listener.local_addr()?);
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    let session = sessions.next().expect("sessions never to run out");
+                    tokio::spawn(connection(stream, session, jobs.clone()));
+                }
+            }
+            #[cfg(unix)]
+            Listen::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                // a socket left behind by a server that has stopped, never another file
+                if std::fs::metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(&path)?;
+                }
+                let listener = tokio::net::UnixListener::bind(&path)
+                    .with_context(|| format!("cannot listen on {}", path.display()))?;
+                eprintln!("serving on {}", path.display());
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    let session = sessions.next().expect("sessions never to run out");
+                    tokio::spawn(connection(stream, session, jobs.clone()));
+                }
+            }
+            #[cfg(not(unix))]
+            Listen::Unix(_) => bail!("Unix sockets are only served on Unix"),
+        }
+    })
+}
+
+async fn connection<S>(mut stream: S, session: Session, jobs: mpsc::Sender<Job>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // a client that breaks off mid-frame has gone away all the same
+    let _ = converse(&mut stream, session, &jobs).await;
+    let _ = jobs.send(Job::Close(session)).await;
+}
+
+// Answer the scripts the client sends until it closes the connection.
+async fn converse<S>(stream: &mut S, session: Session, jobs: &mpsc::Sender<Job>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let mut length = [0; 4];
+        match stream.read_exact(&mut length).await {
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            read => read?,
+        };
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_SCRIPT {
+            bail!("a script of {length} bytes is longer than {MAX_SCRIPT}");
+        }
+        let mut sql = vec![0; length];
+        stream.read_exact(&mut sql).await?;
+        let Ok(sql) = String::from_utf8(sql) else {
+            let err = Frame::Failed("the script is not UTF-8".to_string());
+            stream.write_all(&err.encode()).await?;
+            continue;
+        };
+        let (answer, mut frames) = mpsc::unbounded_channel();
+        let job = Job::Run {
+            session,
+            sql,
+            answer,
+        };
+        if jobs.send(job).await.is_err() {
+            bail!("the database has stopped");
+        }
+        // until the script has run, which drops the answer's sender
+        while let Some(frame) = frames.recv().await {
+            stream.write_all(&frame.encode()).await?;
+        }
+        stream.flush().await?;
+    }
+}
+
+// Run the connections' scripts until the server stops sending them.
+fn run_sessions(mut executor: Executor, mut jobs: mpsc::Receiver<Job>) {
+    let mut sessions = Sessions::default();
+    while let Some(job) = jobs.blocking_recv() {
+        sessions.take(&mut executor, job);
+    }
+}
+
+#[derive(Default)]
+struct Sessions {
+    // the connection whose transaction is open, if one is
+    owner: Option<Session>,
+    // the scripts of the others, in the order they were sent, until it ends
+    waiting: VecDeque<Job>,
+}
+
+impl Sessions {
+    fn take(&mut self, executor: &mut Executor, job: Job) {
+        self.dispatch(executor, job);
+        // once a transaction ends those waiting have their turn, until one begins another,
+        // and those of the connection that began it run on
+        while !self.waiting.is_empty() {
+            let waited = self.waiting.len();
+            for job in std::mem::take(&mut self.waiting) {
+                self.dispatch(executor, job);
+            }
+            if self.waiting.len() == waited {
+                break;
+            }
+        }
+    }
+
+    fn dispatch(&mut self, executor: &mut Executor, job: Job) {
+        match job {
+            Job::Run { session, .. } if self.owner.is_some_and(|owner| owner != session) => {
+                self.waiting.push_back(job)
+            }
+            Job::Run {
+                session,
+                sql,
+                answer,
+            } => {
+                let end = match stream_script(executor, &sql, &answer) {
+                    Ok(()) => Frame::Done,
+                    Err(err) => Frame::Failed(error::render(&err, &English)),
+                };
+                // a client that has gone away doesn't need its answer
+                let _ = answer.send(end);
+                self.owner = executor.in_transaction().then_some(session);
+            }
+            Job::Close(session) => {
+                self.waiting.retain(|job| match job {
+                    Job::Run { session: s, .. } => *s != session,
+                    Job::Close(_) => true,
+                });
+                if self.owner == Some(session) {
+                    executor
+                        .execute_sql("ROLLBACK;")
+                        .expect("an open transaction to roll back");
+                    self.owner = None;
+                }
+            }
+        }
+    }
+}
+
+fn stream_script(
+    executor: &mut Executor,
+    script: &str,
+    answer: &mpsc::UnboundedSender<Frame>,
+) -> Result<()> {
+    run_statements(executor, script, |result| {
+        for row in result.rows {
+            let row = RowSet {
+                columns: vec![],
+                rows: vec![row],
+                changes: None,
+            };
+            let _ = answer.send(Frame::Row(row.to_string()));
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send `sql` from `session`, returning what has been answered so far.
+    fn send(
+        sessions: &mut Sessions,
+        executor: &mut Executor,
+        session: Session,
+        sql: &str,
+    ) -> mpsc::UnboundedReceiver<Frame> {
+        let (answer, frames) = mpsc::unbounded_channel();
+        let sql = sql.to_string();
+        let job = Job::Run {
+            session,
+            sql,
+            answer,
+        };
+        sessions.take(executor, job);
+        frames
+    }
+
+    fn answered(frames: &mut mpsc::UnboundedReceiver<Frame>) -> Vec<Frame> {
+        std::iter::from_fn(|| frames.try_recv().ok()).collect()
+    }
+
+    #[test]
+    fn a_connections_transaction_is_its_own() {
+        let mut executor = Executor::default();
+        let mut sessions = Sessions::default();
+        let script = "CREATE TABLE t (a INTEGER);\nBEGIN;\nINSERT INTO t (a) VALUES (1);";
+        let mut a = send(&mut sessions, &mut executor, 0, script);
+        assert_eq!(answered(&mut a), [Frame::Done]);
+
+        // the other connection waits for the transaction to end, and doesn't see its row
+        let mut b = send(&mut sessions, &mut executor, 1, "SELECT COUNT(*) FROM t;");
+        assert_eq!(answered(&mut b), []);
+        let mut a = send(
+            &mut sessions,
+            &mut executor,
+            0,
+            "SELECT a FROM t;\nROLLBACK;",
+        );
+        assert_eq!(answered(&mut a), [Frame::Row("1".to_string()), Frame::Done]);
+        assert_eq!(answered(&mut b), [Frame::Row("0".to_string()), Frame::Done]);
+
+        // and a transaction left open by a connection that closes is rolled back
+        let mut a = send(
+            &mut sessions,
+            &mut executor,
+            0,
+            "BEGIN;\nINSERT INTO t (a) VALUES (2);",
+        );
+        assert_eq!(answered(&mut a), [Frame::Done]);
+        let mut b = send(&mut sessions, &mut executor, 1, "SELECT COUNT(*) FROM t;");
+        sessions.take(&mut executor, Job::Close(0));
+        assert_eq!(answered(&mut b), [Frame::Row("0".to_string()), Frame::Done]);
+        assert!(!executor.in_transaction());
+
+        let mut b = send(&mut sessions, &mut executor, 1, "SELECT b FROM t;");
+        assert_eq!(
+            answered(&mut b),
+            [Frame::Failed(
+                "in \"SELECT b FROM t;\": no such column: b".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn scripts_and_rows_are_framed() {
+        let (jobs, received) = mpsc::channel(1);
+        let database = std::thread::spawn(move || run_sessions(Executor::default(), received));
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let server = tokio::spawn(connection(server, 0, jobs));
+            let script = "CREATE TABLE t (a INTEGER, b TEXT);\nINSERT INTO t (a, b) VALUES (1, \"a\");\nINSERT INTO t (a) VALUES (2);\nSELECT a, b FROM t;";
+            client
+                .write_all(&(script.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            client.write_all(script.as_bytes()).await.unwrap();
+            let mut answer = vec![];
+            for frame in [
+                Frame::Row("1|a".to_string()),
+                Frame::Row("2|".to_string()),
+                Frame::Done,
+            ] {
+                answer.extend(frame.encode());
+            }
+            let mut read = vec![0; answer.len()];
+            client.read_exact(&mut read).await.unwrap();
+            assert_eq!(read, answer);
+            assert_eq!(&read[..6], b"\0\0\0\x04R1");
+
+            drop(client);
+            server.await.unwrap();
+        });
+        database.join().unwrap();
+    }
+}
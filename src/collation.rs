@@ -0,0 +1,247 @@
+/*
+    Collations, the rules text is compared and sorted by.
+
+    Whenever two text values are compared, for `=`, `<`, ORDER BY or to find a key in a
+    B+tree, a collation decides how they compare. SQLite has three built in:
+
+        BINARY  byte by byte, so "Bob" and "bob" differ and "B" sorts before "a". The
+                default.
+        NOCASE  the same, but ASCII letters compare without regard to case
+        RTRIM   the same as BINARY, but spaces on the end are ignored
+
+    An embedder can add collations of its own with create_collation, the counterpart of
+    sqlite3_create_collation, say one that sorts "item 9" before "item 10".
+
+    A column can choose its collation in CREATE TABLE, `name TEXT COLLATE NOCASE`, and
+    any expression can have one applied with a COLLATE postfix, `name COLLATE BINARY`.
+    Which one a comparison uses is decided as in SQLite: an explicit COLLATE on the left
+    operand, then one on the right, then the column collation of the left operand if it
+    is a column, then the right's, and otherwise BINARY. ORDER BY and indexes on a column
+    use its collation too, so a NOCASE column sorts without regard to case and a UNIQUE
+    index on it rejects "BOB" when "bob" is already there.
+
+    Collations only apply to text. Numbers, and values of different types, always compare
+    the way they do under BINARY.
+*/
+use crate::sql_parser::ast::ColVal;
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// The signature of a collation's comparison of two text values.
+pub type CollateFn = dyn Fn(&str, &str) -> Ordering + Send + Sync;
+
+#[derive(Clone)]
+pub struct Collation {
+    pub name: String,
+    compare: Arc<CollateFn>,
+}
+
+impl fmt::Debug for Collation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+// Collations are known by name, two with the same name are the same collation.
+impl PartialEq for Collation {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for Collation {}
+
+impl Collation {
+    pub fn new(
+        name: &str,
+        compare: impl Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    ) -> Self {
+        Collation {
+            name: name.to_uppercase(),
+            compare: Arc::new(compare),
+        }
+    }
+
+    pub fn binary() -> Self {
+        Collation::new("BINARY", |a, b| a.cmp(b))
+    }
+
+    /// The built in collation with the given name, in any case.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "BINARY" => Some(Collation::binary()),
+            "NOCASE" => Some(Collation::new("NOCASE", |a, b| {
+                a.bytes()
+                    .map(|c| c.to_ascii_lowercase())
+                    .cmp(b.bytes().map(|c| c.to_ascii_lowercase()))
+            })),
+            "RTRIM" => Some(Collation::new("RTRIM", |a, b| {
+                a.trim_end_matches(' ').cmp(b.trim_end_matches(' '))
+            })),
+            _ => None,
+        }
+    }
+
+    pub fn compare(&self, a: &ColVal, b: &ColVal) -> Ordering {
+        match (a, b) {
+            (ColVal::String(a), ColVal::String(b)) => (self.compare)(a, b),
+            _ => a.cmp(b),
+        }
+    }
+
+    /// Compare rows value by value, each under its own collation.
+    pub fn compare_rows(collations: &[Collation], a: &[ColVal], b: &[ColVal]) -> Ordering {
+        collations
+            .iter()
+            .zip(a.iter().zip(b))
+            .map(|(collation, (a, b))| collation.compare(a, b))
+            .find(|o| *o != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len()))
+    }
+
+    /// The shortest row that compare_rows puts after `left` and no later than `right`, for
+    /// a B+tree to separate them by instead of all of `right`: right's values up to and
+    /// including the first that differs from left's, and of that one, if it is text, only
+    /// as many characters as it takes to come after left's. None if no value differs.
+    pub fn separate_rows(
+        collations: &[Collation],
+        left: &[ColVal],
+        right: &[ColVal],
+    ) -> Option<Vec<ColVal>> {
+        let differs = collations
+            .iter()
+            .zip(left.iter().zip(right))
+            .position(|(collation, (l, r))| collation.compare(l, r) != Ordering::Equal)?;
+        let mut row = right[..differs].to_vec();
+        row.push(match (&left[differs], &right[differs]) {
+            (l, ColVal::String(r)) => r
+                .char_indices()
+                .map(|(at, c)| ColVal::String(r[..at + c.len_utf8()].to_string()))
+                .find(|prefix| collations[differs].compare(l, prefix) == Ordering::Less)
+                .unwrap_or_else(|| right[differs].clone()),
+            (_, r) => r.clone(),
+        });
+        Some(row)
+    }
+}
+
+/// The collations a connection knows about, the built in ones and any an embedder adds.
+#[derive(Debug, Clone, Default)]
+pub struct Collations {
+    registered: HashMap<String, Collation>,
+}
+
+impl Collations {
+    /// Add a collation, replacing any earlier one of the same name. Built in collations
+    /// can be replaced too.
+    pub fn create_collation(
+        &mut self,
+        name: &str,
+        compare: impl Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    ) {
+        let collation = Collation::new(name, compare);
+        self.registered.insert(collation.name.clone(), collation);
+    }
+
+    pub fn get(&self, name: &str) -> Result<Collation> {
+        self.registered
+            .get(&name.to_uppercase())
+            .cloned()
+            .or_else(|| Collation::builtin(name))
+            .ok_or_else(|| anyhow!("no such collation sequence: {name}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> ColVal {
+        ColVal::String(s.to_string())
+    }
+
+    #[test]
+    fn builtin_collations_compare_text() {
+        let collations = Collations::default();
+        let compare = |name: &str, a: &str, b: &str| {
+            collations.get(name).unwrap().compare(&text(a), &text(b))
+        };
+        assert_eq!(compare("BINARY", "Bob", "bob"), Ordering::Less);
+        assert_eq!(compare("nocase", "Bob", "bOB"), Ordering::Equal);
+        assert_eq!(compare("NOCASE", "apple", "Banana"), Ordering::Less);
+        assert_eq!(compare("RTRIM", "bob  ", "bob"), Ordering::Equal);
+        assert_eq!(compare("RTRIM", " bob", "bob"), Ordering::Less);
+
+        // other types compare as they always do
+        let nocase = collations.get("NOCASE").unwrap();
+        assert_eq!(nocase.compare(&ColVal::Int(2), &text("a")), Ordering::Less);
+        assert_eq!(
+            collations.get("klingon").unwrap_err().to_string(),
+            "no such collation sequence: klingon"
+        );
+    }
+
+    #[test]
+    fn embedders_can_create_collations() {
+        let mut collations = Collations::default();
+        // compare by length, then as usual
+        collations.create_collation("by_length", |a, b| {
+            a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+        });
+        let by_length = collations.get("BY_LENGTH").unwrap();
+        let mut words = [text("ccc"), text("a"), text("bb")];
+        words.sort_by(|a, b| by_length.compare(a, b));
+        assert_eq!(words, [text("a"), text("bb"), text("ccc")]);
+    }
+
+    #[test]
+    fn rows_are_separated_by_as_little_of_them_as_will_do() {
+        let binary = [Collation::binary(), Collation::binary()];
+        let separate = |collations: &[Collation], left: &[ColVal], right: &[ColVal]| {
+            Collation::separate_rows(collations, left, right)
+        };
+        assert_eq!(
+            separate(
+                &binary,
+                &[text("apple"), text("x")],
+                &[text("apricot"), text("y")]
+            ),
+            Some(vec![text("apr")])
+        );
+        // the first value that differs is cut short, those before it kept whole
+        assert_eq!(
+            separate(
+                &binary,
+                &[text("a"), text("bob")],
+                &[text("a"), text("carol")]
+            ),
+            Some(vec![text("a"), text("c")])
+        );
+        assert_eq!(
+            separate(
+                &binary,
+                &[ColVal::Int(1), text("z")],
+                &[ColVal::Int(2), text("a")]
+            ),
+            Some(vec![ColVal::Int(2)])
+        );
+        assert_eq!(
+            separate(&binary, &[text("a")], &[text("a"), text("b")]),
+            None
+        );
+
+        // under NOCASE "AB" is no higher than "ab", so one more character is needed
+        let nocase = [Collation::builtin("NOCASE").unwrap()];
+        assert_eq!(
+            separate(&nocase, &[text("AB")], &[text("abc")]),
+            Some(vec![text("abc")])
+        );
+        assert_eq!(
+            separate(&nocase, &[text("Ab")], &[text("bcd")]),
+            Some(vec![text("b")])
+        );
+    }
+}
@@ -0,0 +1,87 @@
+/*
+    How the executor puts together the rows of the two sides of a compound SELECT.
+
+    UNION ALL simply has the right side's rows follow the left's. The other operators
+    treat each side as a set of rows: UNION keeps the rows of either, INTERSECT those of
+    both and EXCEPT those of the left that aren't in the right, and every row comes out
+    once. Rows are the same when each of their values is, NULL included, which unlike
+    `=` takes a NULL to be the same as another NULL.
+
+    As in SQLite, which builds a temporary B-tree of the rows for them, the set operators
+    give their rows in order, compared as BINARY, so that without an ORDER BY they come out
+    as SQLite's would.
+*/
+use crate::sql_parser::ast::{ColVal, CompoundOperator};
+use std::collections::BTreeSet;
+
+type Values = Vec<ColVal>;
+
+/// The rows of `left` and `right` put together as `operator` says.
+pub fn combine(
+    operator: CompoundOperator,
+    left: impl IntoIterator<Item = Values>,
+    right: impl IntoIterator<Item = Values>,
+) -> Vec<Values> {
+    let left = left.into_iter();
+    match operator {
+        CompoundOperator::UnionAll => left.chain(right).collect(),
+        CompoundOperator::Union => left
+            .chain(right)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect(),
+        CompoundOperator::Intersect => {
+            let right: BTreeSet<Values> = right.into_iter().collect();
+            let left: BTreeSet<Values> = left.filter(|row| right.contains(row)).collect();
+            left.into_iter().collect()
+        }
+        CompoundOperator::Except => {
+            let right: BTreeSet<Values> = right.into_iter().collect();
+            let left: BTreeSet<Values> = left.filter(|row| !right.contains(row)).collect();
+            left.into_iter().collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(values: &[Option<i64>]) -> Vec<Values> {
+        values
+            .iter()
+            .map(|v| vec![v.map_or(ColVal::Null, ColVal::Int)])
+            .collect()
+    }
+
+    #[test]
+    fn set_operators_return_each_row_once_in_order() {
+        let left = rows(&[Some(3), None, Some(1), Some(3), None]);
+        let right = rows(&[Some(2), Some(3), None, Some(2)]);
+        let combined = |operator| combine(operator, left.clone(), right.clone());
+
+        assert_eq!(
+            combined(CompoundOperator::UnionAll),
+            rows(&[
+                Some(3),
+                None,
+                Some(1),
+                Some(3),
+                None,
+                Some(2),
+                Some(3),
+                None,
+                Some(2)
+            ])
+        );
+        assert_eq!(
+            combined(CompoundOperator::Union),
+            rows(&[None, Some(1), Some(2), Some(3)])
+        );
+        assert_eq!(
+            combined(CompoundOperator::Intersect),
+            rows(&[None, Some(3)])
+        );
+        assert_eq!(combined(CompoundOperator::Except), rows(&[Some(1)]));
+    }
+}
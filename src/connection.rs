@@ -0,0 +1,919 @@
+/*
+    The library's front door, for Rust programs that embed the database rather than run
+    the shell.
+
+        let mut conn = Connection::open(":memory:")?;
+        conn.execute("CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);", &[])?;
+        conn.execute("INSERT INTO users (name) VALUES (?);", &["amy".into()])?;
+        for row in conn.query("SELECT id, name FROM users WHERE name = ?;", &["amy".into()])? {
+            let row = row?;
+            println!("{} {}", row.get::<i64>(0)?, row.get::<String>(1)?);
+        }
+
+    A Connection is one Executor, one connection's view of its databases, and a
+    Statement is a PreparedStatement on it: parameters are bound by position, from 1, or
+    by name, never spliced into the SQL text, and unbound ones are NULL. execute and query
+    on the connection prepare the SQL, bind the values given in order and run it, and
+    preparing the same SQL again finds it in the connection's statement cache, so
+    there's nothing to gain from keeping a Statement around except to bind by name.
+
+    last_insert_rowid is the rowid of the row the connection last inserted into a rowid
+    table, as sqlite3_last_insert_rowid and the SQL function of the same name give it.
+    execute returns how many rows an INSERT, UPDATE or DELETE changed, and changes and
+    total_changes are sqlite3_changes and sqlite3_total_changes, see executor.rs.
+
+    A query's rows are made as they are read, the machine running on to the next row each
+    time one is asked for, see vdbe.rs, so that a query over millions of rows holds one of
+    them at a time. The Rows keeps the connection borrowed until it is dropped, as the
+    statement is still running, and a query that is interrupted, or fails some other way
+    part way through, hands out the error in place of its next row. A query that runs as
+    a tree of operators, see executor.rs, such as one with ORDER BY or GROUP BY, has its
+    rows made all at once before the first is handed out. How a Row's values are
+    read is in row.rs.
+
+    backup_to copies the database to a new file while the connection goes on using it,
+    as sqlite3_backup does, see backup.rs.
+
+    create_module adds a module of virtual tables, whose rows Rust code makes, as
+    sqlite3_create_module does, see vtab.rs, and create_scalar_function a function
+    written in Rust, as sqlite3_create_function does, see functions.rs. create_collation
+    adds a collation, as sqlite3_create_collation does, see collation.rs.
+
+    set_expiry gives a table used as a cache an expiry column, its rows deleted once their
+    time has passed, and sweep_expired prunes every such table at once, see ttl.rs.
+
+    schema describes the tables, indexes, views and triggers as data, for a program that
+    would otherwise read them out of sqlite_schema or the PRAGMAs, see introspect.rs.
+
+    interrupt stops the statement the connection is running, as sqlite3_interrupt does,
+    and as the connection is borrowed while it runs one, interrupt_handle is how another
+    thread gets at it, see interrupt.rs.
+
+    lock_status reports the lock each connection in the process holds on the database file
+    and the one it is waiting for, if any, as the shell's `.locks` does, to find who is in
+    the way when a statement fails with "database is locked", see storage/lock.rs.
+
+    as_of opens a read only connection to the database as it was at an earlier commit,
+    for a file in WAL mode whose log still has the commit, see storage/wal.rs. A report
+    reads one snapshot that way while other connections go on writing. last_commit names
+    the commit a write made, and retain_commits how many commits the log keeps.
+
+    busy_timeout and busy_handler decide what happens when another connection, in this
+    process or another, holds a lock the connection needs, as sqlite3_busy_timeout and
+    sqlite3_busy_handler do: by default the statement fails with "database is locked"
+    straight away, see storage/busy.rs.
+
+    set_authorizer has a callback asked about everything a statement would do before it
+    runs, each column it reads and each table it writes among them, so that it can turn
+    the statement down, as sqlite3_set_authorizer does, see authorizer.rs.
+
+    open takes the same filenames as ATTACH: a path, ":memory:", or a file: URI, see
+    memdb.rs. A path is a database file, created if it doesn't exist, that every change
+    is saved to as it is made or committed, see storage/image.rs.
+*/
+use crate::authorizer::{Action, Authorization, Authorizer};
+use crate::backup::{Backup, Progress, BACKUP_STEP_PAGES};
+use crate::executor::{Executor, RowSet};
+use crate::interrupt::InterruptHandle;
+use crate::introspect::SchemaInfo;
+use crate::prepared::PreparedStatement;
+use crate::row::Rows;
+use crate::sql_parser::ast::ColVal;
+use crate::storage::busy::BusyHandler;
+use crate::storage::lock::LockStatus;
+use crate::storage::memdb::OpenTarget;
+use crate::vtab::VirtualTable;
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A connection to a database, see the module comment.
+#[derive(Debug)]
+pub struct Connection {
+    executor: Executor,
+}
+
+impl Connection {
+    /// Open `filename`, a path, `:memory:` or a `file:` URI.
+    pub fn open(filename: &str) -> Result<Self> {
+        Ok(Connection {
+            executor: Executor::open(OpenTarget::parse(filename)?)?,
+        })
+    }
+
+    pub(crate) fn executor(&self) -> &Executor {
+        &self.executor
+    }
+
+    pub fn open_in_memory() -> Self {
+        Connection {
+            executor: Executor::default(),
+        }
+    }
+
+    /// Run a statement that returns no rows, or whose rows aren't wanted, with `params`
+    /// bound to its parameters in order, returning how many rows it changed.
+    pub fn execute(&mut self, sql: &str, params: &[ColVal]) -> Result<u64> {
+        self.prepare(sql)?.execute(params)
+    }
+
+    /// Run a query with `params` bound to its parameters in order, its rows read as they
+    /// are asked for.
+    pub fn query(&mut self, sql: &str, params: &[ColVal]) -> Result<Rows<'_>> {
+        let mut prepared = self.executor.prepare(sql)?;
+        bind(&mut prepared, params)?;
+        self.executor.query_prepared(&mut prepared)
+    }
+
+    /// Copy the database to a new file at `dest` while it is in use, see backup.rs,
+    /// calling `progress` after each step with how far it has got.
+    pub fn backup_to(
+        &mut self,
+        dest: impl AsRef<Path>,
+        mut progress: impl FnMut(Progress),
+    ) -> Result<()> {
+        let mut backup = self.backup(dest)?;
+        while !backup.step(self, BACKUP_STEP_PAGES)? {
+            progress(backup.progress());
+        }
+        progress(backup.progress());
+        Ok(())
+    }
+
+    /// A backup of the database to a new file at `dest`, to be copied a few pages at a
+    /// time with Backup::step, see backup.rs.
+    pub fn backup(&self, dest: impl AsRef<Path>) -> Result<Backup> {
+        Backup::new(&self.executor, dest.as_ref())
+    }
+
+    /// Add a module that `CREATE VIRTUAL TABLE name USING module(...)` makes tables
+    /// with, calling `create` with the arguments in the parentheses, see vtab.rs.
+    pub fn create_module(
+        &mut self,
+        module: &str,
+        create: impl Fn(&[String]) -> Result<Box<dyn VirtualTable>> + Send + Sync + 'static,
+    ) -> Result<()> {
+        self.executor.create_module(module, Arc::new(create))
+    }
+
+    /// Add a function SQL can call by `name`, implemented by `function` given the values
+    /// of its arguments. `arg_count` is how many it takes, None for any number, and only
+    /// a `deterministic` one may be used in an index, a CHECK or a generated column.
+    pub fn create_scalar_function(
+        &mut self,
+        name: &str,
+        arg_count: Option<usize>,
+        deterministic: bool,
+        function: impl Fn(&[ColVal]) -> Result<ColVal> + Send + Sync + 'static,
+    ) {
+        self.executor
+            .create_scalar_function(name, arg_count, deterministic, function)
+    }
+
+    /// Add a collation `COLLATE name` compares text with, or replace one of that name,
+    /// `compare` ordering two strings. An index keeps the collation it was built with
+    /// until REINDEX rebuilds it.
+    pub fn create_collation(
+        &mut self,
+        name: &str,
+        compare: impl Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    ) {
+        self.executor.create_collation(name, compare)
+    }
+
+    /// Every table, index, view and trigger as it is now, described as data, see
+    /// introspect.rs.
+    pub fn schema(&self) -> SchemaInfo {
+        self.executor.schema_info()
+    }
+
+    /// Give `table` an expiry column, in seconds since the Unix epoch, whose rows are
+    /// deleted once their time has passed, or with None stop them expiring, see ttl.rs.
+    pub fn set_expiry(&mut self, table: &str, column: Option<&str>) -> Result<()> {
+        self.executor.set_expiry(table, column)
+    }
+
+    /// Measure expiry against `clock` rather than the system's, in seconds since the Unix
+    /// epoch, as a test or a simulation would.
+    pub fn set_expiry_clock(&mut self, clock: impl Fn() -> i64 + Send + 'static) {
+        self.executor.set_expiry_clock(Box::new(clock));
+    }
+
+    /// Delete the expired rows of every table with an expiry column, including those no
+    /// statement has read since they expired.
+    pub fn sweep_expired(&mut self) -> Result<()> {
+        self.executor.sweep_expired()
+    }
+
+    /// The rowid of the last row inserted into a rowid table, 0 if there hasn't been one.
+    pub fn last_insert_rowid(&self) -> i64 {
+        self.executor.last_insert_rowid()
+    }
+
+    /// How many rows the last INSERT, UPDATE or DELETE changed, not counting its triggers'.
+    pub fn changes(&self) -> u64 {
+        self.executor.changes()
+    }
+
+    /// How many rows every INSERT, UPDATE and DELETE has changed since the connection
+    /// was opened.
+    pub fn total_changes(&self) -> u64 {
+        self.executor.total_changes()
+    }
+
+    /// Stop the statement running, which fails with "interrupted", or the next one to run
+    /// if there is none.
+    pub fn interrupt(&self) {
+        self.executor.interrupt_handle().interrupt()
+    }
+
+    /// What interrupts this connection's statements from another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.executor.interrupt_handle()
+    }
+
+    /// The lock each of the process's connections to the database file holds, and what
+    /// it last asked for and didn't get, nothing for a database in memory.
+    pub fn lock_status(&self) -> Vec<LockStatus> {
+        self.executor.lock_status()
+    }
+
+    /// A read only connection to the database as it was at the commit `commit`, which
+    /// last_commit gave after it was made, for a report that must add up to read while
+    /// writes go on. Experimental: the file has to be in WAL mode, and the commit still in
+    /// its log, see retain_commits.
+    pub fn as_of(&mut self, commit: u64) -> Result<Connection> {
+        Ok(Connection {
+            executor: self.executor.as_of(commit)?,
+        })
+    }
+
+    /// The latest commit to the database file's write-ahead log, None unless it is in
+    /// WAL mode.
+    pub fn last_commit(&self) -> Option<u64> {
+        self.executor.last_commit()
+    }
+
+    /// Have the write-ahead log keep the last `commits` commits, rather than checkpoint
+    /// them into the file, so that as_of can still read them.
+    pub fn retain_commits(&mut self, commits: usize) -> Result<()> {
+        self.executor.retain_commits(commits)
+    }
+
+    /// Keep trying a lock another connection holds for up to `timeout` before failing,
+    /// as PRAGMA busy_timeout does. A timeout of zero fails straight away.
+    pub fn busy_timeout(&mut self, timeout: Duration) {
+        let busy = match timeout.is_zero() {
+            true => BusyHandler::None,
+            false => BusyHandler::Timeout(timeout),
+        };
+        self.executor.set_busy_handler(busy);
+    }
+
+    /// Call `handler` when a lock is held by another connection, with how many times it
+    /// has been called before for that lock, and try the lock again if it returns true.
+    pub fn busy_handler(&mut self, handler: impl Fn(u32) -> bool + Send + Sync + 'static) {
+        self.executor
+            .set_busy_handler(BusyHandler::Callback(Arc::new(handler)));
+    }
+
+    /// Ask `authorizer` whether each action of a statement is allowed before the statement
+    /// runs, failing the statement with "not authorized" if one isn't, see authorizer.rs.
+    pub fn set_authorizer(
+        &mut self,
+        authorizer: impl Fn(&Action) -> Authorization + Send + Sync + 'static,
+    ) {
+        self.executor
+            .set_authorizer(Some(Authorizer::new(authorizer)));
+    }
+
+    /// Allow every statement again.
+    pub fn remove_authorizer(&mut self) {
+        self.executor.set_authorizer(None);
+    }
+
+    /// A statement to bind values to and run, as many times as needed.
+    pub fn prepare(&mut self, sql: &str) -> Result<Statement<'_>> {
+        let prepared = self.executor.prepare(sql)?;
+        Ok(Statement {
+            conn: self,
+            prepared,
+        })
+    }
+}
+
+impl Default for Connection {
+    fn default() -> Self {
+        Connection::open_in_memory()
+    }
+}
+
+/// A prepared statement on a connection.
+pub struct Statement<'conn> {
+    conn: &'conn mut Connection,
+    prepared: PreparedStatement,
+}
+
+impl Statement<'_> {
+    pub fn parameter_count(&self) -> usize {
+        self.prepared.parameter_count()
+    }
+
+    /// The name of the 1-based parameter `index` with its prefix, e.g. `:name`, None if
+    /// it is a bare `?`.
+    pub fn parameter_name(&self, index: usize) -> Option<&str> {
+        self.prepared.parameter_name(index)
+    }
+
+    /// Bind a value to the 1-based parameter `index`.
+    pub fn bind(&mut self, index: usize, value: impl Into<ColVal>) -> Result<()> {
+        self.prepared.bind(index, value.into())
+    }
+
+    /// Bind a value to a named parameter, written with its prefix, e.g. `:name`.
+    pub fn bind_named(&mut self, name: &str, value: impl Into<ColVal>) -> Result<()> {
+        self.prepared.bind_named(name, value.into())
+    }
+
+    /// Run the statement after binding `params` to its first parameters, leaving the rest
+    /// as they were bound, returning how many rows it changed, 0 unless it is an INSERT,
+    /// UPDATE or DELETE.
+    pub fn execute(&mut self, params: &[ColVal]) -> Result<u64> {
+        Ok(self.run(params)?.changes.unwrap_or(0))
+    }
+
+    /// Run the query after binding `params` to its first parameters, as execute does.
+    pub fn query(&mut self, params: &[ColVal]) -> Result<Rows<'_>> {
+        bind(&mut self.prepared, params)?;
+        self.conn.executor.query_prepared(&mut self.prepared)
+    }
+
+    fn run(&mut self, params: &[ColVal]) -> Result<RowSet> {
+        bind(&mut self.prepared, params)?;
+        self.conn.executor.execute_prepared(&mut self.prepared)
+    }
+}
+
+// Bind `params` to the statement's first parameters in order.
+fn bind(prepared: &mut PreparedStatement, params: &[ColVal]) -> Result<()> {
+    for (i, value) in params.iter().enumerate() {
+        prepared.bind(i + 1, value.clone())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SqlError;
+
+    fn collect(rows: Rows) -> Vec<Vec<ColVal>> {
+        rows.map(|row| row.unwrap().into_values()).collect()
+    }
+
+    #[test]
+    fn statements_run_with_their_parameters_bound() {
+        let mut conn = Connection::open(":memory:").unwrap();
+        conn.execute(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, age INTEGER);",
+            &[],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO users (name, age) VALUES (?, ?);",
+            &["amy".into(), 30.into()],
+        )
+        .unwrap();
+        let mut insert = conn
+            .prepare("INSERT INTO users (name, age) VALUES (:name, :age);")
+            .unwrap();
+        assert_eq!(insert.parameter_name(2), Some(":age"));
+        insert.bind_named(":name", "bob").unwrap();
+        insert.bind_named(":age", None::<i64>).unwrap();
+        insert.execute(&[]).unwrap();
+
+        let rows = conn
+            .query("SELECT name, age FROM users WHERE id >= ?;", &[1.into()])
+            .unwrap();
+        assert_eq!(rows.columns(), ["name", "age"]);
+        assert_eq!(
+            collect(rows),
+            vec![
+                vec!["amy".into(), 30.into()],
+                vec!["bob".into(), ColVal::Null]
+            ]
+        );
+        assert!(conn.query("SELECT ?;", &[1.into(), 2.into()]).is_err());
+    }
+
+    #[test]
+    fn autoincrement_rowids_are_never_used_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log.db");
+        let path = path.to_str().unwrap();
+        let mut conn = Connection::open(path).unwrap();
+        assert_eq!(conn.last_insert_rowid(), 0);
+        conn.execute(
+            "CREATE TABLE events (id INTEGER PRIMARY KEY AUTOINCREMENT, what TEXT);",
+            &[],
+        )
+        .unwrap();
+        conn.execute("CREATE TABLE notes (event INTEGER);", &[])
+            .unwrap();
+        for what in ["start", "stop"] {
+            conn.execute("INSERT INTO events (what) VALUES (?);", &[what.into()])
+                .unwrap();
+        }
+        assert_eq!(conn.last_insert_rowid(), 2);
+        conn.execute(
+            "INSERT INTO notes (event) VALUES (last_insert_rowid());",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(conn.last_insert_rowid(), 1);
+        conn.execute("DELETE FROM events WHERE id = 2;", &[])
+            .unwrap();
+        drop(conn);
+
+        // reopened, the table's largest row is 1 but 2 has been had already
+        let mut conn = Connection::open(path).unwrap();
+        conn.execute("INSERT INTO events (what) VALUES (?);", &["again".into()])
+            .unwrap();
+        assert_eq!(conn.last_insert_rowid(), 3);
+        let rows = conn
+            .query("SELECT name, seq FROM sqlite_sequence;", &[])
+            .unwrap();
+        assert_eq!(collect(rows), [["events".into(), 3.into()]]);
+        let rows = conn.query("SELECT event FROM notes;", &[]).unwrap();
+        assert_eq!(collect(rows), [[2.into()]]);
+    }
+
+    #[test]
+    fn writes_report_how_many_rows_they_changed() {
+        let mut conn = Connection::open_in_memory();
+        conn.execute("CREATE TABLE items (name TEXT, price INTEGER);", &[])
+            .unwrap();
+        conn.execute("CREATE TABLE log (what TEXT);", &[]).unwrap();
+        conn.execute(
+            "CREATE TRIGGER logged AFTER DELETE ON items BEGIN INSERT INTO log (what) VALUES (old.name); END;",
+            &[],
+        )
+        .unwrap();
+        for (name, price) in [("pen", 2), ("ink", 5), ("cap", 1)] {
+            let changed = conn
+                .execute(
+                    "INSERT INTO items (name, price) VALUES (?, ?);",
+                    &[name.into(), price.into()],
+                )
+                .unwrap();
+            assert_eq!(changed, 1);
+        }
+        assert_eq!(
+            conn.execute("UPDATE items SET price = price + 1 WHERE price < 5;", &[])
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            conn.execute("UPDATE items SET price = 0 WHERE price > 100;", &[])
+                .unwrap(),
+            0
+        );
+        // the rows the trigger inserts count towards the total alone
+        assert_eq!(conn.execute("DELETE FROM items;", &[]).unwrap(), 3);
+        assert_eq!(conn.changes(), 3);
+        assert_eq!(conn.total_changes(), 11);
+
+        // a query changes nothing and leaves changes() as the last write left it
+        assert_eq!(conn.execute("SELECT * FROM log;", &[]).unwrap(), 0);
+        let rows = conn
+            .query("SELECT changes(), total_changes();", &[])
+            .unwrap();
+        assert_eq!(collect(rows), vec![vec![3.into(), 11.into()]]);
+        // nor does a write that fails
+        conn.execute("CREATE UNIQUE INDEX log_what ON log (what);", &[])
+            .unwrap();
+        assert!(conn
+            .execute("INSERT INTO log (what) VALUES (?);", &["pen".into()])
+            .is_err());
+        assert_eq!((conn.changes(), conn.total_changes()), (3, 11));
+        let rows = conn.query("SELECT what, changes() FROM log;", &[]).unwrap();
+        assert_eq!(
+            collect(rows),
+            vec![
+                vec!["pen".into(), 3.into()],
+                vec!["ink".into(), 3.into()],
+                vec!["cap".into(), 3.into()],
+            ]
+        );
+    }
+
+    #[test]
+    fn a_database_file_keeps_what_was_written_to_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shop.db");
+        let path = path.to_str().unwrap();
+        let mut conn = Connection::open(path).unwrap();
+        conn.execute("CREATE TABLE items (name TEXT);", &[])
+            .unwrap();
+        conn.execute("CREATE INDEX items_name ON items (name);", &[])
+            .unwrap();
+        conn.execute("INSERT INTO items (name) VALUES (?);", &["pen".into()])
+            .unwrap();
+        conn.execute("INSERT INTO items (name) VALUES (?);", &["ink".into()])
+            .unwrap();
+        conn.execute("DELETE FROM items WHERE name = ?;", &["pen".into()])
+            .unwrap();
+        // what a transaction rolls back is never saved
+        conn.execute("BEGIN;", &[]).unwrap();
+        conn.execute("INSERT INTO items (name) VALUES (?);", &["cap".into()])
+            .unwrap();
+        conn.execute("ROLLBACK;", &[]).unwrap();
+        drop(conn);
+
+        let mut conn = Connection::open(path).unwrap();
+        let rows = conn
+            .query(
+                "SELECT rowid, name FROM items WHERE name = ?;",
+                &["ink".into()],
+            )
+            .unwrap();
+        assert_eq!(collect(rows), vec![vec![2.into(), "ink".into()]]);
+        let rows = conn.query("SELECT name FROM items;", &[]).unwrap();
+        assert_eq!(rows.count(), 1);
+
+        assert!(
+            Connection::open(&format!("file:{}/none.db?mode=rw", dir.path().display())).is_err()
+        );
+    }
+
+    #[test]
+    fn an_interrupted_statement_fails_and_the_next_runs() {
+        let mut conn = Connection::open_in_memory();
+        let handle = conn.interrupt_handle();
+        let interrupter = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            handle.interrupt();
+        });
+        // the count is the query's one row, which the error comes in place of
+        let mut rows = conn
+            .query(
+                "SELECT COUNT(*) FROM generate_series(1, 1000000000000);",
+                &[],
+            )
+            .unwrap();
+        let err = rows.next().unwrap().unwrap_err();
+        drop(rows);
+        interrupter.join().unwrap();
+        assert_eq!(err.to_string(), "interrupted");
+        assert_eq!(err.downcast_ref::<SqlError>().map(SqlError::code), Some(9));
+
+        // an interrupt while nothing runs stops the next statement, and only that one
+        conn.execute("CREATE TABLE t (a INTEGER);", &[]).unwrap();
+        conn.interrupt();
+        assert!(conn.execute("INSERT INTO t (a) VALUES (1);", &[]).is_err());
+        conn.execute("INSERT INTO t (a) VALUES (2);", &[]).unwrap();
+        let rows = conn.query("SELECT a FROM t;", &[]).unwrap();
+        assert_eq!(collect(rows), vec![vec![2.into()]]);
+    }
+
+    #[test]
+    fn a_query_hands_out_its_rows_as_they_are_made() {
+        let mut conn = Connection::open_in_memory();
+        conn.execute("CREATE TABLE t (n INTEGER);", &[]).unwrap();
+        let mut insert = conn.prepare("INSERT INTO t (n) VALUES (?);").unwrap();
+        for n in 0..1000_i64 {
+            insert.execute(&[n.into()]).unwrap();
+        }
+
+        // interrupted part way through, the rows so far are had and then the error
+        let handle = conn.interrupt_handle();
+        let mut rows = conn.query("SELECT n FROM t;", &[]).unwrap();
+        assert_eq!(rows.next().unwrap().unwrap().get::<i64>(0).unwrap(), 0);
+        handle.interrupt();
+        let read = rows.by_ref().take_while(Result::is_ok).count();
+        assert!(read < 1000);
+        assert!(rows.next().is_none());
+        drop(rows);
+
+        // and the statement after it runs
+        let rows = conn.query("SELECT n FROM t WHERE n > 997;", &[]).unwrap();
+        assert_eq!(collect(rows), vec![vec![998.into()], vec![999.into()]]);
+    }
+
+    #[test]
+    fn a_busy_lock_is_waited_for_as_long_as_the_handler_says() {
+        use crate::storage::lock::LockLevel;
+        use crate::storage::memdb::AccessMode;
+        use crate::storage::os_interface::{OsVfs, Vfs, VfsFile};
+        use std::sync::atomic::{AtomicU32, Ordering};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shop.db");
+        let mut conn = Connection::open(path.to_str().unwrap()).unwrap();
+        conn.execute("CREATE TABLE items (name TEXT);", &[])
+            .unwrap();
+        // another connection in the middle of writing the file
+        let mut writer = OsVfs.open(&path, AccessMode::ReadWrite).unwrap();
+        writer.lock(LockLevel::Shared).unwrap();
+        writer.lock(LockLevel::Exclusive).unwrap();
+        let insert = "INSERT INTO items (name) VALUES (\"pen\");";
+        assert_eq!(
+            conn.execute(insert, &[]).unwrap_err().to_string(),
+            "database is locked"
+        );
+
+        let asked = Arc::new(AtomicU32::new(0));
+        let counted = asked.clone();
+        conn.busy_handler(move |count| {
+            counted.store(count + 1, Ordering::Relaxed);
+            count < 3
+        });
+        assert!(conn.execute(insert, &[]).is_err());
+        assert_eq!(asked.load(Ordering::Relaxed), 4);
+
+        conn.execute("PRAGMA busy_timeout = 2000;", &[]).unwrap();
+        let rows = conn.query("PRAGMA busy_timeout;", &[]).unwrap();
+        assert_eq!(collect(rows), vec![vec![2000.into()]]);
+        let finishing = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            writer.unlock(LockLevel::Unlocked).unwrap();
+            writer
+        });
+        conn.execute(insert, &[]).unwrap();
+        drop(finishing.join().unwrap());
+        let rows = conn.query("SELECT name FROM items;", &[]).unwrap();
+        assert_eq!(rows.count(), 1);
+    }
+
+    #[test]
+    fn lock_status_lists_each_connection_to_the_file() {
+        use crate::storage::lock::LockLevel;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shop.db");
+        let first = Connection::open(path.to_str().unwrap()).unwrap();
+        let second = Connection::open(path.to_str().unwrap()).unwrap();
+        let status = first.lock_status();
+        assert_eq!(status.len(), 2);
+        assert!(status.iter().all(|s| s.held == LockLevel::Unlocked));
+        assert_eq!(second.lock_status(), status);
+        drop(second);
+        assert_eq!(first.lock_status().len(), 1);
+        assert!(Connection::open_in_memory().lock_status().is_empty());
+    }
+
+    #[test]
+    fn a_database_in_wal_mode_can_be_read_as_of_a_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shop.db");
+        let mut conn = Connection::open(path.to_str().unwrap()).unwrap();
+        assert_eq!(conn.last_commit(), None);
+        conn.execute("PRAGMA journal_mode = WAL;", &[]).unwrap();
+        conn.retain_commits(10).unwrap();
+        conn.execute("CREATE TABLE items (name TEXT);", &[])
+            .unwrap();
+        conn.execute("INSERT INTO items (name) VALUES ('pen');", &[])
+            .unwrap();
+        let commit = conn.last_commit().unwrap();
+        conn.execute("INSERT INTO items (name) VALUES ('ink');", &[])
+            .unwrap();
+
+        let mut past = conn.as_of(commit).unwrap();
+        let rows = past.query("SELECT name FROM items;", &[]).unwrap();
+        assert_eq!(collect(rows), vec![vec!["pen".into()]]);
+        assert!(past.execute("DELETE FROM items;", &[]).is_err());
+        let rows = conn.query("SELECT count(*) FROM items;", &[]).unwrap();
+        assert_eq!(collect(rows), vec![vec![2.into()]]);
+        assert!(conn.as_of(commit + 1_000).is_err());
+        assert!(Connection::open_in_memory().as_of(commit).is_err());
+    }
+
+    #[test]
+    fn the_authorizer_turns_down_what_it_denies() {
+        let mut conn = Connection::open_in_memory();
+        conn.execute(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, password TEXT);",
+            &[],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO users (name, password) VALUES (?, ?);",
+            &["amy".into(), "secret".into()],
+        )
+        .unwrap();
+        let name = conn.query("SELECT name FROM users;", &[]).unwrap();
+        assert_eq!(collect(name), vec![vec!["amy".into()]]);
+
+        conn.set_authorizer(|action| match action {
+            Action::Read { table, column } if table == "users" && column == "password" => {
+                Authorization::Deny
+            }
+            Action::Delete { .. } => Authorization::Deny,
+            _ => Authorization::Allow,
+        });
+        // a plan kept from before is planned, and asked about, again
+        let name = conn.query("SELECT name FROM users;", &[]).unwrap();
+        assert_eq!(collect(name), vec![vec!["amy".into()]]);
+        for sql in [
+            "SELECT password FROM users;",
+            "SELECT * FROM users;",
+            "SELECT name FROM users WHERE password = \"secret\";",
+            "UPDATE users SET name = password;",
+            "DELETE FROM users;",
+            "EXPLAIN DELETE FROM users;",
+        ] {
+            let err = conn.execute(sql, &[]).unwrap_err();
+            assert_eq!(err.to_string(), "not authorized", "{sql}");
+            assert_eq!(err.downcast_ref::<SqlError>().map(SqlError::code), Some(23));
+        }
+        conn.execute("UPDATE users SET password = \"new\";", &[])
+            .unwrap();
+
+        conn.remove_authorizer();
+        conn.execute("DELETE FROM users;", &[]).unwrap();
+    }
+
+    #[test]
+    fn scalar_functions_are_called_from_sql() {
+        let mut conn = Connection::open_in_memory();
+        conn.execute("CREATE TABLE items (price INTEGER);", &[])
+            .unwrap();
+        for price in [2, 5] {
+            conn.execute("INSERT INTO items (price) VALUES (?);", &[price.into()])
+                .unwrap();
+        }
+        let err = conn
+            .query("SELECT double(price) FROM items;", &[])
+            .and_then(|rows| rows.collect::<Result<Vec<_>>>());
+        assert_eq!(err.unwrap_err().to_string(), "no such function: double");
+
+        conn.create_scalar_function("double", Some(1), true, |args| match &args[0] {
+            ColVal::Int(n) => Ok(ColVal::Int(n * 2)),
+            _ => anyhow::bail!("double wants an integer"),
+        });
+        let doubled = conn
+            .query(
+                "SELECT double(price) FROM items WHERE double(price) > 4;",
+                &[],
+            )
+            .unwrap();
+        assert_eq!(collect(doubled), vec![vec![10.into()]]);
+        let err = conn
+            .query("SELECT double(\"two\");", &[])
+            .and_then(|rows| rows.collect::<Result<Vec<_>>>());
+        assert_eq!(err.unwrap_err().to_string(), "double wants an integer");
+        conn.execute(
+            "CREATE TABLE evens (n INTEGER, CHECK (double(n) = n + n));",
+            &[],
+        )
+        .unwrap();
+        conn.create_scalar_function("roll", Some(0), false, |_| Ok(ColVal::Int(4)));
+        let err = conn
+            .execute("CREATE TABLE dice (n INTEGER, CHECK (n < roll()));", &[])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "non-deterministic functions prohibited in CHECK constraints"
+        );
+    }
+
+    #[test]
+    fn collations_are_used_by_name() {
+        let mut conn = Connection::open_in_memory();
+        conn.execute("CREATE TABLE files (name TEXT);", &[])
+            .unwrap();
+        for name in ["item 10", "item 9", "ITEM 9"] {
+            conn.execute("INSERT INTO files (name) VALUES (?);", &[name.into()])
+                .unwrap();
+        }
+        let sql = "SELECT name FROM files WHERE name COLLATE numeric = \"item 9\";";
+        let err = conn
+            .query(sql, &[])
+            .and_then(|rows| rows.collect::<Result<Vec<_>>>());
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "no such collation sequence: numeric"
+        );
+
+        // "item 9" before "item 10", and case doesn't matter
+        conn.create_collation("numeric", |a, b| {
+            let split = |s: &str| {
+                let s = s.to_lowercase();
+                let digits = s.trim_start_matches(|c: char| !c.is_ascii_digit()).len();
+                let (text, number) = s.split_at(s.len() - digits);
+                (text.to_string(), number.parse::<u64>().unwrap_or(0))
+            };
+            split(a).cmp(&split(b))
+        });
+        let found = conn.query(sql, &[]).unwrap();
+        assert_eq!(
+            collect(found),
+            vec![vec!["item 9".into()], vec!["ITEM 9".into()]]
+        );
+        conn.execute(
+            "CREATE INDEX files_name ON files (name COLLATE numeric);",
+            &[],
+        )
+        .unwrap();
+        let sql = "SELECT name FROM files WHERE name COLLATE numeric > \"item 9\";";
+        assert_eq!(
+            collect(conn.query(sql, &[]).unwrap()),
+            vec![vec!["item 10".into()]]
+        );
+    }
+
+    #[test]
+    fn the_schema_is_read_as_data() {
+        let mut conn = Connection::open_in_memory();
+        let before = conn.schema();
+        assert!(before.tables.is_empty());
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT COLLATE NOCASE);",
+            "CREATE TABLE tags (user INTEGER, tag TEXT, PRIMARY KEY (user, tag), FOREIGN KEY (user) REFERENCES users (id)) WITHOUT ROWID;",
+            "CREATE INDEX users_email ON users (email);",
+        ] {
+            conn.execute(sql, &[]).unwrap();
+        }
+        let schema = conn.schema();
+        assert!(schema.cookie > before.cookie);
+
+        let users = schema.table("USERS").unwrap();
+        let columns: Vec<(&str, &str, bool)> = users
+            .columns
+            .iter()
+            .map(|c| (c.name.as_str(), c.collation.as_str(), c.rowid_alias))
+            .collect();
+        assert_eq!(
+            columns,
+            [("id", "BINARY", true), ("email", "NOCASE", false)]
+        );
+        let indexes: Vec<&str> = schema
+            .indexes_on("users")
+            .iter()
+            .map(|i| i.name.as_str())
+            .collect();
+        assert_eq!(indexes, ["users_email"]);
+
+        let tags = schema.table("tags").unwrap();
+        assert!(tags.without_rowid);
+        assert_eq!(tags.primary_key, ["user", "tag"]);
+        assert_eq!(tags.foreign_keys[0].parent, "users");
+        assert!(schema.table("nothing").is_none());
+    }
+
+    #[test]
+    fn expired_rows_are_deleted() {
+        let mut conn = Connection::open_in_memory();
+        conn.execute(
+            "CREATE TABLE sessions (token TEXT, expires_at INTEGER);",
+            &[],
+        )
+        .unwrap();
+        let later = i64::MAX;
+        for (token, expires_at) in [
+            ("old", 1.into()),
+            ("new", later.into()),
+            ("kept", ColVal::Null),
+        ] {
+            conn.execute(
+                "INSERT INTO sessions (token, expires_at) VALUES (?, ?);",
+                &[token.into(), expires_at],
+            )
+            .unwrap();
+        }
+        let count = |conn: &mut Connection| {
+            let rows = conn.query("SELECT count(*) FROM sessions;", &[]).unwrap();
+            collect(rows)[0][0].clone()
+        };
+        assert_eq!(count(&mut conn), 3.into());
+
+        conn.set_expiry("sessions", Some("expires_at")).unwrap();
+        conn.sweep_expired().unwrap();
+        conn.set_expiry("sessions", None).unwrap();
+        let tokens = conn.query("SELECT token FROM sessions;", &[]).unwrap();
+        assert_eq!(
+            collect(tokens),
+            vec![vec!["new".into()], vec!["kept".into()]]
+        );
+
+        // and on access, without a sweep
+        conn.execute(
+            "INSERT INTO sessions (token, expires_at) VALUES (\"stale\", 2);",
+            &[],
+        )
+        .unwrap();
+        conn.set_expiry("sessions", Some("expires_at")).unwrap();
+        assert_eq!(count(&mut conn), 2.into());
+        // as time goes by only the NULL is left
+        conn.set_expiry_clock(|| i64::MAX);
+        assert_eq!(count(&mut conn), 1.into());
+        assert_eq!(
+            conn.set_expiry("nothing", Some("expires_at"))
+                .unwrap_err()
+                .to_string(),
+            "no such table: nothing"
+        );
+    }
+}
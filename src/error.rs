@@ -0,0 +1,229 @@
+/*
+    Errors the engine reports often enough that a front-end may want to tell them apart,
+    and the wording they are shown with.
+
+    Most errors are an anyhow message and nothing more, but the ones here are an SqlError
+    carried inside the anyhow::Error, so they can be found again with downcast_ref. Each
+    has two things that never change from release to release, so programs can rely on
+    them: the SQLite result code it would have, 1 (SQLITE_ERROR) for "no such table" or
+    2067 (SQLITE_CONSTRAINT_UNIQUE) for a duplicate key, and a key naming the kind of
+    error, like "no_such_table". Its arguments are named too, "table" for the table that
+    wasn't found.
+
+    The words are not fixed. An error is shown by looking its key up in a Messages, a
+    template per key with the arguments in braces:
+
+        no_such_table      no such table: {table}
+        unique_constraint  UNIQUE constraint failed: {columns}
+
+    English, the default, has the same wording as SQLite, and is what Display uses. A
+    front-end can give render a Messages of its own, to translate the errors or say them
+    differently. A key it has no template for falls back to English, and errors that are
+    not an SqlError are shown as they are.
+*/
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlError {
+    NoSuchTable { table: String },
+    NoSuchColumn { column: String },
+    NoSuchIndex { index: String },
+    NoSuchFunction { name: String },
+    WrongArgumentCount { name: String },
+    UniqueConstraint { columns: Vec<String> },
+    CheckConstraint { check: String },
+    NotNullConstraint { column: String },
+    ForeignKeyConstraint,
+    DatatypeMismatch,
+    DatabaseLocked,
+    DatabaseFull,
+    Interrupted,
+    NotAuthorized,
+    TooDeep,
+}
+
+impl SqlError {
+    /// The SQLite result code, extended where SQLite has one.
+    pub fn code(&self) -> i32 {
+        match self {
+            SqlError::NoSuchTable { .. }
+            | SqlError::NoSuchColumn { .. }
+            | SqlError::NoSuchIndex { .. }
+            | SqlError::NoSuchFunction { .. }
+            | SqlError::WrongArgumentCount { .. }
+            | SqlError::TooDeep => 1,
+            SqlError::DatabaseLocked => 5,
+            SqlError::Interrupted => 9,
+            SqlError::DatabaseFull => 13,
+            SqlError::DatatypeMismatch => 20,
+            SqlError::NotAuthorized => 23,
+            SqlError::CheckConstraint { .. } => 275,
+            SqlError::NotNullConstraint { .. } => 1299,
+            SqlError::ForeignKeyConstraint => 787,
+            SqlError::UniqueConstraint { .. } => 2067,
+        }
+    }
+
+    /// The key of the message template the error is shown with.
+    pub fn key(&self) -> &'static str {
+        match self {
+            SqlError::NoSuchTable { .. } => "no_such_table",
+            SqlError::NoSuchColumn { .. } => "no_such_column",
+            SqlError::NoSuchIndex { .. } => "no_such_index",
+            SqlError::NoSuchFunction { .. } => "no_such_function",
+            SqlError::WrongArgumentCount { .. } => "wrong_argument_count",
+            SqlError::UniqueConstraint { .. } => "unique_constraint",
+            SqlError::CheckConstraint { .. } => "check_constraint",
+            SqlError::NotNullConstraint { .. } => "not_null_constraint",
+            SqlError::ForeignKeyConstraint => "foreign_key_constraint",
+            SqlError::DatatypeMismatch => "datatype_mismatch",
+            SqlError::DatabaseLocked => "database_locked",
+            SqlError::DatabaseFull => "database_full",
+            SqlError::Interrupted => "interrupted",
+            SqlError::NotAuthorized => "not_authorized",
+            SqlError::TooDeep => "too_deep",
+        }
+    }
+
+    /// The arguments a template can use, by name.
+    pub fn args(&self) -> Vec<(&'static str, String)> {
+        match self {
+            SqlError::NoSuchTable { table } => vec![("table", table.clone())],
+            SqlError::NoSuchColumn { column } => vec![("column", column.clone())],
+            SqlError::NoSuchIndex { index } => vec![("index", index.clone())],
+            SqlError::NoSuchFunction { name } | SqlError::WrongArgumentCount { name } => {
+                vec![("name", name.clone())]
+            }
+            SqlError::UniqueConstraint { columns } => vec![("columns", columns.join(", "))],
+            SqlError::CheckConstraint { check } => vec![("check", check.clone())],
+            SqlError::NotNullConstraint { column } => vec![("column", column.clone())],
+            SqlError::ForeignKeyConstraint
+            | SqlError::DatatypeMismatch
+            | SqlError::DatabaseLocked
+            | SqlError::DatabaseFull
+            | SqlError::Interrupted
+            | SqlError::NotAuthorized
+            | SqlError::TooDeep => vec![],
+        }
+    }
+
+    /// The error in the words of `messages`, or of English if it has none for it.
+    pub fn render(&self, messages: &dyn Messages) -> String {
+        let template = messages
+            .template(self.key())
+            .or_else(|| English.template(self.key()))
+            .expect("English has a template for every key");
+        self.args()
+            .iter()
+            .fold(template.to_string(), |message, (name, value)| {
+                message.replace(&format!("{{{name}}}"), value)
+            })
+    }
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render(&English))
+    }
+}
+
+impl std::error::Error for SqlError {}
+
+/// Where the wording of errors comes from, a template by key.
+pub trait Messages {
+    fn template(&self, key: &str) -> Option<&str>;
+}
+
+/// SQLite's own wording.
+pub struct English;
+
+impl Messages for English {
+    fn template(&self, key: &str) -> Option<&str> {
+        Some(match key {
+            "no_such_table" => "no such table: {table}",
+            "no_such_column" => "no such column: {column}",
+            "no_such_index" => "no such index: {index}",
+            "no_such_function" => "no such function: {name}",
+            "wrong_argument_count" => "wrong number of arguments to function {name}()",
+            "unique_constraint" => "UNIQUE constraint failed: {columns}",
+            "check_constraint" => "CHECK constraint failed: {check}",
+            "not_null_constraint" => "NOT NULL constraint failed: {column}",
+            "foreign_key_constraint" => "FOREIGN KEY constraint failed",
+            "datatype_mismatch" => "datatype mismatch",
+            "database_locked" => "database is locked",
+            "database_full" => "database or disk is full",
+            "interrupted" => "interrupted",
+            "not_authorized" => "not authorized",
+            "too_deep" => "too many levels of trigger recursion",
+            _ => return None,
+        })
+    }
+}
+
+// Templates given as a map, say read from a translation file.
+impl Messages for HashMap<String, String> {
+    fn template(&self, key: &str) -> Option<&str> {
+        self.get(key).map(String::as_str)
+    }
+}
+
+/// An error and the context around it, as `{err:#}` shows them, but with each SqlError in
+/// the chain in the words of `messages`.
+pub fn render(err: &anyhow::Error, messages: &dyn Messages) -> String {
+    err.chain()
+        .map(|cause| match cause.downcast_ref::<SqlError>() {
+            Some(err) => err.render(messages),
+            None => cause.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(": ")
+}
+
+/// The SqlError somewhere in an error's chain, for its code and arguments.
+pub fn find(err: &anyhow::Error) -> Option<&SqlError> {
+    err.chain()
+        .find_map(|cause| cause.downcast_ref::<SqlError>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use anyhow::Context;
+
+    #[test]
+    fn errors_keep_their_code_whatever_the_wording() {
+        let mut executor = Executor::default();
+        executor
+            .execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY);")
+            .unwrap();
+        executor
+            .execute_sql("INSERT INTO users (id) VALUES (1);")
+            .unwrap();
+        let err = executor
+            .execute_sql("INSERT INTO users (id) VALUES (1);")
+            .context("in \"INSERT\"")
+            .unwrap_err();
+        assert_eq!(find(&err).map(SqlError::code), Some(2067));
+        assert_eq!(
+            render(&err, &English),
+            "in \"INSERT\": UNIQUE constraint failed: users.id"
+        );
+
+        let french: HashMap<String, String> = [(
+            "unique_constraint".to_string(),
+            "contrainte UNIQUE non respectée : {columns}".to_string(),
+        )]
+        .into();
+        assert_eq!(
+            render(&err, &french),
+            "in \"INSERT\": contrainte UNIQUE non respectée : users.id"
+        );
+
+        let err = executor.execute_sql("SELECT * FROM nope;").unwrap_err();
+        assert_eq!(find(&err).map(SqlError::key), Some("no_such_table"));
+        // no template for it, so English
+        assert_eq!(render(&err, &french), "no such table: nope");
+    }
+}
@@ -0,0 +1,1010 @@
+/*
+    Expression evaluation.
+
+    Given an expression and a way to look up the columns of the row currently being
+    looked at, work out the expression's value. This is what decides whether a row
+    matches a WHERE clause.
+
+    SQL uses three valued logic: a comparison is TRUE, FALSE or NULL, where NULL means
+    "unknown". NULL = 1 is not false, it is NULL, because we don't know what the missing
+    value is. Only rows whose WHERE clause is definitely TRUE are returned.
+
+    Values at runtime are ColVals, the same as literals: NULL, an integer, a REAL or
+    text. Arithmetic reads its operands as numbers the way SQLite does, text for as long
+    as it looks like one, so "12abc" + 1 is 13, "abc" + 1 is 1 and "2.5" + 1 is 3.5. The
+    result is an integer when both operands are and a REAL otherwise, and an integer
+    result too big for an i64 becomes a REAL rather than wrapping around. CAST converts
+    with the same rules, and 1 = 1.0 because integers and REALs compare by value.
+
+    A value written to a column is first given the column's affinity, the kind of value
+    its declared type prefers. "30" stored in an INTEGER column is the integer 30 and 12
+    stored in a TEXT column is the text "12", but text that isn't a number is kept as it
+    is even in a number column, as SQLite does outside STRICT tables.
+
+    Comparisons give their operands affinity too. A column has its declared type's and a
+    CAST its type's, while anything else, a literal say, has none. When only one side has
+    a number affinity the other is read as a number if it is one, and when one side is
+    TEXT and the other has no affinity at all the other is read as text. So a TEXT column
+    holding "12" equals 12, and an INTEGER column holding 12 equals "12", but 12 = "12"
+    on its own is false.
+
+    Runtime errors follow SQLite too. Most bad input gives NULL or a best guess rather
+    than an error: dividing by zero is NULL, and so is anything that isn't a number at
+    all, such as infinity minus infinity.
+
+    Row values, `(a, b) = (1, 2)`, compare element by element. Equality is just the
+    conjunction of the element comparisons, while `(a, b) < (1, 2)` is a lexicographic
+    comparison like comparing words in a dictionary: the first differing element decides.
+
+    Text compares under a collation, BINARY unless the operands say otherwise with a
+    COLLATE or by being a column declared with one. Each element of a row value gets its
+    own, so in `(a, b) = (x, y)` a is compared to x under the collation of the two of them.
+*/
+use crate::collation::{Collation, Collations};
+use crate::error::SqlError;
+use crate::json;
+use crate::sql_parser::ast::{format_real, BinaryOp, ColVal, Expr, Statement, UnaryOp};
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+
+/// Everything outside the expression itself that evaluating it may need.
+pub trait EvalContext {
+    fn column(&self, name: &str) -> Result<ColVal>;
+
+    fn qualified_column(&self, table: &str, column: &str) -> Result<ColVal> {
+        bail!(SqlError::NoSuchColumn {
+            column: format!("{table}.{column}")
+        })
+    }
+
+    /// Run a subquery, returning every row it produces.
+    fn subquery(&self, select: &Statement) -> Result<Vec<Vec<ColVal>>>;
+
+    /// Whether a subquery produces any rows at all. Implementations should stop at the
+    /// first row rather than run the subquery to completion.
+    fn exists(&self, select: &Statement) -> Result<bool>;
+
+    /// Run a subquery for its value, the first column of the first row it produces or
+    /// NULL if it produces none.
+    fn scalar_subquery(&self, select: &Statement) -> Result<ColVal> {
+        let first = self.subquery(select)?.into_iter().next();
+        Ok(first
+            .and_then(|row| row.into_iter().next())
+            .unwrap_or(ColVal::Null))
+    }
+
+    /// Call a function with the values of its arguments, usually through a
+    /// FunctionRegistry.
+    fn call(&self, name: &str, _args: &[ColVal]) -> Result<ColVal> {
+        bail!("function {name}() can't be evaluated yet")
+    }
+
+    /// The collation a column was declared with, None for BINARY. `table` is given for a
+    /// qualified column.
+    fn column_collation(&self, _table: Option<&str>, _column: &str) -> Option<String> {
+        None
+    }
+
+    /// The affinity of a column's declared type, BLOB for a column without one and None
+    /// where it isn't known. `table` is given for a qualified column.
+    fn column_affinity(&self, _table: Option<&str>, _column: &str) -> Option<Affinity> {
+        None
+    }
+
+    /// Look up a collation by name, usually through a Collations registry.
+    fn collation(&self, name: &str) -> Result<Collation> {
+        Collations::default().get(name)
+    }
+}
+
+pub fn eval(expr: &Expr, ctx: &dyn EvalContext) -> Result<ColVal> {
+    match expr {
+        Expr::Literal(v) => Ok(v.clone()),
+        Expr::Column(name) => ctx.column(name),
+        Expr::QualifiedColumn { table, column } => ctx.qualified_column(table, column),
+        Expr::Placeholder(_) => bail!("statement has unbound parameters"),
+        Expr::Row(_) => bail!("row value misused"),
+        Expr::Function { name, args } => {
+            let mut values = args
+                .iter()
+                .map(|a| eval(a, ctx))
+                .collect::<Result<Vec<_>>>()?;
+            json::quote_arguments(name, args, &mut values);
+            ctx.call(name, &values)
+        }
+        Expr::Cast { expr, type_name } => cast(eval(expr, ctx)?, type_name),
+        // only comparisons care about the collation, but it must exist
+        Expr::Collate { expr, collation } => {
+            ctx.collation(collation)?;
+            eval(expr, ctx)
+        }
+        Expr::Unary { op, expr } => {
+            let v = eval(expr, ctx)?;
+            match op {
+                UnaryOp::Not => Ok(truth(&v).map_or(ColVal::Null, |b| ColVal::Boolean(!b))),
+                UnaryOp::Plus => Ok(v),
+                UnaryOp::Neg => Ok(match as_number(&v) {
+                    Some(ColVal::Int(n)) => n.checked_neg().map_or(real(-(n as f64)), ColVal::Int),
+                    Some(ColVal::Real(f)) => ColVal::Real(-f),
+                    _ => ColVal::Null,
+                }),
+            }
+        }
+        Expr::Binary { op, left, right } => match op {
+            BinaryOp::And => {
+                let l = truth(&eval(left, ctx)?);
+                if l == Some(false) {
+                    return Ok(ColVal::Boolean(false));
+                }
+                let r = truth(&eval(right, ctx)?);
+                Ok(match (l, r) {
+                    (_, Some(false)) => ColVal::Boolean(false),
+                    (Some(true), Some(true)) => ColVal::Boolean(true),
+                    _ => ColVal::Null,
+                })
+            }
+            BinaryOp::Or => {
+                let l = truth(&eval(left, ctx)?);
+                if l == Some(true) {
+                    return Ok(ColVal::Boolean(true));
+                }
+                let r = truth(&eval(right, ctx)?);
+                Ok(match (l, r) {
+                    (_, Some(true)) => ColVal::Boolean(true),
+                    (Some(false), Some(false)) => ColVal::Boolean(false),
+                    _ => ColVal::Null,
+                })
+            }
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div => {
+                Ok(arithmetic(*op, &eval(left, ctx)?, &eval(right, ctx)?))
+            }
+            BinaryOp::Arrow | BinaryOp::DoubleArrow => json::arrow(
+                &eval(left, ctx)?,
+                &eval(right, ctx)?,
+                *op == BinaryOp::DoubleArrow,
+            ),
+            BinaryOp::Eq
+            | BinaryOp::NotEq
+            | BinaryOp::Lt
+            | BinaryOp::LtEq
+            | BinaryOp::Gt
+            | BinaryOp::GtEq
+            | BinaryOp::Is
+            | BinaryOp::IsNot => {
+                let affinities = comparison_affinities(left, Some(right), ctx);
+                let l = with_affinities(eval_row(left, ctx)?, &affinities);
+                let r = with_affinities(eval_row(right, ctx)?, &affinities);
+                let collations = comparison_collations(left, Some(right), ctx)?;
+                Ok(to_val(compare_rows(*op, &l, &r, &collations)?))
+            }
+        },
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let needle = eval_row(expr, ctx)?;
+            let pairs = list
+                .iter()
+                .map(|e| {
+                    let affinities = comparison_affinities(expr, Some(e), ctx);
+                    Ok((
+                        with_affinities(needle.clone(), &affinities),
+                        with_affinities(eval_row(e, ctx)?, &affinities),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let collations = list
+                .iter()
+                .map(|e| comparison_collations(expr, Some(e), ctx))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(to_val(contains(&pairs, &collations, *negated)?))
+        }
+        Expr::InSelect {
+            expr,
+            select,
+            negated,
+        } => {
+            let affinities = comparison_affinities(expr, None, ctx);
+            let needle = with_affinities(eval_row(expr, ctx)?, &affinities);
+            let pairs: Vec<_> = ctx
+                .subquery(select)?
+                .into_iter()
+                .map(|row| (needle.clone(), with_affinities(row, &affinities)))
+                .collect();
+            let collations = vec![comparison_collations(expr, None, ctx)?; pairs.len()];
+            Ok(to_val(contains(&pairs, &collations, *negated)?))
+        }
+        // Never NULL, a subquery either produces a row or it doesn't.
+        Expr::Exists { select, negated } => Ok(ColVal::Boolean(ctx.exists(select)? != *negated)),
+        Expr::Subquery(select) => ctx.scalar_subquery(select),
+    }
+}
+
+/// Evaluate a WHERE clause style predicate, only a definite TRUE counts.
+pub fn is_true(expr: &Expr, ctx: &dyn EvalContext) -> Result<bool> {
+    Ok(truth(&eval(expr, ctx)?) == Some(true))
+}
+
+// Evaluate an operand of a comparison, a scalar is treated as a row with one element.
+fn eval_row(expr: &Expr, ctx: &dyn EvalContext) -> Result<Vec<ColVal>> {
+    match expr {
+        Expr::Row(exprs) => exprs.iter().map(|e| eval(e, ctx)).collect(),
+        scalar => Ok(vec![eval(scalar, ctx)?]),
+    }
+}
+
+fn to_val(b: Option<bool>) -> ColVal {
+    b.map_or(ColVal::Null, ColVal::Boolean)
+}
+
+/// A value read as a truth value, None for SQL's unknown.
+pub fn truth(v: &ColVal) -> Option<bool> {
+    as_real(v).map(|f| f != 0.0)
+}
+
+/// A value as SQLite reads it when it needs an integer, None for NULL. A REAL loses its
+/// fraction.
+pub fn as_integer(v: &ColVal) -> Option<i64> {
+    match v {
+        ColVal::Null => None,
+        ColVal::Boolean(b) => Some(*b as i64),
+        ColVal::Int(n) => Some(*n),
+        // saturates at the ends of i64's range
+        ColVal::Real(f) => Some(*f as i64),
+        ColVal::String(s) => Some(text_to_integer(s)),
+        // a blob's bytes are read as text, as SQLite reads them
+        ColVal::Blob(b) => Some(text_to_integer(&String::from_utf8_lossy(b))),
+    }
+}
+
+/// A value as SQLite reads it when it needs a REAL, None for NULL.
+pub fn as_real(v: &ColVal) -> Option<f64> {
+    match as_number(v)? {
+        ColVal::Int(n) => Some(n as f64),
+        ColVal::Real(f) => Some(f),
+        other => unreachable!("{other:?} is not a number"),
+    }
+}
+
+/// A value as the number arithmetic reads it, an Int or a Real, None for NULL.
+pub fn as_number(v: &ColVal) -> Option<ColVal> {
+    match v {
+        ColVal::Null => None,
+        ColVal::Boolean(b) => Some(ColVal::Int(*b as i64)),
+        ColVal::Int(_) | ColVal::Real(_) => Some(v.clone()),
+        ColVal::String(s) => Some(text_to_number(s)),
+        ColVal::Blob(b) => Some(text_to_number(&String::from_utf8_lossy(b))),
+    }
+}
+
+// A REAL result, where NaN, which SQL has no use for, is NULL.
+fn real(f: f64) -> ColVal {
+    if f.is_nan() {
+        ColVal::Null
+    } else {
+        ColVal::Real(f)
+    }
+}
+
+// Read text as an integer the way SQLite does: skip leading whitespace, then take the
+// longest prefix that looks like an integer. Text that doesn't start with one is 0 and a
+// number too big for an i64 is clamped to the largest one.
+fn text_to_integer(s: &str) -> i64 {
+    let s = s.trim_start();
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    digits
+        .bytes()
+        .take_while(u8::is_ascii_digit)
+        .fold(0i64, |n, d| {
+            let d = (d - b'0') as i64;
+            if negative {
+                n.saturating_mul(10).saturating_sub(d)
+            } else {
+                n.saturating_mul(10).saturating_add(d)
+            }
+        })
+}
+
+// Read text as a number the way SQLite's arithmetic does: the longest prefix that looks
+// like one, an integer unless it has a decimal point or an exponent, or is too big for an
+// i64. Text that doesn't start with a number is the integer 0.
+fn text_to_number(s: &str) -> ColVal {
+    let s = s.trim_start();
+    let bytes = s.as_bytes();
+    let digits_from = |i: usize| {
+        i + bytes[i.min(bytes.len())..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+
+    let sign = matches!(bytes.first(), Some(b'+' | b'-')) as usize;
+    let mut end = digits_from(sign);
+    let mut has_digits = end > sign;
+    let mut integer = true;
+    if bytes.get(end) == Some(&b'.') {
+        let fraction_end = digits_from(end + 1);
+        has_digits |= fraction_end > end + 1;
+        integer = false;
+        end = fraction_end;
+    }
+    if !has_digits {
+        return ColVal::Int(0);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let exponent = end + 1 + matches!(bytes.get(end + 1), Some(b'+' | b'-')) as usize;
+        let exponent_end = digits_from(exponent);
+        if exponent_end > exponent {
+            integer = false;
+            end = exponent_end;
+        }
+    }
+
+    let number = &s[..end];
+    match number.parse::<i64>() {
+        Ok(n) if integer => ColVal::Int(n),
+        _ => ColVal::Real(number.parse().expect("looks like a number")),
+    }
+}
+
+/// A value other than NULL as text, as CAST(v AS TEXT) gives it.
+pub fn as_text(v: ColVal) -> String {
+    match v {
+        ColVal::String(s) => s,
+        ColVal::Real(f) => format_real(f),
+        ColVal::Blob(b) => String::from_utf8_lossy(&b).into_owned(),
+        other => as_integer(&other).expect("not NULL").to_string(),
+    }
+}
+
+/// The kind of value a column prefers, picked from its declared type by SQLite's rules,
+/// so BIGINT is INTEGER, VARCHAR(255) is TEXT and a column with no type is BLOB.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Affinity {
+    pub fn of(type_name: Option<&str>) -> Affinity {
+        let name = type_name.unwrap_or_default().to_ascii_uppercase();
+        let has = |s: &str| name.contains(s);
+        if has("INT") {
+            Affinity::Integer
+        } else if has("CHAR") || has("CLOB") || has("TEXT") {
+            Affinity::Text
+        } else if has("BLOB") || name.is_empty() {
+            Affinity::Blob
+        } else if has("REAL") || has("FLOA") || has("DOUB") {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    /// A value as a column of this affinity stores it. Unlike a CAST nothing is lost: a
+    /// number column turns text into a number only if the whole text is one, and keeps
+    /// the text otherwise.
+    pub fn apply(self, v: ColVal) -> ColVal {
+        match (self, v) {
+            (_, ColVal::Null) => ColVal::Null,
+            // a blob is stored as it is, whatever the column
+            (Affinity::Blob, v) | (_, v @ ColVal::Blob(_)) => v,
+            (Affinity::Text, ColVal::String(s)) => ColVal::String(s),
+            (Affinity::Text, v) => ColVal::String(as_text(v)),
+            (Affinity::Real, v) => match numeric(v) {
+                ColVal::Int(n) => ColVal::Real(n as f64),
+                v => v,
+            },
+            (Affinity::Integer | Affinity::Numeric, v) => match numeric(v) {
+                ColVal::Real(f) => integral(f),
+                v => v,
+            },
+        }
+    }
+}
+
+// Text that is entirely a number, give or take surrounding spaces, as that number, and
+// any other value as it is.
+fn numeric(v: ColVal) -> ColVal {
+    let ColVal::String(s) = &v else {
+        return v;
+    };
+    let trimmed = s.trim();
+    let looks_numeric = !trimmed.is_empty()
+        && trimmed
+            .bytes()
+            .all(|b| b.is_ascii_digit() || matches!(b, b'+' | b'-' | b'.' | b'e' | b'E'));
+    if !looks_numeric {
+        return v;
+    }
+    if let Ok(n) = trimmed.parse::<i64>() {
+        return ColVal::Int(n);
+    }
+    match trimmed.parse::<f64>() {
+        Ok(f) if f.is_finite() => ColVal::Real(f),
+        _ => v,
+    }
+}
+
+// A REAL as an integer if it has no fraction and fits in one.
+fn integral(f: f64) -> ColVal {
+    if f.fract() == 0.0 && f.abs() < 9.2e18 {
+        ColVal::Int(f as i64)
+    } else {
+        ColVal::Real(f)
+    }
+}
+
+// CAST(v AS type_name), converting to the type's affinity whatever is lost on the way.
+fn cast(v: ColVal, type_name: &str) -> Result<ColVal> {
+    if v == ColVal::Null {
+        return Ok(ColVal::Null);
+    }
+    match Affinity::of(Some(type_name)) {
+        Affinity::Integer => Ok(ColVal::Int(as_integer(&v).expect("not NULL"))),
+        Affinity::Text => Ok(ColVal::String(as_text(v))),
+        // the bytes of the value as text
+        Affinity::Blob => Ok(match v {
+            ColVal::Blob(b) => ColVal::Blob(b),
+            v => ColVal::Blob(as_text(v).into_bytes()),
+        }),
+        Affinity::Real => Ok(ColVal::Real(as_real(&v).expect("not NULL"))),
+        // NUMERIC, which keeps a REAL only if it has a fraction
+        Affinity::Numeric => Ok(match as_number(&v).expect("not NULL") {
+            ColVal::Real(f) => integral(f),
+            number => number,
+        }),
+    }
+}
+
+// Integer arithmetic while both operands are integers and the result fits, REAL
+// arithmetic otherwise.
+fn arithmetic(op: BinaryOp, left: &ColVal, right: &ColVal) -> ColVal {
+    let (Some(l), Some(r)) = (as_number(left), as_number(right)) else {
+        return ColVal::Null;
+    };
+    if let (ColVal::Int(l), ColVal::Int(r)) = (&l, &r) {
+        let result = match op {
+            BinaryOp::Add => l.checked_add(*r),
+            BinaryOp::Sub => l.checked_sub(*r),
+            BinaryOp::Mul => l.checked_mul(*r),
+            BinaryOp::Div if *r == 0 => return ColVal::Null,
+            BinaryOp::Div => l.checked_div(*r),
+            other => unreachable!("{other:?} is not arithmetic"),
+        };
+        if let Some(n) = result {
+            return ColVal::Int(n);
+        }
+    }
+    let (l, r) = (
+        as_real(&l).expect("a number"),
+        as_real(&r).expect("a number"),
+    );
+    real(match op {
+        BinaryOp::Add => l + r,
+        BinaryOp::Sub => l - r,
+        BinaryOp::Mul => l * r,
+        BinaryOp::Div if r == 0.0 => return ColVal::Null,
+        BinaryOp::Div => l / r,
+        other => unreachable!("{other:?} is not arithmetic"),
+    })
+}
+
+/// The collation an ORDER BY term sorts by, that of the expression if it is a COLLATE or a
+/// column and otherwise BINARY.
+pub fn ordering_collation(expr: &Expr, ctx: &dyn EvalContext) -> Result<Collation> {
+    match explicit_collation(expr).or_else(|| declared_collation(expr, ctx)) {
+        Some(name) => ctx.collation(&name),
+        None => Ok(Collation::binary()),
+    }
+}
+
+// The elements of a comparison's operands that are compared with each other. The right
+// side of `x IN (SELECT ...)` has no expression to look at.
+fn element_pairs<'e>(left: &'e Expr, right: Option<&'e Expr>) -> Vec<(&'e Expr, Option<&'e Expr>)> {
+    match (left, right) {
+        (Expr::Row(l), Some(Expr::Row(r))) => l.iter().zip(r.iter().map(Some)).collect(),
+        (Expr::Row(l), _) => l.iter().map(|e| (e, None)).collect(),
+        (l, r) => vec![(l, r)],
+    }
+}
+
+// The collations to compare each element of the left operand under. An explicit COLLATE
+// on either side wins over a column's declared collation, and the left side over the
+// right.
+fn comparison_collations(
+    left: &Expr,
+    right: Option<&Expr>,
+    ctx: &dyn EvalContext,
+) -> Result<Vec<Collation>> {
+    element_pairs(left, right)
+        .into_iter()
+        .map(|(l, r)| {
+            let name = explicit_collation(l)
+                .or_else(|| r.and_then(explicit_collation))
+                .or_else(|| declared_collation(l, ctx))
+                .or_else(|| r.and_then(|r| declared_collation(r, ctx)));
+            match name {
+                Some(name) => ctx.collation(&name),
+                None => Ok(Collation::binary()),
+            }
+        })
+        .collect()
+}
+
+// The affinity to give each pair of elements before they are compared, if any.
+fn comparison_affinities(
+    left: &Expr,
+    right: Option<&Expr>,
+    ctx: &dyn EvalContext,
+) -> Vec<Option<Affinity>> {
+    element_pairs(left, right)
+        .into_iter()
+        .map(|(l, r)| {
+            comparison_affinity(
+                operand_affinity(l, ctx),
+                r.and_then(|r| operand_affinity(r, ctx)),
+            )
+        })
+        .collect()
+}
+
+/// The affinity the two sides of a comparison are given, by SQLite's rules, when they
+/// have the affinities `left` and `right`, None for none. A number affinity on one side
+/// only makes both NUMERIC, and TEXT on one side with nothing on the other makes both
+/// TEXT. Giving a value the affinity it already has changes nothing.
+pub fn comparison_affinity(left: Option<Affinity>, right: Option<Affinity>) -> Option<Affinity> {
+    let numeric = |a: Option<Affinity>| {
+        matches!(
+            a,
+            Some(Affinity::Integer | Affinity::Real | Affinity::Numeric)
+        )
+    };
+    match (left, right) {
+        _ if numeric(left) != numeric(right) => Some(Affinity::Numeric),
+        (Some(Affinity::Text), None) | (None, Some(Affinity::Text)) => Some(Affinity::Text),
+        _ => None,
+    }
+}
+
+// A column's affinity, a CAST's, or none.
+fn operand_affinity(expr: &Expr, ctx: &dyn EvalContext) -> Option<Affinity> {
+    match expr {
+        Expr::Column(column) => ctx.column_affinity(None, column),
+        Expr::QualifiedColumn { table, column } => ctx.column_affinity(Some(table), column),
+        Expr::Cast { type_name, .. } => Some(Affinity::of(Some(type_name))),
+        Expr::Collate { expr, .. } => operand_affinity(expr, ctx),
+        _ => None,
+    }
+}
+
+fn with_affinities(values: Vec<ColVal>, affinities: &[Option<Affinity>]) -> Vec<ColVal> {
+    values
+        .into_iter()
+        .zip(affinities.iter().chain(std::iter::repeat(&None)))
+        .map(|(v, affinity)| match affinity {
+            Some(affinity) => affinity.apply(v),
+            None => v,
+        })
+        .collect()
+}
+
+fn explicit_collation(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Collate { collation, .. } => Some(collation.clone()),
+        _ => None,
+    }
+}
+
+fn declared_collation(expr: &Expr, ctx: &dyn EvalContext) -> Option<String> {
+    match expr {
+        Expr::Column(column) => ctx.column_collation(None, column),
+        Expr::QualifiedColumn { table, column } => ctx.column_collation(Some(table), column),
+        _ => None,
+    }
+}
+
+fn compare_rows(
+    op: BinaryOp,
+    left: &[ColVal],
+    right: &[ColVal],
+    collations: &[Collation],
+) -> Result<Option<bool>> {
+    if left.len() != right.len() {
+        bail!("row value misused");
+    }
+    let pairs = left.iter().zip(right).zip(collations);
+
+    match op {
+        // NULL is NULL, and not any other value
+        BinaryOp::Is | BinaryOp::IsNot => {
+            let same = pairs.into_iter().all(|((l, r), collation)| match (l, r) {
+                (ColVal::Null, ColVal::Null) => true,
+                (ColVal::Null, _) | (_, ColVal::Null) => false,
+                _ => collation.compare(l, r) == Ordering::Equal,
+            });
+            Ok(Some(same == (op == BinaryOp::Is)))
+        }
+        BinaryOp::Eq | BinaryOp::NotEq => {
+            // Any pair known to differ makes the rows unequal, even alongside NULLs.
+            let mut unknown = false;
+            for ((l, r), collation) in pairs {
+                if *l == ColVal::Null || *r == ColVal::Null {
+                    unknown = true;
+                } else if collation.compare(l, r) != Ordering::Equal {
+                    return Ok(Some(op == BinaryOp::NotEq));
+                }
+            }
+            Ok(if unknown {
+                None
+            } else {
+                Some(op == BinaryOp::Eq)
+            })
+        }
+        _ => {
+            let mut ordering = Ordering::Equal;
+            for ((l, r), collation) in pairs {
+                if *l == ColVal::Null || *r == ColVal::Null {
+                    return Ok(None);
+                }
+                ordering = collation.compare(l, r);
+                if ordering != Ordering::Equal {
+                    break;
+                }
+            }
+            Ok(Some(match op {
+                BinaryOp::Lt => ordering == Ordering::Less,
+                BinaryOp::LtEq => ordering != Ordering::Greater,
+                BinaryOp::Gt => ordering == Ordering::Greater,
+                BinaryOp::GtEq => ordering != Ordering::Less,
+                other => unreachable!("{other:?} is not a comparison"),
+            }))
+        }
+    }
+}
+
+// x IN (...) is TRUE if x equals some element. Otherwise it is NULL if any of the
+// comparisons were unknown, and FALSE only if x definitely equals none of them.
+// Each candidate is compared under its own collations, and comes paired with x as given
+// the affinities of the comparison with it.
+fn contains(
+    pairs: &[(Vec<ColVal>, Vec<ColVal>)],
+    collations: &[Vec<Collation>],
+    negated: bool,
+) -> Result<Option<bool>> {
+    let mut unknown = false;
+    for ((needle, candidate), collations) in pairs.iter().zip(collations) {
+        match compare_rows(BinaryOp::Eq, needle, candidate, collations)? {
+            Some(true) => return Ok(Some(!negated)),
+            Some(false) => {}
+            None => unknown = true,
+        }
+    }
+    Ok(if unknown { None } else { Some(negated) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::parse_expr;
+    use std::collections::HashMap;
+
+    struct TestRow {
+        columns: HashMap<&'static str, ColVal>,
+        collations: HashMap<&'static str, &'static str>,
+        subquery_rows: Vec<Vec<ColVal>>,
+    }
+
+    impl EvalContext for TestRow {
+        fn column(&self, name: &str) -> Result<ColVal> {
+            match self.columns.get(name) {
+                Some(v) => Ok(v.clone()),
+                None => bail!(SqlError::NoSuchColumn {
+                    column: name.to_string()
+                }),
+            }
+        }
+
+        fn subquery(&self, _select: &Statement) -> Result<Vec<Vec<ColVal>>> {
+            Ok(self.subquery_rows.clone())
+        }
+
+        fn exists(&self, _select: &Statement) -> Result<bool> {
+            Ok(!self.subquery_rows.is_empty())
+        }
+
+        fn column_collation(&self, _table: Option<&str>, column: &str) -> Option<String> {
+            self.collations.get(column).map(|c| c.to_string())
+        }
+    }
+
+    fn row() -> TestRow {
+        TestRow {
+            columns: HashMap::from([
+                ("a", ColVal::Int(1)),
+                ("b", ColVal::Int(2)),
+                ("n", ColVal::Null),
+                ("s", ColVal::String("Bob".to_string())),
+            ]),
+            collations: HashMap::from([("s", "NOCASE")]),
+            subquery_rows: vec![
+                vec![ColVal::Int(0), ColVal::Int(0)],
+                vec![ColVal::Int(1), ColVal::Int(2)],
+            ],
+        }
+    }
+
+    fn eval_str(src: &str) -> ColVal {
+        eval(&parse_expr(src).unwrap(), &row()).unwrap()
+    }
+
+    #[test]
+    fn row_value_equality() {
+        assert_eq!(eval_str("(a, b) = (1, 2)"), ColVal::Boolean(true));
+        assert_eq!(eval_str("(a, b) = (1, 3)"), ColVal::Boolean(false));
+        assert_eq!(eval_str("(a, b) != (1, 3)"), ColVal::Boolean(true));
+        // unknown unless some element definitely differs
+        assert_eq!(eval_str("(a, n) = (1, 2)"), ColVal::Null);
+        assert_eq!(eval_str("(a, n) = (5, 2)"), ColVal::Boolean(false));
+    }
+
+    #[test]
+    fn is_takes_null_as_equal_to_null() {
+        assert_eq!(eval_str("n IS NULL"), ColVal::Boolean(true));
+        assert_eq!(eval_str("a IS NULL"), ColVal::Boolean(false));
+        assert_eq!(eval_str("a IS NOT NULL"), ColVal::Boolean(true));
+        assert_eq!(eval_str("a IS 1"), ColVal::Boolean(true));
+        assert_eq!(eval_str(r#"s IS "BOB""#), ColVal::Boolean(true));
+        assert_eq!(eval_str("NOT n IS NOT NULL"), ColVal::Boolean(true));
+    }
+
+    #[test]
+    fn row_value_ordering_is_lexicographic() {
+        assert_eq!(eval_str("(a, b) < (1, 3)"), ColVal::Boolean(true));
+        assert_eq!(eval_str("(a, b) < (2, 0)"), ColVal::Boolean(true));
+        assert_eq!(eval_str("(a, b) >= (1, 2)"), ColVal::Boolean(true));
+        assert_eq!(eval_str("(a, b) > (1, 2)"), ColVal::Boolean(false));
+        // decided by the first element before the NULL is reached
+        assert_eq!(eval_str("(a, n) < (2, 0)"), ColVal::Boolean(true));
+        assert_eq!(eval_str("(a, n) < (1, 0)"), ColVal::Null);
+    }
+
+    #[test]
+    fn comparisons_use_collations() {
+        // s is declared COLLATE NOCASE
+        assert_eq!(eval_str(r#"s = "BOB""#), ColVal::Boolean(true));
+        assert_eq!(eval_str(r#""BOB" = s"#), ColVal::Boolean(true));
+        assert_eq!(eval_str(r#"s < "alice""#), ColVal::Boolean(false));
+        assert_eq!(eval_str(r#"s IN ("ann", "bOb")"#), ColVal::Boolean(true));
+        assert_eq!(eval_str(r#"(a, s) = (1, "BOB")"#), ColVal::Boolean(true));
+
+        // an explicit COLLATE on either side wins over the column's
+        assert_eq!(
+            eval_str(r#"s COLLATE BINARY = "BOB""#),
+            ColVal::Boolean(false)
+        );
+        assert_eq!(
+            eval_str(r#"s = "BOB" COLLATE BINARY"#),
+            ColVal::Boolean(false)
+        );
+        assert_eq!(
+            eval_str(r#""Bob  " = "Bob" COLLATE RTRIM"#),
+            ColVal::Boolean(true)
+        );
+        assert_eq!(eval_str(r#""Bob" = "BOB""#), ColVal::Boolean(false));
+
+        let e = parse_expr(r#"s COLLATE klingon = "x""#).unwrap();
+        assert_eq!(
+            eval(&e, &row()).unwrap_err().to_string(),
+            "no such collation sequence: klingon"
+        );
+
+        let term = parse_expr("s COLLATE RTRIM").unwrap();
+        assert_eq!(ordering_collation(&term, &row()).unwrap().name, "RTRIM");
+        let column = parse_expr("s").unwrap();
+        assert_eq!(ordering_collation(&column, &row()).unwrap().name, "NOCASE");
+    }
+
+    #[test]
+    fn row_values_in_lists_and_subqueries() {
+        assert_eq!(
+            eval_str("(a, b) IN ((0, 0), (1, 2))"),
+            ColVal::Boolean(true)
+        );
+        assert_eq!(eval_str("(a, b) NOT IN ((0, 0))"), ColVal::Boolean(true));
+        assert_eq!(
+            eval_str("(a, b) IN (SELECT x, y FROM t)"),
+            ColVal::Boolean(true)
+        );
+        assert_eq!(
+            eval_str("(b, a) IN (SELECT x, y FROM t WHERE x > 0)"),
+            ColVal::Boolean(false)
+        );
+        assert_eq!(eval_str("a IN (3, n)"), ColVal::Null);
+        assert_eq!(eval_str("a IN ()"), ColVal::Boolean(false));
+    }
+
+    #[test]
+    fn exists_is_true_or_false_never_null() {
+        assert_eq!(
+            eval_str("EXISTS (SELECT 1 FROM t WHERE x = n)"),
+            ColVal::Boolean(true)
+        );
+        assert_eq!(
+            eval_str("NOT EXISTS (SELECT 1 FROM t)"),
+            ColVal::Boolean(false)
+        );
+
+        let no_rows = TestRow {
+            subquery_rows: vec![],
+            ..row()
+        };
+        let e = parse_expr("NOT EXISTS (SELECT 1 FROM t) AND a = 1").unwrap();
+        assert_eq!(eval(&e, &no_rows).unwrap(), ColVal::Boolean(true));
+    }
+
+    #[test]
+    fn mismatched_row_sizes_are_an_error() {
+        let e = parse_expr("(a, b) = (1, 2, 3)").unwrap();
+        assert_eq!(
+            eval(&e, &row()).unwrap_err().to_string(),
+            "row value misused"
+        );
+        let e = parse_expr("(a, b) + 1").unwrap();
+        assert!(eval(&e, &row()).is_err());
+    }
+
+    // Expected values are what SQLite 3.45 gives.
+    #[test]
+    fn text_is_read_as_a_number_like_sqlite() {
+        assert_eq!(eval_str(r#""12abc" + 1"#), ColVal::Int(13));
+        assert_eq!(eval_str(r#"" -7 " * 2"#), ColVal::Int(-14));
+        assert_eq!(eval_str(r#""abc" + 1"#), ColVal::Int(1));
+        assert_eq!(eval_str(r#""0x10" + 0"#), ColVal::Int(0));
+        assert_eq!(eval_str(r#"-"5""#), ColVal::Int(-5));
+        assert_eq!(eval_str(r#"6 / "0""#), ColVal::Null);
+        assert_eq!(eval_str(r#""1" AND "abc""#), ColVal::Boolean(false));
+    }
+
+    #[test]
+    fn cast_follows_type_affinity() {
+        assert_eq!(eval_str(r#"CAST("12abc" AS INTEGER)"#), ColVal::Int(12));
+        assert_eq!(eval_str(r#"CAST("3.9" AS BIGINT)"#), ColVal::Int(3));
+        assert_eq!(
+            eval_str(r#"CAST("99999999999999999999" AS INT)"#),
+            ColVal::Int(i64::MAX)
+        );
+        assert_eq!(eval_str(r#"CAST("abc" AS NUMERIC)"#), ColVal::Int(0));
+        assert_eq!(
+            eval_str("CAST(a + b AS VARCHAR(10))"),
+            ColVal::String("3".to_string())
+        );
+        assert_eq!(
+            eval_str("CAST(TRUE AS TEXT)"),
+            ColVal::String("1".to_string())
+        );
+        assert_eq!(eval_str("CAST(n AS INTEGER)"), ColVal::Null);
+        assert_eq!(eval_str("CAST(-3.9 AS INTEGER)"), ColVal::Int(-3));
+        assert_eq!(eval_str(r#"CAST("3.9" AS NUMERIC)"#), ColVal::Real(3.9));
+        assert_eq!(eval_str("CAST(4.0 AS NUMERIC)").to_string(), "4");
+        assert_eq!(eval_str(r#"CAST(" 12e1x" AS DOUBLE)"#), ColVal::Real(120.0));
+        assert_eq!(eval_str("CAST(a AS REAL)").to_string(), "1.0");
+        assert_eq!(
+            eval_str("CAST(1.5 AS TEXT)"),
+            ColVal::String("1.5".to_string())
+        );
+    }
+
+    #[test]
+    fn comparisons_give_their_operands_affinity() {
+        // a literal has no affinity, so nothing is converted
+        assert_eq!(eval_str(r#"12 = "12""#), ColVal::Boolean(false));
+        assert_eq!(eval_str(r#"CAST(a AS TEXT) = 1"#), ColVal::Boolean(true));
+        assert_eq!(eval_str(r#"CAST(a AS INT) = " 1""#), ColVal::Boolean(true));
+        assert_eq!(eval_str(r#"CAST(a AS INT) < "1x""#), ColVal::Boolean(true));
+        assert_eq!(
+            eval_str("CAST(a AS TEXT) = CAST(1 AS REAL)"),
+            ColVal::Boolean(true)
+        );
+        assert_eq!(
+            eval_str("(CAST(a AS TEXT), b) = (1, 2)"),
+            ColVal::Boolean(true)
+        );
+        assert_eq!(
+            eval_str(r#""1" IN (CAST(a AS INT), 5)"#),
+            ColVal::Boolean(true)
+        );
+        assert_eq!(eval_str("CAST(b AS TEXT) IN (1, 2)"), ColVal::Boolean(true));
+    }
+
+    #[test]
+    fn columns_keep_values_in_their_affinity() {
+        let text = |s: &str| ColVal::String(s.to_string());
+        let stored = |type_name: &str, v: ColVal| Affinity::of(Some(type_name)).apply(v);
+        assert_eq!(Affinity::of(None), Affinity::Blob);
+        assert_eq!(Affinity::of(Some("DECIMAL(10, 2)")), Affinity::Numeric);
+
+        assert_eq!(stored("INTEGER", text(" 30 ")), ColVal::Int(30));
+        assert_eq!(stored("BIGINT", ColVal::Real(5.0)), ColVal::Int(5));
+        assert_eq!(stored("INT", text("2.5")), ColVal::Real(2.5));
+        // text that isn't entirely a number stays text, where a CAST would take the 12
+        assert_eq!(stored("INTEGER", text("12abc")), text("12abc"));
+        assert_eq!(stored("NUMERIC", text("3.0")), ColVal::Int(3));
+        assert_eq!(stored("NUMERIC", text("1e2")), ColVal::Int(100));
+        assert_eq!(stored("REAL", ColVal::Int(5)), ColVal::Real(5.0));
+        assert_eq!(stored("DOUBLE", text("-7")), ColVal::Real(-7.0));
+        assert_eq!(stored("TEXT", ColVal::Int(12)), text("12"));
+        assert_eq!(stored("VARCHAR(5)", ColVal::Real(1.5)), text("1.5"));
+        assert_eq!(stored("BLOB", text("7")), text("7"));
+        assert_eq!(stored("TEXT", ColVal::Null), ColVal::Null);
+        assert_eq!(stored("INTEGER", text("inf")), text("inf"));
+    }
+
+    #[test]
+    fn integer_overflow_becomes_real() {
+        assert_eq!(
+            eval_str("9223372036854775807 + 1"),
+            ColVal::Real(9223372036854775808.0)
+        );
+        assert_eq!(
+            eval_str("-9223372036854775807 - 2"),
+            ColVal::Real(-9223372036854775809.0)
+        );
+        assert_eq!(
+            eval_str("4611686018427387904 * 2"),
+            ColVal::Real(9223372036854775808.0)
+        );
+        assert_eq!(
+            eval_str("-(-9223372036854775807 - 1)").to_string(),
+            "9.22337203685478e+18"
+        );
+    }
+
+    #[test]
+    fn integers_and_reals_mix_like_sqlite() {
+        assert_eq!(eval_str("1 + 0.5"), ColVal::Real(1.5));
+        assert_eq!(eval_str("7 / 2"), ColVal::Int(3));
+        assert_eq!(eval_str("7 / 2.0"), ColVal::Real(3.5));
+        assert_eq!(eval_str(r#""2.5" + 1"#), ColVal::Real(3.5));
+        assert_eq!(eval_str(r#""1e3abc" * 1"#), ColVal::Real(1000.0));
+        assert_eq!(eval_str(r#"" .5" + 0"#), ColVal::Real(0.5));
+        assert_eq!(eval_str(r#"".e5" + 0"#).to_string(), "0");
+        assert_eq!(eval_str("1.5 / 0"), ColVal::Null);
+        assert_eq!(eval_str("1e308 * 10 - 1e308 * 10"), ColVal::Null);
+        assert_eq!(eval_str("-2.5"), ColVal::Real(-2.5));
+
+        // compared by value, so 1 and 1.0 are the same number
+        assert_eq!(eval_str("a = 1.0"), ColVal::Boolean(true));
+        assert_eq!(eval_str("b > 1.5"), ColVal::Boolean(true));
+        assert_eq!(
+            eval_str("9007199254740993 > 9007199254740992.0"),
+            ColVal::Boolean(true)
+        );
+        assert_eq!(eval_str("0.5 AND 1"), ColVal::Boolean(true));
+        assert_eq!(eval_str(r#"NOT "0.0""#), ColVal::Boolean(true));
+
+        assert_eq!(eval_str("0.1 + 0.2").to_string(), "0.3");
+        assert_eq!(eval_str("1e20").to_string(), "1.0e+20");
+        assert_eq!(eval_str("2.0 * 3").to_string(), "6.0");
+        assert_eq!(eval_str("1.0 / 3").to_string(), "0.333333333333333");
+        assert_eq!(eval_str("0.00001").to_string(), "1.0e-05");
+    }
+
+    #[test]
+    fn three_valued_logic() {
+        assert_eq!(eval_str("n = 1 AND a = 2"), ColVal::Boolean(false));
+        assert_eq!(eval_str("n = 1 AND a = 1"), ColVal::Null);
+        assert_eq!(eval_str("n = 1 OR a = 1"), ColVal::Boolean(true));
+        assert_eq!(eval_str("NOT n = 1"), ColVal::Null);
+        assert_eq!(eval_str("a + b * 2 = 5"), ColVal::Boolean(true));
+        assert_eq!(eval_str("a / 0"), ColVal::Null);
+    }
+}
@@ -0,0 +1,5381 @@
+/*
+    The executor, which runs statements against the tables and hands back their results.
+
+    A statement goes through three stages on its way from SQL text to a result:
+
+        parse     the text becomes a Statement, see sql_parser
+        plan      the planner resolves the names it uses against the schema and decides
+                  how its rows are to be found, see planner
+        execute   the plan is run here, reading and writing rows in the tables' B+trees
+
+    The first two stages needn't be repeated for SQL that was run recently, as its parsed
+    statement, plan and program are kept in a cache, see prepared.rs.
+
+    A query the virtual machine can run is compiled to bytecode and run by it, see vdbe.rs,
+    and query_prepared hands its rows out as the machine makes them, one at a time.
+    Any other query's plan is run here as a tree of operators, from the leaves up. A Scan
+    reads every row of its table, an IndexSearch only those an index points it to, and
+    each operator above takes the rows its inputs produced and filters, joins, sorts or
+    projects them in turn. For now every operator produces all of its rows before the
+    next one starts, which is simple but holds a query's rows in memory. An EXISTS whose
+    query the machine can run stops at its first row, one that it can't runs to the end.
+
+    A virtual table has no rows stored here at all, a VirtualScan has the table's cursor
+    make them as it reads, see vtab.rs.
+
+    A rowid table's rows are stored by rowid, see storage::table, and a WITHOUT ROWID
+    table's by primary key, see storage::clustered. Either way a write to a row is mirrored
+    in every index on its table, so that an IndexSearch finds exactly the rows a Scan
+    would. A write that an index refuses, such as a second row with the same value in a
+    UNIQUE index, is refused as a whole and leaves the table and its other indexes as
+    they were. So is a statement that writes many rows: if one of them fails, those it
+    wrote before are undone, as SQLite's default ABORT undoes them.
+
+    An INTEGER PRIMARY KEY AUTOINCREMENT table has a row in sqlite_sequence with the
+    largest rowid it has had, made along with the first such table and written as part of
+    each insert, so journaled and saved like any other row. Each insert first skips the
+    table past it, which after a reopen or a change to sqlite_sequence can be past the
+    rowids its rows show. last_insert_rowid() is the rowid of the last row INSERT or
+    append_batch stored in a rowid table, and like SQLite's is left as it was by a
+    statement that failed or was rolled back.
+
+    changes() is how many rows the last INSERT, UPDATE or DELETE to finish changed, not
+    counting those its triggers changed, and total_changes() how many every one of them
+    has changed since the database was opened, triggers' included. A statement that fails
+    counts for neither.
+
+    Writes fire the triggers the planner attached to them, one row at a time: the BEFORE
+    triggers, then the write to that row, then the AFTER triggers. A trigger's statements
+    are run just like the user's own.
+
+    Between BEGIN and COMMIT every row written is journaled, see transaction.rs, so that
+    ROLLBACK can put the rows back as they were. So is each CREATE, and rolling one back
+    forgets what it made, its rows in sqlite_master and its B+tree along with it.
+
+    With `PRAGMA foreign_keys = ON` each row written is checked against the foreign keys
+    it is a parent or a child in, see foreign_key.rs. A parent row's ON DELETE and
+    ON UPDATE actions run just after the write to it, as statements on the child table
+    like a trigger's, and the checks that no child is left without its parent are run
+    once the user's statement is done, before it is saved, so that it fails and is undone
+    as a whole if one isn't met. Off, as in SQLite until it is turned on, foreign keys
+    are only parsed and stored.
+
+    A connection opened on a database file keeps an image of its tables in the file, see
+    storage/image.rs. The tables are loaded from it when it is opened, and each statement
+    that changes them, or each transaction once it commits, saves them to it again. A
+    save that fails fails its statement, though what the statement did stays done in
+    memory. A file SQLite itself wrote is read instead, see storage/sqlite_file.rs, and
+    the connection is then read only: a query runs as it would on any other database,
+    but anything that would change it is refused. Opened with format=sqlite, a file is
+    instead kept in SQLite's format, created in it if it is new, and each save writes it
+    out again as SQLite would have.
+
+    Each save moves the change counter in the file's header on, and one that changes the
+    schema the schema cookie too. Before each statement outside a transaction the
+    connection compares the counter with the one it last loaded or saved, and if another
+    connection has committed since, main is loaded from the file again, tables and all,
+    and the prepared statements on its tables are planned again before they next run, see
+    prepared.rs. Two connections to one file so see each other's commits, though not
+    each other's transactions. A save refuses to write over a commit made since main was
+    last loaded, one a transaction begun DEFERRED may have missed, and main is loaded
+    again, the transaction's writes lost, so neither writes its rows back over the
+    other's. BEGIN IMMEDIATE takes the file's RESERVED lock, loading main again if it has
+    to, so that no other connection commits until the transaction ends, and BEGIN
+    EXCLUSIVE the EXCLUSIVE lock, so that none reads either.
+    Reading the counter takes the file's lock, waiting as the busy handler says as any
+    other lock does, so a PRAGMA of the connection's own settings, which needs nothing
+    from the file, doesn't read it: busy_timeout can be set while another connection
+    writes.
+
+    Between begin_group_commit and end_group_commit the saves are grouped, see
+    transaction.rs: the statements and transactions committed in the meantime are saved
+    as one at the end, which is how the server commits requests that arrive together.
+
+    ATTACH adds another database's tables to the same storage and schema, known by the
+    database's name and theirs, `aux.orders`, next to an `aux.sqlite_master` holding its
+    own catalog. A statement reaches them by that name, CREATE TABLE aux.t makes one, and
+    each save writes them to their own file without the name in front, so that the file
+    is a database of its own. DETACH forgets them. Views and triggers in an attached
+    database aren't supported yet.
+
+    A correlated subquery such as the `EXISTS (SELECT 1 FROM orders WHERE user_id = u.id)`
+    of a filter on users is run once for each row, with the outer row's values put in
+    place of the columns it refers to. The planner turns the common cases into semi-joins
+    so that this is the exception.
+*/
+use crate::aggregate;
+use crate::authorizer::Authorizer;
+use crate::catalog::{self, CatalogEntry, EntryKind};
+use crate::check;
+use crate::collation::{Collation, Collations};
+use crate::compound;
+use crate::error::SqlError;
+use crate::eval::{self, Affinity, EvalContext};
+use crate::foreign_key::{self, ForeignKeyStep};
+use crate::functions::{DeterministicContext, FunctionRegistry};
+use crate::generated;
+use crate::interrupt::InterruptHandle;
+use crate::introspect::SchemaInfo;
+use crate::join;
+use crate::nesting::Nesting;
+use crate::planner::{self, Catalog, JoinAlgorithm, JoinTable, Plan, TableStats};
+use crate::pragma::{self, FileUsage};
+use crate::prepared::{PreparedStatement, StatementCache};
+use crate::profile::Profile;
+use crate::row;
+use crate::schema::Schema;
+use crate::sorter::Sorter;
+use crate::sql_parser::{
+    self,
+    ast::{
+        format_real, BinaryOp, ColVal, CreateIndex, CreateTable, CreateTrigger, Expr, Limit,
+        NewColumnVal, Pragma, Statement, TransactionMode, TriggerTiming,
+    },
+};
+use crate::storage::attach::{Database, Databases, TEMP};
+use crate::storage::busy::BusyHandler;
+use crate::storage::cache::CacheReport;
+use crate::storage::clustered::ClusteredTable;
+use crate::storage::compress::Compression;
+use crate::storage::image::{DatabaseFile, ImageRow};
+use crate::storage::index::{RowId, RowKey, SecondaryIndex};
+use crate::storage::journal;
+use crate::storage::lock::LockStatus;
+use crate::storage::memdb::{AccessMode, FileFormat, OpenTarget};
+use crate::storage::overflow;
+use crate::storage::pager::{JournalMode, PagerConfig, TempStore};
+use crate::storage::record;
+use crate::storage::sqlite_file::{self, Tree};
+use crate::storage::table::RowidTable;
+use crate::storage::wal::PageNumber;
+use crate::transaction::{Journaled, TransactionManager};
+use crate::trigger::{self, TriggerRow};
+use crate::ttl::{Clock, Expiry};
+use crate::vdbe::{self, CursorRow, Machine, Program};
+use crate::vtab::{self, Module};
+use crate::window;
+use anyhow::{anyhow, bail, Context, Result};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Values of an index's first column ANALYZE keeps as samples, as SQLite does by default.
+const ANALYZE_SAMPLES: usize = 24;
+
+// The savepoint an append_batch runs in, so a failed batch undoes only its own rows.
+const APPEND_BATCH_SAVEPOINT: &str = "append_batch";
+
+// The savepoint each INSERT, UPDATE and DELETE runs in, so a failed statement undoes only
+// its own rows.
+const STATEMENT_SAVEPOINT: &str = "statement";
+
+// The pragmas of the connection's own settings, which don't need the schema as the file
+// has it, see check_file.
+const CONNECTION_PRAGMAS: &[&str] = &[
+    "busy_timeout",
+    "cache_replacement",
+    "cache_size",
+    "deterministic_output",
+    "foreign_keys",
+    "hard_heap_limit",
+    "max_trigger_depth",
+    "mmap_size",
+    "synchronous",
+];
+
+/// What a statement returns: the rows of a query and the names of their columns. Empty
+/// for statements that only write, but for an INSERT, UPDATE or DELETE `changes` is how
+/// many rows it changed.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct RowSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<ColVal>>,
+    pub changes: Option<u64>,
+}
+
+// Printed the way the sqlite3 shell prints by default, a line per row with the values
+// separated by | and NULL as nothing at all.
+impl fmt::Display for RowSet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, row) in self.rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let values: Vec<String> = row
+                .iter()
+                .map(|v| match v {
+                    ColVal::Null => String::new(),
+                    ColVal::Boolean(b) => (*b as i64).to_string(),
+                    ColVal::String(s) => s.clone(),
+                    ColVal::Int(n) => n.to_string(),
+                    ColVal::Real(r) => format_real(*r),
+                    // in hex, as the shell prints one, see repl/render.rs
+                    ColVal::Blob(_) => v.to_string(),
+                })
+                .collect();
+            write!(f, "{}", values.join("|"))?;
+        }
+        Ok(())
+    }
+}
+
+// A row being sorted by ORDER BY: the values it sorts by, then the row itself.
+type SortRecord = (Vec<ColVal>, (Vec<ColVal>, Option<RowKey>));
+
+#[derive(Debug)]
+enum Table {
+    Rowid(RowidTable),
+    Clustered(ClusteredTable),
+}
+
+impl Table {
+    // The key a row is to be stored under, which for a rowid table may fill in the row's
+    // INTEGER PRIMARY KEY. A row being updated was stored under `old`.
+    fn key_for(&self, row: &mut [ColVal], old: Option<&RowKey>) -> Result<RowKey> {
+        match self {
+            Table::Rowid(table) => {
+                let old = match old {
+                    Some(RowKey::RowId(rowid)) => Some(*rowid),
+                    _ => None,
+                };
+                Ok(RowKey::RowId(table.rowid_for(row, old)?))
+            }
+            Table::Clustered(table) => Ok(RowKey::PrimaryKey(table.primary_key(row)?)),
+        }
+    }
+
+    fn get(&self, key: &RowKey) -> Option<Vec<ColVal>> {
+        match (self, key) {
+            (Table::Rowid(table), RowKey::RowId(rowid)) => table.get(*rowid).map(<[_]>::to_vec),
+            (Table::Clustered(table), RowKey::PrimaryKey(key)) => table.get(key),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: &RowKey, row: Vec<ColVal>) -> Result<()> {
+        match (self, key) {
+            (Table::Rowid(table), RowKey::RowId(rowid)) => table.insert(*rowid, row),
+            (Table::Clustered(table), RowKey::PrimaryKey(_)) => table.insert(row),
+            _ => unreachable!("a table's rows all have the same kind of key"),
+        }
+    }
+
+    fn delete(&mut self, key: &RowKey) -> Option<Vec<ColVal>> {
+        match (self, key) {
+            (Table::Rowid(table), RowKey::RowId(rowid)) => table.delete(*rowid),
+            (Table::Clustered(table), RowKey::PrimaryKey(key)) => table.delete(key),
+            _ => None,
+        }
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        self.iter().collect()
+    }
+
+    // Every row in key order, read from the tree as the iterator goes.
+    fn iter(&self) -> Box<dyn Iterator<Item = Row> + '_> {
+        match self {
+            Table::Rowid(table) => Box::new(table.iter().map(|(rowid, values)| Row {
+                key: Some(RowKey::RowId(rowid)),
+                values: values.to_vec(),
+            })),
+            Table::Clustered(table) => Box::new(table.iter().map(move |values| Row {
+                key: Some(RowKey::PrimaryKey(
+                    table.primary_key(&values).expect("stored keys aren't NULL"),
+                )),
+                values,
+            })),
+        }
+    }
+}
+
+/// How to reverse a write to one row, or a CREATE.
+#[derive(Debug)]
+enum Undo {
+    Create {
+        kind: EntryKind,
+        name: String,
+    },
+    Insert {
+        table: String,
+        key: RowKey,
+    },
+    Delete {
+        table: String,
+        key: RowKey,
+        row: Vec<ColVal>,
+    },
+}
+
+// The rows of every table and the entries of every index on them.
+#[derive(Debug, Default)]
+struct Storage {
+    tables: BTreeMap<String, Table>,
+    indexes: BTreeMap<String, SecondaryIndex>,
+}
+
+impl Storage {
+    fn table(&self, name: &str) -> Result<&Table> {
+        self.tables.get(name).ok_or_else(|| {
+            anyhow!(SqlError::NoSuchTable {
+                table: name.to_string()
+            })
+        })
+    }
+
+    fn index_names(&self, table: &str) -> Vec<String> {
+        self.indexes
+            .values()
+            .filter(|i| i.table == table)
+            .map(|i| i.name.clone())
+            .collect()
+    }
+
+    // The rowid the first row of a batch gets, if the table is a rowid table with no rows
+    // and no indexes and so can be bulk loaded.
+    fn loadable(&self, table: &str) -> Option<RowId> {
+        match self.tables.get(table)? {
+            Table::Rowid(t) if t.is_empty() && self.index_names(table).is_empty() => {
+                t.next_rowid().ok()
+            }
+            _ => None,
+        }
+    }
+
+    // Rebuild every table and index with its nodes full, see Executor::vacuum.
+    fn rebuild(&mut self) {
+        for table in self.tables.values_mut() {
+            match table {
+                Table::Rowid(table) => table.rebuild(),
+                Table::Clustered(table) => table.rebuild(),
+            }
+        }
+        for index in self.indexes.values_mut() {
+            index.rebuild();
+        }
+    }
+
+    // What is wrong with each table's and index's B+tree, and what each index's entries get
+    // wrong about its table's rows, see SecondaryIndex::check.
+    fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (name, table) in &self.tables {
+            let checked = match table {
+                Table::Rowid(table) => table.check(),
+                Table::Clustered(table) => table.check(),
+            };
+            if let Err(err) = checked {
+                problems.push(format!("table {name}: {err}"));
+            }
+        }
+        for index in self.indexes.values() {
+            if let Some(table) = self.tables.get(&index.table) {
+                let rows: Vec<(RowKey, Vec<ColVal>)> = table
+                    .iter()
+                    .map(|row| (row.key.expect("stored rows have keys"), row.values))
+                    .collect();
+                let rows: Vec<(RowKey, &[ColVal])> = rows
+                    .iter()
+                    .map(|(key, row)| (key.clone(), &row[..]))
+                    .collect();
+                problems.extend(index.check(&rows));
+            }
+        }
+        problems
+    }
+
+    // Fill an empty rowid table with rows in rowid order, see loadable.
+    fn load(&mut self, table: &str, rows: Vec<(RowId, Vec<ColVal>)>) {
+        if let Some(Table::Rowid(t)) = self.tables.get_mut(table) {
+            t.load(rows);
+        }
+    }
+
+    // Store a row in its table and every index on it, or if one of them refuses it in none.
+    fn insert(&mut self, table: &str, key: &RowKey, row: Vec<ColVal>) -> Result<()> {
+        let names = self.index_names(table);
+        let Some(stored) = self.tables.get_mut(table) else {
+            bail!(SqlError::NoSuchTable {
+                table: table.to_string()
+            });
+        };
+        stored.insert(key, row.clone())?;
+        for (i, name) in names.iter().enumerate() {
+            if let Err(err) = self.indexes.get_mut(name).unwrap().on_insert(key, &row) {
+                for done in &names[..i] {
+                    self.indexes.get_mut(done).unwrap().on_delete(key, &row);
+                }
+                self.tables.get_mut(table).unwrap().delete(key);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, table: &str, key: &RowKey) -> Option<Vec<ColVal>> {
+        let row = self.tables.get_mut(table)?.delete(key)?;
+        for index in self.indexes.values_mut().filter(|i| i.table == table) {
+            index.on_delete(key, &row);
+        }
+        Some(row)
+    }
+
+    // Replace the row stored under `key`, returning where the new row went, which is only
+    // somewhere else if its key changed, and the old row. On failure the old row stays.
+    fn update(
+        &mut self,
+        table: &str,
+        key: &RowKey,
+        mut row: Vec<ColVal>,
+    ) -> Result<(RowKey, Vec<ColVal>)> {
+        let Some(old) = self.delete(table, key) else {
+            bail!("no such row in {table}");
+        };
+        let result = self
+            .table(table)
+            .and_then(|t| t.key_for(&mut row, Some(key)));
+        let result = result.and_then(|new_key| {
+            self.insert(table, &new_key, row)?;
+            Ok(new_key)
+        });
+        match result {
+            Ok(new_key) => Ok((new_key, old)),
+            Err(err) => {
+                self.insert(table, key, old)
+                    .expect("the old row fits where it came from");
+                Err(err)
+            }
+        }
+    }
+}
+
+// What a rollback puts back as it was: the rows, and the schema of a CREATE it undoes.
+struct Contents<'e> {
+    schema: &'e mut Schema,
+    storage: &'e mut Storage,
+}
+
+impl Journaled for Contents<'_> {
+    type Undo = Undo;
+
+    fn apply_undo(&mut self, undo: Undo) -> Result<()> {
+        match undo {
+            Undo::Create { kind, name } => {
+                let storage = &mut *self.storage;
+                match kind {
+                    EntryKind::Table => {
+                        storage.tables.remove(&name);
+                        storage.indexes.retain(|_, index| index.table != name);
+                    }
+                    EntryKind::Index => {
+                        storage.indexes.remove(&name);
+                    }
+                    EntryKind::View | EntryKind::Trigger => {}
+                }
+                self.schema.forget(kind, &name);
+            }
+            Undo::Insert { table, key } => {
+                self.storage.delete(&table, &key);
+            }
+            Undo::Delete { table, key, row } => self.storage.insert(&table, &key, row)?,
+        }
+        Ok(())
+    }
+}
+
+// A row on its way up a plan. Rows read from a table know where they are stored, which
+// is how an UPDATE or DELETE finds the rows to change.
+#[derive(Debug, Clone)]
+struct Row {
+    key: Option<RowKey>,
+    values: Vec<ColVal>,
+}
+
+// Where a result column's value comes from: a column of the row, its rowid, or an
+// expression worked out from the row, down to a constant.
+enum ResultSource {
+    Column(usize),
+    Rowid,
+    Expr(Expr),
+}
+
+// The rows an operator produces. `table` is the table they are rows of, as long as they
+// still are, for the collations of its columns.
+#[derive(Debug)]
+struct Rows {
+    table: Option<String>,
+    columns: Vec<String>,
+    rows: Vec<Row>,
+}
+
+/// Runs statements against a database held in memory.
+#[derive(Debug)]
+pub struct Executor {
+    schema: Schema,
+    storage: Storage,
+    functions: FunctionRegistry,
+    collations: Collations,
+    config: PagerConfig,
+    databases: Databases,
+    transactions: TransactionManager<Undo>,
+    statements: StatementCache,
+    expiry: Expiry,
+    // the file the tables are saved to, None for an in-memory database
+    file: Option<DatabaseFile>,
+    // the change counter and schema cookie in its header as of the last load or save,
+    // and the schema's own cookie then, see check_file
+    file_counter: u32,
+    file_cookie: u32,
+    saved_schema: u32,
+    // the files of the attached databases, by name, and temp's
+    attached_files: BTreeMap<String, Option<DatabaseFile>>,
+    // the directory temp's file is in under PRAGMA temp_store = FILE, deleted with it
+    temp_dir: Option<tempfile::TempDir>,
+    // whether main is a file SQLite wrote, which can only be read
+    read_only: bool,
+    // main's file if it is kept in SQLite's format, see storage/sqlite_file.rs
+    sqlite_path: Option<PathBuf>,
+    commit_hooks: CommitHooks,
+    // how many commits there have been, of transactions and statements outside them
+    commits: u64,
+    interrupt: InterruptHandle,
+    // asked whether each statement may do what it does, see authorizer.rs
+    authorizer: Option<Authorizer>,
+    // whether the rows of a query without an ORDER BY are sorted, see pragma.rs
+    deterministic: bool,
+    // whether foreign keys are enforced, and the checks of them waiting for the end of
+    // the user's statement, see foreign_key.rs
+    foreign_keys: bool,
+    foreign_key_checks: Vec<ForeignKeyStep>,
+    // what the operators of the plan being run did, under EXPLAIN ANALYZE, see profile.rs
+    profile: Option<Profile>,
+    // how deep triggers and subqueries are running, see nesting.rs
+    nesting: Nesting,
+    // the rowid of the last row inserted into a rowid table, 0 if none has been
+    last_insert_rowid: RowId,
+    // rows changed by the last INSERT, UPDATE or DELETE, and by all of them
+    changes: u64,
+    total_changes: u64,
+}
+
+// Called with the image of main after every commit, for example to ship it to replicas,
+// see replication.rs.
+type CommitHook = Box<dyn Fn(&[ImageRow]) + Send>;
+
+#[derive(Default)]
+struct CommitHooks(Vec<CommitHook>);
+
+impl fmt::Debug for CommitHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} commit hooks", self.0.len())
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        let mut executor = Executor {
+            schema: Schema::default(),
+            storage: Storage::default(),
+            functions: FunctionRegistry::with_builtins(),
+            collations: Collations::default(),
+            config: PagerConfig::default(),
+            databases: Databases::new(OpenTarget::parse(":memory:").expect("a valid filename")),
+            transactions: TransactionManager::default(),
+            statements: StatementCache::default(),
+            expiry: Expiry::default(),
+            file: None,
+            file_counter: 0,
+            file_cookie: 0,
+            saved_schema: 0,
+            attached_files: BTreeMap::new(),
+            temp_dir: None,
+            read_only: false,
+            sqlite_path: None,
+            commit_hooks: CommitHooks::default(),
+            commits: 0,
+            interrupt: InterruptHandle::default(),
+            authorizer: None,
+            deterministic: false,
+            foreign_keys: false,
+            foreign_key_checks: vec![],
+            profile: None,
+            nesting: Nesting::default(),
+            last_insert_rowid: 0,
+            changes: 0,
+            total_changes: 0,
+        };
+        executor
+            .create_table(&catalog::master_table())
+            .expect("sqlite_master to be created");
+        executor
+    }
+}
+
+impl Executor {
+    /// The database `main` names, loaded from its file unless it is in memory, and the
+    /// file created if it doesn't exist and the access mode allows.
+    pub fn open(main: OpenTarget) -> Result<Self> {
+        let mut executor = Executor::default();
+        if let OpenTarget::File {
+            path, mode, format, ..
+        } = &main
+        {
+            let sqlite = sqlite_file::is_sqlite_file(path)?;
+            if sqlite || *format == FileFormat::Sqlite {
+                let (path, mode, format) = (path.clone(), *mode, *format);
+                if sqlite {
+                    executor.load_image(None, sqlite_file::read(&path)?)?;
+                } else if std::fs::metadata(&path).is_ok_and(|file| file.len() > 0) {
+                    bail!(
+                        "\"{}\" is not a database in SQLite's format",
+                        path.display()
+                    );
+                } else if mode != AccessMode::Create {
+                    bail!("unable to open database file \"{}\"", path.display());
+                }
+                executor.databases = Databases::new(main);
+                executor.read_only = format == FileFormat::Image || mode == AccessMode::ReadOnly;
+                if !executor.read_only {
+                    executor.sqlite_path = Some(path);
+                    executor.save()?;
+                }
+                return Ok(executor);
+            }
+        }
+        executor.file = DatabaseFile::open_target(&main, &executor.config)?;
+        executor.databases = Databases::new(main);
+        let Some(file) = &mut executor.file else {
+            return Ok(executor);
+        };
+        let rows = file.load()?;
+        if file.in_wal_mode() {
+            executor.config.journal_mode = JournalMode::Wal;
+        }
+        executor.file_counter = file.change_counter();
+        executor.file_cookie = file.schema_cookie()?;
+        executor.load_image(None, rows)?;
+        executor.saved_schema = executor.schema.cookie();
+        Ok(executor)
+    }
+
+    /// A read only database of the rows of an image, a replica's copy of its leader's,
+    /// see replication.rs.
+    pub fn replica(rows: Vec<ImageRow>) -> Result<Self> {
+        let mut executor = Executor::default();
+        executor.load_image(None, rows)?;
+        executor.read_only = true;
+        Ok(executor)
+    }
+
+    /// A read only database of main as it was at the commit `commit` to its file's log,
+    /// while the log retains it, see storage/wal.rs.
+    pub fn as_of(&mut self, commit: u64) -> Result<Self> {
+        let Some(file) = &mut self.file else {
+            bail!("only a file in WAL mode can be read as of a commit");
+        };
+        Executor::replica(file.load_as_of(commit)?)
+    }
+
+    /// The latest commit to main's file's log, None unless the file is in WAL mode.
+    pub fn last_commit(&self) -> Option<u64> {
+        self.file.as_ref()?.last_commit()
+    }
+
+    /// Have main's file's log keep its last `commits` commits to be read as of.
+    pub fn retain_commits(&mut self, commits: usize) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            bail!("only a file in WAL mode keeps commits in a log");
+        };
+        file.retain_commits(commits)
+    }
+
+    /// The rows of main's image, as they would be saved to its file.
+    pub fn image(&self) -> Vec<ImageRow> {
+        image(&self.storage, &self.schema, None)
+    }
+
+    /// How many commits there have been since the database was opened, so that a backup
+    /// can tell whether the database changed under it.
+    pub fn commit_count(&self) -> u64 {
+        self.commits
+    }
+
+    /// Group the commits of the statements run from now on until end_group_commit, so
+    /// that they are saved to the file, and synced, once rather than once each, see
+    /// transaction.rs. A statement in the group has committed once the group has.
+    pub fn begin_group_commit(&mut self) {
+        self.transactions.begin_group();
+    }
+
+    /// Save the commits of the group as one, if it made any, and stop grouping them. A
+    /// save that fails fails the whole group: one that can't be as another connection
+    /// has committed since main was loaded loses the group's writes, and main is loaded
+    /// again, as a single statement's failed save does.
+    pub fn end_group_commit(&mut self) -> Result<()> {
+        let saved = self.save_group();
+        self.transactions.end_group();
+        if saved.is_err() {
+            self.load_changes()?;
+        }
+        saved
+    }
+
+    /// The rowid of the last row inserted into a rowid table, 0 if there hasn't been one.
+    pub fn last_insert_rowid(&self) -> RowId {
+        self.last_insert_rowid
+    }
+
+    /// How many rows the last INSERT, UPDATE or DELETE changed, not counting its triggers'.
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// How many rows every INSERT, UPDATE and DELETE has changed since the database was
+    /// opened.
+    pub fn total_changes(&self) -> u64 {
+        self.total_changes
+    }
+
+    pub fn config(&self) -> &PagerConfig {
+        &self.config
+    }
+
+    /// Run `hook` with main's image after every future commit, of a transaction or of a
+    /// statement outside one.
+    pub fn on_commit(&mut self, hook: impl Fn(&[ImageRow]) + Send + 'static) {
+        self.commit_hooks.0.push(Box::new(hook));
+    }
+
+    /// Run `hook` with the schema after every future change to it, see Schema::on_change.
+    pub fn on_schema_change(&mut self, hook: impl Fn(&Schema) + Send + 'static) {
+        self.schema.on_change(hook);
+    }
+
+    /// Change what is done when another connection holds a lock on the database's
+    /// files, main's and the attached ones', see storage/busy.rs.
+    pub fn set_busy_handler(&mut self, busy: BusyHandler) {
+        for file in self
+            .file
+            .iter_mut()
+            .chain(self.attached_files.values_mut().flatten())
+        {
+            file.set_busy_handler(busy.clone());
+        }
+        self.config.busy = busy;
+    }
+
+    // Size the caches of main's and the attached files' pagers as cache_size and the
+    // heap limit say.
+    fn resize_caches(&mut self) {
+        let pages = self.config.cache_pages();
+        for file in self
+            .file
+            .iter_mut()
+            .chain(self.attached_files.values_mut().flatten())
+        {
+            file.set_cache_pages(pages);
+        }
+    }
+
+    // Have the caches of main's and the attached files' pagers evict as
+    // cache_replacement says.
+    fn replace_caches(&mut self) {
+        let replacement = self.config.replacement;
+        for file in self
+            .file
+            .iter_mut()
+            .chain(self.attached_files.values_mut().flatten())
+        {
+            file.set_replacement(replacement);
+        }
+    }
+
+    // Put main's file in WAL mode or take it out of it, as PRAGMA journal_mode asks, see
+    // storage/image.rs. The pragma then reports the mode as it is.
+    fn change_journal_mode(&mut self, pragma: &Pragma) -> Result<()> {
+        let Some(mode) = pragma.value.as_deref().and_then(JournalMode::parse) else {
+            return Ok(());
+        };
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let wal = mode == JournalMode::Wal;
+        if wal != file.in_wal_mode() && self.transactions.in_transaction() {
+            let way = if wal { "into" } else { "out of" };
+            bail!("cannot change {way} wal mode from within a transaction");
+        }
+        file.set_wal(wal, self.file_counter)?;
+        self.file_counter = file.change_counter();
+        Ok(())
+    }
+
+    // Cap every file at max_page_count pages, and map as much of each as mmap_size says.
+    fn limit_files(&mut self) {
+        for file in self
+            .file
+            .iter_mut()
+            .chain(self.attached_files.values_mut().flatten())
+        {
+            file.set_max_page_count(self.config.max_page_count);
+            file.set_mmap_size(self.config.mmap_size);
+        }
+    }
+
+    /// Ask `authorizer` about every statement from now on, or no longer ask with None,
+    /// see authorizer.rs. The plans of statements already prepared are forgotten, so that
+    /// it is asked about them too.
+    pub fn set_authorizer(&mut self, authorizer: Option<Authorizer>) {
+        self.authorizer = authorizer;
+        self.statements = StatementCache::default();
+    }
+
+    /// Stops the statement running, from another thread or a signal handler, see
+    /// interrupt.rs.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// The lock each of the process's connections to main's file holds and what it is
+    /// waiting for, see storage/lock.rs, nothing for a database in memory.
+    pub fn lock_status(&self) -> Vec<LockStatus> {
+        self.file
+            .as_ref()
+            .map(DatabaseFile::lock_status)
+            .unwrap_or_default()
+    }
+
+    /// main and the databases attached to it, in the order they were attached.
+    pub fn databases(&self) -> &Databases {
+        &self.databases
+    }
+
+    /// Whether a transaction begun by BEGIN or a SAVEPOINT is open.
+    pub fn in_transaction(&self) -> bool {
+        self.transactions.in_transaction()
+    }
+
+    // Fill `database`, main if None, with the rows of an image of it.
+    fn load_image(&mut self, database: Option<&str>, rows: Vec<ImageRow>) -> Result<()> {
+        let (catalog, rows): (Vec<ImageRow>, Vec<ImageRow>) = rows
+            .into_iter()
+            .partition(|(table, _, _)| table == catalog::MASTER_TABLE);
+        let catalog: Vec<Vec<ColVal>> = catalog.into_iter().map(|(_, _, row)| row).collect();
+        self.load_database_catalog(database, &catalog)?;
+        for (table, key, row) in rows {
+            self.load_image_row(&qualified(database, &table), key, row)?;
+        }
+        Ok(())
+    }
+
+    // Put a row of an image back in `table`, under the rowid `key` unless that is NULL.
+    fn load_image_row(&mut self, table: &str, key: ColVal, row: Vec<ColVal>) -> Result<()> {
+        let def = self.table_def(table)?;
+        // SQLite leaves out columns added after a row was written and VIRTUAL ones, keeps
+        // an INTEGER PRIMARY KEY in the rowid alone and a whole REAL as an integer
+        let mut row = generated::unstored(&def, row);
+        if let (ColVal::Int(rowid), Some(alias)) = (&key, def.rowid_alias()) {
+            if row[alias] == ColVal::Null {
+                row[alias] = ColVal::Int(*rowid);
+            }
+        }
+        for (value, column) in row.iter_mut().zip(&def.columns) {
+            let affinity = Affinity::of(column.type_name.as_deref());
+            *value = affinity.apply(std::mem::replace(value, ColVal::Null));
+        }
+        let stored = self.storage.table(table)?;
+        let key = match key {
+            ColVal::Int(rowid) => RowKey::RowId(rowid),
+            _ => stored.key_for(&mut row, None)?,
+        };
+        self.generate(table, &def, &key, &mut row)?;
+        self.storage.insert(table, &key, row)
+    }
+
+    /// A database in memory of as much as can be made of `rows`, salvaged from a damaged
+    /// file, see storage/recover.rs. An entry of sqlite_master or a row that can't be put
+    /// back, one that breaks a UNIQUE constraint say, is left out and `lost` says why. A
+    /// table whose entry is gone is made with columns c0, c1 and so on, as many as its
+    /// longest row has.
+    pub fn recovered(rows: Vec<ImageRow>, lost: &mut Vec<String>) -> Self {
+        let mut executor = Executor::default();
+        let (catalog, rows): (Vec<ImageRow>, Vec<ImageRow>) = rows
+            .into_iter()
+            .partition(|(table, _, _)| table == catalog::MASTER_TABLE);
+        for (_, _, row) in catalog {
+            let what = match CatalogEntry::from_row(&row) {
+                Ok(entry) => format!("{} {}", entry.kind, entry.name),
+                Err(_) => format!("{} row {row:?}", catalog::MASTER_TABLE),
+            };
+            if let Err(err) = executor.load_database_catalog(None, &[row]) {
+                lost.push(format!("{what}: {err:#}"));
+            }
+        }
+
+        let mut widths: BTreeMap<&str, usize> = BTreeMap::new();
+        for (table, _, row) in &rows {
+            if executor.table_def(table).is_err() {
+                let width = widths.entry(table).or_default();
+                *width = row.len().max(*width).max(1);
+            }
+        }
+        for (table, width) in widths {
+            let columns: Vec<String> = (0..width).map(|i| format!("c{i}")).collect();
+            let create = format!("CREATE TABLE {table} ({});", columns.join(", "));
+            if let Err(err) = executor.execute_sql(&create) {
+                lost.push(format!("table {table}: {err:#}"));
+            }
+        }
+
+        // the rows of each table that couldn't be put back, and why the first couldn't
+        let mut failed: BTreeMap<String, (usize, String)> = BTreeMap::new();
+        for (table, key, row) in rows {
+            let loaded = executor.table_def(&table).and_then(|def| {
+                if row.len() > def.columns.len() {
+                    bail!("{} values for {} columns", row.len(), def.columns.len());
+                }
+                executor.load_image_row(&table, key, row)
+            });
+            if let Err(err) = loaded {
+                let (count, _) = failed.entry(table).or_insert((0, format!("{err:#}")));
+                *count += 1;
+            }
+        }
+        for (table, (count, err)) in failed {
+            let rows = if count == 1 { "row" } else { "rows" };
+            lost.push(format!("{count} {rows} of {table}, the first: {err}"));
+        }
+        executor
+    }
+
+    // Save a commit, or while commits are grouped put it off to be saved with the rest of
+    // the group's when the group ends, see transaction.rs. Either way it is counted once
+    // it has been saved.
+    fn save(&mut self) -> Result<()> {
+        if self.transactions.defer_commit() {
+            return Ok(());
+        }
+        self.save_now(1)
+    }
+
+    // Save the commits of the group so far, if it has put any off, as one.
+    fn save_group(&mut self) -> Result<()> {
+        let deferred = self.transactions.take_deferred();
+        if deferred > 0 {
+            self.save_now(deferred)?;
+        }
+        Ok(())
+    }
+
+    // Save every database that has a file to it, see storage/image.rs, counting the
+    // `commits` the save holds.
+    fn save_now(&mut self, commits: usize) -> Result<()> {
+        if let Some(path) = &self.sqlite_path {
+            let catalog = self.sqlite_catalog()?;
+            sqlite_file::write(path, &self.config, self.schema.cookie(), catalog)?;
+        }
+        // a change to the schema moves the file's cookie on, as SQLite's does
+        let changed = self.schema.cookie() != self.saved_schema;
+        let cookie = self.file_cookie.wrapping_add(u32::from(changed));
+        let main = self.file.as_ref().map(|file| file.path().to_path_buf());
+        let mut saves = vec![];
+        if let Some(file) = &mut self.file {
+            file.set_schema_cookie(cookie);
+            saves.push(journal::Save {
+                file,
+                rows: image(&self.storage, &self.schema, None),
+                counter: Some(self.file_counter),
+            });
+        }
+        for (name, file) in &mut self.attached_files {
+            if let Some(file) = file {
+                saves.push(journal::Save {
+                    file,
+                    rows: image(&self.storage, &self.schema, Some(name)),
+                    counter: None,
+                });
+            }
+        }
+        // every file or none, see storage/journal.rs
+        journal::commit(main.as_deref(), &mut saves)?;
+        if let Some(file) = &self.file {
+            self.file_counter = file.change_counter();
+            self.file_cookie = cookie;
+            self.saved_schema = self.schema.cookie();
+        }
+        self.commits += commits as u64;
+        if !self.commit_hooks.0.is_empty() {
+            let image = image(&self.storage, &self.schema, None);
+            for hook in &self.commit_hooks.0 {
+                hook(&image);
+            }
+        }
+        Ok(())
+    }
+
+    // Load main again if another connection has committed to its file since this one
+    // loaded or saved it, so that no statement reads rows or is planned against tables as
+    // they were. Not in a transaction, or with the commits of a group yet to be saved, as
+    // loading would lose their writes, and not for a pragma of the connection's own
+    // settings, which would take the file's lock for nothing. A save checks the file
+    // hasn't moved on again by the time it writes, see storage/image.rs.
+    fn check_file(&mut self, statement: &Statement) -> Result<()> {
+        if self.transactions.in_transaction() || self.transactions.has_deferred() {
+            return Ok(());
+        }
+        if let Statement::Pragma(pragma) = statement {
+            if CONNECTION_PRAGMAS.contains(&pragma.name.as_str()) {
+                return Ok(());
+            }
+        }
+        self.load_changes()
+    }
+
+    // Load main again, if another connection has committed to its file since this one
+    // loaded or saved it.
+    fn load_changes(&mut self) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        let header = file.header()?;
+        if header.change_counter == self.file_counter {
+            return Ok(());
+        }
+        let rows = file.load()?;
+        tracing::debug!(
+            counter = header.change_counter,
+            schema_changed = header.schema_cookie != self.file_cookie,
+            "file changed by another connection"
+        );
+        // dropping the tables moves the schema's cookie on, so prepared statements on
+        // them are planned again
+        self.schema.drop_main();
+        self.storage.tables.retain(|name, _| name.contains('.'));
+        self.storage.indexes.retain(|name, _| name.contains('.'));
+        self.create_table(&catalog::master_table())?;
+        self.load_image(None, rows)?;
+        self.file_counter = header.change_counter;
+        self.file_cookie = header.schema_cookie;
+        self.saved_schema = self.schema.cookie();
+        Ok(())
+    }
+
+    // Take the locks on main's file that BEGIN `mode` takes up front, see
+    // storage/image.rs, and load main again if another connection committed before they
+    // were taken, which it can't do after until the transaction ends.
+    fn lock_file(&mut self, mode: TransactionMode) -> Result<()> {
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+        file.begin(mode)?;
+        let loaded = self.load_changes();
+        if loaded.is_err() {
+            if let Some(file) = &mut self.file {
+                file.rollback()?;
+            }
+        }
+        loaded
+    }
+
+    // main's sqlite_master rows, each with the rows of its table or the entries of its
+    // index, to be written in SQLite's format. SQLite keeps an INTEGER PRIMARY KEY in the
+    // rowid alone and NULL in its column.
+    fn sqlite_catalog(&self) -> Result<Vec<(Vec<ColVal>, Option<Tree>)>> {
+        let mut catalog = vec![];
+        for row in self.storage.table(catalog::MASTER_TABLE)?.rows() {
+            let entry = CatalogEntry::from_row(&row.values)?;
+            let tree = match entry.kind {
+                // a virtual table, which has no rows to write
+                EntryKind::Table if entry.root_page == 0 => None,
+                EntryKind::Table => {
+                    let Table::Rowid(table) = self.storage.table(&entry.name)? else {
+                        bail!("WITHOUT ROWID tables can't be written in SQLite's format yet");
+                    };
+                    let def = self.table_def(&entry.name)?;
+                    let alias = def.rowid_alias();
+                    let rows = table.rows().into_iter().map(|(rowid, row)| {
+                        let mut row = row.to_vec();
+                        if let Some(alias) = alias {
+                            row[alias] = ColVal::Null;
+                        }
+                        (rowid, generated::stored(&def, row))
+                    });
+                    Some(Tree::Table(rows.collect()))
+                }
+                EntryKind::Index => match self.storage.indexes.get(&entry.name) {
+                    Some(index) => Some(Tree::Index(index.entries())),
+                    None => bail!("no such index: {}", entry.name),
+                },
+                EntryKind::View | EntryKind::Trigger => None,
+            };
+            catalog.push((row.values, tree));
+        }
+        Ok(catalog)
+    }
+
+    // Attach the database `path` names as `name` and load its tables.
+    fn attach(&mut self, path: &str, name: &str, in_transaction: bool) -> Result<()> {
+        self.databases.attach(path, name, in_transaction)?;
+        let result = self.load_attached(name);
+        if result.is_err() {
+            self.databases.detach(name, false)?;
+            self.drop_database(name);
+        }
+        result
+    }
+
+    fn load_attached(&mut self, name: &str) -> Result<()> {
+        let Some(Database { target, .. }) = self.databases.get(name).cloned() else {
+            bail!("no such database: {name}");
+        };
+        if let OpenTarget::File { path, .. } = &target {
+            if sqlite_file::is_sqlite_file(path)? {
+                bail!("a file SQLite wrote can be opened but not attached yet");
+            }
+        }
+        let mut master = catalog::master_table();
+        master.name = qualified(Some(name), catalog::MASTER_TABLE);
+        self.create_table(&master)?;
+        let mut file = DatabaseFile::open_target(&target, &self.config)?;
+        if let Some(file) = &mut file {
+            self.load_image(Some(name), file.load()?)?;
+        }
+        self.attached_files.insert(name.to_string(), file);
+        Ok(())
+    }
+
+    fn detach(&mut self, name: &str, in_transaction: bool) -> Result<()> {
+        self.databases.detach(name, in_transaction)?;
+        self.drop_database(name);
+        Ok(())
+    }
+
+    // Make the temp database for the first temporary table, with a sqlite_master of its
+    // own and, under PRAGMA temp_store = FILE, a file in a new directory, which is saved
+    // to like an attached database's until the connection closes and deletes it.
+    fn create_temp_database(&mut self) -> Result<()> {
+        let master = qualified(Some(TEMP), catalog::MASTER_TABLE);
+        if self.storage.tables.contains_key(&master) {
+            return Ok(());
+        }
+        let file = match self.config.temp_store {
+            TempStore::File => {
+                let dir = tempfile::tempdir().context("cannot create a temporary file")?;
+                let file = DatabaseFile::open(
+                    &dir.path().join("temp.db"),
+                    AccessMode::Create,
+                    Compression::default(),
+                    &self.config,
+                )?;
+                self.temp_dir = Some(dir);
+                Some(file)
+            }
+            TempStore::Default | TempStore::Memory => None,
+        };
+        let mut def = catalog::master_table();
+        def.name = master;
+        self.create_table(&def)?;
+        self.attached_files.insert(TEMP.to_string(), file);
+        Ok(())
+    }
+
+    // Forget the tables and indexes of an attached database or temp, and its file.
+    fn drop_database(&mut self, name: &str) {
+        let prefix = format!("{name}.");
+        self.storage.tables.retain(|t, _| !t.starts_with(&prefix));
+        self.storage.indexes.retain(|i, _| !i.starts_with(&prefix));
+        self.schema.drop_database(name);
+        self.attached_files.remove(name);
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Add a module for CREATE VIRTUAL TABLE to make tables with, see vtab.rs.
+    pub fn create_module(&mut self, name: &str, module: Arc<Module>) -> Result<()> {
+        self.schema.create_module(name, module)
+    }
+
+    /// Register a function implemented by a Rust closure, see functions.rs. A plan kept
+    /// from before could have been made without it, so the statement cache is emptied.
+    pub fn create_scalar_function(
+        &mut self,
+        name: &str,
+        arg_count: Option<usize>,
+        deterministic: bool,
+        function: impl Fn(&[ColVal]) -> Result<ColVal> + Send + Sync + 'static,
+    ) {
+        self.functions
+            .create_scalar_function(name, arg_count, deterministic, function);
+        self.statements = StatementCache::default();
+    }
+
+    /// Add a collation, or replace one of the same name, see collation.rs. Indexes keep
+    /// the one they were built with until REINDEX, but plans are made again.
+    pub fn create_collation(
+        &mut self,
+        name: &str,
+        compare: impl Fn(&str, &str) -> Ordering + Send + Sync + 'static,
+    ) {
+        self.collations.create_collation(name, compare);
+        self.statements = StatementCache::default();
+    }
+
+    /// Every table, index, view and trigger described as data, see introspect.rs.
+    pub fn schema_info(&self) -> SchemaInfo {
+        self.schema.info()
+    }
+
+    /// What the caches of main's file and the attached ones' have counted, nothing for a
+    /// database with no file, see storage/cache.rs.
+    pub fn cache_report(&self) -> CacheReport {
+        let mut report = CacheReport::default();
+        let main = self.file.iter().map(|file| ("main", file));
+        let attached = self
+            .attached_files
+            .iter()
+            .filter_map(|(name, file)| Some((name.as_str(), file.as_ref()?)));
+        for (name, file) in main.chain(attached) {
+            report.add(name, file.cache_report());
+        }
+        report
+    }
+
+    /// Give `table` an expiry column whose rows are deleted once its time has passed, or
+    /// with None stop them expiring, see ttl.rs.
+    pub fn set_expiry(&mut self, table: &str, column: Option<&str>) -> Result<()> {
+        self.expiry.set(&self.schema, table, column)
+    }
+
+    pub fn set_expiry_clock(&mut self, clock: Clock) {
+        self.expiry.set_clock(clock);
+    }
+
+    /// Delete the expired rows of every table with an expiry column.
+    pub fn sweep_expired(&mut self) -> Result<()> {
+        self.sweep(self.expiry.sweep_all())
+    }
+
+    /// Append rows to `table` without going through SQL at all, for loading a lot of data
+    /// as fast as it can go. Each row is a value per column in the table's order, given
+    /// the column's affinity as INSERT would and stored straight into the B+trees, where
+    /// rowids that only go up are appended to the rightmost leaf. Into an empty rowid
+    /// table with no indexes, rows in rowid order are bulk loaded instead, the table's
+    /// tree built in one go. No triggers fire.
+    ///
+    /// The batch is all or nothing: it runs in a transaction of its own, or in a savepoint
+    /// of the one already open, and the first row that fails undoes those before it.
+    /// Returns how many rows were stored.
+    pub fn append_batch(
+        &mut self,
+        table: &str,
+        rows: impl IntoIterator<Item = Vec<ColVal>>,
+    ) -> Result<usize> {
+        if self.read_only {
+            bail!("attempt to write a readonly database");
+        }
+        planner::check_writable(table, &self.schema)?;
+        let def = self.table_def(table)?;
+        let affinities: Vec<Affinity> = def
+            .columns
+            .iter()
+            .map(|c| Affinity::of(c.type_name.as_deref()))
+            .collect();
+
+        self.transactions.savepoint(APPEND_BATCH_SAVEPOINT);
+        let result = self.follow_sequence(&def).and_then(|()| {
+            let count = match self.storage.loadable(table) {
+                Some(next) => self.load_batch(table, &def, &affinities, rows, next)?,
+                None => rows.into_iter().try_fold(0, |count, row| {
+                    let (key, row) = self.prepare_row(table, &def, &affinities, row, None)?;
+                    self.append_row(table, key, row)?;
+                    Ok::<_, anyhow::Error>(count + 1)
+                })?,
+            };
+            self.advance_sequence(&def)?;
+            Ok(count)
+        });
+        if result.is_err() {
+            self.transactions.rollback_to(
+                APPEND_BATCH_SAVEPOINT,
+                &mut Contents {
+                    schema: &mut self.schema,
+                    storage: &mut self.storage,
+                },
+            )?;
+        }
+        self.transactions.release(APPEND_BATCH_SAVEPOINT)?;
+        if result.is_ok() && !self.transactions.in_transaction() {
+            if let Err(err) = self.save() {
+                self.load_changes()?;
+                return Err(err);
+            }
+        }
+        result
+    }
+
+    // A batch for an empty table, numbering the rows that don't give a rowid from `next`
+    // on, bulk loaded if the rowids come out in order and appended row by row if not.
+    fn load_batch(
+        &mut self,
+        table: &str,
+        def: &CreateTable,
+        affinities: &[Affinity],
+        rows: impl IntoIterator<Item = Vec<ColVal>>,
+        mut next: RowId,
+    ) -> Result<usize> {
+        let mut loaded = vec![];
+        for row in rows {
+            let old = RowKey::RowId(next);
+            let (key, row) = self.prepare_row(table, def, affinities, row, Some(&old))?;
+            let RowKey::RowId(rowid) = key else {
+                unreachable!("a rowid table's rows are stored by rowid");
+            };
+            next = next.max(rowid.saturating_add(1));
+            loaded.push((rowid, row));
+        }
+        let count = loaded.len();
+        if !loaded.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            for (rowid, row) in loaded {
+                self.append_row(table, RowKey::RowId(rowid), row)?;
+            }
+            return Ok(count);
+        }
+        let rowids: Vec<RowId> = loaded.iter().map(|(rowid, _)| *rowid).collect();
+        self.storage.load(table, loaded);
+        for rowid in rowids {
+            self.transactions.record(Undo::Insert {
+                table: table.to_string(),
+                key: RowKey::RowId(rowid),
+            });
+        }
+        Ok(count)
+    }
+
+    // A row of a batch with its columns' affinities applied and its generated columns
+    // worked out, and the key it goes under.
+    fn prepare_row(
+        &self,
+        table: &str,
+        def: &CreateTable,
+        affinities: &[Affinity],
+        row: Vec<ColVal>,
+        old: Option<&RowKey>,
+    ) -> Result<(RowKey, Vec<ColVal>)> {
+        if row.len() != affinities.len() {
+            bail!(
+                "table {table} has {} columns but {} values were supplied",
+                affinities.len(),
+                row.len()
+            );
+        }
+        let mut row: Vec<ColVal> = row
+            .into_iter()
+            .zip(affinities)
+            .map(|(value, affinity)| affinity.apply(value))
+            .collect();
+        let key = self.storage.table(table)?.key_for(&mut row, old)?;
+        self.generate(table, def, &key, &mut row)?;
+        self.check_constraints(table, def, &key, &row)?;
+        self.check_row_size(&row)?;
+        Ok((key, row))
+    }
+
+    fn append_row(&mut self, table: &str, key: RowKey, row: Vec<ColVal>) -> Result<()> {
+        self.storage.insert(table, &key, row)?;
+        if let RowKey::RowId(rowid) = key {
+            self.last_insert_rowid = rowid;
+        }
+        self.transactions.record(Undo::Insert {
+            table: table.to_string(),
+            key,
+        });
+        Ok(())
+    }
+
+    // Run a sweep's DELETE statements, which don't sweep again themselves.
+    fn sweep(&mut self, deletes: Vec<Statement>) -> Result<()> {
+        if deletes.is_empty() {
+            return Ok(());
+        }
+        self.expiry.set_sweeping(true);
+        let result = deletes
+            .iter()
+            .try_for_each(|delete| self.execute(delete).map(|_| ()));
+        self.expiry.set_sweeping(false);
+        result
+    }
+
+    /// Parse and run one statement, or run it again from the statement cache if the same
+    /// SQL was run recently.
+    pub fn execute_sql(&mut self, sql: &str) -> Result<RowSet> {
+        let mut statement = match self.statements.take(sql) {
+            Some(statement) => statement,
+            None => PreparedStatement::prepare(sql)?,
+        };
+        let result = self.execute_prepared(&mut statement);
+        self.statements.put(sql, statement);
+        result
+    }
+
+    /// Run `sql` as execute_sql does, but hand out a query's rows as they are read, see
+    /// query_prepared.
+    pub fn query_sql(&mut self, sql: &str) -> Result<row::Rows<'_>> {
+        let mut statement = match self.statements.take(sql) {
+            Some(statement) => statement,
+            None => PreparedStatement::prepare(sql)?,
+        };
+        let program = self.streamed(&mut statement);
+        self.statements.put(sql, statement.clone());
+        self.query_program(&mut statement, program)
+    }
+
+    /// Run a prepared statement as execute_prepared does, but hand out its rows as they
+    /// are read. A query the machine runs is stepped to each of its rows as it is asked
+    /// for, see vdbe.rs, so that its rows are never all in memory at once. Any other
+    /// statement runs to the end first and its rows are handed out after.
+    pub fn query_prepared(&mut self, statement: &mut PreparedStatement) -> Result<row::Rows<'_>> {
+        let program = self.streamed(statement);
+        self.query_program(statement, program)
+    }
+
+    fn query_program(
+        &mut self,
+        statement: &mut PreparedStatement,
+        program: Result<Option<Program>>,
+    ) -> Result<row::Rows<'_>> {
+        let program = match program {
+            Ok(Some(program)) => program,
+            Ok(None) => return self.execute_prepared(statement).map(row::Rows::from),
+            Err(err) => {
+                self.interrupt.clear();
+                return Err(err);
+            }
+        };
+        let executor: &Executor = self;
+        let mut machine = Machine::new(Cow::Owned(program), executor);
+        let columns = machine.columns().to_vec();
+        let rows = std::iter::from_fn(move || {
+            let row = machine.next();
+            // an interrupt stops one statement, which ends with its last row
+            if !matches!(row, Some(Ok(_))) {
+                executor.interrupt.clear();
+            }
+            row
+        });
+        Ok(row::Rows::stream(columns, rows))
+    }
+
+    // The program a statement compiled to if its rows can be read from the machine as it
+    // runs, after the checks execute_prepared makes before running it. None if it has to
+    // run as execute_prepared runs it, as a write, an EXPLAIN or an operator tree does,
+    // or as every query does whose rows deterministic_output sorts.
+    fn streamed(&mut self, statement: &mut PreparedStatement) -> Result<Option<Program>> {
+        if self.deterministic
+            || matches!(
+                statement.statement(),
+                Statement::Explain(_)
+                    | Statement::ExplainQueryPlan(_)
+                    | Statement::ExplainAnalyze(_)
+            )
+        {
+            return Ok(None);
+        }
+        self.interrupt.check()?;
+        self.check_file(statement.statement())?;
+        self.sweep(self.expiry.sweeps(&self.schema, statement.statement()))?;
+        let compiled = statement.compile(&self.schema, self.authorizer.as_ref())?;
+        // the subqueries execute_plan would fold are run by it
+        if planner::has_uncorrelated(&compiled.plan) {
+            return Ok(None);
+        }
+        Ok(compiled.program.clone())
+    }
+
+    /// A statement to bind values to and run with execute_prepared, as many times as
+    /// needed. Its parameters all start out NULL.
+    pub fn prepare(&mut self, sql: &str) -> Result<PreparedStatement> {
+        let statement = match self.statements.take(sql) {
+            Some(mut statement) => {
+                statement.clear_bindings();
+                statement
+            }
+            None => PreparedStatement::prepare(sql)?,
+        };
+        self.statements.put(sql, statement.clone());
+        Ok(statement)
+    }
+
+    /// Run a prepared statement with the values bound to it, reusing its plan and program
+    /// unless they have to be made again.
+    pub fn execute_prepared(&mut self, statement: &mut PreparedStatement) -> Result<RowSet> {
+        let _span = tracing::debug_span!("statement", sql = statement.sql()).entered();
+        let result = self.execute_prepared_uninterrupted(statement);
+        match &result {
+            Ok(rows) => tracing::debug!(rows = rows.rows.len(), "statement done"),
+            Err(err) => tracing::debug!(%err, "statement failed"),
+        }
+        // an interrupt stops one statement, see interrupt.rs
+        self.interrupt.clear();
+        result
+    }
+
+    fn execute_prepared_uninterrupted(
+        &mut self,
+        statement: &mut PreparedStatement,
+    ) -> Result<RowSet> {
+        self.interrupt.check()?;
+        self.check_file(statement.statement())?;
+        self.sweep(self.expiry.sweeps(&self.schema, statement.statement()))?;
+        if matches!(
+            statement.statement(),
+            Statement::Explain(_) | Statement::ExplainQueryPlan(_) | Statement::ExplainAnalyze(_)
+        ) {
+            let bound = statement.bound_statement();
+            if let Some(authorizer) = &self.authorizer {
+                authorizer.authorize(&bound, &self.schema)?;
+            }
+            return self.execute(&bound);
+        }
+        let sql = statement.sql().to_string();
+        let compiled = statement.compile(&self.schema, self.authorizer.as_ref())?;
+        self.execute_plan(&compiled.plan, compiled.program.as_ref(), Some(&sql))
+    }
+
+    pub fn execute(&mut self, statement: &Statement) -> Result<RowSet> {
+        self.check_file(statement)?;
+        self.sweep(self.expiry.sweeps(&self.schema, statement))?;
+        match statement {
+            Statement::Explain(statement) => {
+                let plan = planner::plan(statement, &self.schema)?;
+                // statements the machine can't run yet still show their plan
+                return match vdbe::compile(&plan, &self.schema)? {
+                    Some(program) => Ok(program.explain()),
+                    None => Ok(plan_tree(&plan)),
+                };
+            }
+            Statement::ExplainQueryPlan(statement) => {
+                return Ok(query_plan(&planner::plan(statement, &self.schema)?))
+            }
+            Statement::ExplainAnalyze(statement) => {
+                let plan = planner::plan(statement, &self.schema)?;
+                return self.explain_analyze(&plan);
+            }
+            _ => {}
+        }
+
+        let plan = planner::plan(statement, &self.schema)?;
+        self.execute_plan(&plan, None, None)
+    }
+
+    // Run a plan with its operators counted, and show what each did, see profile.rs.
+    fn explain_analyze(&mut self, plan: &Plan) -> Result<RowSet> {
+        // folded here rather than in execute_plan, so that the plan counted is the one run
+        let folded = planner::fold_uncorrelated(plan, &mut |select: &Statement| {
+            self.scalar_subquery(select)
+        })?;
+        let plan = folded.as_ref().unwrap_or(plan);
+        self.profile = Some(Profile::new(plan));
+        let start = Instant::now();
+        let result = self.execute_plan(plan, None, None);
+        let profile = self.profile.take().expect("set above");
+        let rows = result?;
+        // a write's operators that aren't run for their rows are counted as a whole
+        if profile.metrics(plan).is_some_and(|m| m.loops == 0) {
+            let count = rows
+                .changes
+                .map_or(rows.rows.len(), |changes| changes as usize);
+            profile.record(plan, count, start.elapsed());
+        }
+        Ok(profile.annotate(plan))
+    }
+
+    // Run a statement's plan, or for a query the program it compiled to if there is one.
+    // `sql` is the text the statement was parsed from, if it was.
+    fn execute_plan(
+        &mut self,
+        plan: &Plan,
+        program: Option<&Program>,
+        sql: Option<&str>,
+    ) -> Result<RowSet> {
+        let in_transaction = self.transactions.in_transaction();
+        if self.read_only && writes(plan) {
+            bail!("attempt to write a readonly database");
+        }
+        // an uncorrelated subquery is run once up front rather than for every row, which
+        // leaves a plan to be compiled afresh
+        let folded = planner::fold_uncorrelated(plan, &mut |select: &Statement| {
+            self.scalar_subquery(select)
+        })?;
+        let (plan, program) = match &folded {
+            Some(folded) => (folded, None),
+            None => (plan, program),
+        };
+        match plan {
+            // saved, unless in a transaction, by write_statement
+            Plan::Insert { .. } | Plan::Update { .. } | Plan::Delete { .. } => {
+                return self.write_statement(plan, &[]).map(changed)
+            }
+            Plan::Triggers { input, triggers } => {
+                return self.write_statement(input, triggers).map(changed)
+            }
+            Plan::CreateTable(_)
+            | Plan::CreateIndex(_)
+            | Plan::CreateView(_)
+            | Plan::CreateTrigger(_)
+            | Plan::CreateVirtualTable(_) => {
+                if self.create(plan)? {
+                    self.add_to_catalog(plan, sql)?;
+                    if let Plan::CreateTable(def) = plan {
+                        if def.autoincrement {
+                            self.create_sequence_table(database_of(&def.name).0)?;
+                        }
+                    }
+                }
+            }
+            Plan::Begin(mode) => {
+                // the group's commits so far are saved without this transaction's writes
+                if !in_transaction {
+                    self.save_group()?;
+                    self.lock_file(*mode)?;
+                }
+                self.transactions.begin()?
+            }
+            Plan::Commit => {
+                self.transactions.commit()?;
+            }
+            Plan::Rollback => {
+                self.transactions.rollback(&mut Contents {
+                    schema: &mut self.schema,
+                    storage: &mut self.storage,
+                })?;
+                if let Some(file) = &mut self.file {
+                    file.rollback()?;
+                }
+                // everything is as it was saved before BEGIN, so there is nothing to save,
+                // and a read that began before another connection committed ends cleanly
+                return Ok(RowSet::default());
+            }
+            Plan::Savepoint(name) => {
+                if !in_transaction {
+                    self.save_group()?;
+                }
+                self.transactions.savepoint(name);
+                if let Some(file) = &mut self.file {
+                    file.savepoint();
+                }
+            }
+            Plan::Release(name) => {
+                let level = self.transactions.release(name)?;
+                if let Some(file) = &mut self.file {
+                    file.release(level);
+                }
+            }
+            Plan::RollbackTo(name) => {
+                let level = self.transactions.rollback_to(
+                    name,
+                    &mut Contents {
+                        schema: &mut self.schema,
+                        storage: &mut self.storage,
+                    },
+                )?;
+                if let Some(file) = &mut self.file {
+                    file.rollback_to(level)?;
+                }
+            }
+            Plan::Attach { path, name } => self.attach(path, name, in_transaction)?,
+            Plan::Detach(name) => self.detach(name, in_transaction)?,
+            Plan::Pragma(pragma) if pragma.name == "stats" => {
+                return Ok(pragma::stats(&self.cache_report()));
+            }
+            Plan::Pragma(pragma) if pragma.name == "deterministic_output" => {
+                return pragma::flag(pragma, &mut self.deterministic);
+            }
+            Plan::Pragma(pragma) if pragma.name == "foreign_keys" => {
+                return pragma::flag(pragma, &mut self.foreign_keys);
+            }
+            Plan::Pragma(pragma) if pragma.name == "max_trigger_depth" => {
+                return pragma::limit(pragma, &mut self.nesting.limit);
+            }
+            Plan::Pragma(pragma) if pragma.name == "integrity_check" => {
+                let found = self.storage.check();
+                return Ok(pragma::integrity_check(&self.schema, found));
+            }
+            Plan::Pragma(pragma) => {
+                let temp_store = self.config.temp_store;
+                let replacement = self.config.replacement;
+                if pragma.name == "temp_store" && pragma.value.is_some() && in_transaction {
+                    bail!("temporary storage cannot be changed from within a transaction");
+                }
+                if pragma.name == "journal_mode" {
+                    self.change_journal_mode(pragma)?;
+                }
+                let file = &mut self.file;
+                let usage = || file_usage(file.as_mut());
+                let result = pragma::execute(pragma, &mut self.config, &self.schema, usage);
+                // as in SQLite, moving the temp database drops its tables
+                if self.config.temp_store != temp_store {
+                    self.drop_database(TEMP);
+                    self.temp_dir = None;
+                }
+                if self.config.replacement != replacement {
+                    self.replace_caches();
+                }
+                self.resize_caches();
+                self.set_busy_handler(self.config.busy.clone());
+                self.limit_files();
+                return result;
+            }
+            Plan::Analyze(name) => self.analyze(name.as_deref())?,
+            Plan::Reindex(name) => self.reindex(name.as_deref())?,
+            Plan::Vacuum => self.vacuum(in_transaction)?,
+            query => {
+                let mut rows = match program {
+                    Some(program) => self.run_program(program)?,
+                    None => self.query(query)?,
+                };
+                if self.deterministic && !planner::is_ordered(query) {
+                    rows.rows.sort();
+                }
+                return Ok(rows);
+            }
+        }
+        // a write outside a transaction, or the end of one, is saved. One that can't be
+        // as another connection has committed since main was loaded is lost, and main is
+        // loaded again.
+        if !self.transactions.in_transaction() {
+            if let Err(err) = self.save() {
+                self.load_changes()?;
+                return Err(err);
+            }
+        }
+        Ok(RowSet::default())
+    }
+
+    // Run a query's plan, compiled to bytecode if the machine can run it, unless its
+    // operators are being counted.
+    fn query(&self, plan: &Plan) -> Result<RowSet> {
+        if self.profile.is_none() {
+            if let Some(program) = vdbe::compile(plan, &self.schema)? {
+                return self.run_program(&program);
+            }
+        }
+        let rows = self.run(plan)?;
+        Ok(RowSet {
+            columns: rows.columns,
+            rows: rows.rows.into_iter().map(|r| r.values).collect(),
+            changes: None,
+        })
+    }
+
+    // Whether a query produces a row at all. The virtual machine stops at the first row it
+    // makes, though a plan it can't run is still run to the end.
+    fn any_rows(&self, plan: &Plan) -> Result<bool> {
+        if self.profile.is_none() {
+            if let Some(program) = vdbe::compile(plan, &self.schema)? {
+                let first = Machine::new(Cow::Owned(program), self).next();
+                return Ok(first.transpose()?.is_some());
+            }
+        }
+        Ok(!self.run(plan)?.rows.is_empty())
+    }
+
+    // The value of a scalar subquery, the first column of its first row or NULL without one.
+    fn scalar_subquery(&self, select: &Statement) -> Result<ColVal> {
+        let rows = self.query(&planner::plan(select, &self.schema)?)?;
+        if rows.columns.len() != 1 {
+            bail!(
+                "sub-select returns {} columns - expected 1",
+                rows.columns.len()
+            );
+        }
+        let first = rows.rows.into_iter().next();
+        Ok(first.map_or(ColVal::Null, |mut row| row.swap_remove(0)))
+    }
+
+    fn run_program(&self, program: &Program) -> Result<RowSet> {
+        Ok(RowSet {
+            columns: program.columns.clone(),
+            rows: program.run(self)?,
+            changes: None,
+        })
+    }
+
+    fn table_def(&self, table: &str) -> Result<CreateTable> {
+        self.schema.table(table).cloned().ok_or_else(|| {
+            anyhow!(SqlError::NoSuchTable {
+                table: table.to_string()
+            })
+        })
+    }
+
+    fn context<'e>(
+        &'e self,
+        table: Option<&'e str>,
+        columns: &'e [String],
+        values: &'e [ColVal],
+    ) -> RowContext<'e> {
+        RowContext {
+            executor: self,
+            table,
+            columns,
+            values,
+            rowid: None,
+        }
+    }
+
+    // The context of a row read from a table, whose rowid it can read too.
+    fn row_context<'e>(
+        &'e self,
+        table: Option<&'e str>,
+        columns: &'e [String],
+        row: &'e Row,
+    ) -> RowContext<'e> {
+        let rowid = match row.key {
+            Some(RowKey::RowId(rowid)) => Some(rowid),
+            _ => None,
+        };
+        RowContext {
+            rowid,
+            ..self.context(table, columns, &row.values)
+        }
+    }
+
+    // Work out the generated columns of `row`, going into `table` under `key`.
+    fn generate(
+        &self,
+        table: &str,
+        def: &CreateTable,
+        key: &RowKey,
+        row: &mut [ColVal],
+    ) -> Result<()> {
+        let columns = def.column_names();
+        let rowid = match key {
+            RowKey::RowId(rowid) => Some(*rowid),
+            _ => None,
+        };
+        generated::fill(def, row, |expr, values| {
+            let ctx = RowContext {
+                rowid,
+                ..self.context(Some(table), &columns, values)
+            };
+            eval::eval(expr, &ctx)
+        })
+    }
+
+    // Refuse `row`, going into `table` under `key`, if it has a NULL in a NOT NULL column
+    // or one of the table's CHECKs fails.
+    fn check_constraints(
+        &self,
+        table: &str,
+        def: &CreateTable,
+        key: &RowKey,
+        row: &[ColVal],
+    ) -> Result<()> {
+        if def.checks.is_empty() && !def.columns.iter().any(|c| c.not_null) {
+            return Ok(());
+        }
+        let columns = def.column_names();
+        let rowid = match key {
+            RowKey::RowId(rowid) => Some(*rowid),
+            _ => None,
+        };
+        check::verify(def, row, |expr, values| {
+            let ctx = RowContext {
+                rowid,
+                ..self.context(Some(table), &columns, values)
+            };
+            eval::eval(expr, &ctx)
+        })
+    }
+
+    // Create the table, index, view or trigger of a CREATE statement's plan, returning
+    // false if IF NOT EXISTS found it already there.
+    fn create(&mut self, plan: &Plan) -> Result<bool> {
+        let name = match plan {
+            Plan::CreateTable(table) => Some(&table.name),
+            Plan::CreateIndex(index) => Some(&index.name),
+            _ => None,
+        };
+        if let Some((Some(database), _)) = name.map(|name| database_of(name)) {
+            if database == TEMP {
+                self.create_temp_database()?;
+            } else if self.databases.get(database).is_none() {
+                bail!("unknown database {database}");
+            }
+        }
+        let (created, kind, name) = match plan {
+            Plan::CreateTable(table) => (self.create_table(table)?, EntryKind::Table, &table.name),
+            Plan::CreateIndex(index) => (self.create_index(index)?, EntryKind::Index, &index.name),
+            Plan::CreateView(view) => (self.schema.create_view(view)?, EntryKind::View, &view.name),
+            Plan::CreateTrigger(trigger) => (
+                self.schema.create_trigger(trigger)?,
+                EntryKind::Trigger,
+                &trigger.name,
+            ),
+            Plan::CreateVirtualTable(table) => (
+                self.schema.create_virtual_table(table)?,
+                EntryKind::Table,
+                &table.name,
+            ),
+            _ => unreachable!("only a CREATE statement creates anything"),
+        };
+        // so that ROLLBACK undoes it, schema and all
+        if created {
+            self.transactions.record(Undo::Create {
+                kind,
+                name: name.clone(),
+            });
+        }
+        Ok(created)
+    }
+
+    // Add the rows for what a CREATE statement made to the sqlite_master of its database,
+    // giving a table or index the next free root page.
+    fn add_to_catalog(&mut self, plan: &Plan, sql: Option<&str>) -> Result<()> {
+        let (kind, name, table, statement) = match plan {
+            Plan::CreateTable(def) => (
+                EntryKind::Table,
+                &def.name,
+                &def.name,
+                Statement::CreateTable(def.clone()),
+            ),
+            Plan::CreateIndex(def) => (
+                EntryKind::Index,
+                &def.name,
+                &def.table,
+                Statement::CreateIndex(def.clone()),
+            ),
+            Plan::CreateView(def) => (
+                EntryKind::View,
+                &def.name,
+                &def.name,
+                Statement::CreateView(def.clone()),
+            ),
+            Plan::CreateTrigger(def) => (
+                EntryKind::Trigger,
+                &def.name,
+                &def.table,
+                Statement::CreateTrigger(def.clone()),
+            ),
+            // a table as far as SQLite is concerned, but one with no pages
+            Plan::CreateVirtualTable(def) => (
+                EntryKind::Table,
+                &def.name,
+                &def.name,
+                Statement::CreateVirtualTable(def.clone()),
+            ),
+            _ => unreachable!("only a CREATE statement creates anything"),
+        };
+        let (database, _) = database_of(name);
+        let unqualified = |name: &str| database_of(name).1.to_string();
+        let mut stored = catalog::stored_sql(&statement, sql);
+        if let Some(database) = database {
+            stored = catalog::unqualified_sql(&stored, database);
+        }
+        let mut entries = vec![CatalogEntry {
+            kind,
+            name: unqualified(name),
+            table: unqualified(table),
+            root_page: 0,
+            sql: Some(stored),
+        }];
+        // the index enforcing a new table's primary key comes with it
+        if kind == EntryKind::Table {
+            entries.extend(
+                self.schema
+                    .table_indexes(name)
+                    .into_iter()
+                    .map(|index| CatalogEntry {
+                        kind: EntryKind::Index,
+                        name: unqualified(&index.name),
+                        table: unqualified(&index.table),
+                        root_page: 0,
+                        sql: None,
+                    }),
+            );
+        }
+
+        let master = qualified(database, catalog::MASTER_TABLE);
+        let mut next_page = self.next_root_page(&master)?;
+        let paged = !matches!(plan, Plan::CreateVirtualTable(_));
+        for mut entry in entries {
+            if paged && matches!(entry.kind, EntryKind::Table | EntryKind::Index) {
+                entry.root_page = next_page;
+                next_page += 1;
+            }
+            self.add_catalog_row(&master, entry.row())?;
+        }
+        Ok(())
+    }
+
+    fn add_catalog_row(&mut self, master: &str, mut row: Vec<ColVal>) -> Result<()> {
+        let key = self.storage.table(master)?.key_for(&mut row, None)?;
+        self.storage.insert(master, &key, row)?;
+        self.transactions.record(Undo::Insert {
+            table: master.to_string(),
+            key,
+        });
+        Ok(())
+    }
+
+    // The page after the last one given to a table or index of the database whose
+    // sqlite_master is `master`.
+    fn next_root_page(&self, master: &str) -> Result<PageNumber> {
+        let last = self
+            .storage
+            .table(master)?
+            .rows()
+            .iter()
+            .filter_map(|row| match row.values[3] {
+                ColVal::Int(page) => Some(page),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(catalog::MASTER_ROOT_PAGE.into());
+        Ok(last.max(catalog::MASTER_ROOT_PAGE.into()) as PageNumber + 1)
+    }
+
+    // Recreate the schema of `database`, main if None, from its sqlite_master's rows, the
+    // names of an attached database's tables and indexes qualified by its own.
+    fn load_database_catalog(
+        &mut self,
+        database: Option<&str>,
+        rows: &[Vec<ColVal>],
+    ) -> Result<()> {
+        let master = qualified(database, catalog::MASTER_TABLE);
+        for row in rows {
+            let entry = CatalogEntry::from_row(row)?;
+            if let Some(sql) = &entry.sql {
+                let mut statement = sql_parser::parse(&format!("{sql};"))?;
+                if let Some(database) = database {
+                    match &mut statement {
+                        Statement::CreateTable(table) => {
+                            table.name = qualified(Some(database), &table.name);
+                        }
+                        Statement::CreateIndex(index) => {
+                            index.name = qualified(Some(database), &index.name);
+                            index.table = qualified(Some(database), &index.table);
+                        }
+                        _ => bail!(
+                            "views and triggers in attached databases are not supported yet: {sql}"
+                        ),
+                    }
+                }
+                let plan = planner::plan(&statement, &self.schema)?;
+                match &plan {
+                    Plan::CreateTable(_)
+                    | Plan::CreateIndex(_)
+                    | Plan::CreateView(_)
+                    | Plan::CreateTrigger(_) => {
+                        self.create(&plan)?;
+                    }
+                    Plan::CreateVirtualTable(table) => self.schema.load_virtual_table(table)?,
+                    _ => bail!("malformed database schema ({}): {sql}", entry.name),
+                }
+            }
+            self.add_catalog_row(&master, entry.row())?;
+        }
+        Ok(())
+    }
+
+    fn create_table(&mut self, def: &CreateTable) -> Result<bool> {
+        for column in &def.columns {
+            if let Some(collation) = &column.collation {
+                self.collations.get(collation)?;
+            }
+            if let Some(generated) = &column.generated {
+                self.functions
+                    .check_deterministic(&generated.expr, DeterministicContext::GeneratedColumn)?;
+            }
+        }
+        for check in &def.checks {
+            self.functions
+                .check_deterministic(check, DeterministicContext::CheckConstraint)?;
+        }
+        let table = if def.without_rowid {
+            Table::Clustered(ClusteredTable::create(
+                def,
+                &self.collations,
+                self.config.node_keys(),
+            )?)
+        } else {
+            Table::Rowid(RowidTable::create(def, self.config.node_keys()))
+        };
+        if !self.schema.create_table(def)? {
+            return Ok(false);
+        }
+        self.storage.tables.insert(def.name.clone(), table);
+        // the index that enforces the primary key, if the table has one
+        for index in self.schema.table_indexes(&def.name) {
+            let index = SecondaryIndex::create(
+                &index,
+                &def.columns,
+                &self.functions,
+                &self.collations,
+                self.config.node_keys(),
+            )?;
+            self.storage.indexes.insert(index.name.clone(), index);
+        }
+        Ok(true)
+    }
+
+    fn create_index(&mut self, def: &CreateIndex) -> Result<bool> {
+        if !self.schema.check_index(def)? {
+            return Ok(false);
+        }
+        let index = self.build_index(def)?;
+        self.schema.create_index(def)?;
+        self.storage.indexes.insert(def.name.clone(), index);
+        Ok(true)
+    }
+
+    // Build an index over the rows its table has now, see SecondaryIndex::build.
+    fn build_index(&self, def: &CreateIndex) -> Result<SecondaryIndex> {
+        let columns = &self.table_def(&def.table)?.columns;
+        let rows = self
+            .storage
+            .table(&def.table)?
+            .iter()
+            .map(|row| (row.key.expect("stored rows have keys"), row.values));
+        SecondaryIndex::build(
+            def,
+            columns,
+            &self.functions,
+            &self.collations,
+            self.config.node_keys(),
+            rows,
+            self.config.memory_budget(),
+        )
+    }
+
+    // Rebuild indexes from their tables' rows as CREATE INDEX builds them: with None every
+    // index, else those of the collation, table or index named, looked for in that order
+    // as SQLite looks. The entries only come out different if the index had fallen out of
+    // step with its table, or one of its collations has been replaced since it was built.
+    fn reindex(&mut self, name: Option<&str>) -> Result<()> {
+        let names: Vec<String> = match name {
+            None => self.storage.indexes.keys().cloned().collect(),
+            Some(name) if self.collations.get(name).is_ok() => self
+                .storage
+                .indexes
+                .values()
+                .filter(|index| {
+                    index
+                        .collations()
+                        .iter()
+                        .any(|c| c.name.eq_ignore_ascii_case(name))
+                })
+                .map(|index| index.name.clone())
+                .collect(),
+            Some(name) if self.schema.table(name).is_some() => self.storage.index_names(name),
+            Some(name) if self.storage.indexes.contains_key(name) => vec![name.to_string()],
+            Some(_) => bail!("unable to identify the object to be reindexed"),
+        };
+        for name in names {
+            let def = self
+                .schema
+                .index(&name)
+                .expect("an index's definition")
+                .clone();
+            let index = self.build_index(&def)?;
+            self.storage.indexes.insert(name, index);
+        }
+        Ok(())
+    }
+
+    // Refuse a row too big for any chain of overflow pages the database has room for.
+    fn check_row_size(&self, row: &[ColVal]) -> Result<()> {
+        overflow::check_row_size(record::record_size(row), self.config.max_row_size())
+    }
+
+    // Rebuild every table and index from its entries, leaving its nodes full, its leaves in
+    // key order, and none of the pages merges emptied. SQLite's VACUUM copies the database
+    // into a new file and swaps it in; each tree here is built whole before it replaces the
+    // old one, and building one can't fail part way. Like SQLite's it can't run inside a
+    // transaction, as it would have nothing to roll back to.
+    fn vacuum(&mut self, in_transaction: bool) -> Result<()> {
+        if in_transaction {
+            bail!("cannot VACUUM from within a transaction");
+        }
+        self.storage.rebuild();
+        Ok(())
+    }
+
+    // Make sqlite_sequence in `database`, main if None, unless it is there already,
+    // declared as SQLite declares it.
+    fn create_sequence_table(&mut self, database: Option<&str>) -> Result<()> {
+        let table = qualified(database, catalog::SEQUENCE_TABLE);
+        let sql = format!("CREATE TABLE IF NOT EXISTS {table}(name,seq);");
+        let plan = planner::plan(&sql_parser::parse(&sql)?, &self.schema)?;
+        self.execute_plan(&plan, None, Some(&sql))?;
+        Ok(())
+    }
+
+    // The key and seq of the row of sqlite_sequence for the AUTOINCREMENT table `table`,
+    // None if it has never had a row.
+    fn sequence(&self, table: &str) -> Result<Option<(RowKey, RowId)>> {
+        let (database, name) = database_of(table);
+        let sequences = qualified(database, catalog::SEQUENCE_TABLE);
+        for row in self.storage.table(&sequences)?.rows() {
+            if let [ColVal::String(of), seq] = row.values.as_slice() {
+                if of == name {
+                    let seq = match seq {
+                        ColVal::Int(seq) => *seq,
+                        _ => 0,
+                    };
+                    return Ok(Some((row.key.expect("a table's rows have keys"), seq)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Skip an AUTOINCREMENT table past the largest rowid sqlite_sequence says it has had.
+    fn follow_sequence(&mut self, def: &CreateTable) -> Result<()> {
+        if !def.autoincrement {
+            return Ok(());
+        }
+        let Some((_, seq)) = self.sequence(&def.name)? else {
+            return Ok(());
+        };
+        if let Some(Table::Rowid(table)) = self.storage.tables.get_mut(&def.name) {
+            table.skip_past(seq);
+        }
+        Ok(())
+    }
+
+    // Write an AUTOINCREMENT table's largest rowid to sqlite_sequence, if it is larger
+    // than what is there.
+    fn advance_sequence(&mut self, def: &CreateTable) -> Result<()> {
+        if !def.autoincrement {
+            return Ok(());
+        }
+        let Table::Rowid(table) = self.storage.table(&def.name)? else {
+            unreachable!("an AUTOINCREMENT table has rowids");
+        };
+        let largest = table.largest_rowid();
+        let (database, name) = database_of(&def.name);
+        let sequences = qualified(database, catalog::SEQUENCE_TABLE);
+        let row = vec![ColVal::String(name.to_string()), ColVal::Int(largest)];
+        match self.sequence(&def.name)? {
+            Some((_, seq)) if seq >= largest => {}
+            Some((key, _)) => {
+                let (new_key, old) = self.storage.update(&sequences, &key, row)?;
+                self.transactions.record(Undo::Delete {
+                    table: sequences.clone(),
+                    key,
+                    row: old,
+                });
+                self.transactions.record(Undo::Insert {
+                    table: sequences,
+                    key: new_key,
+                });
+            }
+            None => {
+                let mut row = row;
+                let key = self.storage.table(&sequences)?.key_for(&mut row, None)?;
+                self.storage.insert(&sequences, &key, row)?;
+                self.transactions.record(Undo::Insert {
+                    table: sequences,
+                    key,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Measure the tables named, or every table, into sqlite_stat1 and sqlite_stat4 and the
+    // statistics the planner reads, replacing what was measured of them before.
+    fn analyze(&mut self, name: Option<&str>) -> Result<()> {
+        let tables: Vec<String> = match name {
+            None => self
+                .schema
+                .table_names()
+                .into_iter()
+                .filter(|t| self.schema.table(t).is_some() && !t.starts_with("sqlite_"))
+                .map(str::to_string)
+                .collect(),
+            Some(name) if self.schema.table(name).is_some() => vec![name.to_string()],
+            Some(name) => match self.schema.index(name) {
+                Some(index) => vec![index.table.clone()],
+                None => bail!(SqlError::NoSuchTable {
+                    table: name.to_string()
+                }),
+            },
+        };
+        for sql in [
+            "CREATE TABLE IF NOT EXISTS sqlite_stat1 (tbl, idx, stat);",
+            "CREATE TABLE IF NOT EXISTS sqlite_stat4 (tbl, idx, neq, nlt, sample);",
+        ] {
+            self.execute_sql(sql)?;
+        }
+
+        for table in tables {
+            for stats_table in ["sqlite_stat1", "sqlite_stat4"] {
+                self.execute(&Statement::Delete {
+                    from_table: stats_table.to_string(),
+                    where_clause: Some(Expr::Binary {
+                        op: BinaryOp::Eq,
+                        left: Box::new(Expr::Column("tbl".to_string())),
+                        right: Box::new(Expr::Literal(ColVal::String(table.clone()))),
+                    }),
+                    order_by: vec![],
+                    limit: None,
+                })?;
+            }
+
+            let rows = self.storage.table(&table)?.rows().len() as u64;
+            let mut stats = TableStats {
+                rows,
+                ..Default::default()
+            };
+            let mut stat1 = vec![];
+            let mut stat4 = vec![];
+            for name in self.storage.index_names(&table) {
+                let (rows_per_key, samples) = self.storage.indexes[&name].analyze(ANALYZE_SAMPLES);
+                if rows_per_key.is_empty() {
+                    continue;
+                }
+                let stat = std::iter::once(rows)
+                    .chain(rows_per_key.iter().copied())
+                    .map(|n| n.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                stat1.push(vec![ColVal::String(name.clone()), ColVal::String(stat)]);
+                for sample in &samples {
+                    stat4.push(vec![
+                        ColVal::String(name.clone()),
+                        ColVal::Int(sample.eq as i64),
+                        ColVal::Int(sample.lt as i64),
+                        sample.value.clone(),
+                    ]);
+                }
+                stats.rows_per_key.insert(name.clone(), rows_per_key);
+                stats.samples.insert(name, samples);
+            }
+            // like SQLite, a table with rows but no index to measure gets a row of its own
+            if stat1.is_empty() && rows > 0 {
+                stat1.push(vec![ColVal::Null, ColVal::String(rows.to_string())]);
+            }
+            for (stats_table, columns, rows) in [
+                ("sqlite_stat1", &["idx", "stat"][..], stat1),
+                ("sqlite_stat4", &["idx", "neq", "nlt", "sample"][..], stat4),
+            ] {
+                for row in rows {
+                    let values = std::iter::once(ColVal::String(table.clone())).chain(row);
+                    self.execute(&Statement::Insert {
+                        into_table: stats_table.to_string(),
+                        columns: std::iter::once("tbl")
+                            .chain(columns.iter().copied())
+                            .zip(values)
+                            .map(|(column, value)| NewColumnVal {
+                                column_name: column.to_string(),
+                                value: Expr::Literal(value),
+                            })
+                            .collect(),
+                    })?;
+                }
+            }
+            self.schema.set_table_stats(&table, stats);
+        }
+        Ok(())
+    }
+
+    // Run a plan that reads rows, every kind of plan other than a write or a statement
+    // that changes the schema or transaction, counting what it did under EXPLAIN ANALYZE.
+    fn run(&self, plan: &Plan) -> Result<Rows> {
+        let Some(profile) = &self.profile else {
+            return self.run_operator(plan);
+        };
+        let start = Instant::now();
+        let rows = self.run_operator(plan)?;
+        profile.record(plan, rows.rows.len(), start.elapsed());
+        Ok(rows)
+    }
+
+    fn run_operator(&self, plan: &Plan) -> Result<Rows> {
+        self.interrupt.check()?;
+        Ok(match plan {
+            Plan::Scan { table } => Rows {
+                table: Some(table.clone()),
+                columns: self.schema.table_columns(table)?,
+                rows: self.storage.table(table)?.rows(),
+            },
+            Plan::ConstantRow => Rows {
+                table: None,
+                columns: vec![],
+                rows: vec![Row {
+                    key: None,
+                    values: vec![],
+                }],
+            },
+            Plan::VirtualScan {
+                table,
+                number,
+                values,
+            } => {
+                let Some(virtual_table) = self.schema.virtual_table(table) else {
+                    bail!(SqlError::NoSuchTable {
+                        table: table.to_string()
+                    });
+                };
+                let ctx = self.context(None, &[], &[]);
+                let values = values
+                    .iter()
+                    .map(|value| eval::eval(value, &ctx))
+                    .collect::<Result<Vec<_>>>()?;
+                let rows = vtab::scan(virtual_table.as_ref(), *number, &values, &self.interrupt)?;
+                Rows {
+                    table: Some(table.clone()),
+                    columns: vtab::all_columns(virtual_table.as_ref()),
+                    rows: rows
+                        .map(|values| values.map(|values| Row { key: None, values }))
+                        .collect::<Result<_>>()?,
+                }
+            }
+            Plan::IndexSearch {
+                table,
+                index,
+                seeks,
+                range,
+                ..
+            } => {
+                let Some(index) = self.storage.indexes.get(index) else {
+                    bail!(SqlError::NoSuchIndex {
+                        index: index.to_string()
+                    });
+                };
+                let stored = self.storage.table(table)?;
+                let mut rows = vec![];
+                // the planner makes the seeks distinct, so no row is found twice
+                let (lower, upper) = match range {
+                    Some(range) => (range.lower.as_ref(), range.upper.as_ref()),
+                    None => (Bound::Unbounded, Bound::Unbounded),
+                };
+                for seek in seeks {
+                    for key in index.rows_in_range(seek, lower, upper) {
+                        self.interrupt.check_row(rows.len())?;
+                        let values = stored.get(&key).expect("indexed rows exist");
+                        rows.push(Row {
+                            key: Some(key),
+                            values,
+                        });
+                    }
+                }
+                Rows {
+                    table: Some(table.clone()),
+                    columns: self.schema.table_columns(table)?,
+                    rows,
+                }
+            }
+            Plan::Filter { input, predicate } => {
+                let mut rows = self.run(input)?;
+                let mut kept = vec![];
+                for (i, row) in std::mem::take(&mut rows.rows).into_iter().enumerate() {
+                    self.interrupt.check_row(i)?;
+                    let ctx = self.row_context(rows.table.as_deref(), &rows.columns, &row);
+                    if eval::is_true(predicate, &ctx)? {
+                        kept.push(row);
+                    }
+                }
+                rows.rows = kept;
+                rows
+            }
+            Plan::SemiJoin {
+                outer,
+                inner,
+                keys,
+                anti,
+                algorithm,
+            } => {
+                let mut outer = self.run(outer)?;
+                let inner = self.run(inner)?;
+                let outer_at = positions(&outer.columns, keys.iter().map(|k| &k.outer))?;
+                let inner_at = positions(&inner.columns, keys.iter().map(|k| &k.inner))?;
+                // each pair of keys compared as `outer = inner` would compare them
+                let affinity = |rows: &Rows, column: &str| {
+                    self.schema.column_affinity(rows.table.as_deref()?, column)
+                };
+                let affinities: Vec<Option<Affinity>> = keys
+                    .iter()
+                    .map(|k| {
+                        eval::comparison_affinity(
+                            affinity(&outer, &k.outer),
+                            affinity(&inner, &k.inner),
+                        )
+                    })
+                    .collect();
+                let (outer_keys, inner_keys) = (
+                    join_keys(&outer.rows, &outer_at, &affinities),
+                    join_keys(&inner.rows, &inner_at, &affinities),
+                );
+                let matched = match algorithm {
+                    JoinAlgorithm::Hash => {
+                        let budget = self.config.memory_budget();
+                        join::hash_semi_join(&outer_keys, &inner_keys, budget)
+                    }
+                    JoinAlgorithm::Merge => join::merge_semi_join(&outer_keys, &inner_keys),
+                };
+                let mut matched = matched.into_iter();
+                outer
+                    .rows
+                    .retain(|_| matched.next().expect("a key per row") != *anti);
+                outer
+            }
+            Plan::Join { tables } => {
+                // the pairs so far, from the one row of no columns every row of the first
+                // table pairs with
+                let mut joined = Rows {
+                    table: None,
+                    columns: vec![],
+                    rows: vec![Row {
+                        key: None,
+                        values: vec![],
+                    }],
+                };
+                // the table the rows of each table joined so far are rows of, by its name
+                let mut sources: Vec<(&str, Option<String>)> = vec![];
+                for JoinTable {
+                    name,
+                    rows,
+                    keys,
+                    algorithm,
+                } in tables
+                {
+                    let inner = self.run(rows)?;
+                    let outer_at = positions(&joined.columns, keys.iter().map(|k| &k.outer))?;
+                    let inner_at = positions(&inner.columns, keys.iter().map(|k| &k.inner))?;
+                    let affinities: Vec<Option<Affinity>> = keys
+                        .iter()
+                        .map(|k| {
+                            let (outer_name, column) =
+                                k.outer.split_once('.').expect("a qualified column");
+                            let outer = sources
+                                .iter()
+                                .find(|(name, _)| *name == outer_name)
+                                .and_then(|(_, table)| table.as_deref());
+                            eval::comparison_affinity(
+                                self.schema.column_affinity(outer?, column),
+                                self.schema
+                                    .column_affinity(inner.table.as_deref()?, &k.inner),
+                            )
+                        })
+                        .collect();
+                    let (outer_keys, inner_keys) = (
+                        join_keys(&joined.rows, &outer_at, &affinities),
+                        join_keys(&inner.rows, &inner_at, &affinities),
+                    );
+                    let pairs = match algorithm {
+                        JoinAlgorithm::Hash => {
+                            let budget = self.config.memory_budget();
+                            join::hash_join(&outer_keys, &inner_keys, budget)
+                        }
+                        JoinAlgorithm::Merge => join::merge_join(&outer_keys, &inner_keys),
+                    };
+                    let mut rows = Vec::with_capacity(pairs.len());
+                    for (n, (o, i)) in pairs.into_iter().enumerate() {
+                        self.interrupt.check_row(n)?;
+                        let values = joined.rows[o].values.iter().chain(&inner.rows[i].values);
+                        rows.push(Row {
+                            key: None,
+                            values: values.cloned().collect(),
+                        });
+                    }
+                    joined.rows = rows;
+                    joined
+                        .columns
+                        .extend(inner.columns.iter().map(|c| format!("{name}.{c}")));
+                    sources.push((name, inner.table));
+                }
+                joined
+            }
+            Plan::Project { input, columns } => {
+                let input = self.run(input)?;
+                let mut sources = vec![];
+                for column in columns {
+                    sources.push(match input.columns.iter().position(|c| c == column) {
+                        Some(i) => ResultSource::Column(i),
+                        None if planner::is_rowid(column, &input.columns) => ResultSource::Rowid,
+                        None => ResultSource::Expr(sql_parser::parse_expr(column)?),
+                    });
+                }
+                let rows = input
+                    .rows
+                    .iter()
+                    .enumerate()
+                    .map(|(i, row)| {
+                        self.interrupt.check_row(i)?;
+                        let values = sources
+                            .iter()
+                            .zip(columns)
+                            .map(|(source, column)| match (source, &row.key) {
+                                (ResultSource::Column(i), _) => Ok(row.values[*i].clone()),
+                                (ResultSource::Rowid, Some(RowKey::RowId(rowid))) => {
+                                    Ok(ColVal::Int(*rowid))
+                                }
+                                // a WITHOUT ROWID table's row, or one of a view
+                                (ResultSource::Rowid, _) => Err(anyhow!(SqlError::NoSuchColumn {
+                                    column: column.to_string()
+                                })),
+                                (ResultSource::Expr(expr), _) => {
+                                    let ctx = self.row_context(
+                                        input.table.as_deref(),
+                                        &input.columns,
+                                        row,
+                                    );
+                                    eval::eval(expr, &ctx)
+                                }
+                            })
+                            .collect::<Result<_>>()?;
+                        Ok(Row { key: None, values })
+                    })
+                    .collect::<Result<_>>()?;
+                Rows {
+                    table: None,
+                    columns: columns.iter().map(|c| planner::column_name(c)).collect(),
+                    rows,
+                }
+            }
+            Plan::Aggregate {
+                input,
+                aggregates,
+                group_by,
+            } => {
+                let input = self.run(input)?;
+                let columns = group_by
+                    .iter()
+                    .map(|e| e.to_string())
+                    .chain(aggregates.iter().map(|a| a.to_string()))
+                    .collect();
+                if group_by.is_empty() {
+                    let values = aggregate::aggregate(
+                        aggregates,
+                        &input.columns,
+                        input.rows.into_iter().map(|row| Ok(row.values)),
+                    )?;
+                    return Ok(Rows {
+                        table: None,
+                        columns,
+                        rows: vec![Row { key: None, values }],
+                    });
+                }
+                // each group's rows under the values of its terms, which lead its row
+                let mut groups: BTreeMap<Vec<ColVal>, Vec<Vec<ColVal>>> = BTreeMap::new();
+                for (i, row) in input.rows.iter().enumerate() {
+                    self.interrupt.check_row(i)?;
+                    let ctx = self.row_context(input.table.as_deref(), &input.columns, row);
+                    let key = group_by
+                        .iter()
+                        .map(|e| eval::eval(e, &ctx))
+                        .collect::<Result<Vec<ColVal>>>()?;
+                    groups.entry(key).or_default().push(row.values.clone());
+                }
+                let rows = groups
+                    .into_iter()
+                    .map(|(mut values, rows)| {
+                        values.extend(aggregate::aggregate(
+                            aggregates,
+                            &input.columns,
+                            rows.into_iter().map(Ok),
+                        )?);
+                        Ok(Row { key: None, values })
+                    })
+                    .collect::<Result<_>>()?;
+                Rows {
+                    table: None,
+                    columns,
+                    rows,
+                }
+            }
+            Plan::Window { input, windows } => {
+                let input = self.run(input)?;
+                let ctx = self.context(input.table.as_deref(), &input.columns, &[]);
+                let rows = window::window(
+                    windows,
+                    &input.columns,
+                    |column| eval::ordering_collation(&Expr::Column(column.to_string()), &ctx),
+                    input
+                        .rows
+                        .into_iter()
+                        .map(|row| (row.key, row.values))
+                        .collect(),
+                )?;
+                Rows {
+                    table: input.table.clone(),
+                    columns: input
+                        .columns
+                        .iter()
+                        .cloned()
+                        .chain(windows.iter().map(|w| w.to_string()))
+                        .collect(),
+                    rows: rows
+                        .into_iter()
+                        .map(|(key, values)| Row { key, values })
+                        .collect(),
+                }
+            }
+            Plan::Sort { input, order_by } => {
+                let mut rows = self.run(input)?;
+                let ctx = self.context(rows.table.as_deref(), &rows.columns, &[]);
+                let collations = order_by
+                    .iter()
+                    .map(|term| eval::ordering_collation(&term.expr, &ctx))
+                    .collect::<Result<Vec<Collation>>>()?;
+                // a stable sort, rows that compare equal stay in the order they were read
+                let mut sorter = Sorter::new(
+                    self.config.memory_budget(),
+                    |(a, _): &SortRecord, (b, _): &SortRecord| {
+                        order_by
+                            .iter()
+                            .zip(&collations)
+                            .zip(a.iter().zip(b))
+                            .map(|((term, collation), (a, b))| {
+                                let ordering = collation.compare(a, b);
+                                if term.descending {
+                                    ordering.reverse()
+                                } else {
+                                    ordering
+                                }
+                            })
+                            .find(|o| *o != Ordering::Equal)
+                            .unwrap_or(Ordering::Equal)
+                    },
+                );
+                for (i, row) in std::mem::take(&mut rows.rows).into_iter().enumerate() {
+                    self.interrupt.check_row(i)?;
+                    let ctx = self.row_context(rows.table.as_deref(), &rows.columns, &row);
+                    let key = order_by
+                        .iter()
+                        .map(|term| eval::eval(&term.expr, &ctx))
+                        .collect::<Result<Vec<ColVal>>>()?;
+                    sorter.push((key, (row.values, row.key)))?;
+                }
+                rows.rows = sorter
+                    .finish()?
+                    .map(|record| record.map(|(_, (values, key))| Row { key, values }))
+                    .collect::<Result<_>>()?;
+                rows
+            }
+            Plan::Limit { input, limit } => {
+                let mut rows = self.run(input)?;
+                let (count, offset) = self.limit(limit)?;
+                rows.rows = std::mem::take(&mut rows.rows)
+                    .into_iter()
+                    .skip(offset)
+                    .take(count)
+                    .collect();
+                rows
+            }
+            Plan::Compound {
+                operator,
+                left,
+                right,
+            } => {
+                let (left, right) = (self.run(left)?, self.run(right)?);
+                let values = |rows: Rows| rows.rows.into_iter().map(|row| row.values);
+                Rows {
+                    table: None,
+                    columns: left.columns.clone(),
+                    rows: compound::combine(*operator, values(left), values(right))
+                        .into_iter()
+                        .map(|values| Row { key: None, values })
+                        .collect(),
+                }
+            }
+            Plan::View { columns, input, .. } | Plan::Cte { columns, input, .. } => {
+                let rows = self.run(input)?;
+                Rows {
+                    table: None,
+                    columns: columns.clone(),
+                    rows: rows.rows,
+                }
+            }
+            other => unreachable!("{other:?} doesn't produce rows"),
+        })
+    }
+
+    // LIMIT's count and OFFSET. A negative count means no limit, as in SQLite.
+    fn limit(&self, limit: &Limit) -> Result<(usize, usize)> {
+        let ctx = self.context(None, &[], &[]);
+        let number = |expr: &Expr| -> Result<i64> {
+            match eval::eval(expr, &ctx)? {
+                ColVal::Int(n) => Ok(n),
+                ColVal::Boolean(b) => Ok(b as i64),
+                _ => bail!(SqlError::DatatypeMismatch),
+            }
+        };
+        let count = number(&limit.count)?;
+        let offset = match &limit.offset {
+            Some(offset) => number(offset)?,
+            None => 0,
+        };
+        Ok((
+            usize::try_from(count).unwrap_or(usize::MAX),
+            usize::try_from(offset).unwrap_or(0),
+        ))
+    }
+
+    // Run an INSERT, UPDATE or DELETE, firing `triggers` for each row it writes, all or
+    // nothing, in a savepoint of its own that is rolled back if any of its rows, or any
+    // of the triggers they fire, fails. The user's own statement, rather than one a
+    // trigger or a foreign key action runs, then checks its foreign keys. Outside a
+    // transaction the write is saved before the savepoint goes, so that one that can't
+    // be, as another connection has the file locked, is undone as well, see
+    // storage/busy.rs. Returns how many rows it changed, which are counted once it has.
+    fn write_statement(&mut self, plan: &Plan, triggers: &[CreateTrigger]) -> Result<u64> {
+        let autocommit = !self.transactions.in_transaction();
+        // a write saved as it ends takes the RESERVED lock on main's file before it reads
+        // a row, waiting for another connection's as the busy handler says, so that none
+        // can commit between what it read and its save, see storage/image.rs
+        let reserved = autocommit && !self.transactions.grouping();
+        if reserved {
+            self.lock_file(TransactionMode::Immediate)?;
+        }
+        self.transactions.savepoint(STATEMENT_SAVEPOINT);
+        let mut result = self.write(plan, triggers);
+        if self.nesting.depth() == 0 {
+            let checks = std::mem::take(&mut self.foreign_key_checks);
+            result = result.and_then(|changes| {
+                for check in checks {
+                    self.foreign_key_step(check)?;
+                }
+                Ok(changes)
+            });
+        }
+        if autocommit {
+            result = result.and_then(|changes| self.save().map(|()| changes));
+        }
+        if result.is_err() {
+            self.transactions.rollback_to(
+                STATEMENT_SAVEPOINT,
+                &mut Contents {
+                    schema: &mut self.schema,
+                    storage: &mut self.storage,
+                },
+            )?;
+            if let (true, Some(file)) = (reserved, &mut self.file) {
+                file.rollback()?;
+            }
+        }
+        self.transactions.release(STATEMENT_SAVEPOINT)?;
+        let changes = result?;
+        self.changes = changes;
+        self.total_changes += changes;
+        Ok(changes)
+    }
+
+    // Write the rows of an INSERT, UPDATE or DELETE, returning how many it changed.
+    fn write(&mut self, plan: &Plan, triggers: &[CreateTrigger]) -> Result<u64> {
+        let mut changes = 0;
+        match plan {
+            Plan::Insert {
+                table,
+                columns,
+                values,
+            } => {
+                let def = self.table_def(table)?;
+                generated::check_writes(&def, columns, "INSERT into")?;
+                let mut row = vec![];
+                let ctx = self.context(None, &[], &[]);
+                for column in &def.columns {
+                    let value = match columns.iter().position(|c| *c == column.name) {
+                        Some(i) => eval::eval(&values[i], &ctx)?,
+                        None => column.default.clone().unwrap_or(ColVal::Null),
+                    };
+                    row.push(Affinity::of(column.type_name.as_deref()).apply(value));
+                }
+
+                self.fire(triggers, TriggerTiming::Before, &def, None, Some(&row))?;
+                self.follow_sequence(&def)?;
+                let key = self.storage.table(table)?.key_for(&mut row, None)?;
+                self.generate(table, &def, &key, &mut row)?;
+                self.check_constraints(table, &def, &key, &row)?;
+                self.check_row_size(&row)?;
+                self.storage.insert(table, &key, row.clone())?;
+                self.transactions.record(Undo::Insert {
+                    table: table.clone(),
+                    key: key.clone(),
+                });
+                self.advance_sequence(&def)?;
+                if let RowKey::RowId(rowid) = key {
+                    self.last_insert_rowid = rowid;
+                }
+                changes += 1;
+                self.foreign_keys(&def, None, Some(&row))?;
+                self.fire(triggers, TriggerTiming::After, &def, None, Some(&row))?;
+            }
+            Plan::Update {
+                input,
+                table,
+                assignments,
+            } => {
+                let def = self.table_def(table)?;
+                let assigned = assignments.iter().map(|a| &a.column_name);
+                generated::check_writes(&def, assigned, "UPDATE")?;
+                let columns = def.column_names();
+                let rows = self.run(input)?;
+                // every new row is worked out from the table as it was before the update
+                let mut updates = vec![];
+                for (i, row) in rows.rows.into_iter().enumerate() {
+                    self.interrupt.check_row(i)?;
+                    let ctx = self.row_context(Some(table), &columns, &row);
+                    let mut new = row.values.clone();
+                    for assignment in assignments {
+                        let i = positions(&columns, [&assignment.column_name])?[0];
+                        let affinity = Affinity::of(def.columns[i].type_name.as_deref());
+                        new[i] = affinity.apply(eval::eval(&assignment.value, &ctx)?);
+                    }
+                    let key = row.key.expect("rows read from a table have keys");
+                    self.generate(table, &def, &key, &mut new)?;
+                    self.check_constraints(table, &def, &key, &new)?;
+                    updates.push((key, row.values, new));
+                }
+
+                for (i, (key, old, new)) in updates.into_iter().enumerate() {
+                    self.interrupt.check_row(i)?;
+                    self.fire(
+                        triggers,
+                        TriggerTiming::Before,
+                        &def,
+                        Some(&old),
+                        Some(&new),
+                    )?;
+                    self.check_row_size(&new)?;
+                    let (new_key, old) = self.storage.update(table, &key, new)?;
+                    let new = self.storage.table(table)?.get(&new_key).unwrap();
+                    self.transactions.record(Undo::Delete {
+                        table: table.clone(),
+                        key,
+                        row: old.clone(),
+                    });
+                    self.transactions.record(Undo::Insert {
+                        table: table.clone(),
+                        key: new_key,
+                    });
+                    changes += 1;
+                    self.foreign_keys(&def, Some(&old), Some(&new))?;
+                    self.fire(triggers, TriggerTiming::After, &def, Some(&old), Some(&new))?;
+                }
+            }
+            Plan::Delete { input, table } => {
+                let def = self.table_def(table)?;
+                for (i, row) in self.run(input)?.rows.into_iter().enumerate() {
+                    self.interrupt.check_row(i)?;
+                    self.fire(
+                        triggers,
+                        TriggerTiming::Before,
+                        &def,
+                        Some(&row.values),
+                        None,
+                    )?;
+                    let key = row.key.expect("rows read from a table have keys");
+                    // a BEFORE trigger may have deleted it already
+                    if let Some(old) = self.storage.delete(table, &key) {
+                        self.transactions.record(Undo::Delete {
+                            table: table.clone(),
+                            key,
+                            row: old,
+                        });
+                        changes += 1;
+                        self.foreign_keys(&def, Some(&row.values), None)?;
+                    }
+                    self.fire(
+                        triggers,
+                        TriggerTiming::After,
+                        &def,
+                        Some(&row.values),
+                        None,
+                    )?;
+                }
+            }
+            other => unreachable!("{other:?} is not a write"),
+        }
+        Ok(changes)
+    }
+
+    // Carry out the foreign key actions of a row written to `table`, the parent, and
+    // leave the checks of the keys it is in for the end of the user's statement, if
+    // foreign keys are on.
+    fn foreign_keys(
+        &mut self,
+        table: &CreateTable,
+        old: Option<&[ColVal]>,
+        new: Option<&[ColVal]>,
+    ) -> Result<()> {
+        if !self.foreign_keys {
+            return Ok(());
+        }
+        let columns = table.column_names();
+        let row = TriggerRow {
+            columns: &columns,
+            old,
+            new,
+        };
+        let checks = foreign_key::parent_checks(&self.schema, table, &row)?;
+        self.foreign_key_checks.extend(checks);
+        for step in foreign_key::actions(&self.schema, &table.name, &row)? {
+            match step {
+                ForeignKeyStep::Check {
+                    immediate: false, ..
+                } => self.foreign_key_checks.push(step),
+                step => self.foreign_key_step(step)?,
+            }
+        }
+        Ok(())
+    }
+
+    // Check a foreign key, or write to the child rows of a parent row, a level deeper.
+    fn foreign_key_step(&mut self, step: ForeignKeyStep) -> Result<()> {
+        let _level = self.nesting.enter()?;
+        let met = match step {
+            ForeignKeyStep::Check { select, .. } => self.execute(&select)?.rows.is_empty(),
+            ForeignKeyStep::Parent(select) => !self.execute(&select)?.rows.is_empty(),
+            ForeignKeyStep::Write(write) => {
+                self.execute(&write)?;
+                true
+            }
+        };
+        if !met {
+            bail!(SqlError::ForeignKeyConstraint);
+        }
+        Ok(())
+    }
+
+    // Run the triggers with the given timing for one row.
+    fn fire(
+        &mut self,
+        triggers: &[CreateTrigger],
+        timing: TriggerTiming,
+        table: &CreateTable,
+        old: Option<&[ColVal]>,
+        new: Option<&[ColVal]>,
+    ) -> Result<()> {
+        let columns = table.column_names();
+        let row = TriggerRow {
+            columns: &columns,
+            old,
+            new,
+        };
+        for trigger in triggers.iter().filter(|t| t.timing == timing) {
+            let body = trigger::instantiate(trigger, &row, &self.context(None, &[], &[]))?;
+            let _level = self.nesting.enter()?;
+            for statement in body.into_iter().flatten() {
+                self.execute(&statement)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// What an INSERT, UPDATE or DELETE that changed `changes` rows returns.
+fn changed(changes: u64) -> RowSet {
+    RowSet {
+        changes: Some(changes),
+        ..RowSet::default()
+    }
+}
+
+// How many pages main's file has and how many of them are free, none for a database in
+// memory.
+fn file_usage(file: Option<&mut DatabaseFile>) -> Result<FileUsage> {
+    let Some(file) = file else {
+        return Ok(FileUsage::default());
+    };
+    let header = file.header()?;
+    Ok(FileUsage {
+        pages: header.page_count,
+        free: header.freelist_count,
+    })
+}
+
+// Whether running `plan` changes the database.
+fn writes(plan: &Plan) -> bool {
+    matches!(
+        plan,
+        Plan::Insert { .. }
+            | Plan::Update { .. }
+            | Plan::Delete { .. }
+            | Plan::Triggers { .. }
+            | Plan::CreateTable(_)
+            | Plan::CreateIndex(_)
+            | Plan::CreateView(_)
+            | Plan::CreateTrigger(_)
+            | Plan::CreateVirtualTable(_)
+            | Plan::Reindex(_)
+    )
+}
+
+// The database a table or index is in, None for main, and its name within it.
+fn database_of(name: &str) -> (Option<&str>, &str) {
+    match name.split_once('.') {
+        Some((database, name)) => (Some(database), name),
+        None => (None, name),
+    }
+}
+
+// The name a table or index of `database`, main if None, is known by.
+fn qualified(database: Option<&str>, name: &str) -> String {
+    match database {
+        Some(database) => format!("{database}.{name}"),
+        None => name.to_string(),
+    }
+}
+
+// The rows of the image of `database`, main if None, its sqlite_master first and every
+// name as it is within the database.
+fn image(storage: &Storage, schema: &Schema, database: Option<&str>) -> Vec<ImageRow> {
+    let tables = storage
+        .tables
+        .iter()
+        .filter(|(name, _)| database_of(name).0 == database)
+        .map(|(name, table)| (database_of(name).1, table));
+    let (catalog, tables): (Vec<_>, Vec<_>) =
+        tables.partition(|(name, _)| *name == catalog::MASTER_TABLE);
+    let mut rows: Vec<ImageRow> = vec![];
+    for (name, table) in catalog.into_iter().chain(tables) {
+        let def = schema.table(&qualified(database, name));
+        for row in table.rows() {
+            let key = match row.key {
+                Some(RowKey::RowId(rowid)) => ColVal::Int(rowid),
+                _ => ColVal::Null,
+            };
+            let values = match def {
+                Some(def) => generated::stored(def, row.values),
+                None => row.values,
+            };
+            rows.push((name.to_string(), key, values));
+        }
+    }
+    rows
+}
+
+// The plan's operator tree, a row per line, for what EXPLAIN can't compile.
+fn plan_tree(plan: &Plan) -> RowSet {
+    RowSet {
+        columns: vec!["plan".to_string()],
+        rows: plan
+            .to_string()
+            .lines()
+            .map(|line| vec![ColVal::String(line.to_string())])
+            .collect(),
+        changes: None,
+    }
+}
+
+// The plan as EXPLAIN QUERY PLAN shows it, a row per line.
+fn query_plan(plan: &Plan) -> RowSet {
+    RowSet {
+        columns: vec!["detail".to_string()],
+        rows: plan
+            .query_plan()
+            .into_iter()
+            .map(|line| vec![ColVal::String(line)])
+            .collect(),
+        changes: None,
+    }
+}
+
+impl vdbe::Database for Executor {
+    fn table_rows(&self, table: &str) -> Result<Box<dyn Iterator<Item = CursorRow> + '_>> {
+        Ok(Box::new(self.storage.table(table)?.iter().map(
+            |row| match row.key {
+                Some(RowKey::RowId(rowid)) => (Some(rowid), row.values),
+                _ => (None, row.values),
+            },
+        )))
+    }
+
+    fn index_rows(
+        &self,
+        table: &str,
+        index: &str,
+        key: &[ColVal],
+        lower: Bound<&ColVal>,
+        upper: Bound<&ColVal>,
+    ) -> Result<Box<dyn Iterator<Item = CursorRow> + '_>> {
+        let Some(index) = self.storage.indexes.get(index) else {
+            bail!(SqlError::NoSuchIndex {
+                index: index.to_string()
+            });
+        };
+        let stored = self.storage.table(table)?;
+        Ok(Box::new(
+            index
+                .rows_in_range(key, lower, upper)
+                .into_iter()
+                .map(move |key| {
+                    let row = stored.get(&key).expect("indexed rows exist");
+                    match key {
+                        RowKey::RowId(rowid) => (Some(rowid), row),
+                        RowKey::PrimaryKey(_) => (None, row),
+                    }
+                }),
+        ))
+    }
+
+    fn virtual_rows(
+        &self,
+        table: &str,
+        number: i64,
+        values: &[ColVal],
+    ) -> Result<Box<dyn Iterator<Item = Result<CursorRow>> + '_>> {
+        let Some(virtual_table) = self.schema.borrowed_virtual_table(table) else {
+            bail!(SqlError::NoSuchTable {
+                table: table.to_string()
+            });
+        };
+        let rows = vtab::scan(virtual_table, number, values, &self.interrupt)?;
+        Ok(Box::new(
+            rows.map(|values| values.map(|values| (None, values))),
+        ))
+    }
+
+    fn eval(
+        &self,
+        expr: &Expr,
+        table: Option<&str>,
+        columns: &[String],
+        row: &[ColVal],
+        rowid: Option<RowId>,
+    ) -> Result<ColVal> {
+        let ctx = RowContext {
+            rowid,
+            ..self.context(table, columns, row)
+        };
+        eval::eval(expr, &ctx)
+    }
+
+    fn check_interrupt(&self) -> Result<()> {
+        self.interrupt.check()
+    }
+}
+
+// Where each of `names` is among `columns`.
+fn positions<'n>(
+    columns: &[String],
+    names: impl IntoIterator<Item = &'n String>,
+) -> Result<Vec<usize>> {
+    names
+        .into_iter()
+        .map(|name| {
+            columns.iter().position(|c| c == name).ok_or_else(|| {
+                anyhow!(SqlError::NoSuchColumn {
+                    column: name.to_string()
+                })
+            })
+        })
+        .collect()
+}
+
+// The key of each row for a join, the values of the columns at `at` compared as
+// `affinities` say, or None if one is NULL.
+fn join_keys(rows: &[Row], at: &[usize], affinities: &[Option<Affinity>]) -> Vec<join::Key> {
+    rows.iter()
+        .map(|row| {
+            let key: Vec<ColVal> = at
+                .iter()
+                .zip(affinities)
+                .map(|(i, affinity)| match affinity {
+                    Some(affinity) => affinity.apply(row.values[*i].clone()),
+                    None => row.values[*i].clone(),
+                })
+                .collect();
+            (!key.contains(&ColVal::Null)).then_some(key)
+        })
+        .collect()
+}
+
+// Evaluates expressions over one row, or over no row at all when there are no columns.
+struct RowContext<'e> {
+    executor: &'e Executor,
+    table: Option<&'e str>,
+    columns: &'e [String],
+    values: &'e [ColVal],
+    // the row's rowid, when it is a row of a rowid table
+    rowid: Option<RowId>,
+}
+
+impl RowContext<'_> {
+    // A subquery with the columns it refers to in this row replaced by their values, so
+    // it can be run on its own. Name resolution has qualified them with the name this
+    // row's table is known by, which isn't kept in the plan but for a join's, so those of
+    // a single table are recognised by their column name alone.
+    fn bind_outer(&self, select: &Statement) -> Statement {
+        let mut select = select.clone();
+        select.walk_exprs_mut(&mut |e| {
+            if let Expr::QualifiedColumn { table, column } = e {
+                let qualified = format!("{table}.{column}");
+                let at = self.columns.iter().position(|c| *c == qualified);
+                if let Some(i) = at.or_else(|| self.columns.iter().position(|c| c == column)) {
+                    *e = Expr::Literal(self.values[i].clone());
+                }
+            }
+        });
+        select
+    }
+}
+
+impl EvalContext for RowContext<'_> {
+    fn column(&self, name: &str) -> Result<ColVal> {
+        match self.columns.iter().position(|c| c == name) {
+            Some(i) => Ok(self.values[i].clone()),
+            None if self.rowid.is_some() && planner::is_rowid(name, self.columns) => {
+                Ok(ColVal::Int(self.rowid.unwrap()))
+            }
+            None => bail!(SqlError::NoSuchColumn {
+                column: name.to_string()
+            }),
+        }
+    }
+
+    // a column of a joined row, see Plan::Join
+    fn qualified_column(&self, table: &str, column: &str) -> Result<ColVal> {
+        let name = format!("{table}.{column}");
+        match self.columns.iter().position(|c| *c == name) {
+            Some(i) => Ok(self.values[i].clone()),
+            None => bail!(SqlError::NoSuchColumn { column: name }),
+        }
+    }
+
+    fn subquery(&self, select: &Statement) -> Result<Vec<Vec<ColVal>>> {
+        let _level = self.executor.nesting.enter()?;
+        let plan = planner::plan(&self.bind_outer(select), &self.executor.schema)?;
+        Ok(self.executor.query(&plan)?.rows)
+    }
+
+    fn exists(&self, select: &Statement) -> Result<bool> {
+        let _level = self.executor.nesting.enter()?;
+        let plan = planner::plan(&self.bind_outer(select), &self.executor.schema)?;
+        self.executor.any_rows(&plan)
+    }
+
+    fn scalar_subquery(&self, select: &Statement) -> Result<ColVal> {
+        let _level = self.executor.nesting.enter()?;
+        self.executor.scalar_subquery(&self.bind_outer(select))
+    }
+
+    fn call(&self, name: &str, args: &[ColVal]) -> Result<ColVal> {
+        // the connection's rather than something worked out from the arguments
+        if args.is_empty() {
+            let state = match name.to_ascii_lowercase().as_str() {
+                "last_insert_rowid" => Some(self.executor.last_insert_rowid),
+                "changes" => Some(self.executor.changes as i64),
+                "total_changes" => Some(self.executor.total_changes as i64),
+                _ => None,
+            };
+            if let Some(value) = state {
+                return Ok(ColVal::Int(value));
+            }
+        }
+        self.executor.functions.call(name, args)
+    }
+
+    fn column_collation(&self, table: Option<&str>, column: &str) -> Option<String> {
+        // a qualified column is another table's, bound to a value before we get here
+        if table.is_some() {
+            return None;
+        }
+        let def = self.executor.schema.table(self.table?)?;
+        def.columns
+            .iter()
+            .find(|c| c.name == column)?
+            .collation
+            .clone()
+    }
+
+    fn column_affinity(&self, table: Option<&str>, column: &str) -> Option<Affinity> {
+        if table.is_some() {
+            return None;
+        }
+        self.executor.schema.column_affinity(self.table?, column)
+    }
+
+    fn collation(&self, name: &str) -> Result<Collation> {
+        self.executor.collations.get(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(executor: &mut Executor, sql: &str) -> Vec<Vec<ColVal>> {
+        executor.execute_sql(sql).unwrap().rows
+    }
+
+    fn executor_with(statements: &[&str]) -> Executor {
+        let mut executor = Executor::default();
+        for sql in statements {
+            executor.execute_sql(sql).expect(sql);
+        }
+        executor
+    }
+
+    fn text(s: &str) -> ColVal {
+        ColVal::String(s.to_string())
+    }
+
+    #[test]
+    fn an_executor_can_move_to_another_thread() {
+        let mut db = executor_with(&["CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT);"]);
+        db = std::thread::spawn(move || {
+            run(&mut db, "INSERT INTO t (name) VALUES (\"amy\");");
+            db
+        })
+        .join()
+        .unwrap();
+        assert_eq!(run(&mut db, "SELECT name FROM t;"), vec![vec![text("amy")]]);
+    }
+
+    #[test]
+    fn rows_written_can_be_read_back() {
+        let mut db = executor_with(&[
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, age INTEGER);",
+            "INSERT INTO users (name, age) VALUES (\"amy\", 30);",
+            "INSERT INTO users (name, age) VALUES (\"bob\", 21);",
+            "INSERT INTO users (id, name) VALUES (10, \"cat\");",
+        ]);
+        let result = db.execute_sql("SELECT * FROM users;").unwrap();
+        assert_eq!(result.columns, ["id", "name", "age"]);
+        assert_eq!(result.to_string(), "1|amy|30\n2|bob|21\n10|cat|");
+
+        assert_eq!(
+            run(&mut db, "SELECT name FROM users WHERE age > 25;"),
+            [[text("amy")]]
+        );
+        run(
+            &mut db,
+            "UPDATE users SET age = age + 1 WHERE name = \"bob\";",
+        );
+        run(&mut db, "DELETE FROM users WHERE id = 10;");
+        assert_eq!(
+            run(&mut db, "SELECT id, age FROM users;"),
+            [
+                [ColVal::Int(1), ColVal::Int(30)],
+                [ColVal::Int(2), ColVal::Int(22)]
+            ]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT COUNT(*), MAX(age) FROM users;"),
+            [[ColVal::Int(2), ColVal::Int(30)]]
+        );
+        assert_eq!(
+            db.execute_sql("INSERT INTO users (id, name) VALUES (1, \"dan\");")
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: users.id"
+        );
+        assert_eq!(
+            db.execute_sql("SELECT name FROM nobody;")
+                .unwrap_err()
+                .to_string(),
+            "no such table: nobody"
+        );
+    }
+
+    #[test]
+    fn blobs_are_stored_as_they_are_and_sort_after_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut db = open();
+        run(&mut db, "CREATE TABLE t (a TEXT);");
+        run(&mut db, "CREATE INDEX t_a ON t (a);");
+        run(&mut db, "INSERT INTO t (a) VALUES (X'00FF');");
+        run(&mut db, "INSERT INTO t (a) VALUES ('z');");
+        run(&mut db, "INSERT INTO t (a) VALUES (CAST('hi' AS BLOB));");
+        drop(db);
+
+        // a TEXT column leaves a blob a blob, and the file keeps it
+        let mut db = open();
+        assert_eq!(
+            run(&mut db, "SELECT a, typeof(a) FROM t ORDER BY a;"),
+            [
+                [text("z"), text("text")],
+                [ColVal::Blob(vec![0, 0xff]), text("blob")],
+                [ColVal::Blob(b"hi".to_vec()), text("blob")],
+            ]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT count(*) FROM t WHERE a = X'00ff';"),
+            [[ColVal::Int(1)]]
+        );
+    }
+
+    #[test]
+    fn indexes_are_kept_in_step_and_used() {
+        let mut db = executor_with(&[
+            "CREATE TABLE users (email TEXT, age INTEGER);",
+            "INSERT INTO users (email, age) VALUES (\"a@x\", 30);",
+            "CREATE UNIQUE INDEX idx_email ON users (email);",
+            "INSERT INTO users (email, age) VALUES (\"b@x\", 21);",
+        ]);
+        let explain = db
+            .execute_sql("EXPLAIN QUERY PLAN SELECT age FROM users WHERE email = \"b@x\";")
+            .unwrap();
+        assert_eq!(
+            explain.to_string(),
+            "QUERY PLAN\n`--SEARCH users USING INDEX idx_email (email=?)"
+        );
+        assert_eq!(
+            run(&mut db, "SELECT age FROM users WHERE email = \"b@x\";"),
+            [[ColVal::Int(21)]]
+        );
+
+        // a refused write leaves the table and its indexes as they were
+        assert_eq!(
+            db.execute_sql("INSERT INTO users (email, age) VALUES (\"a@x\", 5);")
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: users.email"
+        );
+        assert!(db
+            .execute_sql("UPDATE users SET email = \"a@x\" WHERE age = 21;")
+            .is_err());
+        assert_eq!(
+            run(&mut db, "SELECT COUNT(*) FROM users;"),
+            [[ColVal::Int(2)]]
+        );
+
+        run(&mut db, "UPDATE users SET email = \"c@x\" WHERE age = 21;");
+        assert!(run(&mut db, "SELECT age FROM users WHERE email = \"b@x\";").is_empty());
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT age FROM users WHERE email IN (\"a@x\", \"c@x\");"
+            ),
+            [[ColVal::Int(30)], [ColVal::Int(21)]]
+        );
+    }
+
+    #[test]
+    fn vacuum_keeps_every_row_and_index_entry() {
+        let mut db = executor_with(&[
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT);",
+            "CREATE INDEX idx_name ON t (name);",
+            "CREATE TABLE w (k INTEGER PRIMARY KEY, v TEXT) WITHOUT ROWID;",
+        ]);
+        for i in 0..200 {
+            run(
+                &mut db,
+                &format!("INSERT INTO t (name) VALUES (\"n{}\");", i % 7),
+            );
+            run(
+                &mut db,
+                &format!("INSERT INTO w (k, v) VALUES ({i}, \"v\");"),
+            );
+        }
+        run(&mut db, "DELETE FROM t WHERE id > 20;");
+        run(&mut db, "DELETE FROM w WHERE k > 10;");
+        let queries = [
+            "SELECT id FROM t WHERE name = \"n3\";",
+            "SELECT * FROM t;",
+            "SELECT * FROM w;",
+        ];
+        let before: Vec<_> = queries.iter().map(|sql| run(&mut db, sql)).collect();
+        run(&mut db, "VACUUM;");
+        let after: Vec<_> = queries.iter().map(|sql| run(&mut db, sql)).collect();
+        assert_eq!(after, before);
+        assert_eq!(
+            before[0],
+            [[ColVal::Int(4)], [ColVal::Int(11)], [ColVal::Int(18)]]
+        );
+        // rowids carry on from where they were
+        run(&mut db, "INSERT INTO t (name) VALUES (\"new\");");
+        assert_eq!(
+            run(&mut db, "SELECT id FROM t WHERE name = \"new\";"),
+            [[ColVal::Int(201)]]
+        );
+
+        run(&mut db, "BEGIN;");
+        assert_eq!(
+            db.execute_sql("VACUUM;").unwrap_err().to_string(),
+            "cannot VACUUM from within a transaction"
+        );
+    }
+
+    #[test]
+    fn reindex_rebuilds_indexes_from_their_tables() {
+        let mut db = Executor::default();
+        db.collations
+            .create_collation("backwards", |a: &str, b: &str| b.cmp(a));
+        for sql in [
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT);",
+            "CREATE INDEX idx_name ON t (name COLLATE backwards);",
+            "CREATE INDEX idx_id ON t (id);",
+            "INSERT INTO t (name) VALUES (\"a\");",
+            "INSERT INTO t (name) VALUES (\"c\");",
+            "INSERT INTO t (name) VALUES (\"b\");",
+        ] {
+            run(&mut db, sql);
+        }
+        let names = |db: &Executor| -> Vec<ColVal> {
+            db.storage.indexes["idx_name"]
+                .entries()
+                .into_iter()
+                .map(|entry| entry[0].clone())
+                .collect()
+        };
+        assert_eq!(names(&db), [text("c"), text("b"), text("a")]);
+
+        // an index keeps the collation it was built with until it is rebuilt
+        db.collations
+            .create_collation("backwards", |a: &str, b: &str| a.cmp(b));
+        run(&mut db, "INSERT INTO t (name) VALUES (\"d\");");
+        assert_eq!(names(&db), [text("d"), text("c"), text("b"), text("a")]);
+        run(&mut db, "REINDEX backwards;");
+        assert_eq!(names(&db), [text("a"), text("b"), text("c"), text("d")]);
+
+        // and an index that has fallen out of step with its table is put right
+        let ok = [[text("ok")]];
+        let row = db.storage.table("t").unwrap().get(&RowKey::RowId(2));
+        for index in ["idx_name", "idx_id"] {
+            let index = db.storage.indexes.get_mut(index).unwrap();
+            index.on_delete(&RowKey::RowId(2), row.as_ref().unwrap());
+        }
+        assert_ne!(run(&mut db, "PRAGMA integrity_check;"), ok);
+        run(&mut db, "REINDEX idx_name;");
+        assert_ne!(run(&mut db, "PRAGMA integrity_check;"), ok);
+        run(&mut db, "REINDEX t;");
+        assert_eq!(run(&mut db, "PRAGMA integrity_check;"), ok);
+        run(&mut db, "REINDEX;");
+        assert_eq!(
+            run(&mut db, "SELECT id FROM t WHERE name = \"c\";"),
+            [[ColVal::Int(2)]]
+        );
+
+        assert_eq!(
+            db.execute_sql("REINDEX nope;").unwrap_err().to_string(),
+            "unable to identify the object to be reindexed"
+        );
+    }
+
+    #[test]
+    fn indexes_keep_in_step_with_their_tables() {
+        let mut db = executor_with(&[
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, age INTEGER);",
+            "CREATE UNIQUE INDEX idx_email ON users (email COLLATE NOCASE);",
+            "CREATE INDEX idx_age_email ON users (age, email);",
+        ]);
+        let ok = [[ColVal::String("ok".to_string())]];
+        let statements = [
+            "INSERT INTO users (email, age) VALUES (\"a@x\", 30);",
+            "INSERT INTO users (email, age) VALUES (\"b@x\", 40);",
+            "INSERT INTO users (email, age) VALUES (NULL, 40);",
+            "INSERT INTO users (id, email, age) VALUES (10, \"c@x\", NULL);",
+            "UPDATE users SET age = age + 1 WHERE age > 35;",
+            "UPDATE users SET email = \"B@Y\" WHERE email = \"b@x\";",
+            // moving a row to another rowid moves its index entries with it
+            "UPDATE users SET id = 20 WHERE id = 10;",
+            "DELETE FROM users WHERE id = 20;",
+            "BEGIN;",
+            "INSERT INTO users (email, age) VALUES (\"d@x\", 50);",
+            "UPDATE users SET age = 0;",
+            "DELETE FROM users WHERE email = \"a@x\";",
+            "ROLLBACK;",
+        ];
+        for sql in statements {
+            run(&mut db, sql);
+            assert_eq!(run(&mut db, "PRAGMA integrity_check;"), ok, "after {sql}");
+        }
+        // writes that one index refuses leave the table and every other index as they were
+        for sql in [
+            "INSERT INTO users (email, age) VALUES (\"A@X\", 99);",
+            "UPDATE users SET email = \"a@X\" WHERE id = 2;",
+            "UPDATE users SET email = \"same\";",
+        ] {
+            assert!(db.execute_sql(sql).is_err(), "{sql} should fail");
+            assert_eq!(run(&mut db, "PRAGMA integrity_check;"), ok, "after {sql}");
+        }
+        assert_eq!(
+            run(&mut db, "SELECT id, email, age FROM users;"),
+            [
+                [
+                    ColVal::Int(1),
+                    ColVal::String("a@x".to_string()),
+                    ColVal::Int(30)
+                ],
+                [
+                    ColVal::Int(2),
+                    ColVal::String("B@Y".to_string()),
+                    ColVal::Int(41)
+                ],
+                [ColVal::Int(3), ColVal::Null, ColVal::Int(41)],
+            ]
+        );
+
+        // and an index that has somehow fallen out of step is reported
+        let row = db.storage.table("users").unwrap().get(&RowKey::RowId(1));
+        let index = db.storage.indexes.get_mut("idx_age_email").unwrap();
+        index.on_delete(&RowKey::RowId(1), &row.unwrap());
+        assert_eq!(
+            run(&mut db, "PRAGMA integrity_check;"),
+            [
+                [ColVal::String(
+                    "row 1 missing from index idx_age_email".to_string()
+                )],
+                [ColVal::String(
+                    "wrong # of entries in index idx_age_email".to_string()
+                )],
+            ]
+        );
+    }
+
+    #[test]
+    fn rowids_can_be_selected_and_filtered_on() {
+        let mut db = executor_with(&[
+            "CREATE TABLE t (name TEXT);",
+            "CREATE TABLE p (id INTEGER PRIMARY KEY, name TEXT);",
+            "CREATE TABLE r (rowid TEXT, name TEXT);",
+            "CREATE TABLE w (k INTEGER PRIMARY KEY, v TEXT) WITHOUT ROWID;",
+        ]);
+        for name in ["a", "b", "c"] {
+            run(
+                &mut db,
+                &format!("INSERT INTO t (name) VALUES (\"{name}\");"),
+            );
+            run(
+                &mut db,
+                &format!("INSERT INTO p (name) VALUES (\"{name}\");"),
+            );
+        }
+        run(&mut db, "DELETE FROM t WHERE name = \"b\";");
+        let text = |s: &str| ColVal::String(s.to_string());
+        assert_eq!(
+            run(&mut db, "SELECT rowid, * FROM t;"),
+            [[ColVal::Int(1), text("a")], [ColVal::Int(3), text("c")]]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT name FROM t WHERE _rowid_ = 3;"),
+            [[text("c")]]
+        );
+        // an INTEGER PRIMARY KEY is another name for the rowid
+        assert_eq!(
+            run(&mut db, "SELECT oid, id FROM p WHERE rowid > 1;"),
+            [
+                [ColVal::Int(2), ColVal::Int(2)],
+                [ColVal::Int(3), ColVal::Int(3)]
+            ]
+        );
+        run(&mut db, "UPDATE p SET name = \"z\" WHERE rowid = 2;");
+        assert_eq!(
+            run(&mut db, "SELECT name FROM p WHERE id = 2;"),
+            [[text("z")]]
+        );
+
+        // a column of that name wins over the rowid
+        run(
+            &mut db,
+            "INSERT INTO r (rowid, name) VALUES (\"mine\", \"x\");",
+        );
+        assert_eq!(
+            run(&mut db, "SELECT rowid, oid FROM r;"),
+            [[text("mine"), ColVal::Int(1)]]
+        );
+        assert_eq!(
+            db.execute_sql("SELECT rowid FROM w;")
+                .unwrap_err()
+                .to_string(),
+            "no such column: rowid"
+        );
+    }
+
+    #[test]
+    fn analyze_measures_tables_for_the_planner() {
+        let mut db = executor_with(&[
+            "CREATE TABLE users (email TEXT, age INTEGER);",
+            "CREATE INDEX idx_age ON users (age);",
+            "CREATE TABLE empty (x INTEGER);",
+        ]);
+        for i in 0..100 {
+            let age = if i == 0 { 10 } else { 30 };
+            run(
+                &mut db,
+                &format!("INSERT INTO users (email, age) VALUES (\"{i}@x\", {age});"),
+            );
+        }
+        let sql = "EXPLAIN QUERY PLAN SELECT email FROM users WHERE age > 20;";
+        let plan = |db: &mut Executor| db.execute_sql(sql).unwrap().to_string();
+        // without statistics a range is guessed to keep a quarter of the rows
+        assert!(plan(&mut db).contains("SEARCH"));
+
+        run(&mut db, "ANALYZE;");
+        let text = |s: &str| ColVal::String(s.to_string());
+        assert_eq!(
+            run(&mut db, "SELECT * FROM sqlite_stat1;"),
+            [[text("users"), text("idx_age"), text("100 50")]]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT neq, nlt, sample FROM sqlite_stat4;"),
+            [
+                [ColVal::Int(1), ColVal::Int(0), ColVal::Int(10)],
+                [ColVal::Int(99), ColVal::Int(1), ColVal::Int(30)]
+            ]
+        );
+        // but the samples show it keeps all but one
+        assert!(plan(&mut db).contains("SCAN"));
+
+        // analyzing again replaces what was measured before
+        run(&mut db, "DELETE FROM users WHERE age = 30;");
+        run(&mut db, "ANALYZE idx_age;");
+        assert_eq!(
+            run(&mut db, "SELECT stat FROM sqlite_stat1;"),
+            [[text("1 1")]]
+        );
+        assert_eq!(
+            db.execute_sql("ANALYZE nope;").unwrap_err().to_string(),
+            "no such table: nope"
+        );
+    }
+
+    #[test]
+    fn sqlite_master_keeps_the_schema() {
+        let schema = [
+            "CREATE TABLE IF NOT EXISTS users (email TEXT PRIMARY KEY,  age INTEGER);",
+            "CREATE INDEX  idx_age ON users (age);",
+            "CREATE VIEW adults AS SELECT email FROM users WHERE age >= 18;",
+            "CREATE TABLE log (email TEXT);",
+            "CREATE TRIGGER log_user AFTER INSERT ON users BEGIN INSERT INTO log (email) VALUES (NEW.email); END;",
+        ];
+        let mut db = executor_with(&schema);
+        // creating what is already there adds nothing
+        run(&mut db, schema[0]);
+        let entry = |kind: &str, name: &str, table: &str, page: i64, sql: Option<&str>| {
+            let text = |s: &str| ColVal::String(s.to_string());
+            vec![
+                text(kind),
+                text(name),
+                text(table),
+                ColVal::Int(page),
+                sql.map_or(ColVal::Null, text),
+            ]
+        };
+        let rows = run(&mut db, "SELECT * FROM sqlite_master;");
+        assert_eq!(
+            rows,
+            [
+                entry(
+                    "table",
+                    "users",
+                    "users",
+                    2,
+                    Some("CREATE TABLE users (email TEXT PRIMARY KEY,  age INTEGER)")
+                ),
+                entry("index", "sqlite_autoindex_users_1", "users", 3, None),
+                entry(
+                    "index",
+                    "idx_age",
+                    "users",
+                    4,
+                    Some("CREATE INDEX idx_age ON users (age)")
+                ),
+                entry(
+                    "view",
+                    "adults",
+                    "adults",
+                    0,
+                    Some("CREATE VIEW adults AS SELECT email FROM users WHERE age >= 18")
+                ),
+                entry(
+                    "table",
+                    "log",
+                    "log",
+                    5,
+                    Some("CREATE TABLE log (email TEXT)")
+                ),
+                entry(
+                    "trigger",
+                    "log_user",
+                    "users",
+                    0,
+                    Some(&schema[4][..schema[4].len() - 1])
+                ),
+            ]
+        );
+        assert_eq!(
+            db.execute_sql("DELETE FROM sqlite_master;")
+                .unwrap_err()
+                .to_string(),
+            "table sqlite_master may not be modified"
+        );
+
+        // a database opened on the same rows has the same schema
+        let mut reopened = Executor::default();
+        reopened.load_database_catalog(None, &rows).unwrap();
+        assert_eq!(run(&mut reopened, "SELECT * FROM sqlite_master;"), rows);
+        assert_eq!(reopened.schema_info(), db.schema_info());
+        run(
+            &mut reopened,
+            "INSERT INTO users (email, age) VALUES (\"a@x\", 30);",
+        );
+        assert_eq!(
+            run(&mut reopened, "SELECT * FROM log;"),
+            [[ColVal::String("a@x".to_string())]]
+        );
+    }
+
+    #[test]
+    fn attached_databases_keep_their_tables_in_their_own_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.db");
+        let attach = format!("ATTACH DATABASE \"{}\" AS archive;", path.display());
+        let mut db = executor_with(&["CREATE TABLE orders (id INTEGER PRIMARY KEY, item TEXT);"]);
+        run(&mut db, &attach);
+        run(
+            &mut db,
+            "CREATE TABLE archive.orders (item TEXT PRIMARY KEY, qty INTEGER);",
+        );
+        run(&mut db, "CREATE INDEX by_item ON archive.orders (item);");
+        run(
+            &mut db,
+            "INSERT INTO archive.orders (item, qty) VALUES (\"pen\", 1);",
+        );
+        run(
+            &mut db,
+            "INSERT INTO orders (id, item) VALUES (2, \"ink\");",
+        );
+        assert_eq!(
+            run(&mut db, "SELECT orders.item FROM archive.orders;"),
+            [[text("pen")]]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT item FROM main.orders;"),
+            [[text("ink")]]
+        );
+        assert!(db
+            .execute_sql("INSERT INTO archive.sqlite_master (name) VALUES (\"x\");")
+            .is_err());
+        assert_eq!(
+            db.execute_sql("CREATE TABLE nowhere.t (a INTEGER);")
+                .unwrap_err()
+                .to_string(),
+            "unknown database nowhere"
+        );
+
+        // the file is a database of its own, with its names as main's
+        let mut archive =
+            Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(
+            run(&mut archive, "SELECT name, sql FROM sqlite_master;"),
+            [
+                vec![
+                    text("orders"),
+                    text("CREATE TABLE orders (item TEXT PRIMARY KEY, qty INTEGER)")
+                ],
+                vec![text("sqlite_autoindex_orders_1"), ColVal::Null],
+                vec![
+                    text("by_item"),
+                    text("CREATE INDEX by_item ON orders (item)")
+                ],
+            ]
+        );
+        assert_eq!(
+            run(&mut archive, "SELECT item FROM orders;"),
+            [[text("pen")]]
+        );
+        drop(archive);
+
+        run(&mut db, "DETACH DATABASE archive;");
+        assert!(db.execute_sql("SELECT item FROM archive.orders;").is_err());
+        run(&mut db, &attach);
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT item FROM archive.orders WHERE item = \"pen\";"
+            ),
+            [[text("pen")]]
+        );
+        let names: Vec<&str> = db.databases().iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["main", "archive"]);
+    }
+
+    #[test]
+    fn a_group_of_commits_is_saved_once() {
+        use std::sync::atomic;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut db = open();
+        run(&mut db, "CREATE TABLE t (a INTEGER PRIMARY KEY);");
+        let saves = Arc::new(atomic::AtomicUsize::new(0));
+        let counted = saves.clone();
+        db.on_commit(move |_| {
+            counted.fetch_add(1, atomic::Ordering::SeqCst);
+        });
+
+        db.begin_group_commit();
+        for a in 1..=3 {
+            run(&mut db, &format!("INSERT INTO t (a) VALUES ({a});"));
+        }
+        assert_eq!(saves.load(atomic::Ordering::SeqCst), 0);
+        // they have committed once they are saved
+        assert_eq!(db.commit_count(), 1);
+        // a transaction beginning saves the group's commits so far without its own
+        run(&mut db, "BEGIN;");
+        assert_eq!(saves.load(atomic::Ordering::SeqCst), 1);
+        run(&mut db, "INSERT INTO t (a) VALUES (4);");
+        run(&mut db, "COMMIT;");
+        run(&mut db, "INSERT INTO t (a) VALUES (5);");
+        assert_eq!(saves.load(atomic::Ordering::SeqCst), 1);
+        db.end_group_commit().unwrap();
+        assert_eq!(saves.load(atomic::Ordering::SeqCst), 2);
+        assert_eq!(db.commit_count(), 6);
+        db.end_group_commit().unwrap();
+        assert_eq!(saves.load(atomic::Ordering::SeqCst), 2);
+
+        run(&mut db, "INSERT INTO t (a) VALUES (6);");
+        assert_eq!(saves.load(atomic::Ordering::SeqCst), 3);
+
+        // a group another connection commits under can't be saved, and loses its writes
+        // rather than the other's
+        let mut other = open();
+        db.begin_group_commit();
+        run(&mut db, "INSERT INTO t (a) VALUES (7);");
+        run(&mut other, "INSERT INTO t (a) VALUES (8);");
+        let ended = db.end_group_commit().unwrap_err();
+        assert!(ended.to_string().starts_with("database is locked"));
+        // loaded again straight away, not only once the next statement checks the file
+        let last = db
+            .image()
+            .into_iter()
+            .filter(|(table, ..)| table == "t")
+            .last();
+        assert_eq!(last.map(|(_, key, _)| key), Some(ColVal::Int(8)));
+        // and so does a batch
+        run(&mut other, "INSERT INTO t (a) VALUES (9);");
+        assert!(db.append_batch("t", [vec![ColVal::Int(10)]]).is_err());
+        let last = db
+            .image()
+            .into_iter()
+            .filter(|(table, ..)| table == "t")
+            .last();
+        assert_eq!(last.map(|(_, key, _)| key), Some(ColVal::Int(9)));
+        drop(db);
+        assert_eq!(
+            run(&mut open(), "SELECT count(*) FROM t;"),
+            [[ColVal::Int(8)]]
+        );
+    }
+
+    #[test]
+    fn a_schema_changed_by_another_connection_is_loaded_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut first = open();
+        run(&mut first, "CREATE TABLE t (a INTEGER);");
+        run(&mut first, "INSERT INTO t (a) VALUES (1);");
+        let mut second = open();
+        let mut select = second.prepare("SELECT a FROM t WHERE a = 1;").unwrap();
+        assert_eq!(
+            second.execute_prepared(&mut select).unwrap().rows,
+            [[ColVal::Int(1)]]
+        );
+
+        run(&mut first, "CREATE INDEX by_a ON t (a);");
+        run(&mut first, "CREATE TABLE u (b TEXT);");
+        // the prepared statement is planned again, with the index
+        assert_eq!(
+            second.execute_prepared(&mut select).unwrap().rows,
+            [[ColVal::Int(1)]]
+        );
+        let plan = second
+            .execute_sql("EXPLAIN QUERY PLAN SELECT a FROM t WHERE a = 1;")
+            .unwrap();
+        assert!(plan.to_string().contains("USING INDEX by_a"));
+        run(&mut second, "INSERT INTO u (b) VALUES (\"x\");");
+
+        // and the second's save kept the first's schema
+        drop((first, second));
+        assert_eq!(run(&mut open(), "SELECT b FROM u;"), [[text("x")]]);
+        assert_eq!(
+            run(
+                &mut open(),
+                "SELECT name FROM sqlite_master WHERE type = \"index\";"
+            ),
+            [[text("by_a")]]
+        );
+    }
+
+    #[test]
+    fn rows_written_by_another_connection_are_seen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut first = open();
+        run(&mut first, "CREATE TABLE t (a INTEGER);");
+        let mut second = open();
+        run(&mut first, "INSERT INTO t (a) VALUES (1);");
+        assert_eq!(run(&mut second, "SELECT a FROM t;"), [[ColVal::Int(1)]]);
+
+        // and neither writes its rows back over the other's
+        run(&mut second, "INSERT INTO t (a) VALUES (2);");
+        run(&mut first, "INSERT INTO t (a) VALUES (3);");
+        drop((first, second));
+        assert_eq!(
+            run(&mut open(), "SELECT a FROM t;"),
+            [[ColVal::Int(1)], [ColVal::Int(2)], [ColVal::Int(3)]]
+        );
+    }
+
+    #[test]
+    fn stats_count_the_pages_of_main_and_the_attached_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let mut db = Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let attach = format!(
+            "ATTACH DATABASE \"{}\" AS archive;",
+            dir.path().join("archive.db").display()
+        );
+        run(&mut db, &attach);
+        run(&mut db, "CREATE TABLE archive.t (a INTEGER);");
+        run(&mut db, "INSERT INTO archive.t (a) VALUES (1);");
+
+        let report = db.cache_report();
+        assert!(report.syncs > 0);
+        let owners: Vec<&str> = report.owners.keys().map(String::as_str).collect();
+        assert!(owners.contains(&"archive.image"), "{owners:?}");
+        assert!(owners.iter().any(|owner| owner.starts_with("main.")));
+        let stats = run(&mut db, "PRAGMA stats;");
+        let stat = |name: &str| {
+            let row = stats.iter().find(|row| row[0] == text(name)).unwrap();
+            match row[1] {
+                ColVal::Int(value) => value,
+                _ => panic!("a count"),
+            }
+        };
+        assert_eq!(stat("fsyncs"), report.syncs as i64);
+        assert!(stat("cache_hits") + stat("cache_misses") > 0);
+
+        // an in-memory database has no file, and so nothing to count
+        assert_eq!(Executor::default().cache_report(), CacheReport::default());
+    }
+
+    #[test]
+    fn a_shared_in_memory_database_is_shared() {
+        let open = |uri: &str| Executor::open(OpenTarget::parse(uri).unwrap()).unwrap();
+        let uri = "file:shared_executor_test?mode=memory&cache=shared";
+        let mut first = open(uri);
+        run(&mut first, "CREATE TABLE t (a INTEGER);");
+        run(&mut first, "INSERT INTO t (a) VALUES (1);");
+        let mut second = open(uri);
+        assert_eq!(run(&mut second, "SELECT a FROM t;"), [[ColVal::Int(1)]]);
+        run(&mut second, "INSERT INTO t (a) VALUES (2);");
+        assert_eq!(
+            run(&mut first, "SELECT a FROM t;"),
+            [[ColVal::Int(1)], [ColVal::Int(2)]]
+        );
+        assert!(first.execute_sql("PRAGMA journal_mode = WAL;").is_err());
+
+        // a private database of the same name is one of its own
+        let mut private = open("file:shared_executor_test?mode=memory");
+        assert!(private.execute_sql("SELECT a FROM t;").is_err());
+
+        // and the shared one is gone once its last connection closes
+        drop((first, second));
+        assert!(open(uri).execute_sql("SELECT a FROM t;").is_err());
+    }
+
+    #[test]
+    fn a_transaction_never_writes_over_another_connections_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut first = open();
+        run(&mut first, "CREATE TABLE t (a INTEGER);");
+        let mut second = open();
+        let count = |db: &mut Executor| run(db, "SELECT count(*) FROM t;");
+
+        // IMMEDIATE holds RESERVED, so the second can read but not commit until it ends
+        run(&mut first, "BEGIN IMMEDIATE;");
+        run(&mut first, "INSERT INTO t (a) VALUES (1);");
+        let insert = second.execute_sql("INSERT INTO t (a) VALUES (2);");
+        assert_eq!(insert.unwrap_err().to_string(), "database is locked");
+        assert_eq!(count(&mut second), [[ColVal::Int(0)]]);
+        run(&mut first, "COMMIT;");
+        run(&mut second, "INSERT INTO t (a) VALUES (2);");
+        assert_eq!(count(&mut first), [[ColVal::Int(2)]]);
+
+        // a DEFERRED transaction another connection commits under can't be saved, and
+        // loses its writes rather than the other's
+        run(&mut first, "BEGIN;");
+        run(&mut first, "INSERT INTO t (a) VALUES (3);");
+        run(&mut second, "INSERT INTO t (a) VALUES (4);");
+        let commit = first.execute_sql("COMMIT;").unwrap_err();
+        assert!(commit.to_string().starts_with("database is locked"));
+        assert_eq!(
+            run(&mut first, "SELECT a FROM t;"),
+            [[ColVal::Int(1)], [ColVal::Int(2)], [ColVal::Int(4)]]
+        );
+
+        // EXCLUSIVE keeps readers out too
+        run(&mut first, "BEGIN EXCLUSIVE;");
+        let select = second.execute_sql("SELECT a FROM t;");
+        assert_eq!(select.unwrap_err().to_string(), "database is locked");
+        run(&mut first, "ROLLBACK;");
+        assert_eq!(count(&mut second), [[ColVal::Int(3)]]);
+    }
+
+    #[test]
+    fn a_file_in_wal_mode_commits_to_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let journal = dir.path().join("main.db-journal");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut first = open();
+        run(&mut first, "CREATE TABLE t (a INTEGER);");
+        assert_eq!(
+            run(&mut first, "PRAGMA journal_mode = WAL;"),
+            [[text("wal")]]
+        );
+
+        // neither the file nor its journal changes until the log is checkpointed, and
+        // another connection in the process reads the log too
+        let file = std::fs::read(&path).unwrap();
+        run(&mut first, "INSERT INTO t (a) VALUES (1);");
+        let mut second = open();
+        run(&mut second, "INSERT INTO t (a) VALUES (2);");
+        assert_eq!(std::fs::read(&path).unwrap(), file);
+        assert_eq!(std::fs::metadata(&journal).unwrap().len(), 0);
+        assert_eq!(
+            run(&mut first, "SELECT a FROM t;"),
+            [[ColVal::Int(1)], [ColVal::Int(2)]]
+        );
+
+        // the last connection to close checkpoints it, the log's file goes, and the file
+        // opens in WAL mode
+        drop((first, second));
+        assert_ne!(std::fs::read(&path).unwrap(), file);
+        assert!(!dir.path().join("main.db-wal").exists());
+        let mut db = open();
+        assert_eq!(run(&mut db, "PRAGMA journal_mode;"), [[text("wal")]]);
+        assert_eq!(run(&mut db, "SELECT count(*) FROM t;"), [[ColVal::Int(2)]]);
+        assert_eq!(
+            run(&mut db, "PRAGMA journal_mode = DELETE;"),
+            [[text("delete")]]
+        );
+        drop(db);
+        let mut db = open();
+        assert_eq!(run(&mut db, "PRAGMA journal_mode;"), [[text("delete")]]);
+        assert_eq!(run(&mut db, "SELECT count(*) FROM t;"), [[ColVal::Int(2)]]);
+    }
+
+    #[test]
+    fn a_commit_in_wal_mode_outlasts_the_process_before_a_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let mut db = Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        run(&mut db, "CREATE TABLE t (a INTEGER);");
+        run(&mut db, "PRAGMA journal_mode = WAL;");
+        run(&mut db, "INSERT INTO t (a) VALUES (1);");
+        run(&mut db, "INSERT INTO t (a) VALUES (2);");
+        run(&mut db, "INSERT INTO t (a) VALUES (3);");
+
+        // the files as a process killed now, before any checkpoint, would leave them
+        let copy = dir.path().join("copy.db");
+        std::fs::copy(&path, &copy).unwrap();
+        std::fs::copy(
+            dir.path().join("main.db-wal"),
+            dir.path().join("copy.db-wal"),
+        )
+        .unwrap();
+        let mut copied =
+            Executor::open(OpenTarget::parse(copy.to_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(
+            run(&mut copied, "SELECT a FROM t;"),
+            [[ColVal::Int(1)], [ColVal::Int(2)], [ColVal::Int(3)]]
+        );
+    }
+
+    #[test]
+    fn an_index_is_built_in_wal_mode_while_another_connection_reads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut writer = open();
+        run(&mut writer, "CREATE TABLE t (a INTEGER);");
+        run(&mut writer, "PRAGMA journal_mode = WAL;");
+        for a in 1..=50 {
+            run(&mut writer, &format!("INSERT INTO t (a) VALUES ({a});"));
+        }
+
+        // the reader's transaction doesn't hold up the build, which only locks the file
+        // to commit, and the reader goes on seeing the commit it began at
+        let mut reader = open();
+        run(&mut reader, "BEGIN;");
+        assert_eq!(
+            run(&mut reader, "SELECT count(*) FROM t;"),
+            [[ColVal::Int(50)]]
+        );
+        run(&mut writer, "CREATE INDEX t_a ON t (a);");
+        assert_eq!(
+            run(&mut reader, "SELECT count(*) FROM t;"),
+            [[ColVal::Int(50)]]
+        );
+        assert!(run(
+            &mut reader,
+            "SELECT name FROM sqlite_master WHERE type = \"index\";"
+        )
+        .is_empty());
+        run(&mut reader, "ROLLBACK;");
+        assert_eq!(
+            run(
+                &mut reader,
+                "SELECT name FROM sqlite_master WHERE type = \"index\";"
+            ),
+            [[text("t_a")]]
+        );
+    }
+
+    #[test]
+    fn explain_analyze_counts_what_each_operator_did() {
+        let mut db = Executor::default();
+        run(&mut db, "CREATE TABLE t (a INTEGER, b TEXT);");
+        for a in 1..=5 {
+            run(
+                &mut db,
+                &format!("INSERT INTO t (a, b) VALUES ({a}, \"x\");"),
+            );
+        }
+        // the times vary from run to run
+        let analyze = |db: &mut Executor, sql: &str| -> Vec<String> {
+            run(db, sql)
+                .into_iter()
+                .map(|row| match &row[0] {
+                    ColVal::String(line) => line.split(" time=").next().unwrap().to_string(),
+                    other => panic!("{other:?}"),
+                })
+                .collect()
+        };
+        assert_eq!(
+            analyze(&mut db, "EXPLAIN ANALYZE SELECT b FROM t WHERE a > 3;"),
+            [
+                "PROJECT b (actual rows=2 loops=1",
+                "└── FILTER a > 3 (actual rows=2 loops=1",
+                "    └── SCAN t (actual rows=5 loops=1",
+            ]
+        );
+
+        // and a write is run, its rows counted as a whole
+        assert_eq!(
+            analyze(&mut db, "EXPLAIN ANALYZE DELETE FROM t WHERE a < 3;")[0],
+            "DELETE FROM t (actual rows=2 loops=1"
+        );
+        assert_eq!(run(&mut db, "SELECT count(*) FROM t;"), [[ColVal::Int(3)]]);
+    }
+
+    #[test]
+    fn a_trigger_that_fires_itself_stops_at_the_limit() {
+        let mut db = Executor::default();
+        run(&mut db, "CREATE TABLE t (n INTEGER);");
+        run(
+            &mut db,
+            "CREATE TRIGGER again AFTER INSERT ON t WHEN NEW.n < 3 BEGIN \
+             INSERT INTO t (n) VALUES (NEW.n + 1); END;",
+        );
+        run(&mut db, "INSERT INTO t (n) VALUES (1);");
+        assert_eq!(
+            run(&mut db, "SELECT n FROM t;"),
+            [[ColVal::Int(1)], [ColVal::Int(2)], [ColVal::Int(3)]]
+        );
+        assert_eq!(
+            run(&mut db, "PRAGMA max_trigger_depth;"),
+            [[ColVal::Int(100)]]
+        );
+
+        run(&mut db, "PRAGMA max_trigger_depth = 5;");
+        let err = db
+            .execute_sql("INSERT INTO t (n) VALUES (-10);")
+            .unwrap_err();
+        assert_eq!(crate::error::find(&err), Some(&SqlError::TooDeep));
+        // and what the statement did is undone
+        assert_eq!(run(&mut db, "SELECT count(*) FROM t;"), [[ColVal::Int(3)]]);
+        assert_eq!(db.nesting.depth(), 0);
+    }
+
+    #[test]
+    fn foreign_keys_cascade_to_and_check_child_rows() {
+        let mut db = executor_with(&[
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name);",
+            "CREATE TABLE orders (id, user_id REFERENCES users ON DELETE CASCADE ON UPDATE CASCADE);",
+            "CREATE TABLE posts (id, author REFERENCES users);",
+            "INSERT INTO users (id, name) VALUES (1, \"amy\");",
+            "INSERT INTO users (id, name) VALUES (2, \"bob\");",
+            "INSERT INTO orders (id, user_id) VALUES (1, 1);",
+            "INSERT INTO orders (id, user_id) VALUES (2, 2);",
+        ]);
+        // off, as it starts, nothing is checked
+        run(&mut db, "INSERT INTO posts (id, author) VALUES (1, 9);");
+        run(&mut db, "DELETE FROM posts;");
+
+        run(&mut db, "PRAGMA foreign_keys = ON;");
+        assert_eq!(run(&mut db, "PRAGMA foreign_keys;"), [[ColVal::Int(1)]]);
+        run(&mut db, "DELETE FROM users WHERE id = 2;");
+        run(&mut db, "UPDATE users SET id = 3 WHERE id = 1;");
+        assert_eq!(
+            run(&mut db, "SELECT id, user_id FROM orders;"),
+            [[ColVal::Int(1), ColVal::Int(3)]]
+        );
+
+        let violates = |db: &mut Executor, sql: &str| {
+            let err = db.execute_sql(sql).unwrap_err();
+            assert_eq!(
+                crate::error::find(&err),
+                Some(&SqlError::ForeignKeyConstraint),
+                "{sql}"
+            );
+        };
+        violates(&mut db, "INSERT INTO posts (id, author) VALUES (1, 9);");
+        run(&mut db, "INSERT INTO posts (id, author) VALUES (1, 3);");
+        violates(&mut db, "DELETE FROM users;");
+        violates(&mut db, "UPDATE posts SET author = 4;");
+        // and each failed statement is undone as a whole
+        assert_eq!(
+            run(&mut db, "SELECT count(*) FROM users;"),
+            [[ColVal::Int(1)]]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT count(*) FROM orders;"),
+            [[ColVal::Int(1)]]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT author FROM posts;"),
+            [[ColVal::Int(3)]]
+        );
+    }
+
+    #[test]
+    fn deterministic_output_sorts_rows_no_order_was_asked_for() {
+        let mut db = executor_with(&[
+            "CREATE TABLE t (a TEXT);",
+            "INSERT INTO t (a) VALUES (\"b\");",
+            "INSERT INTO t (a) VALUES (\"c\");",
+            "INSERT INTO t (a) VALUES (\"a\");",
+        ]);
+        let select = "SELECT a FROM t;";
+        assert_eq!(
+            run(&mut db, select),
+            [[text("b")], [text("c")], [text("a")]]
+        );
+        assert_eq!(
+            run(&mut db, "PRAGMA deterministic_output;"),
+            [[ColVal::Int(0)]]
+        );
+
+        run(&mut db, "PRAGMA deterministic_output = ON;");
+        assert_eq!(
+            run(&mut db, select),
+            [[text("a")], [text("b")], [text("c")]]
+        );
+        let streamed: Vec<ColVal> = db
+            .query_sql(select)
+            .unwrap()
+            .map(|row| row.unwrap().into_values().remove(0))
+            .collect();
+        assert_eq!(streamed, [text("a"), text("b"), text("c")]);
+        // an ORDER BY keeps its order
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT a FROM t UNION ALL SELECT a FROM t ORDER BY a DESC LIMIT 3;"
+            ),
+            [[text("c")], [text("c")], [text("b")]]
+        );
+
+        run(&mut db, "PRAGMA deterministic_output = off;");
+        assert_eq!(
+            run(&mut db, select),
+            [[text("b")], [text("c")], [text("a")]]
+        );
+    }
+
+    #[test]
+    fn max_page_count_caps_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let mut db = Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        run(
+            &mut db,
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, note TEXT);",
+        );
+        let insert = |id: i64| {
+            format!(
+                "INSERT INTO t (id, note) VALUES ({id}, \"{}\");",
+                "x".repeat(10000)
+            )
+        };
+        run(&mut db, &insert(1));
+        let pages =
+            |db: &mut Executor, pragma: &str| run(db, &format!("PRAGMA {pragma};"))[0][0].clone();
+        let ColVal::Int(count) = pages(&mut db, "page_count") else {
+            panic!("page_count is a number");
+        };
+        assert!(count > 2, "{count}");
+        assert_eq!(pages(&mut db, "freelist_count"), ColVal::Int(0));
+
+        // it can't be set below the pages there are, and a write that needs more fails
+        assert_eq!(pages(&mut db, "max_page_count = 1"), ColVal::Int(count));
+        let err = db.execute_sql(&insert(2)).unwrap_err();
+        assert_eq!(crate::error::find(&err), Some(&SqlError::DatabaseFull));
+        assert_eq!(run(&mut db, "SELECT id FROM t;"), [[ColVal::Int(1)]]);
+
+        // the pages a smaller image leaves go on the freelist
+        run(&mut db, "DELETE FROM t;");
+        assert_eq!(pages(&mut db, "page_count"), ColVal::Int(count));
+        assert!(pages(&mut db, "freelist_count") > ColVal::Int(0));
+        run(&mut db, &insert(2));
+
+        assert_eq!(
+            pages(&mut Executor::default(), "page_count"),
+            ColVal::Int(0)
+        );
+    }
+
+    #[test]
+    fn temporary_tables_last_as_long_as_the_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut db = open();
+        for sql in [
+            "CREATE TABLE t (a INTEGER);",
+            "INSERT INTO t (a) VALUES (1);",
+            "CREATE TEMP TABLE t (b INTEGER PRIMARY KEY, c TEXT);",
+            "CREATE INDEX by_c ON t (c);",
+            "INSERT INTO t (c) VALUES (\"x\");",
+            "INSERT INTO temp.t (c) VALUES (\"y\");",
+            "UPDATE t SET c = \"z\" WHERE b = 2;",
+        ] {
+            run(&mut db, sql);
+        }
+        // named alone the temporary table is found ahead of main's
+        assert_eq!(
+            run(&mut db, "SELECT t.b, c FROM t WHERE c > \"w\";"),
+            [[ColVal::Int(1), text("x")], [ColVal::Int(2), text("z")]]
+        );
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT type, name, tbl_name FROM sqlite_temp_master;"
+            ),
+            [
+                [text("table"), text("t"), text("t")],
+                [text("index"), text("by_c"), text("t")],
+            ]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT name FROM sqlite_master;"),
+            [[text("t")]]
+        );
+        assert_eq!(
+            db.execute_sql("CREATE TEMP TABLE aux.u (a);")
+                .unwrap_err()
+                .to_string()
+                .lines()
+                .next(),
+            Some("Parse error: temporary table name must be unqualified")
+        );
+
+        // and it is never saved with main, so is gone once the connection is
+        drop(db);
+        let mut db = open();
+        assert_eq!(run(&mut db, "SELECT a FROM t;"), [[ColVal::Int(1)]]);
+        assert!(db.execute_sql("SELECT * FROM temp.t;").is_err());
+
+        // kept in a file it is the same, and moving it drops what was there
+        run(&mut db, "PRAGMA temp_store = FILE;");
+        run(&mut db, "CREATE TEMP TABLE scratch (a);");
+        run(&mut db, "INSERT INTO scratch (a) VALUES (5);");
+        let temp_file = db.temp_dir.as_ref().unwrap().path().join("temp.db");
+        assert!(temp_file.exists());
+        assert_eq!(run(&mut db, "SELECT a FROM scratch;"), [[ColVal::Int(5)]]);
+        run(&mut db, "PRAGMA temp_store = MEMORY;");
+        assert!(db.execute_sql("SELECT a FROM scratch;").is_err());
+        assert!(!temp_file.exists());
+    }
+
+    #[test]
+    fn files_sqlite_wrote_are_read_only() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/sqlite/library.db");
+        let mut db = Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        assert_eq!(
+            run(&mut db, "SELECT id, name FROM authors WHERE id = 7;"),
+            [[ColVal::Int(7), text("Calvino")]]
+        );
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT title, price FROM books WHERE author_id IN (SELECT id FROM authors WHERE name = \"Calvino\");"
+            ),
+            [[text("book 300"), ColVal::Real(150.0)]]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT COUNT(*) FROM cheap;"),
+            [[ColVal::Int(19)]]
+        );
+        for sql in [
+            "INSERT INTO authors (id, name) VALUES (8, \"Dante\");",
+            "DELETE FROM books;",
+            "CREATE TABLE t (a INTEGER);",
+        ] {
+            assert_eq!(
+                db.execute_sql(sql).unwrap_err().to_string(),
+                "attempt to write a readonly database",
+                "{sql}"
+            );
+        }
+        assert!(db
+            .append_batch("authors", [vec![ColVal::Null, text("Eco")]])
+            .is_err());
+        assert_eq!(
+            run(&mut db, "SELECT COUNT(*) FROM authors;"),
+            [[ColVal::Int(3)]]
+        );
+    }
+
+    #[test]
+    fn databases_can_be_kept_in_sqlites_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shared.db");
+        let uri = format!("file:{}?format=sqlite", path.display());
+        let open = |uri: &str| Executor::open(OpenTarget::parse(uri).unwrap());
+        let mut db = open(&uri).unwrap();
+        run(
+            &mut db,
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT, score REAL);",
+        );
+        run(&mut db, "CREATE INDEX by_email ON users (email);");
+        run(
+            &mut db,
+            "INSERT INTO users (id, email, score) VALUES (4, \"amy@x\", 2.0);",
+        );
+        drop(db);
+
+        assert!(sqlite_file::is_sqlite_file(&path).unwrap());
+        let mut db = open(&uri).unwrap();
+        run(
+            &mut db,
+            "INSERT INTO users (id, email, score) VALUES (9, \"bob@x\", 1.5);",
+        );
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT id, score FROM users WHERE email = \"amy@x\";"
+            ),
+            [[ColVal::Int(4), ColVal::Real(2.0)]]
+        );
+        drop(db);
+
+        // without format=sqlite it can only be read
+        let mut db = open(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            run(&mut db, "SELECT COUNT(*) FROM users;"),
+            [[ColVal::Int(2)]]
+        );
+        assert!(db.execute_sql("DELETE FROM users;").is_err());
+
+        let ours = dir.path().join("ours.db");
+        open(ours.to_str().unwrap()).unwrap();
+        assert!(open(&format!("file:{}?format=sqlite", ours.display())).is_err());
+    }
+
+    #[test]
+    fn rows_too_large_for_the_database_are_refused() {
+        let mut db = executor_with(&[
+            "PRAGMA page_size = 512;",
+            "CREATE TABLE notes (id INTEGER PRIMARY KEY, body TEXT);",
+            // a cell and an overflow chain of two pages, 1055 bytes in all
+            "PRAGMA max_page_count = 3;",
+        ]);
+        let insert = |body: &str| format!("INSERT INTO notes (body) VALUES (\"{body}\");");
+        // the record's header takes 4 bytes, and rowid 1 none, leaving 1051 for the text
+        run(&mut db, &insert(&"x".repeat(1051)));
+        // rowid 2 takes a byte
+        assert_eq!(
+            db.execute_sql(&insert(&"x".repeat(1051)))
+                .unwrap_err()
+                .to_string(),
+            "row too large: 1056 bytes, at most 1055 fit"
+        );
+        assert_eq!(
+            db.execute_sql(&format!(
+                "UPDATE notes SET body = \"{}\";",
+                "x".repeat(1052)
+            ))
+            .unwrap_err()
+            .to_string(),
+            "row too large: 1056 bytes, at most 1055 fit"
+        );
+        assert_eq!(
+            run(&mut db, "SELECT body FROM notes;"),
+            [[ColVal::String("x".repeat(1051))]]
+        );
+    }
+
+    #[test]
+    fn exists_stops_at_the_first_row() {
+        let mut db = executor_with(&["CREATE TABLE orders (total INTEGER);"]);
+        for total in 0..100 {
+            run(
+                &mut db,
+                &format!("INSERT INTO orders (total) VALUES ({total});"),
+            );
+        }
+        let read = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = read.clone();
+        db.create_scalar_function("seen", Some(1), false, move |args| {
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(args[0].clone())
+        });
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT EXISTS (SELECT 1 FROM orders WHERE seen(total) > 4);"
+            ),
+            [[ColVal::Boolean(true)]]
+        );
+        assert_eq!(read.load(std::sync::atomic::Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn from_clauses_join_their_tables() {
+        let mut db = executor_with(&[
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);",
+            "CREATE TABLE orders (user_id TEXT, total INTEGER);",
+            "INSERT INTO users (name) VALUES ('amy');",
+            "INSERT INTO users (name) VALUES ('bob');",
+            "INSERT INTO users (name) VALUES ('cat');",
+            // user_ids are compared as numbers, as `user_id = id` would compare them
+            "INSERT INTO orders (user_id, total) VALUES ('1', 10);",
+            "INSERT INTO orders (user_id, total) VALUES ('1', 20);",
+            "INSERT INTO orders (user_id, total) VALUES ('2', 5);",
+            "INSERT INTO orders (user_id, total) VALUES (NULL, 99);",
+        ]);
+        let result = db
+            .execute_sql(
+                "SELECT u.name, o.total FROM users u JOIN orders o ON o.user_id = u.id \
+                 ORDER BY o.total;",
+            )
+            .unwrap();
+        assert_eq!(result.columns, ["name", "total"]);
+        assert_eq!(
+            result.rows,
+            [
+                [text("bob"), ColVal::Int(5)],
+                [text("amy"), ColVal::Int(10)],
+                [text("amy"), ColVal::Int(20)],
+            ]
+        );
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT name, count(*), sum(total) FROM users, orders \
+                 WHERE user_id = users.id GROUP BY name;"
+            ),
+            [
+                [text("amy"), ColVal::Int(2), ColVal::Int(30)],
+                [text("bob"), ColVal::Int(1), ColVal::Int(5)],
+            ]
+        );
+        // every pair, less those the WHERE clause turns down
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT count(*) FROM users a CROSS JOIN users b WHERE a.id < b.id;"
+            ),
+            [[ColVal::Int(3)]]
+        );
+        // a correlated subquery sees the joined row it is run for
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT u.name, o.total FROM users u JOIN orders o ON o.user_id = u.id \
+                 WHERE EXISTS (SELECT 1 FROM orders x WHERE x.total > o.total AND x.user_id = u.id);"
+            ),
+            [[text("amy"), ColVal::Int(10)]]
+        );
+        // a view of a join has the columns' own names
+        run(
+            &mut db,
+            "CREATE VIEW spend AS SELECT u.name, o.total FROM users u JOIN orders o ON o.user_id = u.id;",
+        );
+        assert_eq!(
+            run(&mut db, "SELECT name FROM spend WHERE total > 6;"),
+            [[text("amy")], [text("amy")]]
+        );
+    }
+
+    #[test]
+    fn subqueries_views_and_triggers_run() {
+        let mut db = executor_with(&[
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT COLLATE NOCASE);",
+            "CREATE TABLE orders (user_id INTEGER, total INTEGER);",
+            "CREATE TABLE log (name TEXT);",
+            "CREATE VIEW buyers AS SELECT name FROM users u WHERE EXISTS (SELECT 1 FROM orders WHERE user_id = u.id);",
+            "CREATE TRIGGER welcome AFTER INSERT ON users BEGIN INSERT INTO log (name) VALUES (NEW.name); END;",
+            "INSERT INTO users (name) VALUES (\"amy\");",
+            "INSERT INTO users (name) VALUES (\"bob\");",
+            "INSERT INTO orders (user_id, total) VALUES (2, 50);",
+        ]);
+        assert_eq!(run(&mut db, "SELECT * FROM buyers;"), [[text("bob")]]);
+        assert_eq!(
+            run(&mut db, "SELECT name FROM log;"),
+            [[text("amy")], [text("bob")]]
+        );
+        // name is NOCASE
+        assert_eq!(
+            run(&mut db, "SELECT id FROM users WHERE name = \"AMY\";"),
+            [[ColVal::Int(1)]]
+        );
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT id FROM users WHERE id IN (SELECT user_id FROM orders WHERE total > 10);"
+            ),
+            [[ColVal::Int(2)]]
+        );
+        assert_eq!(
+            run(
+                &mut db,
+                "WITH big AS (SELECT user_id FROM orders WHERE total > 10) SELECT * FROM big;"
+            ),
+            [[ColVal::Int(2)]]
+        );
+
+        // both tables are read in rowid order, so they are merged rather than hashed
+        run(&mut db, "CREATE TABLE vips (id INTEGER PRIMARY KEY);");
+        run(&mut db, "INSERT INTO vips (id) VALUES (2);");
+        let sql =
+            "SELECT name FROM users u WHERE NOT EXISTS (SELECT 1 FROM vips v WHERE v.id = u.id);";
+        assert_eq!(
+            db.execute_sql(&format!("EXPLAIN QUERY PLAN {sql}"))
+                .unwrap()
+                .to_string(),
+            "QUERY PLAN\n|--SCAN users\n`--MERGE ANTI JOIN ON id = id\n   `--SCAN vips"
+        );
+        assert_eq!(run(&mut db, sql), [[text("amy")]]);
+    }
+
+    #[test]
+    fn prepared_statements_run_again_with_new_values() {
+        let mut db = executor_with(&["CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);"]);
+        let mut insert = db.prepare("INSERT INTO users (name) VALUES (?);").unwrap();
+        for name in ["amy", "bob"] {
+            insert.bind(1, text(name)).unwrap();
+            db.execute_prepared(&mut insert).unwrap();
+        }
+
+        let sql = "SELECT name FROM users WHERE id = :id;";
+        let mut select = db.prepare(sql).unwrap();
+        select.bind_named(":id", ColVal::Int(2)).unwrap();
+        assert_eq!(
+            db.execute_prepared(&mut select).unwrap().rows,
+            [[text("bob")]]
+        );
+        // the query was compiled to bytecode, which is kept for the next run
+        assert!(select.compile(db.schema(), None).unwrap().program.is_some());
+
+        // preparing the same SQL again comes from the cache, with its parameters cleared
+        let mut again = db.prepare(sql).unwrap();
+        assert!(db.execute_prepared(&mut again).unwrap().rows.is_empty());
+    }
+
+    #[cfg(feature = "update-delete-limit")]
+    #[test]
+    fn sorts_bigger_than_the_cache_spill() {
+        // a one page cache holds about 40 of these rows
+        let mut db = executor_with(&[
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, n INTEGER);",
+            "PRAGMA cache_size = 1;",
+        ]);
+        for i in 0..200 {
+            run(
+                &mut db,
+                &format!("INSERT INTO t (n) VALUES ({});", (i * 37) % 200),
+            );
+        }
+        run(&mut db, "DELETE FROM t ORDER BY n DESC LIMIT 195;");
+        assert_eq!(
+            run(&mut db, "SELECT n FROM t;"),
+            [0, 4, 3, 2, 1].map(|n| [ColVal::Int(n)])
+        );
+    }
+
+    #[test]
+    fn selects_bigger_than_the_cache_sort_in_spilled_runs() {
+        let mut db = executor_with(&[
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, n INTEGER);",
+            "PRAGMA cache_size = 1;",
+            "PRAGMA cache_replacement = '2q';",
+        ]);
+        for i in 0..200 {
+            run(
+                &mut db,
+                &format!("INSERT INTO t (n) VALUES ({});", (i * 37) % 200),
+            );
+        }
+        // a one page cache holds about 40 of these rows, so the sort writes five runs
+        assert_eq!(db.config().memory_budget(), 4096);
+        let sorted = run(&mut db, "SELECT n FROM t ORDER BY n DESC;");
+        assert_eq!(
+            sorted,
+            (0..200).rev().map(|n| [ColVal::Int(n)]).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            run(&mut db, "SELECT id, n FROM t ORDER BY n LIMIT 2 OFFSET 1;"),
+            [
+                [ColVal::Int(174), ColVal::Int(1)],
+                [ColVal::Int(147), ColVal::Int(2)]
+            ]
+        );
+    }
+
+    #[test]
+    fn a_heap_limit_caps_the_cache_and_splits_sorts_and_joins() {
+        let mut db = executor_with(&[
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, n INTEGER);",
+            "CREATE TABLE u (n INTEGER);",
+        ]);
+        for i in 0..200 {
+            run(
+                &mut db,
+                &format!("INSERT INTO t (n) VALUES ({});", (i * 37) % 200),
+            );
+            run(&mut db, &format!("INSERT INTO u (n) VALUES ({});", i * 2));
+        }
+        // far less than the default cache, a page of it
+        run(&mut db, "PRAGMA hard_heap_limit = 5000;");
+        assert_eq!(db.config().cache_pages(), 1);
+        assert_eq!(db.config().memory_budget(), 4096);
+
+        // the index's keys are sorted in runs of a page's worth
+        run(&mut db, "CREATE INDEX t_n ON t (n);");
+        assert_eq!(
+            run(&mut db, "SELECT id FROM t WHERE n = 74;"),
+            [[ColVal::Int(3)]]
+        );
+        let joined = run(&mut db, "SELECT n FROM t WHERE n IN (SELECT n FROM u);");
+        assert_eq!(joined.len(), 100);
+
+        run(&mut db, "PRAGMA hard_heap_limit = 0;");
+        assert_eq!(db.config().cache_pages(), 500);
+    }
+
+    #[test]
+    fn autoincrement_keeps_its_sequence_in_sqlite_sequence() {
+        let mut db = executor_with(&[
+            "CREATE TABLE t (id INTEGER PRIMARY KEY AUTOINCREMENT, v TEXT);",
+            "INSERT INTO t (v) VALUES (\"a\");",
+            "INSERT INTO t (id, v) VALUES (10, \"b\");",
+        ]);
+        let sequence = |db: &mut Executor| run(db, "SELECT seq FROM sqlite_sequence;");
+        assert_eq!(sequence(&mut db), [[ColVal::Int(10)]]);
+
+        // rolled back, the row of sqlite_sequence is as it was
+        run(&mut db, "BEGIN;");
+        run(&mut db, "INSERT INTO t (v) VALUES (\"c\");");
+        assert_eq!(sequence(&mut db), [[ColVal::Int(11)]]);
+        run(&mut db, "ROLLBACK;");
+        assert_eq!(sequence(&mut db), [[ColVal::Int(10)]]);
+
+        // a larger seq written by hand is skipped past, by a batch too
+        run(
+            &mut db,
+            "UPDATE sqlite_sequence SET seq = 100 WHERE name = \"t\";",
+        );
+        db.append_batch("t", [vec![ColVal::Null, text("d")]])
+            .unwrap();
+        assert_eq!(db.last_insert_rowid(), 101);
+        assert_eq!(sequence(&mut db), [[ColVal::Int(101)]]);
+    }
+
+    #[test]
+    fn generated_columns_are_worked_out_as_rows_are_written() {
+        let mut db = executor_with(&[
+            "CREATE TABLE items (price INTEGER, qty INTEGER, \
+             total INTEGER GENERATED ALWAYS AS (price * qty) STORED, \
+             doubled AS (total * 2));",
+            "CREATE INDEX idx_doubled ON items (doubled);",
+            "INSERT INTO items (price, qty) VALUES (2, 3);",
+        ]);
+        run(&mut db, "UPDATE items SET qty = 5 WHERE price = 2;");
+        db.append_batch(
+            "items",
+            [vec![
+                ColVal::Int(1),
+                ColVal::Int(4),
+                ColVal::Null,
+                ColVal::Null,
+            ]],
+        )
+        .unwrap();
+        let all = [
+            [
+                ColVal::Int(2),
+                ColVal::Int(5),
+                ColVal::Int(10),
+                ColVal::Int(20),
+            ],
+            [
+                ColVal::Int(1),
+                ColVal::Int(4),
+                ColVal::Int(4),
+                ColVal::Int(8),
+            ],
+        ];
+        assert_eq!(run(&mut db, "SELECT * FROM items;"), all);
+        assert_eq!(
+            run(&mut db, "SELECT price FROM items WHERE doubled = 20;"),
+            [[ColVal::Int(2)]]
+        );
+
+        let fails = |db: &mut Executor, sql: &str| db.execute_sql(sql).unwrap_err().to_string();
+        assert_eq!(
+            fails(&mut db, "INSERT INTO items (price, total) VALUES (1, 1);"),
+            "cannot INSERT into generated column \"total\""
+        );
+        assert_eq!(
+            fails(&mut db, "UPDATE items SET doubled = 0;"),
+            "cannot UPDATE generated column \"doubled\""
+        );
+        assert_eq!(
+            fails(&mut db, "CREATE TABLE t (a, b AS (random()));"),
+            "non-deterministic functions prohibited in generated columns"
+        );
+
+        // the image leaves the VIRTUAL column out, and it is worked out again when loaded
+        let image = db.image();
+        let (_, _, row) = image.iter().find(|(table, _, _)| table == "items").unwrap();
+        assert_eq!(row.len(), 3);
+        let mut replica = Executor::replica(image).unwrap();
+        assert_eq!(run(&mut replica, "SELECT * FROM items;"), all);
+    }
+
+    #[test]
+    fn built_in_functions_can_be_checked() {
+        let mut db = executor_with(&["CREATE TABLE t (name CHECK (length(trim(name)) > 0));"]);
+        db.execute_sql("INSERT INTO t (name) VALUES (\"ann\");")
+            .unwrap();
+        assert_eq!(
+            db.execute_sql("INSERT INTO t (name) VALUES (\"  \");")
+                .unwrap_err()
+                .to_string(),
+            "CHECK constraint failed: length(trim(name)) > 0"
+        );
+        assert_eq!(
+            run(&mut db, "SELECT upper(name), typeof(name) FROM t;"),
+            vec![vec![text("ANN"), text("text")]]
+        );
+    }
+
+    #[test]
+    fn result_columns_keep_every_digit_of_a_real() {
+        let mut db = executor_with(&[
+            "CREATE TABLE t (a INTEGER);",
+            "INSERT INTO t (a) VALUES (1);",
+        ]);
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT 0.30000000000000004 = 0.3, 0.30000000000000004;"
+            ),
+            [[ColVal::Boolean(false), ColVal::Real(0.30000000000000004)]]
+        );
+        assert_eq!(
+            run(&mut db, "SELECT a * 1e-300, a + 0.1 FROM t;"),
+            [[ColVal::Real(1e-300), ColVal::Real(1.1)]]
+        );
+        assert_eq!(
+            run(
+                &mut db,
+                "SELECT a FROM t WHERE a * 0.30000000000000004 > 0.3;"
+            ),
+            [[ColVal::Int(1)]]
+        );
+    }
+
+    #[test]
+    fn rows_that_fail_a_check_are_refused() {
+        let mut db = executor_with(&[
+            "CREATE TABLE stock (item TEXT, qty INTEGER CHECK (qty >= 0), price INTEGER, \
+             total AS (qty * price), CHECK (total < 100));",
+            "INSERT INTO stock (item, qty, price) VALUES (\"pen\", 3, 2);",
+            // NULL is not FALSE, so passes
+            "INSERT INTO stock (item, price) VALUES (\"ink\", 5);",
+        ]);
+        let fails = |db: &mut Executor, sql: &str| {
+            let err = db.execute_sql(sql).unwrap_err();
+            assert_eq!(crate::error::find(&err).map(SqlError::code), Some(275));
+            err.to_string()
+        };
+        assert_eq!(
+            fails(
+                &mut db,
+                "INSERT INTO stock (item, qty) VALUES (\"cap\", -1);"
+            ),
+            "CHECK constraint failed: qty >= 0"
+        );
+        assert_eq!(
+            fails(&mut db, "UPDATE stock SET price = 50 WHERE item = \"pen\";"),
+            "CHECK constraint failed: total < 100"
+        );
+        assert!(db
+            .append_batch(
+                "stock",
+                [vec![
+                    text("mug"),
+                    ColVal::Int(-2),
+                    ColVal::Int(1),
+                    ColVal::Null
+                ]],
+            )
+            .is_err());
+        // the pen passes but the ink fails, and a failed UPDATE changes no row at all
+        assert!(db.execute_sql("UPDATE stock SET qty = 20;").is_err());
+        assert_eq!(
+            run(&mut db, "SELECT item, qty FROM stock;"),
+            [[text("pen"), ColVal::Int(3)], [text("ink"), ColVal::Null]]
+        );
+
+        assert_eq!(
+            db.execute_sql("CREATE TABLE t (a CHECK (a > random()));")
+                .unwrap_err()
+                .to_string(),
+            "non-deterministic functions prohibited in CHECK constraints"
+        );
+    }
+
+    #[test]
+    fn nulls_are_kept_out_of_not_null_columns() {
+        let mut db = executor_with(&[
+            "CREATE TABLE users (id INTEGER PRIMARY KEY NOT NULL, name TEXT NOT NULL, \
+             email TEXT NOT NULL DEFAULT 'none', CHECK (length(name) > 1));",
+            // the rowid fills in an INTEGER PRIMARY KEY left out
+            "INSERT INTO users (name) VALUES ('ann');",
+        ]);
+        let fails = |db: &mut Executor, sql: &str| {
+            let err = db.execute_sql(sql).unwrap_err();
+            assert_eq!(crate::error::find(&err).map(SqlError::code), Some(1299));
+            err.to_string()
+        };
+        assert_eq!(
+            fails(
+                &mut db,
+                "INSERT INTO users (email) VALUES ('b@example.com');"
+            ),
+            "NOT NULL constraint failed: users.name"
+        );
+        // before the CHECK, which a NULL name would pass
+        assert_eq!(
+            fails(&mut db, "UPDATE users SET name = NULL, email = NULL;"),
+            "NOT NULL constraint failed: users.name"
+        );
+        assert_eq!(
+            run(&mut db, "SELECT id, name, email FROM users;"),
+            [[ColVal::Int(1), text("ann"), text("none")]]
+        );
+    }
+
+    #[test]
+    fn rollback_undoes_the_transactions_writes() {
+        let mut db = executor_with(&[
+            "CREATE TABLE t (k TEXT PRIMARY KEY, v INTEGER) WITHOUT ROWID;",
+            "INSERT INTO t (k, v) VALUES (\"a\", 1);",
+        ]);
+        run(&mut db, "BEGIN;");
+        run(&mut db, "INSERT INTO t (k, v) VALUES (\"b\", 2);");
+        run(&mut db, "UPDATE t SET k = \"z\", v = 9 WHERE k = \"a\";");
+        assert_eq!(
+            run(&mut db, "SELECT k, v FROM t;"),
+            [[text("b"), ColVal::Int(2)], [text("z"), ColVal::Int(9)]]
+        );
+        run(&mut db, "ROLLBACK;");
+        assert_eq!(
+            run(&mut db, "SELECT k, v FROM t;"),
+            [[text("a"), ColVal::Int(1)]]
+        );
+        assert_eq!(run(&mut db, "PRAGMA table_info(t);").len(), 2);
+    }
+
+    #[test]
+    fn rollback_undoes_the_transactions_schema_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let path = path.to_str().unwrap();
+        let mut db = Executor::open(OpenTarget::parse(path).unwrap()).unwrap();
+        run(&mut db, "CREATE TABLE t (a INTEGER);");
+        run(&mut db, "INSERT INTO t (a) VALUES (1);");
+        let catalog = run(&mut db, "SELECT name FROM sqlite_master;");
+
+        run(&mut db, "BEGIN;");
+        run(
+            &mut db,
+            "CREATE TABLE x (a INTEGER, b TEXT, PRIMARY KEY (a, b));",
+        );
+        run(&mut db, "INSERT INTO x (a, b) VALUES (1, \"one\");");
+        run(&mut db, "CREATE INDEX idx_a ON t (a);");
+        run(&mut db, "CREATE VIEW v AS SELECT a FROM t;");
+        run(&mut db, "INSERT INTO t (a) VALUES (2);");
+        run(&mut db, "ROLLBACK;");
+
+        for sql in ["SELECT * FROM x;", "SELECT * FROM v;"] {
+            assert!(db
+                .execute_sql(sql)
+                .unwrap_err()
+                .to_string()
+                .starts_with("no such table"));
+        }
+        assert_eq!(run(&mut db, "SELECT name FROM sqlite_master;"), catalog);
+        assert!(!db
+            .execute_sql("EXPLAIN QUERY PLAN SELECT * FROM t WHERE a = 1;")
+            .unwrap()
+            .to_string()
+            .contains("idx_a"));
+        assert_eq!(run(&mut db, "SELECT a FROM t;"), [[ColVal::Int(1)]]);
+
+        // the names are free again, and the file never had them
+        run(&mut db, "CREATE TABLE x (c TEXT);");
+        run(&mut db, "CREATE INDEX idx_a ON t (a);");
+        drop(db);
+        let mut db = Executor::open(OpenTarget::parse(path).unwrap()).unwrap();
+        assert_eq!(run(&mut db, "PRAGMA table_info(x);").len(), 1);
+
+        // and a savepoint's CREATE is undone by ROLLBACK TO
+        run(&mut db, "SAVEPOINT sp;");
+        run(&mut db, "CREATE TABLE y (a);");
+        run(&mut db, "ROLLBACK TO sp;");
+        run(&mut db, "RELEASE sp;");
+        assert!(db.execute_sql("SELECT * FROM y;").is_err());
+    }
+
+    #[test]
+    fn rollback_to_undoes_the_savepoints_writes() {
+        let mut db = executor_with(&[
+            "CREATE TABLE ledger (id INTEGER PRIMARY KEY, amount INTEGER);",
+            "CREATE INDEX idx_amount ON ledger (amount);",
+        ]);
+        let fails = |db: &mut Executor, sql: &str| db.execute_sql(sql).unwrap_err().to_string();
+        run(&mut db, "SAVEPOINT outer_sp;");
+        run(&mut db, "INSERT INTO ledger (amount) VALUES (10);");
+        run(&mut db, "SAVEPOINT inner_sp;");
+        run(&mut db, "INSERT INTO ledger (amount) VALUES (20);");
+        run(&mut db, "UPDATE ledger SET amount = 11 WHERE id = 1;");
+        run(&mut db, "ROLLBACK TO inner_sp;");
+        assert_eq!(
+            run(&mut db, "SELECT * FROM ledger;"),
+            [[ColVal::Int(1), ColVal::Int(10)]]
+        );
+
+        run(&mut db, "INSERT INTO ledger (amount) VALUES (30);");
+        run(&mut db, "RELEASE inner_sp;");
+        assert_eq!(
+            fails(&mut db, "ROLLBACK TO inner_sp;"),
+            "no such savepoint: inner_sp"
+        );
+        // the savepoint began a transaction, which BEGIN can't begin again
+        assert_eq!(
+            fails(&mut db, "BEGIN;"),
+            "cannot start a transaction within a transaction"
+        );
+        // rowid 2 was handed out before it was rolled back, so isn't again, see table.rs
+        assert_eq!(
+            run(&mut db, "SELECT id FROM ledger WHERE amount = 30;"),
+            [[ColVal::Int(3)]]
+        );
+        // and releasing the outermost savepoint commits it
+        run(&mut db, "RELEASE SAVEPOINT outer_sp;");
+        assert_eq!(
+            fails(&mut db, "ROLLBACK;"),
+            "cannot rollback - no transaction is active"
+        );
+        assert_eq!(run(&mut db, "SELECT * FROM ledger;").len(), 2);
+    }
+
+    #[test]
+    fn batches_are_appended_all_or_nothing() {
+        let mut db = executor_with(&[
+            "CREATE TABLE events (id INTEGER PRIMARY KEY, kind TEXT, at INTEGER);",
+            "CREATE UNIQUE INDEX idx_at ON events (at);",
+        ]);
+        let rows = (0..1000).map(|i| {
+            vec![
+                ColVal::Null,
+                ColVal::String(format!("kind{}", i % 3)),
+                ColVal::String(i.to_string()),
+            ]
+        });
+        assert_eq!(db.append_batch("events", rows).unwrap(), 1000);
+        // rowids were handed out and the text given the column's INTEGER affinity
+        assert_eq!(
+            run(&mut db, "SELECT id, kind, at FROM events WHERE at = 999;"),
+            [[
+                ColVal::Int(1000),
+                ColVal::String("kind0".to_string()),
+                ColVal::Int(999)
+            ]]
+        );
+
+        // the duplicate at the end undoes the rows before it
+        let rows = [1000, 1001, 5].map(|at| vec![ColVal::Null, ColVal::Null, ColVal::Int(at)]);
+        assert_eq!(
+            db.append_batch("events", rows).unwrap_err().to_string(),
+            "UNIQUE constraint failed: events.at"
+        );
+        assert_eq!(
+            run(&mut db, "SELECT COUNT(*) FROM events;"),
+            [[ColVal::Int(1000)]]
+        );
+        // as it does inside a transaction, without ending the transaction
+        run(&mut db, "BEGIN;");
+        run(&mut db, "DELETE FROM events WHERE at < 10;");
+        assert!(db.append_batch("events", [vec![ColVal::Int(1)]]).is_err());
+        run(&mut db, "ROLLBACK;");
+        assert_eq!(
+            run(&mut db, "SELECT COUNT(*) FROM events;"),
+            [[ColVal::Int(1000)]]
+        );
+
+        assert_eq!(
+            db.append_batch("sqlite_master", [])
+                .unwrap_err()
+                .to_string(),
+            "table sqlite_master may not be modified"
+        );
+    }
+
+    #[test]
+    fn batches_into_empty_tables_are_bulk_loaded() {
+        let mut db = executor_with(&["CREATE TABLE log (id INTEGER PRIMARY KEY, line TEXT);"]);
+        let rows = (0..500).map(|i| vec![ColVal::Null, ColVal::String(format!("line {i}"))]);
+        assert_eq!(db.append_batch("log", rows).unwrap(), 500);
+        // numbered as they would have been one by one, and the next goes on the end
+        run(&mut db, "INSERT INTO log (line) VALUES (\"last\");");
+        assert_eq!(
+            run(&mut db, "SELECT id, line FROM log WHERE id > 499;"),
+            [
+                [ColVal::Int(500), ColVal::String("line 499".to_string())],
+                [ColVal::Int(501), ColVal::String("last".to_string())]
+            ]
+        );
+
+        // rowids out of order go in a row at a time, and a duplicate still undoes them all
+        run(&mut db, "CREATE TABLE t (id INTEGER PRIMARY KEY);");
+        let rows = [3, 1, 2].map(|id| vec![ColVal::Int(id)]);
+        assert_eq!(db.append_batch("t", rows).unwrap(), 3);
+        run(&mut db, "DELETE FROM t WHERE id > 0;");
+        let rows = [4, 5, 4].map(|id| vec![ColVal::Int(id)]);
+        assert!(db.append_batch("t", rows).is_err());
+        // and a bulk load is undone with the transaction it is in
+        run(&mut db, "BEGIN;");
+        let rows = [7, 8, 9].map(|id| vec![ColVal::Int(id)]);
+        assert_eq!(db.append_batch("t", rows).unwrap(), 3);
+        run(&mut db, "ROLLBACK;");
+        assert_eq!(run(&mut db, "SELECT COUNT(*) FROM t;"), [[ColVal::Int(0)]]);
+    }
+}
@@ -0,0 +1,369 @@
+/*
+    Foreign key actions, what happens to child rows when the parent row they refer to goes.
+
+        CREATE TABLE orders (
+            id INTEGER PRIMARY KEY,
+            user_id REFERENCES users (id) ON DELETE CASCADE ON UPDATE SET NULL
+        );
+
+    Deleting a user, or changing its id, leaves the orders that referred to it pointing at
+    nothing. Each foreign key says what to do about that, separately for ON DELETE and
+    ON UPDATE:
+
+        NO ACTION    nothing, but the statement fails with "FOREIGN KEY constraint failed"
+                     if child rows are still left pointing at nothing once it is done. The
+                     default.
+        RESTRICT     the same, except it fails straight away rather than at the end of the
+                     statement, so not even the statement itself gets to fix things up
+        SET NULL     set the child rows' key columns to NULL
+        SET DEFAULT  set them to the columns' DEFAULT values, NULL for columns without one
+        CASCADE      delete the child rows with the parent, or change their key along with
+                     the parent's
+
+    Like SQLite we carry the actions out as statements on the child table, much like a
+    trigger. For each parent row a write touches, `actions` gives the checks and writes to
+    run. They run as part of the parent statement, inside its transaction, so when any of
+    them fails the whole statement is undone with everything it cascaded to.
+
+    The child's side is checked too: a row inserted into orders, or one whose user_id
+    changes, must have a user to refer to, which `parent_checks` gives the check for. A
+    NULL in the key refers to nothing and needs no parent. Like NO ACTION these wait for
+    the end of the statement, so that a statement may insert a child before its parent.
+
+    A cascade's writes can have actions of their own, a deleted user deletes its orders
+    which delete their order lines. Each level down goes a level deeper through the
+    executor's Nesting, so a cycle of foreign keys gives up with "too many levels of
+    trigger recursion" rather than running forever, see nesting.rs.
+
+    None of this happens unless `PRAGMA foreign_keys = ON`, which as in SQLite is off
+    when a connection opens, see executor.rs.
+
+    The parent columns a foreign key refers to must be the parent's primary key or have
+    a unique index on them, or we couldn't tell which parent row a child belongs to.
+*/
+use crate::error::SqlError;
+use crate::planner::Catalog;
+use crate::schema::Schema;
+use crate::sql_parser::ast::{
+    BinaryOp, ColVal, CreateTable, Expr, ForeignKey, ForeignKeyAction, NewColumnVal, Statement,
+};
+use crate::trigger::TriggerRow;
+use anyhow::{anyhow, bail, Result};
+
+/// One thing to do on a child table for a write to its parent.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ForeignKeyStep {
+    // Fail with "FOREIGN KEY constraint failed" if the SELECT gives any rows, straight
+    // away when immediate and otherwise once the parent statement is done.
+    Check { select: Statement, immediate: bool },
+    // Fail with the same if the SELECT, of a child row's parent, gives none once the
+    // statement is done.
+    Parent(Statement),
+    // Write to the child rows, which may need actions of its own one level deeper.
+    Write(Statement),
+}
+
+/// The steps a write to a row of `parent` needs on the child tables. A DELETE has no NEW
+/// row, an UPDATE has both, and an INSERT needs nothing as no child can refer to a row
+/// that didn't exist.
+pub fn actions(schema: &Schema, parent: &str, row: &TriggerRow) -> Result<Vec<ForeignKeyStep>> {
+    let Some(old) = row.old else {
+        return Ok(vec![]);
+    };
+
+    let mut steps = vec![];
+    for (child, key) in schema.referencing(parent) {
+        let parent_columns = parent_key(schema, child, key)?;
+        let values = |values: &[ColVal]| -> Result<Vec<ColVal>> {
+            let mut key = vec![];
+            for c in &parent_columns {
+                let Some(i) = row.columns.iter().position(|rc| rc == c) else {
+                    bail!(SqlError::NoSuchColumn {
+                        column: c.to_string()
+                    });
+                };
+                key.push(values[i].clone());
+            }
+            Ok(key)
+        };
+        let old_key = values(old)?;
+        // nothing refers to a NULL key
+        if old_key.contains(&ColVal::Null) {
+            continue;
+        }
+        let (action, new_key) = match row.new {
+            None => (key.on_delete, None),
+            Some(new) => {
+                let new_key = values(new)?;
+                if new_key == old_key {
+                    continue;
+                }
+                (key.on_update, Some(new_key))
+            }
+        };
+
+        let where_clause = Some(key_equals(&key.columns, &old_key));
+        let set = |values: Vec<Expr>| Statement::Update {
+            table: child.name.clone(),
+            assignments: key
+                .columns
+                .iter()
+                .zip(values)
+                .map(|(column, value)| NewColumnVal {
+                    column_name: column.clone(),
+                    value,
+                })
+                .collect(),
+            where_clause: where_clause.clone(),
+            order_by: vec![],
+            limit: None,
+        };
+        steps.push(match (action, new_key) {
+            (ForeignKeyAction::NoAction | ForeignKeyAction::Restrict, _) => ForeignKeyStep::Check {
+                select: select_one(&child.name, key_equals(&key.columns, &old_key)),
+                immediate: action == ForeignKeyAction::Restrict,
+            },
+            (ForeignKeyAction::Cascade, None) => ForeignKeyStep::Write(Statement::Delete {
+                from_table: child.name.clone(),
+                where_clause: where_clause.clone(),
+                order_by: vec![],
+                limit: None,
+            }),
+            (ForeignKeyAction::Cascade, Some(new_key)) => {
+                ForeignKeyStep::Write(set(new_key.into_iter().map(Expr::Literal).collect()))
+            }
+            (ForeignKeyAction::SetNull, _) => ForeignKeyStep::Write(set(key
+                .columns
+                .iter()
+                .map(|_| Expr::Literal(ColVal::Null))
+                .collect())),
+            (ForeignKeyAction::SetDefault, _) => ForeignKeyStep::Write(set(key
+                .columns
+                .iter()
+                .map(|c| {
+                    let column = child.columns.iter().find(|cc| cc.name == *c);
+                    let default = column.and_then(|cc| cc.default.clone());
+                    Expr::Literal(default.unwrap_or(ColVal::Null))
+                })
+                .collect())),
+        });
+    }
+    Ok(steps)
+}
+
+/// The checks that the parents of a row written to `child` are there, one
+/// ForeignKeyStep::Parent for each of its foreign keys. An INSERT has no OLD row, and an
+/// UPDATE needs no check for a key it left as it was. A DELETE needs none.
+pub fn parent_checks(
+    schema: &Schema,
+    child: &CreateTable,
+    row: &TriggerRow,
+) -> Result<Vec<ForeignKeyStep>> {
+    let Some(new) = row.new else {
+        return Ok(vec![]);
+    };
+    let mut steps = vec![];
+    for key in &child.foreign_keys {
+        let mut values = vec![];
+        for column in &key.columns {
+            let Some(i) = row.columns.iter().position(|c| c == column) else {
+                bail!(SqlError::NoSuchColumn {
+                    column: column.to_string()
+                });
+            };
+            values.push(i);
+        }
+        let new_key: Vec<ColVal> = values.iter().map(|&i| new[i].clone()).collect();
+        let unchanged = row
+            .old
+            .is_some_and(|old| values.iter().all(|&i| old[i] == new[i]));
+        if unchanged || new_key.contains(&ColVal::Null) {
+            continue;
+        }
+        let parent_columns = parent_key(schema, child, key)?;
+        steps.push(ForeignKeyStep::Parent(select_one(
+            &key.parent,
+            key_equals(&parent_columns, &new_key),
+        )));
+    }
+    Ok(steps)
+}
+
+// SELECT 1 FROM table WHERE ..., whether there are rows that match.
+fn select_one(table: &str, where_clause: Expr) -> Statement {
+    Statement::Select {
+        columns: vec!["1".to_string()],
+        from_table: table.to_string(),
+        from_select: None,
+        table_args: vec![],
+        alias: None,
+        index_hint: None,
+        joins: vec![],
+        where_clause: Some(where_clause),
+        group_by: vec![],
+        order_by: vec![],
+        limit: None,
+    }
+}
+
+// The parent columns a foreign key refers to, checking they identify a single row.
+fn parent_key(schema: &Schema, child: &CreateTable, key: &ForeignKey) -> Result<Vec<String>> {
+    let mismatch = || {
+        anyhow!(
+            "foreign key mismatch - \"{}\" referencing \"{}\"",
+            child.name,
+            key.parent
+        )
+    };
+    let parent = schema.table(&key.parent).ok_or_else(mismatch)?;
+    let columns = if key.parent_columns.is_empty() {
+        parent.primary_key.clone()
+    } else {
+        key.parent_columns.clone()
+    };
+    let unique = columns == parent.primary_key
+        || schema.table_indexes(&parent.name).iter().any(|i| {
+            i.unique
+                && i.columns.len() == columns.len()
+                && i.columns
+                    .iter()
+                    .all(|c| matches!(c, Expr::Column(c) if columns.contains(c)))
+        });
+    if columns.len() != key.columns.len() || !unique {
+        return Err(mismatch());
+    }
+    Ok(columns)
+}
+
+// c1 = v1 AND c2 = v2 ...
+fn key_equals(columns: &[String], values: &[ColVal]) -> Expr {
+    columns
+        .iter()
+        .zip(values)
+        .map(|(c, v)| Expr::Binary {
+            op: BinaryOp::Eq,
+            left: Box::new(Expr::Column(c.clone())),
+            right: Box::new(Expr::Literal(v.clone())),
+        })
+        .reduce(|left, right| Expr::Binary {
+            op: BinaryOp::And,
+            left: Box::new(left),
+            right: Box::new(right),
+        })
+        .expect("a foreign key has at least one column")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::parse;
+
+    fn schema(tables: &[&str]) -> Schema {
+        let mut schema = Schema::default();
+        for sql in tables {
+            let Statement::CreateTable(table) = parse(sql).unwrap() else {
+                panic!("expected CREATE TABLE");
+            };
+            schema.create_table(&table).unwrap();
+        }
+        schema
+    }
+
+    fn steps(schema: &Schema, old: &[ColVal], new: Option<&[ColVal]>) -> Result<Vec<String>> {
+        let columns = ["id".to_string(), "name".to_string()];
+        let row = TriggerRow {
+            columns: &columns,
+            old: Some(old),
+            new,
+        };
+        Ok(actions(schema, "users", &row)?
+            .into_iter()
+            .map(describe)
+            .collect())
+    }
+
+    fn describe(step: ForeignKeyStep) -> String {
+        match step {
+            ForeignKeyStep::Check { select, immediate } => {
+                format!("CHECK{} {select}", if immediate { " NOW" } else { "" })
+            }
+            ForeignKeyStep::Parent(select) => format!("PARENT {select}"),
+            ForeignKeyStep::Write(write) => write.to_string(),
+        }
+    }
+
+    fn users_and(children: &[&str]) -> Schema {
+        let mut tables = vec!["CREATE TABLE users (id INTEGER PRIMARY KEY, name);"];
+        tables.extend(children);
+        schema(&tables)
+    }
+
+    #[test]
+    fn each_action_becomes_a_statement_on_the_child() {
+        let schema = users_and(&[
+            "CREATE TABLE orders (id, user_id REFERENCES users ON DELETE CASCADE ON UPDATE CASCADE);",
+            "CREATE TABLE posts (id, author REFERENCES users (id) ON DELETE SET NULL ON UPDATE RESTRICT);",
+            "CREATE TABLE logins (who DEFAULT 0 REFERENCES users ON DELETE SET DEFAULT);",
+        ]);
+        let bob = [ColVal::Int(1), ColVal::String("bob".to_string())];
+        assert_eq!(
+            steps(&schema, &bob, None).unwrap(),
+            [
+                "UPDATE logins SET who = 0 WHERE who = 1",
+                "DELETE FROM orders WHERE user_id = 1",
+                "UPDATE posts SET author = NULL WHERE author = 1",
+            ]
+        );
+
+        let renumbered = [ColVal::Int(2), ColVal::String("bob".to_string())];
+        assert_eq!(
+            steps(&schema, &bob, Some(&renumbered)).unwrap(),
+            [
+                "CHECK SELECT 1 FROM logins WHERE who = 1",
+                "UPDATE orders SET user_id = 2 WHERE user_id = 1",
+                "CHECK NOW SELECT 1 FROM posts WHERE author = 1",
+            ]
+        );
+
+        // an update that leaves the key alone concerns no child
+        let renamed = [ColVal::Int(1), ColVal::String("robert".to_string())];
+        assert!(steps(&schema, &bob, Some(&renamed)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keys_must_refer_to_a_unique_parent_key() {
+        let schema = users_and(&["CREATE TABLE orders (user REFERENCES users (name));"]);
+        let bob = [ColVal::Int(1), ColVal::String("bob".to_string())];
+        assert_eq!(
+            steps(&schema, &bob, None).unwrap_err().to_string(),
+            "foreign key mismatch - \"orders\" referencing \"users\""
+        );
+    }
+
+    #[test]
+    fn a_child_row_written_needs_its_parent() {
+        let schema = users_and(&["CREATE TABLE orders (id, user_id REFERENCES users);"]);
+        let orders = schema.table("orders").unwrap();
+        let columns = ["id".to_string(), "user_id".to_string()];
+        let checks = |old: Option<&[ColVal]>, new: &[ColVal]| {
+            let row = TriggerRow {
+                columns: &columns,
+                old,
+                new: Some(new),
+            };
+            parent_checks(&schema, orders, &row)
+                .unwrap()
+                .into_iter()
+                .map(describe)
+                .collect::<Vec<_>>()
+        };
+        let order = [ColVal::Int(7), ColVal::Int(1)];
+        assert_eq!(
+            checks(None, &order),
+            ["PARENT SELECT 1 FROM users WHERE id = 1"]
+        );
+        // a NULL key refers to nothing, and a key left as it was is still fine
+        assert!(checks(None, &[ColVal::Int(7), ColVal::Null]).is_empty());
+        assert!(checks(Some(&order), &[ColVal::Int(8), ColVal::Int(1)]).is_empty());
+    }
+}
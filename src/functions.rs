@@ -0,0 +1,611 @@
+/*
+    The function registry holds every SQL function the engine knows about, built-in or
+    registered later by an embedder, together with the metadata the rest of the engine
+    needs to reason about a call before ever evaluating it.
+
+    The most important piece of metadata is determinism. A deterministic function always
+    returns the same result given the same arguments, abs(-1) is always 1, whereas random()
+    or changes() depend on hidden state. Like SQLite we refuse non-deterministic functions
+    anywhere their result gets persisted and compared later: index expressions, CHECK
+    constraints and generated columns. An index built on random() would never find its
+    own entries again.
+
+    An embedder adds functions of its own with create_scalar_function, the counterpart of
+    sqlite3_create_function. It takes a Rust closure that the evaluator calls with the
+    values of the arguments, so once registered
+
+        functions.create_scalar_function("double", Some(1), true, |args| {
+            Ok(ColVal::Int(as_integer(&args[0]).unwrap_or(0) * 2))
+        });
+
+    `SELECT double(price) FROM items` works like any built-in. An error the closure
+    returns fails the statement with that message, as sqlite3_result_error does. The
+    JSON functions are built in the same way, see json.rs.
+*/
+use crate::error::SqlError;
+use crate::eval::{as_integer, as_number, as_real, as_text};
+use crate::json;
+use crate::sql_parser::ast::{ColVal, Expr};
+use crate::storage::overflow;
+use anyhow::{bail, Result};
+use rand::Rng;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// The signature of a scalar function's implementation, from argument values to result.
+pub type ScalarFn = dyn Fn(&[ColVal]) -> Result<ColVal> + Send + Sync;
+
+#[derive(Clone)]
+struct Implementation(Arc<ScalarFn>);
+
+impl fmt::Debug for Implementation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<function>")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    pub name: String,
+    pub min_args: usize,
+    pub max_args: Option<usize>, // None means variadic
+    pub deterministic: bool,
+}
+
+impl FunctionDef {
+    pub fn new(name: &str, min_args: usize, max_args: Option<usize>, deterministic: bool) -> Self {
+        FunctionDef {
+            name: name.to_lowercase(),
+            min_args,
+            max_args,
+            deterministic,
+        }
+    }
+
+    pub fn accepts_arg_count(&self, n: usize) -> bool {
+        n >= self.min_args && self.max_args.map_or(true, |max| n <= max)
+    }
+}
+
+/// Places in a schema where an expression is stored and re-evaluated later,
+/// so it must produce the same result every time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeterministicContext {
+    IndexExpression,
+    CheckConstraint,
+    GeneratedColumn,
+}
+
+impl DeterministicContext {
+    fn describe(&self) -> &'static str {
+        match self {
+            DeterministicContext::IndexExpression => "index expressions",
+            DeterministicContext::CheckConstraint => "CHECK constraints",
+            DeterministicContext::GeneratedColumn => "generated columns",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, FunctionDef>, // keyed by lowercased name as SQL is case insensitive
+    // for the functions that can be called here, all but the few the executor answers
+    // itself from the connection's state, like changes()
+    implementations: HashMap<String, Implementation>,
+}
+
+impl Default for FunctionRegistry {
+    fn default() -> Self {
+        Self::with_builtins()
+    }
+}
+
+impl FunctionRegistry {
+    pub fn empty() -> Self {
+        FunctionRegistry {
+            functions: HashMap::new(),
+            implementations: HashMap::new(),
+        }
+    }
+
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::empty();
+        let builtins = [
+            FunctionDef::new("changes", 0, Some(0), false),
+            FunctionDef::new("total_changes", 0, Some(0), false),
+            FunctionDef::new("last_insert_rowid", 0, Some(0), false),
+        ];
+        for def in builtins {
+            registry.register(def);
+        }
+        registry.define(FunctionDef::new("abs", 1, Some(1), true), abs);
+        registry.define(FunctionDef::new("coalesce", 2, None, true), coalesce);
+        registry.define(FunctionDef::new("ifnull", 2, Some(2), true), coalesce);
+        registry.define(FunctionDef::new("length", 1, Some(1), true), |args| {
+            // a blob's length is in bytes, text's in characters
+            if let ColVal::Blob(b) = &args[0] {
+                return Ok(ColVal::Int(b.len() as i64));
+            }
+            Ok(text_of(&args[0]).map_or(ColVal::Null, |s| ColVal::Int(s.chars().count() as i64)))
+        });
+        registry.define(FunctionDef::new("lower", 1, Some(1), true), |args| {
+            Ok(text_of(&args[0]).map_or(ColVal::Null, |s| ColVal::String(s.to_ascii_lowercase())))
+        });
+        registry.define(FunctionDef::new("upper", 1, Some(1), true), |args| {
+            Ok(text_of(&args[0]).map_or(ColVal::Null, |s| ColVal::String(s.to_ascii_uppercase())))
+        });
+        registry.define(FunctionDef::new("max", 2, None, true), |args| {
+            Ok(extreme(args, Ordering::Greater))
+        });
+        registry.define(FunctionDef::new("min", 2, None, true), |args| {
+            Ok(extreme(args, Ordering::Less))
+        });
+        registry.define(FunctionDef::new("nullif", 2, Some(2), true), |args| {
+            Ok(if args[0] == args[1] {
+                ColVal::Null
+            } else {
+                args[0].clone()
+            })
+        });
+        registry.define(FunctionDef::new("round", 1, Some(2), true), round);
+        registry.define(FunctionDef::new("substr", 2, Some(3), true), substr);
+        registry.define(FunctionDef::new("trim", 1, Some(2), true), trim);
+        registry.define(FunctionDef::new("typeof", 1, Some(1), true), |args| {
+            let name = match &args[0] {
+                ColVal::Null => "null",
+                ColVal::Boolean(_) | ColVal::Int(_) => "integer",
+                ColVal::Real(_) => "real",
+                ColVal::String(_) => "text",
+                ColVal::Blob(_) => "blob",
+            };
+            Ok(ColVal::String(name.to_string()))
+        });
+        registry.define(FunctionDef::new("random", 0, Some(0), false), |_| {
+            Ok(ColVal::Int(rand::random()))
+        });
+        // at least one byte, as in SQLite, and no more than a blob may hold
+        registry.define(FunctionDef::new("randomblob", 1, Some(1), false), |args| {
+            let n = as_integer(&args[0]).unwrap_or(1).max(1);
+            if n as u64 > overflow::MAX_LENGTH {
+                bail!("string or blob too big");
+            }
+            let mut blob = vec![0; n as usize];
+            rand::thread_rng().fill(&mut blob[..]);
+            Ok(ColVal::Blob(blob))
+        });
+        json::register(&mut registry);
+        registry
+    }
+
+    /// Registering a function under an existing name replaces the previous definition.
+    pub fn register(&mut self, def: FunctionDef) {
+        self.implementations.remove(&def.name);
+        self.functions.insert(def.name.clone(), def);
+    }
+
+    /// Register a function implemented by a Rust closure. `arg_count` is the number of
+    /// arguments it takes, None for any number. Only mark it deterministic if the same
+    /// arguments always give the same result, see check_deterministic.
+    pub fn create_scalar_function(
+        &mut self,
+        name: &str,
+        arg_count: Option<usize>,
+        deterministic: bool,
+        function: impl Fn(&[ColVal]) -> Result<ColVal> + Send + Sync + 'static,
+    ) {
+        let (min_args, max_args) = match arg_count {
+            Some(n) => (n, Some(n)),
+            None => (0, None),
+        };
+        self.define(
+            FunctionDef::new(name, min_args, max_args, deterministic),
+            function,
+        );
+    }
+
+    /// Register a function implemented by a Rust closure, as create_scalar_function does,
+    /// for those that take a range of argument counts.
+    pub fn define(
+        &mut self,
+        def: FunctionDef,
+        function: impl Fn(&[ColVal]) -> Result<ColVal> + Send + Sync + 'static,
+    ) {
+        let name = def.name.clone();
+        self.register(def);
+        self.implementations
+            .insert(name, Implementation(Arc::new(function)));
+    }
+
+    /// Call a function with the values of its arguments.
+    pub fn call(&self, name: &str, args: &[ColVal]) -> Result<ColVal> {
+        let Some(def) = self.lookup(name) else {
+            bail!(SqlError::NoSuchFunction {
+                name: name.to_string()
+            });
+        };
+        if !def.accepts_arg_count(args.len()) {
+            bail!(SqlError::WrongArgumentCount {
+                name: name.to_string()
+            });
+        }
+        match self.implementations.get(&def.name) {
+            Some(Implementation(function)) => function(args),
+            None => bail!("function {name}() can't be evaluated yet"),
+        }
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&FunctionDef> {
+        self.functions.get(&name.to_lowercase())
+    }
+
+    /// Check every function called in `expr` exists, is given a sensible number of
+    /// arguments and is deterministic, as required wherever the result is persisted.
+    pub fn check_deterministic(&self, expr: &Expr, context: DeterministicContext) -> Result<()> {
+        let mut calls = vec![];
+        expr.walk(&mut |e| {
+            if let Expr::Function { name, args } = e {
+                calls.push((name, args.len()));
+            }
+        });
+
+        for (name, arg_count) in calls {
+            let Some(def) = self.lookup(name) else {
+                bail!(SqlError::NoSuchFunction {
+                    name: name.to_string()
+                });
+            };
+            if !def.accepts_arg_count(arg_count) {
+                bail!(SqlError::WrongArgumentCount {
+                    name: name.to_string()
+                });
+            }
+            if !def.deterministic {
+                bail!(
+                    "non-deterministic functions prohibited in {}",
+                    context.describe()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// The text of a value, None for NULL.
+fn text_of(v: &ColVal) -> Option<String> {
+    (*v != ColVal::Null).then(|| as_text(v.clone()))
+}
+
+fn abs(args: &[ColVal]) -> Result<ColVal> {
+    Ok(match as_number(&args[0]) {
+        None => ColVal::Null,
+        Some(ColVal::Int(n)) => match n.checked_abs() {
+            Some(n) => ColVal::Int(n),
+            None => bail!("integer overflow"),
+        },
+        Some(ColVal::Real(f)) => ColVal::Real(f.abs()),
+        Some(other) => unreachable!("{other:?} is not a number"),
+    })
+}
+
+// coalesce() and ifnull(), the first argument that isn't NULL.
+fn coalesce(args: &[ColVal]) -> Result<ColVal> {
+    Ok(args
+        .iter()
+        .find(|v| **v != ColVal::Null)
+        .cloned()
+        .unwrap_or(ColVal::Null))
+}
+
+// The scalar max() and min(), NULL if any argument is, and otherwise the argument that
+// sorts last or first.
+fn extreme(args: &[ColVal], wanted: Ordering) -> ColVal {
+    if args.contains(&ColVal::Null) {
+        return ColVal::Null;
+    }
+    args.iter()
+        .skip(1)
+        .fold(
+            &args[0],
+            |best, v| if v.cmp(best) == wanted { v } else { best },
+        )
+        .clone()
+}
+
+// round(X, N) rounds half away from zero to N decimal places, N 0 if missing or
+// negative, and is always a REAL.
+fn round(args: &[ColVal]) -> Result<ColVal> {
+    let Some(f) = as_real(&args[0]) else {
+        return Ok(ColVal::Null);
+    };
+    let digits = match args.get(1) {
+        Some(n) => match as_integer(n) {
+            Some(n) => n.clamp(0, 30) as i32,
+            None => return Ok(ColVal::Null),
+        },
+        None => 0,
+    };
+    let scale = 10f64.powi(digits);
+    // beyond 2^52 a REAL has no fraction left to round
+    if (f * scale).abs() >= 4.5e15 {
+        return Ok(ColVal::Real(f));
+    }
+    Ok(ColVal::Real((f * scale).round() / scale))
+}
+
+// substr(X, Y, Z), the Z characters of X from the Y-th, counting from 1. A negative Y
+// counts back from the end and a negative Z takes the characters before the Y-th, as
+// SQLite's substrFunc does.
+fn substr(args: &[ColVal]) -> Result<ColVal> {
+    if args.contains(&ColVal::Null) {
+        return Ok(ColVal::Null);
+    }
+    let chars = as_text(args[0].clone()).chars().collect::<Vec<_>>();
+    let len = chars.len() as i64;
+    let mut start = as_integer(&args[1]).expect("not NULL");
+    let (mut count, backwards) = match args.get(2) {
+        Some(z) => {
+            let z = as_integer(z).expect("not NULL");
+            (z.saturating_abs(), z < 0)
+        }
+        None => (i64::MAX, false),
+    };
+    if start < 0 {
+        start = start.saturating_add(len);
+        if start < 0 {
+            count = (count + start).max(0);
+            start = 0;
+        }
+    } else if start > 0 {
+        start -= 1;
+    } else if count > 0 {
+        count -= 1;
+    }
+    if backwards {
+        start -= count;
+        if start < 0 {
+            count += start;
+            start = 0;
+        }
+    }
+    let from = start.min(len) as usize;
+    let to = start.saturating_add(count).min(len) as usize;
+    Ok(ColVal::String(chars[from..to].iter().collect()))
+}
+
+// trim(X, Y), X without any of the characters of Y, spaces if not given, at either end.
+fn trim(args: &[ColVal]) -> Result<ColVal> {
+    let Some(text) = text_of(&args[0]) else {
+        return Ok(ColVal::Null);
+    };
+    let trimmed = match args.get(1) {
+        Some(chars) => match text_of(chars) {
+            Some(chars) => text.trim_matches(|c| chars.contains(c)).to_string(),
+            None => return Ok(ColVal::Null),
+        },
+        None => text.trim_matches(' ').to_string(),
+    };
+    Ok(ColVal::String(trimmed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{eval, EvalContext};
+    use crate::sql_parser::ast::Statement;
+    use crate::sql_parser::parse_expr;
+
+    fn parse(src: &str) -> Expr {
+        parse_expr(src).unwrap()
+    }
+
+    #[test]
+    fn deterministic_builtins_are_allowed_in_index_expressions() {
+        let registry = FunctionRegistry::with_builtins();
+        for src in [
+            "lower(name)",
+            "abs(a - b) * 2",
+            "coalesce(nickname, upper(name), 1)",
+        ] {
+            assert!(registry
+                .check_deterministic(&parse(src), DeterministicContext::IndexExpression)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn random_is_rejected_in_every_persisted_context() {
+        let registry = FunctionRegistry::with_builtins();
+        let e = parse("abs(random()) + 1");
+
+        let err = registry
+            .check_deterministic(&e, DeterministicContext::IndexExpression)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "non-deterministic functions prohibited in index expressions"
+        );
+
+        let err = registry
+            .check_deterministic(&e, DeterministicContext::CheckConstraint)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "non-deterministic functions prohibited in CHECK constraints"
+        );
+
+        let err = registry
+            .check_deterministic(&e, DeterministicContext::GeneratedColumn)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "non-deterministic functions prohibited in generated columns"
+        );
+    }
+
+    #[test]
+    fn user_defined_functions_carry_their_own_flag() {
+        let mut registry = FunctionRegistry::with_builtins();
+        registry.register(FunctionDef::new("my_hash", 1, Some(1), true));
+        registry.register(FunctionDef::new("now_ms", 0, Some(0), false));
+
+        assert!(registry
+            .check_deterministic(
+                &parse("MY_HASH(email)"),
+                DeterministicContext::IndexExpression
+            )
+            .is_ok());
+        assert!(registry
+            .check_deterministic(
+                &parse("now_ms() > 0"),
+                DeterministicContext::CheckConstraint
+            )
+            .is_err());
+    }
+
+    struct Row<'r> {
+        functions: &'r FunctionRegistry,
+    }
+
+    impl EvalContext for Row<'_> {
+        fn column(&self, name: &str) -> Result<ColVal> {
+            match name {
+                "price" => Ok(ColVal::Int(21)),
+                _ => bail!(SqlError::NoSuchColumn {
+                    column: name.to_string()
+                }),
+            }
+        }
+
+        fn subquery(&self, _select: &Statement) -> Result<Vec<Vec<ColVal>>> {
+            Ok(vec![])
+        }
+
+        fn exists(&self, _select: &Statement) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn call(&self, name: &str, args: &[ColVal]) -> Result<ColVal> {
+            self.functions.call(name, args)
+        }
+    }
+
+    #[test]
+    fn embedders_can_create_scalar_functions() {
+        let mut functions = FunctionRegistry::with_builtins();
+        functions.create_scalar_function("double", Some(1), true, |args| {
+            Ok(ColVal::Int(as_integer(&args[0]).unwrap_or(0) * 2))
+        });
+        functions.create_scalar_function("fail", None, false, |args| {
+            bail!("failed with {} arguments", args.len())
+        });
+        let row = Row {
+            functions: &functions,
+        };
+
+        assert_eq!(
+            eval(&parse("DOUBLE(price) + 1"), &row).unwrap(),
+            ColVal::Int(43)
+        );
+        for (src, error) in [
+            ("fail(1, 2, price)", "failed with 3 arguments"),
+            (
+                "double(1, 2)",
+                "wrong number of arguments to function double()",
+            ),
+            ("nope(price)", "no such function: nope"),
+        ] {
+            assert_eq!(eval(&parse(src), &row).unwrap_err().to_string(), error);
+        }
+        assert!(functions
+            .check_deterministic(&parse("fail()"), DeterministicContext::IndexExpression)
+            .is_err());
+    }
+
+    #[test]
+    fn built_in_scalar_functions() {
+        let functions = FunctionRegistry::with_builtins();
+        let row = Row {
+            functions: &functions,
+        };
+        let text = |s: &str| ColVal::String(s.to_string());
+        for (src, value) in [
+            ("abs(-price)", ColVal::Int(21)),
+            ("abs(-1.5)", ColVal::Real(1.5)),
+            ("coalesce(NULL, NULL, price)", ColVal::Int(21)),
+            ("ifnull(NULL, 'x')", text("x")),
+            ("length('h\u{e9}llo')", ColVal::Int(5)),
+            ("length(price)", ColVal::Int(2)),
+            ("length(NULL)", ColVal::Null),
+            ("lower('AbC')", text("abc")),
+            ("upper('AbC')", text("ABC")),
+            ("max(1, price, 3)", ColVal::Int(21)),
+            ("min(1, price, 'a')", ColVal::Int(1)),
+            ("max(1, NULL)", ColVal::Null),
+            ("nullif(price, 21)", ColVal::Null),
+            ("nullif(price, 1)", ColVal::Int(21)),
+            ("round(2.5)", ColVal::Real(3.0)),
+            ("round(-2.5)", ColVal::Real(-3.0)),
+            ("round(1.23456, 2)", ColVal::Real(1.23)),
+            ("round(price)", ColVal::Real(21.0)),
+            ("substr('hello', 2)", text("ello")),
+            ("substr('hello', 2, 3)", text("ell")),
+            ("substr('hello', -3, 2)", text("ll")),
+            ("substr('hello', 0, 2)", text("h")),
+            ("substr('hello', 4, -2)", text("el")),
+            ("substr('hello', 9)", text("")),
+            ("trim('  hi  ')", text("hi")),
+            ("trim('xxhixy', 'xy')", text("hi")),
+            ("typeof(price)", text("integer")),
+            ("typeof(1.5)", text("real")),
+            ("typeof('a')", text("text")),
+            ("typeof(NULL)", text("null")),
+            ("typeof(X'CAFE')", text("blob")),
+            ("length(X'CAFE')", ColVal::Int(2)),
+            ("length(randomblob(16))", ColVal::Int(16)),
+            ("length(randomblob(0))", ColVal::Int(1)),
+            ("typeof(randomblob(4))", text("blob")),
+        ] {
+            assert_eq!(eval(&parse(src), &row).unwrap(), value, "{src}");
+        }
+        assert!(matches!(
+            eval(&parse("random()"), &row).unwrap(),
+            ColVal::Int(_)
+        ));
+        for n in ["9223372036854775807", "1000000001"] {
+            assert_eq!(
+                eval(&parse(&format!("randomblob({n})")), &row)
+                    .unwrap_err()
+                    .to_string(),
+                "string or blob too big"
+            );
+        }
+        assert_eq!(
+            eval(&parse("abs(-9223372036854775807 - 1)"), &row)
+                .unwrap_err()
+                .to_string(),
+            "integer overflow"
+        );
+    }
+
+    #[test]
+    fn unknown_functions_and_bad_arity_are_errors() {
+        let registry = FunctionRegistry::with_builtins();
+        let err = registry
+            .check_deterministic(
+                &parse("frobnicate(a)"),
+                DeterministicContext::IndexExpression,
+            )
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no such function: frobnicate");
+
+        let err = registry
+            .check_deterministic(&parse("abs(a, b)"), DeterministicContext::IndexExpression)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "wrong number of arguments to function abs()"
+        );
+    }
+}
@@ -0,0 +1,159 @@
+/*
+    What the fuzz targets in fuzz/ call, behind the fuzz feature. Each takes whatever
+    bytes a fuzzer makes up and hands them to one of the parts of the engine that read
+    input nobody has checked: the SQL parser, and the decoders of the database header,
+    pages, records and images that a damaged or hostile file goes through.
+
+        cargo +nightly fuzz run sql_parser
+
+    Any of them may fail, and nearly always does, but none may panic, loop forever or
+    run out of stack: malformed input is an Err like any other mistake.
+*/
+use crate::sql_parser;
+use crate::storage::header::DatabaseHeader;
+use crate::storage::image;
+use crate::storage::page::SlottedPage;
+use crate::storage::record::{self, Record};
+
+/// Parse `data` as SQL, as the shell would: split into commands and each one parsed.
+pub fn sql(data: &[u8]) {
+    let Ok(script) = std::str::from_utf8(data) else {
+        return;
+    };
+    for command in sql_parser::commands(script) {
+        let _ = sql_parser::is_complete(&command);
+        let _ = sql_parser::parse(&command);
+    }
+}
+
+/// Decode `data` as records one after another, and each column of the first on its own.
+pub fn record(data: &[u8]) {
+    if let Ok(record) = Record::parse(data) {
+        for i in 0..=record.len() {
+            let _ = record.column(i);
+        }
+    }
+    let mut input = data;
+    while !input.is_empty() && record::decode(&mut input).is_ok() {}
+}
+
+/// Read `data` as a page, its first byte as how many bytes are reserved at its end and
+/// padded with zeroes to the smallest page there is, then its cells as records.
+pub fn page(data: &[u8]) {
+    let Some((&reserved, bytes)) = data.split_first() else {
+        return;
+    };
+    let mut bytes = bytes.to_vec();
+    if bytes.len() < 512 {
+        bytes.resize(512, 0);
+    }
+    let Ok(mut page) = SlottedPage::from_bytes(bytes, reserved) else {
+        return;
+    };
+    for i in 0..page.cell_count() {
+        record(page.cell(i));
+    }
+    let _ = page.free_space();
+    // a page that passed the checks must also take changes
+    if page.cell_count() > 0 {
+        page.remove_cell(0);
+    }
+    page.insert_cell(0, &[0; 8]);
+    page.defragment();
+}
+
+/// Read `data` as a database header and as an image, as a replica is sent one, see
+/// replication.rs.
+pub fn file(data: &[u8]) {
+    let _ = DatabaseHeader::parse(data);
+    let _ = image::decode(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::QuickCheck;
+
+    // Words SQL is made of, for the parser to get further than its first character.
+    const TOKENS: &[&str] = &[
+        "SELECT", "FROM", "WHERE", "INSERT", "INTO", "VALUES", "CREATE", "TABLE", "INDEX",
+        "UPDATE", "SET", "DELETE", "CASE", "WHEN", "THEN", "END", "NOT", "IN", "AND", "OR", "(",
+        ")", ",", ";", "*", "-", "=", "<", "'", "\"", "[", "?", "?1", "t", "a", "1", "1.5", "1e",
+        "NULL", "CAST", "AS", "ORDER", "BY", "LIMIT", "JOIN", "ON", "EXISTS", "--", "\n", ".",
+        "PRAGMA", "BEGIN", "COMMIT", "TRIGGER", "WITH", "COLLATE",
+    ];
+
+    fn tokens_parse(picks: Vec<u8>) -> bool {
+        let script = picks
+            .iter()
+            .map(|i| TOKENS[*i as usize % TOKENS.len()])
+            .collect::<Vec<_>>()
+            .join(" ");
+        sql(script.as_bytes());
+        true
+    }
+
+    fn bytes_decode(data: Vec<u8>) -> bool {
+        sql(&data);
+        record(&data);
+        page(&data);
+        file(&data);
+        true
+    }
+
+    #[test]
+    fn made_up_input_is_an_error_not_a_panic() {
+        QuickCheck::new()
+            .tests(2000)
+            .quickcheck(tokens_parse as fn(Vec<u8>) -> bool);
+        QuickCheck::new()
+            .tests(2000)
+            .quickcheck(bytes_decode as fn(Vec<u8>) -> bool);
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error_not_a_stack_overflow() {
+        for sql in [
+            format!(
+                "SELECT a FROM t WHERE {}1{} = 1;",
+                "(".repeat(5000),
+                ")".repeat(5000)
+            ),
+            format!(
+                "SELECT {} 1 {};",
+                "CASE WHEN 1 THEN ".repeat(5000),
+                "END ".repeat(5000)
+            ),
+            format!(
+                "SELECT a FROM t WHERE a IN {}1{};",
+                "(SELECT a FROM t WHERE a IN ".repeat(500),
+                ")".repeat(500)
+            ),
+        ] {
+            assert_eq!(
+                sql_parser::parse(&sql).unwrap_err().to_string(),
+                "Parse error: parser stack overflow"
+            );
+        }
+    }
+
+    #[test]
+    fn a_freeblock_of_no_size_is_malformed() {
+        // one freeblock at 500 of size 0 that points back at itself
+        let mut bytes = vec![0; 512];
+        bytes[2..4].copy_from_slice(&500u16.to_be_bytes());
+        bytes[4..6].copy_from_slice(&500u16.to_be_bytes());
+        bytes[500..502].copy_from_slice(&500u16.to_be_bytes());
+        assert!(SlottedPage::from_bytes(bytes, 0).is_err());
+    }
+
+    #[test]
+    fn serial_types_too_big_to_add_up_are_malformed() {
+        let mut bytes = vec![19];
+        for _ in 0..2 {
+            record::write_varint(u64::MAX, &mut bytes);
+        }
+        bytes.resize(19, 0);
+        assert!(Record::parse(&bytes).is_err());
+    }
+}
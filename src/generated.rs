@@ -0,0 +1,205 @@
+/*
+    Generated columns, whose values are worked out from the row's other columns rather
+    than written to them.
+
+        CREATE TABLE items (
+            price REAL,
+            quantity INTEGER,
+            total REAL GENERATED ALWAYS AS (price * quantity) STORED,
+            label AS (lower(name)) -- VIRTUAL, and GENERATED ALWAYS may be left out
+        );
+
+    The expression may read any of the table's other columns, generated ones included as
+    long as no column ends up depending on itself, and may only call deterministic
+    functions, see functions.rs, as the same row must always give the same value. It can't
+    have a subquery or a parameter in it, and a generated column can't be part of the
+    primary key or have a DEFAULT, all as in SQLite.
+
+    An INSERT or UPDATE can't give a generated column a value. Each time a row is written
+    its generated columns are worked out in order, each after those it reads, once its
+    rowid is known, and given the column's affinity like any value written to it. So an
+    index on a generated column, or on an expression of one, sees the value every other
+    reader does.
+
+    A STORED column is kept in the file like any other. A VIRTUAL one takes no room
+    there: it is left out of each row when the tables are saved, as SQLite leaves it out
+    of its records, and worked out again as the row is read back. In memory, where the
+    tables live between, a row holds the values of both.
+*/
+use crate::error::SqlError;
+use crate::eval::Affinity;
+use crate::sql_parser::ast::{ColVal, CreateTable, Expr};
+use anyhow::{bail, Result};
+
+/// Check a new table's generated columns can be worked out, see order.
+pub fn check(def: &CreateTable) -> Result<()> {
+    for column in def.columns.iter().filter(|c| c.generated.is_some()) {
+        if def.primary_key.contains(&column.name) {
+            bail!("generated columns cannot be part of the PRIMARY KEY");
+        }
+        if column.default.is_some() {
+            bail!("cannot use DEFAULT on a generated column");
+        }
+    }
+    order(def).map(|_| ())
+}
+
+/// The positions of a table's generated columns in the order they are to be worked out,
+/// each after every generated column its expression reads.
+pub fn order(def: &CreateTable) -> Result<Vec<usize>> {
+    // what each generated column reads of the others
+    let mut reads = vec![];
+    for (pos, column) in def.columns.iter().enumerate() {
+        let Some(generated) = &column.generated else {
+            continue;
+        };
+        let mut names = vec![];
+        let mut refused = None;
+        generated.expr.walk(&mut |e| match e {
+            Expr::Column(name) => names.push(name.clone()),
+            Expr::QualifiedColumn { column, .. } => names.push(column.clone()),
+            Expr::InSelect { .. } | Expr::Exists { .. } | Expr::Subquery(_) => {
+                refused = Some("subqueries")
+            }
+            Expr::Placeholder(_) => refused = Some("parameters"),
+            _ => {}
+        });
+        if let Some(what) = refused {
+            bail!("{what} are prohibited in generated columns");
+        }
+        let mut depends = vec![];
+        for name in names {
+            match def.columns.iter().position(|c| c.name == name) {
+                Some(other) if def.columns[other].generated.is_some() => depends.push(other),
+                Some(_) => {}
+                None => bail!(SqlError::NoSuchColumn { column: name }),
+            }
+        }
+        reads.push((pos, depends));
+    }
+
+    let mut order: Vec<usize> = vec![];
+    while order.len() < reads.len() {
+        let ready = reads.iter().find(|(pos, depends)| {
+            !order.contains(pos) && depends.iter().all(|d| order.contains(d))
+        });
+        match ready {
+            Some((pos, _)) => order.push(*pos),
+            None => {
+                let (stuck, _) = reads.iter().find(|(pos, _)| !order.contains(pos)).unwrap();
+                bail!("generated column loop on \"{}\"", def.columns[*stuck].name);
+            }
+        }
+    }
+    Ok(order)
+}
+
+/// Refuse an INSERT or UPDATE, as `statement` says, that gives a generated column a
+/// value.
+pub fn check_writes<'c>(
+    def: &CreateTable,
+    columns: impl IntoIterator<Item = &'c String>,
+    statement: &str,
+) -> Result<()> {
+    for name in columns {
+        if def
+            .columns
+            .iter()
+            .any(|c| c.name == *name && c.generated.is_some())
+        {
+            bail!("cannot {statement} generated column \"{name}\"");
+        }
+    }
+    Ok(())
+}
+
+/// Work out the generated columns of `row`, a row of `def`, where `eval` evaluates an
+/// expression over the row as it is so far.
+pub fn fill(
+    def: &CreateTable,
+    row: &mut [ColVal],
+    eval: impl Fn(&Expr, &[ColVal]) -> Result<ColVal>,
+) -> Result<()> {
+    if def.columns.iter().all(|c| c.generated.is_none()) {
+        return Ok(());
+    }
+    for pos in order(def)? {
+        let column = &def.columns[pos];
+        let generated = column.generated.as_ref().expect("a generated column");
+        let value = eval(&generated.expr, row)?;
+        row[pos] = Affinity::of(column.type_name.as_deref()).apply(value);
+    }
+    Ok(())
+}
+
+/// The values of a row of `def` as the file keeps them, without its VIRTUAL columns.
+pub fn stored(def: &CreateTable, row: Vec<ColVal>) -> Vec<ColVal> {
+    if !def.columns.iter().any(|c| c.is_virtual()) {
+        return row;
+    }
+    row.into_iter()
+        .zip(&def.columns)
+        .filter(|(_, column)| !column.is_virtual())
+        .map(|(value, _)| value)
+        .collect()
+}
+
+/// A row of `def` as the file kept it with its VIRTUAL columns back in place, NULL until
+/// fill works them out. Columns added to the table after the row was written, which the
+/// row is short of at the end, are NULL too.
+pub fn unstored(def: &CreateTable, row: Vec<ColVal>) -> Vec<ColVal> {
+    let mut values = row.into_iter();
+    def.columns
+        .iter()
+        .map(|column| match column.is_virtual() {
+            true => ColVal::Null,
+            false => values.next().unwrap_or(ColVal::Null),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::Statement;
+    use crate::sql_parser::parse;
+
+    fn table(sql: &str) -> CreateTable {
+        let Statement::CreateTable(table) = parse(sql).unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        table
+    }
+
+    #[test]
+    fn generated_columns_are_worked_out_after_those_they_read() {
+        let def = table("CREATE TABLE t (a, c AS (b + 1), b AS (a * 2) STORED, d);");
+        assert_eq!(order(&def).unwrap(), [2, 1]);
+
+        let row = vec![ColVal::Int(1), ColVal::Null, ColVal::Null, ColVal::Int(4)];
+        let kept = stored(&def, row);
+        assert_eq!(kept, [ColVal::Int(1), ColVal::Null, ColVal::Int(4)]);
+        assert_eq!(
+            unstored(&def, kept),
+            [ColVal::Int(1), ColVal::Null, ColVal::Null, ColVal::Int(4)]
+        );
+
+        for (sql, error) in [
+            (
+                "CREATE TABLE t (a AS (b), b AS (a));",
+                "generated column loop on \"a\"",
+            ),
+            ("CREATE TABLE t (a, b AS (c));", "no such column: c"),
+            (
+                "CREATE TABLE t (a INTEGER PRIMARY KEY AS (1));",
+                "generated columns cannot be part of the PRIMARY KEY",
+            ),
+            (
+                "CREATE TABLE t (a, b AS (a IN (SELECT x FROM u)));",
+                "subqueries are prohibited in generated columns",
+            ),
+        ] {
+            assert_eq!(check(&table(sql)).unwrap_err().to_string(), error, "{sql}");
+        }
+    }
+}
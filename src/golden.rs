@@ -0,0 +1,276 @@
+/*
+    Golden-file tests, checking that this engine answers the same as SQLite.
+
+    Each script in tests/golden is a series of SQL statements, and beside it is a
+    transcript of running them: every statement followed by the rows it returned, printed
+    as the sqlite3 shell prints them by default, or by the error it failed with. The
+    transcripts are made by running the scripts through the real sqlite3, so the one test
+    here checks that the engine writes the same transcript, and the other that sqlite3
+    still does, which catches a transcript edited by hand into saying what the engine says
+    rather than what SQLite says.
+
+    Errors are compared by their message alone. sqlite3 adds where in the script the
+    error came from and the result code, so those are taken off its messages first, as
+    are the lines pointing at where in the statement a parse error is from the engine's.
+
+    The sqlite3 test needs the sqlite3 shell, found on the PATH or at $SQLITE3, and passes
+    without checking anything when there isn't one. Run with $UPDATE_GOLDEN set it writes
+    the transcripts rather than checking them, which is how a new script gets its
+    transcript. Each statement is run by its own sqlite3 against a database file kept
+    between them, with double-quoted strings turned on as this engine reads them as
+    strings as readily as single-quoted ones.
+
+    A script may also `.dump` the database, on a line of its own. The dumps themselves
+    aren't written alike, the engine's INSERTs naming their columns for one, so what is
+    compared is the database each makes again: the dump is run into a new database, and
+    the transcript has its sqlite_master and the rows of each of its tables, queried as
+    though the script went on to query them, in place of the dump.
+*/
+use crate::cli::dump::dump;
+use crate::executor::Executor;
+use crate::sql_parser::split_statements;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let mut scripts: Vec<PathBuf> = fs::read_dir(golden_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sql"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no scripts in {:?}", golden_dir());
+    scripts
+}
+
+// The statements of a script, each running until its `;`, and `.dump`, a line of its own.
+fn statements(script: &str) -> Vec<String> {
+    let mut statements = vec![];
+    let mut sql = String::new();
+    for line in script.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("--") {
+            continue;
+        }
+        if sql.is_empty() && line.starts_with('.') {
+            assert_eq!(line, DUMP, "the only command a script may have");
+            statements.push(line.to_string());
+            continue;
+        }
+        if !sql.is_empty() {
+            sql.push(' ');
+        }
+        sql.push_str(line);
+        if line.ends_with(';') {
+            statements.push(std::mem::take(&mut sql));
+        }
+    }
+    assert!(sql.is_empty(), "a statement without a `;`: {sql}");
+    statements
+}
+
+const DUMP: &str = ".dump";
+
+// The queries that show what a dump made again: its sqlite_master, then each table's
+// rows, which `run` runs against the new database.
+fn made_again(mut run: impl FnMut(&str) -> Result<String, String>) -> String {
+    let mut queries =
+        vec!["SELECT type, name, tbl_name, sql FROM sqlite_master ORDER BY name;".to_string()];
+    let tables = run("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name;")
+        .expect("sqlite_master can be read");
+    queries.extend(
+        tables
+            .lines()
+            .map(|table| format!("SELECT * FROM {table};")),
+    );
+    let mut transcript = String::new();
+    for sql in queries {
+        transcript.push_str(&sql);
+        transcript.push('\n');
+        transcript.push_str(&run(&sql).expect("what the dump made can be read"));
+    }
+    transcript
+}
+
+// Every statement followed by its rows, or its error as `error: <message>`.
+fn transcript(script: &Path, mut run: impl FnMut(&str) -> Result<String, String>) -> String {
+    let mut transcript = String::new();
+    for sql in statements(&fs::read_to_string(script).unwrap()) {
+        transcript.push_str(&sql);
+        transcript.push('\n');
+        match run(&sql) {
+            Ok(rows) => {
+                for row in rows.lines() {
+                    transcript.push_str(row);
+                    transcript.push('\n');
+                }
+            }
+            Err(message) => transcript.push_str(&format!("error: {message}\n")),
+        }
+    }
+    transcript
+}
+
+fn engine_transcript(script: &Path) -> String {
+    let mut executor = Executor::default();
+    transcript(script, |sql| {
+        if sql == DUMP {
+            let dumped = dump(&mut executor, None).map_err(|err| err.to_string())?;
+            let mut again = Executor::default();
+            for sql in split_statements(&dumped) {
+                again.execute_sql(sql).expect("the dump runs");
+            }
+            return Ok(made_again(|sql| engine_run(&mut again, sql)));
+        }
+        engine_run(&mut executor, sql)
+    })
+}
+
+fn engine_run(executor: &mut Executor, sql: &str) -> Result<String, String> {
+    executor
+        .execute_sql(sql)
+        // each row on a line of its own, as sqlite3 prints them, so that a last row that
+        // is all NULLs isn't lost as an empty last line
+        .map(|rows| {
+            if rows.rows.is_empty() {
+                String::new()
+            } else {
+                format!("{rows}\n")
+            }
+        })
+        .map_err(|err| {
+            let message = err.to_string();
+            match message.strip_prefix("Parse error: ") {
+                Some(parse_error) => parse_error.lines().next().unwrap_or("").to_string(),
+                None => message,
+            }
+        })
+}
+
+fn sqlite3() -> String {
+    std::env::var("SQLITE3").unwrap_or_else(|_| "sqlite3".to_string())
+}
+
+fn sqlite3_transcript(script: &Path) -> String {
+    let dir = tempfile::tempdir().unwrap();
+    let db = dir.path().join("golden.db");
+    transcript(script, |sql| {
+        if sql == DUMP {
+            let dumped = sqlite3_run(&db, DUMP)?;
+            let again = dir.path().join("again.db");
+            let _ = fs::remove_file(&again);
+            sqlite3_run(&again, &dumped).expect("the dump runs");
+            return Ok(made_again(|sql| sqlite3_run(&again, sql)));
+        }
+        sqlite3_run(&db, sql)
+    })
+}
+
+// Run `sql` by a sqlite3 of its own against the database file `db`.
+fn sqlite3_run(db: &Path, sql: &str) -> Result<String, String> {
+    let mut child = Command::new(sqlite3())
+        .arg("-batch")
+        .arg(db)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // .dbconfig reports the setting it made, which isn't part of the output
+    let input = format!(
+        ".output /dev/null\n.dbconfig dqs_dml on\n.dbconfig dqs_ddl on\n.output stdout\n{sql}\n"
+    );
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    match stderr.lines().next() {
+        None => Ok(String::from_utf8(output.stdout).unwrap()),
+        Some(line) => Err(sqlite3_error(line)),
+    }
+}
+
+// "Runtime error near line 5: UNIQUE constraint failed: t.a (19)" is just
+// "UNIQUE constraint failed: t.a".
+fn sqlite3_error(line: &str) -> String {
+    let message = match line.split_once(" near line ") {
+        Some((_, rest)) => rest.split_once(": ").map_or(rest, |(_, message)| message),
+        None => line.strip_prefix("Error: ").unwrap_or(line),
+    };
+    let message = message.strip_prefix("in prepare, ").unwrap_or(message);
+    match message.rsplit_once(" (") {
+        Some((message, code))
+            if code.ends_with(')') && code[..code.len() - 1].parse::<u32>().is_ok() =>
+        {
+            message.to_string()
+        }
+        _ => message.to_string(),
+    }
+}
+
+fn golden(script: &Path) -> PathBuf {
+    script.with_extension("out")
+}
+
+fn read_golden(script: &Path) -> String {
+    fs::read_to_string(golden(script))
+        .unwrap_or_else(|_| panic!("no transcript for {script:?}, run with UPDATE_GOLDEN set"))
+}
+
+#[test]
+fn the_engine_matches_the_transcripts() {
+    for script in scripts() {
+        pretty_assertions::assert_eq!(
+            engine_transcript(&script),
+            read_golden(&script),
+            "{script:?}"
+        );
+    }
+}
+
+#[test]
+fn sqlite3_matches_the_transcripts() {
+    let found = Command::new(sqlite3())
+        .arg("-version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !found {
+        eprintln!("no sqlite3 to check the transcripts with");
+        return;
+    }
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    for script in scripts() {
+        let transcript = sqlite3_transcript(&script);
+        if update {
+            fs::write(golden(&script), transcript).unwrap();
+        } else {
+            pretty_assertions::assert_eq!(transcript, read_golden(&script), "{script:?}");
+        }
+    }
+}
+
+#[test]
+fn sqlite3_errors_are_just_their_message() {
+    assert_eq!(
+        sqlite3_error("Runtime error near line 5: UNIQUE constraint failed: t.a (19)"),
+        "UNIQUE constraint failed: t.a"
+    );
+    assert_eq!(
+        sqlite3_error("Parse error near line 4: no such column: nope"),
+        "no such column: nope"
+    );
+    assert_eq!(
+        sqlite3_error("Error: in prepare, no such table: t (1)"),
+        "no such table: t"
+    );
+}
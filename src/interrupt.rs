@@ -0,0 +1,63 @@
+/*
+    Stopping a statement that is taking too long, as sqlite3_interrupt does.
+
+    Each connection has a flag, and an InterruptHandle is a way to set it from anywhere,
+    another thread or a signal handler, as its connection is busy running a statement and
+    can't be reached. The statement notices between batches of rows: every operator of a
+    plan looks before it starts and every so many rows as it goes, as does the bytecode
+    machine each time a cursor steps that many rows on, and a scan of a virtual table
+    while it makes its rows. So a scan or a join of a million rows stops within a few
+    hundred of the interrupt and fails with "interrupted", SQLite's SQLITE_INTERRUPT,
+    while one that finishes in the meantime succeeds as though nothing had happened.
+
+    A write that is interrupted is undone, as a write that fails any other way is, see
+    executor.rs, and a transaction it was part of stays open for ROLLBACK or for more.
+
+    The flag is cleared once the statement ends, however it ends, so an interrupt stops
+    the statement that was running when it came, or the next one to start if none was,
+    and no other. The shell sets it when ^C is pressed while a statement runs, see repl.
+*/
+use crate::error::SqlError;
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How many rows go by between looks at the flag.
+pub const CHECK_EVERY: usize = 256;
+
+/// Interrupts the statement running on the connection it came from, see
+/// Connection::interrupt_handle.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    /// Stop the statement the connection is running, or the next it runs if it is idle.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Fail with "interrupted" if the statement has been interrupted.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_interrupted() {
+            bail!(SqlError::Interrupted);
+        }
+        Ok(())
+    }
+
+    /// Check when the `row`th row of a batch has been reached, the first and every
+    /// CHECK_EVERY after.
+    pub(crate) fn check_row(&self, row: usize) -> Result<()> {
+        if row % CHECK_EVERY == 0 {
+            self.check()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
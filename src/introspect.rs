@@ -0,0 +1,340 @@
+/*
+    The schema as plain data, for programs that embed the engine rather than type at its
+    shell: an ORM checking its models against the tables, or a migration tool working out
+    what has changed since the last run.
+
+    Connection::schema, Executor::schema_info underneath, describes every table, index, view and trigger with the same
+    facts PRAGMA table_info, index_list and index_xinfo give, already typed, so a caller
+    needn't parse SQL or the shell's output to learn them. Each also carries the SQL that
+    would create it again, as the engine prints its statement rather than as it was first
+    written.
+
+    The description is a snapshot. It doesn't change as the schema does, so a caller that
+    keeps one should compare its cookie with that of a fresh one, which goes up with
+    every change to the schema.
+
+    Names are listed in order, tables, indexes and views by name and triggers in the order
+    they fire.
+*/
+use crate::catalog;
+use crate::planner::Catalog;
+use crate::schema::Schema;
+use crate::sql_parser::ast::{
+    ColVal, CreateIndex, CreateTable, CreateTrigger, Expr, ForeignKey, Statement, TriggerEvent,
+    TriggerTiming,
+};
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SchemaInfo {
+    pub cookie: u32,
+    pub tables: Vec<TableInfo>,
+    pub indexes: Vec<IndexInfo>,
+    pub views: Vec<ViewInfo>,
+    pub triggers: Vec<TriggerInfo>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TableInfo {
+    pub name: String,
+    pub columns: Vec<ColumnInfo>,
+    /// The primary key's columns in key order, empty if the table has none.
+    pub primary_key: Vec<String>,
+    pub foreign_keys: Vec<ForeignKey>,
+    pub without_rowid: bool,
+    pub sql: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// The declared type as written, such as VARCHAR(255), None if there wasn't one.
+    pub type_name: Option<String>,
+    pub default: Option<ColVal>,
+    /// The collation the column compares under, BINARY unless it was given one.
+    pub collation: String,
+    /// Where the column is in the primary key, counting from 1, None if it isn't part of it.
+    pub primary_key: Option<usize>,
+    /// Whether the column is an INTEGER PRIMARY KEY, and so the table's rowid.
+    pub rowid_alias: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IndexOrigin {
+    /// Made by CREATE INDEX.
+    CreateIndex,
+    /// Made with its table to enforce the table's PRIMARY KEY.
+    PrimaryKey,
+}
+
+impl IndexOrigin {
+    /// The origin as PRAGMA index_list gives it, "c" or "pk" as in SQLite.
+    pub fn code(self) -> &'static str {
+        match self {
+            IndexOrigin::CreateIndex => "c",
+            IndexOrigin::PrimaryKey => "pk",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IndexInfo {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<IndexColumn>,
+    pub unique: bool,
+    pub origin: IndexOrigin,
+    /// The CREATE INDEX statement, None for an index made with its table, as in SQLite.
+    pub sql: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct IndexColumn {
+    /// The column the key is, None if it is an expression.
+    pub column: Option<String>,
+    /// The key as SQL, the column's name or the expression.
+    pub expr: String,
+    pub collation: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ViewInfo {
+    pub name: String,
+    /// The names of the view's columns, None when its SELECT no longer plans, such as
+    /// when a table it reads was changed.
+    pub columns: Option<Vec<String>>,
+    pub sql: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TriggerInfo {
+    pub name: String,
+    pub table: String,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    pub sql: String,
+}
+
+impl SchemaInfo {
+    /// The table called `name`, in any case.
+    pub fn table(&self, name: &str) -> Option<&TableInfo> {
+        self.tables
+            .iter()
+            .find(|table| table.name.eq_ignore_ascii_case(name))
+    }
+
+    /// The indexes on the table called `name`, in any case.
+    pub fn indexes_on(&self, table: &str) -> Vec<&IndexInfo> {
+        self.indexes
+            .iter()
+            .filter(|index| index.table.eq_ignore_ascii_case(table))
+            .collect()
+    }
+}
+
+impl Schema {
+    /// A description of everything in the schema as it is now.
+    pub fn info(&self) -> SchemaInfo {
+        let mut tables = vec![];
+        let mut views = vec![];
+        for name in self.table_names() {
+            if name == catalog::MASTER_TABLE {
+                continue;
+            }
+            if let Some(table) = self.table(name) {
+                tables.push(self.table_info(table));
+            } else if let Some(view) = self.view(name) {
+                views.push(ViewInfo {
+                    name: view.name.clone(),
+                    columns: self.table_columns(name).ok(),
+                    sql: Statement::CreateView(view).to_string(),
+                });
+            }
+        }
+        let mut indexes: Vec<IndexInfo> = tables
+            .iter()
+            .flat_map(|t| self.table_indexes(&t.name))
+            .map(|index| self.index_info(&index))
+            .collect();
+        indexes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        SchemaInfo {
+            cookie: self.cookie(),
+            tables,
+            indexes,
+            views,
+            triggers: self.triggers().iter().map(trigger_info).collect(),
+        }
+    }
+
+    fn table_info(&self, table: &CreateTable) -> TableInfo {
+        let rowid_alias = table.rowid_alias();
+        let columns = table
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| ColumnInfo {
+                name: column.name.clone(),
+                type_name: column.type_name.clone(),
+                default: column.default.clone(),
+                collation: column
+                    .collation
+                    .clone()
+                    .unwrap_or_else(|| "BINARY".to_string()),
+                primary_key: table
+                    .primary_key
+                    .iter()
+                    .position(|k| *k == column.name)
+                    .map(|i| i + 1),
+                rowid_alias: rowid_alias == Some(i),
+            })
+            .collect();
+        let mut def = table.clone();
+        def.if_not_exists = false;
+        TableInfo {
+            name: table.name.clone(),
+            columns,
+            primary_key: table.primary_key.clone(),
+            foreign_keys: table.foreign_keys.clone(),
+            without_rowid: table.without_rowid,
+            sql: Statement::CreateTable(def).to_string(),
+        }
+    }
+
+    /// A description of one of the schema's indexes, as info gives it.
+    pub fn index_info(&self, index: &CreateIndex) -> IndexInfo {
+        let collations = self.key_collations(&index.name).unwrap_or_default();
+        let columns = index
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, expr)| {
+                let column = match expr {
+                    Expr::Collate { expr, .. } => &**expr,
+                    expr => expr,
+                };
+                IndexColumn {
+                    column: match column {
+                        Expr::Column(name) => Some(name.clone()),
+                        _ => None,
+                    },
+                    expr: column.to_string(),
+                    collation: collations
+                        .get(i)
+                        .cloned()
+                        .unwrap_or_else(|| "BINARY".to_string()),
+                }
+            })
+            .collect();
+        let origin = if index.name.starts_with("sqlite_autoindex_") {
+            IndexOrigin::PrimaryKey
+        } else {
+            IndexOrigin::CreateIndex
+        };
+        let mut def = index.clone();
+        def.if_not_exists = false;
+        IndexInfo {
+            name: index.name.clone(),
+            table: index.table.clone(),
+            columns,
+            unique: index.unique,
+            origin,
+            sql: (origin == IndexOrigin::CreateIndex)
+                .then(|| Statement::CreateIndex(def).to_string()),
+        }
+    }
+}
+
+fn trigger_info(trigger: &CreateTrigger) -> TriggerInfo {
+    let mut def = trigger.clone();
+    def.if_not_exists = false;
+    TriggerInfo {
+        name: trigger.name.clone(),
+        table: trigger.table.clone(),
+        timing: trigger.timing,
+        event: trigger.event.clone(),
+        sql: Statement::CreateTrigger(def).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+
+    #[test]
+    fn the_schema_is_described_as_data() {
+        let mut db = Executor::default();
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT COLLATE NOCASE, age INTEGER DEFAULT 18);",
+            "CREATE TABLE grades (student TEXT, course INTEGER, PRIMARY KEY (student, course), FOREIGN KEY (student) REFERENCES users (email));",
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_email ON users (email);",
+            "CREATE INDEX idx_age ON users (age);",
+            "CREATE VIEW adults AS SELECT email FROM users WHERE age >= 18;",
+            "CREATE TABLE log (email TEXT);",
+            "CREATE TRIGGER log_user AFTER INSERT ON users BEGIN INSERT INTO log (email) VALUES (NEW.email); END;",
+        ] {
+            db.execute_sql(sql).unwrap();
+        }
+        let info = db.schema_info();
+        assert_eq!(info.cookie, db.schema().cookie());
+
+        let users = &info.tables[2];
+        assert_eq!(users.name, "users");
+        assert_eq!(
+            users.columns[0],
+            ColumnInfo {
+                name: "id".to_string(),
+                type_name: Some("INTEGER".to_string()),
+                default: None,
+                collation: "BINARY".to_string(),
+                primary_key: Some(1),
+                rowid_alias: true,
+            }
+        );
+        assert_eq!(users.columns[1].collation, "NOCASE");
+        assert_eq!(users.columns[2].default, Some(ColVal::Int(18)));
+
+        let grades = &info.tables[0];
+        assert_eq!(grades.primary_key, ["student", "course"]);
+        assert_eq!(grades.columns[1].primary_key, Some(2));
+        assert!(!grades.columns[1].rowid_alias);
+        assert_eq!(grades.foreign_keys[0].parent, "users");
+
+        let names: Vec<(&str, IndexOrigin, Option<&str>)> = info
+            .indexes
+            .iter()
+            .map(|i| (i.name.as_str(), i.origin, i.sql.as_deref()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                (
+                    "idx_age",
+                    IndexOrigin::CreateIndex,
+                    Some("CREATE INDEX idx_age ON users (age)")
+                ),
+                (
+                    "idx_email",
+                    IndexOrigin::CreateIndex,
+                    Some("CREATE UNIQUE INDEX idx_email ON users (email)")
+                ),
+                ("sqlite_autoindex_grades_1", IndexOrigin::PrimaryKey, None),
+            ]
+        );
+        // the key compares under the column's collation
+        assert_eq!(
+            info.indexes[1].columns,
+            [IndexColumn {
+                column: Some("email".to_string()),
+                expr: "email".to_string(),
+                collation: "NOCASE".to_string(),
+            }]
+        );
+
+        assert_eq!(info.views[0].columns, Some(vec!["email".to_string()]));
+        assert_eq!(
+            (info.triggers[0].name.as_str(), info.triggers[0].timing),
+            ("log_user", TriggerTiming::After)
+        );
+    }
+}
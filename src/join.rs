@@ -0,0 +1,188 @@
+/*
+    The two ways the executor matches the rows of a join's outer side with those of its
+    inner side, given each row's join key. A key is None when it has a NULL in it, as NULL
+    equals nothing and such a row can never match.
+
+    A hash join builds a hash table of the inner side's keys, then probes it with each
+    outer key. It works on any input, at the cost of holding the whole table in memory.
+    So that the table stays within the memory budget, the same as a sort's, from PRAGMA
+    cache_size and hard_heap_limit, the inner side is split into partitions of about that
+    many bytes of keys by the hash of their keys, and the partitions are joined one at a
+    time, each against just the outer rows whose keys hash to it. This is the
+    Grace hash join: only one partition's table exists at once, and the partitions not
+    being joined are what would be spilled to temporary files. Until there is anywhere to
+    spill them they wait in memory.
+
+    A merge join needs no table at all, but only works when both sides arrive sorted by
+    their keys. It walks the two in step, like merging two sorted lists, so each row is
+    looked at once. The planner picks it when it knows both inputs are in key order, such
+    as two tables scanned in rowid order and joined on their INTEGER PRIMARY KEYs.
+
+    Each comes in two forms. The semi-join's answers the question a semi-join asks: for
+    each outer row in turn, is there an inner row with the same key? The join's pairs
+    each outer row with every inner row that has its key, for a FROM clause joining
+    tables. Either way the outer rows keep the order they came in. A join the virtual
+    machine runs doesn't come here, it keeps a hash table of its own, see vdbe.rs.
+*/
+use crate::sorter::Spill;
+use crate::sql_parser::ast::ColVal;
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+pub type Key = Option<Vec<ColVal>>;
+
+fn hash_of(key: &[ColVal]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+// How many partitions the inner keys are split into for about `budget` bytes of keys in
+// each, and never less than a key.
+fn partitions(inner: &[Key], budget: usize) -> u64 {
+    let bytes: usize = inner.iter().map(Spill::size).sum();
+    bytes.div_ceil(budget.max(1)).clamp(1, inner.len().max(1)) as u64
+}
+
+/// Whether each outer key has an equal inner key, with the inner side's hash table
+/// partitioned to hold about `budget` bytes of keys at a time, and never less than a key.
+pub fn hash_semi_join(outer: &[Key], inner: &[Key], budget: usize) -> Vec<bool> {
+    let partitions = partitions(inner, budget);
+    let partition_of = |key: &[ColVal]| hash_of(key) % partitions;
+
+    let mut matched = vec![false; outer.len()];
+    for partition in 0..partitions {
+        let table: HashSet<&[ColVal]> = inner
+            .iter()
+            .flatten()
+            .filter(|key| partition_of(key) == partition)
+            .map(Vec::as_slice)
+            .collect();
+        for (key, matched) in outer.iter().zip(&mut matched) {
+            if let Some(key) = key {
+                if partition_of(key) == partition {
+                    *matched = table.contains(key.as_slice());
+                }
+            }
+        }
+    }
+    matched
+}
+
+/// The pairs of an outer and an inner row with equal keys, as the index of each, in the
+/// order of the outer rows and then of the inner. The hash table is partitioned as
+/// hash_semi_join's is.
+pub fn hash_join(outer: &[Key], inner: &[Key], budget: usize) -> Vec<(usize, usize)> {
+    let partitions = partitions(inner, budget);
+    let partition_of = |key: &[ColVal]| hash_of(key) % partitions;
+
+    let mut pairs = vec![];
+    for partition in 0..partitions {
+        let mut table: HashMap<&[ColVal], Vec<usize>> = HashMap::new();
+        for (i, key) in inner.iter().enumerate() {
+            if let Some(key) = key.as_deref().filter(|key| partition_of(key) == partition) {
+                table.entry(key).or_default().push(i);
+            }
+        }
+        for (o, key) in outer.iter().enumerate() {
+            if let Some(key) = key.as_deref().filter(|key| partition_of(key) == partition) {
+                let matches = table.get(key).into_iter().flatten();
+                pairs.extend(matches.map(|&i| (o, i)));
+            }
+        }
+    }
+    pairs.sort_unstable();
+    pairs
+}
+
+/// The pairs of an outer and an inner row with equal keys, as hash_join gives them, where
+/// both sides are sorted by key.
+pub fn merge_join(outer: &[Key], inner: &[Key]) -> Vec<(usize, usize)> {
+    let inner: Vec<(usize, &[ColVal])> = inner
+        .iter()
+        .enumerate()
+        .filter_map(|(i, key)| Some((i, key.as_deref()?)))
+        .collect();
+    let mut start = 0;
+    let mut pairs = vec![];
+    for (o, key) in outer.iter().enumerate() {
+        let Some(key) = key else {
+            continue;
+        };
+        // the run of equal inner keys is kept for the next outer key, which may be equal
+        while inner.get(start).is_some_and(|(_, i)| *i < key.as_slice()) {
+            start += 1;
+        }
+        let run = inner[start..]
+            .iter()
+            .take_while(|(_, i)| i.cmp(&key.as_slice()) == Ordering::Equal);
+        pairs.extend(run.map(|(i, _)| (o, *i)));
+    }
+    pairs
+}
+
+/// Whether each outer key has an equal inner key, where both sides are sorted by key.
+pub fn merge_semi_join(outer: &[Key], inner: &[Key]) -> Vec<bool> {
+    let mut inner = inner.iter().flatten().peekable();
+    outer
+        .iter()
+        .map(|key| {
+            let Some(key) = key else {
+                return false;
+            };
+            while inner.next_if(|i| i.as_slice() < key.as_slice()).is_some() {}
+            inner.peek().map(|i| i.as_slice().cmp(key)) == Some(Ordering::Equal)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(values: &[Option<i64>]) -> Vec<Key> {
+        values
+            .iter()
+            .map(|v| v.map(|n| vec![ColVal::Int(n)]))
+            .collect()
+    }
+
+    #[test]
+    fn hash_and_merge_joins_agree() {
+        let outer = keys(&[Some(1), None, Some(2), Some(2), Some(4), Some(7)]);
+        let inner = keys(&[None, Some(2), Some(2), Some(3), Some(4), Some(5), Some(6)]);
+        let expected = [false, false, true, true, true, false];
+
+        assert_eq!(merge_semi_join(&outer, &inner), expected);
+        assert_eq!(hash_semi_join(&outer, &inner, 1 << 20), expected);
+        // a partition per key gives the same answer as one partition for them all
+        assert_eq!(hash_semi_join(&outer, &inner, 1), expected);
+    }
+
+    #[test]
+    fn joins_pair_every_match() {
+        let outer = keys(&[Some(1), None, Some(2), Some(2), Some(4)]);
+        let inner = keys(&[None, Some(2), Some(2), Some(3), Some(4)]);
+        let expected = [(2, 1), (2, 2), (3, 1), (3, 2), (4, 4)];
+
+        assert_eq!(merge_join(&outer, &inner), expected);
+        assert_eq!(hash_join(&outer, &inner, 1 << 20), expected);
+        assert_eq!(hash_join(&outer, &inner, 1), expected);
+        // without keys every row pairs with every other
+        let empty = vec![Some(vec![]); 2];
+        assert_eq!(hash_join(&empty, &empty, 1).len(), 4);
+    }
+
+    #[test]
+    fn keys_match_by_value() {
+        let outer = vec![Some(vec![
+            ColVal::Real(2.0),
+            ColVal::String("a".to_string()),
+        ])];
+        let inner = vec![Some(vec![ColVal::Int(2), ColVal::String("a".to_string())])];
+        assert_eq!(hash_semi_join(&outer, &inner, 1), [true]);
+        assert_eq!(merge_semi_join(&outer, &inner), [true]);
+    }
+}
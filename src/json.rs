@@ -0,0 +1,679 @@
+/*
+    JSON functions, SQLite's JSON1: reading values out of JSON kept in TEXT columns, and
+    making JSON out of SQL values.
+
+        json(X)                    X minified, an error if it isn't JSON
+        json_extract(X, P, ...)    the value at path P as an SQL value, or with more than
+                                   one path a JSON array of the values at each
+        json_type(X [, P])         null, true, false, integer, real, text, array or object
+        json_array(V, ...)         a JSON array of the values
+        json_object(K, V, ...)     a JSON object of each label K and value V
+        X -> P                     the value at P as JSON
+        X ->> P                    the value at P as an SQL value, like json_extract
+
+    A path starts with $, the whole document, and goes on with .label for a member of
+    an object, ."a label" for one with dots or spaces in it, [N] for the Nth element of
+    an array from 0, and [#-N] for the Nth from the end. A path that leads nowhere gives
+    NULL, a path that isn't one is an error. On the right of -> and ->> an integer N is
+    short for $[N] and text that doesn't start with $ for $.text.
+
+    As SQL values, JSON's null is NULL, true and false are 1 and 0, numbers are integers
+    or REALs and strings are text, while arrays and objects stay JSON, as text. The other
+    way round, json_array and json_object make a JSON string of text, except text that
+    an argument made as JSON: json(), json_array(), json_object() and -> nest as what they
+    are, so json_array(json_array(1)) is [[1]] and not ["[1]"]. SQLite marks such text
+    with a subtype. Values carry none here, so eval quotes the other text arguments of
+    json_array and json_object before the call, see quote_arguments, and the two read
+    every text argument as JSON.
+
+    Objects keep their members in the order they were written, and JSON is written back
+    out with no spaces, as SQLite does. JSON5 isn't read, nor are the functions that
+    change JSON: json_set, json_insert, json_remove and json_patch.
+*/
+use crate::functions::{FunctionDef, FunctionRegistry};
+use crate::sql_parser::ast::{format_real, BinaryOp, ColVal, Expr};
+use anyhow::{bail, Result};
+use std::fmt::{self, Write};
+
+/// The names of the functions that make JSON, which json_array and json_object nest.
+const MAKES_JSON: [&str; 3] = ["json", "json_array", "json_object"];
+
+/// A JSON value as parsed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Real(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Add the JSON functions to `registry`.
+pub fn register(registry: &mut FunctionRegistry) {
+    registry.define(FunctionDef::new("json", 1, Some(1), true), |args| {
+        Ok(match document(&args[0])? {
+            Some(json) => ColVal::String(json.to_string()),
+            None => ColVal::Null,
+        })
+    });
+    registry.define(FunctionDef::new("json_extract", 2, None, true), extract);
+    registry.define(FunctionDef::new("json_type", 1, Some(2), true), json_type);
+    registry.define(FunctionDef::new("json_array", 0, None, true), |args| {
+        let elements = args.iter().map(argument).collect::<Result<_>>()?;
+        Ok(ColVal::String(Json::Array(elements).to_string()))
+    });
+    registry.define(FunctionDef::new("json_object", 0, None, true), object);
+}
+
+/// `left -> right`, or with `sql_value` `left ->> right`.
+pub fn arrow(left: &ColVal, right: &ColVal, sql_value: bool) -> Result<ColVal> {
+    let path = match right {
+        ColVal::Null => return Ok(ColVal::Null),
+        ColVal::String(path) if path.starts_with('$') => path.clone(),
+        ColVal::Int(n) => format!("$[{n}]"),
+        other => format!("$.{}", text(other)),
+    };
+    let Some(json) = document(left)? else {
+        return Ok(ColVal::Null);
+    };
+    Ok(match (json.get(&path)?, sql_value) {
+        (None, _) => ColVal::Null,
+        (Some(value), true) => value.to_sql(),
+        (Some(value), false) => ColVal::String(value.to_string()),
+    })
+}
+
+/// Whether `expr` gives JSON, which json_array and json_object nest as it is.
+pub fn makes_json(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function { name, .. } => MAKES_JSON.iter().any(|f| f.eq_ignore_ascii_case(name)),
+        Expr::Binary { op, .. } => *op == BinaryOp::Arrow,
+        _ => false,
+    }
+}
+
+/// Quote the text arguments of a call to json_array or json_object that aren't JSON to
+/// begin with, as json_array and json_object read all their text as JSON, see the
+/// module comment. Calls to any other function are left alone.
+pub fn quote_arguments(name: &str, args: &[Expr], values: &mut [ColVal]) {
+    if !["json_array", "json_object"]
+        .iter()
+        .any(|f| f.eq_ignore_ascii_case(name))
+    {
+        return;
+    }
+    for (i, (arg, value)) in args.iter().zip(values.iter_mut()).enumerate() {
+        // json_object's labels are text, never JSON
+        let label = name.eq_ignore_ascii_case("json_object") && i % 2 == 0;
+        if let ColVal::String(s) = value {
+            if !label && !makes_json(arg) {
+                *s = Json::String(std::mem::take(s)).to_string();
+            }
+        }
+    }
+}
+
+// The JSON in an argument, None for NULL.
+fn document(value: &ColVal) -> Result<Option<Json>> {
+    match value {
+        ColVal::Null => Ok(None),
+        other => parse(&text(other)).map(Some),
+    }
+}
+
+// A value as the text a function reads it as.
+fn text(value: &ColVal) -> String {
+    match value {
+        ColVal::String(s) => s.clone(),
+        ColVal::Boolean(b) => (*b as i64).to_string(),
+        other => other.to_string(),
+    }
+}
+
+// An argument of json_array or json_object as the JSON it puts in, text being JSON
+// already, see quote_arguments.
+fn argument(value: &ColVal) -> Result<Json> {
+    Ok(match value {
+        ColVal::Null => Json::Null,
+        ColVal::Boolean(b) => Json::Int(*b as i64),
+        ColVal::Int(n) => Json::Int(*n),
+        ColVal::Real(f) => Json::Real(*f),
+        ColVal::String(s) => parse(s)?,
+        ColVal::Blob(_) => bail!("JSON cannot hold BLOB values"),
+    })
+}
+
+fn extract(args: &[ColVal]) -> Result<ColVal> {
+    let Some(json) = document(&args[0])? else {
+        return Ok(ColVal::Null);
+    };
+    let paths = &args[1..];
+    if let [path] = paths {
+        return Ok(match json.get(&text(path))? {
+            Some(value) => value.to_sql(),
+            None => ColVal::Null,
+        });
+    }
+    let mut values = vec![];
+    for path in paths {
+        values.push(json.get(&text(path))?.cloned().unwrap_or(Json::Null));
+    }
+    Ok(ColVal::String(Json::Array(values).to_string()))
+}
+
+fn json_type(args: &[ColVal]) -> Result<ColVal> {
+    let Some(json) = document(&args[0])? else {
+        return Ok(ColVal::Null);
+    };
+    let value = match args.get(1) {
+        Some(path) => json.get(&text(path))?,
+        None => Some(&json),
+    };
+    Ok(match value {
+        Some(value) => ColVal::String(value.type_name().to_string()),
+        None => ColVal::Null,
+    })
+}
+
+fn object(args: &[ColVal]) -> Result<ColVal> {
+    if args.len() % 2 != 0 {
+        bail!("json_object() requires an even number of arguments");
+    }
+    let mut members = vec![];
+    for pair in args.chunks(2) {
+        let ColVal::String(label) = &pair[0] else {
+            bail!("json_object() labels must be TEXT");
+        };
+        members.push((label.clone(), argument(&pair[1])?));
+    }
+    Ok(ColVal::String(Json::Object(members).to_string()))
+}
+
+impl Json {
+    /// The value at `path`, None if there is nothing there.
+    pub fn get(&self, path: &str) -> Result<Option<&Json>> {
+        let bad_path = || anyhow::anyhow!("bad JSON path: '{path}'");
+        let mut rest = path.strip_prefix('$').ok_or_else(bad_path)?;
+        let mut value = self;
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let (label, after) = match after.strip_prefix('"') {
+                    Some(quoted) => {
+                        let end = quoted.find('"').ok_or_else(bad_path)?;
+                        (&quoted[..end], &quoted[end + 1..])
+                    }
+                    None => {
+                        let end = after.find(['.', '[']).unwrap_or(after.len());
+                        (&after[..end], &after[end..])
+                    }
+                };
+                if label.is_empty() {
+                    return Err(bad_path());
+                }
+                rest = after;
+                let Json::Object(members) = value else {
+                    return Ok(None);
+                };
+                // the last of a label written twice wins, as SQLite's does
+                match members.iter().rev().find(|(l, _)| l == label) {
+                    Some((_, member)) => value = member,
+                    None => return Ok(None),
+                }
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(bad_path)?;
+                let (index, after) = (&after[..end], &after[end + 1..]);
+                rest = after;
+                // how far from the end with #-N, or from the start
+                let (from_end, n) = match index.strip_prefix("#-") {
+                    Some(back) => (true, back),
+                    None => (false, index),
+                };
+                let n: usize = n.parse().map_err(|_| bad_path())?;
+                let Json::Array(elements) = value else {
+                    return Ok(None);
+                };
+                let position = match from_end {
+                    true => elements.len().checked_sub(n),
+                    false => Some(n),
+                };
+                match position.and_then(|i| elements.get(i)) {
+                    Some(element) => value = element,
+                    None => return Ok(None),
+                }
+            } else {
+                return Err(bad_path());
+            }
+        }
+        Ok(Some(value))
+    }
+
+    /// The value as SQL has it, arrays and objects as their JSON.
+    pub fn to_sql(&self) -> ColVal {
+        match self {
+            Json::Null => ColVal::Null,
+            Json::Bool(b) => ColVal::Int(*b as i64),
+            Json::Int(n) => ColVal::Int(*n),
+            Json::Real(f) => ColVal::Real(*f),
+            Json::String(s) => ColVal::String(s.clone()),
+            Json::Array(_) | Json::Object(_) => ColVal::String(self.to_string()),
+        }
+    }
+
+    /// What json_type calls the value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(true) => "true",
+            Json::Bool(false) => "false",
+            Json::Int(_) => "integer",
+            Json::Real(_) => "real",
+            Json::String(_) => "text",
+            Json::Array(_) => "array",
+            Json::Object(_) => "object",
+        }
+    }
+}
+
+/// Parse the whole of `src` as one JSON value.
+pub fn parse(src: &str) -> Result<Json> {
+    let mut parser = Parser {
+        src: src.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    if parser.pos != parser.src.len() {
+        bail!("malformed JSON");
+    }
+    Ok(value)
+}
+
+// How deep arrays and objects may nest, SQLite's JSON_MAX_DEPTH.
+const MAX_DEPTH: usize = 1000;
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn whitespace(&mut self) {
+        while self
+            .src
+            .get(self.pos)
+            .is_some_and(|b| b" \t\n\r".contains(b))
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.whitespace();
+        let found = self.src.get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json> {
+        if !self.src[self.pos..].starts_with(word.as_bytes()) {
+            bail!("malformed JSON");
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.whitespace();
+        match self.src.get(self.pos) {
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => self.nested(|parser| {
+                let mut elements = vec![];
+                if parser.eat(b']') {
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(parser.value()?);
+                    if parser.eat(b']') {
+                        return Ok(Json::Array(elements));
+                    }
+                    if !parser.eat(b',') {
+                        bail!("malformed JSON");
+                    }
+                }
+            }),
+            Some(b'{') => self.nested(|parser| {
+                let mut members = vec![];
+                if parser.eat(b'}') {
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    parser.whitespace();
+                    let label = parser.string()?;
+                    if !parser.eat(b':') {
+                        bail!("malformed JSON");
+                    }
+                    members.push((label, parser.value()?));
+                    if parser.eat(b'}') {
+                        return Ok(Json::Object(members));
+                    }
+                    if !parser.eat(b',') {
+                        bail!("malformed JSON");
+                    }
+                }
+            }),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => bail!("malformed JSON"),
+        }
+    }
+
+    // An array or object, the opening bracket not yet read.
+    fn nested(&mut self, read: impl FnOnce(&mut Self) -> Result<Json>) -> Result<Json> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            bail!("JSON nested too deep");
+        }
+        self.pos += 1;
+        let value = read(self)?;
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let from = parser.pos;
+            while parser.src.get(parser.pos).is_some_and(u8::is_ascii_digit) {
+                parser.pos += 1;
+            }
+            parser.pos > from
+        };
+        if self.src[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        if !digits(self) {
+            bail!("malformed JSON");
+        }
+        let mut real = false;
+        if self.src.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            real = true;
+            if !digits(self) {
+                bail!("malformed JSON");
+            }
+        }
+        if matches!(self.src.get(self.pos), Some(b'e' | b'E')) {
+            self.pos += 1;
+            real = true;
+            if matches!(self.src.get(self.pos), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                bail!("malformed JSON");
+            }
+        }
+        let number = std::str::from_utf8(&self.src[start..self.pos])?;
+        // an integer too big for 64 bits is read as a REAL, as SQLite does
+        match number.parse::<i64>() {
+            Ok(n) if !real => Ok(Json::Int(n)),
+            _ => Ok(Json::Real(number.parse()?)),
+        }
+    }
+
+    // A string, the opening quote not yet read.
+    fn string(&mut self) -> Result<String> {
+        if self.src.get(self.pos) != Some(&b'"') {
+            bail!("malformed JSON");
+        }
+        self.pos += 1;
+        let mut out = String::new();
+        loop {
+            let start = self.pos;
+            while self
+                .src
+                .get(self.pos)
+                .is_some_and(|b| *b != b'"' && *b != b'\\' && *b >= 0x20)
+            {
+                self.pos += 1;
+            }
+            out.push_str(std::str::from_utf8(&self.src[start..self.pos])?);
+            match self.src.get(self.pos) {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(out);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let escaped = match self.src.get(self.pos) {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            let c = self.unicode_escape()?;
+                            out.push(c);
+                            continue;
+                        }
+                        _ => bail!("malformed JSON"),
+                    };
+                    self.pos += 1;
+                    out.push(escaped);
+                }
+                _ => bail!("malformed JSON"),
+            }
+        }
+    }
+
+    // \uXXXX, the \ already read, and the second half of a surrogate pair after it.
+    fn unicode_escape(&mut self) -> Result<char> {
+        let hex = |parser: &mut Self| -> Result<u32> {
+            let digits = parser
+                .src
+                .get(parser.pos + 1..parser.pos + 5)
+                .and_then(|d| std::str::from_utf8(d).ok())
+                .and_then(|d| u32::from_str_radix(d, 16).ok());
+            let Some(n) = digits else {
+                bail!("malformed JSON");
+            };
+            parser.pos += 5;
+            Ok(n)
+        };
+        let high = hex(self)?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.src[self.pos..].starts_with(b"\\u") {
+                bail!("malformed JSON");
+            }
+            self.pos += 1;
+            let low = hex(self)?;
+            if !(0xdc00..0xe000).contains(&low) {
+                bail!("malformed JSON");
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| anyhow::anyhow!("malformed JSON"))
+    }
+}
+
+// JSON written out with no spaces, as SQLite's json() does.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Int(n) => write!(f, "{n}"),
+            Json::Real(r) if r.is_nan() => write!(f, "null"),
+            Json::Real(r) if r.is_infinite() => {
+                write!(f, "{}9e999", if *r < 0.0 { "-" } else { "" })
+            }
+            Json::Real(r) => write!(f, "{}", format_real(*r)),
+            Json::String(s) => {
+                f.write_char('"')?;
+                for c in s.chars() {
+                    match c {
+                        '"' => f.write_str("\\\"")?,
+                        '\\' => f.write_str("\\\\")?,
+                        '\n' => f.write_str("\\n")?,
+                        '\r' => f.write_str("\\r")?,
+                        '\t' => f.write_str("\\t")?,
+                        c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                        c => f.write_char(c)?,
+                    }
+                }
+                f.write_char('"')
+            }
+            Json::Array(elements) => {
+                f.write_char('[')?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{element}")?;
+                }
+                f.write_char(']')
+            }
+            Json::Object(members) => {
+                f.write_char('{')?;
+                for (i, (label, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}:{value}", Json::String(label.clone()))?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+
+    fn run(executor: &mut Executor, sql: &str) -> Vec<Vec<ColVal>> {
+        executor.execute_sql(sql).unwrap().rows
+    }
+
+    fn text(s: &str) -> ColVal {
+        ColVal::String(s.to_string())
+    }
+
+    #[test]
+    fn json_in_text_columns_is_queried_with_sql() {
+        let mut executor = Executor::default();
+        run(&mut executor, "CREATE TABLE docs (body TEXT);");
+        run(
+            &mut executor,
+            "INSERT INTO docs (body) VALUES \
+             (json_object(\"name\", \"tea\", \"tags\", json_array(\"hot\", \"[1]\"), \"price\", 2.5));",
+        );
+        run(
+            &mut executor,
+            "INSERT INTO docs (body) VALUES (json_object(\"name\", \"cake\", \"price\", 4));",
+        );
+        let tea = [[text(r#"{"name":"tea","tags":["hot","[1]"],"price":2.5}"#)]];
+        let cake = [[text(r#"{"name":"cake","price":4}"#)]];
+        for (condition, expected) in [
+            ("body ->> \"price\" < 3", &tea),
+            ("body ->> \"$.tags[1]\" = \"[1]\"", &tea),
+            ("body -> \"tags\" = json_array(\"hot\", \"[1]\")", &tea),
+            ("json_type(body, \"$.price\") = \"integer\"", &cake),
+            (
+                "json_extract(body, \"$.name\", \"$.tags\") = json_array(\"cake\", NULL)",
+                &cake,
+            ),
+            // JSON made by a function or -> nests as it is, other text as a string
+            (
+                "json_array(json(\" [1, 2] \"), body -> \"price\") = \"[[1,2],2.5]\"",
+                &tea,
+            ),
+            (
+                "body -> \"tags\" ->> 0 = json_array(\"hot\") ->> \"$[#-1]\"",
+                &tea,
+            ),
+        ] {
+            let sql = format!("SELECT body FROM docs WHERE {condition};");
+            assert_eq!(run(&mut executor, &sql), *expected, "{condition}");
+        }
+
+        for (sql, err) in [
+            ("json(\"{\") = 1", "malformed JSON"),
+            ("json_extract(body, \"name\") = 1", "bad JSON path: 'name'"),
+            (
+                "json_object(\"a\") = 1",
+                "json_object() requires an even number of arguments",
+            ),
+            ("json_object(1, 2) = 1", "json_object() labels must be TEXT"),
+        ] {
+            let sql = format!("SELECT body FROM docs WHERE {sql};");
+            let got = executor.execute_sql(&sql).unwrap_err();
+            assert_eq!(format!("{got:#}"), err, "{sql}");
+        }
+    }
+
+    #[test]
+    fn json_is_read_and_written_back_without_spaces() {
+        let json =
+            parse(r#" { "a" : [1, -2.5, 1e3, true, null], "b\n" : {"c": "\u00e9\ud83d\ude00"} } "#)
+                .unwrap();
+        assert_eq!(
+            json.to_string(),
+            r#"{"a":[1,-2.5,1000.0,true,null],"b\n":{"c":"é😀"}}"#
+        );
+        // members stay in the order they were written
+        assert_eq!(
+            parse(r#"{"z":1,"a":2}"#).unwrap().to_string(),
+            r#"{"z":1,"a":2}"#
+        );
+        assert_eq!(parse("99999999999999999999").unwrap(), Json::Real(1e20));
+        for bad in [
+            "",
+            "[1,]",
+            "{\"a\"}",
+            "01x",
+            "\"\\x\"",
+            "[1] 2",
+            "tru",
+            "\"\\ud800\"",
+        ] {
+            assert_eq!(
+                parse(bad).unwrap_err().to_string(),
+                "malformed JSON",
+                "{bad}"
+            );
+        }
+        assert!(parse(&"[".repeat(2000)).is_err());
+    }
+
+    #[test]
+    fn paths_lead_to_values() {
+        let json = parse(r#"{"a":{"b c":[10,[20,30]]},"x":1,"x":2}"#).unwrap();
+        let get = |path| json.get(path).unwrap().map(|v| v.to_string());
+        assert_eq!(get("$.a.\"b c\"[1][0]").as_deref(), Some("20"));
+        assert_eq!(get("$.a.\"b c\"[#-1]").as_deref(), Some("[20,30]"));
+        assert_eq!(get("$.x").as_deref(), Some("2"));
+        assert_eq!(get("$.a.nope"), None);
+        assert_eq!(get("$.x[0]"), None);
+        assert_eq!(get("$").unwrap().len(), json.to_string().len());
+        for bad in ["a", "$.", "$[x]", "$a", "$[1"] {
+            assert_eq!(
+                json.get(bad).unwrap_err().to_string(),
+                format!("bad JSON path: '{bad}'")
+            );
+        }
+    }
+}
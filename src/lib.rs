@@ -0,0 +1,124 @@
+/*
+    A SQLite clone, as a library for Rust programs to embed.
+
+        use rust_wrapper::Connection;
+
+        let mut conn = Connection::open("shop.db")?;
+        let rows = conn.query("SELECT name FROM users WHERE age > ?;", &[21.into()])?;
+
+    Connection and Statement in connection.rs, Rows and Row in row.rs, Backup in
+    backup.rs, InterruptHandle in interrupt.rs, Pool in pool.rs, LockStatus in storage/lock.rs, the authorizer's Action
+    in authorizer.rs, the schema described as data in introspect.rs and the VirtualTable
+    trait in vtab.rs are all there is to the API;
+    the engine behind them, the parser, planner, executor and storage, is private to the
+    crate so that it can keep changing. With the serde feature rows can be read into
+    structs and structs inserted as rows, see row_serde.rs, and with the fuzz feature the
+    fuzz targets in fuzz/ reach the parser and decoders through fuzz.rs. The sqlite3-style
+    shell in cli and repl, behind the cli feature, is the binary in main.rs and just
+    another user of the engine.
+*/
+// Replication, salvaging a damaged file, group commit and the like are only reached
+// through the shell, so a build without it leaves them unused.
+#![cfg_attr(not(feature = "cli"), allow(dead_code))]
+
+#[cfg(feature = "cli")]
+mod repl;
+
+mod aggregate;
+
+mod authorizer;
+
+mod backup;
+
+mod catalog;
+
+mod check;
+
+#[cfg(feature = "cli")]
+pub mod cli;
+
+mod collation;
+
+mod compound;
+
+mod connection;
+
+mod error;
+
+mod eval;
+
+mod executor;
+
+mod foreign_key;
+
+mod functions;
+
+mod generated;
+
+#[cfg(any(test, feature = "fuzz"))]
+pub mod fuzz;
+
+#[cfg(all(test, feature = "cli"))]
+mod golden;
+
+mod interrupt;
+
+mod introspect;
+
+mod join;
+
+mod json;
+
+mod nesting;
+
+mod planner;
+
+mod pool;
+
+mod pragma;
+
+mod prepared;
+
+mod profile;
+
+mod replication;
+
+mod resolve;
+
+mod row;
+
+#[cfg(feature = "serde")]
+mod row_serde;
+
+mod schema;
+
+mod sorter;
+
+mod sql_parser;
+
+mod storage;
+
+mod transaction;
+
+mod trigger;
+
+mod ttl;
+
+mod vdbe;
+
+mod vtab;
+
+mod window;
+
+pub use authorizer::{Action, Authorization};
+pub use backup::{Backup, Progress};
+pub use connection::{Connection, Statement};
+pub use interrupt::InterruptHandle;
+pub use introspect::{
+    ColumnInfo, IndexColumn, IndexInfo, IndexOrigin, SchemaInfo, TableInfo, TriggerInfo, ViewInfo,
+};
+pub use pool::{Pool, PooledConnection};
+pub use row::{FromValue, Row, Rows};
+pub use sql_parser::ast::{ColVal, ForeignKey, ForeignKeyAction, TriggerEvent, TriggerTiming};
+pub use storage::lock::{ConnectionId, LockLevel, LockStatus};
+pub use vtab::{Constraint, ConstraintOp, IndexPlan, Module, VirtualCursor, VirtualTable};
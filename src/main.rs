@@ -1,10 +1,40 @@
-mod repl;
-use repl::repl_loop;
-
-mod sql_parser;
+#[cfg(feature = "cli")]
+fn main() -> std::process::ExitCode {
+    rust_wrapper::cli::main()
+}
 
-mod storage;
+// Just the engine, for a build without the shell.
+#[cfg(not(feature = "cli"))]
+fn main() -> anyhow::Result<()> {
+    use rust_wrapper::ColVal;
+    use std::io::BufRead;
 
-fn main() {
-    repl_loop().expect("something went wrong in REPL");
+    let mut conn = rust_wrapper::Connection::open_in_memory();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match conn.query(line.trim(), &[]) {
+            Ok(rows) => {
+                for row in rows {
+                    let row = row?;
+                    // as the shell prints them, NULL as nothing and strings unquoted
+                    let values: Vec<String> = row
+                        .values()
+                        .iter()
+                        .map(|v| match v {
+                            ColVal::Null => String::new(),
+                            ColVal::Boolean(b) => (*b as i64).to_string(),
+                            ColVal::String(s) => s.clone(),
+                            v => v.to_string(),
+                        })
+                        .collect();
+                    println!("{}", values.join("|"));
+                }
+            }
+            Err(err) => eprintln!("{err}"),
+        }
+    }
+    Ok(())
 }
@@ -0,0 +1,96 @@
+/*
+    How deep statements may run inside one another.
+
+    A statement can start others while it runs. A trigger's body runs for each row the
+    write that fired it touches, and its own writes may fire more triggers, the same one
+    included, and a subquery in an expression, a WHERE clause, a CHECK constraint or a
+    trigger's WHEN, is run for each row it is worked out for. Each of these goes a level
+    deeper through the executor's one Nesting, which fails the statement that would go
+    past the limit with "too many levels of trigger recursion", in SQLite's words,
+    rather than let a trigger that fires itself run until the stack overflows. The
+    statement the user ran fails with it and is undone, as any failed write is.
+
+        CREATE TRIGGER again AFTER INSERT ON t BEGIN
+            INSERT INTO t (n) VALUES (NEW.n + 1);
+        END;
+        INSERT INTO t (n) VALUES (1);   -- too many levels of trigger recursion
+
+    The limit is 100 levels, a tenth of SQLite's SQLITE_MAX_TRIGGER_DEPTH, as each level
+    takes more of the stack here than one of SQLite's does. PRAGMA max_trigger_depth
+    changes it, and a thread with a big enough stack can raise it.
+*/
+use crate::error::SqlError;
+use anyhow::{bail, Result};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+/// How many levels statements may nest by default.
+pub const DEFAULT_LIMIT: u32 = 100;
+
+/// How deep the statements running are, and how deep they may go.
+#[derive(Debug)]
+pub struct Nesting {
+    // shared with the Level of each statement running, which may outlive a borrow of the
+    // executor
+    depth: Arc<AtomicU32>,
+    pub limit: u32,
+}
+
+impl Default for Nesting {
+    fn default() -> Self {
+        Nesting {
+            depth: Arc::default(),
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+impl Nesting {
+    /// Go a level deeper, or fail if that would go past the limit. The level is left
+    /// when the Level is dropped.
+    pub fn enter(&self) -> Result<Level> {
+        let depth = self.depth.fetch_add(1, Ordering::Relaxed) + 1;
+        let level = Level(self.depth.clone());
+        if depth > self.limit {
+            bail!(SqlError::TooDeep);
+        }
+        Ok(level)
+    }
+
+    /// How many levels deep the statements running are, 0 at the top.
+    pub fn depth(&self) -> u32 {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+/// A level of nesting, left when dropped.
+#[derive(Debug)]
+pub struct Level(Arc<AtomicU32>);
+
+impl Drop for Level {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_past_the_limit_fail() {
+        let nesting = Nesting {
+            limit: 2,
+            ..Nesting::default()
+        };
+        let first = nesting.enter().unwrap();
+        let second = nesting.enter().unwrap();
+        let err = nesting.enter().unwrap_err();
+        assert_eq!(err.to_string(), "too many levels of trigger recursion");
+        // the failed level is left too
+        assert_eq!(nesting.depth(), 2);
+        drop((first, second));
+        assert_eq!(nesting.depth(), 0);
+        assert!(nesting.enter().is_ok());
+    }
+}
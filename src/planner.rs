@@ -0,0 +1,3389 @@
+/*
+    Query planning.
+
+    The parser tells us what a query asks for, the planner decides how to get it. A plan
+    is a tree of operators where rows flow from the leaves up to the root: a Scan reads a
+    table, a Filter drops rows whose predicate isn't TRUE and a Project picks out the
+    result columns.
+
+    The simplest plan for any SELECT is Scan -> Filter(WHERE) -> Project and that is always
+    correct, but it isn't always fast. Take
+
+        SELECT name FROM users WHERE EXISTS (SELECT 1 FROM orders WHERE user_id = id)
+
+    Evaluated as written, the subquery runs once for every user and each run scans all
+    of orders. The only link between the two queries is `user_id = id` so we can instead
+    read orders once, collect the user_ids that appear, and keep each user whose id is
+    among them. This is a semi-join: like a join, except a user is kept at most once no
+    matter how many orders match, and none of the orders' columns come along. NOT EXISTS
+    becomes an anti-join which keeps the users that have no match.
+
+    The rewrite is only valid when the subquery is tied to the outer query by equalities
+    alone. Anything else, e.g. `WHERE total > balance`, stays a filter evaluated row by row.
+
+    A join is a hash join, which collects the inner side's keys in a hash table, unless
+    both sides are known to arrive sorted by the keys, say both are tables scanned in
+    rowid order and joined on their INTEGER PRIMARY KEYs. Then it is a merge join, which
+    walks the two in step without building anything. See join.rs for both.
+
+    A FROM clause can join several tables, `FROM users u JOIN orders o ON o.user_id = u.id`
+    or `FROM users u, orders o WHERE ...`, pairing each row of one with each row of the
+    other that the ON and WHERE clauses accept. Their terms are sorted three ways. A term
+    of one table's columns alone is pushed down to that table, which is read as though
+    queried on its own, from an index if the term allows. A term equating a column of one
+    table with a column of another, `u.id = o.user_id`, is a key the two are joined on,
+    by a hash join or a merge join as above. Anything else filters the joined rows.
+
+    The tables are joined one at a time, each onto the rows of those before it, and the
+    order matters: every row a join makes is one the joins after it have to match. So it
+    is chosen greedily by the estimates above, with each table's own terms applied. The
+    table with the fewest rows comes first, then of the tables with a key to those
+    joined so far the one with the fewest rows, and so on. A table with no key to any of
+    them pairs with all of their rows, so it is only joined once no keyed table is left.
+
+    Scanning a whole table to find a handful of rows is wasteful when an index can take us
+    straight to them. For `WHERE age IN (21, 30, 40)` and an index on age we seek the index
+    once for each value in the list, and `a = 1 AND b IN (2, 3)` on an index over (a, b)
+    becomes the two seeks (1, 2) and (1, 3).
+
+    An index also keeps its entries in order, so the rows with `age > 30 AND age <= 40`
+    are all together in an index on age: the search starts at the first entry above 30
+    and reads until one goes past 40. A range can only bound the column straight after
+    those the seeks pin, `a = 1 AND b > 2` on (a, b) but not `b > 2` alone, and only one
+    term from each side is used, any others stay in the filter.
+
+    An index isn't always the better way though. Each seek walks the index from its root,
+    and a key shared by many rows reads all of them, so a long IN list against a small
+    table is cheaper to answer with a scan. The planner estimates what each way costs,
+    roughly how many rows and index entries it will touch, and takes the cheapest:
+
+        scan             the table's rows
+        index search     seeks * (log2(rows) + rows per key)
+
+    where each bound of a range is guessed to leave a quarter of the rows per key.
+
+    The numbers come from statistics about each table, its number of rows and how many of
+    them share a key of each index, when the catalog has them. ANALYZE measures them into
+    sqlite_stat1, and into sqlite_stat4 samples of each index's first column, which let a
+    range of that column be counted rather than guessed. Without statistics we do as
+    SQLite does and assume a table of about a million rows, and that a key of one column
+    matches 10 rows, of two columns 9, then 8, 7 and 6, unless the index is UNIQUE and the
+    key covers all its columns, when it matches one row.
+
+    Statistics can mislead, so as in SQLite a query can overrule the planner. After the
+    table in FROM, `INDEXED BY idx` makes it search that index, and fail with "no query
+    solution" if the WHERE clause gives it nothing to seek, while `NOT INDEXED` makes it
+    scan. A unary plus hides a single term from every index: in `WHERE +age = 30` the
+    term `+age` is an expression rather than the column age, so it stays a filter.
+
+    A virtual table's rows are made by Rust code, see vtab.rs. Its scan is a VirtualScan,
+    which hands the table the values of whichever terms of the WHERE clause it chooses to
+    narrow its rows down with, while the filter above it checks every term as usual.
+
+    A view is a named SELECT stored in the schema and read like a table. Reading a view
+    simply runs its SELECT, so the view's plan goes where a scan of a table would be.
+
+    Writes to a table with triggers on it are wrapped in a Triggers operator carrying the
+    triggers that fire, see the trigger module for how the executor runs them.
+
+    A WITH clause names queries that the statement then reads like tables. We inline them:
+    wherever the statement scans a CTE, the CTE's own plan is put in place of the scan.
+    SQLite does the same unless a CTE is used more than once, when it may instead run the
+    query once into a temporary table.
+
+    A subquery in FROM, `SELECT * FROM (SELECT ...) AS x`, is a CTE by another name, and
+    is planned as though it had been written `WITH x AS (SELECT ...) SELECT * FROM x`.
+
+    A scalar subquery, `(SELECT ...)` used as a value, stays in the expression it is part
+    of and is run whenever the expression is evaluated, once for each row being filtered.
+    When it refers to nothing of that row its value is the same every time, so before
+    running the plan the executor asks `fold_uncorrelated` to put the value in its place.
+
+    A WHERE clause on a view or CTE is pushed down into it where it can be: a term that
+    only uses columns the view passes through unchanged from its table is moved beneath the
+    view's projection, renamed to the table's columns. There it drops rows before they are
+    projected, and may let the view search an index instead of scanning its table.
+
+    When a query has several semi-joins, the one whose inner side is estimated to have the
+    fewest rows is done first, as it is the cheapest to build and every outer row it drops
+    is one the more expensive joins above it never see.
+
+    The plan prints as a tree of its operators, which is the quickest way to see what the
+    planner made of a query:
+
+        PROJECT name
+        └── HASH SEMI JOIN ON id = user_id
+            ├── SCAN users
+            └── SCAN orders
+
+    EXPLAIN QUERY PLAN shows less, only the decisions a user can do something about: how
+    each table is read, whether by scanning it or searching one of its indexes, and where a
+    join, view or sort comes in. It is laid out the way the sqlite3 shell lays it out:
+
+        QUERY PLAN
+        |--SCAN users
+        `--HASH SEMI JOIN ON id = user_id
+           `--SEARCH orders USING INDEX idx_orders_user (user_id=?)
+*/
+use crate::catalog::MASTER_TABLE;
+use crate::check;
+use crate::error::SqlError;
+use crate::eval::{self, Affinity};
+use crate::generated;
+use crate::resolve::resolve;
+use crate::sql_parser::ast::{
+    Aggregate, BinaryOp, ColVal, CommonTableExpr, CompoundOperator, CreateIndex, CreateTable,
+    CreateTrigger, CreateView, CreateVirtualTable, Expr, IndexHint, Join, Limit, NewColumnVal,
+    OrderingTerm, Pragma, Statement, TransactionMode, WindowFunction,
+};
+use crate::sql_parser::{parse_aggregate, parse_expr, parse_window_function};
+use crate::trigger;
+use crate::vtab::{self, Constraint, ConstraintOp, VirtualTable};
+use anyhow::{anyhow, bail, Result};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// What the planner needs to know about the tables a query touches.
+pub trait Catalog {
+    /// The column names of a table in the order they appear in its rows.
+    fn table_columns(&self, table: &str) -> Result<Vec<String>>;
+
+    /// The definitions of the indexes on a table.
+    fn table_indexes(&self, table: &str) -> Vec<CreateIndex>;
+
+    /// The definition of a view, None if there is no view of that name.
+    fn view(&self, name: &str) -> Option<CreateView>;
+
+    /// The definitions of the triggers on a table.
+    fn table_triggers(&self, table: &str) -> Vec<CreateTrigger>;
+
+    /// What is known about a table's contents, None if it has never been measured.
+    fn table_stats(&self, _table: &str) -> Option<TableStats> {
+        None
+    }
+
+    /// The columns a scan of a table returns its rows sorted by, compared as BINARY.
+    fn scan_order(&self, _table: &str) -> Vec<String> {
+        vec![]
+    }
+
+    /// Whether a table's rows have rowids, which a WITHOUT ROWID table's, a view's and a
+    /// CTE's don't.
+    fn has_rowid(&self, _table: &str) -> bool {
+        false
+    }
+
+    /// The virtual table of that name, None if it isn't one, see vtab.rs.
+    fn virtual_table(&self, _name: &str) -> Option<Arc<dyn VirtualTable>> {
+        None
+    }
+
+    /// What a table named without its database is called if it is a temporary table,
+    /// `temp.name`, None if it isn't one, see storage::attach.
+    fn temp_table(&self, _name: &str) -> Option<String> {
+        None
+    }
+
+    /// The affinity of a table's column, from its declared type, None if it has no
+    /// declared type to go by, as a CTE's columns don't.
+    fn column_affinity(&self, _table: &str, _column: &str) -> Option<Affinity> {
+        None
+    }
+}
+
+/// The names a query can read a row's rowid by, as in SQLite, though like column names
+/// here only in lower case.
+pub const ROWID_NAMES: [&str; 3] = ["rowid", "oid", "_rowid_"];
+
+/// Whether `name` reads the rowid of a row with `columns`, being one of the rowid's names
+/// that no column has taken for itself.
+pub fn is_rowid(name: &str, columns: &[String]) -> bool {
+    ROWID_NAMES.contains(&name) && !columns.iter().any(|c| c == name)
+}
+
+/// Statistics the planner estimates costs from, like the rows of SQLite's sqlite_stat1.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TableStats {
+    pub rows: u64,
+    /// For each index, how many rows share a key of its first column, of its first two
+    /// columns and so on, on average.
+    pub rows_per_key: BTreeMap<String, Vec<u64>>,
+    /// For each index, values of its first column in order, like SQLite's sqlite_stat4.
+    pub samples: BTreeMap<String, Vec<Sample>>,
+}
+
+/// A value of an index's first column that ANALYZE sampled, with how many rows hold it
+/// and how many hold a smaller value.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Sample {
+    pub value: ColVal,
+    pub eq: u64,
+    pub lt: u64,
+}
+
+// SQLite's estimates for a table with no statistics.
+const DEFAULT_ROWS: u64 = 1 << 20;
+const DEFAULT_ROWS_PER_KEY: [u64; 5] = [10, 9, 8, 7, 6];
+// The share of rows a term of a WHERE clause is guessed to keep.
+const TERM_SELECTIVITY: f64 = 0.25;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Plan {
+    Scan {
+        table: String,
+    },
+    // The one row, of no columns, that a SELECT without FROM works its result columns out
+    // from.
+    ConstantRow,
+    // Read a virtual table, handing it the number its best_index chose and the values of
+    // the terms it chose, see vtab.rs.
+    VirtualScan {
+        table: String,
+        number: i64,
+        values: Vec<Expr>,
+    },
+    // Seek an index once per key, each key giving values for the index's leading columns,
+    // and read the entries with that key whose next column is within `range`, if given.
+    IndexSearch {
+        table: String,
+        index: String,
+        columns: Vec<String>,
+        seeks: Vec<Vec<ColVal>>,
+        range: Option<KeyRange>,
+    },
+    Filter {
+        input: Box<Plan>,
+        predicate: Expr,
+    },
+    // Rows of `outer` with (or with anti, without) a row of `inner` whose keys are equal.
+    SemiJoin {
+        outer: Box<Plan>,
+        inner: Box<Plan>,
+        keys: Vec<JoinKey>,
+        anti: bool,
+        algorithm: JoinAlgorithm,
+    },
+    // The rows of the first table paired with those of each table after it in turn, see
+    // JoinTable. A table's columns are named `name.column` after the name it is known by.
+    Join {
+        tables: Vec<JoinTable>,
+    },
+    Project {
+        input: Box<Plan>,
+        columns: Vec<String>,
+    },
+    // Fold every row of `input` into one row, of the aggregates' values named as written,
+    // or with a GROUP BY one row per group in the order of its values, those first.
+    Aggregate {
+        input: Box<Plan>,
+        aggregates: Vec<Aggregate>,
+        group_by: Vec<Expr>,
+    },
+    // The rows of `input` with the value of each window function added after their own
+    // columns, named as written.
+    Window {
+        input: Box<Plan>,
+        windows: Vec<WindowFunction>,
+    },
+    Sort {
+        input: Box<Plan>,
+        order_by: Vec<OrderingTerm>,
+    },
+    Limit {
+        input: Box<Plan>,
+        limit: Limit,
+    },
+    // The rows of `left` and `right` put together as a compound SELECT's `operator` says,
+    // under `left`'s column names.
+    Compound {
+        operator: CompoundOperator,
+        left: Box<Plan>,
+        right: Box<Plan>,
+    },
+    // Write the rows produced by `input`.
+    Update {
+        input: Box<Plan>,
+        table: String,
+        assignments: Vec<NewColumnVal>,
+    },
+    Delete {
+        input: Box<Plan>,
+        table: String,
+    },
+    // The rows of a view's SELECT, under the view's column names.
+    View {
+        name: String,
+        columns: Vec<String>,
+        input: Box<Plan>,
+    },
+    // The rows of a common table expression, under the CTE's name and column names.
+    Cte {
+        name: String,
+        columns: Vec<String>,
+        input: Box<Plan>,
+    },
+    Insert {
+        table: String,
+        columns: Vec<String>,
+        values: Vec<Expr>,
+    },
+    // A write and the triggers that fire for each row it touches.
+    Triggers {
+        input: Box<Plan>,
+        triggers: Vec<CreateTrigger>,
+    },
+    CreateTable(CreateTable),
+    CreateIndex(CreateIndex),
+    CreateView(CreateView),
+    CreateTrigger(CreateTrigger),
+    CreateVirtualTable(CreateVirtualTable),
+    Begin(TransactionMode),
+    Commit,
+    Rollback,
+    Savepoint(String),
+    Release(String),
+    RollbackTo(String),
+    Attach {
+        path: String,
+        name: String,
+    },
+    Detach(String),
+    Pragma(Pragma),
+    // Measure a table, the table of an index, or with None every table.
+    Analyze(Option<String>),
+    // Rebuild an index, the indexes of a table or of a collation, or with None every index.
+    Reindex(Option<String>),
+    Vacuum,
+}
+
+/// How a join matches rows, see join.rs.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JoinAlgorithm {
+    Hash,
+    // both inputs arrive sorted by their keys, in the order the keys are listed
+    Merge,
+}
+
+impl fmt::Display for JoinAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JoinAlgorithm::Hash => write!(f, "HASH"),
+            JoinAlgorithm::Merge => write!(f, "MERGE"),
+        }
+    }
+}
+
+/// Bounds on an index column, from terms like `age > 30` and `age <= 40`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeyRange {
+    pub column: String,
+    pub lower: Bound<ColVal>,
+    pub upper: Bound<ColVal>,
+}
+
+impl KeyRange {
+    fn bounds(&self) -> usize {
+        [&self.lower, &self.upper]
+            .iter()
+            .filter(|b| !matches!(b, Bound::Unbounded))
+            .count()
+    }
+}
+
+impl fmt::Display for KeyRange {
+    // As the terms it came from: `age > 30 AND age <= 40`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut terms = vec![];
+        match &self.lower {
+            Bound::Included(v) => terms.push(format!("{} >= {v}", self.column)),
+            Bound::Excluded(v) => terms.push(format!("{} > {v}", self.column)),
+            Bound::Unbounded => {}
+        }
+        match &self.upper {
+            Bound::Included(v) => terms.push(format!("{} <= {v}", self.column)),
+            Bound::Excluded(v) => terms.push(format!("{} < {v}", self.column)),
+            Bound::Unbounded => {}
+        }
+        write!(f, "{}", terms.join(" AND "))
+    }
+}
+
+/// A table of a join, and how its rows are matched with the rows joined before it: those
+/// whose keys equal theirs are found with `algorithm`, and without keys all of them are.
+#[derive(Debug, PartialEq, Clone)]
+pub struct JoinTable {
+    pub name: String,
+    pub rows: Plan,
+    // `outer` names a column of a table joined before as `name.column`
+    pub keys: Vec<JoinKey>,
+    pub algorithm: JoinAlgorithm,
+}
+
+impl JoinTable {
+    // How the table is joined onto those before it: `HASH JOIN o ON u.id = o.user_id`.
+    fn label(&self) -> String {
+        if self.keys.is_empty() {
+            return format!("CROSS JOIN {}", self.name);
+        }
+        let on: Vec<String> = self
+            .keys
+            .iter()
+            .map(|k| format!("{} = {}.{}", k.outer, self.name, k.inner))
+            .collect();
+        format!(
+            "{} JOIN {} ON {}",
+            self.algorithm,
+            self.name,
+            on.join(" AND ")
+        )
+    }
+}
+
+/// A pair of columns that must be equal for an outer row to match an inner row.
+#[derive(Debug, PartialEq, Clone)]
+pub struct JoinKey {
+    pub outer: String,
+    pub inner: String,
+}
+
+/// Decide how to run a statement, resolving the tables and columns it names.
+pub fn plan(statement: &Statement, catalog: &dyn Catalog) -> Result<Plan> {
+    if let Statement::Select {
+        from_table,
+        from_select: Some(select),
+        ..
+    } = statement
+    {
+        return plan(&from_select_as_cte(statement, from_table, select), catalog);
+    }
+    let statement = &resolve(statement, catalog)?;
+    match statement {
+        Statement::Select {
+            columns,
+            from_table,
+            alias,
+            index_hint,
+            joins,
+            where_clause,
+            group_by,
+            order_by,
+            limit,
+            ..
+        } => {
+            let mut tables = vec![];
+            if !from_table.is_empty() {
+                tables.push(FromTable {
+                    name: alias.as_deref().unwrap_or(from_table),
+                    table: from_table,
+                    index_hint: index_hint.as_ref(),
+                });
+            }
+            tables.extend(joins.iter().map(|join| FromTable {
+                name: join.alias.as_deref().unwrap_or(&join.table),
+                table: &join.table,
+                index_hint: None,
+            }));
+            let mut rows = match joins.is_empty() {
+                true => plan_rows(
+                    from_table,
+                    alias.as_deref(),
+                    index_hint.as_ref(),
+                    where_clause.as_ref(),
+                    catalog,
+                )?,
+                false => plan_join(&tables, joins, where_clause.as_ref(), catalog)?,
+            };
+            let columns = expand_wildcards(columns, &tables, catalog)?;
+            let group_by = numbered_terms(group_by, &columns, "GROUP BY")?;
+            let aggregates = aggregates(&columns, &group_by)?;
+            let windows = windows(&columns);
+            // what the rows are known by once folded, for ORDER BY to sort them by
+            let mut folded: Vec<String> = vec![];
+            if !aggregates.is_empty() || !group_by.is_empty() {
+                if !windows.is_empty() {
+                    bail!("aggregates alongside window functions are not supported");
+                }
+                folded.extend(group_by.iter().map(|e| e.to_string()));
+                folded.extend(aggregates.iter().map(|a| a.to_string()));
+                rows = Plan::Aggregate {
+                    input: Box::new(rows),
+                    aggregates,
+                    group_by,
+                };
+            }
+            if !windows.is_empty() {
+                folded.extend(windows.iter().map(|w| w.to_string()));
+                rows = Plan::Window {
+                    input: Box::new(rows),
+                    windows,
+                };
+            }
+            let terms: Vec<Expr> = order_by.iter().map(|o| o.expr.clone()).collect();
+            let order_by = numbered_terms(&terms, &columns, "ORDER BY")?
+                .into_iter()
+                .zip(order_by)
+                .map(|(mut expr, term)| {
+                    over_folded(&mut expr, &folded);
+                    OrderingTerm {
+                        expr,
+                        descending: term.descending,
+                    }
+                })
+                .collect::<Vec<_>>();
+            // sorted and limited before the result columns are worked out, so ORDER BY can
+            // name columns that aren't among them
+            rows = sort_and_limit(rows, &order_by, limit);
+            push_down(&mut rows, catalog);
+            Ok(Plan::Project {
+                input: Box::new(rows),
+                columns,
+            })
+        }
+        Statement::Insert {
+            into_table,
+            columns,
+        } => {
+            check_writable(into_table, catalog)?;
+            let table_columns = catalog.table_columns(into_table)?;
+            for c in columns {
+                if !table_columns.contains(&c.column_name) {
+                    bail!("table {into_table} has no column named {}", c.column_name);
+                }
+            }
+            let insert = Plan::Insert {
+                table: into_table.clone(),
+                columns: columns.iter().map(|c| c.column_name.clone()).collect(),
+                values: columns.iter().map(|c| c.value.clone()).collect(),
+            };
+            Ok(with_triggers(insert, into_table, statement, catalog))
+        }
+        Statement::Update {
+            table,
+            assignments,
+            where_clause,
+            order_by,
+            limit,
+        } => {
+            check_writable(table, catalog)?;
+            let table_columns = catalog.table_columns(table)?;
+            for a in assignments {
+                if !table_columns.contains(&a.column_name) {
+                    bail!(SqlError::NoSuchColumn {
+                        column: a.column_name.to_string()
+                    });
+                }
+            }
+            let rows = plan_rows(table, None, None, where_clause.as_ref(), catalog)?;
+            let update = Plan::Update {
+                input: Box::new(sort_and_limit(rows, order_by, limit)),
+                table: table.clone(),
+                assignments: assignments.clone(),
+            };
+            Ok(with_triggers(update, table, statement, catalog))
+        }
+        Statement::Delete {
+            from_table,
+            where_clause,
+            order_by,
+            limit,
+        } => {
+            check_writable(from_table, catalog)?;
+            let rows = plan_rows(from_table, None, None, where_clause.as_ref(), catalog)?;
+            let delete = Plan::Delete {
+                input: Box::new(sort_and_limit(rows, order_by, limit)),
+                table: from_table.clone(),
+            };
+            Ok(with_triggers(delete, from_table, statement, catalog))
+        }
+        Statement::Compound {
+            first,
+            rest,
+            order_by,
+            limit,
+        } => {
+            let mut rows = plan(first, catalog)?;
+            for (operator, select) in rest {
+                let right = plan(select, catalog)?;
+                if result_columns(&right).len() != result_columns(&rows).len() {
+                    bail!(
+                        "SELECTs to the left and right of {operator} do not have the same number of result columns"
+                    );
+                }
+                rows = Plan::Compound {
+                    operator: *operator,
+                    left: Box::new(rows),
+                    right: Box::new(right),
+                };
+            }
+            let order_by = compound_order_by(order_by, &result_columns(&rows))?;
+            Ok(sort_and_limit(rows, &order_by, limit))
+        }
+        Statement::With { ctes, body } => {
+            let mut scope = WithScope {
+                outer: catalog,
+                ctes: vec![],
+            };
+            for cte in ctes {
+                if scope.ctes.iter().any(|(c, _)| c.name == cte.name) {
+                    bail!("duplicate WITH table name: {}", cte.name);
+                }
+                let mut input = plan(&cte.select, &scope)?;
+                scope.inline(&mut input);
+                scope.ctes.push((
+                    cte.clone(),
+                    Plan::Cte {
+                        name: cte.name.clone(),
+                        columns: cte_columns(cte, &input)?,
+                        input: Box::new(input),
+                    },
+                ));
+            }
+            let mut body = plan(body, &scope)?;
+            scope.inline(&mut body);
+            push_down(&mut body, &scope);
+            Ok(body)
+        }
+        Statement::CreateTable(table) => {
+            let columns = table.column_names();
+            for (i, column) in columns.iter().enumerate() {
+                if columns[..i].contains(column) {
+                    bail!("duplicate column name: {column}");
+                }
+            }
+            for (i, column) in table.primary_key.iter().enumerate() {
+                if !columns.contains(column) {
+                    bail!(SqlError::NoSuchColumn {
+                        column: column.to_string()
+                    });
+                }
+                if table.primary_key[..i].contains(column) {
+                    bail!("duplicate column name in PRIMARY KEY: {column}");
+                }
+            }
+            for key in &table.foreign_keys {
+                if let Some(column) = key.columns.iter().find(|c| !columns.contains(c)) {
+                    bail!("unknown column \"{column}\" in foreign key definition");
+                }
+                if !key.parent_columns.is_empty() && key.parent_columns.len() != key.columns.len() {
+                    bail!(
+                        "number of columns in foreign key does not match the number of columns in the referenced table"
+                    );
+                }
+            }
+            if table.without_rowid && table.primary_key.is_empty() {
+                bail!("PRIMARY KEY missing on table {}", table.name);
+            }
+            generated::check(table)?;
+            check::check(table)?;
+            Ok(Plan::CreateTable(table.clone()))
+        }
+        Statement::CreateIndex(index) => {
+            let table_columns = catalog.table_columns(&index.table)?;
+            for column in &index.columns {
+                let mut unknown = None;
+                column.walk(&mut |e| match e {
+                    Expr::Column(c) if !table_columns.contains(c) => unknown = Some(c),
+                    _ => {}
+                });
+                if let Some(c) = unknown {
+                    bail!(SqlError::NoSuchColumn {
+                        column: c.to_string()
+                    });
+                }
+            }
+            Ok(Plan::CreateIndex(index.clone()))
+        }
+        Statement::CreateView(view) => {
+            // a view has to be readable when it is created
+            let input = plan(&view.select, catalog)?;
+            view_columns(view, &input)?;
+            Ok(Plan::CreateView(view.clone()))
+        }
+        Statement::CreateTrigger(trigger) => {
+            if catalog.view(&trigger.table).is_some() {
+                bail!(
+                    "cannot create {} trigger on view: {}",
+                    trigger.timing,
+                    trigger.table
+                );
+            }
+            if catalog.virtual_table(&trigger.table).is_some() {
+                bail!("cannot create triggers on virtual tables");
+            }
+            catalog.table_columns(&trigger.table)?;
+            Ok(Plan::CreateTrigger(trigger.clone()))
+        }
+        Statement::CreateVirtualTable(table) => {
+            if table.name.contains('.') {
+                bail!("virtual tables in attached databases are not supported yet");
+            }
+            Ok(Plan::CreateVirtualTable(table.clone()))
+        }
+        Statement::Begin(mode) => Ok(Plan::Begin(*mode)),
+        Statement::Commit => Ok(Plan::Commit),
+        Statement::Rollback => Ok(Plan::Rollback),
+        Statement::Savepoint(name) => Ok(Plan::Savepoint(name.clone())),
+        Statement::Release(name) => Ok(Plan::Release(name.clone())),
+        Statement::RollbackTo(name) => Ok(Plan::RollbackTo(name.clone())),
+        Statement::Attach { path, name } => Ok(Plan::Attach {
+            path: path.clone(),
+            name: name.clone(),
+        }),
+        Statement::Detach(name) => Ok(Plan::Detach(name.clone())),
+        Statement::Pragma(pragma) => Ok(Plan::Pragma(pragma.clone())),
+        Statement::Analyze(name) => Ok(Plan::Analyze(name.clone())),
+        Statement::Reindex(name) => Ok(Plan::Reindex(name.clone())),
+        Statement::Vacuum => Ok(Plan::Vacuum),
+        Statement::Explain(_) | Statement::ExplainQueryPlan(_) | Statement::ExplainAnalyze(_) => {
+            bail!("EXPLAIN can only be applied to a single statement")
+        }
+    }
+}
+
+// The aggregates among a SELECT's result columns. The only other columns allowed
+// alongside them are the GROUP BY terms and expressions of those, where SQLite would
+// take a bare column's value from an arbitrary row of the group.
+fn aggregates(columns: &[String], group_by: &[Expr]) -> Result<Vec<Aggregate>> {
+    let groups: Vec<String> = group_by.iter().map(|e| e.to_string()).collect();
+    let grouped = |column: &String| {
+        if groups.contains(column) {
+            return true;
+        }
+        let Ok(expr) = parse_expr(column) else {
+            return false;
+        };
+        let mut grouped = true;
+        expr.walk(&mut |e| match e {
+            Expr::Column(c) => grouped &= groups.contains(c),
+            Expr::QualifiedColumn { .. } => grouped &= groups.contains(&e.to_string()),
+            _ => {}
+        });
+        grouped
+    };
+    let mut aggregates = vec![];
+    let mut bare = None;
+    for column in columns {
+        if parse_window_function(column).is_ok() {
+            continue;
+        }
+        match parse_aggregate(column) {
+            Ok(aggregate) => aggregates.push(aggregate),
+            Err(_) if !grouped(column) => bare = Some(column),
+            Err(_) => {}
+        }
+    }
+    match bare {
+        Some(column) if !aggregates.is_empty() => {
+            bail!("bare column {column} alongside aggregates is not supported")
+        }
+        _ => Ok(aggregates),
+    }
+}
+
+// The window functions among a SELECT's result columns.
+fn windows(columns: &[String]) -> Vec<WindowFunction> {
+    columns
+        .iter()
+        .filter_map(|column| parse_window_function(column).ok())
+        .collect()
+}
+
+// Replace `*` with every column of the tables read, but for a virtual table's hidden ones.
+// Name resolution has already turned `table.*` into `*` when there is one table. With
+// several, each column is qualified with the name its table is known by, and `u.*` is
+// the columns of u alone.
+fn expand_wildcards(
+    columns: &[String],
+    tables: &[FromTable],
+    catalog: &dyn Catalog,
+) -> Result<Vec<String>> {
+    let joined = tables.len() > 1;
+    let mut expanded = vec![];
+    for column in columns {
+        let read: Vec<&FromTable> = match column.strip_suffix(".*") {
+            _ if column == "*" => tables.iter().collect(),
+            Some(name) if joined => tables.iter().filter(|t| t.name == name).collect(),
+            _ => {
+                expanded.push(column.clone());
+                continue;
+            }
+        };
+        if tables.is_empty() {
+            bail!("no tables specified");
+        }
+        for from in read {
+            let names = match catalog.virtual_table(from.table) {
+                Some(table) => table.columns(),
+                None => catalog.table_columns(from.table)?,
+            };
+            expanded.extend(names.into_iter().map(|column| match joined {
+                true => format!("{}.{column}", from.name),
+                false => column,
+            }));
+        }
+    }
+    Ok(expanded)
+}
+
+/// What a result column is called: a column of a joined table by its own name, `name` for
+/// `u.name`, and anything else as it is written.
+pub fn column_name(column: &str) -> String {
+    match parse_expr(column) {
+        Ok(Expr::QualifiedColumn { column, .. }) => column,
+        _ => column.to_string(),
+    }
+}
+
+// Every row of a table, or of a view.
+fn scan(table: &str, catalog: &dyn Catalog) -> Result<Plan> {
+    let Some(view) = catalog.view(table) else {
+        return Ok(Plan::Scan {
+            table: table.to_string(),
+        });
+    };
+    let input = plan(&view.select, catalog)?;
+    Ok(Plan::View {
+        name: view.name.clone(),
+        columns: view_columns(&view, &input)?,
+        input: Box::new(input),
+    })
+}
+
+/// The column names a view's rows are known by.
+pub fn view_columns(view: &CreateView, input: &Plan) -> Result<Vec<String>> {
+    let Plan::Project { columns, .. } = input else {
+        bail!("{} must be a SELECT", view.name);
+    };
+    if view.columns.is_empty() {
+        return Ok(columns.iter().map(|c| column_name(c)).collect());
+    }
+    if view.columns.len() != columns.len() {
+        bail!(
+            "expected {} columns for '{}' but got {}",
+            view.columns.len(),
+            view.name,
+            columns.len()
+        );
+    }
+    Ok(view.columns.clone())
+}
+
+// A view has no rows of its own to write, a virtual table's are only made to be read, and
+// sqlite_master is only written by the schema.
+pub fn check_writable(table: &str, catalog: &dyn Catalog) -> Result<()> {
+    let unqualified = table.split_once('.').map_or(table, |(_, name)| name);
+    if unqualified == MASTER_TABLE {
+        bail!("table {table} may not be modified");
+    }
+    if catalog.view(table).is_some() {
+        bail!("cannot modify {table} because it is a view");
+    }
+    if catalog.virtual_table(table).is_some() {
+        bail!("table {table} may not be modified");
+    }
+    Ok(())
+}
+
+// The rows of a table matching a WHERE clause. The table is known by `alias` in the WHERE
+// clause's subqueries if it has one.
+fn plan_rows(
+    from_table: &str,
+    alias: Option<&str>,
+    index_hint: Option<&IndexHint>,
+    where_clause: Option<&Expr>,
+    catalog: &dyn Catalog,
+) -> Result<Plan> {
+    let outer_name = alias.unwrap_or(from_table);
+    let mut filters = vec![];
+    let mut semi_joins = vec![];
+    for term in where_clause.into_iter().flat_map(conjuncts) {
+        match &term {
+            Expr::Exists { select, negated } => {
+                match semi_join(select, *negated, outer_name, catalog)? {
+                    Some(join) => semi_joins.push(join),
+                    None => filters.push(term),
+                }
+            }
+            _ => filters.push(term),
+        }
+    }
+
+    let mut plan = match catalog.virtual_table(from_table) {
+        _ if from_table.is_empty() => Plan::ConstantRow,
+        Some(table) => virtual_scan(from_table, table.as_ref(), &filters)?,
+        None => match index_search(from_table, &mut filters, index_hint, catalog)? {
+            Some(search) => search,
+            None => scan(from_table, catalog)?,
+        },
+    };
+    semi_joins.sort_by(|(a, ..), (b, ..)| {
+        estimated_rows(a, catalog).total_cmp(&estimated_rows(b, catalog))
+    });
+    // Filter first so the joins only see rows that survive the rest of the WHERE clause.
+    if let Some(predicate) = conjoin(filters) {
+        plan = Plan::Filter {
+            input: Box::new(plan),
+            predicate,
+        };
+    }
+    for (inner, keys, anti) in semi_joins {
+        let order = (sort_order(&plan, catalog), sort_order(&inner, catalog));
+        let (keys, algorithm) = match merge_keys(&order.0, &order.1, &keys) {
+            Some(keys) => (keys, JoinAlgorithm::Merge),
+            None => (keys, JoinAlgorithm::Hash),
+        };
+        plan = Plan::SemiJoin {
+            outer: Box::new(plan),
+            inner: Box::new(inner),
+            keys,
+            anti,
+            algorithm,
+        };
+    }
+    Ok(plan)
+}
+
+// A table in a SELECT's FROM clause, under the name the query knows it by.
+struct FromTable<'s> {
+    name: &'s str,
+    table: &'s str,
+    index_hint: Option<&'s IndexHint>,
+}
+
+// The rows of a FROM clause of several tables. Each table is read as plan_rows would read
+// it alone, with the terms of the WHERE and ON clauses that use none of the others', and
+// a term equating a column of one table with a column of another is a key to join the
+// two on. The tables are joined in the order next_table picks, and whatever terms are
+// left filter the joined rows.
+fn plan_join(
+    tables: &[FromTable],
+    joins: &[Join],
+    where_clause: Option<&Expr>,
+    catalog: &dyn Catalog,
+) -> Result<Plan> {
+    let names: Vec<&str> = tables.iter().map(|t| t.name).collect();
+    let terms = where_clause
+        .into_iter()
+        .chain(joins.iter().flat_map(|j| &j.on))
+        .flat_map(conjuncts);
+
+    let mut local: Vec<Vec<Expr>> = vec![vec![]; tables.len()];
+    let mut equalities = vec![];
+    let mut rest = vec![];
+    for term in terms {
+        if let Some(equality) = equality(&term, &names) {
+            equalities.push(equality);
+            continue;
+        }
+        match used_tables(&term, &names)[..] {
+            [name] => {
+                let i = names
+                    .iter()
+                    .position(|n| *n == name)
+                    .expect("a table in FROM");
+                local[i].push(local_term(&term, name));
+            }
+            _ => rest.push(term),
+        }
+    }
+
+    let mut unjoined = vec![];
+    for (from, terms) in tables.iter().zip(local) {
+        let rows = plan_rows(
+            from.table,
+            Some(from.name),
+            from.index_hint,
+            conjoin(terms).as_ref(),
+            catalog,
+        )?;
+        unjoined.push((from.name, estimated_rows(&rows, catalog), rows));
+    }
+
+    let mut joined: Vec<JoinTable> = vec![];
+    let mut order = vec![];
+    while !unjoined.is_empty() {
+        let (name, _, rows) = unjoined.remove(next_table(&unjoined, &joined, &equalities));
+        let keys: Vec<JoinKey> = equalities
+            .iter()
+            .filter_map(|[a, b]| {
+                let (outer, inner) = match (a, b) {
+                    (outer, (table, inner)) | ((table, inner), outer) if table == name => {
+                        (outer, inner)
+                    }
+                    _ => return None,
+                };
+                joined.iter().any(|t| t.name == outer.0).then(|| JoinKey {
+                    outer: format!("{}.{}", outer.0, outer.1),
+                    inner: inner.clone(),
+                })
+            })
+            .collect();
+        let inner_order = sort_order(&rows, catalog);
+        let (keys, algorithm) = match merge_keys(&order, &inner_order, &keys) {
+            Some(sorted) if !keys.is_empty() => (sorted, JoinAlgorithm::Merge),
+            _ => (keys, JoinAlgorithm::Hash),
+        };
+        // every join keeps the order of the rows before it, so the first table's is theirs
+        if joined.is_empty() {
+            order = inner_order.iter().map(|c| format!("{name}.{c}")).collect();
+        }
+        joined.push(JoinTable {
+            name: name.to_string(),
+            rows,
+            keys,
+            algorithm,
+        });
+    }
+
+    let mut plan = Plan::Join { tables: joined };
+    if let Some(predicate) = conjoin(rest) {
+        plan = Plan::Filter {
+            input: Box::new(plan),
+            predicate,
+        };
+    }
+    Ok(plan)
+}
+
+// Which of the tables not yet joined to join next, each with its estimated rows: of those
+// with a key to the tables joined so far the one with the fewest rows, or of them all if
+// none has one, the first written of any that tie.
+fn next_table(
+    unjoined: &[(&str, f64, Plan)],
+    joined: &[JoinTable],
+    equalities: &[[(String, String); 2]],
+) -> usize {
+    let is_joined = |name: &str| joined.iter().any(|t| t.name == name);
+    let keyed = |name: &str| {
+        equalities
+            .iter()
+            .any(|[a, b]| (a.0 == name && is_joined(&b.0)) || (b.0 == name && is_joined(&a.0)))
+    };
+    let fewest = |candidates: Vec<usize>| {
+        candidates
+            .into_iter()
+            .min_by(|a, b| unjoined[*a].1.total_cmp(&unjoined[*b].1))
+    };
+    let keyed: Vec<usize> = (0..unjoined.len())
+        .filter(|i| keyed(unjoined[*i].0))
+        .collect();
+    fewest(keyed)
+        .or_else(|| fewest((0..unjoined.len()).collect()))
+        .expect("a table left to join")
+}
+
+// A term `a.x = b.y` equating the columns of two different tables of a join, as the name
+// and column of each.
+fn equality(term: &Expr, names: &[&str]) -> Option<[(String, String); 2]> {
+    let Expr::Binary {
+        op: BinaryOp::Eq,
+        left,
+        right,
+    } = term
+    else {
+        return None;
+    };
+    let column = |e: &Expr| match e {
+        Expr::QualifiedColumn { table, column } if names.contains(&table.as_str()) => {
+            Some((table.clone(), column.clone()))
+        }
+        _ => None,
+    };
+    let (left, right) = (column(left)?, column(right)?);
+    (left.0 != right.0).then_some([left, right])
+}
+
+// The names of a join's tables that a term uses, in its subqueries too.
+fn used_tables<'n>(term: &Expr, names: &[&'n str]) -> Vec<&'n str> {
+    let mut used = vec![];
+    term.walk(&mut |e| {
+        if let Expr::QualifiedColumn { table, .. } = e {
+            if let Some(name) = names.iter().find(|n| **n == table) {
+                if !used.contains(name) {
+                    used.push(*name);
+                }
+            }
+        }
+    });
+    used
+}
+
+// A term that only uses the table known as `name`, as a term of that table read alone:
+// its columns are plain but for those in subqueries, which still reach out to it by name.
+fn local_term(term: &Expr, name: &str) -> Expr {
+    let mut term = term.clone();
+    let mut subqueries = vec![];
+    term.walk_mut(&mut |e| match e {
+        Expr::InSelect { select, .. } | Expr::Exists { select, .. } | Expr::Subquery(select) => {
+            subqueries.push(std::mem::replace(&mut **select, Statement::Vacuum));
+        }
+        Expr::QualifiedColumn { table, column } if table == name => {
+            *e = Expr::Column(std::mem::take(column));
+        }
+        _ => {}
+    });
+    let mut subqueries = subqueries.into_iter();
+    term.walk_mut(&mut |e| {
+        if let Expr::InSelect { select, .. }
+        | Expr::Exists { select, .. }
+        | Expr::Subquery(select) = e
+        {
+            if **select == Statement::Vacuum {
+                **select = subqueries.next().expect("a subquery taken out");
+            }
+        }
+    });
+    term
+}
+
+// A scan of a virtual table, which chooses which of the terms comparing one of its
+// columns with a constant it is handed the values of. They all stay in `filters` too.
+fn virtual_scan(name: &str, table: &dyn VirtualTable, filters: &[Expr]) -> Result<Plan> {
+    let columns = vtab::all_columns(table);
+    let (constraints, values): (Vec<Constraint>, Vec<&Expr>) = filters
+        .iter()
+        .filter_map(|term| virtual_constraint(term, &columns))
+        .unzip();
+    let choice = table.best_index(&constraints);
+    let values = choice
+        .used
+        .iter()
+        .map(|&i| {
+            let value = values
+                .get(i)
+                .ok_or_else(|| anyhow!("{name} chose constraint {i} of {}", constraints.len()))?;
+            Ok((*value).clone())
+        })
+        .collect::<Result<_>>()?;
+    Ok(Plan::VirtualScan {
+        table: name.to_string(),
+        number: choice.number,
+        values,
+    })
+}
+
+// A term comparing a column of a virtual table with an expression of no columns, `a > 1`
+// or `1 < a`, and that expression.
+fn virtual_constraint<'e>(term: &'e Expr, columns: &[String]) -> Option<(Constraint, &'e Expr)> {
+    let Expr::Binary { op, left, right } = term else {
+        return None;
+    };
+    let (column, value, flipped) = match (left.as_ref(), right.as_ref()) {
+        (Expr::Column(c), value) => (c, value, false),
+        (value, Expr::Column(c)) => (c, value, true),
+        _ => return None,
+    };
+    let mut constant = true;
+    value.walk(&mut |e| {
+        if matches!(
+            e,
+            Expr::Column(_)
+                | Expr::QualifiedColumn { .. }
+                | Expr::Row(_)
+                | Expr::InSelect { .. }
+                | Expr::Exists { .. }
+                | Expr::Subquery(_)
+        ) {
+            constant = false;
+        }
+    });
+    // with the column on the right, `1 < a` is `a > 1`
+    let op = match (op, flipped) {
+        (BinaryOp::Eq, _) => ConstraintOp::Eq,
+        (BinaryOp::Lt, false) | (BinaryOp::Gt, true) => ConstraintOp::Lt,
+        (BinaryOp::LtEq, false) | (BinaryOp::GtEq, true) => ConstraintOp::Le,
+        (BinaryOp::Gt, false) | (BinaryOp::Lt, true) => ConstraintOp::Gt,
+        (BinaryOp::GtEq, false) | (BinaryOp::LtEq, true) => ConstraintOp::Ge,
+        _ => return None,
+    };
+    let column = columns.iter().position(|c| c == column)?;
+    constant.then_some((Constraint { column, op }, value))
+}
+
+// The columns a plan's rows come out sorted by. Filters and semi-joins keep the order of
+// their input, and a join that of its first table, anything else is taken to lose it.
+fn sort_order(plan: &Plan, catalog: &dyn Catalog) -> Vec<String> {
+    match plan {
+        Plan::Scan { table } => catalog.scan_order(table),
+        Plan::Filter { input, .. } | Plan::SemiJoin { outer: input, .. } => {
+            sort_order(input, catalog)
+        }
+        Plan::Join { tables } => match tables.first() {
+            Some(first) => sort_order(&first.rows, catalog)
+                .into_iter()
+                .map(|c| format!("{}.{c}", first.name))
+                .collect(),
+            None => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+// The keys of a join in the order both its inputs are sorted by, if they are, so that it
+// can be a merge join.
+fn merge_keys(
+    outer_order: &[String],
+    inner_order: &[String],
+    keys: &[JoinKey],
+) -> Option<Vec<JoinKey>> {
+    if outer_order.len() < keys.len() || inner_order.len() < keys.len() {
+        return None;
+    }
+    let sorted: Vec<JoinKey> = outer_order
+        .iter()
+        .zip(inner_order)
+        .take(keys.len())
+        .map(|(o, i)| {
+            keys.iter()
+                .find(|k| k.outer == *o && k.inner == *i)
+                .cloned()
+        })
+        .collect::<Option<_>>()?;
+    // every key must be among them, not one key twice
+    keys.iter().all(|k| sorted.contains(k)).then_some(sorted)
+}
+
+// A WHERE clause term that pins some columns to one of a list of constant values.
+struct KeyConstraint {
+    term: usize,
+    columns: Vec<String>,
+    values: Vec<Vec<ColVal>>,
+}
+
+// Terms that an index seek can answer: `a = 1`, `a IN (1, 2)`, `(a, b) = (1, 2)` and
+// `(a, b) IN ((1, 2), (3, 4))`. Comparisons with NULL never match so NULLs are dropped.
+fn key_constraint(term: usize, expr: &Expr) -> Option<KeyConstraint> {
+    let columns_of = |e: &Expr| -> Option<Vec<String>> {
+        match e {
+            Expr::Column(c) => Some(vec![c.clone()]),
+            Expr::Row(exprs) => exprs
+                .iter()
+                .map(|e| match e {
+                    Expr::Column(c) => Some(c.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    };
+    let values_of = |e: &Expr| -> Option<Vec<ColVal>> {
+        match e {
+            Expr::Literal(v) => Some(vec![v.clone()]),
+            Expr::Row(exprs) => exprs
+                .iter()
+                .map(|e| match e {
+                    Expr::Literal(v) => Some(v.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => None,
+        }
+    };
+
+    let (columns, values) = match expr {
+        Expr::Binary {
+            op: BinaryOp::Eq,
+            left,
+            right,
+        } => match (columns_of(left), values_of(right)) {
+            (Some(c), Some(v)) => (c, vec![v]),
+            _ => (columns_of(right)?, vec![values_of(left)?]),
+        },
+        Expr::InList {
+            expr,
+            list,
+            negated: false,
+        } => (
+            columns_of(expr)?,
+            list.iter().map(values_of).collect::<Option<Vec<_>>>()?,
+        ),
+        _ => return None,
+    };
+    if values.iter().any(|v| v.len() != columns.len()) {
+        return None;
+    }
+    let mut values: Vec<Vec<ColVal>> = values
+        .into_iter()
+        .filter(|v| !v.contains(&ColVal::Null))
+        .collect();
+    values.sort();
+    values.dedup();
+    Some(KeyConstraint {
+        term,
+        columns,
+        values,
+    })
+}
+
+// A WHERE clause term bounding a column from one side: `a > 1`, `a <= 2` or `3 < a`.
+struct RangeConstraint {
+    term: usize,
+    column: String,
+    lower: bool,
+    bound: Bound<ColVal>,
+}
+
+fn range_constraint(term: usize, expr: &Expr) -> Option<RangeConstraint> {
+    let Expr::Binary { op, left, right } = expr else {
+        return None;
+    };
+    // with the column on the right, `3 < a` is `a > 3`
+    let (column, value, op) = match (left.as_ref(), right.as_ref()) {
+        (Expr::Column(c), Expr::Literal(v)) => (c, v, *op),
+        (Expr::Literal(v), Expr::Column(c)) => (
+            c,
+            v,
+            match op {
+                BinaryOp::Lt => BinaryOp::Gt,
+                BinaryOp::LtEq => BinaryOp::GtEq,
+                BinaryOp::Gt => BinaryOp::Lt,
+                BinaryOp::GtEq => BinaryOp::LtEq,
+                _ => return None,
+            },
+        ),
+        _ => return None,
+    };
+    // a comparison with NULL matches nothing, left to the filter to find out
+    if *value == ColVal::Null {
+        return None;
+    }
+    let (lower, bound) = match op {
+        BinaryOp::Gt => (true, Bound::Excluded(value.clone())),
+        BinaryOp::GtEq => (true, Bound::Included(value.clone())),
+        BinaryOp::Lt => (false, Bound::Excluded(value.clone())),
+        BinaryOp::LtEq => (false, Bound::Included(value.clone())),
+        _ => return None,
+    };
+    Some(RangeConstraint {
+        term,
+        column: column.clone(),
+        lower,
+        bound,
+    })
+}
+
+// Pick the index search that costs the least, unless scanning the table costs less still,
+// and take the terms it answers out of `filters`.
+fn index_search(
+    table: &str,
+    filters: &mut Vec<Expr>,
+    hint: Option<&IndexHint>,
+    catalog: &dyn Catalog,
+) -> Result<Option<Plan>> {
+    let mut indexes = catalog.table_indexes(table);
+    match hint {
+        Some(IndexHint::NotIndexed) => return Ok(None),
+        Some(IndexHint::IndexedBy(name)) => {
+            indexes.retain(|i| i.name == *name);
+            if indexes.is_empty() {
+                bail!(SqlError::NoSuchIndex {
+                    index: name.to_string()
+                });
+            }
+        }
+        None => {}
+    }
+    let mut constraints: Vec<KeyConstraint> = filters
+        .iter()
+        .enumerate()
+        .filter_map(|(i, term)| key_constraint(i, term))
+        .collect();
+    let mut ranges: Vec<RangeConstraint> = filters
+        .iter()
+        .enumerate()
+        .filter_map(|(i, term)| range_constraint(i, term))
+        .collect();
+    // the values are literals, which have no affinity, so are compared with the column's
+    let affinity =
+        |column: &str| eval::comparison_affinity(catalog.column_affinity(table, column), None);
+    for constraint in &mut constraints {
+        let affinities: Vec<_> = constraint.columns.iter().map(|c| affinity(c)).collect();
+        for key in &mut constraint.values {
+            for (value, affinity) in key.iter_mut().zip(&affinities) {
+                if let Some(affinity) = affinity {
+                    *value = affinity.apply(std::mem::replace(value, ColVal::Null));
+                }
+            }
+        }
+        // `a IN (1, '1')` is one key once both are numbers
+        constraint.values.sort();
+        constraint.values.dedup();
+    }
+    for range in &mut ranges {
+        if let Some(affinity) = affinity(&range.column) {
+            range.bound = match std::mem::replace(&mut range.bound, Bound::Unbounded) {
+                Bound::Included(v) => Bound::Included(affinity.apply(v)),
+                Bound::Excluded(v) => Bound::Excluded(affinity.apply(v)),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+        }
+    }
+
+    let stats = catalog.table_stats(table);
+    let rows = table_rows(stats.as_ref());
+    let mut best: Option<(f64, Plan, Vec<usize>)> = None;
+    for index in indexes {
+        let index_columns: Vec<&str> = index
+            .columns
+            .iter()
+            .map_while(|c| match c {
+                Expr::Column(c) => Some(c.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        let mut columns: Vec<String> = vec![];
+        let mut seeks: Vec<Vec<ColVal>> = vec![vec![]];
+        let mut used = vec![];
+        while let Some(constraint) = constraints.iter().find(|c| {
+            index_columns[columns.len()..]
+                .starts_with(&c.columns.iter().map(String::as_str).collect::<Vec<_>>())
+        }) {
+            columns.extend(constraint.columns.iter().cloned());
+            seeks = seeks
+                .iter()
+                .flat_map(|prefix| {
+                    constraint.values.iter().map(move |v| {
+                        let mut key = prefix.clone();
+                        key.extend(v.iter().cloned());
+                        key
+                    })
+                })
+                .collect();
+            used.push(constraint.term);
+        }
+        // the column after those the seeks pin can be bounded from below and above
+        let mut range = None;
+        if let Some(column) = index_columns.get(columns.len()) {
+            let bound = |lower: bool| {
+                ranges
+                    .iter()
+                    .find(|r| r.column == *column && r.lower == lower)
+            };
+            let (lower, upper) = (bound(true), bound(false));
+            if lower.is_some() || upper.is_some() {
+                used.extend(lower.iter().chain(&upper).map(|r| r.term));
+                let bound =
+                    |r: Option<&RangeConstraint>| r.map_or(Bound::Unbounded, |r| r.bound.clone());
+                range = Some(KeyRange {
+                    column: column.to_string(),
+                    lower: bound(lower),
+                    upper: bound(upper),
+                });
+            }
+        }
+        if columns.is_empty() && range.is_none() {
+            continue;
+        }
+
+        let per_key = rows_in_range(&index, columns.len(), range.as_ref(), stats.as_ref());
+        let cost = seeks.len() as f64 * (rows.max(2.0).log2() + per_key);
+        // an index named by INDEXED BY is used whatever it costs
+        if (cost < rows || hint.is_some())
+            && best
+                .as_ref()
+                .map_or(true, |(best_cost, ..)| cost < *best_cost)
+        {
+            let search = Plan::IndexSearch {
+                table: table.to_string(),
+                index: index.name,
+                columns,
+                seeks,
+                range,
+            };
+            best = Some((cost, search, used));
+        }
+    }
+
+    let Some((_, search, used)) = best else {
+        if hint.is_some() {
+            // the index named can't be searched with this WHERE clause
+            bail!("no query solution");
+        }
+        return Ok(None);
+    };
+    let mut i = 0;
+    filters.retain(|_| {
+        i += 1;
+        !used.contains(&(i - 1))
+    });
+    Ok(Some(search))
+}
+
+fn table_rows(stats: Option<&TableStats>) -> f64 {
+    stats.map_or(DEFAULT_ROWS, |s| s.rows) as f64
+}
+
+// How many rows share a key of the first `columns` columns of an index.
+fn rows_per_key(index: &CreateIndex, columns: usize, stats: Option<&TableStats>) -> f64 {
+    let measured = stats
+        .and_then(|s| s.rows_per_key.get(&index.name))
+        .and_then(|per_key| per_key.get(columns - 1));
+    if let Some(n) = measured {
+        return *n as f64;
+    }
+    if index.unique && columns == index.columns.len() {
+        return 1.0;
+    }
+    DEFAULT_ROWS_PER_KEY[(columns - 1).min(DEFAULT_ROWS_PER_KEY.len() - 1)] as f64
+}
+
+// How many rows one seek of an index finds, with a key for its first `columns` columns
+// and the next column bounded by `range`. A range of the first column is counted from the
+// index's samples if ANALYZE took any, otherwise each bound is guessed to keep a quarter.
+fn rows_in_range(
+    index: &CreateIndex,
+    columns: usize,
+    range: Option<&KeyRange>,
+    stats: Option<&TableStats>,
+) -> f64 {
+    let rows = table_rows(stats);
+    let samples = stats.and_then(|s| s.samples.get(&index.name));
+    if let (0, Some(range), Some(samples)) = (columns, range, samples) {
+        return sampled_rows(samples, range, rows);
+    }
+    let per_key = match columns {
+        0 => rows,
+        _ => rows_per_key(index, columns, stats).min(rows),
+    };
+    per_key * TERM_SELECTIVITY.powi(range.map_or(0, KeyRange::bounds) as i32)
+}
+
+// How many of an index's `rows` are within `range` of its first column, going by samples
+// of it. The rows up to a sample are known exactly, those between two samples are not, so
+// a bound that falls between samples counts from the sample below it.
+fn sampled_rows(samples: &[Sample], range: &KeyRange, rows: f64) -> f64 {
+    // rows with a value below `value`, or also equal to it if `equal`
+    let below = |value: &ColVal, equal: bool| {
+        let mut below = 0;
+        for sample in samples {
+            match sample.value.cmp(value) {
+                Ordering::Less => below = sample.lt + sample.eq,
+                Ordering::Equal if equal => return (sample.lt + sample.eq) as f64,
+                Ordering::Equal => return sample.lt as f64,
+                Ordering::Greater => break,
+            }
+        }
+        below as f64
+    };
+    let from = match &range.lower {
+        Bound::Included(v) => below(v, false),
+        Bound::Excluded(v) => below(v, true),
+        // a range never includes NULL, the smallest value
+        Bound::Unbounded => below(&ColVal::Null, true),
+    };
+    let to = match &range.upper {
+        Bound::Included(v) => below(v, true),
+        Bound::Excluded(v) => below(v, false),
+        Bound::Unbounded => rows,
+    };
+    (to - from).max(1.0)
+}
+
+// Roughly how many rows a plan produces.
+fn estimated_rows(plan: &Plan, catalog: &dyn Catalog) -> f64 {
+    match plan {
+        Plan::Scan { table } => table_rows(catalog.table_stats(table).as_ref()),
+        Plan::IndexSearch {
+            table,
+            index,
+            columns,
+            seeks,
+            range,
+        } => {
+            let stats = catalog.table_stats(table);
+            let per_key = catalog
+                .table_indexes(table)
+                .iter()
+                .find(|i| i.name == *index)
+                .map_or(1.0, |i| {
+                    rows_in_range(i, columns.len(), range.as_ref(), stats.as_ref())
+                });
+            seeks.len() as f64 * per_key
+        }
+        Plan::Filter { input, predicate } => {
+            let terms = conjuncts(predicate).len() as i32;
+            estimated_rows(input, catalog) * TERM_SELECTIVITY.powi(terms)
+        }
+        Plan::SemiJoin { outer, .. } => estimated_rows(outer, catalog) * TERM_SELECTIVITY,
+        // each key is taken to match as many rows as a term keeps
+        Plan::Join { tables } => tables.iter().fold(1.0, |rows, table| {
+            rows * estimated_rows(&table.rows, catalog)
+                * TERM_SELECTIVITY.powi(table.keys.len() as i32)
+        }),
+        Plan::Aggregate {
+            input, group_by, ..
+        } if !group_by.is_empty() => estimated_rows(input, catalog),
+        Plan::Aggregate { .. } | Plan::ConstantRow => 1.0,
+        Plan::Compound { left, right, .. } => {
+            estimated_rows(left, catalog) + estimated_rows(right, catalog)
+        }
+        Plan::Project { input, .. }
+        | Plan::Window { input, .. }
+        | Plan::Sort { input, .. }
+        | Plan::Limit { input, .. }
+        | Plan::View { input, .. }
+        | Plan::Cte { input, .. } => estimated_rows(input, catalog),
+        _ => 0.0,
+    }
+}
+
+// Push the terms of filters over views and CTEs down into them, see the module comment.
+fn push_down(plan: &mut Plan, catalog: &dyn Catalog) {
+    for input in plan.inputs_mut() {
+        push_down(input, catalog);
+    }
+    let Plan::Filter { input, predicate } = plan else {
+        return;
+    };
+    let (Plan::View {
+        columns: names,
+        input: select,
+        ..
+    }
+    | Plan::Cte {
+        columns: names,
+        input: select,
+        ..
+    }) = input.as_mut()
+    else {
+        return;
+    };
+    let Plan::Project {
+        input: rows,
+        columns,
+    } = select.as_mut()
+    else {
+        return;
+    };
+    // the view's rows must be its table's, filtered but not folded or joined
+    let (table, existing) = match rows.as_ref() {
+        Plan::Scan { table } => (table.clone(), vec![]),
+        Plan::Filter { input, predicate } => match input.as_ref() {
+            Plan::Scan { table } => (table.clone(), conjuncts(predicate)),
+            _ => return,
+        },
+        _ => return,
+    };
+    let Ok(table_columns) = catalog.table_columns(&table) else {
+        return;
+    };
+    let renames: HashMap<&str, &str> = names
+        .iter()
+        .zip(columns.iter())
+        .filter(|(_, column)| table_columns.contains(column))
+        .map(|(name, column)| (name.as_str(), column.as_str()))
+        .collect();
+
+    let mut kept = vec![];
+    let mut pushed = vec![];
+    for term in conjuncts(predicate) {
+        match renamed(&term, &renames) {
+            Some(term) => pushed.push(term),
+            None => kept.push(term),
+        }
+    }
+    if pushed.is_empty() {
+        return;
+    }
+
+    let mut filters = existing;
+    filters.extend(pushed);
+    let mut new_rows = match index_search(&table, &mut filters, None, catalog)
+        .expect("only a hint makes an index search fail")
+    {
+        Some(search) => search,
+        None => Plan::Scan { table },
+    };
+    if let Some(predicate) = conjoin(filters) {
+        new_rows = Plan::Filter {
+            input: Box::new(new_rows),
+            predicate,
+        };
+    }
+    **rows = new_rows;
+    match conjoin(kept) {
+        Some(rest) => *predicate = rest,
+        None => *plan = std::mem::replace(input.as_mut(), Plan::Commit),
+    }
+}
+
+// A term over a view's columns as a term over its table's, None if it uses anything but
+// columns passed through unchanged. Subqueries could refer to the view by name, so they
+// stay where they are.
+fn renamed(term: &Expr, renames: &HashMap<&str, &str>) -> Option<Expr> {
+    let mut movable = true;
+    term.walk(&mut |e| match e {
+        Expr::Column(c) if !renames.contains_key(c.as_str()) => movable = false,
+        Expr::QualifiedColumn { .. }
+        | Expr::InSelect { .. }
+        | Expr::Exists { .. }
+        | Expr::Subquery(_) => movable = false,
+        _ => {}
+    });
+    if !movable {
+        return None;
+    }
+    let mut term = term.clone();
+    term.walk_mut(&mut |e| {
+        if let Expr::Column(c) = e {
+            *c = renames[c.as_str()].to_string();
+        }
+    });
+    Some(term)
+}
+
+// Wrap a write in the triggers that fire for it, if there are any.
+fn with_triggers(plan: Plan, table: &str, write: &Statement, catalog: &dyn Catalog) -> Plan {
+    let triggers: Vec<CreateTrigger> = catalog
+        .table_triggers(table)
+        .into_iter()
+        .filter(|t| trigger::fires_for(t, write))
+        .collect();
+    if triggers.is_empty() {
+        return plan;
+    }
+    Plan::Triggers {
+        input: Box::new(plan),
+        triggers,
+    }
+}
+
+// `SELECT ... FROM (SELECT ...) AS name` as `WITH name AS (SELECT ...) SELECT ... FROM name`,
+// the subquery read like any other CTE.
+fn from_select_as_cte(select: &Statement, name: &str, from_select: &Statement) -> Statement {
+    let mut body = select.clone();
+    if let Statement::Select { from_select, .. } = &mut body {
+        *from_select = None;
+    }
+    Statement::With {
+        ctes: vec![CommonTableExpr {
+            name: name.to_string(),
+            columns: vec![],
+            select: Box::new(from_select.clone()),
+        }],
+        body: Box::new(body),
+    }
+}
+
+/// The names of the result columns of a query, for a subquery in FROM to be read by.
+pub fn query_columns(select: &Statement, catalog: &dyn Catalog) -> Result<Vec<String>> {
+    Ok(result_columns(&plan(select, catalog)?))
+}
+
+// The names of the columns of a query's rows.
+fn result_columns(plan: &Plan) -> Vec<String> {
+    match plan {
+        Plan::Project { columns, .. } => columns.iter().map(|c| column_name(c)).collect(),
+        Plan::Compound { left, .. } => result_columns(left),
+        Plan::Sort { input, .. } | Plan::Limit { input, .. } => result_columns(input),
+        _ => vec![],
+    }
+}
+
+// A compound SELECT's ORDER BY, whose terms can only be its result columns, by name or by
+// number from 1 as in `ORDER BY 2`, each made into its column's name.
+fn compound_order_by(order_by: &[OrderingTerm], columns: &[String]) -> Result<Vec<OrderingTerm>> {
+    let mut terms = vec![];
+    for (i, term) in order_by.iter().enumerate() {
+        let mut term = term.clone();
+        let column = match &mut term.expr {
+            Expr::Collate { expr, .. } => expr.as_mut(),
+            expr => expr,
+        };
+        match column {
+            Expr::Literal(ColVal::Int(n)) => match usize::try_from(*n) {
+                Ok(n) if (1..=columns.len()).contains(&n) => {
+                    *column = Expr::Column(columns[n - 1].clone())
+                }
+                _ => bail!(
+                    "{} ORDER BY term out of range - should be between 1 and {}",
+                    ordinal(i + 1),
+                    columns.len()
+                ),
+            },
+            Expr::Column(name) if columns.contains(name) => {}
+            _ => bail!(
+                "{} ORDER BY term does not match any column in the result set",
+                ordinal(i + 1)
+            ),
+        }
+        terms.push(term);
+    }
+    Ok(terms)
+}
+
+// The terms of a SELECT's GROUP BY or ORDER BY, where a number from 1 as in `ORDER BY 2`
+// stands for that result column.
+fn numbered_terms(terms: &[Expr], columns: &[String], clause: &str) -> Result<Vec<Expr>> {
+    let mut numbered = vec![];
+    for (i, term) in terms.iter().enumerate() {
+        let mut term = term.clone();
+        let column = match &mut term {
+            Expr::Collate { expr, .. } => expr.as_mut(),
+            expr => expr,
+        };
+        if let Expr::Literal(ColVal::Int(n)) = column {
+            let n = match usize::try_from(*n) {
+                Ok(n) if (1..=columns.len()).contains(&n) => n,
+                _ => bail!(
+                    "{} {clause} term out of range - should be between 1 and {}",
+                    ordinal(i + 1),
+                    columns.len()
+                ),
+            };
+            // an aggregate's text is no expression, but it names a column of the folded rows
+            *column = parse_expr(&columns[n - 1])
+                .unwrap_or_else(|_| Expr::Column(columns[n - 1].clone()));
+        }
+        numbered.push(term);
+    }
+    Ok(numbered)
+}
+
+// An ORDER BY term over the rows of a GROUP BY, aggregates or window functions, whose
+// groups and function values are columns named as written.
+fn over_folded(expr: &mut Expr, folded: &[String]) {
+    expr.walk_mut(&mut |e| {
+        let text = e.to_string();
+        let text = parse_aggregate(&text).map_or(text, |a| a.to_string());
+        if folded.contains(&text) {
+            *e = Expr::Column(text);
+        }
+    });
+}
+
+// 1st, 2nd, 3rd, 4th and so on, as SQLite numbers the terms of an ORDER BY in its errors.
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 100, n % 10) {
+        (11..=13, _) => "th",
+        (_, 1) => "st",
+        (_, 2) => "nd",
+        (_, 3) => "rd",
+        _ => "th",
+    };
+    format!("{n}{suffix}")
+}
+
+fn sort_and_limit(mut plan: Plan, order_by: &[OrderingTerm], limit: &Option<Limit>) -> Plan {
+    if !order_by.is_empty() {
+        plan = Plan::Sort {
+            input: Box::new(plan),
+            order_by: order_by.to_vec(),
+        };
+    }
+    if let Some(limit) = limit {
+        plan = Plan::Limit {
+            input: Box::new(plan),
+            limit: limit.clone(),
+        };
+    }
+    plan
+}
+
+/// The plan with each uncorrelated scalar subquery of its filters, a `(SELECT ...)` that
+/// refers to nothing of the rows being filtered, replaced by its `value`, so that it is
+/// worked out once rather than for every row. None if there are none.
+pub fn fold_uncorrelated(
+    plan: &Plan,
+    value: &mut impl FnMut(&Statement) -> Result<ColVal>,
+) -> Result<Option<Plan>> {
+    fn fold(plan: &mut Plan, value: &mut impl FnMut(&Statement) -> Result<ColVal>) -> Result<()> {
+        if let Plan::Filter { predicate, .. } = plan {
+            let mut result = Ok(());
+            predicate.walk_mut(&mut |e| {
+                if result.is_err() || !is_uncorrelated_subquery(e) {
+                    return;
+                }
+                let Expr::Subquery(select) = e else {
+                    unreachable!("a subquery");
+                };
+                match value(select) {
+                    Ok(v) => *e = Expr::Literal(v),
+                    Err(err) => result = Err(err),
+                }
+            });
+            result?;
+        }
+        for input in plan.inputs_mut() {
+            fold(input, value)?;
+        }
+        Ok(())
+    }
+
+    if !has_uncorrelated(plan) {
+        return Ok(None);
+    }
+    let mut plan = plan.clone();
+    fold(&mut plan, value)?;
+    Ok(Some(plan))
+}
+
+/// Whether a query's rows come in the order its ORDER BY asked for, rather than whichever
+/// order the tables were read in.
+pub fn is_ordered(plan: &Plan) -> bool {
+    match plan {
+        Plan::Sort { .. } => true,
+        Plan::Limit { input, .. } | Plan::Project { input, .. } => is_ordered(input),
+        _ => false,
+    }
+}
+
+/// Whether the plan has an uncorrelated scalar subquery that fold_uncorrelated would fold.
+pub fn has_uncorrelated(plan: &Plan) -> bool {
+    let mut here = false;
+    if let Plan::Filter { predicate, .. } = plan {
+        predicate.walk(&mut |e| here |= is_uncorrelated_subquery(e));
+    }
+    here || plan.inputs().into_iter().any(has_uncorrelated)
+}
+
+// Names have been resolved, so a subquery refers to an outer query's row only through a
+// qualified column.
+fn is_uncorrelated_subquery(e: &Expr) -> bool {
+    let Expr::Subquery(select) = e else {
+        return false;
+    };
+    let mut correlated = false;
+    select.walk_exprs(&mut |e| correlated |= matches!(e, Expr::QualifiedColumn { .. }));
+    !correlated
+}
+
+// Plan an EXISTS subquery as the inner side of a semi-join, if it only refers to the outer
+// query through `inner_column = outer_column` terms of its WHERE clause. Names have been
+// resolved, so the subquery's own columns are plain and the outer query's are qualified
+// with `outer_name`.
+fn semi_join(
+    select: &Statement,
+    anti: bool,
+    outer_name: &str,
+    catalog: &dyn Catalog,
+) -> Result<Option<(Plan, Vec<JoinKey>, bool)>> {
+    let Statement::Select {
+        from_table,
+        from_select: None,
+        index_hint,
+        where_clause,
+        group_by,
+        limit,
+        ..
+    } = select
+    else {
+        return Ok(None);
+    };
+    // a subquery whose rows are folded or cut short is run as written
+    if !group_by.is_empty() || limit.is_some() {
+        return Ok(None);
+    }
+    // the join scans the inner table, so a subquery that must use an index is run as written
+    if let Some(IndexHint::IndexedBy(_)) = index_hint {
+        return Ok(None);
+    }
+    let outer_column = |e: &Expr| match e {
+        Expr::QualifiedColumn { table, column } if table == outer_name => Some(column.clone()),
+        _ => None,
+    };
+
+    let mut keys = vec![];
+    let mut filters = vec![];
+    for term in where_clause.iter().flat_map(conjuncts) {
+        if let Expr::Binary {
+            op: BinaryOp::Eq,
+            left,
+            right,
+        } = &term
+        {
+            if let (Expr::Column(inner), other) | (other, Expr::Column(inner)) = (&**left, &**right)
+            {
+                if let Some(outer) = outer_column(other) {
+                    keys.push(JoinKey {
+                        outer,
+                        inner: inner.clone(),
+                    });
+                    continue;
+                }
+            }
+        }
+
+        // anything else mentioning an outer column has to be evaluated row by row
+        let mut local = true;
+        term.walk(&mut |e| {
+            if let Expr::QualifiedColumn { .. } = e {
+                local = false;
+            }
+        });
+        if !local {
+            return Ok(None);
+        }
+        filters.push(term);
+    }
+
+    // Uncorrelated subqueries have the same answer for every row, no join needed.
+    if keys.is_empty() {
+        return Ok(None);
+    }
+
+    let mut inner = scan(from_table, catalog)?;
+    if let Some(predicate) = conjoin(filters) {
+        inner = Plan::Filter {
+            input: Box::new(inner),
+            predicate,
+        };
+    }
+    Ok(Some((inner, keys, anti)))
+}
+
+// The tables defined by a WITH clause, in front of the catalog. A CTE hides any real table
+// with the same name.
+struct WithScope<'c> {
+    outer: &'c dyn Catalog,
+    ctes: Vec<(CommonTableExpr, Plan)>,
+}
+
+impl Catalog for WithScope<'_> {
+    fn table_columns(&self, table: &str) -> Result<Vec<String>> {
+        match self.ctes.iter().find(|(cte, _)| cte.name == table) {
+            Some((_, Plan::Cte { columns, .. })) => Ok(columns.clone()),
+            _ => self.outer.table_columns(table),
+        }
+    }
+
+    fn table_indexes(&self, table: &str) -> Vec<CreateIndex> {
+        if self.ctes.iter().any(|(cte, _)| cte.name == table) {
+            return vec![];
+        }
+        self.outer.table_indexes(table)
+    }
+
+    fn view(&self, name: &str) -> Option<CreateView> {
+        if self.ctes.iter().any(|(cte, _)| cte.name == name) {
+            return None;
+        }
+        self.outer.view(name)
+    }
+
+    fn table_stats(&self, table: &str) -> Option<TableStats> {
+        if self.ctes.iter().any(|(cte, _)| cte.name == table) {
+            return None;
+        }
+        self.outer.table_stats(table)
+    }
+
+    fn table_triggers(&self, table: &str) -> Vec<CreateTrigger> {
+        if self.ctes.iter().any(|(cte, _)| cte.name == table) {
+            return vec![];
+        }
+        self.outer.table_triggers(table)
+    }
+
+    fn scan_order(&self, table: &str) -> Vec<String> {
+        if self.ctes.iter().any(|(cte, _)| cte.name == table) {
+            return vec![];
+        }
+        self.outer.scan_order(table)
+    }
+
+    fn has_rowid(&self, table: &str) -> bool {
+        !self.ctes.iter().any(|(cte, _)| cte.name == table) && self.outer.has_rowid(table)
+    }
+
+    fn virtual_table(&self, name: &str) -> Option<Arc<dyn VirtualTable>> {
+        if self.ctes.iter().any(|(cte, _)| cte.name == name) {
+            return None;
+        }
+        self.outer.virtual_table(name)
+    }
+
+    fn temp_table(&self, name: &str) -> Option<String> {
+        if self.ctes.iter().any(|(cte, _)| cte.name == name) {
+            return None;
+        }
+        self.outer.temp_table(name)
+    }
+
+    fn column_affinity(&self, table: &str, column: &str) -> Option<Affinity> {
+        if self.ctes.iter().any(|(cte, _)| cte.name == table) {
+            return None;
+        }
+        self.outer.column_affinity(table, column)
+    }
+}
+
+impl WithScope<'_> {
+    // Replace scans of CTEs with the CTE's plan. Subqueries left in expressions are run
+    // on their own later, so those that read a CTE get the WITH clause attached.
+    fn inline(&self, plan: &mut Plan) {
+        if let Plan::Scan { table } = plan {
+            if let Some((_, cte)) = self.ctes.iter().find(|(cte, _)| cte.name == *table) {
+                *plan = cte.clone();
+            }
+            return;
+        }
+        if let Plan::Filter { predicate, .. } = plan {
+            predicate.walk_mut(&mut |e| {
+                if let Expr::InSelect { select, .. }
+                | Expr::Exists { select, .. }
+                | Expr::Subquery(select) = e
+                {
+                    let ctes: Vec<CommonTableExpr> = self
+                        .ctes
+                        .iter()
+                        .map(|(cte, _)| cte.clone())
+                        .filter(|cte| select.tables().contains(&cte.name.as_str()))
+                        .collect();
+                    if !ctes.is_empty() && !matches!(**select, Statement::With { .. }) {
+                        **select = Statement::With {
+                            ctes,
+                            body: select.clone(),
+                        };
+                    }
+                }
+            });
+        }
+        for input in plan.inputs_mut() {
+            self.inline(input);
+        }
+    }
+}
+
+// The column names a CTE's rows are known by.
+fn cte_columns(cte: &CommonTableExpr, input: &Plan) -> Result<Vec<String>> {
+    let columns = result_columns(input);
+    if columns.is_empty() {
+        bail!("{} must be a SELECT", cte.name);
+    }
+    if cte.columns.is_empty() {
+        return Ok(columns);
+    }
+    if cte.columns.len() != columns.len() {
+        bail!(
+            "table {} has {} values for {} columns",
+            cte.name,
+            columns.len(),
+            cte.columns.len()
+        );
+    }
+    Ok(cte.columns.clone())
+}
+
+// Split `a AND b AND c` into [a, b, c].
+fn conjuncts(expr: &Expr) -> Vec<Expr> {
+    match expr {
+        Expr::Binary {
+            op: BinaryOp::And,
+            left,
+            right,
+        } => {
+            let mut terms = conjuncts(left);
+            terms.extend(conjuncts(right));
+            terms
+        }
+        other => vec![other.clone()],
+    }
+}
+
+/// The terms joined with AND, None if there are none.
+pub fn conjoin(terms: Vec<Expr>) -> Option<Expr> {
+    terms.into_iter().reduce(|left, right| Expr::Binary {
+        op: BinaryOp::And,
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
+// What an index search looks up, as SQLite shows it: `user_id=? AND total>?`.
+fn search_key(columns: &[String], range: Option<&KeyRange>) -> String {
+    let mut key: Vec<String> = columns.iter().map(|c| format!("{c}=?")).collect();
+    if let Some(range) = range {
+        match range.lower {
+            Bound::Included(_) => key.push(format!("{}>=?", range.column)),
+            Bound::Excluded(_) => key.push(format!("{}>?", range.column)),
+            Bound::Unbounded => {}
+        }
+        match range.upper {
+            Bound::Included(_) => key.push(format!("{}<=?", range.column)),
+            Bound::Excluded(_) => key.push(format!("{}<?", range.column)),
+            Bound::Unbounded => {}
+        }
+    }
+    key.join(" AND ")
+}
+
+impl Plan {
+    // One line describing this operator, without its inputs.
+    fn label(&self) -> String {
+        match self {
+            Plan::Scan { table } => format!("SCAN {table}"),
+            Plan::ConstantRow => "SCAN CONSTANT ROW".to_string(),
+            Plan::VirtualScan {
+                table,
+                number,
+                values,
+            } => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                format!(
+                    "SCAN {table} VIRTUAL TABLE INDEX {number}: ({})",
+                    values.join(", ")
+                )
+            }
+            Plan::IndexSearch {
+                table,
+                index,
+                columns,
+                seeks,
+                range,
+            } => {
+                let mut label = format!(
+                    "SEARCH {table} USING INDEX {index} ({})",
+                    search_key(columns, range.as_ref())
+                );
+                if !columns.is_empty() {
+                    let seeks: Vec<String> = seeks
+                        .iter()
+                        .map(|seek| {
+                            let values: Vec<String> = seek.iter().map(|v| v.to_string()).collect();
+                            format!("({})", values.join(", "))
+                        })
+                        .collect();
+                    label.push_str(&format!(" SEEKS {}", seeks.join(", ")));
+                }
+                if let Some(range) = range {
+                    label.push_str(&format!(" RANGE {range}"));
+                }
+                label
+            }
+            Plan::Filter { predicate, .. } => format!("FILTER {predicate}"),
+            Plan::SemiJoin {
+                keys,
+                anti,
+                algorithm,
+                ..
+            } => {
+                let on: Vec<String> = keys
+                    .iter()
+                    .map(|k| format!("{} = {}", k.outer, k.inner))
+                    .collect();
+                let kind = if *anti { "ANTI JOIN" } else { "SEMI JOIN" };
+                format!("{algorithm} {kind} ON {}", on.join(" AND "))
+            }
+            Plan::Join { tables } => {
+                let tables: Vec<String> = tables
+                    .iter()
+                    .enumerate()
+                    .map(|(i, table)| match i {
+                        0 => table.name.clone(),
+                        _ => table.label(),
+                    })
+                    .collect();
+                format!("JOIN {}", tables.join(", "))
+            }
+            Plan::Project { columns, .. } => format!("PROJECT {}", columns.join(", ")),
+            Plan::Aggregate {
+                aggregates,
+                group_by,
+                ..
+            } => {
+                let aggregates: Vec<String> = aggregates.iter().map(|a| a.to_string()).collect();
+                let mut label = "AGGREGATE".to_string();
+                if !aggregates.is_empty() {
+                    label = format!("{label} {}", aggregates.join(", "));
+                }
+                if !group_by.is_empty() {
+                    let groups: Vec<String> = group_by.iter().map(|e| e.to_string()).collect();
+                    label = format!("{label} GROUP BY {}", groups.join(", "));
+                }
+                label
+            }
+            Plan::Window { windows, .. } => {
+                let windows: Vec<String> = windows.iter().map(|w| w.to_string()).collect();
+                format!("WINDOW {}", windows.join(", "))
+            }
+            Plan::Sort { order_by, .. } => {
+                let terms: Vec<String> = order_by.iter().map(|o| o.to_string()).collect();
+                format!("SORT BY {}", terms.join(", "))
+            }
+            Plan::Limit { limit, .. } => limit.to_string(),
+            Plan::Compound { operator, .. } => operator.to_string(),
+            Plan::Update {
+                table, assignments, ..
+            } => {
+                let set: Vec<String> = assignments
+                    .iter()
+                    .map(|a| format!("{} = {}", a.column_name, a.value))
+                    .collect();
+                format!("UPDATE {table} SET {}", set.join(", "))
+            }
+            Plan::Delete { table, .. } => format!("DELETE FROM {table}"),
+            Plan::Cte { name, columns, .. } => format!("CTE {name} ({})", columns.join(", ")),
+            Plan::View { name, columns, .. } => format!("VIEW {name} ({})", columns.join(", ")),
+            Plan::CreateView(view) => {
+                format!("CREATE VIEW {}", view.name)
+            }
+            Plan::Insert {
+                table,
+                columns,
+                values,
+            } => {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                format!(
+                    "INSERT INTO {table} ({}) VALUES ({})",
+                    columns.join(", "),
+                    values.join(", ")
+                )
+            }
+            Plan::Triggers { triggers, .. } => {
+                let triggers: Vec<String> = triggers
+                    .iter()
+                    .map(|t| format!("{} {}", t.timing, t.name))
+                    .collect();
+                format!("TRIGGERS {}", triggers.join(", "))
+            }
+            Plan::CreateIndex(index) => Statement::CreateIndex(index.clone()).to_string(),
+            Plan::CreateTable(table) => format!("CREATE TABLE {}", table.name),
+            Plan::CreateTrigger(trigger) => format!("CREATE TRIGGER {}", trigger.name),
+            Plan::CreateVirtualTable(table) => format!("CREATE VIRTUAL TABLE {}", table.name),
+            Plan::Begin(mode) => format!("BEGIN {mode}"),
+            Plan::Commit => "COMMIT".to_string(),
+            Plan::Rollback => "ROLLBACK".to_string(),
+            Plan::Savepoint(name) => format!("SAVEPOINT {name}"),
+            Plan::Release(name) => format!("RELEASE {name}"),
+            Plan::RollbackTo(name) => format!("ROLLBACK TO {name}"),
+            Plan::Attach { path, name } => format!("ATTACH \"{path}\" AS {name}"),
+            Plan::Detach(name) => format!("DETACH {name}"),
+            Plan::Pragma(pragma) => Statement::Pragma(pragma.clone()).to_string(),
+            Plan::Analyze(name) => Statement::Analyze(name.clone()).to_string(),
+            Plan::Reindex(name) => Statement::Reindex(name.clone()).to_string(),
+            Plan::Vacuum => "VACUUM".to_string(),
+        }
+    }
+
+    fn inputs(&self) -> Vec<&Plan> {
+        match self {
+            Plan::Filter { input, .. }
+            | Plan::Project { input, .. }
+            | Plan::Aggregate { input, .. }
+            | Plan::Window { input, .. }
+            | Plan::Sort { input, .. }
+            | Plan::Limit { input, .. }
+            | Plan::Update { input, .. }
+            | Plan::Delete { input, .. }
+            | Plan::Triggers { input, .. }
+            | Plan::Cte { input, .. }
+            | Plan::View { input, .. } => vec![input],
+            Plan::SemiJoin { outer, inner, .. } => vec![outer, inner],
+            Plan::Join { tables } => tables.iter().map(|t| &t.rows).collect(),
+            Plan::Compound { left, right, .. } => vec![left, right],
+            _ => vec![],
+        }
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Plan> {
+        match self {
+            Plan::Filter { input, .. }
+            | Plan::Project { input, .. }
+            | Plan::Aggregate { input, .. }
+            | Plan::Window { input, .. }
+            | Plan::Sort { input, .. }
+            | Plan::Limit { input, .. }
+            | Plan::Update { input, .. }
+            | Plan::Delete { input, .. }
+            | Plan::Triggers { input, .. }
+            | Plan::Cte { input, .. }
+            | Plan::View { input, .. } => vec![input],
+            Plan::SemiJoin { outer, inner, .. } => vec![outer, inner],
+            Plan::Join { tables } => tables.iter_mut().map(|t| &mut t.rows).collect(),
+            Plan::Compound { left, right, .. } => vec![left, right],
+            _ => vec![],
+        }
+    }
+
+    /// The plan as it prints, a line per operator, with what `note` says of each operator
+    /// after its label.
+    pub fn tree(&self, note: &dyn Fn(&Plan) -> String) -> Vec<String> {
+        let mut lines = vec![];
+        self.write_tree(note, "", "", &mut lines);
+        lines
+    }
+
+    fn write_tree(
+        &self,
+        note: &dyn Fn(&Plan) -> String,
+        branch: &str,
+        indent: &str,
+        lines: &mut Vec<String>,
+    ) {
+        lines.push(format!("{branch}{}{}", self.label(), note(self)));
+        let inputs = self.inputs();
+        for (i, input) in inputs.iter().enumerate() {
+            let last = i + 1 == inputs.len();
+            input.write_tree(
+                note,
+                &format!("{indent}{}", if last { "└── " } else { "├── " }),
+                &format!("{indent}{}", if last { "    " } else { "│   " }),
+                lines,
+            );
+        }
+    }
+
+    /// Every operator of the plan, itself first and then its inputs' in order.
+    pub fn operators(&self) -> Vec<&Plan> {
+        let mut operators = vec![self];
+        for input in self.inputs() {
+            operators.extend(input.operators());
+        }
+        operators
+    }
+
+    fn fmt_tree(&self, f: &mut fmt::Formatter, indent: &str) -> fmt::Result {
+        writeln!(f, "{}", self.label())?;
+        let inputs = self.inputs();
+        for (i, input) in inputs.iter().enumerate() {
+            let last = i + 1 == inputs.len();
+            write!(f, "{indent}{}", if last { "└── " } else { "├── " })?;
+            input.fmt_tree(
+                f,
+                &format!("{indent}{}", if last { "    " } else { "│   " }),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// A line of EXPLAIN QUERY PLAN and the lines nested beneath it.
+struct QueryPlanStep {
+    detail: String,
+    steps: Vec<QueryPlanStep>,
+}
+
+impl QueryPlanStep {
+    fn new(detail: String, steps: Vec<QueryPlanStep>) -> Self {
+        QueryPlanStep { detail, steps }
+    }
+}
+
+impl Plan {
+    /// The plan as EXPLAIN QUERY PLAN shows it, one line per table read, join, view or
+    /// sort, nested as in the sqlite3 shell.
+    pub fn query_plan(&self) -> Vec<String> {
+        let mut lines = vec!["QUERY PLAN".to_string()];
+        write_steps(&self.steps(), "", &mut lines);
+        lines
+    }
+
+    fn steps(&self) -> Vec<QueryPlanStep> {
+        match self {
+            Plan::Scan { table } => vec![QueryPlanStep::new(format!("SCAN {table}"), vec![])],
+            Plan::ConstantRow => vec![QueryPlanStep::new(self.label(), vec![])],
+            Plan::VirtualScan { table, number, .. } => vec![QueryPlanStep::new(
+                format!("SCAN {table} VIRTUAL TABLE INDEX {number}:"),
+                vec![],
+            )],
+            Plan::IndexSearch {
+                table,
+                index,
+                columns,
+                range,
+                ..
+            } => {
+                let detail = format!(
+                    "SEARCH {table} USING INDEX {index} ({})",
+                    search_key(columns, range.as_ref())
+                );
+                vec![QueryPlanStep::new(detail, vec![])]
+            }
+            Plan::SemiJoin { outer, inner, .. } => {
+                let mut steps = outer.steps();
+                steps.push(QueryPlanStep::new(self.label(), inner.steps()));
+                steps
+            }
+            // the first table's steps, then each table after it under how it is joined
+            Plan::Join { tables } => {
+                let mut steps = vec![];
+                for (i, table) in tables.iter().enumerate() {
+                    match i {
+                        0 => steps.extend(table.rows.steps()),
+                        _ => steps.push(QueryPlanStep::new(table.label(), table.rows.steps())),
+                    }
+                }
+                steps
+            }
+            // the view's rows are produced as they are read, like one of SQLite's co-routines
+            Plan::View { name, input, .. } | Plan::Cte { name, input, .. } => {
+                vec![QueryPlanStep::new(
+                    format!("CO-ROUTINE {name}"),
+                    input.steps(),
+                )]
+            }
+            Plan::Sort { input, .. } => {
+                let mut steps = input.steps();
+                steps.push(QueryPlanStep::new(
+                    "USE TEMP B-TREE FOR ORDER BY".to_string(),
+                    vec![],
+                ));
+                steps
+            }
+            // the leftmost SELECT and then each operator with the SELECT it brings in
+            Plan::Compound { .. } => {
+                let mut arms = vec![];
+                let mut plan = self;
+                while let Plan::Compound {
+                    operator,
+                    left,
+                    right,
+                } = plan
+                {
+                    let detail = match operator {
+                        CompoundOperator::UnionAll => operator.to_string(),
+                        _ => format!("{operator} USING TEMP B-TREE"),
+                    };
+                    arms.push(QueryPlanStep::new(detail, right.steps()));
+                    plan = left.as_ref();
+                }
+                arms.push(QueryPlanStep::new(
+                    "LEFT-MOST SUBQUERY".to_string(),
+                    plan.steps(),
+                ));
+                arms.reverse();
+                vec![QueryPlanStep::new("COMPOUND QUERY".to_string(), arms)]
+            }
+            _ => self
+                .inputs()
+                .iter()
+                .flat_map(|input| input.steps())
+                .collect(),
+        }
+    }
+}
+
+fn write_steps(steps: &[QueryPlanStep], indent: &str, lines: &mut Vec<String>) {
+    for (i, step) in steps.iter().enumerate() {
+        let last = i + 1 == steps.len();
+        lines.push(format!(
+            "{indent}{}{}",
+            if last { "`--" } else { "|--" },
+            step.detail
+        ));
+        let indent = format!("{indent}{}", if last { "   " } else { "|  " });
+        write_steps(&step.steps, &indent, lines);
+    }
+}
+
+/// Plans print as a tree, one operator per line with its inputs beneath it.
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_tree(f, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::{parse, parse_expr};
+    use std::collections::HashMap;
+
+    struct TestCatalog {
+        tables: HashMap<&'static str, Vec<&'static str>>,
+        indexes: Vec<CreateIndex>,
+        stats: HashMap<&'static str, TableStats>,
+        scan_orders: HashMap<&'static str, Vec<&'static str>>,
+    }
+
+    impl Catalog for TestCatalog {
+        fn table_columns(&self, table: &str) -> Result<Vec<String>> {
+            match self.tables.get(table) {
+                Some(columns) => Ok(columns.iter().map(|c| c.to_string()).collect()),
+                None => bail!(SqlError::NoSuchTable {
+                    table: table.to_string()
+                }),
+            }
+        }
+
+        fn table_indexes(&self, table: &str) -> Vec<CreateIndex> {
+            self.indexes
+                .iter()
+                .filter(|i| i.table == table)
+                .cloned()
+                .collect()
+        }
+
+        fn view(&self, _name: &str) -> Option<CreateView> {
+            None
+        }
+
+        fn table_triggers(&self, _table: &str) -> Vec<CreateTrigger> {
+            vec![]
+        }
+
+        fn table_stats(&self, table: &str) -> Option<TableStats> {
+            self.stats.get(table).cloned()
+        }
+
+        fn scan_order(&self, table: &str) -> Vec<String> {
+            self.scan_orders.get(table).map_or(vec![], |order| {
+                order.iter().map(|c| c.to_string()).collect()
+            })
+        }
+    }
+
+    fn index(name: &str, table: &str, columns: &[&str]) -> CreateIndex {
+        CreateIndex {
+            name: name.to_string(),
+            table: table.to_string(),
+            columns: columns
+                .iter()
+                .map(|c| Expr::Column(c.to_string()))
+                .collect(),
+            unique: false,
+            if_not_exists: false,
+        }
+    }
+
+    fn catalog() -> TestCatalog {
+        TestCatalog {
+            tables: HashMap::from([
+                ("users", vec!["id", "name", "balance"]),
+                ("orders", vec!["order_id", "user_id", "total", "status"]),
+            ]),
+            indexes: vec![
+                index("idx_orders_user", "orders", &["user_id"]),
+                index("idx_orders_user_status", "orders", &["user_id", "status"]),
+            ],
+            stats: HashMap::new(),
+            scan_orders: HashMap::new(),
+        }
+    }
+
+    fn plan_sql(sql: &str) -> Plan {
+        plan(&parse(sql).unwrap(), &catalog()).unwrap()
+    }
+
+    fn scan(table: &str) -> Box<Plan> {
+        Box::new(Plan::Scan {
+            table: table.to_string(),
+        })
+    }
+
+    #[test]
+    fn correlated_exists_becomes_a_semi_join() {
+        assert_eq!(
+            plan_sql(
+                "SELECT name FROM users WHERE NOT EXISTS \
+                 (SELECT 1 FROM orders WHERE id = user_id AND total > 100) AND balance > 0;"
+            ),
+            Plan::Project {
+                input: Box::new(Plan::SemiJoin {
+                    outer: Box::new(Plan::Filter {
+                        input: scan("users"),
+                        predicate: parse_expr("balance > 0").unwrap(),
+                    }),
+                    inner: Box::new(Plan::Filter {
+                        input: scan("orders"),
+                        predicate: parse_expr("total > 100").unwrap(),
+                    }),
+                    keys: vec![JoinKey {
+                        outer: "id".to_string(),
+                        inner: "user_id".to_string(),
+                    }],
+                    anti: true,
+                    algorithm: JoinAlgorithm::Hash,
+                }),
+                columns: vec!["name".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn other_exists_stay_filters() {
+        for sql in [
+            // correlated by something other than equality
+            "SELECT name FROM users WHERE EXISTS (SELECT 1 FROM orders WHERE total > balance);",
+            // not correlated at all
+            "SELECT name FROM users WHERE EXISTS (SELECT 1 FROM orders WHERE total > 100);",
+            // under an OR, so not every row must satisfy it
+            "SELECT name FROM users WHERE balance > 0 OR EXISTS (SELECT 1 FROM orders WHERE user_id = id);",
+        ] {
+            let Plan::Project { input, .. } = plan_sql(sql) else {
+                panic!("expected a projection");
+            };
+            assert!(matches!(*input, Plan::Filter { .. }), "{sql}");
+        }
+    }
+
+    #[test]
+    fn wildcards_expand_to_the_table_columns() {
+        let Plan::Project { columns, .. } = plan_sql("SELECT *, 1, users.* FROM users;") else {
+            panic!("expected a projection");
+        };
+        assert_eq!(
+            columns,
+            ["id", "name", "balance", "1", "id", "name", "balance"]
+        );
+        assert_eq!(
+            plan(&parse("SELECT orders.* FROM users;").unwrap(), &catalog())
+                .unwrap_err()
+                .to_string(),
+            "no such table: orders"
+        );
+    }
+
+    #[test]
+    fn explain_prints_the_plan_tree() {
+        let statement = parse(
+            "EXPLAIN SELECT name FROM users WHERE EXISTS \
+             (SELECT 1 FROM orders WHERE user_id = id AND total > 100) AND balance > 0;",
+        )
+        .unwrap();
+        let Statement::Explain(statement) = statement else {
+            panic!("expected EXPLAIN");
+        };
+        assert_eq!(
+            plan(&statement, &catalog()).unwrap().to_string(),
+            "\
+PROJECT name
+└── HASH SEMI JOIN ON id = user_id
+    ├── FILTER balance > 0
+    │   └── SCAN users
+    └── FILTER total > 100
+        └── SCAN orders
+"
+        );
+    }
+
+    #[test]
+    fn query_plans_show_how_each_table_is_read() {
+        let query_plan = |sql: &str| plan_sql(sql).query_plan().join("\n");
+        assert_eq!(
+            query_plan(
+                "SELECT name FROM users u WHERE EXISTS (SELECT 1 FROM orders WHERE user_id = u.id) \
+                 AND EXISTS (SELECT 1 FROM orders WHERE user_id = u.id AND total > 100);"
+            ),
+            "\
+QUERY PLAN
+|--SCAN users
+|--HASH SEMI JOIN ON id = user_id
+|  `--SCAN orders
+`--HASH SEMI JOIN ON id = user_id
+   `--SCAN orders"
+        );
+        assert_eq!(
+            query_plan(
+                "WITH big (buyer, amount) AS (SELECT user_id, total FROM orders) \
+                 SELECT amount FROM big WHERE buyer = 3;"
+            ),
+            "\
+QUERY PLAN
+`--CO-ROUTINE big
+   `--SEARCH orders USING INDEX idx_orders_user (user_id=?)"
+        );
+    }
+
+    #[test]
+    fn window_functions_add_a_column_to_each_row() {
+        assert_eq!(
+            plan_sql("SELECT total, RANK() OVER (PARTITION BY o.user_id ORDER BY total DESC) FROM orders o WHERE status = 1;")
+                .to_string(),
+            "\
+PROJECT total, RANK() OVER (PARTITION BY user_id ORDER BY total DESC)
+└── WINDOW RANK() OVER (PARTITION BY user_id ORDER BY total DESC)
+    └── FILTER status = 1
+        └── SCAN orders
+"
+        );
+        assert_eq!(
+            plan(
+                &parse("SELECT COUNT(*), SUM(total) OVER () FROM orders;").unwrap(),
+                &catalog()
+            )
+            .unwrap_err()
+            .to_string(),
+            "aggregates alongside window functions are not supported"
+        );
+    }
+
+    #[test]
+    fn uncorrelated_subqueries_fold_into_their_values() {
+        let plan = plan_sql(
+            "SELECT name FROM users WHERE balance > (SELECT AVG(total) FROM orders) \
+             AND id = (SELECT MAX(user_id) FROM orders WHERE total > balance);",
+        );
+        let mut asked = vec![];
+        let folded = fold_uncorrelated(&plan, &mut |select: &Statement| {
+            asked.push(select.to_string());
+            Ok(ColVal::Int(7))
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(asked, ["SELECT AVG(total) FROM orders"]);
+        assert_eq!(
+            folded.to_string(),
+            "\
+PROJECT name
+└── FILTER balance > 7 AND id = (SELECT MAX(user_id) FROM orders WHERE total > users.balance)
+    └── SCAN users
+"
+        );
+        let correlated = plan_sql(
+            "SELECT name FROM users WHERE id = (SELECT MAX(user_id) FROM orders WHERE total > balance);",
+        );
+        assert_eq!(
+            fold_uncorrelated(&correlated, &mut |_: &Statement| unreachable!()).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn aggregates_fold_the_rows_into_one() {
+        assert_eq!(
+            plan_sql("SELECT COUNT(*), sum(DISTINCT o.total) FROM orders o WHERE status = 1;")
+                .to_string(),
+            "\
+PROJECT COUNT(*), SUM(DISTINCT total)
+└── AGGREGATE COUNT(*), SUM(DISTINCT total)
+    └── FILTER status = 1
+        └── SCAN orders
+"
+        );
+        for (sql, error) in [
+            (
+                "SELECT status, COUNT(*) FROM orders;",
+                "bare column status alongside aggregates is not supported",
+            ),
+            ("SELECT MAX(nope) FROM orders;", "no such column: nope"),
+        ] {
+            assert_eq!(
+                plan(&parse(sql).unwrap(), &catalog())
+                    .unwrap_err()
+                    .to_string(),
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn aliased_tables_join_on_their_qualified_columns() {
+        assert_eq!(
+            plan_sql(
+                "SELECT u.name FROM users u WHERE NOT EXISTS \
+                 (SELECT 1 FROM orders o WHERE o.user_id = u.id AND o.total > 100);"
+            )
+            .to_string(),
+            "\
+PROJECT name
+└── HASH ANTI JOIN ON id = user_id
+    ├── SCAN users
+    └── FILTER total > 100
+        └── SCAN orders
+"
+        );
+        // outer columns stay qualified in subqueries that can't be joined
+        assert_eq!(
+            plan_sql(
+                "SELECT name FROM users u WHERE EXISTS \
+                 (SELECT 1 FROM orders WHERE total > balance);"
+            )
+            .to_string(),
+            "\
+PROJECT name
+└── FILTER EXISTS (SELECT 1 FROM orders WHERE total > u.balance)
+    └── SCAN users
+"
+        );
+    }
+
+    #[test]
+    fn writes_are_planned_over_the_rows_they_change() {
+        assert_eq!(
+            plan_sql("UPDATE users SET balance = balance - 1 WHERE id = 7;").to_string(),
+            "\
+UPDATE users SET balance = balance - 1
+└── FILTER id = 7
+    └── SCAN users
+"
+        );
+    }
+
+    #[cfg(feature = "update-delete-limit")]
+    #[test]
+    fn limited_deletes_sort_then_limit() {
+        assert_eq!(
+            plan_sql("DELETE FROM orders WHERE total = 0 ORDER BY order_id DESC LIMIT 10;")
+                .to_string(),
+            "\
+DELETE FROM orders
+└── LIMIT 10
+    └── SORT BY order_id DESC
+        └── FILTER total = 0
+            └── SCAN orders
+"
+        );
+    }
+
+    #[test]
+    fn ctes_are_inlined_where_they_are_read() {
+        assert_eq!(
+            plan_sql(
+                "WITH big (buyer) AS (SELECT user_id FROM orders WHERE total > 100), \
+                 names AS (SELECT name, id FROM users) \
+                 SELECT name FROM names WHERE EXISTS (SELECT 1 FROM big WHERE buyer = id);"
+            )
+            .to_string(),
+            "\
+PROJECT name
+└── HASH SEMI JOIN ON id = buyer
+    ├── CTE names (name, id)
+    │   └── PROJECT name, id
+    │       └── SCAN users
+    └── CTE big (buyer)
+        └── PROJECT user_id
+            └── FILTER total > 100
+                └── SCAN orders
+"
+        );
+
+        // a subquery that can't become a join is run by itself so takes the CTE with it
+        let Plan::Project { input, .. } = plan_sql(
+            "WITH big AS (SELECT user_id FROM orders WHERE total > 100) \
+             SELECT name FROM users WHERE id IN (SELECT user_id FROM big);",
+        ) else {
+            panic!("expected a projection");
+        };
+        let Plan::Filter { predicate, .. } = *input else {
+            panic!("expected a filter");
+        };
+        assert_eq!(
+            predicate.to_string(),
+            "id IN (WITH big AS (SELECT user_id FROM orders WHERE total > 100) \
+             SELECT user_id FROM big)"
+        );
+    }
+
+    #[test]
+    fn statistics_decide_between_scan_and_index() {
+        let sql = "SELECT total FROM orders WHERE user_id IN (1, 2, 3, 4);";
+        let searches = |catalog: &TestCatalog| {
+            plan(&parse(sql).unwrap(), catalog)
+                .unwrap()
+                .to_string()
+                .contains("SEARCH")
+        };
+        let mut catalog = catalog();
+        assert!(searches(&catalog));
+
+        // four seeks cost more than reading 20 rows
+        catalog.stats.insert(
+            "orders",
+            TableStats {
+                rows: 20,
+                rows_per_key: BTreeMap::new(),
+                ..Default::default()
+            },
+        );
+        assert!(!searches(&catalog));
+
+        // unless each key is known to match a single row of many
+        catalog.stats.insert(
+            "orders",
+            TableStats {
+                rows: 1000,
+                rows_per_key: BTreeMap::from([("idx_orders_user".to_string(), vec![1])]),
+                ..Default::default()
+            },
+        );
+        assert!(searches(&catalog));
+    }
+
+    #[test]
+    fn samples_count_the_rows_in_a_range() {
+        let sql = "SELECT total FROM orders WHERE user_id > 10;";
+        let searches = |catalog: &TestCatalog| {
+            plan(&parse(sql).unwrap(), catalog)
+                .unwrap()
+                .to_string()
+                .contains("SEARCH")
+        };
+        let sample = |user_id, eq, lt| Sample {
+            value: ColVal::Int(user_id),
+            eq,
+            lt,
+        };
+        // both indexes on orders start with user_id
+        let stats = |samples: Vec<Sample>| TableStats {
+            rows: 1000,
+            rows_per_key: BTreeMap::new(),
+            samples: BTreeMap::from([
+                ("idx_orders_user".to_string(), samples.clone()),
+                ("idx_orders_user_status".to_string(), samples),
+            ]),
+        };
+        let mut catalog = catalog();
+        // guessed to keep a quarter of the rows, a search is cheaper
+        catalog.stats.insert(
+            "orders",
+            TableStats {
+                rows: 1000,
+                ..Default::default()
+            },
+        );
+        assert!(searches(&catalog));
+
+        // but not when the samples show that all but one row is above 10
+        catalog
+            .stats
+            .insert("orders", stats(vec![sample(5, 1, 0), sample(20, 999, 1)]));
+        assert!(!searches(&catalog));
+
+        // and it is again when nearly every row is at or below it
+        catalog.stats.insert(
+            "orders",
+            stats(vec![
+                sample(1, 900, 0),
+                sample(10, 90, 900),
+                sample(50, 10, 990),
+            ]),
+        );
+        assert!(searches(&catalog));
+    }
+
+    #[test]
+    fn hints_pin_how_a_table_is_read() {
+        let mut catalog = catalog();
+        // twenty rows are cheaper to scan than to seek four times
+        catalog.stats.insert(
+            "orders",
+            TableStats {
+                rows: 20,
+                rows_per_key: BTreeMap::new(),
+                ..Default::default()
+            },
+        );
+        let explain = |sql: &str| plan(&parse(sql).unwrap(), &catalog).map(|p| p.to_string());
+
+        assert_eq!(
+            explain(
+                "SELECT total FROM orders INDEXED BY idx_orders_user_status \
+                 WHERE user_id IN (1, 2, 3, 4);"
+            )
+            .unwrap(),
+            "\
+PROJECT total
+└── SEARCH orders USING INDEX idx_orders_user_status (user_id=?) SEEKS (1), (2), (3), (4)
+"
+        );
+        assert_eq!(
+            explain("SELECT total FROM orders NOT INDEXED WHERE user_id = 1;").unwrap(),
+            "PROJECT total\n└── FILTER user_id = 1\n    └── SCAN orders\n"
+        );
+        // +user_id isn't the column itself, so the index can't be used for it
+        assert!(explain("SELECT total FROM orders WHERE user_id = 1;")
+            .unwrap()
+            .contains("SEARCH"));
+        assert_eq!(
+            explain("SELECT total FROM orders WHERE +user_id = 1 AND status = 2;").unwrap(),
+            "PROJECT total\n└── FILTER +user_id = 1 AND status = 2\n    └── SCAN orders\n"
+        );
+
+        assert_eq!(
+            explain("SELECT total FROM orders INDEXED BY nope WHERE user_id = 1;")
+                .unwrap_err()
+                .to_string(),
+            "no such index: nope"
+        );
+        assert_eq!(
+            explain("SELECT total FROM orders INDEXED BY idx_orders_user WHERE total = 1;")
+                .unwrap_err()
+                .to_string(),
+            "no query solution"
+        );
+    }
+
+    #[test]
+    fn filters_are_pushed_into_ctes() {
+        assert_eq!(
+            plan_sql(
+                "WITH big (buyer, amount) AS (SELECT user_id, total FROM orders WHERE total > 100) \
+                 SELECT amount FROM big WHERE buyer = 3 AND amount < 500 AND amount = buyer;"
+            )
+            .to_string(),
+            "\
+PROJECT amount
+└── CTE big (buyer, amount)
+    └── PROJECT user_id, total
+        └── FILTER total > 100 AND total < 500 AND total = user_id
+            └── SEARCH orders USING INDEX idx_orders_user (user_id=?) SEEKS (3)
+"
+        );
+    }
+
+    #[test]
+    fn the_smallest_semi_join_is_done_first() {
+        assert_eq!(
+            plan_sql(
+                "SELECT name FROM users u WHERE EXISTS (SELECT 1 FROM orders WHERE user_id = u.id) \
+                 AND EXISTS (SELECT 1 FROM orders WHERE user_id = u.id AND total > 100);"
+            )
+            .to_string(),
+            "\
+PROJECT name
+└── HASH SEMI JOIN ON id = user_id
+    ├── HASH SEMI JOIN ON id = user_id
+    │   ├── SCAN users
+    │   └── FILTER total > 100
+    │       └── SCAN orders
+    └── SCAN orders
+"
+        );
+    }
+
+    #[test]
+    fn joins_of_sorted_inputs_merge() {
+        let mut catalog = catalog();
+        catalog.scan_orders = HashMap::from([("users", vec!["id"]), ("orders", vec!["order_id"])]);
+        let plan_sql = |sql: &str| plan(&parse(sql).unwrap(), &catalog).unwrap().to_string();
+
+        // both tables are scanned in the order of the columns they are joined on
+        assert_eq!(
+            plan_sql(
+                "SELECT name FROM users WHERE balance > 0 AND EXISTS \
+                 (SELECT 1 FROM orders WHERE order_id = id AND total > 100);"
+            ),
+            "\
+PROJECT name
+└── MERGE SEMI JOIN ON id = order_id
+    ├── FILTER balance > 0
+    │   └── SCAN users
+    └── FILTER total > 100
+        └── SCAN orders
+"
+        );
+        // orders aren't sorted by user_id
+        assert_eq!(
+            plan_sql(
+                "SELECT name FROM users WHERE NOT EXISTS (SELECT 1 FROM orders WHERE user_id = id);"
+            ),
+            "\
+PROJECT name
+└── HASH ANTI JOIN ON id = user_id
+    ├── SCAN users
+    └── SCAN orders
+"
+        );
+    }
+
+    #[test]
+    fn joined_tables_are_read_with_their_own_terms() {
+        let mut catalog = catalog();
+        catalog.scan_orders = HashMap::from([("users", vec!["id"]), ("orders", vec!["order_id"])]);
+        let plan_sql = |sql: &str| plan(&parse(sql).unwrap(), &catalog).unwrap().to_string();
+
+        // a term of one table is pushed down to it, even into an index search, and terms
+        // of two are either the join's keys or filter the joined rows. The search finds
+        // fewer rows than the scan so it is joined onto.
+        assert_eq!(
+            plan_sql(
+                "SELECT u.name, o.total FROM users u JOIN orders o ON o.user_id = u.id \
+                 WHERE u.balance > 0 AND o.user_id = 7 AND o.total > u.balance;"
+            ),
+            "\
+PROJECT u.name, o.total
+└── FILTER o.total > u.balance
+    └── JOIN o, HASH JOIN u ON o.user_id = u.id
+        ├── SEARCH orders USING INDEX idx_orders_user (user_id=?) SEEKS (7)
+        └── FILTER balance > 0
+            └── SCAN users
+"
+        );
+        // tables sorted by the columns they are joined on merge, and tables with nothing
+        // to join them on are crossed
+        assert_eq!(
+            plan_sql("SELECT * FROM users, orders o WHERE o.order_id = users.id;"),
+            "\
+PROJECT users.id, users.name, users.balance, o.order_id, o.user_id, o.total, o.status
+└── JOIN users, MERGE JOIN o ON users.id = o.order_id
+    ├── SCAN users
+    └── SCAN orders
+"
+        );
+        assert_eq!(
+            plan_sql("SELECT a.name, b.name FROM users a CROSS JOIN users b;"),
+            "\
+PROJECT a.name, b.name
+└── JOIN a, CROSS JOIN b
+    ├── SCAN users
+    └── SCAN users
+"
+        );
+    }
+
+    #[test]
+    fn joins_start_from_the_smallest_table() {
+        let mut catalog = catalog();
+        catalog.tables.insert("items", vec!["order_id", "sku"]);
+        for (table, rows) in [("users", 100), ("orders", 10_000)] {
+            catalog.stats.insert(
+                table,
+                TableStats {
+                    rows,
+                    ..Default::default()
+                },
+            );
+        }
+        let query_plan = |sql: &str| {
+            plan(&parse(sql).unwrap(), &catalog)
+                .unwrap()
+                .query_plan()
+                .join("\n")
+        };
+
+        // items has no key to users, so it waits for orders, however it was written
+        let sql = "SELECT sku FROM items i JOIN orders o ON i.order_id = o.order_id \
+                   JOIN users u ON o.user_id = u.id;";
+        assert_eq!(
+            query_plan(sql),
+            "\
+QUERY PLAN
+|--SCAN users
+|--HASH JOIN o ON u.id = o.user_id
+|  `--SCAN orders
+`--HASH JOIN i ON o.order_id = i.order_id
+   `--SCAN items"
+        );
+        // the rows each table is estimated to have are those its own terms leave
+        assert_eq!(
+            query_plan(&sql.replace(';', " WHERE o.user_id IN (1, 2);")),
+            "\
+QUERY PLAN
+|--SEARCH orders USING INDEX idx_orders_user (user_id=?)
+|--HASH JOIN u ON o.user_id = u.id
+|  `--SCAN users
+`--HASH JOIN i ON o.order_id = i.order_id
+   `--SCAN items"
+        );
+    }
+
+    #[test]
+    fn in_lists_on_indexed_columns_become_index_seeks() {
+        assert_eq!(
+            plan_sql("SELECT total FROM orders WHERE user_id IN (3, 1, 2, 1, NULL) AND total > 0;")
+                .to_string(),
+            "\
+PROJECT total
+└── FILTER total > 0
+    └── SEARCH orders USING INDEX idx_orders_user (user_id=?) SEEKS (1), (2), (3)
+"
+        );
+
+        // the longest usable prefix wins, each combination of values is one seek
+        assert_eq!(
+            plan_sql(r#"SELECT total FROM orders WHERE status = "new" AND user_id = 7;"#)
+                .to_string(),
+            "\
+PROJECT total
+└── SEARCH orders USING INDEX idx_orders_user_status (user_id=? AND status=?) SEEKS (7, \"new\")
+"
+        );
+        // unless it takes more seeks than it saves rows
+        assert_eq!(
+            plan_sql(
+                r#"SELECT total FROM orders WHERE status IN ("new", "paid") AND user_id = 7;"#
+            )
+            .to_string(),
+            "\
+PROJECT total
+└── FILTER status IN (\"new\", \"paid\")
+    └── SEARCH orders USING INDEX idx_orders_user (user_id=?) SEEKS (7)
+"
+        );
+        assert_eq!(
+            plan_sql(
+                r#"SELECT total FROM orders WHERE (user_id, status) IN ((1, "new"), (2, "paid"));"#
+            )
+            .to_string(),
+            "\
+PROJECT total
+└── SEARCH orders USING INDEX idx_orders_user_status (user_id=? AND status=?) SEEKS (1, \"new\"), (2, \"paid\")
+"
+        );
+
+        // without the leading column the index is no help
+        let Plan::Project { input, .. } =
+            plan_sql(r#"SELECT total FROM orders WHERE status IN ("new", "paid");"#)
+        else {
+            panic!("expected a projection");
+        };
+        assert_eq!(
+            *input,
+            Plan::Filter {
+                input: scan("orders"),
+                predicate: parse_expr(r#"status IN ("new", "paid")"#).unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn ranges_on_indexed_columns_become_index_searches() {
+        assert_eq!(
+            plan_sql("SELECT total FROM orders WHERE user_id > 10 AND 20 >= user_id;").to_string(),
+            "\
+PROJECT total
+└── SEARCH orders USING INDEX idx_orders_user (user_id>? AND user_id<=?) RANGE user_id > 10 AND user_id <= 20
+"
+        );
+
+        // a range can follow the columns the seeks pin, and leaves the terms it can't use
+        let sql =
+            r#"SELECT total FROM orders WHERE user_id = 7 AND status < "paid" AND status < "x";"#;
+        assert_eq!(
+            plan_sql(sql).to_string(),
+            "\
+PROJECT total
+└── FILTER status < \"x\"
+    └── SEARCH orders USING INDEX idx_orders_user_status (user_id=? AND status<?) SEEKS (7) RANGE status < \"paid\"
+"
+        );
+        assert_eq!(
+            plan_sql(sql).query_plan(),
+            [
+                "QUERY PLAN",
+                "`--SEARCH orders USING INDEX idx_orders_user_status (user_id=? AND status<?)"
+            ]
+        );
+
+        // a column after one the seeks don't pin is no help, nor is a comparison with NULL
+        for sql in [
+            r#"SELECT total FROM orders WHERE status > "new";"#,
+            "SELECT total FROM orders WHERE user_id > NULL;",
+        ] {
+            assert!(!plan_sql(sql).to_string().contains("SEARCH"), "{sql}");
+        }
+    }
+
+    #[test]
+    fn unknown_names_are_an_error() {
+        for (sql, error) in [
+            ("SELECT name FROM nope;", "no such table: nope"),
+            (
+                "INSERT INTO users (name, age) VALUES (\"bob\", 3);",
+                "table users has no column named age",
+            ),
+            ("UPDATE users SET age = 1;", "no such column: age"),
+            (
+                "WITH a AS (SELECT id FROM users), a AS (SELECT id FROM users) SELECT id FROM a;",
+                "duplicate WITH table name: a",
+            ),
+            (
+                "WITH a (x, y) AS (SELECT id FROM users) SELECT x FROM a;",
+                "table a has 1 values for 2 columns",
+            ),
+            (
+                "CREATE INDEX idx ON orders (lower(name));",
+                "no such column: name",
+            ),
+        ] {
+            let statement = parse(sql).unwrap();
+            assert_eq!(plan(&statement, &catalog()).unwrap_err().to_string(), error);
+        }
+    }
+}
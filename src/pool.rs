@@ -0,0 +1,367 @@
+/*
+    A pool of connections to one database, for a program that runs statements on it from
+    many threads, such as a server answering each request on a thread of its own.
+
+        let pool = Pool::open("shop.db", 4)?;
+        let mut conn = pool.get()?;
+        conn.execute("INSERT INTO orders (item) VALUES (?);", &["tea".into()])?;
+        drop(conn); // back in the pool
+
+    Connections are opened as they are first asked for, up to the pool's size, and kept
+    open once they are given back, each with the statements it has prepared still in its
+    cache, see prepared.rs. So a statement the threads run over and over is parsed and
+    planned once per connection rather than once per request, and the file is loaded once
+    per connection rather than every time. open_with runs a function on each connection
+    as it is opened, to set a busy timeout of its own, say, or create a module.
+
+    When every connection is out, get waits for one to come back. The threads waiting
+    are served in the order they asked, each taking a ticket as it comes, so that none is
+    passed over for ever while later ones keep getting connections. A thread that holds
+    one connection while it waits for a second can wait for ever in a pool of one.
+
+    Each connection is a connection of its own to the file, as two opened with
+    Connection::open are: it sees what the others have committed before its next
+    statement, see executor.rs, but a transaction it begins is its own. So that threads
+    writing at once take turns rather than fail with "database is locked", each
+    connection waits up to five seconds for another's lock, as PRAGMA busy_timeout = 5000
+    would have it, and opening one waits as long for a commit to finish. A connection
+    comes back as it is, so one given back in the middle of a transaction has it rolled
+    back first. A pool of ":memory:" is a pool of separate databases.
+*/
+use crate::connection::Connection;
+use crate::error::SqlError;
+use crate::storage::busy::BusyHandler;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+// How long a pooled connection waits for another's lock before it fails with "database
+// is locked", unless init sets a timeout of its own.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Init = Box<dyn Fn(&mut Connection) -> Result<()> + Send + Sync>;
+
+/// Connections to one database shared between threads, see the module comment. Cloning
+/// the pool gives another handle on the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    filename: String,
+    size: usize,
+    init: Init,
+    state: Mutex<State>,
+    // signalled whenever a connection comes back or a turn moves on
+    turn: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    // the connections given back, the last given back first to go out again
+    idle: Vec<Connection>,
+    // how many connections are open, idle or out
+    open: usize,
+    // the ticket the next thread to ask takes, and the one whose turn it is
+    next_ticket: u64,
+    serving: u64,
+}
+
+impl Pool {
+    /// A pool of at most `size` connections to `filename`, a path or a `file:` URI. The
+    /// first is opened now, so that a file that can't be opened fails here.
+    pub fn open(filename: &str, size: usize) -> Result<Pool> {
+        Pool::open_with(filename, size, |_| Ok(()))
+    }
+
+    /// As open, running `init` on each connection as it is opened.
+    pub fn open_with(
+        filename: &str,
+        size: usize,
+        init: impl Fn(&mut Connection) -> Result<()> + Send + Sync + 'static,
+    ) -> Result<Pool> {
+        if size == 0 {
+            bail!("a pool must have room for at least one connection");
+        }
+        let shared = Shared {
+            filename: filename.to_string(),
+            size,
+            init: Box::new(init),
+            state: Mutex::default(),
+            turn: Condvar::new(),
+        };
+        let first = shared.connect()?;
+        let mut state = shared.state();
+        state.idle.push(first);
+        state.open = 1;
+        drop(state);
+        Ok(Pool {
+            shared: Arc::new(shared),
+        })
+    }
+
+    /// A connection from the pool, waiting for one to be given back if all are out. It
+    /// goes back into the pool when the guard is dropped.
+    pub fn get(&self) -> Result<PooledConnection> {
+        let shared = &self.shared;
+        let mut state = shared.state();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        loop {
+            if state.serving == ticket {
+                if let Some(conn) = state.idle.pop() {
+                    state.serving += 1;
+                    drop(state);
+                    shared.turn.notify_all();
+                    return Ok(self.guard(conn));
+                }
+                if state.open < shared.size {
+                    state.open += 1;
+                    state.serving += 1;
+                    drop(state);
+                    shared.turn.notify_all();
+                    // opened outside the lock, as loading the file may take a while
+                    return match shared.connect() {
+                        Ok(conn) => Ok(self.guard(conn)),
+                        Err(err) => {
+                            shared.state().open -= 1;
+                            shared.turn.notify_all();
+                            Err(err)
+                        }
+                    };
+                }
+            }
+            state = shared.turn.wait(state).unwrap();
+        }
+    }
+
+    /// The most connections the pool will have open at once.
+    pub fn size(&self) -> usize {
+        self.shared.size
+    }
+
+    /// How many connections are open, whether in the pool or out of it.
+    pub fn open_connections(&self) -> usize {
+        self.shared.state().open
+    }
+
+    /// How many open connections are in the pool, waiting to be handed out.
+    pub fn idle_connections(&self) -> usize {
+        self.shared.state().idle.len()
+    }
+
+    fn guard(&self, conn: Connection) -> PooledConnection {
+        PooledConnection {
+            conn: Some(conn),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("filename", &self.shared.filename)
+            .field("size", &self.shared.size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Shared {
+    fn connect(&self) -> Result<Connection> {
+        // loading the file waits for a writer's lock as the connection will once open
+        let busy = BusyHandler::Timeout(BUSY_TIMEOUT);
+        let mut count = 0;
+        let mut conn = loop {
+            match Connection::open(&self.filename) {
+                Err(err) if is_locked(&err) && busy.retry(count) => count += 1,
+                opened => break opened?,
+            }
+        };
+        conn.busy_timeout(BUSY_TIMEOUT);
+        (self.init)(&mut conn)?;
+        Ok(conn)
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+fn is_locked(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref(), Some(SqlError::DatabaseLocked))
+}
+
+/// A connection taken from a Pool, used as the Connection it derefs to and given back to
+/// the pool when dropped.
+pub struct PooledConnection {
+    // None only once it has been given back
+    conn: Option<Connection>,
+    shared: Arc<Shared>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl fmt::Debug for PooledConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PooledConnection").field(&self.conn).finish()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let Some(mut conn) = self.conn.take() else {
+            return;
+        };
+        if conn.executor().in_transaction() {
+            if let Err(err) = conn.execute("ROLLBACK;", &[]) {
+                // a connection that can't be rolled back is closed, not handed out again
+                tracing::warn!(%err, "pooled connection given back in a transaction");
+                self.shared.state().open -= 1;
+                self.shared.turn.notify_all();
+                return;
+            }
+        }
+        self.shared.state().idle.push(conn);
+        self.shared.turn.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::ColVal;
+    use std::thread;
+
+    fn count(conn: &mut Connection) -> i64 {
+        let mut rows = conn.query("SELECT count(*) FROM t;", &[]).unwrap();
+        rows.next().unwrap().unwrap().get(0).unwrap()
+    }
+
+    #[test]
+    fn connections_are_opened_as_needed_and_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pool.db");
+        let pool = Pool::open(path.to_str().unwrap(), 2).unwrap();
+        assert_eq!(pool.open_connections(), 1);
+
+        let mut first = pool.get().unwrap();
+        first
+            .execute("CREATE TABLE t (id INTEGER PRIMARY KEY);", &[])
+            .unwrap();
+        first
+            .execute("INSERT INTO t (id) VALUES (7);", &[])
+            .unwrap();
+        let mut second = pool.get().unwrap();
+        assert_eq!(pool.open_connections(), 2);
+        // each sees what the other committed
+        assert_eq!(count(&mut second), 1);
+        drop(second);
+        drop(first);
+        assert_eq!(pool.idle_connections(), 2);
+
+        // the last given back is the first out again, as it was
+        let conn = pool.get().unwrap();
+        assert_eq!(conn.last_insert_rowid(), 7);
+        drop(conn);
+        assert_eq!(pool.open_connections(), 2);
+        assert!(Pool::open(":memory:", 0).is_err());
+    }
+
+    #[test]
+    fn a_transaction_left_open_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pool.db");
+        let pool = Pool::open(path.to_str().unwrap(), 1).unwrap();
+        let mut conn = pool.get().unwrap();
+        conn.execute("CREATE TABLE t (id INTEGER PRIMARY KEY);", &[])
+            .unwrap();
+        conn.execute("BEGIN;", &[]).unwrap();
+        conn.execute("INSERT INTO t (id) VALUES (1);", &[]).unwrap();
+        drop(conn);
+
+        let mut conn = pool.get().unwrap();
+        assert!(!conn.executor().in_transaction());
+        assert_eq!(count(&mut conn), 0);
+    }
+
+    #[test]
+    fn waiting_threads_are_served_in_the_order_they_asked() {
+        let pool = Pool::open_with(":memory:", 1, |conn| {
+            conn.execute("CREATE TABLE t (n INTEGER);", &[])?;
+            Ok(())
+        })
+        .unwrap();
+        let held = pool.get().unwrap();
+        let mut waiting = vec![];
+        for id in 0..4i64 {
+            let waiter = pool.clone();
+            waiting.push(thread::spawn(move || {
+                let mut conn = waiter.get().unwrap();
+                conn.execute("INSERT INTO t (n) VALUES (?);", &[ColVal::Int(id)])
+                    .unwrap();
+            }));
+            // wait for it to take its ticket before the next asks
+            while pool.shared.state().next_ticket < id as u64 + 2 {
+                thread::yield_now();
+            }
+        }
+        drop(held);
+        for thread in waiting {
+            thread.join().unwrap();
+        }
+
+        let mut conn = pool.get().unwrap();
+        let rows: Vec<i64> = conn
+            .query("SELECT n FROM t;", &[])
+            .unwrap()
+            .map(|row| row.unwrap().get(0).unwrap())
+            .collect();
+        assert_eq!(rows, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn writers_on_every_thread_wait_their_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pool.db");
+        let pool = Pool::open(path.to_str().unwrap(), 2).unwrap();
+        pool.get()
+            .unwrap()
+            .execute("CREATE TABLE t (n INTEGER);", &[])
+            .unwrap();
+        let writers: Vec<_> = (0..4i64)
+            .map(|id| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    for n in 0..25 {
+                        let mut conn = pool.get().unwrap();
+                        conn.execute(
+                            "INSERT INTO t (n) VALUES (?);",
+                            &[ColVal::Int(id * 100 + n)],
+                        )
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(count(&mut pool.get().unwrap()), 100);
+    }
+}
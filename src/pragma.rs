@@ -0,0 +1,716 @@
+/*
+    PRAGMA statements, which read and change the engine's settings from SQL.
+
+        PRAGMA page_size;              the current value, as a one row result
+        PRAGMA cache_size = -4000;     change it
+        PRAGMA table_info(users);      some pragmas take an argument instead
+
+    Each pragma is a named knob on something that already owns the setting, the pager's
+    PagerConfig or the schema, rather than a setting of its own. The ones we know:
+
+        page_size        bytes per page, a power of two from 512 to 65536
+        reserved_bytes   bytes at the end of each page kept out of the B+tree for
+                         extensions like checksums, 0 to 255, see storage::header
+        cache_size       pages the cache may hold, or KiB of them when negative
+        cache_replacement
+                         lru, clock or '2q', quoted as it starts with a digit, how the
+                         cache picks a page to evict when it is full, see
+                         storage::replacement
+        journal_mode     delete, truncate, persist, memory, wal or off. wal puts main's
+                         file in WAL mode and the others take it out of it, see
+                         storage::image
+        synchronous      off, normal, full or extra, reported as 0 to 3
+        max_page_count   the most pages the database may have, which caps how big a row
+                         can be, see storage::overflow. A write that would grow the
+                         file past it fails with "database or disk is full", and it
+                         can't be set below the pages the file already has
+        page_count       the pages main's file has, read only
+        freelist_count   how many of them are free, read only
+        mmap_size        bytes at the start of each file read through a memory map rather
+                         than the page cache, 0 for none, on Unix only, see
+                         storage::pager
+        busy_timeout     milliseconds to keep trying a lock another connection holds
+                         before failing with "database is locked", 0 to fail at once,
+                         see storage::busy
+        hard_heap_limit  bytes the cache may hold whatever cache_size says, and a sort
+                         or a hash join's table before it spills or is split, 0 for no
+                         limit
+        temp_store       where temporary tables are kept: default or memory, both in
+                         memory here, or file, see storage::attach. Changing it drops
+                         every temporary table, as in SQLite
+        table_info(t)    a row per column of table t
+        index_list(t)    a row per index on table t, the one its PRIMARY KEY makes too
+        index_info(i)    a row per key column of index i
+        index_xinfo(i)   the same with the collation each sorts by, and the rowid
+        integrity_check  "ok", or a row per problem found
+        stats            the counts of the caches of main's file and the attached
+                         ones', a row for each
+        deterministic_output
+                         on or off, whether the rows of a query without an ORDER BY
+                         come sorted, for tests that compare them, see below
+        max_trigger_depth
+                         how deep triggers and subqueries may run inside one another
+                         before the statement fails, see nesting.rs
+        foreign_keys     on or off, whether foreign keys are enforced and their ON DELETE
+                         and ON UPDATE actions run, off to start with as in SQLite,
+                         see foreign_key.rs
+
+    page_count and freelist_count are read from the file's header, see header.rs, as it is
+    when the pragma runs. A database in memory keeps its tables in no pages, and has 0 of
+    each.
+
+    As in SQLite a pragma it doesn't know does nothing and returns nothing, so scripts
+    written for a newer version still run. A bad value for page_size, reserved_bytes or
+    journal_mode is ignored in the same way, leaving the setting as it was, and so is a
+    new page_size once the database has a table in it. The page size a database is created
+    with is the size of every page of its file, see header.rs, and sets how many keys its
+    B+tree nodes hold before they split.
+
+    A query without an ORDER BY returns its rows in whatever order its plan reads them,
+    which changes with the indexes there are and the plan chosen. With
+    deterministic_output on they are sorted instead, by their first column, then their
+    second and so on, so that a test's expected output holds whichever plan ran. Such a
+    query's rows are then made all at once rather than streamed. The rows of a query
+    that has an ORDER BY are left in its order. SQLite has no such pragma, and ignores it.
+
+    integrity_check looks at the schema, checking that each index is on columns its table
+    has, and the executor adds what it finds comparing each index's entries with its
+    table's rows, see SecondaryIndex::check, and what it finds checking the shape of every
+    table's and index's B+tree, see Btree::check_invariants.
+*/
+use crate::catalog;
+use crate::executor::RowSet;
+use crate::planner::Catalog;
+use crate::schema::Schema;
+use crate::sql_parser::ast::{literal, ColVal, Expr, Pragma};
+use crate::storage::busy::BusyHandler;
+use crate::storage::cache::CacheReport;
+use crate::storage::pager::{JournalMode, PagerConfig, Synchronous, TempStore};
+use crate::storage::replacement::Replacement;
+use anyhow::{bail, Result};
+
+// The one row, one column result of a pragma that reads a setting.
+fn single(column: &str, value: ColVal) -> RowSet {
+    RowSet {
+        columns: vec![column.to_string()],
+        rows: vec![vec![value]],
+        changes: None,
+    }
+}
+
+/// How much of main's file is in use: the pages it has and how many of them are free.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileUsage {
+    pub pages: u32,
+    pub free: u32,
+}
+
+pub fn execute(
+    pragma: &Pragma,
+    config: &mut PagerConfig,
+    schema: &Schema,
+    // read only by the pragmas that report it, as it takes a read of the file's header
+    usage: impl FnOnce() -> Result<FileUsage>,
+) -> Result<RowSet> {
+    let value = pragma.value.as_deref();
+    let number = |value: &str| -> Result<i64> {
+        match value.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => bail!("{} must be a number, not {value}", pragma.name),
+        }
+    };
+
+    Ok(match (pragma.name.as_str(), value) {
+        ("page_size", None) => single("page_size", ColVal::Int(config.page_size.into())),
+        // like SQLite the page size is the database's from its first table on
+        ("page_size", Some(size)) => {
+            let tables = schema.table_names();
+            if tables.iter().all(|name| *name == catalog::MASTER_TABLE) {
+                config.set_page_size(number(size)?);
+            }
+            RowSet::default()
+        }
+        ("reserved_bytes", None) => {
+            single("reserved_bytes", ColVal::Int(config.reserved_bytes.into()))
+        }
+        ("reserved_bytes", Some(reserved)) => {
+            config.set_reserved_bytes(number(reserved)?);
+            RowSet::default()
+        }
+        ("cache_size", None) => single("cache_size", ColVal::Int(config.cache_size)),
+        ("cache_size", Some(size)) => {
+            config.cache_size = number(size)?;
+            RowSet::default()
+        }
+        ("cache_replacement", policy) => {
+            if let Some(policy) = policy.and_then(Replacement::parse) {
+                config.replacement = policy;
+            }
+            single(
+                "cache_replacement",
+                ColVal::String(config.replacement.to_string()),
+            )
+        }
+        ("journal_mode", mode) => {
+            if let Some(mode) = mode.and_then(JournalMode::parse) {
+                config.journal_mode = mode;
+            }
+            single(
+                "journal_mode",
+                ColVal::String(config.journal_mode.to_string()),
+            )
+        }
+        ("synchronous", None) => single("synchronous", ColVal::Int(config.synchronous.level())),
+        ("synchronous", Some(level)) => {
+            let Some(level) = Synchronous::parse(level) else {
+                bail!("unknown synchronous level: {level}");
+            };
+            config.synchronous = level;
+            RowSet::default()
+        }
+        // setting it reports the new count, as SQLite does, which is never less than the
+        // pages there are
+        ("max_page_count", count) => {
+            if let Some(count) = count {
+                let count = number(count)?;
+                if count > 0 {
+                    config.set_max_page_count(count.max(usage()?.pages.into()));
+                }
+            }
+            single("max_page_count", ColVal::Int(config.max_page_count.into()))
+        }
+        ("page_count", _) => single("page_count", ColVal::Int(usage()?.pages.into())),
+        ("freelist_count", _) => single("freelist_count", ColVal::Int(usage()?.free.into())),
+        ("mmap_size", size) => {
+            if let Some(size) = size {
+                config.set_mmap_size(number(size)?);
+            }
+            single("mmap_size", ColVal::Int(config.mmap_size as i64))
+        }
+        // setting it reports the new timeout, as SQLite does
+        ("busy_timeout", ms) => {
+            if let Some(ms) = ms {
+                config.busy = BusyHandler::timeout(number(ms)?);
+            }
+            single("busy_timeout", ColVal::Int(config.busy.timeout_ms()))
+        }
+        ("hard_heap_limit", limit) => {
+            if let Some(limit) = limit {
+                config.set_heap_limit(number(limit)?);
+            }
+            single("hard_heap_limit", ColVal::Int(config.heap_limit as i64))
+        }
+        ("temp_store", None) => single("temp_store", ColVal::Int(config.temp_store.level())),
+        // as in SQLite a value it doesn't know is the default
+        ("temp_store", Some(store)) => {
+            config.temp_store = TempStore::parse(store).unwrap_or_default();
+            RowSet::default()
+        }
+        ("table_info", Some(table)) => table_info(schema, table),
+        ("index_list", Some(table)) => index_list(schema, table),
+        ("index_info", Some(index)) => index_info(schema, index),
+        ("index_xinfo", Some(index)) => index_xinfo(schema, index),
+        ("integrity_check", _) => integrity_check(schema, vec![]),
+        _ => RowSet::default(),
+    })
+}
+
+// One row per column: cid, name, type, notnull, dflt_value and pk, the column's position
+// in the primary key counting from 1 or 0 when it isn't part of it. notnull is 1 for a
+// NOT NULL column and for the key of a WITHOUT ROWID table, which can't be NULL, as in
+// SQLite, and the default is written as the SQL literal it would be. No rows for a table
+// that doesn't exist.
+fn table_info(schema: &Schema, table: &str) -> RowSet {
+    let columns = ["cid", "name", "type", "notnull", "dflt_value", "pk"];
+    let Some(table) = schema.table(table) else {
+        return RowSet {
+            columns: columns.map(str::to_string).to_vec(),
+            rows: vec![],
+            changes: None,
+        };
+    };
+    let rows = table
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(cid, column)| {
+            let pk = table
+                .primary_key
+                .iter()
+                .position(|k| *k == column.name)
+                .map_or(0, |i| i as i64 + 1);
+            vec![
+                ColVal::Int(cid as i64),
+                ColVal::String(column.name.clone()),
+                ColVal::String(column.type_name.clone().unwrap_or_default()),
+                ColVal::Int((column.not_null || table.without_rowid && pk > 0).into()),
+                column
+                    .default
+                    .as_ref()
+                    .map_or(ColVal::Null, |d| ColVal::String(literal(d))),
+                ColVal::Int(pk),
+            ]
+        })
+        .collect();
+    RowSet {
+        columns: columns.map(str::to_string).to_vec(),
+        rows,
+        changes: None,
+    }
+}
+
+// One row per index on a table: seq, name, unique, origin, "c" for one made by CREATE
+// INDEX and "pk" for the one the PRIMARY KEY made, and partial, always 0 as there are no
+// partial indexes. No rows for a table that doesn't exist or has no indexes.
+fn index_list(schema: &Schema, table: &str) -> RowSet {
+    let rows = schema
+        .table_indexes(table)
+        .iter()
+        .enumerate()
+        .map(|(seq, index)| {
+            let info = schema.index_info(index);
+            vec![
+                ColVal::Int(seq as i64),
+                ColVal::String(info.name),
+                ColVal::Int(info.unique.into()),
+                ColVal::String(info.origin.code().to_string()),
+                ColVal::Int(0),
+            ]
+        })
+        .collect();
+    RowSet {
+        columns: ["seq", "name", "unique", "origin", "partial"]
+            .map(str::to_string)
+            .to_vec(),
+        rows,
+        changes: None,
+    }
+}
+
+// One row per key column of an index: seqno, cid and name, with a cid of -2 and no name
+// for a key that is an expression, as in SQLite. No rows for an index that doesn't exist.
+fn index_info(schema: &Schema, name: &str) -> RowSet {
+    let mut rows = vec![];
+    if let Some(index) = schema.index(name) {
+        let table_columns = schema.table_columns(&index.table).unwrap_or_default();
+        for (seqno, key) in schema.index_info(index).columns.into_iter().enumerate() {
+            let (cid, name) = match key.column {
+                Some(column) => (
+                    table_columns
+                        .iter()
+                        .position(|c| *c == column)
+                        .map_or(-1, |cid| cid as i64),
+                    ColVal::String(column),
+                ),
+                None => (-2, ColVal::Null),
+            };
+            rows.push(vec![ColVal::Int(seqno as i64), ColVal::Int(cid), name]);
+        }
+    }
+    RowSet {
+        columns: ["seqno", "cid", "name"].map(str::to_string).to_vec(),
+        rows,
+        changes: None,
+    }
+}
+
+// One row per key column of an index or WITHOUT ROWID table: seqno, cid, name and coll,
+// the collation its B+tree compares the column under. SQLite adds the rowid on the end of
+// every index key, which we list too with a cid of -1. No rows for an index that doesn't
+// exist.
+fn index_xinfo(schema: &Schema, name: &str) -> RowSet {
+    let columns = ["seqno", "cid", "name", "coll"];
+    let mut rows = vec![];
+    let key = match schema.index(name) {
+        Some(index) => Some((index.table.as_str(), index.columns.clone(), true)),
+        None => schema.table(name).filter(|t| t.without_rowid).map(|t| {
+            let key = t.primary_key.iter().cloned().map(Expr::Column).collect();
+            (t.name.as_str(), key, false)
+        }),
+    };
+    if let (Some((table, key, rowid)), Some(collations)) = (key, schema.key_collations(name)) {
+        let table_columns = schema.table_columns(table).unwrap_or_default();
+        for (seqno, (column, collation)) in key.iter().zip(collations).enumerate() {
+            let column = match column {
+                Expr::Collate { expr, .. } => &**expr,
+                column => column,
+            };
+            let Expr::Column(column) = column else {
+                continue;
+            };
+            let cid = table_columns.iter().position(|c| c == column);
+            rows.push(vec![
+                ColVal::Int(seqno as i64),
+                ColVal::Int(cid.map_or(-1, |cid| cid as i64)),
+                ColVal::String(column.clone()),
+                ColVal::String(collation),
+            ]);
+        }
+        if rowid {
+            rows.push(vec![
+                ColVal::Int(rows.len() as i64),
+                ColVal::Int(-1),
+                ColVal::Null,
+                ColVal::Null,
+            ]);
+        }
+    }
+    RowSet {
+        columns: columns.map(str::to_string).to_vec(),
+        rows,
+        changes: None,
+    }
+}
+
+/// The result of integrity_check: the problems with the schema followed by `found`, the
+/// ones found in the rows, or "ok" if there are none.
+pub fn integrity_check(schema: &Schema, found: Vec<String>) -> RowSet {
+    let mut problems = vec![];
+    for table in schema.table_names() {
+        let columns = schema.table_columns(table).unwrap_or_default();
+        for index in schema.table_indexes(table) {
+            for column in &index.columns {
+                column.walk(&mut |e| {
+                    if let Expr::Column(c) = e {
+                        if !columns.contains(c) {
+                            problems.push(format!("index {} is on missing column {c}", index.name));
+                        }
+                    }
+                });
+            }
+        }
+    }
+    problems.extend(found);
+    if problems.is_empty() {
+        problems.push("ok".to_string());
+    }
+    RowSet {
+        columns: vec!["integrity_check".to_string()],
+        rows: problems
+            .into_iter()
+            .map(|p| vec![ColVal::String(p)])
+            .collect(),
+        changes: None,
+    }
+}
+
+/// The result of a pragma that turns something on or off, which like SQLite takes on, yes,
+/// true or 1 and off, no, false or 0, and reports 1 or 0 when asked. Any other value is
+/// ignored.
+pub fn flag(pragma: &Pragma, flag: &mut bool) -> Result<RowSet> {
+    let Some(value) = &pragma.value else {
+        return Ok(single(&pragma.name, ColVal::Int((*flag).into())));
+    };
+    match value.to_lowercase().as_str() {
+        "on" | "yes" | "true" | "1" => *flag = true,
+        "off" | "no" | "false" | "0" => *flag = false,
+        _ => {}
+    }
+    Ok(RowSet::default())
+}
+
+/// The result of a pragma that sets a limit, which reports the limit as it is afterwards,
+/// as max_page_count does. A limit below 1 is ignored.
+pub fn limit(pragma: &Pragma, limit: &mut u32) -> Result<RowSet> {
+    if let Some(value) = &pragma.value {
+        let Ok(value) = value.parse::<i64>() else {
+            bail!("{} must be a number, not {value}", pragma.name);
+        };
+        if value > 0 {
+            *limit = u32::try_from(value).unwrap_or(u32::MAX);
+        }
+    }
+    Ok(single(&pragma.name, ColVal::Int((*limit).into())))
+}
+
+/// The result of stats: how the caches in `report` have done and how much I/O they took,
+/// in total since the files were opened. The shell's `.stats` shows the same.
+pub fn stats(report: &CacheReport) -> RowSet {
+    let total = report.total();
+    let stats = [
+        ("cache_hits", total.hits),
+        ("cache_misses", total.faults),
+        ("pages_read", total.faults + total.read_ahead),
+        ("pages_written", total.writebacks),
+        ("evictions", total.evictions),
+        ("fsyncs", report.syncs),
+    ];
+    RowSet {
+        columns: vec!["stat".to_string(), "value".to_string()],
+        rows: stats
+            .into_iter()
+            .map(|(name, value)| vec![ColVal::String(name.to_string()), ColVal::Int(value as i64)])
+            .collect(),
+        changes: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::Statement;
+    use crate::sql_parser::parse;
+    use crate::storage::cache::{PageCache, PageStore};
+    use crate::storage::wal::PageNumber;
+
+    fn execute_sql(sql: &str, config: &mut PagerConfig, schema: &Schema) -> Result<RowSet> {
+        let Statement::Pragma(pragma) = parse(sql).unwrap() else {
+            panic!("expected PRAGMA");
+        };
+        execute(&pragma, config, schema, || Ok(FileUsage::default()))
+    }
+
+    fn rows(result: RowSet) -> Vec<Vec<ColVal>> {
+        result.rows
+    }
+
+    #[test]
+    fn pragmas_read_and_change_the_pager_config() {
+        let mut config = PagerConfig::default();
+        let schema = Schema::default();
+        let mut run = |sql: &str| rows(execute_sql(sql, &mut config, &schema).unwrap());
+
+        assert_eq!(run("PRAGMA page_size;"), [[ColVal::Int(4096)]]);
+        assert!(run("PRAGMA page_size = 8192;").is_empty());
+        assert_eq!(run("PRAGMA page_size;"), [[ColVal::Int(8192)]]);
+        // not a power of two, so ignored
+        run("PRAGMA page_size = 1000;");
+        assert_eq!(run("PRAGMA page_size;"), [[ColVal::Int(8192)]]);
+
+        run("PRAGMA cache_size = -4000;");
+        assert_eq!(run("PRAGMA cache_size;"), [[ColVal::Int(-4000)]]);
+
+        let two_queue = [[ColVal::String("2q".to_string())]];
+        assert_eq!(run("PRAGMA cache_replacement = '2Q';"), two_queue);
+        assert_eq!(run("PRAGMA cache_replacement = newest;"), two_queue);
+
+        let wal = [[ColVal::String("wal".to_string())]];
+        assert_eq!(run("PRAGMA journal_mode = WAL;"), wal);
+        assert_eq!(run("PRAGMA journal_mode = sideways;"), wal);
+
+        run("PRAGMA synchronous = NORMAL;");
+        assert_eq!(run("PRAGMA synchronous;"), [[ColVal::Int(1)]]);
+
+        assert!(run("PRAGMA reserved_bytes = 32;").is_empty());
+        // 8192 - 7800 would leave too little of a page for cells
+        run("PRAGMA reserved_bytes = 7800;");
+        assert_eq!(run("PRAGMA reserved_bytes;"), [[ColVal::Int(32)]]);
+
+        assert_eq!(run("PRAGMA max_page_count = 5;"), [[ColVal::Int(5)]]);
+        assert_eq!(run("PRAGMA max_page_count = 0;"), [[ColVal::Int(5)]]);
+
+        assert_eq!(run("PRAGMA mmap_size;"), [[ColVal::Int(0)]]);
+        assert_eq!(run("PRAGMA mmap_size = 1048576;"), [[ColVal::Int(1048576)]]);
+        assert_eq!(run("PRAGMA mmap_size = -1;"), [[ColVal::Int(1048576)]]);
+
+        assert_eq!(run("PRAGMA busy_timeout;"), [[ColVal::Int(0)]]);
+        assert_eq!(run("PRAGMA busy_timeout = 500;"), [[ColVal::Int(500)]]);
+
+        assert_eq!(run("PRAGMA hard_heap_limit;"), [[ColVal::Int(0)]]);
+        assert_eq!(
+            run("PRAGMA hard_heap_limit = 1000000;"),
+            [[ColVal::Int(1000000)]]
+        );
+        assert_eq!(
+            run("PRAGMA hard_heap_limit = -1;"),
+            [[ColVal::Int(1000000)]]
+        );
+
+        assert_eq!(run("PRAGMA temp_store;"), [[ColVal::Int(0)]]);
+        run("PRAGMA temp_store = FILE;");
+        assert_eq!(run("PRAGMA temp_store;"), [[ColVal::Int(1)]]);
+
+        assert!(run("PRAGMA no_such_pragma;").is_empty());
+
+        assert_eq!(
+            config,
+            PagerConfig {
+                page_size: 8192,
+                reserved_bytes: 32,
+                cache_size: -4000,
+                journal_mode: JournalMode::Wal,
+                synchronous: Synchronous::Normal,
+                max_page_count: 5,
+                mmap_size: 1048576,
+                replacement: Replacement::TwoQueue,
+                busy: BusyHandler::timeout(500),
+                heap_limit: 1000000,
+                temp_store: TempStore::File,
+            }
+        );
+        // 4000 KiB of pages is 500 of them, but the heap limit has room for only 122
+        assert_eq!(config.cache_pages(), 122);
+        assert_eq!(config.memory_budget(), 999424);
+        config.set_heap_limit(0);
+        assert_eq!(config.cache_pages(), 500);
+        assert_eq!(
+            execute_sql("PRAGMA synchronous = 7;", &mut config, &schema)
+                .unwrap_err()
+                .to_string(),
+            "unknown synchronous level: 7"
+        );
+    }
+
+    #[test]
+    fn stats_count_the_caches_io() {
+        struct File;
+        impl PageStore for File {
+            fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+                Ok(vec![page as u8])
+            }
+
+            fn write_page(&mut self, _page: PageNumber, _data: &[u8]) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut cache = PageCache::with_policy(1, Replacement::Lru.policy());
+        cache.get_mut(1, "users", &mut File).unwrap();
+        cache.get(1, "users", &mut File).unwrap();
+        cache.get(2, "users", &mut File).unwrap();
+        cache.sync(&mut File).unwrap();
+        assert_eq!(
+            rows(stats(&cache.report()))
+                .into_iter()
+                .map(|row| row[1].clone())
+                .collect::<Vec<_>>(),
+            [1, 2, 2, 1, 1, 1].map(ColVal::Int)
+        );
+    }
+
+    #[test]
+    fn table_info_and_integrity_check_read_the_schema() {
+        let mut schema = Schema::default();
+        let Statement::CreateTable(table) =
+            parse("CREATE TABLE users (id INTEGER, name TEXT NOT NULL DEFAULT 'it''s', PRIMARY KEY (id));")
+                .unwrap()
+        else {
+            panic!("expected CREATE TABLE");
+        };
+        schema.create_table(&table).unwrap();
+        let mut config = PagerConfig::default();
+
+        // the database has a table, so its page size is settled
+        execute_sql("PRAGMA page_size = 1024;", &mut config, &schema).unwrap();
+        assert_eq!(config.page_size, 4096);
+        assert_eq!(config.node_keys(), 64);
+
+        let info = execute_sql("PRAGMA table_info(users);", &mut config, &schema).unwrap();
+        assert_eq!(
+            info.columns,
+            ["cid", "name", "type", "notnull", "dflt_value", "pk"]
+        );
+        let text = |s: &str| ColVal::String(s.to_string());
+        assert_eq!(
+            info.rows,
+            [
+                vec![
+                    ColVal::Int(0),
+                    text("id"),
+                    text("INTEGER"),
+                    ColVal::Int(0),
+                    ColVal::Null,
+                    ColVal::Int(1)
+                ],
+                vec![
+                    ColVal::Int(1),
+                    text("name"),
+                    text("TEXT"),
+                    ColVal::Int(1),
+                    text("'it''s'"),
+                    ColVal::Int(0)
+                ],
+            ]
+        );
+        assert!(
+            rows(execute_sql("PRAGMA table_info(nope);", &mut config, &schema).unwrap()).is_empty()
+        );
+
+        assert_eq!(
+            rows(execute_sql("PRAGMA integrity_check;", &mut config, &schema).unwrap()),
+            [[text("ok")]]
+        );
+
+        let Statement::CreateIndex(index) =
+            parse("CREATE INDEX users_name ON users (name COLLATE NOCASE);").unwrap()
+        else {
+            panic!("expected CREATE INDEX");
+        };
+        schema.create_index(&index).unwrap();
+        assert_eq!(
+            rows(execute_sql("PRAGMA index_xinfo(users_name);", &mut config, &schema).unwrap()),
+            [
+                vec![ColVal::Int(0), ColVal::Int(1), text("name"), text("NOCASE")],
+                vec![ColVal::Int(1), ColVal::Int(-1), ColVal::Null, ColVal::Null],
+            ]
+        );
+    }
+
+    #[test]
+    fn index_list_and_index_info_describe_a_tables_indexes() {
+        let mut schema = Schema::default();
+        for sql in [
+            "CREATE TABLE grades (student TEXT, course INTEGER, mark INTEGER, \
+             PRIMARY KEY (student, course));",
+            "CREATE UNIQUE INDEX by_mark ON grades (mark, lower(student));",
+            "CREATE TABLE courses (code TEXT, name TEXT, PRIMARY KEY (code)) WITHOUT ROWID;",
+        ] {
+            match parse(sql).unwrap() {
+                Statement::CreateTable(table) => schema.create_table(&table).unwrap(),
+                Statement::CreateIndex(index) => schema.create_index(&index).unwrap(),
+                other => panic!("{other:?}"),
+            };
+        }
+        let mut config = PagerConfig::default();
+        let mut pragma = |sql: &str| rows(execute_sql(sql, &mut config, &schema).unwrap());
+        let text = |s: &str| ColVal::String(s.to_string());
+
+        // seq, then the rest of each row
+        let mut list: Vec<Vec<ColVal>> = pragma("PRAGMA index_list(grades);")
+            .into_iter()
+            .enumerate()
+            .map(|(seq, mut row)| {
+                assert_eq!(row.remove(0), ColVal::Int(seq as i64));
+                row
+            })
+            .collect();
+        list.sort_by_key(|row| row[0].to_string());
+        assert_eq!(
+            list,
+            [
+                vec![text("by_mark"), ColVal::Int(1), text("c"), ColVal::Int(0)],
+                vec![
+                    text("sqlite_autoindex_grades_1"),
+                    ColVal::Int(1),
+                    text("pk"),
+                    ColVal::Int(0)
+                ],
+            ]
+        );
+        assert!(pragma("PRAGMA index_list(courses);").is_empty());
+        assert!(pragma("PRAGMA index_list(nope);").is_empty());
+
+        assert_eq!(
+            pragma("PRAGMA index_info(by_mark);"),
+            [
+                vec![ColVal::Int(0), ColVal::Int(2), text("mark")],
+                vec![ColVal::Int(1), ColVal::Int(-2), ColVal::Null],
+            ]
+        );
+        assert_eq!(
+            pragma("PRAGMA index_info(sqlite_autoindex_grades_1);"),
+            [
+                vec![ColVal::Int(0), ColVal::Int(0), text("student")],
+                vec![ColVal::Int(1), ColVal::Int(1), text("course")],
+            ]
+        );
+        assert!(pragma("PRAGMA index_info(nope);").is_empty());
+
+        // the key of a WITHOUT ROWID table can't be NULL
+        let notnull: Vec<ColVal> = pragma("PRAGMA table_info(courses);")
+            .into_iter()
+            .map(|row| row[3].clone())
+            .collect();
+        assert_eq!(notnull, [1, 0].map(ColVal::Int));
+    }
+}
@@ -0,0 +1,398 @@
+/*
+    Prepared statements are parsed once and then run many times with different values
+    bound to their placeholders. Binding values rather than splicing them into the SQL
+    text is both faster (no re-parse) and safe from SQL injection, since a bound value
+    can never be mistaken for SQL syntax.
+
+    Placeholders are numbered the same way SQLite numbers them:
+
+        ?       the next number after the largest one used so far
+        ?NNN    exactly NNN
+        :name   (or @name, $name) the next number on first use, reused on later uses
+
+    so `INSERT INTO t(a, b, c) VALUES (?, ?5, :x);` has parameters 1, 5 and 6, while
+    parameters 2 to 4 exist but can never be referenced.
+
+    The plan is kept between runs too, along with the bytecode it compiles to if the
+    virtual machine can run it, but it was made for the schema as it was at the time. If a
+    table the statement uses has changed since, say an index was added or a view it reads
+    was created, the statement is quietly planned again rather than run with a stale plan.
+    That goes for a change another connection made to the file too, which the executor
+    notices by the file's change counter before the statement runs, see executor.rs.
+    The bound values are part of the plan, an IN list of them may become index seeks, so
+    binding new ones plans the statement again too. Only the parse is saved then. The
+    authorizer, if the connection has one, is asked about the statement each time it is
+    planned, see authorizer.rs.
+
+    A connection keeps the statements it has prepared in a cache keyed by their SQL text,
+    so running the same SQL again, as an application tends to do with the handful of
+    queries it runs all the time, neither parses nor plans it again. The cache holds a
+    fixed number of statements and forgets the one used least recently to make room.
+*/
+use crate::authorizer::Authorizer;
+use crate::planner::{self, Plan};
+use crate::schema::Schema;
+use crate::sql_parser::{
+    ast::{ColVal, Expr, Placeholder, Statement},
+    parse,
+};
+use crate::vdbe::{self, Program};
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+
+// As many statements as SQLite's own wrappers tend to cache by default.
+const STATEMENT_CACHE_SIZE: usize = 16;
+
+/// A statement's plan and the program it compiled to, if the machine can run it.
+#[derive(Debug, Clone)]
+pub struct Compiled {
+    // the schema cookie the plan was made against
+    cookie: u32,
+    pub plan: Plan,
+    pub program: Option<Program>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PreparedStatement {
+    // The SQL it was prepared from.
+    sql: String,
+    // The parsed statement with every placeholder rewritten to Placeholder::Numbered.
+    statement: Statement,
+    // Parameter i + 1 is named names[i], if it was written as a named placeholder.
+    names: Vec<Option<String>>,
+    // Unbound parameters are NULL, as in SQLite.
+    bindings: Vec<ColVal>,
+    // The plan for the current bindings.
+    compiled: Option<Compiled>,
+}
+
+impl PreparedStatement {
+    pub fn prepare(sql: &str) -> Result<Self> {
+        let mut statement = parse(sql)?;
+        let mut names: Vec<Option<String>> = vec![];
+
+        statement.walk_exprs_mut(&mut |e| {
+            if let Expr::Placeholder(p) = e {
+                let index = match p {
+                    Placeholder::Anonymous => {
+                        names.push(None);
+                        names.len()
+                    }
+                    Placeholder::Numbered(n) => {
+                        if *n > names.len() {
+                            names.resize(*n, None);
+                        }
+                        *n
+                    }
+                    Placeholder::Named(name) => {
+                        match names.iter().position(|n| n.as_deref() == Some(name)) {
+                            Some(pos) => pos + 1,
+                            None => {
+                                names.push(Some(name.clone()));
+                                names.len()
+                            }
+                        }
+                    }
+                };
+                *p = Placeholder::Numbered(index);
+            }
+        });
+
+        tracing::debug!(sql, parameters = names.len(), "statement parsed");
+        let bindings = vec![ColVal::Null; names.len()];
+        Ok(PreparedStatement {
+            sql: sql.to_string(),
+            statement,
+            names,
+            bindings,
+            compiled: None,
+        })
+    }
+
+    /// The SQL the statement was prepared from, as sqlite3_sql gives it.
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// The statement as parsed, with its placeholders numbered.
+    pub fn statement(&self) -> &Statement {
+        &self.statement
+    }
+
+    /// The largest parameter number in the statement.
+    pub fn parameter_count(&self) -> usize {
+        self.names.len()
+    }
+
+    /// The 1-based index of a named parameter, the name includes its prefix e.g. ":id".
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .position(|n| n.as_deref() == Some(name))
+            .map(|pos| pos + 1)
+    }
+
+    pub fn parameter_name(&self, index: usize) -> Option<&str> {
+        self.names.get(index.checked_sub(1)?)?.as_deref()
+    }
+
+    /// Bind a value to the 1-based parameter `index`.
+    pub fn bind(&mut self, index: usize, value: ColVal) -> Result<()> {
+        if index == 0 || index > self.bindings.len() {
+            bail!(
+                "bind index {index} out of range, statement has {} parameters",
+                self.bindings.len()
+            );
+        }
+        if self.bindings[index - 1] != value {
+            self.bindings[index - 1] = value;
+            self.compiled = None;
+        }
+        Ok(())
+    }
+
+    pub fn bind_named(&mut self, name: &str, value: ColVal) -> Result<()> {
+        let Some(index) = self.parameter_index(name) else {
+            bail!("no such parameter: {name}");
+        };
+        self.bind(index, value)
+    }
+
+    /// Reset every parameter back to NULL.
+    pub fn clear_bindings(&mut self) {
+        if self.bindings.iter().any(|b| *b != ColVal::Null) {
+            self.bindings.fill(ColVal::Null);
+            self.compiled = None;
+        }
+    }
+
+    /// The plan and, if the machine can run it, the program to run the statement with,
+    /// made again if the bindings or any table the statement uses changed since it was
+    /// last planned.
+    pub fn compile(
+        &mut self,
+        schema: &Schema,
+        authorizer: Option<&Authorizer>,
+    ) -> Result<&Compiled> {
+        if let Some(compiled) = &mut self.compiled {
+            if schema.changed_since(compiled.cookie, &self.statement.tables()) {
+                self.compiled = None;
+            } else {
+                compiled.cookie = schema.cookie();
+            }
+        }
+        if self.compiled.is_none() {
+            let statement = self.bound_statement();
+            if let Some(authorizer) = authorizer {
+                authorizer.authorize(&statement, schema)?;
+            }
+            let plan = planner::plan(&statement, schema)?;
+            let program = vdbe::compile(&plan, schema)?;
+            let shown = plan.to_string();
+            tracing::debug!(
+                plan = shown.trim_end(),
+                bytecode = program.is_some(),
+                "statement planned"
+            );
+            self.compiled = Some(Compiled {
+                cookie: schema.cookie(),
+                program,
+                plan,
+            });
+        } else {
+            tracing::trace!("plan reused");
+        }
+        Ok(self.compiled.as_ref().expect("compiled above"))
+    }
+
+    /// The statement with the current bindings substituted for its placeholders,
+    /// ready to be executed.
+    pub fn bound_statement(&self) -> Statement {
+        let mut statement = self.statement.clone();
+        statement.walk_exprs_mut(&mut |e| {
+            if let Expr::Placeholder(Placeholder::Numbered(n)) = e {
+                *e = Expr::Literal(self.bindings[*n - 1].clone());
+            }
+        });
+        statement
+    }
+}
+
+/// The statements a connection has prepared, by SQL text, most recently used first.
+#[derive(Debug)]
+pub struct StatementCache {
+    capacity: usize,
+    statements: VecDeque<(String, PreparedStatement)>,
+}
+
+impl Default for StatementCache {
+    fn default() -> Self {
+        StatementCache::new(STATEMENT_CACHE_SIZE)
+    }
+}
+
+impl StatementCache {
+    pub fn new(capacity: usize) -> Self {
+        StatementCache {
+            capacity,
+            statements: VecDeque::new(),
+        }
+    }
+
+    /// Take the statement prepared from `sql` out of the cache, to be put back once it
+    /// has run.
+    pub fn take(&mut self, sql: &str) -> Option<PreparedStatement> {
+        let pos = self.statements.iter().position(|(s, _)| s == sql)?;
+        self.statements.remove(pos).map(|(_, statement)| statement)
+    }
+
+    /// Keep a statement as the most recently used, forgetting the least recently used
+    /// one if the cache is full.
+    pub fn put(&mut self, sql: &str, statement: PreparedStatement) {
+        self.take(sql);
+        self.statements.push_front((sql.to_string(), statement));
+        self.statements.truncate(self.capacity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::{BinaryOp, NewColumnVal};
+
+    fn values(statement: Statement) -> Vec<Expr> {
+        match statement {
+            Statement::Insert { columns, .. } => columns.into_iter().map(|c| c.value).collect(),
+            other => panic!("expected insert, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn placeholders_are_numbered_like_sqlite() {
+        let stmt = PreparedStatement::prepare("INSERT INTO t(a, b, c, d) VALUES (?, ?5, :x, :x);")
+            .unwrap();
+
+        assert_eq!(stmt.parameter_count(), 6);
+        assert_eq!(stmt.parameter_index(":x"), Some(6));
+        assert_eq!(stmt.parameter_name(6), Some(":x"));
+        assert_eq!(stmt.parameter_name(1), None);
+    }
+
+    #[test]
+    fn bound_values_replace_placeholders_and_unbound_are_null() {
+        let mut stmt =
+            PreparedStatement::prepare("INSERT INTO t(a, b, c) VALUES (?, @b, ?);").unwrap();
+        stmt.bind(1, ColVal::Int(7)).unwrap();
+        stmt.bind_named("@b", ColVal::String("bob".to_string()))
+            .unwrap();
+
+        assert_eq!(
+            values(stmt.bound_statement()),
+            vec![
+                Expr::Literal(ColVal::Int(7)),
+                Expr::Literal(ColVal::String("bob".to_string())),
+                Expr::Literal(ColVal::Null),
+            ]
+        );
+
+        // the same statement can be re-run with different values
+        stmt.clear_bindings();
+        stmt.bind(3, ColVal::Boolean(true)).unwrap();
+        assert_eq!(
+            values(stmt.bound_statement()),
+            vec![
+                Expr::Literal(ColVal::Null),
+                Expr::Literal(ColVal::Null),
+                Expr::Literal(ColVal::Boolean(true)),
+            ]
+        );
+    }
+
+    #[test]
+    fn placeholders_in_where_clause() {
+        let mut stmt =
+            PreparedStatement::prepare("SELECT name FROM users WHERE id = $id;").unwrap();
+        stmt.bind_named("$id", ColVal::Int(42)).unwrap();
+
+        match stmt.bound_statement() {
+            Statement::Select { where_clause, .. } => assert_eq!(
+                where_clause,
+                Some(Expr::Binary {
+                    op: BinaryOp::Eq,
+                    left: Box::new(Expr::Column("id".to_string())),
+                    right: Box::new(Expr::Literal(ColVal::Int(42))),
+                })
+            ),
+            other => panic!("expected select, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn binding_out_of_range_or_unknown_names_fails() {
+        let mut stmt = PreparedStatement::prepare("INSERT INTO t(a) VALUES (?);").unwrap();
+        assert!(stmt.bind(0, ColVal::Int(1)).is_err());
+        assert!(stmt.bind(2, ColVal::Int(1)).is_err());
+        assert!(stmt.bind_named(":nope", ColVal::Int(1)).is_err());
+    }
+
+    #[test]
+    fn literal_inserts_have_no_parameters() {
+        let stmt = PreparedStatement::prepare("INSERT INTO t(a) VALUES (1);").unwrap();
+        assert_eq!(stmt.parameter_count(), 0);
+        assert_eq!(
+            stmt.bound_statement(),
+            Statement::Insert {
+                into_table: "t".to_string(),
+                columns: vec![NewColumnVal {
+                    column_name: "a".to_string(),
+                    value: Expr::Literal(ColVal::Int(1)),
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn schema_changes_replan_affected_statements() {
+        let mut schema = Schema::default();
+        let Statement::CreateTable(users) = parse("CREATE TABLE users (id, name);").unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        schema.create_table(&users).unwrap();
+        let mut stmt =
+            PreparedStatement::prepare("SELECT name FROM users WHERE id IN (1, 2);").unwrap();
+        assert_eq!(
+            stmt.compile(&schema, None).unwrap().plan.to_string(),
+            "PROJECT name\n└── FILTER id IN (1, 2)\n    └── SCAN users\n"
+        );
+
+        let Statement::CreateIndex(index) =
+            parse("CREATE INDEX idx_users_id ON users (id);").unwrap()
+        else {
+            panic!("expected CREATE INDEX");
+        };
+        schema.create_index(&index).unwrap();
+        assert_eq!(
+            stmt.compile(&schema, None).unwrap().plan.to_string(),
+            "PROJECT name\n└── SEARCH users USING INDEX idx_users_id (id=?) SEEKS (1), (2)\n"
+        );
+    }
+
+    #[test]
+    fn the_cache_forgets_the_least_recently_used_statement() {
+        let mut cache = StatementCache::new(2);
+        let sql = ["SELECT a FROM t;", "SELECT b FROM t;", "SELECT c FROM t;"];
+        for sql in &sql[..2] {
+            cache.put(sql, PreparedStatement::prepare(sql).unwrap());
+        }
+        // using the first statement leaves the second as the least recently used
+        let first = cache.take(sql[0]).unwrap();
+        cache.put(sql[0], first);
+        cache.put(sql[2], PreparedStatement::prepare(sql[2]).unwrap());
+
+        assert_eq!(cache.statements.len(), 2);
+        assert!(cache.take(sql[1]).is_none());
+        assert!(cache.take(sql[0]).is_some());
+        assert!(cache.take(sql[2]).is_some());
+        assert!(cache.statements.is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+/*
+    EXPLAIN ANALYZE, which runs a statement and then shows its plan as EXPLAIN would,
+    with what each operator did along the way, so that a slow query shows where its time
+    went:
+
+        PROJECT name (actual rows=2 loops=1 time=0.031 ms)
+        └── FILTER age > 21 (actual rows=2 loops=1 time=0.024 ms)
+            └── SCAN users (actual rows=5 loops=1 time=0.009 ms)
+
+    While the executor has a Profile, each operator of the plan it runs is counted as it
+    finishes: the rows it produced, how many times it ran and how long it took, the time
+    its inputs took included, as in PostgreSQL. An operator that runs more than once has
+    the sums of its runs. The statement is run as a tree of operators even when the
+    bytecode machine could run it, see vdbe.rs, as a program has no operators to count.
+
+    The statement is run for real, so EXPLAIN ANALYZE DELETE deletes, and its writes are
+    counted on the first line as a whole, the rows being those it changed. A subquery run
+    for each row is planned afresh each time and isn't part of the plan shown, its time
+    is part of the operator's that ran it, and an uncorrelated one is run before the rest
+    and shows as its value.
+*/
+use crate::executor::RowSet;
+use crate::planner::Plan;
+use crate::sql_parser::ast::ColVal;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What an operator did, summed over its runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub rows: usize,
+    pub loops: u64,
+    pub time: Duration,
+}
+
+/// The metrics of the operators of one plan, as they run.
+#[derive(Debug)]
+pub struct Profile {
+    // by the address of the operator in the plan, which outlives the profile's use
+    operators: Mutex<HashMap<usize, Metrics>>,
+}
+
+impl Profile {
+    /// A profile of the operators of `plan`. Operators of other plans, those of
+    /// subqueries planned as they run, aren't counted.
+    pub fn new(plan: &Plan) -> Profile {
+        let operators = plan
+            .operators()
+            .into_iter()
+            .map(|operator| (address(operator), Metrics::default()))
+            .collect();
+        Profile {
+            operators: Mutex::new(operators),
+        }
+    }
+
+    /// Count a run of `operator` that produced `rows` rows in `time`.
+    pub fn record(&self, operator: &Plan, rows: usize, time: Duration) {
+        if let Some(metrics) = self.operators.lock().unwrap().get_mut(&address(operator)) {
+            metrics.rows += rows;
+            metrics.loops += 1;
+            metrics.time += time;
+        }
+    }
+
+    /// What `operator` has done so far, None if it isn't one of the plan's.
+    pub fn metrics(&self, operator: &Plan) -> Option<Metrics> {
+        self.operators
+            .lock()
+            .unwrap()
+            .get(&address(operator))
+            .copied()
+    }
+
+    /// The plan a row per line, each operator that ran with its metrics.
+    pub fn annotate(&self, plan: &Plan) -> RowSet {
+        let note = |operator: &Plan| match self.metrics(operator) {
+            Some(metrics) if metrics.loops > 0 => format!(
+                " (actual rows={} loops={} time={:.3} ms)",
+                metrics.rows,
+                metrics.loops,
+                metrics.time.as_secs_f64() * 1000.0
+            ),
+            _ => String::new(),
+        };
+        RowSet {
+            columns: vec!["plan".to_string()],
+            rows: plan
+                .tree(&note)
+                .into_iter()
+                .map(|line| vec![ColVal::String(line)])
+                .collect(),
+            changes: None,
+        }
+    }
+}
+
+fn address(operator: &Plan) -> usize {
+    operator as *const Plan as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::{Expr, Limit};
+
+    #[test]
+    fn only_the_plans_operators_are_counted() {
+        let plan = Plan::Limit {
+            input: Box::new(Plan::Scan {
+                table: "t".to_string(),
+            }),
+            limit: Limit {
+                count: Expr::Literal(ColVal::Int(1)),
+                offset: None,
+            },
+        };
+        let profile = Profile::new(&plan);
+        let Plan::Limit { input, .. } = &plan else {
+            unreachable!()
+        };
+        profile.record(input, 3, Duration::from_millis(2));
+        profile.record(input, 4, Duration::from_millis(1));
+        let elsewhere = plan.clone();
+        profile.record(&elsewhere, 1, Duration::from_millis(1));
+
+        assert_eq!(
+            profile.metrics(input),
+            Some(Metrics {
+                rows: 7,
+                loops: 2,
+                time: Duration::from_millis(3),
+            })
+        );
+        assert_eq!(profile.metrics(&elsewhere), None);
+        let line = |line: &str| vec![ColVal::String(line.to_string())];
+        assert_eq!(
+            profile.annotate(&plan).rows,
+            [
+                line("LIMIT 1"),
+                line("└── SCAN t (actual rows=7 loops=2 time=3.000 ms)"),
+            ]
+        );
+    }
+}
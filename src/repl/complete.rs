@@ -0,0 +1,166 @@
+/*
+    What tab completes at the prompt: SQL keywords, the shell's own commands, and the
+    names of the tables, views and columns of the database as it is now. The names are
+    read from the schema when the shell starts and again after every change to it, by a
+    hook on the schema (see Schema::on_change), so that a table just created completes,
+    whether it was created at the prompt or by another connection. How a word is
+    completed from these is in editor.rs.
+*/
+use crate::executor::Executor;
+use crate::planner::Catalog;
+use crate::schema::Schema;
+use std::sync::{Arc, Mutex};
+
+const KEYWORDS: &[&str] = &[
+    "ALTER",
+    "ANALYZE",
+    "AND",
+    "AS",
+    "ASC",
+    "ATTACH",
+    "AUTOINCREMENT",
+    "BEGIN",
+    "BETWEEN",
+    "BY",
+    "CASE",
+    "CHECK",
+    "COLLATE",
+    "COMMIT",
+    "COUNT",
+    "CREATE",
+    "CROSS",
+    "DATABASE",
+    "DEFAULT",
+    "DELETE",
+    "DESC",
+    "DETACH",
+    "DISTINCT",
+    "DROP",
+    "ELSE",
+    "END",
+    "EXCLUSIVE",
+    "EXISTS",
+    "EXPLAIN",
+    "FOREIGN",
+    "FROM",
+    "GROUP",
+    "HAVING",
+    "IMMEDIATE",
+    "IN",
+    "INDEX",
+    "INNER",
+    "INSERT",
+    "INTEGER",
+    "INTO",
+    "IS",
+    "JOIN",
+    "KEY",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "NOT",
+    "NULL",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "PLAN",
+    "PRAGMA",
+    "PRIMARY",
+    "QUERY",
+    "REAL",
+    "REFERENCES",
+    "REINDEX",
+    "RELEASE",
+    "ROLLBACK",
+    "ROWID",
+    "SAVEPOINT",
+    "SELECT",
+    "SET",
+    "TABLE",
+    "TEXT",
+    "THEN",
+    "TO",
+    "TRANSACTION",
+    "TRIGGER",
+    "UNIQUE",
+    "UPDATE",
+    "VACUUM",
+    "VALUES",
+    "VIEW",
+    "WHEN",
+    "WHERE",
+    "WITHOUT",
+];
+
+/// The words tab may complete to, kept up to date with the schema of an executor.
+pub struct Completions {
+    // the keywords and the shell's own commands
+    fixed: Vec<String>,
+    // the names of the tables, views and columns, as of the last change to the schema
+    names: Arc<Mutex<Vec<String>>>,
+}
+
+impl Completions {
+    /// The words of `executor`'s schema, which it refreshes after every change, and
+    /// `commands`, the shell's own.
+    pub fn new<'c>(executor: &mut Executor, commands: impl IntoIterator<Item = &'c str>) -> Self {
+        let mut fixed: Vec<String> = KEYWORDS.iter().map(|k| k.to_string()).collect();
+        fixed.extend(commands.into_iter().map(str::to_string));
+        let names = Arc::new(Mutex::new(schema_names(executor.schema())));
+        let refreshed = names.clone();
+        executor.on_schema_change(move |schema| *refreshed.lock().unwrap() = schema_names(schema));
+        Completions { fixed, names }
+    }
+
+    /// Every word tab may complete to, in order.
+    pub fn words(&self) -> Vec<String> {
+        let mut words = self.fixed.clone();
+        words.extend(self.names.lock().unwrap().iter().cloned());
+        words.sort();
+        words.dedup();
+        words
+    }
+}
+
+// The names of the tables and views of `schema` and of their columns.
+fn schema_names(schema: &Schema) -> Vec<String> {
+    let mut names = vec![];
+    for table in schema.table_names() {
+        if let Ok(columns) = schema.table_columns(table) {
+            names.extend(columns);
+        }
+        names.push(table.to_string());
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_catalog_names_complete() {
+        let mut executor = Executor::default();
+        executor
+            .execute_sql("CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT);")
+            .unwrap();
+        let completions = Completions::new(&mut executor, [".tables"]);
+        let words = completions.words();
+        for word in ["SELECT", ".tables", "users", "id", "email"] {
+            assert!(words.iter().any(|w| w == word), "{word}");
+        }
+
+        // a table and a view created since
+        executor
+            .execute_sql("CREATE TABLE orders (total REAL);")
+            .unwrap();
+        executor
+            .execute_sql("CREATE VIEW big (amount) AS SELECT total FROM orders;")
+            .unwrap();
+        let words = completions.words();
+        for word in ["orders", "total", "big", "amount"] {
+            assert!(words.iter().any(|w| w == word), "{word}");
+        }
+    }
+}
@@ -0,0 +1,91 @@
+/*
+    The `.demo` command, a small shop to try queries on without writing any DDL first.
+
+    It creates three tables, users, products and orders, with a few rows in each and an
+    index or two, by running ordinary SQL through the executor exactly as though it had
+    been typed at the prompt. So what it builds is no different from anything else in the
+    database and can be changed or added to in the same way.
+*/
+use crate::executor::Executor;
+use crate::sql_parser::commands;
+use anyhow::{anyhow, Result};
+
+const SCRIPT: &str = r#"
+CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, email TEXT COLLATE NOCASE, age INTEGER);
+CREATE UNIQUE INDEX idx_users_email ON users (email);
+CREATE TABLE products (id INTEGER PRIMARY KEY, name TEXT, price INTEGER);
+CREATE TABLE orders (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER,
+    product_id INTEGER,
+    quantity INTEGER,
+    FOREIGN KEY (user_id) REFERENCES users (id),
+    FOREIGN KEY (product_id) REFERENCES products (id)
+);
+CREATE INDEX idx_orders_user ON orders (user_id);
+
+INSERT INTO users (name, email, age) VALUES ("amy", "amy@example.com", 34);
+INSERT INTO users (name, email, age) VALUES ("bob", "bob@example.com", 27);
+INSERT INTO users (name, email, age) VALUES ("cat", "cat@example.com", 41);
+INSERT INTO users (name, email, age) VALUES ("dan", "dan@example.com", 19);
+INSERT INTO users (name, email, age) VALUES ("eve", "eve@example.com", 30);
+
+INSERT INTO products (name, price) VALUES ("kettle", 25);
+INSERT INTO products (name, price) VALUES ("teapot", 18);
+INSERT INTO products (name, price) VALUES ("mug", 6);
+INSERT INTO products (name, price) VALUES ("tea", 4);
+
+INSERT INTO orders (user_id, product_id, quantity) VALUES (1, 1, 1);
+INSERT INTO orders (user_id, product_id, quantity) VALUES (1, 4, 3);
+INSERT INTO orders (user_id, product_id, quantity) VALUES (2, 3, 2);
+INSERT INTO orders (user_id, product_id, quantity) VALUES (3, 2, 1);
+INSERT INTO orders (user_id, product_id, quantity) VALUES (3, 3, 4);
+INSERT INTO orders (user_id, product_id, quantity) VALUES (3, 4, 10);
+INSERT INTO orders (user_id, product_id, quantity) VALUES (5, 4, 1);
+"#;
+
+/// Printed once the tables are made, to get started.
+pub const WELCOME: &str = "\
+Created the tables users, products and orders. Try
+  SELECT name, age FROM users WHERE age > 30;
+  SELECT u.name FROM users u WHERE EXISTS (SELECT 1 FROM orders o WHERE o.user_id = u.id);
+  EXPLAIN QUERY PLAN SELECT quantity FROM orders WHERE user_id = 3;";
+
+/// Create and fill the demo tables, stopping at the first statement that fails, say
+/// because there is already a table called users.
+pub fn load(executor: &mut Executor) -> Result<()> {
+    for sql in commands(SCRIPT) {
+        executor
+            .execute_sql(&sql)
+            .map_err(|err| anyhow!("cannot load the demo: {err}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_demo_tables_can_be_queried() {
+        let mut executor = Executor::default();
+        load(&mut executor).unwrap();
+        let query =
+            |executor: &mut Executor, sql: &str| executor.execute_sql(sql).unwrap().to_string();
+        assert_eq!(query(&mut executor, "SELECT count(*) FROM orders;"), "7");
+        assert_eq!(
+            query(
+                &mut executor,
+                "SELECT u.name FROM users u WHERE NOT EXISTS \
+                 (SELECT 1 FROM orders o WHERE o.user_id = u.id);"
+            ),
+            "dan"
+        );
+
+        // loading it twice finds the tables already there
+        assert_eq!(
+            load(&mut executor).unwrap_err().to_string(),
+            "cannot load the demo: table users already exists"
+        );
+    }
+}
@@ -0,0 +1,324 @@
+/*
+    Reading a line at the prompt, with the editing a shell is expected to have.
+
+    At a terminal the line is read a key at a time with the terminal in raw mode (see the
+    console crate), and edited in place:
+
+        left, right, home, end, ^A, ^E    move along the line
+        backspace, delete, ^D             delete a character
+        up, down                          step back and forth through the history
+        tab                               complete the word before the cursor
+        ^C                                drop the line and start a new one
+        ^D on an empty line               end of input, as it is for sqlite3
+
+    ^C while a statement runs, when no line is being read, interrupts the statement
+    instead, see RUNNING in mod.rs.
+
+    The history is every line entered, oldest first, kept in ~/.sqlite_clone_history a
+    line at a time so that the next session starts with it. A line is added once it is
+    entered, unless it is blank or the same as the one before it, and only the last
+    MAX_HISTORY lines are read back.
+
+    Tab completes the word before the cursor from the words it is given (see
+    complete.rs), ignoring ASCII case. A word only one of them starts is completed and
+    followed by a space; if several do, the word is taken as far as they agree and, if
+    that is no further, they are listed under the line. A keyword takes the case of a
+    word typed in lower case, so that "sel" completes to "select".
+
+    When stdin isn't a terminal, such as a script piped in, lines are read as they come
+    with no editing and no history.
+*/
+use anyhow::{Context, Result};
+use console::{Key, Term};
+use std::fs::OpenOptions;
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+const HISTORY_FILE: &str = ".sqlite_clone_history";
+const MAX_HISTORY: usize = 1000;
+
+/// What reading a line came to.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    Line(String),
+    /// ^C, the line given up on.
+    Interrupted,
+    Eof,
+}
+
+#[derive(Debug)]
+pub struct Editor {
+    history: Vec<String>,
+    path: Option<PathBuf>,
+    terminal: bool,
+}
+
+impl Editor {
+    /// An editor with the history in the user's home directory.
+    pub fn new() -> Self {
+        let path = std::env::var_os("HOME").map(|home| Path::new(&home).join(HISTORY_FILE));
+        Editor::with_history(path)
+    }
+
+    /// An editor with the history in `path`, or none kept at all.
+    pub fn with_history(path: Option<PathBuf>) -> Self {
+        let mut history: Vec<String> = path
+            .as_deref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        history.drain(..history.len().saturating_sub(MAX_HISTORY));
+        Editor {
+            history,
+            path,
+            terminal: std::io::stdin().is_terminal(),
+        }
+    }
+
+    /// Read a line after printing `prompt`, tab completing from `words`.
+    pub fn read_line(&mut self, prompt: &str, words: &[String]) -> Result<Input> {
+        let mut stdout = std::io::stdout();
+        if !self.terminal {
+            write!(stdout, "{prompt}").context("failed to write prompt to std out")?;
+            stdout.flush().context("failed to flush std out")?;
+            let mut line = String::new();
+            let read = std::io::stdin()
+                .lock()
+                .read_line(&mut line)
+                .context("failed to read line from stdin")?;
+            return Ok(match read {
+                0 => Input::Eof,
+                _ => Input::Line(line),
+            });
+        }
+        let term = Term::stdout();
+        let input = self.edit(prompt, words, || term.read_key_raw(), &mut stdout)?;
+        if let Input::Line(line) = &input {
+            self.add_history(line)?;
+        }
+        Ok(input)
+    }
+
+    // Edit a line with the keys `read_key` gives, drawing it on `out` as it changes.
+    fn edit(
+        &self,
+        prompt: &str,
+        words: &[String],
+        mut read_key: impl FnMut() -> std::io::Result<Key>,
+        out: &mut impl Write,
+    ) -> Result<Input> {
+        let mut line: Vec<char> = vec![];
+        let mut cursor = 0;
+        // where in the history the line came from, history.len() for a new line, and
+        // the new line while an old one is shown
+        let mut entry = self.history.len();
+        let mut draft: Vec<char> = vec![];
+        loop {
+            let text: String = line.iter().collect();
+            write!(out, "\r\x1b[2K{prompt}{text}")?;
+            if cursor < line.len() {
+                write!(out, "\x1b[{}D", line.len() - cursor)?;
+            }
+            out.flush()?;
+            match read_key()? {
+                Key::Enter => {
+                    writeln!(out)?;
+                    return Ok(Input::Line(line.into_iter().collect()));
+                }
+                Key::CtrlC => {
+                    writeln!(out, "^C")?;
+                    return Ok(Input::Interrupted);
+                }
+                Key::Char('\u{4}') if line.is_empty() => {
+                    writeln!(out)?;
+                    return Ok(Input::Eof);
+                }
+                Key::Char('\u{4}') | Key::Del if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::ArrowLeft if cursor > 0 => cursor -= 1,
+                Key::ArrowRight if cursor < line.len() => cursor += 1,
+                Key::Home | Key::Char('\u{1}') => cursor = 0,
+                Key::End | Key::Char('\u{5}') => cursor = line.len(),
+                Key::ArrowUp if entry > 0 => {
+                    if entry == self.history.len() {
+                        draft = std::mem::take(&mut line);
+                    }
+                    entry -= 1;
+                    line = self.history[entry].chars().collect();
+                    cursor = line.len();
+                }
+                Key::ArrowDown if entry < self.history.len() => {
+                    entry += 1;
+                    line = match self.history.get(entry) {
+                        Some(old) => old.chars().collect(),
+                        None => std::mem::take(&mut draft),
+                    };
+                    cursor = line.len();
+                }
+                Key::Tab => {
+                    let start = line[..cursor]
+                        .iter()
+                        .rposition(|c| !is_word_char(*c))
+                        .map_or(0, |i| i + 1);
+                    let word: String = line[start..cursor].iter().collect();
+                    let (completed, candidates) = complete(&word, words);
+                    if completed.chars().count() > word.chars().count() {
+                        line.splice(start..cursor, completed.chars());
+                        cursor = start + completed.chars().count();
+                    } else if candidates.len() > 1 {
+                        writeln!(out)?;
+                        writeln!(out, "{}", candidates.join("  "))?;
+                    }
+                }
+                Key::Char(c) if !c.is_control() => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn add_history(&mut self, line: &str) -> Result<()> {
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return Ok(());
+        }
+        self.history.push(line.to_string());
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("cannot open \"{}\"", path.display()))?;
+        writeln!(file, "{line}").with_context(|| format!("cannot write \"{}\"", path.display()))
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+// `word` taken as far as the words it starts agree, followed by a space if there is
+// only one, and those words.
+fn complete(word: &str, words: &[String]) -> (String, Vec<String>) {
+    if word.is_empty() {
+        return (String::new(), vec![]);
+    }
+    let lower = word.chars().all(|c| !c.is_ascii_uppercase());
+    let candidates: Vec<String> = words
+        .iter()
+        .filter(|w| {
+            w.len() >= word.len()
+                && w.is_char_boundary(word.len())
+                && w[..word.len()].eq_ignore_ascii_case(word)
+        })
+        .map(
+            |w| match lower && !w.chars().any(|c| c.is_ascii_lowercase()) {
+                true => w.to_ascii_lowercase(),
+                false => w.clone(),
+            },
+        )
+        .collect();
+    let Some(first) = candidates.first() else {
+        return (word.to_string(), candidates);
+    };
+    if candidates.len() == 1 {
+        return (format!("{first} "), candidates);
+    }
+    let mut common = first.chars().count();
+    for other in &candidates[1..] {
+        common = first
+            .chars()
+            .zip(other.chars())
+            .take(common)
+            .take_while(|(a, b)| a.eq_ignore_ascii_case(b))
+            .count();
+    }
+    // the word as typed, and what they all have after it
+    let rest: String = first
+        .chars()
+        .take(common)
+        .skip(word.chars().count())
+        .collect();
+    (format!("{word}{rest}"), candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(editor: &Editor, keys: Vec<Key>) -> Input {
+        let mut keys = keys.into_iter();
+        let mut out = vec![];
+        editor
+            .edit(
+                "$ ",
+                &["SELECT".to_string(), "SET".to_string(), "users".to_string()],
+                || Ok(keys.next().expect("a key")),
+                &mut out,
+            )
+            .unwrap()
+    }
+
+    fn typed(text: &str) -> Vec<Key> {
+        text.chars().map(Key::Char).collect()
+    }
+
+    #[test]
+    fn lines_are_edited_in_place() {
+        let editor = Editor::with_history(None);
+        let mut keys = typed("SELEC 1");
+        keys.extend([Key::ArrowLeft, Key::ArrowLeft, Key::Char('T')]);
+        keys.extend([Key::Home, Key::Del, Key::Char('s'), Key::End]);
+        keys.extend([Key::Backspace, Key::Char('2'), Key::Enter]);
+        assert_eq!(edit(&editor, keys), Input::Line("sELECT 2".to_string()));
+
+        let mut keys = typed("half");
+        keys.push(Key::CtrlC);
+        assert_eq!(edit(&editor, keys), Input::Interrupted);
+        assert_eq!(edit(&editor, vec![Key::Char('\u{4}')]), Input::Eof);
+    }
+
+    #[test]
+    fn history_is_kept_across_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(HISTORY_FILE);
+        let mut editor = Editor::with_history(Some(path.clone()));
+        for line in ["SELECT 1;", "SELECT 1;", " ", "SELECT 2;"] {
+            editor.add_history(line).unwrap();
+        }
+
+        let editor = Editor::with_history(Some(path));
+        assert_eq!(editor.history, ["SELECT 1;", "SELECT 2;"]);
+        let mut keys = typed("draft");
+        keys.extend([Key::ArrowUp, Key::ArrowUp, Key::ArrowUp, Key::Enter]);
+        assert_eq!(edit(&editor, keys), Input::Line("SELECT 1;".to_string()));
+        let mut keys = typed("draft");
+        keys.extend([Key::ArrowUp, Key::ArrowDown, Key::Enter]);
+        assert_eq!(edit(&editor, keys), Input::Line("draft".to_string()));
+    }
+
+    #[test]
+    fn tab_completes_the_word_before_the_cursor() {
+        let editor = Editor::with_history(None);
+        let mut keys = typed("sel");
+        keys.extend([Key::Tab, Key::Char('*'), Key::Char(' '), Key::Char('U')]);
+        keys.extend([Key::Tab, Key::Enter]);
+        assert_eq!(
+            edit(&editor, keys),
+            Input::Line("select * users ".to_string())
+        );
+
+        // as far as SELECT and SET agree, and no further
+        let mut keys = typed("S");
+        keys.extend([Key::Tab, Key::Tab, Key::Enter]);
+        assert_eq!(edit(&editor, keys), Input::Line("SE".to_string()));
+    }
+}
@@ -1,30 +1,210 @@
-use anyhow::{anyhow, bail, Result};
-use derive_more::Display;
-use log::debug;
-use std::str::FromStr;
-
-#[derive(Debug, Display)]
-enum Metacommand {
-    Tables,
-    Schema,
-    Indexes,
-}
-
-impl FromStr for Metacommand {
-    type Err = anyhow::Error;
-
-    fn from_str(s: &str) -> Result<Self> {
-        debug!("{}", s);
-        match s {
-            ".tables" => Ok(Metacommand::Tables),
-            ".schema" => Ok(Metacommand::Schema),
-            ".indexes" => Ok(Metacommand::Indexes),
-            _ => bail!("Failed to parse metacommand"),
+use crate::catalog::{CatalogEntry, EntryKind, MASTER_TABLE};
+use crate::executor::Executor;
+use crate::planner::Catalog;
+use crate::storage::memdb::OpenTarget;
+use anyhow::Result;
+
+// The entries of sqlite_master, in the order they were made.
+fn catalog(executor: &mut Executor) -> Result<Vec<CatalogEntry>> {
+    executor
+        .execute_sql(&format!("SELECT * FROM {MASTER_TABLE};"))?
+        .rows
+        .iter()
+        .map(|row| CatalogEntry::from_row(row))
+        .collect()
+}
+
+// Names laid out as sqlite3 lays them out, in columns down the page as many as fit in 80
+// characters, each as wide as the longest name.
+fn columns(mut names: Vec<String>) -> String {
+    names.sort();
+    let width = names.iter().map(|n| n.chars().count()).max().unwrap_or(0);
+    let per_line = (80 / (width + 2)).max(1);
+    let lines = names.len().div_ceil(per_line);
+    (0..lines)
+        .map(|line| {
+            let row: Vec<String> = names[line..]
+                .iter()
+                .step_by(lines)
+                .map(|name| format!("{name:<width$}"))
+                .collect();
+            row.join("  ").trim_end().to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// `.tables [TABLE]`, the names of the tables and views, but for SQLite's own, or just
+/// TABLE's if there is one by that name.
+pub fn tables(executor: &mut Executor, table: Option<&str>) -> Result<String> {
+    let names = catalog(executor)?
+        .into_iter()
+        .filter(|e| matches!(e.kind, EntryKind::Table | EntryKind::View))
+        .filter(|e| !e.name.starts_with("sqlite_"))
+        .filter(|e| table.map_or(true, |t| t.eq_ignore_ascii_case(&e.name)))
+        .map(|e| e.name)
+        .collect();
+    Ok(columns(names))
+}
+
+/// `.indexes [TABLE]`, the names of the indexes, those of PRIMARY KEY and UNIQUE
+/// constraints included, or of the indexes on TABLE.
+pub fn indexes(executor: &mut Executor, table: Option<&str>) -> Result<String> {
+    let names = catalog(executor)?
+        .into_iter()
+        .filter(|e| e.kind == EntryKind::Index)
+        .filter(|e| table.map_or(true, |t| t.eq_ignore_ascii_case(&e.table)))
+        .map(|e| e.name)
+        .collect();
+    Ok(columns(names))
+}
+
+/// `.schema [TABLE]`, the CREATE statement of everything in the database, or of TABLE and
+/// its indexes and triggers, as sqlite_master keeps them. Like sqlite3 a view is followed
+/// by a comment naming its columns.
+pub fn schema(executor: &mut Executor, table: Option<&str>) -> Result<String> {
+    let mut statements = vec![];
+    for entry in catalog(executor)? {
+        let Some(sql) = entry.sql else { continue };
+        if table.is_some_and(|t| !t.eq_ignore_ascii_case(&entry.table)) {
+            continue;
+        }
+        if entry.kind == EntryKind::View {
+            if let Ok(columns) = executor.schema().table_columns(&entry.name) {
+                let columns = columns.join(",");
+                statements.push(format!("{sql}\n/* {}({columns}) */;", entry.name));
+                continue;
+            }
         }
+        statements.push(format!("{sql};"));
     }
+    Ok(statements.join("\n"))
 }
 
-pub fn handle_metacommand(cmd: &str) -> Result<String> {
-    let cmd: Metacommand = Metacommand::from_str(cmd)?;
-    return Ok(cmd.to_string());
+/// `.locks`, the lock each connection to main's file holds and what it is waiting for,
+/// one connection to a line, nothing for a database in memory.
+pub fn locks(executor: &Executor) -> String {
+    let lines: Vec<String> = executor
+        .lock_status()
+        .iter()
+        .map(|status| status.to_string())
+        .collect();
+    lines.join("\n")
+}
+
+/// `.databases`, main and each attached database with the file it is in, nothing for one
+/// in memory.
+pub fn databases(executor: &Executor) -> String {
+    let lines: Vec<String> = executor
+        .databases()
+        .iter()
+        .map(|database| match &database.target {
+            OpenTarget::File { path, .. } => format!("{}: {}", database.name, path.display()),
+            OpenTarget::Memory { .. } => format!("{}:", database.name),
+        })
+        .collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schema_prints_the_create_statements() {
+        let mut executor = Executor::default();
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT);",
+            "CREATE INDEX  idx_email ON users (email);",
+            "CREATE TABLE log (x INTEGER);",
+            "CREATE VIEW v AS SELECT id FROM users;",
+            "CREATE TRIGGER tr AFTER INSERT ON users BEGIN INSERT INTO log (x) VALUES (1); END;",
+        ] {
+            executor.execute_sql(sql).unwrap();
+        }
+        assert_eq!(
+            schema(&mut executor, None).unwrap(),
+            "\
+CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT);
+CREATE INDEX idx_email ON users (email);
+CREATE TABLE log (x INTEGER);
+CREATE VIEW v AS SELECT id FROM users
+/* v(id) */;
+CREATE TRIGGER tr AFTER INSERT ON users BEGIN INSERT INTO log (x) VALUES (1); END;"
+        );
+        assert_eq!(
+            schema(&mut executor, Some("USERS"))
+                .unwrap()
+                .lines()
+                .count(),
+            3
+        );
+        assert_eq!(schema(&mut executor, Some("nope")).unwrap(), "");
+    }
+
+    #[test]
+    fn tables_and_indexes_are_listed_from_the_catalog() {
+        let mut executor = Executor::default();
+        assert_eq!(tables(&mut executor, None).unwrap(), "");
+        for sql in [
+            "CREATE TABLE users (email TEXT PRIMARY KEY, name TEXT);",
+            "CREATE INDEX idx_email ON users (email);",
+            "CREATE TABLE log (x INTEGER PRIMARY KEY AUTOINCREMENT);",
+            "CREATE VIEW v AS SELECT name FROM users;",
+            "CREATE INDEX idx_x ON log (x);",
+        ] {
+            executor.execute_sql(sql).unwrap();
+        }
+        // in columns down the page as sqlite3 has them, sqlite_sequence left out
+        assert_eq!(tables(&mut executor, None).unwrap(), "log    users  v");
+        assert_eq!(tables(&mut executor, Some("USERS")).unwrap(), "users");
+        assert_eq!(
+            indexes(&mut executor, None).unwrap(),
+            "idx_email                 idx_x                     sqlite_autoindex_users_1"
+        );
+        assert_eq!(
+            indexes(&mut executor, Some("users")).unwrap(),
+            "idx_email                 sqlite_autoindex_users_1"
+        );
+
+        let names = (0..12).map(|i| format!("table_{i:02}")).collect();
+        assert_eq!(
+            columns(names),
+            "\
+table_00  table_02  table_04  table_06  table_08  table_10
+table_01  table_03  table_05  table_07  table_09  table_11"
+        );
+    }
+
+    #[test]
+    fn locks_shows_each_connection_to_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main.db");
+        let open = || Executor::open(OpenTarget::parse(path.to_str().unwrap()).unwrap()).unwrap();
+        let mut first = open();
+        first.execute_sql("CREATE TABLE t (a INTEGER);").unwrap();
+        let second = open();
+        // between statements neither holds a lock
+        let lines = locks(&second);
+        assert_eq!(lines.lines().count(), 2, "{lines}");
+        assert!(lines.lines().all(|l| l.ends_with(": UNLOCKED")), "{lines}");
+        assert_eq!(locks(&first), lines);
+        drop(first);
+        assert_eq!(locks(&second).lines().count(), 1);
+        assert_eq!(locks(&Executor::default()), "");
+    }
+
+    #[test]
+    fn databases_lists_main_and_the_attached() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.db");
+        let mut executor = Executor::default();
+        executor
+            .execute_sql(&format!("ATTACH \"{}\" AS archive;", path.display()))
+            .unwrap();
+        assert_eq!(
+            databases(&executor),
+            format!("main:\narchive: {}", path.display())
+        );
+    }
 }
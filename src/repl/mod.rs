@@ -1,49 +1,280 @@
+mod complete;
+mod demo;
+mod editor;
 mod metacommand;
+mod pager;
+mod render;
+mod report;
 
-use crate::repl::metacommand::handle_metacommand;
-use anyhow::{anyhow, bail, Context, Result};
-use clap::Command;
+use crate::cli::dump;
+use crate::cli::import::{self, ImportOptions};
+use crate::cli::trace;
+use crate::executor::Executor;
+use crate::interrupt::InterruptHandle;
+use crate::pragma;
+use crate::repl::complete::Completions;
+use crate::repl::editor::{Editor, Input};
+use crate::repl::pager::Pager;
+use crate::repl::render::{Mode, Render};
+use crate::replication::{self, Follower};
+use crate::sql_parser::{is_complete, numbered_commands, split_statements};
+use crate::storage::cache::CacheStats;
+use crate::storage::memdb::OpenTarget;
+use anyhow::{bail, Context, Result};
+use clap::{Arg, ArgAction, Command};
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once};
+use std::time::Instant;
 
-pub fn repl_loop() -> Result<()> {
+// Read at startup when no --init file is given, like sqlite3's ~/.sqliterc.
+const RC_FILE: &str = ".sqliteclonerc";
+// The prompt for the next line of a statement, sqlite3's.
+const CONTINUATION_PROMPT: &str = "   ...> ";
+
+// What ^C interrupts while a statement runs, see interrupt.rs. With none running it
+// ends the shell, as it would have without a handler; at the prompt the terminal is in
+// raw mode and ^C is a key, see editor.rs.
+static RUNNING: Mutex<Option<InterruptHandle>> = Mutex::new(None);
+static CTRL_C: Once = Once::new();
+
+fn handle_ctrl_c() {
+    CTRL_C.call_once(|| {
+        std::thread::spawn(|| {
+            let Ok(runtime) = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            else {
+                return;
+            };
+            runtime.block_on(async {
+                while tokio::signal::ctrl_c().await.is_ok() {
+                    match RUNNING.lock().expect("not poisoned").as_ref() {
+                        Some(statement) => statement.interrupt(),
+                        None => std::process::exit(130),
+                    }
+                }
+            })
+        });
+    });
+}
+
+// What the shell's own commands have set.
+#[derive(Debug, Default)]
+struct Settings {
+    pager: Pager,
+    render: Render,
+    // .timer on, which prints after each statement how long it took in wall clock time
+    // and, from the counters of the files' caches, how many pages it read and found in
+    // them: loading or saving the files' trees, as rows are read from memory once loaded
+    timer: bool,
+    // the leader the database is a replica of since .replicate, see replication.rs
+    follower: Option<Follower>,
+    // .verbose on, which shows the kind and code of each error and all of its causes,
+    // see report.rs
+    verbose: bool,
+}
+
+/// Run the init file, then read commands from stdin until `.exit` or the end of input,
+/// see editor.rs for how a line is read.
+pub fn repl_loop(mut executor: Executor, init: Option<PathBuf>) -> Result<()> {
+    let init = init.or_else(|| {
+        let rc = Path::new(&std::env::var_os("HOME")?).join(RC_FILE);
+        rc.exists().then_some(rc)
+    });
+    handle_ctrl_c();
+    let mut settings = Settings::default();
+    if let Some(path) = init {
+        if read_file(&mut executor, &mut settings, &path)? {
+            return Ok(());
+        }
+    }
+
+    let mut editor = Editor::new();
+    let commands = cli();
+    let completions = Completions::new(
+        &mut executor,
+        commands.get_subcommands().map(|c| c.get_name()),
+    );
+    // the lines of a statement that hasn't reached its ; yet
+    let mut sql = String::new();
     loop {
-        let line: String = readline()?;
+        let prompt = if sql.is_empty() {
+            "$ "
+        } else {
+            CONTINUATION_PROMPT
+        };
+        let words = completions.words();
+        let line = match editor.read_line(prompt, &words)? {
+            Input::Line(line) => line,
+            Input::Interrupted => {
+                sql.clear();
+                continue;
+            }
+            Input::Eof if sql.is_empty() => break,
+            Input::Eof => std::mem::take(&mut sql),
+        };
         let line: &str = line.trim();
-        if line.is_empty() {
+        if line.is_empty() || (sql.is_empty() && line.starts_with("--")) {
             continue;
         }
-
-        match respond(line) {
-            Ok(quit) => {
-                if quit {
-                    break;
-                }
+        // a command of the shell's own is a line, SQL goes on until its ;, and each of
+        // the statements it holds runs in turn
+        let lines = if sql.is_empty() && (line.starts_with('.') || line == "ping") {
+            vec![line.to_string()]
+        } else {
+            if !sql.is_empty() {
+                sql.push('\n');
             }
-            Err(err) => {
-                write!(std::io::stdout(), "{err}").context("failed to write err to std out")?;
+            sql.push_str(line);
+            if !is_complete(&sql) {
+                continue;
+            }
+            let sql = std::mem::take(&mut sql);
+            split_statements(&sql)
+                .into_iter()
+                .map(str::to_string)
+                .collect()
+        };
 
-                std::io::stdout()
-                    .flush()
-                    .context("failed to flush std out")?;
+        for line in &lines {
+            match respond(&mut executor, &mut settings, line) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(err) => writeln!(
+                    std::io::stderr(),
+                    "{}",
+                    report::describe(&err, settings.verbose)
+                )
+                .context("failed to write err to std err")?,
             }
         }
     }
     Ok(())
 }
 
-fn readline() -> Result<String> {
-    write!(std::io::stdout(), "\n$ ").context("failed to write $ to stdout")?;
-    std::io::stdout()
-        .flush()
-        .context("failed to flush std out")?;
-    let mut buffer: String = String::new();
-    std::io::stdin()
-        .read_line(&mut buffer)
-        .context("failed to read line from stdin")?;
-    Ok(buffer)
+/// Run the commands of `script` one after another with no prompt, for the command line's
+/// batch mode, stopping at the first that fails with an error naming the line it starts
+/// on.
+pub fn run_batch(executor: &mut Executor, script: &str) -> Result<()> {
+    handle_ctrl_c();
+    let mut settings = Settings::default();
+    for (line, command) in numbered_commands(script) {
+        if respond(executor, &mut settings, &command)
+            .with_context(|| format!("near line {line}"))?
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Run the commands in a file as though they were typed at the prompt, returning true if
+/// one of them was `.exit`. A failing command is reported with the line it starts on and
+/// the rest still run, as sqlite3 does without -bail.
+fn read_file(executor: &mut Executor, settings: &mut Settings, path: &Path) -> Result<bool> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("cannot open \"{}\"", path.display()))?;
+    for (line, command) in numbered_commands(&contents) {
+        match respond(executor, settings, &command) {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(err) => {
+                let err = err.context(format!("{} near line {line}", path.display()));
+                writeln!(
+                    std::io::stderr(),
+                    "{}",
+                    report::describe(&err, settings.verbose)
+                )
+                .context("failed to write err to std err")?
+            }
+        }
+    }
+    Ok(false)
+}
+
+// Run `sql` and print its rows, each as soon as it is read unless the mode is table, whose
+// columns are as wide as their longest value, or the pager is on, which has to know how
+// many lines there are. Returns how many rows it changed if it was a write.
+fn print_result(executor: &mut Executor, settings: &Settings, sql: &str) -> Result<Option<u64>> {
+    let render = &settings.render;
+    if settings.pager.enabled || !render.streams() {
+        let result = executor.execute_sql(sql)?;
+        let output = render.render(&result);
+        if !output.is_empty() {
+            settings.pager.print(&output)?;
+            writeln!(std::io::stdout()).context("failed to write to std out")?;
+        }
+        return Ok(result.changes);
+    }
+    let mut rows = executor.query_sql(sql)?;
+    let columns = rows.columns().to_vec();
+    let mut stdout = std::io::stdout().lock();
+    let mut printed = 0;
+    let mut failed = None;
+    for row in rows.by_ref() {
+        match row {
+            Ok(row) => {
+                write!(stdout, "{}", render.row(&columns, row.values(), printed))
+                    .context("failed to write to std out")?;
+                printed += 1;
+            }
+            Err(err) => {
+                failed = Some(err);
+                break;
+            }
+        }
+    }
+    // the rows before a failure stay printed, as in sqlite3
+    if printed > 0 {
+        writeln!(stdout, "{}", render.end()).context("failed to write to std out")?;
+    }
+    match failed {
+        Some(err) => Err(err),
+        None => Ok(rows.changes()),
+    }
 }
 
-fn respond(line: &str) -> Result<bool> {
+fn respond(executor: &mut Executor, settings: &mut Settings, line: &str) -> Result<bool> {
+    // a replica is brought up to the latest commit to arrive before each command
+    if let Some(follower) = &settings.follower {
+        let newer = follower.newer();
+        if newer.is_err() {
+            settings.follower = None;
+        }
+        if let Some(replica) = newer? {
+            *executor = replica;
+        }
+    }
+    // anything that isn't a command of the shell's own is SQL
+    if !line.starts_with('.') && line != "ping" {
+        let started = Instant::now();
+        let before = executor.cache_report().total();
+        *RUNNING.lock().expect("not poisoned") = Some(executor.interrupt_handle());
+        let changes = print_result(executor, settings, line);
+        *RUNNING.lock().expect("not poisoned") = None;
+        let changes = changes?;
+        let elapsed = started.elapsed();
+        let after = executor.cache_report().total();
+        if let Some(changes) = changes {
+            let rows = if changes == 1 { "row" } else { "rows" };
+            writeln!(std::io::stdout(), "{changes} {rows} affected")
+                .context("failed to write to std out")?;
+        }
+        if settings.timer {
+            // saturating, as PRAGMA can reset the counters
+            let read = |stats: &CacheStats| stats.faults + stats.read_ahead;
+            writeln!(
+                std::io::stdout(),
+                "Run Time: real {:.3} pages read {} cache hits {}",
+                elapsed.as_secs_f64(),
+                read(&after).saturating_sub(read(&before)),
+                after.hits.saturating_sub(before.hits)
+            )
+            .context("failed to write to std out")?;
+        }
+        return Ok(false);
+    }
     let args: Vec<String> = shlex::split(line)
         //.ok_or("error: Invalid quoting")
         .context("invalid quoting on args")?;
@@ -53,28 +284,142 @@ fn respond(line: &str) -> Result<bool> {
 
     match matches.subcommand() {
         Some(("ping", _matches)) => {
-            write!(std::io::stdout(), "Pong").context("failed to write to std out")?;
+            writeln!(std::io::stdout(), "Pong").context("failed to write to std out")?;
             std::io::stdout()
                 .flush()
                 .context("failed to flush std out")?;
         }
         Some((".exit", _matches)) => {
-            write!(std::io::stdout(), "Exiting ...").context("failed to write to std out")?;
+            writeln!(std::io::stdout(), "Exiting ...").context("failed to write to std out")?;
             std::io::stdout()
                 .flush()
                 .context("failed to flush std out")?;
             return Ok(true);
         }
-        Some((cmd, _matches)) if cmd.starts_with('.') => {
-            write!(std::io::stdout(), "calling metacommand: \n")
+        Some((".demo", _matches)) => {
+            demo::load(executor)?;
+            writeln!(std::io::stdout(), "{}", demo::WELCOME)
                 .context("failed to write to std out")?;
-            let res = &handle_metacommand(cmd)?;
-            write!(std::io::stdout(), "{res}").context("failed to flush std out")?;
             std::io::stdout()
                 .flush()
                 .context("failed to flush std out")?;
         }
-        Some((name, _matches)) => unimplemented!("{name}"),
+        Some((".tables", matches)) => {
+            let table = matches.get_one::<String>("table").map(String::as_str);
+            let tables = metacommand::tables(executor, table)?;
+            if !tables.is_empty() {
+                writeln!(std::io::stdout(), "{tables}").context("failed to write to std out")?;
+            }
+        }
+        Some((".indexes", matches)) => {
+            let table = matches.get_one::<String>("table").map(String::as_str);
+            let indexes = metacommand::indexes(executor, table)?;
+            if !indexes.is_empty() {
+                writeln!(std::io::stdout(), "{indexes}").context("failed to write to std out")?;
+            }
+        }
+        Some((".schema", matches)) => {
+            let table = matches.get_one::<String>("table").map(String::as_str);
+            let schema = metacommand::schema(executor, table)?;
+            if !schema.is_empty() {
+                writeln!(std::io::stdout(), "{schema}").context("failed to write to std out")?;
+            }
+        }
+        Some((".dump", matches)) => {
+            let table = matches.get_one::<String>("table").map(String::as_str);
+            write!(std::io::stdout(), "{}", dump::dump(executor, table)?)
+                .context("failed to write to std out")?;
+        }
+        Some((".import", matches)) => {
+            let path = matches.get_one::<String>("file").expect("file is required");
+            let table = matches
+                .get_one::<String>("table")
+                .expect("table is required");
+            let options = ImportOptions {
+                separator: *matches.get_one::<char>("separator").expect("a default"),
+                skip: *matches.get_one::<usize>("skip").expect("a default"),
+            };
+            let csv =
+                std::fs::File::open(path).with_context(|| format!("cannot open \"{path}\""))?;
+            import::import(executor, std::io::BufReader::new(csv), table, &options)
+                .with_context(|| format!("cannot import \"{path}\""))?;
+        }
+        Some((".databases", _matches)) => {
+            writeln!(std::io::stdout(), "{}", metacommand::databases(executor))
+                .context("failed to write to std out")?;
+        }
+        Some((".cachestats", _matches)) => {
+            writeln!(std::io::stdout(), "{}", executor.cache_report())
+                .context("failed to write to std out")?;
+        }
+        Some((".locks", _matches)) => {
+            writeln!(std::io::stdout(), "{}", metacommand::locks(executor))
+                .context("failed to write to std out")?;
+        }
+        Some((".stats", _matches)) => {
+            let stats = pragma::stats(&executor.cache_report());
+            writeln!(std::io::stdout(), "{stats}").context("failed to write to std out")?;
+        }
+        Some((".open", matches)) => {
+            settings.follower = None;
+            *executor = match matches.get_one::<String>("file") {
+                Some(file) => Executor::open(OpenTarget::parse(file)?)?,
+                None => Executor::default(),
+            };
+        }
+        Some((".replicate", matches)) => {
+            let leader = matches
+                .get_one::<String>("leader")
+                .expect("leader is required");
+            let (replica, follower) = replication::follow(leader)?;
+            *executor = replica;
+            settings.follower = Some(follower);
+        }
+        Some((".read", matches)) => {
+            let path = matches.get_one::<String>("file").expect("file is required");
+            return read_file(executor, settings, Path::new(path));
+        }
+        Some((".mode", matches)) => {
+            let mode = matches.get_one::<String>("mode").expect("mode is required");
+            settings.render.set_mode(mode.parse::<Mode>()?);
+        }
+        Some((".headers", matches)) => {
+            settings.render.headers =
+                matches.get_one::<String>("mode").expect("mode is required") == "on";
+        }
+        Some((".width", matches)) => {
+            let widths = matches.get_many::<i64>("widths").into_iter().flatten();
+            settings.render.widths = widths.copied().collect();
+        }
+        Some((".nullvalue", matches)) => {
+            let null = matches.get_one::<String>("text").expect("text is required");
+            settings.render.null_value = null.clone();
+        }
+        Some((".separator", matches)) => {
+            let separator = matches.get_one::<String>("separator").expect("a separator");
+            settings.render.separator = unescape(separator);
+        }
+        Some((".trace", matches)) => {
+            trace::set(
+                matches
+                    .get_one::<String>("filter")
+                    .expect("filter is required"),
+            )?;
+        }
+        Some((".verbose", matches)) => {
+            settings.verbose = matches.get_one::<String>("mode").expect("mode is required") == "on";
+        }
+        Some((".timer", matches)) => {
+            settings.timer = matches.get_one::<String>("mode").expect("mode is required") == "on";
+        }
+        Some((".pager", matches)) => {
+            settings.pager.enabled =
+                matches.get_one::<String>("mode").expect("mode is required") == "on";
+            if let Some(rows) = matches.get_one::<usize>("rows") {
+                settings.pager.page_rows = *rows;
+            }
+        }
+        Some((name, _matches)) => bail!("unknown command or invalid arguments: \"{name}\""),
         None => unreachable!("subcommand required"),
     }
 
@@ -108,17 +453,194 @@ fn cli() -> Command {
         )
         .subcommand(
             Command::new(".tables")
-                .about("Get tables")
+                .about("List the tables and views, or just TABLE")
+                .arg(Arg::new("table").value_name("TABLE"))
                 .help_template(APPLET_TEMPLATE),
         )
         .subcommand(
             Command::new(".schema")
-                .about("Get Schema")
+                .about("Show the CREATE statements of the database, or of TABLE")
+                .arg(Arg::new("table").value_name("TABLE"))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".dump")
+                .about("Write the database, or TABLE, out as the SQL that would make it again")
+                .arg(Arg::new("table").value_name("TABLE"))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".import")
+                .about("Add the rows of a CSV file to TABLE, creating it from the header if need be")
+                .arg(
+                    Arg::new("csv")
+                        .long("csv")
+                        .action(ArgAction::SetTrue)
+                        .help("Read the file as CSV, the only format there is"),
+                )
+                .arg(
+                    Arg::new("separator")
+                        .long("separator")
+                        .value_name("SEP")
+                        .value_parser(clap::value_parser!(char))
+                        .default_value(",")
+                        .help("The character between fields"),
+                )
+                .arg(
+                    Arg::new("skip")
+                        .long("skip")
+                        .value_name("N")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("0")
+                        .help("Pass over the first N lines"),
+                )
+                .arg(Arg::new("file").value_name("FILE").required(true))
+                .arg(Arg::new("table").value_name("TABLE").required(true))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".databases")
+                .about("List main and the attached databases and their files")
                 .help_template(APPLET_TEMPLATE),
         )
         .subcommand(
             Command::new(".indexes")
-                .about("Get Indexes")
+                .about("List the indexes, or those on TABLE")
+                .arg(Arg::new("table").value_name("TABLE"))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".locks")
+                .about("Show the lock each connection holds and what it is waiting for")
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".cachestats")
+                .about("Show how often each database file found its pages in its page cache")
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".stats")
+                .about(
+                    "Show the page cache's hits and misses and the pages read, written and synced",
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".headers")
+                .about("Print the column names above the rows, or don't")
+                .arg(
+                    Arg::new("mode")
+                        .value_name("on|off")
+                        .value_parser(["on", "off"])
+                        .required(true),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".width")
+                .about("Set the width of each column in table mode, negative to right align, 0 to fit")
+                .arg(
+                    Arg::new("widths")
+                        .value_name("NUM")
+                        .value_parser(clap::value_parser!(i64))
+                        .allow_negative_numbers(true)
+                        .num_args(0..),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".mode")
+                .about("Print query results as MODE")
+                .arg(
+                    Arg::new("mode")
+                        .value_name("MODE")
+                        .value_parser(Mode::NAMES)
+                        .required(true),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".open")
+                .about("Close the database and open FILE, creating it if need be, or a new one in memory")
+                .arg(Arg::new("file").value_name("FILE"))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".replicate")
+                .about("Close the database and follow the leader at ADDRESS as a read only replica")
+                .arg(Arg::new("leader").value_name("ADDRESS").required(true))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".read")
+                .about("Run the commands in FILE")
+                .arg(Arg::new("file").value_name("FILE").required(true))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".nullvalue")
+                .about("Print NULL as TEXT")
+                .arg(Arg::new("text").value_name("TEXT").required(true))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".separator")
+                .about("Separate the values of a row with SEP in list mode, \\t for a tab")
+                .arg(Arg::new("separator").value_name("SEP").required(true))
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".trace")
+                .about("Show what the engine does on stderr, or a RUST_LOG-style FILTER of it")
+                .arg(
+                    Arg::new("filter")
+                        .value_name("on|off|FILTER")
+                        .required(true),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".verbose")
+                .about("Show the kind and code of each error and every cause of it, or don't")
+                .arg(
+                    Arg::new("mode")
+                        .value_name("on|off")
+                        .value_parser(["on", "off"])
+                        .required(true),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".timer")
+                .about("Print how long each statement took and the pages it read, or don't")
+                .arg(
+                    Arg::new("mode")
+                        .value_name("on|off")
+                        .value_parser(["on", "off"])
+                        .required(true),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".pager")
+                .about("Page results longer than ROWS through $PAGER, or a --More-- prompt")
+                .arg(
+                    Arg::new("mode")
+                        .value_name("on|off")
+                        .value_parser(["on", "off"])
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("rows")
+                        .value_name("ROWS")
+                        .value_parser(clap::value_parser!(usize)),
+                )
+                .help_template(APPLET_TEMPLATE),
+        )
+        .subcommand(
+            Command::new(".demo")
+                .about("Create some tables with rows in them to try queries on")
                 .help_template(APPLET_TEMPLATE),
         )
         .subcommand(
@@ -128,3 +650,9 @@ fn cli() -> Command {
                 .help_template(APPLET_TEMPLATE),
         )
 }
+
+// A separator as it is written at the prompt, where \t is a tab and \n a line break, as
+// in sqlite3.
+fn unescape(text: &str) -> String {
+    text.replace("\\t", "\t").replace("\\n", "\n")
+}
@@ -0,0 +1,118 @@
+/*
+    The `.pager on|off` command, so a stray `SELECT * FROM big_table` doesn't flood the
+    terminal.
+
+    With paging on, a result longer than a page goes through $PAGER when it is set, the
+    way git and psql do it, and otherwise through a small pager of our own that prints a
+    page of rows at a time and waits at a "--More--" prompt: enter shows the next page and
+    q drops the rest. Results that fit on one page are printed as they always were.
+*/
+use anyhow::{Context, Result};
+use std::io::{BufRead, Write};
+use std::process::{Command, Stdio};
+
+/// Rows the internal pager shows before stopping at "--More--", unless `.pager on N`.
+pub const DEFAULT_PAGE_ROWS: usize = 24;
+
+const MORE: &str = "--More--";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pager {
+    pub enabled: bool,
+    pub page_rows: usize,
+}
+
+impl Default for Pager {
+    fn default() -> Self {
+        Pager {
+            enabled: false,
+            page_rows: DEFAULT_PAGE_ROWS,
+        }
+    }
+}
+
+impl Pager {
+    /// Print `output`, a page at a time when paging is on and it runs past one page.
+    pub fn print(&self, output: &str) -> Result<()> {
+        let mut stdout = std::io::stdout();
+        if !self.enabled || output.lines().count() <= self.page_rows {
+            write!(stdout, "{output}").context("failed to write to std out")?;
+            return stdout.flush().context("failed to flush std out");
+        }
+        match std::env::var("PAGER") {
+            Ok(pager) if !pager.trim().is_empty() => external(&pager, output),
+            _ => self.page(output, &mut std::io::stdin().lock(), &mut stdout),
+        }
+    }
+
+    /// The internal pager: `page_rows` lines, then "--More--" until a line is read from
+    /// `input`. A line starting with q, or the end of `input`, stops it early.
+    pub fn page(&self, output: &str, input: &mut impl BufRead, out: &mut impl Write) -> Result<()> {
+        let lines: Vec<&str> = output.lines().collect();
+        let mut pages = lines.chunks(self.page_rows.max(1)).peekable();
+        while let Some(page) = pages.next() {
+            write!(out, "{}", page.join("\n")).context("failed to write to std out")?;
+            if pages.peek().is_none() {
+                break;
+            }
+            write!(out, "\n{MORE}").context("failed to write to std out")?;
+            out.flush().context("failed to flush std out")?;
+            let mut answer = String::new();
+            let read = input
+                .read_line(&mut answer)
+                .context("failed to read line from stdin")?;
+            if read == 0 || answer.trim_start().starts_with(['q', 'Q']) {
+                break;
+            }
+        }
+        out.flush().context("failed to flush std out")
+    }
+}
+
+// Hand the output to $PAGER through the shell, so a PAGER with arguments like "less -S"
+// works, and wait for the user to quit it.
+fn external(pager: &str, output: &str) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(pager)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("cannot run pager \"{pager}\""))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // a pager quit before the end closes the pipe, which is not an error
+    let _ = writeln!(stdin, "{output}");
+    drop(stdin);
+    child
+        .wait()
+        .with_context(|| format!("pager \"{pager}\" failed"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paged(rows: usize, output: &str, input: &str) -> String {
+        let pager = Pager {
+            enabled: true,
+            page_rows: rows,
+        };
+        let mut out = vec![];
+        pager.page(output, &mut input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn pages_stop_at_more_until_enter() {
+        assert_eq!(
+            paged(2, "1\n2\n3\n4\n5", "\n\n"),
+            "1\n2\n--More--3\n4\n--More--5"
+        );
+    }
+
+    #[test]
+    fn q_drops_the_rest() {
+        assert_eq!(paged(2, "1\n2\n3\n4\n5", "q\n"), "1\n2\n--More--");
+        assert_eq!(paged(2, "1\n2\n3\n4\n5", ""), "1\n2\n--More--");
+    }
+}
@@ -0,0 +1,407 @@
+/*
+    The `.mode` command and the formats the shell prints query results in, sqlite3's own.
+
+        list    the values separated by | or the .separator, the default
+        table   an ASCII table with the column names at the top
+        csv     values separated by commas, quoted where they must be
+        tsv     values separated by tabs, as they are
+        json    an array with an object per row, its columns as keys
+        line    a line per value, `name = value`, and a blank line between rows
+
+    As in sqlite3 NULL is printed as nothing at all, or as the string `.nullvalue` sets,
+    except in json where it is null, and a boolean as 0 or 1. A BLOB, whose bytes may well
+    not be text, is printed in hex as the literal that would give it, X'CAFE', and in
+    json as a string of that. A statement that returns no rows prints nothing whatever the mode, as does a
+    write.
+
+    `.headers on|off` says whether the column names come first in list, table, csv and
+    tsv; json and line always name the columns. Choosing table turns them on, as it does
+    in sqlite3, and `.headers off` after that leaves them off. `.width` sets the width of
+    each column of a table in turn: a value is cut off at that many characters and padded
+    to it, on the left instead of the right if the width is negative, and 0 leaves a
+    column as wide as its longest value, as are columns past the last width given.
+
+    Every mode but table prints a query's rows one at a time as they are read, so that
+    the shell holds one row of a big result at a time rather than all of them; a table
+    has to see every row first to know how wide its columns are. So does the pager, see
+    pager.rs, which has to know whether the rows run past a page.
+*/
+use crate::executor::RowSet;
+use crate::sql_parser::ast::{format_real, ColVal};
+use anyhow::{bail, Result};
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Mode {
+    #[default]
+    List,
+    Table,
+    Csv,
+    Tsv,
+    Json,
+    Line,
+}
+
+impl Mode {
+    /// The names `.mode` takes.
+    pub const NAMES: [&'static str; 6] = ["list", "table", "csv", "tsv", "json", "line"];
+}
+
+impl FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "list" => Mode::List,
+            "table" => Mode::Table,
+            "csv" => Mode::Csv,
+            "tsv" => Mode::Tsv,
+            "json" => Mode::Json,
+            "line" => Mode::Line,
+            _ => bail!("no output mode {s}"),
+        })
+    }
+}
+
+/// How query results are printed, see the module comment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Render {
+    pub mode: Mode,
+    pub headers: bool,
+    pub widths: Vec<i64>,
+    /// What NULL is printed as.
+    pub null_value: String,
+    /// What separates the values of a row in list mode.
+    pub separator: String,
+}
+
+impl Default for Render {
+    fn default() -> Self {
+        Render {
+            mode: Mode::default(),
+            headers: false,
+            widths: vec![],
+            null_value: String::new(),
+            separator: "|".to_string(),
+        }
+    }
+}
+
+impl Render {
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        if mode == Mode::Table {
+            self.headers = true;
+        }
+    }
+
+    /// `rows` as the current mode prints them, with no newline at the end.
+    pub fn render(&self, rows: &RowSet) -> String {
+        if rows.rows.is_empty() {
+            return String::new();
+        }
+        if self.mode == Mode::Table {
+            return self.table(rows);
+        }
+        let mut out: String = rows
+            .rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| self.row(&rows.columns, row, i))
+            .collect();
+        out.push_str(self.end());
+        out
+    }
+
+    /// Whether rows can be printed one at a time as they are read, which a table can't
+    /// be as its columns are as wide as their longest value.
+    pub fn streams(&self) -> bool {
+        self.mode != Mode::Table
+    }
+
+    /// The `i`th row of a result, from 0, with whatever goes before it: the column names
+    /// before the first, and what separates it from the one before after that. Printed
+    /// one after the other, followed by `end` if there were any, the rows come out as
+    /// render prints them.
+    pub fn row(&self, columns: &[String], row: &[ColVal], i: usize) -> String {
+        let before = match (self.mode, i) {
+            (Mode::Json, 0) => "[".to_string(),
+            (Mode::Json, _) => ",\n".to_string(),
+            (Mode::Line, 0) => String::new(),
+            (Mode::Line, _) => "\n\n".to_string(),
+            (_, 0) if self.headers => {
+                let names: Vec<ColVal> = columns.iter().cloned().map(ColVal::String).collect();
+                self.separated(&names) + "\n"
+            }
+            (_, 0) => String::new(),
+            (_, _) => "\n".to_string(),
+        };
+        let row = match self.mode {
+            Mode::Json => json(columns, row),
+            Mode::Line => self.line(columns, row),
+            _ => self.separated(row),
+        };
+        before + &row
+    }
+
+    /// What follows the last row, if there were any.
+    pub fn end(&self) -> &'static str {
+        match self.mode {
+            Mode::Json => "]",
+            _ => "",
+        }
+    }
+
+    // A value as the shell prints it.
+    fn text(&self, value: &ColVal) -> String {
+        match value {
+            ColVal::Null => self.null_value.clone(),
+            value => text(value),
+        }
+    }
+
+    // A row of list, csv or tsv, its values separated as the mode separates them.
+    fn separated(&self, row: &[ColVal]) -> String {
+        let fields = row.iter().map(|v| match self.mode {
+            Mode::Csv => csv(self.text(v)),
+            _ => self.text(v),
+        });
+        let separator = match self.mode {
+            Mode::Csv => ",",
+            Mode::Tsv => "\t",
+            _ => self.separator.as_str(),
+        };
+        fields.collect::<Vec<_>>().join(separator)
+    }
+
+    fn table(&self, rows: &RowSet) -> String {
+        let cells: Vec<Vec<String>> = rows
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|v| self.text(v)).collect())
+            .collect();
+        // the width each column is cut and padded to and whether it is right aligned
+        let mut widths: Vec<(usize, bool)> = vec![];
+        for (i, column) in rows.columns.iter().enumerate() {
+            widths.push(match self.widths.get(i).copied().unwrap_or(0) {
+                0 => {
+                    let longest = cells.iter().map(|row| row[i].chars().count()).max();
+                    let header = if self.headers {
+                        column.chars().count()
+                    } else {
+                        0
+                    };
+                    (longest.unwrap_or(0).max(header), false)
+                }
+                width => (width.unsigned_abs() as usize, width < 0),
+            });
+        }
+        let dashes: Vec<String> = widths.iter().map(|(w, _)| "-".repeat(w + 2)).collect();
+        let border = format!("+{}+", dashes.join("+"));
+        let line = |cells: &[String]| {
+            let cells: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, &(w, right))| {
+                    let cell: String = cell.chars().take(w).collect();
+                    match right {
+                        true => format!(" {cell:>w$} "),
+                        false => format!(" {cell:<w$} "),
+                    }
+                })
+                .collect();
+            format!("|{}|", cells.join("|"))
+        };
+        let mut lines = vec![border.clone()];
+        if self.headers {
+            lines.extend([line(&rows.columns), border.clone()]);
+        }
+        lines.extend(cells.iter().map(|row| line(row)));
+        lines.push(border);
+        lines.join("\n")
+    }
+
+    fn line(&self, columns: &[String], row: &[ColVal]) -> String {
+        let width = columns.iter().map(|c| c.chars().count()).max().unwrap_or(0);
+        columns
+            .iter()
+            .zip(row)
+            .map(|(column, value)| format!("{column:>width$} = {}", self.text(value)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+// A value as it is printed, NULL as nothing.
+fn text(value: &ColVal) -> String {
+    match value {
+        ColVal::Null => String::new(),
+        ColVal::Boolean(b) => (*b as i64).to_string(),
+        ColVal::String(s) => s.clone(),
+        ColVal::Int(n) => n.to_string(),
+        ColVal::Real(r) => format_real(*r),
+        ColVal::Blob(_) => value.to_string(),
+    }
+}
+
+// A value as a CSV field, in double quotes if it holds anything that would break the line.
+fn csv(text: String) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+// A row as a JSON object, its columns as keys.
+fn json(columns: &[String], row: &[ColVal]) -> String {
+    let members: Vec<String> = columns
+        .iter()
+        .zip(row)
+        .map(|(column, value)| {
+            let value = match value {
+                ColVal::Null => "null".to_string(),
+                ColVal::String(s) => json_string(s),
+                ColVal::Blob(_) => json_string(&text(value)),
+                value => text(value),
+            };
+            format!("{}:{value}", json_string(column))
+        })
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> RowSet {
+        RowSet {
+            columns: vec!["id".to_string(), "name".to_string()],
+            rows: vec![
+                vec![ColVal::Int(1), ColVal::String("amy, \"a\"".to_string())],
+                vec![ColVal::Real(2.5), ColVal::Null],
+            ],
+            changes: None,
+        }
+    }
+
+    fn render(mode: Mode) -> String {
+        let mut render = Render::default();
+        render.set_mode(mode);
+        render.render(&rows())
+    }
+
+    #[test]
+    fn every_mode_prints_the_rows() {
+        assert_eq!(render(Mode::List), "1|amy, \"a\"\n2.5|");
+        assert_eq!(render(Mode::Csv), "1,\"amy, \"\"a\"\"\"\n2.5,");
+        assert_eq!(render(Mode::Tsv), "1\tamy, \"a\"\n2.5\t");
+        assert_eq!(
+            render(Mode::Json),
+            "[{\"id\":1,\"name\":\"amy, \\\"a\\\"\"},\n{\"id\":2.5,\"name\":null}]"
+        );
+        assert_eq!(
+            render(Mode::Line),
+            "  id = 1\nname = amy, \"a\"\n\n  id = 2.5\nname = "
+        );
+        assert_eq!(
+            render(Mode::Table),
+            "\
++-----+----------+
+| id  | name     |
++-----+----------+
+| 1   | amy, \"a\" |
+| 2.5 |          |
++-----+----------+"
+        );
+        assert_eq!(Render::default().render(&RowSet::default()), "");
+    }
+
+    #[test]
+    fn headers_and_widths_shape_the_output() {
+        let mut render = Render {
+            headers: true,
+            ..Render::default()
+        };
+        assert_eq!(render.render(&rows()), "id|name\n1|amy, \"a\"\n2.5|");
+        render.mode = Mode::Csv;
+        assert_eq!(
+            render.render(&rows()),
+            "id,name\n1,\"amy, \"\"a\"\"\"\n2.5,"
+        );
+
+        render.mode = Mode::Table;
+        render.widths = vec![-4, 3];
+        assert_eq!(
+            render.render(&rows()),
+            "\
++------+-----+
+|   id | nam |
++------+-----+
+|    1 | amy |
+|  2.5 |     |
++------+-----+"
+        );
+        render.headers = false;
+        render.widths = vec![];
+        assert_eq!(
+            render.render(&rows()),
+            "\
++-----+----------+
+| 1   | amy, \"a\" |
+| 2.5 |          |
++-----+----------+"
+        );
+    }
+
+    #[test]
+    fn nulls_and_separators_are_printed_as_set() {
+        let mut render = Render {
+            null_value: "(null)".to_string(),
+            separator: " :: ".to_string(),
+            ..Render::default()
+        };
+        assert_eq!(render.render(&rows()), "1 :: amy, \"a\"\n2.5 :: (null)");
+        render.mode = Mode::Json;
+        assert!(render.render(&rows()).ends_with("\"name\":null}]"));
+        render.mode = Mode::Line;
+        assert!(render.render(&rows()).ends_with("name = (null)"));
+    }
+
+    #[test]
+    fn blobs_are_printed_in_hex() {
+        let rows = RowSet {
+            columns: vec!["b".to_string()],
+            rows: vec![
+                vec![ColVal::Blob(vec![0xca, 0xfe, 0x00])],
+                vec![ColVal::Blob(vec![])],
+            ],
+            changes: None,
+        };
+        let mut render = Render::default();
+        assert_eq!(render.render(&rows), "X'CAFE00'\nX''");
+        render.mode = Mode::Json;
+        assert_eq!(
+            render.render(&rows),
+            "[{\"b\":\"X'CAFE00'\"},\n{\"b\":\"X''\"}]"
+        );
+    }
+}
@@ -0,0 +1,208 @@
+/*
+    How the shell tells the user a command failed.
+
+    An error is shown on one line, `Error: ` and then what went wrong with the causes it
+    was given joined by colons, `Error: cannot import "stock.csv": UNIQUE constraint
+    failed: stock.item`, in the words of error.rs. A parse error lists every mistake
+    chumsky found, each with the line of the statement it is on and a caret under where,
+    and only the first is shown, on the three lines it takes:
+
+        Error: near line 1: Parse error: found ( expected 'IF', or a name
+          CREATE TABLE (a);
+                       ^--- error here
+
+    Each error is also of a kind, with a code that stays the same from release to release,
+    SQLite's primary result code for errors of that kind:
+
+        parse_error            1   the SQL couldn't be parsed
+        constraint_violation  19   a UNIQUE, NOT NULL, CHECK or FOREIGN KEY constraint
+        busy                   5   another connection holds the lock
+        corrupt               11   the file isn't a database, or is damaged
+        io_error              10   reading or writing a file failed
+        error                  1   anything else, such as no such table
+
+    `.verbose on` adds the kind and the code after the line, the extended code where
+    error.rs has one, 2067 for a UNIQUE constraint, and then every line of every cause.
+*/
+use crate::error::{self, English, SqlError};
+use std::fmt;
+
+/// What kind of error a command failed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    ParseError,
+    ConstraintViolation,
+    Busy,
+    Corrupt,
+    IoError,
+    Other,
+}
+
+impl ErrorKind {
+    /// The kind of `err`, going by the first of its causes that says.
+    pub fn of(err: &anyhow::Error) -> ErrorKind {
+        if let Some(err) = error::find(err) {
+            return match err {
+                SqlError::UniqueConstraint { .. }
+                | SqlError::CheckConstraint { .. }
+                | SqlError::ForeignKeyConstraint => ErrorKind::ConstraintViolation,
+                SqlError::DatabaseLocked => ErrorKind::Busy,
+                _ => ErrorKind::Other,
+            };
+        }
+        for cause in err.chain() {
+            if cause.downcast_ref::<std::io::Error>().is_some() {
+                return ErrorKind::IoError;
+            }
+            let message = cause.to_string();
+            if message.starts_with("Parse error") {
+                return ErrorKind::ParseError;
+            }
+            if message.contains("constraint failed") {
+                return ErrorKind::ConstraintViolation;
+            }
+            if message.starts_with("database is locked") {
+                return ErrorKind::Busy;
+            }
+            if message.starts_with("database disk image is malformed")
+                || message.starts_with("malformed database schema")
+                || message.starts_with("file is not a database")
+            {
+                return ErrorKind::Corrupt;
+            }
+        }
+        ErrorKind::Other
+    }
+
+    /// SQLite's primary result code for errors of this kind.
+    pub fn code(self) -> i32 {
+        match self {
+            ErrorKind::ParseError | ErrorKind::Other => 1,
+            ErrorKind::Busy => 5,
+            ErrorKind::IoError => 10,
+            ErrorKind::Corrupt => 11,
+            ErrorKind::ConstraintViolation => 19,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::ParseError => "parse_error",
+            ErrorKind::ConstraintViolation => "constraint_violation",
+            ErrorKind::Busy => "busy",
+            ErrorKind::Corrupt => "corrupt",
+            ErrorKind::IoError => "io_error",
+            ErrorKind::Other => "error",
+        })
+    }
+}
+
+/// `err` as the shell shows it, see above.
+pub fn describe(err: &anyhow::Error, verbose: bool) -> String {
+    let line = err
+        .chain()
+        .map(|cause| match cause.downcast_ref::<SqlError>() {
+            Some(err) => err.render(&English),
+            None => {
+                let message = cause.to_string();
+                let lines = if message.starts_with("Parse error") {
+                    3
+                } else {
+                    1
+                };
+                message.lines().take(lines).collect::<Vec<_>>().join("\n")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(": ");
+    let mut shown = format!("Error: {line}");
+    if verbose {
+        let kind = ErrorKind::of(err);
+        let code = error::find(err).map_or(kind.code(), SqlError::code);
+        shown += &format!(" [{kind} {code}]");
+        for cause in err.chain() {
+            for line in cause.to_string().lines() {
+                shown += &format!("\n  {line}");
+            }
+        }
+    }
+    shown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use anyhow::Context;
+
+    fn fails(executor: &mut Executor, sql: &str) -> anyhow::Error {
+        executor.execute_sql(sql).unwrap_err()
+    }
+
+    #[test]
+    fn errors_are_told_apart_by_kind() {
+        let mut executor = Executor::default();
+        executor
+            .execute_sql("CREATE TABLE t (id INTEGER PRIMARY KEY, n CHECK (n > 0));")
+            .unwrap();
+        executor
+            .execute_sql("INSERT INTO t (id, n) VALUES (1, 1);")
+            .unwrap();
+
+        let err = fails(&mut executor, "SELEC * FROM t;");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ParseError);
+        // the first of the mistakes found, and where it is
+        let err = fails(&mut executor, "CREATE TABLE (a);");
+        assert_eq!(
+            describe(&err, false),
+            "Error: Parse error: found ( expected 'IF', or a name\n  \
+             CREATE TABLE (a);\n               \
+             ^--- error here"
+        );
+
+        let err = fails(&mut executor, "INSERT INTO t (id, n) VALUES (1, 2);");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ConstraintViolation);
+        let err = fails(&mut executor, "INSERT INTO t (id, n) VALUES (2, 0);");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::ConstraintViolation);
+        assert_eq!(ErrorKind::of(&err).code(), 19);
+
+        let err = fails(&mut executor, "SELECT * FROM nowhere;");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Other);
+
+        let err = std::fs::read("/no/such/file")
+            .context("cannot open \"/no/such/file\"")
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::IoError);
+
+        let err = anyhow::anyhow!(SqlError::DatabaseLocked);
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Busy);
+        let err = anyhow::anyhow!("file is not a database: page size 7");
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Corrupt);
+    }
+
+    #[test]
+    fn an_error_is_one_line_unless_verbose() {
+        let mut executor = Executor::default();
+        executor
+            .execute_sql("CREATE TABLE t (id INTEGER PRIMARY KEY);")
+            .unwrap();
+        executor
+            .execute_sql("INSERT INTO t (id) VALUES (1);")
+            .unwrap();
+        let err = fails(&mut executor, "INSERT INTO t (id) VALUES (1);")
+            .context("cannot import \"t.csv\"");
+        assert_eq!(
+            describe(&err, false),
+            "Error: cannot import \"t.csv\": UNIQUE constraint failed: t.id"
+        );
+        assert_eq!(
+            describe(&err, true),
+            "Error: cannot import \"t.csv\": UNIQUE constraint failed: t.id \
+             [constraint_violation 2067]\n  \
+             cannot import \"t.csv\"\n  \
+             UNIQUE constraint failed: t.id"
+        );
+    }
+}
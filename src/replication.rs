@@ -0,0 +1,411 @@
+/*
+    Replication: every commit shipped to read replicas, follower processes that keep a
+    copy of the database of their own and apply each commit to it.
+
+        sqlite-clone app.db serve --wire --replicas 9000    the leader
+        sqlite-clone                                         a follower
+        $ .replicate 127.0.0.1:9000
+
+    What is shipped are the frames of a write-ahead log, see storage/wal.rs. A database
+    file here holds an image of the database rather than its tables' pages, see
+    storage/image.rs, so the leader cuts the image of each commit into pages of its own,
+    PAGE_SIZE bytes numbered from 1, and logs those that differ from the last commit's
+    in a Wal of its own, the last of them marked as the commit. Its file, if it has one,
+    is saved as it always is; the log is only for shipping.
+
+    A follower connects and sends the frame of the last commit it has, 8 bytes
+    big-endian, 0 when it has none. The leader sends it the pages committed since, the
+    newest copy of each as the frames of a single commit, and then the same again each
+    time it commits, for as long as the follower stays, so a follower that falls behind
+    catches up in one step rather than commit by commit. Each
+    frame is sent as its number (8 bytes), its page (4), a byte that is 1 for a commit's
+    last frame, the length of its data (4) and the data, all big-endian. The follower
+    applies a commit's frames to its copy only once the commit frame arrives, so its
+    copy is always the database as of some commit, never part way through one.
+
+    The log keeps the last RETAINED_COMMITS commits. A follower further behind than that,
+    or one with nothing, is sent every page of the latest commit instead, and carries on
+    from there.
+
+    A follower only reads. Its database is loaded from its copy each time a commit
+    completes it and is read only, see Executor::replica, and may lag the leader by the
+    commits still on their way. Having a consistent copy is what failing over to a
+    follower would start from, but promoting one to leader isn't done here.
+*/
+use crate::executor::Executor;
+use crate::storage::image::{self, ImageRow};
+use crate::storage::wal::{FrameNumber, PageNumber, Wal};
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::watch;
+
+/// The size of the pages the image is cut into for shipping.
+pub const PAGE_SIZE: usize = 4096;
+// How many of the latest commits the leader's log keeps for followers to catch up on.
+const RETAINED_COMMITS: usize = 100;
+
+/// A page of a commit, as it is shipped to followers.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    pub number: FrameNumber,
+    pub page: PageNumber,
+    pub commit: bool,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(17 + self.data.len());
+        bytes.extend(self.number.to_be_bytes());
+        bytes.extend(self.page.to_be_bytes());
+        bytes.push(self.commit as u8);
+        bytes.extend((self.data.len() as u32).to_be_bytes());
+        bytes.extend(&self.data);
+        bytes
+    }
+
+    fn read(input: &mut impl Read) -> io::Result<Frame> {
+        let mut head = [0; 17];
+        input.read_exact(&mut head)?;
+        let length = u32::from_be_bytes(head[13..].try_into().expect("4 bytes"));
+        let mut data = vec![0; length as usize];
+        input.read_exact(&mut data)?;
+        Ok(Frame {
+            number: FrameNumber::from_be_bytes(head[..8].try_into().expect("8 bytes")),
+            page: PageNumber::from_be_bytes(head[8..12].try_into().expect("4 bytes")),
+            commit: head[12] == 1,
+            data,
+        })
+    }
+}
+
+/// The leader's log of the commits it has made, for its followers.
+#[derive(Debug)]
+pub struct Log {
+    // the pages of the latest commit
+    pages: Vec<Vec<u8>>,
+    wal: Wal,
+}
+
+impl Log {
+    /// A log that starts with `rows`, the image of the database as it is now.
+    pub fn new(rows: &[ImageRow]) -> Self {
+        let mut log = Log {
+            pages: vec![],
+            wal: Wal::new(RETAINED_COMMITS),
+        };
+        log.commit(rows);
+        log
+    }
+
+    /// Log the commit that left the database's image as `rows`, returning its commit
+    /// frame, or None if it left it as it was.
+    pub fn commit(&mut self, rows: &[ImageRow]) -> Option<FrameNumber> {
+        let bytes = image::encode(rows);
+        let pages: Vec<Vec<u8>> = bytes.chunks(PAGE_SIZE).map(<[u8]>::to_vec).collect();
+        let changed: Vec<(PageNumber, Vec<u8>)> = pages
+            .iter()
+            .enumerate()
+            .filter(|(i, page)| self.pages.get(*i) != Some(page))
+            .map(|(i, page)| (i as PageNumber + 1, page.clone()))
+            .collect();
+        if changed.is_empty() {
+            return None;
+        }
+        self.pages = pages;
+        let commit = self
+            .wal
+            .commit(changed)
+            .expect("a commit of at least one page");
+        // the pages are in self.pages already, the log only keeps the latest commits
+        self.wal
+            .checkpoint(|_, _| Ok(()))
+            .expect("a checkpoint that writes nothing not to fail");
+        Some(commit)
+    }
+
+    /// The frames a follower whose copy is as of the commit `after` needs to be brought
+    /// up to date, the newest copy of each page committed since, as one commit.
+    pub fn since(&self, after: FrameNumber) -> Vec<Frame> {
+        let last = self.wal.last_commit();
+        let Some(frames) = self.wal.committed_since(after) else {
+            // too far behind for the log: every page of the latest commit
+            let pages = self.pages.iter().map(Vec::as_slice);
+            return as_commit(last, (1..).zip(pages).collect());
+        };
+        let mut pages = BTreeMap::new();
+        for (_, page, data, _) in frames {
+            pages.insert(page, data);
+        }
+        as_commit(last, pages.into_iter().collect())
+    }
+}
+
+// Pages as the frames of the commit `number`, the last of them its commit frame.
+fn as_commit(number: FrameNumber, pages: Vec<(PageNumber, &[u8])>) -> Vec<Frame> {
+    let last = pages.len();
+    let frames = pages.into_iter().enumerate();
+    frames
+        .map(|(i, (page, data))| Frame {
+            number,
+            page,
+            commit: i + 1 == last,
+            data: data.to_vec(),
+        })
+        .collect()
+}
+
+/// A follower's copy of the leader's database, as of the last commit applied to it.
+#[derive(Debug, Default)]
+pub struct Replica {
+    pages: Vec<Vec<u8>>,
+    // the frames of a commit whose commit frame hasn't arrived yet
+    pending: Vec<Frame>,
+    applied: FrameNumber,
+}
+
+impl Replica {
+    /// The commit frame of the last commit applied, 0 before the first.
+    pub fn applied(&self) -> FrameNumber {
+        self.applied
+    }
+
+    /// Take a frame from the leader, returning the rows of the database once it completes
+    /// a commit.
+    pub fn apply(&mut self, frame: Frame) -> Result<Option<Vec<ImageRow>>> {
+        if frame.page == 0 {
+            bail!("replication frame {} is for page 0", frame.number);
+        }
+        let commit = frame.commit.then_some(frame.number);
+        self.pending.push(frame);
+        let Some(commit) = commit else {
+            return Ok(None);
+        };
+        for frame in self.pending.drain(..) {
+            let index = frame.page as usize - 1;
+            if index >= self.pages.len() {
+                self.pages.resize(index + 1, vec![]);
+            }
+            self.pages[index] = frame.data;
+        }
+        self.applied = commit;
+        let rows = image::decode(&self.pages.concat())
+            .with_context(|| format!("the replica as of frame {commit}"))?;
+        Ok(Some(rows))
+    }
+}
+
+/// Ship every commit `executor` makes from now on to the followers that connect to
+/// `bind`:`port`, from a thread of its own, returning the address it listens on.
+pub fn lead(executor: &mut Executor, bind: &str, port: u16) -> Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind((bind, port))
+        .with_context(|| format!("cannot listen for replicas on {bind}:{port}"))?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    let log = Arc::new(Mutex::new(Log::new(&executor.image())));
+    let (commits, committed) = watch::channel(0);
+    let logged = log.clone();
+    executor.on_commit(move |rows| {
+        if let Some(commit) = logged.lock().unwrap().commit(rows) {
+            commits.send_replace(commit);
+        }
+    });
+    std::thread::spawn(move || {
+        if let Err(err) = ship(listener, log, committed) {
+            eprintln!("replication stopped: {err:#}");
+        }
+    });
+    Ok(addr)
+}
+
+fn ship(
+    listener: std::net::TcpListener,
+    log: Arc<Mutex<Log>>,
+    committed: watch::Receiver<FrameNumber>,
+) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        loop {
+            let (stream, _) = listener.accept().await?;
+            // a follower that goes away just stops being sent commits
+            let (log, committed) = (log.clone(), committed.clone());
+            tokio::spawn(async move { follower(stream, log, committed).await.ok() });
+        }
+    })
+}
+
+// Send a follower every commit since the one it has, then each as it is made.
+async fn follower(
+    mut stream: tokio::net::TcpStream,
+    log: Arc<Mutex<Log>>,
+    mut committed: watch::Receiver<FrameNumber>,
+) -> Result<()> {
+    let mut after = stream.read_u64().await?;
+    loop {
+        // marked seen before the log is read, so a commit made after is waited for
+        committed.borrow_and_update();
+        let frames = log.lock().unwrap().since(after);
+        if let Some(last) = frames.last() {
+            after = last.number;
+        }
+        let bytes: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+        stream.write_all(&bytes).await?;
+        // until the leader's executor, and the hook that sends commits, is gone
+        committed.changed().await?;
+    }
+}
+
+/// A follower's connection to its leader, applying the commits it ships to a copy of
+/// the database from a thread of its own.
+#[derive(Debug)]
+pub struct Follower {
+    leader: String,
+    latest: Latest,
+}
+
+// The rows of the latest commit the database hasn't been loaded from yet, or why the
+// replication stopped.
+type Latest = Arc<Mutex<Option<Result<Vec<ImageRow>, String>>>>;
+
+/// Connect to the leader at `addr` and follow it, returning the leader's database once
+/// its latest commit has arrived, and the follower that brings it up to date.
+pub fn follow(addr: &str) -> Result<(Executor, Follower)> {
+    let mut stream =
+        TcpStream::connect(addr).with_context(|| format!("cannot connect to \"{addr}\""))?;
+    let mut replica = Replica::default();
+    stream.write_all(&replica.applied().to_be_bytes())?;
+    let rows = loop {
+        let frame = Frame::read(&mut stream).context("the leader closed the connection")?;
+        if let Some(rows) = replica.apply(frame)? {
+            break rows;
+        }
+    };
+    let latest = Arc::new(Mutex::new(None));
+    let receiving = latest.clone();
+    std::thread::spawn(move || receive(stream, replica, receiving));
+    let follower = Follower {
+        leader: addr.to_string(),
+        latest,
+    };
+    Ok((Executor::replica(rows)?, follower))
+}
+
+fn receive(mut stream: TcpStream, mut replica: Replica, latest: Latest) {
+    loop {
+        let applied = Frame::read(&mut stream)
+            .context("the leader closed the connection")
+            .and_then(|frame| replica.apply(frame));
+        match applied {
+            Ok(None) => {}
+            Ok(Some(rows)) => *latest.lock().unwrap() = Some(Ok(rows)),
+            Err(err) => {
+                *latest.lock().unwrap() = Some(Err(format!("{err:#}")));
+                return;
+            }
+        }
+    }
+}
+
+impl Follower {
+    /// The database as of the latest commit to arrive, if one has since this was last
+    /// asked, or why the replication has stopped.
+    pub fn newer(&self) -> Result<Option<Executor>> {
+        match self.latest.lock().unwrap().take() {
+            None => Ok(None),
+            Some(Ok(rows)) => Ok(Some(Executor::replica(rows)?)),
+            Some(Err(err)) => bail!("replication from {} stopped: {err}", self.leader),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::ColVal;
+
+    fn rows(count: i64) -> Vec<ImageRow> {
+        let text = ColVal::String("x".repeat(1000));
+        let row = |i| ("t".to_string(), ColVal::Int(i), vec![text.clone()]);
+        (1..=count).map(row).collect()
+    }
+
+    #[test]
+    fn followers_are_sent_the_pages_each_commit_changed() {
+        let mut log = Log::new(&rows(10));
+        let mut replica = Replica::default();
+        let mut applied = None;
+        for frame in log.since(replica.applied()) {
+            applied = replica.apply(frame).unwrap();
+        }
+        assert_eq!(applied, Some(rows(10)));
+
+        // a row added at the end changes the pages it is on and the length at the start
+        let commit = log.commit(&rows(11)).unwrap();
+        assert_eq!(log.commit(&rows(11)), None);
+        let frames = log.since(replica.applied());
+        let pages: Vec<PageNumber> = frames.iter().map(|f| f.page).collect();
+        assert_eq!(pages, [1, 3]);
+        assert_eq!(frames.last().unwrap().number, commit);
+        let (last, rest) = frames.split_last().unwrap();
+        for frame in rest {
+            assert_eq!(replica.apply(frame.clone()).unwrap(), None);
+        }
+        assert_eq!(replica.apply(last.clone()).unwrap(), Some(rows(11)));
+        assert_eq!(replica.applied(), commit);
+
+        // one too far behind for the log is sent the whole of the latest commit
+        for count in 12..=12 + RETAINED_COMMITS as i64 {
+            log.commit(&rows(count));
+        }
+        let frames = log.since(commit);
+        assert_eq!(frames.len(), log.pages.len());
+        let mut applied = None;
+        for frame in frames {
+            applied = replica.apply(frame).unwrap();
+        }
+        assert_eq!(applied, Some(rows(12 + RETAINED_COMMITS as i64)));
+    }
+
+    #[test]
+    fn a_follower_applies_what_its_leader_commits() {
+        let mut leader = Executor::default();
+        leader.execute_sql("CREATE TABLE t (a INTEGER);").unwrap();
+        let addr = lead(&mut leader, "127.0.0.1", 0).unwrap();
+        let (mut replica, follower) = follow(&addr.to_string()).unwrap();
+        let count = "SELECT COUNT(*) FROM t;";
+        assert_eq!(replica.execute_sql(count).unwrap().to_string(), "0");
+        assert_eq!(
+            replica
+                .execute_sql("INSERT INTO t (a) VALUES (1);")
+                .unwrap_err()
+                .to_string(),
+            "attempt to write a readonly database"
+        );
+
+        leader.execute_sql("INSERT INTO t (a) VALUES (1);").unwrap();
+        leader.execute_sql("INSERT INTO t (a) VALUES (2);").unwrap();
+        // until both commits have arrived
+        while replica.execute_sql(count).unwrap().to_string() != "2" {
+            if let Some(newer) = follower.newer().unwrap() {
+                replica = newer;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // and once the leader is gone, so is the replication
+        drop(leader);
+        let err = loop {
+            match follower.newer() {
+                Ok(_) => std::thread::sleep(std::time::Duration::from_millis(10)),
+                Err(err) => break err,
+            }
+        };
+        let stopped = format!("replication from {addr} stopped: the leader closed the connection");
+        assert!(err.to_string().starts_with(&stopped), "{err}");
+    }
+}
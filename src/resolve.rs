@@ -0,0 +1,504 @@
+/*
+    Name resolution, working out which table each column name in a statement belongs to.
+
+    A table in a FROM clause is known by its alias if it has one, `FROM users u`, and by
+    its own name otherwise. A qualified name `u.name` picks the table outright while a
+    bare `name` belongs to whichever table in scope has a column of that name. Should two
+    tables in the same FROM clause both have it the name is ambiguous and has to be
+    qualified. A rowid table also has its rowid, known as rowid, oid and _rowid_ unless a
+    column of its own goes by that name.
+
+    Subqueries open a scope of their own inside the statement's. Names are looked up in
+    the innermost scope first and then outwards, so in
+
+        SELECT name FROM users u WHERE EXISTS (SELECT 1 FROM orders WHERE user_id = u.id)
+
+    `user_id` is a column of orders and `u.id` reaches out to the users row the subquery
+    is being run for. A column from an outer scope is what makes a subquery correlated.
+
+    A table-valued function in FROM, `generate_series(1, 10)`, is a virtual table whose
+    arguments go to its hidden columns in order, see vtab.rs. Here they become terms of
+    the WHERE clause, `start = 1 AND stop = 10`, and from then on it is read like any
+    other table.
+
+    The pass rewrites every name into one of two forms so later stages needn't repeat the
+    search: columns of the scope's own table become plain Column names, and columns of an
+    outer scope become QualifiedColumn under the name that scope knows the table by. When
+    FROM joins several tables there is no one table of the scope's own, so their columns
+    are all qualified too, and `u.*` is left for the planner to expand.
+
+    Tables get their full names too. A temporary table can be named without its database,
+    and then hides a table of the same name in main, so `FROM t` becomes `FROM temp.t AS t`
+    when there is one. An index or trigger on it is made in temp alongside it.
+*/
+use crate::error::SqlError;
+use crate::planner::{conjoin, is_rowid, query_columns, Catalog, ROWID_NAMES};
+use crate::sql_parser::ast::{BinaryOp, CreateIndex, CreateTrigger, Expr, Join, Statement};
+use crate::sql_parser::{parse_aggregate, parse_expr, parse_window_function};
+use crate::storage::attach::TEMP;
+use anyhow::{bail, Result};
+
+// A table in a FROM clause.
+struct ScopeTable {
+    name: String, // the alias when there is one
+    columns: Vec<String>,
+}
+
+// The tables of one FROM clause, and the scope of the query it is nested in.
+struct Scope<'s> {
+    tables: Vec<ScopeTable>,
+    outer: Option<&'s Scope<'s>>,
+}
+
+impl Scope<'_> {
+    // Find a column by its name and, if qualified, the name of its table.
+    fn find(&self, table: Option<&str>, column: &str) -> Result<Expr> {
+        let mut scope = Some(self);
+        let mut depth = 0;
+        while let Some(s) = scope {
+            let mut matches = s.tables.iter().filter(|t| match table {
+                Some(table) => t.name == table,
+                None => t.columns.iter().any(|c| c == column),
+            });
+            if let Some(found) = matches.next() {
+                if table.is_none() && matches.next().is_some() {
+                    bail!("ambiguous column name: {column}");
+                }
+                if !found.columns.iter().any(|c| c == column) {
+                    break;
+                }
+                return Ok(if depth == 0 && s.tables.len() == 1 {
+                    Expr::Column(column.to_string())
+                } else {
+                    Expr::QualifiedColumn {
+                        table: found.name.clone(),
+                        column: column.to_string(),
+                    }
+                });
+            }
+            scope = s.outer;
+            depth += 1;
+        }
+        match table {
+            Some(table) => bail!(SqlError::NoSuchColumn {
+                column: format!("{table}.{column}")
+            }),
+            None => bail!(SqlError::NoSuchColumn {
+                column: column.to_string()
+            }),
+        }
+    }
+
+    fn has_table(&self, name: &str) -> bool {
+        self.tables.iter().any(|t| t.name == name)
+    }
+}
+
+/// Check every name in a statement refers to a column, rewriting it to say which.
+pub fn resolve(statement: &Statement, catalog: &dyn Catalog) -> Result<Statement> {
+    let mut statement = statement.clone();
+    resolve_in(&mut statement, None, catalog)?;
+    Ok(statement)
+}
+
+fn resolve_in(
+    statement: &mut Statement,
+    outer: Option<&Scope>,
+    catalog: &dyn Catalog,
+) -> Result<()> {
+    let (table, alias) = match statement {
+        // a subquery in FROM is a table of its result columns, the subquery itself being
+        // resolved as it is planned
+        Statement::Select {
+            from_table,
+            from_select: Some(select),
+            joins,
+            ..
+        } => {
+            temp_joins(joins, catalog);
+            let mut tables = vec![ScopeTable {
+                name: from_table.clone(),
+                columns: query_columns(select, catalog)?,
+            }];
+            tables.extend(joined_tables(joins, catalog)?);
+            let scope = Scope { tables, outer };
+            if let Statement::Select { columns, .. } = statement {
+                for column in columns.iter_mut() {
+                    *column = resolve_result_column(column, &scope, catalog)?;
+                }
+            }
+            return resolve_exprs(statement, &scope, catalog);
+        }
+        // without FROM only an outer query's columns can be named
+        Statement::Select {
+            from_table,
+            columns,
+            ..
+        } if from_table.is_empty() => {
+            let scope = Scope {
+                tables: vec![],
+                outer,
+            };
+            for column in columns.iter_mut() {
+                *column = resolve_result_column(column, &scope, catalog)?;
+            }
+            return resolve_exprs(statement, &scope, catalog);
+        }
+        Statement::Select {
+            from_table,
+            alias,
+            joins,
+            ..
+        } => {
+            if let Some(temp) = catalog.temp_table(from_table) {
+                alias.get_or_insert_with(|| from_table.clone());
+                *from_table = temp;
+            }
+            temp_joins(joins, catalog);
+            (from_table.clone(), alias.clone())
+        }
+        Statement::Update { table, .. }
+        | Statement::Delete {
+            from_table: table, ..
+        } => {
+            if let Some(temp) = catalog.temp_table(table) {
+                *table = temp;
+            }
+            (table.clone(), None)
+        }
+        // VALUES can't refer to any columns
+        Statement::Insert { into_table, .. } => {
+            if let Some(temp) = catalog.temp_table(into_table) {
+                *into_table = temp;
+            }
+            let scope = Scope {
+                tables: vec![],
+                outer,
+            };
+            return resolve_exprs(statement, &scope, catalog);
+        }
+        Statement::CreateIndex(CreateIndex { name, table, .. })
+        | Statement::CreateTrigger(CreateTrigger { name, table, .. }) => {
+            if let Some(temp) = catalog.temp_table(table) {
+                if !name.contains('.') {
+                    *name = format!("{TEMP}.{name}");
+                }
+                *table = temp;
+            }
+            return Ok(());
+        }
+        // the rest are resolved as they are planned, e.g. a WITH clause's body once the
+        // CTEs it can read are known
+        _ => return Ok(()),
+    };
+    let mut tables = vec![scope_table(&table, alias, catalog)?];
+    if let Statement::Select { joins, .. } = statement {
+        tables.extend(joined_tables(joins, catalog)?);
+    }
+    let scope = Scope { tables, outer };
+
+    if let Statement::Select { columns, .. } = statement {
+        for column in columns.iter_mut() {
+            *column = resolve_result_column(column, &scope, catalog)?;
+        }
+    }
+    table_arguments(statement, catalog)?;
+    resolve_exprs(statement, &scope, catalog)
+}
+
+// A table of a FROM clause, with its rowid if it has one.
+fn scope_table(table: &str, alias: Option<String>, catalog: &dyn Catalog) -> Result<ScopeTable> {
+    let mut columns = catalog.table_columns(table)?;
+    if catalog.has_rowid(table) {
+        let rowid = ROWID_NAMES.map(str::to_string);
+        columns.extend(
+            rowid
+                .into_iter()
+                .filter(|name| is_rowid(name, &columns))
+                .collect::<Vec<_>>(),
+        );
+    }
+    Ok(ScopeTable {
+        name: alias.unwrap_or(table.to_string()),
+        columns,
+    })
+}
+
+fn joined_tables(joins: &[Join], catalog: &dyn Catalog) -> Result<Vec<ScopeTable>> {
+    joins
+        .iter()
+        .map(|join| scope_table(&join.table, join.alias.clone(), catalog))
+        .collect()
+}
+
+// Joined tables get their full names as the first table does.
+fn temp_joins(joins: &mut [Join], catalog: &dyn Catalog) {
+    for join in joins {
+        if let Some(temp) = catalog.temp_table(&join.table) {
+            join.alias.get_or_insert_with(|| join.table.clone());
+            join.table = temp;
+        }
+    }
+}
+
+// Turn the arguments of a table-valued function into terms on its hidden columns, in
+// front of the rest of the WHERE clause.
+fn table_arguments(statement: &mut Statement, catalog: &dyn Catalog) -> Result<()> {
+    let Statement::Select {
+        from_table,
+        table_args,
+        where_clause,
+        ..
+    } = statement
+    else {
+        return Ok(());
+    };
+    if table_args.is_empty() {
+        return Ok(());
+    }
+    let Some(table) = catalog.virtual_table(from_table) else {
+        bail!("'{from_table}' is not a function");
+    };
+    let arguments = table.arguments();
+    if table_args.len() > arguments.len() {
+        bail!(
+            "too many arguments on {from_table}() - max {}",
+            arguments.len()
+        );
+    }
+    let terms = arguments
+        .into_iter()
+        .zip(std::mem::take(table_args))
+        .map(|(column, arg)| Expr::Binary {
+            op: BinaryOp::Eq,
+            left: Box::new(Expr::Column(column)),
+            right: Box::new(arg),
+        });
+    *where_clause = conjoin(terms.chain(where_clause.take()).collect());
+    Ok(())
+}
+
+// Result columns are kept as text: `*`, a constant, or a name which resolves as any other,
+// alone or in an aggregate or window function, or else an expression whose names do.
+fn resolve_result_column(column: &str, scope: &Scope, catalog: &dyn Catalog) -> Result<String> {
+    if column == "*" || column.parse::<i64>().is_ok() {
+        return Ok(column.to_string());
+    }
+    if let Ok(mut aggregate) = parse_aggregate(column) {
+        if let Some(arg) = &aggregate.arg {
+            aggregate.arg = Some(resolve_result_column(arg, scope, catalog)?);
+        }
+        return Ok(aggregate.to_string());
+    }
+    if let Ok(mut window) = parse_window_function(column) {
+        for column in window.columns_mut() {
+            *column = resolve_result_column(column, scope, catalog)?;
+        }
+        return Ok(window.to_string());
+    }
+    if let Some(table) = column.strip_suffix(".*") {
+        if !scope.has_table(table) {
+            bail!(SqlError::NoSuchTable {
+                table: table.to_string()
+            });
+        }
+        if scope.tables.len() > 1 {
+            return Ok(column.to_string());
+        }
+        return Ok("*".to_string());
+    }
+    let mut expr = parse_expr(column)?;
+    resolve_expr(&mut expr, scope, catalog)?;
+    Ok(expr.to_string())
+}
+
+fn resolve_exprs(statement: &mut Statement, scope: &Scope, catalog: &dyn Catalog) -> Result<()> {
+    let mut exprs = vec![];
+    // only the statement's own top level expressions, resolve_expr does the subqueries
+    match statement {
+        Statement::Select {
+            joins,
+            where_clause,
+            group_by,
+            order_by,
+            limit,
+            ..
+        } => {
+            exprs.extend(joins.iter_mut().flat_map(|j| j.on.as_mut()));
+            exprs.extend(where_clause.as_mut());
+            exprs.extend(group_by.iter_mut());
+            exprs.extend(order_by.iter_mut().map(|o| &mut o.expr));
+            if let Some(limit) = limit {
+                exprs.push(&mut limit.count);
+                exprs.extend(limit.offset.as_mut());
+            }
+        }
+        Statement::Insert { columns, .. } => exprs.extend(columns.iter_mut().map(|c| &mut c.value)),
+        Statement::Update {
+            assignments,
+            where_clause,
+            order_by,
+            limit,
+            ..
+        } => {
+            exprs.extend(assignments.iter_mut().map(|a| &mut a.value));
+            exprs.extend(where_clause.as_mut());
+            exprs.extend(order_by.iter_mut().map(|o| &mut o.expr));
+            if let Some(limit) = limit {
+                exprs.push(&mut limit.count);
+                exprs.extend(limit.offset.as_mut());
+            }
+        }
+        Statement::Delete {
+            where_clause,
+            order_by,
+            limit,
+            ..
+        } => {
+            exprs.extend(where_clause.as_mut());
+            exprs.extend(order_by.iter_mut().map(|o| &mut o.expr));
+            if let Some(limit) = limit {
+                exprs.push(&mut limit.count);
+                exprs.extend(limit.offset.as_mut());
+            }
+        }
+        _ => {}
+    }
+    for expr in exprs {
+        resolve_expr(expr, scope, catalog)?;
+    }
+    Ok(())
+}
+
+fn resolve_expr(expr: &mut Expr, scope: &Scope, catalog: &dyn Catalog) -> Result<()> {
+    match expr {
+        Expr::Column(column) => *expr = scope.find(None, column)?,
+        Expr::QualifiedColumn { table, column } => *expr = scope.find(Some(table), column)?,
+        Expr::Literal(_) | Expr::Placeholder(_) => {}
+        Expr::Row(exprs) | Expr::Function { args: exprs, .. } => {
+            for e in exprs {
+                resolve_expr(e, scope, catalog)?;
+            }
+        }
+        Expr::InList { expr, list, .. } => {
+            resolve_expr(expr, scope, catalog)?;
+            for e in list {
+                resolve_expr(e, scope, catalog)?;
+            }
+        }
+        Expr::InSelect { expr, select, .. } => {
+            resolve_expr(expr, scope, catalog)?;
+            resolve_in(select, Some(scope), catalog)?;
+        }
+        Expr::Exists { select, .. } | Expr::Subquery(select) => {
+            resolve_in(select, Some(scope), catalog)?
+        }
+        Expr::Unary { expr, .. } | Expr::Cast { expr, .. } | Expr::Collate { expr, .. } => {
+            resolve_expr(expr, scope, catalog)?
+        }
+        Expr::Binary { left, right, .. } => {
+            resolve_expr(left, scope, catalog)?;
+            resolve_expr(right, scope, catalog)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Schema;
+    use crate::sql_parser::{parse, parse_expr};
+
+    fn schema() -> Schema {
+        let mut schema = Schema::default();
+        for sql in [
+            "CREATE TABLE users (id, name);",
+            "CREATE TABLE orders (id, user_id, total);",
+        ] {
+            let Statement::CreateTable(table) = parse(sql).unwrap() else {
+                panic!("expected CREATE TABLE");
+            };
+            schema.create_table(&table).unwrap();
+        }
+        schema
+    }
+
+    fn resolve_sql(sql: &str) -> Result<String> {
+        Ok(resolve(&parse(sql).unwrap(), &schema())?.to_string())
+    }
+
+    #[test]
+    fn names_resolve_innermost_scope_first() {
+        assert_eq!(
+            resolve_sql(
+                r#"SELECT u.name FROM users AS u WHERE EXISTS (SELECT 1 FROM orders WHERE user_id = u.id AND id > 1 AND name = "x");"#
+            )
+            .unwrap(),
+            r#"SELECT name FROM users AS u WHERE EXISTS (SELECT 1 FROM orders WHERE user_id = u.id AND id > 1 AND u.name = "x")"#
+        );
+        // without an alias the table is known by its name
+        assert_eq!(
+            resolve_sql(
+                "SELECT users.* FROM users WHERE users.id IN (SELECT user_id FROM orders);"
+            )
+            .unwrap(),
+            "SELECT * FROM users WHERE id IN (SELECT user_id FROM orders)"
+        );
+        assert_eq!(
+            resolve_sql("SELECT count(*), MAX( DISTINCT o.total ) FROM orders o;").unwrap(),
+            "SELECT COUNT(*), MAX(DISTINCT total) FROM orders AS o"
+        );
+        assert_eq!(
+            resolve_sql(
+                "SELECT sum(o.total) OVER (PARTITION BY o.user_id ORDER BY o.id DESC) FROM orders o;"
+            )
+            .unwrap(),
+            "SELECT SUM(total) OVER (PARTITION BY user_id ORDER BY id DESC) FROM orders AS o"
+        );
+    }
+
+    #[test]
+    fn unknown_and_ambiguous_names() {
+        for (sql, error) in [
+            ("SELECT nope FROM users;", "no such column: nope"),
+            (
+                "SELECT users.name FROM users u;",
+                "no such column: users.name",
+            ),
+            ("SELECT u.nope FROM users u;", "no such column: u.nope"),
+            ("SELECT o.* FROM users u;", "no such table: o"),
+            (
+                "DELETE FROM users WHERE total > 1;",
+                "no such column: total",
+            ),
+            ("INSERT INTO users (id) VALUES (id);", "no such column: id"),
+        ] {
+            assert_eq!(resolve_sql(sql).unwrap_err().to_string(), error, "{sql}");
+        }
+
+        let scope = Scope {
+            tables: vec![
+                ScopeTable {
+                    name: "u".to_string(),
+                    columns: vec!["id".to_string(), "name".to_string()],
+                },
+                ScopeTable {
+                    name: "o".to_string(),
+                    columns: vec!["id".to_string(), "total".to_string()],
+                },
+            ],
+            outer: None,
+        };
+        let mut e = parse_expr("id = 1").unwrap();
+        assert_eq!(
+            resolve_expr(&mut e, &scope, &schema())
+                .unwrap_err()
+                .to_string(),
+            "ambiguous column name: id"
+        );
+        let mut e = parse_expr("o.id = total AND name = 1").unwrap();
+        resolve_expr(&mut e, &scope, &schema()).unwrap();
+        assert_eq!(e.to_string(), "o.id = o.total AND u.name = 1");
+    }
+}
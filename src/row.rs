@@ -0,0 +1,276 @@
+/*
+    The rows of a query as the library hands them out, see connection.rs.
+
+        for row in conn.query("SELECT name, age FROM users;", &[])? {
+            let row = row?;
+            let name: String = row.get(0)?;
+            let age: Option<i64> = row.get_by_name("age")?;
+        }
+
+    Rows is an Iterator of Row, each a Result as a query can fail part way through its
+    rows, when it is interrupted for one, see connection.rs. A Row's values come out as
+    Rust types through FromValue, which is implemented for i64, f64, bool, String, ColVal
+    itself, and Option of any of them for a column that may be NULL. Unlike
+    sqlite3_column_int and friends, which read anything as anything, a value is only
+    converted where its meaning carries over: an INTEGER reads as an f64 as well as an
+    i64, and a boolean as 0 or 1, but TEXT never reads as a number nor a number as TEXT,
+    and NULL only reads as None. Anything else is an error naming the column and both
+    types, rather than a quietly wrong value.
+
+    Column names are looked up as SQLite does, ignoring ASCII case, and the first column
+    of that name wins if there are several.
+*/
+use crate::executor::RowSet;
+use crate::sql_parser::ast::ColVal;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::sync::Arc;
+
+/// The rows of a query, in order, read from the connection as they are asked for.
+pub struct Rows<'c> {
+    columns: Arc<[String]>,
+    rows: Box<dyn Iterator<Item = Result<Vec<ColVal>>> + 'c>,
+    // how many rows the statement changed, if it was an INSERT, UPDATE or DELETE
+    changes: Option<u64>,
+}
+
+impl<'c> Rows<'c> {
+    pub(crate) fn new(columns: Vec<String>, rows: Vec<Vec<ColVal>>) -> Self {
+        Rows::stream(columns, rows.into_iter().map(Ok))
+    }
+
+    /// Rows made as they are read, by a program the statement runs a step at a time.
+    pub(crate) fn stream(
+        columns: Vec<String>,
+        rows: impl Iterator<Item = Result<Vec<ColVal>>> + 'c,
+    ) -> Self {
+        Rows {
+            columns: columns.into(),
+            rows: Box::new(rows),
+            changes: None,
+        }
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// How many rows the statement changed, if it was an INSERT, UPDATE or DELETE.
+    pub(crate) fn changes(&self) -> Option<u64> {
+        self.changes
+    }
+}
+
+impl From<RowSet> for Rows<'_> {
+    fn from(rows: RowSet) -> Self {
+        Rows {
+            changes: rows.changes,
+            ..Rows::new(rows.columns, rows.rows)
+        }
+    }
+}
+
+impl Iterator for Rows<'_> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Result<Row>> {
+        let values = self.rows.next()?;
+        Some(values.map(|values| Row {
+            columns: Arc::clone(&self.columns),
+            values,
+        }))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rows.size_hint()
+    }
+}
+
+impl fmt::Debug for Rows<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Rows")
+            .field("columns", &self.columns)
+            .finish_non_exhaustive()
+    }
+}
+
+/// One row of a query's result, a value for each of its columns.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Row {
+    columns: Arc<[String]>,
+    values: Vec<ColVal>,
+}
+
+impl Row {
+    /// The value of column `index`, from 0, as a `T`.
+    pub fn get<T: FromValue>(&self, index: usize) -> Result<T> {
+        let Some(value) = self.values.get(index) else {
+            bail!(
+                "column index {index} out of range, the row has {} columns",
+                self.values.len()
+            );
+        };
+        T::from_value(value).or_else(|expected| {
+            bail!(
+                "column {} is {}, which cannot be read as {expected}",
+                self.columns[index],
+                type_name(value)
+            )
+        })
+    }
+
+    /// The value of the column called `name` as a `T`.
+    pub fn get_by_name<T: FromValue>(&self, name: &str) -> Result<T> {
+        let Some(index) = self.column_index(name) else {
+            bail!("no such column: {name}");
+        };
+        self.get(index)
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.columns
+            .iter()
+            .position(|c| c.eq_ignore_ascii_case(name))
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn values(&self) -> &[ColVal] {
+        &self.values
+    }
+
+    pub fn into_values(self) -> Vec<ColVal> {
+        self.values
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+
+/// A Rust type a column's value can be read as, see the module comment.
+pub trait FromValue: Sized {
+    /// The value as Self, or the name of the type it was expected to be.
+    fn from_value(value: &ColVal) -> std::result::Result<Self, &'static str>;
+}
+
+impl FromValue for ColVal {
+    fn from_value(value: &ColVal) -> std::result::Result<Self, &'static str> {
+        Ok(value.clone())
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &ColVal) -> std::result::Result<Self, &'static str> {
+        match value {
+            ColVal::Int(n) => Ok(*n),
+            ColVal::Boolean(b) => Ok(*b as i64),
+            _ => Err("INTEGER"),
+        }
+    }
+}
+
+impl FromValue for f64 {
+    fn from_value(value: &ColVal) -> std::result::Result<Self, &'static str> {
+        match value {
+            ColVal::Real(r) => Ok(*r),
+            ColVal::Int(n) => Ok(*n as f64),
+            _ => Err("REAL"),
+        }
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &ColVal) -> std::result::Result<Self, &'static str> {
+        match value {
+            ColVal::Boolean(b) => Ok(*b),
+            ColVal::Int(n) => Ok(*n != 0),
+            _ => Err("a boolean"),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(value: &ColVal) -> std::result::Result<Self, &'static str> {
+        match value {
+            ColVal::String(s) => Ok(s.clone()),
+            _ => Err("TEXT"),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &ColVal) -> std::result::Result<Self, &'static str> {
+        match value {
+            ColVal::Null => Ok(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+// The storage class of a value, as SQLite's typeof() names it but upper case.
+fn type_name(value: &ColVal) -> &'static str {
+    match value {
+        ColVal::Null => "NULL",
+        ColVal::Boolean(_) | ColVal::Int(_) => "INTEGER",
+        ColVal::Real(_) => "REAL",
+        ColVal::String(_) => "TEXT",
+        ColVal::Blob(_) => "BLOB",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(columns: &[&str], values: Vec<ColVal>) -> Row {
+        let mut rows = Rows::new(
+            columns.iter().map(|c| c.to_string()).collect(),
+            vec![values],
+        );
+        rows.next().unwrap().unwrap()
+    }
+
+    #[test]
+    fn values_read_as_the_types_they_convert_to_losslessly() {
+        let row = row(
+            &["id", "Name", "score", "note"],
+            vec![
+                ColVal::Int(7),
+                ColVal::String("amy".to_string()),
+                ColVal::Real(2.5),
+                ColVal::Null,
+            ],
+        );
+        assert_eq!(row.get::<i64>(0).unwrap(), 7);
+        assert_eq!(row.get::<f64>(0).unwrap(), 7.0);
+        assert!(row.get::<bool>(0).unwrap());
+        assert_eq!(row.get_by_name::<String>("name").unwrap(), "amy");
+        assert_eq!(row.get_by_name::<f64>("SCORE").unwrap(), 2.5);
+        assert_eq!(row.get::<Option<String>>(3).unwrap(), None);
+        assert_eq!(row.get::<Option<i64>>(0).unwrap(), Some(7));
+
+        let err = row.get::<i64>(1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "column Name is TEXT, which cannot be read as INTEGER"
+        );
+        let err = row.get::<String>(3).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "column note is NULL, which cannot be read as TEXT"
+        );
+        assert!(row.get::<i64>(2).is_err());
+        assert!(row.get::<i64>(4).is_err());
+        assert_eq!(
+            row.get_by_name::<i64>("age").unwrap_err().to_string(),
+            "no such column: age"
+        );
+    }
+}
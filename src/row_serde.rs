@@ -0,0 +1,710 @@
+/*
+    Rows read into structs and structs written as rows, with the serde feature.
+
+        #[derive(Serialize, Deserialize)]
+        struct User { id: i64, name: String, age: Option<i64> }
+
+        conn.insert("users", &User { id: 1, name: "amy".into(), age: None })?;
+        let users: Vec<User> = conn.query_as("SELECT * FROM users WHERE age IS NULL;", &[])?;
+
+    A row deserializes as a map from its column names to its values, so a struct's fields
+    are matched to columns by name, in any order. A column the struct has no field for is
+    ignored unless the struct says #[serde(deny_unknown_fields)], and a field with no
+    column is an error unless it is an Option, which is None, or has a #[serde(default)].
+    Field names are compared exactly, as serde always does; #[serde(rename)] maps a field
+    to a column spelled differently. A row can also be read as a tuple or Vec, its values in order.
+
+    A value deserializes as whatever it is, an integer as an integer and so on, and the
+    type the field asks for decides what it accepts, with serde's own checks: an INTEGER
+    fits an i32 field only if it is in range, NULL fits only an Option or (), and TEXT
+    fits a unit-only enum by the variant's name. An INTEGER is read as a bool as SQLite
+    stores them, 0 as false and anything else as true. A value that doesn't fit its field
+    is an error naming the column.
+
+    insert goes the other way: a struct or map serializes as column names and values and
+    becomes an INSERT of one row, the values bound as parameters rather than written
+    into the SQL. Integers, floats, bools, strings and chars become the value they are,
+    bytes a BLOB, None and () NULL, a unit enum variant its name, and a ColVal itself.
+    Anything that would need a column of its own to store, a nested struct or a
+    sequence, is an error.
+*/
+use crate::connection::Connection;
+use crate::row::{Row, Rows};
+use crate::sql_parser::ast::ColVal;
+use anyhow::Result;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Impossible, Serialize};
+use std::fmt;
+
+/// What serde reports in either direction, turned into an anyhow::Error once out.
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl Row {
+    /// The row as a `T`, its fields filled from the columns of the same name.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T> {
+        Ok(T::deserialize(RowDeserializer { row: self })?)
+    }
+}
+
+impl Rows<'_> {
+    /// Every remaining row as a `T`, see Row::deserialize.
+    pub fn deserialize<T: DeserializeOwned>(self) -> Result<Vec<T>> {
+        self.map(|row| row?.deserialize()).collect()
+    }
+}
+
+impl Connection {
+    /// Run a query with `params` bound to its parameters in order, reading each row as a
+    /// `T`.
+    pub fn query_as<T: DeserializeOwned>(
+        &mut self,
+        sql: &str,
+        params: &[ColVal],
+    ) -> Result<Vec<T>> {
+        self.query(sql, params)?.deserialize()
+    }
+
+    /// Insert `row`, a struct or map, into `table`, a column for each of its fields.
+    pub fn insert<T: Serialize>(&mut self, table: &str, row: &T) -> Result<()> {
+        let fields = row.serialize(RowSerializer::default())?;
+        let columns: Vec<&str> = fields.iter().map(|(name, _)| name.as_str()).collect();
+        let values: Vec<ColVal> = fields.iter().map(|(_, value)| value.clone()).collect();
+        let placeholders = vec!["?"; values.len()].join(", ");
+        let sql = format!(
+            "INSERT INTO {table} ({}) VALUES ({placeholders});",
+            columns.join(", ")
+        );
+        self.execute(&sql, &values).map(|_| ())
+    }
+}
+
+impl Serialize for ColVal {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ColVal::Null => serializer.serialize_none(),
+            ColVal::Boolean(b) => serializer.serialize_bool(*b),
+            ColVal::Int(n) => serializer.serialize_i64(*n),
+            ColVal::Real(r) => serializer.serialize_f64(*r),
+            ColVal::String(s) => serializer.serialize_str(s),
+            ColVal::Blob(b) => serializer.serialize_bytes(b),
+        }
+    }
+}
+
+struct RowDeserializer<'a> {
+    row: &'a Row,
+}
+
+impl<'de> de::Deserializer<'de> for RowDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(Columns {
+            row: self.row,
+            next: 0,
+        })
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_seq(Columns {
+            row: self.row,
+            next: 0,
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct map struct enum identifier ignored_any
+    }
+}
+
+// The columns of a row, as map entries by name or as a sequence.
+struct Columns<'a> {
+    row: &'a Row,
+    next: usize,
+}
+
+impl Columns<'_> {
+    fn value<'de, T: de::DeserializeSeed<'de>>(
+        &self,
+        seed: T,
+        i: usize,
+    ) -> Result<T::Value, Error> {
+        seed.deserialize(ValueDeserializer(&self.row.values()[i]))
+            .map_err(|err| Error(format!("column {}: {err}", self.row.columns()[i])))
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Columns<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some(column) = self.row.columns().get(self.next) else {
+            return Ok(None);
+        };
+        seed.deserialize(column.as_str().into_deserializer())
+            .map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        self.next += 1;
+        self.value(seed, self.next - 1)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.row.len() - self.next)
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Columns<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.next == self.row.len() {
+            return Ok(None);
+        }
+        self.next += 1;
+        self.value(seed, self.next - 1).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.row.len() - self.next)
+    }
+}
+
+struct ValueDeserializer<'a>(&'a ColVal);
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            ColVal::Null => visitor.visit_unit(),
+            ColVal::Boolean(b) => visitor.visit_bool(*b),
+            ColVal::Int(n) => visitor.visit_i64(*n),
+            ColVal::Real(r) => visitor.visit_f64(*r),
+            ColVal::String(s) => visitor.visit_str(s),
+            ColVal::Blob(b) => visitor.visit_bytes(b),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            ColVal::Int(n) => visitor.visit_bool(*n != 0),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.0 {
+            ColVal::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.0 {
+            ColVal::String(s) => visitor.visit_enum(s.as_str().into_deserializer()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+// A struct or map as the columns and values of a row.
+#[derive(Default)]
+struct RowSerializer {
+    fields: Vec<(String, ColVal)>,
+}
+
+fn not_a_row<T>() -> Result<T, Error> {
+    Err(Error(
+        "only a struct or a map can be inserted as a row".to_string(),
+    ))
+}
+
+impl ser::Serializer for RowSerializer {
+    type Ok = Vec<(String, ColVal)>;
+    type Error = Error;
+    type SerializeSeq = Impossible<Self::Ok, Error>;
+    type SerializeTuple = Impossible<Self::Ok, Error>;
+    type SerializeTupleStruct = Impossible<Self::Ok, Error>;
+    type SerializeTupleVariant = Impossible<Self::Ok, Error>;
+    type SerializeMap = MapFields;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<Self::Ok, Error>;
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+        Ok(self)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<MapFields, Error> {
+        Ok(MapFields {
+            row: self,
+            key: None,
+        })
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_i8(self, _: i8) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_i16(self, _: i16) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_i32(self, _: i32) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_i64(self, _: i64) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_u8(self, _: u8) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_u16(self, _: u16) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_u32(self, _: u32) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_u64(self, _: u64) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_f32(self, _: f32) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_f64(self, _: f64) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_char(self, _: char) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_str(self, _: &str) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, Error> {
+        not_a_row()
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        not_a_row()
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+        not_a_row()
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        not_a_row()
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        not_a_row()
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        not_a_row()
+    }
+}
+
+impl ser::SerializeStruct for RowSerializer {
+    type Ok = Vec<(String, ColVal)>;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        let value = value
+            .serialize(ValueSerializer)
+            .map_err(|err| Error(format!("field {name}: {err}")))?;
+        self.fields.push((name.to_string(), value));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.fields)
+    }
+}
+
+// A map's entries as columns, its keys as their names.
+struct MapFields {
+    row: RowSerializer,
+    key: Option<String>,
+}
+
+impl ser::SerializeMap for MapFields {
+    type Ok = Vec<(String, ColVal)>;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        match key.serialize(ValueSerializer)? {
+            ColVal::String(name) => self.key = Some(name),
+            _ => return Err(Error("a column name must be a string".to_string())),
+        }
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let name = self.key.take().expect("a key before its value");
+        let value = value
+            .serialize(ValueSerializer)
+            .map_err(|err| Error(format!("field {name}: {err}")))?;
+        self.row.fields.push((name, value));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(self.row.fields)
+    }
+}
+
+// A field's value as the ColVal stored for it.
+struct ValueSerializer;
+
+fn not_a_value<T>() -> Result<T, Error> {
+    Err(Error(
+        "only a number, string, bool or None can be stored in a column".to_string(),
+    ))
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = ColVal;
+    type Error = Error;
+    type SerializeSeq = Impossible<ColVal, Error>;
+    type SerializeTuple = Impossible<ColVal, Error>;
+    type SerializeTupleStruct = Impossible<ColVal, Error>;
+    type SerializeTupleVariant = Impossible<ColVal, Error>;
+    type SerializeMap = Impossible<ColVal, Error>;
+    type SerializeStruct = Impossible<ColVal, Error>;
+    type SerializeStructVariant = Impossible<ColVal, Error>;
+
+    fn serialize_bool(self, b: bool) -> Result<ColVal, Error> {
+        Ok(ColVal::Boolean(b))
+    }
+    fn serialize_i8(self, n: i8) -> Result<ColVal, Error> {
+        self.serialize_i64(n.into())
+    }
+    fn serialize_i16(self, n: i16) -> Result<ColVal, Error> {
+        self.serialize_i64(n.into())
+    }
+    fn serialize_i32(self, n: i32) -> Result<ColVal, Error> {
+        self.serialize_i64(n.into())
+    }
+    fn serialize_i64(self, n: i64) -> Result<ColVal, Error> {
+        Ok(ColVal::Int(n))
+    }
+    fn serialize_u8(self, n: u8) -> Result<ColVal, Error> {
+        self.serialize_i64(n.into())
+    }
+    fn serialize_u16(self, n: u16) -> Result<ColVal, Error> {
+        self.serialize_i64(n.into())
+    }
+    fn serialize_u32(self, n: u32) -> Result<ColVal, Error> {
+        self.serialize_i64(n.into())
+    }
+    fn serialize_u64(self, n: u64) -> Result<ColVal, Error> {
+        match i64::try_from(n) {
+            Ok(n) => self.serialize_i64(n),
+            Err(_) => Err(Error(format!("{n} is too large for an INTEGER"))),
+        }
+    }
+    fn serialize_f32(self, r: f32) -> Result<ColVal, Error> {
+        self.serialize_f64(r.into())
+    }
+    fn serialize_f64(self, r: f64) -> Result<ColVal, Error> {
+        Ok(r.into())
+    }
+    fn serialize_char(self, c: char) -> Result<ColVal, Error> {
+        Ok(ColVal::String(c.to_string()))
+    }
+    fn serialize_str(self, s: &str) -> Result<ColVal, Error> {
+        Ok(s.into())
+    }
+    fn serialize_bytes(self, b: &[u8]) -> Result<ColVal, Error> {
+        Ok(ColVal::Blob(b.to_vec()))
+    }
+    fn serialize_none(self) -> Result<ColVal, Error> {
+        Ok(ColVal::Null)
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<ColVal, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<ColVal, Error> {
+        Ok(ColVal::Null)
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<ColVal, Error> {
+        Ok(ColVal::Null)
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<ColVal, Error> {
+        Ok(variant.into())
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<ColVal, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<ColVal, Error> {
+        not_a_value()
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        not_a_value()
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> {
+        not_a_value()
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        not_a_value()
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        not_a_value()
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        not_a_value()
+    }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Error> {
+        not_a_value()
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        not_a_value()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Plan {
+        Free,
+        Paid,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        id: i64,
+        name: String,
+        age: Option<u8>,
+        tier: Plan,
+        active: bool,
+    }
+
+    fn conn() -> Connection {
+        let mut conn = Connection::open_in_memory();
+        conn.execute(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, age INTEGER, tier TEXT, active INTEGER);",
+            &[],
+        )
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn structs_go_in_as_rows_and_come_back_out() {
+        let mut conn = conn();
+        let amy = User {
+            id: 1,
+            name: "amy".to_string(),
+            age: Some(30),
+            tier: Plan::Paid,
+            active: true,
+        };
+        conn.insert("users", &amy).unwrap();
+        let bob: BTreeMap<&str, ColVal> = [
+            ("id", 2.into()),
+            ("name", "bob".into()),
+            ("tier", "free".into()),
+            ("active", 0.into()),
+        ]
+        .into();
+        conn.insert("users", &bob).unwrap();
+
+        // columns match fields by name, whatever their order
+        let users: Vec<User> = conn
+            .query_as("SELECT active, tier, age, name, id FROM users;", &[])
+            .unwrap();
+        assert_eq!(users[0], amy);
+        assert_eq!(users[1].age, None);
+        assert_eq!(users[1].tier, Plan::Free);
+        assert!(!users[1].active);
+        let ageless: Vec<User> = conn
+            .query_as("SELECT * FROM users WHERE age IS NULL;", &[])
+            .unwrap();
+        assert_eq!(ageless.len(), 1);
+        assert_eq!(ageless[0].name, "bob");
+
+        let pairs: Vec<(i64, String)> = conn
+            .query_as("SELECT id, name FROM users WHERE id = ?;", &[2.into()])
+            .unwrap();
+        assert_eq!(pairs, [(2, "bob".to_string())]);
+    }
+
+    #[test]
+    fn a_value_that_does_not_fit_its_field_names_the_column() {
+        let mut conn = conn();
+        conn.execute(
+            "INSERT INTO users (id, name, age, tier, active) VALUES (1, \"amy\", 300, \"free\", 1);",
+            &[],
+        )
+        .unwrap();
+        let err = conn
+            .query_as::<User>("SELECT * FROM users;", &[])
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("column age: invalid value"),
+            "{err}"
+        );
+
+        let err = conn
+            .query_as::<User>("SELECT id, name FROM users;", &[])
+            .unwrap_err();
+        // a missing Option is None, anything else is an error
+        assert_eq!(err.to_string(), "missing field `tier`");
+
+        let err = conn.insert("users", &(1, "amy")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "only a struct or a map can be inserted as a row"
+        );
+    }
+}
@@ -0,0 +1,820 @@
+/*
+    The schema: every table, index, view and trigger in the database.
+
+    Tables, indexes and views share one namespace, so a view can't be called the same as
+    a table. Definitions are kept as the statements that created them, which is also how
+    SQLite keeps them in its sqlite_master table, and so do we, see catalog.rs.
+
+    A view stores only its SELECT, never any rows. Reading from a view runs the SELECT,
+    see the planner, and as there are no rows of its own to change views are read only.
+
+    A table's PRIMARY KEY, which may span several columns, is enforced as in SQLite by a
+    unique index the schema creates with the table, sqlite_autoindex_<table>_1. Two kinds
+    of table need no such index. An INTEGER PRIMARY KEY is the rowid itself, and a
+    WITHOUT ROWID table is stored in primary key order, see storage::clustered.
+
+    A virtual table's rows are made by a module's Rust code, see vtab.rs. It shares the
+    tables' namespace, and generate_series is one that is always there.
+
+    Triggers have a namespace of their own and belong to the table they are on, so the
+    statements writing to that table are re-planned when one is created.
+
+    Every schema change bumps the schema cookie, a counter SQLite keeps in the database
+    header. Anything that depends on the schema, such as the plan of a prepared statement,
+    remembers the cookie it was made against and can tell it may be stale when the cookie
+    has moved on. We also remember which table each change was to, so a statement on
+    `users` needn't be re-planned because an index was added to `orders`.
+*/
+use crate::catalog::{self, EntryKind};
+use crate::error::SqlError;
+use crate::eval::Affinity;
+use crate::planner::{self, Catalog, TableStats};
+use crate::sql_parser::ast::{
+    CreateIndex, CreateTable, CreateTrigger, CreateView, CreateVirtualTable, Expr, ForeignKey,
+    Statement,
+};
+use crate::storage::attach::TEMP;
+use crate::vtab::{Module, VirtualTable, VirtualTables};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Default)]
+pub struct Schema {
+    tables: BTreeMap<String, CreateTable>,
+    indexes: BTreeMap<String, CreateIndex>,
+    views: BTreeMap<String, CreateView>,
+    virtual_tables: VirtualTables,
+    // in the order they were created, which is the order they fire in
+    triggers: Vec<CreateTrigger>,
+    // what ANALYZE last measured of each table
+    stats: BTreeMap<String, TableStats>,
+    // changes[i] is the table or view that change i + 1 was to, so the cookie is its length
+    changes: Vec<String>,
+    hooks: ChangeHooks,
+}
+
+// Called after every schema change, for example so the REPL can refresh the table names
+// it tab completes.
+type ChangeHook = Box<dyn Fn(&Schema) + Send>;
+
+#[derive(Default)]
+struct ChangeHooks(Vec<ChangeHook>);
+
+impl fmt::Debug for ChangeHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} change hooks", self.0.len())
+    }
+}
+
+impl Schema {
+    /// Bumped by every change to the schema.
+    pub fn cookie(&self) -> u32 {
+        self.changes.len() as u32
+    }
+
+    /// Whether any change since `cookie` was to one of `tables`. A change to a temporary
+    /// table is one to the table its name alone would find, which it may now hide.
+    pub fn changed_since(&self, cookie: u32, tables: &[&str]) -> bool {
+        self.changes.get(cookie as usize..).map_or(true, |changes| {
+            changes.iter().any(|c| {
+                let unqualified = c.strip_prefix(TEMP).and_then(|c| c.strip_prefix('.'));
+                tables.contains(&c.as_str()) || unqualified.is_some_and(|c| tables.contains(&c))
+            })
+        })
+    }
+
+    /// Run `hook` after every future schema change.
+    pub fn on_change(&mut self, hook: impl Fn(&Schema) + Send + 'static) {
+        self.hooks.0.push(Box::new(hook));
+    }
+
+    /// The names of the tables and views, what can appear after FROM.
+    pub fn table_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .tables
+            .keys()
+            .chain(self.views.keys())
+            .map(String::as_str)
+            .chain(self.virtual_tables.names())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn changed(&mut self, table: &str) {
+        self.changes.push(table.to_string());
+        for hook in &self.hooks.0 {
+            hook(self);
+        }
+    }
+
+    // The kind of schema object with this name, if there is one.
+    fn kind_of(&self, name: &str) -> Option<&'static str> {
+        if self.tables.contains_key(name) || self.virtual_tables.contains(name) {
+            Some("table")
+        } else if self.indexes.contains_key(name) {
+            Some("index")
+        } else if self.views.contains_key(name) {
+            Some("view")
+        } else {
+            None
+        }
+    }
+
+    fn check_name_is_free(&self, name: &str) -> Result<()> {
+        if let Some(kind) = self.kind_of(name) {
+            bail!("{kind} {name} already exists");
+        }
+        Ok(())
+    }
+
+    /// Forget the tables, indexes and triggers of the attached database `database`, or of
+    /// temp, which are known by its name and theirs, `database.table`.
+    pub fn drop_database(&mut self, database: &str) {
+        let prefix = format!("{database}.");
+        let tables: Vec<String> = self
+            .tables
+            .keys()
+            .filter(|name| name.starts_with(&prefix))
+            .cloned()
+            .collect();
+        self.indexes.retain(|name, _| !name.starts_with(&prefix));
+        self.triggers.retain(|t| !t.table.starts_with(&prefix));
+        self.stats.retain(|name, _| !name.starts_with(&prefix));
+        for table in tables {
+            self.tables.remove(&table);
+            self.changed(&table);
+        }
+    }
+
+    /// Forget main's tables, indexes, views and triggers, for its catalog to be loaded
+    /// again when another connection has changed it. Those of attached databases and temp
+    /// stay, and so do the modules.
+    pub fn drop_main(&mut self) {
+        let main = |name: &String| !name.contains('.');
+        let mut dropped: Vec<String> = self
+            .tables
+            .keys()
+            .chain(self.views.keys())
+            .filter(|name| main(name))
+            .cloned()
+            .collect();
+        dropped.extend(self.virtual_tables.drop_main());
+        self.tables.retain(|name, _| !main(name));
+        self.views.retain(|name, _| !main(name));
+        self.indexes.retain(|name, _| !main(name));
+        self.triggers.retain(|t| !main(&t.table));
+        self.stats.retain(|name, _| !main(name));
+        for table in dropped {
+            self.changed(&table);
+        }
+    }
+
+    /// Every trigger, in the order they were created.
+    pub fn triggers(&self) -> &[CreateTrigger] {
+        &self.triggers
+    }
+
+    /// Returns false when IF NOT EXISTS skipped creating the table.
+    pub fn create_table(&mut self, table: &CreateTable) -> Result<bool> {
+        if table.if_not_exists && self.tables.contains_key(&table.name) {
+            return Ok(false);
+        }
+        self.check_name_is_free(&table.name)?;
+        planner::plan(&Statement::CreateTable(table.clone()), self)?;
+        self.tables.insert(table.name.clone(), table.clone());
+        if let Some(index) = primary_key_index(table) {
+            self.indexes.insert(index.name.clone(), index);
+        }
+        self.changed(&table.name);
+        Ok(true)
+    }
+
+    /// Record what ANALYZE measured of a table. Plans made before may have been made
+    /// without knowing, so like any other change this moves the cookie on.
+    pub fn set_table_stats(&mut self, table: &str, stats: TableStats) {
+        self.stats.insert(table.to_string(), stats);
+        self.changed(table);
+    }
+
+    /// The tables with a foreign key to `parent`, and those keys.
+    pub fn referencing(&self, parent: &str) -> Vec<(&CreateTable, &ForeignKey)> {
+        self.tables
+            .values()
+            .flat_map(|t| t.foreign_keys.iter().map(move |k| (t, k)))
+            .filter(|(_, k)| k.parent == parent)
+            .collect()
+    }
+
+    /// The definition of a table, None if there is no table of that name.
+    pub fn table(&self, name: &str) -> Option<&CreateTable> {
+        self.tables.get(name)
+    }
+
+    /// The definition of an index, None if there is no index of that name.
+    pub fn index(&self, name: &str) -> Option<&CreateIndex> {
+        self.indexes.get(name)
+    }
+
+    /// A virtual table as Catalog::virtual_table gives it, but borrowed from the schema,
+    /// so that a cursor on it can go on being read for as long as the schema is.
+    pub fn borrowed_virtual_table(&self, name: &str) -> Option<&dyn VirtualTable> {
+        self.virtual_tables.get(name).map(|table| table.as_ref())
+    }
+
+    /// Check an index can be created, false if IF NOT EXISTS means it won't be. Only reads
+    /// the schema, which the index is added to once it has been built.
+    pub fn check_index(&self, index: &CreateIndex) -> Result<bool> {
+        if index.if_not_exists && self.indexes.contains_key(&index.name) {
+            return Ok(false);
+        }
+        if self.views.contains_key(&index.table) {
+            bail!("views may not be indexed");
+        }
+        if self.virtual_tables.contains(&index.table) {
+            bail!("virtual tables may not be indexed");
+        }
+        self.check_name_is_free(&index.name)?;
+        // validates the table and columns
+        planner::plan(&Statement::CreateIndex(index.clone()), self)?;
+        Ok(true)
+    }
+
+    /// Returns false when IF NOT EXISTS skipped creating the index.
+    pub fn create_index(&mut self, index: &CreateIndex) -> Result<bool> {
+        if !self.check_index(index)? {
+            return Ok(false);
+        }
+        self.indexes.insert(index.name.clone(), index.clone());
+        self.changed(&index.table);
+        Ok(true)
+    }
+
+    /// The collation each key column of an index or WITHOUT ROWID table compares under, in
+    /// key order: the one given in the index, else the one the column was declared with,
+    /// else BINARY. Joined with commas this names the comparator its B+tree is ordered by.
+    /// None if there is no such index or WITHOUT ROWID table, or a key is an expression.
+    pub fn key_collations(&self, name: &str) -> Option<Vec<String>> {
+        let declared = |table: &CreateTable, column: &str| {
+            let column = table.columns.iter().find(|c| c.name == column)?;
+            Some(
+                column
+                    .collation
+                    .as_deref()
+                    .unwrap_or("BINARY")
+                    .to_uppercase(),
+            )
+        };
+        if let Some(index) = self.indexes.get(name) {
+            let table = self.tables.get(&index.table)?;
+            return index
+                .columns
+                .iter()
+                .map(|column| match column {
+                    Expr::Collate { expr, collation } if matches!(**expr, Expr::Column(_)) => {
+                        Some(collation.to_uppercase())
+                    }
+                    Expr::Column(column) => declared(table, column),
+                    _ => None,
+                })
+                .collect();
+        }
+        let table = self.tables.get(name).filter(|t| t.without_rowid)?;
+        table
+            .primary_key
+            .iter()
+            .map(|column| declared(table, column))
+            .collect()
+    }
+
+    /// Returns false when IF NOT EXISTS skipped creating the view.
+    pub fn create_view(&mut self, view: &CreateView) -> Result<bool> {
+        if view.if_not_exists && self.views.contains_key(&view.name) {
+            return Ok(false);
+        }
+        self.check_name_is_free(&view.name)?;
+        // A view that can't be read is rejected up front rather than on first use.
+        let input = planner::plan(&view.select, self)?;
+        planner::view_columns(view, &input)?;
+        self.views.insert(view.name.clone(), view.clone());
+        self.changed(&view.name);
+        Ok(true)
+    }
+
+    /// Returns false when IF NOT EXISTS skipped creating the virtual table.
+    pub fn create_virtual_table(&mut self, table: &CreateVirtualTable) -> Result<bool> {
+        if table.if_not_exists && self.virtual_tables.contains(&table.name) {
+            return Ok(false);
+        }
+        self.check_name_is_free(&table.name)?;
+        self.virtual_tables.create(table)?;
+        self.changed(&table.name);
+        Ok(true)
+    }
+
+    /// A virtual table kept in sqlite_master, made straight away if its module is there
+    /// and otherwise once it is.
+    pub fn load_virtual_table(&mut self, table: &CreateVirtualTable) -> Result<()> {
+        self.check_name_is_free(&table.name)?;
+        self.virtual_tables.load(table)?;
+        self.changed(&table.name);
+        Ok(())
+    }
+
+    /// Add a module for CREATE VIRTUAL TABLE to make tables with, see vtab.rs.
+    pub fn create_module(&mut self, name: &str, module: Arc<Module>) -> Result<()> {
+        self.virtual_tables.create_module(name, module)?;
+        // the tables it made can be read now
+        let names: Vec<String> = self.virtual_tables.names().map(str::to_string).collect();
+        for name in names {
+            self.changed(&name);
+        }
+        Ok(())
+    }
+
+    /// Forget what a CREATE made again, as ROLLBACK undoes it: the table of that name,
+    /// with the index enforcing its primary key, or the index, view or trigger. Like any
+    /// other change this moves the cookie on, so plans made meanwhile aren't used again.
+    pub fn forget(&mut self, kind: EntryKind, name: &str) {
+        let changed = match kind {
+            EntryKind::Table => {
+                if self.tables.remove(name).is_some() {
+                    self.indexes.retain(|_, index| index.table != name);
+                    self.stats.remove(name);
+                } else {
+                    self.virtual_tables.remove(name);
+                }
+                name.to_string()
+            }
+            EntryKind::Index => match self.indexes.remove(name) {
+                Some(index) => index.table,
+                None => return,
+            },
+            EntryKind::View => {
+                self.views.remove(name);
+                name.to_string()
+            }
+            EntryKind::Trigger => match self.triggers.iter().position(|t| t.name == name) {
+                Some(i) => self.triggers.remove(i).table,
+                None => return,
+            },
+        };
+        self.changed(&changed);
+    }
+
+    /// Returns false when IF NOT EXISTS skipped creating the trigger.
+    pub fn create_trigger(&mut self, trigger: &CreateTrigger) -> Result<bool> {
+        if self.triggers.iter().any(|t| t.name == trigger.name) {
+            if trigger.if_not_exists {
+                return Ok(false);
+            }
+            bail!("trigger {} already exists", trigger.name);
+        }
+        // validates the table
+        planner::plan(&Statement::CreateTrigger(trigger.clone()), self)?;
+        self.triggers.push(trigger.clone());
+        self.changed(&trigger.table);
+        Ok(true)
+    }
+}
+
+// The unique index that enforces a rowid table's primary key, named as SQLite names it. An
+// INTEGER PRIMARY KEY is the rowid itself and a WITHOUT ROWID table is stored in key
+// order, neither needs one.
+fn primary_key_index(table: &CreateTable) -> Option<CreateIndex> {
+    if table.primary_key.is_empty() || table.rowid_alias().is_some() || table.without_rowid {
+        return None;
+    }
+    // in an attached database the index is in the table's database
+    let name = match table.name.split_once('.') {
+        Some((database, name)) => format!("{database}.sqlite_autoindex_{name}_1"),
+        None => format!("sqlite_autoindex_{}_1", table.name),
+    };
+    Some(CreateIndex {
+        name,
+        table: table.name.clone(),
+        columns: table
+            .primary_key
+            .iter()
+            .map(|c| Expr::Column(c.clone()))
+            .collect(),
+        unique: true,
+        if_not_exists: false,
+    })
+}
+
+impl Catalog for Schema {
+    fn table_columns(&self, table: &str) -> Result<Vec<String>> {
+        if let Some(table) = self.tables.get(table) {
+            return Ok(table.column_names());
+        }
+        if let Some(columns) = self.virtual_tables.columns(table) {
+            return columns;
+        }
+        if let Some(view) = self.views.get(table) {
+            let input = planner::plan(&view.select, self)?;
+            return planner::view_columns(view, &input);
+        }
+        bail!(SqlError::NoSuchTable {
+            table: table.to_string()
+        })
+    }
+
+    fn table_indexes(&self, table: &str) -> Vec<CreateIndex> {
+        self.indexes
+            .values()
+            .filter(|i| i.table == table)
+            .cloned()
+            .collect()
+    }
+
+    fn view(&self, name: &str) -> Option<CreateView> {
+        self.views.get(name).cloned()
+    }
+
+    fn table_triggers(&self, table: &str) -> Vec<CreateTrigger> {
+        self.triggers
+            .iter()
+            .filter(|t| t.table == table)
+            .cloned()
+            .collect()
+    }
+
+    fn table_stats(&self, table: &str) -> Option<TableStats> {
+        self.stats.get(table).cloned()
+    }
+
+    fn has_rowid(&self, table: &str) -> bool {
+        self.tables.get(table).is_some_and(|t| !t.without_rowid)
+    }
+
+    fn virtual_table(&self, name: &str) -> Option<Arc<dyn VirtualTable>> {
+        self.virtual_tables.get(name).cloned()
+    }
+
+    fn column_affinity(&self, table: &str, column: &str) -> Option<Affinity> {
+        let def = self.tables.get(table)?;
+        let column = def.columns.iter().find(|c| c.name == column)?;
+        Some(Affinity::of(column.type_name.as_deref()))
+    }
+
+    // sqlite_master is always main's, and temp's is sqlite_temp_master as in SQLite.
+    fn temp_table(&self, name: &str) -> Option<String> {
+        let name = match name {
+            catalog::MASTER_TABLE => return None,
+            "sqlite_temp_master" | "sqlite_temp_schema" => catalog::MASTER_TABLE,
+            name if name.contains('.') => return None,
+            name => name,
+        };
+        let temp = format!("{TEMP}.{name}");
+        self.tables.contains_key(&temp).then_some(temp)
+    }
+
+    // A rowid table is stored in rowid order, and a WITHOUT ROWID table in primary key
+    // order, which is only BINARY order if the key's columns are all compared as BINARY.
+    fn scan_order(&self, table: &str) -> Vec<String> {
+        let Some(def) = self.tables.get(table) else {
+            return vec![];
+        };
+        if let Some(alias) = def.rowid_alias() {
+            return vec![def.columns[alias].name.clone()];
+        }
+        let binary = self
+            .key_collations(table)
+            .is_some_and(|collations| collations.iter().all(|c| c == "BINARY"));
+        if binary {
+            def.primary_key.clone()
+        } else {
+            vec![]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::parse;
+
+    fn schema() -> Schema {
+        let mut schema = Schema::default();
+        create_table(&mut schema, "CREATE TABLE users (id, name, age);").unwrap();
+        schema
+    }
+
+    fn create_table(schema: &mut Schema, sql: &str) -> Result<bool> {
+        let Statement::CreateTable(table) = parse(sql).unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        schema.create_table(&table)
+    }
+
+    fn create_view(schema: &mut Schema, sql: &str) -> Result<bool> {
+        let Statement::CreateView(view) = parse(sql).unwrap() else {
+            panic!("expected CREATE VIEW");
+        };
+        schema.create_view(&view)
+    }
+
+    fn explain(schema: &Schema, sql: &str) -> Result<String> {
+        planner::plan(&parse(sql).unwrap(), schema).map(|plan| plan.to_string())
+    }
+
+    #[test]
+    fn primary_keys_of_rowid_tables_get_a_unique_index() {
+        let mut schema = schema();
+        create_table(
+            &mut schema,
+            "CREATE TABLE grades (student, course, grade INTEGER, PRIMARY KEY (student, course));",
+        )
+        .unwrap();
+        create_table(&mut schema, "CREATE TABLE ids (id INTEGER PRIMARY KEY);").unwrap();
+        create_table(
+            &mut schema,
+            "CREATE TABLE pairs (a, b, PRIMARY KEY (a, b)) WITHOUT ROWID;",
+        )
+        .unwrap();
+        assert_eq!(
+            schema.table_indexes("grades"),
+            [CreateIndex {
+                name: "sqlite_autoindex_grades_1".to_string(),
+                table: "grades".to_string(),
+                columns: vec![
+                    Expr::Column("student".to_string()),
+                    Expr::Column("course".to_string()),
+                ],
+                unique: true,
+                if_not_exists: false,
+            }]
+        );
+        // an INTEGER PRIMARY KEY is the rowid and a WITHOUT ROWID table is its own index
+        assert!(schema.table_indexes("ids").is_empty());
+        assert!(schema.table_indexes("pairs").is_empty());
+
+        assert_eq!(
+            explain(
+                &schema,
+                r#"SELECT grade FROM grades WHERE student = "bob" AND course = 1;"#
+            )
+            .unwrap(),
+            "\
+PROJECT grade
+└── SEARCH grades USING INDEX sqlite_autoindex_grades_1 (student=? AND course=?) SEEKS (\"bob\", 1)
+"
+        );
+    }
+
+    #[test]
+    fn key_collations_name_how_keys_are_ordered() {
+        let mut schema = Schema::default();
+        create_table(
+            &mut schema,
+            "CREATE TABLE people (email TEXT COLLATE nocase, name, age, PRIMARY KEY (email, age));",
+        )
+        .unwrap();
+        create_table(
+            &mut schema,
+            "CREATE TABLE tags (tag TEXT COLLATE RTRIM PRIMARY KEY) WITHOUT ROWID;",
+        )
+        .unwrap();
+        let Statement::CreateIndex(index) = parse(
+            "CREATE INDEX people_name ON people (name COLLATE NOCASE, email COLLATE BINARY);",
+        )
+        .unwrap() else {
+            panic!("expected CREATE INDEX");
+        };
+        schema.create_index(&index).unwrap();
+
+        let collations = |name: &str| schema.key_collations(name).map(|c| c.join(","));
+        assert_eq!(
+            collations("sqlite_autoindex_people_1").as_deref(),
+            Some("NOCASE,BINARY")
+        );
+        assert_eq!(collations("people_name").as_deref(), Some("NOCASE,BINARY"));
+        assert_eq!(collations("tags").as_deref(), Some("RTRIM"));
+        // a rowid table is ordered by rowid, not by a collation
+        assert_eq!(collations("people"), None);
+    }
+
+    #[test]
+    fn bad_table_definitions_are_rejected() {
+        let mut schema = schema();
+        for (sql, error) in [
+            ("CREATE TABLE users (id);", "table users already exists"),
+            (
+                "CREATE TABLE t (a, b, PRIMARY KEY (a, c));",
+                "no such column: c",
+            ),
+            ("CREATE TABLE t (a, b, a);", "duplicate column name: a"),
+            (
+                "CREATE TABLE t (a, b) WITHOUT ROWID;",
+                "PRIMARY KEY missing on table t",
+            ),
+        ] {
+            assert_eq!(
+                create_table(&mut schema, sql).unwrap_err().to_string(),
+                error,
+                "{sql}"
+            );
+        }
+        assert!(!create_table(&mut schema, "CREATE TABLE IF NOT EXISTS users (x);").unwrap());
+    }
+
+    #[test]
+    fn views_expand_where_they_are_read() {
+        let mut schema = schema();
+        create_view(
+            &mut schema,
+            "CREATE VIEW adults (who, years) AS SELECT name, age FROM users WHERE age > 17;",
+        )
+        .unwrap();
+        create_view(
+            &mut schema,
+            "CREATE VIEW seniors AS SELECT who FROM adults WHERE years > 64;",
+        )
+        .unwrap();
+
+        // seniors' own filter is pushed down into adults, where it is on users' columns,
+        // but seniors' rows are a view's rather than a table's so who = "bob" stays put
+        assert_eq!(
+            explain(&schema, r#"SELECT who FROM seniors WHERE who = "bob";"#).unwrap(),
+            "\
+PROJECT who
+└── FILTER who = \"bob\"
+    └── VIEW seniors (who)
+        └── PROJECT who
+            └── VIEW adults (who, years)
+                └── PROJECT name, age
+                    └── FILTER age > 17 AND age > 64
+                        └── SCAN users
+"
+        );
+    }
+
+    #[test]
+    fn views_are_read_only() {
+        let mut schema = schema();
+        create_view(&mut schema, "CREATE VIEW names AS SELECT name FROM users;").unwrap();
+        for sql in [
+            r#"INSERT INTO names (name) VALUES ("bob");"#,
+            r#"UPDATE names SET name = "bob";"#,
+            "DELETE FROM names;",
+        ] {
+            assert_eq!(
+                explain(&schema, sql).unwrap_err().to_string(),
+                "cannot modify names because it is a view"
+            );
+        }
+    }
+
+    #[test]
+    fn changes_bump_the_cookie_and_run_hooks() {
+        use std::sync::{Arc, Mutex};
+
+        let mut schema = schema();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let hook_seen = seen.clone();
+        schema.on_change(move |s| {
+            *hook_seen.lock().unwrap() = s.table_names().iter().map(|t| t.to_string()).collect()
+        });
+
+        let cookie = schema.cookie();
+        create_view(&mut schema, "CREATE VIEW names AS SELECT name FROM users;").unwrap();
+        assert_eq!(schema.cookie(), cookie + 1);
+        assert_eq!(*seen.lock().unwrap(), vec!["names", "users"]);
+        assert!(schema.changed_since(cookie, &["names"]));
+        assert!(!schema.changed_since(cookie, &["users"]));
+
+        // skipped by IF NOT EXISTS so nothing changed
+        create_view(
+            &mut schema,
+            "CREATE VIEW IF NOT EXISTS names AS SELECT name FROM users;",
+        )
+        .unwrap();
+        assert_eq!(schema.cookie(), cookie + 1);
+    }
+
+    #[test]
+    fn view_definitions_are_checked() {
+        let mut schema = schema();
+        assert_eq!(
+            create_view(&mut schema, "CREATE VIEW v AS SELECT name FROM nope;")
+                .unwrap_err()
+                .to_string(),
+            "no such table: nope"
+        );
+        assert_eq!(
+            create_view(
+                &mut schema,
+                "CREATE VIEW v (a, b) AS SELECT name FROM users;"
+            )
+            .unwrap_err()
+            .to_string(),
+            "expected 2 columns for 'v' but got 1"
+        );
+        assert_eq!(
+            create_view(&mut schema, "CREATE VIEW users AS SELECT name FROM users;")
+                .unwrap_err()
+                .to_string(),
+            "table users already exists"
+        );
+
+        assert!(create_view(&mut schema, "CREATE VIEW v AS SELECT name FROM users;").unwrap());
+        assert!(!create_view(
+            &mut schema,
+            "CREATE VIEW IF NOT EXISTS v AS SELECT age FROM users;"
+        )
+        .unwrap());
+    }
+
+    fn create_trigger(schema: &mut Schema, sql: &str) -> Result<bool> {
+        let Statement::CreateTrigger(trigger) = parse(sql).unwrap() else {
+            panic!("expected CREATE TRIGGER");
+        };
+        schema.create_trigger(&trigger)
+    }
+
+    #[test]
+    fn writes_fire_the_triggers_on_their_table() {
+        let mut schema = schema();
+        create_table(&mut schema, "CREATE TABLE log (who, what);").unwrap();
+        for sql in [
+            r#"CREATE TRIGGER log_birthday AFTER UPDATE OF age ON users BEGIN INSERT INTO log (who, what) VALUES (NEW.name, "birthday"); END;"#,
+            r#"CREATE TRIGGER log_leaver BEFORE DELETE ON users FOR EACH ROW WHEN OLD.age > 17 BEGIN INSERT INTO log (who, what) VALUES (OLD.name, "left"); END;"#,
+        ] {
+            let cookie = schema.cookie();
+            assert!(create_trigger(&mut schema, sql).unwrap());
+            assert!(schema.changed_since(cookie, &["users"]));
+        }
+
+        assert_eq!(
+            explain(&schema, "UPDATE users SET age = age + 1 WHERE id = 1;").unwrap(),
+            "\
+TRIGGERS AFTER log_birthday
+└── UPDATE users SET age = age + 1
+    └── FILTER id = 1
+        └── SCAN users
+"
+        );
+        assert_eq!(
+            explain(&schema, r#"UPDATE users SET name = "bob";"#).unwrap(),
+            "\
+UPDATE users SET name = \"bob\"
+└── SCAN users
+"
+        );
+        assert_eq!(
+            explain(&schema, "DELETE FROM users;").unwrap(),
+            "\
+TRIGGERS BEFORE log_leaver
+└── DELETE FROM users
+    └── SCAN users
+"
+        );
+    }
+
+    #[test]
+    fn trigger_definitions_are_checked() {
+        let mut schema = schema();
+        create_view(&mut schema, "CREATE VIEW names AS SELECT name FROM users;").unwrap();
+
+        assert_eq!(
+            create_trigger(
+                &mut schema,
+                "CREATE TRIGGER t AFTER INSERT ON nope BEGIN DELETE FROM users; END;"
+            )
+            .unwrap_err()
+            .to_string(),
+            "no such table: nope"
+        );
+        assert_eq!(
+            create_trigger(
+                &mut schema,
+                "CREATE TRIGGER t AFTER INSERT ON names BEGIN DELETE FROM users; END;"
+            )
+            .unwrap_err()
+            .to_string(),
+            "cannot create AFTER trigger on view: names"
+        );
+        assert!(create_trigger(
+            &mut schema,
+            "CREATE TRIGGER t AFTER INSERT ON users BEGIN DELETE FROM users; END;"
+        )
+        .unwrap());
+        assert_eq!(
+            create_trigger(
+                &mut schema,
+                "CREATE TRIGGER t BEFORE DELETE ON users BEGIN DELETE FROM users; END;"
+            )
+            .unwrap_err()
+            .to_string(),
+            "trigger t already exists"
+        );
+        assert!(!create_trigger(
+            &mut schema,
+            "CREATE TRIGGER IF NOT EXISTS t BEFORE DELETE ON users BEGIN DELETE FROM users; END;"
+        )
+        .unwrap());
+    }
+}
@@ -0,0 +1,364 @@
+/*
+    An external merge sort, for sorting more rows than fit in memory. ORDER BY sorts its
+    rows with it, and CREATE INDEX the keys of the new index.
+
+    Records are gathered in memory until they take up more than the sorter's memory
+    budget. The batch is then sorted and written out to a temporary file as a sorted run,
+    and the sorter starts on the next batch. Once every record has been added the runs
+    are merged: the smallest of the records at the head of each run is the next in order,
+    so reading the runs side by side like this gives every record in order while holding
+    only one record of each run in memory. A sort that never outgrows its budget never
+    touches a file at all.
+
+    Like SQLite's, the budget is the page cache's size, from PRAGMA cache_size, and no
+    more than PRAGMA hard_heap_limit allows, see PagerConfig::memory_budget.
+
+    The sort is stable, records that compare equal come out in the order they went in. A
+    run holds records that went in after those of every run before it, so ties between
+    runs go to the earlier run.
+
+    Temporary files are anonymous files from the operating system, deleted as soon as the
+    sorter is done with them. They should be opened through the VFS once os_interface has
+    one.
+*/
+use crate::sql_parser::ast::ColVal;
+use anyhow::{Context, Result};
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, Write};
+
+/// A record the sorter can write to a run and read back again.
+pub trait Spill: Sized {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()>;
+    fn read_from(input: &mut dyn Read) -> io::Result<Self>;
+    /// Roughly how many bytes the record takes up in memory.
+    fn size(&self) -> usize;
+}
+
+fn read_u8(input: &mut dyn Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u64(input: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+impl Spill for i64 {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&self.to_be_bytes())
+    }
+
+    fn read_from(input: &mut dyn Read) -> io::Result<Self> {
+        Ok(read_u64(input)? as i64)
+    }
+
+    fn size(&self) -> usize {
+        8
+    }
+}
+
+impl Spill for ColVal {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self {
+            ColVal::Null => out.write_all(&[0]),
+            ColVal::Boolean(b) => out.write_all(&[1, *b as u8]),
+            ColVal::Int(n) => {
+                out.write_all(&[2])?;
+                out.write_all(&n.to_be_bytes())
+            }
+            ColVal::Real(f) => {
+                out.write_all(&[3])?;
+                out.write_all(&f.to_bits().to_be_bytes())
+            }
+            ColVal::String(s) => {
+                out.write_all(&[4])?;
+                out.write_all(&(s.len() as u64).to_be_bytes())?;
+                out.write_all(s.as_bytes())
+            }
+            ColVal::Blob(b) => {
+                out.write_all(&[5])?;
+                out.write_all(&(b.len() as u64).to_be_bytes())?;
+                out.write_all(b)
+            }
+        }
+    }
+
+    fn read_from(input: &mut dyn Read) -> io::Result<Self> {
+        Ok(match read_u8(input)? {
+            0 => ColVal::Null,
+            1 => ColVal::Boolean(read_u8(input)? != 0),
+            2 => ColVal::Int(read_u64(input)? as i64),
+            3 => ColVal::Real(f64::from_bits(read_u64(input)?)),
+            4 => {
+                let mut bytes = vec![0; read_u64(input)? as usize];
+                input.read_exact(&mut bytes)?;
+                ColVal::String(
+                    String::from_utf8(bytes)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+                )
+            }
+            5 => {
+                let mut bytes = vec![0; read_u64(input)? as usize];
+                input.read_exact(&mut bytes)?;
+                ColVal::Blob(bytes)
+            }
+            tag => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad value tag {tag}"),
+                ))
+            }
+        })
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<ColVal>()
+            + match self {
+                ColVal::String(s) => s.len(),
+                ColVal::Blob(b) => b.len(),
+                _ => 0,
+            }
+    }
+}
+
+impl<T: Spill> Spill for Vec<T> {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&(self.len() as u64).to_be_bytes())?;
+        self.iter().try_for_each(|item| item.write_to(out))
+    }
+
+    fn read_from(input: &mut dyn Read) -> io::Result<Self> {
+        let len = read_u64(input)?;
+        (0..len).map(|_| T::read_from(input)).collect()
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.iter().map(Spill::size).sum::<usize>()
+    }
+}
+
+impl<T: Spill> Spill for Option<T> {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self {
+            None => out.write_all(&[0]),
+            Some(item) => {
+                out.write_all(&[1])?;
+                item.write_to(out)
+            }
+        }
+    }
+
+    fn read_from(input: &mut dyn Read) -> io::Result<Self> {
+        Ok(match read_u8(input)? {
+            0 => None,
+            _ => Some(T::read_from(input)?),
+        })
+    }
+
+    fn size(&self) -> usize {
+        1 + self.as_ref().map_or(0, Spill::size)
+    }
+}
+
+impl<A: Spill, B: Spill> Spill for (A, B) {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.0.write_to(out)?;
+        self.1.write_to(out)
+    }
+
+    fn read_from(input: &mut dyn Read) -> io::Result<Self> {
+        Ok((A::read_from(input)?, B::read_from(input)?))
+    }
+
+    fn size(&self) -> usize {
+        self.0.size() + self.1.size()
+    }
+}
+
+type Compare<'a, T> = Box<dyn Fn(&T, &T) -> Ordering + 'a>;
+
+// A sorted run in a temporary file, and how many of its records are left to read.
+struct Run {
+    reader: BufReader<File>,
+    remaining: usize,
+}
+
+impl Run {
+    fn next<T: Spill>(&mut self) -> Result<Option<T>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        let record = T::read_from(&mut self.reader).context("cannot read a sorted run")?;
+        Ok(Some(record))
+    }
+}
+
+pub struct Sorter<'a, T> {
+    memory: usize,
+    compare: Compare<'a, T>,
+    batch: Vec<T>,
+    batch_size: usize,
+    runs: Vec<Run>,
+}
+
+impl<'a, T: Spill> Sorter<'a, T> {
+    /// A sorter that holds at most about `memory` bytes of records before spilling them.
+    pub fn new(memory: usize, compare: impl Fn(&T, &T) -> Ordering + 'a) -> Self {
+        Sorter {
+            memory,
+            compare: Box::new(compare),
+            batch: vec![],
+            batch_size: 0,
+            runs: vec![],
+        }
+    }
+
+    pub fn push(&mut self, record: T) -> Result<()> {
+        self.batch_size += record.size();
+        self.batch.push(record);
+        if self.batch_size > self.memory {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    // Sort the batch and write it out as a run.
+    fn spill(&mut self) -> Result<()> {
+        let compare = &self.compare;
+        self.batch.sort_by(|a, b| compare(a, b));
+        let file = tempfile::tempfile().context("cannot create a temporary file to sort in")?;
+        let mut writer = BufWriter::new(file);
+        for record in &self.batch {
+            record
+                .write_to(&mut writer)
+                .context("cannot write a sorted run")?;
+        }
+        let mut file = writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("cannot write a sorted run")?;
+        file.rewind().context("cannot read a sorted run")?;
+        self.runs.push(Run {
+            reader: BufReader::new(file),
+            remaining: self.batch.len(),
+        });
+        self.batch.clear();
+        self.batch_size = 0;
+        Ok(())
+    }
+
+    /// Every record added, in order.
+    pub fn finish(mut self) -> Result<Sorted<'a, T>> {
+        if self.runs.is_empty() {
+            let compare = &self.compare;
+            self.batch.sort_by(|a, b| compare(a, b));
+        } else if !self.batch.is_empty() {
+            self.spill()?;
+        }
+        let mut heads = vec![];
+        for run in &mut self.runs {
+            heads.push(run.next()?);
+        }
+        Ok(Sorted {
+            in_memory: self.batch.into_iter(),
+            compare: self.compare,
+            runs: self.runs,
+            heads,
+        })
+    }
+}
+
+/// The records of a sort in order, straight from memory if it never spilled and
+/// otherwise merged from its runs.
+pub struct Sorted<'a, T> {
+    in_memory: std::vec::IntoIter<T>,
+    compare: Compare<'a, T>,
+    runs: Vec<Run>,
+    // the next record of each run, None once it is used up
+    heads: Vec<Option<T>>,
+}
+
+impl<T: Spill> Iterator for Sorted<'_, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.runs.is_empty() {
+            return self.in_memory.next().map(Ok);
+        }
+        // the earliest run wins a tie, which keeps the sort stable
+        let mut smallest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some(head) = head else {
+                continue;
+            };
+            let smaller = match smallest {
+                None => true,
+                Some(s) => {
+                    let current = self.heads[s].as_ref().expect("a head");
+                    (self.compare)(head, current) == Ordering::Less
+                }
+            };
+            if smaller {
+                smallest = Some(i);
+            }
+        }
+        let i = smallest?;
+        Some(
+            self.runs[i]
+                .next()
+                .map(|next| std::mem::replace(&mut self.heads[i], next).expect("a head")),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Records of (key, position added), sorted by key alone.
+    fn sort(keys: &[i64], memory: usize) -> (Vec<(i64, i64)>, usize) {
+        let mut sorter = Sorter::new(memory, |a: &(i64, i64), b: &(i64, i64)| a.0.cmp(&b.0));
+        for (i, key) in keys.iter().enumerate() {
+            sorter.push((*key, i as i64)).unwrap();
+        }
+        let runs = sorter.runs.len();
+        let sorted = sorter.finish().unwrap().collect::<Result<_>>().unwrap();
+        (sorted, runs)
+    }
+
+    #[test]
+    fn runs_merge_into_one_stable_order() {
+        let keys: Vec<i64> = (0..100).map(|i| (i * 37) % 10).collect();
+        let (in_memory, runs) = sort(&keys, 1 << 20);
+        assert_eq!(runs, 0);
+        // 16 bytes a record, so a run of every 7 or so
+        let (spilled, runs) = sort(&keys, 100);
+        assert_eq!(runs, 14);
+
+        assert_eq!(spilled, in_memory);
+        assert!(spilled
+            .windows(2)
+            .all(|w| w[0].0 < w[1].0 || (w[0].0 == w[1].0 && w[0].1 < w[1].1)));
+    }
+
+    #[test]
+    fn values_survive_a_run() {
+        let record = vec![
+            ColVal::Null,
+            ColVal::Boolean(true),
+            ColVal::Int(-7),
+            ColVal::Real(2.5),
+            ColVal::String("héllo".to_string()),
+        ];
+        let mut sorter = Sorter::new(0, |a: &Vec<ColVal>, b: &Vec<ColVal>| a.cmp(b));
+        sorter.push(record.clone()).unwrap();
+        assert_eq!(sorter.runs.len(), 1);
+        let sorted: Vec<Vec<ColVal>> = sorter.finish().unwrap().map(Result::unwrap).collect();
+        assert_eq!(format!("{sorted:?}"), format!("{:?}", [record]));
+    }
+}
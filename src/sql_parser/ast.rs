@@ -0,0 +1,1649 @@
+/*
+    The abstract syntax tree produced by the parser.
+
+    A Statement is one complete SQL command (SELECT, INSERT ...) while an Expr is a
+    scalar expression that appears inside statements, for example the `age + 1` in
+    `UPDATE users SET age = age + 1` or an index expression like `lower(name)`.
+*/
+use std::cmp::Ordering;
+use std::fmt::{self, Write};
+use std::hash::{Hash, Hasher};
+
+/// A column of a CREATE TABLE.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Column {
+    pub name: String,
+    pub type_name: Option<String>, // as written, e.g. VARCHAR(255)
+    pub default: Option<ColVal>,
+    pub collation: Option<String>, // None is BINARY
+    pub generated: Option<Generated>,
+    pub not_null: bool,
+}
+
+/// GENERATED ALWAYS AS (expr) [VIRTUAL | STORED], a column worked out from the others.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Generated {
+    pub expr: Expr,
+    // STORED rather than VIRTUAL, the default
+    pub stored: bool,
+}
+
+impl Column {
+    /// Whether the column is a VIRTUAL generated column, which isn't kept in the file.
+    pub fn is_virtual(&self) -> bool {
+        self.generated.as_ref().is_some_and(|g| !g.stored)
+    }
+}
+
+// NULL, True, "foo", 21, 1.5, X'CAFE' etc.
+#[derive(Debug, Clone)]
+pub enum ColVal {
+    Null,
+    Boolean(bool),
+    String(String),
+    Int(i64),
+    Real(f64), // never NaN, which the evaluator turns into NULL
+    Blob(Vec<u8>),
+}
+
+impl ColVal {
+    // SQLite sorts NULLs first, then numbers, then text, then blobs.
+    fn sort_class(&self) -> u8 {
+        match self {
+            ColVal::Null => 0,
+            ColVal::Boolean(_) | ColVal::Int(_) | ColVal::Real(_) => 1,
+            ColVal::String(_) => 2,
+            ColVal::Blob(_) => 3,
+        }
+    }
+}
+
+/// A REAL as SQLite prints it: 15 significant digits, so 0.1 + 0.2 is 0.3, and always
+/// with a decimal point or an exponent, so 2.0 doesn't read back as an integer.
+pub fn format_real(f: f64) -> String {
+    if f.is_infinite() {
+        return if f > 0.0 { "Inf" } else { "-Inf" }.to_string();
+    }
+    let rounded: f64 = format!("{f:.14e}").parse().expect("a formatted float");
+    if rounded != 0.0 && !(1e-4..1e15).contains(&rounded.abs()) {
+        let s = format!("{rounded:e}");
+        let (mantissa, exponent) = s.split_once('e').expect("an exponent");
+        let mantissa = if mantissa.contains('.') {
+            mantissa.to_string()
+        } else {
+            format!("{mantissa}.0")
+        };
+        return match exponent.strip_prefix('-') {
+            Some(e) => format!("{mantissa}e-{e:0>2}"),
+            None => format!("{mantissa}e+{exponent:0>2}"),
+        };
+    }
+    let s = rounded.to_string();
+    if s.contains('.') {
+        s
+    } else {
+        format!("{s}.0")
+    }
+}
+
+// A REAL as a literal that reads back as the very same number, since a result column
+// is kept as its text and parsed again: as SQLite prints it where that is exact, and
+// otherwise with every digit it takes.
+fn real_literal(r: f64) -> String {
+    if r.is_infinite() {
+        return if r > 0.0 { "1e999" } else { "-1e999" }.to_string();
+    }
+    let printed = format_real(r);
+    if printed.parse::<f64>() == Ok(r) {
+        return printed;
+    }
+    // the shortest digits that read back exactly, 0.30000000000000004 or 1.2345678901234567e-7
+    format!("{r:?}")
+}
+
+// Compare an integer with a REAL exactly, even where the integer has no exact f64.
+fn compare_int_real(i: i64, f: f64) -> Ordering {
+    match (i as f64).partial_cmp(&f).expect("not NaN") {
+        // f is a whole number in i64's range, give or take rounding at the very ends
+        Ordering::Equal => i.cmp(&(f as i64)),
+        ordering => ordering,
+    }
+}
+
+// Equality is the ordering's, so 1 = 1.0 as in SQL.
+impl PartialEq for ColVal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ColVal {}
+
+impl PartialOrd for ColVal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ColVal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Booleans are just the integers 0 and 1 to SQLite, but a boolean sorts before
+        // the number of the same value. Integers and REALs compare by value alone.
+        fn as_number(v: &ColVal) -> (f64, Option<i64>, u8) {
+            match v {
+                ColVal::Boolean(b) => (*b as i64 as f64, Some(*b as i64), 0),
+                ColVal::Int(n) => (*n as f64, Some(*n), 1),
+                ColVal::Real(f) => (*f, None, 1),
+                _ => unreachable!("only called on numbers"),
+            }
+        }
+
+        match (self, other) {
+            (ColVal::String(a), ColVal::String(b)) => a.cmp(b),
+            (ColVal::Blob(a), ColVal::Blob(b)) => a.cmp(b),
+            (a, b) if a.sort_class() == 1 && b.sort_class() == 1 => {
+                let ((fa, ia, ka), (fb, ib, kb)) = (as_number(a), as_number(b));
+                let by_value = match (ia, ib) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    (Some(a), None) => compare_int_real(a, fb),
+                    (None, Some(b)) => compare_int_real(b, fa).reverse(),
+                    (None, None) => fa.partial_cmp(&fb).expect("not NaN"),
+                };
+                by_value.then(ka.cmp(&kb))
+            }
+            (a, b) => a.sort_class().cmp(&b.sort_class()),
+        }
+    }
+}
+
+// Hashed consistently with equality, so 2 and 2.0 hash alike.
+impl Hash for ColVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            ColVal::Null => 0u8.hash(state),
+            ColVal::Boolean(b) => (1u8, *b).hash(state),
+            ColVal::Int(n) => (2u8, *n).hash(state),
+            ColVal::Real(f) if *f as i64 as f64 == *f => (2u8, *f as i64).hash(state),
+            ColVal::Real(f) => (3u8, f.to_bits()).hash(state),
+            ColVal::String(s) => (4u8, s).hash(state),
+            ColVal::Blob(b) => (5u8, b).hash(state),
+        }
+    }
+}
+
+impl fmt::Display for ColVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ColVal::Null => write!(f, "NULL"),
+            ColVal::Boolean(true) => write!(f, "TRUE"),
+            ColVal::Boolean(false) => write!(f, "FALSE"),
+            // as it would be written, a quote inside written twice
+            ColVal::String(s) => write!(f, "\"{}\"", s.replace('"', "\"\"")),
+            ColVal::Int(n) => write!(f, "{n}"),
+            ColVal::Real(r) => write!(f, "{}", format_real(*r)),
+            ColVal::Blob(b) => write!(f, "X'{}'", hex(b)),
+        }
+    }
+}
+
+/// A value as SQL that reads back as the same value, text in single quotes as SQLite
+/// writes it rather than in the double quotes Display uses.
+pub fn literal(value: &ColVal) -> String {
+    match value {
+        ColVal::String(s) => format!("'{}'", s.replace('\'', "''")),
+        ColVal::Real(f) if f.is_infinite() => {
+            (if *f > 0.0 { "1e999" } else { "-1e999" }).to_string()
+        }
+        // the shortest digits that read back as the same number, with a point or exponent
+        ColVal::Real(f) => format!("{f:?}"),
+        other => other.to_string(),
+    }
+}
+
+/// Bytes as upper case hex digits, as a blob literal and SQLite's hex() write them.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, b| {
+        let _ = write!(out, "{b:02X}");
+        out
+    })
+}
+
+impl From<i64> for ColVal {
+    fn from(n: i64) -> Self {
+        ColVal::Int(n)
+    }
+}
+
+impl From<f64> for ColVal {
+    fn from(r: f64) -> Self {
+        if r.is_nan() {
+            ColVal::Null
+        } else {
+            ColVal::Real(r)
+        }
+    }
+}
+
+impl From<&str> for ColVal {
+    fn from(s: &str) -> Self {
+        ColVal::String(s.to_string())
+    }
+}
+
+impl From<String> for ColVal {
+    fn from(s: String) -> Self {
+        ColVal::String(s)
+    }
+}
+
+impl<T: Into<ColVal>> From<Option<T>> for ColVal {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(ColVal::Null, Into::into)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct NewColumnVal {
+    pub column_name: String,
+    pub value: Expr,
+}
+
+/// How the table of a SELECT must be read, INDEXED BY an index or NOT INDEXED at all,
+/// whatever the planner would otherwise choose.
+#[derive(Debug, PartialEq, Clone)]
+pub enum IndexHint {
+    IndexedBy(String),
+    NotIndexed,
+}
+
+impl fmt::Display for IndexHint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexHint::IndexedBy(index) => write!(f, "INDEXED BY {index}"),
+            IndexHint::NotIndexed => write!(f, "NOT INDEXED"),
+        }
+    }
+}
+
+/// A table joined onto those before it in FROM, `JOIN orders AS o ON o.user_id = u.id` or
+/// `, orders o`. Its rows are paired with each row of the tables before it, and only the
+/// pairs `on` is TRUE for are kept.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Join {
+    pub table: String,
+    pub alias: Option<String>,
+    pub on: Option<Expr>,
+}
+
+impl fmt::Display for Join {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, " JOIN {}", self.table)?;
+        if let Some(alias) = &self.alias {
+            write!(f, " AS {alias}")?;
+        }
+        if let Some(on) = &self.on {
+            write!(f, " ON {on}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Statement {
+    Select {
+        columns: Vec<String>,
+        // empty for a SELECT without FROM, whose columns are worked out from no row
+        from_table: String,
+        // FROM (SELECT ...) AS name, a subquery read like a table. `from_table` is then its
+        // alias, or "subquery" without one.
+        from_select: Option<Box<Statement>>,
+        // FROM generate_series(1, 10), the arguments of a table-valued function, see vtab.rs
+        table_args: Vec<Expr>,
+        alias: Option<String>, // FROM users AS u
+        index_hint: Option<IndexHint>,
+        // the tables after the first, in the order written
+        joins: Vec<Join>,
+        where_clause: Option<Expr>,
+        // GROUP BY folds the rows with the same values of these into one row each
+        group_by: Vec<Expr>,
+        order_by: Vec<OrderingTerm>,
+        limit: Option<Limit>,
+    },
+    Insert {
+        into_table: String,
+        columns: Vec<NewColumnVal>,
+    },
+    // ORDER BY and LIMIT are only parsed with the update-delete-limit feature
+    Update {
+        table: String,
+        assignments: Vec<NewColumnVal>,
+        where_clause: Option<Expr>,
+        order_by: Vec<OrderingTerm>,
+        limit: Option<Limit>,
+    },
+    Delete {
+        from_table: String,
+        where_clause: Option<Expr>,
+        order_by: Vec<OrderingTerm>,
+        limit: Option<Limit>,
+    },
+    // SELECT ... UNION SELECT ... [ORDER BY ...] [LIMIT ...], the operators applied left
+    // to right and the ORDER BY and LIMIT to the rows of them all
+    Compound {
+        first: Box<Statement>,
+        rest: Vec<(CompoundOperator, Statement)>,
+        order_by: Vec<OrderingTerm>,
+        limit: Option<Limit>,
+    },
+    // WITH name AS (SELECT ...), ... <body>
+    With {
+        ctes: Vec<CommonTableExpr>,
+        body: Box<Statement>,
+    },
+    CreateTable(CreateTable),
+    CreateIndex(CreateIndex),
+    CreateView(CreateView),
+    CreateTrigger(CreateTrigger),
+    CreateVirtualTable(CreateVirtualTable),
+    Begin(TransactionMode),
+    Commit,
+    Rollback,
+    Savepoint(String),
+    Release(String),
+    // ROLLBACK TO name
+    RollbackTo(String),
+    // ATTACH DATABASE "file" AS name
+    Attach {
+        path: String,
+        name: String,
+    },
+    Detach(String),
+    Pragma(Pragma),
+    // ANALYZE [table or index]
+    Analyze(Option<String>),
+    // REINDEX [table, index or collation] rebuilds indexes from their tables' rows
+    Reindex(Option<String>),
+    // VACUUM rebuilds every table and index
+    Vacuum,
+    // EXPLAIN <statement> lists the bytecode the statement would run instead of running it
+    Explain(Box<Statement>),
+    // EXPLAIN QUERY PLAN <statement> shows the plan instead
+    ExplainQueryPlan(Box<Statement>),
+    // EXPLAIN ANALYZE <statement> runs the statement and shows its plan with what each
+    // operator did, see profile.rs
+    ExplainAnalyze(Box<Statement>),
+}
+
+/// PRAGMA name, PRAGMA name = value or PRAGMA name(value). The value is kept as written,
+/// without quotes, as pragmas take names like WAL as often as numbers.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Pragma {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+/// A named query in a WITH clause that the rest of the statement can read like a table,
+/// `name [(column, ...)] AS (SELECT ...)`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CommonTableExpr {
+    pub name: String,
+    pub columns: Vec<String>, // renames the query's result columns when given
+    pub select: Box<Statement>,
+}
+
+/// What joins the SELECTs of a compound SELECT. Only UNION ALL keeps rows that are the
+/// same, the others return each row once.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompoundOperator {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+impl fmt::Display for CompoundOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompoundOperator::Union => write!(f, "UNION"),
+            CompoundOperator::UnionAll => write!(f, "UNION ALL"),
+            CompoundOperator::Intersect => write!(f, "INTERSECT"),
+            CompoundOperator::Except => write!(f, "EXCEPT"),
+        }
+    }
+}
+
+/// One `expr [ASC | DESC]` of an ORDER BY clause.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OrderingTerm {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+/// LIMIT count [OFFSET offset], also written LIMIT offset, count.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Limit {
+    pub count: Expr,
+    pub offset: Option<Expr>,
+}
+
+/// When a transaction takes its locks, BEGIN DEFERRED (the default) waits until the first
+/// read or write, IMMEDIATE starts writing straight away and EXCLUSIVE also locks out readers.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum TransactionMode {
+    #[default]
+    Deferred,
+    Immediate,
+    Exclusive,
+}
+
+impl fmt::Display for TransactionMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionMode::Deferred => write!(f, "DEFERRED"),
+            TransactionMode::Immediate => write!(f, "IMMEDIATE"),
+            TransactionMode::Exclusive => write!(f, "EXCLUSIVE"),
+        }
+    }
+}
+
+/// CREATE TABLE [IF NOT EXISTS] name (column [type] [PRIMARY KEY [AUTOINCREMENT]], ...,
+/// [PRIMARY KEY (a, b)]) [WITHOUT ROWID];
+#[derive(Debug, PartialEq, Clone)]
+pub struct CreateTable {
+    pub name: String,
+    pub columns: Vec<Column>,
+    // in key order, from either the column's or the table's PRIMARY KEY, empty if none
+    pub primary_key: Vec<String>,
+    // from the columns' REFERENCES clauses and the table's FOREIGN KEYs
+    pub foreign_keys: Vec<ForeignKey>,
+    // INTEGER PRIMARY KEY AUTOINCREMENT, rowids are never used twice even across reopens
+    pub autoincrement: bool,
+    // from the columns' CHECKs and the table's, in the order they are written
+    pub checks: Vec<Expr>,
+    pub without_rowid: bool,
+    pub if_not_exists: bool,
+}
+
+/// FOREIGN KEY (a, b) REFERENCES parent (x, y) [ON DELETE action] [ON UPDATE action], or
+/// REFERENCES on a column for a key of one column.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ForeignKey {
+    pub columns: Vec<String>,
+    pub parent: String,
+    pub parent_columns: Vec<String>, // empty means the parent's primary key
+    pub on_delete: ForeignKeyAction,
+    pub on_update: ForeignKeyAction,
+}
+
+/// What happens to the child rows when the parent row they refer to is deleted or its
+/// key changes.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ForeignKeyAction {
+    #[default]
+    NoAction,
+    Restrict,
+    SetNull,
+    SetDefault,
+    Cascade,
+}
+
+impl fmt::Display for ForeignKeyAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ForeignKeyAction::NoAction => write!(f, "NO ACTION"),
+            ForeignKeyAction::Restrict => write!(f, "RESTRICT"),
+            ForeignKeyAction::SetNull => write!(f, "SET NULL"),
+            ForeignKeyAction::SetDefault => write!(f, "SET DEFAULT"),
+            ForeignKeyAction::Cascade => write!(f, "CASCADE"),
+        }
+    }
+}
+
+impl fmt::Display for ForeignKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "FOREIGN KEY ({}) REFERENCES {}",
+            self.columns.join(", "),
+            self.parent
+        )?;
+        if !self.parent_columns.is_empty() {
+            write!(f, " ({})", self.parent_columns.join(", "))?;
+        }
+        if self.on_delete != ForeignKeyAction::NoAction {
+            write!(f, " ON DELETE {}", self.on_delete)?;
+        }
+        if self.on_update != ForeignKeyAction::NoAction {
+            write!(f, " ON UPDATE {}", self.on_update)?;
+        }
+        Ok(())
+    }
+}
+
+impl CreateTable {
+    pub fn column_names(&self) -> Vec<String> {
+        self.columns.iter().map(|c| c.name.clone()).collect()
+    }
+
+    /// The position of the column that is another name for the rowid, a rowid table's
+    /// INTEGER PRIMARY KEY. Only that exact type name counts, an INT PRIMARY KEY is an
+    /// ordinary column, as in SQLite.
+    pub fn rowid_alias(&self) -> Option<usize> {
+        let [key] = self.primary_key.as_slice() else {
+            return None;
+        };
+        if self.without_rowid {
+            return None;
+        }
+        self.columns.iter().position(|c| {
+            c.name == *key
+                && c.type_name
+                    .as_deref()
+                    .is_some_and(|t| t.eq_ignore_ascii_case("INTEGER"))
+        })
+    }
+}
+
+/// CREATE [UNIQUE] INDEX [IF NOT EXISTS] name ON table (column, lower(other), ...);
+#[derive(Debug, PartialEq, Clone)]
+pub struct CreateIndex {
+    pub name: String,
+    pub table: String,
+    pub columns: Vec<Expr>, // plain columns or deterministic expressions over them
+    pub unique: bool,
+    pub if_not_exists: bool,
+}
+
+/// CREATE VIEW [IF NOT EXISTS] name [(column, ...)] AS SELECT ...;
+#[derive(Debug, PartialEq, Clone)]
+pub struct CreateView {
+    pub name: String,
+    pub columns: Vec<String>, // renames the query's result columns when given
+    pub select: Box<Statement>,
+    pub if_not_exists: bool,
+}
+
+/// CREATE VIRTUAL TABLE [IF NOT EXISTS] name USING module [(argument, ...)];
+///
+/// The arguments are kept as written for the module to make sense of, see vtab.rs.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CreateVirtualTable {
+    pub name: String,
+    pub module: String,
+    pub args: Vec<String>,
+    pub if_not_exists: bool,
+}
+
+/// CREATE TRIGGER [IF NOT EXISTS] name BEFORE | AFTER event ON table [FOR EACH ROW]
+/// [WHEN condition] BEGIN statement; ... END;
+#[derive(Debug, PartialEq, Clone)]
+pub struct CreateTrigger {
+    pub name: String,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    pub table: String,
+    pub when: Option<Expr>, // may refer to NEW.column and OLD.column
+    pub body: Vec<Statement>,
+    pub if_not_exists: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum TriggerTiming {
+    #[default]
+    Before,
+    After,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TriggerEvent {
+    Insert,
+    // UPDATE OF a, b only fires when one of the listed columns is assigned, an empty list
+    // fires for any UPDATE
+    Update(Vec<String>),
+    Delete,
+}
+
+impl fmt::Display for TriggerTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TriggerTiming::Before => write!(f, "BEFORE"),
+            TriggerTiming::After => write!(f, "AFTER"),
+        }
+    }
+}
+
+impl fmt::Display for TriggerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TriggerEvent::Insert => write!(f, "INSERT"),
+            TriggerEvent::Update(columns) if columns.is_empty() => write!(f, "UPDATE"),
+            TriggerEvent::Update(columns) => write!(f, "UPDATE OF {}", columns.join(", ")),
+            TriggerEvent::Delete => write!(f, "DELETE"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum AggregateFunc {
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+/// An aggregate result column, `COUNT(*)` or `SUM([DISTINCT] column)` and the like.
+/// Like the other result columns it is kept as text in a SELECT, see `sql_parser::aggregate`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Aggregate {
+    pub func: AggregateFunc,
+    pub arg: Option<String>, // None for COUNT(*)
+    pub distinct: bool,
+}
+
+impl fmt::Display for AggregateFunc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AggregateFunc::Count => "COUNT",
+            AggregateFunc::Sum => "SUM",
+            AggregateFunc::Avg => "AVG",
+            AggregateFunc::Min => "MIN",
+            AggregateFunc::Max => "MAX",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let distinct = if self.distinct { "DISTINCT " } else { "" };
+        let arg = self.arg.as_deref().unwrap_or("*");
+        write!(f, "{}({distinct}{arg})", self.func)
+    }
+}
+
+/// A window function result column, `RANK() OVER (PARTITION BY dept ORDER BY pay DESC)`, or
+/// an aggregate over a window as in `SUM(pay) OVER (ORDER BY hired)`. Kept as text in a
+/// SELECT like the aggregates, see `sql_parser::window_function`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct WindowFunction {
+    pub func: WindowFunc,
+    pub partition_by: Vec<String>,
+    pub order_by: Vec<(String, bool)>, // each column and whether it is DESC
+    pub frame: Option<Frame>,          // None for the default frame
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum WindowFunc {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Aggregate(Aggregate),
+}
+
+/// `ROWS` or `RANGE BETWEEN start AND end`, the rows of its partition a row's aggregate is
+/// taken over. Without one it is RANGE BETWEEN UNBOUNDED PRECEDING AND CURRENT ROW.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Frame {
+    pub range: bool, // RANGE counts a row's peers, the rows equal in the ORDER BY, as one
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FrameBound {
+    UnboundedPreceding,
+    Preceding(u64),
+    CurrentRow,
+    Following(u64),
+    UnboundedFollowing,
+}
+
+impl WindowFunction {
+    /// The columns the function reads, for name resolution to rewrite.
+    pub fn columns_mut(&mut self) -> Vec<&mut String> {
+        let arg = match &mut self.func {
+            WindowFunc::Aggregate(aggregate) => aggregate.arg.as_mut(),
+            _ => None,
+        };
+        arg.into_iter()
+            .chain(self.partition_by.iter_mut())
+            .chain(self.order_by.iter_mut().map(|(column, _)| column))
+            .collect()
+    }
+}
+
+impl fmt::Display for WindowFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.func {
+            WindowFunc::RowNumber => write!(f, "ROW_NUMBER()")?,
+            WindowFunc::Rank => write!(f, "RANK()")?,
+            WindowFunc::DenseRank => write!(f, "DENSE_RANK()")?,
+            WindowFunc::Aggregate(aggregate) => write!(f, "{aggregate}")?,
+        }
+        let mut clauses = vec![];
+        if !self.partition_by.is_empty() {
+            clauses.push(format!("PARTITION BY {}", self.partition_by.join(", ")));
+        }
+        if !self.order_by.is_empty() {
+            let terms: Vec<String> = self
+                .order_by
+                .iter()
+                .map(|(column, descending)| match descending {
+                    true => format!("{column} DESC"),
+                    false => column.clone(),
+                })
+                .collect();
+            clauses.push(format!("ORDER BY {}", terms.join(", ")));
+        }
+        if let Some(frame) = &self.frame {
+            clauses.push(frame.to_string());
+        }
+        write!(f, " OVER ({})", clauses.join(" "))
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let units = if self.range { "RANGE" } else { "ROWS" };
+        write!(f, "{units} BETWEEN {} AND {}", self.start, self.end)
+    }
+}
+
+impl fmt::Display for FrameBound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameBound::UnboundedPreceding => write!(f, "UNBOUNDED PRECEDING"),
+            FrameBound::Preceding(n) => write!(f, "{n} PRECEDING"),
+            FrameBound::CurrentRow => write!(f, "CURRENT ROW"),
+            FrameBound::Following(n) => write!(f, "{n} FOLLOWING"),
+            FrameBound::UnboundedFollowing => write!(f, "UNBOUNDED FOLLOWING"),
+        }
+    }
+}
+
+impl Statement {
+    /// Visit every expression of the statement, in the order they appear in the SQL text.
+    pub fn walk_exprs<'e>(&'e self, visit: &mut impl FnMut(&'e Expr)) {
+        match self {
+            Statement::Select {
+                from_select,
+                table_args,
+                joins,
+                where_clause,
+                group_by,
+                order_by,
+                limit,
+                ..
+            } => {
+                if let Some(select) = from_select {
+                    select.walk_exprs(visit);
+                }
+                table_args.iter().for_each(|e| e.walk(visit));
+                joins.iter().flat_map(|j| &j.on).for_each(|e| e.walk(visit));
+                if let Some(e) = where_clause {
+                    e.walk(visit);
+                }
+                group_by.iter().for_each(|e| e.walk(visit));
+                order_by.iter().for_each(|o| o.expr.walk(visit));
+                if let Some(limit) = limit {
+                    limit.count.walk(visit);
+                    limit.offset.iter().for_each(|e| e.walk(visit));
+                }
+            }
+            Statement::Insert { columns, .. } => columns.iter().for_each(|c| c.value.walk(visit)),
+            Statement::Update {
+                assignments,
+                where_clause,
+                order_by,
+                limit,
+                ..
+            } => {
+                assignments.iter().for_each(|a| a.value.walk(visit));
+                if let Some(e) = where_clause {
+                    e.walk(visit);
+                }
+                order_by.iter().for_each(|o| o.expr.walk(visit));
+                if let Some(limit) = limit {
+                    limit.count.walk(visit);
+                    limit.offset.iter().for_each(|e| e.walk(visit));
+                }
+            }
+            Statement::Delete {
+                where_clause,
+                order_by,
+                limit,
+                ..
+            } => {
+                if let Some(e) = where_clause {
+                    e.walk(visit);
+                }
+                order_by.iter().for_each(|o| o.expr.walk(visit));
+                if let Some(limit) = limit {
+                    limit.count.walk(visit);
+                    limit.offset.iter().for_each(|e| e.walk(visit));
+                }
+            }
+            Statement::CreateIndex(index) => index.columns.iter().for_each(|c| c.walk(visit)),
+            Statement::CreateView(view) => view.select.walk_exprs(visit),
+            Statement::CreateTrigger(trigger) => {
+                trigger.when.iter().for_each(|e| e.walk(visit));
+                trigger.body.iter().for_each(|s| s.walk_exprs(visit));
+            }
+            Statement::Compound {
+                first,
+                rest,
+                order_by,
+                limit,
+            } => {
+                first.walk_exprs(visit);
+                rest.iter().for_each(|(_, select)| select.walk_exprs(visit));
+                order_by.iter().for_each(|o| o.expr.walk(visit));
+                if let Some(limit) = limit {
+                    limit.count.walk(visit);
+                    limit.offset.iter().for_each(|e| e.walk(visit));
+                }
+            }
+            Statement::With { ctes, body } => {
+                ctes.iter().for_each(|cte| cte.select.walk_exprs(visit));
+                body.walk_exprs(visit);
+            }
+            Statement::CreateTable(_)
+            | Statement::CreateVirtualTable(_)
+            | Statement::Begin(_)
+            | Statement::Commit
+            | Statement::Rollback
+            | Statement::Savepoint(_)
+            | Statement::Release(_)
+            | Statement::RollbackTo(_)
+            | Statement::Attach { .. }
+            | Statement::Detach(_)
+            | Statement::Pragma(_)
+            | Statement::Analyze(_)
+            | Statement::Reindex(_)
+            | Statement::Vacuum => {}
+            Statement::Explain(statement)
+            | Statement::ExplainQueryPlan(statement)
+            | Statement::ExplainAnalyze(statement) => statement.walk_exprs(visit),
+        }
+    }
+
+    /// The tables (or views, or CTEs) the statement names anywhere, subqueries included.
+    pub fn tables(&self) -> Vec<&str> {
+        let mut tables = vec![];
+        self.named_tables(&mut tables);
+        self.walk_exprs(&mut |e| {
+            if let Expr::InSelect { select, .. }
+            | Expr::Exists { select, .. }
+            | Expr::Subquery(select) = e
+            {
+                select.named_tables(&mut tables);
+            }
+        });
+        tables.sort();
+        tables.dedup();
+        tables
+    }
+
+    // The tables named by the statement itself rather than by its expressions.
+    fn named_tables<'s>(&'s self, tables: &mut Vec<&'s str>) {
+        match self {
+            Statement::Select {
+                from_select: Some(select),
+                joins,
+                ..
+            } => {
+                select.named_tables(tables);
+                tables.extend(joins.iter().map(|j| j.table.as_str()));
+            }
+            Statement::Select { from_table, .. } if from_table.is_empty() => {}
+            Statement::Select {
+                from_table, joins, ..
+            } => {
+                tables.push(from_table);
+                tables.extend(joins.iter().map(|j| j.table.as_str()));
+            }
+            Statement::Delete { from_table, .. } => tables.push(from_table),
+            Statement::Insert { into_table, .. } => tables.push(into_table),
+            Statement::Update { table, .. } => tables.push(table),
+            Statement::Compound { first, rest, .. } => {
+                first.named_tables(tables);
+                rest.iter()
+                    .for_each(|(_, select)| select.named_tables(tables));
+            }
+            Statement::With { ctes, body } => {
+                ctes.iter().for_each(|cte| cte.select.named_tables(tables));
+                body.named_tables(tables);
+            }
+            Statement::CreateTable(table) => tables.push(&table.name),
+            Statement::CreateVirtualTable(table) => tables.push(&table.name),
+            Statement::CreateIndex(index) => tables.push(&index.table),
+            Statement::CreateView(view) => view.select.named_tables(tables),
+            Statement::CreateTrigger(trigger) => {
+                tables.push(&trigger.table);
+                trigger.body.iter().for_each(|s| s.named_tables(tables));
+            }
+            Statement::Explain(statement)
+            | Statement::ExplainQueryPlan(statement)
+            | Statement::ExplainAnalyze(statement) => statement.named_tables(tables),
+            Statement::Begin(_)
+            | Statement::Commit
+            | Statement::Rollback
+            | Statement::Savepoint(_)
+            | Statement::Release(_)
+            | Statement::RollbackTo(_)
+            | Statement::Attach { .. }
+            | Statement::Detach(_)
+            | Statement::Pragma(_)
+            | Statement::Analyze(_)
+            | Statement::Reindex(_)
+            | Statement::Vacuum => {}
+        }
+    }
+
+    /// Like walk_exprs but allows rewriting expressions in place.
+    pub fn walk_exprs_mut(&mut self, visit: &mut impl FnMut(&mut Expr)) {
+        match self {
+            Statement::Select {
+                from_select,
+                table_args,
+                joins,
+                where_clause,
+                group_by,
+                order_by,
+                limit,
+                ..
+            } => {
+                if let Some(select) = from_select {
+                    select.walk_exprs_mut(visit);
+                }
+                table_args.iter_mut().for_each(|e| e.walk_mut(visit));
+                joins
+                    .iter_mut()
+                    .flat_map(|j| &mut j.on)
+                    .for_each(|e| e.walk_mut(visit));
+                if let Some(e) = where_clause {
+                    e.walk_mut(visit);
+                }
+                group_by.iter_mut().for_each(|e| e.walk_mut(visit));
+                order_by.iter_mut().for_each(|o| o.expr.walk_mut(visit));
+                if let Some(limit) = limit {
+                    limit.count.walk_mut(visit);
+                    limit.offset.iter_mut().for_each(|e| e.walk_mut(visit));
+                }
+            }
+            Statement::Insert { columns, .. } => {
+                columns.iter_mut().for_each(|c| c.value.walk_mut(visit))
+            }
+            Statement::Update {
+                assignments,
+                where_clause,
+                order_by,
+                limit,
+                ..
+            } => {
+                assignments.iter_mut().for_each(|a| a.value.walk_mut(visit));
+                if let Some(e) = where_clause {
+                    e.walk_mut(visit);
+                }
+                order_by.iter_mut().for_each(|o| o.expr.walk_mut(visit));
+                if let Some(limit) = limit {
+                    limit.count.walk_mut(visit);
+                    limit.offset.iter_mut().for_each(|e| e.walk_mut(visit));
+                }
+            }
+            Statement::Delete {
+                where_clause,
+                order_by,
+                limit,
+                ..
+            } => {
+                if let Some(e) = where_clause {
+                    e.walk_mut(visit);
+                }
+                order_by.iter_mut().for_each(|o| o.expr.walk_mut(visit));
+                if let Some(limit) = limit {
+                    limit.count.walk_mut(visit);
+                    limit.offset.iter_mut().for_each(|e| e.walk_mut(visit));
+                }
+            }
+            Statement::CreateIndex(index) => {
+                index.columns.iter_mut().for_each(|c| c.walk_mut(visit))
+            }
+            Statement::CreateView(view) => view.select.walk_exprs_mut(visit),
+            Statement::CreateTrigger(trigger) => {
+                trigger.when.iter_mut().for_each(|e| e.walk_mut(visit));
+                trigger
+                    .body
+                    .iter_mut()
+                    .for_each(|s| s.walk_exprs_mut(visit));
+            }
+            Statement::Compound {
+                first,
+                rest,
+                order_by,
+                limit,
+            } => {
+                first.walk_exprs_mut(visit);
+                rest.iter_mut()
+                    .for_each(|(_, select)| select.walk_exprs_mut(visit));
+                order_by.iter_mut().for_each(|o| o.expr.walk_mut(visit));
+                if let Some(limit) = limit {
+                    limit.count.walk_mut(visit);
+                    limit.offset.iter_mut().for_each(|e| e.walk_mut(visit));
+                }
+            }
+            Statement::With { ctes, body } => {
+                ctes.iter_mut()
+                    .for_each(|cte| cte.select.walk_exprs_mut(visit));
+                body.walk_exprs_mut(visit);
+            }
+            Statement::CreateTable(_)
+            | Statement::CreateVirtualTable(_)
+            | Statement::Begin(_)
+            | Statement::Commit
+            | Statement::Rollback
+            | Statement::Savepoint(_)
+            | Statement::Release(_)
+            | Statement::RollbackTo(_)
+            | Statement::Attach { .. }
+            | Statement::Detach(_)
+            | Statement::Pragma(_)
+            | Statement::Analyze(_)
+            | Statement::Reindex(_)
+            | Statement::Vacuum => {}
+            Statement::Explain(statement)
+            | Statement::ExplainQueryPlan(statement)
+            | Statement::ExplainAnalyze(statement) => statement.walk_exprs_mut(visit),
+        }
+    }
+}
+
+// Write items separated by commas.
+fn comma_separated<T: fmt::Display>(f: &mut fmt::Formatter, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{item}")?;
+    }
+    Ok(())
+}
+
+fn fmt_where_order_limit(
+    f: &mut fmt::Formatter,
+    where_clause: &Option<Expr>,
+    order_by: &[OrderingTerm],
+    limit: &Option<Limit>,
+) -> fmt::Result {
+    if let Some(e) = where_clause {
+        write!(f, " WHERE {e}")?;
+    }
+    if !order_by.is_empty() {
+        write!(f, " ORDER BY ")?;
+        comma_separated(f, order_by)?;
+    }
+    if let Some(limit) = limit {
+        write!(f, " {limit}")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for CommonTableExpr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if !self.columns.is_empty() {
+            write!(f, " ({})", self.columns.join(", "))?;
+        }
+        write!(f, " AS ({})", self.select)
+    }
+}
+
+impl fmt::Display for OrderingTerm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.expr)?;
+        if self.descending {
+            write!(f, " DESC")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LIMIT {}", self.count)?;
+        if let Some(offset) = &self.offset {
+            write!(f, " OFFSET {offset}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Statements print back as SQL that parses to the same statement, minus the `;`.
+impl fmt::Display for Statement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Statement::Select {
+                columns,
+                from_table,
+                from_select,
+                table_args,
+                alias,
+                index_hint,
+                joins,
+                where_clause,
+                group_by,
+                order_by,
+                limit,
+            } => {
+                write!(f, "SELECT {}", columns.join(", "))?;
+                match from_select {
+                    Some(select) => write!(f, " FROM ({select})")?,
+                    None if from_table.is_empty() => {}
+                    None => write!(f, " FROM {from_table}")?,
+                }
+                if !table_args.is_empty() {
+                    write!(f, "(")?;
+                    comma_separated(f, table_args)?;
+                    write!(f, ")")?;
+                }
+                if let Some(alias) = alias {
+                    write!(f, " AS {alias}")?;
+                }
+                if let Some(hint) = index_hint {
+                    write!(f, " {hint}")?;
+                }
+                for join in joins {
+                    write!(f, "{join}")?;
+                }
+                if let Some(e) = where_clause {
+                    write!(f, " WHERE {e}")?;
+                }
+                if !group_by.is_empty() {
+                    write!(f, " GROUP BY ")?;
+                    comma_separated(f, group_by)?;
+                }
+                fmt_where_order_limit(f, &None, order_by, limit)
+            }
+            Statement::Insert {
+                into_table,
+                columns,
+            } => {
+                let names: Vec<&str> = columns.iter().map(|c| c.column_name.as_str()).collect();
+                let values: Vec<&Expr> = columns.iter().map(|c| &c.value).collect();
+                write!(
+                    f,
+                    "INSERT INTO {into_table} ({}) VALUES (",
+                    names.join(", ")
+                )?;
+                comma_separated(f, &values)?;
+                write!(f, ")")
+            }
+            Statement::Update {
+                table,
+                assignments,
+                where_clause,
+                order_by,
+                limit,
+            } => {
+                write!(f, "UPDATE {table} SET ")?;
+                for (i, a) in assignments.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{} = {}", a.column_name, a.value)?;
+                }
+                fmt_where_order_limit(f, where_clause, order_by, limit)
+            }
+            Statement::Delete {
+                from_table,
+                where_clause,
+                order_by,
+                limit,
+            } => {
+                write!(f, "DELETE FROM {from_table}")?;
+                fmt_where_order_limit(f, where_clause, order_by, limit)
+            }
+            Statement::Compound {
+                first,
+                rest,
+                order_by,
+                limit,
+            } => {
+                write!(f, "{first}")?;
+                for (operator, select) in rest {
+                    write!(f, " {operator} {select}")?;
+                }
+                fmt_where_order_limit(f, &None, order_by, limit)
+            }
+            Statement::With { ctes, body } => {
+                write!(f, "WITH ")?;
+                comma_separated(f, ctes)?;
+                write!(f, " {body}")
+            }
+            Statement::CreateTable(table) => {
+                write!(f, "CREATE TABLE ")?;
+                if table.if_not_exists {
+                    write!(f, "IF NOT EXISTS ")?;
+                }
+                // AUTOINCREMENT can only be said of the column itself
+                let inline_key = table.autoincrement.then(|| table.rowid_alias()).flatten();
+                let mut defs: Vec<String> = table
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| {
+                        let mut def = c.name.clone();
+                        if let Some(type_name) = &c.type_name {
+                            def += &format!(" {type_name}");
+                        }
+                        if inline_key == Some(i) {
+                            def += " PRIMARY KEY AUTOINCREMENT";
+                        }
+                        if c.not_null {
+                            def += " NOT NULL";
+                        }
+                        if let Some(default) = &c.default {
+                            def += &format!(" DEFAULT {default}");
+                        }
+                        if let Some(collation) = &c.collation {
+                            def += &format!(" COLLATE {collation}");
+                        }
+                        if let Some(generated) = &c.generated {
+                            let kind = if generated.stored {
+                                "STORED"
+                            } else {
+                                "VIRTUAL"
+                            };
+                            def += &format!(" GENERATED ALWAYS AS ({}) {kind}", generated.expr);
+                        }
+                        def
+                    })
+                    .collect();
+                if !table.primary_key.is_empty() && inline_key.is_none() {
+                    defs.push(format!("PRIMARY KEY ({})", table.primary_key.join(", ")));
+                }
+                defs.extend(table.foreign_keys.iter().map(|k| k.to_string()));
+                defs.extend(table.checks.iter().map(|e| format!("CHECK ({e})")));
+                write!(f, "{} ({})", table.name, defs.join(", "))?;
+                if table.without_rowid {
+                    write!(f, " WITHOUT ROWID")?;
+                }
+                Ok(())
+            }
+            Statement::CreateIndex(index) => {
+                write!(f, "CREATE ")?;
+                if index.unique {
+                    write!(f, "UNIQUE ")?;
+                }
+                write!(f, "INDEX ")?;
+                if index.if_not_exists {
+                    write!(f, "IF NOT EXISTS ")?;
+                }
+                write!(f, "{} ON {} (", index.name, index.table)?;
+                comma_separated(f, &index.columns)?;
+                write!(f, ")")
+            }
+            Statement::CreateView(view) => {
+                write!(f, "CREATE VIEW ")?;
+                if view.if_not_exists {
+                    write!(f, "IF NOT EXISTS ")?;
+                }
+                write!(f, "{}", view.name)?;
+                if !view.columns.is_empty() {
+                    write!(f, " ({})", view.columns.join(", "))?;
+                }
+                write!(f, " AS {}", view.select)
+            }
+            Statement::CreateVirtualTable(table) => {
+                write!(f, "CREATE VIRTUAL TABLE ")?;
+                if table.if_not_exists {
+                    write!(f, "IF NOT EXISTS ")?;
+                }
+                write!(f, "{} USING {}", table.name, table.module)?;
+                if !table.args.is_empty() {
+                    write!(f, "({})", table.args.join(", "))?;
+                }
+                Ok(())
+            }
+            Statement::CreateTrigger(trigger) => {
+                write!(f, "CREATE TRIGGER ")?;
+                if trigger.if_not_exists {
+                    write!(f, "IF NOT EXISTS ")?;
+                }
+                write!(
+                    f,
+                    "{} {} {} ON {}",
+                    trigger.name, trigger.timing, trigger.event, trigger.table
+                )?;
+                if let Some(e) = &trigger.when {
+                    write!(f, " WHEN {e}")?;
+                }
+                write!(f, " BEGIN")?;
+                for statement in &trigger.body {
+                    write!(f, " {statement};")?;
+                }
+                write!(f, " END")
+            }
+            Statement::Begin(mode) => write!(f, "BEGIN {mode}"),
+            Statement::Commit => write!(f, "COMMIT"),
+            Statement::Rollback => write!(f, "ROLLBACK"),
+            Statement::Savepoint(name) => write!(f, "SAVEPOINT {name}"),
+            Statement::Release(name) => write!(f, "RELEASE {name}"),
+            Statement::RollbackTo(name) => write!(f, "ROLLBACK TO {name}"),
+            Statement::Attach { path, name } => write!(f, "ATTACH DATABASE \"{path}\" AS {name}"),
+            Statement::Detach(name) => write!(f, "DETACH DATABASE {name}"),
+            Statement::Pragma(Pragma { name, value: None }) => write!(f, "PRAGMA {name}"),
+            Statement::Pragma(Pragma {
+                name,
+                value: Some(value),
+            }) => write!(f, "PRAGMA {name} = {value}"),
+            Statement::Analyze(None) => write!(f, "ANALYZE"),
+            Statement::Analyze(Some(name)) => write!(f, "ANALYZE {name}"),
+            Statement::Reindex(None) => write!(f, "REINDEX"),
+            Statement::Reindex(Some(name)) => write!(f, "REINDEX {name}"),
+            Statement::Vacuum => write!(f, "VACUUM"),
+            Statement::Explain(statement) => write!(f, "EXPLAIN {statement}"),
+            Statement::ExplainQueryPlan(statement) => write!(f, "EXPLAIN QUERY PLAN {statement}"),
+            Statement::ExplainAnalyze(statement) => write!(f, "EXPLAIN ANALYZE {statement}"),
+        }
+    }
+}
+
+// Bind parameters: ?, ?NNN, :name, @name or $name
+#[derive(Debug, PartialEq, Clone)]
+pub enum Placeholder {
+    Anonymous,
+    Numbered(usize),
+    Named(String), // includes the prefix character, e.g. ":name"
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    // does nothing to the value, but `+age` is no longer the column age as far as the
+    // planner is concerned, so no index on age is used for it
+    Plus,
+    Not,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    // IS and IS NOT, = and != with NULL equal to NULL
+    Is,
+    IsNot,
+    And,
+    Or,
+    // JSON's -> and ->>, see json.rs
+    Arrow,
+    DoubleArrow,
+}
+
+impl BinaryOp {
+    // How tightly the operator binds, higher binds tighter. Matches the parser.
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq
+            | BinaryOp::NotEq
+            | BinaryOp::Lt
+            | BinaryOp::LtEq
+            | BinaryOp::Gt
+            | BinaryOp::GtEq
+            | BinaryOp::Is
+            | BinaryOp::IsNot => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div => 6,
+            BinaryOp::Arrow | BinaryOp::DoubleArrow => 7,
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Eq => "=",
+            BinaryOp::NotEq => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::LtEq => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::GtEq => ">=",
+            BinaryOp::Is => "IS",
+            BinaryOp::IsNot => "IS NOT",
+            BinaryOp::And => "AND",
+            BinaryOp::Or => "OR",
+            BinaryOp::Arrow => "->",
+            BinaryOp::DoubleArrow => "->>",
+        };
+        write!(f, "{op}")
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Literal(ColVal),
+    Placeholder(Placeholder),
+    Column(String),
+    // table.column, also NEW.column and OLD.column in a trigger
+    QualifiedColumn {
+        table: String,
+        column: String,
+    },
+    // (a, b, c), only meaningful as an operand of a comparison or IN
+    Row(Vec<Expr>),
+    Function {
+        name: String,
+        args: Vec<Expr>,
+    },
+    // CAST(x AS INTEGER)
+    Cast {
+        expr: Box<Expr>,
+        type_name: String,
+    },
+    // name COLLATE NOCASE
+    Collate {
+        expr: Box<Expr>,
+        collation: String,
+    },
+    // x [NOT] IN (1, 2, 3)
+    InList {
+        expr: Box<Expr>,
+        list: Vec<Expr>,
+        negated: bool,
+    },
+    // x [NOT] IN (SELECT ...)
+    InSelect {
+        expr: Box<Expr>,
+        select: Box<Statement>,
+        negated: bool,
+    },
+    // [NOT] EXISTS (SELECT ...)
+    Exists {
+        select: Box<Statement>,
+        negated: bool,
+    },
+    // (SELECT ...) as a value, the first column of the first row it returns or NULL
+    Subquery(Box<Statement>),
+    Unary {
+        op: UnaryOp,
+        expr: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+}
+
+impl Expr {
+    /// Visit this expression and every sub expression beneath it, parents before children.
+    pub fn walk<'e>(&'e self, visit: &mut impl FnMut(&'e Expr)) {
+        visit(self);
+        match self {
+            Expr::Literal(_)
+            | Expr::Placeholder(_)
+            | Expr::Column(_)
+            | Expr::QualifiedColumn { .. } => {}
+            Expr::Row(exprs) | Expr::Function { args: exprs, .. } => {
+                exprs.iter().for_each(|e| e.walk(visit))
+            }
+            Expr::InList { expr, list, .. } => {
+                expr.walk(visit);
+                list.iter().for_each(|e| e.walk(visit));
+            }
+            Expr::InSelect { expr, select, .. } => {
+                expr.walk(visit);
+                select.walk_exprs(visit);
+            }
+            Expr::Exists { select, .. } | Expr::Subquery(select) => select.walk_exprs(visit),
+            Expr::Unary { expr, .. } | Expr::Cast { expr, .. } | Expr::Collate { expr, .. } => {
+                expr.walk(visit)
+            }
+            Expr::Binary { left, right, .. } => {
+                left.walk(visit);
+                right.walk(visit);
+            }
+        }
+    }
+
+    /// Like walk but allows rewriting nodes in place, parents before children.
+    pub fn walk_mut(&mut self, visit: &mut impl FnMut(&mut Expr)) {
+        visit(self);
+        match self {
+            Expr::Literal(_)
+            | Expr::Placeholder(_)
+            | Expr::Column(_)
+            | Expr::QualifiedColumn { .. } => {}
+            Expr::Row(exprs) | Expr::Function { args: exprs, .. } => {
+                exprs.iter_mut().for_each(|e| e.walk_mut(visit))
+            }
+            Expr::InList { expr, list, .. } => {
+                expr.walk_mut(visit);
+                list.iter_mut().for_each(|e| e.walk_mut(visit));
+            }
+            Expr::InSelect { expr, select, .. } => {
+                expr.walk_mut(visit);
+                select.walk_exprs_mut(visit);
+            }
+            Expr::Exists { select, .. } | Expr::Subquery(select) => select.walk_exprs_mut(visit),
+            Expr::Unary { expr, .. } | Expr::Cast { expr, .. } | Expr::Collate { expr, .. } => {
+                expr.walk_mut(visit)
+            }
+            Expr::Binary { left, right, .. } => {
+                left.walk_mut(visit);
+                right.walk_mut(visit);
+            }
+        }
+    }
+}
+
+impl Expr {
+    // See BinaryOp::precedence. NOT sits between AND and the comparisons.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary { op, .. } => op.precedence(),
+            Expr::Unary {
+                op: UnaryOp::Not, ..
+            }
+            | Expr::Exists { negated: true, .. } => 3,
+            Expr::InList { .. } | Expr::InSelect { .. } => 4,
+            Expr::Unary {
+                op: UnaryOp::Neg | UnaryOp::Plus,
+                ..
+            } => 7,
+            _ => 8,
+        }
+    }
+
+    // Write a sub expression, parenthesized if it binds looser than its parent requires.
+    fn fmt_operand(&self, f: &mut fmt::Formatter, min_precedence: u8) -> fmt::Result {
+        if self.precedence() < min_precedence {
+            write!(f, "({self})")
+        } else {
+            write!(f, "{self}")
+        }
+    }
+}
+
+/// Expressions print back as SQL, with only the parentheses that precedence needs.
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Literal(ColVal::Real(r)) => write!(f, "{}", real_literal(*r)),
+            Expr::Literal(v) => write!(f, "{v}"),
+            Expr::Placeholder(Placeholder::Anonymous) => write!(f, "?"),
+            Expr::Placeholder(Placeholder::Numbered(n)) => write!(f, "?{n}"),
+            Expr::Placeholder(Placeholder::Named(name)) => write!(f, "{name}"),
+            Expr::Column(name) => write!(f, "{name}"),
+            Expr::QualifiedColumn { table, column } => write!(f, "{table}.{column}"),
+            Expr::Row(exprs) => {
+                write!(f, "(")?;
+                comma_separated(f, exprs)?;
+                write!(f, ")")
+            }
+            Expr::Function { name, args } => {
+                write!(f, "{name}(")?;
+                comma_separated(f, args)?;
+                write!(f, ")")
+            }
+            Expr::Cast { expr, type_name } => write!(f, "CAST({expr} AS {type_name})"),
+            Expr::Collate { expr, collation } => {
+                expr.fmt_operand(f, 8)?;
+                write!(f, " COLLATE {collation}")
+            }
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                expr.fmt_operand(f, 4)?;
+                write!(f, " {}IN (", if *negated { "NOT " } else { "" })?;
+                comma_separated(f, list)?;
+                write!(f, ")")
+            }
+            Expr::InSelect {
+                expr,
+                select,
+                negated,
+            } => {
+                expr.fmt_operand(f, 4)?;
+                write!(f, " {}IN ({select})", if *negated { "NOT " } else { "" })
+            }
+            Expr::Exists { select, negated } => {
+                write!(f, "{}EXISTS ({select})", if *negated { "NOT " } else { "" })
+            }
+            Expr::Subquery(select) => write!(f, "({select})"),
+            Expr::Unary { op, expr } => {
+                let precedence = self.precedence();
+                match op {
+                    UnaryOp::Not => write!(f, "NOT ")?,
+                    UnaryOp::Neg => write!(f, "-")?,
+                    UnaryOp::Plus => write!(f, "+")?,
+                }
+                expr.fmt_operand(f, precedence)
+            }
+            Expr::Binary { op, left, right } => {
+                // operators are left associative so a right operand of equal precedence
+                // needs parentheses: a - (b - c)
+                left.fmt_operand(f, op.precedence())?;
+                write!(f, " {op} ")?;
+                right.fmt_operand(f, op.precedence() + 1)
+            }
+        }
+    }
+}
@@ -0,0 +1,446 @@
+/*
+    The tokens a statement is made of, which the grammar in mod.rs reads rather than its
+    characters.
+
+        SELECT name FROM users WHERE age >= 21; -- adults
+
+        Keyword(SELECT) Ident(name) Keyword(FROM) Ident(users) Keyword(WHERE) Ident(age)
+        Op(>=) Int(21) Op(;) Comment(-- adults)
+
+    A word is a keyword if it is one of KEYWORDS, in any case, `select` as much as
+    `SELECT`, and a name otherwise, though a keyword is still taken as a name where the
+    grammar expects one. A keyword's token is the keyword in capitals, for the grammar to
+    match, while a name is read from the statement as written.
+
+    A string is anything between single or double quotes, `'it''s'` or `"say ""hi"""`,
+    where a quote is written twice to be part of the string, and its token keeps the
+    quotes and the doubled ones for string_value to take off. A blob is written as hex
+    digits in quotes after an X, `X'CAFE'`, two to a byte, and its token is taken off by
+    blob_value. A number is an Int unless
+    it has a fraction or an exponent. Whitespace is dropped, while comments, `--` to the
+    end of the line or between `/*` and `*/`, are tokens of their own for the grammar's
+    caller to leave out.
+
+    Each token comes with its span, and each span carries the statement it is in, so
+    that what the grammar keeps as written, a type name like VARCHAR(255) or a virtual
+    table's arguments, is read from the statement as it was typed, spacing and all.
+*/
+use chumsky::input::SpannedInput;
+use chumsky::prelude::*;
+use std::fmt;
+
+/// The words read as keywords, every one the grammar asks for.
+pub const KEYWORDS: &[&str] = &[
+    "ACTION",
+    "AFTER",
+    "ALL",
+    "ALWAYS",
+    "ANALYZE",
+    "AND",
+    "AS",
+    "ASC",
+    "ATTACH",
+    "AUTOINCREMENT",
+    "BEFORE",
+    "BEGIN",
+    "BETWEEN",
+    "BY",
+    "CASCADE",
+    "CAST",
+    "CHECK",
+    "COLLATE",
+    "COMMIT",
+    "CREATE",
+    "CROSS",
+    "CURRENT",
+    "DATABASE",
+    "DEFAULT",
+    "DEFERRED",
+    "DELETE",
+    "DESC",
+    "DETACH",
+    "DISTINCT",
+    "EACH",
+    "END",
+    "EXCEPT",
+    "EXCLUSIVE",
+    "EXISTS",
+    "EXPLAIN",
+    "FALSE",
+    "FOLLOWING",
+    "FOR",
+    "FOREIGN",
+    "FROM",
+    "GENERATED",
+    "GROUP",
+    "IF",
+    "IMMEDIATE",
+    "IN",
+    "INDEX",
+    "INDEXED",
+    "INNER",
+    "INSERT",
+    "INTERSECT",
+    "INTO",
+    "IS",
+    "JOIN",
+    "KEY",
+    "LIMIT",
+    "NO",
+    "NOT",
+    "NULL",
+    "OF",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "OVER",
+    "PARTITION",
+    "PLAN",
+    "PRAGMA",
+    "PRECEDING",
+    "PRIMARY",
+    "QUERY",
+    "RANGE",
+    "REFERENCES",
+    "REINDEX",
+    "RELEASE",
+    "RESTRICT",
+    "ROLLBACK",
+    "ROW",
+    "ROWID",
+    "ROWS",
+    "SAVEPOINT",
+    "SELECT",
+    "SET",
+    "STORED",
+    "TABLE",
+    "TEMP",
+    "TEMPORARY",
+    "TO",
+    "TRANSACTION",
+    "TRIGGER",
+    "TRUE",
+    "UNBOUNDED",
+    "UNION",
+    "UNIQUE",
+    "UPDATE",
+    "USING",
+    "VACUUM",
+    "VALUES",
+    "VIEW",
+    "VIRTUAL",
+    "WHEN",
+    "WHERE",
+    "WITH",
+    "WITHOUT",
+];
+
+/// A token, with its text as written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Token<'a> {
+    Keyword(&'a str),
+    Ident(&'a str),
+    Int(&'a str),
+    Real(&'a str),
+    // the string as written, quotes and all, see string_value
+    Str(&'a str),
+    // X'CAFE', see blob_value
+    Blob(&'a str),
+    // ?, ?3, :name, @name or $name
+    Variable(&'a str),
+    // punctuation and operators, `(`, `,`, `<=`, `->>` and so on
+    Op(&'a str),
+    Comment(&'a str),
+    // any other character, such as `[`, which only a virtual table's arguments may have
+    Other(char),
+}
+
+impl fmt::Display for Token<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Other(c) => write!(f, "{c}"),
+            Token::Keyword(s)
+            | Token::Ident(s)
+            | Token::Str(s)
+            | Token::Blob(s)
+            | Token::Int(s)
+            | Token::Real(s)
+            | Token::Variable(s)
+            | Token::Op(s)
+            | Token::Comment(s) => f.write_str(s),
+        }
+    }
+}
+
+/// Where a token is, by its bytes, in the statement the span carries.
+pub type SourceSpan<'a> = SimpleSpan<usize, &'a str>;
+
+/// A statement's tokens as the grammar reads them.
+pub type Tokens<'a> = SpannedInput<Token<'a>, SourceSpan<'a>, &'a [(Token<'a>, SourceSpan<'a>)]>;
+
+/// The text of a string token, without its quotes and with each quote that was written
+/// twice written once.
+pub fn string_value(token: &str) -> String {
+    let quote = &token[..1];
+    token[1..token.len() - 1].replace(&quote.repeat(2), quote)
+}
+
+/// The bytes of a blob token, `X'CAFE'`, which the lexer has checked are hex digits.
+pub fn blob_value(token: &str) -> Vec<u8> {
+    let digits = &token.as_bytes()[2..token.len() - 1];
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).expect("hex digits");
+            u8::from_str_radix(pair, 16).expect("hex digits")
+        })
+        .collect()
+}
+
+/// The tokens of `src`, comments included, or what in it isn't one, such as a string
+/// without its closing quote.
+pub fn lex(src: &str) -> Result<Vec<(Token<'_>, SourceSpan<'_>)>, Vec<Rich<'_, char>>> {
+    let tokens = lexer().parse(src).into_result()?;
+    Ok(tokens
+        .into_iter()
+        .map(|(token, span)| (token, SourceSpan::new(src, span.into_range())))
+        .collect())
+}
+
+/// `tokens`, lexed from `src`, as the grammar's input.
+pub fn input<'a>(tokens: &'a [(Token<'a>, SourceSpan<'a>)], src: &'a str) -> Tokens<'a> {
+    tokens.spanned(SourceSpan::new(src, src.len()..src.len()))
+}
+
+fn lexer<'a>() -> impl Parser<'a, &'a str, Vec<(Token<'a>, SimpleSpan)>, extra::Err<Rich<'a, char>>>
+{
+    // like SQLite a name may start with an underscore, as in `_rowid_`
+    let word = text::ident().or(text::ascii::ident()).map(|word: &str| {
+        match KEYWORDS.iter().find(|k| k.eq_ignore_ascii_case(word)) {
+            Some(keyword) => Token::Keyword(keyword),
+            None => Token::Ident(word),
+        }
+    });
+
+    // 1.5, 2., 1e10 or 2.5E-3, a number with a fraction or an exponent
+    let exponent = one_of("eE")
+        .then(one_of("+-").or_not())
+        .then(text::digits(10));
+    let real = text::digits(10)
+        .then(
+            just('.')
+                .then(text::digits(10).or_not())
+                .then(exponent.or_not())
+                .ignored()
+                .or(exponent.ignored()),
+        )
+        .to_slice()
+        .map(Token::Real);
+    let int = text::digits(10).to_slice().map(Token::Int);
+
+    // any text up to the closing quote, which may well not be a name or a number, though
+    // a quote written twice is part of the string
+    let quoted = |quote: char| {
+        just(quote)
+            .then(
+                none_of(quote)
+                    .ignored()
+                    .or(just([quote, quote]).ignored())
+                    .repeated(),
+            )
+            .then(just(quote))
+    };
+    let string = quoted('\'')
+        .ignored()
+        .or(quoted('"').ignored())
+        .to_slice()
+        .map(Token::Str);
+
+    // X'CAFE', an even number of hex digits, any other X'...' being no token at all
+    let blob = one_of("xX")
+        .then(quoted('\''))
+        .to_slice()
+        .validate(|blob: &str, e, emitter| {
+            let digits = &blob[2..blob.len() - 1];
+            if digits.len() % 2 != 0 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+                emitter.emit(Rich::custom(
+                    e.span(),
+                    format!("unrecognized token: \"{blob}\""),
+                ));
+            }
+            Token::Blob(blob)
+        });
+
+    let variable = just('?')
+        .then(text::digits(10).or_not())
+        .ignored()
+        .or(one_of(":@$").then(text::ident()).ignored())
+        .to_slice()
+        .map(Token::Variable);
+
+    // a comment left open runs to the end, as in SQLite
+    let comment = just("--")
+        .then(none_of('\n').repeated())
+        .ignored()
+        .or(just("/*")
+            .then(any().and_is(just("*/").not()).repeated())
+            .then(just("*/").ignored().or(end()))
+            .ignored())
+        .to_slice()
+        .map(Token::Comment);
+
+    let op = choice((
+        just("->>"),
+        just("->"),
+        just("=="),
+        just("!="),
+        just("<>"),
+        just("<="),
+        just(">="),
+        one_of("(),;.*+-/=<>").to_slice(),
+    ))
+    .map(Token::Op);
+
+    // a quote that is never closed is an error rather than a character of its own, which
+    // like SQLite's takes in the rest of the statement
+    let unclosed =
+        one_of("'\"")
+            .then(any().repeated())
+            .to_slice()
+            .validate(|rest: &str, e, emitter| {
+                emitter.emit(Rich::custom(
+                    e.span(),
+                    format!("unrecognized token: \"{rest}\""),
+                ));
+                Token::Str(rest)
+            });
+    let other = any().map(Token::Other);
+
+    choice((
+        comment, blob, word, real, int, string, variable, op, unclosed, other,
+    ))
+    .map_with(|token, e| (token, e.span()))
+    .padded()
+    .repeated()
+    .collect()
+    .padded()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(src: &str) -> Vec<Token<'_>> {
+        lex(src)
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect()
+    }
+
+    #[test]
+    fn statements_are_split_into_tokens() {
+        assert_eq!(
+            tokens("SELECT t.name, 2.5E-1 FROM users -- adults\nWHERE age>=21 AND x->>\"$.a\";"),
+            [
+                Token::Keyword("SELECT"),
+                Token::Ident("t"),
+                Token::Op("."),
+                Token::Ident("name"),
+                Token::Op(","),
+                Token::Real("2.5E-1"),
+                Token::Keyword("FROM"),
+                Token::Ident("users"),
+                Token::Comment("-- adults"),
+                Token::Keyword("WHERE"),
+                Token::Ident("age"),
+                Token::Op(">="),
+                Token::Int("21"),
+                Token::Keyword("AND"),
+                Token::Ident("x"),
+                Token::Op("->>"),
+                Token::Str("\"$.a\""),
+                Token::Op(";"),
+            ]
+        );
+        // a keyword in any case is the keyword in capitals
+        assert_eq!(
+            tokens("select Distinct _rowid_"),
+            [
+                Token::Keyword("SELECT"),
+                Token::Keyword("DISTINCT"),
+                Token::Ident("_rowid_")
+            ]
+        );
+        assert_eq!(
+            tokens("? ?3 :a @b $c /* note */ 1e [x]"),
+            [
+                Token::Variable("?"),
+                Token::Variable("?3"),
+                Token::Variable(":a"),
+                Token::Variable("@b"),
+                Token::Variable("$c"),
+                Token::Comment("/* note */"),
+                Token::Int("1"),
+                Token::Ident("e"),
+                Token::Other('['),
+                Token::Ident("x"),
+                Token::Other(']'),
+            ]
+        );
+        assert_eq!(tokens("  "), []);
+    }
+
+    #[test]
+    fn strings_take_either_quote_written_twice_inside() {
+        assert_eq!(
+            tokens(r#"'it''s' "say ""hi""" '' 'a"b'"#),
+            [
+                Token::Str("'it''s'"),
+                Token::Str(r#""say ""hi""""#),
+                Token::Str("''"),
+                Token::Str(r#"'a"b'"#),
+            ]
+        );
+        let values: Vec<String> = tokens(r#"'it''s' "say ""hi""" '' 'a"b'"#)
+            .into_iter()
+            .map(|token| string_value(&token.to_string()))
+            .collect();
+        assert_eq!(values, ["it's", r#"say "hi""#, "", r#"a"b"#]);
+        assert!(lex("'open").is_err());
+        assert!(lex("'it''s").is_err());
+    }
+
+    #[test]
+    fn blobs_are_hex_digits_after_an_x() {
+        assert_eq!(
+            tokens("X'CAFE' x'00ff' x''"),
+            [
+                Token::Blob("X'CAFE'"),
+                Token::Blob("x'00ff'"),
+                Token::Blob("x''"),
+            ]
+        );
+        assert_eq!(blob_value("X'CAFE'"), [0xca, 0xfe]);
+        assert_eq!(blob_value("x'00ff'"), [0x00, 0xff]);
+        assert!(blob_value("x''").is_empty());
+        // an odd number of digits, or something that isn't one
+        assert!(lex("X'ABC'").is_err());
+        assert!(lex("X'GG'").is_err());
+        // a name that starts with an x is still a name
+        assert_eq!(tokens("xs"), [Token::Ident("xs")]);
+    }
+
+    #[test]
+    fn spans_point_into_the_statement() {
+        let src = "CREATE TABLE t (a VARCHAR( 10 ))";
+        let tokens = lex(src).unwrap();
+        let (token, span) = tokens[5];
+        assert_eq!(token, Token::Ident("VARCHAR"));
+        assert_eq!(span.start..span.end, 18..25);
+        assert_eq!(span.context(), src);
+        assert!(lex("SELECT \"unclosed").is_err());
+        assert_eq!(lex("a /* open").unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,3272 @@
+pub mod ast;
+mod lexer;
+
+use anyhow::{anyhow, bail, Result};
+use ast::{
+    Aggregate, AggregateFunc, BinaryOp, ColVal, Column, CommonTableExpr, CompoundOperator,
+    CreateIndex, CreateTable, CreateTrigger, CreateView, CreateVirtualTable, Expr, ForeignKey,
+    ForeignKeyAction, Frame, FrameBound, Generated, IndexHint, Join, Limit, NewColumnVal,
+    OrderingTerm, Placeholder, Pragma, Statement, TransactionMode, TriggerEvent, TriggerTiming,
+    UnaryOp, WindowFunc, WindowFunction,
+};
+use chumsky::{error::Rich, prelude::*, span::Span};
+use lexer::{SourceSpan, Token, Tokens};
+use std::fmt;
+
+// SQLite's default SQLITE_MAX_VARIABLE_NUMBER
+pub const MAX_VARIABLE_NUMBER: usize = 32766;
+
+// How deep parentheses and CASE expressions may nest. The parser recurses for each level,
+// so a statement nested much deeper would run it out of stack, see nesting_depth.
+pub const MAX_NESTING: usize = 50;
+
+// The grammar reads the tokens lexer.rs splits a statement into.
+type Extra<'a> = extra::Err<Rich<'a, Token<'a>, SourceSpan<'a>>>;
+
+// A keyword, written in any case, see lexer.rs.
+fn keyword<'a>(keyword: &'static str) -> impl Parser<'a, Tokens<'a>, &'a str, Extra<'a>> + Clone {
+    debug_assert!(
+        lexer::KEYWORDS.contains(&keyword),
+        "{keyword} isn't a keyword"
+    );
+    just(Token::Keyword(keyword)).to(keyword)
+}
+
+// The next token, if `pick` makes something of it. Like chumsky's select!, but a token
+// that isn't picked is an error at that token, where select! puts it after the token,
+// and chumsky then reports it in place of the error that really is there.
+fn token<'a, O>(
+    pick: impl Fn(Token<'a>) -> Option<O> + Clone,
+) -> impl Parser<'a, Tokens<'a>, O, Extra<'a>> + Clone {
+    any().try_map(move |token: Token<'a>, span| {
+        pick(token).ok_or_else(|| {
+            <Rich<_, _> as chumsky::error::Error<Tokens<'a>>>::expected_found(
+                None,
+                Some(token.into()),
+                span,
+            )
+        })
+    })
+}
+
+// A name of a table, a column or anything else, as written. A keyword is a name too
+// where one is expected, as it always was here.
+fn ident<'a>() -> impl Parser<'a, Tokens<'a>, &'a str, Extra<'a>> + Clone {
+    token(|token| matches!(token, Token::Ident(_) | Token::Keyword(_)).then_some(()))
+        .map_with(|_, e| written(e.span()))
+        .labelled("a name")
+}
+
+// An operator or a punctuation mark such as `(` or `,`.
+fn op<'a>(op: &'static str) -> impl Parser<'a, Tokens<'a>, Token<'a>, Extra<'a>> + Clone {
+    just(Token::Op(op))
+}
+
+// A whole number as written, 21 but not 2.5.
+fn integer<'a>() -> impl Parser<'a, Tokens<'a>, &'a str, Extra<'a>> + Clone {
+    token(|token| match token {
+        Token::Int(n) => Some(n),
+        _ => None,
+    })
+}
+
+// A string's text, without its quotes.
+fn string<'a>() -> impl Parser<'a, Tokens<'a>, String, Extra<'a>> + Clone {
+    token(|token| match token {
+        Token::Str(s) => Some(lexer::string_value(s)),
+        _ => None,
+    })
+}
+
+// A name, or two joined by a dot as in `u.name` or `archive.orders`, as written, with no
+// spaces around the dot.
+fn dotted_name<'a>() -> impl Parser<'a, Tokens<'a>, &'a str, Extra<'a>> + Clone {
+    ident()
+        .then(op(".").then(ident()).or_not())
+        .map_with(|_, e| written(e.span()))
+        .filter(|name: &&str| !name.contains(char::is_whitespace))
+}
+
+// What `span` covers in the statement, as it was written.
+fn written(span: SourceSpan) -> &str {
+    &span.context()[span.start..span.end]
+}
+
+// TRUE, "foo", 21, X'CAFE' etc.
+fn column_value<'a>() -> impl Parser<'a, Tokens<'a>, ColVal, Extra<'a>> {
+    let bool_val = keyword("TRUE")
+        .to(ColVal::Boolean(true))
+        .or(keyword("FALSE").to(ColVal::Boolean(false)));
+
+    let str_val = string().map(ColVal::String);
+
+    let int_val = integer().try_map(|n: &str, span| match n.parse::<i64>() {
+        Ok(num) => Ok(ColVal::Int(num)),
+        Err(e) => Err(Rich::custom(
+            span,
+            format!("Error parsing int as column val: {}", e),
+        )),
+    });
+
+    let real_val = token(|token| match token {
+        Token::Real(n) => Some(ColVal::Real(n.parse().expect("a valid float"))),
+        _ => None,
+    });
+
+    let blob_val = token(|token| match token {
+        Token::Blob(b) => Some(ColVal::Blob(lexer::blob_value(b))),
+        _ => None,
+    });
+
+    let null_val = keyword("NULL").to(ColVal::Null);
+
+    null_val
+        .or(bool_val)
+        .or(real_val)
+        .or(int_val)
+        .or(str_val)
+        .or(blob_val)
+}
+
+// parse column values separated by commas for exmaple:  NULL, True, "foo", 21, ?, :age etc.
+fn column_vals<'a>() -> impl Parser<'a, Tokens<'a>, Vec<Expr>, Extra<'a>> {
+    expr()
+        .separated_by(op(",").repeated().at_least(1))
+        .collect::<Vec<_>>()
+}
+
+// ?, ?3, :name, @name or $name
+fn placeholder<'a>() -> impl Parser<'a, Tokens<'a>, Placeholder, Extra<'a>> + Clone {
+    token(|token| match token {
+        Token::Variable(name) => Some(name),
+        _ => None,
+    })
+    .try_map(|name: &str, span| match name.strip_prefix('?') {
+        None => Ok(Placeholder::Named(name.to_string())),
+        Some("") => Ok(Placeholder::Anonymous),
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) if (1..=MAX_VARIABLE_NUMBER).contains(&n) => Ok(Placeholder::Numbered(n)),
+            _ => Err(Rich::custom(
+                span,
+                format!("variable number must be between ?1 and ?{MAX_VARIABLE_NUMBER}"),
+            )),
+        },
+    })
+}
+
+fn csv<'a>() -> impl Parser<'a, Tokens<'a>, Vec<&'a str>, Extra<'a>> + Clone {
+    // comma separated values, for example foo, bar, goo
+    ident()
+        .separated_by(op(",").repeated().at_least(1))
+        .collect::<Vec<_>>()
+}
+
+/// this is an insert whereby a subset of the columns can be inserted - some columns may be left unspecified
+/// INSERT INTO table_name (column1, column2, column3, ...)
+/// VALUES (value1, value2, value3, ...);
+fn insert_patch<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    keyword("INSERT")
+        .then_ignore(keyword("INTO"))
+        .then(table_name())
+        .then_ignore(op("("))
+        .then(csv())
+        .then_ignore(op(")"))
+        .then_ignore(keyword("VALUES"))
+        .then_ignore(op("("))
+        .then(column_vals())
+        .then_ignore(op(")"))
+        .validate(|(((_, table_name), col_names), col_values), e, emitter| {
+            // reported as SQLite does rather than failing the parse, which would only say
+            // that a statement was expected
+            if col_names.len() != col_values.len() {
+                emitter.emit(Rich::custom(
+                    e.span(),
+                    format!(
+                        "{} values for {} columns",
+                        col_values.len(),
+                        col_names.len()
+                    ),
+                ));
+            }
+            let columns: Vec<NewColumnVal> = col_names
+                .into_iter()
+                .zip(col_values)
+                .map(|(column_name, value): (&str, Expr)| NewColumnVal {
+                    column_name: column_name.to_string(),
+                    value,
+                })
+                .collect();
+
+            Statement::Insert {
+                into_table: table_name.to_string(),
+                columns,
+            }
+        })
+}
+
+/// COUNT(*), COUNT([DISTINCT] column), and SUM, AVG, MIN and MAX of a column. As with any
+/// function the name can be in any case.
+fn aggregate<'a>() -> impl Parser<'a, Tokens<'a>, Aggregate, Extra<'a>> + Clone {
+    let func = ident().try_map(
+        |name: &str, span| match name.to_ascii_uppercase().as_str() {
+            "COUNT" => Ok(AggregateFunc::Count),
+            "SUM" => Ok(AggregateFunc::Sum),
+            "AVG" => Ok(AggregateFunc::Avg),
+            "MIN" => Ok(AggregateFunc::Min),
+            "MAX" => Ok(AggregateFunc::Max),
+            _ => Err(Rich::custom(span, format!("not an aggregate: {name}"))),
+        },
+    );
+    let column = dotted_name().map(|c: &str| Some(c.to_string()));
+    let arg = op("*")
+        .to(None)
+        .map(|arg| (false, arg))
+        .or(keyword("DISTINCT")
+            .or_not()
+            .map(|d| d.is_some())
+            .then(column));
+
+    func.then(arg.delimited_by(op("("), op(")")))
+        .validate(|(func, (distinct, arg)), e, emitter| {
+            if arg.is_none() && func != AggregateFunc::Count {
+                emitter.emit(Rich::custom(e.span(), format!("{func}(*) isn't allowed")));
+            }
+            Aggregate {
+                func,
+                arg,
+                distinct,
+            }
+        })
+}
+
+/// ROW_NUMBER(), RANK(), DENSE_RANK() or an aggregate, then OVER ([PARTITION BY column, ...]
+/// [ORDER BY column [ASC | DESC], ...] [frame]). The frame is `ROWS` or `RANGE`, then
+/// `BETWEEN start AND end` or just the start with CURRENT ROW the end, where a bound is
+/// UNBOUNDED PRECEDING, n PRECEDING, CURRENT ROW, n FOLLOWING or UNBOUNDED FOLLOWING.
+fn window_function<'a>() -> impl Parser<'a, Tokens<'a>, WindowFunction, Extra<'a>> + Clone {
+    let ranking = ident()
+        .try_map(
+            |name: &str, span| match name.to_ascii_uppercase().as_str() {
+                "ROW_NUMBER" => Ok(WindowFunc::RowNumber),
+                "RANK" => Ok(WindowFunc::Rank),
+                "DENSE_RANK" => Ok(WindowFunc::DenseRank),
+                _ => Err(Rich::custom(span, format!("not a window function: {name}"))),
+            },
+        )
+        .then_ignore(op("(").then(op(")")));
+    let func = ranking.or(aggregate().map(WindowFunc::Aggregate));
+
+    let column = dotted_name().map(str::to_string);
+    let partition_by = keyword("PARTITION").then(keyword("BY")).ignore_then(
+        column
+            .clone()
+            .separated_by(op(","))
+            .at_least(1)
+            .collect::<Vec<_>>(),
+    );
+    let direction = keyword("ASC")
+        .to(false)
+        .or(keyword("DESC").to(true))
+        .or_not()
+        .map(|d| d.unwrap_or(false));
+    let order_by = keyword("ORDER").then(keyword("BY")).ignore_then(
+        column
+            .then(direction)
+            .separated_by(op(","))
+            .at_least(1)
+            .collect::<Vec<_>>(),
+    );
+
+    let count = integer().try_map(|n: &str, span| {
+        n.parse::<u64>()
+            .map_err(|e| Rich::custom(span, format!("frame offset {n}: {e}")))
+    });
+    let bound = choice((
+        keyword("UNBOUNDED")
+            .then(keyword("PRECEDING"))
+            .to(FrameBound::UnboundedPreceding),
+        keyword("UNBOUNDED")
+            .then(keyword("FOLLOWING"))
+            .to(FrameBound::UnboundedFollowing),
+        keyword("CURRENT")
+            .then(keyword("ROW"))
+            .to(FrameBound::CurrentRow),
+        count
+            .clone()
+            .then_ignore(keyword("PRECEDING"))
+            .map(FrameBound::Preceding),
+        count
+            .then_ignore(keyword("FOLLOWING"))
+            .map(FrameBound::Following),
+    ));
+    let frame = keyword("ROWS")
+        .to(false)
+        .or(keyword("RANGE").to(true))
+        .then(
+            keyword("BETWEEN")
+                .ignore_then(bound.clone())
+                .then_ignore(keyword("AND"))
+                .then(bound.clone())
+                .or(bound.map(|start| (start, FrameBound::CurrentRow))),
+        )
+        .map(|(range, (start, end))| Frame { range, start, end });
+
+    func.then_ignore(keyword("OVER"))
+        .then(
+            partition_by
+                .or_not()
+                .then(order_by.or_not())
+                .then(frame.or_not())
+                .delimited_by(op("("), op(")")),
+        )
+        .validate(|(func, ((partition_by, order_by), frame)), e, emitter| {
+            if let WindowFunc::Aggregate(Aggregate { distinct: true, .. }) = func {
+                emitter.emit(Rich::custom(
+                    e.span(),
+                    "DISTINCT is not supported for window functions",
+                ));
+            }
+            if let Some(frame) = frame {
+                let offset = |b: FrameBound| {
+                    matches!(b, FrameBound::Preceding(_) | FrameBound::Following(_))
+                };
+                if frame.start == FrameBound::UnboundedFollowing
+                    || frame.end == FrameBound::UnboundedPreceding
+                {
+                    emitter.emit(Rich::custom(e.span(), "unsupported frame specification"));
+                } else if frame.range && (offset(frame.start) || offset(frame.end)) {
+                    emitter.emit(Rich::custom(
+                        e.span(),
+                        "RANGE with an offset PRECEDING or FOLLOWING is not supported",
+                    ));
+                }
+            }
+            WindowFunction {
+                func,
+                partition_by: partition_by.unwrap_or_default(),
+                order_by: order_by.unwrap_or_default(),
+                frame,
+            }
+        })
+}
+
+// The columns a SELECT returns, plain or qualified as in `u.name`, or any expression,
+// `changes()` or `price * qty`, down to a constant, most often seen as the `SELECT 1` of
+// an EXISTS subquery where only whether rows come back matters. `*` and `table.*` are
+// left for the planner to expand into the table's columns. Each is kept as text, written
+// out the same way whatever its spacing and case, which is also the name its column is
+// known by. The text reads back as the very expression that was parsed, a REAL with
+// every digit it has.
+fn result_columns<'a>(
+    expr: impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone + 'a,
+) -> impl Parser<'a, Tokens<'a>, Vec<String>, Extra<'a>> + Clone {
+    let table_wildcard = ident()
+        .then(op("."))
+        .then(op("*"))
+        .map_with(|_, e| written(e.span()))
+        .filter(|name: &&str| !name.contains(char::is_whitespace));
+
+    window_function()
+        .map(|w| w.to_string())
+        .or(aggregate().map(|a| a.to_string()))
+        .or(table_wildcard.or(op("*").to("*")).map(str::to_string))
+        .or(expr.map(|e| e.to_string()))
+        .separated_by(op(",").repeated().at_least(1))
+        .collect::<Vec<_>>()
+}
+
+// Words that can follow the table in a FROM clause, which mustn't be mistaken for its alias.
+const NOT_AN_ALIAS: [&str; 16] = [
+    "WHERE",
+    "INDEXED",
+    "NOT",
+    "ORDER",
+    "LIMIT",
+    "GROUP",
+    "HAVING",
+    "UNION",
+    "EXCEPT",
+    "INTERSECT",
+    "JOIN",
+    "INNER",
+    "LEFT",
+    "CROSS",
+    "NATURAL",
+    "ON",
+];
+
+/// A table's name, `orders` or, in an attached database, `archive.orders`. `main.orders`
+/// is main's orders, the same as `orders`.
+fn table_name<'a>() -> impl Parser<'a, Tokens<'a>, &'a str, Extra<'a>> + Clone {
+    dotted_name().map(|name: &str| name.strip_prefix("main.").unwrap_or(name))
+}
+
+/// table_name [(argument, ...)] [[AS] alias]
+///
+/// A table in an attached database is known by its name without the database's unless it
+/// is given an alias, so `archive.orders` is `orders` to its columns as in SQLite. The
+/// arguments are a table-valued function's, `generate_series(1, 10)`.
+fn table_and_alias<'a>(
+    expr: impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone + 'a,
+) -> impl Parser<'a, Tokens<'a>, (&'a str, Vec<Expr>, Option<&'a str>), Extra<'a>> + Clone {
+    let args = expr
+        .separated_by(op(","))
+        .collect::<Vec<_>>()
+        .delimited_by(op("("), op(")"))
+        .or_not()
+        .map(Option::unwrap_or_default);
+    table_name()
+        .then(args)
+        .then(alias().or_not())
+        .map(|((name, args), alias): ((&str, _), _)| {
+            let unqualified = name.split_once('.').map(|(_, table)| table);
+            (name, args, alias.or(unqualified))
+        })
+}
+
+// [AS] alias, after a table or subquery in FROM.
+fn alias<'a>() -> impl Parser<'a, Tokens<'a>, &'a str, Extra<'a>> + Clone {
+    keyword("AS")
+        .or_not()
+        .ignore_then(ident())
+        .filter(|alias: &&str| !NOT_AN_ALIAS.iter().any(|w| w.eq_ignore_ascii_case(alias)))
+}
+
+/// INDEXED BY name or NOT INDEXED, after the table in FROM.
+fn index_hint<'a>() -> impl Parser<'a, Tokens<'a>, IndexHint, Extra<'a>> + Clone {
+    let indexed_by = keyword("INDEXED")
+        .ignore_then(keyword("BY"))
+        .ignore_then(ident())
+        .map(|index: &str| IndexHint::IndexedBy(index.to_string()));
+    let not_indexed = keyword("NOT")
+        .then(keyword("INDEXED"))
+        .to(IndexHint::NotIndexed);
+    indexed_by.or(not_indexed)
+}
+
+/// The tables joined onto the first in FROM, each after a comma or [INNER | CROSS] JOIN,
+/// with an ON clause or without.
+fn joins<'a>(
+    expr: impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone + 'a,
+) -> impl Parser<'a, Tokens<'a>, Vec<Join>, Extra<'a>> + Clone {
+    let join = op(",")
+        .ignored()
+        .or(choice((keyword("INNER"), keyword("CROSS")))
+            .or_not()
+            .then(keyword("JOIN"))
+            .ignored());
+    join.ignore_then(table_name())
+        .then(alias().or_not())
+        .then(keyword("ON").ignore_then(expr).or_not())
+        .map(|((table, alias), on): ((&str, Option<&str>), _)| {
+            let unqualified = table.split_once('.').map(|(_, table)| table);
+            Join {
+                table: table.to_string(),
+                alias: alias.or(unqualified).map(str::to_string),
+                on,
+            }
+        })
+        .repeated()
+        .collect()
+}
+
+/// SELECT name, age FROM users WHERE age > 21;
+fn select<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    select_with(expr())
+}
+
+// What a SELECT reads FROM: the table's name, or the subquery's with its SELECT, the
+// table's arguments and the alias.
+type FromItem<'a> = (&'a str, Option<Box<Statement>>, Vec<Expr>, Option<&'a str>);
+
+// SELECT built on top of a given expression parser. Expressions can contain SELECTs as in
+// `id IN (SELECT ...)` so the expression parser builds its subqueries with this too. FROM
+// can read a SELECT of its own, `FROM (SELECT ...) AS name`.
+fn select_with<'a>(
+    expr: impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone + 'a,
+) -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> + Clone {
+    recursive(move |select| {
+        let subquery = select
+            .delimited_by(op("("), op(")"))
+            .then(alias().or_not())
+            .map(|(select, alias): (Statement, Option<&str>)| {
+                let name = alias.unwrap_or("subquery");
+                (name, Some(Box::new(select)), vec![], alias)
+            });
+        let table = table_and_alias(expr.clone())
+            .map(|(name, table_args, alias)| (name, None, table_args, alias));
+
+        // without FROM the result columns are worked out once, from no table at all
+        let nothing = || ("", None, vec![], None);
+        keyword("SELECT")
+            .ignored()
+            .then(result_columns(expr.clone()))
+            .then(
+                keyword("FROM")
+                    .ignore_then(subquery.or(table))
+                    .then(index_hint().or_not())
+                    .then(joins(expr.clone()))
+                    .or_not()
+                    .map(move |from| from.unwrap_or(((nothing(), None), vec![]))),
+            )
+            .then(select_clauses(expr))
+            .map(
+                |(
+                    (
+                        (_, columns),
+                        (((table_name, from_select, table_args, alias), index_hint), joins),
+                    ),
+                    (where_clause, group_by, order_by, limit),
+                ): ((_, ((FromItem, _), _)), SelectClauses)| {
+                    Statement::Select {
+                        columns,
+                        from_table: table_name.to_string(),
+                        from_select,
+                        table_args,
+                        alias: alias.map(str::to_string),
+                        index_hint,
+                        joins,
+                        where_clause,
+                        group_by,
+                        order_by,
+                        limit,
+                    }
+                },
+            )
+    })
+}
+
+// What follows a SELECT's FROM: [WHERE expr] [GROUP BY expr, ...] [ORDER BY ...] [LIMIT ...]
+type SelectClauses = (Option<Expr>, Vec<Expr>, Vec<OrderingTerm>, Option<Limit>);
+
+fn select_clauses<'a>(
+    expr: impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone + 'a,
+) -> impl Parser<'a, Tokens<'a>, SelectClauses, Extra<'a>> + Clone {
+    let group_by = keyword("GROUP").then(keyword("BY")).ignore_then(
+        expr.clone()
+            .separated_by(op(","))
+            .at_least(1)
+            .collect::<Vec<_>>(),
+    );
+    keyword("WHERE")
+        .ignore_then(expr.clone())
+        .or_not()
+        .then(group_by.or_not())
+        .then(order_by(expr.clone()).or_not())
+        .then(limit(expr).or_not())
+        .map(|(((where_clause, group_by), order_by), limit)| {
+            (
+                where_clause,
+                group_by.unwrap_or_default(),
+                order_by.unwrap_or_default(),
+                limit,
+            )
+        })
+}
+
+/// SELECT ... UNION SELECT ... EXCEPT SELECT ... ORDER BY name LIMIT 10;
+///
+/// The ORDER BY and LIMIT belong to the compound as a whole, none of its SELECTs can have
+/// its own. They are parsed as the last SELECT's and moved to the compound.
+fn compound_select<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let operator = choice((
+        keyword("UNION")
+            .ignore_then(keyword("ALL").or_not())
+            .map(|all| match all {
+                Some(_) => CompoundOperator::UnionAll,
+                None => CompoundOperator::Union,
+            }),
+        keyword("INTERSECT").to(CompoundOperator::Intersect),
+        keyword("EXCEPT").to(CompoundOperator::Except),
+    ));
+
+    select()
+        .then(
+            operator
+                .then(select())
+                .repeated()
+                .at_least(1)
+                .collect::<Vec<_>>(),
+        )
+        .validate(|(first, mut rest), e, emitter| {
+            let (order_by, limit) = match rest.last_mut() {
+                Some((
+                    _,
+                    Statement::Select {
+                        order_by, limit, ..
+                    },
+                )) => (std::mem::take(order_by), limit.take()),
+                _ => (vec![], None),
+            };
+            let selects = std::iter::once(&first).chain(rest.iter().map(|(_, s)| s));
+            for (select, (operator, _)) in selects.zip(&rest) {
+                if let Statement::Select {
+                    order_by, limit, ..
+                } = select
+                {
+                    let clause = match (order_by.is_empty(), limit) {
+                        (false, _) => "ORDER BY",
+                        (true, Some(_)) => "LIMIT",
+                        (true, None) => continue,
+                    };
+                    emitter.emit(Rich::custom(
+                        e.span(),
+                        format!("{clause} clause should come after {operator} not before"),
+                    ));
+                }
+            }
+            Statement::Compound {
+                first: Box::new(first),
+                rest,
+                order_by,
+                limit,
+            }
+        })
+}
+
+/// WITH recent AS (SELECT id FROM orders WHERE total > 100), big (id) AS (...) SELECT ...;
+fn with_select<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let cte = ident()
+        .then(csv().delimited_by(op("("), op(")")).or_not())
+        .then_ignore(keyword("AS"))
+        .then(select().delimited_by(op("("), op(")")))
+        .map(
+            |((name, columns), select): ((&str, Option<Vec<&str>>), _)| CommonTableExpr {
+                name: name.to_string(),
+                columns: columns
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|c| c.to_string())
+                    .collect(),
+                select: Box::new(select),
+            },
+        );
+
+    keyword("WITH")
+        .ignore_then(cte.separated_by(op(",")).at_least(1).collect::<Vec<_>>())
+        .then(compound_select().or(select()))
+        .map(|(ctes, body)| Statement::With {
+            ctes,
+            body: Box::new(body),
+        })
+}
+
+/// IF NOT EXISTS
+fn if_not_exists<'a>() -> impl Parser<'a, Tokens<'a>, bool, Extra<'a>> {
+    keyword("IF")
+        .then(keyword("NOT"))
+        .then(keyword("EXISTS"))
+        .or_not()
+        .map(|clause| clause.is_some())
+}
+
+// A type name like INTEGER or VARCHAR(255), as in CAST(x AS type) or a column definition.
+fn type_name<'a>() -> impl Parser<'a, Tokens<'a>, &'a str, Extra<'a>> + Clone {
+    ident()
+        .and_is(
+            choice((
+                keyword("CHECK"),
+                keyword("PRIMARY"),
+                keyword("NOT"),
+                keyword("UNIQUE"),
+                keyword("DEFAULT"),
+                keyword("REFERENCES"),
+                keyword("COLLATE"),
+                keyword("GENERATED"),
+                keyword("AS"),
+            ))
+            .not(),
+        )
+        .then(
+            integer()
+                .separated_by(op(","))
+                .at_least(1)
+                .delimited_by(op("("), op(")"))
+                .or_not(),
+        )
+        .map_with(|_, e| written(e.span()))
+}
+
+// COLLATE name, naming the collation of a column or expression.
+fn collate<'a>() -> impl Parser<'a, Tokens<'a>, &'a str, Extra<'a>> + Clone {
+    keyword("COLLATE").ignore_then(ident())
+}
+
+// One item between a CREATE TABLE's brackets. A column with constraints, as in
+// `id INTEGER PRIMARY KEY`, gives the column and then an item for each constraint.
+#[derive(Debug, Clone)]
+enum TableDefinition {
+    Column(Column),
+    // the key's columns, and whether it is a column's PRIMARY KEY AUTOINCREMENT
+    PrimaryKey(Vec<String>, bool),
+    ForeignKey(ForeignKey),
+    Check(Expr),
+}
+
+// A CREATE TABLE as parsed, before its definitions are sorted out: TEMP if it was
+// written, IF NOT EXISTS, the name and the items between the brackets.
+type TableParts<'a> = (((Option<&'a str>, bool), &'a str), Vec<TableDefinition>);
+
+#[derive(Debug, Clone)]
+enum ColumnConstraint {
+    // with AUTOINCREMENT or not
+    PrimaryKey(bool),
+    Default(ColVal),
+    NotNull,
+    Collate(String),
+    References(ForeignKey),
+    Generated(Generated),
+    Check(Expr),
+}
+
+// CHECK (expr), a condition every row of the table has to meet.
+fn check<'a>() -> impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone {
+    keyword("CHECK").ignore_then(expr().delimited_by(op("("), op(")")))
+}
+
+/// REFERENCES parent [(column, ...)] [ON DELETE action] [ON UPDATE action], the columns
+/// of the key itself are left for the caller to fill in.
+fn references<'a>() -> impl Parser<'a, Tokens<'a>, ForeignKey, Extra<'a>> + Clone {
+    let action = choice((
+        keyword("SET").ignore_then(
+            keyword("NULL")
+                .to(ForeignKeyAction::SetNull)
+                .or(keyword("DEFAULT").to(ForeignKeyAction::SetDefault)),
+        ),
+        keyword("CASCADE").to(ForeignKeyAction::Cascade),
+        keyword("RESTRICT").to(ForeignKeyAction::Restrict),
+        keyword("NO")
+            .then(keyword("ACTION"))
+            .to(ForeignKeyAction::NoAction),
+    ));
+    let on = keyword("ON")
+        .ignore_then(keyword("DELETE").to(true).or(keyword("UPDATE").to(false)))
+        .then(action);
+
+    keyword("REFERENCES")
+        .ignore_then(ident())
+        .then(csv().delimited_by(op("("), op(")")).or_not())
+        .then(on.repeated().collect::<Vec<_>>())
+        .map(
+            |((parent, parent_columns), actions): ((&str, Option<Vec<&str>>), Vec<_>)| {
+                let mut key = ForeignKey {
+                    columns: vec![],
+                    parent: parent.to_string(),
+                    parent_columns: parent_columns
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(str::to_string)
+                        .collect(),
+                    on_delete: ForeignKeyAction::default(),
+                    on_update: ForeignKeyAction::default(),
+                };
+                for (on_delete, action) in actions {
+                    if on_delete {
+                        key.on_delete = action;
+                    } else {
+                        key.on_update = action;
+                    }
+                }
+                key
+            },
+        )
+}
+
+/// [GENERATED ALWAYS] AS (expr) [VIRTUAL | STORED], VIRTUAL if it doesn't say.
+fn generated<'a>() -> impl Parser<'a, Tokens<'a>, Generated, Extra<'a>> + Clone {
+    keyword("GENERATED")
+        .then(keyword("ALWAYS"))
+        .or_not()
+        .ignore_then(keyword("AS"))
+        .ignore_then(expr().delimited_by(op("("), op(")")))
+        .then(
+            keyword("STORED")
+                .to(true)
+                .or(keyword("VIRTUAL").to(false))
+                .or_not(),
+        )
+        .map(|(expr, stored)| Generated {
+            expr,
+            stored: stored.unwrap_or(false),
+        })
+}
+
+/// CREATE TABLE [IF NOT EXISTS] name (id INTEGER PRIMARY KEY, name TEXT DEFAULT "", ...)
+/// or with keys as constraints of their own, (a, b, c, PRIMARY KEY (a, b),
+/// FOREIGN KEY (c) REFERENCES other (id) ON DELETE CASCADE). WITHOUT ROWID at the end
+/// stores the rows in primary key order rather than by rowid. `id INTEGER PRIMARY KEY
+/// AUTOINCREMENT` is only allowed on the column that is the rowid. A CHECK (expr) can be
+/// a column's constraint or the table's, and either way may read any of the columns, and
+/// `name TEXT NOT NULL` keeps NULL out of a column.
+///
+/// CREATE TEMP TABLE, or TEMPORARY, makes the table in the temp database, and is the same
+/// as CREATE TABLE temp.name.
+fn create_table<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let primary_key = keyword("PRIMARY").then(keyword("KEY"));
+    let constraint = primary_key
+        .clone()
+        .ignore_then(keyword("AUTOINCREMENT").or_not())
+        .map(|autoincrement| ColumnConstraint::PrimaryKey(autoincrement.is_some()))
+        .or(keyword("DEFAULT")
+            .ignore_then(column_value())
+            .map(ColumnConstraint::Default))
+        .or(keyword("NOT")
+            .then(keyword("NULL"))
+            .to(ColumnConstraint::NotNull))
+        .or(collate().map(|name: &str| ColumnConstraint::Collate(name.to_string())))
+        .or(references().map(ColumnConstraint::References))
+        .or(generated().map(ColumnConstraint::Generated))
+        .or(check().map(ColumnConstraint::Check));
+    let column = ident()
+        .then(type_name().or_not())
+        .then(constraint.repeated().collect::<Vec<_>>())
+        .map(
+            |((name, type_name), constraints): ((&str, Option<&str>), Vec<_>)| {
+                let mut column = Column {
+                    name: name.to_string(),
+                    type_name: type_name.map(str::to_string),
+                    default: None,
+                    collation: None,
+                    generated: None,
+                    not_null: false,
+                };
+                let mut definitions = vec![];
+                for constraint in constraints {
+                    match constraint {
+                        ColumnConstraint::PrimaryKey(autoincrement) => definitions.push(
+                            TableDefinition::PrimaryKey(vec![name.to_string()], autoincrement),
+                        ),
+                        ColumnConstraint::Default(value) => column.default = Some(value),
+                        ColumnConstraint::NotNull => column.not_null = true,
+                        ColumnConstraint::Collate(name) => column.collation = Some(name),
+                        ColumnConstraint::Generated(generated) => {
+                            column.generated = Some(generated)
+                        }
+                        ColumnConstraint::References(key) => {
+                            definitions.push(TableDefinition::ForeignKey(ForeignKey {
+                                columns: vec![name.to_string()],
+                                ..key
+                            }))
+                        }
+                        ColumnConstraint::Check(expr) => {
+                            definitions.push(TableDefinition::Check(expr))
+                        }
+                    }
+                }
+                definitions.insert(0, TableDefinition::Column(column));
+                definitions
+            },
+        );
+    let columns = || {
+        csv()
+            .delimited_by(op("("), op(")"))
+            .map(|columns| columns.into_iter().map(str::to_string).collect::<Vec<_>>())
+    };
+    let table_key = primary_key
+        .ignore_then(columns())
+        .map(|key| vec![TableDefinition::PrimaryKey(key, false)]);
+    let foreign_key = keyword("FOREIGN")
+        .then(keyword("KEY"))
+        .ignore_then(columns())
+        .then(references())
+        .map(|(columns, key)| vec![TableDefinition::ForeignKey(ForeignKey { columns, ..key })]);
+    let table_check = check().map(|expr| vec![TableDefinition::Check(expr)]);
+    let definitions = table_key
+        .or(foreign_key)
+        .or(table_check)
+        .or(column)
+        .separated_by(op(","))
+        .at_least(1)
+        .collect::<Vec<_>>()
+        .map(|definitions| definitions.into_iter().flatten().collect::<Vec<_>>())
+        .delimited_by(op("("), op(")"));
+
+    let temporary = keyword("TEMP").or(keyword("TEMPORARY"));
+    keyword("CREATE")
+        .ignore_then(temporary.or_not())
+        .then_ignore(keyword("TABLE"))
+        .then(if_not_exists())
+        .then(table_name())
+        .then(definitions)
+        .then(keyword("WITHOUT").then(keyword("ROWID")).or_not())
+        .validate(
+            |((((temporary, if_not_exists), name), definitions), without_rowid): (
+                TableParts,
+                _,
+            ),
+             e,
+             emitter| {
+                let name = match (temporary, name.split_once('.')) {
+                    (None, _) | (Some(_), Some(("temp", _))) => name.to_string(),
+                    (Some(_), None) => format!("temp.{name}"),
+                    (Some(_), Some(_)) => {
+                        emitter.emit(Rich::custom(
+                            e.span(),
+                            "temporary table name must be unqualified",
+                        ));
+                        name.to_string()
+                    }
+                };
+                let mut columns = vec![];
+                let mut primary_keys = vec![];
+                let mut foreign_keys = vec![];
+                let mut checks = vec![];
+                for definition in definitions {
+                    match definition {
+                        TableDefinition::Column(column) => columns.push(column),
+                        TableDefinition::PrimaryKey(key, autoincrement) => {
+                            primary_keys.push((key, autoincrement))
+                        }
+                        TableDefinition::ForeignKey(key) => foreign_keys.push(key),
+                        TableDefinition::Check(expr) => checks.push(expr),
+                    }
+                }
+                if primary_keys.len() > 1 {
+                    emitter.emit(Rich::custom(
+                        e.span(),
+                        format!("table \"{name}\" has more than one primary key"),
+                    ));
+                }
+                let (primary_key, autoincrement) = primary_keys.pop().unwrap_or_default();
+                let table = CreateTable {
+                    name,
+                    columns,
+                    primary_key,
+                    foreign_keys,
+                    autoincrement,
+                    checks,
+                    without_rowid: without_rowid.is_some(),
+                    if_not_exists,
+                };
+                if table.autoincrement && table.rowid_alias().is_none() {
+                    emitter.emit(Rich::custom(
+                        e.span(),
+                        "AUTOINCREMENT is only allowed on an INTEGER PRIMARY KEY",
+                    ));
+                }
+                Statement::CreateTable(table)
+            },
+        )
+}
+
+/// CREATE [UNIQUE] INDEX [IF NOT EXISTS] idx_name ON table_name (column1, lower(column2), ...);
+fn create_index<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    keyword("CREATE")
+        .ignore_then(keyword("UNIQUE").or_not())
+        .then_ignore(keyword("INDEX"))
+        .then(if_not_exists())
+        .then(table_name())
+        .then_ignore(keyword("ON"))
+        .then(table_name())
+        .then(
+            expr()
+                .separated_by(op(","))
+                .at_least(1)
+                .collect::<Vec<_>>()
+                .delimited_by(op("("), op(")")),
+        )
+        .map(
+            |((((unique, if_not_exists), name), table), columns): (
+                (((_, bool), &str), &str),
+                _,
+            )| {
+                // the index is in its table's database, which either name may give
+                let (name, table) = match (name.split_once('.'), table.split_once('.')) {
+                    (Some((database, _)), None) => {
+                        (name.to_string(), format!("{database}.{table}"))
+                    }
+                    (None, Some((database, _))) => {
+                        (format!("{database}.{name}"), table.to_string())
+                    }
+                    _ => (name.to_string(), table.to_string()),
+                };
+                Statement::CreateIndex(CreateIndex {
+                    name,
+                    table,
+                    columns,
+                    unique: unique.is_some(),
+                    if_not_exists,
+                })
+            },
+        )
+}
+
+/// CREATE VIEW [IF NOT EXISTS] view_name [(column1, ...)] AS SELECT ...;
+fn create_view<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let columns = csv()
+        .delimited_by(op("("), op(")"))
+        .or_not()
+        .map(|columns| {
+            columns
+                .unwrap_or_default()
+                .into_iter()
+                .map(|c: &str| c.to_string())
+                .collect::<Vec<_>>()
+        });
+
+    keyword("CREATE")
+        .ignore_then(keyword("VIEW"))
+        .ignore_then(if_not_exists())
+        .then(ident())
+        .then(columns)
+        .then_ignore(keyword("AS"))
+        .then(select())
+        .map(
+            |(((if_not_exists, name), columns), select): (((bool, &str), _), _)| {
+                Statement::CreateView(CreateView {
+                    name: name.to_string(),
+                    columns,
+                    select: Box::new(select),
+                    if_not_exists,
+                })
+            },
+        )
+}
+
+/// CREATE VIRTUAL TABLE [IF NOT EXISTS] name USING module [(argument, ...)];
+///
+/// An argument is the tokens up to the next comma or parenthesis, a string being one
+/// token whatever is in it, `filename = "data.csv"`, kept as written.
+fn create_virtual_table<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let arg = token(|token| (!matches!(token, Token::Op("," | "(" | ")"))).then_some(()))
+        .repeated()
+        .at_least(1)
+        .map_with(|_, e| written(e.span()).to_string());
+    let args = arg
+        .separated_by(op(","))
+        .collect::<Vec<_>>()
+        .delimited_by(op("("), op(")"))
+        .or_not()
+        .map(Option::unwrap_or_default);
+
+    keyword("CREATE")
+        .ignore_then(keyword("VIRTUAL"))
+        .ignore_then(keyword("TABLE"))
+        .ignore_then(if_not_exists())
+        .then(table_name())
+        .then_ignore(keyword("USING"))
+        .then(ident())
+        .then(args)
+        .map(
+            |(((if_not_exists, name), module), args): (((bool, &str), &str), _)| {
+                Statement::CreateVirtualTable(CreateVirtualTable {
+                    name: name.to_string(),
+                    module: module.to_string(),
+                    args,
+                    if_not_exists,
+                })
+            },
+        )
+}
+
+/// CREATE TRIGGER [IF NOT EXISTS] name [BEFORE | AFTER] INSERT | UPDATE [OF column, ...] | DELETE
+/// ON table_name [FOR EACH ROW] [WHEN condition] BEGIN statement; ... END;
+fn create_trigger<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    // SQLite defaults to BEFORE
+    let timing = choice((
+        keyword("BEFORE").to(TriggerTiming::Before),
+        keyword("AFTER").to(TriggerTiming::After),
+    ))
+    .or_not()
+    .map(Option::unwrap_or_default);
+
+    let update_of = keyword("OF").ignore_then(csv()).or_not().map(|columns| {
+        columns
+            .unwrap_or_default()
+            .into_iter()
+            .map(|c: &str| c.to_string())
+            .collect::<Vec<_>>()
+    });
+    let event = choice((
+        keyword("INSERT").to(TriggerEvent::Insert),
+        keyword("UPDATE")
+            .ignore_then(update_of)
+            .map(TriggerEvent::Update),
+        keyword("DELETE").to(TriggerEvent::Delete),
+    ));
+
+    // every trigger is a row trigger, as in SQLite, so this is just noise
+    let for_each_row = keyword("FOR")
+        .then(keyword("EACH"))
+        .then(keyword("ROW"))
+        .or_not();
+
+    let body = choice((select(), insert_patch(), update(), delete()))
+        .then_ignore(op(";"))
+        .repeated()
+        .at_least(1)
+        .collect::<Vec<_>>()
+        .delimited_by(keyword("BEGIN"), keyword("END"));
+
+    keyword("CREATE")
+        .ignore_then(keyword("TRIGGER"))
+        .ignore_then(if_not_exists())
+        .then(ident())
+        .then(timing)
+        .then(event)
+        .then_ignore(keyword("ON"))
+        .then(ident())
+        .then_ignore(for_each_row)
+        .then(where_or_when("WHEN"))
+        .then(body)
+        .map(
+            |((((((if_not_exists, name), timing), event), table), when), body)| {
+                Statement::CreateTrigger(CreateTrigger {
+                    name: name.to_string(),
+                    timing,
+                    event,
+                    table: table.to_string(),
+                    when,
+                    body,
+                    if_not_exists,
+                })
+            },
+        )
+}
+
+/// UPDATE table_name SET column1 = value1, column2 = column2 + 1 [WHERE condition];
+fn update<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let assignment =
+        ident()
+            .then_ignore(op("="))
+            .then(expr())
+            .map(|(column_name, value): (&str, Expr)| NewColumnVal {
+                column_name: column_name.to_string(),
+                value,
+            });
+
+    keyword("UPDATE")
+        .ignore_then(table_name())
+        .then_ignore(keyword("SET"))
+        .then(
+            assignment
+                .separated_by(op(","))
+                .at_least(1)
+                .collect::<Vec<_>>(),
+        )
+        .then(where_clause())
+        .then(order_by_limit("UPDATE"))
+        .map(
+            |(((table, assignments), where_clause), (order_by, limit)): (((&str, _), _), _)| {
+                Statement::Update {
+                    table: table.to_string(),
+                    assignments,
+                    where_clause,
+                    order_by,
+                    limit,
+                }
+            },
+        )
+}
+
+/// DELETE FROM table_name [WHERE condition];
+fn delete<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    keyword("DELETE")
+        .then_ignore(keyword("FROM"))
+        .ignore_then(table_name())
+        .then(where_clause())
+        .then(order_by_limit("DELETE"))
+        .map(
+            |((from_table, where_clause), (order_by, limit)): ((&str, _), _)| Statement::Delete {
+                from_table: from_table.to_string(),
+                where_clause,
+                order_by,
+                limit,
+            },
+        )
+}
+
+fn where_clause<'a>() -> impl Parser<'a, Tokens<'a>, Option<Expr>, Extra<'a>> {
+    where_or_when("WHERE")
+}
+
+// An optional condition introduced by `word`, WHERE or a trigger's WHEN.
+fn where_or_when<'a>(word: &'static str) -> impl Parser<'a, Tokens<'a>, Option<Expr>, Extra<'a>> {
+    keyword(word).ignore_then(expr()).or_not()
+}
+
+/// [ORDER BY expr [ASC | DESC], ...] [LIMIT count [OFFSET offset]]
+///
+/// Like SQLite built with SQLITE_ENABLE_UPDATE_DELETE_LIMIT, UPDATE and DELETE only accept
+/// these with the update-delete-limit feature. ORDER BY alone is pointless on a write so
+/// it is only allowed alongside a LIMIT.
+#[cfg(feature = "update-delete-limit")]
+fn order_by_limit<'a>(
+    statement: &'static str,
+) -> impl Parser<'a, Tokens<'a>, (Vec<OrderingTerm>, Option<Limit>), Extra<'a>> {
+    order_by(expr())
+        .or_not()
+        .then(limit(expr()).or_not())
+        .validate(move |(order_by, limit), e, emitter| {
+            if order_by.is_some() && limit.is_none() {
+                emitter.emit(Rich::custom(
+                    e.span(),
+                    format!("ORDER BY without LIMIT on {statement}"),
+                ));
+            }
+            (order_by.unwrap_or_default(), limit)
+        })
+}
+
+#[cfg(not(feature = "update-delete-limit"))]
+fn order_by_limit<'a>(
+    _statement: &'static str,
+) -> impl Parser<'a, Tokens<'a>, (Vec<OrderingTerm>, Option<Limit>), Extra<'a>> {
+    empty().to((vec![], None))
+}
+
+/// ORDER BY expr [ASC | DESC], ...
+fn order_by<'a>(
+    expr: impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone + 'a,
+) -> impl Parser<'a, Tokens<'a>, Vec<OrderingTerm>, Extra<'a>> + Clone {
+    let direction = choice((keyword("ASC").to(false), keyword("DESC").to(true)))
+        .or_not()
+        .map(|desc| desc.unwrap_or(false));
+
+    keyword("ORDER").ignore_then(keyword("BY")).ignore_then(
+        expr.then(direction)
+            .map(|(expr, descending)| OrderingTerm { expr, descending })
+            .separated_by(op(","))
+            .at_least(1)
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// LIMIT count [OFFSET offset], or the same thing written as LIMIT offset, count
+fn limit<'a>(
+    expr: impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone + 'a,
+) -> impl Parser<'a, Tokens<'a>, Limit, Extra<'a>> + Clone {
+    keyword("LIMIT")
+        .ignore_then(expr.clone())
+        .then(
+            keyword("OFFSET")
+                .ignore_then(expr.clone())
+                .map(|offset| (offset, false))
+                .or(op(",").ignore_then(expr).map(|count| (count, true)))
+                .or_not(),
+        )
+        .map(|(first, rest)| match rest {
+            None => Limit {
+                count: first,
+                offset: None,
+            },
+            Some((offset, false)) => Limit {
+                count: first,
+                offset: Some(offset),
+            },
+            Some((count, true)) => Limit {
+                count,
+                offset: Some(first),
+            },
+        })
+}
+
+/// BEGIN [DEFERRED | IMMEDIATE | EXCLUSIVE] [TRANSACTION]
+/// COMMIT [TRANSACTION] or its alias END [TRANSACTION]
+/// ROLLBACK [TRANSACTION] [TO [SAVEPOINT] name]
+/// SAVEPOINT name
+/// RELEASE [SAVEPOINT] name
+fn transaction_control<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let optional_transaction = keyword("TRANSACTION").or_not();
+
+    let mode = choice((
+        keyword("DEFERRED").to(TransactionMode::Deferred),
+        keyword("IMMEDIATE").to(TransactionMode::Immediate),
+        keyword("EXCLUSIVE").to(TransactionMode::Exclusive),
+    ))
+    .or_not()
+    .map(Option::unwrap_or_default);
+
+    let begin = keyword("BEGIN")
+        .ignore_then(mode)
+        .then_ignore(optional_transaction.clone())
+        .map(Statement::Begin);
+
+    let commit = keyword("COMMIT")
+        .or(keyword("END"))
+        .then_ignore(optional_transaction.clone())
+        .to(Statement::Commit);
+
+    let optional_savepoint = keyword("SAVEPOINT").or_not();
+
+    let rollback = keyword("ROLLBACK")
+        .then_ignore(optional_transaction)
+        .ignore_then(
+            keyword("TO")
+                .ignore_then(optional_savepoint.clone())
+                .ignore_then(ident())
+                .or_not(),
+        )
+        .map(|name: Option<&str>| match name {
+            Some(name) => Statement::RollbackTo(name.to_string()),
+            None => Statement::Rollback,
+        });
+
+    let savepoint = keyword("SAVEPOINT")
+        .ignore_then(ident())
+        .map(|name: &str| Statement::Savepoint(name.to_string()));
+
+    let release = keyword("RELEASE")
+        .ignore_then(optional_savepoint)
+        .ignore_then(ident())
+        .map(|name: &str| Statement::Release(name.to_string()));
+
+    choice((begin, commit, rollback, savepoint, release))
+}
+
+/// ATTACH [DATABASE] "file" AS name
+/// DETACH [DATABASE] name
+fn attach_detach<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let optional_database = keyword("DATABASE").or_not();
+
+    let attach = keyword("ATTACH")
+        .ignore_then(optional_database.clone())
+        .ignore_then(string())
+        .then_ignore(keyword("AS"))
+        .then(ident())
+        .map(|(path, name): (String, &str)| Statement::Attach {
+            path,
+            name: name.to_string(),
+        });
+
+    let detach = keyword("DETACH")
+        .ignore_then(optional_database)
+        .ignore_then(ident())
+        .map(|name: &str| Statement::Detach(name.to_string()));
+
+    attach.or(detach)
+}
+
+/// PRAGMA name [= value | (value)], where the value is a name, a possibly negative
+/// number or a string.
+fn pragma<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    let number = op("-")
+        .or_not()
+        .then(integer())
+        .map(|(minus, n)| match minus {
+            Some(_) => format!("-{n}"),
+            None => n.to_string(),
+        });
+    let value = ident().map(str::to_string).or(string()).or(number);
+
+    keyword("PRAGMA")
+        .ignore_then(ident())
+        .then(
+            op("=")
+                .ignore_then(value.clone())
+                .or(value.delimited_by(op("("), op(")")))
+                .or_not(),
+        )
+        .map(|(name, value): (&str, Option<String>)| {
+            Statement::Pragma(Pragma {
+                name: name.to_lowercase(),
+                value,
+            })
+        })
+}
+
+/// ANALYZE [table or index]
+fn analyze<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    keyword("ANALYZE")
+        .ignore_then(ident().or_not())
+        .map(|name: Option<&str>| Statement::Analyze(name.map(str::to_string)))
+}
+
+/// REINDEX [table, index or collation]
+fn reindex<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    keyword("REINDEX")
+        .ignore_then(ident().or_not())
+        .map(|name: Option<&str>| Statement::Reindex(name.map(str::to_string)))
+}
+
+/// VACUUM
+fn vacuum<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    keyword("VACUUM").to(Statement::Vacuum)
+}
+
+fn binary(op: BinaryOp, left: Expr, right: Expr) -> Expr {
+    Expr::Binary {
+        op,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+// The right hand side of `x IN (...)`.
+enum InRhs {
+    List(Vec<Expr>),
+    Select(Box<Statement>),
+}
+
+// What may follow an operand at the comparison level of precedence.
+enum ComparisonSuffix {
+    Op(BinaryOp, Expr),
+    In { negated: bool, rhs: InRhs },
+}
+
+/// Scalar expressions such as `age + 1`, `lower(name)`, `a = 1 AND NOT b`,
+/// `(a, b) = (1, 2)` or `id IN (SELECT id FROM admins)`.
+/// Operator precedence from loosest to tightest binding is
+/// OR, AND, NOT, comparisons and IN, + -, * /, JSON's -> and ->>, unary minus and finally
+/// COLLATE.
+fn expr<'a>() -> impl Parser<'a, Tokens<'a>, Expr, Extra<'a>> + Clone {
+    recursive(|expr| {
+        let subquery = select_with(expr.clone()).boxed();
+
+        // (a) is just a, but (a, b) is a row value
+        let parenthesized = expr
+            .clone()
+            .separated_by(op(","))
+            .at_least(1)
+            .collect::<Vec<_>>()
+            .delimited_by(op("("), op(")"))
+            .map(|mut exprs: Vec<Expr>| {
+                if exprs.len() == 1 {
+                    exprs.remove(0)
+                } else {
+                    Expr::Row(exprs)
+                }
+            });
+
+        let call = ident()
+            .then(
+                expr.clone()
+                    .separated_by(op(","))
+                    .collect::<Vec<_>>()
+                    .delimited_by(op("("), op(")")),
+            )
+            .map(|(name, args): (&str, Vec<Expr>)| Expr::Function {
+                name: name.to_string(),
+                args,
+            });
+
+        let exists = keyword("EXISTS")
+            .ignore_then(subquery.clone().delimited_by(op("("), op(")")))
+            .map(|select| Expr::Exists {
+                select: Box::new(select),
+                negated: false,
+            });
+
+        // (SELECT ...) where a value goes
+        let scalar_subquery = subquery
+            .clone()
+            .delimited_by(op("("), op(")"))
+            .map(|select| Expr::Subquery(Box::new(select)));
+
+        let qualified_column =
+            ident()
+                .then_ignore(op("."))
+                .then(ident())
+                .map(|(table, column): (&str, &str)| Expr::QualifiedColumn {
+                    table: table.to_string(),
+                    column: column.to_string(),
+                });
+
+        let cast = keyword("CAST")
+            .ignore_then(
+                expr.clone()
+                    .then_ignore(keyword("AS"))
+                    .then(type_name())
+                    .delimited_by(op("("), op(")")),
+            )
+            .map(|(expr, type_name): (Expr, &str)| Expr::Cast {
+                expr: Box::new(expr),
+                type_name: type_name.to_string(),
+            });
+
+        let atom = column_value()
+            .map(Expr::Literal)
+            .or(placeholder().map(Expr::Placeholder))
+            .or(exists)
+            .or(cast)
+            .or(call)
+            .or(qualified_column)
+            .or(ident().map(|name: &str| Expr::Column(name.to_string())))
+            .or(scalar_subquery)
+            .or(parenthesized)
+            .foldl(collate().repeated(), |expr, collation| Expr::Collate {
+                expr: Box::new(expr),
+                collation: collation.to_string(),
+            })
+            .boxed();
+
+        let unary = choice((op("-").to(UnaryOp::Neg), op("+").to(UnaryOp::Plus)))
+            .repeated()
+            .foldr(atom, |op, expr| Expr::Unary {
+                op,
+                expr: Box::new(expr),
+            })
+            .boxed();
+
+        let json_path = unary
+            .clone()
+            .foldl(
+                choice((
+                    op("->>").to(BinaryOp::DoubleArrow),
+                    op("->").to(BinaryOp::Arrow),
+                ))
+                .then(unary)
+                .repeated(),
+                |left, (op, right)| binary(op, left, right),
+            )
+            .boxed();
+
+        let product = json_path
+            .clone()
+            .foldl(
+                choice((op("*").to(BinaryOp::Mul), op("/").to(BinaryOp::Div)))
+                    .then(json_path)
+                    .repeated(),
+                |left, (op, right)| binary(op, left, right),
+            )
+            .boxed();
+
+        let sum = product
+            .clone()
+            .foldl(
+                choice((op("+").to(BinaryOp::Add), op("-").to(BinaryOp::Sub)))
+                    .then(product)
+                    .repeated(),
+                |left, (op, right)| binary(op, left, right),
+            )
+            .boxed();
+
+        let comparison_op = choice((
+            op("==").to(BinaryOp::Eq),
+            op("=").to(BinaryOp::Eq),
+            op("!=").to(BinaryOp::NotEq),
+            op("<>").to(BinaryOp::NotEq),
+            op("<=").to(BinaryOp::LtEq),
+            op(">=").to(BinaryOp::GtEq),
+            op("<").to(BinaryOp::Lt),
+            op(">").to(BinaryOp::Gt),
+        ));
+        let in_rhs = subquery
+            .map(|select| InRhs::Select(Box::new(select)))
+            .or(expr
+                .separated_by(op(","))
+                .collect::<Vec<_>>()
+                .map(InRhs::List))
+            .delimited_by(op("("), op(")"));
+
+        let is_op = keyword("IS")
+            .ignore_then(keyword("NOT").or_not())
+            .map(|not| match not {
+                Some(_) => BinaryOp::IsNot,
+                None => BinaryOp::Is,
+            });
+        let suffix = comparison_op
+            .or(is_op)
+            .then(sum.clone())
+            .map(|(op, right)| ComparisonSuffix::Op(op, right))
+            .or(keyword("NOT")
+                .or_not()
+                .then_ignore(keyword("IN"))
+                .then(in_rhs)
+                .map(|(not, rhs)| ComparisonSuffix::In {
+                    negated: not.is_some(),
+                    rhs,
+                }));
+
+        let comparison = sum
+            .foldl(suffix.repeated(), |left, suffix| match suffix {
+                ComparisonSuffix::Op(op, right) => binary(op, left, right),
+                ComparisonSuffix::In {
+                    negated,
+                    rhs: InRhs::List(list),
+                } => Expr::InList {
+                    expr: Box::new(left),
+                    list,
+                    negated,
+                },
+                ComparisonSuffix::In {
+                    negated,
+                    rhs: InRhs::Select(select),
+                } => Expr::InSelect {
+                    expr: Box::new(left),
+                    select,
+                    negated,
+                },
+            })
+            .boxed();
+
+        let not = keyword("NOT")
+            .to(UnaryOp::Not)
+            .repeated()
+            .foldr(comparison, |op, expr| match expr {
+                // NOT EXISTS is its own predicate rather than NOT applied to EXISTS
+                Expr::Exists { select, negated } => Expr::Exists {
+                    select,
+                    negated: !negated,
+                },
+                expr => Expr::Unary {
+                    op,
+                    expr: Box::new(expr),
+                },
+            })
+            .boxed();
+
+        let and = not
+            .clone()
+            .foldl(
+                keyword("AND").to(BinaryOp::And).then(not).repeated(),
+                |left, (op, right)| binary(op, left, right),
+            )
+            .boxed();
+
+        and.clone().foldl(
+            keyword("OR").to(BinaryOp::Or).then(and).repeated(),
+            |left, (op, right)| binary(op, left, right),
+        )
+    })
+}
+
+fn parser<'a>() -> impl Parser<'a, Tokens<'a>, Statement, Extra<'a>> {
+    //  recursive(|value| {
+    let statement = choice((
+        compound_select(),
+        select(),
+        with_select(),
+        insert_patch(),
+        update(),
+        delete(),
+        create_table(),
+        create_index(),
+        create_view(),
+        create_trigger(),
+        create_virtual_table(),
+        transaction_control(),
+        attach_detach(),
+        pragma(),
+        analyze(),
+        reindex(),
+        vacuum(),
+    ));
+
+    // EXPLAIN SELECT ..., EXPLAIN QUERY PLAN SELECT ... or EXPLAIN ANALYZE SELECT ...,
+    // though EXPLAIN ANALYZE with no statement after it explains ANALYZE
+    let statement = statement.boxed();
+    let query_plan = keyword("QUERY").then(keyword("PLAN"));
+    let explain = keyword("EXPLAIN").ignore_then(choice((
+        query_plan
+            .ignore_then(statement.clone())
+            .map(|statement| Statement::ExplainQueryPlan(Box::new(statement))),
+        keyword("ANALYZE")
+            .ignore_then(statement.clone())
+            .map(|statement| Statement::ExplainAnalyze(Box::new(statement))),
+        statement
+            .clone()
+            .map(|statement| Statement::Explain(Box::new(statement))),
+    )));
+    explain.or(statement).then_ignore(op(";"))
+}
+
+/// Parse a single statement, flattening chumsky's errors into one message.
+pub fn parse(src: &str) -> Result<Statement> {
+    let tokens = tokens(src)?;
+    if nesting_depth(&tokens) > MAX_NESTING {
+        bail!("Parse error: parser stack overflow");
+    }
+    parse_tokens(parser(), &tokens, src)
+}
+
+/// Parse an expression on its own, `age + 1`, as parse does a statement.
+pub fn parse_expr(src: &str) -> Result<Expr> {
+    let tokens = tokens(src)?;
+    if nesting_depth(&tokens) > MAX_NESTING {
+        bail!("Parse error: parser stack overflow");
+    }
+    parse_tokens(expr(), &tokens, src)
+}
+
+/// Parse the text of a result column that is an aggregate, `COUNT(DISTINCT a)`, see
+/// aggregate.
+pub fn parse_aggregate(src: &str) -> Result<Aggregate> {
+    let tokens = tokens(src)?;
+    parse_tokens(aggregate(), &tokens, src)
+}
+
+/// Parse the text of a result column that is a window function, see window_function.
+pub fn parse_window_function(src: &str) -> Result<WindowFunction> {
+    let tokens = tokens(src)?;
+    parse_tokens(window_function(), &tokens, src)
+}
+
+// The tokens of `src` for the grammar to read, without its comments.
+fn tokens(src: &str) -> Result<Vec<(Token<'_>, SourceSpan<'_>)>> {
+    let mut tokens = lexer::lex(src).map_err(|errs| parse_error(src, errs))?;
+    tokens.retain(|(token, _)| !matches!(token, Token::Comment(_)));
+    Ok(tokens)
+}
+
+fn parse_tokens<'a, T>(
+    parser: impl Parser<'a, Tokens<'a>, T, Extra<'a>>,
+    tokens: &'a [(Token<'a>, SourceSpan<'a>)],
+    src: &'a str,
+) -> Result<T> {
+    parser
+        .parse(lexer::input(tokens, src))
+        .into_result()
+        .map_err(|errs| parse_error(src, errs))
+}
+
+// chumsky's errors, the lexer's or the grammar's, as one message. Each is a line and
+// then, as sqlite3 shows where an error is, the line of `src` it is on with a caret
+// under where it starts:
+//
+//     Parse error: found FRM expected '.', ',', or 'FROM'
+//       SELECT a FRM t;
+//                ^--- error here
+fn parse_error<T, S>(src: &str, errs: Vec<Rich<'_, T, S>>) -> anyhow::Error
+where
+    T: fmt::Display,
+    S: Span<Offset = usize> + fmt::Display,
+{
+    let shown = errs.into_iter().map(|e| {
+        let start = e.span().start().min(src.len());
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+        let column = src[line_start..start].chars().count();
+        format!(
+            "Parse error: {e}\n  {}\n  {}^--- error here",
+            &src[line_start..line_end],
+            " ".repeat(column)
+        )
+    });
+    anyhow!("{}", shown.collect::<Vec<_>>().join("\n"))
+}
+
+/// Split a script into commands: a dot command is one line, and each SQL statement runs
+/// until its `;`, two on a line being two commands.
+pub fn commands(script: &str) -> Vec<String> {
+    numbered_commands(script)
+        .into_iter()
+        .map(|(_, command)| command)
+        .collect()
+}
+
+/// The commands of a script, as commands splits it, each with the line it starts on,
+/// from 1, for errors to point at.
+pub fn numbered_commands(script: &str) -> Vec<(usize, String)> {
+    let mut commands = vec![];
+    let mut sql = String::new();
+    let mut start = 0;
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("--") {
+            continue;
+        }
+        if sql.is_empty() && line.starts_with('.') {
+            commands.push((number + 1, line.to_string()));
+            continue;
+        }
+        // the lines are joined with spaces, so a comment at the end of one would run on
+        // over the next
+        let line = without_comment(line);
+        if sql.is_empty() {
+            start = number + 1;
+        } else {
+            sql.push(' ');
+        }
+        sql.push_str(line);
+        if is_complete(&sql) {
+            let statements = split_statements(&sql);
+            commands.extend(statements.into_iter().map(|s| (start, s.to_string())));
+            sql.clear();
+        }
+    }
+    let statements = split_statements(&sql);
+    commands.extend(statements.into_iter().map(|s| (start, s.to_string())));
+    commands
+}
+
+// `line` without the `--` comment at its end, if it has one outside strings and quoted
+// names.
+fn without_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut dash = false;
+    for (i, c) in line.char_indices() {
+        match quote {
+            Some(close) if c == close => quote = None,
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' | '`' => quote = Some(c),
+                '[' => quote = Some(']'),
+                '-' if dash => return line[..i - 1].trim_end(),
+                _ => {}
+            },
+        }
+        dash = quote.is_none() && c == '-';
+    }
+    line
+}
+
+/// Whether `sql` ends with a `;` that isn't in a string, a quoted name, a comment or a
+/// trigger's BEGIN ... END, so that it is a whole statement, or more, rather than the
+/// start of one, like sqlite3_complete.
+pub fn is_complete(sql: &str) -> bool {
+    let scan = Scan::of(sql);
+    !scan.open && scan.unfinished.is_none() && !scan.statements.is_empty()
+}
+
+/// The statements of `sql`, each up to and including the `;` that ends it, the last
+/// without one if it has none, as is_complete tells where a statement ends. So
+/// `SELECT 1; SELECT 2;` is two statements, to be parsed and run one at a time.
+pub fn split_statements(sql: &str) -> Vec<&str> {
+    let scan = Scan::of(sql);
+    let unfinished = scan.unfinished.map(|start| (start, sql.len()));
+    scan.statements
+        .into_iter()
+        .chain(unfinished)
+        .map(|(start, end)| sql[start..end].trim_end())
+        .collect()
+}
+
+// Where the statements of some SQL start and end, by its bytes.
+#[derive(Default)]
+struct Scan {
+    statements: Vec<(usize, usize)>,
+    // where a statement with no `;` yet starts
+    unfinished: Option<usize>,
+    // whether it ends in a string, a quoted name or a comment left open
+    open: bool,
+    // the words of the statement read so far, for as long as they might make it a trigger
+    words: Vec<String>,
+    trigger: bool,
+    // how many of a trigger's BEGIN and CASE are waiting for their END
+    depth: usize,
+}
+
+impl Scan {
+    fn of(sql: &str) -> Scan {
+        let mut scan = Scan::default();
+        let mut quote = None;
+        let mut word = String::new();
+        let mut chars = sql.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if let Some(close) = quote {
+                if c == close {
+                    quote = None;
+                }
+                continue;
+            }
+            if c.is_alphanumeric() || c == '_' {
+                scan.unfinished.get_or_insert(i);
+                word.push(c);
+                continue;
+            }
+            scan.end_word(&mut word);
+            match c {
+                c if c.is_whitespace() => {}
+                // comments count for no more than whitespace
+                '-' if chars.peek().is_some_and(|(_, c)| *c == '-') => {
+                    chars.find(|(_, c)| *c == '\n');
+                }
+                '/' if chars.peek().is_some_and(|(_, c)| *c == '*') => {
+                    chars.next();
+                    let mut star = false;
+                    let closed = chars.find(|(_, c)| {
+                        let end = star && *c == '/';
+                        star = *c == '*';
+                        end
+                    });
+                    scan.open = closed.is_none();
+                }
+                ';' if scan.depth == 0 => {
+                    let start = scan.unfinished.take().unwrap_or(i);
+                    scan.statements.push((start, i + 1));
+                    scan.words.clear();
+                    scan.trigger = false;
+                }
+                c => {
+                    scan.unfinished.get_or_insert(i);
+                    quote = match c {
+                        '\'' | '"' | '`' => Some(c),
+                        '[' => Some(']'),
+                        _ => None,
+                    };
+                }
+            }
+        }
+        scan.end_word(&mut word);
+        scan.open |= quote.is_some();
+        scan
+    }
+
+    // A word has been read. CREATE [TEMP] TRIGGER starts a trigger, whose BEGIN ... END
+    // holds statements of its own, each with its own `;`.
+    fn end_word(&mut self, word: &mut String) {
+        if word.is_empty() {
+            return;
+        }
+        let word = std::mem::take(word).to_ascii_uppercase();
+        if self.trigger {
+            match word.as_str() {
+                "BEGIN" | "CASE" => self.depth += 1,
+                "END" => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+        } else if self.words.len() < 3 {
+            self.words.push(word);
+            let words: Vec<&str> = self.words.iter().map(String::as_str).collect();
+            self.trigger = matches!(
+                words[..],
+                ["CREATE", "TRIGGER"] | ["CREATE", "TEMP" | "TEMPORARY", "TRIGGER"]
+            );
+        }
+    }
+}
+
+// How deep parentheses and CASE ... END nest in a statement's tokens. END also closes a
+// trigger's BEGIN, which only makes the count err on the low side.
+fn nesting_depth(tokens: &[(Token, SourceSpan)]) -> usize {
+    let (mut depth, mut deepest) = (0usize, 0);
+    for (token, _) in tokens {
+        match token {
+            Token::Op("(") => depth += 1,
+            Token::Op(")") => depth = depth.saturating_sub(1),
+            Token::Keyword(word) | Token::Ident(word) if word.eq_ignore_ascii_case("CASE") => {
+                depth += 1
+            }
+            Token::Keyword(word) | Token::Ident(word) if word.eq_ignore_ascii_case("END") => {
+                depth = depth.saturating_sub(1)
+            }
+            _ => {}
+        }
+        deepest = deepest.max(depth);
+    }
+    deepest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The tokens of `src` as a part of the grammar reads them, leaked as a test is short.
+    fn lexed(src: &str) -> Tokens<'_> {
+        lexer::input(Vec::leak(tokens(src).unwrap()), src)
+    }
+
+    #[test]
+    fn scripts_split_into_commands() {
+        let script = "\
+-- settings
+.tables
+PRAGMA cache_size = 100; -- pages
+SELECT name -- and nothing else
+  FROM users;
+.exit
+";
+        assert_eq!(
+            commands(script),
+            [
+                ".tables",
+                "PRAGMA cache_size = 100;",
+                "SELECT name FROM users;",
+                ".exit",
+            ]
+        );
+    }
+
+    #[test]
+    fn a_statement_is_complete_at_a_semicolon_outside_quotes() {
+        assert!(is_complete("SELECT 1;"));
+        assert!(is_complete("SELECT 1;  "));
+        assert!(!is_complete("SELECT 1"));
+        assert!(!is_complete("INSERT INTO t (a) VALUES (\"a;"));
+        assert!(is_complete("INSERT INTO t (a) VALUES (\"a;\");"));
+        assert!(!is_complete("SELECT [a;b"));
+        assert!(!is_complete("SELECT 1; SELECT"));
+        assert!(is_complete("SELECT 1; -- one"));
+        assert!(is_complete("SELECT 1; /* one */"));
+        assert!(!is_complete("SELECT 1 -- one;"));
+        assert!(!is_complete("SELECT 1 /* one; */"));
+        assert_eq!(
+            commands("INSERT INTO t (a) VALUES (\"a;\nb\");\nSELECT a FROM t;"),
+            ["INSERT INTO t (a) VALUES (\"a; b\");", "SELECT a FROM t;"]
+        );
+    }
+
+    #[test]
+    fn statements_on_one_line_are_commands_of_their_own() {
+        assert_eq!(
+            commands("SELECT 1; SELECT 'a;b';\n.tables\nSELECT 3"),
+            ["SELECT 1;", "SELECT 'a;b';", ".tables", "SELECT 3"]
+        );
+        assert_eq!(
+            numbered_commands("SELECT 1;\nSELECT 2; SELECT\n3;"),
+            [
+                (1, "SELECT 1;".into()),
+                (2, "SELECT 2;".into()),
+                (2, "SELECT 3;".into())
+            ]
+        );
+        // a trigger's body is a part of it, ;s and all
+        let trigger = "CREATE TEMP TRIGGER log AFTER INSERT ON t BEGIN \
+            INSERT INTO l VALUES (CASE WHEN 1 THEN 2 END); DELETE FROM m; END;";
+        assert!(!is_complete(
+            "CREATE TRIGGER log AFTER INSERT ON t BEGIN DELETE FROM m;"
+        ));
+        assert!(is_complete(trigger));
+        assert_eq!(
+            split_statements(&format!("{trigger} SELECT 1;")),
+            [trigger, "SELECT 1;"]
+        );
+    }
+
+    #[test]
+    fn commands_know_the_line_they_start_on() {
+        let script = "\
+CREATE TABLE users (id INTEGER);
+
+-- fixtures
+INSERT INTO users
+  VALUES (1);
+.tables
+";
+        assert_eq!(
+            numbered_commands(script),
+            [
+                (1, "CREATE TABLE users (id INTEGER);".to_string()),
+                (4, "INSERT INTO users VALUES (1);".to_string()),
+                (6, ".tables".to_string()),
+            ]
+        );
+    }
+
+    /*Query
+    SELECT name, age FROM users WHERE age > 21;
+
+    AST
+    ├── Select
+    │   ├── Columns
+    │   │   ├── Column "name"
+    │   │   └── Column "age"
+    │   ├── From
+    │   │   └── Table "users"
+    │   └── Where
+    │       └── GreaterThan
+    │           ├── Column "age"
+    │           └── Value 21
+
+     */
+
+    /*
+    Query
+    INSERT INTO users (name, age) VALUES ('Alice', 30);
+
+    AST
+    ├── Insert
+    │   ├── Table "users"
+    │   ├── Columns
+    │   │   ├── Column "name"
+    │   │   └── Column "age"
+    │   └── Values
+    │       ├── Value "Alice"
+    │       └── Value 30 */
+
+    //INSERT INTO Product(Name, Age) VALUES (Tom', 21);
+
+    #[test]
+    fn parse_insert_patch() {
+        let query = r#"INSERT INTO Person(Name, Age, Admin) VALUES ("Bob", 21, FALSE);"#;
+
+        assert_eq!(
+            parser().parse(lexed(query)).unwrap(),
+            Statement::Insert {
+                columns: vec![
+                    NewColumnVal {
+                        column_name: "Name".to_string(),
+                        value: Expr::Literal(ColVal::String("Bob".to_string()))
+                    },
+                    NewColumnVal {
+                        column_name: "Age".to_string(),
+                        value: Expr::Literal(ColVal::Int(21))
+                    },
+                    NewColumnVal {
+                        column_name: "Admin".to_string(),
+                        value: Expr::Literal(ColVal::Boolean(false))
+                    }
+                ],
+                into_table: "Person".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn insert_needs_a_value_for_each_column() {
+        for (sql, error) in [
+            (
+                r#"INSERT INTO users (name, age) VALUES ("a");"#,
+                "Parse error: 1 values for 2 columns",
+            ),
+            (
+                r#"INSERT INTO users (name) VALUES ("a", 2);"#,
+                "Parse error: 2 values for 1 columns",
+            ),
+        ] {
+            let err = parse(sql).unwrap_err().to_string();
+            assert_eq!(err.lines().next(), Some(error));
+        }
+    }
+
+    #[test]
+    fn errors_point_at_where_they_are() {
+        assert_eq!(
+            parse("SELECT a\n  FROM t WHERE a = = 1;")
+                .unwrap_err()
+                .to_string()
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>(),
+            [
+                "    FROM t WHERE a = = 1;",
+                "                     ^--- error here"
+            ]
+        );
+        // the lexer's errors too, at the quote left open
+        assert_eq!(
+            parse("SELECT a FROM t WHERE a = 'open;")
+                .unwrap_err()
+                .to_string()
+                .lines()
+                .collect::<Vec<_>>(),
+            [
+                "Parse error: unrecognized token: \"'open;\"",
+                "  SELECT a FROM t WHERE a = 'open;",
+                "                            ^--- error here"
+            ]
+        );
+        // at the token that is out of place, even just after a literal
+        for (sql, found, caret) in [
+            ("SELECT * FROM t WHERE a BETWEEN 1 AND 2;", "BETWEEN", 24),
+            ("SELECT 1 foo bar;", "foo", 9),
+            ("SELECT 1/0, 5%0;", "%", 13),
+            ("SELECT 'x' FRM t;", "FRM", 11),
+        ] {
+            let err = parse(sql).unwrap_err().to_string();
+            let lines: Vec<&str> = err.lines().collect();
+            assert!(
+                lines[0].starts_with(&format!("Parse error: found {found} expected")),
+                "{err}"
+            );
+            assert_eq!(lines[2].find('^'), Some(caret + 2), "{err}");
+        }
+    }
+
+    #[test]
+    fn keywords_and_strings_are_written_either_way() {
+        assert_eq!(
+            parse("select name from users where name = 'it''s' or name = \"say \"\"hi\"\"\";")
+                .unwrap(),
+            parse("SELECT name FROM users WHERE name = \"it's\" OR name = 'say \"hi\"';").unwrap()
+        );
+        // a name that is a keyword is still kept as written
+        let Statement::Select { columns, .. } = parse("SELECT key FROM t;").unwrap() else {
+            panic!("expected a SELECT");
+        };
+        assert_eq!(columns, ["key"]);
+    }
+
+    #[test]
+    fn parse_basic_select() {
+        assert_eq!(
+            parser()
+                .parse(lexed("SELECT name, age FROM user;"))
+                .unwrap(),
+            Statement::Select {
+                columns: vec!["name".to_string(), "age".to_string()],
+                from_table: "user".to_string(),
+                from_select: None,
+                table_args: vec![],
+                alias: None,
+                index_hint: None,
+                joins: vec![],
+                group_by: vec![],
+                order_by: vec![],
+                limit: None,
+                where_clause: None
+            }
+        );
+    }
+
+    #[test]
+    fn parse_basic_select_with_extra_whitespace() {
+        assert_eq!(
+            parser()
+                .parse(lexed("  SELECT  name , age FROM   user  ;  "))
+                .unwrap(),
+            Statement::Select {
+                columns: vec!["name".to_string(), "age".to_string()],
+                from_table: "user".to_string(),
+                from_select: None,
+                table_args: vec![],
+                alias: None,
+                index_hint: None,
+                joins: vec![],
+                group_by: vec![],
+                order_by: vec![],
+                limit: None,
+                where_clause: None
+            }
+        );
+    }
+
+    #[test]
+    fn result_columns_can_be_expressions_with_or_without_from() {
+        let statement = parse("SELECT changes(), total_changes();").unwrap();
+        let Statement::Select {
+            columns,
+            from_table,
+            ..
+        } = &statement
+        else {
+            panic!("expected a SELECT");
+        };
+        assert_eq!(columns, &["changes()", "total_changes()"]);
+        assert_eq!(from_table, "");
+        assert_eq!(statement.to_string(), "SELECT changes(), total_changes()");
+        assert!(statement.tables().is_empty());
+
+        let statement = parse("SELECT price*qty, -1, 'each' FROM items WHERE qty > 0;").unwrap();
+        assert_eq!(
+            statement.to_string(),
+            "SELECT price * qty, -1, \"each\" FROM items WHERE qty > 0"
+        );
+        assert_eq!(parse(&format!("{statement};")).unwrap(), statement);
+    }
+
+    #[test]
+    fn parse_table_alias() {
+        for sql in [
+            "SELECT u.name FROM users u WHERE u.id = 1;",
+            "SELECT u.name FROM users AS u WHERE u.id = 1;",
+        ] {
+            assert_eq!(
+                parser().parse(lexed(sql)).unwrap(),
+                Statement::Select {
+                    columns: vec!["u.name".to_string()],
+                    from_table: "users".to_string(),
+                    from_select: None,
+                    table_args: vec![],
+                    alias: Some("u".to_string()),
+                    index_hint: None,
+                    joins: vec![],
+                    group_by: vec![],
+                    order_by: vec![],
+                    limit: None,
+                    where_clause: Some(expr().parse(lexed("u.id = 1")).unwrap()),
+                }
+            );
+        }
+        // a keyword after the table isn't an alias
+        let Statement::Select { alias, .. } =
+            parser().parse(lexed("SELECT a FROM t WHERE b;")).unwrap()
+        else {
+            panic!("expected SELECT");
+        };
+        assert_eq!(alias, None);
+    }
+
+    #[test]
+    fn parse_joins() {
+        let joins = |sql: &str| {
+            let Statement::Select { joins, .. } = parser().parse(lexed(sql)).unwrap() else {
+                panic!("expected SELECT");
+            };
+            joins
+        };
+        let join = |table: &str, alias: Option<&str>, on: Option<&str>| Join {
+            table: table.to_string(),
+            alias: alias.map(str::to_string),
+            on: on.map(|on| expr().parse(lexed(on)).unwrap()),
+        };
+        assert_eq!(
+            joins("SELECT * FROM users u JOIN orders AS o ON o.user_id = u.id, items;"),
+            [
+                join("orders", Some("o"), Some("o.user_id = u.id")),
+                join("items", None, None),
+            ]
+        );
+        assert_eq!(
+            joins("SELECT * FROM a INNER JOIN b ON a.x = b.x CROSS JOIN archive.c;"),
+            [
+                join("b", None, Some("a.x = b.x")),
+                join("archive.c", Some("c"), None),
+            ]
+        );
+        // and they print back as joins that parse the same
+        let statement = parser()
+            .parse(lexed("SELECT * FROM a, b ON a.x = b.x WHERE a.y = 1;"))
+            .unwrap();
+        assert_eq!(
+            statement.to_string(),
+            "SELECT * FROM a JOIN b ON a.x = b.x WHERE a.y = 1"
+        );
+    }
+
+    #[test]
+    fn parse_index_hints() {
+        let hint = |sql: &str| {
+            let statement = parser().parse(lexed(sql)).unwrap();
+            // and they print back as they were written
+            assert_eq!(format!("{statement};"), sql);
+            let Statement::Select { index_hint, .. } = statement else {
+                panic!("expected SELECT");
+            };
+            index_hint
+        };
+        assert_eq!(
+            hint("SELECT a FROM t AS x INDEXED BY t_a WHERE a = 1;"),
+            Some(IndexHint::IndexedBy("t_a".to_string()))
+        );
+        assert_eq!(
+            hint("SELECT a FROM t NOT INDEXED WHERE a = 1;"),
+            Some(IndexHint::NotIndexed)
+        );
+        assert_eq!(hint("SELECT a FROM t WHERE +a = 1;"), None);
+        assert_eq!(
+            expr().parse(lexed("+a = 1")).unwrap(),
+            binary(
+                BinaryOp::Eq,
+                Expr::Unary {
+                    op: UnaryOp::Plus,
+                    expr: Box::new(Expr::Column("a".to_string())),
+                },
+                Expr::Literal(ColVal::Int(1)),
+            )
+        );
+    }
+
+    #[test]
+    fn parse_expr_respects_precedence() {
+        // a + b * 2 > 3 AND NOT lower(c) = "x"
+        let expected = binary(
+            BinaryOp::And,
+            binary(
+                BinaryOp::Gt,
+                binary(
+                    BinaryOp::Add,
+                    Expr::Column("a".to_string()),
+                    binary(
+                        BinaryOp::Mul,
+                        Expr::Column("b".to_string()),
+                        Expr::Literal(ColVal::Int(2)),
+                    ),
+                ),
+                Expr::Literal(ColVal::Int(3)),
+            ),
+            Expr::Unary {
+                op: UnaryOp::Not,
+                expr: Box::new(binary(
+                    BinaryOp::Eq,
+                    Expr::Function {
+                        name: "lower".to_string(),
+                        args: vec![Expr::Column("c".to_string())],
+                    },
+                    Expr::Literal(ColVal::String("x".to_string())),
+                )),
+            },
+        );
+
+        assert_eq!(
+            expr()
+                .parse(lexed(r#"a + b * 2 > 3 AND NOT lower(c) = "x""#))
+                .unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_numbers() {
+        // compared through Debug, as the integer 2 equals the REAL 2.0
+        let literal = |src: &str| format!("{:?}", column_value().parse(lexed(src)).unwrap());
+        assert_eq!(literal("21"), "Int(21)");
+        assert_eq!(literal("2.0"), "Real(2.0)");
+        assert_eq!(literal("2."), "Real(2.0)");
+        assert_eq!(literal("1e3"), "Real(1000.0)");
+        assert_eq!(literal("2.5E-1"), "Real(0.25)");
+        assert_eq!(
+            expr().parse(lexed("t.a + 1.5")).unwrap(),
+            binary(
+                BinaryOp::Add,
+                Expr::QualifiedColumn {
+                    table: "t".to_string(),
+                    column: "a".to_string(),
+                },
+                Expr::Literal(ColVal::Real(1.5)),
+            )
+        );
+    }
+
+    #[test]
+    fn parse_create_table() {
+        let column = |name: &str, type_name: Option<&str>| Column {
+            name: name.to_string(),
+            type_name: type_name.map(str::to_string),
+            default: None,
+            collation: None,
+            generated: None,
+            not_null: false,
+        };
+        assert_eq!(
+            parser()
+                .parse(lexed(
+                    "CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY, name VARCHAR(255));"
+                ))
+                .unwrap(),
+            Statement::CreateTable(CreateTable {
+                name: "users".to_string(),
+                columns: vec![
+                    column("id", Some("INTEGER")),
+                    column("name", Some("VARCHAR(255)")),
+                ],
+                primary_key: vec!["id".to_string()],
+                foreign_keys: vec![],
+                autoincrement: false,
+                checks: vec![],
+                without_rowid: false,
+                if_not_exists: true,
+            })
+        );
+        assert_eq!(
+            parser()
+                .parse(lexed("CREATE TABLE grades (student, course INT, grade, PRIMARY KEY (course, student)) WITHOUT ROWID;"))
+                .unwrap(),
+            Statement::CreateTable(CreateTable {
+                name: "grades".to_string(),
+                columns: vec![
+                    column("student", None),
+                    column("course", Some("INT")),
+                    column("grade", None),
+                ],
+                primary_key: vec!["course".to_string(), "student".to_string()],
+                foreign_keys: vec![],
+                autoincrement: false,
+                checks: vec![],
+                without_rowid: true,
+                if_not_exists: false,
+            })
+        );
+
+        let Statement::CreateTable(table) = parser()
+            .parse(lexed(
+                "CREATE TABLE t (a COLLATE NOCASE, b TEXT COLLATE RTRIM PRIMARY KEY);",
+            ))
+            .unwrap()
+        else {
+            panic!("expected CREATE TABLE");
+        };
+        assert_eq!(table.columns[0].collation.as_deref(), Some("NOCASE"));
+        assert_eq!(table.columns[0].type_name, None);
+        assert_eq!(table.columns[1].collation.as_deref(), Some("RTRIM"));
+        assert_eq!(table.primary_key, ["b"]);
+
+        let errors = parser()
+            .parse(lexed(
+                "CREATE TABLE t (a PRIMARY KEY, b, PRIMARY KEY (a, b));",
+            ))
+            .into_errors();
+        assert_eq!(
+            errors[0].to_string(),
+            "table \"t\" has more than one primary key"
+        );
+
+        // a column's CHECK is the table's like any other
+        let sql = "CREATE TABLE t (a INT CHECK (a > 0), b, CHECK (a < b OR b = 0));";
+        let statement = parser().parse(lexed(sql)).unwrap();
+        let Statement::CreateTable(table) = &statement else {
+            panic!("expected CREATE TABLE");
+        };
+        assert_eq!(table.columns.len(), 2);
+        assert_eq!(table.checks.len(), 2);
+        assert_eq!(
+            statement.to_string(),
+            "CREATE TABLE t (a INT, b, CHECK (a > 0), CHECK (a < b OR b = 0))"
+        );
+
+        for sql in [
+            "CREATE TEMP TABLE t (a);",
+            "CREATE TEMPORARY TABLE temp.t (a);",
+        ] {
+            let Statement::CreateTable(table) = parser().parse(lexed(sql)).unwrap() else {
+                panic!("expected CREATE TABLE");
+            };
+            assert_eq!(table.name, "temp.t");
+        }
+        let errors = parser()
+            .parse(lexed("CREATE TEMP TABLE aux.t (a);"))
+            .into_errors();
+        assert_eq!(
+            errors[0].to_string(),
+            "temporary table name must be unqualified"
+        );
+
+        let sql = "CREATE TABLE log (id INTEGER PRIMARY KEY AUTOINCREMENT, what TEXT);";
+        let statement = parser().parse(lexed(sql)).unwrap();
+        let Statement::CreateTable(table) = &statement else {
+            panic!("expected CREATE TABLE");
+        };
+        assert!(table.autoincrement);
+        assert_eq!(table.primary_key, ["id"]);
+        assert_eq!(statement.to_string(), sql.trim_end_matches(';'));
+        for sql in [
+            "CREATE TABLE t (id INT PRIMARY KEY AUTOINCREMENT);",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY AUTOINCREMENT) WITHOUT ROWID;",
+        ] {
+            let errors = parser().parse(lexed(sql)).into_errors();
+            assert_eq!(
+                errors[0].to_string(),
+                "AUTOINCREMENT is only allowed on an INTEGER PRIMARY KEY",
+                "{sql}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_generated_columns() {
+        let statement = parser()
+            .parse(lexed(
+                "CREATE TABLE t (a INTEGER, b GENERATED ALWAYS AS (a + 1) STORED, c AS (b * 2));",
+            ))
+            .unwrap();
+        let Statement::CreateTable(table) = &statement else {
+            panic!("expected CREATE TABLE");
+        };
+        let generated: Vec<_> = table
+            .columns
+            .iter()
+            .map(|c| c.generated.as_ref().map(|g| g.stored))
+            .collect();
+        assert_eq!(generated, [None, Some(true), Some(false)]);
+        assert_eq!(table.columns[1].type_name, None);
+        assert_eq!(
+            statement.to_string(),
+            "CREATE TABLE t (a INTEGER, b GENERATED ALWAYS AS (a + 1) STORED, \
+             c GENERATED ALWAYS AS (b * 2) VIRTUAL)"
+        );
+    }
+
+    #[test]
+    fn parse_foreign_keys() {
+        let Statement::CreateTable(table) = parser()
+            .parse(lexed(
+                "CREATE TABLE orders (id INTEGER PRIMARY KEY, \
+                 user_id REFERENCES users ON DELETE CASCADE ON UPDATE SET NULL, \
+                 status DEFAULT \"new\", a, b, \
+                 FOREIGN KEY (a, b) REFERENCES pairs (x, y) ON UPDATE RESTRICT ON DELETE SET DEFAULT);",
+            ))
+            .unwrap()
+        else {
+            panic!("expected CREATE TABLE");
+        };
+        assert_eq!(table.primary_key, ["id"]);
+        assert_eq!(
+            table.columns[2].default,
+            Some(ColVal::String("new".to_string()))
+        );
+        assert_eq!(
+            table.foreign_keys,
+            [
+                ForeignKey {
+                    columns: vec!["user_id".to_string()],
+                    parent: "users".to_string(),
+                    parent_columns: vec![],
+                    on_delete: ForeignKeyAction::Cascade,
+                    on_update: ForeignKeyAction::SetNull,
+                },
+                ForeignKey {
+                    columns: vec!["a".to_string(), "b".to_string()],
+                    parent: "pairs".to_string(),
+                    parent_columns: vec!["x".to_string(), "y".to_string()],
+                    on_delete: ForeignKeyAction::SetDefault,
+                    on_update: ForeignKeyAction::Restrict,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_create_index() {
+        assert_eq!(
+            parser()
+                .parse(lexed(
+                    "CREATE UNIQUE INDEX IF NOT EXISTS idx_email ON users (email, lower(name));"
+                ))
+                .unwrap(),
+            Statement::CreateIndex(CreateIndex {
+                name: "idx_email".to_string(),
+                table: "users".to_string(),
+                columns: vec![
+                    Expr::Column("email".to_string()),
+                    Expr::Function {
+                        name: "lower".to_string(),
+                        args: vec![Expr::Column("name".to_string())],
+                    },
+                ],
+                unique: true,
+                if_not_exists: true,
+            })
+        );
+
+        assert_eq!(
+            parser()
+                .parse(lexed("CREATE INDEX idx_age ON users(age);"))
+                .unwrap(),
+            Statement::CreateIndex(CreateIndex {
+                name: "idx_age".to_string(),
+                table: "users".to_string(),
+                columns: vec![Expr::Column("age".to_string())],
+                unique: false,
+                if_not_exists: false,
+            })
+        );
+
+        assert!(parser()
+            .parse(lexed("CREATE INDEX idx ON users();"))
+            .has_errors());
+    }
+
+    #[test]
+    fn parse_transaction_control() {
+        for (sql, expected) in [
+            ("BEGIN;", Statement::Begin(TransactionMode::Deferred)),
+            (
+                "BEGIN TRANSACTION;",
+                Statement::Begin(TransactionMode::Deferred),
+            ),
+            (
+                "BEGIN IMMEDIATE;",
+                Statement::Begin(TransactionMode::Immediate),
+            ),
+            (
+                "BEGIN EXCLUSIVE TRANSACTION;",
+                Statement::Begin(TransactionMode::Exclusive),
+            ),
+            ("COMMIT;", Statement::Commit),
+            ("END TRANSACTION;", Statement::Commit),
+            ("ROLLBACK;", Statement::Rollback),
+            ("ROLLBACK TRANSACTION ;", Statement::Rollback),
+            ("SAVEPOINT sp1;", Statement::Savepoint("sp1".to_string())),
+            ("RELEASE sp1;", Statement::Release("sp1".to_string())),
+            (
+                "RELEASE SAVEPOINT sp1;",
+                Statement::Release("sp1".to_string()),
+            ),
+            ("ROLLBACK TO sp1;", Statement::RollbackTo("sp1".to_string())),
+            (
+                "ROLLBACK TRANSACTION TO SAVEPOINT sp1;",
+                Statement::RollbackTo("sp1".to_string()),
+            ),
+        ] {
+            assert_eq!(parser().parse(lexed(sql)).unwrap(), expected, "{sql}");
+        }
+        assert!(parser().parse(lexed("BEGIN SOMETIMES;")).has_errors());
+    }
+
+    #[test]
+    fn parse_attach_and_detach() {
+        assert_eq!(
+            parser()
+                .parse(lexed(r#"ATTACH "data/archive.db" AS archive;"#))
+                .unwrap(),
+            Statement::Attach {
+                path: "data/archive.db".to_string(),
+                name: "archive".to_string(),
+            }
+        );
+        assert_eq!(
+            parser().parse(lexed("DETACH archive;")).unwrap(),
+            Statement::Detach("archive".to_string())
+        );
+        assert!(parser().parse(lexed("ATTACH archive;")).has_errors());
+    }
+
+    #[test]
+    fn tables_can_be_named_with_their_database() {
+        let parse = |sql: &str| parser().parse(lexed(sql)).unwrap();
+        assert_eq!(parse("SELECT a FROM main.t;"), parse("SELECT a FROM t;"));
+        assert_eq!(
+            parse("SELECT a FROM archive.t;"),
+            parse("SELECT a FROM archive.t AS t;")
+        );
+        assert_eq!(
+            parse("CREATE INDEX i ON archive.t (a);"),
+            parse("CREATE INDEX archive.i ON t (a);")
+        );
+        match parse("DELETE FROM archive.t;") {
+            Statement::Delete { from_table, .. } => assert_eq!(from_table, "archive.t"),
+            other => panic!("expected DELETE, got {other}"),
+        }
+    }
+
+    #[test]
+    fn parse_window_function() {
+        let parse = super::parse_window_function;
+        assert_eq!(
+            parse("rank( ) OVER(PARTITION BY dept ORDER BY pay DESC,name ASC)").unwrap(),
+            WindowFunction {
+                func: WindowFunc::Rank,
+                partition_by: vec!["dept".to_string()],
+                order_by: vec![("pay".to_string(), true), ("name".to_string(), false)],
+                frame: None,
+            }
+        );
+        assert_eq!(
+            parse("SUM(e.pay) OVER (RANGE UNBOUNDED PRECEDING)").unwrap(),
+            WindowFunction {
+                func: WindowFunc::Aggregate(Aggregate {
+                    func: AggregateFunc::Sum,
+                    arg: Some("e.pay".to_string()),
+                    distinct: false,
+                }),
+                partition_by: vec![],
+                order_by: vec![],
+                frame: Some(Frame {
+                    range: true,
+                    start: FrameBound::UnboundedPreceding,
+                    end: FrameBound::CurrentRow,
+                }),
+            }
+        );
+        for sql in [
+            "COUNT(*)",
+            "LAG(a) OVER ()",
+            "COUNT(DISTINCT a) OVER ()",
+            "SUM(a) OVER (ROWS BETWEEN CURRENT ROW AND UNBOUNDED PRECEDING)",
+            "SUM(a) OVER (ORDER BY a RANGE BETWEEN 1 PRECEDING AND CURRENT ROW)",
+        ] {
+            assert!(parse(sql).is_err(), "{sql}");
+        }
+    }
+
+    #[test]
+    fn parse_compound_select() {
+        let parse = |sql: &str| parser().parse(lexed(sql)).unwrap();
+        let Statement::Compound {
+            first,
+            rest,
+            order_by,
+            limit,
+        } = parse("SELECT a FROM t UNION ALL SELECT b FROM u WHERE b > 1 EXCEPT SELECT c FROM v ORDER BY 1 DESC LIMIT 5;")
+        else {
+            panic!("expected a compound SELECT");
+        };
+        assert_eq!(*first, parse("SELECT a FROM t;"));
+        assert_eq!(
+            rest,
+            vec![
+                (
+                    CompoundOperator::UnionAll,
+                    parse("SELECT b FROM u WHERE b > 1;")
+                ),
+                (CompoundOperator::Except, parse("SELECT c FROM v;")),
+            ]
+        );
+        assert_eq!(
+            order_by,
+            vec![OrderingTerm {
+                expr: Expr::Literal(ColVal::Int(1)),
+                descending: true,
+            }]
+        );
+        assert_eq!(limit.map(|l| l.count), Some(Expr::Literal(ColVal::Int(5))));
+
+        let sql = "SELECT a FROM t INTERSECT SELECT a FROM u ORDER BY a";
+        assert_eq!(parse(&format!("{sql};")).to_string(), sql);
+        // the SELECTs can't be ordered or limited on their own
+        assert!(parser()
+            .parse(lexed("SELECT a FROM t LIMIT 1 UNION SELECT a FROM u;"))
+            .has_errors());
+    }
+
+    #[test]
+    fn parse_select_group_order_and_limit() {
+        let parse = |sql: &str| parser().parse(lexed(sql)).unwrap();
+        let Statement::Select {
+            group_by,
+            order_by,
+            limit,
+            ..
+        } = parse(
+            "SELECT dept, COUNT(*) FROM t WHERE a > 1 GROUP BY dept ORDER BY 2 DESC LIMIT 3;",
+        )
+        else {
+            panic!("expected a SELECT");
+        };
+        assert_eq!(group_by, vec![Expr::Column("dept".to_string())]);
+        assert_eq!(
+            order_by,
+            vec![OrderingTerm {
+                expr: Expr::Literal(ColVal::Int(2)),
+                descending: true,
+            }]
+        );
+        assert_eq!(limit.map(|l| l.count), Some(Expr::Literal(ColVal::Int(3))));
+
+        let sql = "SELECT a, b FROM t GROUP BY a, b ORDER BY a, b DESC LIMIT 1 OFFSET 2";
+        assert_eq!(parse(&format!("{sql};")).to_string(), sql);
+        let sql = "SELECT a FROM t WHERE a IN (SELECT b FROM u ORDER BY b LIMIT 1)";
+        assert_eq!(parse(&format!("{sql};")).to_string(), sql);
+    }
+
+    #[test]
+    fn parse_pragma() {
+        let pragma = |sql: &str| match parser().parse(lexed(sql)).unwrap() {
+            Statement::Pragma(pragma) => (pragma.name, pragma.value),
+            other => panic!("expected PRAGMA, got {other}"),
+        };
+        assert_eq!(pragma("PRAGMA page_size;"), ("page_size".to_string(), None));
+        assert_eq!(
+            pragma("PRAGMA cache_size = -4000;"),
+            ("cache_size".to_string(), Some("-4000".to_string()))
+        );
+        assert_eq!(
+            pragma("PRAGMA TABLE_INFO(users);"),
+            ("table_info".to_string(), Some("users".to_string()))
+        );
+        assert_eq!(
+            pragma(r#"PRAGMA journal_mode = "wal";"#),
+            ("journal_mode".to_string(), Some("wal".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_analyze() {
+        assert_eq!(
+            parser().parse(lexed("ANALYZE;")).unwrap(),
+            Statement::Analyze(None)
+        );
+        assert_eq!(
+            parser().parse(lexed("ANALYZE users;")).unwrap(),
+            Statement::Analyze(Some("users".to_string()))
+        );
+        assert_eq!(parser().parse(lexed("VACUUM;")).unwrap(), Statement::Vacuum);
+        assert_eq!(
+            parser().parse(lexed("REINDEX;")).unwrap(),
+            Statement::Reindex(None)
+        );
+        assert_eq!(
+            parser().parse(lexed("REINDEX idx_email;")).unwrap(),
+            Statement::Reindex(Some("idx_email".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_exists() {
+        let subquery = Statement::Select {
+            columns: vec!["1".to_string()],
+            from_table: "orders".to_string(),
+            from_select: None,
+            table_args: vec![],
+            alias: None,
+            index_hint: None,
+            joins: vec![],
+            group_by: vec![],
+            order_by: vec![],
+            limit: None,
+            where_clause: Some(binary(
+                BinaryOp::Eq,
+                Expr::Column("user_id".to_string()),
+                Expr::Column("id".to_string()),
+            )),
+        };
+
+        assert_eq!(
+            expr()
+                .parse(lexed("EXISTS (SELECT 1 FROM orders WHERE user_id = id)"))
+                .unwrap(),
+            Expr::Exists {
+                select: Box::new(subquery.clone()),
+                negated: false,
+            }
+        );
+        assert_eq!(
+            expr()
+                .parse(lexed(
+                    "NOT EXISTS (SELECT 1 FROM orders WHERE user_id = id)"
+                ))
+                .unwrap(),
+            Expr::Exists {
+                select: Box::new(subquery),
+                negated: true,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_explain() {
+        assert_eq!(
+            parser()
+                .parse(lexed("EXPLAIN SELECT name FROM users;"))
+                .unwrap(),
+            Statement::Explain(Box::new(Statement::Select {
+                columns: vec!["name".to_string()],
+                from_table: "users".to_string(),
+                from_select: None,
+                table_args: vec![],
+                alias: None,
+                index_hint: None,
+                joins: vec![],
+                group_by: vec![],
+                order_by: vec![],
+                limit: None,
+                where_clause: None
+            }))
+        );
+        assert_eq!(
+            parser().parse(lexed("EXPLAIN QUERY PLAN COMMIT;")).unwrap(),
+            Statement::ExplainQueryPlan(Box::new(Statement::Commit))
+        );
+        assert_eq!(
+            parser().parse(lexed("EXPLAIN ANALYZE COMMIT;")).unwrap(),
+            Statement::ExplainAnalyze(Box::new(Statement::Commit))
+        );
+        // ANALYZE on its own is the statement explained
+        assert_eq!(
+            parser().parse(lexed("EXPLAIN ANALYZE users;")).unwrap(),
+            Statement::Explain(Box::new(Statement::Analyze(Some("users".to_string()))))
+        );
+        assert!(parser()
+            .parse(lexed("EXPLAIN EXPLAIN COMMIT;"))
+            .has_errors());
+    }
+
+    #[test]
+    fn parse_with() {
+        assert_eq!(
+            parser()
+                .parse(lexed("WITH adults (who) AS (SELECT name FROM users WHERE age > 17) SELECT who FROM adults;"))
+                .unwrap(),
+            Statement::With {
+                ctes: vec![CommonTableExpr {
+                    name: "adults".to_string(),
+                    columns: vec!["who".to_string()],
+                    select: Box::new(Statement::Select {
+                        columns: vec!["name".to_string()],
+                        from_table: "users".to_string(),
+                        from_select: None,
+                        table_args: vec![],
+                        alias: None,
+                        index_hint: None,
+                        joins: vec![],
+                        group_by: vec![],
+                        order_by: vec![],
+                        limit: None,
+                        where_clause: Some(expr().parse(lexed("age > 17")).unwrap()),
+                    }),
+                }],
+                body: Box::new(Statement::Select {
+                    columns: vec!["who".to_string()],
+                    from_table: "adults".to_string(),
+                    from_select: None,
+                    table_args: vec![],
+                    alias: None,
+                    index_hint: None,
+                    joins: vec![],
+                    group_by: vec![],
+                    order_by: vec![],
+                    limit: None,
+                    where_clause: None,
+                }),
+            }
+        );
+        assert!(parser()
+            .parse(lexed("WITH x AS (SELECT a FROM t);"))
+            .has_errors());
+    }
+
+    #[test]
+    fn parse_create_view() {
+        assert_eq!(
+            parser()
+                .parse(lexed(
+                    "CREATE VIEW adults AS SELECT name FROM users WHERE age > 17;"
+                ))
+                .unwrap(),
+            Statement::CreateView(CreateView {
+                name: "adults".to_string(),
+                columns: vec![],
+                select: Box::new(Statement::Select {
+                    columns: vec!["name".to_string()],
+                    from_table: "users".to_string(),
+                    from_select: None,
+                    table_args: vec![],
+                    alias: None,
+                    index_hint: None,
+                    joins: vec![],
+                    group_by: vec![],
+                    order_by: vec![],
+                    limit: None,
+                    where_clause: Some(expr().parse(lexed("age > 17")).unwrap()),
+                }),
+                if_not_exists: false,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_create_trigger() {
+        assert_eq!(
+            parser()
+                .parse(lexed(
+                    "CREATE TRIGGER IF NOT EXISTS audit AFTER UPDATE OF balance ON accounts
+                     FOR EACH ROW WHEN NEW.balance < 0
+                     BEGIN
+                         DELETE FROM alerts WHERE account = OLD.id;
+                         INSERT INTO alerts (account) VALUES (NEW.id);
+                     END;"
+                ))
+                .unwrap(),
+            Statement::CreateTrigger(CreateTrigger {
+                name: "audit".to_string(),
+                timing: TriggerTiming::After,
+                event: TriggerEvent::Update(vec!["balance".to_string()]),
+                table: "accounts".to_string(),
+                when: Some(expr().parse(lexed("NEW.balance < 0")).unwrap()),
+                body: vec![
+                    parser()
+                        .parse(lexed("DELETE FROM alerts WHERE account = OLD.id;"))
+                        .unwrap(),
+                    parser()
+                        .parse(lexed("INSERT INTO alerts (account) VALUES (NEW.id);"))
+                        .unwrap(),
+                ],
+                if_not_exists: true,
+            })
+        );
+        assert_eq!(
+            expr().parse(lexed("OLD.id")).unwrap(),
+            Expr::QualifiedColumn {
+                table: "OLD".to_string(),
+                column: "id".to_string(),
+            }
+        );
+        // BEFORE is the default, and a trigger must do something
+        let Statement::CreateTrigger(trigger) = parser()
+            .parse(lexed(
+                "CREATE TRIGGER t DELETE ON a BEGIN DELETE FROM b; END;",
+            ))
+            .unwrap()
+        else {
+            panic!("expected CREATE TRIGGER");
+        };
+        assert_eq!(trigger.timing, TriggerTiming::Before);
+        assert!(parser()
+            .parse(lexed("CREATE TRIGGER t AFTER DELETE ON a BEGIN END;"))
+            .has_errors());
+    }
+
+    #[test]
+    fn parse_update_and_delete() {
+        assert_eq!(
+            parser()
+                .parse(lexed(
+                    "UPDATE users SET age = age + 1, admin = FALSE WHERE age < 21;"
+                ))
+                .unwrap(),
+            Statement::Update {
+                table: "users".to_string(),
+                assignments: vec![
+                    NewColumnVal {
+                        column_name: "age".to_string(),
+                        value: expr().parse(lexed("age + 1")).unwrap(),
+                    },
+                    NewColumnVal {
+                        column_name: "admin".to_string(),
+                        value: Expr::Literal(ColVal::Boolean(false)),
+                    },
+                ],
+                where_clause: Some(expr().parse(lexed("age < 21")).unwrap()),
+                order_by: vec![],
+                limit: None,
+            }
+        );
+        assert_eq!(
+            parser().parse(lexed("DELETE FROM users;")).unwrap(),
+            Statement::Delete {
+                from_table: "users".to_string(),
+                where_clause: None,
+                order_by: vec![],
+                limit: None,
+            }
+        );
+    }
+
+    #[cfg(feature = "update-delete-limit")]
+    #[test]
+    fn parse_update_delete_limit() {
+        let Statement::Delete {
+            order_by, limit, ..
+        } = parser()
+            .parse(lexed(
+                "DELETE FROM logs WHERE old = TRUE ORDER BY created DESC, id LIMIT 100;",
+            ))
+            .unwrap()
+        else {
+            panic!("expected DELETE");
+        };
+        assert_eq!(
+            order_by,
+            vec![
+                OrderingTerm {
+                    expr: Expr::Column("created".to_string()),
+                    descending: true,
+                },
+                OrderingTerm {
+                    expr: Expr::Column("id".to_string()),
+                    descending: false,
+                },
+            ]
+        );
+        assert_eq!(
+            limit,
+            Some(Limit {
+                count: Expr::Literal(ColVal::Int(100)),
+                offset: None,
+            })
+        );
+
+        // LIMIT offset, count is the same as LIMIT count OFFSET offset
+        assert_eq!(
+            parser()
+                .parse(lexed("UPDATE t SET a = 1 LIMIT 5, 10;"))
+                .unwrap(),
+            parser()
+                .parse(lexed("UPDATE t SET a = 1 LIMIT 10 OFFSET 5;"))
+                .unwrap()
+        );
+
+        let sql = "DELETE FROM logs ORDER BY name COLLATE NOCASE DESC LIMIT 1";
+        let src = format!("{sql};");
+        assert_eq!(parser().parse(lexed(&src)).unwrap().to_string(), sql);
+
+        let errs = parser()
+            .parse(lexed("DELETE FROM logs ORDER BY id;"))
+            .into_errors();
+        assert_eq!(errs[0].to_string(), "ORDER BY without LIMIT on DELETE");
+    }
+
+    #[cfg(not(feature = "update-delete-limit"))]
+    #[test]
+    fn update_delete_limit_needs_the_feature() {
+        assert!(parser()
+            .parse(lexed("DELETE FROM logs LIMIT 100;"))
+            .has_errors());
+        assert!(parser()
+            .parse(lexed("UPDATE logs SET a = 1 ORDER BY id LIMIT 1;"))
+            .has_errors());
+    }
+
+    #[test]
+    fn statements_print_back_as_sql() {
+        for sql in [
+            r#"SELECT name, age FROM users WHERE (a + b) * -c > 3 AND NOT (x = 1 OR lower(y) = "z")"#,
+            "SELECT 1 FROM t WHERE a - (b - c) = ?1 AND (a, b) NOT IN (SELECT x, y FROM u)",
+            "SELECT a FROM t WHERE NOT EXISTS (SELECT 1 FROM u WHERE u_id = :id) OR a IN (1, NULL)",
+            r#"INSERT INTO users (name, admin) VALUES ("bob", TRUE)"#,
+            "EXPLAIN CREATE UNIQUE INDEX IF NOT EXISTS idx ON users (email, lower(name))",
+            "BEGIN IMMEDIATE",
+            "ATTACH DATABASE \"archive.db\" AS archive",
+            "DETACH DATABASE archive",
+            "PRAGMA journal_mode = WAL",
+            "PRAGMA integrity_check",
+            "CREATE VIEW IF NOT EXISTS adults (who) AS SELECT name FROM users WHERE age > 17",
+            "WITH a AS (SELECT x FROM t), b (y) AS (SELECT x FROM a WHERE x > 1) SELECT y FROM b",
+            "UPDATE users SET age = age + 1, admin = FALSE WHERE age < 21",
+            "DELETE FROM users WHERE EXISTS (SELECT 1 FROM bans WHERE user_id = id)",
+            "SELECT *, users.*, id FROM users",
+            "SELECT u.name FROM users AS u WHERE EXISTS (SELECT 1 FROM orders AS o WHERE o.user_id = u.id)",
+            "SELECT a FROM t WHERE CAST(b + 1 AS VARCHAR(255)) = \"2\"",
+            "SELECT COUNT(*), COUNT(DISTINCT a), AVG(t.b) FROM t",
+            "SELECT a, ROW_NUMBER() OVER (PARTITION BY b ORDER BY c DESC, a), RANK() OVER () FROM t",
+            "SELECT SUM(b) OVER (ORDER BY a ROWS BETWEEN 2 PRECEDING AND 1 FOLLOWING) FROM t",
+            "SELECT name FROM emp WHERE salary > (SELECT AVG(salary) FROM emp)",
+            "SELECT * FROM (SELECT a, b FROM t WHERE a > 1) AS x WHERE b = (SELECT MAX(b) FROM u WHERE u.a = x.a)",
+            "DELETE FROM t WHERE -b COLLATE NOCASE = c AND (a + b) COLLATE RTRIM > a",
+            "CREATE TABLE t (a TEXT DEFAULT \"\" COLLATE NOCASE, b COLLATE RTRIM)",
+            "CREATE TABLE t (a INTEGER NOT NULL DEFAULT 0, b NOT NULL)",
+            "CREATE TABLE IF NOT EXISTS t (a INTEGER, b VARCHAR(255), c, PRIMARY KEY (b, a)) WITHOUT ROWID",
+            "CREATE TABLE t (a DEFAULT 0, b, FOREIGN KEY (a) REFERENCES p ON DELETE CASCADE, FOREIGN KEY (a, b) REFERENCES q (x, y) ON UPDATE SET NULL)",
+            "CREATE TRIGGER t BEFORE INSERT ON users BEGIN SELECT a FROM b; DELETE FROM c WHERE d = NEW.d; END",
+            "CREATE TRIGGER IF NOT EXISTS t AFTER UPDATE OF a, b ON users WHEN OLD.a != NEW.a BEGIN UPDATE c SET a = NEW.a; END",
+            "SELECT value FROM generate_series(1, ?1 + 2) AS s WHERE value > 1",
+            "CREATE VIRTUAL TABLE IF NOT EXISTS t USING csv(filename = \"a, (b).csv\", header = yes)",
+            "CREATE VIRTUAL TABLE t USING echo",
+        ] {
+            let src = format!("{sql};");
+            let statement = parser().parse(lexed(&src)).unwrap();
+            assert_eq!(statement.to_string(), sql);
+        }
+    }
+
+    #[test]
+    fn parse_placeholders() {
+        assert_eq!(
+            expr().parse(lexed("?")).unwrap(),
+            Expr::Placeholder(Placeholder::Anonymous)
+        );
+        assert_eq!(
+            expr().parse(lexed("?12")).unwrap(),
+            Expr::Placeholder(Placeholder::Numbered(12))
+        );
+        for name in [":name", "@name", "$name"] {
+            assert_eq!(
+                expr().parse(lexed(name)).unwrap(),
+                Expr::Placeholder(Placeholder::Named(name.to_string()))
+            );
+        }
+        assert!(expr().parse(lexed("?0")).has_errors());
+        assert!(expr().parse(lexed("?32767")).has_errors());
+    }
+
+    /*
+    Query
+    UPDATE users SET age = age + 1 WHERE name = 'Bob';
+
+    AST
+     ├── Update
+     │   ├── Table "users"
+     │   ├── Set
+     │   │   └── Assignment
+     │   │       ├── Column "age"
+     │   │       └── Expression
+     │   │           ├── Column "age"
+     │   │           └── Plus
+     │   │               └── Value 1
+     │   └── Where
+     │       └── Equals
+     │           ├── Column "name"
+     │           └── Value "Bob"
+      */
+
+    /*
+      CreateTableStatement
+
+      CREATE TABLE Persons (
+         PersonID int,
+         LastName varchar(255),
+         FirstName varchar(255),
+         Address varchar(255),
+         City varchar(255)
+      );
+    |
+    +-- TableName: "users"
+    +-- Columns
+    |    |
+    |    +-- ColumnDefinition
+    |    |    |
+    |    |    +-- ColumnName: "id"
+    |    |    +-- DataType: "INTEGER"
+    |    |    +-- Constraints
+    |    |        |
+    |    |        +-- PrimaryKeyConstraint
+    |    +-- ColumnDefinition
+    |         |
+    |         +-- ColumnName: "name"
+    |         +-- DataType: "TEXT"
+    |         +-- Constraints
+    +-- Constraints
+         |
+         +-- PrimaryKeyConstraint */
+}
@@ -0,0 +1,146 @@
+/*
+    Attached databases.
+
+        ATTACH DATABASE "archive.db" AS archive;
+        INSERT INTO archive.orders SELECT * FROM orders WHERE year < 2020;
+
+    A connection always has its main database and can attach others to it under a schema
+    name, after which their tables are reachable as name.table in the same statements and
+    the same transactions as main's. A transaction that writes to more than one of them
+    commits atomically across all of their files, see journal.rs for how.
+
+    That only works if the set of databases stays put while a transaction is open. A
+    DETACH in the middle of one would drop a database whose journal the commit still has
+    to account for, so like SQLite we refuse it with "database x is locked" and leave the
+    database attached. ATTACH within a transaction is refused too. main and temp are built
+    in and can't be detached, and as in SQLite at most MAX_ATTACHED databases can be
+    attached at once.
+
+    temp holds the tables CREATE TEMP TABLE makes, which only the connection that made
+    them can see and which are gone once it closes. It comes into being with the first of
+    them, kept in memory or, under PRAGMA temp_store = FILE, in a file of its own that is
+    deleted with the connection. Unlike an attached database's its tables can be named
+    without it, and are found ahead of main's of the same name, see the planner.
+*/
+use super::memdb::OpenTarget;
+use anyhow::{bail, Result};
+
+// SQLite's default SQLITE_MAX_ATTACHED.
+pub const MAX_ATTACHED: usize = 10;
+
+/// The name of the database of temporary tables.
+pub const TEMP: &str = "temp";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Database {
+    pub name: String,
+    pub target: OpenTarget,
+}
+
+/// The databases of a connection, main first and then the attached ones in the order they
+/// were attached.
+#[derive(Debug)]
+pub struct Databases {
+    databases: Vec<Database>,
+}
+
+impl Databases {
+    pub fn new(main: OpenTarget) -> Self {
+        Databases {
+            databases: vec![Database {
+                name: "main".to_string(),
+                target: main,
+            }],
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Database> {
+        self.databases.iter().find(|d| d.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Database> {
+        self.databases.iter()
+    }
+
+    pub fn attach(&mut self, filename: &str, name: &str, in_transaction: bool) -> Result<()> {
+        if in_transaction {
+            bail!("cannot ATTACH database within transaction");
+        }
+        if name == TEMP || self.get(name).is_some() {
+            bail!("database {name} is already in use");
+        }
+        if self.databases.len() > MAX_ATTACHED {
+            bail!("too many attached databases - max {MAX_ATTACHED}");
+        }
+        self.databases.push(Database {
+            name: name.to_string(),
+            target: OpenTarget::parse(filename)?,
+        });
+        Ok(())
+    }
+
+    pub fn detach(&mut self, name: &str, in_transaction: bool) -> Result<Database> {
+        if name == "main" || name == TEMP {
+            bail!("cannot detach database {name}");
+        }
+        let Some(pos) = self.databases.iter().position(|d| d.name == name) else {
+            bail!("no such database: {name}");
+        };
+        if in_transaction {
+            bail!("database {name} is locked");
+        }
+        Ok(self.databases.remove(pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(dbs: &Databases) -> Vec<&str> {
+        dbs.iter().map(|d| d.name.as_str()).collect()
+    }
+
+    #[test]
+    fn databases_attach_and_detach_outside_transactions() {
+        let mut dbs = Databases::new(OpenTarget::parse("app.db").unwrap());
+        dbs.attach("archive.db", "archive", false).unwrap();
+        assert_eq!(names(&dbs), ["main", "archive"]);
+        assert_eq!(
+            dbs.attach("other.db", "archive", false)
+                .unwrap_err()
+                .to_string(),
+            "database archive is already in use"
+        );
+        assert_eq!(
+            dbs.attach("x.db", "x", true).unwrap_err().to_string(),
+            "cannot ATTACH database within transaction"
+        );
+
+        // a transaction may be committing to it, so it stays until the transaction ends
+        assert_eq!(
+            dbs.detach("archive", true).unwrap_err().to_string(),
+            "database archive is locked"
+        );
+        assert_eq!(names(&dbs), ["main", "archive"]);
+        dbs.detach("archive", false).unwrap();
+        assert_eq!(
+            dbs.detach("archive", false).unwrap_err().to_string(),
+            "no such database: archive"
+        );
+        assert_eq!(
+            dbs.detach("main", false).unwrap_err().to_string(),
+            "cannot detach database main"
+        );
+
+        for i in 0..MAX_ATTACHED {
+            dbs.attach(":memory:", &format!("db{i}"), false).unwrap();
+        }
+        assert_eq!(
+            dbs.attach(":memory:", "one_more", false)
+                .unwrap_err()
+                .to_string(),
+            "too many attached databases - max 10"
+        );
+    }
+}
@@ -27,218 +27,3093 @@
   Fanout determines how many keys we can squeeze into a node/page and consequently the number of
   child pointers in an inner node. Higher fanout means smaller tree height and the faster the lookups.
 
+  Every node also records the range of keys it is responsible for, its fence keys: the low fence
+  is the smallest key that may live in it and the high key is where the next node to the right
+  takes over. A split happens in two steps. First the node hands its upper half to a new right
+  sibling, lowering its own high key to the separator and linking to the sibling, and only then
+  is the separator added to the parent. Until the second step the parent still sends every key in
+  the old range to the left node, but a search can tell it has come to the wrong place because the
+  key is at or past the node's high key, and simply follows the link right (Lehman and Yao's
+  B-link tree). So a split that was interrupted between the two steps, by a crash or by another
+  writer getting in first, never loses a key. The next insert that passes through the parent
+  notices that a child's high key doesn't match the parent's separator for it and finishes the
+  split by adding the missing separator.
+
+  A node that overflows isn't necessarily split. Like SQLite's balance routine, its parent first
+  looks for a sibling either side of it with room to spare and evens the two out, moving keys
+  across and the separator between them to suit. Only when both neighbours are full does the
+  node split. Splitting leaves two half full nodes, so a run of inserts in key order, which
+  always lands in the rightmost node, would otherwise leave every leaf behind it half empty.
+  Handing keys to the left instead fills those leaves up and the tree grows taller less often.
+
+  Inserting in key order is also the usual case: rowids only go up, and so do timestamps in a
+  log. Each such key belongs at the end of the rightmost leaf, so the tree remembers which leaf
+  that is and appends there directly, without descending from the root, as long as the key is
+  larger than every key in the tree and the leaf has room. Only the insert that fills the leaf
+  takes the long way down, to split it or even it out with its sibling under their parent.
+
+  Deleting is the reverse. A node other than the root left with fewer than half the keys it
+  can hold takes some from a sibling that has more than half, rotating them through the
+  parent's separator as evening out does, and when neither sibling has any to spare it is
+  merged into one of them and the parent loses a separator and a child. That can leave the
+  parent underfull in turn, and so on up, until the root is an inner node with a single
+  child and the child becomes the root. Merged away nodes go on a free list, to be used by
+  the next split before the arena grows, as SQLite reuses the pages on its freelist.
+
+  Keys are ordered by the tree's comparator rather than by their own Ord, so one key type can
+  be sorted different ways: text under a column's collation, or an index key column by column
+  under each column's collation. The comparator has a name, which the catalog records, so
+  that whatever reads a tree knows how its keys were ordered. Comparing the same keys a
+  different way would send searches to the wrong leaves. A tree made with Btree::empty uses
+  the key type's own ordering, named BINARY after SQLite's default collation.
+
+  The separator between two leaves need not be a key either leaf holds, only one above every
+  key of the left and no higher than the first of the right. A comparator can say how to
+  make a short one, and those of indexes and WITHOUT ROWID tables keep only as much of the
+  right hand key as tells it from the left (suffix truncation), "bo" rather than all of
+  "bob@example.com" after "bill@example.com". Inner pages then write each key as the bytes
+  it shares with the one before it and the rest, see Node::to_page. A tree kept on pages
+  sizes its nodes by the bytes of their cells, so shorter separators let an inner node hold
+  more children, the database file's tree among them (see image.rs). Each key is counted
+  whole, whatever it shares with the one before it, as whichever key ends up first in
+  a node after a split or a shift between siblings has nothing before it to share with,
+  and a node must fit its page however its keys are shared out.
+
+  Each node is known by a number from 1 up, its place in the arena, and links to its children
+  and siblings by their numbers. The nodes are held decoded in memory, and changing one marks
+  it dirty. A tree made with Btree::on_pages is kept in a database file, whose pages it gets
+  from and gives back to the file through its pager (see pager.rs). Btree::flush gives each
+  new node a page of the file, and lays each dirty node out on its page as a slotted page
+  (see page.rs): a cell for the node's fence keys and links, the links written as the pages
+  the other nodes are on, then one for each entry, its key and value written as their
+  CellData says. A cell too big for a page keeps its first bytes and spills the rest onto a
+  chain of overflow pages (see overflow.rs). A node written before is changed on its page
+  rather than laid out afresh: the cells it no longer has are taken off and the new ones
+  put in the holes they leave, the rest staying where they are, and the page is
+  defragmented when none of its holes is big enough. A node merged away gives its page
+  back, with its overflow pages, for the file's freelist to hand out again (see
+  freelist.rs). The root is always written to the same page, so a reader knows where to
+  start, and Btree::open reads the tree back from it, numbering the nodes in the order it
+  reads them.
+
+  Such a tree's nodes hold as many entries as fit on a page rather than a number of them, so
+  a node splits when its cells take up more bytes than a page has room for, and is underfull
+  when they take up less than half that. Entries differ in size, so a split or a shift
+  between siblings goes by bytes as well, and a delete can leave a node too big: a shorter
+  key in the parent, replaced by a longer one, can overflow it.
+
 */
-use anyhow::{anyhow, Result};
+use super::cache::PageStore;
+use super::header::DatabaseHeader;
+use super::overflow::Layout;
+use super::overflow::{self, PayloadKind};
+use super::page::{self, SlottedPage};
+use super::record;
+use super::wal::PageNumber;
+use anyhow::{bail, Context, Result};
 use std::cmp::Ordering;
-use std::vec::{self, Vec};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::mem;
+use std::ops::{Bound, Index, IndexMut, RangeBounds};
+use std::sync::Arc;
+use std::vec::Vec;
+
+/// The number a node is known by in its tree, from 1 up. Nodes refer to each other by number
+/// rather than by Rust references, which lets leaves point at their siblings without
+/// fighting the borrow checker, and a tree on pages writes the numbers out as the pages the
+/// nodes are on.
+pub type PageId = PageNumber;
+
+/// The signature of a comparator's comparison of two keys.
+pub type CompareFn<K> = dyn Fn(&K, &K) -> Ordering + Send + Sync;
+
+/// The signature of a comparator's choice of a short key to separate two others.
+pub type SeparatorFn<K> = dyn Fn(&K, &K) -> K + Send + Sync;
+
+/// How a tree orders its keys, and the name it is known by.
+#[derive(Clone)]
+pub struct Comparator<K> {
+    name: String,
+    compare: Arc<CompareFn<K>>,
+    shorten: Option<Arc<SeparatorFn<K>>>,
+}
+
+impl<K> Comparator<K> {
+    pub fn new(name: &str, compare: impl Fn(&K, &K) -> Ordering + Send + Sync + 'static) -> Self {
+        Comparator {
+            name: name.to_string(),
+            compare: Arc::new(compare),
+            shorten: None,
+        }
+    }
+
+    /// The same ordering, with `shorten(left, right)` choosing the separator between two
+    /// nodes whose keys end at left and start at right. The shorter the key it chooses
+    /// above left and no higher than right, the less room inner nodes take.
+    pub fn with_separator(mut self, shorten: impl Fn(&K, &K) -> K + Send + Sync + 'static) -> Self {
+        self.shorten = Some(Arc::new(shorten));
+        self
+    }
+
+    pub fn compare(&self, a: &K, b: &K) -> Ordering {
+        (self.compare)(a, b)
+    }
+
+    // The key to separate a node ending at `left` from one starting at `right`: the
+    // shortened one if it does lie above left and no higher than right, else right itself.
+    fn separator(&self, left: &K, right: &K) -> K
+    where
+        K: Clone,
+    {
+        if let Some(shorten) = &self.shorten {
+            let short = shorten(left, right);
+            if self.compare(left, &short).is_lt() && self.compare(&short, right).is_le() {
+                return short;
+            }
+        }
+        right.clone()
+    }
+}
+
+impl<K: Ord + 'static> Comparator<K> {
+    /// The key type's own ordering.
+    pub fn binary() -> Self {
+        Comparator::new("BINARY", K::cmp)
+    }
+}
+
+impl<K> fmt::Debug for Comparator<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+// Comparators are known by name, like the collations they are usually made from.
+impl<K> PartialEq for Comparator<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
 
 #[derive(Debug, PartialEq)]
-struct Btree<'a, K: Ord, V: Ord> {
-    interior_node_count: u64, // The k in "k-ary btree" or number of interior node per node.
-    root: RootNode<'a, K, V>,
+pub struct Btree<K, V> {
+    interior_node_count: u64, // The k in "k-ary btree", the most keys a single node may hold.
+    root: PageId,
+    nodes: Pages<K, V>,
+    // The leaf holding the largest keys, where keys inserted in order are appended.
+    rightmost_leaf: PageId,
+    comparator: Comparator<K>,
+    // Nodes emptied by merges, reused by the next splits before the arena grows.
+    free: Vec<PageId>,
+    // How much of a page each entry takes, for a tree kept on pages, see Btree::on_pages.
+    room: Option<Room<K, V>>,
 }
 
-impl<'a, K: Ord, V: Ord> Btree<'a, K, V> {
-    pub fn new(interior_node_count: u64, key: K, value: V) -> Self {
-        let mut root: RootNode<'a, K, V> = RootNode {
-            interior_nodes: vec![],
-        };
+// How a tree kept on pages sizes its nodes: by the bytes their cells take up on a page,
+// rather than by their number of keys, so that a node holds as many entries as its page
+// has room for, more short ones than long.
+struct Room<K, V> {
+    // the bytes of a page a node's cells may take up before it overflows
+    bytes: usize,
+    // the bytes a leaf's entry takes, and an inner node's key with the child before it
+    entry: Arc<EntrySizeFn<K, V>>,
+    key: Arc<dyn Fn(&K) -> usize + Send + Sync>,
+}
+
+type EntrySizeFn<K, V> = dyn Fn(&K, &V) -> usize + Send + Sync;
+
+impl<K, V> fmt::Debug for Room<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes", self.bytes)
+    }
+}
 
-        let interior_node: InnerNodeInterior<'a, K, V> =
-            InnerNodeInterior::new(key, value, Vec::new());
+// Trees kept on pages of the same size size their nodes the same way.
+impl<K, V> PartialEq for Room<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.bytes == other.bytes
+    }
+}
 
-        root.insert_interior_node(interior_node);
+impl<K: Ord + Clone + 'static, V> Btree<K, V> {
+    pub fn empty(interior_node_count: u64) -> Self {
+        Self::with_comparator(interior_node_count, Comparator::binary())
+    }
+}
 
+impl<K: Clone, V> Btree<K, V> {
+    pub fn with_comparator(interior_node_count: u64, comparator: Comparator<K>) -> Self {
+        assert!(
+            interior_node_count >= 2,
+            "a node must be able to hold at least two keys to split"
+        );
+        let mut nodes = Pages::default();
+        let root = nodes.push(Node::empty_leaf());
         Btree {
             interior_node_count,
             root,
+            nodes,
+            rightmost_leaf: root,
+            comparator,
+            free: vec![],
+            room: None,
+        }
+    }
+
+    /// A tree holding `entries`, which must be in key order with no key twice, built from
+    /// the bottom up: the leaves filled left to right, then each level of inner nodes over
+    /// the level below, until a level has only the root. Inserting sorted keys one at a
+    /// time would split node after node on the way to the same tree.
+    pub fn bulk_load(
+        interior_node_count: u64,
+        comparator: Comparator<K>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        let mut btree = Self::with_comparator(interior_node_count, comparator);
+        let entries: Vec<LeafNodeInterior<K, V>> = entries
+            .into_iter()
+            .map(|(key, value)| LeafNodeInterior { key, value })
+            .collect();
+        assert!(
+            entries
+                .windows(2)
+                .all(|w| btree.comparator.compare(&w[0].key, &w[1].key).is_lt()),
+            "bulk loaded entries must be in key order"
+        );
+        if entries.is_empty() {
+            return btree;
+        }
+        btree.nodes = Pages::default();
+
+        // each node of a level with its low fence, which is the separator to its left
+        let mut level: Vec<(PageId, Option<K>)> = vec![];
+        let mut entries = entries.into_iter();
+        for size in fill(entries.len(), btree.max_keys()) {
+            let chunk: Vec<_> = entries.by_ref().take(size).collect();
+            let low_fence = level.last().map(|(left, _)| {
+                let Node::Leaf(left) = &btree.nodes[*left] else {
+                    unreachable!("the level below the inner nodes is of leaves")
+                };
+                let last = &left.interior_nodes[left.interior_nodes.len() - 1].key;
+                btree.comparator.separator(last, &chunk[0].key)
+            });
+            let id = btree.nodes.push(Node::Leaf(LeafNode {
+                interior_nodes: chunk,
+                low_fence: low_fence.clone(),
+                high_key: None,
+                left_sibling: level.last().map(|(id, _)| *id),
+                right_sibling: None,
+            }));
+            level.push((id, low_fence));
+        }
+        for pair in level.windows(2) {
+            if let Node::Leaf(leaf) = &mut btree.nodes[pair[0].0] {
+                leaf.high_key = pair[1].1.clone();
+                leaf.right_sibling = Some(pair[1].0);
+            }
+        }
+        btree.rightmost_leaf = level[level.len() - 1].0;
+
+        while level.len() > 1 {
+            let mut children = level.into_iter();
+            let mut above = vec![];
+            for size in fill(children.len(), btree.max_keys() + 1) {
+                let group: Vec<(PageId, Option<K>)> = children.by_ref().take(size).collect();
+                let low_fence = group[0].1.clone();
+                let keys = group[1..]
+                    .iter()
+                    .map(|(_, fence)| fence.clone().expect("only the leftmost node is unbounded"))
+                    .collect();
+                let id = btree.nodes.push(Node::Inner(InnerNode {
+                    keys,
+                    children: group.into_iter().map(|(id, _)| id).collect(),
+                    low_fence: low_fence.clone(),
+                    high_key: None,
+                    right_link: None,
+                }));
+                above.push((id, low_fence));
+            }
+            for pair in above.windows(2) {
+                if let Node::Inner(inner) = &mut btree.nodes[pair[0].0] {
+                    inner.high_key = pair[1].1.clone();
+                }
+            }
+            level = above;
+        }
+        btree.root = level[0].0;
+        btree
+    }
+
+    /// The same entries in a tree built afresh by bulk_load: its nodes full, its leaves on
+    /// pages numbered in key order, and no free pages left among them by merges.
+    pub fn rebuilt(&self) -> Self
+    where
+        V: Clone,
+    {
+        let entries = self.range(..).map(|(k, v)| (k.clone(), v.clone()));
+        Self::bulk_load(self.interior_node_count, self.comparator.clone(), entries)
+    }
+
+    pub fn comparator(&self) -> &Comparator<K> {
+        &self.comparator
+    }
+
+    /// The most keys a node holds before it splits.
+    pub fn max_keys(&self) -> usize {
+        self.interior_node_count as usize
+    }
+
+    // The fewest keys a node other than the root may be left with by a delete. Two nodes
+    // at or below it fit in one, separator and all, when they have to be merged.
+    fn min_keys(&self) -> usize {
+        self.max_keys() / 2
+    }
+
+    // How much a node has room for: its most keys, or for a tree kept on pages the bytes
+    // its cells may take up.
+    fn capacity(&self) -> usize {
+        self.room
+            .as_ref()
+            .map_or(self.max_keys(), |room| room.bytes)
+    }
+
+    // How much of its room each of a node's entries takes, one apiece unless the tree is
+    // kept on pages.
+    fn sizes(&self, id: PageId) -> Vec<usize> {
+        match (&self.room, &self.nodes[id]) {
+            (None, node) => vec![1; node.key_count()],
+            (Some(room), Node::Leaf(leaf)) => leaf
+                .interior_nodes
+                .iter()
+                .map(|e| (room.entry)(&e.key, &e.value))
+                .collect(),
+            (Some(room), Node::Inner(inner)) => inner.keys.iter().map(|k| (room.key)(k)).collect(),
+        }
+    }
+
+    fn load(&self, id: PageId) -> usize {
+        match self.room {
+            None => self.nodes[id].key_count(),
+            Some(_) => self.sizes(id).iter().sum(),
+        }
+    }
+
+    // Whether a node holds more than it has room for, and must be evened out with a
+    // sibling or split.
+    fn overfull(&self, id: PageId) -> bool {
+        self.load(id) > self.capacity()
+    }
+
+    // Whether a node other than the root is left less than half full, and should take
+    // entries from a sibling or be merged with one.
+    fn underfull(&self, id: PageId) -> bool {
+        self.load(id) < self.capacity() / 2
+    }
+
+    // Where the next node will go, the last freed one if there is one.
+    fn next_id(&self) -> PageId {
+        self.free.last().copied().unwrap_or(self.nodes.next_page())
+    }
+
+    fn allocate(&mut self, node: Node<K, V>) -> PageId {
+        match self.free.pop() {
+            Some(id) => {
+                self.nodes[id] = node;
+                id
+            }
+            None => self.nodes.push(node),
         }
     }
 }
 
+// The tree's nodes by number, held decoded in memory, and which have changed since they
+// were last written out by flush.
 #[derive(Debug)]
-enum NonRootNode<'a, K: Ord, V: Ord> {
-    Inner(InnerNode<'a, K, V>),
-    Leaf(InnerNodeInterior<'a, K, V>),
+struct Pages<K, V> {
+    nodes: Vec<Node<K, V>>,
+    dirty: BTreeSet<PageId>,
+    // the page of the file each node was written to, for a tree kept on pages
+    files: BTreeMap<PageId, PageNumber>,
+    // the overflow pages each node's cells spilled onto when it was last written
+    chains: BTreeMap<PageId, Vec<PageNumber>>,
 }
 
-impl<'a, K: Ord, V: Ord> InnerNodeInterior<'a, K, V> {
-    pub fn new(
-        key: K,
-        value: V,
-        children: Vec<NonRootNode<'a, K, V>>,
-    ) -> InnerNodeInterior<'a, K, V> {
-        InnerNodeInterior {
-            key,
-            value,
-            children,
+impl<K, V> Default for Pages<K, V> {
+    fn default() -> Self {
+        Pages {
+            nodes: vec![],
+            dirty: BTreeSet::new(),
+            files: BTreeMap::new(),
+            chains: BTreeMap::new(),
         }
     }
 }
 
-impl<'a, K: Ord, V: Ord> PartialEq for InnerNodeInterior<'a, K, V> {
-    fn eq(&self, other: &Self) -> bool {
-        self.key == other.key
+impl<K, V> Pages<K, V> {
+    fn next_page(&self) -> PageId {
+        self.nodes.len() as PageId + 1
     }
-}
 
-impl<'a, K: Ord, V: Ord> PartialOrd for InnerNodeInterior<'a, K, V> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    fn push(&mut self, node: Node<K, V>) -> PageId {
+        let page = self.next_page();
+        self.nodes.push(node);
+        self.dirty.insert(page);
+        page
     }
-}
 
-impl<'a, K: Ord + PartialOrd, V: Ord> Ord for InnerNodeInterior<'a, K, V> {
-    fn cmp(&self, other: &Self) -> Ordering {
-        (self.key).cmp(&other.key)
+    // Two different pages to change at once.
+    fn pair_mut(&mut self, a: PageId, b: PageId) -> (&mut Node<K, V>, &mut Node<K, V>) {
+        self.dirty.extend([a, b]);
+        let (a, b) = (a as usize - 1, b as usize - 1);
+        let (low, high) = self.nodes.split_at_mut(a.max(b));
+        if a < b {
+            (&mut low[a], &mut high[0])
+        } else {
+            (&mut high[0], &mut low[b])
+        }
     }
 }
 
-impl<'a, K: Ord + PartialOrd, V: Ord> Eq for InnerNodeInterior<'a, K, V> {}
+impl<K, V> Index<PageId> for Pages<K, V> {
+    type Output = Node<K, V>;
 
-// Generic operations on all nodes.
-trait HasInteriorNodes<'a, K: Ord, V: Ord> {
-    fn find_interior_node(&self) -> Option<&InnerNodeInterior<'a, K, V>>;
+    fn index(&self, page: PageId) -> &Node<K, V> {
+        &self.nodes[page as usize - 1]
+    }
+}
 
-    fn insert_interior_node(&mut self, n: InnerNodeInterior<'a, K, V>) -> ();
+// Borrowing a page to change it marks it dirty.
+impl<K, V> IndexMut<PageId> for Pages<K, V> {
+    fn index_mut(&mut self, page: PageId) -> &mut Node<K, V> {
+        self.dirty.insert(page);
+        &mut self.nodes[page as usize - 1]
+    }
+}
 
-    fn delete_interior_node(&mut self, key: K) -> ();
+// Trees are equal if their nodes are, whichever have been written out.
+impl<K: PartialEq, V: PartialEq> PartialEq for Pages<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.nodes == other.nodes
+    }
 }
 
 #[derive(Debug, PartialEq)]
-struct RootNode<'a, K: Ord, V: Ord> {
-    interior_nodes: Vec<InnerNodeInterior<'a, K, V>>,
+enum Node<K, V> {
+    Inner(InnerNode<K>),
+    Leaf(LeafNode<K, V>),
 }
 
-// An Inner node is a node that is not a leaf node and not a root Node.
-#[derive(Debug)]
-struct InnerNode<'a, K: Ord, V: Ord> {
-    // -- Our guideposts to get to leaf Nodes which hold the actual data..
-    interior_nodes: Vec<InnerNodeInterior<'a, K, V>>, // sorted by K to enable binary search lookup
+impl<K, V> Node<K, V> {
+    fn empty_leaf() -> Self {
+        Node::Leaf(LeafNode {
+            interior_nodes: vec![],
+            low_fence: None,
+            high_key: None,
+            left_sibling: None,
+            right_sibling: None,
+        })
+    }
 
-    // -- Metadata
-    // We don't have a pointer to parent because allowing backtracking will open
-    // the door to deadlocks when concurrent access to the B+Tree occurs.
-    left_sibling: Option<&'a InnerNode<'a, K, V>>,
-    right_sibling: Option<&'a InnerNode<'a, K, V>>,
+    // The keys this node is responsible for are low_fence <= key < high_key, where None is
+    // unbounded.
+    fn fences(&self) -> (Option<&K>, Option<&K>) {
+        match self {
+            Node::Inner(inner) => (inner.low_fence.as_ref(), inner.high_key.as_ref()),
+            Node::Leaf(leaf) => (leaf.low_fence.as_ref(), leaf.high_key.as_ref()),
+        }
+    }
+
+    fn right_link(&self) -> Option<PageId> {
+        match self {
+            Node::Inner(inner) => inner.right_link,
+            Node::Leaf(leaf) => leaf.right_sibling,
+        }
+    }
+
+    fn key_count(&self) -> usize {
+        match self {
+            Node::Inner(inner) => inner.keys.len(),
+            Node::Leaf(leaf) => leaf.interior_nodes.len(),
+        }
+    }
+
+    // Whether `key` belongs to a node somewhere to the right of this one.
+    fn is_past_high_key(&self, key: &K, comparator: &Comparator<K>) -> bool {
+        self.fences()
+            .1
+            .is_some_and(|high| comparator.compare(key, high).is_ge())
+    }
 }
 
-#[derive(Debug)]
-struct InnerNodeInterior<'a, K: Ord, V: Ord> {
-    key: K,   //   Key is used to maintain the order of the tree,
-    value: V, // Value is the actual data being stored.
-    // Maximum of N + 1 child nodes
-    children: Vec<NonRootNode<'a, K, V>>,
+// An Inner node is a node that is not a leaf node. It holds no data, only the separator
+// keys which act as guideposts to get to the leaf Nodes which hold the actual data.
+#[derive(Debug, PartialEq)]
+struct InnerNode<K> {
+    // children[i] holds the keys below keys[i] and children[i + 1] the keys from keys[i]
+    // upwards, so there is always one more child than there are keys.
+    keys: Vec<K>,
+    children: Vec<PageId>,
+    low_fence: Option<K>,
+    high_key: Option<K>,
+    // the inner node that took over from this one's high key when it split
+    right_link: Option<PageId>,
 }
 
-impl<'a, K: Ord, V: Ord> InnerNode<'a, K, V> {
-    pub fn new(
-        right_sibling: Option<&'a InnerNode<'a, K, V>>,
-        left_sibling: Option<&'a InnerNode<'a, K, V>>,
-        key: K,
-        value: V,
-    ) -> InnerNode<'a, K, V> {
-        InnerNode {
-            left_sibling,
-            right_sibling,
-            // Maximum of N interior nodes
-            interior_nodes: vec![InnerNodeInterior {
-                key,
-                value,
-                children: Vec::new(),
-            }],
-        }
+impl<K> InnerNode<K> {
+    // The child to descend into to find `key`.
+    fn child_index(&self, key: &K, comparator: &Comparator<K>) -> usize {
+        self.keys
+            .partition_point(|k| comparator.compare(k, key).is_le())
     }
 }
 
-#[derive(Debug)]
-struct LeafNode<'a, K: Ord, V: Ord> {
-    // -- Our guideposts --
-    // to get to leaf Nodes which hold the actual data..
+#[derive(Debug, PartialEq)]
+struct LeafNode<K, V> {
     interior_nodes: Vec<LeafNodeInterior<K, V>>, // sorted by K to enable binary search lookup
+    low_fence: Option<K>,
+    high_key: Option<K>,
 
     // -- Metadata --
     // We don't have a pointer to parent because allowing backtracking will open
-    // the door to deadlocks when concurrent access to the B+Tree occurs.
-    left_sibling: Option<&'a InnerNode<'a, K, V>>,
-    right_sibling: Option<&'a InnerNode<'a, K, V>>,
+    // the door to deadlocks when concurrent access to the B+Tree occurs, see latch.rs.
+    left_sibling: Option<PageId>,
+    right_sibling: Option<PageId>,
 }
 
-#[derive(Debug)]
-struct LeafNodeInterior<K: Ord, V> {
+#[derive(Debug, PartialEq)]
+struct LeafNodeInterior<K, V> {
     key: K,
     value: V, // Value is the actual data being stored. The PageId in our case for the tuple
               // that is associated with the attribute(s) represented by the key.
 }
 
-impl<'a, K: Ord, V: Ord> HasInteriorNodes<'a, K, V> for RootNode<'a, K, V> {
-    // insert in sorted order
-    fn insert_interior_node(&mut self, n: InnerNodeInterior<'a, K, V>) -> () {
-        match self.interior_nodes.binary_search(&n) {
-            Ok(pos) | Err(pos) => self.interior_nodes.insert(pos, n),
+struct Outcome<K, V> {
+    // The value the key had if it was already present, replaced by an insert or removed
+    // by a delete.
+    previous: Option<V>,
+    // Set when the node overflowed and was split in two. The parent must add the new
+    // node as a child to the right of this one with the separator as the key between them.
+    // A delete only overflows a node of a tree kept on pages, when a longer separator
+    // takes the place of a shorter one as keys move between its children.
+    split: Option<(K, PageId)>,
+}
+
+// Public interface
+
+impl<K: Clone, V> Btree<K, V> {
+    /// Insert a key, returning the previous value if the key was already present.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let size = self
+            .room
+            .as_ref()
+            .map_or(1, |room| (room.entry)(&key, &value));
+        let has_room = self.load(self.rightmost_leaf) + size <= self.capacity();
+        let comparator = &self.comparator;
+        let rightmost = self.leaf(self.rightmost_leaf);
+        let past_the_end = rightmost
+            .interior_nodes
+            .last()
+            .map_or(true, |last| comparator.compare(&key, &last.key).is_gt())
+            && rightmost
+                .low_fence
+                .as_ref()
+                .map_or(true, |low| comparator.compare(&key, low).is_ge());
+        if past_the_end && has_room {
+            let Node::Leaf(rightmost) = &mut self.nodes[self.rightmost_leaf] else {
+                unreachable!("the rightmost leaf is a leaf")
+            };
+            rightmost
+                .interior_nodes
+                .push(LeafNodeInterior { key, value });
+            return None;
+        }
+
+        // a root split that never got its new root
+        if let (Some(right), Some(separator)) = (
+            self.nodes[self.root].right_link(),
+            self.nodes[self.root].fences().1.cloned(),
+        ) {
+            self.grow_root(separator, right);
+        }
+        let outcome = self.insert_into(self.root, None, key, value);
+        if let Some((separator, right)) = outcome.split {
+            self.grow_root(separator, right);
+        }
+        outcome.previous
+    }
+
+    /// Remove a key, returning its value if it was present. A node left less than half full
+    /// borrows keys from a sibling, or is merged with it when the sibling has none to
+    /// spare, and a root left with a single child is replaced by that child.
+    pub fn delete(&mut self, key: &K) -> Option<V> {
+        // a root split that never got its new root
+        if let (Some(right), Some(separator)) = (
+            self.nodes[self.root].right_link(),
+            self.nodes[self.root].fences().1.cloned(),
+        ) {
+            self.grow_root(separator, right);
+        }
+        let outcome = self.delete_from(self.root, None, key);
+        if let Some((separator, right)) = outcome.split {
+            self.grow_root(separator, right);
+        }
+        self.shrink_root();
+        outcome.previous
+    }
+
+    /// The value stored under `key`, found by descending from the root through the
+    /// separators, a binary search at each level, so it visits one node per level.
+    pub fn find(&self, key: &K) -> Option<&V> {
+        let Node::Leaf(leaf) = &self.nodes[self.find_leaf(key)] else {
+            unreachable!("find_leaf always returns a leaf")
+        };
+        let pos = leaf
+            .interior_nodes
+            .binary_search_by(|e| self.comparator.compare(&e.key, key))
+            .ok()?;
+        Some(&leaf.interior_nodes[pos].value)
+    }
+
+    /// The entries whose keys are in `range`, in key order: a descent to the leaf the
+    /// range starts in, then a walk right along the leaves until it ends. `btree.range(..)`
+    /// is every entry.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Range<'_, K, V> {
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let leaf_id = match start {
+            Some(key) => self.find_leaf(key),
+            None => {
+                let mut node_id = self.root;
+                while let Node::Inner(inner) = &self.nodes[node_id] {
+                    node_id = inner.children[0];
+                }
+                node_id
+            }
+        };
+        let Node::Leaf(leaf) = &self.nodes[leaf_id] else {
+            unreachable!("find_leaf always returns a leaf")
+        };
+        let pos = match range.start_bound() {
+            Bound::Included(key) => leaf
+                .interior_nodes
+                .partition_point(|e| self.comparator.compare(&e.key, key).is_lt()),
+            Bound::Excluded(key) => leaf
+                .interior_nodes
+                .partition_point(|e| self.comparator.compare(&e.key, key).is_le()),
+            Bound::Unbounded => 0,
+        };
+        Range {
+            btree: self,
+            leaf: Some(leaf_id),
+            pos,
+            end: range.end_bound().cloned(),
+        }
+    }
+
+    /// The first entry whose key is greater than or equal to `key`.
+    pub fn lower_bound(&self, key: &K) -> Option<(&K, &V)> {
+        self.seek_position(key).map(|p| self.entry(p))
+    }
+
+    /// A cursor to move about the tree's entries with, not yet on any of them.
+    pub fn cursor(&self) -> Cursor<'_, K, V> {
+        Cursor {
+            btree: self,
+            position: None,
+        }
+    }
+
+    /// A cursor that can also delete the entry it is on.
+    pub fn cursor_mut(&mut self) -> CursorMut<'_, K, V> {
+        CursorMut {
+            btree: self,
+            position: None,
+        }
+    }
+
+    /// Levels from the root down to the leaves, which are all at the same depth.
+    pub fn height(&self) -> usize {
+        let mut node_id = self.root;
+        let mut height = 1;
+        while let Node::Inner(inner) = &self.nodes[node_id] {
+            node_id = inner.children[0];
+            height += 1;
+        }
+        height
+    }
+}
+
+// Where a cursor is, the pos'th entry of a leaf.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Position {
+    leaf: PageId,
+    pos: usize,
+}
+
+impl<K: Clone, V> Btree<K, V> {
+    fn leaf(&self, id: PageId) -> &LeafNode<K, V> {
+        let Node::Leaf(leaf) = &self.nodes[id] else {
+            unreachable!("leaves only link to leaves")
+        };
+        leaf
+    }
+
+    fn entry(&self, p: Position) -> (&K, &V) {
+        let e = &self.leaf(p.leaf).interior_nodes[p.pos];
+        (&e.key, &e.value)
+    }
+
+    // The entry at `p` or, if the leaf has no entry there, the first of a later leaf.
+    fn settle_forward(&self, mut p: Position) -> Option<Position> {
+        loop {
+            let leaf = self.leaf(p.leaf);
+            if p.pos < leaf.interior_nodes.len() {
+                return Some(p);
+            }
+            p = Position {
+                leaf: leaf.right_sibling?,
+                pos: 0,
+            };
+        }
+    }
+
+    fn seek_position(&self, key: &K) -> Option<Position> {
+        let leaf = self.find_leaf(key);
+        let pos = self
+            .leaf(leaf)
+            .interior_nodes
+            .partition_point(|e| self.comparator.compare(&e.key, key).is_lt());
+        self.settle_forward(Position { leaf, pos })
+    }
+
+    fn first_position(&self) -> Option<Position> {
+        let mut node_id = self.root;
+        while let Node::Inner(inner) = &self.nodes[node_id] {
+            node_id = inner.children[0];
         }
+        self.settle_forward(Position {
+            leaf: node_id,
+            pos: 0,
+        })
     }
 
-    fn find_interior_node(&self) -> Option<&InnerNodeInterior<'a, K, V>> {
-        None
+    fn next_position(&self, p: Position) -> Option<Position> {
+        self.settle_forward(Position {
+            leaf: p.leaf,
+            pos: p.pos + 1,
+        })
     }
+}
 
-    fn delete_interior_node(&mut self, _key: K) -> () {}
+/// A position among a tree's entries, moved forwards a step at a time, so that whoever
+/// reads a table needn't know how its tree is laid out. A cursor starts out on no entry,
+/// and falls off the end when moved past the last one. The moves return whether it is on
+/// an entry afterwards.
+pub struct Cursor<'a, K, V> {
+    btree: &'a Btree<K, V>,
+    position: Option<Position>,
 }
 
-// Public interface
+impl<'a, K: Clone, V> Cursor<'a, K, V> {
+    pub fn first(&mut self) -> bool {
+        self.position = self.btree.first_position();
+        self.position.is_some()
+    }
+
+    pub fn next(&mut self) -> bool {
+        self.position = self.position.and_then(|p| self.btree.next_position(p));
+        self.position.is_some()
+    }
+
+    pub fn current(&self) -> Option<(&'a K, &'a V)> {
+        let btree = self.btree;
+        self.position.map(|p| btree.entry(p))
+    }
+
+    /// The entries from the one the cursor is on to the last, the cursor moving on a step
+    /// as each is read, which is how a table is scanned.
+    pub fn entries(mut self) -> impl Iterator<Item = (&'a K, &'a V)> {
+        std::iter::from_fn(move || {
+            let entry = self.current()?;
+            self.next();
+            Some(entry)
+        })
+    }
+}
+
+/// A cursor sent straight to a key, that can delete the entry it is on.
+pub struct CursorMut<'a, K, V> {
+    btree: &'a mut Btree<K, V>,
+    position: Option<Position>,
+}
+
+impl<K: Clone, V> CursorMut<'_, K, V> {
+    /// Move to the first entry at or after `key`, returning whether it is `key` itself.
+    pub fn seek(&mut self, key: &K) -> bool {
+        self.position = self.btree.seek_position(key);
+        self.current()
+            .is_some_and(|(k, _)| self.btree.comparator.compare(k, key).is_eq())
+    }
 
-fn find() {}
+    pub fn current(&self) -> Option<(&K, &V)> {
+        self.position.map(|p| self.btree.entry(p))
+    }
+
+    /// Delete the entry the cursor is on, leaving it on the entry that came after. The
+    /// delete may rebalance the tree, so the cursor finds its place again by key.
+    pub fn delete(&mut self) -> Option<(K, V)> {
+        let key = self.current()?.0.clone();
+        let value = self.btree.delete(&key)?;
+        self.position = self.btree.seek_position(&key);
+        Some((key, value))
+    }
+}
+
+// How many of `count` items to put in each node of a level that holds at most `capacity`
+// each: as few nodes as will do, sharing the items out evenly so that the last node isn't
+// left with the few over and less than half full.
+fn fill(count: usize, capacity: usize) -> impl Iterator<Item = usize> {
+    let nodes = count.div_ceil(capacity);
+    (0..nodes).map(move |i| count / nodes + usize::from(i < count % nodes))
+}
+
+/// The entries of a key range in key order, see Btree::range.
+pub struct Range<'a, K, V> {
+    btree: &'a Btree<K, V>,
+    // the leaf and position of the next entry, None once the range is done
+    leaf: Option<PageId>,
+    pos: usize,
+    end: Bound<K>,
+}
 
-fn insert() {}
+impl<'a, K, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
 
-fn delete() {}
+    fn next(&mut self) -> Option<Self::Item> {
+        let btree = self.btree;
+        loop {
+            let Node::Leaf(leaf) = &btree.nodes[self.leaf?] else {
+                unreachable!("leaves only link to leaves")
+            };
+            let Some(entry) = leaf.interior_nodes.get(self.pos) else {
+                // an emptied leaf is passed over like any other
+                self.leaf = leaf.right_sibling;
+                self.pos = 0;
+                continue;
+            };
+            let past_the_end = match &self.end {
+                Bound::Included(end) => btree.comparator.compare(&entry.key, end).is_gt(),
+                Bound::Excluded(end) => btree.comparator.compare(&entry.key, end).is_ge(),
+                Bound::Unbounded => false,
+            };
+            if past_the_end {
+                self.leaf = None;
+                return None;
+            }
+            self.pos += 1;
+            return Some((&entry.key, &entry.value));
+        }
+    }
+}
 
 /* Private Interface - balancing operations */
 
-fn split() {}
+impl<K: Clone, V> Btree<K, V> {
+    // The root split so the tree grows a level, the only way its height increases.
+    fn grow_root(&mut self, separator: K, right: PageId) {
+        let old_root = self.root;
+        self.root = self.allocate(Node::Inner(InnerNode {
+            keys: vec![separator],
+            children: vec![old_root, right],
+            low_fence: None,
+            high_key: None,
+            right_link: None,
+        }));
+    }
 
-fn merge() {}
+    // Descend from the root to the leaf that does or would hold `key`, moving right past
+    // any split the parent doesn't know about yet.
+    fn find_leaf(&self, key: &K) -> PageId {
+        self.descend(key).0
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // find_leaf, along with how many nodes it visited on the way.
+    fn descend(&self, key: &K) -> (PageId, usize) {
+        let mut visits = 0;
+        let mut node_id = self.move_right(self.root, key, &mut visits);
+        while let Node::Inner(inner) = &self.nodes[node_id] {
+            let child = inner.children[inner.child_index(key, &self.comparator)];
+            node_id = self.move_right(child, key, &mut visits);
+        }
+        (node_id, visits)
+    }
 
-    /*
-        Unit Tests
-    */
+    fn move_right(&self, mut node_id: PageId, key: &K, visits: &mut usize) -> PageId {
+        *visits += 1;
+        while self.nodes[node_id].is_past_high_key(key, &self.comparator) {
+            match self.nodes[node_id].right_link() {
+                Some(right) => node_id = right,
+                None => break,
+            }
+            *visits += 1;
+        }
+        node_id
+    }
 
-    #[test]
-    fn new_btree_inits_correctly_with_single_key_value() {
-        let interior_node_count: u64 = 2;
-        let key: u8 = 1;
-        let value: u8 = 2;
-        let init_btree: Btree<u8, u8> = Btree::new(interior_node_count, key, value);
-        let expected_btree = Btree {
-            interior_node_count,
-            root: RootNode {
-                interior_nodes: vec![InnerNodeInterior {
-                    key,
-                    value,
-                    children: Vec::new(),
-                }],
-            },
+    // If the child at `pos` split without the separator reaching this parent, its high key
+    // won't be the parent's bound for it. Returns the separator and new node to add.
+    fn unfinished_split(&self, parent: &InnerNode<K>, pos: usize) -> Option<(K, PageId)> {
+        let child = &self.nodes[parent.children[pos]];
+        let bound = parent.keys.get(pos).or(parent.high_key.as_ref());
+        match (child.fences().1, child.right_link()) {
+            (Some(high), Some(right))
+                if bound.map_or(true, |bound| self.comparator.compare(high, bound).is_ne()) =>
+            {
+                Some((high.clone(), right))
+            }
+            _ => None,
+        }
+    }
+
+    // Insert into the subtree under `node_id`, which is the pos'th child of `parent` unless it
+    // is the root.
+    fn insert_into(
+        &mut self,
+        node_id: PageId,
+        parent: Option<(PageId, usize)>,
+        key: K,
+        value: V,
+    ) -> Outcome<K, V> {
+        // only borrowing the node to change it marks its page dirty, see Pages
+        match self.nodes[node_id] {
+            Node::Leaf(_) => {
+                let Node::Leaf(leaf) = &mut self.nodes[node_id] else {
+                    unreachable!("node was a leaf a moment ago")
+                };
+                let comparator = &self.comparator;
+                let previous = match leaf
+                    .interior_nodes
+                    .binary_search_by(|e| comparator.compare(&e.key, &key))
+                {
+                    Ok(pos) => Some(mem::replace(&mut leaf.interior_nodes[pos].value, value)),
+                    Err(pos) => {
+                        leaf.interior_nodes
+                            .insert(pos, LeafNodeInterior { key, value });
+                        None
+                    }
+                };
+                // on pages a bigger value can overflow the leaf as a new entry can
+                let split = if self.overfull(node_id) {
+                    self.balance(node_id, parent)
+                } else {
+                    None
+                };
+                Outcome { previous, split }
+            }
+            Node::Inner(_) => {
+                // finish the splits of any children on the way down before descending
+                loop {
+                    let Node::Inner(inner) = &self.nodes[node_id] else {
+                        unreachable!("node was inner a moment ago")
+                    };
+                    let pos = inner.child_index(&key, &self.comparator);
+                    let Some((separator, right)) = self.unfinished_split(inner, pos) else {
+                        break;
+                    };
+                    self.add_child(node_id, separator, right);
+                }
+                let Node::Inner(inner) = &self.nodes[node_id] else {
+                    unreachable!("node was inner a moment ago")
+                };
+                let pos = inner.child_index(&key, &self.comparator);
+                let child = inner.children[pos];
+
+                let outcome = self.insert_into(child, Some((node_id, pos)), key, value);
+                if let Some((separator, right)) = outcome.split {
+                    self.add_child(node_id, separator, right);
+                }
+                let split = if self.overfull(node_id) {
+                    self.balance(node_id, parent)
+                } else {
+                    None
+                };
+                Outcome {
+                    previous: outcome.previous,
+                    split,
+                }
+            }
+        }
+    }
+
+    // Delete from the subtree under `node_id`, which is the pos'th child of `parent` unless
+    // it is the root, rebalancing any child of it left underfull.
+    fn delete_from(
+        &mut self,
+        node_id: PageId,
+        parent: Option<(PageId, usize)>,
+        key: &K,
+    ) -> Outcome<K, V> {
+        match &self.nodes[node_id] {
+            Node::Leaf(leaf) => {
+                let found = leaf
+                    .interior_nodes
+                    .binary_search_by(|e| self.comparator.compare(&e.key, key));
+                let previous = found.ok().map(|pos| {
+                    let Node::Leaf(leaf) = &mut self.nodes[node_id] else {
+                        unreachable!("node was a leaf a moment ago")
+                    };
+                    leaf.interior_nodes.remove(pos).value
+                });
+                Outcome {
+                    previous,
+                    split: None,
+                }
+            }
+            Node::Inner(_) => {
+                // as on the way down to insert, finish any split the child is part of
+                loop {
+                    let Node::Inner(inner) = &self.nodes[node_id] else {
+                        unreachable!("node was inner a moment ago")
+                    };
+                    let pos = inner.child_index(key, &self.comparator);
+                    let Some((separator, right)) = self.unfinished_split(inner, pos) else {
+                        break;
+                    };
+                    self.add_child(node_id, separator, right);
+                }
+                let Node::Inner(inner) = &self.nodes[node_id] else {
+                    unreachable!("node was inner a moment ago")
+                };
+                let pos = inner.child_index(key, &self.comparator);
+                let child = inner.children[pos];
+
+                let outcome = self.delete_from(child, Some((node_id, pos)), key);
+                if let Some((separator, right)) = outcome.split {
+                    self.add_child(node_id, separator, right);
+                }
+                if outcome.previous.is_some() && self.underfull(child) {
+                    self.rebalance(node_id, pos);
+                }
+                let split = if self.overfull(node_id) {
+                    self.balance(node_id, parent)
+                } else {
+                    None
+                };
+                Outcome {
+                    previous: outcome.previous,
+                    split,
+                }
+            }
+        }
+    }
+
+    // Top up the parent's underfull pos'th child with keys from a sibling that has some to
+    // spare, the left one first, or else merge it with a sibling. A sibling whose split is
+    // unfinished doesn't end where the parent thinks it does and is left alone, so the
+    // child may stay underfull until a later delete passes by.
+    fn rebalance(&mut self, parent_id: PageId, pos: usize) {
+        let Node::Inner(parent) = &self.nodes[parent_id] else {
+            unreachable!("only inner nodes have children")
         };
+        if self.unfinished_split(parent, pos).is_some() {
+            return;
+        }
+        let children = parent.children.clone();
+        let load = self.load(children[pos]);
+        let left = pos.checked_sub(1);
+        let right = Some(pos + 1).filter(|p| *p < children.len());
+        let siblings: Vec<usize> = [left, right]
+            .into_iter()
+            .flatten()
+            .filter(|sibling| self.unfinished_split(parent, *sibling).is_none())
+            .collect();
+        for &sibling in &siblings {
+            let sibling_load = self.load(children[sibling]);
+            if sibling_load <= self.capacity() / 2 {
+                continue;
+            }
+            // the entries nearest the child, until the two are about even
+            let sizes = self.sizes(children[sibling]);
+            let gap = sibling_load - load;
+            let count = match sibling < pos {
+                true => at_least_half(sizes.iter().rev().copied(), gap),
+                false => at_least_half(sizes.iter().copied(), gap),
+            };
+            let count = count.min(sizes.len() - 1);
+            if count == 0 {
+                continue;
+            }
+            if sibling < pos {
+                self.shift_right(parent_id, sibling, count);
+            } else {
+                self.shift_left(parent_id, pos, count);
+            }
+            // on pages, entries too big to take in move back again
+            if self.overfull(children[pos]) {
+                if sibling < pos {
+                    self.shift_left(parent_id, sibling, count);
+                } else {
+                    self.shift_right(parent_id, pos, count);
+                }
+                continue;
+            }
+            return;
+        }
+        for &sibling in &siblings {
+            if self.merge_fits(parent_id, sibling.min(pos)) {
+                self.merge(parent_id, sibling.min(pos));
+                return;
+            }
+        }
+    }
 
-        assert_eq!(init_btree, expected_btree);
+    // Whether the parent's children at left_pos and left_pos + 1 fit in one node, with the
+    // separator between them if they are inner nodes. Two nodes at or below half full
+    // always do unless the tree is kept on pages.
+    fn merge_fits(&self, parent_id: PageId, left_pos: usize) -> bool {
+        let Node::Inner(parent) = &self.nodes[parent_id] else {
+            unreachable!("only inner nodes have children")
+        };
+        let (left, right) = (parent.children[left_pos], parent.children[left_pos + 1]);
+        let separator = match (&self.nodes[left], &self.room) {
+            (Node::Leaf(_), _) => 0,
+            (Node::Inner(_), None) => 1,
+            (Node::Inner(_), Some(room)) => (room.key)(&parent.keys[left_pos]),
+        };
+        self.load(left) + self.load(right) + separator <= self.capacity()
     }
 
-    /*
-         Property Based Tests (PBTs)
+    // Move every key of the child right of left_pos onto the end of the one at left_pos and
+    // drop the emptied node and its separator from the parent. Between inner nodes the
+    // separator comes down between the two halves, as it does when shift_left rotates keys.
+    fn merge(&mut self, parent_id: PageId, left_pos: usize) {
+        let (separator, left, right) = self.siblings_mut(parent_id, left_pos);
+        let right_sibling = match (left, right) {
+            (Node::Leaf(left), Node::Leaf(right)) => {
+                left.interior_nodes.append(&mut right.interior_nodes);
+                left.high_key = right.high_key.take();
+                left.right_sibling = right.right_sibling;
+                right.right_sibling
+            }
+            (Node::Inner(left), Node::Inner(right)) => {
+                left.keys.push(separator);
+                left.keys.append(&mut right.keys);
+                left.children.append(&mut right.children);
+                left.high_key = right.high_key.take();
+                left.right_link = right.right_link;
+                None
+            }
+            _ => unreachable!("siblings are on the same level"),
+        };
 
-         According to Knuth's Definition a B Tree has the following properties.
+        let Node::Inner(parent) = &mut self.nodes[parent_id] else {
+            unreachable!("only inner nodes have children")
+        };
+        let left_id = parent.children[left_pos];
+        parent.keys.remove(left_pos);
+        let right_id = parent.children.remove(left_pos + 1);
+        if let Some(Node::Leaf(sibling)) = right_sibling.map(|id| &mut self.nodes[id]) {
+            sibling.left_sibling = Some(left_id);
+        }
+        if self.rightmost_leaf == right_id {
+            self.rightmost_leaf = left_id;
+        }
+        self.free.push(right_id);
+    }
 
-             1. Every node has at most m children.
-             2. Every node, except for the root and the leaves, has at least ⌈m/2⌉ children.
-             3. The root node has at least two children unless it is a leaf.
-             4. All leaves appear on the same level.
-             5. A non-leaf node with k children contains k−1 keys.
+    // The tree loses a level while the root is an inner node with just the one child, the
+    // only way its height decreases.
+    fn shrink_root(&mut self) {
+        while let Node::Inner(root) = &self.nodes[self.root] {
+            if !root.keys.is_empty() || root.right_link.is_some() {
+                break;
+            }
+            let old_root = self.root;
+            self.root = root.children[0];
+            self.free.push(old_root);
+        }
+    }
 
-          Lets Test these properties with PBT.
-    */
+    // Deal with a node that has overflowed, by evening it out with a sibling if one has room
+    // and otherwise by splitting it. Returns the split for the parent to add if it was split.
+    fn balance(&mut self, node_id: PageId, parent: Option<(PageId, usize)>) -> Option<(K, PageId)> {
+        if let Some((parent_id, pos)) = parent {
+            if self.redistribute(parent_id, pos) {
+                return None;
+            }
+        }
+        Some(match self.nodes[node_id] {
+            Node::Leaf(_) => self.split_leaf(node_id),
+            Node::Inner(_) => self.split_inner(node_id),
+        })
+    }
+
+    // Move keys from the parent's pos'th child into its left or, failing that, its right
+    // sibling, so the two hold about as many each. False if neither sibling has room.
+    fn redistribute(&mut self, parent_id: PageId, pos: usize) -> bool {
+        let Node::Inner(parent) = &self.nodes[parent_id] else {
+            unreachable!("only inner nodes have children")
+        };
+        let children = parent.children.clone();
+        let load = self.load(children[pos]);
+        let sizes = self.sizes(children[pos]);
+        let left = pos.checked_sub(1);
+        let right = Some(pos + 1).filter(|p| *p < children.len());
+        for sibling in [left, right].into_iter().flatten() {
+            let Node::Inner(parent) = &self.nodes[parent_id] else {
+                unreachable!("only inner nodes have children")
+            };
+            // a sibling whose split is unfinished doesn't end where the parent thinks it does
+            if self.unfinished_split(parent, sibling).is_some() {
+                continue;
+            }
+            let sibling_load = self.load(children[sibling]);
+            if sibling_load >= self.capacity() {
+                continue;
+            }
+            let gap = load - sibling_load;
+            let count = match sibling < pos {
+                true => at_most_half(sizes.iter().copied(), gap),
+                false => at_most_half(sizes.iter().rev().copied(), gap),
+            };
+            let count = count.min(sizes.len() - 1);
+            if count == 0 {
+                continue;
+            }
+            if sibling < pos {
+                self.shift_left(parent_id, sibling, count);
+            } else {
+                self.shift_right(parent_id, pos, count);
+            }
+            // on pages, where the two still don't fit the entries move back and the node
+            // is split after all
+            if self.overfull(children[pos]) || self.overfull(children[sibling]) {
+                if sibling < pos {
+                    self.shift_right(parent_id, sibling, count);
+                } else {
+                    self.shift_left(parent_id, pos, count);
+                }
+                continue;
+            }
+            return true;
+        }
+        false
+    }
+
+    // The parent's children at left_pos and left_pos + 1, the second of which must not be
+    // the first.
+    fn siblings_mut(
+        &mut self,
+        parent_id: PageId,
+        left_pos: usize,
+    ) -> (K, &mut Node<K, V>, &mut Node<K, V>) {
+        let Node::Inner(parent) = &self.nodes[parent_id] else {
+            unreachable!("only inner nodes have children")
+        };
+        let separator = parent.keys[left_pos].clone();
+        let (left_id, right_id) = (parent.children[left_pos], parent.children[left_pos + 1]);
+        let (left, right) = self.nodes.pair_mut(left_id, right_id);
+        (separator, left, right)
+    }
+
+    // Move the first `count` keys of the child right of left_pos onto the end of the one at
+    // left_pos. In a leaf the new first key of the right node becomes the separator; between
+    // inner nodes keys rotate through the parent, the separator coming down to the left node
+    // while the right node's first key goes up in its place.
+    fn shift_left(&mut self, parent_id: PageId, left_pos: usize, count: usize) {
+        let comparator = self.comparator.clone();
+        let (mut separator, left, right) = self.siblings_mut(parent_id, left_pos);
+        match (left, right) {
+            (Node::Leaf(left), Node::Leaf(right)) => {
+                left.interior_nodes
+                    .extend(right.interior_nodes.drain(..count));
+                separator = leaf_separator(&comparator, left, right);
+                left.high_key = Some(separator.clone());
+                right.low_fence = Some(separator.clone());
+            }
+            (Node::Inner(left), Node::Inner(right)) => {
+                for _ in 0..count {
+                    left.keys.push(separator);
+                    left.children.push(right.children.remove(0));
+                    separator = right.keys.remove(0);
+                }
+                left.high_key = Some(separator.clone());
+                right.low_fence = Some(separator.clone());
+            }
+            _ => unreachable!("siblings are on the same level"),
+        }
+        self.set_separator(parent_id, left_pos, separator);
+    }
+
+    // Move the last `count` keys of the child at left_pos onto the front of the one to its
+    // right, the mirror image of shift_left.
+    fn shift_right(&mut self, parent_id: PageId, left_pos: usize, count: usize) {
+        let comparator = self.comparator.clone();
+        let (mut separator, left, right) = self.siblings_mut(parent_id, left_pos);
+        match (left, right) {
+            (Node::Leaf(left), Node::Leaf(right)) => {
+                let at = left.interior_nodes.len() - count;
+                let moved = left.interior_nodes.split_off(at);
+                right.interior_nodes.splice(0..0, moved);
+                separator = leaf_separator(&comparator, left, right);
+                left.high_key = Some(separator.clone());
+                right.low_fence = Some(separator.clone());
+            }
+            (Node::Inner(left), Node::Inner(right)) => {
+                for _ in 0..count {
+                    right.keys.insert(0, separator);
+                    right
+                        .children
+                        .insert(0, left.children.pop().expect("an inner node has children"));
+                    separator = left.keys.pop().expect("an overflowing node has keys");
+                }
+                left.high_key = Some(separator.clone());
+                right.low_fence = Some(separator.clone());
+            }
+            _ => unreachable!("siblings are on the same level"),
+        }
+        self.set_separator(parent_id, left_pos, separator);
+    }
+
+    fn set_separator(&mut self, parent_id: PageId, pos: usize, separator: K) {
+        let Node::Inner(parent) = &mut self.nodes[parent_id] else {
+            unreachable!("only inner nodes have children")
+        };
+        parent.keys[pos] = separator;
+    }
+
+    // Add a child to the right of the one holding `separator`'s old range.
+    fn add_child(&mut self, node_id: PageId, separator: K, right: PageId) {
+        let Node::Inner(inner) = &mut self.nodes[node_id] else {
+            unreachable!("only inner nodes have children")
+        };
+        let pos = inner.child_index(&separator, &self.comparator);
+        inner.keys.insert(pos, separator);
+        inner.children.insert(pos + 1, right);
+    }
+
+    // Move the upper half of an overflowing leaf into a new right sibling. The separator is
+    // copied up rather than moved as every key must remain in a leaf. This is the first step
+    // of a split, the caller adds the separator to the parent.
+    fn split_leaf(&mut self, node_id: PageId) -> (K, PageId) {
+        let right_id = self.next_id();
+        let mid = split_point(&self.sizes(node_id));
+        let Node::Leaf(leaf) = &mut self.nodes[node_id] else {
+            unreachable!("split_leaf called on an inner node")
+        };
+        let right_entries = leaf.interior_nodes.split_off(mid);
+        let separator = self
+            .comparator
+            .separator(&leaf.interior_nodes[mid - 1].key, &right_entries[0].key);
+        let old_right_sibling = leaf.right_sibling.replace(right_id);
+        let high_key = leaf.high_key.replace(separator.clone());
+
+        self.allocate(Node::Leaf(LeafNode {
+            interior_nodes: right_entries,
+            low_fence: Some(separator.clone()),
+            high_key,
+            left_sibling: Some(node_id),
+            right_sibling: old_right_sibling,
+        }));
+        if let Some(Node::Leaf(sibling)) = old_right_sibling.map(|id| &mut self.nodes[id]) {
+            sibling.left_sibling = Some(right_id);
+        }
+        if self.rightmost_leaf == node_id {
+            self.rightmost_leaf = right_id;
+        }
+        tracing::debug!(node = node_id, right = right_id, "leaf split");
+
+        (separator, right_id)
+    }
+
+    // Split an overflowing inner node around its middle key, which moves up to the parent.
+    fn split_inner(&mut self, node_id: PageId) -> (K, PageId) {
+        let right_id = self.next_id();
+        let mid = split_point(&self.sizes(node_id));
+        let Node::Inner(inner) = &mut self.nodes[node_id] else {
+            unreachable!("split_inner called on a leaf")
+        };
+        let mut right_keys = inner.keys.split_off(mid);
+        let separator = right_keys.remove(0);
+        let right_children = inner.children.split_off(mid + 1);
+        let high_key = inner.high_key.replace(separator.clone());
+        let right_link = inner.right_link.replace(right_id);
+
+        self.allocate(Node::Inner(InnerNode {
+            keys: right_keys,
+            children: right_children,
+            low_fence: Some(separator.clone()),
+            high_key,
+            right_link,
+        }));
+        tracing::debug!(node = node_id, right = right_id, "inner node split");
+
+        (separator, right_id)
+    }
+}
+
+// How many of the entries whose sizes are `sizes`, taken in turn, it takes to add up to at
+// least half of `gap`.
+fn at_least_half(sizes: impl Iterator<Item = usize>, gap: usize) -> usize {
+    let mut moved = 0;
+    sizes
+        .take_while(|size| {
+            let short = 2 * moved < gap;
+            moved += size;
+            short
+        })
+        .count()
+}
+
+// How many of the entries whose sizes are `sizes`, taken in turn, add up to no more than
+// half of `gap`.
+fn at_most_half(sizes: impl Iterator<Item = usize>, gap: usize) -> usize {
+    let mut moved = 0;
+    sizes
+        .take_while(|size| {
+            moved += size;
+            2 * moved <= gap
+        })
+        .count()
+}
+
+// Where to split a node whose entries take `sizes`: after as many as take no more than
+// half of it, but at least one and never all of them.
+fn split_point(sizes: &[usize]) -> usize {
+    let total = sizes.iter().sum();
+    at_most_half(sizes.iter().copied(), total).clamp(1, sizes.len() - 1)
+}
+
+// The separator between two neighbouring leaves.
+fn leaf_separator<K: Clone, V>(
+    comparator: &Comparator<K>,
+    left: &LeafNode<K, V>,
+    right: &LeafNode<K, V>,
+) -> K {
+    let first = &right.interior_nodes[0].key;
+    match left.interior_nodes.last() {
+        Some(last) => comparator.separator(&last.key, first),
+        None => first.clone(),
+    }
+}
+
+/* Public Interface - checking */
+
+impl<K: Clone, V> Btree<K, V> {
+    /// Check the tree is a well formed B+tree, for PRAGMA integrity_check and for debugging
+    /// a change to the balancing: every node other than the root at least half full and none
+    /// over full, or for a tree kept on pages none empty and none too big for its page, an
+    /// inner node with one more child than keys, every leaf at the same depth, each node's
+    /// keys in order and within its fences, each child's fences the separators either side
+    /// of it in its parent, and the leaves linked in key order both ways. A tree with a
+    /// split still to be finished, see the module comment, fails the check.
+    pub fn check_invariants(&self) -> Result<()> {
+        let mut leaves = vec![];
+        // the root's fences are whatever it had before it became the root
+        let root_fences = self.nodes[self.root].fences();
+        self.check_node(self.root, root_fences, &mut leaves)?;
+        for (i, id) in leaves.iter().enumerate() {
+            let leaf = self.leaf(*id);
+            let (left, right) = (i.checked_sub(1).map(|i| leaves[i]), leaves.get(i + 1));
+            if leaf.left_sibling != left || leaf.right_sibling != right.copied() {
+                bail!("leaf {id} isn't linked to the leaves either side of it");
+            }
+        }
+        if leaves.last() != Some(&self.rightmost_leaf) {
+            bail!("leaf {} isn't the rightmost leaf", self.rightmost_leaf);
+        }
+        Ok(())
+    }
+
+    // Check the node at `id` and everything under it, given the fences its parent's
+    // separators give it, adding its leaves to `leaves` in order. Returns how many levels
+    // down its leaves are, 1 for a leaf.
+    fn check_node(
+        &self,
+        id: PageId,
+        fences: (Option<&K>, Option<&K>),
+        leaves: &mut Vec<PageId>,
+    ) -> Result<usize> {
+        let node = &self.nodes[id];
+        let compare = |a: &K, b: &K| self.comparator.compare(a, b);
+        let same = |a: Option<&K>, b: Option<&K>| match (a, b) {
+            (Some(a), Some(b)) => compare(a, b).is_eq(),
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        let (low, high) = node.fences();
+        if !same(low, fences.0) || !same(high, fences.1) {
+            bail!("node {id} has fences other than its parent's separators around it");
+        }
+        let count = node.key_count();
+        let load = self.load(id);
+        if load > self.capacity() {
+            match self.room {
+                None => bail!("node {id} has {count} keys, more than {}", self.max_keys()),
+                Some(_) => bail!(
+                    "node {id} takes up {load} bytes, more than the {} it has room for",
+                    self.capacity()
+                ),
+            }
+        }
+        let keys: Vec<&K> = match node {
+            Node::Leaf(leaf) => leaf.interior_nodes.iter().map(|e| &e.key).collect(),
+            Node::Inner(inner) => inner.keys.iter().collect(),
+        };
+        if keys
+            .windows(2)
+            .any(|pair| compare(pair[0], pair[1]).is_ge())
+        {
+            bail!("node {id} has keys out of order");
+        }
+        let in_range = |k: &&K| {
+            low.map_or(true, |l| compare(k, l).is_ge())
+                && high.map_or(true, |h| compare(k, h).is_lt())
+        };
+        if !keys.iter().all(in_range) {
+            bail!("node {id} has keys outside its fences");
+        }
+        let inner = match node {
+            Node::Leaf(_) => {
+                let fewest = match self.room {
+                    None => self.min_keys(),
+                    Some(_) => 1,
+                };
+                if id != self.root && count < fewest {
+                    bail!("leaf {id} has {count} keys, fewer than {fewest}");
+                }
+                leaves.push(id);
+                return Ok(1);
+            }
+            Node::Inner(inner) => inner,
+        };
+        let children = inner.children.len();
+        if children != count + 1 {
+            bail!("inner node {id} has {children} children for {count} keys");
+        }
+        let fewest = match self.room {
+            None if id != self.root => (self.max_keys() + 1).div_ceil(2),
+            _ => 2,
+        };
+        if children < fewest {
+            bail!("inner node {id} has {children} children, fewer than {fewest}");
+        }
+        let mut depth = None;
+        for (i, child) in inner.children.iter().enumerate() {
+            let child_low = if i == 0 { low } else { inner.keys.get(i - 1) };
+            let child_high = inner.keys.get(i).or(high);
+            let child_depth = self.check_node(*child, (child_low, child_high), leaves)?;
+            if depth.is_some_and(|depth| depth != child_depth) {
+                bail!("inner node {id} has leaves at different depths under it");
+            }
+            depth = Some(child_depth);
+        }
+        Ok(depth.unwrap_or(0) + 1)
+    }
+}
+
+/* Public Interface - pages */
+
+// What a page holds, its first byte.
+const LEAF_PAGE: u8 = 1;
+const INNER_PAGE: u8 = 2;
+
+/// How a key or value is written into a node's cell and read back out of it. A cell holds
+/// a key and then its value, so each must know where it ends: a row is a record, see
+/// record.rs, and a rowid a varint.
+pub trait CellData: Sized {
+    fn write_cell(&self, out: &mut Vec<u8>);
+    /// Read it off the front of `input`, leaving what follows.
+    fn read_cell(input: &mut &[u8]) -> Result<Self>;
+
+    /// How many bytes write_cell writes, which a tree kept on pages sizes its nodes by.
+    fn cell_size(&self) -> usize {
+        let mut out = vec![];
+        self.write_cell(&mut out);
+        out.len()
+    }
+}
+
+impl CellData for PageId {
+    fn write_cell(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn read_cell(input: &mut &[u8]) -> Result<Self> {
+        if input.len() < 4 {
+            bail!("database disk image is malformed: a page number runs off the end");
+        }
+        let (page, rest) = input.split_at(4);
+        *input = rest;
+        Ok(PageId::from_be_bytes(page.try_into()?))
+    }
+}
+
+impl CellData for i64 {
+    fn write_cell(&self, out: &mut Vec<u8>) {
+        record::write_varint(*self as u64, out);
+    }
+
+    fn read_cell(input: &mut &[u8]) -> Result<Self> {
+        Ok(record::read_varint(input)? as i64)
+    }
+}
+
+impl CellData for () {
+    fn write_cell(&self, _out: &mut Vec<u8>) {}
+
+    fn read_cell(_input: &mut &[u8]) -> Result<Self> {
+        Ok(())
+    }
+}
+
+// Bytes as they are, after how many there are.
+impl CellData for Vec<u8> {
+    fn write_cell(&self, out: &mut Vec<u8>) {
+        record::write_varint(self.len() as u64, out);
+        out.extend_from_slice(self);
+    }
+
+    fn read_cell(input: &mut &[u8]) -> Result<Self> {
+        let len = record::read_varint(input)? as usize;
+        if len > input.len() {
+            bail!("database disk image is malformed: {len} bytes run off the end");
+        }
+        let (bytes, rest) = input.split_at(len);
+        *input = rest;
+        Ok(bytes.to_vec())
+    }
+
+    fn cell_size(&self) -> usize {
+        record::varint_size(self.len() as u64) + self.len()
+    }
+}
+
+impl<T: CellData> CellData for Option<T> {
+    fn write_cell(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(item) => {
+                out.push(1);
+                item.write_cell(out);
+            }
+        }
+    }
+
+    fn read_cell(input: &mut &[u8]) -> Result<Self> {
+        let Some((&flag, rest)) = input.split_first() else {
+            bail!("database disk image is malformed: a cell runs off the end");
+        };
+        *input = rest;
+        Ok(match flag {
+            0 => None,
+            _ => Some(T::read_cell(input)?),
+        })
+    }
+}
+
+/// Where a tree's pages are kept, a database file as its pager has it: pages to read and
+/// write by number, a new one handed out when a node needs one and given back when it is
+/// done with.
+pub trait TreeStore: PageStore {
+    fn allocate(&mut self) -> Result<PageNumber>;
+    fn free(&mut self, page: PageNumber) -> Result<()>;
+}
+
+// An overflow page's number and its bytes.
+type OverflowPage = (PageNumber, Vec<u8>);
+
+// A node's page. Cell 0 holds where the node sits in the tree: its fence keys, then for a
+// leaf its siblings and for an inner node its right link and last child. The cells after it
+// are a leaf's entries, or an inner node's children each with the key that follows it, the
+// last child kept apart as SQLite keeps an interior page's right-most pointer. The links
+// are to the pages the nodes are on rather than to their numbers in the tree.
+//
+// An inner node's keys are in order, so neighbours tend to begin alike, and each is written
+// as how many of its first bytes are the same as the key before it's, a varint, followed by
+// the rest of its bytes. The node's first key has none in common.
+//
+// A cell too big for the page keeps only its first bytes and spills the rest onto a chain of
+// overflow pages, see overflow.rs, laid out as an index's keys are so that at least four
+// cells fit on a page. Every cell starts with the size of its payload as a varint, which
+// says how much of it is in the cell and whether the first overflow page's number follows.
+impl<K: CellData, V: CellData> Node<K, V> {
+    // The node's page, with its links to the pages `page_of` gives, and the overflow pages
+    // its cells spilled onto, numbered by `allocate`.
+    fn to_page(
+        &self,
+        header: &DatabaseHeader,
+        page_of: &dyn Fn(PageId) -> PageNumber,
+        allocate: &mut dyn FnMut() -> PageNumber,
+    ) -> Result<(SlottedPage, Vec<OverflowPage>)> {
+        let mut page = SlottedPage::with_reserved(header.page_size as usize, header.reserved_bytes);
+        let mut first = vec![];
+        let mut cells = vec![];
+        match self {
+            Node::Leaf(leaf) => {
+                page.set_page_type(LEAF_PAGE);
+                leaf.low_fence.write_cell(&mut first);
+                leaf.high_key.write_cell(&mut first);
+                leaf.left_sibling.map(page_of).write_cell(&mut first);
+                leaf.right_sibling.map(page_of).write_cell(&mut first);
+                for entry in &leaf.interior_nodes {
+                    let mut cell = vec![];
+                    entry.key.write_cell(&mut cell);
+                    entry.value.write_cell(&mut cell);
+                    cells.push(cell);
+                }
+            }
+            Node::Inner(inner) => {
+                page.set_page_type(INNER_PAGE);
+                inner.low_fence.write_cell(&mut first);
+                inner.high_key.write_cell(&mut first);
+                inner.right_link.map(page_of).write_cell(&mut first);
+                page_of(inner.children[inner.keys.len()]).write_cell(&mut first);
+                let mut previous = vec![];
+                for (child, key) in inner.children.iter().zip(&inner.keys) {
+                    let mut bytes = vec![];
+                    key.write_cell(&mut bytes);
+                    let shared = previous
+                        .iter()
+                        .zip(&bytes)
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    let mut cell = vec![];
+                    page_of(*child).write_cell(&mut cell);
+                    record::write_varint(shared as u64, &mut cell);
+                    cell.extend_from_slice(&bytes[shared..]);
+                    cells.push(cell);
+                    previous = bytes;
+                }
+            }
+        }
+        let mut spilled = vec![];
+        let mut spill = |payload: &[u8]| {
+            let usable = header.usable_size();
+            let (local, pages) = overflow::split(payload, usable, PayloadKind::Index, allocate);
+            spilled.extend(pages);
+            let mut cell = vec![];
+            record::write_varint(payload.len() as u64, &mut cell);
+            cell.extend(local);
+            cell
+        };
+        let cells: Vec<Vec<u8>> = [spill(&first)]
+            .into_iter()
+            .chain(cells.iter().map(|cell| spill(cell)))
+            .collect();
+        for (i, cell) in cells.iter().enumerate() {
+            if !page.insert_cell(i, cell) {
+                bail!(
+                    "a node of {} entries doesn't fit on a {} byte page",
+                    cells.len() - 1,
+                    header.page_size
+                );
+            }
+        }
+        Ok((page, spilled))
+    }
+
+    // The node on `page`, its links to pages, and the overflow pages of `store` its cells
+    // spilled onto.
+    fn from_page(
+        page: &SlottedPage,
+        usable_size: usize,
+        store: &mut dyn PageStore,
+    ) -> Result<(Self, Vec<PageNumber>)> {
+        if page.cell_count() == 0 {
+            bail!("a page with no cells");
+        }
+        let mut chains = vec![];
+        let mut payloads = vec![];
+        for i in 0..page.cell_count() {
+            let mut cell = page.cell(i);
+            let size = record::read_varint(&mut cell)? as usize;
+            let (payload, chain) =
+                overflow::join(cell, size, usable_size, PayloadKind::Index, store)?;
+            chains.extend(chain);
+            payloads.push(payload);
+        }
+        let mut first = payloads[0].as_slice();
+        let cells = payloads[1..].iter().map(Vec::as_slice);
+        let node = match page.page_type() {
+            LEAF_PAGE => Node::Leaf(LeafNode {
+                low_fence: Option::read_cell(&mut first)?,
+                high_key: Option::read_cell(&mut first)?,
+                left_sibling: Option::read_cell(&mut first)?,
+                right_sibling: Option::read_cell(&mut first)?,
+                interior_nodes: cells
+                    .map(|mut cell| {
+                        Ok(LeafNodeInterior {
+                            key: K::read_cell(&mut cell)?,
+                            value: V::read_cell(&mut cell)?,
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            }),
+            INNER_PAGE => {
+                let low_fence = Option::read_cell(&mut first)?;
+                let high_key = Option::read_cell(&mut first)?;
+                let right_link = Option::read_cell(&mut first)?;
+                let last_child = PageId::read_cell(&mut first)?;
+                let mut keys = vec![];
+                let mut children = vec![];
+                let mut previous: Vec<u8> = vec![];
+                for mut cell in cells {
+                    children.push(PageId::read_cell(&mut cell)?);
+                    let shared = record::read_varint(&mut cell)? as usize;
+                    if shared > previous.len() {
+                        bail!(
+                            "a key sharing {shared} bytes with one of {}",
+                            previous.len()
+                        );
+                    }
+                    previous.truncate(shared);
+                    previous.extend_from_slice(cell);
+                    keys.push(K::read_cell(&mut previous.as_slice())?);
+                }
+                children.push(last_child);
+                Node::Inner(InnerNode {
+                    keys,
+                    children,
+                    low_fence,
+                    high_key,
+                    right_link,
+                })
+            }
+            kind => bail!("unknown page type {kind}"),
+        };
+        Ok((node, chains))
+    }
+}
+
+impl<K, V> Node<K, V> {
+    // The node with each of its links to another replaced by what `relink` makes of it.
+    fn relink(&mut self, relink: &mut dyn FnMut(PageId) -> Result<PageId>) -> Result<()> {
+        match self {
+            Node::Leaf(leaf) => {
+                for link in [&mut leaf.left_sibling, &mut leaf.right_sibling] {
+                    *link = link.map(&mut *relink).transpose()?;
+                }
+            }
+            Node::Inner(inner) => {
+                inner.right_link = inner.right_link.map(&mut *relink).transpose()?;
+                for child in &mut inner.children {
+                    *child = relink(*child)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The page `old` changed to hold the cells `new` does, in the same order, by taking off
+// the cells `new` doesn't have and putting in those `old` doesn't, the cells both have
+// left where they are. Cells go into the holes others leave, and the page is defragmented
+// when none is big enough. None if `old` isn't laid out as a page should be, or even
+// defragmented hasn't room for the new cells.
+fn edited(old: Vec<u8>, new: &SlottedPage, reserved: u8) -> Option<SlottedPage> {
+    let mut page = SlottedPage::from_bytes(old, reserved).ok()?;
+    let cells = |page: &SlottedPage| -> Vec<Vec<u8>> {
+        (0..page.cell_count())
+            .map(|i| page.cell(i).to_vec())
+            .collect()
+    };
+    let (before, after) = (cells(&page), cells(new));
+    let wanted: HashSet<&Vec<u8>> = after.iter().collect();
+    for (i, cell) in before.iter().enumerate().rev() {
+        if !wanted.contains(cell) {
+            page.remove_cell(i);
+        }
+    }
+    let had: HashSet<&Vec<u8>> = before.iter().collect();
+    for (i, cell) in after.iter().enumerate() {
+        if !had.contains(cell) && !page.insert_cell(i, cell) {
+            return None;
+        }
+    }
+    page.set_page_type(new.page_type());
+    // the cells both have are in key order on both pages, so they end up in place
+    (cells(&page) == after).then_some(page)
+}
+
+/// A page's entries, and the pages it links to.
+pub type PageEntries<K, V> = (Vec<(K, V)>, Vec<PageNumber>);
+
+/// The entries a page of a tree holds if it is a leaf, none if it is an inner node, and
+/// the pages it links to, read on its own rather than as part of the tree, for salvaging
+/// what can be read of a damaged file. The page is `reserved` bytes short of its usable
+/// end, and `store` has the overflow pages its cells spilled onto.
+pub fn read_page<K: CellData, V: CellData>(
+    data: Vec<u8>,
+    reserved: u8,
+    store: &mut dyn PageStore,
+) -> Result<PageEntries<K, V>> {
+    let page = SlottedPage::from_bytes(data, reserved)?;
+    let usable_size = page.as_bytes().len() - reserved as usize;
+    let (node, _) = Node::<K, V>::from_page(&page, usable_size, store)
+        .context("database disk image is malformed")?;
+    Ok(match node {
+        Node::Leaf(leaf) => {
+            let links = [leaf.left_sibling, leaf.right_sibling];
+            let entries = leaf.interior_nodes.into_iter();
+            (
+                entries.map(|e| (e.key, e.value)).collect(),
+                links.into_iter().flatten().collect(),
+            )
+        }
+        Node::Inner(inner) => (
+            vec![],
+            inner.children.into_iter().chain(inner.right_link).collect(),
+        ),
+    })
+}
+
+impl<K: Clone + CellData + 'static, V: CellData + 'static> Btree<K, V> {
+    /// An empty tree to be kept on pages laid out as `header` says, see flush. Its nodes
+    /// are sized by the bytes their cells take up on a page rather than by their number
+    /// of keys, and hold as many as fit beside the cell of fence keys and links every
+    /// node's page starts with, however big that is.
+    pub fn on_pages(header: &DatabaseHeader, comparator: Comparator<K>) -> Self {
+        let usable = header.usable_size();
+        // the bytes a cell with a payload of `size` bytes takes, as to_page writes it
+        let cell = move |size: usize| {
+            let Layout {
+                local,
+                overflow_pages,
+            } = overflow::layout(size, usable, PayloadKind::Index);
+            let link = if overflow_pages > 0 { 4 } else { 0 };
+            page::cell_space(record::varint_size(size as u64) + local + link)
+        };
+        let biggest = page::cell_space(9 + overflow::max_local(usable, PayloadKind::Index) + 4);
+        let bytes = page::cell_room(usable) - biggest;
+        let mut btree = Self::with_comparator((bytes / page::cell_space(1)) as u64, comparator);
+        btree.room = Some(Room {
+            bytes,
+            entry: Arc::new(move |key: &K, value: &V| cell(key.cell_size() + value.cell_size())),
+            // the child's page and a varint of the bytes shared with the key before
+            key: Arc::new(move |key: &K| cell(4 + 1 + key.cell_size())),
+        });
+        btree
+    }
+
+    /// Write every node changed since the last flush to a page of `store`, laid out as
+    /// `header` says pages are, along with the overflow pages of any cell too big for it.
+    /// A node written for the first time is given a page by the store, and one merged away
+    /// gives its page back, overflow pages and all. The root is always written to page
+    /// `root`, so that whatever reads the tree knows where to start: when another node
+    /// becomes the root the two swap pages, and every node is written again with its
+    /// links changed. A flush that fails leaves the store part written, for whatever
+    /// transaction the store is in to be rolled back.
+    pub fn flush(
+        &mut self,
+        store: &mut dyn TreeStore,
+        header: &DatabaseHeader,
+        root: PageNumber,
+    ) -> Result<()> {
+        let free: BTreeSet<PageId> = self.free.iter().copied().collect();
+        if self.nodes.files.get(&self.root) != Some(&root) {
+            let files = &mut self.nodes.files;
+            let had = files
+                .iter()
+                .find(|(_, page)| **page == root)
+                .map(|(id, _)| *id);
+            let old = files.insert(self.root, root);
+            if let Some(id) = had {
+                match old {
+                    Some(page) => files.insert(id, page),
+                    None => files.remove(&id),
+                };
+            }
+            let live = (1..self.nodes.next_page()).filter(|id| !free.contains(id));
+            self.nodes.dirty.extend(live.collect::<Vec<_>>());
+        }
+        for id in &free {
+            let chain = self.nodes.chains.remove(id).unwrap_or_default();
+            for page in self.nodes.files.remove(id).into_iter().chain(chain) {
+                store.free(page)?;
+            }
+            self.nodes.dirty.remove(id);
+        }
+        // every node gets its page before any is written, for the links to them
+        let dirty: Vec<PageId> = self.nodes.dirty.iter().copied().collect();
+        let mut fresh = HashSet::new();
+        for id in &dirty {
+            if !self.nodes.files.contains_key(id) {
+                let page = store.allocate()?;
+                self.nodes.files.insert(*id, page);
+                fresh.insert(*id);
+            }
+        }
+        for id in dirty {
+            self.write_node(id, fresh.contains(&id), store, header)
+                .with_context(|| format!("writing page {}", self.nodes.files[&id]))?;
+            self.nodes.dirty.remove(&id);
+        }
+        Ok(())
+    }
+
+    // Write one node out to its page, its cells spilling onto overflow pages the store
+    // hands out in place of those they spilled onto before. A page that held the node
+    // before, unless it is `fresh` from the store, is changed in place: only the cells
+    // that differ are taken off and the new ones put in, see edited.
+    fn write_node(
+        &mut self,
+        id: PageId,
+        fresh: bool,
+        store: &mut dyn TreeStore,
+        header: &DatabaseHeader,
+    ) -> Result<()> {
+        for page in self.nodes.chains.remove(&id).unwrap_or_default() {
+            store.free(page)?;
+        }
+        let files = &self.nodes.files;
+        let page_of = |id: PageId| files[&id];
+        let mut failed = None;
+        let mut allocate = || {
+            store.allocate().unwrap_or_else(|err| {
+                failed.get_or_insert(err);
+                0
+            })
+        };
+        let (data, spilled) = self.nodes[id].to_page(header, &page_of, &mut allocate)?;
+        if let Some(err) = failed {
+            return Err(err);
+        }
+        let chain: Vec<PageNumber> = spilled.iter().map(|(page, _)| *page).collect();
+        for (page, mut data) in spilled {
+            data.resize(header.page_size as usize, 0);
+            store.write_page(page, &data)?;
+        }
+        if !chain.is_empty() {
+            self.nodes.chains.insert(id, chain);
+        }
+        let page = self.nodes.files[&id];
+        let data = match fresh {
+            true => data,
+            false => edited(store.read_page(page)?, &data, header.reserved_bytes).unwrap_or(data),
+        };
+        store.write_page(page, data.as_bytes())
+    }
+
+    /// The tree flushed to `store` with its root on page `root`, laid out as `header` says
+    /// pages are. Its nodes are read a level at a time from the root down, each level left
+    /// to right and across right links, which reach the new half of a split its parent
+    /// doesn't know about yet.
+    pub fn open(
+        header: &DatabaseHeader,
+        comparator: Comparator<K>,
+        store: &mut dyn PageStore,
+        root: PageNumber,
+    ) -> Result<Self> {
+        let mut btree = Self::on_pages(header, comparator);
+        btree.nodes = Pages::default();
+        let mut ids = HashMap::new();
+        let mut pending = VecDeque::from([root]);
+        while let Some(page) = pending.pop_front() {
+            if page == 0 {
+                bail!("database disk image is malformed: a link to page 0");
+            }
+            if ids.contains_key(&page) {
+                continue;
+            }
+            if page > header.page_count {
+                bail!("database disk image is malformed: a link to page {page} past the end");
+            }
+            let data = store.read_page(page)?;
+            if data.len() != header.page_size as usize {
+                bail!("database disk image is malformed: page {page} is the wrong size");
+            }
+            let (node, chain): (Node<K, V>, _) =
+                SlottedPage::from_bytes(data, header.reserved_bytes)
+                    .and_then(|data| {
+                        Node::from_page(&data, header.usable_size(), store)
+                            .context("database disk image is malformed")
+                    })
+                    .with_context(|| format!("reading page {page}"))?;
+            if let Node::Inner(inner) = &node {
+                pending.extend(&inner.children);
+            }
+            pending.extend(node.right_link());
+            let id = btree.nodes.push(node);
+            ids.insert(page, id);
+            btree.nodes.files.insert(id, page);
+            if !chain.is_empty() {
+                btree.nodes.chains.insert(id, chain);
+            }
+        }
+        // the nodes link to each other by their numbers in the tree rather than their pages
+        for node in &mut btree.nodes.nodes {
+            node.relink(&mut |page| match ids.get(&page) {
+                Some(id) => Ok(*id),
+                None => bail!("database disk image is malformed: a link to page {page}"),
+            })?;
+        }
+        btree.nodes.dirty.clear();
+        btree.root = ids[&root];
+        let mut rightmost = btree.root;
+        while let Node::Inner(inner) = &btree.nodes[rightmost] {
+            rightmost = inner.children[inner.children.len() - 1];
+        }
+        while let Some(right) = btree.nodes[rightmost].right_link() {
+            rightmost = right;
+        }
+        btree.rightmost_leaf = rightmost;
+        Ok(btree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collation::Collation;
+    use crate::sql_parser::ast::ColVal;
+    use crate::storage::pager::PagerConfig;
+    use quickcheck::QuickCheck;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+    /*
+        Unit Tests
+    */
+
+    // Walk the leaves left to right via their sibling pointers.
+    fn leaf_keys<K: Clone, V>(btree: &Btree<K, V>) -> Vec<K> {
+        let mut node_id = btree.root;
+        while let Node::Inner(inner) = &btree.nodes[node_id] {
+            node_id = inner.children[0];
+        }
+        let mut keys = vec![];
+        let mut leaf_id = Some(node_id);
+        while let Some(Node::Leaf(leaf)) = leaf_id.map(|id| &btree.nodes[id]) {
+            keys.extend(leaf.interior_nodes.iter().map(|e| e.key.clone()));
+            leaf_id = leaf.right_sibling;
+        }
+        keys
+    }
+
+    // Check every node's keys lie within its fences, and that each child's fences are the
+    // parent's separators around it.
+    fn check_fences<K: Clone + PartialEq + std::fmt::Debug, V>(
+        btree: &Btree<K, V>,
+        node_id: PageId,
+    ) {
+        let node = &btree.nodes[node_id];
+        let (low, high) = node.fences();
+        let compare = |a: &K, b: &K| btree.comparator.compare(a, b);
+        let in_range = |k: &K| {
+            low.map_or(true, |l| compare(k, l).is_ge())
+                && high.map_or(true, |h| compare(k, h).is_lt())
+        };
+        match node {
+            Node::Leaf(leaf) => assert!(leaf.interior_nodes.iter().all(|e| in_range(&e.key))),
+            Node::Inner(inner) => {
+                assert!(inner.keys.iter().all(in_range));
+                for (i, child) in inner.children.iter().enumerate() {
+                    let child_low = if i == 0 { low } else { inner.keys.get(i - 1) };
+                    let child_high = inner.keys.get(i).or(high);
+                    assert_eq!(btree.nodes[*child].fences(), (child_low, child_high));
+                    check_fences(btree, *child);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn new_btree_inits_correctly_with_single_key_value() {
+        let interior_node_count: u64 = 2;
+        let key: u8 = 1;
+        let value: u8 = 2;
+        let mut init_btree: Btree<u8, u8> = Btree::empty(interior_node_count);
+        init_btree.insert(key, value);
+        let mut nodes = Pages::default();
+        nodes.push(Node::Leaf(LeafNode {
+            interior_nodes: vec![LeafNodeInterior { key, value }],
+            low_fence: None,
+            high_key: None,
+            left_sibling: None,
+            right_sibling: None,
+        }));
+        let expected_btree = Btree {
+            interior_node_count,
+            root: 1,
+            nodes,
+            rightmost_leaf: 1,
+            comparator: Comparator::binary(),
+            free: vec![],
+            room: None,
+        };
+
+        assert_eq!(init_btree, expected_btree);
+    }
+
+    #[test]
+    fn inserting_past_capacity_splits_the_root() {
+        let mut btree: Btree<u8, u8> = Btree::empty(2);
+        for k in [10, 20, 30] {
+            btree.insert(k, k);
+        }
+
+        // [10] [20 30] under a root of [20]
+        let Node::Inner(root) = &btree.nodes[btree.root] else {
+            panic!("root should have become an inner node")
+        };
+        assert_eq!(root.keys, vec![20]);
+        assert_eq!(root.children.len(), 2);
+        assert_eq!(leaf_keys(&btree), vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn many_inserts_in_any_order_keep_leaves_sorted() {
+        let mut btree: Btree<u32, u32> = Btree::empty(3);
+        // a permutation of 0..100
+        let keys: Vec<u32> = (0..100).map(|i| (i * 37) % 100).collect();
+        for k in &keys {
+            assert_eq!(btree.insert(*k, k * 2), None);
+        }
+
+        assert_eq!(leaf_keys(&btree), (0..100).collect::<Vec<_>>());
+        btree.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn an_interrupted_split_is_followed_and_then_finished() {
+        let mut btree: Btree<u32, u32> = Btree::empty(4);
+        for k in 0..20 {
+            btree.insert(k * 10, k);
+        }
+        // the leaf holding 100 splits but the parent never hears of it
+        let leaf = btree.find_leaf(&100);
+        let (separator, right) = btree.split_leaf(leaf);
+        assert_eq!(btree.find_leaf(&separator), right);
+        for k in 0..20 {
+            assert_eq!(btree.lower_bound(&(k * 10)), Some((&(k * 10), &k)));
+        }
+
+        // the next insert that passes by adds the missing separator to the parent
+        btree.insert(105, 0);
+        btree.check_invariants().unwrap();
+        assert_eq!(leaf_keys(&btree).len(), 21);
+
+        // a root that split without growing a new root is finished the same way
+        let mut btree: Btree<u32, u32> = Btree::empty(4);
+        for k in 0..4 {
+            btree.insert(k, k);
+        }
+        btree.split_leaf(btree.root);
+        assert_eq!(btree.lower_bound(&3), Some((&3, &3)));
+        btree.insert(10, 10);
+        // appended to the rightmost leaf without passing the root, so the split is still
+        // to be finished and the tree isn't yet one check_invariants accepts
+        check_fences(&btree, btree.root);
+        assert!(btree.check_invariants().is_err());
+        assert_eq!(leaf_keys(&btree), vec![0, 1, 2, 3, 10]);
+    }
+
+    fn leaf_count<K: Ord, V>(btree: &Btree<K, V>) -> usize {
+        (1..)
+            .zip(&btree.nodes.nodes)
+            .filter(|(id, n)| matches!(n, Node::Leaf(_)) && !btree.free.contains(id))
+            .count()
+    }
+
+    #[test]
+    fn overflow_is_handed_to_a_sibling_before_splitting() {
+        // [0 10] [20 30 40] under [20], then 25 overflows the right leaf while its left
+        // sibling has room
+        let mut btree: Btree<u32, u32> = Btree::empty(4);
+        for k in [0, 10, 20, 30, 40, 50] {
+            btree.insert(k, k);
+        }
+        btree.delete(&50);
+        assert_eq!(leaf_count(&btree), 2);
+        btree.insert(25, 25);
+        btree.insert(35, 35);
+        assert_eq!(leaf_count(&btree), 2);
+        let Node::Inner(root) = &btree.nodes[btree.root] else {
+            panic!("root should be an inner node")
+        };
+        assert_eq!(root.keys, vec![25]);
+        btree.check_invariants().unwrap();
+        assert_eq!(leaf_keys(&btree), vec![0, 10, 20, 25, 30, 35, 40]);
+
+        // inserting in key order leaves every leaf but the last two full
+        let mut btree: Btree<u32, u32> = Btree::empty(4);
+        for k in 0..1000 {
+            btree.insert(k, k);
+        }
+        assert!(leaf_count(&btree) <= 1000 / 4 + 1);
+        btree.check_invariants().unwrap();
+        assert_eq!(leaf_keys(&btree), (0..1000).collect::<Vec<_>>());
+
+        // and in reverse order keys go to the right instead
+        let mut btree: Btree<u32, u32> = Btree::empty(4);
+        for k in (0..1000).rev() {
+            btree.insert(k, k);
+        }
+        assert!(leaf_count(&btree) <= 1000 / 4 + 1);
+        btree.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn keys_in_order_are_appended_to_the_rightmost_leaf() {
+        let mut btree: Btree<u32, u32> = Btree::empty(4);
+        for k in 0..100 {
+            btree.insert(k * 2, k);
+            assert_eq!(btree.rightmost_leaf, btree.find_leaf(&(k * 2)));
+        }
+        btree.check_invariants().unwrap();
+
+        // the leaf is remembered across deletes, and a key past the end still appends
+        btree.delete(&198);
+        btree.delete(&196);
+        btree.insert(197, 0);
+        assert_eq!(btree.rightmost_leaf, btree.find_leaf(&197));
+
+        // keys out of order, or already present, take the usual way down
+        btree.insert(51, 0);
+        assert_eq!(btree.insert(197, 1), Some(0));
+        btree.check_invariants().unwrap();
+        let mut expected: Vec<u32> = (0..98).map(|k| k * 2).collect();
+        expected.extend([51, 197]);
+        expected.sort();
+        assert_eq!(leaf_keys(&btree), expected);
+    }
+
+    #[test]
+    fn inserting_an_existing_key_replaces_its_value() {
+        let mut btree: Btree<u8, &str> = Btree::empty(2);
+        btree.insert(1, "a");
+        assert_eq!(btree.insert(1, "b"), Some("a"));
+        assert_eq!(btree.lower_bound(&1), Some((&1, &"b")));
+    }
+
+    #[test]
+    fn delete_removes_only_the_given_key() {
+        let mut btree: Btree<u32, u32> = Btree::empty(2);
+        for k in 0..20 {
+            btree.insert(k, k);
+        }
+
+        assert_eq!(btree.delete(&7), Some(7));
+        assert_eq!(btree.delete(&7), None);
+        assert_eq!(
+            leaf_keys(&btree),
+            (0..20).filter(|k| *k != 7).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn find_visits_one_node_per_level() {
+        let mut btree: Btree<u32, u32> = Btree::empty(3);
+        for k in (0..1000).map(|i| (i * 37) % 1000) {
+            btree.insert(k * 2, k);
+        }
+        let height = btree.height();
+        assert!(height > 3);
+
+        for k in 0..1000 {
+            assert_eq!(btree.find(&(k * 2)), Some(&k));
+            assert_eq!(btree.find(&(k * 2 + 1)), None);
+            assert_eq!(btree.descend(&(k * 2)).1, height);
+        }
+        assert_eq!(Btree::<u32, u32>::empty(3).find(&0), None);
+    }
+
+    #[test]
+    fn lower_bound_crosses_into_the_next_leaf() {
+        let mut btree: Btree<u32, ()> = Btree::empty(2);
+        for k in [10, 20, 30, 40, 50] {
+            btree.insert(k, ());
+        }
+
+        assert_eq!(btree.lower_bound(&0).map(|(k, _)| *k), Some(10));
+        assert_eq!(btree.lower_bound(&21).map(|(k, _)| *k), Some(30));
+        assert_eq!(btree.lower_bound(&50).map(|(k, _)| *k), Some(50));
+        assert_eq!(btree.lower_bound(&51), None);
+
+        // an emptied leaf is skipped over via the sibling links
+        btree.delete(&30);
+        assert_eq!(btree.lower_bound(&21).map(|(k, _)| *k), Some(40));
+    }
+
+    #[test]
+    fn ranges_walk_the_leaves_in_order() {
+        let mut btree: Btree<u32, u32> = Btree::empty(3);
+        for k in (0..200).map(|i| (i * 37) % 200) {
+            btree.insert(k * 2, k);
+        }
+        let keys = |range: Range<u32, u32>| range.map(|(k, _)| *k).collect::<Vec<_>>();
+
+        assert_eq!(
+            keys(btree.range(..)),
+            (0..200).map(|k| k * 2).collect::<Vec<_>>()
+        );
+        assert_eq!(keys(btree.range(10..16)), [10, 12, 14]);
+        assert_eq!(keys(btree.range(9..=16)), [10, 12, 14, 16]);
+        assert_eq!(
+            keys(btree.range((Bound::Excluded(10), Bound::Included(14)))),
+            [12, 14]
+        );
+        assert_eq!(keys(btree.range(395..)), [396, 398]);
+        assert_eq!(keys(btree.range(..3)), [0, 2]);
+        assert!(keys(btree.range(400..)).is_empty());
+        assert!(keys(btree.range(11..12)).is_empty());
+        assert_eq!(
+            btree.range(100..).next(),
+            Some((&100, &50)),
+            "values come with their keys"
+        );
+
+        // leaves emptied by deletes are stepped over
+        for k in 20..180 {
+            btree.delete(&(k * 2));
+        }
+        assert_eq!(keys(btree.range(34..364)), [34, 36, 38, 360, 362]);
+    }
+
+    #[test]
+    fn cursors_move_both_ways_across_leaves() {
+        let mut btree: Btree<u32, u32> = Btree::empty(3);
+        for k in (0..100).map(|i| (i * 37) % 100) {
+            btree.insert(k * 2, k);
+        }
+        // a run of deletes, merging leaves away from under where the cursor will go
+        for k in 10..40 {
+            btree.delete(&(k * 2));
+        }
+        let expected: Vec<u32> = (0..100)
+            .filter(|k| !(10..40).contains(k))
+            .map(|k| k * 2)
+            .collect();
+
+        let mut cursor = btree.cursor();
+        assert_eq!(cursor.current(), None);
+        let mut forwards = vec![];
+        let mut on = cursor.first();
+        while on {
+            forwards.push(*cursor.current().unwrap().0);
+            on = cursor.next();
+        }
+        assert_eq!(forwards, expected);
+        assert!(!cursor.next(), "a cursor off the end stays off it");
+        assert!(!Btree::<u32, u32>::empty(3).cursor().first());
+
+        // a scan reads on from wherever the cursor is
+        let mut cursor = btree.cursor();
+        cursor.first();
+        cursor.next();
+        let scanned: Vec<u32> = cursor.entries().map(|(k, _)| *k).collect();
+        assert_eq!(scanned, expected[1..]);
+    }
+
+    #[test]
+    fn deleting_at_a_cursor_moves_it_on() {
+        let mut btree: Btree<u16, u16> = Btree::empty(3);
+        for k in 0..300 {
+            btree.insert(k, k);
+        }
+        let mut cursor = btree.cursor_mut();
+        // delete every key divisible by 3, each leaving the cursor on the key after it
+        for k in (0..300).step_by(3) {
+            assert!(cursor.seek(&k));
+            assert_eq!(cursor.delete(), Some((k, k)));
+            assert_eq!(cursor.current(), Some((&(k + 1), &(k + 1))));
+        }
+        assert!(!cursor.seek(&300));
+        assert_eq!(cursor.current(), None);
+        let expected: BTreeMap<u16, u16> =
+            (0..300).filter(|k| k % 3 != 0).map(|k| (k, k)).collect();
+        check_tree(&btree, &expected);
+    }
+
+    #[test]
+    fn bulk_loading_builds_the_tree_bottom_up() {
+        for (fanout, count) in [(2, 1), (2, 7), (3, 100), (4, 1000), (5, 26)] {
+            let entries = (0..count).map(|k| (k * 2, k * 3));
+            let btree = Btree::bulk_load(fanout, Comparator::binary(), entries);
+            let expected: BTreeMap<u16, u16> = (0..count).map(|k| (k * 2, k * 3)).collect();
+            check_tree(&btree, &expected);
+            // leaves as full as they can be, with none less than half full
+            assert_eq!(
+                leaf_count(&btree),
+                (count as usize).div_ceil(fanout as usize)
+            );
+            assert_eq!(btree.find(&(count * 2 - 2)), Some(&(count * 3 - 3)));
+        }
+
+        // and it goes on as a tree like any other
+        let mut btree = Btree::bulk_load(3, Comparator::binary(), (0..50).map(|k| (k * 2, k)));
+        let mut expected: BTreeMap<u16, u16> = (0..50).map(|k| (k * 2, k)).collect();
+        for k in (1..100).step_by(4) {
+            btree.insert(k, k);
+            expected.insert(k, k);
+        }
+        for k in (0..100).step_by(3) {
+            assert_eq!(btree.delete(&k), expected.remove(&k));
+        }
+        check_tree(&btree, &expected);
+
+        let empty: Btree<u16, u16> = Btree::bulk_load(3, Comparator::binary(), []);
+        check_tree(&empty, &BTreeMap::new());
+    }
+
+    impl CellData for u16 {
+        fn write_cell(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&self.to_be_bytes());
+        }
+
+        fn read_cell(input: &mut &[u8]) -> Result<Self> {
+            let (n, rest) = input.split_at(2);
+            *input = rest;
+            Ok(u16::from_be_bytes(n.try_into()?))
+        }
+    }
+
+    // Pages kept by number, counting the writes, handed out lowest free first.
+    #[derive(Default)]
+    struct Store {
+        pages: HashMap<PageNumber, Vec<u8>>,
+        free: BTreeSet<PageNumber>,
+        writes: usize,
+    }
+
+    impl Store {
+        // A header for pages of `page_size` bytes, saying the store has as many as it has.
+        fn header(&self, page_size: i64) -> DatabaseHeader {
+            let mut config = PagerConfig::default();
+            config.set_page_size(page_size);
+            let mut header = DatabaseHeader::new(&config);
+            header.page_count = self.pages.len() as PageNumber;
+            header
+        }
+    }
+
+    impl PageStore for Store {
+        fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+            match self.pages.get(&page) {
+                Some(data) => Ok(data.clone()),
+                None => bail!("no page {page}"),
+            }
+        }
+
+        fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+            self.writes += 1;
+            self.pages.insert(page, data.to_vec());
+            Ok(())
+        }
+    }
+
+    impl TreeStore for Store {
+        fn allocate(&mut self) -> Result<PageNumber> {
+            if let Some(page) = self.free.pop_first() {
+                return Ok(page);
+            }
+            let page = self.pages.len() as PageNumber + 1;
+            self.pages.insert(page, vec![]);
+            Ok(page)
+        }
+
+        fn free(&mut self, page: PageNumber) -> Result<()> {
+            if !self.free.insert(page) {
+                bail!("page {page} freed twice");
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trees_read_back_from_their_pages() {
+        let mut store = Store::default();
+        let root = store.allocate().unwrap();
+        let header = store.header(512);
+        let mut btree: Btree<u16, u16> = Btree::on_pages(&header, Comparator::binary());
+        btree.flush(&mut store, &header, root).unwrap();
+        // the root keeps its page as the tree grows taller
+        let mut expected = BTreeMap::new();
+        for k in (0..2000u32).map(|k| (k * 37 % 2000) as u16) {
+            btree.insert(k, k + 1);
+            expected.insert(k, k + 1);
+        }
+        check_tree(&btree, &expected);
+        assert!(btree.height() > 2);
+        btree.flush(&mut store, &header, root).unwrap();
+        // every node is on a page of its own
+        assert_eq!(store.pages.len(), btree.nodes.next_page() as usize - 1);
+        assert!(store.pages.values().all(|page| page.len() == 512));
+
+        let header = store.header(512);
+        let mut opened = Btree::open(&header, Comparator::binary(), &mut store, root).unwrap();
+        check_tree(&opened, &expected);
+        assert!(opened.nodes.dirty.is_empty());
+        assert_eq!(opened.rightmost_leaf, opened.find_leaf(&1999));
+
+        // merges give their pages back, for the next node to take
+        for k in (0..1500).filter(|k| k % 4 != 0) {
+            opened.delete(&k);
+            expected.remove(&k);
+        }
+        opened.flush(&mut store, &header, root).unwrap();
+        let live = opened.nodes.next_page() as usize - 1 - opened.free.len();
+        assert_eq!(store.pages.len() - store.free.len(), live);
+        let header = store.header(512);
+        let mut opened = Btree::open(&header, Comparator::binary(), &mut store, root).unwrap();
+        check_tree(&opened, &expected);
+
+        // only what changes is written again
+        let writes = store.writes;
+        opened.insert(1, 2);
+        opened.flush(&mut store, &header, root).unwrap();
+        assert_eq!(store.writes, writes + 1);
+
+        // a split the parent never heard of is still found by its right link
+        let leaf = opened.find_leaf(&1999);
+        opened.split_leaf(leaf);
+        opened.flush(&mut store, &header, root).unwrap();
+        let header = store.header(512);
+        let reopened: Btree<u16, u16> =
+            Btree::open(&header, Comparator::binary(), &mut store, root).unwrap();
+        assert_eq!(reopened.find(&1999), Some(&2000));
+        assert_eq!(
+            reopened.nodes[reopened.rightmost_leaf],
+            opened.nodes[opened.rightmost_leaf]
+        );
+
+        let open =
+            |store: &mut Store| Btree::<u16, u16>::open(&header, Comparator::binary(), store, root);
+        store.pages.insert(root, vec![9]);
+        assert!(open(&mut store).is_err());
+        // a page laid out right but of no kind of node
+        let mut page = SlottedPage::with_reserved(512, 0);
+        page.insert_cell(0, &[0]);
+        store.pages.insert(root, page.as_bytes().to_vec());
+        assert_eq!(
+            format!("{:#}", open(&mut store).unwrap_err()),
+            format!("reading page {root}: database disk image is malformed: unknown page type 0")
+        );
+        // a header that says the database is smaller than the tree
+        let mut short = header;
+        short.page_count = 0;
+        let opened = Btree::<u16, u16>::open(&short, Comparator::binary(), &mut store, root);
+        assert_eq!(
+            opened.unwrap_err().to_string(),
+            format!("database disk image is malformed: a link to page {root} past the end")
+        );
+    }
+
+    #[test]
+    fn separators_are_cut_short_and_share_their_prefixes() {
+        let collations = [Collation::binary()];
+        let plain = Comparator::new("BINARY", move |a: &Vec<ColVal>, b: &Vec<ColVal>| {
+            Collation::compare_rows(&collations, a, b)
+        });
+        let short = plain.clone().with_separator(|a, b| {
+            Collation::separate_rows(&[Collation::binary()], a, b).unwrap_or_else(|| b.clone())
+        });
+        let email = |i: i64| vec![ColVal::String(format!("customer-{i:06}@example.com"))];
+        // the bytes the cells of a tree's inner pages take up
+        let inner_bytes = |comparator: Comparator<Vec<ColVal>>| {
+            let mut store = Store::default();
+            let root = store.allocate().unwrap();
+            let header = store.header(4096);
+            let mut btree = Btree::on_pages(&header, comparator);
+            for i in (0..20_000).map(|i| i * 7919 % 20_000) {
+                btree.insert(email(i), i);
+            }
+            btree.flush(&mut store, &header, root).unwrap();
+            let used: usize = store
+                .pages
+                .values()
+                .map(|data| SlottedPage::from_bytes(data.clone(), 0).unwrap())
+                .filter(|page| page.page_type() == INNER_PAGE)
+                .map(|page| header.usable_size() - page.free_space())
+                .sum();
+            (btree, store, used)
+        };
+        let (plain_tree, _, plain_bytes) = inner_bytes(plain);
+        let (btree, mut store, short_bytes) = inner_bytes(short.clone());
+        // the inner pages of the second hold more children each, in fewer bytes
+        assert!(btree.height() <= plain_tree.height());
+        assert!(
+            short_bytes * 3 < plain_bytes * 2,
+            "{short_bytes} of {plain_bytes}"
+        );
+        let Node::Inner(root) = &btree.nodes[btree.root] else {
+            panic!("20000 keys need more than a leaf");
+        };
+        let ColVal::String(first) = &root.keys[0][0] else {
+            panic!("a separator of text keys is text");
+        };
+        assert!(!first.contains('@'), "{first}");
+
+        let header = store.header(4096);
+        let opened: Btree<Vec<ColVal>, i64> = Btree::open(&header, short, &mut store, 1).unwrap();
+        opened.check_invariants().unwrap();
+        assert_eq!(opened.range(..).count(), 20_000);
+        assert_eq!(opened.find(&email(12_345)), Some(&12_345));
+    }
+
+    #[test]
+    fn nodes_too_big_for_a_page_are_not_written() {
+        // a tree sized by its number of keys, rather than made for pages
+        let mut btree: Btree<u16, u16> = Btree::empty(64);
+        for k in 0..64 {
+            btree.insert(k, k);
+        }
+        let mut store = Store::default();
+        let root = store.allocate().unwrap();
+        let (small, big) = (store.header(512), store.header(4096));
+        assert_eq!(
+            format!("{:#}", btree.flush(&mut store, &small, root).unwrap_err()),
+            "writing page 1: a node of 64 entries doesn't fit on a 512 byte page"
+        );
+        // and the page is still to be written
+        assert_eq!(btree.nodes.dirty, BTreeSet::from([1]));
+        btree.flush(&mut store, &big, root).unwrap();
+        assert!(btree.nodes.dirty.is_empty());
+    }
+
+    #[test]
+    fn cells_too_big_for_a_page_spill_onto_overflow_pages() {
+        let row = |k: i64, len| vec![ColVal::Int(k), ColVal::String("x".repeat(len))];
+        let mut store = Store::default();
+        let root = store.allocate().unwrap();
+        let header = store.header(4096);
+        let mut btree: Btree<i64, Vec<ColVal>> = Btree::on_pages(&header, Comparator::binary());
+        for k in 0..8 {
+            btree.insert(k, row(k, 10));
+        }
+        btree.insert(3, row(3, 10_000));
+        btree.flush(&mut store, &header, root).unwrap();
+        let chain = btree.nodes.chains[&btree.find_leaf(&3)].clone();
+        assert_eq!(chain, [2, 3, 4]);
+
+        let header = store.header(4096);
+        let opened: Btree<i64, Vec<ColVal>> =
+            Btree::open(&header, Comparator::binary(), &mut store, root).unwrap();
+        assert_eq!(opened.find(&3), Some(&row(3, 10_000)));
+        assert_eq!(opened.nodes.chains, btree.nodes.chains);
+
+        // a leaf written again gives its old chain back to be used again
+        btree.insert(3, row(3, 6_000));
+        btree.flush(&mut store, &header, root).unwrap();
+        let shorter = &btree.nodes.chains[&btree.find_leaf(&3)];
+        assert_eq!(shorter, &[2, 3]);
+        assert_eq!(store.free, BTreeSet::from([4]));
+        btree.insert(3, row(3, 10));
+        btree.flush(&mut store, &header, root).unwrap();
+        assert!(btree.nodes.chains.is_empty());
+        assert_eq!(store.free, BTreeSet::from_iter(chain));
+        let opened: Btree<i64, Vec<ColVal>> =
+            Btree::open(&header, Comparator::binary(), &mut store, root).unwrap();
+        assert_eq!(opened.find(&3), Some(&row(3, 10)));
+    }
+
+    // Where on its page each cell is.
+    fn cell_offsets(page: &SlottedPage) -> Vec<usize> {
+        let start = page.as_bytes().as_ptr() as usize;
+        (0..page.cell_count())
+            .map(|i| page.cell(i).as_ptr() as usize - start)
+            .collect()
+    }
+
+    #[test]
+    fn changed_nodes_are_edited_in_place() {
+        let mut store = Store::default();
+        let root = store.allocate().unwrap();
+        let header = store.header(4096);
+        let mut btree: Btree<u16, Vec<u8>> = Btree::on_pages(&header, Comparator::binary());
+        for k in 0..20 {
+            btree.insert(k, vec![k as u8; 30]);
+        }
+        btree.flush(&mut store, &header, root).unwrap();
+        let read = |store: &Store| SlottedPage::from_bytes(store.pages[&root].clone(), 0).unwrap();
+        let before = cell_offsets(&read(&store));
+
+        // the cells either side of one taken off stay where they were
+        btree.delete(&5);
+        btree.flush(&mut store, &header, root).unwrap();
+        let mut expected = before.clone();
+        let hole = expected.remove(6);
+        assert_eq!(cell_offsets(&read(&store)), expected);
+
+        // and a cell put on goes in the hole it left
+        btree.insert(20, vec![20; 30]);
+        btree.flush(&mut store, &header, root).unwrap();
+        expected.push(hole);
+        assert_eq!(cell_offsets(&read(&store)), expected);
+
+        let opened: Btree<u16, Vec<u8>> =
+            Btree::open(&header, Comparator::binary(), &mut store, root).unwrap();
+        assert_eq!(leaf_keys(&opened), leaf_keys(&btree));
+    }
+
+    #[test]
+    fn pages_written_again_are_defragmented_when_their_holes_are_too_small() {
+        let mut store = Store::default();
+        let root = store.allocate().unwrap();
+        let header = store.header(512);
+        let mut btree: Btree<u16, Vec<u8>> = Btree::on_pages(&header, Comparator::binary());
+        for k in 0..10 {
+            btree.insert(k, vec![k as u8; 30]);
+        }
+        btree.flush(&mut store, &header, root).unwrap();
+        for k in (0..10).step_by(2) {
+            btree.delete(&k);
+        }
+        btree.flush(&mut store, &header, root).unwrap();
+        // the first bigger cell takes most of what is left of the gap and the second fits
+        // in none of the holes
+        for k in 10..12 {
+            btree.insert(k, vec![k as u8; 60]);
+            btree.flush(&mut store, &header, root).unwrap();
+        }
+        assert_eq!(btree.height(), 1);
+        let page = SlottedPage::from_bytes(store.pages[&root].clone(), 0).unwrap();
+        // so the page was defragmented, its cells packed up against its end
+        let packed: usize = (0..page.cell_count()).map(|i| page.cell(i).len() + 2).sum();
+        assert_eq!(
+            cell_offsets(&page).into_iter().min().unwrap() - 2,
+            512 - packed
+        );
+        let opened: Btree<u16, Vec<u8>> =
+            Btree::open(&header, Comparator::binary(), &mut store, root).unwrap();
+        assert_eq!(leaf_keys(&opened), leaf_keys(&btree));
+    }
+
+    #[test]
+    fn rebuilt_trees_are_compact() {
+        let mut btree: Btree<u16, u16> = Btree::empty(4);
+        let mut expected = BTreeMap::new();
+        for k in (0..300).map(|k| k * 7 % 300) {
+            btree.insert(k, k);
+            expected.insert(k, k);
+        }
+        for k in (0..300).filter(|k| k % 5 != 0) {
+            btree.delete(&k);
+            expected.remove(&k);
+        }
+        let rebuilt = btree.rebuilt();
+        check_tree(&rebuilt, &expected);
+        assert!(rebuilt.free.is_empty());
+        // 60 keys in 15 full leaves, then 3 inner nodes of 5 children each and the root
+        assert_eq!(rebuilt.nodes.next_page() - 1, 19);
+        assert!(rebuilt.nodes.next_page() < btree.nodes.next_page());
+        let mut leaf = rebuilt.find_leaf(&0);
+        for page in 1..=15 {
+            assert_eq!(leaf, page);
+            if let Node::Leaf(node) = &rebuilt.nodes[leaf] {
+                leaf = node.right_sibling.unwrap_or(0);
+            }
+        }
+    }
+
+    #[test]
+    fn keys_are_ordered_by_the_trees_comparator() {
+        let by_length = Comparator::new("BY_LENGTH", |a: &String, b: &String| {
+            a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+        });
+        let mut btree = Btree::with_comparator(2, by_length);
+        for word in ["ccc", "a", "bbbb", "dd", "eeeee", "f"] {
+            btree.insert(word.to_string(), ());
+        }
+        btree.check_invariants().unwrap();
+        assert_eq!(leaf_keys(&btree), ["a", "f", "dd", "ccc", "bbbb", "eeeee"]);
+        assert_eq!(
+            btree
+                .lower_bound(&"zz".to_string())
+                .map(|(k, _)| k.as_str()),
+            Some("ccc")
+        );
+        assert_eq!(btree.delete(&"dd".to_string()), Some(()));
+        assert_eq!(format!("{:?}", btree.comparator()), "BY_LENGTH");
+        // the default comparator is the key type's own ordering
+        assert_eq!(
+            format!("{:?}", Btree::<u32, ()>::empty(2).comparator()),
+            "BINARY"
+        );
+    }
+
+    /*
+         Property Based Tests (PBTs)
+
+         According to Knuth's Definition a B Tree has the following properties.
+
+             1. Every node has at most m children.
+             2. Every node, except for the root and the leaves, has at least ⌈m/2⌉ children.
+             3. The root node has at least two children unless it is a leaf.
+             4. All leaves appear on the same level.
+             5. A non-leaf node with k children contains k−1 keys.
+
+          Btree::check_invariants checks them, with m the most children a node may have,
+          along with the order of the keys and the links between leaves. Lets Test that
+          they hold whatever is inserted and deleted with PBT.
+    */
+
+    // Every node is either in the tree or free to reuse, none is both.
+    fn reachable<K, V>(btree: &Btree<K, V>, node_id: PageId, found: &mut Vec<PageId>) {
+        found.push(node_id);
+        if let Node::Inner(inner) = &btree.nodes[node_id] {
+            for child in &inner.children {
+                reachable(btree, *child, found);
+            }
+        }
+    }
+
+    fn check_tree(btree: &Btree<u16, u16>, expected: &BTreeMap<u16, u16>) {
+        btree.check_invariants().unwrap();
+        assert_eq!(
+            leaf_keys(btree),
+            expected.keys().copied().collect::<Vec<_>>()
+        );
+        let mut found = vec![];
+        reachable(btree, btree.root, &mut found);
+        found.extend(&btree.free);
+        found.sort();
+        assert_eq!(found, (1..btree.nodes.next_page()).collect::<Vec<_>>());
+        let Node::Leaf(rightmost) = &btree.nodes[btree.rightmost_leaf] else {
+            panic!("the rightmost leaf should be a leaf")
+        };
+        assert_eq!(rightmost.right_sibling, None);
+        assert_eq!(rightmost.high_key, None);
+    }
+
+    // Insert the keys, then delete the keys in `deletes` one at a time, checking the tree
+    // against a BTreeMap after each.
+    fn deletes_keep_the_properties(keys: Vec<u16>, deletes: Vec<u16>, fanout: u8) -> bool {
+        let mut btree: Btree<u16, u16> = Btree::empty(2 + fanout as u64 % 6);
+        let mut expected = BTreeMap::new();
+        for k in keys {
+            btree.insert(k, k.wrapping_mul(3));
+            expected.insert(k, k.wrapping_mul(3));
+        }
+        check_tree(&btree, &expected);
+        for k in deletes {
+            // mostly keys that are there
+            let k = expected
+                .keys()
+                .nth(k as usize % (expected.len() + 1))
+                .copied()
+                .unwrap_or(k);
+            assert_eq!(btree.delete(&k), expected.remove(&k));
+            check_tree(&btree, &expected);
+            assert_eq!(btree.find(&k), None);
+        }
+        expected.iter().all(|(k, v)| btree.find(k) == Some(v))
+    }
+
+    #[test]
+    fn random_deletes_keep_the_tree_a_btree() {
+        QuickCheck::new()
+            .tests(300)
+            .quickcheck(deletes_keep_the_properties as fn(Vec<u16>, Vec<u16>, u8) -> bool);
+    }
+
+    // Inserts and deletes in any order, a delete where the key is odd, over a small range
+    // of keys so that they hit each other, checking the tree against a BTreeMap after each.
+    fn mixed_changes_keep_the_properties(changes: Vec<(u8, u16)>, fanout: u8) -> bool {
+        let mut btree: Btree<u16, u16> = Btree::empty(2 + fanout as u64 % 6);
+        let mut expected = BTreeMap::new();
+        for (key, value) in changes {
+            let key = key as u16;
+            if value % 2 == 0 {
+                assert_eq!(btree.insert(key, value), expected.insert(key, value));
+            } else {
+                assert_eq!(btree.delete(&key), expected.remove(&key));
+            }
+            check_tree(&btree, &expected);
+        }
+        btree.range(..).eq(expected.iter())
+    }
+
+    #[test]
+    fn random_inserts_and_deletes_match_a_btreemap() {
+        QuickCheck::new()
+            .tests(300)
+            .quickcheck(mixed_changes_keep_the_properties as fn(Vec<(u8, u16)>, u8) -> bool);
+    }
+
+    // As above, on 512 byte pages with keys and values of all sizes, some too big for a
+    // page, written out and read back now and then.
+    fn changes_on_pages_keep_the_properties(changes: Vec<(u8, u16, u16)>) -> bool {
+        let mut store = Store::default();
+        let root = store.allocate().unwrap();
+        let header = store.header(512);
+        let mut btree: Btree<Vec<u8>, Vec<u8>> = Btree::on_pages(&header, Comparator::binary());
+        let mut expected = BTreeMap::new();
+        for (i, (key, length, value)) in changes.into_iter().enumerate() {
+            let key = [vec![key], vec![b'k'; length as usize % 1500]].concat();
+            if value % 3 == 0 {
+                assert_eq!(btree.delete(&key), expected.remove(&key));
+            } else {
+                let value = vec![b'v'; value as usize % 3000];
+                assert_eq!(
+                    btree.insert(key.clone(), value.clone()),
+                    expected.insert(key, value)
+                );
+            }
+            btree.check_invariants().unwrap();
+            if i % 10 == 0 {
+                btree.flush(&mut store, &header, root).unwrap();
+                let header = store.header(512);
+                btree = Btree::open(&header, Comparator::binary(), &mut store, root).unwrap();
+            }
+        }
+        btree.range(..).eq(expected.iter())
+    }
+
+    #[test]
+    fn random_changes_to_a_tree_on_pages_match_a_btreemap() {
+        QuickCheck::new()
+            .tests(100)
+            .quickcheck(changes_on_pages_keep_the_properties as fn(Vec<(u8, u16, u16)>) -> bool);
+    }
+
+    #[test]
+    fn broken_trees_fail_the_check() {
+        let mut btree: Btree<u16, u16> = Btree::empty(4);
+        for k in 0..50 {
+            btree.insert(k, k);
+        }
+        btree.check_invariants().unwrap();
+
+        let leaf = btree.find_leaf(&20);
+        let Node::Leaf(node) = &mut btree.nodes[leaf] else {
+            unreachable!("find_leaf always returns a leaf")
+        };
+        node.interior_nodes.swap(0, 1);
+        assert_eq!(
+            btree.check_invariants().unwrap_err().to_string(),
+            format!("node {leaf} has keys out of order")
+        );
+        let Node::Leaf(node) = &mut btree.nodes[leaf] else {
+            unreachable!("find_leaf always returns a leaf")
+        };
+        node.interior_nodes.swap(0, 1);
+        node.interior_nodes.clear();
+        assert_eq!(
+            btree.check_invariants().unwrap_err().to_string(),
+            format!("leaf {leaf} has 0 keys, fewer than 2")
+        );
+    }
+
+    #[test]
+    fn deleting_everything_shrinks_the_tree_to_one_leaf() {
+        let mut btree: Btree<u16, u16> = Btree::empty(3);
+        for k in 0..500 {
+            btree.insert(k, k);
+        }
+        let nodes = btree.nodes.next_page();
+        for k in (0..500).map(|i| (i * 7) % 500) {
+            assert_eq!(btree.delete(&k), Some(k));
+        }
+        check_tree(&btree, &BTreeMap::new());
+        assert!(matches!(btree.nodes[btree.root], Node::Leaf(_)));
+
+        // and refilling it reuses the freed nodes rather than growing the arena
+        for k in 0..500 {
+            btree.insert(k, k);
+        }
+        assert_eq!(btree.nodes.next_page(), nodes);
+    }
 }
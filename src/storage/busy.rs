@@ -0,0 +1,135 @@
+/*
+    What to do when a lock can't be had because another connection holds it, as
+    sqlite3_busy_handler and sqlite3_busy_timeout decide.
+
+    A lock that conflicts with another connection's fails straight away, see lock.rs, and
+    by default that is that: the statement fails with "database is locked". A busy
+    handler is asked instead, with how many times it has been asked already for this
+    lock, and the lock is tried again for as long as it says to. So the other connection
+    has a chance to finish its write and let go.
+
+        PRAGMA busy_timeout = 2000;    keep trying for two seconds
+
+    busy_timeout is the handler most want. It sleeps a little longer each time, 1ms,
+    then 2, 5, 10 and so on up to 100ms between tries as SQLite's does, and gives up once
+    the sleeps add up to the timeout. Rust code can set a handler of its own with
+    Connection::busy_handler, which does any waiting itself, and 0, the default, has
+    none.
+
+    The handler is the pager's, see pager.rs, and is asked wherever the pager takes a
+    lock, for a read, for a write and for writing the file out at a commit.
+*/
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+// How long each try waits, the last for every try after, in milliseconds, SQLite's.
+const DELAYS: [u64; 12] = [1, 2, 5, 10, 15, 20, 25, 25, 25, 50, 50, 100];
+
+/// Called with how many times the lock has been found busy before, returning whether to
+/// try it again.
+pub type BusyCallback = Arc<dyn Fn(u32) -> bool + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub enum BusyHandler {
+    /// Fail straight away with "database is locked".
+    #[default]
+    None,
+    /// Sleep and try again until this long has been spent sleeping.
+    Timeout(Duration),
+    Callback(BusyCallback),
+}
+
+impl BusyHandler {
+    /// The handler PRAGMA busy_timeout sets, none for a timeout of 0 or less.
+    pub fn timeout(ms: i64) -> Self {
+        match u64::try_from(ms) {
+            Ok(ms) if ms > 0 => BusyHandler::Timeout(Duration::from_millis(ms)),
+            _ => BusyHandler::None,
+        }
+    }
+
+    /// What PRAGMA busy_timeout reads, 0 unless the handler is a timeout.
+    pub fn timeout_ms(&self) -> i64 {
+        match self {
+            BusyHandler::Timeout(timeout) => timeout.as_millis() as i64,
+            _ => 0,
+        }
+    }
+
+    /// Whether to try a lock again after it has been found busy `count` times before,
+    /// having waited as long as the handler does.
+    pub fn retry(&self, count: u32) -> bool {
+        match self {
+            BusyHandler::None => false,
+            BusyHandler::Timeout(timeout) => match delay(count, *timeout) {
+                Some(delay) => {
+                    std::thread::sleep(delay);
+                    true
+                }
+                None => false,
+            },
+            BusyHandler::Callback(callback) => callback(count),
+        }
+    }
+}
+
+// How long to sleep before the try after `count` busy ones, None once that would take
+// the sleeps past `timeout`.
+fn delay(count: u32, timeout: Duration) -> Option<Duration> {
+    let count = count as usize;
+    let slept: u64 = match DELAYS.get(..count) {
+        Some(delays) => delays.iter().sum(),
+        None => DELAYS.iter().sum::<u64>() + (count - DELAYS.len()) as u64 * DELAYS[11],
+    };
+    let timeout = timeout.as_millis() as u64;
+    let delay = DELAYS[count.min(DELAYS.len() - 1)].min(timeout.saturating_sub(slept));
+    (delay > 0).then(|| Duration::from_millis(delay))
+}
+
+impl fmt::Debug for BusyHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusyHandler::None => write!(f, "None"),
+            BusyHandler::Timeout(timeout) => write!(f, "Timeout({timeout:?})"),
+            BusyHandler::Callback(_) => write!(f, "Callback"),
+        }
+    }
+}
+
+impl PartialEq for BusyHandler {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (BusyHandler::None, BusyHandler::None) => true,
+            (BusyHandler::Timeout(a), BusyHandler::Timeout(b)) => a == b,
+            (BusyHandler::Callback(a), BusyHandler::Callback(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_timeout_backs_off_until_it_is_spent() {
+        let timeout = Duration::from_millis(100);
+        let delays: Vec<u64> = (0..)
+            .map_while(|count| delay(count, timeout))
+            .map(|delay| delay.as_millis() as u64)
+            .collect();
+        assert_eq!(delays, [1, 2, 5, 10, 15, 20, 25, 22]);
+        assert_eq!(delays.iter().sum::<u64>(), 100);
+
+        // after the table runs out every try waits the last of it
+        let long = Duration::from_secs(1);
+        assert_eq!(delay(12, long), Some(Duration::from_millis(100)));
+        assert_eq!(delay(1000, long), None);
+
+        assert_eq!(BusyHandler::timeout(0), BusyHandler::None);
+        assert_eq!(BusyHandler::timeout(-5), BusyHandler::None);
+        assert_eq!(BusyHandler::timeout(250).timeout_ms(), 250);
+        assert!(!BusyHandler::None.retry(0));
+    }
+}
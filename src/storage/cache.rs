@@ -0,0 +1,831 @@
+/*
+    The page cache, the pages of the database file the pager holds in memory.
+
+    A B+tree asks the cache for a page by number. If the page is there already that's a
+    hit and costs nothing, otherwise it's a fault and the page is read from the file. The
+    cache holds at most `capacity` pages, from PRAGMA cache_size and hard_heap_limit,
+    so a fault on a full cache first evicts a page, by default the one that has gone
+    unused the longest, see replacement.rs for the others. A page that was changed since it was read is dirty,
+    and evicting it means writing it back to the file first, which is the expensive case:
+    a query that keeps evicting dirty pages is doing a write for every read.
+
+    Changing a page only marks it dirty, however many times it changes. A commit flushes
+    every dirty page in page order, handing each run of consecutive pages to the file as
+    one write, so a transaction that fills neighbouring pages writes them together rather
+    than a write per change.
+
+    Every fault, eviction and writeback is a tracing event at debug level with the page's
+    number and what owns it, the file's tree or its header, so that running with a
+    subscriber shows exactly what is churning through the cache. The cache also keeps
+    count, in total and for each owner, and of the syncs that make sure a commit's writes
+    have reached the disk. The shell's `.cachestats` shows the counts of the caches of
+    main's file and the attached ones' by owner, `.stats` and PRAGMA stats their totals,
+    see pragma.rs.
+    A slow query with plenty of hits is cache-bound, one with faults and writebacks to
+    match its pages is waiting on I/O.
+
+    Inside a savepoint the cache also copies each page the first time it is changed,
+    before the change, so that ROLLBACK TO can put back just those pages rather than
+    replaying the journal of the whole transaction. Each open savepoint has its own set of
+    copies. Rolling back to one copies back the pages of it and of every savepoint opened
+    after it, oldest copy last so that it wins, and releasing one hands its copies down to
+    the savepoint it was opened in, which keeps its own older copy of a page if it has one.
+    A copied back page is dirty, and is cached again even if it had been evicted since.
+
+    A page can be pinned while a B+tree is in the middle of working on it, say walking
+    down from a parent it will come back to. A pinned page is never evicted, the least
+    recently used unpinned one goes instead, and should every cached page be pinned the
+    cache grows past its capacity rather than fail, shrinking again as they're unpinned
+    and the next faults evict them.
+
+    Each file's pager in pager.rs reads and writes its pages through a cache of its own.
+    Tables and indexes are kept in memory once loaded, so the pages counted are those a
+    connection reads loading the file's tree and writes saving it, see image.rs, and with
+    cache=shared those of every connection sharing the pager.
+*/
+use super::lock::LockLevel;
+use super::replacement::ReplacementPolicy;
+use super::wal::PageNumber;
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+/// Where the cache reads pages from and writes them back to, the database file.
+pub trait PageStore {
+    fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>>;
+    fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()>;
+
+    /// The start of the file as a memory-mapped view, for a store that maps it, see
+    /// pager.rs.
+    fn mapping(&self) -> Option<&[u8]> {
+        None
+    }
+
+    /// Map the first `size` bytes of the file for mapping to hand out, see
+    /// VfsFile::map. A store that doesn't map its file ignores it.
+    fn map(&mut self, _size: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Make sure what has been written has reached the disk, fsync on a file.
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Read `count` pages one after another from `first` on, which a file can do with a
+    /// single read where it would otherwise take one for each page.
+    fn read_pages(&mut self, first: PageNumber, count: u32) -> Result<Vec<Vec<u8>>> {
+        (first..first + count)
+            .map(|page| self.read_page(page))
+            .collect()
+    }
+
+    /// Write a run of pages one after another from `first` on, which a file can do with a
+    /// single write where it would otherwise take one for each page.
+    fn write_pages(&mut self, first: PageNumber, pages: &[&[u8]]) -> Result<()> {
+        for (page, data) in (first..).zip(pages) {
+            self.write_page(page, data)?;
+        }
+        Ok(())
+    }
+
+    /// Whether other connections may change the file while this one holds no lock on it,
+    /// so that what was read from it before has to be checked before it is used again.
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    /// Take the lock `level` on the file, for a store other connections share, see lock.rs.
+    fn lock(&mut self, _level: LockLevel) -> Result<()> {
+        Ok(())
+    }
+
+    /// Come down to the lock `level`, SHARED or UNLOCKED.
+    fn unlock(&mut self, _level: LockLevel) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// How the cache has been doing.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub faults: u64,
+    pub evictions: u64,
+    pub writebacks: u64,
+    // pages read before they were asked for, see pager.rs
+    pub read_ahead: u64,
+}
+
+impl CacheStats {
+    /// The share of lookups the cache answered without a read, None before the first.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let lookups = self.hits + self.faults;
+        (lookups > 0).then(|| self.hits as f64 / lookups as f64)
+    }
+
+    fn add(&mut self, other: &CacheStats) {
+        self.hits += other.hits;
+        self.faults += other.faults;
+        self.evictions += other.evictions;
+        self.writebacks += other.writebacks;
+        self.read_ahead += other.read_ahead;
+    }
+}
+
+/// What the caches of one or more pagers have counted, by owner.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CacheReport {
+    pub owners: BTreeMap<String, CacheStats>,
+    pub syncs: u64,
+    // how many pages are cached, and how many may be
+    pub cached: usize,
+    pub capacity: usize,
+}
+
+impl CacheReport {
+    /// The counts for every owner together.
+    pub fn total(&self) -> CacheStats {
+        let mut total = CacheStats::default();
+        for stats in self.owners.values() {
+            total.add(stats);
+        }
+        total
+    }
+
+    /// Count in the report of the cache of `database`'s file, its owners qualified by
+    /// the database's name.
+    pub fn add(&mut self, database: &str, report: CacheReport) {
+        for (owner, stats) in report.owners {
+            self.owners
+                .entry(format!("{database}.{owner}"))
+                .or_default()
+                .add(&stats);
+        }
+        self.syncs += report.syncs;
+        self.cached += report.cached;
+        self.capacity += report.capacity;
+    }
+}
+
+#[derive(Debug)]
+struct CachedPage {
+    data: Vec<u8>,
+    owner: String,
+    dirty: bool,
+}
+
+// A page as it was before a savepoint first changed it.
+#[derive(Debug)]
+struct Snapshot {
+    data: Vec<u8>,
+    owner: String,
+}
+
+#[derive(Debug)]
+pub struct PageCache {
+    capacity: usize,
+    pages: HashMap<PageNumber, CachedPage>,
+    // which page goes when the cache is full, see replacement.rs
+    policy: Box<dyn ReplacementPolicy>,
+    stats: BTreeMap<String, CacheStats>,
+    // the pages each open savepoint has changed as they were before, oldest savepoint first
+    snapshots: Vec<HashMap<PageNumber, Snapshot>>,
+    // how many times each pinned page has been pinned and not yet unpinned
+    pins: HashMap<PageNumber, usize>,
+    // how many times the file has been synced after a flush
+    syncs: u64,
+}
+
+impl PageCache {
+    /// A cache of at most `capacity` pages, and always room for one, that evicts the page
+    /// `policy` picks.
+    pub fn with_policy(capacity: usize, policy: Box<dyn ReplacementPolicy>) -> Self {
+        PageCache {
+            capacity: capacity.max(1),
+            pages: HashMap::new(),
+            policy,
+            stats: BTreeMap::new(),
+            snapshots: vec![],
+            pins: HashMap::new(),
+            syncs: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Change how many pages the cache may hold. A cache left holding more than that
+    /// shrinks as the next faults evict pages without adding to the count.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+    }
+
+    /// Evict the page `policy` picks from now on. The pages cached already are handed to
+    /// it in page order, as if they had just come in.
+    pub fn set_policy(&mut self, mut policy: Box<dyn ReplacementPolicy>) {
+        let mut pages: Vec<PageNumber> = self.pages.keys().copied().collect();
+        pages.sort();
+        for page in pages {
+            policy.admit(page);
+        }
+        self.policy = policy;
+    }
+
+    /// Page `page` of the B+tree `owner`, read from `store` if it isn't cached.
+    pub fn get(
+        &mut self,
+        page: PageNumber,
+        owner: &str,
+        store: &mut dyn PageStore,
+    ) -> Result<&[u8]> {
+        self.load(page, owner, store)?;
+        Ok(&self.pages[&page].data)
+    }
+
+    /// Page `page` of the B+tree `owner` to change, which makes it dirty.
+    pub fn get_mut(
+        &mut self,
+        page: PageNumber,
+        owner: &str,
+        store: &mut dyn PageStore,
+    ) -> Result<&mut Vec<u8>> {
+        self.load(page, owner, store)?;
+        let cached = self.pages.get_mut(&page).expect("just loaded");
+        if let Some(snapshot) = self.snapshots.last_mut() {
+            snapshot.entry(page).or_insert_with(|| Snapshot {
+                data: cached.data.clone(),
+                owner: cached.owner.clone(),
+            });
+        }
+        cached.dirty = true;
+        Ok(&mut cached.data)
+    }
+
+    /// Cache `data` as the whole of page `page` without reading it first, as for a page
+    /// just allocated, which makes it dirty.
+    pub fn put(
+        &mut self,
+        page: PageNumber,
+        owner: &str,
+        data: Vec<u8>,
+        store: &mut dyn PageStore,
+    ) -> Result<()> {
+        match self.pages.get_mut(&page) {
+            Some(cached) => {
+                if let Some(snapshot) = self.snapshots.last_mut() {
+                    snapshot.entry(page).or_insert_with(|| Snapshot {
+                        data: cached.data.clone(),
+                        owner: cached.owner.clone(),
+                    });
+                }
+                self.policy.touch(page);
+            }
+            None => {
+                while self.pages.len() >= self.capacity && self.evict(store)? {}
+                self.policy.admit(page);
+            }
+        }
+        self.pages.insert(
+            page,
+            CachedPage {
+                data,
+                owner: owner.to_string(),
+                dirty: true,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn contains(&self, page: PageNumber) -> bool {
+        self.pages.contains_key(&page)
+    }
+
+    /// Cache page `page` of the B+tree `owner`, read ahead of being asked for, unless it
+    /// is cached already. Asking for it later is a hit.
+    pub fn read_ahead(
+        &mut self,
+        page: PageNumber,
+        owner: &str,
+        data: Vec<u8>,
+        store: &mut dyn PageStore,
+    ) -> Result<()> {
+        if self.pages.contains_key(&page) {
+            return Ok(());
+        }
+        while self.pages.len() >= self.capacity && self.evict(store)? {}
+        self.pages.insert(
+            page,
+            CachedPage {
+                data,
+                owner: owner.to_string(),
+                dirty: false,
+            },
+        );
+        self.policy.admit(page);
+        tracing::debug!(page, owner, "page read ahead");
+        self.owner_stats(owner).read_ahead += 1;
+        Ok(())
+    }
+
+    /// Keep page `page`, which must be cached, until it is unpinned as many times as it
+    /// was pinned.
+    pub fn pin(&mut self, page: PageNumber) {
+        assert!(
+            self.pages.contains_key(&page),
+            "page {page} pinned uncached"
+        );
+        *self.pins.entry(page).or_default() += 1;
+    }
+
+    /// Let go of page `page` once, so that it may be evicted when no pin is left.
+    pub fn unpin(&mut self, page: PageNumber) {
+        if let Some(pins) = self.pins.get_mut(&page) {
+            *pins -= 1;
+            if *pins == 0 {
+                self.pins.remove(&page);
+            }
+        }
+    }
+
+    /// How many pages are pinned.
+    pub fn pinned(&self) -> usize {
+        self.pins.len()
+    }
+
+    /// Page `page` as cached, without counting a lookup.
+    pub fn peek(&self, page: PageNumber) -> Option<&[u8]> {
+        self.pages.get(&page).map(|cached| &cached.data[..])
+    }
+
+    /// Page `page` as cached to change, without counting a lookup. The page must have
+    /// been made dirty already, by get_mut.
+    pub fn peek_mut(&mut self, page: PageNumber) -> Option<&mut [u8]> {
+        let cached = self.pages.get_mut(&page)?;
+        debug_assert!(cached.dirty, "page {page} changed without get_mut");
+        Some(&mut cached.data[..])
+    }
+
+    /// Write every dirty page back, as a commit does, keeping them cached. The pages go
+    /// in page order, each run of consecutive ones in a single write.
+    pub fn flush(&mut self, store: &mut dyn PageStore) -> Result<()> {
+        let mut runs: Vec<Vec<PageNumber>> = vec![];
+        for page in self.dirty_pages() {
+            match runs.last_mut() {
+                Some(run) if run.last() == Some(&(page - 1)) => run.push(page),
+                _ => runs.push(vec![page]),
+            }
+        }
+        for run in runs {
+            let data: Vec<&[u8]> = run.iter().map(|page| &self.pages[page].data[..]).collect();
+            store.write_pages(run[0], &data)?;
+            for page in run {
+                let cached = self.pages.get_mut(&page).expect("a cached page");
+                cached.dirty = false;
+                let owner = cached.owner.clone();
+                tracing::debug!(page, owner = owner.as_str(), "page written back");
+                self.owner_stats(&owner).writebacks += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// The dirty pages, in page order.
+    pub fn dirty_pages(&self) -> Vec<PageNumber> {
+        let mut dirty: Vec<PageNumber> = self
+            .pages
+            .iter()
+            .filter(|(_, cached)| cached.dirty)
+            .map(|(page, _)| *page)
+            .collect();
+        dirty.sort();
+        dirty
+    }
+
+    /// Sync `store`, as a commit does after its flush when PRAGMA synchronous asks it to.
+    pub fn sync(&mut self, store: &mut dyn PageStore) -> Result<()> {
+        store.sync()?;
+        tracing::debug!("file synced");
+        self.syncs += 1;
+        Ok(())
+    }
+
+    /// Start copying pages before they change, for a savepoint just opened.
+    pub fn savepoint(&mut self) {
+        self.snapshots.push(HashMap::new());
+    }
+
+    /// Hand the copies of the savepoint at `level` and those opened after it down to the
+    /// savepoint it was opened in, or drop them if it was the outermost.
+    pub fn release(&mut self, level: usize) {
+        let released = self.snapshots.split_off(level.min(self.snapshots.len()));
+        if let Some(parent) = self.snapshots.last_mut() {
+            for snapshot in released {
+                for (page, copy) in snapshot {
+                    parent.entry(page).or_insert(copy);
+                }
+            }
+        }
+    }
+
+    /// Copy back every page changed since the savepoint at `level` was opened, which stays
+    /// open, returning how many there were.
+    pub fn rollback_to(&mut self, level: usize) -> usize {
+        let undone = self.snapshots.split_off(level.min(self.snapshots.len()));
+        let mut restored = BTreeMap::new();
+        // newest first, so the oldest copy of a page is the one left
+        for snapshot in undone.into_iter().rev() {
+            restored.extend(snapshot);
+        }
+        let count = restored.len();
+        for (page, Snapshot { data, owner }) in restored {
+            tracing::debug!(page, owner = owner.as_str(), "page restored");
+            self.restore(page, data, owner);
+        }
+        self.snapshots.push(HashMap::new());
+        count
+    }
+
+    /// Drop every copy, as the transaction they were kept for has ended.
+    pub fn end_transaction(&mut self) {
+        self.snapshots.clear();
+    }
+
+    /// Forget every page past `page_count` without writing it back, as for pages
+    /// allocated since a savepoint rolled back to.
+    pub fn forget_past(&mut self, page_count: PageNumber) {
+        let past: Vec<PageNumber> = self
+            .pages
+            .keys()
+            .copied()
+            .filter(|&page| page > page_count)
+            .collect();
+        for page in past {
+            debug_assert!(!self.pins.contains_key(&page), "a forgotten page is pinned");
+            self.policy.remove(page);
+            self.pages.remove(&page);
+        }
+    }
+
+    /// What the cache has counted so far.
+    pub fn report(&self) -> CacheReport {
+        CacheReport {
+            owners: self.stats.clone(),
+            syncs: self.syncs,
+            cached: self.pages.len(),
+            capacity: self.capacity,
+        }
+    }
+
+    fn load(&mut self, page: PageNumber, owner: &str, store: &mut dyn PageStore) -> Result<()> {
+        if self.pages.contains_key(&page) {
+            self.policy.touch(page);
+            self.owner_stats(owner).hits += 1;
+            return Ok(());
+        }
+
+        tracing::debug!(page, owner, "page fault");
+        self.owner_stats(owner).faults += 1;
+        while self.pages.len() >= self.capacity && self.evict(store)? {}
+        let data = store.read_page(page)?;
+        self.pages.insert(
+            page,
+            CachedPage {
+                data,
+                owner: owner.to_string(),
+                dirty: false,
+            },
+        );
+        self.policy.admit(page);
+        Ok(())
+    }
+
+    // Drop the page the policy picks from those that aren't pinned, writing it back first
+    // if it is dirty. False when every cached page is pinned.
+    fn evict(&mut self, store: &mut dyn PageStore) -> Result<bool> {
+        let pins = &self.pins;
+        let Some(page) = self
+            .policy
+            .victim(self.capacity, &|page| pins.contains_key(&page))
+        else {
+            return Ok(false);
+        };
+        self.write_back(page, store)?;
+        self.policy.remove(page);
+        let cached = self.pages.remove(&page).expect("a cached page");
+        tracing::debug!(page, owner = cached.owner.as_str(), "page evicted");
+        self.owner_stats(&cached.owner).evictions += 1;
+        Ok(true)
+    }
+
+    fn write_back(&mut self, page: PageNumber, store: &mut dyn PageStore) -> Result<()> {
+        let cached = self.pages.get_mut(&page).expect("a cached page");
+        if !cached.dirty {
+            return Ok(());
+        }
+        store.write_page(page, &cached.data)?;
+        cached.dirty = false;
+        let owner = cached.owner.clone();
+        tracing::debug!(page, owner = owner.as_str(), "page written back");
+        self.owner_stats(&owner).writebacks += 1;
+        Ok(())
+    }
+
+    // Put a page back as it was, caching it again if it was evicted. The cache may hold
+    // more than its capacity afterwards until the next faults shrink it.
+    fn restore(&mut self, page: PageNumber, data: Vec<u8>, owner: String) {
+        if self.pages.contains_key(&page) {
+            self.policy.touch(page);
+        } else {
+            self.policy.admit(page);
+        }
+        self.pages.insert(
+            page,
+            CachedPage {
+                data,
+                owner,
+                dirty: true,
+            },
+        );
+    }
+
+    fn owner_stats(&mut self, owner: &str) -> &mut CacheStats {
+        self.stats.entry(owner.to_string()).or_default()
+    }
+}
+
+// A table of the counts, one line per owner and a total, as `.cachestats` prints them.
+impl fmt::Display for CacheReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total = self.total();
+        let mut lines = vec![("owner".to_string(), None)];
+        lines.extend(
+            self.owners
+                .iter()
+                .map(|(owner, stats)| (owner.clone(), Some(*stats))),
+        );
+        lines.push(("total".to_string(), Some(total)));
+        let width = lines
+            .iter()
+            .map(|(owner, _)| owner.len())
+            .max()
+            .unwrap_or(0);
+        for (owner, stats) in lines {
+            match stats {
+                None => writeln!(
+                    f,
+                    "{owner:width$}  {:>8}  {:>8}  {:>9}  {:>10}",
+                    "hits", "faults", "evictions", "writebacks"
+                )?,
+                Some(s) => writeln!(
+                    f,
+                    "{owner:width$}  {:>8}  {:>8}  {:>9}  {:>10}",
+                    s.hits, s.faults, s.evictions, s.writebacks
+                )?,
+            }
+        }
+        match total.hit_rate() {
+            Some(rate) => write!(
+                f,
+                "{} of {} pages cached, hit rate {:.1}%",
+                self.cached,
+                self.capacity,
+                rate * 100.0
+            ),
+            None => write!(f, "0 of {} pages cached, no lookups yet", self.capacity),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::replacement::Replacement;
+
+    // A cache of at most `capacity` pages that evicts the least recently used.
+    fn lru(capacity: usize) -> PageCache {
+        PageCache::with_policy(capacity, Replacement::Lru.policy())
+    }
+
+    // A file of pages that each start out holding their own number, counting its I/O.
+    #[derive(Default)]
+    struct File {
+        written: BTreeMap<PageNumber, Vec<u8>>,
+        reads: usize,
+        writes: usize,
+    }
+
+    impl PageStore for File {
+        fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+            self.reads += 1;
+            Ok(self
+                .written
+                .get(&page)
+                .cloned()
+                .unwrap_or_else(|| vec![page as u8]))
+        }
+
+        fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+            self.writes += 1;
+            self.written.insert(page, data.to_vec());
+            Ok(())
+        }
+
+        fn write_pages(&mut self, first: PageNumber, pages: &[&[u8]]) -> Result<()> {
+            self.writes += 1;
+            for (page, data) in (first..).zip(pages) {
+                self.written.insert(page, data.to_vec());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn the_least_recently_used_page_is_evicted() {
+        let mut file = File::default();
+        let mut cache = lru(2);
+        assert_eq!(cache.get(1, "users", &mut file).unwrap(), [1]);
+        cache.get(2, "users", &mut file).unwrap();
+        cache.get(1, "users", &mut file).unwrap();
+        // page 2 is the one left unused, so it makes way for page 3
+        cache.get(3, "idx_email", &mut file).unwrap();
+        cache.get(1, "users", &mut file).unwrap();
+        assert_eq!(file.reads, 3);
+        cache.get(2, "users", &mut file).unwrap();
+        assert_eq!(file.reads, 4);
+
+        let users = cache.stats["users"];
+        assert_eq!((users.hits, users.faults, users.evictions), (2, 3, 1));
+        assert_eq!(cache.stats["idx_email"].evictions, 1);
+        assert_eq!(cache.report().total().hit_rate(), Some(2.0 / 6.0));
+    }
+
+    #[test]
+    fn the_policy_can_change_with_pages_cached() {
+        let mut file = File::default();
+        let mut cache = lru(2);
+        cache.get(2, "users", &mut file).unwrap();
+        cache.get(1, "users", &mut file).unwrap();
+        cache.get(2, "users", &mut file).unwrap();
+        // the clock's hand starts at the lowest page, where LRU would have taken 1 anyway
+        cache.set_policy(Replacement::Clock.policy());
+        cache.get(3, "users", &mut file).unwrap();
+        assert!(!cache.contains(1));
+        assert!(cache.contains(2) && cache.contains(3));
+    }
+
+    #[test]
+    fn dirty_pages_are_written_back() {
+        let mut file = File::default();
+        let mut cache = lru(1);
+        cache.get_mut(1, "users", &mut file).unwrap()[0] = 10;
+        assert!(file.written.is_empty());
+
+        // evicting it writes it, and it reads back as written
+        cache.get(2, "users", &mut file).unwrap();
+        assert_eq!(file.written[&1], [10]);
+        assert_eq!(cache.get(1, "users", &mut file).unwrap(), [10]);
+
+        cache.get_mut(1, "users", &mut file).unwrap()[0] = 11;
+        cache.flush(&mut file).unwrap();
+        assert_eq!(file.written[&1], [11]);
+        // a clean page is dropped without a write
+        cache.get(3, "users", &mut file).unwrap();
+        assert_eq!(cache.report().total().writebacks, 2);
+
+        // a smaller cache sheds pages as it faults
+        cache.set_capacity(3);
+        for page in 4..=6 {
+            cache.get(page, "users", &mut file).unwrap();
+        }
+        cache.set_capacity(1);
+        cache.get(7, "users", &mut file).unwrap();
+        assert_eq!(
+            cache.report().to_string().lines().last(),
+            Some("1 of 1 pages cached, hit rate 11.1%")
+        );
+    }
+
+    #[test]
+    fn a_flush_writes_each_run_of_dirty_pages_at_once() {
+        let mut file = File::default();
+        let mut cache = lru(10);
+        for page in [7, 3, 2, 4, 9, 8] {
+            cache.get_mut(page, "users", &mut file).unwrap()[0] += 10;
+        }
+        // changing a page again before the flush costs nothing more
+        cache.get_mut(3, "users", &mut file).unwrap()[0] += 10;
+        cache.get(5, "users", &mut file).unwrap();
+        assert_eq!(file.writes, 0);
+
+        // 2 to 4 and 7 to 9
+        cache.flush(&mut file).unwrap();
+        assert_eq!(file.writes, 2);
+        assert_eq!(
+            file.written.keys().copied().collect::<Vec<_>>(),
+            [2, 3, 4, 7, 8, 9]
+        );
+        assert_eq!(file.written[&3], [23]);
+        assert_eq!(cache.report().total().writebacks, 6);
+
+        cache.flush(&mut file).unwrap();
+        assert_eq!(file.writes, 2);
+    }
+
+    #[test]
+    fn pinned_pages_are_never_evicted() {
+        let mut file = File::default();
+        let mut cache = lru(2);
+        cache.get(1, "users", &mut file).unwrap();
+        cache.pin(1);
+        cache.get(2, "users", &mut file).unwrap();
+        // page 1 is the least recently used but pinned, so page 2 goes
+        cache.get(3, "users", &mut file).unwrap();
+        cache.get(1, "users", &mut file).unwrap();
+        assert_eq!(file.reads, 3);
+
+        // with every page pinned the cache grows instead
+        cache.pin(3);
+        cache.get(4, "users", &mut file).unwrap();
+        assert_eq!(cache.report().total().evictions, 1);
+        assert_eq!(cache.pages.len(), 3);
+
+        // pinned twice, it takes two unpins to let it go
+        cache.pin(1);
+        cache.unpin(1);
+        cache.unpin(3);
+        cache.get(5, "users", &mut file).unwrap();
+        cache.get(6, "users", &mut file).unwrap();
+        assert_eq!(cache.get(1, "users", &mut file).unwrap(), [1]);
+        assert_eq!(file.reads, 6);
+        cache.unpin(1);
+        cache.get(7, "users", &mut file).unwrap();
+        cache.get(8, "users", &mut file).unwrap();
+        cache.get(1, "users", &mut file).unwrap();
+        assert_eq!(file.reads, 9);
+    }
+
+    #[test]
+    fn rollback_to_copies_back_the_savepoints_pages() {
+        let mut file = File::default();
+        let mut cache = lru(2);
+        cache.get_mut(1, "users", &mut file).unwrap()[0] = 10;
+
+        cache.savepoint();
+        cache.get_mut(1, "users", &mut file).unwrap()[0] = 11;
+        cache.savepoint();
+        cache.get_mut(1, "users", &mut file).unwrap()[0] = 12;
+        cache.get_mut(2, "users", &mut file).unwrap()[0] = 20;
+        // page 1 is evicted and written back, and restored all the same
+        cache.get(3, "users", &mut file).unwrap();
+        assert_eq!(file.written[&1], [12]);
+
+        // back to the inner savepoint, then the outer one
+        assert_eq!(cache.rollback_to(1), 2);
+        assert_eq!(cache.get(1, "users", &mut file).unwrap(), [11]);
+        assert_eq!(cache.get(2, "users", &mut file).unwrap(), [2]);
+        cache.get_mut(2, "users", &mut file).unwrap()[0] = 21;
+        assert_eq!(cache.rollback_to(0), 2);
+        assert_eq!(cache.get(1, "users", &mut file).unwrap(), [10]);
+        assert_eq!(cache.get(2, "users", &mut file).unwrap(), [2]);
+
+        // a released savepoint's copies are rolled back by the one it was opened in
+        cache.savepoint();
+        cache.get_mut(2, "users", &mut file).unwrap()[0] = 22;
+        cache.release(1);
+        assert_eq!(cache.rollback_to(0), 1);
+        assert_eq!(cache.get(2, "users", &mut file).unwrap(), [2]);
+
+        // nothing is copied outside a savepoint
+        cache.release(0);
+        cache.get_mut(2, "users", &mut file).unwrap()[0] = 23;
+        cache.savepoint();
+        assert_eq!(cache.rollback_to(0), 0);
+        assert_eq!(cache.get(2, "users", &mut file).unwrap(), [23]);
+        cache.end_transaction();
+    }
+
+    #[test]
+    fn stats_print_per_owner() {
+        let mut file = File::default();
+        let mut cache = lru(4);
+        assert_eq!(
+            cache.report().to_string(),
+            "owner      hits    faults  evictions  writebacks\n\
+             total         0         0          0           0\n\
+             0 of 4 pages cached, no lookups yet"
+        );
+        cache.get(1, "orders", &mut file).unwrap();
+        cache.get(1, "orders", &mut file).unwrap();
+        cache.get(2, "idx_orders_user", &mut file).unwrap();
+        assert_eq!(
+            cache.report().to_string(),
+            "owner                hits    faults  evictions  writebacks\n\
+             idx_orders_user         0         1          0           0\n\
+             orders                  1         1          0           0\n\
+             total                   1         2          0           0\n\
+             2 of 4 pages cached, hit rate 33.3%"
+        );
+    }
+}
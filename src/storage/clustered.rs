@@ -0,0 +1,291 @@
+/*
+    WITHOUT ROWID tables, whose rows are stored in their primary key's B+tree.
+
+    An ordinary table is a B+tree keyed by rowid, and its PRIMARY KEY is a separate unique
+    index from the key columns to the rowid. Finding a row by its key therefore means
+    searching two trees. A WITHOUT ROWID table has no rowid at all: the primary key is the
+    tree's key and the row is stored right there in the leaf, clustered in key order. A
+    key lookup is a single search and rows that share leading key columns sit side by
+    side, which suits tables mostly read by (parent, child) style composite keys.
+
+    The key is the row's primary key values in the order the PRIMARY KEY lists them, which
+    need not be the order of the columns, and the entry's value is the rest of the row, its
+    other columns in column order. A key column is stored once, in the key, and a row is
+    put back together from the two when it is read. Keys compare value by value, so with
+    PRIMARY KEY (a, b) the rows are ordered by a and then b. Two rows with the same key
+    would be the same tree entry, so the uniqueness of the key is enforced by the tree
+    itself. As in SQLite the key columns of a WITHOUT ROWID table can't be NULL.
+
+    Key columns compare under the collations they were declared with, so in a table with
+    `name TEXT COLLATE NOCASE PRIMARY KEY` the names "bob" and "BOB" are the same key.
+    The tree compares keys with a comparator made from those collations.
+*/
+use super::btree::{Btree, Comparator};
+use crate::collation::{Collation, Collations};
+use crate::error::SqlError;
+use crate::sql_parser::ast::{ColVal, CreateTable};
+use anyhow::{anyhow, bail, Result};
+use std::cmp::Ordering;
+
+type Key = Vec<ColVal>;
+
+#[derive(Debug)]
+pub struct ClusteredTable {
+    pub name: String,
+    key_columns: Vec<String>,
+    key_positions: Vec<usize>,
+    key_collations: Vec<Collation>,
+    // how many columns a row has, key columns included
+    width: usize,
+    tree: Btree<Key, Vec<ColVal>>,
+}
+
+impl ClusteredTable {
+    /// An empty table whose B+tree nodes split past `node_keys` keys.
+    pub fn create(def: &CreateTable, collations: &Collations, node_keys: u64) -> Result<Self> {
+        if !def.without_rowid {
+            bail!("{} is not a WITHOUT ROWID table", def.name);
+        }
+        let mut key_positions = vec![];
+        let mut key_collations = vec![];
+        for key in &def.primary_key {
+            let Some(pos) = def.columns.iter().position(|c| c.name == *key) else {
+                bail!(SqlError::NoSuchColumn {
+                    column: key.to_string()
+                });
+            };
+            key_positions.push(pos);
+            key_collations.push(match &def.columns[pos].collation {
+                Some(name) => collations.get(name)?,
+                None => Collation::binary(),
+            });
+        }
+        Ok(ClusteredTable {
+            name: def.name.clone(),
+            key_columns: def.primary_key.clone(),
+            key_positions,
+            tree: Btree::with_comparator(node_keys, comparator(&key_collations)),
+            key_collations,
+            width: def.columns.len(),
+        })
+    }
+
+    /// A row's primary key values, in the order the PRIMARY KEY lists them.
+    pub fn primary_key(&self, row: &[ColVal]) -> Result<Vec<ColVal>> {
+        let mut key = vec![];
+        for (column, pos) in self.key_columns.iter().zip(&self.key_positions) {
+            if row[*pos] == ColVal::Null {
+                bail!("NOT NULL constraint failed: {}.{column}", self.name);
+            }
+            key.push(row[*pos].clone());
+        }
+        Ok(key)
+    }
+
+    // A row split into its key and the rest of it, as the tree stores it.
+    fn split(&self, row: Vec<ColVal>) -> Result<(Key, Vec<ColVal>)> {
+        let key = self.primary_key(&row)?;
+        let rest = row
+            .into_iter()
+            .enumerate()
+            .filter(|(pos, _)| !self.key_positions.contains(pos))
+            .map(|(_, value)| value)
+            .collect();
+        Ok((key, rest))
+    }
+
+    // The row a key and the rest of its values make up, put back together.
+    fn join(&self, key: &[ColVal], rest: &[ColVal]) -> Vec<ColVal> {
+        let mut row = vec![ColVal::Null; self.width];
+        for (pos, value) in self.key_positions.iter().zip(key) {
+            row[*pos] = value.clone();
+        }
+        let others = (0..self.width).filter(|pos| !self.key_positions.contains(pos));
+        for (pos, value) in others.zip(rest) {
+            row[pos] = value.clone();
+        }
+        row
+    }
+
+    // Whether `a` and `b` hold the same values, each compared under its column's collation.
+    // Either may be a prefix, of which only the leading values are compared.
+    fn same_values(&self, a: &[ColVal], b: &[ColVal]) -> bool {
+        self.key_collations
+            .iter()
+            .zip(a.iter().zip(b))
+            .all(|(collation, (a, b))| collation.compare(a, b) == Ordering::Equal)
+    }
+
+    fn unique_violation(&self) -> anyhow::Error {
+        let columns: Vec<String> = self
+            .key_columns
+            .iter()
+            .map(|c| format!("{}.{c}", self.name))
+            .collect();
+        anyhow!(SqlError::UniqueConstraint { columns })
+    }
+
+    // The entry stored under a key equal to `key`, whose own key may differ from it in
+    // ways its collations ignore, such as case under NOCASE.
+    fn entry(&self, key: &[ColVal]) -> Option<(&Key, &Vec<ColVal>)> {
+        self.tree
+            .lower_bound(&key.to_vec())
+            .filter(|(found, _)| found.len() == key.len() && self.same_values(found, key))
+    }
+
+    pub fn get(&self, key: &[ColVal]) -> Option<Vec<ColVal>> {
+        self.entry(key).map(|(found, rest)| self.join(found, rest))
+    }
+
+    pub fn insert(&mut self, row: Vec<ColVal>) -> Result<()> {
+        let (key, rest) = self.split(row)?;
+        if self.entry(&key).is_some() {
+            return Err(self.unique_violation());
+        }
+        self.tree.insert(key, rest);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[ColVal]) -> Option<Vec<ColVal>> {
+        let (found, rest) = self.entry(key)?;
+        let (found, row) = (found.clone(), self.join(found, rest));
+        self.tree.delete(&found);
+        Some(row)
+    }
+
+    /// Rebuild the table's tree with its nodes full, see Btree::rebuilt.
+    pub fn rebuild(&mut self) {
+        self.tree = self.tree.rebuilt();
+    }
+
+    /// Whether the table's tree is well formed, see Btree::check_invariants.
+    pub fn check(&self) -> Result<()> {
+        self.tree.check_invariants()
+    }
+
+    /// Every row in primary key order, read from the tree by a cursor as the iterator
+    /// goes.
+    pub fn iter(&self) -> impl Iterator<Item = Vec<ColVal>> + '_ {
+        let mut cursor = self.tree.cursor();
+        cursor.first();
+        cursor.entries().map(|(key, rest)| self.join(key, rest))
+    }
+}
+
+// Order keys value by value, each under its column's collation.
+fn comparator(collations: &[Collation]) -> Comparator<Key> {
+    let names: Vec<&str> = collations.iter().map(|c| c.name.as_str()).collect();
+    let collations = collations.to_vec();
+    let compare = collations.to_vec();
+    Comparator::new(&names.join(","), move |a: &Key, b: &Key| {
+        Collation::compare_rows(&compare, a, b)
+    })
+    .with_separator(move |a: &Key, b: &Key| {
+        Collation::separate_rows(&collations, a, b).unwrap_or_else(|| b.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::Statement;
+    use crate::sql_parser::parse;
+
+    fn table() -> ClusteredTable {
+        let Statement::CreateTable(def) = parse(
+            "CREATE TABLE enrolment (student, course, grade, PRIMARY KEY (course, student)) WITHOUT ROWID;",
+        )
+        .unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        ClusteredTable::create(&def, &Collations::default(), 64).unwrap()
+    }
+
+    fn row(student: &str, course: i64, grade: i64) -> Vec<ColVal> {
+        vec![
+            ColVal::String(student.to_string()),
+            ColVal::Int(course),
+            ColVal::Int(grade),
+        ]
+    }
+
+    #[test]
+    fn rows_are_kept_in_key_order() {
+        let mut t = table();
+        for r in [row("bob", 2, 70), row("ann", 2, 80), row("bob", 1, 60)] {
+            t.insert(r).unwrap();
+        }
+        assert_eq!(
+            t.iter().collect::<Vec<_>>(),
+            [row("bob", 1, 60), row("ann", 2, 80), row("bob", 2, 70)]
+        );
+        assert_eq!(
+            t.get(&[ColVal::Int(1), ColVal::String("bob".to_string())]),
+            Some(row("bob", 1, 60))
+        );
+    }
+
+    #[test]
+    fn key_columns_are_stored_only_in_the_key() {
+        let mut t = table();
+        t.insert(row("bob", 1, 60)).unwrap();
+        let (key, rest) = t.tree.range(..).next().unwrap();
+        assert_eq!(key, &[ColVal::Int(1), ColVal::String("bob".to_string())]);
+        assert_eq!(rest, &[ColVal::Int(60)]);
+
+        let key = [ColVal::Int(1), ColVal::String("bob".to_string())];
+        assert_eq!(t.delete(&key), Some(row("bob", 1, 60)));
+        assert_eq!(t.iter().count(), 0);
+    }
+
+    #[test]
+    fn keys_are_unique_and_not_null() {
+        let mut t = table();
+        t.insert(row("bob", 1, 60)).unwrap();
+        assert_eq!(
+            t.insert(row("bob", 1, 99)).unwrap_err().to_string(),
+            "UNIQUE constraint failed: enrolment.course, enrolment.student"
+        );
+        assert_eq!(
+            t.insert(vec![ColVal::Null, ColVal::Int(1), ColVal::Int(1)])
+                .unwrap_err()
+                .to_string(),
+            "NOT NULL constraint failed: enrolment.student"
+        );
+
+        // a row of the same key may follow once the first has gone
+        let bob = [ColVal::Int(1), ColVal::String("bob".to_string())];
+        t.delete(&bob);
+        t.insert(row("bob", 1, 99)).unwrap();
+        assert_eq!(t.get(&bob), Some(row("bob", 1, 99)));
+    }
+
+    #[test]
+    fn keys_compare_under_their_columns_collation() {
+        let Statement::CreateTable(def) =
+            parse("CREATE TABLE tags (name TEXT COLLATE NOCASE PRIMARY KEY, uses) WITHOUT ROWID;")
+                .unwrap()
+        else {
+            panic!("expected CREATE TABLE");
+        };
+        let mut t = ClusteredTable::create(&def, &Collations::default(), 64).unwrap();
+        let tag = |name: &str, uses: i64| vec![ColVal::String(name.to_string()), ColVal::Int(uses)];
+        for r in [tag("rust", 1), tag("Go", 2), tag("c", 3)] {
+            t.insert(r).unwrap();
+        }
+        assert_eq!(
+            t.insert(tag("RUST", 4)).unwrap_err().to_string(),
+            "UNIQUE constraint failed: tags.name"
+        );
+        assert_eq!(
+            t.get(&[ColVal::String("GO".to_string())]),
+            Some(tag("Go", 2))
+        );
+        // a row deleted by an equal key comes back as it was stored
+        assert_eq!(
+            t.delete(&[ColVal::String("RUST".to_string())]),
+            Some(tag("rust", 1))
+        );
+        assert_eq!(t.iter().collect::<Vec<_>>(), [tag("c", 3), tag("Go", 2)]);
+    }
+}
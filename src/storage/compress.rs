@@ -0,0 +1,570 @@
+/*
+    Database files whose pages are kept compressed, for a database opened with
+
+        file:notes.db?compression=lz4
+
+    which trades some CPU on every page read from or written to the file for a file that
+    takes a good deal less room when its tables are mostly text. It is only for a new
+    database, one made without it stays as it is, and a compressed file is found to be
+    one when it is opened again whatever the filename says.
+
+    CompressedFile is a VfsFile over the real one, so the pager, the journal and the
+    header reading above it see the pages as they always do, page n at offset (n - 1)
+    times the page size, and only the bytes that reach the disk are different. Each page
+    written goes through LZ4, see lz4.rs, and as it comes back from the file, before the
+    cache holds it, it is decompressed again. A page that doesn't get any smaller is kept
+    as it is.
+
+    The file is cut into chunks of 512 bytes. The first holds a header: "sqlite-clone lz4",
+    then the page size, the number of pages and a version, 4, 4 and 8 bytes big-endian.
+    Each page after is a frame taking as few whole chunks as it fits in:
+
+        magic       "page"
+        page        its number, 4 bytes
+        generation  8 bytes, one more than any frame written before it
+        length      how many bytes of it follow the frame's header, 4 bytes
+        codec       a byte, 0 for a page kept as it is and 1 for LZ4
+                    3 bytes of 0
+        checksum    FNV-1a of the frame's header before it and of what follows, 4 bytes
+
+    Where each page's frame is, and which chunks are free, is only kept in memory, and
+    worked out when the file is opened by reading it through once: a frame whose checksum
+    is right is the page's if no other has a later generation. So a page rewritten where
+    it was before and cut short by a crash is lost, as it would be in a file that isn't
+    compressed, and the journal puts it back, while one moved elsewhere leaves the frame
+    it had behind until the new one is written. A new frame goes where the page's last one
+    was if it fits, and otherwise in the first free run of chunks it fits in, or at the
+    end. Chunks left free at the end are cut off the file when it is next synced.
+
+    A connection that writes to the file bumps the version in its header first, so
+    another that takes a lock on the file after and finds the version changed reads it
+    through again to find where the pages have gone.
+
+    zstd isn't supported, and a backup, see backup.rs, is written to an ordinary file.
+*/
+use super::lock::{LockLevel, LockStatus};
+use super::lz4;
+use super::os_interface::VfsFile;
+use super::wal::PageNumber;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+const CHUNK: u64 = 512;
+const MAGIC: &[u8; 16] = b"sqlite-clone lz4";
+const HEADER_SIZE: usize = 32;
+const FRAME_MAGIC: &[u8; 4] = b"page";
+const FRAME_HEADER: usize = 28;
+// How a frame's page is kept.
+const RAW: u8 = 0;
+const LZ4: u8 = 1;
+
+/// How the pages of a new database file are to be kept.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+}
+
+impl Compression {
+    /// The compression a URI's compression parameter names.
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            other => bail!("no such compression: {other}"),
+        }
+    }
+}
+
+/// `file` as the pages above it are to see it: through a CompressedFile if it is a
+/// compressed file, or is a new one and `compression` asks for it, and as it is if not.
+pub fn open(
+    mut file: impl VfsFile + Send + 'static,
+    compression: Compression,
+    page_size: u32,
+) -> Result<Box<dyn VfsFile + Send>> {
+    if is_compressed(&mut file)? {
+        return Ok(Box::new(CompressedFile::open(file, page_size)?));
+    }
+    match compression {
+        Compression::None => Ok(Box::new(file)),
+        Compression::Lz4 if file.size()? > 0 => {
+            bail!("compression=lz4 only applies to a new database")
+        }
+        Compression::Lz4 => Ok(Box::new(CompressedFile::open(file, page_size)?)),
+    }
+}
+
+/// Whether `file` starts with a compressed file's header.
+pub fn is_compressed(file: &mut dyn VfsFile) -> Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    Ok(file.read_at(0, &mut magic)? == MAGIC.len() && magic == *MAGIC)
+}
+
+// Where a page's frame is, in chunks.
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    chunk: u64,
+    chunks: u64,
+}
+
+/// A file of compressed pages, as the file of them all one after another.
+#[derive(Debug)]
+pub struct CompressedFile<F: VfsFile> {
+    file: F,
+    page_size: usize,
+    page_count: PageNumber,
+    // bumped by the first write each time a connection locks the file
+    version: u64,
+    // the generation the next frame gets
+    generation: u64,
+    // each page's frame, by page number less one
+    frames: Vec<Option<Frame>>,
+    // the free runs of chunks before the end, their lengths by where they start
+    free: BTreeMap<u64, u64>,
+    // the chunk after the last in use
+    end: u64,
+    // whether chunks at the end have been freed since the file was last cut short
+    shrunk: bool,
+    level: LockLevel,
+    // whether the version has been bumped for the lock held
+    written: bool,
+}
+
+impl<F: VfsFile> CompressedFile<F> {
+    /// The compressed file in `file`, made one with pages of `page_size` if it is empty.
+    pub fn open(file: F, page_size: u32) -> Result<Self> {
+        let mut compressed = CompressedFile {
+            file,
+            page_size: page_size as usize,
+            page_count: 0,
+            version: 0,
+            generation: 1,
+            frames: vec![],
+            free: BTreeMap::new(),
+            end: 1,
+            shrunk: false,
+            level: LockLevel::Unlocked,
+            written: false,
+        };
+        if compressed.file.size()? == 0 {
+            compressed.write_header()?;
+        } else {
+            compressed.scan()?;
+        }
+        Ok(compressed)
+    }
+
+    // Read the file through, finding each page's frame and the free chunks between.
+    fn scan(&mut self) -> Result<()> {
+        let mut bytes = vec![0; self.file.size()? as usize];
+        self.file.read_at(0, &mut bytes)?;
+        if bytes.len() < HEADER_SIZE || bytes[..MAGIC.len()] != *MAGIC {
+            bail!("file is not a compressed database");
+        }
+        self.page_size = be_u32(&bytes[16..]) as usize;
+        self.page_count = be_u32(&bytes[20..]);
+        self.version = u64::from_be_bytes(bytes[24..32].try_into()?);
+
+        self.frames = vec![None; self.page_count as usize];
+        let mut generations = vec![0; self.page_count as usize];
+        let chunks = (bytes.len() as u64).div_ceil(CHUNK);
+        let mut chunk = 1;
+        while chunk < chunks {
+            let Some((page, generation, length)) = frame_at(&bytes, chunk) else {
+                chunk += 1;
+                continue;
+            };
+            let frame = Frame {
+                chunk,
+                chunks: frame_chunks(length),
+            };
+            self.generation = self.generation.max(generation + 1);
+            if let Some(seen) = generations.get_mut((page as usize).wrapping_sub(1)) {
+                if generation > *seen {
+                    *seen = generation;
+                    self.frames[page as usize - 1] = Some(frame);
+                }
+            }
+            chunk += frame.chunks;
+        }
+
+        // whatever isn't a page's frame is free
+        let mut used: Vec<Frame> = self.frames.iter().flatten().copied().collect();
+        used.sort_by_key(|frame| frame.chunk);
+        self.free.clear();
+        let mut next = 1;
+        for frame in used {
+            if frame.chunk > next {
+                self.free.insert(next, frame.chunk - next);
+            }
+            next = frame.chunk + frame.chunks;
+        }
+        self.end = next;
+        self.shrunk = chunks > next;
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let mut header = MAGIC.to_vec();
+        header.extend((self.page_size as u32).to_be_bytes());
+        header.extend(self.page_count.to_be_bytes());
+        header.extend(self.version.to_be_bytes());
+        self.file.write_at(0, &header)
+    }
+
+    // Bump the version, the first time the file is written to under the lock held.
+    fn begin_write(&mut self) -> Result<()> {
+        if !self.written {
+            self.written = true;
+            self.version += 1;
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    // The page as it was written, all zeroes if it never was.
+    fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+        let Some(Some(frame)) = self.frames.get(page as usize - 1).copied() else {
+            return Ok(vec![0; self.page_size]);
+        };
+        let mut bytes = vec![0; (frame.chunks * CHUNK) as usize];
+        let read = self.file.read_at(frame.chunk * CHUNK, &mut bytes)?;
+        bytes.truncate(read);
+        let damaged = || format!("database disk image is malformed: page {page} is damaged");
+        let Some((found, _, length)) = frame_at(&bytes, 0) else {
+            bail!(damaged());
+        };
+        let payload = &bytes[FRAME_HEADER..FRAME_HEADER + length];
+        match bytes[20] {
+            _ if found != page => bail!(damaged()),
+            RAW if length == self.page_size => Ok(payload.to_vec()),
+            LZ4 => lz4::decompress(payload, self.page_size).map_err(|_| anyhow::anyhow!(damaged())),
+            _ => bail!(damaged()),
+        }
+    }
+
+    fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+        self.begin_write()?;
+        let compressed = lz4::compress(data);
+        let (codec, payload) = match compressed.len() < data.len() {
+            true => (LZ4, compressed.as_slice()),
+            false => (RAW, data),
+        };
+        let frame = encode_frame(page, self.generation, codec, payload);
+        self.generation += 1;
+        let chunks = frame_chunks(payload.len());
+
+        let index = page as usize - 1;
+        if self.frames.len() <= index {
+            self.frames.resize(index + 1, None);
+        }
+        let chunk = match self.frames[index] {
+            Some(old) if old.chunks >= chunks => {
+                self.release(old.chunk + chunks, old.chunks - chunks);
+                self.file.write_at(old.chunk * CHUNK, &frame)?;
+                old.chunk
+            }
+            // the old frame is kept until the new one is written
+            old => {
+                let chunk = self.allocate(chunks);
+                self.file.write_at(chunk * CHUNK, &frame)?;
+                if let Some(old) = old {
+                    self.release(old.chunk, old.chunks);
+                }
+                chunk
+            }
+        };
+        self.frames[index] = Some(Frame { chunk, chunks });
+        if page > self.page_count {
+            self.page_count = page;
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    // The first chunk of `count` free ones one after another, from the first free run
+    // they fit in or past the end.
+    fn allocate(&mut self, count: u64) -> u64 {
+        let fits = self.free.iter().find(|(_, length)| **length >= count);
+        let Some((&start, &length)) = fits else {
+            self.end += count;
+            return self.end - count;
+        };
+        self.free.remove(&start);
+        if length > count {
+            self.free.insert(start + count, length - count);
+        }
+        start
+    }
+
+    // Free `count` chunks from `start` on, joining them to the runs either side.
+    fn release(&mut self, mut start: u64, mut count: u64) {
+        if count == 0 {
+            return;
+        }
+        if let Some((&before, &length)) = self.free.range(..start).next_back() {
+            if before + length == start {
+                self.free.remove(&before);
+                start = before;
+                count += length;
+            }
+        }
+        if let Some(length) = self.free.remove(&(start + count)) {
+            count += length;
+        }
+        if start + count == self.end {
+            self.end = start;
+            self.shrunk = true;
+        } else {
+            self.free.insert(start, count);
+        }
+    }
+}
+
+impl<F: VfsFile> VfsFile for CompressedFile<F> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let size = self.size()?;
+        let wanted = buf.len().min(size.saturating_sub(offset) as usize);
+        let mut read = 0;
+        while read < wanted {
+            let at = offset + read as u64;
+            let page = (at / self.page_size as u64) as PageNumber + 1;
+            let within = (at % self.page_size as u64) as usize;
+            let count = (self.page_size - within).min(wanted - read);
+            let data = self.read_page(page)?;
+            buf[read..read + count].copy_from_slice(&data[within..within + count]);
+            read += count;
+        }
+        Ok(read)
+    }
+
+    // A write of part of a page reads the rest of it first.
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let mut written = 0;
+        while written < data.len() {
+            let at = offset + written as u64;
+            let page = (at / self.page_size as u64) as PageNumber + 1;
+            let within = (at % self.page_size as u64) as usize;
+            let count = (self.page_size - within).min(data.len() - written);
+            let part = &data[written..written + count];
+            if count == self.page_size {
+                self.write_page(page, part)?;
+            } else {
+                let mut whole = self.read_page(page)?;
+                whole[within..within + count].copy_from_slice(part);
+                self.write_page(page, &whole)?;
+            }
+            written += count;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.shrunk {
+            self.file.truncate(self.end * CHUNK)?;
+            self.shrunk = false;
+        }
+        self.file.sync()
+    }
+
+    // The pages cut off are forgotten and their frames spoilt, so that reading the file
+    // through doesn't find them again if it grows back.
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        let count = size.div_ceil(self.page_size as u64) as PageNumber;
+        if count >= self.page_count {
+            return Ok(());
+        }
+        self.begin_write()?;
+        self.page_count = count;
+        self.write_header()?;
+        let cut: Vec<Frame> = self
+            .frames
+            .drain((count as usize).min(self.frames.len())..)
+            .flatten()
+            .collect();
+        for frame in cut {
+            self.file
+                .write_at(frame.chunk * CHUNK, &[0; FRAME_MAGIC.len()])?;
+            self.release(frame.chunk, frame.chunks);
+        }
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.page_count as u64 * self.page_size as u64)
+    }
+
+    // Taking a lock again, where another connection may have written since, reads the
+    // file through again if it has.
+    fn lock(&mut self, level: LockLevel) -> Result<()> {
+        self.file.lock(level)?;
+        if self.level == LockLevel::Unlocked {
+            self.written = false;
+            let mut header = [0; HEADER_SIZE];
+            self.file.read_at(0, &mut header)?;
+            if u64::from_be_bytes(header[24..32].try_into()?) != self.version {
+                self.scan()?;
+            }
+        }
+        self.level = self.level.max(level);
+        Ok(())
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> Result<()> {
+        self.file.unlock(level)?;
+        self.level = level;
+        if level < LockLevel::Reserved {
+            self.written = false;
+        }
+        Ok(())
+    }
+
+    fn lock_status(&self) -> Vec<LockStatus> {
+        self.file.lock_status()
+    }
+}
+
+fn encode_frame(page: PageNumber, generation: u64, codec: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER + payload.len());
+    frame.extend(FRAME_MAGIC);
+    frame.extend(page.to_be_bytes());
+    frame.extend(generation.to_be_bytes());
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend([codec, 0, 0, 0]);
+    let checksum = fnv1a(fnv1a(FNV_OFFSET, &frame), payload);
+    frame.extend(checksum.to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+// The page, generation and length of the frame starting at `chunk` of `bytes`, None if
+// there isn't a whole one there whose checksum is right.
+fn frame_at(bytes: &[u8], chunk: u64) -> Option<(PageNumber, u64, usize)> {
+    let frame = bytes.get((chunk * CHUNK) as usize..)?;
+    let header = frame.get(..FRAME_HEADER)?;
+    if header[..4] != *FRAME_MAGIC {
+        return None;
+    }
+    let length = be_u32(&header[16..]) as usize;
+    let payload = frame.get(FRAME_HEADER..FRAME_HEADER + length)?;
+    let checksum = fnv1a(fnv1a(FNV_OFFSET, &header[..24]), payload);
+    if checksum != be_u32(&header[24..]) {
+        return None;
+    }
+    let generation = u64::from_be_bytes(header[8..16].try_into().ok()?);
+    Some((be_u32(&header[4..]), generation, length))
+}
+
+// How many chunks a frame of `length` bytes after its header takes.
+fn frame_chunks(length: usize) -> u64 {
+    ((FRAME_HEADER + length) as u64).div_ceil(CHUNK)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+pub(super) const FNV_OFFSET: u32 = 0x811c_9dc5;
+
+pub(super) fn fnv1a(mut hash: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes {
+        hash = (hash ^ *byte as u32).wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memdb::{AccessMode, MemVfs};
+    use crate::storage::os_interface::Vfs;
+    use rand::RngCore;
+    use std::path::Path;
+
+    fn text_page(n: u8) -> Vec<u8> {
+        format!("row {n} of a table of text, ")
+            .repeat(200)
+            .as_bytes()[..4096]
+            .to_vec()
+    }
+
+    #[test]
+    fn pages_are_kept_compressed_and_read_back_as_they_were() {
+        let vfs = MemVfs::default();
+        let path = Path::new("test.db");
+        let mut file =
+            CompressedFile::open(vfs.open(path, AccessMode::Create).unwrap(), 4096).unwrap();
+        for n in 0..8 {
+            file.write_at(n as u64 * 4096, &text_page(n)).unwrap();
+        }
+        file.sync().unwrap();
+        assert_eq!(file.size().unwrap(), 8 * 4096);
+        let stored = vfs
+            .open(path, AccessMode::ReadOnly)
+            .unwrap()
+            .size()
+            .unwrap();
+        assert!(stored < 8 * 4096 / 4, "{stored}");
+
+        // a page that doesn't compress is kept as it is, somewhere it fits
+        let mut random = vec![0; 4096];
+        rand::thread_rng().fill_bytes(&mut random);
+        file.write_at(2 * 4096, &random).unwrap();
+        // and part of a page is written over what it had
+        file.write_at(5 * 4096 + 10, b"changed").unwrap();
+        let mut page = vec![0; 4096];
+        file.read_at(5 * 4096, &mut page).unwrap();
+        assert_eq!(&page[10..17], b"changed");
+
+        // opened again, the file is read through for where its pages are
+        let mut reopened =
+            CompressedFile::open(vfs.open(path, AccessMode::ReadWrite).unwrap(), 512).unwrap();
+        assert_eq!(reopened.page_size, 4096);
+        let mut all = vec![0; 8 * 4096];
+        assert_eq!(reopened.read_at(0, &mut all).unwrap(), 8 * 4096);
+        assert_eq!(all[..4096], text_page(0));
+        assert_eq!(all[2 * 4096..3 * 4096], random);
+        assert_eq!(&all[5 * 4096 + 10..5 * 4096 + 17], b"changed");
+        assert_eq!(all[7 * 4096..], text_page(7));
+
+        // cut short, the pages past the end are gone for good
+        reopened.truncate(3 * 4096).unwrap();
+        reopened.write_at(5 * 4096, &text_page(9)).unwrap();
+        reopened.sync().unwrap();
+        let mut reopened =
+            CompressedFile::open(vfs.open(path, AccessMode::ReadWrite).unwrap(), 4096).unwrap();
+        assert_eq!(reopened.size().unwrap(), 6 * 4096);
+        let mut page = vec![1; 4096];
+        reopened.read_at(3 * 4096, &mut page).unwrap();
+        assert!(page.iter().all(|b| *b == 0));
+        reopened.read_at(5 * 4096, &mut page).unwrap();
+        assert_eq!(page, text_page(9));
+    }
+
+    #[test]
+    fn a_connection_finds_pages_another_has_moved() {
+        let vfs = MemVfs::default();
+        let path = Path::new("test.db");
+        let open =
+            || CompressedFile::open(vfs.open(path, AccessMode::Create).unwrap(), 4096).unwrap();
+        let mut writer = open();
+        writer.write_at(0, &text_page(1)).unwrap();
+        let mut reader = open();
+
+        writer.lock(LockLevel::Shared).unwrap();
+        writer.lock(LockLevel::Exclusive).unwrap();
+        let mut random = vec![0; 4096];
+        rand::thread_rng().fill_bytes(&mut random);
+        writer.write_at(0, &random).unwrap();
+        writer.write_at(4096, &text_page(2)).unwrap();
+        writer.unlock(LockLevel::Unlocked).unwrap();
+
+        reader.lock(LockLevel::Shared).unwrap();
+        let mut pages = vec![0; 2 * 4096];
+        assert_eq!(reader.read_at(0, &mut pages).unwrap(), 2 * 4096);
+        assert_eq!(pages[..4096], random);
+        assert_eq!(pages[4096..], text_page(2));
+    }
+}
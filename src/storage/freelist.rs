@@ -0,0 +1,160 @@
+/*
+    The freelist, the pages of the database that nothing uses any more, kept on disk as
+    SQLite keeps it so that a page freed by a merge or an overflow chain that got shorter
+    is used again before the file grows.
+
+    The header holds the first trunk page of the list and how many pages are free in all
+    (see header.rs). A trunk page is itself a free page, holding the numbers of others:
+
+        0   u32 the next trunk page, 0 on the last
+        4   u32 how many leaf pages follow
+        8   u32 each leaf page, one after another
+
+    A leaf page holds nothing, it is only free. The trunk pages count among the free
+    pages, so the header's count is every trunk and every leaf. A trunk has room for
+    usable_size / 4 - 2 leaves, and that many are read, but like SQLite we write no more
+    than usable_size / 4 - 8 of them, as versions of SQLite before 3.6.0 read no more.
+
+    The list is written whole from the free pages the pager keeps in memory (see pager.rs),
+    in page order so that a page reused from it is as near the start of the file as can be.
+*/
+use super::cache::PageStore;
+use super::header::DatabaseHeader;
+use super::wal::PageNumber;
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+
+// the next trunk and the leaf count at the start of a trunk page
+const TRUNK_HEADER_SIZE: usize = 8;
+
+// How many leaves a trunk page of this database is written with.
+fn leaves_per_trunk(header: &DatabaseHeader) -> usize {
+    header.usable_size() / 4 - 8
+}
+
+/// Write `pages` out as the freelist, trunk pages first among each run of them, and
+/// record its first trunk and its length in `header`.
+pub fn write(
+    pages: &[PageNumber],
+    store: &mut dyn PageStore,
+    header: &mut DatabaseHeader,
+) -> Result<()> {
+    let mut pages = pages.to_vec();
+    pages.sort();
+    let trunks: Vec<&[PageNumber]> = pages.chunks(leaves_per_trunk(header) + 1).collect();
+    for (i, trunk) in trunks.iter().enumerate() {
+        let next = trunks.get(i + 1).map_or(0, |next| next[0]);
+        let leaves = &trunk[1..];
+        let mut data = vec![0; header.page_size as usize];
+        data[..4].copy_from_slice(&next.to_be_bytes());
+        data[4..8].copy_from_slice(&(leaves.len() as u32).to_be_bytes());
+        for (leaf, slot) in leaves.iter().zip(data[TRUNK_HEADER_SIZE..].chunks_mut(4)) {
+            slot.copy_from_slice(&leaf.to_be_bytes());
+        }
+        store.write_page(trunk[0], &data)?;
+    }
+    header.freelist_trunk = trunks.first().map_or(0, |trunk| trunk[0]);
+    header.freelist_count = pages.len() as u32;
+    Ok(())
+}
+
+/// Every page on the freelist `header` points at, trunks and leaves, in page order.
+pub fn read(store: &mut dyn PageStore, header: &DatabaseHeader) -> Result<Vec<PageNumber>> {
+    let most_leaves = header.usable_size() / 4 - 2;
+    let mut pages = BTreeSet::new();
+    let mut trunk = header.freelist_trunk;
+    while trunk != 0 {
+        if !pages.insert(trunk) {
+            bail!("database disk image is malformed: the freelist loops back to page {trunk}");
+        }
+        let data = store.read_page(trunk)?;
+        let word = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+        let leaves = word(4) as usize;
+        if leaves > most_leaves {
+            bail!("database disk image is malformed: freelist trunk {trunk} has {leaves} leaves");
+        }
+        for i in 0..leaves {
+            let leaf = word(TRUNK_HEADER_SIZE + 4 * i);
+            if leaf == 0 || !pages.insert(leaf) {
+                bail!("database disk image is malformed: page {leaf} on the freelist");
+            }
+        }
+        trunk = word(0);
+    }
+    if pages.len() != header.freelist_count as usize {
+        bail!(
+            "database disk image is malformed: the freelist has {} pages, not {}",
+            pages.len(),
+            header.freelist_count
+        );
+    }
+    Ok(pages.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::pager::PagerConfig;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct Store {
+        pages: HashMap<PageNumber, Vec<u8>>,
+    }
+
+    impl PageStore for Store {
+        fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+            match self.pages.get(&page) {
+                Some(data) => Ok(data.clone()),
+                None => bail!("no page {page}"),
+            }
+        }
+
+        fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+            self.pages.insert(page, data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn free_pages_read_back_as_written() {
+        let mut config = PagerConfig::default();
+        config.set_page_size(512);
+        let mut header = DatabaseHeader::new(&config);
+        let mut store = Store::default();
+        let free: Vec<PageNumber> = (2..600).step_by(2).rev().collect();
+        write(&free, &mut store, &mut header).unwrap();
+        // 120 leaves a trunk, so two full trunks and one of the 57 pages left
+        assert_eq!(store.pages.len(), 3);
+        assert_eq!(header.freelist_trunk, 2);
+        assert_eq!(header.freelist_count, 299);
+        assert_eq!(&store.pages[&2][..8], [0, 0, 0, 244, 0, 0, 0, 120]);
+        let mut sorted = free.clone();
+        sorted.sort();
+        assert_eq!(read(&mut store, &header).unwrap(), sorted);
+
+        write(&[], &mut store, &mut header).unwrap();
+        assert_eq!((header.freelist_trunk, header.freelist_count), (0, 0));
+        assert!(read(&mut store, &header).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_freelists_are_refused() {
+        let mut header = DatabaseHeader::new(&PagerConfig::default());
+        let mut store = Store::default();
+        write(&[3, 5, 7], &mut store, &mut header).unwrap();
+        header.freelist_count = 4;
+        assert_eq!(
+            read(&mut store, &header).unwrap_err().to_string(),
+            "database disk image is malformed: the freelist has 3 pages, not 4"
+        );
+
+        // a trunk whose next trunk is itself
+        header.freelist_count = 3;
+        store.pages.get_mut(&3).unwrap()[..4].copy_from_slice(&3u32.to_be_bytes());
+        assert_eq!(
+            read(&mut store, &header).unwrap_err().to_string(),
+            "database disk image is malformed: the freelist loops back to page 3"
+        );
+    }
+}
@@ -0,0 +1,229 @@
+/*
+    The database header, the first 100 bytes of the file, laid out as SQLite lays out its
+    own so that the file says how to read the rest of it.
+
+        0   16  "SQLite format 3\0"
+        16  u16 page size, 1 meaning 65536
+        18  u8  file format write version, 1 for a rollback journal and 2 for WAL
+        19  u8  file format read version, the same
+        20  u8  bytes reserved at the end of every page
+        21  u8  maximum embedded payload fraction, always 64
+        22  u8  minimum embedded payload fraction, always 32
+        23  u8  leaf payload fraction, always 32
+        24  u32 the file change counter, bumped by every commit in rollback journal mode
+        28  u32 the size of the database in pages
+        32  u32 the first freelist trunk page, 0 when no page is free
+        36  u32 how many pages are free, see freelist.rs
+        40  u32 the schema cookie, bumped by every schema change
+        44  u32 schema format number, 4
+        56  u32 text encoding, 1 for UTF-8
+
+    The rest of the 100 bytes, counters and the like that nothing keeps yet, are zero. All
+    numbers are big endian.
+
+    The pager writes the header at the start of page 1 whenever it commits, see pager.rs,
+    and a new database file is never without one. Opening a file reads it back and checks
+    it before anything else is read: a file too short for its header or for the pages its
+    header counts is truncated, and one of a format version, schema format or text
+    encoding this engine doesn't know is refused rather than misread. Only UTF-8 is
+    supported, where SQLite also allows UTF-16.
+
+    The reserved bytes are the part a reader most needs: cells stop that many bytes short
+    of the end of each page, see page.rs, and how much of a row a cell holds is worked out
+    from what is left, the usable size, see overflow.rs. A file whose header claims
+    fewer than 480 usable bytes per page is refused, as SQLite refuses it.
+*/
+use super::os_interface::VfsFile;
+use super::page::MIN_USABLE_SIZE;
+use super::pager::{JournalMode, PagerConfig};
+use super::wal::PageNumber;
+use anyhow::{bail, Result};
+
+pub const HEADER_SIZE: usize = 100;
+
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+// The newest file format version, 2 for WAL, and schema format number this engine reads.
+const FORMAT_VERSION: u8 = 2;
+const SCHEMA_FORMAT: u32 = 4;
+const UTF8: u32 = 1;
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct DatabaseHeader {
+    pub page_size: u32,
+    pub reserved_bytes: u8,
+    pub wal: bool,
+    pub page_count: u32,
+    pub freelist_trunk: PageNumber,
+    pub freelist_count: u32,
+    pub schema_cookie: u32,
+    pub change_counter: u32,
+}
+
+impl DatabaseHeader {
+    pub fn new(config: &PagerConfig) -> Self {
+        DatabaseHeader {
+            page_size: config.page_size,
+            reserved_bytes: config.reserved_bytes,
+            wal: config.journal_mode == JournalMode::Wal,
+            page_count: 0,
+            freelist_trunk: 0,
+            freelist_count: 0,
+            schema_cookie: 0,
+            change_counter: 0,
+        }
+    }
+
+    /// The header at the start of `file`, checked, or None if the file is empty and so a
+    /// new database.
+    pub fn read(file: &mut dyn VfsFile) -> Result<Option<Self>> {
+        let size = file.size()?;
+        if size == 0 {
+            return Ok(None);
+        }
+        let mut bytes = [0; HEADER_SIZE];
+        let read = file.read_at(0, &mut bytes)?;
+        if read < HEADER_SIZE {
+            bail!("database disk image is malformed: the file is truncated, {read} bytes is too short for its header");
+        }
+        let header = DatabaseHeader::parse(&bytes)?;
+        // a page cut short counts as missing
+        let pages = size / header.page_size as u64;
+        if pages < header.page_count as u64 {
+            bail!(
+                "database disk image is malformed: the file is truncated, its header counts {} pages but it holds {pages}",
+                header.page_count
+            );
+        }
+        Ok(Some(header))
+    }
+
+    /// The bytes of each page that cells may use.
+    pub fn usable_size(&self) -> usize {
+        self.page_size as usize - self.reserved_bytes as usize
+    }
+
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0; HEADER_SIZE];
+        bytes[..16].copy_from_slice(MAGIC);
+        // 65536 doesn't fit in a u16 and is written as 1
+        let page_size = if self.page_size == 65536 {
+            1
+        } else {
+            self.page_size as u16
+        };
+        bytes[16..18].copy_from_slice(&page_size.to_be_bytes());
+        let version = if self.wal { 2 } else { 1 };
+        bytes[18] = version;
+        bytes[19] = version;
+        bytes[20] = self.reserved_bytes;
+        bytes[21..24].copy_from_slice(&[64, 32, 32]);
+        bytes[24..28].copy_from_slice(&self.change_counter.to_be_bytes());
+        bytes[28..32].copy_from_slice(&self.page_count.to_be_bytes());
+        bytes[32..36].copy_from_slice(&self.freelist_trunk.to_be_bytes());
+        bytes[36..40].copy_from_slice(&self.freelist_count.to_be_bytes());
+        bytes[40..44].copy_from_slice(&self.schema_cookie.to_be_bytes());
+        bytes[44..48].copy_from_slice(&SCHEMA_FORMAT.to_be_bytes());
+        bytes[56..60].copy_from_slice(&UTF8.to_be_bytes());
+        bytes
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[..16] != MAGIC {
+            bail!("file is not a database");
+        }
+        let page_size = match u16::from_be_bytes([bytes[16], bytes[17]]) {
+            1 => 65536,
+            size => size as u32,
+        };
+        if !(512..=65536).contains(&page_size) || page_size.count_ones() != 1 {
+            bail!("file is not a database: page size {page_size}");
+        }
+        if bytes[21..24] != [64, 32, 32] {
+            bail!("file is not a database: bad payload fractions");
+        }
+        let (write_version, read_version) = (bytes[18], bytes[19]);
+        if !(1..=FORMAT_VERSION).contains(&read_version)
+            || !(1..=FORMAT_VERSION).contains(&write_version)
+        {
+            bail!("unsupported file format: version {write_version}.{read_version}, this engine reads up to {FORMAT_VERSION}");
+        }
+        let schema_format = u32_at(bytes, 44);
+        if !(1..=SCHEMA_FORMAT).contains(&schema_format) {
+            bail!("unsupported file format: schema format {schema_format}");
+        }
+        match u32_at(bytes, 56) {
+            UTF8 => {}
+            2 | 3 => bail!("unsupported file format: UTF-16 text, only UTF-8 is supported"),
+            encoding => bail!("file is not a database: text encoding {encoding}"),
+        }
+        let header = DatabaseHeader {
+            page_size,
+            reserved_bytes: bytes[20],
+            wal: bytes[18] == 2,
+            page_count: u32_at(bytes, 28),
+            freelist_trunk: u32_at(bytes, 32),
+            freelist_count: u32_at(bytes, 36),
+            schema_cookie: u32_at(bytes, 40),
+            change_counter: u32_at(bytes, 24),
+        };
+        if header.usable_size() < MIN_USABLE_SIZE {
+            bail!(
+                "file is not a database: {} reserved bytes leave too little of a {page_size} byte page",
+                header.reserved_bytes
+            );
+        }
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_read_back_as_written() {
+        let mut config = PagerConfig::default();
+        config.set_page_size(65536);
+        config.set_reserved_bytes(40);
+        config.journal_mode = JournalMode::Wal;
+        let mut header = DatabaseHeader::new(&config);
+        header.page_count = 7;
+        header.freelist_trunk = 3;
+        header.freelist_count = 2;
+        let bytes = header.to_bytes();
+        assert_eq!(&bytes[28..40], [0, 0, 0, 7, 0, 0, 0, 3, 0, 0, 0, 2]);
+        assert_eq!(&bytes[16..24], [0, 1, 2, 2, 40, 64, 32, 32]);
+        assert_eq!(DatabaseHeader::parse(&bytes).unwrap(), header);
+        assert_eq!(header.usable_size(), 65536 - 40);
+
+        // too few usable bytes left
+        let mut bytes = DatabaseHeader::new(&PagerConfig::default()).to_bytes();
+        bytes[16..18].copy_from_slice(&512u16.to_be_bytes());
+        bytes[20] = 33;
+        assert!(DatabaseHeader::parse(&bytes).is_err());
+        assert!(DatabaseHeader::parse(b"not a database").is_err());
+    }
+
+    #[test]
+    fn formats_this_engine_doesnt_know_are_refused() {
+        let header = DatabaseHeader::new(&PagerConfig::default());
+        let refused = |at: usize, value: u8| {
+            let mut bytes = header.to_bytes();
+            bytes[at] = value;
+            DatabaseHeader::parse(&bytes).unwrap_err().to_string()
+        };
+        assert_eq!(
+            refused(19, 3),
+            "unsupported file format: version 1.3, this engine reads up to 2"
+        );
+        assert_eq!(refused(47, 5), "unsupported file format: schema format 5");
+        assert_eq!(
+            refused(59, 2),
+            "unsupported file format: UTF-16 text, only UTF-8 is supported"
+        );
+    }
+}
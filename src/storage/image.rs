@@ -0,0 +1,1224 @@
+/*
+    The database file as a connection that opened one keeps its tables in it, for now.
+
+    Tables and indexes live in memory, in B+trees the executor holds (see executor.rs), and
+    the file keeps every row of every table in a B+tree of its own (see btree.rs), whose
+    root is always page 2, after page 1 and the header at its start (see header.rs). Each
+    row is an entry of the tree, keyed by a record (see record.rs) of its table's name and
+    its rowid, which keeps rows that have no INTEGER PRIMARY KEY under the rowids they had,
+    with a record of its values. A WITHOUT ROWID table's rows have no rowid, so the key is
+    the table's name, NULL and then the row's values, its primary key among them, and the
+    value is empty. Keys are compared byte by byte, which keeps a table's rows together
+    but not in order of rowid, so loading sorts them, the rows of sqlite_master first so
+    that it makes the tables before it fills them. A separator on an inner page is only as
+    many of a key's first bytes as tell it from the key before, and as the keys of a table
+    begin with its name they share much of what is left, which inner pages don't write
+    twice (see Node::to_page), so an inner page has room for many children. All the rows together are the image
+    of the database.
+
+    Saving reads the tree from its pages, inserts the rows that are new or have changed,
+    deletes those that are gone, and flushes the nodes that changed through the pager (see
+    Btree::flush), which takes pages from the freelist or past the end as the tree grows
+    and frees those it no longer needs. So a save is one pager transaction, committed
+    through the rollback journal next to the file, and a crash in the middle of one leaves
+    the file as it was before it. A new file can keep its pages compressed, see
+    compress.rs.
+
+    Each save moves the header's change counter on, and one that changes the schema the
+    schema cookie too, so that another connection with the file open can tell its copy of
+    the tables is out of date and load the image again. A save takes the RESERVED lock
+    and then checks the counter is still the one the connection's tables were loaded or
+    last saved at, refusing to write an image older than the file's over it. BEGIN
+    IMMEDIATE and EXCLUSIVE take their locks on the pager straight away instead, and the
+    transaction's save, or its rollback, lets go of them. Within such a transaction
+    SAVEPOINT, RELEASE and ROLLBACK TO open and close savepoints on the pager as well,
+    so that rolling back to one puts its header and freelist back as they were.
+
+    A shared in-memory database, one opened with mode=memory&cache=shared, is kept the
+    same way in a file in memory that every connection to it in the process opens, see
+    memdb.rs, so that each sees the others' commits as it would on disk.
+
+    Connections in one process that open the same file with cache=shared in its URI
+    share a single pager for it, and with it the page cache, the file handle and the
+    journal, rather than each reading the file into a cache of its own. The pager sits
+    behind a mutex, so a save or a load through one waits for the others', and the cache
+    size and busy handler are whichever a sharer set last. They share the file's rows as
+    well: the first load reads the tree's entries into a B+tree with a latch on each node
+    (see latch.rs), which each save brings up to date as it commits, and the sharers load
+    from it rather than reading the file's tree from its pages, on threads of their own
+    at once and while one of them saves. Each connection still makes its own tables and
+    schema from the rows it loads, see executor.rs, so those are not shared: only the
+    pages and the rows under them are. The registry of shared pagers holds
+    weak references, so the pager goes once the last connection sharing it closes.
+
+    PRAGMA journal_mode = WAL puts the file in WAL mode, marked so in its header so that
+    it opens in it again: a save is then a commit to a write-ahead log, see wal.rs, which
+    readers don't wait for, rather than through the rollback journal. The log is shared by
+    every connection to the file in the process, as SQLite shares one with
+    locking_mode=EXCLUSIVE, and a handle of the log's own holds the file's EXCLUSIVE lock
+    meanwhile, so that no other process reads a file the log is ahead of. Each commit is
+    written to the log's file beside the database, "app.db-wal", and a log opened on a
+    file that has one reads its commits back, so a commit that has yet to be checkpointed
+    survives the process. A save checkpoints the log into the file once it has 1000
+    frames, and the last connection to close checkpoints the rest and deletes the log's
+    file.
+
+    Only the pages of nodes that changed are written, but the executor still holds its
+    tables in trees of its own, so a save reads the whole of the file's tree to compare
+    every row with it, and a load reads the whole of it back.
+*/
+use super::btree::{Btree, Comparator, TreeStore};
+use super::busy::BusyHandler;
+use super::cache::{CacheReport, PageStore};
+use super::compress::{self, Compression};
+use super::header::DatabaseHeader;
+use super::journal;
+use super::latch::LatchedBtree;
+use super::lock::{LockLevel, LockStatus};
+use super::memdb::{AccessMode, OpenTarget};
+use super::os_interface::{OsVfs, PageFile, Vfs, VfsFile};
+use super::pager::{Pager, PagerConfig};
+use super::record;
+use super::replacement::Replacement;
+use super::wal::{self, PageNumber, Wal};
+use crate::catalog;
+use crate::sql_parser::ast::{ColVal, TransactionMode};
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, Weak};
+
+// Whom the image's pages are counted against in the cache's statistics.
+const OWNER: &str = "image";
+/// The page the file's tree has its root on.
+pub const ROOT_PAGE: PageNumber = 2;
+// How many frames the log of a file in WAL mode may have before a save checkpoints it,
+// as SQLite's wal_autocheckpoint does by default.
+const AUTOCHECKPOINT_FRAMES: usize = 1000;
+
+/// A row of the image: its table's name, its rowid or NULL, and its values.
+pub type ImageRow = (String, ColVal, Vec<ColVal>);
+
+/// A database file and the image of the database in it.
+pub struct DatabaseFile {
+    path: PathBuf,
+    pager: Arc<Mutex<FilePager>>,
+    // whether BEGIN has the pager in a transaction for this connection, rather than
+    // another sharing the pager
+    in_transaction: bool,
+    // the log, in WAL mode
+    log: Option<Arc<FileLog>>,
+    // whether this is a shared in-memory database's file, which has nowhere for a log
+    memory: bool,
+    // the rows every connection sharing the pager loads, with cache=shared
+    shared_rows: Option<Arc<SharedRows>>,
+    // the change counter before the save prepare left open, and what it changed
+    saved: Option<(u32, Vec<Change>)>,
+}
+
+type FilePager = Pager<PageFile<Box<dyn VfsFile + Send>>>;
+
+// A file's pager and rows, as connections that open it with cache=shared share them.
+type SharedFile = (Weak<Mutex<FilePager>>, Weak<SharedRows>);
+
+// The pagers of the files open with cache=shared, by path, while a connection has them.
+fn shared_pagers() -> &'static Mutex<HashMap<PathBuf, SharedFile>> {
+    static SHARED: OnceLock<Mutex<HashMap<PathBuf, SharedFile>>> = OnceLock::new();
+    SHARED.get_or_init(Default::default)
+}
+
+// An entry of the file's tree a save puts in, with its value, or takes out.
+type Change = (Vec<u8>, Option<Vec<u8>>);
+
+// How many keys a node of the shared rows' tree holds.
+const SHARED_NODE_KEYS: usize = 64;
+
+// The entries of the tree of a file whose pager connections share, with cache=shared,
+// decoded for each of them to load rather than reading the tree's pages again, as of the
+// commit that left the file's change counter at `counter`. They are kept in a latched
+// tree, see latch.rs, which connections on other threads read at once while one of them
+// saves: a save changes it with the pager locked, moving `version` on before and after,
+// and a load reads it without, and tries again if the version moved meanwhile.
+struct SharedRows {
+    entries: LatchedBtree<Vec<u8>, Vec<u8>>,
+    // None until a load first reads the entries
+    counter: Mutex<Option<u32>>,
+    version: AtomicU64,
+}
+
+impl SharedRows {
+    fn new() -> Self {
+        SharedRows {
+            entries: LatchedBtree::new(SHARED_NODE_KEYS),
+            counter: Mutex::new(None),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    // The rows in the file `pager` is of, from the entries if they are as of its last
+    // commit, and otherwise from its pages, which the entries are then made over from.
+    fn load(&self, pager: &Mutex<FilePager>) -> Result<Vec<ImageRow>> {
+        loop {
+            let mut locked = pager.lock().unwrap();
+            if locked.in_transaction() {
+                // a connection's save is part way through, and reads see what it wrote
+                let entries = read_entries(&mut locked);
+                locked.end_read()?;
+                return rows_of(entries?);
+            }
+            let counter = locked.current_header()?.change_counter;
+            let mut current = self.counter.lock().unwrap();
+            if *current != Some(counter) {
+                let entries = read_entries(&mut locked);
+                let counter = locked.header().change_counter;
+                locked.end_read()?;
+                let entries = entries?;
+                self.change(|tree| {
+                    tree.clear();
+                    for (key, value) in &entries {
+                        tree.insert(key.clone(), value.clone());
+                    }
+                });
+                *current = Some(counter);
+                return rows_of(entries);
+            }
+            let version = self.version.load(Ordering::SeqCst);
+            drop(current);
+            drop(locked);
+            let entries: Vec<(Vec<u8>, Vec<u8>)> = self.entries.range(..).collect();
+            if self.version.load(Ordering::SeqCst) == version {
+                return rows_of(entries);
+            }
+        }
+    }
+
+    // Bring the entries into line with a save that moved the change counter from `before`
+    // to `after`, making `changes`, if they were as of `before`. Called with the pager
+    // locked.
+    fn saved(&self, before: u32, after: u32, changes: Vec<Change>) {
+        let mut current = self.counter.lock().unwrap();
+        if *current != Some(before) {
+            // the next load reads the pages
+            return;
+        }
+        self.change(|tree| {
+            for (key, value) in changes {
+                match value {
+                    Some(value) => tree.insert(key, value),
+                    None => tree.delete(&key),
+                };
+            }
+        });
+        *current = Some(after);
+    }
+
+    fn change(&self, change: impl FnOnce(&LatchedBtree<Vec<u8>, Vec<u8>>)) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+        change(&self.entries);
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// The write-ahead log of a file in WAL mode, which every connection to the file in the
+// process shares, and a handle of its own on the file holding the EXCLUSIVE lock, which
+// keeps other processes out for as long as the log is open here.
+struct FileLog {
+    wal: Arc<Mutex<Wal>>,
+    _lock: Mutex<Box<dyn VfsFile + Send>>,
+    // the log's file
+    path: PathBuf,
+}
+
+// The logs of the files in WAL mode, by path, while a connection has one open.
+fn shared_logs() -> &'static Mutex<HashMap<PathBuf, Weak<FileLog>>> {
+    static LOGS: OnceLock<Mutex<HashMap<PathBuf, Weak<FileLog>>>> = OnceLock::new();
+    LOGS.get_or_init(Default::default)
+}
+
+impl FileLog {
+    // The log of the file at `path`, of pages of `page_size` bytes, that of another
+    // connection in the process if one has it in WAL mode already. Otherwise the log is
+    // read back from its file, if there is one.
+    fn open(path: &Path, page_size: u32) -> Result<Arc<Self>> {
+        let key = fs::canonicalize(path)?;
+        let mut logs = shared_logs().lock().unwrap();
+        if let Some(log) = logs.get(&key).and_then(Weak::upgrade) {
+            return Ok(log);
+        }
+        let mut lock = OsVfs.open(path, AccessMode::ReadWrite)?;
+        lock.lock(LockLevel::Shared)?;
+        lock.lock(LockLevel::Exclusive)?;
+        let log_path = wal::wal_path(path);
+        let file = OsVfs.open(&log_path, AccessMode::Create)?;
+        let wal = Wal::open(Box::new(file), page_size, 0)
+            .with_context(|| format!("cannot open \"{}\"", log_path.display()))?;
+        let log = Arc::new(FileLog {
+            wal: Arc::new(Mutex::new(wal)),
+            _lock: Mutex::new(Box::new(lock)),
+            path: log_path,
+        });
+        logs.retain(|_, log| log.strong_count() > 0);
+        logs.insert(key, Arc::downgrade(&log));
+        Ok(log)
+    }
+}
+
+impl Drop for FileLog {
+    // The log's file goes once everything in it is in the database file.
+    fn drop(&mut self) {
+        if self.wal.lock().unwrap().close() {
+            let _ = OsVfs.delete(&self.path);
+        }
+    }
+}
+
+impl fmt::Debug for DatabaseFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DatabaseFile")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl DatabaseFile {
+    /// Open the database file at `path`, or create it if `mode` allows and there isn't
+    /// one, its journal beside it. A new file keeps its pages compressed as `compression`
+    /// says, see compress.rs.
+    pub fn open(
+        path: &Path,
+        mode: AccessMode,
+        compression: Compression,
+        config: &PagerConfig,
+    ) -> Result<Self> {
+        let file = OsVfs
+            .open(path, mode)
+            .with_context(|| format!("unable to open database file \"{}\"", path.display()))?;
+        let file = compress::open(file, compression, config.page_size)
+            .with_context(|| format!("cannot open \"{}\"", path.display()))?;
+        let journal = OsVfs.open(&journal::journal_path(path), AccessMode::Create)?;
+        // a file whose log is open in the process is read beside it, see open_file_beside_log
+        let logged = fs::canonicalize(path).is_ok_and(|key| {
+            let logs = shared_logs().lock().unwrap();
+            logs.get(&key).is_some_and(|log| log.strong_count() > 0)
+        });
+        let pager = match logged {
+            true => Pager::open_file_beside_log(file, config, Box::new(journal)),
+            false => Pager::open_file(file, config, Box::new(journal)),
+        }
+        .with_context(|| format!("cannot open \"{}\"", path.display()))?;
+        let wal = pager.header().wal;
+        let mut file = DatabaseFile {
+            path: path.to_path_buf(),
+            pager: Arc::new(Mutex::new(pager)),
+            in_transaction: false,
+            log: None,
+            memory: false,
+            shared_rows: None,
+            saved: None,
+        };
+        if wal {
+            file.join_log()?;
+        }
+        Ok(file)
+    }
+
+    /// Open the database file at `path` as open does, but with the pager of another
+    /// connection in the process that has it open this way already, if there is one, and
+    /// the rows loaded from it, which every such connection loads from in turn.
+    pub fn open_shared(
+        path: &Path,
+        mode: AccessMode,
+        compression: Compression,
+        config: &PagerConfig,
+    ) -> Result<Self> {
+        let mut shared = shared_pagers().lock().unwrap();
+        let found = fs::canonicalize(path).ok().and_then(|key| {
+            let (pager, rows) = shared.get(&key)?;
+            Some((pager.upgrade()?, rows.upgrade()?))
+        });
+        if let Some((pager, rows)) = found {
+            let log = {
+                let pager = pager.lock().unwrap();
+                match pager.in_wal_mode() {
+                    true => Some(FileLog::open(path, pager.header().page_size)?),
+                    false => None,
+                }
+            };
+            return Ok(DatabaseFile {
+                path: path.to_path_buf(),
+                pager,
+                in_transaction: false,
+                log,
+                memory: false,
+                shared_rows: Some(rows),
+                saved: None,
+            });
+        }
+        let mut file = DatabaseFile::open(path, mode, compression, config)?;
+        let rows = Arc::new(SharedRows::new());
+        // forget the pagers whose connections have all closed
+        shared.retain(|_, (pager, _)| pager.strong_count() > 0);
+        shared.insert(
+            fs::canonicalize(path)?,
+            (Arc::downgrade(&file.pager), Arc::downgrade(&rows)),
+        );
+        file.shared_rows = Some(rows);
+        Ok(file)
+    }
+
+    /// The file `target` names, None for an in-memory database.
+    pub fn open_target(target: &OpenTarget, config: &PagerConfig) -> Result<Option<Self>> {
+        match target {
+            OpenTarget::File {
+                path,
+                mode,
+                compression,
+                shared: true,
+                ..
+            } => Ok(Some(DatabaseFile::open_shared(
+                path,
+                *mode,
+                *compression,
+                config,
+            )?)),
+            OpenTarget::File {
+                path,
+                mode,
+                compression,
+                ..
+            } => Ok(Some(DatabaseFile::open(path, *mode, *compression, config)?)),
+            OpenTarget::Memory { shared: true, .. } => {
+                Ok(Some(DatabaseFile::open_memory(target, config)?))
+            }
+            OpenTarget::Memory { .. } => Ok(None),
+        }
+    }
+
+    // The in-memory database `target` names with cache=shared, a file in memory that every
+    // connection to it in the process reads and writes as they would a file on disk, see
+    // memdb.rs. Each keeps its journal in memory of its own.
+    fn open_memory(target: &OpenTarget, config: &PagerConfig) -> Result<Self> {
+        let OpenTarget::Memory { name, .. } = target else {
+            unreachable!("only called for an in-memory database");
+        };
+        let journal = OpenTarget::parse(":memory:")?.open()?;
+        let pager = Pager::open_file(target.open()?, config, journal)?;
+        Ok(DatabaseFile {
+            path: PathBuf::from(name),
+            pager: Arc::new(Mutex::new(pager)),
+            in_transaction: false,
+            log: None,
+            memory: true,
+            shared_rows: None,
+            saved: None,
+        })
+    }
+
+    /// Whether the file commits through a rollback journal on disk, and so can take part
+    /// in a commit across several files, see journal.rs: not a shared in-memory database
+    /// nor a file in WAL mode.
+    pub fn journals_on_disk(&self) -> bool {
+        !self.memory && self.log.is_none()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The lock each of the process's connections to the file holds and what it is
+    /// waiting for, see lock.rs.
+    pub fn lock_status(&self) -> Vec<LockStatus> {
+        self.pager().lock_status()
+    }
+
+    /// Change what is done when the file's lock is busy, see busy.rs.
+    pub fn set_busy_handler(&mut self, busy: BusyHandler) {
+        self.pager().set_busy_handler(busy);
+    }
+
+    /// Change how many pages the file's cache may hold.
+    pub fn set_cache_pages(&mut self, pages: u64) {
+        self.pager().set_cache_pages(pages);
+    }
+
+    /// Change how the file's cache picks a page to evict, see replacement.rs.
+    pub fn set_replacement(&mut self, replacement: Replacement) {
+        self.pager().set_replacement(replacement);
+    }
+
+    /// What the file's cache has counted, see cache.rs.
+    pub fn cache_report(&self) -> CacheReport {
+        self.pager().cache_report()
+    }
+
+    /// Change how much of the file is read from its memory map, see pager.rs.
+    pub fn set_mmap_size(&mut self, size: u64) {
+        self.pager().set_mmap_size(size);
+    }
+
+    /// The schema cookie in the file's header as it is now, which another connection may
+    /// have moved on since this one loaded the image or last saved it.
+    pub fn schema_cookie(&mut self) -> Result<u32> {
+        Ok(self.header()?.schema_cookie)
+    }
+
+    /// The change counter in the file's header as of this connection's last read or save
+    /// of it. Each commit moves it on, see pager.rs, so another connection has committed
+    /// since if the header as it is now has another.
+    pub fn change_counter(&self) -> u32 {
+        self.pager().header().change_counter
+    }
+
+    /// The file's header as it is now, with how many pages it has and how many are free.
+    pub fn header(&mut self) -> Result<DatabaseHeader> {
+        self.pager().current_header()
+    }
+
+    /// Change the most pages the file may grow to, see PRAGMA max_page_count.
+    pub fn set_max_page_count(&mut self, count: u32) {
+        self.pager().set_max_page_count(count);
+    }
+
+    /// Put `cookie` in the header, to be written by the next save.
+    pub fn set_schema_cookie(&mut self, cookie: u32) {
+        self.pager().set_schema_cookie(cookie);
+    }
+
+    /// The rows in the file, those of sqlite_master first and then each table's in
+    /// order of its key.
+    pub fn load(&mut self) -> Result<Vec<ImageRow>> {
+        if let (Some(rows), None) = (&self.shared_rows, &self.log) {
+            return rows.load(&self.pager);
+        }
+        let mut pager = self.pager();
+        let rows = read_tree(&mut pager);
+        pager.end_read()?;
+        rows
+    }
+
+    /// How many levels the file's tree has, from its root down to its leaves, none in a
+    /// new database.
+    pub fn tree_height(&mut self) -> Result<usize> {
+        let mut pager = self.pager();
+        let header = *pager.header();
+        let height = match header.page_count < ROOT_PAGE {
+            true => Ok(0),
+            false => FileTree::open(
+                &header,
+                comparator(),
+                &mut PagerStore(&mut pager),
+                ROOT_PAGE,
+            )
+            .map(|tree| tree.height()),
+        };
+        pager.end_read()?;
+        height
+    }
+
+    /// Whether the file is in WAL mode, see PRAGMA journal_mode.
+    pub fn in_wal_mode(&self) -> bool {
+        self.log.is_some()
+    }
+
+    /// Put the file in WAL mode, or take it out of it, as PRAGMA journal_mode does, unless
+    /// another connection has committed since the change counter was `counter`. The mode
+    /// is kept in the header, so that the file opens in it again. The log is checkpointed
+    /// into the file on the way out, which no other connection may have open then.
+    pub fn set_wal(&mut self, wal: bool, counter: u32) -> Result<()> {
+        if wal == self.in_wal_mode() {
+            return Ok(());
+        }
+        if self.memory {
+            bail!("an in-memory database can't be put in WAL mode");
+        }
+        if Arc::strong_count(&self.pager) > 1 {
+            bail!("database is locked: the pager is shared with another connection");
+        }
+        if !wal {
+            let log = self.log.take().expect("a file in WAL mode has a log");
+            if Arc::strong_count(&log) > 1 {
+                self.log = Some(log);
+                bail!("database is locked: another connection has the file in WAL mode");
+            }
+            checkpoint(&mut self.pager(), &log, true)?;
+            self.pager().leave_wal()?;
+        }
+        // the header is written through the rollback journal either way, before the log
+        // is joined or after it is left
+        let mut pager = self.pager.lock().unwrap();
+        pager.begin(TransactionMode::Immediate)?;
+        if counter != pager.header().change_counter {
+            pager.rollback()?;
+            bail!(
+                "database is locked: another connection has committed since this one read the file"
+            );
+        }
+        pager.set_wal_format(wal);
+        if let Err(err) = pager.flush() {
+            let _ = pager.rollback();
+            return Err(err);
+        }
+        drop(pager);
+        if wal {
+            if let Err(err) = self.join_log() {
+                let mut pager = self.pager();
+                pager.set_wal_format(false);
+                pager.flush()?;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // Commit to the process's log for the file from now on.
+    fn join_log(&mut self) -> Result<()> {
+        let page_size = self.pager().header().page_size;
+        let log = FileLog::open(&self.path, page_size)?;
+        self.pager().use_wal(log.wal.clone())?;
+        self.log = Some(log);
+        Ok(())
+    }
+
+    /// Take the locks BEGIN `mode` takes up front, see Pager::begin, and hold them until
+    /// the transaction is saved or rolled back. DEFERRED takes none, and leaves the save
+    /// to take them.
+    pub fn begin(&mut self, mode: TransactionMode) -> Result<()> {
+        if mode == TransactionMode::Deferred {
+            return Ok(());
+        }
+        let mut pager = self.pager.lock().unwrap();
+        if pager.in_transaction() {
+            // another connection sharing the pager began one
+            bail!("database table is locked");
+        }
+        pager.begin(mode)?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    /// Let go of the locks begin took, as the transaction was rolled back.
+    pub fn rollback(&mut self) -> Result<()> {
+        self.saved = None;
+        if std::mem::take(&mut self.in_transaction) {
+            self.pager().rollback()?;
+        }
+        Ok(())
+    }
+
+    /// Open a savepoint on the pager, see Pager::savepoint, while a transaction BEGIN
+    /// began holds it for this connection. Outside one the pager has nothing to undo.
+    pub fn savepoint(&mut self) {
+        if self.in_transaction {
+            self.pager().savepoint();
+        }
+    }
+
+    /// Close the pager's savepoint at `level` and those opened after it.
+    pub fn release(&mut self, level: usize) {
+        let mut pager = self.pager();
+        if self.in_transaction && level < pager.savepoint_depth() {
+            pager.release(level);
+        }
+    }
+
+    /// Put the pager back as it was when the savepoint at `level` was opened, its header
+    /// and freelist included.
+    pub fn rollback_to(&mut self, level: usize) -> Result<()> {
+        let mut pager = self.pager();
+        if self.in_transaction && level < pager.savepoint_depth() {
+            pager.rollback_to(level)?;
+        }
+        Ok(())
+    }
+
+    /// The rows of the image as they were at the commit `commit` to the log, while it is
+    /// retained there, see wal.rs.
+    pub fn load_as_of(&mut self, commit: u64) -> Result<Vec<ImageRow>> {
+        let mut pager = self.pager();
+        pager.begin_read_as_of(commit)?;
+        let rows = read_tree(&mut pager);
+        pager.end_read()?;
+        rows
+    }
+
+    /// The log's latest commit, None outside WAL mode.
+    pub fn last_commit(&self) -> Option<u64> {
+        let log = self.log.as_ref()?;
+        let last = log.wal.lock().unwrap().last_commit();
+        Some(last)
+    }
+
+    /// Have the log keep its last `commits` commits when it is checkpointed, so that they
+    /// can still be read as of, rather than copying them into the file as soon as no read
+    /// needs them. Only in WAL mode.
+    pub fn retain_commits(&mut self, commits: usize) -> Result<()> {
+        let Some(log) = &self.log else {
+            bail!("only a file in WAL mode keeps commits in a log");
+        };
+        log.wal.lock().unwrap().retain_commits(commits);
+        Ok(())
+    }
+
+    /// Replace the rows in the file with `rows`, but leave the transaction open with its
+    /// journal naming `super_journal`, the first phase of a commit across several files,
+    /// see journal.rs. finish commits it and rollback undoes it. A save that fails, say for
+    /// want of a page past max_page_count, leaves the file and the pager as they were.
+    /// With a `counter`, only if no other connection has committed since the file's change
+    /// counter was `counter`, which the RESERVED lock taken first makes sure of until the
+    /// save is done. Otherwise the rows are those of an image older than the file's, and
+    /// writing them would lose the other connection's commit.
+    pub fn prepare<'r>(
+        &mut self,
+        rows: impl IntoIterator<Item = &'r ImageRow>,
+        counter: Option<u32>,
+        super_journal: Option<&Path>,
+    ) -> Result<()> {
+        let began = std::mem::take(&mut self.in_transaction);
+        let mut pager = self.pager();
+        if !began {
+            if pager.in_transaction() {
+                bail!("database table is locked");
+            }
+            pager.begin(TransactionMode::Immediate)?;
+        }
+        if counter.is_some_and(|counter| counter != pager.header().change_counter) {
+            pager.rollback()?;
+            bail!(
+                "database is locked: another connection has committed since this one read the file"
+            );
+        }
+        let name = super_journal.map(|path| path.to_string_lossy());
+        let before = pager.header().change_counter;
+        let written = write_tree(&mut pager, rows).and_then(|changes| {
+            pager.commit_phase_one(name.as_deref())?;
+            Ok(changes)
+        });
+        if written.is_err() {
+            // the error that stopped the save is the one to report
+            let _ = pager.rollback();
+        }
+        let changes = written?;
+        drop(pager);
+        self.saved = Some((before, changes));
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    /// Commit the transaction prepare left open.
+    pub fn finish(&mut self) -> Result<()> {
+        self.in_transaction = false;
+        let saved = self.saved.take();
+        let mut pager = self.pager();
+        pager.commit_phase_two()?;
+        if let (Some(rows), Some((before, changes))) = (&self.shared_rows, saved) {
+            rows.saved(before, pager.header().change_counter, changes);
+        }
+        match &self.log {
+            Some(log) if log.wal.lock().unwrap().logged_frames() >= AUTOCHECKPOINT_FRAMES => {
+                checkpoint(&mut pager, log, false)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write `data` as page `number`, taking the pages up to it past the end if the file
+    /// hasn't got them, without committing, for a backup to fill a new file a page at a
+    /// time with the pages made by pages.
+    pub fn write_page(&mut self, number: PageNumber, data: &[u8]) -> Result<()> {
+        let mut pager = self.pager();
+        while pager.header().page_count < number {
+            pager.allocate_page(OWNER)?;
+        }
+        pager.get_page_mut(number, OWNER)?.copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Free the pages past the first `count` written by write_page, and commit.
+    pub fn commit_pages(&mut self, count: usize) -> Result<()> {
+        let mut pager = self.pager();
+        let end = pager.header().page_count;
+        for page in ROOT_PAGE + count as PageNumber..=end {
+            pager.free_page(page)?;
+        }
+        pager.flush()
+    }
+
+    fn pager(&self) -> MutexGuard<'_, FilePager> {
+        self.pager.lock().unwrap()
+    }
+}
+
+impl Drop for DatabaseFile {
+    // A transaction left open lets go of its locks, which another connection sharing
+    // the pager would otherwise never see go, and the last connection with the file in
+    // WAL mode checkpoints the log into it before the log goes.
+    fn drop(&mut self) {
+        let _ = self.rollback();
+        if let Some(log) = self.log.take() {
+            if Arc::strong_count(&log) == 1 {
+                let _ = checkpoint(&mut self.pager(), &log, true);
+            }
+        }
+    }
+}
+
+// Copy the commits in `log` into the file, every one of them if `all`, even those it
+// would keep, or else as far as the reads open and the retention allow.
+fn checkpoint(pager: &mut FilePager, log: &FileLog, all: bool) -> Result<()> {
+    let retained = match all {
+        true => Some(log.wal.lock().unwrap().retain_commits(0)),
+        false => None,
+    };
+    let checkpointed = pager.checkpoint();
+    if let Some(retained) = retained {
+        log.wal.lock().unwrap().retain_commits(retained);
+    }
+    let upto = checkpointed?;
+    if all && upto != log.wal.lock().unwrap().last_commit() {
+        bail!("database is locked: a read of the log is open");
+    }
+    Ok(())
+}
+
+// The file's tree, a row per entry, see the module comment.
+type FileTree = Btree<Vec<u8>, Vec<u8>>;
+
+// Keys in byte order, the separator between two leaves only as many of the right hand
+// key's first bytes as tell it from the left.
+fn comparator() -> Comparator<Vec<u8>> {
+    Comparator::binary().with_separator(|left: &Vec<u8>, right: &Vec<u8>| {
+        let shared = left.iter().zip(right).take_while(|(a, b)| a == b).count();
+        right[..(shared + 1).min(right.len())].to_vec()
+    })
+}
+
+// The file's pages as its tree gets them, through the pager.
+struct PagerStore<'p>(&'p mut FilePager);
+
+impl PageStore for PagerStore<'_> {
+    fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+        Ok(self.0.get_page(page, OWNER)?.to_vec())
+    }
+
+    fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+        self.0.get_page_mut(page, OWNER)?.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl TreeStore for PagerStore<'_> {
+    fn allocate(&mut self) -> Result<PageNumber> {
+        self.0.allocate_page(OWNER)
+    }
+
+    fn free(&mut self, page: PageNumber) -> Result<()> {
+        self.0.free_page(page)
+    }
+}
+
+// Bring the file's tree into line with `rows`, writing the pages that change without
+// committing, and returning the changes to its entries.
+fn write_tree<'r>(
+    pager: &mut FilePager,
+    rows: impl IntoIterator<Item = &'r ImageRow>,
+) -> Result<Vec<Change>> {
+    let header = *pager.header();
+    let mut store = PagerStore(pager);
+    let mut tree = match header.page_count < ROOT_PAGE {
+        true => {
+            if store.allocate()? != ROOT_PAGE {
+                bail!("database disk image is malformed: no page for the root of its tree");
+            }
+            FileTree::on_pages(&header, comparator())
+        }
+        false => FileTree::open(&header, comparator(), &mut store, ROOT_PAGE)?,
+    };
+    let mut kept = HashSet::new();
+    let mut changes = vec![];
+    for row in rows {
+        let (key, value) = tree_entry(row);
+        if tree.find(&key) != Some(&value) {
+            tree.insert(key.clone(), value.clone());
+            changes.push((key.clone(), Some(value)));
+        }
+        kept.insert(key);
+    }
+    let gone: Vec<Vec<u8>> = tree
+        .range(..)
+        .map(|(key, _)| key)
+        .filter(|key| !kept.contains(*key))
+        .cloned()
+        .collect();
+    for key in gone {
+        tree.delete(&key);
+        changes.push((key, None));
+    }
+    tree.flush(&mut store, &header, ROOT_PAGE)?;
+    Ok(changes)
+}
+
+// The rows in the file's tree, none in a new database.
+fn read_tree(pager: &mut FilePager) -> Result<Vec<ImageRow>> {
+    rows_of(read_entries(pager)?)
+}
+
+// The entries of the file's tree, in its order.
+fn read_entries(pager: &mut FilePager) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let header = *pager.header();
+    if header.page_count < ROOT_PAGE {
+        return Ok(vec![]);
+    }
+    let tree = FileTree::open(&header, comparator(), &mut PagerStore(pager), ROOT_PAGE)?;
+    Ok(tree
+        .range(..)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect())
+}
+
+// The rows the entries of the file's tree hold, in the order they load in.
+fn rows_of(entries: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<ImageRow>> {
+    let rows = entries.iter().map(|(key, value)| tree_row(key, value));
+    Ok(in_load_order(rows.collect::<Result<_>>()?))
+}
+
+// The rows of sqlite_master first, then each table's in order of their keys, as the
+// tree's byte order doesn't keep rowids in order.
+pub(super) fn in_load_order(mut rows: Vec<ImageRow>) -> Vec<ImageRow> {
+    rows.sort_by(|a, b| {
+        let master = |row: &ImageRow| row.0 != catalog::MASTER_TABLE;
+        (master(a), &a.0, &a.1).cmp(&(master(b), &b.0, &b.1))
+    });
+    rows
+}
+
+// The entry of the file's tree that holds `row`, its key and its value.
+fn tree_entry((table, key, values): &ImageRow) -> (Vec<u8>, Vec<u8>) {
+    let table = ColVal::String(table.clone());
+    match key {
+        ColVal::Null => {
+            let mut row = vec![table, ColVal::Null];
+            row.extend(values.iter().cloned());
+            (record::encode(&row), vec![])
+        }
+        key => (
+            record::encode(&[table, key.clone()]),
+            record::encode(values),
+        ),
+    }
+}
+
+// The row an entry of the file's tree holds.
+pub(super) fn tree_row(key: &[u8], value: &[u8]) -> Result<ImageRow> {
+    let mut values = record::decode(&mut &key[..])
+        .context("database disk image is malformed")?
+        .into_iter();
+    let (Some(ColVal::String(table)), Some(key)) = (values.next(), values.next()) else {
+        bail!("database disk image is malformed: a row with no table");
+    };
+    let values = match key {
+        ColVal::Null => values.collect(),
+        _ => record::decode(&mut &value[..]).context("database disk image is malformed")?,
+    };
+    Ok((table, key, values))
+}
+
+/// The pages from page 2 on of a new file of pages laid out as `header` says holding
+/// `rows`, for a backup to copy one at a time, see write_page.
+pub fn pages<'r>(
+    rows: impl IntoIterator<Item = &'r ImageRow>,
+    header: &DatabaseHeader,
+) -> Result<Vec<Vec<u8>>> {
+    let mut store = MemoryPages {
+        pages: vec![vec![0; header.page_size as usize]],
+        free: vec![],
+        page_size: header.page_size as usize,
+    };
+    let mut tree = FileTree::on_pages(header, comparator());
+    for row in rows {
+        let (key, value) = tree_entry(row);
+        tree.insert(key, value);
+    }
+    tree.flush(&mut store, header, ROOT_PAGE)?;
+    Ok(store.pages)
+}
+
+// The pages of a file from page 2 on, made in memory.
+struct MemoryPages {
+    pages: Vec<Vec<u8>>,
+    free: Vec<PageNumber>,
+    page_size: usize,
+}
+
+impl MemoryPages {
+    fn page(&mut self, page: PageNumber) -> Result<&mut Vec<u8>> {
+        let index = (page as usize).wrapping_sub(ROOT_PAGE as usize);
+        match self.pages.get_mut(index) {
+            Some(data) => Ok(data),
+            None => bail!("no page {page}"),
+        }
+    }
+}
+
+impl PageStore for MemoryPages {
+    fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+        Ok(self.page(page)?.clone())
+    }
+
+    fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+        self.page(page)?.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl TreeStore for MemoryPages {
+    fn allocate(&mut self) -> Result<PageNumber> {
+        if let Some(page) = self.free.pop() {
+            return Ok(page);
+        }
+        self.pages.push(vec![0; self.page_size]);
+        Ok(ROOT_PAGE + self.pages.len() as PageNumber - 1)
+    }
+
+    fn free(&mut self, page: PageNumber) -> Result<()> {
+        self.page(page)?.fill(0);
+        self.free.push(page);
+        Ok(())
+    }
+}
+
+/// The image of `rows` in one run of bytes, as a replica is sent it (see replication.rs):
+/// its length as a varint, then a record per row.
+pub fn encode<'r>(rows: impl IntoIterator<Item = &'r ImageRow>) -> Vec<u8> {
+    let mut image = vec![];
+    for (table, key, values) in rows {
+        let mut row = Vec::with_capacity(values.len() + 2);
+        row.push(ColVal::String(table.clone()));
+        row.push(key.clone());
+        row.extend(values.iter().cloned());
+        image.extend(record::encode(&row));
+    }
+    let mut bytes = vec![];
+    record::write_varint(image.len() as u64, &mut bytes);
+    bytes.extend(image);
+    bytes
+}
+
+/// The rows of an image, which may be followed by bytes that aren't part of it, and is
+/// empty in a new database.
+pub fn decode(bytes: &[u8]) -> Result<Vec<ImageRow>> {
+    let mut input = bytes;
+    if input.is_empty() {
+        return Ok(vec![]);
+    }
+    let size = record::read_varint(&mut input)? as usize;
+    if size > input.len() {
+        bail!("database disk image is malformed: an image of {size} bytes ends early");
+    }
+    let mut input = &input[..size];
+    let mut rows = vec![];
+    while !input.is_empty() {
+        let mut values = record::decode(&mut input)
+            .context("database disk image is malformed")?
+            .into_iter();
+        let (Some(ColVal::String(table)), Some(key)) = (values.next(), values.next()) else {
+            bail!("database disk image is malformed: a row with no table");
+        };
+        rows.push((table, key, values.collect()));
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(table: &str, key: ColVal, values: &[ColVal]) -> ImageRow {
+        (table.to_string(), key, values.to_vec())
+    }
+
+    #[test]
+    fn an_image_is_saved_and_loaded_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut config = PagerConfig::default();
+        config.set_page_size(512);
+        let mut file =
+            DatabaseFile::open(&path, AccessMode::Create, Compression::None, &config).unwrap();
+        assert_eq!(file.load().unwrap(), vec![]);
+
+        // long enough to take several pages
+        let text = ColVal::String("x".repeat(1500));
+        let rows = vec![
+            row("tags", ColVal::Null, &[ColVal::String("a".to_string())]),
+            row("users", ColVal::Int(1), &[ColVal::Int(1), text.clone()]),
+            row("users", ColVal::Int(5), &[ColVal::Null, ColVal::Real(2.5)]),
+        ];
+        file.prepare(&rows, None, None).unwrap();
+        file.finish().unwrap();
+        drop(file);
+        let mut file =
+            DatabaseFile::open(&path, AccessMode::ReadWrite, Compression::None, &config).unwrap();
+        assert_eq!(file.load().unwrap(), rows);
+        let pages = file.pager().header().page_count;
+        assert!(pages > 4, "{pages}");
+
+        // fewer rows free the pages they no longer need
+        let fewer = vec![rows[0].clone(), rows[2].clone()];
+        file.prepare(&fewer, None, None).unwrap();
+        file.finish().unwrap();
+        assert_eq!(file.load().unwrap(), fewer);
+        assert_eq!(file.pager().header().freelist_count, pages - 2);
+
+        // within BEGIN's transaction ROLLBACK TO puts the freelist back as it was
+        let free = file.pager().header().freelist_count;
+        file.savepoint();
+        assert_eq!(file.pager().savepoint_depth(), 0);
+        file.begin(TransactionMode::Immediate).unwrap();
+        file.savepoint();
+        let page = file.pager().allocate_page(OWNER).unwrap();
+        file.pager().free_page(ROOT_PAGE).unwrap();
+        file.rollback_to(0).unwrap();
+        assert_eq!(file.pager().allocate_page(OWNER).unwrap(), page);
+        file.rollback_to(0).unwrap();
+        file.release(0);
+        assert_eq!(file.pager().savepoint_depth(), 0);
+        file.rollback().unwrap();
+        assert_eq!(file.pager().header().freelist_count, free);
+        assert_eq!(file.load().unwrap(), fewer);
+
+        assert!(DatabaseFile::open(
+            &dir.path().join("none.db"),
+            AccessMode::ReadWrite,
+            Compression::None,
+            &config
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn a_compressed_image_takes_less_room_and_loads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PagerConfig::default();
+        let rows: Vec<ImageRow> = (0..200)
+            .map(|n| {
+                let note = format!("note {n}: nothing much happened today");
+                row("notes", ColVal::Int(n), &[ColVal::String(note)])
+            })
+            .collect();
+        let save = |name: &str, compression| {
+            let path = dir.path().join(name);
+            let mut file =
+                DatabaseFile::open(&path, AccessMode::Create, compression, &config).unwrap();
+            file.prepare(&rows, None, None).unwrap();
+            file.finish().unwrap();
+            std::fs::metadata(&path).unwrap().len()
+        };
+        let plain = save("plain.db", Compression::None);
+        let compressed = save("compressed.db", Compression::Lz4);
+        assert!(compressed < plain / 2, "{compressed} of {plain}");
+
+        // it is found to be compressed without being asked
+        let path = dir.path().join("compressed.db");
+        let mut file =
+            DatabaseFile::open(&path, AccessMode::ReadWrite, Compression::None, &config).unwrap();
+        assert_eq!(file.load().unwrap(), rows);
+
+        let path = dir.path().join("plain.db");
+        let error = DatabaseFile::open(&path, AccessMode::ReadWrite, Compression::Lz4, &config)
+            .unwrap_err();
+        assert_eq!(
+            error.root_cause().to_string(),
+            "compression=lz4 only applies to a new database"
+        );
+    }
+
+    #[test]
+    fn connections_with_a_shared_cache_share_the_pager() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shared.db");
+        let config = PagerConfig::default();
+        let open = |shared| {
+            let target = OpenTarget::File {
+                path: path.clone(),
+                mode: AccessMode::Create,
+                format: Default::default(),
+                compression: Compression::None,
+                shared,
+            };
+            DatabaseFile::open_target(&target, &config)
+                .unwrap()
+                .unwrap()
+        };
+        let mut first = open(true);
+        let mut second = open(true);
+        let private = open(false);
+        assert!(Arc::ptr_eq(&first.pager, &second.pager));
+        assert!(!Arc::ptr_eq(&first.pager, &private.pager));
+
+        let rows = vec![row("t", ColVal::Int(1), &[ColVal::Int(7)])];
+        first.prepare(&rows, None, None).unwrap();
+        first.finish().unwrap();
+        assert_eq!(second.load().unwrap(), rows);
+
+        // once both close the next shared open starts a pager of its own
+        let pager = Arc::downgrade(&first.pager);
+        drop((first, second));
+        assert!(pager.upgrade().is_none());
+        let mut third = open(true);
+        assert_eq!(third.load().unwrap(), rows);
+    }
+
+    #[test]
+    fn connections_sharing_a_pager_load_whole_commits_while_one_saves() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shared.db");
+        let config = PagerConfig::default();
+        let open = || {
+            DatabaseFile::open_shared(&path, AccessMode::Create, Compression::None, &config)
+                .unwrap()
+        };
+        // every row of the nth commit holds n
+        let image = |n: i64| -> Vec<ImageRow> {
+            (1..=50)
+                .map(|k| row("t", ColVal::Int(k), &[ColVal::Int(n)]))
+                .collect()
+        };
+        let mut writer = open();
+        writer.prepare(&image(0), None, None).unwrap();
+        writer.finish().unwrap();
+        let readers: Vec<DatabaseFile> = (0..3).map(|_| open()).collect();
+        std::thread::scope(|s| {
+            for mut reader in readers {
+                s.spawn(move || {
+                    let mut last = 0;
+                    while last < 20 {
+                        let rows = reader.load().unwrap();
+                        let n = match rows[0].2[..] {
+                            [ColVal::Int(n)] => n,
+                            _ => panic!("a row of commit numbers"),
+                        };
+                        assert_eq!(rows, image(n));
+                        assert!(n >= last);
+                        last = n;
+                    }
+                });
+            }
+            s.spawn(|| {
+                for n in 1..=20 {
+                    writer.prepare(&image(n), None, None).unwrap();
+                    writer.finish().unwrap();
+                }
+            });
+        });
+
+        // the saves kept the rows the connections load up to date
+        let rows = writer.shared_rows.clone().unwrap();
+        assert_eq!(*rows.counter.lock().unwrap(), Some(writer.change_counter()));
+        let version = rows.version.load(Ordering::SeqCst);
+        assert_eq!(open().load().unwrap(), image(20));
+        assert_eq!(rows.version.load(Ordering::SeqCst), version);
+    }
+}
@@ -0,0 +1,845 @@
+/*
+    Secondary indexes.
+
+    A table's rows live in a B+tree keyed by rowid, which makes `WHERE rowid = 5` fast but
+    leaves `WHERE email = "bob"` scanning every row. A secondary index is a second B+tree
+    whose keys are the indexed column values of each row, so rows can be found by those
+    values instead.
+
+    Like SQLite we make every index entry unique by appending the rowid to the key, so an
+    entry for a row with email "bob" and rowid 7 is the key ("bob", 7) with no value at all.
+    Many rows may share the same email but no two entries share the same key, and all the
+    rows for "bob" sit next to each other in the leaves ready to be range scanned. A
+    WITHOUT ROWID table has no rowid, so an index on one appends the row's primary key
+    instead, its RowKey, which is how the row is found in the table's own tree.
+
+    The index must be kept in step with its table: every insert, update and delete of a
+    row has to be mirrored here or the index starts lying about what the table contains.
+    The executor's storage does this for every write, and PRAGMA integrity_check compares
+    each index with its table to find where it didn't, see check.
+
+    Indexing a table that already has rows in it means reading every row. Rather than
+    insert entries one by one in table order, which lands each one somewhere random in the
+    tree, we collect them all, sort them and then load them in order, sorting with the
+    external sorter so that an index on a table bigger than memory can still be built.
+    Sorted keys are bulk loaded, the tree built leaves first with no splitting, see
+    Btree::bulk_load, and duplicate values end up side by side, so a UNIQUE violation is
+    found by comparing neighbours. The build reads the connection's own copy of the
+    table and takes no lock on the file, which is only locked to save the finished index,
+    so other connections go on reading meanwhile. In WAL mode not even the save holds
+    them up, a reader seeing the commit it began at. The build does hold its own
+    connection for as long as it takes, as any other statement does, and the index is
+    only added to the schema once it is finished, so a build that fails, on a UNIQUE
+    violation say, leaves nothing behind.
+
+    Each indexed column compares under a collation, the one given in the index as in
+    `CREATE INDEX i ON users (email COLLATE NOCASE)` or else the one the column was
+    declared with. Under NOCASE "bob@x" and "BOB@X" are the same value, so they sit
+    together in the index and a UNIQUE index allows only one of them. The index's tree
+    orders its keys with a comparator made from these collations, named after them, say
+    "NOCASE,BINARY" for an index on (email COLLATE NOCASE, age).
+*/
+use super::btree::{Btree, Comparator};
+use crate::collation::{Collation, Collations};
+use crate::error::SqlError;
+use crate::functions::{DeterministicContext, FunctionRegistry};
+use crate::planner::Sample;
+use crate::sorter::{Sorter, Spill};
+use crate::sql_parser::ast::{ColVal, Column, CreateIndex, Expr};
+use anyhow::{anyhow, bail, Result};
+use std::cmp::Ordering;
+use std::io::{self, Read, Write};
+use std::ops::Bound;
+
+pub type RowId = i64;
+
+/// Where a row is stored, by rowid in a rowid table and by primary key in a WITHOUT ROWID
+/// table.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RowKey {
+    RowId(RowId),
+    PrimaryKey(Vec<ColVal>),
+}
+
+impl RowKey {
+    // Rows of the same table all have the same kind of key. A rowid sorts before any
+    // primary key only so that RowId::MIN can start a search in either kind of index.
+    fn compare(&self, other: &RowKey) -> Ordering {
+        match (self, other) {
+            (RowKey::RowId(a), RowKey::RowId(b)) => a.cmp(b),
+            (RowKey::PrimaryKey(a), RowKey::PrimaryKey(b)) => a.cmp(b),
+            (RowKey::RowId(_), RowKey::PrimaryKey(_)) => Ordering::Less,
+            (RowKey::PrimaryKey(_), RowKey::RowId(_)) => Ordering::Greater,
+        }
+    }
+}
+
+impl Spill for RowKey {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self {
+            RowKey::RowId(rowid) => {
+                out.write_all(&[0])?;
+                rowid.write_to(out)
+            }
+            RowKey::PrimaryKey(key) => {
+                out.write_all(&[1])?;
+                key.write_to(out)
+            }
+        }
+    }
+
+    fn read_from(input: &mut dyn Read) -> io::Result<Self> {
+        let mut tag = [0];
+        input.read_exact(&mut tag)?;
+        Ok(match tag[0] {
+            0 => RowKey::RowId(RowId::read_from(input)?),
+            _ => RowKey::PrimaryKey(Vec::read_from(input)?),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            RowKey::RowId(rowid) => rowid.size(),
+            RowKey::PrimaryKey(key) => key.size(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct IndexKey {
+    values: Vec<ColVal>,
+    row: RowKey,
+}
+
+// The key that sorts before every entry with `values`.
+fn first_with(values: Vec<ColVal>) -> IndexKey {
+    IndexKey {
+        values,
+        row: RowKey::RowId(RowId::MIN),
+    }
+}
+
+impl Spill for IndexKey {
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        self.values.write_to(out)?;
+        self.row.write_to(out)
+    }
+
+    fn read_from(input: &mut dyn Read) -> io::Result<Self> {
+        Ok(IndexKey {
+            values: Vec::read_from(input)?,
+            row: RowKey::read_from(input)?,
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.values.size() + self.row.size()
+    }
+}
+
+impl IndexKey {
+    // The entry as SQLite's index B-tree holds it, the values followed by the row's key.
+    fn entry(&self) -> Vec<ColVal> {
+        let mut entry = self.values.clone();
+        match &self.row {
+            RowKey::RowId(rowid) => entry.push(ColVal::Int(*rowid)),
+            RowKey::PrimaryKey(key) => entry.extend(key.iter().cloned()),
+        }
+        entry
+    }
+}
+
+#[derive(Debug)]
+pub struct SecondaryIndex {
+    pub name: String,
+    pub table: String,
+    pub unique: bool,
+    // The indexed columns in index key order, and their positions in the table's rows.
+    columns: Vec<String>,
+    column_positions: Vec<usize>,
+    collations: Vec<Collation>,
+    tree: Btree<IndexKey, ()>,
+}
+
+impl SecondaryIndex {
+    /// Build an empty index from its definition and the columns of the table it is on,
+    /// in the order they appear in the table's rows, whose B+tree nodes split past
+    /// `node_keys` keys.
+    pub fn create(
+        def: &CreateIndex,
+        table_columns: &[Column],
+        functions: &FunctionRegistry,
+        collations: &Collations,
+        node_keys: u64,
+    ) -> Result<Self> {
+        let mut columns = vec![];
+        let mut column_positions = vec![];
+        let mut column_collations = vec![];
+        for column in &def.columns {
+            let (column, explicit) = match column {
+                Expr::Collate { expr, collation } => (&**expr, Some(collation)),
+                other => (other, None),
+            };
+            match column {
+                Expr::Column(name) => {
+                    let Some(pos) = table_columns.iter().position(|c| c.name == *name) else {
+                        bail!(SqlError::NoSuchColumn {
+                            column: name.to_string()
+                        });
+                    };
+                    let collation = explicit.or(table_columns[pos].collation.as_ref());
+                    columns.push(name.clone());
+                    column_positions.push(pos);
+                    column_collations.push(match collation {
+                        Some(name) => collations.get(name)?,
+                        None => Collation::binary(),
+                    });
+                }
+                other => {
+                    functions.check_deterministic(other, DeterministicContext::IndexExpression)?;
+                    bail!("indexes on expressions are not supported yet");
+                }
+            }
+        }
+
+        Ok(SecondaryIndex {
+            name: def.name.clone(),
+            table: def.table.clone(),
+            unique: def.unique,
+            columns,
+            column_positions,
+            tree: Btree::with_comparator(node_keys, comparator(&column_collations)),
+            collations: column_collations,
+        })
+    }
+
+    /// Create an index over a table's existing rows, sorting them in at most about
+    /// `memory` bytes.
+    pub fn build(
+        def: &CreateIndex,
+        table_columns: &[Column],
+        functions: &FunctionRegistry,
+        collations: &Collations,
+        node_keys: u64,
+        rows: impl IntoIterator<Item = (RowKey, Vec<ColVal>)>,
+        memory: usize,
+    ) -> Result<Self> {
+        let mut index = Self::create(def, table_columns, functions, collations, node_keys)?;
+        let comparator = index.tree.comparator().clone();
+        let mut sorter = Sorter::new(memory, |a, b| comparator.compare(a, b));
+        for (key, row) in rows {
+            sorter.push(index.key_for(key, &row))?;
+        }
+
+        let mut keys: Vec<(IndexKey, ())> = vec![];
+        for key in sorter.finish()? {
+            let key = key?;
+            if let Some((previous, _)) = keys.last() {
+                if index.unique
+                    && index.same_values(&previous.values, &key.values)
+                    && !has_null(&key.values)
+                {
+                    return Err(index.unique_failed());
+                }
+            }
+            keys.push((key, ()));
+        }
+        index.tree = Btree::bulk_load(node_keys, comparator, keys);
+        Ok(index)
+    }
+
+    /// Rebuild the index's tree with its nodes full, see Btree::rebuilt.
+    pub fn rebuild(&mut self) {
+        self.tree = self.tree.rebuilt();
+    }
+
+    fn key_for(&self, row_key: RowKey, row: &[ColVal]) -> IndexKey {
+        IndexKey {
+            values: self
+                .column_positions
+                .iter()
+                .map(|pos| row[*pos].clone())
+                .collect(),
+            row: row_key,
+        }
+    }
+
+    /// Every entry in order, the indexed values followed by the rowid, or the primary key
+    /// of a WITHOUT ROWID table, as SQLite's own index B-tree holds them.
+    pub fn entries(&self) -> Vec<Vec<ColVal>> {
+        self.tree.range(..).map(|(key, _)| key.entry()).collect()
+    }
+
+    /// The collation each indexed column compares under.
+    pub fn collations(&self) -> &[Collation] {
+        &self.collations
+    }
+
+    // Whether `a` and `b` hold the same values, each compared under its column's collation.
+    // Either may be a prefix, of which only the leading values are compared.
+    fn same_values(&self, a: &[ColVal], b: &[ColVal]) -> bool {
+        self.collations
+            .iter()
+            .zip(a.iter().zip(b))
+            .all(|(collation, (a, b))| collation.compare(a, b) == Ordering::Equal)
+    }
+
+    // For a UNIQUE index, another row already holding the same values. NULLs are
+    // distinct from each other so rows with a NULL in the key never conflict.
+    fn conflicting_row(&self, key: &IndexKey) -> Option<RowKey> {
+        if !self.unique || has_null(&key.values) {
+            return None;
+        }
+        match self.tree.lower_bound(&first_with(key.values.clone())) {
+            Some((existing, _))
+                if self.same_values(&existing.values, &key.values) && existing.row != key.row =>
+            {
+                Some(existing.row.clone())
+            }
+            _ => None,
+        }
+    }
+
+    // Named like SQLite names it, by the table's indexed columns.
+    fn unique_failed(&self) -> anyhow::Error {
+        let columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|column| format!("{}.{column}", self.table))
+            .collect();
+        anyhow!(SqlError::UniqueConstraint { columns })
+    }
+
+    pub fn on_insert(&mut self, row_key: &RowKey, row: &[ColVal]) -> Result<()> {
+        let key = self.key_for(row_key.clone(), row);
+        if self.conflicting_row(&key).is_some() {
+            return Err(self.unique_failed());
+        }
+        self.tree.insert(key, ());
+        Ok(())
+    }
+
+    pub fn on_delete(&mut self, row_key: &RowKey, row: &[ColVal]) {
+        let key = self.key_for(row_key.clone(), row);
+        self.tree.delete(&key);
+    }
+
+    /// What is wrong with the index as an index of `rows`, its table's rows: a tree that
+    /// isn't well formed, a row with no entry, or entries for rows that aren't there,
+    /// worded as SQLite's integrity_check words them. A row of a WITHOUT ROWID table is numbered by where it is in the table.
+    pub fn check(&self, rows: &[(RowKey, &[ColVal])]) -> Vec<String> {
+        let mut problems = vec![];
+        if let Err(err) = self.tree.check_invariants() {
+            problems.push(format!("index {}: {err}", self.name));
+        }
+        for (i, (key, row)) in rows.iter().enumerate() {
+            if self.tree.find(&self.key_for(key.clone(), row)).is_none() {
+                let number = match key {
+                    RowKey::RowId(rowid) => *rowid,
+                    RowKey::PrimaryKey(_) => i as RowId + 1,
+                };
+                problems.push(format!("row {number} missing from index {}", self.name));
+            }
+        }
+        if self.tree.range(..).count() != rows.len() {
+            problems.push(format!("wrong # of entries in index {}", self.name));
+        }
+        problems
+    }
+
+    /// The rows whose leading indexed values equal `prefix` and whose value of the next
+    /// column lies between `lower` and `upper`, in index order. Bounding the column leaves
+    /// out its NULLs, which no comparison is ever true of.
+    pub fn rows_in_range(
+        &self,
+        prefix: &[ColVal],
+        lower: Bound<&ColVal>,
+        upper: Bound<&ColVal>,
+    ) -> Vec<RowKey> {
+        let column = prefix.len();
+        let bounded = !matches!((lower, upper), (Bound::Unbounded, Bound::Unbounded));
+        let collation = self.collations.get(column);
+        let compare = |a: &ColVal, b: &ColVal| match collation {
+            Some(collation) => collation.compare(a, b),
+            None => a.cmp(b),
+        };
+        let below = |value: &ColVal| match lower {
+            Bound::Included(bound) => compare(value, bound) == Ordering::Less,
+            Bound::Excluded(bound) => compare(value, bound) != Ordering::Greater,
+            Bound::Unbounded => *value == ColVal::Null,
+        };
+        let above = |value: &ColVal| match upper {
+            Bound::Included(bound) => compare(value, bound) == Ordering::Greater,
+            Bound::Excluded(bound) => compare(value, bound) != Ordering::Less,
+            Bound::Unbounded => false,
+        };
+
+        // start at the lower bound, or at the first key with the prefix
+        let mut start = prefix.to_vec();
+        if let Bound::Included(bound) | Bound::Excluded(bound) = lower {
+            start.push(bound.clone());
+        }
+        let mut rows = vec![];
+        for (key, _) in self.tree.range(first_with(start)..) {
+            if !self.same_values(&key.values, prefix) {
+                break;
+            }
+            if bounded {
+                let value = &key.values[column];
+                if below(value) {
+                    continue;
+                }
+                if above(value) {
+                    break;
+                }
+            }
+            rows.push(key.row.clone());
+        }
+        rows
+    }
+
+    /// What ANALYZE measures of the index: for its first column, its first two and so on,
+    /// how many rows share a value of them on average, rounded up as in SQLite, and up to
+    /// `samples` values of its first column from evenly spaced entries. NULLs count as
+    /// values like any other.
+    pub fn analyze(&self, samples: usize) -> (Vec<u64>, Vec<Sample>) {
+        let keys: Vec<&Vec<ColVal>> = self.tree.range(..).map(|(key, _)| &key.values).collect();
+        if keys.is_empty() {
+            return (vec![], vec![]);
+        }
+
+        // a key starts a new value of every prefix from the first column it differs in
+        let columns = self.columns.len();
+        let mut distinct = vec![0; columns];
+        // the runs of entries sharing a first column value, as (first entry, length)
+        let mut runs: Vec<(usize, usize)> = vec![];
+        for (i, key) in keys.iter().enumerate() {
+            let differs = match i {
+                0 => 0,
+                _ => (0..columns)
+                    .find(|c| {
+                        self.collations[*c].compare(&keys[i - 1][*c], &key[*c]) != Ordering::Equal
+                    })
+                    .unwrap_or(columns),
+            };
+            for count in &mut distinct[differs..] {
+                *count += 1;
+            }
+            match runs.last_mut() {
+                Some((_, len)) if differs > 0 => *len += 1,
+                _ => runs.push((i, 1)),
+            }
+        }
+        let rows = keys.len() as u64;
+        let rows_per_key = distinct.iter().map(|d| rows.div_ceil(*d)).collect();
+
+        let mut sampled: Vec<Sample> = vec![];
+        let samples = samples.min(keys.len());
+        for k in 0..samples {
+            let entry = k * keys.len() / samples;
+            let run = runs.partition_point(|(start, _)| *start <= entry) - 1;
+            let (start, len) = runs[run];
+            if sampled.last().is_some_and(|s| s.lt == start as u64) {
+                continue;
+            }
+            sampled.push(Sample {
+                value: keys[start][0].clone(),
+                eq: len as u64,
+                lt: start as u64,
+            });
+        }
+        (rows_per_key, sampled)
+    }
+}
+
+// Order keys by their values, each under its column's collation, and then by the row's key.
+fn comparator(collations: &[Collation]) -> Comparator<IndexKey> {
+    let names: Vec<&str> = collations.iter().map(|c| c.name.as_str()).collect();
+    let collations = collations.to_vec();
+    let compare = collations.to_vec();
+    Comparator::new(&names.join(","), move |a: &IndexKey, b: &IndexKey| {
+        Collation::compare_rows(&compare, &a.values, &b.values).then_with(|| a.row.compare(&b.row))
+    })
+    // entries for the same values are told apart by their rowids, and any others by just
+    // enough of the values, which come before every rowid that has them all
+    .with_separator(move |a: &IndexKey, b: &IndexKey| {
+        match Collation::separate_rows(&collations, &a.values, &b.values) {
+            Some(values) => first_with(values),
+            None => b.clone(),
+        }
+    })
+}
+
+fn has_null(values: &[ColVal]) -> bool {
+    values.contains(&ColVal::Null)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::parse_expr;
+
+    fn columns() -> Vec<Column> {
+        ["name", "email", "age"]
+            .into_iter()
+            .map(|name| Column {
+                name: name.to_string(),
+                type_name: None,
+                default: None,
+                collation: None,
+                generated: None,
+                not_null: false,
+            })
+            .collect()
+    }
+
+    fn row(name: &str, email: &str, age: i64) -> Vec<ColVal> {
+        vec![
+            ColVal::String(name.to_string()),
+            ColVal::String(email.to_string()),
+            ColVal::Int(age),
+        ]
+    }
+
+    fn rowids(keys: Vec<RowKey>) -> Vec<RowId> {
+        keys.into_iter()
+            .map(|key| match key {
+                RowKey::RowId(rowid) => rowid,
+                other => panic!("{other:?}"),
+            })
+            .collect()
+    }
+
+    // The rows whose leading indexed values are `prefix`, whatever the next column holds.
+    fn with_prefix(idx: &SecondaryIndex, prefix: &[ColVal]) -> Vec<RowKey> {
+        idx.rows_in_range(prefix, Bound::Unbounded, Bound::Unbounded)
+    }
+
+    fn index_on(column: &str, unique: bool) -> SecondaryIndex {
+        let def = CreateIndex {
+            name: format!("idx_{column}"),
+            table: "users".to_string(),
+            columns: vec![Expr::Column(column.to_string())],
+            unique,
+            if_not_exists: false,
+        };
+        SecondaryIndex::create(
+            &def,
+            &columns(),
+            &FunctionRegistry::with_builtins(),
+            &Collations::default(),
+            64,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn index_follows_inserts_updates_and_deletes() {
+        let mut idx = index_on("age", false);
+        idx.on_insert(&RowKey::RowId(1), &row("amy", "a@x", 30))
+            .unwrap();
+        idx.on_insert(&RowKey::RowId(2), &row("bob", "b@x", 21))
+            .unwrap();
+        idx.on_insert(&RowKey::RowId(3), &row("cat", "c@x", 21))
+            .unwrap();
+
+        assert_eq!(rowids(with_prefix(&idx, &[ColVal::Int(21)])), vec![2, 3]);
+        assert_eq!(rowids(with_prefix(&idx, &[ColVal::Int(30)])), vec![1]);
+
+        idx.on_delete(&RowKey::RowId(2), &row("bob", "b@x", 21));
+        idx.on_insert(&RowKey::RowId(2), &row("bob", "b@x", 22))
+            .unwrap();
+        assert_eq!(rowids(with_prefix(&idx, &[ColVal::Int(21)])), vec![3]);
+        assert_eq!(rowids(with_prefix(&idx, &[ColVal::Int(22)])), vec![2]);
+
+        idx.on_delete(&RowKey::RowId(3), &row("cat", "c@x", 21));
+        assert!(rowids(with_prefix(&idx, &[ColVal::Int(21)])).is_empty());
+    }
+
+    #[test]
+    fn ranges_are_read_between_their_bounds() {
+        let mut idx = index_on("age", false);
+        for (rowid, age) in [(1, 30), (2, 21), (3, 30), (4, 40), (5, 25)] {
+            idx.on_insert(&RowKey::RowId(rowid), &row("x", "x@x", age))
+                .unwrap();
+        }
+        let mut nameless = row("x", "x@x", 0);
+        nameless[2] = ColVal::Null;
+        idx.on_insert(&RowKey::RowId(6), &nameless).unwrap();
+
+        let (n21, n30) = (ColVal::Int(21), ColVal::Int(30));
+        let range = |lower, upper| rowids(idx.rows_in_range(&[], lower, upper));
+        assert_eq!(
+            range(Bound::Included(&n21), Bound::Included(&n30)),
+            [2, 5, 1, 3]
+        );
+        assert_eq!(range(Bound::Excluded(&n21), Bound::Excluded(&n30)), [5]);
+        assert_eq!(range(Bound::Excluded(&n30), Bound::Unbounded), [4]);
+        // a bound leaves out the NULLs that sort before every number
+        assert_eq!(range(Bound::Unbounded, Bound::Excluded(&n30)), [2, 5]);
+        assert_eq!(
+            range(Bound::Unbounded, Bound::Unbounded),
+            [6, 2, 5, 1, 3, 4]
+        );
+    }
+
+    #[test]
+    fn analyze_counts_keys_and_samples_values() {
+        let mut idx = index_on("age", false);
+        assert_eq!(idx.analyze(4), (vec![], vec![]));
+        for (rowid, age) in [(1, 30), (2, 21), (3, 30), (4, 40), (5, 30), (6, 25)] {
+            idx.on_insert(&RowKey::RowId(rowid), &row("x", "x@x", age))
+                .unwrap();
+        }
+        let sample = |age, eq, lt| Sample {
+            value: ColVal::Int(age),
+            eq,
+            lt,
+        };
+        // 6 rows of 4 ages, and entries 0, 1, 3 and 4 sampled, two of them the 30s
+        assert_eq!(
+            idx.analyze(4),
+            (
+                vec![2],
+                vec![sample(21, 1, 0), sample(25, 1, 1), sample(30, 3, 2)]
+            )
+        );
+        // asking for more samples than entries samples each value once
+        assert_eq!(idx.analyze(100).1.len(), 4);
+    }
+
+    #[test]
+    fn unique_index_rejects_duplicates_but_not_nulls() {
+        let mut idx = index_on("email", true);
+        idx.on_insert(&RowKey::RowId(1), &row("amy", "a@x", 30))
+            .unwrap();
+
+        let err = idx
+            .on_insert(&RowKey::RowId(2), &row("imposter", "a@x", 40))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: users.email");
+
+        // keeping its own email is not a conflict with itself
+        idx.on_delete(&RowKey::RowId(1), &row("amy", "a@x", 30));
+        idx.on_insert(&RowKey::RowId(1), &row("amy", "a@x", 31))
+            .unwrap();
+
+        let null_email = vec![
+            ColVal::String("x".to_string()),
+            ColVal::Null,
+            ColVal::Int(1),
+        ];
+        idx.on_insert(&RowKey::RowId(3), &null_email).unwrap();
+        idx.on_insert(&RowKey::RowId(4), &null_email).unwrap();
+    }
+
+    #[test]
+    fn check_finds_entries_out_of_step_with_the_table() {
+        let mut idx = index_on("email", false);
+        let (amy, bob) = (row("amy", "a@x", 30), row("bob", "b@x", 40));
+        idx.on_insert(&RowKey::RowId(1), &amy).unwrap();
+        idx.on_insert(&RowKey::RowId(2), &bob).unwrap();
+        assert!(idx
+            .check(&[(RowKey::RowId(1), &amy), (RowKey::RowId(2), &bob)])
+            .is_empty());
+
+        // bob's email changed in the table but not in the index
+        let moved = row("bob", "c@x", 40);
+        assert_eq!(
+            idx.check(&[(RowKey::RowId(1), &amy), (RowKey::RowId(2), &moved)]),
+            ["row 2 missing from index idx_email"]
+        );
+        // and an entry for a row that was deleted
+        assert_eq!(
+            idx.check(&[(RowKey::RowId(1), &amy)]),
+            ["wrong # of entries in index idx_email"]
+        );
+    }
+
+    #[test]
+    fn entries_on_without_rowid_tables_end_in_the_primary_key() {
+        let mut idx = index_on("age", true);
+        let key = |name: &str| RowKey::PrimaryKey(vec![ColVal::String(name.to_string())]);
+        idx.on_insert(&key("bob"), &row("bob", "b@x", 30)).unwrap();
+        idx.on_insert(&key("amy"), &row("amy", "a@x", 21)).unwrap();
+        assert_eq!(
+            idx.entries(),
+            [
+                vec![ColVal::Int(21), ColVal::String("amy".to_string())],
+                vec![ColVal::Int(30), ColVal::String("bob".to_string())],
+            ]
+        );
+        assert_eq!(with_prefix(&idx, &[ColVal::Int(30)]), [key("bob")]);
+        let err = idx
+            .on_insert(&key("cat"), &row("cat", "c@x", 30))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "UNIQUE constraint failed: users.age");
+        // a row keeping its own value isn't a conflict with itself
+        idx.on_delete(&key("bob"), &row("bob", "b@x", 30));
+        idx.on_insert(&key("bob"), &row("bob", "c@x", 30)).unwrap();
+        idx.on_delete(&key("bob"), &row("bob", "c@x", 30));
+        assert!(with_prefix(&idx, &[ColVal::Int(30)]).is_empty());
+    }
+
+    #[test]
+    fn build_indexes_existing_rows() {
+        let def = CreateIndex {
+            name: "idx_age".to_string(),
+            table: "users".to_string(),
+            columns: vec![Expr::Column("age".to_string())],
+            unique: false,
+            if_not_exists: false,
+        };
+        let functions = FunctionRegistry::with_builtins();
+        let rows = (0..1000).map(|rowid| {
+            let row = row("x", "x@x", (rowid * 7) % 100);
+            (RowKey::RowId(rowid), row)
+        });
+        // little enough memory that the keys are sorted in several runs
+        let idx = SecondaryIndex::build(
+            &def,
+            &columns(),
+            &functions,
+            &Collations::default(),
+            64,
+            rows,
+            4096,
+        )
+        .unwrap();
+        assert_eq!(
+            rowids(with_prefix(&idx, &[ColVal::Int(0)])),
+            (0..1000).filter(|r| r * 7 % 100 == 0).collect::<Vec<_>>()
+        );
+
+        let unique = CreateIndex {
+            unique: true,
+            ..def
+        };
+        let rows = vec![
+            (RowKey::RowId(1), row("a", "a@x", 30)),
+            (RowKey::RowId(2), row("b", "b@x", 30)),
+        ];
+        assert_eq!(
+            SecondaryIndex::build(
+                &unique,
+                &columns(),
+                &functions,
+                &Collations::default(),
+                64,
+                rows,
+                4096
+            )
+            .unwrap_err()
+            .to_string(),
+            "UNIQUE constraint failed: users.age"
+        );
+    }
+
+    #[test]
+    fn create_validates_columns_and_expressions() {
+        let functions = FunctionRegistry::with_builtins();
+        let mut def = CreateIndex {
+            name: "idx".to_string(),
+            table: "users".to_string(),
+            columns: vec![Expr::Column("agee".to_string())],
+            unique: false,
+            if_not_exists: false,
+        };
+        let err = SecondaryIndex::create(&def, &columns(), &functions, &Collations::default(), 64)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "no such column: agee");
+
+        def.columns = vec![Expr::Function {
+            name: "random".to_string(),
+            args: vec![],
+        }];
+        let err = SecondaryIndex::create(&def, &columns(), &functions, &Collations::default(), 64)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "non-deterministic functions prohibited in index expressions"
+        );
+    }
+
+    #[test]
+    fn rows_are_found_by_their_leading_columns() {
+        let def = CreateIndex {
+            name: "idx_name_age".to_string(),
+            table: "users".to_string(),
+            columns: vec![
+                Expr::Column("name".to_string()),
+                Expr::Column("age".to_string()),
+            ],
+            unique: false,
+            if_not_exists: false,
+        };
+        let mut idx = SecondaryIndex::create(
+            &def,
+            &columns(),
+            &FunctionRegistry::with_builtins(),
+            &Collations::default(),
+            64,
+        )
+        .unwrap();
+        idx.on_insert(&RowKey::RowId(1), &row("amy", "a@x", 30))
+            .unwrap();
+        idx.on_insert(&RowKey::RowId(2), &row("amy", "b@x", 31))
+            .unwrap();
+        idx.on_insert(&RowKey::RowId(3), &row("bob", "c@x", 30))
+            .unwrap();
+
+        let amy = ColVal::String("amy".to_string());
+        let both = [amy.clone(), ColVal::Int(31)];
+        assert_eq!(rowids(with_prefix(&idx, &both)), vec![2]);
+        // an index on (name, age) finds rows by name alone
+        assert_eq!(rowids(with_prefix(&idx, &[amy])), vec![1, 2]);
+        assert_eq!(rowids(with_prefix(&idx, &[])), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn keys_compare_under_their_collation() {
+        let mut table = columns();
+        table[0].collation = Some("NOCASE".to_string());
+        let def = CreateIndex {
+            name: "idx".to_string(),
+            table: "users".to_string(),
+            columns: vec![
+                Expr::Column("name".to_string()),
+                parse_expr("email COLLATE RTRIM").unwrap(),
+            ],
+            unique: true,
+            if_not_exists: false,
+        };
+        let functions = FunctionRegistry::with_builtins();
+        let collations = Collations::default();
+        let mut idx = SecondaryIndex::create(&def, &table, &functions, &collations, 64).unwrap();
+        idx.on_insert(&RowKey::RowId(1), &row("amy", "a@x", 30))
+            .unwrap();
+        idx.on_insert(&RowKey::RowId(2), &row("Bob", "b@x", 30))
+            .unwrap();
+
+        // the column's NOCASE and the index's RTRIM make this the same as amy's key
+        assert_eq!(
+            idx.on_insert(&RowKey::RowId(3), &row("AMY", "a@x  ", 40))
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: users.name, users.email"
+        );
+        assert_eq!(
+            rowids(with_prefix(&idx, &[ColVal::String("BOB".to_string())])),
+            vec![2]
+        );
+        assert_eq!(format!("{:?}", idx.tree.comparator()), "NOCASE,RTRIM");
+
+        let unknown = CreateIndex {
+            columns: vec![parse_expr("email COLLATE klingon").unwrap()],
+            ..def
+        };
+        assert_eq!(
+            SecondaryIndex::create(&unknown, &table, &functions, &collations, 64)
+                .unwrap_err()
+                .to_string(),
+            "no such collation sequence: klingon"
+        );
+    }
+}
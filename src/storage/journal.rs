@@ -0,0 +1,323 @@
+/*
+    Rollback journals, and committing a transaction across several database files at once.
+
+    Before a transaction overwrites pages of a database file it saves their original
+    contents in a journal next to it, "app.db-journal". If we crash halfway through
+    writing the new pages, the next time the database is opened the journal is still
+    there, a "hot" journal, and copying the originals back undoes the half written
+    transaction. Deleting the journal once every new page is safely in the file is the
+    moment the transaction commits.
+
+    With attached databases one transaction can write to several files, each with its
+    own journal. Deleting one journal and then the next isn't atomic: crash in between
+    and one database keeps the transaction while the other rolls it back. So like SQLite
+    we add a super-journal, a file named after the main database, "app.db-mj01A2B3C4",
+    that lists the databases taking part. The commit goes
+
+        1. write the super-journal
+        2. write each database's journal, naming the super-journal in it
+        3. write the new pages to each database file
+        4. delete the super-journal, which is the moment the transaction commits
+        5. delete the journals
+
+    and every step is synced to disk before the next begins. Recovering a database with a
+    hot journal first looks at the super-journal it names. If that still exists we crashed
+    before step 4, so the transaction didn't commit and is rolled back here just as it will
+    be in every other database when they are opened. If it is gone the transaction did
+    commit, and the journal is stale and simply deleted. A super-journal is deleted once no
+    journal names it any more, so the databases can be recovered one at a time in any
+    order.
+
+    The pager keeps each database's journal, see pager.rs, and splits its commit in two
+    as SQLite does, so that the steps above can be taken across several files:
+    commit_phase_one writes the journal, naming the super-journal in a record after the
+    pages saved, and then the new pages, and commit_phase_two empties the journal. commit
+    below runs the protocol for the files of a connection's databases, and opening a file
+    whose journal is hot recovers it as above, see Pager::open_file. A super-journal is
+    only needed when two or more of the files commit through a journal on disk. A shared
+    in-memory database has nothing to recover after a crash, and like SQLite we don't
+    commit a file in WAL mode atomically with the others, as its commit is to its log.
+    Nor is there a super-journal without a main database file to name it after.
+*/
+use super::cache::PageStore;
+use super::image::{DatabaseFile, ImageRow};
+use super::memdb::AccessMode;
+use super::os_interface::{OsVfs, Vfs, VfsFile};
+use super::pager;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// One database's part in a commit: its file, the rows to save to it and, for main, the
+/// change counter they were read at, see DatabaseFile::prepare.
+pub struct Save<'a> {
+    pub file: &'a mut DatabaseFile,
+    pub rows: Vec<ImageRow>,
+    pub counter: Option<u32>,
+}
+
+/// The name of the super-journal for a transaction on the connection whose main database
+/// is `main`. `nonce` keeps those of different transactions apart.
+pub fn super_journal_name(main: &str, nonce: u32) -> String {
+    format!("{main}-mj{nonce:08X}")
+}
+
+/// The journal beside the database file at `database`.
+pub fn journal_path(database: &Path) -> PathBuf {
+    let mut path = database.as_os_str().to_owned();
+    path.push("-journal");
+    PathBuf::from(path)
+}
+
+/// Save every one of `saves` in one transaction, all or nothing, with a super-journal
+/// beside `main`, the main database's file, when more than one file needs it. An error
+/// leaves every file as it was.
+pub fn commit(main: Option<&Path>, saves: &mut [Save]) -> Result<()> {
+    let databases: Vec<&Path> = saves
+        .iter()
+        .filter(|save| save.file.journals_on_disk())
+        .map(|save| save.file.path())
+        .collect();
+    let super_journal = match main {
+        Some(main) if databases.len() > 1 => {
+            let mut nonce = [0; 4];
+            OsVfs.randomness(&mut nonce);
+            let name = super_journal_name(&main.to_string_lossy(), u32::from_be_bytes(nonce));
+            let path = PathBuf::from(name);
+            write(&path, &databases)?;
+            Some(path)
+        }
+        _ => None,
+    };
+
+    let mut prepared = 0;
+    let mut result = saves.iter_mut().try_for_each(|save| {
+        let named = super_journal
+            .as_deref()
+            .filter(|_| save.file.journals_on_disk());
+        save.file.prepare(&save.rows, save.counter, named)?;
+        prepared += 1;
+        Ok(())
+    });
+    // the moment the transaction commits
+    if let (Ok(()), Some(path)) = (&result, &super_journal) {
+        result = OsVfs.delete(path);
+    }
+    if let Err(err) = result {
+        for save in &mut saves[..prepared] {
+            let _ = save.file.rollback();
+        }
+        if let Some(path) = &super_journal {
+            let _ = OsVfs.delete(path);
+        }
+        return Err(err);
+    }
+    for save in saves {
+        save.file.finish()?;
+    }
+    Ok(())
+}
+
+/// Deal with a hot journal found on opening its database, the EXCLUSIVE lock held: roll
+/// the transaction back, unless the journal names a super-journal that is gone, when the
+/// transaction committed and the journal is stale. A super-journal that no hot journal
+/// names any more is deleted.
+pub fn recover(journal: &mut dyn VfsFile, store: &mut dyn PageStore) -> Result<()> {
+    let super_journal = pager::super_journal_of(journal)?.map(PathBuf::from);
+    match &super_journal {
+        Some(path) if !OsVfs.exists(path)? => {
+            tracing::debug!(?path, "the journal's transaction committed");
+            journal.truncate(0)?;
+            journal.sync()?;
+        }
+        _ => {
+            pager::roll_back(journal, store)?;
+        }
+    }
+    match super_journal {
+        Some(path) => forget_if_done(&path),
+        None => Ok(()),
+    }
+}
+
+// Write the super-journal at `path`, listing `databases` each followed by a NUL as
+// SQLite does, and sync it before any journal names it.
+fn write(path: &Path, databases: &[&Path]) -> Result<()> {
+    let mut list = vec![];
+    for database in databases {
+        list.extend(database.to_string_lossy().as_bytes());
+        list.push(0);
+    }
+    let mut file = OsVfs.open(path, AccessMode::Create)?;
+    file.truncate(0)?;
+    file.write_at(0, &list)?;
+    file.sync()
+}
+
+// Delete the super-journal at `path` once none of the databases it lists has a hot
+// journal naming it, so that nothing is left for it to decide.
+fn forget_if_done(path: &Path) -> Result<()> {
+    if !OsVfs.exists(path)? {
+        return Ok(());
+    }
+    let mut file = OsVfs.open(path, AccessMode::ReadOnly)?;
+    let mut list = vec![0; file.size()? as usize];
+    file.read_at(0, &mut list)?;
+    for database in list.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let database = Path::new(std::str::from_utf8(database)?);
+        let Ok(mut journal) = OsVfs.open(&journal_path(database), AccessMode::ReadOnly) else {
+            continue;
+        };
+        if pager::super_journal_of(&mut journal)?.is_some_and(|name| Path::new(&name) == path) {
+            return Ok(());
+        }
+    }
+    OsVfs.delete(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::ColVal;
+    use crate::storage::compress::Compression;
+    use crate::storage::pager::PagerConfig;
+    use std::fs;
+
+    fn rows(value: &str) -> Vec<ImageRow> {
+        vec![(
+            "t".to_string(),
+            ColVal::Int(1),
+            vec![ColVal::String(value.to_string())],
+        )]
+    }
+
+    fn open(path: &Path) -> DatabaseFile {
+        let config = PagerConfig::default();
+        DatabaseFile::open(path, AccessMode::Create, Compression::None, &config).unwrap()
+    }
+
+    // What each database holds once a copy of the files as they are now, as a crash
+    // would leave them, is opened and recovered.
+    fn after_a_crash(dir: &Path, names: &[&str]) -> Vec<Vec<ImageRow>> {
+        let crashed = tempfile::tempdir().unwrap();
+        for name in names {
+            for file in [name.to_string(), format!("{name}-journal")] {
+                fs::copy(dir.join(&file), crashed.path().join(&file)).unwrap();
+            }
+        }
+        names
+            .iter()
+            .map(|name| open(&crashed.path().join(name)).load().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn a_crash_at_any_step_leaves_every_database_all_or_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let names = ["app.db", "archive.db"];
+        let (app_path, archive_path) = (dir.path().join(names[0]), dir.path().join(names[1]));
+        let (mut app, mut archive) = (open(&app_path), open(&archive_path));
+        app.prepare(&rows("a1"), None, None).unwrap();
+        app.finish().unwrap();
+        archive.prepare(&rows("x1"), None, None).unwrap();
+        archive.finish().unwrap();
+        let before = vec![rows("a1"), rows("x1")];
+        let after = vec![rows("a2"), rows("x2")];
+
+        // the steps of commit one at a time, with a crash after each
+        let super_journal = PathBuf::from(super_journal_name(&app_path.to_string_lossy(), 2));
+        write(&super_journal, &[&app_path, &archive_path]).unwrap();
+        assert_eq!(after_a_crash(dir.path(), &names), before);
+        app.prepare(&rows("a2"), None, Some(&super_journal))
+            .unwrap();
+        assert_eq!(after_a_crash(dir.path(), &names), before);
+        archive
+            .prepare(&rows("x2"), None, Some(&super_journal))
+            .unwrap();
+        assert_eq!(after_a_crash(dir.path(), &names), before);
+        // recovering the copies kept the super-journal, which the journals here name
+        assert!(super_journal.exists());
+        fs::remove_file(&super_journal).unwrap();
+        assert_eq!(after_a_crash(dir.path(), &names), after);
+        app.finish().unwrap();
+        assert_eq!(after_a_crash(dir.path(), &names), after);
+        archive.finish().unwrap();
+        assert_eq!(after_a_crash(dir.path(), &names), after);
+
+        // and all in one go, leaving no super-journal behind
+        let mut saves = [
+            Save {
+                file: &mut app,
+                rows: rows("a3"),
+                counter: None,
+            },
+            Save {
+                file: &mut archive,
+                rows: rows("x3"),
+                counter: None,
+            },
+        ];
+        commit(Some(&app_path), &mut saves).unwrap();
+        assert_eq!(after_a_crash(dir.path(), &names), [rows("a3"), rows("x3")]);
+        let left: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.contains("-mj"))
+            .collect();
+        assert!(left.is_empty(), "{left:?}");
+
+        // a save that fails leaves every file as it was
+        let counter = app.change_counter();
+        let mut saves = [
+            Save {
+                file: &mut archive,
+                rows: rows("x4"),
+                counter: None,
+            },
+            Save {
+                file: &mut app,
+                rows: rows("a4"),
+                counter: Some(counter.wrapping_sub(1)),
+            },
+        ];
+        assert!(commit(Some(&app_path), &mut saves).is_err());
+        assert_eq!(archive.load().unwrap(), rows("x3"));
+        assert_eq!(app.load().unwrap(), rows("a3"));
+    }
+
+    #[test]
+    fn a_stale_journal_naming_a_finished_commit_is_not_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.db");
+        let mut app = open(&path);
+        app.prepare(&rows("a1"), None, None).unwrap();
+        app.finish().unwrap();
+        let super_journal = dir.path().join("app.db-mj00000001");
+        write(&super_journal, &[&path]).unwrap();
+        app.prepare(&rows("a2"), None, Some(&super_journal))
+            .unwrap();
+        let mut journal = OsVfs
+            .open(&journal_path(&path), AccessMode::ReadOnly)
+            .unwrap();
+        assert_eq!(
+            pager::super_journal_of(&mut journal).unwrap(),
+            Some(super_journal.to_string_lossy().into_owned())
+        );
+
+        // the copy is rolled back while the super-journal is there, which it then
+        // deletes, as no journal but the copy's named it
+        let crashed = tempfile::tempdir().unwrap();
+        let copy = crashed.path().join("app.db");
+        let saved = crashed.path().join("saved.db");
+        for (from, to) in [(&path, &copy), (&path, &saved)] {
+            fs::copy(from, to).unwrap();
+            fs::copy(journal_path(from), journal_path(to)).unwrap();
+        }
+        drop(app);
+        assert!(super_journal.exists());
+        assert_eq!(open(&copy).load().unwrap(), rows("a1"));
+        assert!(!super_journal.exists());
+
+        // once it is gone the same journal is stale
+        assert_eq!(open(&saved).load().unwrap(), rows("a2"));
+    }
+}
@@ -0,0 +1,419 @@
+/*
+    A B+tree that threads in one process can use at once, readers alongside a writer.
+
+    Btree in btree.rs is borrowed whole: any number of readers or one writer, never both,
+    which is all a single connection needs. Here every node has a latch of its own, a
+    read-write lock held only while the node is looked at or changed, so a reader is only
+    ever held up by a writer in the very node it wants, and only for as long as that one
+    change takes.
+
+    A latch is not a lock in the sense of lock.rs. Locks keep transactions apart and are
+    held until a commit; latches keep threads from seeing a node half changed and are
+    held for a few instructions.
+
+    Going down the tree takes latches the way a crab walks (latch crabbing): latch the
+    root, then latch the child the key belongs in before letting go of the parent, and so
+    on down to the leaf, so that no thread ever holds more than two latches at once or
+    sees a node that its parent no longer leads to. Every thread takes its latches top
+    down and left to right, so none can be waiting on a latch another holds while it
+    holds one the other waits for, and nobody deadlocks.
+
+    Like btree.rs this is a B-link tree. Each node has a high key, where its right
+    sibling takes over, and a link to that sibling, and a split moves the upper half of a
+    node into a new right sibling before the parent learns of it. A thread that lands in
+    a node too far left for its key, because the node split after the parent was read,
+    follows the link right. That is why there are no parent pointers, and why the writer
+    doesn't have to keep the ancestors of a leaf latched in case the leaf splits: it lets
+    go of each as it goes down like a reader does, and latches the parent again, after
+    letting go of the child, to add the separator. There is one writer at a time, which
+    the writer's mutex sees to, so nothing else changes the tree's shape meanwhile.
+
+    A delete takes the key out of its leaf and nothing more. Nodes are never merged, so
+    keys only ever move right, which is what lets a scan let go of each leaf before it
+    latches the next: whatever split off to the right in between is still ahead of it,
+    and it picks up after the last key it returned. A scan sees each key as it was when
+    it read the key's leaf, not the whole tree as of one moment; for that there are
+    transactions, see wal.rs.
+
+    Keys are ordered by their own Ord rather than a comparator.
+
+    The connections in a process that share a file's pager, with cache=shared, keep the
+    file's rows in one of these, for each to load them from while another saves, see
+    image.rs.
+*/
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// A node and the latch that guards it.
+type Latch<K, V> = Arc<RwLock<Node<K, V>>>;
+
+struct Node<K, V> {
+    // 0 for a leaf, and one more than its children's for an inner node
+    level: usize,
+    keys: Vec<K>,
+    // a leaf's values, one for each key
+    values: Vec<V>,
+    // an inner node's children, one more than its keys
+    children: Vec<Latch<K, V>>,
+    // where the right sibling takes over, None for the rightmost node of its level
+    high_key: Option<K>,
+    next: Option<Latch<K, V>>,
+}
+
+// Where a descent is headed: the node that holds a key, or the leftmost leaf.
+enum Target<'a, K> {
+    Key(&'a K),
+    First,
+}
+
+// By hand, as deriving would ask for K: Copy
+impl<K> Clone for Target<'_, K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for Target<'_, K> {}
+
+impl<K: Ord, V> Node<K, V> {
+    fn leaf() -> Self {
+        Node {
+            level: 0,
+            keys: vec![],
+            values: vec![],
+            children: vec![],
+            high_key: None,
+            next: None,
+        }
+    }
+
+    // The right sibling, if `target` lies past this node's high key.
+    fn right_of(&self, target: Target<K>) -> Option<&Latch<K, V>> {
+        match (target, &self.high_key) {
+            (Target::Key(key), Some(high_key)) if key >= high_key => self.next.as_ref(),
+            _ => None,
+        }
+    }
+
+    // The node to go to next on the way to `target`, None in the leaf it is in.
+    fn step(&self, target: Target<K>) -> Option<Latch<K, V>> {
+        if let Some(next) = self.right_of(target) {
+            return Some(Arc::clone(next));
+        }
+        if self.level == 0 {
+            return None;
+        }
+        let child = match target {
+            Target::Key(key) => self.keys.partition_point(|k| k <= key),
+            Target::First => 0,
+        };
+        Some(Arc::clone(&self.children[child]))
+    }
+
+    // Move the upper half into a new right sibling, returning the separator between them
+    // and the sibling, linked in already.
+    fn split(&mut self) -> (K, Latch<K, V>)
+    where
+        K: Clone,
+    {
+        let half = self.keys.len() / 2;
+        let (separator, keys, values, children) = if self.level == 0 {
+            let keys = self.keys.split_off(half);
+            (keys[0].clone(), keys, self.values.split_off(half), vec![])
+        } else {
+            // an inner node's middle key moves up rather than across
+            let keys = self.keys.split_off(half + 1);
+            let separator = self.keys.pop().expect("a full node");
+            (separator, keys, vec![], self.children.split_off(half + 1))
+        };
+        let sibling = Arc::new(RwLock::new(Node {
+            level: self.level,
+            keys,
+            values,
+            children,
+            high_key: self.high_key.replace(separator.clone()),
+            next: self.next.take(),
+        }));
+        self.next = Some(Arc::clone(&sibling));
+        (separator, sibling)
+    }
+}
+
+/// A B+tree readers on many threads can search and scan while a writer changes it.
+pub struct LatchedBtree<K, V> {
+    // the root's latch is taken before the root's own, so it can't move in between
+    root: RwLock<Latch<K, V>>,
+    writer: Mutex<()>,
+    max_keys: usize,
+}
+
+impl<K: Ord + Clone, V: Clone> LatchedBtree<K, V> {
+    /// An empty tree whose nodes hold at most `max_keys` keys, and at least 3.
+    pub fn new(max_keys: usize) -> Self {
+        LatchedBtree {
+            root: RwLock::new(Arc::new(RwLock::new(Node::leaf()))),
+            writer: Mutex::new(()),
+            max_keys: max_keys.max(3),
+        }
+    }
+
+    /// Insert `value` under `key`, returning the value it replaces.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let _writer = self.writer.lock().unwrap();
+        let mut path = vec![];
+        let (replaced, split) = self.write_leaf(&key, &mut path, |leaf| {
+            match leaf.keys.binary_search(&key) {
+                Ok(i) => return (Some(std::mem::replace(&mut leaf.values[i], value)), None),
+                Err(i) => {
+                    leaf.keys.insert(i, key.clone());
+                    leaf.values.insert(i, value);
+                }
+            }
+            let split = (leaf.keys.len() > self.max_keys).then(|| leaf.split());
+            (None, split)
+        });
+        if let Some(split) = split {
+            self.add_separator(path, split);
+        }
+        replaced
+    }
+
+    /// Take `key` out of the tree, returning its value.
+    pub fn delete(&self, key: &K) -> Option<V> {
+        let _writer = self.writer.lock().unwrap();
+        self.write_leaf(key, &mut vec![], |leaf| {
+            let i = leaf.keys.binary_search(key).ok()?;
+            leaf.keys.remove(i);
+            Some(leaf.values.remove(i))
+        })
+    }
+
+    /// Take every entry out at once. A scan already under way carries on through the
+    /// entries as they were.
+    pub fn clear(&self) {
+        let _writer = self.writer.lock().unwrap();
+        *self.root.write().unwrap() = Arc::new(RwLock::new(Node::leaf()));
+    }
+
+    /// The entries with keys in `range`, in key order, read a leaf at a time.
+    pub fn range(&self, range: impl RangeBounds<K>) -> Range<K, V> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let target = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => Target::Key(key),
+            Bound::Unbounded => Target::First,
+        };
+        let leaf = self.read_leaf(target, |latch, _| Arc::clone(latch));
+        Range {
+            buffered: VecDeque::new(),
+            leaf: Some(leaf),
+            start,
+            end,
+        }
+    }
+
+    // Crab down to the leaf `target` is in and look at it.
+    fn read_leaf<R>(
+        &self,
+        target: Target<K>,
+        visit: impl FnOnce(&Latch<K, V>, &Node<K, V>) -> R,
+    ) -> R {
+        let root = self.root.read().unwrap();
+        let node = Arc::clone(&root);
+        let latched = node.read().unwrap();
+        drop(root);
+        Self::crab(latched, &node, target, visit)
+    }
+
+    // Latch the next node on the way to `target` before letting go of this one.
+    fn crab<R>(
+        latched: RwLockReadGuard<'_, Node<K, V>>,
+        node: &Latch<K, V>,
+        target: Target<K>,
+        visit: impl FnOnce(&Latch<K, V>, &Node<K, V>) -> R,
+    ) -> R {
+        let Some(next) = latched.step(target) else {
+            return visit(node, &latched);
+        };
+        let next_latched = next.read().unwrap();
+        drop(latched);
+        Self::crab(next_latched, &next, target, visit)
+    }
+
+    // Crab down to the leaf `key` is in as `crab` does, but latching the leaf to change
+    // it, and noting the inner nodes passed through in `path`.
+    fn write_leaf<R>(
+        &self,
+        key: &K,
+        path: &mut Vec<Latch<K, V>>,
+        change: impl FnOnce(&mut Node<K, V>) -> R,
+    ) -> R {
+        let root = self.root.read().unwrap();
+        let node = Arc::clone(&root);
+        // only the writer makes the root anything but a leaf, and it is the writer
+        if node.read().unwrap().level == 0 {
+            let mut leaf = node.write().unwrap();
+            drop(root);
+            return change(&mut leaf);
+        }
+        let latched = node.read().unwrap();
+        drop(root);
+        Self::crab_to_write(latched, &node, key, path, change)
+    }
+
+    fn crab_to_write<R>(
+        latched: RwLockReadGuard<'_, Node<K, V>>,
+        node: &Latch<K, V>,
+        key: &K,
+        path: &mut Vec<Latch<K, V>>,
+        change: impl FnOnce(&mut Node<K, V>) -> R,
+    ) -> R {
+        let next = latched.step(Target::Key(key)).expect("an inner node");
+        // nothing splits but the writer, so it never has to go right
+        debug_assert!(latched.right_of(Target::Key(key)).is_none());
+        path.push(Arc::clone(node));
+        if latched.level == 1 {
+            let mut leaf: RwLockWriteGuard<'_, Node<K, V>> = next.write().unwrap();
+            drop(latched);
+            return change(&mut leaf);
+        }
+        let next_latched = next.read().unwrap();
+        drop(latched);
+        Self::crab_to_write(next_latched, &next, key, path, change)
+    }
+
+    // Add the separator of a node that split to its parent, the last of `path`, splitting
+    // the parent in turn if that overfills it, up to a new root if the root split.
+    fn add_separator(&self, mut path: Vec<Latch<K, V>>, (separator, sibling): (K, Latch<K, V>)) {
+        let Some(parent) = path.pop() else {
+            let mut root = self.root.write().unwrap();
+            let old = Arc::clone(&root);
+            let level = old.read().unwrap().level + 1;
+            *root = Arc::new(RwLock::new(Node {
+                level,
+                keys: vec![separator],
+                values: vec![],
+                children: vec![old, sibling],
+                high_key: None,
+                next: None,
+            }));
+            return;
+        };
+        let split = {
+            let mut latched = parent.write().unwrap();
+            let i = latched.keys.partition_point(|k| *k <= separator);
+            latched.keys.insert(i, separator);
+            latched.children.insert(i + 1, sibling);
+            (latched.keys.len() > self.max_keys).then(|| latched.split())
+        };
+        if let Some(split) = split {
+            self.add_separator(path, split);
+        }
+    }
+}
+
+/// The entries of a range of keys, see `LatchedBtree::range`. No latch is held between
+/// one leaf and the next.
+pub struct Range<K, V> {
+    buffered: VecDeque<(K, V)>,
+    // the leaf the last entries came from, which may have split or grown since
+    leaf: Option<Latch<K, V>>,
+    // past the last key returned, once one has been
+    start: Bound<K>,
+    end: Bound<K>,
+}
+
+impl<K: Ord + Clone, V: Clone> Iterator for Range<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        while self.buffered.is_empty() {
+            // read the leaf again rather than the one after it, as anything it split off
+            // since lies in between
+            let latch = self.leaf.take()?;
+            let leaf = latch.read().unwrap();
+            let in_range = (self.start.as_ref(), self.end.as_ref());
+            for (key, value) in leaf.keys.iter().zip(&leaf.values) {
+                if in_range.contains(key) {
+                    self.buffered.push_back((key.clone(), value.clone()));
+                }
+            }
+            let ends_here = match (&leaf.high_key, &self.end) {
+                (None, _) => true,
+                (Some(high_key), Bound::Included(end)) => high_key > end,
+                (Some(high_key), Bound::Excluded(end)) => high_key >= end,
+                (Some(_), Bound::Unbounded) => false,
+            };
+            self.leaf = match (self.buffered.is_empty(), ends_here) {
+                (false, _) => Some(Arc::clone(&latch)),
+                (true, false) => leaf.next.clone(),
+                (true, true) => None,
+            };
+        }
+        let (key, value) = self.buffered.pop_front()?;
+        self.start = Bound::Excluded(key.clone());
+        Some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(tree: &LatchedBtree<usize, usize>, key: usize) -> Option<usize> {
+        tree.range(key..=key).next().map(|(_, value)| value)
+    }
+
+    #[test]
+    fn splits_keep_every_key_in_order() {
+        let tree = LatchedBtree::new(3);
+        for i in (0..200).rev() {
+            assert_eq!(tree.insert(i, i * 10), None);
+        }
+        assert_eq!(tree.insert(7, 0), Some(70));
+        assert_eq!(find(&tree, 150), Some(1500));
+        assert_eq!(tree.delete(&150), Some(1500));
+        assert_eq!(find(&tree, 150), None);
+
+        let keys: Vec<_> = tree.range(..).map(|(k, _)| k).collect();
+        let expected: Vec<_> = (0..200).filter(|k| *k != 150).collect();
+        assert_eq!(keys, expected);
+        let keys: Vec<_> = tree.range(148..=152).map(|(k, _)| k).collect();
+        assert_eq!(keys, vec![148, 149, 151, 152]);
+
+        tree.clear();
+        assert_eq!(tree.range(..).count(), 0);
+        assert_eq!(tree.insert(1, 1), None);
+        assert_eq!(find(&tree, 1), Some(1));
+    }
+
+    #[test]
+    fn readers_run_alongside_a_writer() {
+        let tree = LatchedBtree::new(4);
+        let written = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..2000 {
+                    tree.insert(i, i);
+                    written.store(i + 1, std::sync::atomic::Ordering::Release);
+                }
+            });
+            for _ in 0..3 {
+                s.spawn(|| loop {
+                    let done = written.load(std::sync::atomic::Ordering::Acquire);
+                    // whatever was inserted before the read began is found
+                    for key in (0..done).step_by(97) {
+                        assert_eq!(find(&tree, key), Some(key));
+                    }
+                    let keys: Vec<_> = tree.range(..).map(|(k, _)| k).collect();
+                    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                    assert!(keys.len() >= done);
+                    if done == 2000 {
+                        break;
+                    }
+                });
+            }
+        });
+    }
+}
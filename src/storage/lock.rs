@@ -0,0 +1,259 @@
+/*
+    File locks, which decide which connections may read and write the database at once.
+
+    SQLite has five lock levels that a connection moves up through:
+
+        UNLOCKED   nothing held, the connection isn't reading or writing
+        SHARED     reading. Any number of connections can hold SHARED together.
+        RESERVED   intends to write. Only one connection can hold RESERVED, but readers
+                   can carry on and new readers can still come in.
+        PENDING    wants EXCLUSIVE and is waiting for the readers to finish. Existing
+                   readers keep their SHARED locks but no new ones are granted, so a writer
+                   can't be starved by a stream of readers.
+        EXCLUSIVE  writing to the file. No other connection holds any lock at all.
+
+    A request that conflicts with another connection's lock fails straight away with
+    "database is locked" (SQLITE_BUSY) rather than blocking, and it is up to the caller to
+    retry. A failed request to go to EXCLUSIVE still leaves the connection at PENDING.
+
+    When that error shows up it is often far from obvious who is in the way, so the lock
+    table also remembers what each connection last failed to get. lock_status() reports
+    every connection's lock and what it is waiting for, which the REPL shows with `.locks`.
+*/
+use crate::error::SqlError;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum LockLevel {
+    #[default]
+    Unlocked,
+    Shared,
+    Reserved,
+    Pending,
+    Exclusive,
+}
+
+impl fmt::Display for LockLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self {
+            LockLevel::Unlocked => "UNLOCKED",
+            LockLevel::Shared => "SHARED",
+            LockLevel::Reserved => "RESERVED",
+            LockLevel::Pending => "PENDING",
+            LockLevel::Exclusive => "EXCLUSIVE",
+        };
+        write!(f, "{level}")
+    }
+}
+
+pub type ConnectionId = u32;
+
+/// One connection's entry in the lock table.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct LockStatus {
+    pub connection: ConnectionId,
+    pub held: LockLevel,
+    // the lock it last asked for and didn't get
+    pub waiting_for: Option<LockLevel>,
+}
+
+impl fmt::Display for LockStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "connection {}: {}", self.connection, self.held)?;
+        if let Some(level) = self.waiting_for {
+            write!(f, ", waiting for {level}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The locks every connection to one database file holds.
+#[derive(Debug, Default)]
+pub struct LockTable {
+    connections: BTreeMap<ConnectionId, LockStatus>,
+    next_id: ConnectionId,
+}
+
+impl LockTable {
+    pub fn connect(&mut self) -> ConnectionId {
+        self.next_id += 1;
+        let connection = self.next_id;
+        self.connections.insert(
+            connection,
+            LockStatus {
+                connection,
+                ..LockStatus::default()
+            },
+        );
+        connection
+    }
+
+    /// Closing a connection drops whatever locks it held.
+    pub fn disconnect(&mut self, connection: ConnectionId) {
+        self.connections.remove(&connection);
+    }
+
+    /// Raise a connection's lock to `level`, doing nothing if it already holds at least
+    /// that. SHARED must be held before RESERVED or EXCLUSIVE are asked for, and PENDING
+    /// is only ever taken on the way to EXCLUSIVE.
+    pub fn lock(&mut self, connection: ConnectionId, level: LockLevel) -> Result<()> {
+        let held = self.status(connection)?.held;
+        if held >= level {
+            return Ok(());
+        }
+        match level {
+            LockLevel::Unlocked => unreachable!("nothing is below UNLOCKED"),
+            LockLevel::Pending => bail!("PENDING can't be asked for directly"),
+            LockLevel::Reserved | LockLevel::Exclusive if held == LockLevel::Unlocked => {
+                bail!("{level} requires a SHARED lock first")
+            }
+            _ => {}
+        }
+
+        let others = self.highest_other(connection);
+        let granted = match level {
+            LockLevel::Shared => others < LockLevel::Pending,
+            LockLevel::Reserved => others < LockLevel::Reserved,
+            LockLevel::Exclusive => {
+                // take PENDING to keep out new readers while the current ones finish
+                if held < LockLevel::Pending && others < LockLevel::Reserved {
+                    self.set(connection, LockLevel::Pending);
+                }
+                self.status(connection)?.held == LockLevel::Pending && others == LockLevel::Unlocked
+            }
+            _ => unreachable!(),
+        };
+
+        let status = self
+            .connections
+            .get_mut(&connection)
+            .expect("checked above");
+        if !granted {
+            status.waiting_for = Some(level);
+            bail!(SqlError::DatabaseLocked);
+        }
+        status.held = level;
+        status.waiting_for = None;
+        Ok(())
+    }
+
+    /// Lower a connection's lock to SHARED, when a write transaction ends but the
+    /// connection is still reading, or to UNLOCKED.
+    pub fn unlock(&mut self, connection: ConnectionId, level: LockLevel) -> Result<()> {
+        if level > LockLevel::Shared {
+            bail!("can only unlock to SHARED or UNLOCKED");
+        }
+        let status = self.status(connection)?;
+        if status.held > level {
+            self.set(connection, level);
+        }
+        Ok(())
+    }
+
+    /// Which lock each connection holds and what it is waiting for.
+    pub fn lock_status(&self) -> Vec<LockStatus> {
+        self.connections.values().copied().collect()
+    }
+
+    pub fn held(&self, connection: ConnectionId) -> Result<LockLevel> {
+        Ok(self.status(connection)?.held)
+    }
+
+    /// The strongest lock any connection holds.
+    pub fn strongest(&self) -> LockLevel {
+        self.connections
+            .values()
+            .map(|s| s.held)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Put a connection back to `held` after something outside the table, the OS, turned
+    /// down the `wanted` lock the table had granted.
+    pub fn fall_back(&mut self, connection: ConnectionId, held: LockLevel, wanted: LockLevel) {
+        if let Some(status) = self.connections.get_mut(&connection) {
+            status.held = held;
+            status.waiting_for = Some(wanted);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    fn status(&self, connection: ConnectionId) -> Result<LockStatus> {
+        match self.connections.get(&connection) {
+            Some(status) => Ok(*status),
+            None => bail!("no such connection: {connection}"),
+        }
+    }
+
+    fn set(&mut self, connection: ConnectionId, level: LockLevel) {
+        if let Some(status) = self.connections.get_mut(&connection) {
+            status.held = level;
+            status.waiting_for = None;
+        }
+    }
+
+    // The strongest lock held by any connection but this one.
+    fn highest_other(&self, connection: ConnectionId) -> LockLevel {
+        self.connections
+            .values()
+            .filter(|s| s.connection != connection)
+            .map(|s| s.held)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_writer_many_readers() {
+        let mut locks = LockTable::default();
+        let (a, b, c) = (locks.connect(), locks.connect(), locks.connect());
+        for connection in [a, b, c] {
+            locks.lock(connection, LockLevel::Shared).unwrap();
+        }
+        locks.lock(a, LockLevel::Reserved).unwrap();
+        assert_eq!(
+            locks.lock(b, LockLevel::Reserved).unwrap_err().to_string(),
+            "database is locked"
+        );
+
+        // a can't write while b and c are reading, but new readers are now kept out
+        assert!(locks.lock(a, LockLevel::Exclusive).is_err());
+        let d = locks.connect();
+        assert!(locks.lock(d, LockLevel::Shared).is_err());
+
+        locks.unlock(b, LockLevel::Unlocked).unwrap();
+        locks.disconnect(c);
+        locks.lock(a, LockLevel::Exclusive).unwrap();
+        locks.unlock(a, LockLevel::Shared).unwrap();
+        locks.lock(d, LockLevel::Shared).unwrap();
+    }
+
+    #[test]
+    fn status_shows_who_is_waiting_for_what() {
+        let mut locks = LockTable::default();
+        let (a, b) = (locks.connect(), locks.connect());
+        locks.lock(a, LockLevel::Shared).unwrap();
+        locks.lock(b, LockLevel::Shared).unwrap();
+        locks.lock(b, LockLevel::Reserved).unwrap();
+        assert!(locks.lock(b, LockLevel::Exclusive).is_err());
+        assert!(locks.lock(a, LockLevel::Reserved).is_err());
+
+        let status: Vec<String> = locks.lock_status().iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            status,
+            [
+                "connection 1: SHARED, waiting for RESERVED",
+                "connection 2: PENDING, waiting for EXCLUSIVE",
+            ]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+/*
+    LZ4's block format, which compress.rs compresses pages with.
+
+    A block is a run of sequences, each some literal bytes copied as they are followed by
+    a match, a copy of bytes that came earlier in the output:
+
+        token       a byte, the literals' length in its high four bits and the match's
+                    length less 4 in its low four, 15 in either meaning more follows
+        length      the rest of the literals' length, bytes of 255 and one less than
+                    255 added up, only if the token said 15
+        literals
+        offset      how far back the match starts, two bytes little-endian
+        length      the rest of the match's length, the same way
+
+    The last sequence is literals alone, and as the format asks the last 5 bytes of the
+    input are always literals and no match starts in the last 12. Anything lz4 or
+    liblz4 decompresses as a block decompresses here and the other way round, but there
+    is no frame around it, compress.rs has its own, so the lz4 command line can't read a
+    page of ours without it.
+
+    Compression is the fast greedy kind: a table of where each 4 bytes were last seen,
+    hashed, and the first match found is taken and extended as far as it goes. It finds
+    less than liblz4's and is slower, but text compresses to well under half.
+*/
+use anyhow::{bail, Result};
+
+const MIN_MATCH: usize = 4;
+// The input's last bytes that are always literals, and how near its end a match may start.
+const LAST_LITERALS: usize = 5;
+const MATCH_LIMIT: usize = 12;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+
+/// `input` as an LZ4 block.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2);
+    // where each hash of 4 bytes was last seen, plus one so that 0 is nowhere
+    let mut seen = vec![0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MATCH_LIMIT < input.len() {
+        let bytes = read_u32(input, pos);
+        let slot = hash(bytes);
+        let candidate = seen[slot];
+        seen[slot] = pos + 1;
+        let Some(start) = candidate.checked_sub(1) else {
+            pos += 1;
+            continue;
+        };
+        if pos - start > MAX_OFFSET || read_u32(input, start) != bytes {
+            pos += 1;
+            continue;
+        }
+        let mut length = MIN_MATCH;
+        while pos + length < input.len() - LAST_LITERALS
+            && input[start + length] == input[pos + length]
+        {
+            length += 1;
+        }
+        write_sequence(&mut out, &input[anchor..pos], Some((pos - start, length)));
+        pos += length;
+        anchor = pos;
+    }
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// The `size` bytes `block` was compressed from, failing if it isn't a block of them.
+pub fn decompress(block: &[u8], size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+    loop {
+        let Some(&token) = block.get(pos) else {
+            bail!("malformed LZ4 block: it ends early");
+        };
+        pos += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(block, &mut pos)?;
+        }
+        match block.get(pos..pos + literals) {
+            Some(bytes) if out.len() + literals <= size => out.extend_from_slice(bytes),
+            _ => bail!("malformed LZ4 block: literals past its end"),
+        }
+        pos += literals;
+        if pos == block.len() {
+            break;
+        }
+
+        let Some(offset) = block.get(pos..pos + 2) else {
+            bail!("malformed LZ4 block: it ends early");
+        };
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        pos += 2;
+        let mut length = (token & 15) as usize + MIN_MATCH;
+        if token & 15 == 15 {
+            length += read_length(block, &mut pos)?;
+        }
+        if offset == 0 || offset > out.len() || out.len() + length > size {
+            bail!("malformed LZ4 block: a match out of bounds");
+        }
+        // a byte at a time, as a match may overlap what it copies
+        let start = out.len() - offset;
+        for i in start..start + length {
+            out.push(out[i]);
+        }
+    }
+    if out.len() != size {
+        bail!(
+            "malformed LZ4 block: {} bytes rather than {size}",
+            out.len()
+        );
+    }
+    Ok(out)
+}
+
+// A sequence of `literals` and the match at (offset, length), or the last of the block,
+// literals alone.
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], found: Option<(usize, usize)>) {
+    let extra = found.map_or(0, |(_, length)| length - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | extra.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = found {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if extra >= 15 {
+            write_length(out, extra - 15);
+        }
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        out.push(255);
+        length -= 255;
+    }
+    out.push(length as u8);
+}
+
+fn read_length(block: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut length = 0;
+    loop {
+        let Some(&byte) = block.get(*pos) else {
+            bail!("malformed LZ4 block: it ends early");
+        };
+        *pos += 1;
+        length += byte as usize;
+        if byte != 255 {
+            return Ok(length);
+        }
+    }
+}
+
+fn read_u32(input: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(input[pos..pos + 4].try_into().unwrap())
+}
+
+fn hash(bytes: u32) -> usize {
+    (bytes.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    #[test]
+    fn blocks_decompress_to_what_was_compressed() {
+        let text = "the quick brown fox jumps over the lazy dog, ".repeat(100);
+        let mut random = vec![0; 4096];
+        rand::thread_rng().fill_bytes(&mut random);
+        let inputs: [&[u8]; 5] = [b"", b"short", text.as_bytes(), &random, &[7; 5000]];
+        for input in inputs {
+            let block = compress(input);
+            assert_eq!(decompress(&block, input.len()).unwrap(), input);
+        }
+        assert!(compress(text.as_bytes()).len() < text.len() / 10);
+        // incompressible bytes grow by only a little
+        assert!(compress(&random).len() < random.len() + 32);
+
+        // "abc", a match of 24 overlapping it, then the last literals
+        let block = [
+            0x3f, b'a', b'b', b'c', 3, 0, 5, 0x50, b'x', b'y', b'z', b'z', b'y',
+        ];
+        let expected = "abc".repeat(9) + "xyzzy";
+        assert_eq!(decompress(&block, 32).unwrap(), expected.as_bytes());
+
+        let block = compress(text.as_bytes());
+        assert!(decompress(&block[..block.len() - 3], text.len()).is_err());
+        assert!(decompress(&block, text.len() - 1).is_err());
+        assert_eq!(
+            decompress(&[0x00, 9, 0], 10).unwrap_err().to_string(),
+            "malformed LZ4 block: a match out of bounds"
+        );
+    }
+}
@@ -0,0 +1,494 @@
+/*
+    In-memory databases, and the URI filenames that ask for them.
+
+    Besides a plain path a database can be opened by a URI like SQLite's
+
+        file:data.db?mode=ro
+        file:memdb1?mode=memory&cache=shared
+        file:data.db?format=sqlite
+        file:data.db?compression=lz4
+
+    mode=memory keeps the whole database in memory, it never touches disk and vanishes
+    when closed. Normally every connection that opens an in-memory database gets a fresh
+    empty one of its own, even if the names are the same. With cache=shared connections in
+    the same process that open the same name share one database instead, which is how to
+    test several connections against one database without creating files. The database
+    lives as long as at least one connection to it is open and is gone once the last one
+    closes, so the registry of shared databases only holds weak references. cache=shared
+    on a file of our own format shares its pager, page cache and the rows read from its
+    tree between the process's connections to it, see image.rs, though each connection
+    still builds a schema and tables of its own from those rows.
+
+    `:memory:` on its own is the private in-memory database, as in SQLite.
+
+    format=sqlite keeps a file in SQLite's own format rather than ours, so that sqlite3
+    can open it too, see sqlite_file.rs. compression=lz4 keeps the pages of a new file
+    compressed, see compress.rs, and can't be had with it. Both are this engine's own
+    parameters, which SQLite ignores.
+
+    An in-memory database is a file like any other as far as the pager is concerned, a
+    MemFile, which is a VfsFile over bytes in memory, see os_interface.rs. MemVfs is a
+    whole file system of them, files created and deleted by path that last as long as the
+    MemVfs does, which makes for tests of the storage stack that never touch the disk.
+*/
+use super::compress::Compression;
+use super::lock::{ConnectionId, LockLevel, LockStatus, LockTable};
+use super::os_interface::{OsVfs, Vfs, VfsFile};
+use anyhow::{bail, Result};
+use rand::RngCore;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum AccessMode {
+    ReadOnly,
+    ReadWrite,
+    // read-write, creating the file if it doesn't exist
+    #[default]
+    Create,
+}
+
+/// How a database file is laid out.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum FileFormat {
+    /// An image of the tables, see image.rs.
+    #[default]
+    Image,
+    /// SQLite's own B-trees, see sqlite_file.rs.
+    Sqlite,
+}
+
+/// Where a connection's database lives.
+#[derive(Debug, PartialEq, Clone)]
+pub enum OpenTarget {
+    File {
+        path: PathBuf,
+        mode: AccessMode,
+        format: FileFormat,
+        compression: Compression,
+        // whether connections in the process share the file's pager, see image.rs
+        shared: bool,
+    },
+    Memory {
+        name: String,
+        shared: bool,
+    },
+}
+
+impl OpenTarget {
+    /// Parse a filename given to open, either a path or a `file:` URI.
+    pub fn parse(filename: &str) -> Result<OpenTarget> {
+        if filename == ":memory:" {
+            return Ok(OpenTarget::Memory {
+                name: filename.to_string(),
+                shared: false,
+            });
+        }
+        let Some(uri) = filename.strip_prefix("file:") else {
+            return Ok(OpenTarget::File {
+                path: PathBuf::from(filename),
+                mode: AccessMode::default(),
+                format: FileFormat::default(),
+                compression: Compression::default(),
+                shared: false,
+            });
+        };
+
+        let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let path = percent_decode(path)?;
+        let mut memory = path == ":memory:";
+        let mut mode = AccessMode::default();
+        let mut shared = false;
+        let mut format = FileFormat::default();
+        let mut compression = Compression::default();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match (key, percent_decode(value)?.as_str()) {
+                ("mode", "ro") => mode = AccessMode::ReadOnly,
+                ("mode", "rw") => mode = AccessMode::ReadWrite,
+                ("mode", "rwc") => mode = AccessMode::Create,
+                ("mode", "memory") => memory = true,
+                ("mode", other) => bail!("no such access mode: {other}"),
+                ("cache", "shared") => shared = true,
+                ("cache", "private") => shared = false,
+                ("cache", other) => bail!("no such cache mode: {other}"),
+                ("format", "sqlite") => format = FileFormat::Sqlite,
+                ("format", "image") => format = FileFormat::Image,
+                ("format", other) => bail!("no such file format: {other}"),
+                ("compression", name) => compression = Compression::parse(name)?,
+                // SQLite ignores parameters it doesn't know
+                _ => {}
+            }
+        }
+
+        if format == FileFormat::Sqlite && compression != Compression::None {
+            bail!("a file in SQLite's format can't be compressed");
+        }
+        Ok(if memory {
+            OpenTarget::Memory { name: path, shared }
+        } else {
+            OpenTarget::File {
+                path: PathBuf::from(path),
+                mode,
+                format,
+                compression,
+                shared,
+            }
+        })
+    }
+}
+
+impl OpenTarget {
+    /// Open the file the database lives in: from disk, or a fresh in-memory one, or the
+    /// shared in-memory database of that name if it is open already.
+    pub fn open(&self) -> Result<Box<dyn VfsFile + Send>> {
+        Ok(match self {
+            OpenTarget::File { path, mode, .. } => Box::new(OsVfs.open(path, *mode)?),
+            OpenTarget::Memory { name, shared } => {
+                static SHARED: OnceLock<MemoryDatabases<MemoryFile>> = OnceLock::new();
+                let file =
+                    SHARED
+                        .get_or_init(Default::default)
+                        .open(name, *shared, MemoryFile::default);
+                Box::new(MemFile::new(file))
+            }
+        })
+    }
+}
+
+// Undo %XX escapes, e.g. %20 for a space.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail
+                .get(..2)
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            let Some(decoded) = hex else {
+                bail!("invalid escape in uri: {s}");
+            };
+            bytes.push(decoded);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    match String::from_utf8(bytes) {
+        Ok(decoded) => Ok(decoded),
+        Err(_) => bail!("invalid escape in uri: {s}"),
+    }
+}
+
+/// The shared in-memory databases that are open in this process, by name.
+#[derive(Debug)]
+pub struct MemoryDatabases<T> {
+    open: Mutex<HashMap<String, Weak<Mutex<T>>>>,
+}
+
+impl<T> Default for MemoryDatabases<T> {
+    fn default() -> Self {
+        MemoryDatabases {
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<T> MemoryDatabases<T> {
+    /// The in-memory database a new connection to `name` uses. A shared name that is
+    /// already open gives the same database, otherwise `create` makes a new one.
+    pub fn open(&self, name: &str, shared: bool, create: impl FnOnce() -> T) -> Arc<Mutex<T>> {
+        if !shared {
+            return Arc::new(Mutex::new(create()));
+        }
+        let mut open = self.open.lock().expect("memory database registry poisoned");
+        if let Some(db) = open.get(name).and_then(Weak::upgrade) {
+            return db;
+        }
+        // forget databases whose connections have all closed
+        open.retain(|_, db| db.strong_count() > 0);
+        let db = Arc::new(Mutex::new(create()));
+        open.insert(name.to_string(), Arc::downgrade(&db));
+        db
+    }
+}
+
+/// The bytes of an in-memory file and the locks its connections hold on it.
+#[derive(Debug, Default)]
+pub struct MemoryFile {
+    bytes: Vec<u8>,
+    locks: LockTable,
+}
+
+/// A connection to a file kept in memory.
+#[derive(Debug)]
+pub struct MemFile {
+    file: Arc<Mutex<MemoryFile>>,
+    connection: ConnectionId,
+}
+
+impl MemFile {
+    fn new(file: Arc<Mutex<MemoryFile>>) -> Self {
+        let connection = file.lock().unwrap().locks.connect();
+        MemFile { file, connection }
+    }
+}
+
+impl VfsFile for MemFile {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let file = self.file.lock().unwrap();
+        let start = (offset as usize).min(file.bytes.len());
+        let read = buf.len().min(file.bytes.len() - start);
+        buf[..read].copy_from_slice(&file.bytes[start..start + read]);
+        Ok(read)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        let end = offset as usize + data.len();
+        if file.bytes.len() < end {
+            file.bytes.resize(end, 0);
+        }
+        file.bytes[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    // nothing to sync, and nothing survives a crash anyway
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        self.file.lock().unwrap().bytes.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.file.lock().unwrap().bytes.len() as u64)
+    }
+
+    fn lock(&mut self, level: LockLevel) -> Result<()> {
+        self.file.lock().unwrap().locks.lock(self.connection, level)
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> Result<()> {
+        self.file
+            .lock()
+            .unwrap()
+            .locks
+            .unlock(self.connection, level)
+    }
+
+    fn lock_status(&self) -> Vec<LockStatus> {
+        self.file.lock().unwrap().locks.lock_status()
+    }
+}
+
+impl Drop for MemFile {
+    fn drop(&mut self) {
+        if let Ok(mut file) = self.file.lock() {
+            file.locks.disconnect(self.connection);
+        }
+    }
+}
+
+/// A file system held in memory, its files by path.
+#[derive(Debug, Default)]
+pub struct MemVfs {
+    files: Mutex<HashMap<PathBuf, Arc<Mutex<MemoryFile>>>>,
+}
+
+impl Vfs for MemVfs {
+    type File = MemFile;
+
+    fn open(&self, path: &Path, mode: AccessMode) -> Result<MemFile> {
+        let mut files = self.files.lock().unwrap();
+        let file = match files.get(path) {
+            Some(file) => file.clone(),
+            None if mode == AccessMode::Create => {
+                files.entry(path.to_path_buf()).or_default().clone()
+            }
+            None => bail!("unable to open database file {}", path.display()),
+        };
+        Ok(MemFile::new(file))
+    }
+
+    // Open connections keep the file's bytes, as an open file on disk keeps its blocks.
+    fn delete(&self, path: &Path) -> Result<()> {
+        match self.files.lock().unwrap().remove(path) {
+            Some(_) => Ok(()),
+            None => bail!("unable to delete {}", path.display()),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(self.files.lock().unwrap().contains_key(path))
+    }
+
+    fn randomness(&self, buf: &mut [u8]) {
+        rand::thread_rng().fill_bytes(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::header::DatabaseHeader;
+    use crate::storage::os_interface::PageFile;
+    use crate::storage::pager::{Pager, PagerConfig};
+
+    #[test]
+    fn parse_uri_filenames() {
+        assert_eq!(
+            OpenTarget::parse("file:memdb1?mode=memory&cache=shared").unwrap(),
+            OpenTarget::Memory {
+                name: "memdb1".to_string(),
+                shared: true,
+            }
+        );
+        assert_eq!(
+            OpenTarget::parse(":memory:").unwrap(),
+            OpenTarget::Memory {
+                name: ":memory:".to_string(),
+                shared: false,
+            }
+        );
+        assert_eq!(
+            OpenTarget::parse("file:my%20data.db?mode=ro&vfs=unix").unwrap(),
+            OpenTarget::File {
+                path: PathBuf::from("my data.db"),
+                mode: AccessMode::ReadOnly,
+                format: FileFormat::Image,
+                compression: Compression::None,
+                shared: false,
+            }
+        );
+        assert_eq!(
+            OpenTarget::parse("data.db?mode=ro").unwrap(),
+            OpenTarget::File {
+                path: PathBuf::from("data.db?mode=ro"),
+                mode: AccessMode::Create,
+                format: FileFormat::Image,
+                compression: Compression::None,
+                shared: false,
+            }
+        );
+        assert_eq!(
+            OpenTarget::parse("file:data.db?format=sqlite").unwrap(),
+            OpenTarget::File {
+                path: PathBuf::from("data.db"),
+                mode: AccessMode::Create,
+                format: FileFormat::Sqlite,
+                compression: Compression::None,
+                shared: false,
+            }
+        );
+        assert_eq!(
+            OpenTarget::parse("file:x?cache=public")
+                .unwrap_err()
+                .to_string(),
+            "no such cache mode: public"
+        );
+        assert_eq!(
+            OpenTarget::parse("file:data.db?compression=lz4").unwrap(),
+            OpenTarget::File {
+                path: PathBuf::from("data.db"),
+                mode: AccessMode::Create,
+                format: FileFormat::Image,
+                compression: Compression::Lz4,
+                shared: false,
+            }
+        );
+        assert_eq!(
+            OpenTarget::parse("file:data.db?format=sqlite&compression=lz4")
+                .unwrap_err()
+                .to_string(),
+            "a file in SQLite's format can't be compressed"
+        );
+        assert_eq!(
+            OpenTarget::parse("file:data.db?cache=shared").unwrap(),
+            OpenTarget::File {
+                path: PathBuf::from("data.db"),
+                mode: AccessMode::Create,
+                format: FileFormat::Image,
+                compression: Compression::None,
+                shared: true,
+            }
+        );
+    }
+
+    #[test]
+    fn the_pager_runs_over_files_in_memory() {
+        let vfs = MemVfs::default();
+        let path = Path::new("test.db");
+        assert!(vfs.open(path, AccessMode::ReadWrite).is_err());
+        let config = PagerConfig::default();
+        let header = DatabaseHeader::new(&config);
+
+        let file = PageFile::new(vfs.open(path, AccessMode::Create).unwrap(), 4096);
+        let mut pager = Pager::open(file, header, &config).unwrap();
+        pager.allocate_page("users").unwrap();
+        pager.allocate_page("users").unwrap();
+        pager.get_page_mut(2, "users").unwrap()[0] = 7;
+        pager.flush().unwrap();
+        let header = *pager.header();
+
+        let file = PageFile::new(vfs.open(path, AccessMode::ReadOnly).unwrap(), 4096);
+        let mut pager = Pager::open(file, header, &config).unwrap();
+        assert_eq!(pager.get_page(2, "users").unwrap()[0], 7);
+        let mut file = vfs.open(path, AccessMode::ReadOnly).unwrap();
+        assert_eq!(file.size().unwrap(), 2 * 4096);
+
+        vfs.delete(path).unwrap();
+        assert!(!vfs.exists(path).unwrap());
+        // the connections still open keep what they had
+        assert_eq!(file.size().unwrap(), 2 * 4096);
+    }
+
+    #[test]
+    fn memory_targets_open_as_files() {
+        let mut a = OpenTarget::parse(":memory:").unwrap().open().unwrap();
+        let mut b = OpenTarget::parse(":memory:").unwrap().open().unwrap();
+        a.write_at(0, b"abc").unwrap();
+        assert_eq!(b.size().unwrap(), 0);
+
+        let shared = OpenTarget::parse("file:target_test?mode=memory&cache=shared").unwrap();
+        let mut a = shared.open().unwrap();
+        let mut b = shared.open().unwrap();
+        a.write_at(0, b"abc").unwrap();
+        let mut buf = [0; 4];
+        assert_eq!(b.read_at(0, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"abc");
+        // and share their locks
+        a.lock(LockLevel::Shared).unwrap();
+        b.lock(LockLevel::Shared).unwrap();
+        assert!(a.lock(LockLevel::Exclusive).is_err());
+    }
+
+    #[test]
+    fn shared_memory_databases_live_while_connections_do() {
+        let databases = MemoryDatabases::default();
+        let a = databases.open("memdb1", true, Vec::new);
+        a.lock().unwrap().push(1);
+
+        let b = databases.open("memdb1", true, Vec::new);
+        assert_eq!(*b.lock().unwrap(), [1]);
+        // a private database of the same name is a different database
+        assert!(databases
+            .open("memdb1", false, Vec::new)
+            .lock()
+            .unwrap()
+            .is_empty());
+
+        drop(a);
+        drop(b);
+        assert!(databases
+            .open("memdb1", true, Vec::new)
+            .lock()
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,3 +1,29 @@
+pub mod attach;
 mod btree;
-mod os_interface;
-mod pager;
+pub mod busy;
+pub mod cache;
+pub mod clustered;
+pub mod compress;
+pub mod freelist;
+pub mod header;
+pub mod image;
+pub mod index;
+pub mod journal;
+pub mod latch;
+pub mod lock;
+pub mod lz4;
+pub mod memdb;
+pub mod os_interface;
+#[cfg(unix)]
+mod os_unix;
+#[cfg(windows)]
+mod os_windows;
+pub mod overflow;
+pub mod page;
+pub mod pager;
+pub mod record;
+pub mod recover;
+pub mod replacement;
+pub mod sqlite_file;
+pub mod table;
+pub mod wal;
@@ -8,4 +8,599 @@
 
     Oh another reason is we want portability across OSs for our db. This module abstracts away
     operating system specific code for reading, writing and locking files.
+
+    A Vfs opens and deletes files and supplies what else the engine needs from the OS,
+    random bytes. A file it opens is a VfsFile, read and written at byte offsets, synced,
+    truncated and locked. The pager doesn't care which Vfs its file came from: PageFile turns any VfsFile into the
+    PageStore the pager reads and writes pages through, page n at offset (n - 1) times the
+    page size, so another backend is another pair of these two traits.
+
+    OsVfs is the one for files on disk, and MemVfs in memdb.rs the one for files in
+    memory, as `:memory:` databases are kept.
+
+    OsVfs locks files twice over. Between the connections of one process a lock table for
+    each file settles who gets which lock, see lock.rs. Between processes it is settled by
+    the OS's locks on bytes of the file set aside for them, laid out as SQLite lays them
+    out so the two can share a file: the byte at 1GB is the PENDING byte, the next one the
+    RESERVED byte, and the 510 bytes after those the SHARED range. The process holds the
+    strongest lock any of its connections holds, taken through a handle of its own on the
+    file that stays open while any connection to the file does.
+
+    What OsVfs needs from the OS itself, positional reads and writes and those locks, is
+    in os_unix.rs, with pread, pwrite and fcntl, and in os_windows.rs, with ReadFile,
+    WriteFile and LockFileEx. On Unix closing any descriptor of a file drops every lock
+    the process holds on it, so an OsFile closed while others to the same file are still
+    open keeps its descriptor open until they close too, as SQLite's unix VFS does.
+    An OsFile can also map the start of its file into memory, with mmap on Unix, for
+    the pager to read pages from under PRAGMA mmap_size.
 */
+use super::cache::PageStore;
+use super::lock::{ConnectionId, LockLevel, LockStatus, LockTable};
+use super::memdb::AccessMode;
+#[cfg(unix)]
+use super::os_unix as os;
+#[cfg(windows)]
+use super::os_windows as os;
+use super::wal::PageNumber;
+use crate::error::SqlError;
+use anyhow::{bail, Context, Result};
+use rand::RngCore;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+// The bytes of a file its locks are taken on, as in SQLite.
+pub const PENDING_BYTE: u64 = 0x4000_0000;
+pub const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+pub const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+pub const SHARED_SIZE: u64 = 510;
+
+/// Where the engine's files live and what else it needs from the OS.
+pub trait Vfs {
+    type File: VfsFile;
+
+    fn open(&self, path: &Path, mode: AccessMode) -> Result<Self::File>;
+    fn delete(&self, path: &Path) -> Result<()>;
+    fn exists(&self, path: &Path) -> Result<bool>;
+    /// Fill `buf` with random bytes.
+    fn randomness(&self, buf: &mut [u8]);
+}
+
+/// A file a Vfs has opened.
+pub trait VfsFile {
+    /// Read into `buf` from `offset`, returning how many bytes there were, fewer than
+    /// asked for only at the end of the file.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+    /// Make sure what has been written has reached the disk.
+    fn sync(&mut self) -> Result<()>;
+    fn truncate(&mut self, size: u64) -> Result<()>;
+    fn size(&mut self) -> Result<u64>;
+    /// Move this file's lock up to `level`, failing with "database is locked" if another
+    /// connection's lock is in the way.
+    fn lock(&mut self, level: LockLevel) -> Result<()>;
+    /// Move this file's lock down to `level`.
+    fn unlock(&mut self, level: LockLevel) -> Result<()>;
+    /// The lock each connection to the file holds and what it is waiting for, nothing for
+    /// a file that isn't shared.
+    fn lock_status(&self) -> Vec<LockStatus> {
+        vec![]
+    }
+    /// Map the first `size` bytes of the file into memory, or the whole file if it is
+    /// shorter, in place of what was mapped before, and nothing for 0. A file that can't
+    /// be mapped is left unmapped.
+    fn map(&mut self, _size: u64) -> Result<()> {
+        Ok(())
+    }
+    /// What map mapped, None if nothing is.
+    fn mapping(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+// Each open file's locks, shared by the whole process.
+#[derive(Debug)]
+struct FileLocks {
+    // the handle the OS locks are taken through, open for writing if it can be
+    file: File,
+    // the locks each of the process's connections to the file holds
+    table: LockTable,
+    // the lock the process holds on the file from the OS, the strongest in the table
+    os: LockLevel,
+    // Files closed while other connections were still open. Closing any descriptor of a
+    // file drops every lock the process holds on it, so they stay open until the last
+    // connection closes.
+    closed: Vec<File>,
+}
+
+impl FileLocks {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .or_else(|_| File::open(path))?;
+        Ok(FileLocks {
+            file,
+            table: LockTable::default(),
+            os: LockLevel::Unlocked,
+            closed: vec![],
+        })
+    }
+
+    // Bring the OS lock down to the strongest left in the table.
+    fn lower(&mut self) -> Result<()> {
+        let strongest = self.table.strongest();
+        if strongest < self.os {
+            os::lower(&self.file, self.os, strongest)?;
+            self.os = strongest;
+        }
+        Ok(())
+    }
+}
+
+// Every open file's locks, by path.
+fn lock_tables() -> &'static Mutex<HashMap<PathBuf, FileLocks>> {
+    static TABLES: OnceLock<Mutex<HashMap<PathBuf, FileLocks>>> = OnceLock::new();
+    TABLES.get_or_init(Default::default)
+}
+
+/// The files of the OS the engine runs on.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsVfs;
+
+#[derive(Debug)]
+pub struct OsFile {
+    // only taken when the file is closed
+    file: Option<File>,
+    // the start of the file, mapped for the pager to read
+    mapping: Option<os::Mapping>,
+    // the path the file's locks are kept under
+    path: PathBuf,
+    connection: ConnectionId,
+}
+
+impl OsFile {
+    fn file(&mut self) -> &mut File {
+        self.file.as_mut().expect("an open file")
+    }
+}
+
+impl Vfs for OsVfs {
+    type File = OsFile;
+
+    fn open(&self, path: &Path, mode: AccessMode) -> Result<OsFile> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode != AccessMode::ReadOnly)
+            .create(mode == AccessMode::Create)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("unable to open database file {}", path.display()))?;
+        let path = fs::canonicalize(path)?;
+        let mut tables = lock_tables().lock().unwrap();
+        let locks = match tables.entry(path.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(FileLocks::open(&path)?),
+        };
+        let connection = locks.table.connect();
+        tracing::debug!(path = %path.display(), connection, "file opened");
+        Ok(OsFile {
+            file: Some(file),
+            mapping: None,
+            path,
+            connection,
+        })
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        fs::remove_file(path).with_context(|| format!("unable to delete {}", path.display()))
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool> {
+        Ok(path.try_exists()?)
+    }
+
+    fn randomness(&self, buf: &mut [u8]) {
+        rand::thread_rng().fill_bytes(buf);
+    }
+}
+
+impl VfsFile for OsFile {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let file = self.file();
+        let mut read = 0;
+        while read < buf.len() {
+            match os::read_at(file, offset + read as u64, &mut buf[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(read)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        tracing::trace!(offset, bytes = data.len(), "file written");
+        Ok(os::write_at(self.file(), offset, data)?)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        tracing::trace!(path = %self.path.display(), "fsync");
+        Ok(self.file().sync_all()?)
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        Ok(self.file().set_len(size)?)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        Ok(self.file().metadata()?.len())
+    }
+
+    // The lock table settles it between this process's connections first, then if the
+    // process needs a stronger lock than it has, the OS settles it with other processes.
+    fn lock(&mut self, level: LockLevel) -> Result<()> {
+        let _span = tracing::debug_span!("lock", connection = self.connection, %level).entered();
+        let mut tables = lock_tables().lock().unwrap();
+        let locks = tables.get_mut(&self.path).expect("an open file's locks");
+        let held = locks.table.held(self.connection)?;
+        let granted = locks.table.lock(self.connection, level);
+        let strongest = locks.table.strongest();
+        if strongest > locks.os {
+            match os::raise(&locks.file, locks.os, strongest) {
+                Ok(reached) => {
+                    locks.os = reached;
+                    if reached < strongest {
+                        locks.table.fall_back(self.connection, reached, level);
+                        tracing::debug!(%reached, "lock refused by the OS");
+                        bail!(SqlError::DatabaseLocked);
+                    }
+                }
+                Err(err) => {
+                    locks.table.fall_back(self.connection, held, level);
+                    return Err(err);
+                }
+            }
+        }
+        match &granted {
+            Ok(()) => tracing::debug!("lock taken"),
+            Err(_) => tracing::debug!("lock busy"),
+        }
+        granted
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> Result<()> {
+        let mut tables = lock_tables().lock().unwrap();
+        let locks = tables.get_mut(&self.path).expect("an open file's locks");
+        locks.table.unlock(self.connection, level)?;
+        tracing::debug!(connection = self.connection, %level, "lock lowered");
+        locks.lower()
+    }
+
+    // this process's connections, those of others are known only to the OS
+    fn lock_status(&self) -> Vec<LockStatus> {
+        let tables = lock_tables().lock().unwrap();
+        tables
+            .get(&self.path)
+            .map(|locks| locks.table.lock_status())
+            .unwrap_or_default()
+    }
+
+    // Remapped only when the length changes, which asking the file's size costs a call
+    // for unless nothing is to be mapped.
+    fn map(&mut self, size: u64) -> Result<()> {
+        let len = match size {
+            0 => 0,
+            size => size.min(self.size()?) as usize,
+        };
+        if self.mapping().map_or(0, <[u8]>::len) == len {
+            return Ok(());
+        }
+        self.mapping = None;
+        self.mapping = os::map(self.file(), len)?;
+        tracing::debug!(path = %self.path.display(), bytes = len, "file mapped");
+        Ok(())
+    }
+
+    fn mapping(&self) -> Option<&[u8]> {
+        self.mapping.as_ref().map(os::Mapping::bytes)
+    }
+}
+
+impl Drop for OsFile {
+    fn drop(&mut self) {
+        let mut tables = lock_tables().lock().unwrap();
+        let Some(locks) = tables.get_mut(&self.path) else {
+            return;
+        };
+        let file = self.file.take().expect("an open file");
+        locks.table.disconnect(self.connection);
+        // nothing to be done about an error here, and the locks go when the file closes
+        let _ = locks.lower();
+        if locks.table.is_empty() {
+            tables.remove(&self.path);
+        } else {
+            locks.closed.push(file);
+        }
+    }
+}
+
+// A file from whichever Vfs, as OpenTarget::open gives it.
+impl<F: VfsFile + ?Sized> VfsFile for Box<F> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        (**self).write_at(offset, data)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+
+    fn truncate(&mut self, size: u64) -> Result<()> {
+        (**self).truncate(size)
+    }
+
+    fn size(&mut self) -> Result<u64> {
+        (**self).size()
+    }
+
+    fn lock(&mut self, level: LockLevel) -> Result<()> {
+        (**self).lock(level)
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> Result<()> {
+        (**self).unlock(level)
+    }
+
+    fn lock_status(&self) -> Vec<LockStatus> {
+        (**self).lock_status()
+    }
+
+    fn map(&mut self, size: u64) -> Result<()> {
+        (**self).map(size)
+    }
+
+    fn mapping(&self) -> Option<&[u8]> {
+        (**self).mapping()
+    }
+}
+
+/// The pages of a database kept in a VfsFile, for the pager to read and write.
+#[derive(Debug)]
+pub struct PageFile<F: VfsFile> {
+    file: F,
+    page_size: usize,
+}
+
+impl<F: VfsFile> PageFile<F> {
+    pub fn new(file: F, page_size: u32) -> Self {
+        PageFile {
+            file,
+            page_size: page_size as usize,
+        }
+    }
+
+    pub fn file(&mut self) -> &mut F {
+        &mut self.file
+    }
+
+    pub fn lock_status(&self) -> Vec<LockStatus> {
+        self.file.lock_status()
+    }
+
+    fn offset(&self, page: PageNumber) -> u64 {
+        (page as u64 - 1) * self.page_size as u64
+    }
+}
+
+impl<F: VfsFile> PageStore for PageFile<F> {
+    // Past the end of the file a page reads as zeroes, as in SQLite.
+    fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+        tracing::trace!(page, "page read from the file");
+        let mut data = vec![0; self.page_size];
+        self.file.read_at(self.offset(page), &mut data)?;
+        Ok(data)
+    }
+
+    fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+        tracing::trace!(page, "page written to the file");
+        self.file.write_at(self.offset(page), data)
+    }
+
+    fn write_pages(&mut self, first: PageNumber, pages: &[&[u8]]) -> Result<()> {
+        tracing::trace!(first, count = pages.len(), "pages written to the file");
+        self.file.write_at(self.offset(first), &pages.concat())
+    }
+
+    fn read_pages(&mut self, first: PageNumber, count: u32) -> Result<Vec<Vec<u8>>> {
+        tracing::trace!(first, count, "pages read from the file");
+        let mut data = vec![0; self.page_size * count as usize];
+        self.file.read_at(self.offset(first), &mut data)?;
+        Ok(data.chunks(self.page_size).map(<[u8]>::to_vec).collect())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync()
+    }
+
+    fn map(&mut self, size: u64) -> Result<()> {
+        self.file.map(size)
+    }
+
+    fn mapping(&self) -> Option<&[u8]> {
+        self.file.mapping()
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    fn lock(&mut self, level: LockLevel) -> Result<()> {
+        self.file.lock(level)
+    }
+
+    fn unlock(&mut self, level: LockLevel) -> Result<()> {
+        self.file.unlock(level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::header::DatabaseHeader;
+    use crate::storage::pager::{Pager, PagerConfig};
+    use std::io::{Read, Write};
+
+    #[test]
+    fn the_pager_reads_and_writes_os_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut config = PagerConfig::default();
+        config.set_page_size(512);
+        let header = DatabaseHeader::new(&config);
+
+        let file = OsVfs.open(&path, AccessMode::Create).unwrap();
+        let mut pager = Pager::open(PageFile::new(file, 512), header, &config).unwrap();
+        for page in 1..=3u8 {
+            pager.allocate_page("users").unwrap();
+            pager.get_page_mut(page.into(), "users").unwrap()[0] = page;
+        }
+        pager.flush().unwrap();
+        let header = *pager.header();
+        drop(pager);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 3 * 512);
+
+        let file = OsVfs.open(&path, AccessMode::ReadOnly).unwrap();
+        let mut pager = Pager::open(PageFile::new(file, 512), header, &config).unwrap();
+        assert_eq!(pager.get_page(3, "users").unwrap()[..2], [3, 0]);
+
+        assert!(OsVfs.exists(&path).unwrap());
+        OsVfs.delete(&path).unwrap();
+        assert!(!OsVfs.exists(&path).unwrap());
+        assert!(OsVfs.open(&path, AccessMode::ReadWrite).is_err());
+    }
+
+    #[test]
+    fn files_opened_twice_share_their_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut writer = OsVfs.open(&path, AccessMode::Create).unwrap();
+        let mut reader = OsVfs.open(&path, AccessMode::ReadOnly).unwrap();
+        reader.lock(LockLevel::Shared).unwrap();
+        writer.lock(LockLevel::Shared).unwrap();
+        assert_eq!(
+            writer.lock(LockLevel::Exclusive).unwrap_err().to_string(),
+            "database is locked"
+        );
+        drop(reader);
+        writer.lock(LockLevel::Exclusive).unwrap();
+
+        writer.write_at(10, b"abc").unwrap();
+        let mut buf = [0; 20];
+        assert_eq!(writer.read_at(8, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], [0, 0, b'a', b'b', b'c']);
+        writer.truncate(4).unwrap();
+        assert_eq!(writer.size().unwrap(), 4);
+    }
+
+    // Open the file named by LOCK_TEST_FILE from another process and take the lock
+    // LOCK_TEST_LEVEL there, holding it until the parent closes stdin. The test harness
+    // runs this like any other test, when it does nothing.
+    #[test]
+    fn lock_in_another_process() {
+        let Ok(path) = std::env::var("LOCK_TEST_FILE") else {
+            return;
+        };
+        let mut file = OsVfs.open(Path::new(&path), AccessMode::ReadWrite).unwrap();
+        let level = std::env::var("LOCK_TEST_LEVEL").unwrap();
+        let mut result = file.lock(LockLevel::Shared);
+        if level == "RESERVED" {
+            result = result.and_then(|()| file.lock(LockLevel::Reserved));
+        }
+        // on a line of its own, after the harness's "test ... "
+        println!("\nlocked: {}", result.is_ok());
+        std::io::stdout().flush().unwrap();
+        std::io::stdin().read_to_end(&mut vec![]).unwrap();
+    }
+
+    struct OtherProcess(std::process::Child);
+
+    impl OtherProcess {
+        // Start another process taking `level`, returning it and whether it got it.
+        fn lock(path: &Path, level: &str) -> (Self, bool) {
+            use std::io::BufRead;
+            use std::process::{Command, Stdio};
+            let mut child = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "storage::os_interface::tests::lock_in_another_process",
+                    "--nocapture",
+                ])
+                .env("LOCK_TEST_FILE", path)
+                .env("LOCK_TEST_LEVEL", level)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let mut stdout = std::io::BufReader::new(child.stdout.take().unwrap());
+            let locked = (&mut stdout)
+                .lines()
+                .map(Result::unwrap)
+                .find_map(|line| Some(line.strip_prefix("locked: ")? == "true"))
+                .unwrap();
+            child.stdout = Some(stdout.into_inner());
+            (OtherProcess(child), locked)
+        }
+    }
+
+    impl Drop for OtherProcess {
+        fn drop(&mut self) {
+            drop(self.0.stdin.take());
+            // read what's left so the harness there can finish
+            let mut stdout = self.0.stdout.take().unwrap();
+            std::io::copy(&mut stdout, &mut std::io::sink()).unwrap();
+            self.0.wait().unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn processes_see_each_others_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut file = OsVfs.open(&path, AccessMode::Create).unwrap();
+
+        // a reader elsewhere lets this process reserve the file but not write it
+        let (reader, got) = OtherProcess::lock(&path, "SHARED");
+        assert!(got);
+        file.lock(LockLevel::Shared).unwrap();
+        file.lock(LockLevel::Reserved).unwrap();
+        assert!(!OtherProcess::lock(&path, "RESERVED").1);
+        assert_eq!(
+            file.lock(LockLevel::Exclusive).unwrap_err().to_string(),
+            "database is locked"
+        );
+        // PENDING now keeps new readers out
+        assert!(!OtherProcess::lock(&path, "SHARED").1);
+
+        drop(reader);
+        file.lock(LockLevel::Exclusive).unwrap();
+        // closing another connection to the file in this process keeps the locks
+        drop(OsVfs.open(&path, AccessMode::ReadOnly).unwrap());
+        assert!(!OtherProcess::lock(&path, "SHARED").1);
+
+        file.unlock(LockLevel::Shared).unwrap();
+        let (_reader, got) = OtherProcess::lock(&path, "SHARED");
+        assert!(got);
+        drop(file);
+        assert!(OtherProcess::lock(&path, "RESERVED").1);
+    }
+}
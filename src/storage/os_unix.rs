@@ -0,0 +1,167 @@
+/*
+    The Unix half of the OS interface, after SQLite's os_unix.c.
+
+    Reads and writes are positional, pread and pwrite, so they never move a file offset
+    another read on the same file is relying on. Locks are fcntl's POSIX advisory locks on
+    the bytes os_interface.rs sets aside for them. A reader read-locks the SHARED range,
+    checking on the way in that nobody holds the PENDING byte; a writer write-locks the
+    RESERVED byte, then the PENDING byte to keep new readers out, and at EXCLUSIVE
+    write-locks the SHARED range, which it only gets once every reader in every process
+    has gone. A lock another process holds in the way stops the climb where it is.
+
+    fcntl locks belong to the process, not the descriptor, so the OsVfs takes them all
+    through one descriptor for each file, and a write lock taken through a descriptor
+    only opened for reading fails.
+
+    PRAGMA mmap_size maps the start of the file with mmap, read-only and shared, so that
+    what pwrite writes shows through the map straight away, as the OS keeps a single
+    copy of the file's pages for both.
+*/
+use super::lock::LockLevel;
+use super::os_interface::{PENDING_BYTE, RESERVED_BYTE, SHARED_FIRST, SHARED_SIZE};
+use anyhow::Result;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+/// Read into `buf` from `offset`, returning how many bytes there were.
+pub fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    file.read_at(buf, offset)
+}
+
+pub fn write_at(file: &File, offset: u64, data: &[u8]) -> io::Result<()> {
+    file.write_all_at(data, offset)
+}
+
+/// The first bytes of a file mapped into memory, unmapped when dropped.
+pub struct Mapping {
+    address: *mut libc::c_void,
+    len: usize,
+}
+
+// SAFETY: the map is only ever read, and belongs to nothing but the Mapping
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    pub fn bytes(&self) -> &[u8] {
+        // SAFETY: mmap mapped `len` readable bytes at `address`, which stay mapped until
+        // the Mapping is dropped
+        unsafe { std::slice::from_raw_parts(self.address as *const u8, self.len) }
+    }
+}
+
+impl fmt::Debug for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes mapped", self.len)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the bytes were mapped by map and nothing borrows them any longer
+        unsafe { libc::munmap(self.address, self.len) };
+    }
+}
+
+/// Map the first `len` bytes of `file`, which must have that many, None for 0.
+pub fn map(file: &File, len: usize) -> io::Result<Option<Mapping>> {
+    if len == 0 {
+        return Ok(None);
+    }
+    // SAFETY: a new mapping of a descriptor open for as long as the call, which is
+    // left to the kernel to place
+    let address = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if address == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Some(Mapping { address, len }))
+}
+
+/// Climb from `from` towards `to`, returning how far the OS let it get.
+pub fn raise(file: &File, from: LockLevel, to: LockLevel) -> Result<LockLevel> {
+    let mut reached = from;
+    let climbed = climb(file, &mut reached, to);
+    if climbed.is_err() {
+        let _ = lower(file, reached, from);
+    }
+    climbed.map(|()| reached)
+}
+
+fn climb(file: &File, reached: &mut LockLevel, to: LockLevel) -> Result<()> {
+    if *reached == LockLevel::Unlocked {
+        // a writer holding PENDING keeps new readers out
+        if !set(file, libc::F_RDLCK, PENDING_BYTE, 1)? {
+            return Ok(());
+        }
+        let shared = set(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE);
+        set(file, libc::F_UNLCK, PENDING_BYTE, 1)?;
+        if !shared? {
+            return Ok(());
+        }
+        *reached = LockLevel::Shared;
+    }
+    for (level, start, len) in [
+        (LockLevel::Reserved, RESERVED_BYTE, 1),
+        (LockLevel::Pending, PENDING_BYTE, 1),
+        (LockLevel::Exclusive, SHARED_FIRST, SHARED_SIZE),
+    ] {
+        if *reached >= level || to < level {
+            continue;
+        }
+        if !set(file, libc::F_WRLCK, start, len)? {
+            return Ok(());
+        }
+        *reached = level;
+    }
+    Ok(())
+}
+
+/// Come down from `from` to `to`.
+pub fn lower(file: &File, from: LockLevel, to: LockLevel) -> Result<()> {
+    if to == LockLevel::Unlocked {
+        set(file, libc::F_UNLCK, PENDING_BYTE, 2 + SHARED_SIZE)?;
+        return Ok(());
+    }
+    if from == LockLevel::Exclusive {
+        set(file, libc::F_RDLCK, SHARED_FIRST, SHARED_SIZE)?;
+    }
+    if to < LockLevel::Pending {
+        set(file, libc::F_UNLCK, PENDING_BYTE, 1)?;
+    }
+    if to < LockLevel::Reserved {
+        set(file, libc::F_UNLCK, RESERVED_BYTE, 1)?;
+    }
+    Ok(())
+}
+
+// Set a lock of `kind` on `len` bytes from `start` without waiting, returning false if
+// another process holds a lock in the way.
+fn set(file: &File, kind: i32, start: u64, len: u64) -> Result<bool> {
+    // SAFETY: flock is plain data, for which zeroes are valid
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = kind as _;
+    lock.l_whence = libc::SEEK_SET as _;
+    lock.l_start = start as _;
+    lock.l_len = len as _;
+    // SAFETY: the descriptor is open for as long as `file` is and `lock` outlives the call
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EACCES | libc::EAGAIN) => Ok(false),
+        _ => Err(err.into()),
+    }
+}
@@ -0,0 +1,193 @@
+/*
+    The Windows half of the OS interface, after SQLite's os_win.c.
+
+    Reads and writes are positional, ReadFile and WriteFile at an offset given in an
+    OVERLAPPED, which std's seek_read and seek_write wrap. Locks are LockFileEx's on the
+    bytes os_interface.rs sets aside for them, laid out as on Unix so that SQLite and this
+    engine can share a file. LockFileEx locks are shared or exclusive rather than read or
+    write, they belong to the handle that took them, and they are mandatory: nobody else
+    can read or write a locked byte, which is why the bytes lie at 1GB, past the end of
+    any smaller database. SQLite never keeps a page there in a bigger one, and the pager
+    here doesn't skip that page yet, so a database of more than 1GB is for Unix only.
+
+    Windows can't turn a lock it holds into another, so going to EXCLUSIVE lets go of the
+    shared lock on the SHARED range before asking for the exclusive one, and takes the
+    shared lock back if it is refused, and coming down from EXCLUSIVE does the reverse.
+
+    Files aren't mapped into memory on Windows yet, so PRAGMA mmap_size changes nothing
+    here and every page is read into the cache.
+*/
+use super::lock::LockLevel;
+use super::os_interface::{PENDING_BYTE, RESERVED_BYTE, SHARED_FIRST, SHARED_SIZE};
+use anyhow::Result;
+use std::fs::File;
+use std::io;
+use std::os::windows::fs::FileExt;
+use std::os::windows::io::AsRawHandle;
+use windows_sys::Win32::Foundation::{ERROR_LOCK_VIOLATION, ERROR_NOT_LOCKED, HANDLE};
+use windows_sys::Win32::Storage::FileSystem::{
+    LockFileEx, UnlockFileEx, LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
+};
+use windows_sys::Win32::System::IO::OVERLAPPED;
+
+/// A mapping of a file, of which there are none on Windows yet.
+#[derive(Debug)]
+pub enum Mapping {}
+
+impl Mapping {
+    pub fn bytes(&self) -> &[u8] {
+        match *self {}
+    }
+}
+
+/// Files aren't mapped on Windows yet, so always None.
+pub fn map(_file: &File, _len: usize) -> io::Result<Option<Mapping>> {
+    Ok(None)
+}
+
+/// Read into `buf` from `offset`, returning how many bytes there were.
+pub fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    file.seek_read(buf, offset)
+}
+
+pub fn write_at(file: &File, mut offset: u64, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match file.seek_write(data, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Climb from `from` towards `to`, returning how far the OS let it get.
+pub fn raise(file: &File, from: LockLevel, to: LockLevel) -> Result<LockLevel> {
+    let mut reached = from;
+    let climbed = climb(file, &mut reached, to);
+    if climbed.is_err() {
+        let _ = lower(file, reached, from);
+    }
+    climbed.map(|()| reached)
+}
+
+fn climb(file: &File, reached: &mut LockLevel, to: LockLevel) -> Result<()> {
+    if *reached == LockLevel::Unlocked {
+        // a writer holding PENDING keeps new readers out
+        if !lock(file, false, PENDING_BYTE, 1)? {
+            return Ok(());
+        }
+        let shared = lock(file, false, SHARED_FIRST, SHARED_SIZE);
+        unlock(file, PENDING_BYTE, 1)?;
+        if !shared? {
+            return Ok(());
+        }
+        *reached = LockLevel::Shared;
+    }
+    for (level, byte) in [
+        (LockLevel::Reserved, RESERVED_BYTE),
+        (LockLevel::Pending, PENDING_BYTE),
+    ] {
+        if *reached >= level || to < level {
+            continue;
+        }
+        if !lock(file, true, byte, 1)? {
+            return Ok(());
+        }
+        *reached = level;
+    }
+    if to == LockLevel::Exclusive && *reached == LockLevel::Pending {
+        unlock(file, SHARED_FIRST, SHARED_SIZE)?;
+        if !lock(file, true, SHARED_FIRST, SHARED_SIZE)? {
+            lock(file, false, SHARED_FIRST, SHARED_SIZE)?;
+            return Ok(());
+        }
+        *reached = LockLevel::Exclusive;
+    }
+    Ok(())
+}
+
+/// Come down from `from` to `to`.
+pub fn lower(file: &File, from: LockLevel, to: LockLevel) -> Result<()> {
+    // held exclusively at EXCLUSIVE and shared below it
+    if from == LockLevel::Exclusive || to == LockLevel::Unlocked {
+        unlock(file, SHARED_FIRST, SHARED_SIZE)?;
+    }
+    if from == LockLevel::Exclusive && to >= LockLevel::Shared {
+        lock(file, false, SHARED_FIRST, SHARED_SIZE)?;
+    }
+    if to < LockLevel::Pending {
+        unlock(file, PENDING_BYTE, 1)?;
+    }
+    if to < LockLevel::Reserved {
+        unlock(file, RESERVED_BYTE, 1)?;
+    }
+    Ok(())
+}
+
+// An OVERLAPPED giving the offset `start`, which is all LockFileEx uses it for on a file
+// opened for synchronous I/O.
+fn at(start: u64) -> OVERLAPPED {
+    // SAFETY: OVERLAPPED is plain data, for which zeroes are valid
+    let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+    overlapped.Anonymous.Anonymous.Offset = start as u32;
+    overlapped.Anonymous.Anonymous.OffsetHigh = (start >> 32) as u32;
+    overlapped
+}
+
+// Lock `len` bytes from `start` without waiting, returning false if another handle holds
+// a lock in the way.
+fn lock(file: &File, exclusive: bool, start: u64, len: u64) -> Result<bool> {
+    let mut flags = LOCKFILE_FAIL_IMMEDIATELY;
+    if exclusive {
+        flags |= LOCKFILE_EXCLUSIVE_LOCK;
+    }
+    let mut overlapped = at(start);
+    // SAFETY: the handle is open for as long as `file` is and `overlapped` outlives the
+    // call, which doesn't return until the lock is taken or refused
+    let locked = unsafe {
+        LockFileEx(
+            file.as_raw_handle() as HANDLE,
+            flags,
+            0,
+            len as u32,
+            (len >> 32) as u32,
+            &mut overlapped,
+        )
+    };
+    if locked != 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(code) if code as u32 == ERROR_LOCK_VIOLATION => Ok(false),
+        _ => Err(err.into()),
+    }
+}
+
+// Let go of the lock on `len` bytes from `start`, if this handle holds one.
+fn unlock(file: &File, start: u64, len: u64) -> Result<()> {
+    let mut overlapped = at(start);
+    // SAFETY: as for LockFileEx
+    let unlocked = unsafe {
+        UnlockFileEx(
+            file.as_raw_handle() as HANDLE,
+            0,
+            len as u32,
+            (len >> 32) as u32,
+            &mut overlapped,
+        )
+    };
+    if unlocked != 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(code) if code as u32 == ERROR_NOT_LOCKED => Ok(()),
+        _ => Err(err.into()),
+    }
+}
@@ -0,0 +1,264 @@
+/*
+    Overflow chains, how a row too big for one page is stored across several.
+
+    A row is stored as a record, the row's values encoded one after another behind a
+    header of their types, see record.rs. A cell holding the whole record has to fit on a
+    B+tree page with room to spare for other cells, so a record longer than a page's
+    max_local bytes keeps only its first bytes in the cell and the rest spills onto a chain
+    of overflow pages. Each overflow page starts with the number of the next page in the
+    chain, 0 on the last, and holds usable_size - 4 bytes of the record after it. The cell
+    ends with the number of the first overflow page.
+
+    How much stays in the cell follows SQLite exactly, so that a page of ours reads the
+    same as a page of SQLite's. With U the usable size of a page, its size less the bytes
+    reserved at its end (see page.rs):
+
+        max_local  U - 35 for a table, (U - 12) * 64 / 255 - 23 for an index key
+        min_local  (U - 12) * 32 / 255 - 23
+
+    A record of up to max_local bytes is stored whole. A longer one keeps min_local bytes,
+    plus however many more would otherwise only part fill the last overflow page if that
+    still fits in max_local, so that every overflow page but the last is full.
+
+    A chain can be no longer than the database has pages, so max_page_count bounds how big
+    a row can get, and SQLite's SQLITE_MAX_LENGTH bounds it whatever the pages. A write of a
+    bigger row fails with "row too large" before anything is stored.
+
+    A B+tree spills any cell too big for its page this way when it writes its nodes out,
+    see btree.rs. All its cells are laid out as an index's keys are, whatever they hold, so
+    that a page holds at least four of them.
+*/
+use super::cache::PageStore;
+use super::wal::PageNumber;
+use anyhow::{bail, Result};
+
+/// SQLite's limit on the length of a string, blob or row, SQLITE_MAX_LENGTH.
+pub const MAX_LENGTH: u64 = 1_000_000_000;
+
+// the number of the next page at the start of an overflow page
+const NEXT_PAGE_SIZE: usize = 4;
+
+/// What a payload is, which decides how much of it a cell may hold.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PayloadKind {
+    Table,
+    Index,
+}
+
+/// How a payload is stored: how many of its bytes are in the cell, and how many overflow
+/// pages hold the rest.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Layout {
+    pub local: usize,
+    pub overflow_pages: usize,
+}
+
+/// The most bytes of a payload its cell holds, the rest spilling onto overflow pages.
+pub fn max_local(usable_size: usize, kind: PayloadKind) -> usize {
+    match kind {
+        PayloadKind::Table => usable_size - 35,
+        PayloadKind::Index => (usable_size - 12) * 64 / 255 - 23,
+    }
+}
+
+fn min_local(usable_size: usize) -> usize {
+    (usable_size - 12) * 32 / 255 - 23
+}
+
+/// How a payload of `size` bytes is split between its cell and overflow pages.
+pub fn layout(size: usize, usable_size: usize, kind: PayloadKind) -> Layout {
+    let max = max_local(usable_size, kind);
+    if size <= max {
+        return Layout {
+            local: size,
+            overflow_pages: 0,
+        };
+    }
+    let min = min_local(usable_size);
+    let per_page = usable_size - NEXT_PAGE_SIZE;
+    let fill = min + (size - min) % per_page;
+    let local = if fill <= max { fill } else { min };
+    Layout {
+        local,
+        overflow_pages: (size - local).div_ceil(per_page),
+    }
+}
+
+/// The biggest payload a database of at most `max_page_count` pages can hold, one page
+/// for the cell and the rest for its overflow chain.
+pub fn max_payload(usable_size: usize, max_page_count: u32, kind: PayloadKind) -> u64 {
+    let chain = max_page_count.saturating_sub(1) as u64;
+    let spilled = min_local(usable_size) as u64 + chain * (usable_size - NEXT_PAGE_SIZE) as u64;
+    spilled
+        .max(max_local(usable_size, kind) as u64)
+        .min(MAX_LENGTH)
+}
+
+/// Fail unless a row whose record takes `size` bytes is at most `max` bytes.
+pub fn check_row_size(size: u64, max: u64) -> Result<()> {
+    if size > max {
+        bail!("row too large: {size} bytes, at most {max} fit");
+    }
+    Ok(())
+}
+
+/// Split a payload into the bytes its cell holds and its overflow pages, numbered by
+/// `allocate`. A cell with a chain ends with the number of its first page.
+pub fn split(
+    payload: &[u8],
+    usable_size: usize,
+    kind: PayloadKind,
+    allocate: &mut dyn FnMut() -> PageNumber,
+) -> (Vec<u8>, Vec<(PageNumber, Vec<u8>)>) {
+    let Layout {
+        local,
+        overflow_pages,
+    } = layout(payload.len(), usable_size, kind);
+    let mut cell = payload[..local].to_vec();
+    if overflow_pages == 0 {
+        return (cell, vec![]);
+    }
+    let numbers: Vec<PageNumber> = (0..overflow_pages).map(|_| allocate()).collect();
+    cell.extend_from_slice(&numbers[0].to_be_bytes());
+    let pages = payload[local..]
+        .chunks(usable_size - NEXT_PAGE_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let next = numbers.get(i + 1).copied().unwrap_or(0);
+            let mut page = next.to_be_bytes().to_vec();
+            page.extend_from_slice(chunk);
+            page.resize(usable_size, 0);
+            (numbers[i], page)
+        })
+        .collect();
+    (cell, pages)
+}
+
+/// The payload of `size` bytes a cell holds, read back from its overflow chain if it has
+/// one, and the pages of the chain.
+pub fn join(
+    cell: &[u8],
+    size: usize,
+    usable_size: usize,
+    kind: PayloadKind,
+    store: &mut dyn PageStore,
+) -> Result<(Vec<u8>, Vec<PageNumber>)> {
+    let Layout { local, .. } = layout(size, usable_size, kind);
+    let expected = if local == size { local } else { local + 4 };
+    if cell.len() < expected {
+        bail!("a cell of {} bytes should have {expected}", cell.len());
+    }
+    let mut payload = cell[..local].to_vec();
+    let mut chain = vec![];
+    if local == size {
+        return Ok((payload, chain));
+    }
+    let mut next = PageNumber::from_be_bytes(cell[local..local + 4].try_into()?);
+    while payload.len() < size {
+        if next == 0 {
+            bail!("overflow chain ends {} bytes short", size - payload.len());
+        }
+        if chain.contains(&next) {
+            bail!("overflow chain comes back round to page {next}");
+        }
+        let page = store.read_page(next)?;
+        if page.len() < usable_size {
+            bail!("overflow page {next} is only {} bytes", page.len());
+        }
+        chain.push(next);
+        let wanted = (size - payload.len()).min(usable_size - NEXT_PAGE_SIZE);
+        payload.extend_from_slice(&page[NEXT_PAGE_SIZE..NEXT_PAGE_SIZE + wanted]);
+        next = PageNumber::from_be_bytes(page[..NEXT_PAGE_SIZE].try_into()?);
+    }
+    Ok((payload, chain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[derive(Default)]
+    struct File {
+        pages: BTreeMap<PageNumber, Vec<u8>>,
+    }
+
+    impl PageStore for File {
+        fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+            Ok(self.pages[&page].clone())
+        }
+
+        fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+            self.pages.insert(page, data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn payloads_are_split_as_in_sqlite() {
+        let table = |size| layout(size, 4096, PayloadKind::Table);
+        assert_eq!(table(4061).overflow_pages, 0);
+        // just over max_local, filling the one page would leave too much in the cell
+        assert_eq!(
+            table(4062),
+            Layout {
+                local: 489,
+                overflow_pages: 1
+            }
+        );
+        // whereas here the cell takes what would only part fill a third page
+        assert_eq!(
+            table(10_000),
+            Layout {
+                local: 489 + (10_000 - 489) % 4092,
+                overflow_pages: 2
+            }
+        );
+        assert_eq!(layout(1002, 4096, PayloadKind::Index).overflow_pages, 0);
+        assert_eq!(layout(1003, 4096, PayloadKind::Index).overflow_pages, 1);
+    }
+
+    #[test]
+    fn chains_read_back_as_written() {
+        let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let mut next = 10;
+        let mut allocate = || {
+            next += 1;
+            next
+        };
+        let (cell, pages) = split(&payload, 512, PayloadKind::Table, &mut allocate);
+        let Layout {
+            local,
+            overflow_pages,
+        } = layout(payload.len(), 512, PayloadKind::Table);
+        assert_eq!(cell.len(), local + 4);
+        assert_eq!(pages.len(), overflow_pages);
+        assert_eq!(pages[0].0, 11);
+
+        let mut file = File::default();
+        for (page, data) in &pages {
+            file.write_page(*page, data).unwrap();
+        }
+        let (read, chain) = join(&cell, payload.len(), 512, PayloadKind::Table, &mut file).unwrap();
+        assert_eq!(read, payload);
+        assert_eq!(
+            chain,
+            pages.iter().map(|(page, _)| *page).collect::<Vec<_>>()
+        );
+
+        // a chain cut short is an error rather than a panic
+        file.pages.insert(12, vec![0; 512]);
+        assert!(join(&cell, payload.len(), 512, PayloadKind::Table, &mut file).is_err());
+    }
+
+    #[test]
+    fn rows_are_bounded_by_the_page_count() {
+        assert_eq!(max_payload(512, 1, PayloadKind::Table), 477);
+        assert_eq!(max_payload(512, 3, PayloadKind::Table), 39 + 2 * 508);
+        assert_eq!(max_payload(65536, u32::MAX, PayloadKind::Table), MAX_LENGTH);
+        assert_eq!(
+            check_row_size(2000, 1055).unwrap_err().to_string(),
+            "row too large: 2000 bytes, at most 1055 fit"
+        );
+    }
+}
@@ -0,0 +1,466 @@
+/*
+    The layout of cells, the entries of a B+tree node, within a page.
+
+    A page is laid out like an SQLite B-tree page. A small header at the start is followed
+    by the cell pointer array, one two byte offset per cell in key order. The cells
+    themselves are in the cell content area at the other end of the page, which grows down
+    towards the pointers as cells are added. Whatever lies between the two is unallocated.
+
+        | header | pointers -->        unallocated          <-- cell content | reserved |
+
+    The last few bytes of a page can be set aside, as SQLite's reserved region, for an
+    extension to keep something of its own per page, a checksum or an encryption nonce.
+    Cells never go there, so the page's usable size is its size less the reserved bytes,
+    and how many there are is recorded in the database header, see header.rs, so that
+    anything reading the file knows where each page's cells stop.
+
+    Keeping the pointers separate from the cells means a cell can go anywhere in the
+    content area while inserting one in the middle of the key order only shifts pointers.
+
+    Removing a cell leaves a hole in the content area. Holes are chained together into a
+    list of freeblocks, each starting with the offset of the next and its own size, and a
+    new cell reuses the first one big enough. A freeblock needs four bytes for that, so the
+    smallest cell is four bytes, and when reusing a block would leave less than four bytes
+    over they are counted as fragmented bytes instead and lost until the page is tidied up,
+    which, as in SQLite, happens before more than 60 are lost.
+
+    An update heavy page ends up with plenty of free space in total but scattered across
+    holes too small for the next cell. Rather than split the page, which would halve how
+    full two pages are, insert_cell then defragments it: it moves every cell to the end of
+    the page one after another, which gathers all the free space into one unallocated gap.
+    Only a page without enough free space in total reports that it is full and must split.
+
+    Header, all numbers big endian as in SQLite:
+
+        0  u16  number of cells
+        2  u16  start of the cell content area, 0 meaning 65536
+        4  u16  first freeblock, 0 if there are none
+        6  u8   fragmented bytes
+        7  u8   page type, what the cells are, set by whatever stores them and 0 on a
+                page that holds none yet
+
+    A B+tree node is stored on a page this way, see btree.rs, and a page read back from the
+    file is checked to be laid out as above before its cells are trusted.
+*/
+use anyhow::{bail, Result};
+
+/// The fewest bytes of a page that must be left for cells, as in SQLite.
+pub const MIN_USABLE_SIZE: usize = 480;
+
+const HEADER_SIZE: usize = 8;
+const POINTER_SIZE: usize = 2;
+// room for a freeblock's next pointer and size when the cell is freed
+const MIN_CELL_SIZE: usize = 4;
+// the most bytes left lost in fragments before the page is defragmented, as in SQLite
+const MAX_FRAGMENTED: usize = 60;
+// each cell starts with the length of its payload
+const CELL_HEADER_SIZE: usize = 2;
+
+/// The bytes of a page a cell of `len` bytes takes up, its pointer included.
+pub fn cell_space(len: usize) -> usize {
+    (CELL_HEADER_SIZE + len).max(MIN_CELL_SIZE) + POINTER_SIZE
+}
+
+/// The bytes a page of `usable_size` usable bytes has for cells and their pointers.
+pub fn cell_room(usable_size: usize) -> usize {
+    usable_size - HEADER_SIZE
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SlottedPage {
+    data: Vec<u8>,
+    // where the reserved bytes begin and the cell content area ends
+    usable: usize,
+}
+
+impl SlottedPage {
+    /// A page whose last `reserved` bytes are kept out of the cell content area.
+    pub fn with_reserved(page_size: usize, reserved: u8) -> Self {
+        assert!(
+            (512..=65536).contains(&page_size),
+            "page size must be from 512 to 65536 bytes"
+        );
+        let usable = page_size - reserved as usize;
+        assert!(
+            usable >= MIN_USABLE_SIZE,
+            "a page must have at least {MIN_USABLE_SIZE} usable bytes"
+        );
+        let mut page = SlottedPage {
+            data: vec![0; page_size],
+            usable,
+        };
+        page.set_content_start(usable);
+        page
+    }
+
+    /// A page as read from the file, `reserved` being the bytes at its end set aside, as
+    /// the database header says.
+    pub fn from_bytes(data: Vec<u8>, reserved: u8) -> Result<Self> {
+        let page_size = data.len();
+        if !(512..=65536).contains(&page_size) || page_size - (reserved as usize) < MIN_USABLE_SIZE
+        {
+            bail!("database disk image is malformed: a page of {page_size} bytes");
+        }
+        let page = SlottedPage {
+            data,
+            usable: page_size - reserved as usize,
+        };
+        let malformed = page.pointers_end() > page.content_start()
+            || page.content_start() > page.usable
+            || (0..page.cell_count()).any(|i| {
+                let offset = page.pointer(i);
+                offset < page.content_start()
+                    || offset + CELL_HEADER_SIZE > page.usable
+                    || offset + page.cell_size(offset) > page.usable
+            })
+            || page.freeblocks_malformed();
+        if malformed {
+            bail!("database disk image is malformed: bad cell layout");
+        }
+        Ok(page)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn page_type(&self) -> u8 {
+        self.data[7]
+    }
+
+    pub fn set_page_type(&mut self, page_type: u8) {
+        self.data[7] = page_type;
+    }
+
+    fn read_u16(&self, offset: usize) -> usize {
+        u16::from_be_bytes([self.data[offset], self.data[offset + 1]]) as usize
+    }
+
+    fn write_u16(&mut self, offset: usize, value: usize) {
+        self.data[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.read_u16(0)
+    }
+
+    fn set_cell_count(&mut self, count: usize) {
+        self.write_u16(0, count);
+    }
+
+    fn content_start(&self) -> usize {
+        match self.read_u16(2) {
+            0 => 65536,
+            start => start,
+        }
+    }
+
+    fn set_content_start(&mut self, start: usize) {
+        // 65536 doesn't fit in a u16 and wraps to 0, which is what it is read back as
+        self.write_u16(2, start % 65536);
+    }
+
+    fn first_freeblock(&self) -> usize {
+        self.read_u16(4)
+    }
+
+    fn fragmented_bytes(&self) -> usize {
+        self.data[6] as usize
+    }
+
+    fn pointer(&self, index: usize) -> usize {
+        self.read_u16(HEADER_SIZE + index * POINTER_SIZE)
+    }
+
+    fn pointers_end(&self) -> usize {
+        HEADER_SIZE + self.cell_count() * POINTER_SIZE
+    }
+
+    // (offset, size) of each freeblock, in the order of the list, which is page order.
+    fn freeblocks(&self) -> Vec<(usize, usize)> {
+        let mut blocks = vec![];
+        let mut offset = self.first_freeblock();
+        while offset != 0 {
+            blocks.push((offset, self.read_u16(offset + 2)));
+            offset = self.read_u16(offset);
+        }
+        blocks
+    }
+
+    // Whether following the freeblock list would leave the content area or go round in
+    // circles, which a page read from a damaged file might.
+    fn freeblocks_malformed(&self) -> bool {
+        let mut offset = self.first_freeblock();
+        let mut previous_end = self.content_start();
+        while offset != 0 {
+            if offset < previous_end || offset + MIN_CELL_SIZE > self.usable {
+                return true;
+            }
+            let size = self.read_u16(offset + 2);
+            if size < MIN_CELL_SIZE {
+                return true;
+            }
+            previous_end = offset + size;
+            offset = self.read_u16(offset);
+        }
+        previous_end > self.usable
+    }
+
+    fn set_freeblocks(&mut self, blocks: &[(usize, usize)]) {
+        self.write_u16(4, blocks.first().map_or(0, |b| b.0));
+        for (i, (offset, size)) in blocks.iter().enumerate() {
+            let next = blocks.get(i + 1).map_or(0, |b| b.0);
+            self.write_u16(*offset, next);
+            self.write_u16(*offset + 2, *size);
+        }
+    }
+
+    fn cell_size(&self, offset: usize) -> usize {
+        (CELL_HEADER_SIZE + self.read_u16(offset)).max(MIN_CELL_SIZE)
+    }
+
+    pub fn cell(&self, index: usize) -> &[u8] {
+        let offset = self.pointer(index);
+        let len = self.read_u16(offset);
+        &self.data[offset + CELL_HEADER_SIZE..offset + CELL_HEADER_SIZE + len]
+    }
+
+    /// Every free byte on the page, whether or not it is in one piece.
+    pub fn free_space(&self) -> usize {
+        let freeblocks: usize = self.freeblocks().iter().map(|b| b.1).sum();
+        self.content_start() - self.pointers_end() + freeblocks + self.fragmented_bytes()
+    }
+
+    /// Insert a cell so it is the index'th in order, returning false if the page hasn't
+    /// room for it even once defragmented, and must be split.
+    pub fn insert_cell(&mut self, index: usize, payload: &[u8]) -> bool {
+        let size = cell_space(payload.len()) - POINTER_SIZE;
+        if size + POINTER_SIZE > self.free_space() {
+            return false;
+        }
+        let offset = match self.allocate(size) {
+            Some(offset) => offset,
+            None => {
+                self.defragment();
+                self.allocate(size)
+                    .expect("defragmented free space is in one piece")
+            }
+        };
+
+        self.write_u16(offset, payload.len());
+        self.data[offset + CELL_HEADER_SIZE..offset + CELL_HEADER_SIZE + payload.len()]
+            .copy_from_slice(payload);
+        let count = self.cell_count();
+        let at = HEADER_SIZE + index * POINTER_SIZE;
+        let end = self.pointers_end();
+        self.data.copy_within(at..end, at + POINTER_SIZE);
+        self.write_u16(at, offset);
+        self.set_cell_count(count + 1);
+        true
+    }
+
+    // Find room for a cell and the pointer to it without moving any other cell, first in
+    // the freeblocks and then in the unallocated gap.
+    fn allocate(&mut self, size: usize) -> Option<usize> {
+        let gap = self.content_start() - self.pointers_end();
+        if gap < POINTER_SIZE {
+            return None;
+        }
+        let mut blocks = self.freeblocks();
+        if let Some(i) = blocks.iter().position(|b| b.1 >= size) {
+            let (offset, block_size) = blocks[i];
+            let left_over = block_size - size;
+            if left_over < MIN_CELL_SIZE {
+                if self.fragmented_bytes() + left_over > MAX_FRAGMENTED {
+                    // too many bytes lost already, as SQLite has it: tidy the page up first
+                    return None;
+                }
+                blocks.remove(i);
+                self.data[6] += left_over as u8;
+                self.set_freeblocks(&blocks);
+                return Some(offset);
+            }
+            // take the end of the block so the rest of it stays where the list says
+            blocks[i].1 = left_over;
+            self.set_freeblocks(&blocks);
+            return Some(offset + left_over);
+        }
+        if gap >= size + POINTER_SIZE {
+            let start = self.content_start() - size;
+            self.set_content_start(start);
+            return Some(start);
+        }
+        None
+    }
+
+    /// Take the index'th cell off the page, its bytes becoming a freeblock for the next
+    /// cell that fits.
+    pub fn remove_cell(&mut self, index: usize) {
+        let offset = self.pointer(index);
+        let size = self.cell_size(offset);
+        let at = HEADER_SIZE + index * POINTER_SIZE;
+        let end = self.pointers_end();
+        self.data.copy_within(at + POINTER_SIZE..end, at);
+        self.set_cell_count(self.cell_count() - 1);
+
+        let mut blocks = self.freeblocks();
+        let pos = blocks.partition_point(|b| b.0 < offset);
+        blocks.insert(pos, (offset, size));
+        // merge neighbouring blocks so the holes don't get ever smaller
+        let mut merged: Vec<(usize, usize)> = vec![];
+        for (offset, size) in blocks {
+            match merged.last_mut() {
+                Some(last) if last.0 + last.1 == offset => last.1 += size,
+                _ => merged.push((offset, size)),
+            }
+        }
+        // a hole at the start of the content area goes back to the unallocated gap
+        if let Some(&(offset, size)) = merged.first() {
+            if offset == self.content_start() {
+                merged.remove(0);
+                self.set_content_start(offset + size);
+            }
+        }
+        self.set_freeblocks(&merged);
+    }
+
+    /// Move every cell to the end of the page, in pointer order, so that all the free space
+    /// is one unallocated gap with no freeblocks or fragments left.
+    pub fn defragment(&mut self) {
+        let cells: Vec<Vec<u8>> = (0..self.cell_count())
+            .map(|i| {
+                let offset = self.pointer(i);
+                self.data[offset..offset + self.cell_size(offset)].to_vec()
+            })
+            .collect();
+        let mut start = self.usable;
+        for (i, cell) in cells.iter().enumerate() {
+            start -= cell.len();
+            self.data[start..start + cell.len()].copy_from_slice(cell);
+            self.write_u16(HEADER_SIZE + i * POINTER_SIZE, start);
+        }
+        self.set_content_start(start);
+        self.set_freeblocks(&[]);
+        self.data[6] = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(page: &SlottedPage) -> Vec<Vec<u8>> {
+        (0..page.cell_count())
+            .map(|i| page.cell(i).to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn cells_are_kept_in_pointer_order() {
+        let mut page = SlottedPage::with_reserved(512, 0);
+        assert!(page.insert_cell(0, b"bbb"));
+        assert!(page.insert_cell(0, b"a"));
+        assert!(page.insert_cell(2, b"cccc"));
+        assert_eq!(
+            cells(&page),
+            [b"a".to_vec(), b"bbb".to_vec(), b"cccc".to_vec()]
+        );
+
+        page.remove_cell(1);
+        assert_eq!(cells(&page), [b"a".to_vec(), b"cccc".to_vec()]);
+        // the freed cell is reused rather than taking from the gap, each cell taking its
+        // bytes, their length and a pointer
+        let free = page.free_space();
+        assert!(page.insert_cell(1, b"xyz"));
+        assert_eq!(page.free_space(), free - cell_space(3));
+        assert_eq!(cells(&page)[1], b"xyz");
+
+        // a cell bigger than all the free space needs a split
+        let huge = vec![0u8; page.free_space()];
+        assert!(!page.insert_cell(0, &huge));
+    }
+
+    #[test]
+    fn fragmented_free_space_is_compacted_before_giving_up() {
+        let mut page = SlottedPage::with_reserved(512, 0);
+        let payload = [7u8; 38];
+        let mut count = 0;
+        while page.insert_cell(count, &payload) {
+            count += 1;
+        }
+        // every other cell freed leaves many 40 byte holes and no gap to speak of
+        for i in (0..count).rev().step_by(2) {
+            page.remove_cell(i);
+        }
+        let remaining = cells(&page);
+        let big = [9u8; 100];
+        assert!(page.free_space() >= big.len() + CELL_HEADER_SIZE + POINTER_SIZE);
+        assert!(page.freeblocks().iter().all(|b| b.1 < big.len()));
+
+        assert!(page.insert_cell(0, &big));
+        assert_eq!(page.cell(0), big);
+        assert_eq!(cells(&page)[1..], remaining[..]);
+        assert!(page.freeblocks().is_empty());
+
+        // but a cell bigger than all the free space still needs a split
+        let huge = vec![0u8; page.free_space()];
+        assert!(!page.insert_cell(0, &huge));
+    }
+
+    #[test]
+    fn lost_fragments_are_gathered_up_before_they_pile_up() {
+        let mut page = SlottedPage::with_reserved(512, 0);
+        for i in 0..40 {
+            assert!(page.insert_cell(i, &[1; 6]));
+        }
+        // each smaller cell put in a freed one's place loses the two bytes left over
+        let mut most = 0;
+        for i in 0..40 {
+            page.remove_cell(i);
+            assert!(page.insert_cell(i, &[2; 4]));
+            most = most.max(page.fragmented_bytes());
+            assert!(page.fragmented_bytes() <= MAX_FRAGMENTED);
+        }
+        assert_eq!(most, MAX_FRAGMENTED);
+        assert!(cells(&page).iter().all(|cell| cell == &[2; 4]));
+    }
+
+    #[test]
+    fn pages_read_back_as_written() {
+        let mut page = SlottedPage::with_reserved(1024, 8);
+        page.set_page_type(2);
+        for (i, cell) in [&b"one"[..], b"two", b"three"].iter().enumerate() {
+            assert!(page.insert_cell(i, cell));
+        }
+        page.remove_cell(1);
+        let read = SlottedPage::from_bytes(page.as_bytes().to_vec(), 8).unwrap();
+        assert_eq!(read, page);
+        assert_eq!(read.page_type(), 2);
+        assert_eq!(cells(&read), [b"one".to_vec(), b"three".to_vec()]);
+
+        // a pointer past the end of the page
+        let mut bytes = page.as_bytes().to_vec();
+        bytes[HEADER_SIZE..HEADER_SIZE + 2].copy_from_slice(&1020u16.to_be_bytes());
+        assert!(SlottedPage::from_bytes(bytes, 8).is_err());
+        assert!(SlottedPage::from_bytes(vec![0; 100], 0).is_err());
+    }
+
+    #[test]
+    fn cells_stay_clear_of_the_reserved_bytes() {
+        let mut bytes = SlottedPage::with_reserved(512, 32).as_bytes().to_vec();
+        bytes[480..].fill(0xab);
+        let mut page = SlottedPage::from_bytes(bytes, 32).unwrap();
+        assert_eq!(page.free_space(), 512 - 32 - HEADER_SIZE);
+
+        let mut count = 0;
+        while page.insert_cell(count, &[count as u8; 30]) {
+            count += 1;
+        }
+        for i in (0..count).step_by(2) {
+            page.remove_cell(i / 2);
+        }
+        page.defragment();
+        assert!(page.insert_cell(0, &[1; 60]));
+        assert_eq!(page.as_bytes()[480..], [0xab; 32]);
+    }
+}
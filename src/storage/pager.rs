@@ -18,4 +18,2233 @@
     The OS is not your friend when it comes to databases. We want to use both the OS's page cache as well
     as our own sqlite page cache together as this boosts performance by removing unneeded system calls for disk I/O.
 
+    The Pager is the one way between the B+tree of a database file (see image.rs) and the
+    file, while a file of SQLite's format is read and written whole, see sqlite_file.rs.
+    A page is asked for by number and comes from the cache, read from the file on a
+    fault, as a guard that keeps it pinned in the cache until the guard is dropped. A new page comes off
+    the freelist when one is free, lowest first, and from past the end of the file
+    otherwise, and a page given back goes onto the freelist.
+
+    With PRAGMA mmap_size set, and a store that can map the file into memory, as a file
+    on disk can on Unix, see os_unix.rs, pages in the first mmap_size bytes of the file
+    are read straight from the map rather than copied into the cache. The map is made
+    again whenever a read takes the SHARED lock, to cover what the file holds then. Pages are still changed in the cache and written back to the
+    file, and a page that is cached, perhaps changed since it was written, is read from
+    the cache rather than the map until it is evicted.
+
+    Opening a B+tree reads it a level at a time, each level left to right, and its pages
+    were given out one after another as it grew, so pages are often read in order, which
+    the pager notices: once a few pages in a row have each been read from the file right
+    after the one before, the next read brings the 16 pages after it along in one go, or as many as half the cache holds. A flush writes back every
+    dirty page and then the freelist if it changed, and records in the header how big the
+    file is and where its freelist starts. The header is kept in the first 100 bytes of
+    page 1, as in SQLite, and a B+tree with its root on page 1 starts after them.
+    open_file reads it back and checks it, see header.rs.
+
+    A pager opened with a rollback journal commits with a flush. Before a page that was in
+    the file when the transaction began is first overwritten, whether by the flush or by
+    the cache evicting it, its original goes into the journal, and emptying the journal
+    once the new pages are all written is the moment the transaction commits. A journal
+    found with pages in it on opening is from a commit that never finished, and copying
+    its pages back undoes it, unless another connection's lock says it is committing
+    through the journal that moment. PRAGMA synchronous decides what gets synced on the way:
+
+        OFF     nothing. A power cut mid-commit can leave the file corrupt, but a bulk
+                load runs as fast as the OS lets it.
+        NORMAL  the journal before the file is overwritten and the file before the
+                journal is emptied, so a power cut never corrupts the file. A transaction
+                committed just before one may still be rolled back, as emptying the
+                journal isn't synced.
+        FULL    that too, so a transaction is durable once its flush returns.
+
+    EXTRA syncs what FULL does. SQLite's EXTRA also syncs the directory once the journal
+    is deleted, and a journal here is only ever truncated.
+
+    Outside WAL mode the pager locks the file as it goes, see lock.rs: SHARED at its first
+    read, RESERVED at its first change and EXCLUSIVE before it first writes to the file,
+    letting go of them all once a flush commits, so every flush ends a transaction. A
+    commit bumps the change counter in the header, and a pager taking SHARED again reads
+    it back to learn whether another connection has committed meanwhile, forgetting its
+    cache if one has. BEGIN can take the locks up front instead, and only a transaction
+    it began can be rolled back, undoing what was written through the journal.
+
+    A pager moved over with use_wal writes to a write-ahead log
+    instead, see wal.rs, which every pager of the database shares. Its first page read
+    starts a read of the latest commit, and it goes on reading the database as it was
+    then, its cache included, while other pagers commit, until a flush ends the read. Its
+    first change makes it the log's writer, which it can't be while another pager is or
+    once a commit has been made since its read began, and a flush commits what it wrote,
+    bumping the change counter as a commit through the journal does. The file only
+    changes when a checkpoint copies commits into it that no open read is older than.
+    A log with a file writes each commit to it, which is synced at FULL and EXTRA as the
+    commit is made, so that it is durable once the flush returns, and at NORMAL only
+    before a checkpoint copies it into the database file. OFF syncs neither.
+
+    Within a transaction, savepoints nest. Opening one marks where the changes made after
+    it begin: the cache copies each page the first time it changes, and the pager keeps
+    the header and free pages as they were. Rolling back to a savepoint puts them all
+    back, forgetting any page allocated since, while releasing one hands its copies to
+    the savepoint it was opened in, so that one can still undo them. The rollback journal
+    is not involved, as it only ever holds pages as they were when the transaction began.
+
+    The page cache itself is in cache.rs and the freelist's layout in freelist.rs, this
+    module holds the pager and its settings. How a row too big for a page spills onto
+    overflow pages is in overflow.rs.
+
 */
+use super::busy::BusyHandler;
+use super::cache::{CacheReport, PageCache, PageStore};
+use super::freelist;
+use super::header::{DatabaseHeader, HEADER_SIZE};
+use super::lock::{LockLevel, LockStatus};
+use super::os_interface::{PageFile, VfsFile};
+use super::overflow::{self, PayloadKind};
+use super::page::MIN_USABLE_SIZE;
+use super::replacement::Replacement;
+use super::wal::{FrameNumber, PageNumber, Snapshot, Wal};
+use crate::error::SqlError;
+use crate::sql_parser::ast::TransactionMode;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_PAGE_SIZE: u32 = 4096;
+// A negative cache size is in KiB rather than pages, SQLite's default is about 2MB.
+pub const DEFAULT_CACHE_SIZE: i64 = -2000;
+pub const DEFAULT_MAX_PAGE_COUNT: u32 = 0xffff_fffe;
+// How many reads in a row of the page after the last make a scan worth reading ahead
+// of, and how many pages it reads ahead each time.
+const SEQUENTIAL_FAULTS: u32 = 2;
+const READ_AHEAD_PAGES: u32 = 16;
+// The most of the file PRAGMA mmap_size may map, as in SQLite.
+pub const MAX_MMAP_SIZE: u64 = 0x7fff_0000;
+// A rollback journal starts with the page count the file had before the transaction and
+// its page size, followed by each page saved, its number and then its original contents.
+// A commit across several files ends it with a record for page 0, the length of the
+// super-journal's name and the name, see journal.rs.
+const JOURNAL_HEADER_SIZE: u64 = 8;
+// The owner page 1 is counted against when the pager writes the header onto it.
+const HEADER_OWNER: &str = "header";
+
+/// How a transaction's original pages are kept so it can be rolled back.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum JournalMode {
+    // a rollback journal, deleted at commit
+    #[default]
+    Delete,
+    // truncated to nothing at commit rather than deleted
+    Truncate,
+    // left in place at commit with its header zeroed
+    Persist,
+    // held in memory, so a crash can corrupt the database
+    Memory,
+    // a write-ahead log instead of a rollback journal
+    Wal,
+    // no journal at all and no ROLLBACK
+    Off,
+}
+
+impl JournalMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name.to_lowercase().as_str() {
+            "delete" => JournalMode::Delete,
+            "truncate" => JournalMode::Truncate,
+            "persist" => JournalMode::Persist,
+            "memory" => JournalMode::Memory,
+            "wal" => JournalMode::Wal,
+            "off" => JournalMode::Off,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for JournalMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self {
+            JournalMode::Delete => "delete",
+            JournalMode::Truncate => "truncate",
+            JournalMode::Persist => "persist",
+            JournalMode::Memory => "memory",
+            JournalMode::Wal => "wal",
+            JournalMode::Off => "off",
+        };
+        write!(f, "{mode}")
+    }
+}
+
+/// How hard the pager works to make sure a commit has reached the disk before going on,
+/// trading speed against surviving a power cut, see the top of this file.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default)]
+pub enum Synchronous {
+    Off,
+    Normal,
+    #[default]
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    /// By name or by number, as PRAGMA synchronous accepts either.
+    pub fn parse(level: &str) -> Option<Self> {
+        Some(match level.to_lowercase().as_str() {
+            "off" | "0" => Synchronous::Off,
+            "normal" | "1" => Synchronous::Normal,
+            "full" | "2" => Synchronous::Full,
+            "extra" | "3" => Synchronous::Extra,
+            _ => return None,
+        })
+    }
+
+    pub fn level(&self) -> i64 {
+        *self as i64
+    }
+}
+
+/// Where the temp database keeps its tables. The default here is memory.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum TempStore {
+    #[default]
+    Default,
+    File,
+    Memory,
+}
+
+impl TempStore {
+    /// By name or by number, as PRAGMA temp_store accepts either.
+    pub fn parse(store: &str) -> Option<Self> {
+        Some(match store.to_lowercase().as_str() {
+            "default" | "0" => TempStore::Default,
+            "file" | "1" => TempStore::File,
+            "memory" | "2" => TempStore::Memory,
+            _ => return None,
+        })
+    }
+
+    pub fn level(&self) -> i64 {
+        *self as i64
+    }
+}
+
+/// The pager's settings, most of which PRAGMAs can change.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PagerConfig {
+    pub page_size: u32,
+    // bytes at the end of each page kept for extensions, see page.rs
+    pub reserved_bytes: u8,
+    // in pages, or in KiB when negative
+    pub cache_size: i64,
+    pub journal_mode: JournalMode,
+    pub synchronous: Synchronous,
+    // the most pages the database file may grow to
+    pub max_page_count: u32,
+    // how many bytes at the start of the file are read through a memory map, 0 for none
+    pub mmap_size: u64,
+    // how the cache picks a page to evict, see replacement.rs
+    pub replacement: Replacement,
+    // what to do when a lock is held by another connection, see busy.rs
+    pub busy: BusyHandler,
+    // the most bytes the cache, a sort or a hash join's table may hold, 0 for no limit
+    pub heap_limit: u64,
+    // where temporary tables are kept, see storage::attach
+    pub temp_store: TempStore,
+}
+
+impl Default for PagerConfig {
+    fn default() -> Self {
+        PagerConfig {
+            page_size: DEFAULT_PAGE_SIZE,
+            reserved_bytes: 0,
+            cache_size: DEFAULT_CACHE_SIZE,
+            journal_mode: JournalMode::default(),
+            synchronous: Synchronous::default(),
+            max_page_count: DEFAULT_MAX_PAGE_COUNT,
+            mmap_size: 0,
+            replacement: Replacement::default(),
+            busy: BusyHandler::default(),
+            heap_limit: 0,
+            temp_store: TempStore::default(),
+        }
+    }
+}
+
+impl PagerConfig {
+    /// Change the page size, which like SQLite silently keeps the old one unless the new
+    /// one is a power of two from 512 to 65536, and leaves enough of each page usable.
+    /// A database takes the page size it has when it is created, see pragma.rs.
+    pub fn set_page_size(&mut self, size: i64) {
+        if (512..=65536).contains(&size)
+            && size.count_ones() == 1
+            && size - self.reserved_bytes as i64 >= MIN_USABLE_SIZE as i64
+        {
+            self.page_size = size as u32;
+        }
+    }
+
+    /// Change how many bytes are reserved at the end of each page, keeping the old count
+    /// unless the new one is from 0 to 255 and leaves enough of a page usable.
+    pub fn set_reserved_bytes(&mut self, reserved: i64) {
+        if (0..=255).contains(&reserved)
+            && self.page_size as i64 - reserved >= MIN_USABLE_SIZE as i64
+        {
+            self.reserved_bytes = reserved as u8;
+        }
+    }
+
+    /// The bytes of each page that cells may use.
+    pub fn usable_size(&self) -> usize {
+        self.page_size as usize - self.reserved_bytes as usize
+    }
+
+    /// The most keys a B+tree node holds before it splits, one for every 64 bytes of a
+    /// page, so 64 on a page of the default size.
+    pub fn node_keys(&self) -> u64 {
+        self.page_size as u64 / 64
+    }
+
+    /// Change the most pages the database may have. Like SQLite a count of 0 or less
+    /// leaves it as it was.
+    pub fn set_max_page_count(&mut self, count: i64) {
+        if count > 0 {
+            self.max_page_count = count.min(DEFAULT_MAX_PAGE_COUNT.into()) as u32;
+        }
+    }
+
+    /// Change how much of the file is read through a memory map, no more than
+    /// MAX_MMAP_SIZE. Like SQLite a negative size leaves it as it was.
+    pub fn set_mmap_size(&mut self, size: i64) {
+        if size >= 0 {
+            self.mmap_size = (size as u64).min(MAX_MMAP_SIZE);
+        }
+    }
+
+    /// The most bytes a row's record may take, see overflow.rs.
+    pub fn max_row_size(&self) -> u64 {
+        overflow::max_payload(self.usable_size(), self.max_page_count, PayloadKind::Table)
+    }
+
+    /// Change the most memory the cache, a sort or a hash join may hold. Like SQLite's
+    /// hard_heap_limit 0 is no limit and a negative limit leaves it as it was.
+    pub fn set_heap_limit(&mut self, limit: i64) {
+        if limit >= 0 {
+            self.heap_limit = limit as u64;
+        }
+    }
+
+    /// How many pages the cache may hold, as many as cache_size says but no more than fit
+    /// in the heap limit, and always one.
+    pub fn cache_pages(&self) -> u64 {
+        let pages = if self.cache_size >= 0 {
+            self.cache_size as u64
+        } else {
+            self.cache_size.unsigned_abs() * 1024 / self.page_size as u64
+        };
+        match self.heap_limit {
+            0 => pages,
+            limit => pages.min(limit / self.page_size as u64).max(1),
+        }
+    }
+
+    /// The memory budget: how many bytes the cache may hold, which is also how much a
+    /// sort or a hash join's table may hold before it spills to temporary files or is
+    /// split up.
+    pub fn memory_budget(&self) -> usize {
+        let bytes = self.cache_pages() * self.page_size as u64;
+        match self.heap_limit {
+            0 => bytes as usize,
+            limit => bytes.min(limit) as usize,
+        }
+    }
+}
+
+/// A page borrowed from the pager to read, pinned in the cache until the guard is dropped.
+/// The pager stays reachable through the guard, so that a B+tree can read a child page
+/// while the parent it will come back to stays cached.
+pub struct PageGuard<'p, S: PageStore> {
+    pager: &'p mut Pager<S>,
+    number: PageNumber,
+    // read from the store's memory map rather than the cache, and so not pinned
+    mapped: bool,
+}
+
+impl<S: PageStore> Deref for PageGuard<'_, S> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        if self.mapped {
+            let size = self.pager.header.page_size as usize;
+            let start = (self.number as usize - 1) * size;
+            let mapping = self.pager.store.mapping().expect("a mapped page");
+            return &mapping[start..start + size];
+        }
+        self.pager.cache.peek(self.number).expect("a pinned page")
+    }
+}
+
+impl<S: PageStore> Drop for PageGuard<'_, S> {
+    fn drop(&mut self) {
+        if !self.mapped {
+            self.pager.cache.unpin(self.number);
+        }
+    }
+}
+
+/// A page borrowed from the pager to change, pinned as a PageGuard is. The page is dirty
+/// from the moment it is borrowed, to be written by the next flush.
+pub struct PageGuardMut<'p, S: PageStore> {
+    pager: &'p mut Pager<S>,
+    number: PageNumber,
+}
+
+impl<S: PageStore> Deref for PageGuardMut<'_, S> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.pager.cache.peek(self.number).expect("a pinned page")
+    }
+}
+
+impl<S: PageStore> DerefMut for PageGuardMut<'_, S> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.pager
+            .cache
+            .peek_mut(self.number)
+            .expect("a pinned page")
+    }
+}
+
+impl<S: PageStore> Drop for PageGuardMut<'_, S> {
+    fn drop(&mut self) {
+        self.pager.cache.unpin(self.number);
+    }
+}
+
+pub struct Pager<S: PageStore> {
+    store: S,
+    cache: PageCache,
+    header: DatabaseHeader,
+    max_page_count: u32,
+    synchronous: Synchronous,
+    mmap_size: u64,
+    free: BTreeSet<PageNumber>,
+    // whether the free pages differ from the freelist last written
+    freelist_changed: bool,
+    // the page last read from the file, and how many reads in a row have each been of
+    // the page after the one before
+    last_fault: Option<PageNumber>,
+    sequential_faults: u32,
+    journal: Option<RollbackJournal>,
+    wal: Option<WalReader>,
+    // the lock held on the file, outside WAL mode
+    lock: LockLevel,
+    busy: BusyHandler,
+    // the transaction BEGIN began, if one is open
+    transaction: Option<TransactionMode>,
+    // the header as of the last commit the pager made or read
+    committed: DatabaseHeader,
+    // what was outside the cache as each open savepoint began, oldest first
+    savepoints: Vec<Savepoint>,
+}
+
+impl<S: PageStore> Pager<S> {
+    /// The pager of the database in `store` described by `header`, which for a new
+    /// database is `DatabaseHeader::new(config)`. The freelist is read up front.
+    pub fn open(mut store: S, header: DatabaseHeader, config: &PagerConfig) -> Result<Self> {
+        let free = freelist::read(&mut store, &header).context("reading the freelist")?;
+        Ok(Pager {
+            store,
+            cache: PageCache::with_policy(
+                config.cache_pages() as usize,
+                config.replacement.policy(),
+            ),
+            header,
+            max_page_count: config.max_page_count,
+            synchronous: config.synchronous,
+            mmap_size: config.mmap_size,
+            free: free.into_iter().collect(),
+            freelist_changed: false,
+            last_fault: None,
+            sequential_faults: 0,
+            journal: None,
+            wal: None,
+            lock: LockLevel::Unlocked,
+            busy: config.busy.clone(),
+            transaction: None,
+            committed: header,
+            savepoints: vec![],
+        })
+    }
+
+    // The pager of the database in `store`, as `open` gives it, committing through the
+    // rollback journal `journal`, already rolled back if it was hot, see open_file.
+    fn with_journal(
+        store: S,
+        header: DatabaseHeader,
+        config: &PagerConfig,
+        journal: Box<dyn VfsFile + Send>,
+    ) -> Result<Self> {
+        let mut pager = Pager::open(store, header, config)?;
+        pager.journal = Some(RollbackJournal {
+            file: journal,
+            page_size: header.page_size,
+            page_count: header.page_count,
+            saved: BTreeSet::new(),
+            synchronous: config.synchronous,
+        });
+        Ok(pager)
+    }
+
+    /// In WAL mode, start a read of the latest commit, which this pager goes on seeing
+    /// whatever other pagers commit until the read ends with a flush. The first page asked
+    /// for starts one if there isn't one, and it is an error to start one while another
+    /// is open.
+    pub fn begin_read(&mut self) -> Result<()> {
+        let Some(reader) = &mut self.wal else {
+            bail!("only a pager in WAL mode reads a snapshot");
+        };
+        if reader.snapshot.is_some() {
+            bail!("a read is already open");
+        }
+        let snapshot = reader.wal.lock().unwrap().begin_read();
+        reader.snapshot = Some(snapshot);
+        if snapshot.read_mark == reader.cached_at {
+            return Ok(());
+        }
+        // other pagers have committed since the cache was filled
+        reader.cached_at = snapshot.read_mark;
+        self.reload()
+    }
+
+    /// Commit to the write-ahead log `wal` from now on, rather than through the rollback
+    /// journal. Not in a transaction.
+    pub fn use_wal(&mut self, wal: Arc<Mutex<Wal>>) -> Result<()> {
+        if self.transaction.is_some() {
+            bail!("cannot change into wal mode from within a transaction");
+        }
+        self.let_go()?;
+        self.wal = Some(WalReader {
+            wal,
+            snapshot: None,
+            writing: false,
+            // another pager may have committed to the log, so the first read reloads
+            cached_at: FrameNumber::MAX,
+        });
+        Ok(())
+    }
+
+    /// Go back to committing through the rollback journal, once every commit in the log
+    /// has been checkpointed into the file. Not in a transaction.
+    pub fn leave_wal(&mut self) -> Result<()> {
+        if self.transaction.is_some() {
+            bail!("cannot change out of wal mode from within a transaction");
+        }
+        self.let_go()?;
+        self.wal = None;
+        // the pages are all in the file now, which is read afresh
+        self.cache.forget_past(0);
+        Ok(())
+    }
+
+    /// Whether the pager commits to a write-ahead log.
+    pub fn in_wal_mode(&self) -> bool {
+        self.wal.is_some()
+    }
+
+    /// Mark the header as that of a database in WAL mode, or not, to be written by the
+    /// next flush.
+    pub fn set_wal_format(&mut self, wal: bool) {
+        self.header.wal = wal;
+    }
+
+    /// In WAL mode, start a read of the database as it was at the earlier commit
+    /// `commit`, which must still be in the log, see Wal::begin_read_as_of. It ends as
+    /// any other read does, and the next read is of the latest commit again. Not in a
+    /// transaction.
+    pub fn begin_read_as_of(&mut self, commit: FrameNumber) -> Result<()> {
+        if self.transaction.is_some() {
+            bail!("cannot read as of an earlier commit within a transaction");
+        }
+        self.let_go()?;
+        let Some(reader) = &mut self.wal else {
+            bail!("only a pager in WAL mode reads a snapshot");
+        };
+        let snapshot = reader.wal.lock().unwrap().begin_read_as_of(commit)?;
+        reader.snapshot = Some(snapshot);
+        if snapshot.read_mark == reader.cached_at {
+            return Ok(());
+        }
+        reader.cached_at = snapshot.read_mark;
+        self.reload()
+    }
+
+    /// The snapshot the pager is reading in WAL mode, if a read is open.
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.wal.as_ref().and_then(|reader| reader.snapshot)
+    }
+
+    /// Page `page` of the B+tree `owner`, straight from the file's memory map if it lies
+    /// within mmap_size and isn't cached, as a page that has been changed would be. The
+    /// next few pages are read along with it when the pages read before it were each the
+    /// one after the last.
+    pub fn get_page(&mut self, page: PageNumber, owner: &str) -> Result<PageGuard<'_, S>> {
+        self.reading()?;
+        self.check(page)?;
+        if self.is_mapped(page) {
+            return Ok(PageGuard {
+                pager: self,
+                number: page,
+                mapped: true,
+            });
+        }
+        let fault = !self.cache.contains(page);
+        let mut store = Journaled::new(
+            &mut self.store,
+            &mut self.journal,
+            &self.wal,
+            &mut self.lock,
+            &self.busy,
+        );
+        self.cache.get(page, owner, &mut store)?;
+        self.cache.pin(page);
+        if fault {
+            self.read_ahead(page, owner)?;
+        }
+        Ok(PageGuard {
+            pager: self,
+            number: page,
+            mapped: false,
+        })
+    }
+
+    /// Page `page` of the B+tree `owner` to change.
+    pub fn get_page_mut(&mut self, page: PageNumber, owner: &str) -> Result<PageGuardMut<'_, S>> {
+        self.writing()?;
+        self.check(page)?;
+        let mut store = Journaled::new(
+            &mut self.store,
+            &mut self.journal,
+            &self.wal,
+            &mut self.lock,
+            &self.busy,
+        );
+        self.cache.get_mut(page, owner, &mut store)?;
+        self.cache.pin(page);
+        Ok(PageGuardMut {
+            pager: self,
+            number: page,
+        })
+    }
+
+    /// A page of zeroes for the B+tree `owner`, the lowest free page if there is one and
+    /// a new one at the end of the file otherwise.
+    pub fn allocate_page(&mut self, owner: &str) -> Result<PageNumber> {
+        self.writing()?;
+        let (page, reused) = match self.free.pop_first() {
+            Some(page) => {
+                self.freelist_changed = true;
+                (page, true)
+            }
+            None if self.header.page_count >= self.max_page_count => {
+                bail!(SqlError::DatabaseFull)
+            }
+            None => {
+                self.header.page_count += 1;
+                (self.header.page_count, false)
+            }
+        };
+        let zeroes = vec![0; self.header.page_size as usize];
+        let mut store = Journaled::new(
+            &mut self.store,
+            &mut self.journal,
+            &self.wal,
+            &mut self.lock,
+            &self.busy,
+        );
+        if reused && !self.savepoints.is_empty() {
+            // the cache only copies a page it holds, and one freed since a savepoint
+            // opened has contents to put back if it is rolled back to
+            self.cache.get_mut(page, owner, &mut store)?;
+        }
+        self.cache.put(page, owner, zeroes, &mut store)?;
+        Ok(page)
+    }
+
+    /// Give back a page nothing uses any more, to be allocated again.
+    pub fn free_page(&mut self, page: PageNumber) -> Result<()> {
+        self.writing()?;
+        self.check(page)?;
+        if !self.free.insert(page) {
+            bail!("page {page} is already free");
+        }
+        self.freelist_changed = true;
+        Ok(())
+    }
+
+    /// Write every dirty page back and then the freelist if it has changed, recording its
+    /// start and the size of the file in the header, then sync the file unless PRAGMA
+    /// synchronous is off. With a journal this commits the transaction, the originals of
+    /// the pages written going into the journal first. Holding a guard across a flush, by
+    /// way of its pager, is a bug that debug builds panic on, as a change made through
+    /// the guard afterwards would never be written. A flush closes every savepoint.
+    ///
+    /// In WAL mode the pages go to the log instead, the last of them marked as a commit,
+    /// and the flush ends the read. The log's file is synced at FULL and EXTRA, and
+    /// otherwise only before a checkpoint, see wal.rs.
+    pub fn flush(&mut self) -> Result<()> {
+        self.commit_phase_one(None)?;
+        self.commit_phase_two()
+    }
+
+    /// The first half of a flush, as SQLite splits its commit for a transaction across
+    /// several files, see journal.rs: the journal is written, naming `super_journal` at
+    /// its end, and then the pages, but the transaction stays open, to be committed by
+    /// commit_phase_two or undone by rollback.
+    pub fn commit_phase_one(&mut self, super_journal: Option<&str>) -> Result<()> {
+        debug_assert_eq!(self.cache.pinned(), 0, "a page is pinned across a commit");
+        let changed = self.freelist_changed
+            || self.header != self.committed
+            || !self.cache.dirty_pages().is_empty();
+        if changed {
+            self.header.change_counter = self.header.change_counter.wrapping_add(1);
+        }
+        if self.freelist_changed {
+            let free: Vec<PageNumber> = self.free.iter().copied().collect();
+            let mut store = IntoCache {
+                cache: &mut self.cache,
+                store: Journaled::new(
+                    &mut self.store,
+                    &mut self.journal,
+                    &self.wal,
+                    &mut self.lock,
+                    &self.busy,
+                ),
+            };
+            freelist::write(&free, &mut store, &mut self.header).context("writing the freelist")?;
+            self.freelist_changed = false;
+        }
+        self.write_header()?;
+        // the dirty pages' originals all at once, and so with one sync
+        if let Some(journal) = &mut self.journal {
+            journal.save(&mut self.store, self.cache.dirty_pages())?;
+            if let Some(name) = super_journal {
+                journal.name_super_journal(name)?;
+            }
+        }
+        let mut store = Journaled::new(
+            &mut self.store,
+            &mut self.journal,
+            &self.wal,
+            &mut self.lock,
+            &self.busy,
+        );
+        self.cache.flush(&mut store)?;
+        if self.synchronous != Synchronous::Off && self.wal.is_none() {
+            self.cache.sync(&mut store)?;
+        }
+        Ok(())
+    }
+
+    /// The second half of a flush, once commit_phase_one has written every page: empty
+    /// the journal, which commits the transaction, or mark the last frame logged as a
+    /// commit.
+    pub fn commit_phase_two(&mut self) -> Result<()> {
+        if let Some(journal) = &mut self.journal {
+            journal.commit(self.header.page_count)?;
+        }
+        if let Some(reader) = &mut self.wal {
+            if let Err(err) = reader.commit(self.synchronous >= Synchronous::Full) {
+                // what was appended is dropped from the log, and what was cached of it
+                // is forgotten
+                reader.cached_at = FrameNumber::MAX;
+                self.cache.forget_past(0);
+                self.header = self.committed;
+                self.freelist_changed = false;
+                self.end_transaction()?;
+                return Err(err);
+            }
+        }
+        tracing::debug!(pages = self.header.page_count, "pager committed");
+        self.committed = self.header;
+        self.end_transaction()
+    }
+
+    /// Begin a transaction as BEGIN does, to end with `commit`, which is a flush, or with
+    /// `rollback`. DEFERRED takes no lock until the transaction first reads or writes.
+    /// IMMEDIATE takes the RESERVED lock, or in WAL mode becomes the log's writer,
+    /// straight away, and EXCLUSIVE goes on to the EXCLUSIVE lock, so nobody else even
+    /// reads. Readers don't block a writer in WAL mode, so there EXCLUSIVE is IMMEDIATE.
+    pub fn begin(&mut self, mode: TransactionMode) -> Result<()> {
+        if self.transaction.is_some() {
+            bail!("cannot start a transaction within a transaction");
+        }
+        let idle = self.lock == LockLevel::Unlocked && self.snapshot().is_none();
+        let locked = match mode {
+            TransactionMode::Deferred => Ok(()),
+            TransactionMode::Immediate => self.writing(),
+            TransactionMode::Exclusive => self.writing().and_then(|()| match self.wal {
+                Some(_) => Ok(()),
+                None => lock_to(
+                    &mut self.store,
+                    &mut self.lock,
+                    &self.busy,
+                    LockLevel::Exclusive,
+                ),
+            }),
+        };
+        if let Err(err) = locked {
+            // let go of what was taken on the way, but not of changes already made
+            if idle {
+                self.let_go()?;
+            }
+            return Err(err);
+        }
+        self.transaction = Some(mode);
+        Ok(())
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Change what is done when a lock is busy, see busy.rs.
+    pub fn set_busy_handler(&mut self, busy: BusyHandler) {
+        self.busy = busy;
+    }
+
+    /// Change how many pages the cache may hold, see PagerConfig::cache_pages.
+    pub fn set_cache_pages(&mut self, pages: u64) {
+        self.cache.set_capacity(pages as usize);
+    }
+
+    /// Have the cache evict as `replacement` says, see replacement.rs.
+    pub fn set_replacement(&mut self, replacement: Replacement) {
+        self.cache.set_policy(replacement.policy());
+    }
+
+    /// Change how much of the file is read from its memory map, from the next read on.
+    pub fn set_mmap_size(&mut self, size: u64) {
+        self.mmap_size = size;
+    }
+
+    /// Undo every change the transaction BEGIN began has made. The pages the cache wrote
+    /// back to the file along the way are copied back from the journal, or in WAL mode
+    /// were only ever appended to the log and are dropped from it, and everything cached
+    /// is forgotten. Without a journal, a transaction that has written to the file can't
+    /// be rolled back.
+    pub fn rollback(&mut self) -> Result<()> {
+        if self.transaction.is_none() {
+            bail!("cannot rollback - no transaction is active");
+        }
+        debug_assert_eq!(self.cache.pinned(), 0, "a page is pinned across a rollback");
+        match (&mut self.wal, &mut self.journal) {
+            (Some(reader), _) => {
+                reader.end();
+                // read afresh once the next read begins
+                reader.cached_at = FrameNumber::MAX;
+            }
+            (None, Some(journal)) => {
+                roll_back(&mut journal.file, &mut self.store).context("rolling back")?;
+                journal.saved.clear();
+            }
+            (None, None) if self.lock == LockLevel::Exclusive => {
+                bail!("cannot rollback - pages have been written to a file without a journal")
+            }
+            (None, None) => {}
+        }
+        tracing::debug!("pager rolled back");
+        self.cache.forget_past(0);
+        self.header = self.committed;
+        if self.wal.is_none() {
+            let free =
+                freelist::read(&mut self.store, &self.header).context("reading the freelist")?;
+            self.free = free.into_iter().collect();
+        }
+        self.freelist_changed = false;
+        self.end_transaction()
+    }
+
+    /// Open a savepoint, nested in those already open, that `rollback_to` can undo the
+    /// changes made after. Until it is released the cache keeps each page's contents
+    /// from before it was first changed.
+    pub fn savepoint(&mut self) {
+        self.savepoints.push(Savepoint {
+            header: self.header,
+            free: self.free.clone(),
+            freelist_changed: self.freelist_changed,
+        });
+        self.cache.savepoint();
+    }
+
+    /// What the cache has counted since the pager opened.
+    pub fn cache_report(&self) -> CacheReport {
+        self.cache.report()
+    }
+
+    /// How many savepoints are open.
+    pub fn savepoint_depth(&self) -> usize {
+        self.savepoints.len()
+    }
+
+    /// Close the savepoint at `level`, 0 being the outermost, and those opened after it,
+    /// keeping their changes. The savepoint it was opened in can still undo them.
+    pub fn release(&mut self, level: usize) {
+        self.savepoints.truncate(level);
+        self.cache.release(level);
+    }
+
+    /// Undo every change since the savepoint at `level` was opened, which stays open, and
+    /// close those opened after it. Pages allocated since are forgotten and pages freed
+    /// since are in use again.
+    pub fn rollback_to(&mut self, level: usize) -> Result<()> {
+        let Some(savepoint) = self.savepoints.get(level) else {
+            bail!(
+                "no savepoint {level} among the {} open",
+                self.savepoints.len()
+            );
+        };
+        self.header = savepoint.header;
+        self.free = savepoint.free.clone();
+        self.freelist_changed = savepoint.freelist_changed;
+        self.savepoints.truncate(level + 1);
+        self.cache.rollback_to(level);
+        self.cache.forget_past(self.header.page_count);
+        Ok(())
+    }
+
+    pub fn header(&self) -> &DatabaseHeader {
+        &self.header
+    }
+
+    /// The header as the file has it now, read again if another connection has committed
+    /// since it was last read. Outside a transaction no lock is left held, so this is
+    /// cheap enough to ask before every statement.
+    pub fn current_header(&mut self) -> Result<DatabaseHeader> {
+        if self.transaction.is_some() {
+            return Ok(self.header);
+        }
+        self.let_go()?;
+        self.reading()?;
+        let header = self.header;
+        self.let_go()?;
+        Ok(header)
+    }
+
+    /// Let go of the SHARED lock, or in WAL mode end the read, that reading pages outside
+    /// a transaction took, so that another connection can commit. A reader is otherwise
+    /// only let go of by the next flush.
+    pub fn end_read(&mut self) -> Result<()> {
+        if self.transaction.is_some() {
+            return Ok(());
+        }
+        self.let_go()
+    }
+
+    /// Change the most pages the file may grow to. A page allocated past it fails with
+    /// SQLITE_FULL, and so does the commit that needed it.
+    pub fn set_max_page_count(&mut self, count: u32) {
+        self.max_page_count = count;
+    }
+
+    /// Record a change to the schema in the header, to be written by the next flush.
+    pub fn set_schema_cookie(&mut self, cookie: u32) {
+        self.header.schema_cookie = cookie;
+    }
+
+    /// In WAL mode, copy the commits in the log into the file, as far as the reads open
+    /// and the log's retention allow, and sync it unless PRAGMA synchronous is off, the
+    /// log's file first. The log's file starts over once the log is empty. Returns the
+    /// frame the file is up to date with.
+    pub fn checkpoint(&mut self) -> Result<FrameNumber> {
+        let Some(reader) = &self.wal else {
+            bail!("only a pager in WAL mode has a log to checkpoint");
+        };
+        let sync = self.synchronous != Synchronous::Off;
+        let store = &mut self.store;
+        let mut wal = reader.wal.lock().unwrap();
+        if sync {
+            wal.sync()?;
+        }
+        let upto = wal.checkpoint(|page, data| store.write_page(page, data))?;
+        if sync {
+            self.cache.sync(store)?;
+        }
+        wal.restart()?;
+        Ok(upto)
+    }
+
+    // In WAL mode, start a read unless one is open.
+    // Otherwise take the SHARED lock unless one is held, and forget what was cached if
+    // another connection has committed since the lock was last let go of, which the
+    // change counter in the header tells. The file is mapped again once the lock is
+    // taken, as it may have grown, or shrunk, since.
+    fn reading(&mut self) -> Result<()> {
+        match &self.wal {
+            Some(reader) if reader.snapshot.is_none() => return self.begin_read(),
+            Some(_) => return Ok(()),
+            None if self.lock > LockLevel::Unlocked => return Ok(()),
+            None => {}
+        }
+        lock_to(
+            &mut self.store,
+            &mut self.lock,
+            &self.busy,
+            LockLevel::Shared,
+        )?;
+        if self.store.is_shared() && self.committed.page_count > 0 {
+            let page = self.store.read_page(1).context("reading the header")?;
+            if page[24..28] != self.committed.change_counter.to_be_bytes() {
+                self.reload()?;
+            }
+        }
+        self.store.map(self.mmap_size)
+    }
+
+    // In WAL mode, become the log's writer unless already it, which needs the read to be
+    // of the latest commit, and otherwise take the RESERVED lock. A pager that held no
+    // lock waits for RESERVED holding none either, as SQLite does: waiting with SHARED
+    // held would keep the writer that has RESERVED from ever getting EXCLUSIVE, and so
+    // each would wait for the other until the busy handler gave up.
+    fn writing(&mut self) -> Result<()> {
+        if self.wal.is_none() && self.lock == LockLevel::Unlocked {
+            let mut count = 0;
+            loop {
+                self.reading()?;
+                let reserved = lock_to(
+                    &mut self.store,
+                    &mut self.lock,
+                    &BusyHandler::None,
+                    LockLevel::Reserved,
+                );
+                match reserved {
+                    Ok(()) => return Ok(()),
+                    Err(err) if is_busy(&err) && self.busy.retry(count) => count += 1,
+                    Err(err) => {
+                        self.let_go()?;
+                        return Err(err);
+                    }
+                }
+                self.let_go()?;
+            }
+        }
+        self.reading()?;
+        let Some(reader) = &mut self.wal else {
+            return lock_to(
+                &mut self.store,
+                &mut self.lock,
+                &self.busy,
+                LockLevel::Reserved,
+            );
+        };
+        if !reader.writing {
+            let snapshot = reader.snapshot.expect("a read is open");
+            reader.wal.lock().unwrap().begin_write(&snapshot)?;
+            reader.writing = true;
+        }
+        Ok(())
+    }
+
+    // Forget the cache and read the header and free pages afresh, as another pager has
+    // committed since they were read.
+    fn reload(&mut self) -> Result<()> {
+        self.cache.forget_past(0);
+        let mut store = Journaled::new(
+            &mut self.store,
+            &mut self.journal,
+            &self.wal,
+            &mut self.lock,
+            &self.busy,
+        );
+        let page = store.read_page(1).context("reading the header")?;
+        self.header = DatabaseHeader::parse(&page)?;
+        let free = freelist::read(&mut store, &self.header).context("reading the freelist")?;
+        self.free = free.into_iter().collect();
+        self.freelist_changed = false;
+        self.committed = self.header;
+        Ok(())
+    }
+
+    // Close the savepoints and the transaction, committed or rolled back, and let go of
+    // the read or lock.
+    fn end_transaction(&mut self) -> Result<()> {
+        self.savepoints.clear();
+        self.cache.end_transaction();
+        self.transaction = None;
+        self.let_go()
+    }
+
+    fn let_go(&mut self) -> Result<()> {
+        if let Some(reader) = &mut self.wal {
+            reader.end();
+        }
+        if self.lock > LockLevel::Unlocked {
+            self.store.unlock(LockLevel::Unlocked)?;
+            self.lock = LockLevel::Unlocked;
+        }
+        Ok(())
+    }
+
+    // Bring the header at the start of page 1 up to date, if there is a page 1 yet.
+    fn write_header(&mut self) -> Result<()> {
+        if self.header.page_count == 0 {
+            return Ok(());
+        }
+        let header = self.header.to_bytes();
+        if self.get_page(1, HEADER_OWNER)?[..HEADER_SIZE] != header {
+            self.get_page_mut(1, HEADER_OWNER)?[..HEADER_SIZE].copy_from_slice(&header);
+        }
+        Ok(())
+    }
+
+    // Follow a read of `page` from the file with the pages after it if the reads have
+    // been in page order, as a scan's are.
+    fn read_ahead(&mut self, page: PageNumber, owner: &str) -> Result<()> {
+        self.sequential_faults = match self.last_fault {
+            Some(last) if last + 1 == page => self.sequential_faults + 1,
+            _ => 0,
+        };
+        self.last_fault = Some(page);
+        if self.sequential_faults < SEQUENTIAL_FAULTS {
+            return Ok(());
+        }
+        // no more than half the cache, so the pages read ahead don't push each other out
+        let room = (self.cache.capacity() / 2) as u32;
+        let count = READ_AHEAD_PAGES
+            .min(room)
+            .min(self.header.page_count - page);
+        if count == 0 {
+            return Ok(());
+        }
+        let mut store = Journaled::new(
+            &mut self.store,
+            &mut self.journal,
+            &self.wal,
+            &mut self.lock,
+            &self.busy,
+        );
+        let pages = store.read_pages(page + 1, count)?;
+        for (ahead, data) in (page + 1..).zip(pages) {
+            self.cache.read_ahead(ahead, owner, data, &mut store)?;
+        }
+        self.last_fault = Some(page + count);
+        Ok(())
+    }
+
+    // Whether page `page` is to be read from the store's memory map.
+    fn is_mapped(&self, page: PageNumber) -> bool {
+        let end = page as u64 * self.header.page_size as u64;
+        // the file lags the log, so a page must be looked for there first
+        end <= self.mmap_size
+            && self.wal.is_none()
+            && !self.cache.contains(page)
+            && self
+                .store
+                .mapping()
+                .is_some_and(|mapping| end <= mapping.len() as u64)
+    }
+
+    fn check(&self, page: PageNumber) -> Result<()> {
+        if page == 0 || page > self.header.page_count {
+            bail!(
+                "no page {page} in a database of {} pages",
+                self.header.page_count
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<F: VfsFile> Pager<PageFile<F>> {
+    /// The pager of the database in `file`, its header read from the start of the file
+    /// and checked, or of a new database if the file is empty, which gets its header,
+    /// and page 1 to keep it on, straight away. A hot journal is rolled back first.
+    pub fn open_file(
+        file: F,
+        config: &PagerConfig,
+        journal: Box<dyn VfsFile + Send>,
+    ) -> Result<Self> {
+        Pager::open_file_locking(file, config, journal, true)
+    }
+
+    /// As open_file, but without taking the SHARED lock while the file is read, and so
+    /// without rolling back a journal, for a file in WAL mode whose log another connection
+    /// in the process has open, and whose EXCLUSIVE lock the log holds, see image.rs.
+    pub fn open_file_beside_log(
+        file: F,
+        config: &PagerConfig,
+        journal: Box<dyn VfsFile + Send>,
+    ) -> Result<Self> {
+        Pager::open_file_locking(file, config, journal, false)
+    }
+
+    fn open_file_locking(
+        mut file: F,
+        config: &PagerConfig,
+        mut journal: Box<dyn VfsFile + Send>,
+        lock: bool,
+    ) -> Result<Self> {
+        // SHARED while the journal, header and freelist are read, so that no commit is
+        // half written meanwhile. An error drops the file, and the lock with it.
+        if lock {
+            file.lock(LockLevel::Shared)?;
+        }
+        // the page size the journal was written with, or the one in the header
+        let page_size = match journal_header(&mut journal)? {
+            Some((_, page_size)) if lock => page_size,
+            _ => DatabaseHeader::read(&mut file)?.map_or(config.page_size, |h| h.page_size),
+        };
+        let mut store = PageFile::new(file, page_size);
+        // a journal is only hot if no other connection is committing through it, which
+        // the RESERVED or EXCLUSIVE lock it holds meanwhile keeps this one from EXCLUSIVE
+        if lock && journal_header(&mut journal)?.is_some() {
+            match store.file().lock(LockLevel::Exclusive) {
+                Ok(()) => {
+                    super::journal::recover(&mut journal, &mut store)
+                        .context("rolling back a hot journal")?;
+                }
+                Err(err) if is_busy(&err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        let header = DatabaseHeader::read(store.file())?;
+        let new = header.is_none();
+        let header = header.unwrap_or_else(|| DatabaseHeader::new(config));
+        let mut pager = Pager::with_journal(store, header, config, journal)?;
+        if lock {
+            pager.store.file().unlock(LockLevel::Unlocked)?;
+        }
+        if new {
+            pager.allocate_page(HEADER_OWNER)?;
+            pager.flush()?;
+        }
+        Ok(pager)
+    }
+
+    /// The lock each connection to the file holds and what it is waiting for.
+    pub fn lock_status(&self) -> Vec<LockStatus> {
+        self.store.lock_status()
+    }
+}
+
+// The pager's state outside the cache when a savepoint was opened. The pages are
+// copied by the cache.
+struct Savepoint {
+    header: DatabaseHeader,
+    free: BTreeSet<PageNumber>,
+    freelist_changed: bool,
+}
+
+// A pager's place in the write-ahead log it shares with the database's other pagers.
+struct WalReader {
+    wal: Arc<Mutex<Wal>>,
+    // the read open, if there is one, and whether it is the log's writer
+    snapshot: Option<Snapshot>,
+    writing: bool,
+    // the commit the cached pages, header and free pages are as of
+    cached_at: FrameNumber,
+}
+
+impl WalReader {
+    // Commit what the writer has appended, if anything, syncing the log's file if
+    // `sync`, and end the read. A commit that fails to be written drops what was
+    // appended.
+    fn commit(&mut self, sync: bool) -> Result<()> {
+        let Some(snapshot) = self.snapshot.take() else {
+            return Ok(());
+        };
+        let mut wal = self.wal.lock().unwrap();
+        let mut committed = Ok(());
+        if self.writing && wal.has_uncommitted() {
+            committed = wal.commit_appended().map(|frame| self.cached_at = frame);
+            if committed.is_ok() && sync {
+                committed = wal.sync();
+            }
+        }
+        self.writing = false;
+        wal.end_read(snapshot);
+        committed
+    }
+
+    // End the read without committing, dropping whatever the writer appended.
+    fn end(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            if let Ok(mut wal) = self.wal.lock() {
+                wal.end_read(snapshot);
+            }
+        }
+        self.writing = false;
+    }
+
+    // A page as the read open sees it, None when it is to be read from the file.
+    fn read_page(&self, page: PageNumber) -> Option<Vec<u8>> {
+        let snapshot = self.snapshot.as_ref()?;
+        let wal = self.wal.lock().unwrap();
+        wal.read_page(snapshot, page).map(<[u8]>::to_vec)
+    }
+}
+
+impl Drop for WalReader {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+// The journal of a pager opened with one, and the transaction it is saving pages for.
+struct RollbackJournal {
+    file: Box<dyn VfsFile + Send>,
+    page_size: u32,
+    // how many pages the file had when the transaction began
+    page_count: PageNumber,
+    // the pages whose originals are in the journal
+    saved: BTreeSet<PageNumber>,
+    synchronous: Synchronous,
+}
+
+impl RollbackJournal {
+    // Save the originals of those of `pages` that were in the file when the transaction
+    // began and aren't saved already, syncing them before the file is overwritten.
+    fn save(
+        &mut self,
+        store: &mut dyn PageStore,
+        pages: impl IntoIterator<Item = PageNumber>,
+    ) -> Result<()> {
+        let mut saved = false;
+        for page in pages {
+            if page > self.page_count || !self.saved.insert(page) {
+                continue;
+            }
+            if self.saved.len() == 1 {
+                let mut header = self.page_count.to_be_bytes().to_vec();
+                header.extend(self.page_size.to_be_bytes());
+                self.file.write_at(0, &header)?;
+            }
+            let mut record = page.to_be_bytes().to_vec();
+            record.extend(store.read_page(page)?);
+            let offset = JOURNAL_HEADER_SIZE + (self.saved.len() as u64 - 1) * record.len() as u64;
+            self.file.write_at(offset, &record)?;
+            saved = true;
+        }
+        if saved && self.synchronous != Synchronous::Off {
+            self.file.sync()?;
+        }
+        Ok(())
+    }
+
+    // Name the super-journal of a commit across several files after the pages saved,
+    // syncing it before the file is overwritten. A journal with no page saved has
+    // nothing to roll back, and so needs no name.
+    fn name_super_journal(&mut self, name: &str) -> Result<()> {
+        if self.saved.is_empty() {
+            return Ok(());
+        }
+        let mut record = 0u32.to_be_bytes().to_vec();
+        record.extend((name.len() as u32).to_be_bytes());
+        record.extend(name.as_bytes());
+        let offset = JOURNAL_HEADER_SIZE + self.saved.len() as u64 * (4 + self.page_size as u64);
+        self.file.write_at(offset, &record)?;
+        if self.synchronous != Synchronous::Off {
+            self.file.sync()?;
+        }
+        Ok(())
+    }
+
+    // Empty the journal, which commits the transaction, and begin the next one with the
+    // file at `page_count` pages.
+    fn commit(&mut self, page_count: PageNumber) -> Result<()> {
+        if !self.saved.is_empty() {
+            self.file.truncate(0)?;
+            if self.synchronous >= Synchronous::Full {
+                self.file.sync()?;
+            }
+            self.saved.clear();
+        }
+        self.page_count = page_count;
+        Ok(())
+    }
+}
+
+// Copy the pages saved in `journal` back into `store` and empty the journal, returning
+// the page count the file had before the transaction, if the journal was hot. A record
+// cut short was being written when the commit stopped, before the page it saves was
+// overwritten, and is passed over.
+pub(super) fn roll_back(
+    journal: &mut dyn VfsFile,
+    store: &mut dyn PageStore,
+) -> Result<Option<PageNumber>> {
+    let Some((page_count, page_size)) = journal_header(journal)? else {
+        return Ok(None);
+    };
+    tracing::debug!(page_count, "rolling back from the journal");
+    let mut record = vec![0; 4 + page_size as usize];
+    let mut offset = JOURNAL_HEADER_SIZE;
+    while journal.read_at(offset, &mut record)? == record.len() {
+        let page = PageNumber::from_be_bytes(record[..4].try_into().unwrap());
+        if page == 0 {
+            // the super-journal's name, after the last page saved
+            break;
+        }
+        store.write_page(page, &record[4..])?;
+        offset += record.len() as u64;
+    }
+    store.sync()?;
+    journal.truncate(0)?;
+    journal.sync()?;
+    Ok(Some(page_count))
+}
+
+// Take the lock `level` on `store` unless `held` is as strong already, trying again for
+// as long as `busy` says to while another connection is in the way.
+fn lock_to(
+    store: &mut dyn PageStore,
+    held: &mut LockLevel,
+    busy: &BusyHandler,
+    level: LockLevel,
+) -> Result<()> {
+    if *held >= level {
+        return Ok(());
+    }
+    let mut count = 0;
+    loop {
+        match store.lock(level) {
+            Ok(()) => break,
+            Err(err) if is_busy(&err) && busy.retry(count) => {
+                tracing::debug!(%level, count, "lock busy, trying again");
+                count += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    *held = level;
+    Ok(())
+}
+
+fn is_busy(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref(), Some(SqlError::DatabaseLocked))
+}
+
+/// The super-journal a hot journal names, if it was written by a commit across several
+/// files, see journal.rs.
+pub fn super_journal_of(journal: &mut dyn VfsFile) -> Result<Option<String>> {
+    let Some((_, page_size)) = journal_header(journal)? else {
+        return Ok(None);
+    };
+    let mut offset = JOURNAL_HEADER_SIZE;
+    let mut page = [0; 4];
+    while journal.read_at(offset, &mut page)? == page.len() {
+        if page != [0; 4] {
+            offset += 4 + page_size as u64;
+            continue;
+        }
+        let mut len = [0; 4];
+        if journal.read_at(offset + 4, &mut len)? < len.len() {
+            break;
+        }
+        let len = u32::from_be_bytes(len) as u64;
+        if offset + 8 + len > journal.size()? {
+            break;
+        }
+        let mut name = vec![0; len as usize];
+        journal.read_at(offset + 8, &mut name)?;
+        return Ok(Some(String::from_utf8(name)?));
+    }
+    Ok(None)
+}
+
+// The page count and page size at the start of a hot journal.
+fn journal_header(journal: &mut dyn VfsFile) -> Result<Option<(PageNumber, u32)>> {
+    let mut header = [0; JOURNAL_HEADER_SIZE as usize];
+    if journal.read_at(0, &mut header)? < header.len() {
+        return Ok(None);
+    }
+    let page_count = PageNumber::from_be_bytes(header[..4].try_into().unwrap());
+    Ok(Some((
+        page_count,
+        u32::from_be_bytes(header[4..].try_into().unwrap()),
+    )))
+}
+
+// The pager as a store for the freelist to write its trunk pages to, which puts them in
+// the cache to be written with the rest of a flush.
+struct IntoCache<'a, S: PageStore> {
+    cache: &'a mut PageCache,
+    store: Journaled<'a, S>,
+}
+
+impl<S: PageStore> PageStore for IntoCache<'_, S> {
+    fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+        self.store.read_page(page)
+    }
+
+    fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+        self.cache
+            .put(page, "freelist", data.to_vec(), &mut self.store)
+    }
+}
+
+// The file as the cache sees it, which saves each page's original in the journal, if
+// there is one, before the page is first overwritten. In WAL mode pages are read from
+// the log when it has them as of the read open, and written to it.
+struct Journaled<'a, S: PageStore> {
+    store: &'a mut S,
+    journal: Option<&'a mut RollbackJournal>,
+    wal: Option<&'a WalReader>,
+    // the pager's lock, which goes to EXCLUSIVE before the file is first written
+    lock: &'a mut LockLevel,
+    busy: &'a BusyHandler,
+}
+
+impl<'a, S: PageStore> Journaled<'a, S> {
+    fn new(
+        store: &'a mut S,
+        journal: &'a mut Option<RollbackJournal>,
+        wal: &'a Option<WalReader>,
+        lock: &'a mut LockLevel,
+        busy: &'a BusyHandler,
+    ) -> Self {
+        Journaled {
+            store,
+            journal: journal.as_mut(),
+            wal: wal.as_ref(),
+            lock,
+            busy,
+        }
+    }
+}
+
+impl<S: PageStore> PageStore for Journaled<'_, S> {
+    fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+        match self.wal.and_then(|reader| reader.read_page(page)) {
+            Some(data) => Ok(data),
+            None => self.store.read_page(page),
+        }
+    }
+
+    fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+        if let Some(reader) = self.wal {
+            // appended to the log, where the writer reads it back from
+            reader.wal.lock().unwrap().append(page, data.to_vec());
+            return Ok(());
+        }
+        lock_to(self.store, self.lock, self.busy, LockLevel::Exclusive)?;
+        if let Some(journal) = &mut self.journal {
+            journal.save(self.store, [page])?;
+        }
+        self.store.write_page(page, data)
+    }
+
+    fn write_pages(&mut self, first: PageNumber, pages: &[&[u8]]) -> Result<()> {
+        if self.wal.is_some() {
+            for (page, data) in (first..).zip(pages) {
+                self.write_page(page, data)?;
+            }
+            return Ok(());
+        }
+        lock_to(self.store, self.lock, self.busy, LockLevel::Exclusive)?;
+        if let Some(journal) = &mut self.journal {
+            journal.save(self.store, first..first + pages.len() as PageNumber)?;
+        }
+        self.store.write_pages(first, pages)
+    }
+
+    fn read_pages(&mut self, first: PageNumber, count: u32) -> Result<Vec<Vec<u8>>> {
+        if self.wal.is_some() {
+            return (first..first + count)
+                .map(|page| self.read_page(page))
+                .collect();
+        }
+        self.store.read_pages(first, count)
+    }
+
+    fn mapping(&self) -> Option<&[u8]> {
+        match self.wal {
+            Some(_) => None,
+            None => self.store.mapping(),
+        }
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.store.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::lock::LockLevel;
+    use crate::storage::memdb::{AccessMode, MemFile, MemVfs};
+    use crate::storage::os_interface::{PageFile, Vfs};
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Pages kept in memory, counting the reads it is asked for.
+    #[derive(Default)]
+    struct File {
+        pages: HashMap<PageNumber, Vec<u8>>,
+        reads: usize,
+    }
+
+    impl PageStore for File {
+        fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+            self.reads += 1;
+            match self.pages.get(&page) {
+                Some(data) => Ok(data.clone()),
+                None => bail!("no page {page}"),
+            }
+        }
+
+        fn read_pages(&mut self, first: PageNumber, count: u32) -> Result<Vec<Vec<u8>>> {
+            self.reads += 1;
+            (first..first + count)
+                .map(|page| match self.pages.get(&page) {
+                    Some(data) => Ok(data.clone()),
+                    None => bail!("no page {page}"),
+                })
+                .collect()
+        }
+
+        fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+            self.pages.insert(page, data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn config() -> PagerConfig {
+        let mut config = PagerConfig::default();
+        config.set_page_size(512);
+        config.cache_size = 2;
+        config
+    }
+
+    #[test]
+    fn pages_are_allocated_freed_and_allocated_again() {
+        let config = config();
+        let mut pager =
+            Pager::open(File::default(), DatabaseHeader::new(&config), &config).unwrap();
+        for i in 1..=4 {
+            let page = pager.allocate_page("users").unwrap();
+            assert_eq!(page, i);
+            pager.get_page_mut(page, "users").unwrap()[0] = i as u8 * 10;
+        }
+        // the cache holds two, so the first two pages have been written already
+        assert_eq!(pager.store.pages.len(), 2);
+        assert_eq!(pager.get_page(1, "users").unwrap()[..1], [10]);
+
+        pager.free_page(3).unwrap();
+        pager.free_page(2).unwrap();
+        assert!(pager.free_page(2).is_err());
+        // the lowest free page comes back first, zeroed
+        assert_eq!(pager.allocate_page("idx_email").unwrap(), 2);
+        assert!(pager
+            .get_page(2, "idx_email")
+            .unwrap()
+            .iter()
+            .all(|&b| b == 0));
+        pager.flush().unwrap();
+        let header = *pager.header();
+        assert_eq!(
+            (
+                header.page_count,
+                header.freelist_trunk,
+                header.freelist_count
+            ),
+            (4, 3, 1)
+        );
+        assert!(pager.get_page(5, "users").is_err());
+
+        // opened again the free page is still free and the rest read back as written
+        let mut pager = Pager::open(pager.store, header, &config).unwrap();
+        assert_eq!(pager.get_page(4, "users").unwrap()[..1], [40]);
+        assert_eq!(pager.allocate_page("users").unwrap(), 3);
+        assert_eq!(pager.allocate_page("users").unwrap(), 5);
+    }
+
+    #[test]
+    fn guards_keep_their_pages_cached() {
+        let config = config();
+        let mut pager =
+            Pager::open(File::default(), DatabaseHeader::new(&config), &config).unwrap();
+        for _ in 1..=5 {
+            pager.allocate_page("users").unwrap();
+        }
+        pager.flush().unwrap();
+        let faults = pager.cache.report().total().faults;
+
+        let mut parent = pager.get_page_mut(2, "users").unwrap();
+        parent[0] = 10;
+        // the cache holds two pages, and reading three more through the parent's guard
+        // still leaves the parent cached
+        for child in 3..=5 {
+            let child = parent.pager.get_page(child, "users").unwrap();
+            assert!(child.iter().all(|&b| b == 0));
+        }
+        assert_eq!(parent[0], 10);
+        // one read for the parent and one for each child
+        assert_eq!(parent.pager.cache.report().total().faults - faults, 4);
+        drop(parent);
+        assert_eq!(pager.cache.pinned(), 0);
+        pager.flush().unwrap();
+        assert_eq!(pager.store.pages[&2][0], 10);
+        assert_eq!(pager.cache_report().syncs, 2);
+
+        // without syncs
+        let config = PagerConfig {
+            synchronous: Synchronous::Off,
+            ..config
+        };
+        let header = *pager.header();
+        let mut pager = Pager::open(pager.store, header, &config).unwrap();
+        pager.get_page_mut(2, "users").unwrap()[0] = 11;
+        pager.flush().unwrap();
+        assert_eq!(pager.cache_report().syncs, 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "a page is pinned across a commit")]
+    fn holding_a_guard_across_a_flush_is_a_bug() {
+        let config = config();
+        let mut pager =
+            Pager::open(File::default(), DatabaseHeader::new(&config), &config).unwrap();
+        pager.allocate_page("users").unwrap();
+        let mut page = pager.get_page_mut(1, "users").unwrap();
+        page[0] = 10;
+        page.pager.flush().unwrap();
+    }
+
+    #[test]
+    fn scans_read_ahead() {
+        let mut config = config();
+        config.cache_size = 64;
+        let mut file = File::default();
+        for page in 1..=100 {
+            file.pages.insert(page, vec![page as u8; 512]);
+        }
+        let mut header = DatabaseHeader::new(&config);
+        header.page_count = 100;
+        let mut pager = Pager::open(file, header, &config).unwrap();
+
+        // pages 1 to 3 are read one at a time, then 4 to 19 in one read after 3, 21 to 36
+        // after 20 and so on, each read of a page followed by one of the pages after it
+        for page in 1..=100 {
+            assert_eq!(pager.get_page(page, "users").unwrap()[0], page as u8);
+        }
+        assert_eq!(pager.store.reads, 8 + 6);
+        let users = pager.cache.report().total();
+        assert_eq!((users.faults, users.read_ahead), (8, 92));
+
+        // reading pages out of order reads only those pages
+        let mut pager = Pager::open(pager.store, header, &config).unwrap();
+        for page in [50, 7, 51, 90, 3, 4] {
+            pager.get_page(page, "users").unwrap();
+        }
+        assert_eq!(pager.store.reads, 14 + 6);
+    }
+
+    // A file mapped into memory whole, as a store over a real file would map it.
+    struct MappedFile {
+        bytes: Vec<u8>,
+        reads: usize,
+    }
+
+    impl PageStore for MappedFile {
+        fn read_page(&mut self, page: PageNumber) -> Result<Vec<u8>> {
+            self.reads += 1;
+            let start = (page as usize - 1) * 512;
+            Ok(self.bytes[start..start + 512].to_vec())
+        }
+
+        fn write_page(&mut self, page: PageNumber, data: &[u8]) -> Result<()> {
+            let start = (page as usize - 1) * 512;
+            self.bytes[start..start + 512].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn mapping(&self) -> Option<&[u8]> {
+            Some(&self.bytes)
+        }
+    }
+
+    #[test]
+    fn pages_within_mmap_size_are_read_from_the_map() {
+        let mut config = config();
+        config.set_mmap_size(2 * 512);
+        let file = MappedFile {
+            bytes: (1..=4u8).flat_map(|page| [page; 512]).collect(),
+            reads: 0,
+        };
+        let mut header = DatabaseHeader::new(&config);
+        header.page_count = 4;
+        let mut pager = Pager::open(file, header, &config).unwrap();
+
+        for page in 1..=4 {
+            assert_eq!(pager.get_page(page, "users").unwrap()[511], page as u8);
+        }
+        // only the two pages past mmap_size went through the cache
+        assert_eq!(pager.store.reads, 2);
+
+        // a changed page is read from the cache until it is written back
+        pager.get_page_mut(1, "users").unwrap()[HEADER_SIZE] = 10;
+        assert_eq!(pager.store.reads, 3);
+        assert_eq!(pager.get_page(1, "users").unwrap()[HEADER_SIZE], 10);
+        assert_eq!(pager.store.bytes[HEADER_SIZE], 1);
+        pager.flush().unwrap();
+        assert_eq!(pager.store.bytes[HEADER_SIZE], 10);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_file_on_disk_is_read_through_its_map() {
+        use crate::storage::os_interface::{OsVfs, Vfs};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let journal = dir.path().join("test.db-journal");
+        let open = |config: &PagerConfig| {
+            let file = OsVfs.open(&path, AccessMode::Create).unwrap();
+            let journal = OsVfs.open(&journal, AccessMode::Create).unwrap();
+            Pager::open_file(file, config, Box::new(journal)).unwrap()
+        };
+        let mut pager = open(&config());
+        for i in 2..=4 {
+            let page = pager.allocate_page("users").unwrap();
+            pager.get_page_mut(page, "users").unwrap()[0] = i;
+        }
+        pager.flush().unwrap();
+
+        let mut config = config();
+        config.set_mmap_size(3 * 512);
+        let mut pager = open(&config);
+        for page in 2..=4 {
+            assert_eq!(pager.get_page(page, "users").unwrap()[0], page as u8);
+        }
+        assert_eq!(pager.store.mapping().map(<[u8]>::len), Some(3 * 512));
+        // the three pages in the map never went through the cache
+        assert!((1..=3).all(|page| !pager.cache.contains(page)));
+        assert!(pager.cache.contains(4));
+
+        // what is written shows through the map, and the map grows with the file
+        pager.get_page_mut(2, "users").unwrap()[0] = 20;
+        pager.allocate_page("users").unwrap();
+        pager.flush().unwrap();
+        pager.set_mmap_size(1 << 20);
+        assert_eq!(pager.get_page(2, "users").unwrap()[0], 20);
+        assert!(!pager.cache.contains(2));
+        assert_eq!(pager.store.mapping().map(<[u8]>::len), Some(5 * 512));
+        pager.flush().unwrap();
+        pager.set_mmap_size(0);
+        pager.get_page(2, "users").unwrap();
+        assert!(pager.store.mapping().is_none());
+    }
+
+    #[test]
+    fn savepoints_undo_what_was_done_since() {
+        let config = config();
+        let mut pager =
+            Pager::open(File::default(), DatabaseHeader::new(&config), &config).unwrap();
+        for i in 1..=4 {
+            let page = pager.allocate_page("users").unwrap();
+            pager.get_page_mut(page, "users").unwrap()[HEADER_SIZE] = i as u8 * 10;
+        }
+        pager.flush().unwrap();
+
+        pager.savepoint();
+        pager.get_page_mut(2, "users").unwrap()[HEADER_SIZE] = 21;
+        pager.free_page(4).unwrap();
+        pager.get_page(1, "users").unwrap();
+        pager.get_page(2, "users").unwrap();
+        // page 4 comes back zeroed, having been evicted and so never copied
+        assert!(!pager.cache.contains(4));
+        assert_eq!(pager.allocate_page("idx_email").unwrap(), 4);
+        assert_eq!(pager.get_page(4, "idx_email").unwrap()[HEADER_SIZE], 0);
+        assert_eq!(pager.allocate_page("users").unwrap(), 5);
+        pager.get_page_mut(5, "users").unwrap()[HEADER_SIZE] = 50;
+        pager.free_page(3).unwrap();
+
+        pager.savepoint();
+        pager.get_page_mut(2, "users").unwrap()[HEADER_SIZE] = 22;
+        assert_eq!(pager.allocate_page("users").unwrap(), 3);
+        assert_eq!(pager.allocate_page("users").unwrap(), 6);
+        assert_eq!(pager.savepoint_depth(), 2);
+
+        // back to the inner savepoint, which stays open
+        pager.rollback_to(1).unwrap();
+        assert_eq!(pager.savepoint_depth(), 2);
+        assert_eq!(pager.get_page(2, "users").unwrap()[HEADER_SIZE], 21);
+        assert_eq!(pager.get_page(5, "users").unwrap()[HEADER_SIZE], 50);
+        assert_eq!(pager.header().page_count, 5);
+        assert!(pager.get_page(6, "users").is_err());
+
+        // then the outer one, closing the inner
+        pager.rollback_to(0).unwrap();
+        assert_eq!(pager.savepoint_depth(), 1);
+        for page in 2..=4 {
+            assert_eq!(
+                pager.get_page(page, "users").unwrap()[HEADER_SIZE],
+                page as u8 * 10
+            );
+        }
+        assert_eq!(pager.header().page_count, 4);
+        assert!(pager.get_page(5, "users").is_err());
+        assert!(pager.rollback_to(1).is_err());
+
+        // a released savepoint's changes are undone by the one it was opened in
+        pager.savepoint();
+        pager.free_page(2).unwrap();
+        pager.release(1);
+        pager.rollback_to(0).unwrap();
+        // nothing is free, so the next page is a new one
+        assert_eq!(pager.allocate_page("users").unwrap(), 5);
+        pager.rollback_to(0).unwrap();
+
+        // and a flush closes them all, writing only what was kept
+        pager.get_page_mut(3, "users").unwrap()[HEADER_SIZE] = 31;
+        pager.flush().unwrap();
+        assert_eq!(pager.savepoint_depth(), 0);
+        let header = *pager.header();
+        assert_eq!((header.page_count, header.freelist_count), (4, 0));
+        let written: Vec<u8> = (2..=4)
+            .map(|page| pager.store.pages[&page][HEADER_SIZE])
+            .collect();
+        assert_eq!(written, [20, 31, 40]);
+    }
+
+    #[test]
+    fn reads_in_wal_mode_see_the_commit_they_began_at() {
+        let config = config();
+        let vfs = MemVfs::default();
+        let wal = Arc::new(Mutex::new(Wal::new(0)));
+        let open = || {
+            let file = vfs.open(Path::new("test.db"), AccessMode::Create).unwrap();
+            let header = DatabaseHeader::new(&config);
+            let mut pager = Pager::open(PageFile::new(file, 512), header, &config).unwrap();
+            pager.wal = Some(WalReader {
+                wal: wal.clone(),
+                snapshot: None,
+                writing: false,
+                cached_at: 0,
+            });
+            pager
+        };
+        let mut writer = open();
+        for i in 1..=3 {
+            let page = writer.allocate_page("users").unwrap();
+            writer.get_page_mut(page, "users").unwrap()[HEADER_SIZE] = i as u8 * 10;
+        }
+        writer.flush().unwrap();
+
+        let mut reader = open();
+        reader.begin_read().unwrap();
+        let first = reader.snapshot().unwrap().read_mark;
+        assert_eq!(reader.header().page_count, 3);
+        assert_eq!(reader.get_page(2, "users").unwrap()[HEADER_SIZE], 20);
+
+        // neither what the writer has yet to commit nor what it commits is seen
+        writer.get_page_mut(2, "users").unwrap()[HEADER_SIZE] = 21;
+        assert_eq!(writer.allocate_page("users").unwrap(), 4);
+        assert!(reader.get_page_mut(3, "users").is_err());
+        writer.flush().unwrap();
+        assert_eq!(reader.get_page(2, "users").unwrap()[HEADER_SIZE], 20);
+        assert!(reader.get_page(4, "users").is_err());
+        assert_eq!(
+            reader.get_page_mut(3, "users").err().unwrap().to_string(),
+            "database is locked: a commit has been made since this read began"
+        );
+        let mut latest = open();
+        assert_eq!(latest.get_page(2, "users").unwrap()[HEADER_SIZE], 21);
+
+        // the file catches up no further than the oldest read
+        assert_eq!(writer.checkpoint().unwrap(), first);
+        reader.flush().unwrap();
+        assert_eq!(reader.get_page(2, "users").unwrap()[HEADER_SIZE], 21);
+        assert_eq!(reader.header().page_count, 4);
+        let last = writer.checkpoint().unwrap();
+        assert!(last > first);
+        let mut file = vfs.open(Path::new("test.db"), AccessMode::Create).unwrap();
+        let header = DatabaseHeader::read(&mut file).unwrap().unwrap();
+        assert_eq!(header.page_count, 4);
+        let mut pager = Pager::open(PageFile::new(file, 512), header, &config).unwrap();
+        assert_eq!(pager.get_page(2, "users").unwrap()[HEADER_SIZE], 21);
+
+        // a writer dropped before committing lets another write, and leaves no trace
+        let mut dropped = open();
+        dropped.get_page_mut(3, "users").unwrap()[HEADER_SIZE] = 31;
+        drop(dropped);
+        latest.flush().unwrap();
+        latest.get_page_mut(3, "users").unwrap()[HEADER_SIZE] = 32;
+        latest.flush().unwrap();
+        assert_eq!(reader.get_page(3, "users").unwrap()[HEADER_SIZE], 30);
+        reader.flush().unwrap();
+        assert_eq!(reader.get_page(3, "users").unwrap()[HEADER_SIZE], 32);
+
+        // a write rolled back leaves the log as it was and another free to write
+        latest.begin(TransactionMode::Immediate).unwrap();
+        latest.get_page_mut(3, "users").unwrap()[HEADER_SIZE] = 33;
+        latest.rollback().unwrap();
+        assert_eq!(latest.get_page(3, "users").unwrap()[HEADER_SIZE], 32);
+        reader.get_page_mut(3, "users").unwrap()[HEADER_SIZE] = 34;
+        reader.flush().unwrap();
+    }
+
+    #[test]
+    fn transactions_lock_as_their_mode_asks_and_roll_back() {
+        let config = config();
+        let vfs = MemVfs::default();
+        let mut a = open_file(&vfs, &config);
+        for i in 2..=4 {
+            let page = a.allocate_page("users").unwrap();
+            a.get_page_mut(page, "users").unwrap()[0] = i as u8 * 10;
+        }
+        a.flush().unwrap();
+        assert_eq!(a.lock, LockLevel::Unlocked);
+        let mut b = open_file(&vfs, &config);
+
+        // IMMEDIATE keeps other writers out but lets readers in
+        a.begin(TransactionMode::Immediate).unwrap();
+        assert_eq!(a.lock, LockLevel::Reserved);
+        assert_eq!(
+            a.begin(TransactionMode::Deferred).unwrap_err().to_string(),
+            "cannot start a transaction within a transaction"
+        );
+        assert_eq!(b.get_page(2, "users").unwrap()[0], 20);
+        assert_eq!(
+            b.begin(TransactionMode::Immediate).unwrap_err().to_string(),
+            "database is locked"
+        );
+        b.flush().unwrap();
+        a.get_page_mut(2, "users").unwrap()[0] = 21;
+        assert_eq!(a.allocate_page("users").unwrap(), 5);
+        // the cache holds two pages, so a dirty one is written back, which takes EXCLUSIVE
+        a.get_page(3, "users").unwrap();
+        a.get_page(4, "users").unwrap();
+        assert_eq!(a.lock, LockLevel::Exclusive);
+        a.flush().unwrap();
+        assert_eq!(a.lock, LockLevel::Unlocked);
+        // the other pager sees the change counter has moved and reads afresh
+        assert_eq!(b.get_page(2, "users").unwrap()[0], 21);
+        assert_eq!(b.header().page_count, 5);
+        b.flush().unwrap();
+
+        // a rollback copies back what was written from the journal
+        a.begin(TransactionMode::Deferred).unwrap();
+        assert_eq!(a.lock, LockLevel::Unlocked);
+        a.get_page_mut(2, "users").unwrap()[0] = 22;
+        a.free_page(3).unwrap();
+        assert_eq!(a.allocate_page("users").unwrap(), 3);
+        assert_eq!(a.allocate_page("users").unwrap(), 6);
+        a.get_page(4, "users").unwrap();
+        a.get_page(5, "users").unwrap();
+        assert_eq!(a.lock, LockLevel::Exclusive);
+        a.rollback().unwrap();
+        assert!(!a.in_transaction());
+        assert_eq!(a.lock, LockLevel::Unlocked);
+        assert_eq!((a.header().page_count, a.header().freelist_count), (5, 0));
+        assert_eq!(a.get_page(2, "users").unwrap()[0], 21);
+        assert_eq!(a.get_page(3, "users").unwrap()[0], 30);
+        a.flush().unwrap();
+        assert_eq!(b.get_page(2, "users").unwrap()[0], 21);
+        b.flush().unwrap();
+
+        // EXCLUSIVE keeps readers out too
+        a.begin(TransactionMode::Exclusive).unwrap();
+        assert_eq!(
+            b.get_page(2, "users").err().unwrap().to_string(),
+            "database is locked"
+        );
+        a.rollback().unwrap();
+        assert_eq!(b.get_page(2, "users").unwrap()[0], 21);
+        assert!(a.rollback().is_err());
+    }
+
+    #[test]
+    fn the_file_stops_growing_at_max_page_count() {
+        let mut config = config();
+        config.set_max_page_count(2);
+        let mut pager =
+            Pager::open(File::default(), DatabaseHeader::new(&config), &config).unwrap();
+        pager.allocate_page("users").unwrap();
+        pager.allocate_page("users").unwrap();
+        assert_eq!(
+            pager.allocate_page("users").unwrap_err().to_string(),
+            "database or disk is full"
+        );
+        pager.free_page(1).unwrap();
+        assert_eq!(pager.allocate_page("users").unwrap(), 1);
+        pager.set_max_page_count(3);
+        assert_eq!(pager.allocate_page("users").unwrap(), 3);
+    }
+
+    // A file in memory that counts its syncs.
+    struct Synced {
+        file: MemFile,
+        syncs: Arc<AtomicUsize>,
+    }
+
+    impl VfsFile for Synced {
+        fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+            self.file.read_at(offset, buf)
+        }
+
+        fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+            self.file.write_at(offset, data)
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            self.syncs.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn truncate(&mut self, size: u64) -> Result<()> {
+            self.file.truncate(size)
+        }
+
+        fn size(&mut self) -> Result<u64> {
+            self.file.size()
+        }
+
+        fn lock(&mut self, level: LockLevel) -> Result<()> {
+            self.file.lock(level)
+        }
+
+        fn unlock(&mut self, level: LockLevel) -> Result<()> {
+            self.file.unlock(level)
+        }
+    }
+
+    fn open_file(vfs: &MemVfs, config: &PagerConfig) -> Pager<PageFile<MemFile>> {
+        let file = vfs.open(Path::new("test.db"), AccessMode::Create).unwrap();
+        let journal = vfs
+            .open(Path::new("test.db-journal"), AccessMode::Create)
+            .unwrap();
+        Pager::open_file(file, config, Box::new(journal)).unwrap()
+    }
+
+    #[test]
+    fn a_commit_cut_short_is_rolled_back() {
+        let config = config();
+        let vfs = MemVfs::default();
+        let mut pager = open_file(&vfs, &config);
+        for page in 2..=4 {
+            pager.allocate_page("users").unwrap();
+            pager.get_page_mut(page, "users").unwrap()[0] = page as u8;
+        }
+        pager.flush().unwrap();
+
+        // the cache holds two pages, so changing three writes the first back early, its
+        // original saved in the journal first
+        for page in 2..=4 {
+            pager.get_page_mut(page, "users").unwrap()[0] = 10 + page as u8;
+        }
+        pager.allocate_page("users").unwrap();
+        let mut first = [0];
+        pager.store.file().read_at(512, &mut first).unwrap();
+        assert_eq!(first, [12]);
+        let mut journal = vfs
+            .open(Path::new("test.db-journal"), AccessMode::ReadOnly)
+            .unwrap();
+        assert_eq!(journal.size().unwrap(), 8 + 2 * (4 + 512));
+        drop(pager);
+
+        let mut pager = open_file(&vfs, &config);
+        assert_eq!(journal.size().unwrap(), 0);
+        for page in 2..=4 {
+            assert_eq!(pager.get_page(page, "users").unwrap()[0], page as u8);
+        }
+        assert_eq!(pager.header().page_count, 4);
+
+        // and a flush commits
+        pager.get_page_mut(2, "users").unwrap()[0] = 20;
+        pager.flush().unwrap();
+        assert_eq!(journal.size().unwrap(), 0);
+        let mut pager = open_file(&vfs, &config);
+        assert_eq!(pager.get_page(2, "users").unwrap()[0], 20);
+    }
+
+    #[test]
+    fn the_header_is_written_on_creation_and_checked_on_opening() {
+        let config = config();
+        let vfs = MemVfs::default();
+        let mut pager = open_file(&vfs, &config);
+        assert_eq!(pager.header().page_count, 1);
+        pager.allocate_page("users").unwrap();
+        pager.set_schema_cookie(3);
+        pager.flush().unwrap();
+        drop(pager);
+
+        let mut file = vfs
+            .open(Path::new("test.db"), AccessMode::ReadWrite)
+            .unwrap();
+        let header = DatabaseHeader::read(&mut file).unwrap().unwrap();
+        assert_eq!((header.page_count, header.schema_cookie), (2, 3));
+        // a database of another page size opens with the page size it was made with
+        let mut other = config.clone();
+        other.set_page_size(1024);
+        assert_eq!(open_file(&vfs, &other).header().page_size, 512);
+
+        file.truncate(600).unwrap();
+        let journal = vfs
+            .open(Path::new("test.db-journal"), AccessMode::Create)
+            .unwrap();
+        assert_eq!(
+            Pager::open_file(file, &config, Box::new(journal))
+                .err()
+                .unwrap()
+                .to_string(),
+            "database disk image is malformed: the file is truncated, its header counts 2 pages but it holds 1"
+        );
+    }
+
+    #[test]
+    fn the_header_is_read_again_once_another_pager_commits() {
+        let config = config();
+        let vfs = MemVfs::default();
+        let mut first = open_file(&vfs, &config);
+        let mut second = open_file(&vfs, &config);
+        assert_eq!(second.current_header().unwrap().schema_cookie, 0);
+        first.set_schema_cookie(1);
+        first.flush().unwrap();
+        assert_eq!(second.header().schema_cookie, 0);
+        assert_eq!(second.current_header().unwrap().schema_cookie, 1);
+        assert_eq!(second.lock, LockLevel::Unlocked);
+    }
+
+    #[test]
+    fn synchronous_decides_what_a_commit_syncs() {
+        for (synchronous, syncs) in [
+            (Synchronous::Off, (0, 0)),
+            (Synchronous::Normal, (1, 1)),
+            (Synchronous::Full, (2, 1)),
+        ] {
+            let config = PagerConfig {
+                synchronous,
+                ..config()
+            };
+            let vfs = MemVfs::default();
+            let mut header = DatabaseHeader::new(&config);
+            header.page_count = 2;
+            let open = |name: &str| {
+                let syncs = Arc::new(AtomicUsize::new(0));
+                let file = Synced {
+                    file: vfs.open(Path::new(name), AccessMode::Create).unwrap(),
+                    syncs: syncs.clone(),
+                };
+                (file, syncs)
+            };
+            let (file, file_syncs) = open("test.db");
+            let (journal, journal_syncs) = open("test.db-journal");
+            let mut pager =
+                Pager::with_journal(PageFile::new(file, 512), header, &config, Box::new(journal))
+                    .unwrap();
+            pager.get_page_mut(1, "users").unwrap()[0] = 1;
+            pager.get_page_mut(2, "users").unwrap()[0] = 2;
+            pager.flush().unwrap();
+            assert_eq!(
+                (
+                    journal_syncs.load(Ordering::Relaxed),
+                    file_syncs.load(Ordering::Relaxed)
+                ),
+                syncs,
+                "{synchronous:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,336 @@
+/*
+    Records, SQLite's encoding of a row as bytes, which is what a B+tree cell holds.
+
+    A record is a header followed by a body. The header starts with its own size in bytes,
+    then has each value's serial type, which says what the value is and how many bytes of
+    the body it takes:
+
+        0       NULL, no bytes
+        1 to 6  an integer of 1, 2, 3, 4, 6 or 8 bytes, big endian two's complement
+        7       a float of 8 bytes, big endian IEEE 754
+        8, 9    the integers 0 and 1, no bytes
+        N >= 12 even, a blob of (N - 12) / 2 bytes
+        N >= 13 odd, text of (N - 13) / 2 bytes of UTF-8
+
+    The body is the values one after another with nothing between them. Every number in
+    the header is a varint: big endian, 7 bits a byte with the top bit set on every byte
+    but the last, except that a ninth byte holds all 8 of its bits. Small numbers, and so
+    the serial types of most values, take a single byte.
+
+    Because the header says where every value starts, one column can be read without
+    decoding those before it: Record::parse reads just the header, and Record::column
+    decodes the one value asked for. A record with fewer values than asked for, say one
+    written before ALTER TABLE added a column, has NULL for the ones it lacks.
+
+    A boolean is written as the integer 0 or 1, as SQLite has no booleans of its own.
+*/
+use super::btree::CellData;
+use crate::sql_parser::ast::ColVal;
+use anyhow::{bail, Result};
+
+// How many bytes SQLite's varint encoding of `n` takes, 7 bits a byte for the first 8
+// bytes and all 8 bits of a ninth.
+pub fn varint_size(n: u64) -> usize {
+    match n {
+        0..=0x7f => 1,
+        _ if n >> 56 != 0 => 9,
+        _ => (64 - n.leading_zeros() as usize).div_ceil(7),
+    }
+}
+
+pub fn write_varint(n: u64, out: &mut Vec<u8>) {
+    if n >> 56 != 0 {
+        let mut bytes = [0; 9];
+        bytes[8] = n as u8;
+        let mut rest = n >> 8;
+        for byte in bytes[..8].iter_mut().rev() {
+            *byte = (rest & 0x7f) as u8 | 0x80;
+            rest >>= 7;
+        }
+        out.extend_from_slice(&bytes);
+        return;
+    }
+    for i in (0..varint_size(n)).rev() {
+        let byte = (n >> (7 * i)) as u8 & 0x7f;
+        out.push(if i == 0 { byte } else { byte | 0x80 });
+    }
+}
+
+/// Read a varint off the front of `input`, leaving what follows it.
+pub fn read_varint(input: &mut &[u8]) -> Result<u64> {
+    let mut n = 0;
+    for i in 0..9 {
+        let Some((&byte, rest)) = input.split_first() else {
+            bail!("database disk image is malformed: a varint runs off the end");
+        };
+        *input = rest;
+        if i == 8 {
+            return Ok(n << 8 | byte as u64);
+        }
+        n = n << 7 | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(n)
+}
+
+// A value's serial type in a record header, and how many bytes it takes in the body.
+fn serial_type(value: &ColVal) -> (u64, usize) {
+    match value {
+        ColVal::Null => (0, 0),
+        ColVal::Boolean(false) | ColVal::Int(0) => (8, 0),
+        ColVal::Boolean(true) | ColVal::Int(1) => (9, 0),
+        ColVal::Int(n) => match n {
+            -0x80..=0x7f => (1, 1),
+            -0x8000..=0x7fff => (2, 2),
+            -0x80_0000..=0x7f_ffff => (3, 3),
+            -0x8000_0000..=0x7fff_ffff => (4, 4),
+            -0x8000_0000_0000..=0x7fff_ffff_ffff => (5, 6),
+            _ => (6, 8),
+        },
+        ColVal::Real(_) => (7, 8),
+        ColVal::String(s) => (s.len() as u64 * 2 + 13, s.len()),
+        ColVal::Blob(b) => (b.len() as u64 * 2 + 12, b.len()),
+    }
+}
+
+// How many bytes of the body a value of serial type `serial_type` takes.
+fn body_size(serial_type: u64) -> Result<usize> {
+    Ok(match serial_type {
+        0 | 8 | 9 => 0,
+        1..=4 => serial_type as usize,
+        5 => 6,
+        6 | 7 => 8,
+        10 | 11 => bail!("database disk image is malformed: serial type {serial_type}"),
+        _ => (serial_type as usize - 12) / 2,
+    })
+}
+
+// The size of a header holding serial types that take `types` bytes, which counts the
+// varint it is written in.
+fn header_size(types: usize) -> usize {
+    let mut header = types + 1;
+    while varint_size(header as u64) + types > header {
+        header += 1;
+    }
+    header
+}
+
+/// How many bytes a row takes as an SQLite record: a header of its size and each value's
+/// serial type, then the values.
+pub fn record_size(row: &[ColVal]) -> u64 {
+    let types: usize = row.iter().map(|v| varint_size(serial_type(v).0)).sum();
+    let body: usize = row.iter().map(|v| serial_type(v).1).sum();
+    (header_size(types) + body) as u64
+}
+
+/// A row as a record.
+pub fn encode(row: &[ColVal]) -> Vec<u8> {
+    let types: Vec<u64> = row.iter().map(|v| serial_type(v).0).collect();
+    let mut out = Vec::with_capacity(record_size(row) as usize);
+    write_varint(
+        header_size(types.iter().map(|t| varint_size(*t)).sum()) as u64,
+        &mut out,
+    );
+    for serial_type in &types {
+        write_varint(*serial_type, &mut out);
+    }
+    for value in row {
+        match value {
+            ColVal::Null | ColVal::Boolean(_) | ColVal::Int(0 | 1) => {}
+            ColVal::Int(n) => {
+                let size = serial_type(value).1;
+                out.extend_from_slice(&n.to_be_bytes()[8 - size..]);
+            }
+            ColVal::Real(f) => out.extend_from_slice(&f.to_be_bytes()),
+            ColVal::String(s) => out.extend_from_slice(s.as_bytes()),
+            ColVal::Blob(b) => out.extend_from_slice(b),
+        }
+    }
+    out
+}
+
+/// A record whose header has been read, with its values left to decode one at a time.
+#[derive(Debug)]
+pub struct Record<'a> {
+    bytes: &'a [u8],
+    // each value's serial type and where it starts in `bytes`
+    columns: Vec<(u64, usize)>,
+    size: usize,
+}
+
+impl<'a> Record<'a> {
+    /// The record at the start of `bytes`, which may go on past its end.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let mut header = bytes;
+        let header_size = read_varint(&mut header)? as usize;
+        if header_size > bytes.len() || header_size < bytes.len() - header.len() {
+            bail!("database disk image is malformed: a record header of {header_size} bytes");
+        }
+        let mut types = &bytes[bytes.len() - header.len()..header_size];
+        let mut columns = vec![];
+        let mut offset = header_size;
+        while !types.is_empty() {
+            let serial_type = read_varint(&mut types)?;
+            columns.push((serial_type, offset));
+            let Some(end) = offset.checked_add(body_size(serial_type)?) else {
+                bail!("database disk image is malformed: a record runs off the end");
+            };
+            offset = end;
+        }
+        if offset > bytes.len() {
+            bail!("database disk image is malformed: a record runs off the end");
+        }
+        Ok(Record {
+            bytes,
+            columns,
+            size: offset,
+        })
+    }
+
+    /// How many values the record holds.
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// How many bytes the record takes, header and body.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The value of column `i`, NULL if the record has no such column.
+    pub fn column(&self, i: usize) -> Result<ColVal> {
+        let Some(&(serial_type, offset)) = self.columns.get(i) else {
+            return Ok(ColVal::Null);
+        };
+        let body = &self.bytes[offset..offset + body_size(serial_type)?];
+        Ok(match serial_type {
+            0 => ColVal::Null,
+            1..=6 => {
+                let sign = if body[0] & 0x80 != 0 { -1 } else { 0 };
+                ColVal::Int(body.iter().fold(sign, |n, byte| n << 8 | *byte as i64))
+            }
+            7 => ColVal::Real(f64::from_be_bytes(body.try_into()?)),
+            8 => ColVal::Int(0),
+            9 => ColVal::Int(1),
+            n if n % 2 == 0 => ColVal::Blob(body.to_vec()),
+            _ => match String::from_utf8(body.to_vec()) {
+                Ok(s) => ColVal::String(s),
+                Err(_) => bail!("database disk image is malformed: text that isn't UTF-8"),
+            },
+        })
+    }
+
+    /// Every value in order.
+    pub fn values(&self) -> Result<Vec<ColVal>> {
+        (0..self.len()).map(|i| self.column(i)).collect()
+    }
+}
+
+/// Read the record off the front of `input`, leaving what follows it.
+pub fn decode(input: &mut &[u8]) -> Result<Vec<ColVal>> {
+    let record = Record::parse(input)?;
+    let values = record.values()?;
+    *input = &input[record.size()..];
+    Ok(values)
+}
+
+// Rows, and the keys of WITHOUT ROWID tables, are stored in cells as records.
+impl CellData for Vec<ColVal> {
+    fn write_cell(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&encode(self));
+    }
+
+    fn read_cell(input: &mut &[u8]) -> Result<Self> {
+        decode(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_read_back_as_written() {
+        for n in [
+            0,
+            0x7f,
+            0x80,
+            300,
+            0x3fff,
+            0x4000,
+            1 << 55,
+            (1 << 56) - 1,
+            1 << 56,
+            u64::MAX,
+        ] {
+            let mut bytes = vec![];
+            write_varint(n, &mut bytes);
+            assert_eq!(bytes.len(), varint_size(n), "{n}");
+            let mut input = &bytes[..];
+            assert_eq!(read_varint(&mut input).unwrap(), n);
+            assert!(input.is_empty());
+        }
+        let mut bytes = vec![];
+        write_varint(300, &mut bytes);
+        assert_eq!(bytes, [0x82, 0x2c]);
+        assert!(read_varint(&mut &[0x82][..]).is_err());
+    }
+
+    #[test]
+    fn records_are_encoded_like_sqlite() {
+        // a header of 4 bytes, then 2 bytes of 300 and 5 of "hello"
+        let row = [
+            ColVal::Null,
+            ColVal::Int(300),
+            ColVal::String("hello".to_string()),
+        ];
+        assert_eq!(record_size(&row), 4 + 2 + 5);
+        assert_eq!(encode(&row), b"\x04\x00\x02\x17\x01\x2chello");
+        assert_eq!(record_size(&[ColVal::Int(1), ColVal::Real(0.5)]), 3 + 8);
+        // a string's serial type needs a varint of two bytes past 57 bytes
+        assert_eq!(record_size(&[ColVal::String("x".repeat(100))]), 3 + 100);
+
+        let row = vec![
+            ColVal::Int(-1),
+            ColVal::Int(-0x8000_0000_0000),
+            ColVal::Int(i64::MIN),
+            ColVal::Int(0),
+            ColVal::Real(-2.5),
+            ColVal::String("x".repeat(100)),
+            ColVal::Null,
+            ColVal::Blob(vec![0, 1, 2]),
+            ColVal::Blob(vec![]),
+        ];
+        let bytes = encode(&row);
+        assert_eq!(bytes.len() as u64, record_size(&row));
+        let mut input = &bytes[..];
+        assert_eq!(decode(&mut input).unwrap(), row);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn columns_are_read_one_at_a_time() {
+        let row = [
+            ColVal::String("skipped".to_string()),
+            ColVal::Int(7),
+            ColVal::Boolean(true),
+        ];
+        let mut bytes = encode(&row);
+        bytes.extend_from_slice(b"after");
+        let record = Record::parse(&bytes).unwrap();
+        assert_eq!(record.len(), 3);
+        assert_eq!(record.column(1).unwrap(), ColVal::Int(7));
+        assert_eq!(record.column(2).unwrap(), ColVal::Int(1));
+        // a column added since the record was written
+        assert_eq!(record.column(3).unwrap(), ColVal::Null);
+        assert_eq!(&bytes[record.size()..], b"after");
+
+        // cut short
+        assert!(Record::parse(&bytes[..5]).is_err());
+        // a blob of one byte
+        let record = Record::parse(b"\x02\x0e\xff").unwrap();
+        assert_eq!(record.column(0).unwrap(), ColVal::Blob(vec![0xff]));
+    }
+}
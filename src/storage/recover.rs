@@ -0,0 +1,422 @@
+/*
+    Salvaging the rows of a database file too damaged to open, for the recover command,
+    see cli/recover.rs.
+
+    Opening a file checks its header and then reads it from its first page on, so one bad
+    page is enough to keep the whole database from loading. Salvaging reads the file's
+    bytes a page at a time instead, trusts nothing it hasn't checked, and keeps every row
+    it can make sense of. What it had to skip is described, a line for each damaged page.
+
+    The page size comes from the header if the header is intact, otherwise from its page
+    size field alone if that holds a power of two, and otherwise is taken to be 4096.
+
+    In a file of SQLite's format (see sqlite_file.rs) rows are only ever on table leaf
+    pages, so every page is looked at to see whether it is one: its type byte, cell
+    pointers that land on the page, and cells that are each a rowid and a record that
+    decodes to the last byte, spilling onto overflow pages that are there. A page that
+    says it is a leaf but fails any of that is damaged, and its rows are lost. A leaf
+    belongs to the table whose B-tree reaches it from the root page sqlite_master gives,
+    walking interior pages for as long as they make sense, and one that no table reaches,
+    because an interior page above it is gone, has its rows put in lost_and_found.
+
+    Our own files keep every row in one B+tree instead (see image.rs), whose root is page
+    2. The tree is walked from there over the links of every page that reads back as a
+    node, its children, right links and siblings, and a page that doesn't read is
+    damaged. A damaged inner page cuts off the nodes below it, so every other page that
+    isn't on the freelist is then read too, and the rows of any leaf among them kept, so
+    that a damaged page costs only the rows on it. A row found twice, on a leaf and on a
+    stale copy of it, is kept as the tree reached it.
+*/
+use super::btree;
+use super::cache::PageStore;
+use super::freelist;
+use super::header::{DatabaseHeader, HEADER_SIZE};
+use super::image::{self, ImageRow, ROOT_PAGE};
+use super::overflow::{self, PayloadKind};
+use super::page::MIN_USABLE_SIZE;
+use super::pager::PagerConfig;
+use super::record;
+use super::sqlite_file::{MASTER_ROOT, TABLE_INTERIOR, TABLE_LEAF};
+use super::wal::PageNumber;
+use crate::catalog::MASTER_TABLE;
+use crate::sql_parser::ast::ColVal;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// The table the rows of leaves no table's B-tree reaches are put in, as sqlite3's
+/// .recover does.
+pub const LOST_AND_FOUND: &str = "lost_and_found";
+
+/// What could be read out of a damaged file.
+#[derive(Debug, Default, PartialEq)]
+pub struct Salvage {
+    /// The rows found, sqlite_master's first, each with its table and rowid as an image
+    /// holds them.
+    pub rows: Vec<ImageRow>,
+    /// What was skipped, a line for each damaged page.
+    pub damage: Vec<String>,
+}
+
+/// Every row that can be read out of the database file at `path`, whatever state it is in.
+pub fn salvage(path: &Path) -> Result<Salvage> {
+    let file =
+        std::fs::read(path).with_context(|| format!("cannot open \"{}\"", path.display()))?;
+    if file.len() < HEADER_SIZE {
+        bail!(
+            "file is not a database: {} bytes is too short for its header",
+            file.len()
+        );
+    }
+    let pages = Pages::new(&file);
+    Ok(match file[HEADER_SIZE] {
+        TABLE_LEAF | TABLE_INTERIOR => salvage_sqlite(pages),
+        _ => salvage_tree(pages),
+    })
+}
+
+// The rows of an SQLite file's table leaves, each given to the table whose tree reaches it.
+fn salvage_sqlite(mut pages: Pages) -> Salvage {
+    let mut salvage = Salvage::default();
+    let mut leaves = BTreeMap::new();
+    for number in 1..=pages.count() {
+        match pages.leaf_rows(number) {
+            Ok(Some(rows)) => {
+                leaves.insert(number, rows);
+            }
+            Ok(None) => {}
+            Err(err) => salvage.damage.push(format!("page {number}: {err:#}")),
+        }
+    }
+    let mut claim = |table: &str, root: PageNumber, salvage: &mut Salvage| {
+        for leaf in pages.leaves(root) {
+            let rows = leaves.remove(&leaf).unwrap_or_default();
+            salvage.rows.extend(
+                rows.into_iter()
+                    .map(|(rowid, row)| (table.to_string(), ColVal::Int(rowid), row)),
+            );
+        }
+    };
+    claim(MASTER_TABLE, MASTER_ROOT, &mut salvage);
+    let roots: Vec<(String, PageNumber)> = salvage
+        .rows
+        .iter()
+        .filter_map(|(_, _, row)| match row.as_slice() {
+            [ColVal::String(kind), ColVal::String(name), _, ColVal::Int(root), ..]
+                if kind == "table" =>
+            {
+                Some((name.clone(), PageNumber::try_from(*root).ok()?))
+            }
+            _ => None,
+        })
+        .collect();
+    for (table, root) in roots {
+        claim(&table, root, &mut salvage);
+    }
+    for (rowid, row) in leaves.into_values().flatten() {
+        salvage
+            .rows
+            .push((LOST_AND_FOUND.to_string(), ColVal::Int(rowid), row));
+    }
+    salvage
+}
+
+// The rows of the file's tree, from every leaf that reads back, whether or not the tree
+// still reaches it.
+fn salvage_tree(mut pages: Pages) -> Salvage {
+    let mut salvage = Salvage::default();
+    let reserved = (pages.page_size - pages.usable_size) as u8;
+    let free: HashSet<PageNumber> = match pages.header {
+        Some(header) => match freelist::read(&mut pages, &header) {
+            Ok(free) => free.into_iter().collect(),
+            Err(err) => {
+                salvage.damage.push(format!("the freelist: {err:#}"));
+                HashSet::new()
+            }
+        },
+        None => HashSet::new(),
+    };
+    let mut entries = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut pending = vec![ROOT_PAGE];
+    while let Some(number) = pending.pop() {
+        let Some(page) = pages.page(number).filter(|_| seen.insert(number)) else {
+            continue;
+        };
+        match btree::read_page::<Vec<u8>, Vec<u8>>(page.to_vec(), reserved, &mut pages) {
+            Ok((found, links)) => {
+                for (key, value) in found {
+                    entries.entry(key).or_insert(value);
+                }
+                pending.extend(links.into_iter().rev());
+            }
+            Err(err) => salvage.damage.push(format!("page {number}: {err:#}")),
+        }
+    }
+    // the leaves cut off from the tree, the pages that aren't nodes reading as none
+    for number in ROOT_PAGE..=pages.count() {
+        if seen.contains(&number) || free.contains(&number) {
+            continue;
+        }
+        let page = pages.page(number).expect("a page of the file").to_vec();
+        if let Ok((found, _)) = btree::read_page::<Vec<u8>, Vec<u8>>(page, reserved, &mut pages) {
+            for (key, value) in found {
+                entries.entry(key).or_insert(value);
+            }
+        }
+    }
+    let mut rows = vec![];
+    for (key, value) in entries {
+        match image::tree_row(&key, &value) {
+            Ok(row) => rows.push(row),
+            Err(err) => salvage.damage.push(format!("a row: {err:#}")),
+        }
+    }
+    salvage.rows = image::in_load_order(rows);
+    salvage
+}
+
+// The file's pages, as big as its header says if the header can be believed.
+struct Pages<'a> {
+    file: &'a [u8],
+    page_size: usize,
+    usable_size: usize,
+    // the header, if it is intact
+    header: Option<DatabaseHeader>,
+}
+
+// The rows of a table leaf, by rowid.
+type LeafRows = Vec<(i64, Vec<ColVal>)>;
+
+impl<'a> Pages<'a> {
+    fn new(file: &'a [u8]) -> Self {
+        if let Ok(header) = DatabaseHeader::parse(file) {
+            return Pages {
+                file,
+                page_size: header.page_size as usize,
+                usable_size: header.usable_size(),
+                header: Some(header),
+            };
+        }
+        let page_size = match u16::from_be_bytes([file[16], file[17]]) {
+            1 => 65536,
+            size if size >= 512 && size.is_power_of_two() => size as usize,
+            _ => PagerConfig::default().page_size as usize,
+        };
+        let reserved = match file[20] as usize {
+            reserved if page_size - reserved >= MIN_USABLE_SIZE => reserved,
+            _ => 0,
+        };
+        Pages {
+            file,
+            page_size,
+            usable_size: page_size - reserved,
+            header: None,
+        }
+    }
+
+    // How many whole pages the file holds.
+    fn count(&self) -> PageNumber {
+        (self.file.len() / self.page_size) as PageNumber
+    }
+
+    fn page(&self, number: PageNumber) -> Option<&'a [u8]> {
+        let start = (number as usize).checked_sub(1)? * self.page_size;
+        self.file.get(start..start + self.page_size)
+    }
+
+    // The rows on page `number` if it is a table's leaf, None if it is some other page,
+    // and an error if it says it is a leaf but isn't laid out as one.
+    fn leaf_rows(&mut self, number: PageNumber) -> Result<Option<LeafRows>> {
+        let Some(page) = self.page(number) else {
+            bail!("no page {number}");
+        };
+        let at = if number == MASTER_ROOT {
+            HEADER_SIZE
+        } else {
+            0
+        };
+        if page[at] != TABLE_LEAF {
+            return Ok(None);
+        }
+        let cells = u16::from_be_bytes([page[at + 3], page[at + 4]]) as usize;
+        let pointers = at + 8;
+        let content = pointers + 2 * cells;
+        if content > self.usable_size {
+            bail!("{cells} cells don't fit on the page");
+        }
+        let mut rows = vec![];
+        for i in 0..cells {
+            let offset =
+                u16::from_be_bytes([page[pointers + 2 * i], page[pointers + 2 * i + 1]]) as usize;
+            if !(content..self.usable_size).contains(&offset) {
+                bail!("cell {i} is at {offset}, off the cell content area");
+            }
+            let mut cell = &page[offset..self.usable_size];
+            let size = record::read_varint(&mut cell)? as usize;
+            let rowid = record::read_varint(&mut cell)? as i64;
+            let (payload, _) =
+                overflow::join(cell, size, self.usable_size, PayloadKind::Table, self)
+                    .with_context(|| format!("cell {i}"))?;
+            let mut input = payload.as_slice();
+            let row = record::decode(&mut input).with_context(|| format!("cell {i}"))?;
+            if !input.is_empty() {
+                bail!("cell {i} has {} bytes past its record", input.len());
+            }
+            rows.push((rowid, row));
+        }
+        Ok(Some(rows))
+    }
+
+    // The leaves of the B-tree whose root is page `root`, left to right, as far as its
+    // interior pages can be read.
+    fn leaves(&self, root: PageNumber) -> Vec<PageNumber> {
+        let mut leaves = vec![];
+        let mut seen = HashSet::new();
+        let mut pending = vec![root];
+        while let Some(number) = pending.pop() {
+            let Some(page) = self.page(number).filter(|_| seen.insert(number)) else {
+                continue;
+            };
+            let at = if number == MASTER_ROOT {
+                HEADER_SIZE
+            } else {
+                0
+            };
+            match page[at] {
+                TABLE_LEAF => leaves.push(number),
+                TABLE_INTERIOR => {
+                    let cells = u16::from_be_bytes([page[at + 3], page[at + 4]]) as usize;
+                    let child = |offset: usize| {
+                        let bytes = page.get(offset..offset + 4)?;
+                        Some(PageNumber::from_be_bytes(bytes.try_into().unwrap()))
+                    };
+                    // the right-most child last, so that it is read after the others
+                    let mut children: Vec<PageNumber> = (0..cells)
+                        .map_while(|i| {
+                            let pointer = page.get(at + 12 + 2 * i..at + 14 + 2 * i)?;
+                            child(u16::from_be_bytes([pointer[0], pointer[1]]) as usize)
+                        })
+                        .collect();
+                    children.extend(child(at + 8));
+                    pending.extend(children.into_iter().rev());
+                }
+                _ => {}
+            }
+        }
+        leaves
+    }
+}
+
+impl PageStore for Pages<'_> {
+    fn read_page(&mut self, number: PageNumber) -> Result<Vec<u8>> {
+        match self.page(number) {
+            Some(page) => Ok(page.to_vec()),
+            None => bail!("no page {number} in a file of {}", self.count()),
+        }
+    }
+
+    fn write_page(&mut self, _page: PageNumber, _data: &[u8]) -> Result<()> {
+        bail!("attempt to write a readonly database")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use crate::storage::compress::Compression;
+    use crate::storage::image::DatabaseFile;
+    use crate::storage::memdb::AccessMode;
+
+    fn row(table: &str, key: i64, values: &[ColVal]) -> ImageRow {
+        (table.to_string(), ColVal::Int(key), values.to_vec())
+    }
+
+    #[test]
+    fn rows_are_found_with_their_schema_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut config = PagerConfig::default();
+        config.set_page_size(512);
+        let create = ColVal::String("CREATE TABLE t (a INTEGER PRIMARY KEY, b TEXT)".into());
+        let entry = [
+            ColVal::String("table".into()),
+            ColVal::String("t".into()),
+            ColVal::String("t".into()),
+            ColVal::Int(2),
+            create,
+        ];
+        let mut rows = vec![row(MASTER_TABLE, 1, &entry)];
+        // enough rows for the tree to need several leaves
+        for key in 1..=100 {
+            rows.push(row(
+                "t",
+                key,
+                &[ColVal::Null, ColVal::String(format!("row {key}"))],
+            ));
+        }
+        let mut file =
+            DatabaseFile::open(&path, AccessMode::Create, Compression::None, &config).unwrap();
+        file.prepare(&rows, None, None).unwrap();
+        file.finish().unwrap();
+        drop(file);
+        assert_eq!(
+            salvage(&path).unwrap(),
+            Salvage {
+                rows: rows.clone(),
+                damage: vec![]
+            }
+        );
+
+        // with the root gone every leaf is still found
+        let intact = std::fs::read(&path).unwrap();
+        let mut bytes = intact.clone();
+        bytes[512..1024].fill(0xff);
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            salvage(&path).unwrap(),
+            Salvage {
+                rows: rows.clone(),
+                damage: vec![
+                    "page 2: database disk image is malformed: bad cell layout".to_string()
+                ]
+            }
+        );
+
+        // the leaf holding the schema is lost, and the rows that shared it with it
+        let leaf = intact
+            .chunks(512)
+            .position(|page| page.windows(12).any(|bytes| bytes == b"CREATE TABLE"))
+            .unwrap();
+        let mut bytes = intact.clone();
+        bytes[leaf * 512..(leaf + 1) * 512].fill(0xff);
+        std::fs::write(&path, &bytes).unwrap();
+        let Salvage {
+            rows: found,
+            damage,
+        } = salvage(&path).unwrap();
+        assert_eq!(
+            damage,
+            [format!(
+                "page {}: database disk image is malformed: bad cell layout",
+                leaf + 1
+            )]
+        );
+        assert!(found.len() > 50 && found.len() < 100, "{}", found.len());
+        assert!(found.iter().all(|row| rows.contains(row)));
+
+        // made again with columns of its own, and a row that is there twice left out
+        let mut lost = vec![];
+        let twice = found[0].clone();
+        let mut executor = Executor::recovered([found, vec![twice]].concat(), &mut lost);
+        assert_eq!(
+            lost,
+            ["1 row of t, the first: UNIQUE constraint failed: t.rowid"]
+        );
+        let first = executor
+            .execute_sql("SELECT c1 FROM t WHERE rowid = 2;")
+            .unwrap();
+        assert_eq!(first.to_string(), "row 2");
+    }
+}
@@ -0,0 +1,293 @@
+/*
+    Replacement policies, how the page cache picks the page to evict when it is full.
+
+    The cache tells its policy about every page that comes into it, every hit on a page
+    already there and every page that leaves, and asks it for a victim when it needs room.
+    The policy never sees the pages themselves, only their numbers, and is told which are
+    pinned so it can pass over them. Three come with the cache, picked with PRAGMA
+    cache_replacement, see pragma.rs:
+
+    LRU evicts the page that has gone unused the longest. It is the default and does well
+    until a scan comes along: every page of a big table scan is used once and once only,
+    but each one is the most recently used page while it lasts, so a scan of more pages
+    than the cache holds pushes out every other page, however hot.
+
+    Clock approximates LRU more cheaply. The pages sit in a ring with a bit each that a
+    hit sets. The hand goes round looking for a victim, clearing the bit of each page it
+    passes that has one, so a page is evicted once it has gone a whole turn of the hand
+    without being used. Scans push it around much as they do LRU.
+
+    2Q resists scans. A page comes in on a FIFO queue of pages seen once and only moves to
+    the LRU list of the main queue when it is used again while still cached. Victims come
+    from the FIFO queue first while it holds more than a quarter of the cache, so a scan
+    churns through the FIFO queue and leaves the pages in the main queue alone. This is
+    the simplified 2Q of Johnson and Shasha's paper, without the queue of pages recently
+    evicted.
+*/
+use super::wal::PageNumber;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+
+/// Which pages the cache keeps when it is full.
+pub trait ReplacementPolicy: fmt::Debug + Send {
+    /// Page `page` has come into the cache.
+    fn admit(&mut self, page: PageNumber);
+    /// Page `page`, already cached, has been used again.
+    fn touch(&mut self, page: PageNumber);
+    /// Page `page` has left the cache.
+    fn remove(&mut self, page: PageNumber);
+    /// The page to evict from a cache of `capacity` pages, passing over those `pinned`
+    /// says are pinned. The page stays cached until `remove`.
+    fn victim(
+        &mut self,
+        capacity: usize,
+        pinned: &dyn Fn(PageNumber) -> bool,
+    ) -> Option<PageNumber>;
+}
+
+/// The replacement policies there are, by name.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Replacement {
+    #[default]
+    Lru,
+    Clock,
+    TwoQueue,
+}
+
+impl Replacement {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name.to_lowercase().as_str() {
+            "lru" => Replacement::Lru,
+            "clock" => Replacement::Clock,
+            "2q" => Replacement::TwoQueue,
+            _ => return None,
+        })
+    }
+
+    pub fn policy(self) -> Box<dyn ReplacementPolicy> {
+        match self {
+            Replacement::Lru => Box::new(Lru::default()),
+            Replacement::Clock => Box::new(Clock::default()),
+            Replacement::TwoQueue => Box::new(TwoQueue::default()),
+        }
+    }
+}
+
+impl fmt::Display for Replacement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Replacement::Lru => "lru",
+            Replacement::Clock => "clock",
+            Replacement::TwoQueue => "2q",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Lru {
+    // the pages by when they were last used, least recently first
+    recency: BTreeMap<u64, PageNumber>,
+    last_used: HashMap<PageNumber, u64>,
+    clock: u64,
+}
+
+impl Lru {
+    fn contains(&self, page: PageNumber) -> bool {
+        self.last_used.contains_key(&page)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.last_used.is_empty()
+    }
+}
+
+impl ReplacementPolicy for Lru {
+    fn admit(&mut self, page: PageNumber) {
+        self.touch(page);
+    }
+
+    fn touch(&mut self, page: PageNumber) {
+        self.clock += 1;
+        if let Some(used) = self.last_used.insert(page, self.clock) {
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.clock, page);
+    }
+
+    fn remove(&mut self, page: PageNumber) {
+        if let Some(used) = self.last_used.remove(&page) {
+            self.recency.remove(&used);
+        }
+    }
+
+    fn victim(
+        &mut self,
+        _capacity: usize,
+        pinned: &dyn Fn(PageNumber) -> bool,
+    ) -> Option<PageNumber> {
+        self.recency.values().find(|page| !pinned(**page)).copied()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Clock {
+    // the ring, the hand pointing at the front
+    ring: VecDeque<PageNumber>,
+    // each page's reference bit
+    referenced: HashMap<PageNumber, bool>,
+}
+
+impl ReplacementPolicy for Clock {
+    fn admit(&mut self, page: PageNumber) {
+        if self.referenced.insert(page, false).is_none() {
+            self.ring.push_back(page);
+        }
+    }
+
+    fn touch(&mut self, page: PageNumber) {
+        if let Some(bit) = self.referenced.get_mut(&page) {
+            *bit = true;
+        }
+    }
+
+    fn remove(&mut self, page: PageNumber) {
+        if self.referenced.remove(&page).is_some() {
+            self.ring.retain(|p| *p != page);
+        }
+    }
+
+    fn victim(
+        &mut self,
+        _capacity: usize,
+        pinned: &dyn Fn(PageNumber) -> bool,
+    ) -> Option<PageNumber> {
+        // two turns clear every bit, so a page not found by then is pinned
+        for _ in 0..2 * self.ring.len() {
+            let page = *self.ring.front()?;
+            let bit = self.referenced.get_mut(&page).expect("a page in the ring");
+            if !*bit && !pinned(page) {
+                return Some(page);
+            }
+            *bit = false;
+            self.ring.rotate_left(1);
+        }
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TwoQueue {
+    // pages seen once, oldest first
+    fifo: VecDeque<PageNumber>,
+    // pages used again while on the FIFO queue
+    main: Lru,
+}
+
+impl ReplacementPolicy for TwoQueue {
+    fn admit(&mut self, page: PageNumber) {
+        if !self.main.contains(page) && !self.fifo.contains(&page) {
+            self.fifo.push_back(page);
+        }
+    }
+
+    fn touch(&mut self, page: PageNumber) {
+        if let Some(at) = self.fifo.iter().position(|p| *p == page) {
+            self.fifo.remove(at);
+            self.main.admit(page);
+        } else {
+            self.main.touch(page);
+        }
+    }
+
+    fn remove(&mut self, page: PageNumber) {
+        self.fifo.retain(|p| *p != page);
+        self.main.remove(page);
+    }
+
+    fn victim(
+        &mut self,
+        capacity: usize,
+        pinned: &dyn Fn(PageNumber) -> bool,
+    ) -> Option<PageNumber> {
+        let from_fifo = self.fifo.iter().find(|page| !pinned(**page)).copied();
+        if self.fifo.len() > (capacity / 4).max(1) || self.main.is_empty() {
+            from_fifo.or_else(|| self.main.victim(capacity, pinned))
+        } else {
+            self.main.victim(capacity, pinned).or(from_fifo)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run `pages` through a cache of `capacity` pages under `policy`, returning the pages
+    // it evicted in order.
+    fn evicted(policy: Replacement, capacity: usize, pages: &[PageNumber]) -> Vec<PageNumber> {
+        let mut policy = policy.policy();
+        let mut cached = vec![];
+        let mut evicted = vec![];
+        for &page in pages {
+            if cached.contains(&page) {
+                policy.touch(page);
+                continue;
+            }
+            if cached.len() == capacity {
+                let victim = policy.victim(capacity, &|_| false).unwrap();
+                policy.remove(victim);
+                cached.retain(|p| *p != victim);
+                evicted.push(victim);
+            }
+            policy.admit(page);
+            cached.push(page);
+        }
+        evicted
+    }
+
+    #[test]
+    fn lru_evicts_the_page_unused_longest() {
+        assert_eq!(
+            evicted(Replacement::Lru, 3, &[1, 2, 3, 1, 4, 2, 5]),
+            [2, 3, 1]
+        );
+    }
+
+    #[test]
+    fn clock_gives_used_pages_a_second_chance() {
+        // 1 has its bit set, so the hand clears it and takes 2, then 3, then 1 as 4 and 5
+        // came in unused
+        assert_eq!(
+            evicted(Replacement::Clock, 3, &[1, 2, 3, 1, 4, 5, 6]),
+            [2, 3, 1]
+        );
+    }
+
+    #[test]
+    fn two_queue_keeps_pages_used_twice_through_a_scan() {
+        let mut pages = vec![1, 2, 1, 2];
+        pages.extend(100..120);
+        pages.extend([1, 2]);
+        let evicted_2q = evicted(Replacement::TwoQueue, 4, &pages);
+        assert!(!evicted_2q.contains(&1) && !evicted_2q.contains(&2));
+        assert!(evicted(Replacement::Lru, 4, &pages).contains(&1));
+    }
+
+    #[test]
+    fn pinned_pages_are_passed_over() {
+        for replacement in [Replacement::Lru, Replacement::Clock, Replacement::TwoQueue] {
+            let mut policy = replacement.policy();
+            for page in 1..=3 {
+                policy.admit(page);
+            }
+            assert_eq!(
+                policy.victim(3, &|page| page != 2),
+                Some(2),
+                "{replacement}"
+            );
+            assert_eq!(policy.victim(3, &|_| true), None, "{replacement}");
+        }
+        assert_eq!(Replacement::parse("2Q"), Some(Replacement::TwoQueue));
+    }
+}
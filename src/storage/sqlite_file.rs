@@ -0,0 +1,613 @@
+/*
+    Database files in SQLite's own format, read so that a file SQLite wrote can be opened
+    and queried, and written so that sqlite3 can open one of ours.
+
+    Our own files keep every table's rows in one B+tree (see image.rs), but an SQLite file
+    keeps each table in a B-tree of its own pages. The header (see header.rs) and the records
+    in the cells (see record.rs) are already laid out as SQLite lays them out, so what is
+    left is the B-tree pages themselves. A page starts with a header, after the database
+    header on page 1:
+
+        0  u8   page type: 0x05 a table's interior page, 0x0d a table's leaf, 0x02 and
+                0x0a the same for an index
+        1  u16  first freeblock
+        3  u16  number of cells
+        5  u16  start of the cell content area
+        7  u8   fragmented bytes
+        8  u32  the right-most child, on an interior page only
+
+    followed by a u16 offset for each cell, in key order. A table's leaf cell is the
+    payload's size and the rowid as varints and then the payload, the row's record,
+    spilling onto an overflow chain if it is too big (see overflow.rs, which follows
+    SQLite's rules for how much stays in the cell). An interior cell is the page of its
+    left child and the largest rowid under it, and the rows are read by walking the tree
+    from its root left to right.
+
+    sqlite_master is the table whose root is page 1, and reading a file gives its rows
+    and then every table's, in the form of an image, so a connection loads an SQLite file
+    as it loads one of ours. Indexes are built again from the rows rather than read.
+
+    The file is read once, and a connection on it refuses writes unless it was opened
+    with format=sqlite (see memdb.rs), in which case every save writes the whole file
+    again in SQLite's format. Writing builds each B-tree from the bottom up: the rows,
+    or the index's entries, are laid into leaves in order, each leaf as full as it will
+    go, and then the level above is made of cells pointing at them, and so on until one
+    page holds the rest. A table's interior cell holds the largest rowid under its child,
+    every row being in a leaf, but an index's B-tree keeps each entry once, so the entry
+    after each full page goes up to the level above instead, as the divider between that
+    page and the next. sqlite_master is written last, so that it can give every tree's
+    root page, with its root on page 1. The new file is written beside the old one and
+    renamed over it, so a crash leaves one or the other.
+
+    Tables WITHOUT ROWID, kept in index B-trees, and a file whose write-ahead log
+    still holds commits that aren't in it can be neither read nor written yet.
+*/
+use super::cache::PageStore;
+use super::header::{DatabaseHeader, HEADER_SIZE};
+use super::image::ImageRow;
+use super::memdb::AccessMode;
+use super::os_interface::{OsFile, OsVfs, Vfs, VfsFile};
+use super::overflow::{self, PayloadKind};
+use super::pager::PagerConfig;
+use super::record;
+use super::wal::PageNumber;
+use crate::catalog::MASTER_TABLE;
+use crate::sql_parser::ast::ColVal;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+
+pub(super) const TABLE_INTERIOR: u8 = 0x05;
+pub(super) const TABLE_LEAF: u8 = 0x0d;
+const INDEX_INTERIOR: u8 = 0x02;
+const INDEX_LEAF: u8 = 0x0a;
+// sqlite_master's root
+pub(super) const MASTER_ROOT: PageNumber = 1;
+
+/// Whether `path` is a database file SQLite wrote rather than one of ours: page 1 holds
+/// sqlite_master's B-tree after the header, where ours holds nothing.
+pub fn is_sqlite_file(path: &Path) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let mut file = OsVfs.open(path, AccessMode::ReadOnly)?;
+    let mut bytes = [0; HEADER_SIZE + 1];
+    if file.read_at(0, &mut bytes)? < bytes.len() || DatabaseHeader::parse(&bytes).is_err() {
+        return Ok(false);
+    }
+    Ok(matches!(bytes[HEADER_SIZE], TABLE_LEAF | TABLE_INTERIOR))
+}
+
+/// The rows of sqlite_master and then of every table in the SQLite file at `path`.
+pub fn read(path: &Path) -> Result<Vec<ImageRow>> {
+    let mut wal = path.as_os_str().to_owned();
+    wal.push("-wal");
+    if std::fs::metadata(&wal).is_ok_and(|wal| wal.len() > 0) {
+        bail!(
+            "cannot read \"{}\": its write-ahead log holds commits that aren't in it yet",
+            path.display()
+        );
+    }
+    let mut file = SqliteFile::open(path)?;
+    let mut rows: Vec<ImageRow> = file
+        .table_rows(MASTER_ROOT)
+        .context("cannot read sqlite_master")?
+        .into_iter()
+        .map(|(rowid, row)| (MASTER_TABLE.to_string(), ColVal::Int(rowid), row))
+        .collect();
+    let tables: Vec<(String, PageNumber)> = rows
+        .iter()
+        .filter_map(|(_, _, row)| match row.as_slice() {
+            [ColVal::String(kind), ColVal::String(name), _, ColVal::Int(root), ..]
+                if kind == "table" && *root > 0 =>
+            {
+                Some((name.clone(), *root as PageNumber))
+            }
+            _ => None,
+        })
+        .collect();
+    for (table, root) in tables {
+        let table_rows = file
+            .table_rows(root)
+            .with_context(|| format!("cannot read table {table}"))?;
+        rows.extend(
+            table_rows
+                .into_iter()
+                .map(|(rowid, row)| (table.clone(), ColVal::Int(rowid), row)),
+        );
+    }
+    Ok(rows)
+}
+
+struct SqliteFile {
+    file: OsFile,
+    page_size: usize,
+    usable_size: usize,
+    page_count: PageNumber,
+}
+
+impl SqliteFile {
+    fn open(path: &Path) -> Result<Self> {
+        let mut file = OsVfs
+            .open(path, AccessMode::ReadOnly)
+            .with_context(|| format!("unable to open database file \"{}\"", path.display()))?;
+        let Some(header) = DatabaseHeader::read(&mut file)? else {
+            bail!("file is not a database");
+        };
+        // a header whose page count is 0 was written by a version of SQLite that didn't
+        // keep it, and the file's size says instead
+        let page_count = match header.page_count {
+            0 => (file.size()? / header.page_size as u64) as PageNumber,
+            count => count,
+        };
+        Ok(SqliteFile {
+            file,
+            page_size: header.page_size as usize,
+            usable_size: header.usable_size(),
+            page_count,
+        })
+    }
+
+    // The rows of the table whose B-tree starts at `root`, in rowid order.
+    fn table_rows(&mut self, root: PageNumber) -> Result<Vec<(i64, Vec<ColVal>)>> {
+        let mut rows = vec![];
+        let mut seen = HashSet::new();
+        self.walk(root, &mut seen, &mut rows)?;
+        Ok(rows)
+    }
+
+    fn walk(
+        &mut self,
+        number: PageNumber,
+        seen: &mut HashSet<PageNumber>,
+        rows: &mut Vec<(i64, Vec<ColVal>)>,
+    ) -> Result<()> {
+        if !seen.insert(number) {
+            bail!("database disk image is malformed: page {number} is in the tree twice");
+        }
+        let page = self.read_page(number)?;
+        let at = if number == 1 { HEADER_SIZE } else { 0 };
+        let malformed = || anyhow!("database disk image is malformed: page {number}");
+        let header = page.get(at..at + 12).ok_or_else(malformed)?;
+        let kind = header[0];
+        let cells = u16::from_be_bytes([header[3], header[4]]) as usize;
+        let pointers = at
+            + match kind {
+                TABLE_INTERIOR => 12,
+                TABLE_LEAF => 8,
+                INDEX_INTERIOR | INDEX_LEAF => {
+                    bail!("WITHOUT ROWID tables can't be read from an SQLite file yet")
+                }
+                kind => bail!("database disk image is malformed: page {number} is of type {kind}"),
+            };
+        for i in 0..cells {
+            let pointer = page
+                .get(pointers + 2 * i..pointers + 2 * i + 2)
+                .ok_or_else(malformed)?;
+            let offset = u16::from_be_bytes([pointer[0], pointer[1]]) as usize;
+            let mut cell = page.get(offset..self.usable_size).ok_or_else(malformed)?;
+            if kind == TABLE_INTERIOR {
+                let child = cell.get(..4).ok_or_else(malformed)?;
+                self.walk(PageNumber::from_be_bytes(child.try_into()?), seen, rows)?;
+                continue;
+            }
+            let size = record::read_varint(&mut cell)? as usize;
+            let rowid = record::read_varint(&mut cell)? as i64;
+            let (payload, _) =
+                overflow::join(cell, size, self.usable_size, PayloadKind::Table, self)
+                    .with_context(malformed)?;
+            rows.push((rowid, record::decode(&mut payload.as_slice())?));
+        }
+        if kind == TABLE_INTERIOR {
+            let right = PageNumber::from_be_bytes(header[8..12].try_into()?);
+            self.walk(right, seen, rows)?;
+        }
+        Ok(())
+    }
+}
+
+impl PageStore for SqliteFile {
+    fn read_page(&mut self, number: PageNumber) -> Result<Vec<u8>> {
+        if number == 0 || number > self.page_count {
+            bail!(
+                "database disk image is malformed: no page {number} in a file of {}",
+                self.page_count
+            );
+        }
+        let mut page = vec![0; self.page_size];
+        let offset = (number as u64 - 1) * self.page_size as u64;
+        if self.file.read_at(offset, &mut page)? < self.page_size {
+            bail!("database disk image is malformed: page {number} is cut short");
+        }
+        Ok(page)
+    }
+
+    fn write_page(&mut self, _page: PageNumber, _data: &[u8]) -> Result<()> {
+        bail!("attempt to write a readonly database")
+    }
+}
+
+/// What one of the file's B-trees holds: a table's rows by rowid, or an index's entries in
+/// order, each the indexed values followed by the rowid.
+#[derive(Debug, PartialEq)]
+pub enum Tree {
+    Table(Vec<(i64, Vec<ColVal>)>),
+    Index(Vec<Vec<ColVal>>),
+}
+
+/// Write a database to `path` in SQLite's format, replacing what is there: `catalog` is
+/// the rows of its sqlite_master in order, each with the tree of its table or index if
+/// it has one, whose root page the row is written with.
+pub fn write(
+    path: &Path,
+    config: &PagerConfig,
+    schema_cookie: u32,
+    catalog: Vec<(Vec<ColVal>, Option<Tree>)>,
+) -> Result<()> {
+    let mut header = DatabaseHeader::new(config);
+    header.wal = false;
+    header.schema_cookie = schema_cookie;
+    let mut builder = Builder {
+        page_size: header.page_size as usize,
+        usable_size: header.usable_size(),
+        // page 1 is kept for sqlite_master's root
+        pages: vec![vec![0; header.page_size as usize]],
+    };
+    let mut master = vec![];
+    for (mut row, tree) in catalog {
+        if let Some(tree) = tree {
+            let Some(root) = row.get_mut(3) else {
+                bail!("malformed database schema: {row:?}");
+            };
+            *root = ColVal::Int(builder.tree(tree, None).into());
+        }
+        master.push((master.len() as i64 + 1, row));
+    }
+    builder.tree(Tree::Table(master), Some(MASTER_ROOT));
+
+    header.page_count = builder.pages.len() as PageNumber;
+    builder.pages[0][..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push("-new");
+    let written = (|| -> std::io::Result<()> {
+        let mut file = std::fs::File::create(&temporary)?;
+        for page in &builder.pages {
+            file.write_all(page)?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)
+    })();
+    written.with_context(|| format!("cannot write \"{}\"", path.display()))
+}
+
+// The pages of a file being written, page 1 first.
+struct Builder {
+    page_size: usize,
+    usable_size: usize,
+    pages: Vec<Vec<u8>>,
+}
+
+// A cell on its way into an interior page: the page to the left of its key, and the key,
+// which is the largest rowid under that page in a table and an entry in an index.
+struct Divider {
+    child: PageNumber,
+    key: Vec<u8>,
+}
+
+impl Builder {
+    fn allocate(&mut self) -> PageNumber {
+        self.pages.push(vec![0; self.page_size]);
+        self.pages.len() as PageNumber
+    }
+
+    // The bytes of `payload` a cell holds, the rest written to overflow pages if it
+    // doesn't all fit.
+    fn spill(&mut self, payload: &[u8], kind: PayloadKind) -> Vec<u8> {
+        let (local, chain) =
+            overflow::split(payload, self.usable_size, kind, &mut || self.allocate());
+        for (number, data) in chain {
+            self.pages[number as usize - 1][..data.len()].copy_from_slice(&data);
+        }
+        local
+    }
+
+    // Write a B-tree, returning its root page, which is `root` if that is given.
+    fn tree(&mut self, tree: Tree, root: Option<PageNumber>) -> PageNumber {
+        // the root of sqlite_master shares page 1 with the header, and any page may be it
+        let room = |interior: bool| {
+            let header = if interior { 12 } else { 8 };
+            let shared = if root == Some(MASTER_ROOT) {
+                HEADER_SIZE
+            } else {
+                0
+            };
+            self.usable_size - header - shared
+        };
+        let (leaf_room, interior_room) = (room(false), room(true));
+        let (kind, leaves, mut dividers) = match tree {
+            Tree::Table(rows) => {
+                let cells: Vec<(i64, Vec<u8>)> = rows
+                    .into_iter()
+                    .map(|(rowid, row)| {
+                        let payload = record::encode(&row);
+                        let mut cell = vec![];
+                        record::write_varint(payload.len() as u64, &mut cell);
+                        record::write_varint(rowid as u64, &mut cell);
+                        cell.extend(self.spill(&payload, PayloadKind::Table));
+                        (rowid, cell)
+                    })
+                    .collect();
+                // every row is in a leaf, and each leaf but the last goes up with its
+                // largest rowid
+                let pages = pack(cells, leaf_room, |(_, cell)| cell.len(), false).0;
+                let dividers = pages
+                    .iter()
+                    .map(|page| page.last().map_or(0, |(rowid, _)| *rowid))
+                    .collect::<Vec<i64>>();
+                let leaves: Vec<Vec<Vec<u8>>> = pages
+                    .into_iter()
+                    .map(|page| page.into_iter().map(|(_, cell)| cell).collect())
+                    .collect();
+                let dividers = dividers[..leaves.len() - 1]
+                    .iter()
+                    .map(|rowid| {
+                        let mut key = vec![];
+                        record::write_varint(*rowid as u64, &mut key);
+                        key
+                    })
+                    .collect();
+                (TABLE_LEAF, leaves, dividers)
+            }
+            Tree::Index(entries) => {
+                let cells: Vec<Vec<u8>> = entries
+                    .iter()
+                    .map(|entry| {
+                        let payload = record::encode(entry);
+                        let mut cell = vec![];
+                        record::write_varint(payload.len() as u64, &mut cell);
+                        cell.extend(self.spill(&payload, PayloadKind::Index));
+                        cell
+                    })
+                    .collect();
+                let (leaves, dividers) = pack(cells, leaf_room, Vec::len, true);
+                (INDEX_LEAF, leaves, dividers)
+            }
+        };
+        let interior = if kind == TABLE_LEAF {
+            TABLE_INTERIOR
+        } else {
+            INDEX_INTERIOR
+        };
+
+        let mut level: Vec<PageNumber> = vec![];
+        let last = leaves.len() - 1;
+        for (i, cells) in leaves.into_iter().enumerate() {
+            let number = match (i == last && dividers.is_empty(), root) {
+                (true, Some(root)) => root,
+                _ => self.allocate(),
+            };
+            self.write_page(number, kind, &cells, None);
+            level.push(number);
+        }
+        // each level up is the dividers between the pages of the one below it
+        while level.len() > 1 {
+            let right = level.pop().expect("more than one page");
+            let entries: Vec<Divider> = level
+                .into_iter()
+                .zip(dividers)
+                .map(|(child, key)| Divider { child, key })
+                .collect();
+            let (pages, up) = pack(entries, interior_room, |d| d.key.len() + 4, true);
+            let last = pages.len() - 1;
+            level = vec![];
+            let mut rights = up.iter().map(|d| d.child).chain([right]);
+            for (i, divider) in pages.into_iter().enumerate() {
+                let number = match (i == last && up.is_empty(), root) {
+                    (true, Some(root)) => root,
+                    _ => self.allocate(),
+                };
+                let cells: Vec<Vec<u8>> = divider
+                    .into_iter()
+                    .map(|d| [d.child.to_be_bytes().to_vec(), d.key].concat())
+                    .collect();
+                let right = rights.next().expect("a right child for every page");
+                self.write_page(number, interior, &cells, Some(right));
+                level.push(number);
+            }
+            dividers = up.into_iter().map(|d| d.key).collect();
+        }
+        level[0]
+    }
+
+    // Lay `cells` out on page `number`, with `right` its right-most child if it is an
+    // interior page.
+    fn write_page(
+        &mut self,
+        number: PageNumber,
+        kind: u8,
+        cells: &[Vec<u8>],
+        right: Option<PageNumber>,
+    ) {
+        let at = if number == MASTER_ROOT {
+            HEADER_SIZE
+        } else {
+            0
+        };
+        let pointers = at + if right.is_some() { 12 } else { 8 };
+        let page = &mut self.pages[number as usize - 1];
+        let mut content = self.usable_size;
+        for (i, cell) in cells.iter().enumerate() {
+            content -= cell.len();
+            page[content..content + cell.len()].copy_from_slice(cell);
+            let pointer = pointers + 2 * i;
+            page[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+        }
+        page[at] = kind;
+        page[at + 3..at + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        // 65536 doesn't fit in a u16 and is written as 0
+        page[at + 5..at + 7].copy_from_slice(&(content as u16).to_be_bytes());
+        if let Some(right) = right {
+            page[at + 8..at + 12].copy_from_slice(&right.to_be_bytes());
+        }
+    }
+}
+
+// Split `items` into pages of at most `room` bytes, each cell `size` bytes and a two
+// byte pointer to it. If `dividing`, the item that doesn't fit on a page goes up between
+// it and the next instead of starting the next, and these are returned too.
+fn pack<T>(
+    items: Vec<T>,
+    room: usize,
+    size: impl Fn(&T) -> usize,
+    dividing: bool,
+) -> (Vec<Vec<T>>, Vec<T>) {
+    let mut pages: Vec<Vec<T>> = vec![vec![]];
+    let mut dividers = vec![];
+    let mut used = 0;
+    for item in items {
+        let page = pages.last_mut().expect("a page");
+        if used + size(&item) + 2 > room && !page.is_empty() {
+            used = 0;
+            if dividing {
+                dividers.push(item);
+                pages.push(vec![]);
+                continue;
+            }
+            pages.push(vec![]);
+        }
+        used += size(&item) + 2;
+        pages.last_mut().expect("a page").push(item);
+    }
+    // a divider can't be the last item, as there would be no page after it, so the
+    // page before it gives up its last item to go up instead
+    let n = pages.len();
+    if n > 1 && pages[n - 1].is_empty() {
+        let divider = dividers
+            .pop()
+            .expect("a divider before every page but the first");
+        let moved = pages[n - 2].pop().expect("a page holds an item");
+        dividers.push(moved);
+        pages[n - 1].push(divider);
+    }
+    (pages, dividers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+    use std::path::PathBuf;
+
+    // A database written by sqlite3 with 512 byte pages, so that its tables take interior
+    // pages and one row an overflow chain, see tests/sqlite/library.sql.
+    fn library() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/sqlite/library.db")
+    }
+
+    #[test]
+    fn an_sqlite_file_is_read_as_an_image() {
+        assert!(is_sqlite_file(&library()).unwrap());
+        let rows = read(&library()).unwrap();
+        let of = |table: &'static str| rows.iter().filter(move |(t, _, _)| t == table);
+        let master: Vec<&str> = of(MASTER_TABLE)
+            .map(|(_, _, row)| match &row[1] {
+                ColVal::String(name) => name.as_str(),
+                other => panic!("a name, not {other:?}"),
+            })
+            .collect();
+        assert_eq!(master, ["authors", "books", "books_by_author", "cheap"]);
+        assert_eq!(
+            of("authors").nth(2).unwrap(),
+            &(
+                "authors".to_string(),
+                ColVal::Int(7),
+                vec![ColVal::Null, ColVal::String("Calvino".to_string())]
+            )
+        );
+
+        let books: Vec<_> = of("books").collect();
+        assert_eq!(books.len(), 300);
+        let rowids: Vec<&ColVal> = books.iter().map(|(_, rowid, _)| rowid).collect();
+        assert!(rowids.windows(2).all(|w| match (w[0], w[1]) {
+            (ColVal::Int(a), ColVal::Int(b)) => a < b,
+            _ => false,
+        }));
+        let (_, _, book) = books[41];
+        assert_eq!(book[0], ColVal::String("book 42".to_string()));
+        assert_eq!(book[3], ColVal::String("ab".repeat(1000)));
+    }
+
+    #[test]
+    fn files_are_written_as_sqlite_writes_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("written.db");
+        let mut config = PagerConfig::default();
+        config.set_page_size(512);
+        let text = |s: &str| ColVal::String(s.to_string());
+        let entry = |kind: &str, name: &str, sql: &str| {
+            vec![text(kind), text(name), text("t"), ColVal::Int(0), text(sql)]
+        };
+        // enough rows for tables and indexes three levels deep, and one with an overflow
+        // chain in each
+        let mut rows: Vec<(i64, Vec<ColVal>)> = (1..=2000)
+            .map(|i| {
+                (
+                    i * 3,
+                    vec![ColVal::Int(i % 7), text(&format!("name {i:05}"))],
+                )
+            })
+            .collect();
+        rows[500].1[1] = text(&"long ".repeat(300));
+        let mut entries: Vec<Vec<ColVal>> = rows
+            .iter()
+            .map(|(rowid, row)| vec![row[1].clone(), ColVal::Int(*rowid)])
+            .collect();
+        entries.sort_by(|a, b| match (&a[0], &b[0]) {
+            (ColVal::String(a), ColVal::String(b)) => a.cmp(b),
+            _ => Ordering::Equal,
+        });
+        let catalog = vec![
+            (
+                entry("table", "t", "CREATE TABLE t (a INTEGER, b TEXT)"),
+                Some(Tree::Table(rows.clone())),
+            ),
+            (
+                entry("index", "tb", "CREATE INDEX tb ON t (b)"),
+                Some(Tree::Index(entries)),
+            ),
+        ];
+        write(&path, &config, 1, catalog).unwrap();
+
+        assert!(is_sqlite_file(&path).unwrap());
+        let read: Vec<(i64, Vec<ColVal>)> = read(&path)
+            .unwrap()
+            .into_iter()
+            .filter(|(table, _, _)| table == "t")
+            .map(|(_, rowid, row)| match rowid {
+                ColVal::Int(rowid) => (rowid, row),
+                other => panic!("a rowid, not {other:?}"),
+            })
+            .collect();
+        assert_eq!(read, rows);
+
+        // sqlite3 itself, where it is installed, finds nothing wrong with the file
+        let Ok(output) = std::process::Command::new("sqlite3")
+            .arg(&path)
+            .arg("PRAGMA integrity_check; SELECT count(*) FROM t WHERE b >= 'name 01000';")
+            .output()
+        else {
+            return;
+        };
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n1001\n");
+    }
+
+    #[test]
+    fn our_own_files_are_not_sqlite_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ours.db");
+        assert!(!is_sqlite_file(&path).unwrap());
+        std::fs::write(&path, b"not a database at all").unwrap();
+        assert!(!is_sqlite_file(&path).unwrap());
+    }
+}
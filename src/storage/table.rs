@@ -0,0 +1,226 @@
+/*
+    Rowid tables, the ordinary kind, whose rows are stored in a B+tree keyed by rowid.
+
+    Every row of a rowid table has a 64 bit integer key, its rowid, unique within the
+    table. An INSERT that doesn't say otherwise gets one more than the largest rowid the
+    table has used, so rows sit in the tree in the order they were inserted and new rows
+    always go on the right hand end.
+
+    A column declared INTEGER PRIMARY KEY is not stored apart from the rowid but is another
+    name for it, see CreateTable::rowid_alias. Inserting a value into it picks the row's
+    rowid, inserting NULL picks the next one as usual, and either way reading the column
+    gives the rowid. Two rows can't have the same rowid, so the column is unique without
+    needing an index. Whether or not a table has such a column, a query can read a row's
+    rowid as rowid, oid or _rowid_, unless a column of its own has taken the name.
+
+    Unlike SQLite we never hand out a rowid twice: deleting the row with the largest rowid
+    doesn't make that rowid the next one handed out again. The largest rowid used is kept
+    in memory only, as the table is, and a table loaded afresh starts from its largest row.
+    A table with INTEGER PRIMARY KEY AUTOINCREMENT also has it kept in sqlite_sequence,
+    which the executor keeps up to date and skips the table past before each insert, so
+    its rowids aren't used twice even once the database is reopened.
+*/
+use super::btree::{Btree, Comparator, Cursor};
+use super::index::RowId;
+use crate::error::SqlError;
+use crate::sql_parser::ast::{ColVal, CreateTable};
+use anyhow::{bail, Result};
+
+#[derive(Debug)]
+pub struct RowidTable {
+    pub name: String,
+    // the INTEGER PRIMARY KEY column's position and name
+    rowid_column: Option<(usize, String)>,
+    largest_rowid: RowId,
+    tree: Btree<RowId, Vec<ColVal>>,
+}
+
+impl RowidTable {
+    /// An empty table whose B+tree nodes split past `node_keys` keys.
+    pub fn create(def: &CreateTable, node_keys: u64) -> Self {
+        RowidTable {
+            name: def.name.clone(),
+            rowid_column: def
+                .rowid_alias()
+                .map(|pos| (pos, def.columns[pos].name.clone())),
+            largest_rowid: 0,
+            tree: Btree::empty(node_keys),
+        }
+    }
+
+    /// The rowid a row is to be stored under, setting its INTEGER PRIMARY KEY column if it
+    /// has one. A row being updated keeps `old` unless the column says otherwise.
+    pub fn rowid_for(&self, row: &mut [ColVal], old: Option<RowId>) -> Result<RowId> {
+        let given = match &self.rowid_column {
+            Some((pos, _)) => match &row[*pos] {
+                ColVal::Null => None,
+                ColVal::Int(n) => Some(*n),
+                ColVal::Real(f) if f.fract() == 0.0 && f.abs() < 9.2e18 => Some(*f as i64),
+                _ => bail!(SqlError::DatatypeMismatch),
+            },
+            None => None,
+        };
+        let rowid = match given.or(old) {
+            Some(rowid) => rowid,
+            None => self.next_rowid()?,
+        };
+        if let Some((pos, _)) = &self.rowid_column {
+            row[*pos] = ColVal::Int(rowid);
+        }
+        Ok(rowid)
+    }
+
+    /// The rowid a new row gets if it doesn't pick one itself.
+    pub fn next_rowid(&self) -> Result<RowId> {
+        if self.largest_rowid == RowId::MAX {
+            bail!(SqlError::DatabaseFull);
+        }
+        Ok(self.largest_rowid + 1)
+    }
+
+    /// The largest rowid the table has had, 0 if it has had none.
+    pub fn largest_rowid(&self) -> RowId {
+        self.largest_rowid
+    }
+
+    /// Never hand out `rowid` or any before it, as though a row had had it.
+    pub fn skip_past(&mut self, rowid: RowId) {
+        self.largest_rowid = self.largest_rowid.max(rowid);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.range(..).next().is_none()
+    }
+
+    pub fn get(&self, rowid: RowId) -> Option<&[ColVal]> {
+        self.tree.find(&rowid).map(Vec::as_slice)
+    }
+
+    pub fn insert(&mut self, rowid: RowId, row: Vec<ColVal>) -> Result<()> {
+        if self.get(rowid).is_some() {
+            match &self.rowid_column {
+                Some((_, column)) => bail!(SqlError::UniqueConstraint {
+                    columns: vec![format!("{}.{column}", self.name)]
+                }),
+                None => bail!(SqlError::UniqueConstraint {
+                    columns: vec![format!("{}.rowid", self.name)]
+                }),
+            }
+        }
+        self.largest_rowid = self.largest_rowid.max(rowid);
+        self.tree.insert(rowid, row);
+        Ok(())
+    }
+
+    /// Fill an empty table with `rows`, which must be in rowid order, building its tree
+    /// in one go rather than a row at a time, see Btree::bulk_load.
+    pub fn load(&mut self, rows: Vec<(RowId, Vec<ColVal>)>) {
+        assert!(self.is_empty(), "only an empty table can be loaded");
+        if let Some((rowid, _)) = rows.last() {
+            self.largest_rowid = self.largest_rowid.max(*rowid);
+        }
+        let node_keys = self.tree.max_keys() as u64;
+        self.tree = Btree::bulk_load(node_keys, Comparator::binary(), rows);
+    }
+
+    pub fn delete(&mut self, rowid: RowId) -> Option<Vec<ColVal>> {
+        let mut cursor = self.tree.cursor_mut();
+        if !cursor.seek(&rowid) {
+            return None;
+        }
+        cursor.delete().map(|(_, row)| row)
+    }
+
+    /// A cursor over the rows by rowid, see btree.rs.
+    pub fn cursor(&self) -> Cursor<'_, RowId, Vec<ColVal>> {
+        self.tree.cursor()
+    }
+
+    /// Rebuild the table's tree with its nodes full, see Btree::rebuilt.
+    pub fn rebuild(&mut self) {
+        self.tree = self.tree.rebuilt();
+    }
+
+    /// Whether the table's tree is well formed, see Btree::check_invariants.
+    pub fn check(&self) -> Result<()> {
+        self.tree.check_invariants()
+    }
+
+    /// Every row in rowid order.
+    pub fn rows(&self) -> Vec<(RowId, &[ColVal])> {
+        self.iter().collect()
+    }
+
+    /// Every row in rowid order, read from the tree by a cursor as the iterator goes.
+    pub fn iter(&self) -> impl Iterator<Item = (RowId, &[ColVal])> + '_ {
+        let mut cursor = self.cursor();
+        cursor.first();
+        cursor
+            .entries()
+            .map(|(rowid, row)| (*rowid, row.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::ast::Statement;
+    use crate::sql_parser::parse;
+
+    fn table(sql: &str) -> RowidTable {
+        let Statement::CreateTable(def) = parse(sql).unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        RowidTable::create(&def, 64)
+    }
+
+    #[test]
+    fn integer_primary_key_is_the_rowid() {
+        let mut t = table("CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT);");
+        let bob = || vec![ColVal::Null, ColVal::String("bob".to_string())];
+
+        let mut row = bob();
+        let rowid = t.rowid_for(&mut row, None).unwrap();
+        assert_eq!((rowid, &row[0]), (1, &ColVal::Int(1)));
+        t.insert(rowid, row).unwrap();
+
+        let mut row = vec![ColVal::Int(10), ColVal::String("amy".to_string())];
+        let rowid = t.rowid_for(&mut row, None).unwrap();
+        t.insert(rowid, row).unwrap();
+        let mut row = bob();
+        assert_eq!(t.rowid_for(&mut row, None).unwrap(), 11);
+
+        let mut row = vec![ColVal::Int(1), ColVal::Null];
+        assert_eq!(
+            t.insert(t.rowid_for(&mut row, None).unwrap(), row)
+                .unwrap_err()
+                .to_string(),
+            "UNIQUE constraint failed: users.id"
+        );
+        let mut row = vec![ColVal::String("x".to_string()), ColVal::Null];
+        assert_eq!(
+            t.rowid_for(&mut row, None).unwrap_err().to_string(),
+            "datatype mismatch"
+        );
+        assert_eq!(
+            t.rows().iter().map(|(rowid, _)| *rowid).collect::<Vec<_>>(),
+            [1, 10]
+        );
+    }
+
+    #[test]
+    fn other_tables_number_their_rows() {
+        // INT isn't INTEGER, so id is an ordinary column
+        let mut t = table("CREATE TABLE t (id INT PRIMARY KEY);");
+        for id in [5, 6] {
+            let mut row = vec![ColVal::Int(id)];
+            let rowid = t.rowid_for(&mut row, None).unwrap();
+            t.insert(rowid, row).unwrap();
+        }
+        assert_eq!(t.get(2), Some([ColVal::Int(6)].as_slice()));
+        t.delete(2);
+        let mut row = vec![ColVal::Int(7)];
+        assert_eq!(t.rowid_for(&mut row, None).unwrap(), 3);
+        assert_eq!(t.rowid_for(&mut row, Some(1)).unwrap(), 1);
+    }
+}
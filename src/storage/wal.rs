@@ -0,0 +1,628 @@
+/*
+    The write-ahead log, and reading the database as it was at an earlier commit.
+
+    In WAL mode a write doesn't change the database file. The new contents of each page
+    it touches are appended to the log as a frame instead, and the last frame of a
+    transaction is marked as a commit. A reader looking for a page takes the newest
+    frame for that page in the log, falling back to the database file when there is
+    none. Every so often a checkpoint copies the logged pages back into the database file
+    so the log doesn't grow forever.
+
+    When a read transaction starts it notes the last commit frame, its read mark, and
+    ignores every frame after it. Writers carry on appending behind it without disturbing
+    what it sees, which is why readers and a writer don't block each other in WAL mode.
+    A checkpoint must not copy a frame past any reader's mark into the file though, or
+    that reader would find a page newer than its snapshot there.
+
+    There is one writer at a time, and only a reader whose mark is the latest commit can
+    become it, as otherwise it would write over pages changed by commits it never saw.
+    A writer may log frames before it commits, as the pager does with a page evicted from
+    its cache mid-transaction. Those frames lie past every reader's mark, the writer
+    itself reads them back, and ending the write without committing drops them.
+
+    Since a read mark is just a frame number, nothing forces it to be the latest one. As
+    long as every frame after some earlier commit is still in the log, and none of them
+    has been checkpointed into the file, a reader can start at that commit and see the
+    database exactly as it was then. This is experimental: `begin_read_as_of` opens such
+    a read, and a retention of N commits makes checkpoints leave the last N commits in
+    the log so they stay readable. Reports that must add up, say a balance sheet as of the
+    end of the day, can run against one snapshot while writes continue, see
+    Connection::as_of. The retention also lets a copy of the database as of any of them
+    be brought up to date from the frames committed since, which is how replication
+    ships commits to its followers, see replication.rs.
+
+    A log opened on a file, "app.db-wal" beside the database, writes each commit's
+    frames to it as the commit is made, so that a commit outlasts the process even
+    though the file has yet to be checkpointed. The frames a writer appends before it
+    commits stay in memory until then. The file starts with a header and then holds
+    the frames in order, each a header and the page:
+
+        magic       "sqlite-clone wal"
+        page size   4 bytes
+        salt        4 bytes, picked at random each time the file starts over
+        checksum    FNV-1a of the header before it, 4 bytes
+
+        page        its number, 4 bytes
+        commit      1 on the last frame of a commit and 0 before it, 4 bytes
+        checksum    FNV-1a of the frame's header before it and of its page, starting
+                    from the checksum of the frame before, or the file's, 4 bytes
+
+    Opening a log on its file reads back the frames up to the last commit whose frames
+    all check out, so a commit cut short by a crash is dropped from the end, and the
+    rest are in the log again as they were, to be read and checkpointed as before. A
+    checkpoint that leaves the log empty starts the file over, once the database file
+    has what it held, and a log that keeps commits back for reading as of them keeps
+    its file growing until it is emptied.
+*/
+use super::compress::{fnv1a, FNV_OFFSET};
+use super::os_interface::VfsFile;
+use crate::error::SqlError;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+pub type PageNumber = u32;
+// Frames are numbered from 1 and never reused, so a snapshot names a moment for good.
+pub type FrameNumber = u64;
+/// A frame as committed_since hands it out: its number, page, data and whether it is a
+/// commit.
+pub type LoggedFrame<'w> = (FrameNumber, PageNumber, &'w [u8], bool);
+
+const MAGIC: &[u8; 16] = b"sqlite-clone wal";
+const HEADER_SIZE: usize = 28;
+const FRAME_HEADER: usize = 12;
+
+#[derive(Debug)]
+struct Frame {
+    page: PageNumber,
+    data: Vec<u8>,
+    commit: bool,
+}
+
+/// A read transaction's view of the database, every commit up to its read mark.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Snapshot {
+    id: u64,
+    pub read_mark: FrameNumber,
+}
+
+#[derive(Debug, Default)]
+pub struct Wal {
+    frames: VecDeque<Frame>,
+    // the file commits are written to, None for a log kept in memory alone
+    file: Option<LogFile>,
+    // frames up to here have been copied into the database file and dropped from the log
+    backfilled: FrameNumber,
+    readers: BTreeMap<u64, FrameNumber>,
+    next_reader: u64,
+    // how many of the latest commits a checkpoint leaves in the log
+    retained_commits: usize,
+    // the reader that is writing, if one is
+    writer: Option<u64>,
+}
+
+// A log's file, see the module comment.
+struct LogFile {
+    file: Box<dyn VfsFile + Send>,
+    page_size: usize,
+    // how many frames it holds, and the checksum the last of them, or the header, ends on
+    frames: u64,
+    checksum: u32,
+}
+
+impl fmt::Debug for LogFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a log file of {} frames", self.frames)
+    }
+}
+
+impl LogFile {
+    // Write `frames` after those the file holds, behind a new header if it holds none.
+    fn write<'f>(&mut self, frames: impl Iterator<Item = &'f Frame>) -> Result<()> {
+        let mut bytes = vec![];
+        let mut checksum = self.checksum;
+        let offset = match self.frames {
+            0 => {
+                let header = log_header(self.page_size as u32, rand::random());
+                checksum = be_u32(&header[HEADER_SIZE - 4..]);
+                bytes.extend(header);
+                0
+            }
+            frames => (HEADER_SIZE + frames as usize * (FRAME_HEADER + self.page_size)) as u64,
+        };
+        let mut written = 0;
+        for frame in frames {
+            if frame.data.len() != self.page_size {
+                bail!(
+                    "a page of {} bytes can't be logged among pages of {}",
+                    frame.data.len(),
+                    self.page_size
+                );
+            }
+            let start = bytes.len();
+            bytes.extend(frame.page.to_be_bytes());
+            bytes.extend(u32::from(frame.commit).to_be_bytes());
+            checksum = fnv1a(fnv1a(checksum, &bytes[start..]), &frame.data);
+            bytes.extend(checksum.to_be_bytes());
+            bytes.extend(&frame.data);
+            written += 1;
+        }
+        self.file.write_at(offset, &bytes)?;
+        self.frames += written;
+        self.checksum = checksum;
+        Ok(())
+    }
+}
+
+// The header of a log file of pages of `page_size` bytes.
+fn log_header(page_size: u32, salt: u32) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend(page_size.to_be_bytes());
+    header.extend(salt.to_be_bytes());
+    let checksum = fnv1a(FNV_OFFSET, &header);
+    header.extend(checksum.to_be_bytes());
+    header
+}
+
+// The frames of a log file up to the last commit whose frames all check out, the checksum
+// the last of them ends on and how many bytes they take with the header, None if the
+// file doesn't start with a header that checks out.
+fn read_log(bytes: &[u8]) -> Option<(Vec<Frame>, u32, usize)> {
+    let header = bytes.get(..HEADER_SIZE)?;
+    let page_size = be_u32(&header[16..]) as usize;
+    if header[..16] != *MAGIC || be_u32(&header[24..]) != fnv1a(FNV_OFFSET, &header[..24]) {
+        return None;
+    }
+    let mut checksum = be_u32(&header[24..]);
+    let (mut frames, mut committed) = (vec![], (0, checksum, HEADER_SIZE));
+    let mut offset = HEADER_SIZE;
+    while let Some(frame) = bytes.get(offset..offset + FRAME_HEADER + page_size) {
+        let data = &frame[FRAME_HEADER..];
+        checksum = fnv1a(fnv1a(checksum, &frame[..8]), data);
+        if checksum != be_u32(&frame[8..]) {
+            break;
+        }
+        let commit = be_u32(&frame[4..]) == 1;
+        frames.push(Frame {
+            page: be_u32(frame),
+            data: data.to_vec(),
+            commit,
+        });
+        offset += frame.len();
+        if commit {
+            committed = (frames.len(), checksum, offset);
+        }
+    }
+    frames.truncate(committed.0);
+    Some((frames, committed.1, committed.2))
+}
+
+/// The log's file beside the database file at `database`.
+pub fn wal_path(database: &Path) -> PathBuf {
+    let mut path = database.as_os_str().to_owned();
+    path.push("-wal");
+    PathBuf::from(path)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+impl Wal {
+    pub fn new(retained_commits: usize) -> Self {
+        Wal {
+            retained_commits,
+            ..Wal::default()
+        }
+    }
+
+    /// A log of pages of `page_size` bytes kept in `file` as well as in memory, with the
+    /// commits the file holds from before, see the module comment.
+    pub fn open(
+        mut file: Box<dyn VfsFile + Send>,
+        page_size: u32,
+        retained_commits: usize,
+    ) -> Result<Self> {
+        let mut bytes = vec![0; file.size()? as usize];
+        let read = file.read_at(0, &mut bytes)?;
+        bytes.truncate(read);
+        let (frames, checksum, size) = match read_log(&bytes) {
+            Some((frames, checksum, size)) if be_u32(&bytes[16..]) == page_size => {
+                (frames, checksum, size)
+            }
+            _ => (vec![], 0, 0),
+        };
+        // a commit cut short is dropped from the file too, so that nothing follows the
+        // frames written next that could be taken for theirs
+        let size = if frames.is_empty() { 0 } else { size };
+        if size as u64 != bytes.len() as u64 {
+            file.truncate(size as u64)?;
+        }
+        if !frames.is_empty() {
+            tracing::debug!(frames = frames.len(), "log recovered from its file");
+        }
+        Ok(Wal {
+            retained_commits,
+            file: Some(LogFile {
+                file,
+                page_size: page_size as usize,
+                frames: frames.len() as u64,
+                checksum,
+            }),
+            frames: frames.into(),
+            ..Wal::default()
+        })
+    }
+
+    /// Make sure the commits written to the log's file have reached the disk.
+    pub fn sync(&mut self) -> Result<()> {
+        match &mut self.file {
+            Some(log) => log.file.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Start the log's file over if a checkpoint has left the log empty, which is only
+    /// safe once the database file has been synced.
+    pub fn restart(&mut self) -> Result<()> {
+        match &mut self.file {
+            Some(log) if self.frames.is_empty() && log.frames > 0 => {
+                log.file.truncate(0)?;
+                log.frames = 0;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Stop writing to the log's file, returning whether it has nothing left in it that
+    /// isn't in the database file, and so can go.
+    pub fn close(&mut self) -> bool {
+        self.file = None;
+        self.frames.is_empty()
+    }
+
+    fn frame(&self, number: FrameNumber) -> Option<&Frame> {
+        let index = number.checked_sub(self.backfilled + 1)?;
+        self.frames.get(index as usize)
+    }
+
+    fn last_frame(&self) -> FrameNumber {
+        self.backfilled + self.frames.len() as FrameNumber
+    }
+
+    /// The frame of the latest commit, 0 when nothing has been logged since the database
+    /// file was last brought up to date.
+    pub fn last_commit(&self) -> FrameNumber {
+        (self.backfilled + 1..=self.last_frame())
+            .rev()
+            .find(|n| self.frame(*n).is_some_and(|f| f.commit))
+            .unwrap_or(self.backfilled)
+    }
+
+    /// Append a transaction's pages, returning its commit frame.
+    pub fn commit(&mut self, pages: Vec<(PageNumber, Vec<u8>)>) -> Result<FrameNumber> {
+        for (page, data) in pages {
+            self.append(page, data);
+        }
+        self.commit_appended()
+    }
+
+    /// Append a page the writer has changed, to be committed along with the rest of its
+    /// transaction.
+    pub fn append(&mut self, page: PageNumber, data: Vec<u8>) {
+        self.frames.push_back(Frame {
+            page,
+            data,
+            commit: false,
+        });
+    }
+
+    /// Mark the last frame appended as a commit, returning it, and write the commit's
+    /// frames to the log's file if it has one. A write that fails leaves the frames
+    /// uncommitted.
+    pub fn commit_appended(&mut self) -> Result<FrameNumber> {
+        if !self.has_uncommitted() {
+            bail!("a transaction must write at least one page to be logged");
+        }
+        let first = (self.last_commit() - self.backfilled) as usize;
+        self.frames.back_mut().expect("a frame to commit").commit = true;
+        if let Some(log) = &mut self.file {
+            if let Err(err) = log.write(self.frames.range(first..)) {
+                self.frames.back_mut().expect("a frame to commit").commit = false;
+                return Err(err);
+            }
+        }
+        Ok(self.last_frame())
+    }
+
+    /// How many frames the log holds, committed or not.
+    pub fn logged_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Have checkpoints leave the last `commits` commits in the log from now on, returning
+    /// how many they left before.
+    pub fn retain_commits(&mut self, commits: usize) -> usize {
+        std::mem::replace(&mut self.retained_commits, commits)
+    }
+
+    /// Whether frames have been appended since the latest commit.
+    pub fn has_uncommitted(&self) -> bool {
+        self.last_frame() > self.last_commit()
+    }
+
+    /// Start a read of the latest commit.
+    pub fn begin_read(&mut self) -> Snapshot {
+        let mark = self.last_commit();
+        self.snapshot_at(mark)
+    }
+
+    /// Start a read of the database as it was at an earlier commit, which must still be
+    /// in the log.
+    pub fn begin_read_as_of(&mut self, commit: FrameNumber) -> Result<Snapshot> {
+        if commit < self.backfilled {
+            bail!("snapshot at frame {commit} is no longer retained");
+        }
+        let is_commit = commit == self.backfilled || self.frame(commit).is_some_and(|f| f.commit);
+        if !is_commit {
+            bail!("frame {commit} is not a commit");
+        }
+        Ok(self.snapshot_at(commit))
+    }
+
+    fn snapshot_at(&mut self, read_mark: FrameNumber) -> Snapshot {
+        self.next_reader += 1;
+        self.readers.insert(self.next_reader, read_mark);
+        Snapshot {
+            id: self.next_reader,
+            read_mark,
+        }
+    }
+
+    pub fn end_read(&mut self, snapshot: Snapshot) {
+        self.end_write(snapshot);
+        self.readers.remove(&snapshot.id);
+    }
+
+    /// Make the read `snapshot` the writer, which it can only be if there is no other and
+    /// nothing has been committed since it began.
+    pub fn begin_write(&mut self, snapshot: &Snapshot) -> Result<()> {
+        match self.writer {
+            Some(id) if id == snapshot.id => return Ok(()),
+            Some(_) => bail!(SqlError::DatabaseLocked),
+            None => {}
+        }
+        if snapshot.read_mark != self.last_commit() {
+            return Err(SqlError::DatabaseLocked)
+                .context("database is locked: a commit has been made since this read began");
+        }
+        self.writer = Some(snapshot.id);
+        Ok(())
+    }
+
+    /// Stop `snapshot` writing, dropping any frames it appended and didn't commit.
+    pub fn end_write(&mut self, snapshot: Snapshot) {
+        if self.writer != Some(snapshot.id) {
+            return;
+        }
+        self.writer = None;
+        let committed = self.last_commit() - self.backfilled;
+        self.frames.truncate(committed as usize);
+    }
+
+    /// A page as a snapshot sees it, None when the log has no copy at or before its read
+    /// mark and the page is to be read from the database file.
+    pub fn read_page(&self, snapshot: &Snapshot, page: PageNumber) -> Option<&[u8]> {
+        let upto = if self.writer == Some(snapshot.id) {
+            // the writer sees the frames it hasn't committed yet
+            self.last_frame()
+        } else {
+            snapshot.read_mark
+        };
+        (self.backfilled + 1..=upto)
+            .rev()
+            .filter_map(|n| self.frame(n))
+            .find(|f| f.page == page)
+            .map(|f| f.data.as_slice())
+    }
+
+    /// The frames committed after `after`, to bring a copy of the database as of `after` up to date.
+    /// None once some of them have been checkpointed out of the log.
+    pub fn committed_since(&self, after: FrameNumber) -> Option<Vec<LoggedFrame<'_>>> {
+        if after < self.backfilled {
+            return None;
+        }
+        let frames = (after + 1..=self.last_commit()).filter_map(|n| {
+            let frame = self.frame(n)?;
+            Some((n, frame.page, frame.data.as_slice(), frame.commit))
+        });
+        Some(frames.collect())
+    }
+
+    /// Copy logged pages into the database file through `write_page`, as far as readers
+    /// and the retention allow, and drop them from the log. Returns the frame the file is
+    /// now up to date with.
+    pub fn checkpoint(
+        &mut self,
+        mut write_page: impl FnMut(PageNumber, &[u8]) -> Result<()>,
+    ) -> Result<FrameNumber> {
+        let commits: Vec<FrameNumber> = (self.backfilled + 1..=self.last_frame())
+            .filter(|n| self.frame(*n).is_some_and(|f| f.commit))
+            .collect();
+        let retained = commits
+            .len()
+            .checked_sub(self.retained_commits + 1)
+            .map_or(self.backfilled, |i| commits[i]);
+        let oldest_reader = self.readers.values().min().copied();
+        let upto = oldest_reader.map_or(retained, |mark| mark.min(retained));
+
+        while self.backfilled < upto {
+            let frame = self.frames.front().expect("upto is within the log");
+            write_page(frame.page, &frame.data)?;
+            self.frames.pop_front();
+            self.backfilled += 1;
+        }
+        Ok(self.backfilled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn page(s: &str) -> Vec<u8> {
+        s.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn readers_see_only_commits_up_to_their_mark() {
+        let mut wal = Wal::new(0);
+        let first = wal.commit(vec![(1, page("a1")), (2, page("b1"))]).unwrap();
+        let reader = wal.begin_read();
+        wal.commit(vec![(1, page("a2"))]).unwrap();
+
+        assert_eq!(wal.read_page(&reader, 1), Some(&b"a1"[..]));
+        assert_eq!(wal.read_page(&reader, 3), None);
+        let latest = wal.begin_read();
+        assert_eq!(wal.read_page(&latest, 1), Some(&b"a2"[..]));
+        assert_eq!(wal.read_page(&latest, 2), Some(&b"b1"[..]));
+
+        // the past can be read as long as it is in the log
+        let past = wal.begin_read_as_of(first).unwrap();
+        assert_eq!(wal.read_page(&past, 1), Some(&b"a1"[..]));
+        assert_eq!(
+            wal.begin_read_as_of(1).unwrap_err().to_string(),
+            "frame 1 is not a commit"
+        );
+    }
+
+    #[test]
+    fn checkpoints_stop_at_readers_and_retained_commits() {
+        let mut wal = Wal::new(1);
+        let mut file = HashMap::new();
+        let first = wal.commit(vec![(1, page("a1"))]).unwrap();
+        let second = wal.commit(vec![(1, page("a2"))]).unwrap();
+        let third = wal.commit(vec![(1, page("a3"))]).unwrap();
+
+        let old = wal.begin_read_as_of(first).unwrap();
+        assert_eq!(
+            wal.checkpoint(|p, d| {
+                file.insert(p, d.to_vec());
+                Ok(())
+            })
+            .unwrap(),
+            first
+        );
+        assert_eq!(file[&1], page("a1"));
+        wal.end_read(old);
+
+        // the last commit is retained, so the one before it is as far as it goes
+        assert_eq!(
+            wal.checkpoint(|p, d| {
+                file.insert(p, d.to_vec());
+                Ok(())
+            })
+            .unwrap(),
+            second
+        );
+        assert_eq!(file[&1], page("a2"));
+        assert_eq!(
+            wal.begin_read_as_of(first).unwrap_err().to_string(),
+            "snapshot at frame 1 is no longer retained"
+        );
+        let at_second = wal.begin_read_as_of(second).unwrap();
+        assert_eq!(wal.read_page(&at_second, 1), None);
+        let latest = wal.begin_read();
+        assert_eq!(latest.read_mark, third);
+        assert_eq!(wal.read_page(&latest, 1), Some(&b"a3"[..]));
+
+        // and a copy as of the first commit can no longer be brought up to date from it
+        assert_eq!(wal.committed_since(first), None);
+        assert_eq!(
+            wal.committed_since(second),
+            Some(vec![(third, 1, &b"a3"[..], true)])
+        );
+    }
+
+    #[test]
+    fn one_up_to_date_reader_at_a_time_writes() {
+        let mut wal = Wal::new(0);
+        wal.commit(vec![(1, page("a1"))]).unwrap();
+        let writer = wal.begin_read();
+        let reader = wal.begin_read();
+        wal.begin_write(&writer).unwrap();
+        assert!(wal.begin_write(&reader).is_err());
+
+        // frames appended ahead of the commit are the writer's alone
+        wal.append(1, page("a2"));
+        assert_eq!(wal.read_page(&writer, 1), Some(&b"a2"[..]));
+        assert_eq!(wal.read_page(&reader, 1), Some(&b"a1"[..]));
+        // and are dropped if it stops without committing
+        wal.end_write(writer);
+        assert!(!wal.has_uncommitted());
+        assert_eq!(wal.read_page(&writer, 1), Some(&b"a1"[..]));
+
+        wal.begin_write(&writer).unwrap();
+        wal.append(1, page("a3"));
+        let commit = wal.commit_appended().unwrap();
+        wal.end_read(writer);
+        // the other reader began before that commit, so can no longer write
+        assert_eq!(
+            wal.begin_write(&reader).unwrap_err().to_string(),
+            "database is locked: a commit has been made since this read began"
+        );
+        wal.end_read(reader);
+        let latest = wal.begin_read();
+        assert_eq!(latest.read_mark, commit);
+        wal.begin_write(&latest).unwrap();
+    }
+
+    #[test]
+    fn a_file_gives_back_its_commits_and_drops_one_cut_short() {
+        use crate::storage::memdb::{AccessMode, MemVfs};
+        use crate::storage::os_interface::Vfs;
+        let vfs = MemVfs::default();
+        let path = Path::new("app.db-wal");
+        let open = || {
+            let file = vfs.open(path, AccessMode::Create).unwrap();
+            Wal::open(Box::new(file), 4, 0).unwrap()
+        };
+        let mut wal = open();
+        wal.commit(vec![(1, page("one1")), (2, page("two1"))])
+            .unwrap();
+        wal.commit(vec![(2, page("two2"))]).unwrap();
+        let size = vfs
+            .open(path, AccessMode::ReadOnly)
+            .unwrap()
+            .size()
+            .unwrap();
+        // a third commit whose last frame never reached the disk
+        wal.commit(vec![(3, page("thr3")), (1, page("one3"))])
+            .unwrap();
+        let mut file = vfs.open(path, AccessMode::ReadOnly).unwrap();
+        file.truncate(size + (FRAME_HEADER + 4) as u64 + 3).unwrap();
+        drop(wal);
+
+        let mut wal = open();
+        assert_eq!(wal.last_commit(), 3);
+        let snapshot = wal.begin_read();
+        assert_eq!(wal.read_page(&snapshot, 1), Some(&b"one1"[..]));
+        assert_eq!(wal.read_page(&snapshot, 2), Some(&b"two2"[..]));
+        assert_eq!(wal.read_page(&snapshot, 3), None);
+        wal.end_read(snapshot);
+        assert_eq!(file.size().unwrap(), size);
+
+        // what is committed next follows on from the recovered commits, and once a
+        // checkpoint has emptied the log its file starts over
+        wal.commit(vec![(3, page("thr4"))]).unwrap();
+        assert_eq!(open().last_commit(), 4);
+        let mut wal = open();
+        wal.checkpoint(|_, _| Ok(())).unwrap();
+        wal.restart().unwrap();
+        assert_eq!(file.size().unwrap(), 0);
+        assert!(wal.close());
+    }
+}
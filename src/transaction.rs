@@ -0,0 +1,379 @@
+/*
+    Transactions make a group of writes all or nothing. Between BEGIN and COMMIT other
+    statements may insert, update and delete as they please, and if anything goes wrong
+    ROLLBACK puts the database back exactly as it was at BEGIN.
+
+    We do this with an undo journal. Before a write is applied we record how to reverse
+    it, e.g. the undo of inserting a key is deleting it again and the undo of a delete is
+    re-inserting the deleted value. COMMIT simply throws the journal away. ROLLBACK
+    replays it newest entry first, so every write is reversed in the opposite order to
+    which it was made and the database walks backwards to where it started.
+
+    SQLite's rollback journal works the same way but at the level of whole pages: the
+    pager saves a copy of each page before it is first modified in a transaction, and a
+    rollback copies the saved pages back. Our pager does the same, see pager.rs, where
+    BEGIN's mode also decides which locks are taken up front, but until tables live in
+    its pages writes here are undone at the level of the individual keys that changed.
+
+    Every statement runs in a transaction. Outside BEGIN a write is a transaction of its
+    own, opened and committed around it by a savepoint, see executor.rs, and BEGIN inside
+    a transaction is an error rather than a nested transaction, for which there are
+    savepoints.
+
+    Savepoints nest transactions inside one another. SAVEPOINT name marks how long the
+    journal is, ROLLBACK TO name replays only the entries written since that mark, and
+    RELEASE name forgets the mark along with any made after it, keeping the writes. So an
+    inner scope undone in a long transaction costs the writes it made rather than a replay
+    of everything since BEGIN. ROLLBACK TO leaves its savepoint in place to be rolled back
+    to again, as SQLite does. A SAVEPOINT outside a transaction begins one, which the
+    RELEASE of that outermost savepoint commits.
+
+    A transaction's rows only reach the file's pages when it commits, so its savepoints
+    are undone here and not in the file. The snapshots a pager's cache keeps of the pages
+    each savepoint changes, see storage/cache.rs, serve savepoints opened on the pager
+    itself, which so far puts back only the file's header and freelist.
+
+    Commits can be grouped, so that many small transactions arriving together, each a
+    write outside BEGIN, say, are saved to the file as one, with one sync where each
+    would have had its own. Between begin_group and end_group a commit is counted rather
+    than saved, and the executor saves the lot at the end of the group, or before a
+    transaction begins in the middle of it so that the save has none of that
+    transaction's writes. Nothing is reported as committed until then, so a group
+    loses no durability, only the latency of its first commits.
+*/
+use anyhow::{bail, Result};
+
+/// Something whose writes can be reversed by replaying undo records against it.
+pub trait Journaled {
+    type Undo;
+
+    fn apply_undo(&mut self, undo: Self::Undo) -> Result<()>;
+}
+
+#[derive(Debug)]
+struct ActiveTransaction<U> {
+    journal: Vec<U>,
+    // the savepoints open, oldest first
+    savepoints: Vec<Savepoint>,
+    // whether a SAVEPOINT began the transaction rather than BEGIN
+    by_savepoint: bool,
+}
+
+#[derive(Debug)]
+struct Savepoint {
+    name: String,
+    // how long the journal was when it was made
+    mark: usize,
+}
+
+/// Tracks whether an explicit transaction is open and journals its writes.
+#[derive(Debug)]
+pub struct TransactionManager<U> {
+    active: Option<ActiveTransaction<U>>,
+    // the commits put off since the group began or was last saved, while one is open
+    group: Option<usize>,
+}
+
+impl<U> Default for TransactionManager<U> {
+    fn default() -> Self {
+        TransactionManager {
+            active: None,
+            group: None,
+        }
+    }
+}
+
+impl<U> TransactionManager<U> {
+    pub fn in_transaction(&self) -> bool {
+        self.active.is_some()
+    }
+
+    pub fn begin(&mut self) -> Result<()> {
+        if self.in_transaction() {
+            bail!("cannot start a transaction within a transaction");
+        }
+        self.active = Some(ActiveTransaction {
+            journal: vec![],
+            savepoints: vec![],
+            by_savepoint: false,
+        });
+        Ok(())
+    }
+
+    /// Open a savepoint, beginning a transaction if there isn't one.
+    pub fn savepoint(&mut self, name: &str) {
+        let t = self.active.get_or_insert_with(|| ActiveTransaction {
+            journal: vec![],
+            savepoints: vec![],
+            by_savepoint: true,
+        });
+        t.savepoints.push(Savepoint {
+            name: name.to_string(),
+            mark: t.journal.len(),
+        });
+    }
+
+    // Where the newest savepoint called `name` is among those open.
+    fn find_savepoint(&self, name: &str) -> Result<usize> {
+        let found = self.active.as_ref().and_then(|t| {
+            t.savepoints
+                .iter()
+                .rposition(|s| s.name.eq_ignore_ascii_case(name))
+        });
+        match found {
+            Some(level) => Ok(level),
+            None => bail!("no such savepoint: {name}"),
+        }
+    }
+
+    /// Close the savepoint `name` and every savepoint opened after it, keeping their
+    /// writes, and commit if it began the transaction. Returns where it was among the
+    /// savepoints open.
+    pub fn release(&mut self, name: &str) -> Result<usize> {
+        let level = self.find_savepoint(name)?;
+        let t = self.active.as_mut().expect("a savepoint is open");
+        t.savepoints.truncate(level);
+        if level == 0 && t.by_savepoint {
+            self.active = None;
+        }
+        Ok(level)
+    }
+
+    /// Undo every write since the savepoint `name` was opened and close the savepoints
+    /// opened after it, leaving it open. Returns where it is among the savepoints open.
+    pub fn rollback_to<T: Journaled<Undo = U>>(
+        &mut self,
+        name: &str,
+        target: &mut T,
+    ) -> Result<usize> {
+        let level = self.find_savepoint(name)?;
+        let t = self.active.as_mut().expect("a savepoint is open");
+        t.savepoints.truncate(level + 1);
+        let undone = t.journal.split_off(t.savepoints[level].mark);
+        for undo in undone.into_iter().rev() {
+            target.apply_undo(undo)?;
+        }
+        Ok(level)
+    }
+
+    /// Record how to undo a write that is about to happen. Outside of a transaction
+    /// there is nothing to roll back to so the record is dropped.
+    pub fn record(&mut self, undo: U) {
+        if let Some(t) = &mut self.active {
+            t.journal.push(undo);
+        }
+    }
+
+    pub fn commit(&mut self) -> Result<()> {
+        if self.active.take().is_none() {
+            bail!("cannot commit - no transaction is active");
+        }
+        Ok(())
+    }
+
+    pub fn rollback<T: Journaled<Undo = U>>(&mut self, target: &mut T) -> Result<()> {
+        let Some(t) = self.active.take() else {
+            bail!("cannot rollback - no transaction is active");
+        };
+        for undo in t.journal.into_iter().rev() {
+            target.apply_undo(undo)?;
+        }
+        Ok(())
+    }
+
+    /// Start grouping commits, which defer_commit puts off until the group is saved.
+    pub fn begin_group(&mut self) {
+        self.group.get_or_insert(0);
+    }
+
+    /// Whether a commit is to wait for the end of its group, counting it if so.
+    pub fn defer_commit(&mut self) -> bool {
+        match &mut self.group {
+            Some(deferred) => {
+                *deferred += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How many commits have been put off since the group began or this was last called,
+    /// which are to be saved now as one. The group stays open.
+    pub fn take_deferred(&mut self) -> usize {
+        self.group.as_mut().map_or(0, std::mem::take)
+    }
+
+    /// Whether commits are being grouped, see begin_group.
+    pub fn grouping(&self) -> bool {
+        self.group.is_some()
+    }
+
+    /// Whether commits have been put off that are yet to be saved.
+    pub fn has_deferred(&self) -> bool {
+        self.group.is_some_and(|deferred| deferred > 0)
+    }
+
+    /// Stop grouping commits, returning how many were put off and not yet saved.
+    pub fn end_group(&mut self) -> usize {
+        self.group.take().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    enum KvUndo {
+        Remove(u32),
+        Restore(u32, String),
+    }
+
+    #[derive(Default, Debug, PartialEq)]
+    struct Data(BTreeMap<u32, String>);
+
+    #[derive(Default)]
+    struct Kv {
+        data: Data,
+        transactions: TransactionManager<KvUndo>,
+    }
+
+    impl Journaled for Data {
+        type Undo = KvUndo;
+
+        fn apply_undo(&mut self, undo: KvUndo) -> Result<()> {
+            match undo {
+                KvUndo::Remove(k) => {
+                    self.0.remove(&k);
+                }
+                KvUndo::Restore(k, v) => {
+                    self.0.insert(k, v);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl Kv {
+        fn put(&mut self, k: u32, v: &str) {
+            let undo = match self.data.0.insert(k, v.to_string()) {
+                Some(previous) => KvUndo::Restore(k, previous),
+                None => KvUndo::Remove(k),
+            };
+            self.transactions.record(undo);
+        }
+
+        fn delete(&mut self, k: u32) {
+            if let Some(previous) = self.data.0.remove(&k) {
+                self.transactions.record(KvUndo::Restore(k, previous));
+            }
+        }
+
+        fn rollback(&mut self) -> Result<()> {
+            self.transactions.rollback(&mut self.data)
+        }
+    }
+
+    #[test]
+    fn rollback_undoes_every_write_since_begin() {
+        let mut kv = Kv::default();
+        kv.put(1, "one");
+
+        kv.transactions.begin().unwrap();
+        kv.put(2, "two");
+        kv.put(1, "uno");
+        kv.put(1, "ein");
+        kv.delete(1);
+        kv.rollback().unwrap();
+
+        assert_eq!(kv.data, Data(BTreeMap::from([(1, "one".to_string())])));
+        assert!(!kv.transactions.in_transaction());
+    }
+
+    #[test]
+    fn commit_keeps_writes() {
+        let mut kv = Kv::default();
+        kv.transactions.begin().unwrap();
+        kv.put(1, "one");
+        kv.transactions.commit().unwrap();
+
+        // a later rollback has nothing to undo and is an error
+        assert!(kv.rollback().is_err());
+        assert_eq!(kv.data.0.get(&1).map(String::as_str), Some("one"));
+    }
+
+    #[test]
+    fn rollback_to_undoes_only_the_savepoints_writes() {
+        let mut kv = Kv::default();
+        kv.transactions.begin().unwrap();
+        kv.put(1, "one");
+        kv.transactions.savepoint("a");
+        kv.put(2, "two");
+        kv.transactions.savepoint("b");
+        kv.put(1, "uno");
+
+        assert_eq!(kv.transactions.rollback_to("A", &mut kv.data).unwrap(), 0);
+        assert_eq!(kv.data, Data(BTreeMap::from([(1, "one".to_string())])));
+        // b is gone but a is still open, to be rolled back to again
+        assert_eq!(kv.transactions.active.as_ref().unwrap().savepoints.len(), 1);
+        assert_eq!(
+            kv.transactions.release("b").unwrap_err().to_string(),
+            "no such savepoint: b"
+        );
+        kv.put(3, "three");
+        kv.transactions.rollback_to("a", &mut kv.data).unwrap();
+        assert_eq!(kv.data.0.len(), 1);
+
+        // releasing keeps the writes, which the transaction can still roll back
+        kv.put(4, "four");
+        kv.transactions.release("a").unwrap();
+        assert!(kv.transactions.in_transaction());
+        kv.rollback().unwrap();
+        assert!(kv.data.0.is_empty());
+    }
+
+    #[test]
+    fn a_savepoint_outside_a_transaction_begins_one() {
+        let mut kv = Kv::default();
+        kv.transactions.savepoint("outer");
+        kv.transactions.savepoint("inner");
+        kv.put(1, "one");
+        kv.transactions.release("inner").unwrap();
+        assert!(kv.transactions.in_transaction());
+        kv.transactions.release("outer").unwrap();
+        assert!(!kv.transactions.in_transaction());
+        assert_eq!(kv.data.0.len(), 1);
+    }
+
+    #[test]
+    fn commits_in_a_group_are_put_off_until_it_ends() {
+        let mut kv = Kv::default();
+        assert!(!kv.transactions.defer_commit());
+        kv.transactions.begin_group();
+        for k in 1..=3 {
+            kv.transactions.begin().unwrap();
+            kv.put(k, "v");
+            kv.transactions.commit().unwrap();
+            assert!(kv.transactions.defer_commit());
+        }
+        assert_eq!(kv.transactions.take_deferred(), 3);
+        assert!(kv.transactions.defer_commit());
+        assert_eq!(kv.transactions.end_group(), 1);
+        assert!(!kv.transactions.defer_commit());
+        assert_eq!(kv.transactions.end_group(), 0);
+    }
+
+    #[test]
+    fn transaction_state_errors() {
+        let mut kv = Kv::default();
+        assert_eq!(
+            kv.transactions.commit().unwrap_err().to_string(),
+            "cannot commit - no transaction is active"
+        );
+        kv.transactions.begin().unwrap();
+        assert_eq!(
+            kv.transactions.begin().unwrap_err().to_string(),
+            "cannot start a transaction within a transaction"
+        );
+    }
+}
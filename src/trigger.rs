@@ -0,0 +1,221 @@
+/*
+    Triggers are statements stored in the schema that run whenever a table is written to.
+
+        CREATE TRIGGER audit_balance AFTER UPDATE OF balance ON accounts
+        WHEN NEW.balance < 0
+        BEGIN
+            INSERT INTO overdrawn (account, balance) VALUES (NEW.id, NEW.balance);
+        END;
+
+    A trigger fires once for every row the write touches. Its WHEN clause and body can
+    refer to that row as it was before the write, OLD, and as it will be after, NEW. An
+    INSERT has no OLD row and a DELETE no NEW row.
+
+    The planner attaches the triggers that fire for a write to its plan. For each row the
+    write touches the executor then asks `instantiate` for the statements to run: the
+    trigger's body with every NEW.column and OLD.column replaced by the row's values, or
+    nothing when the WHEN clause isn't TRUE for the row. BEFORE triggers run ahead of the
+    row's write and AFTER triggers once it is done.
+*/
+use crate::error::SqlError;
+use crate::eval::{self, EvalContext};
+use crate::sql_parser::ast::{ColVal, CreateTrigger, Expr, Statement, TriggerEvent};
+use anyhow::{bail, Result};
+
+/// The row a trigger fires for. OLD is None for an INSERT and NEW is None for a DELETE.
+pub struct TriggerRow<'r> {
+    pub columns: &'r [String],
+    pub old: Option<&'r [ColVal]>,
+    pub new: Option<&'r [ColVal]>,
+}
+
+impl TriggerRow<'_> {
+    fn value(&self, row: &str, column: &str) -> Result<Option<ColVal>> {
+        let values = if row.eq_ignore_ascii_case("NEW") {
+            self.new
+        } else if row.eq_ignore_ascii_case("OLD") {
+            self.old
+        } else {
+            // not one of ours, some other table's column
+            return Ok(None);
+        };
+        let position = self.columns.iter().position(|c| c == column);
+        match (values, position) {
+            (Some(values), Some(i)) => Ok(Some(values[i].clone())),
+            _ => bail!(SqlError::NoSuchColumn {
+                column: format!("{row}.{column}")
+            }),
+        }
+    }
+}
+
+/// Whether a trigger fires for a write statement. Every trigger on the table for the same
+/// kind of write does, except that UPDATE OF only fires when one of its columns is set.
+pub fn fires_for(trigger: &CreateTrigger, write: &Statement) -> bool {
+    match (&trigger.event, write) {
+        (TriggerEvent::Insert, Statement::Insert { into_table, .. }) => {
+            *into_table == trigger.table
+        }
+        (TriggerEvent::Delete, Statement::Delete { from_table, .. }) => {
+            *from_table == trigger.table
+        }
+        (
+            TriggerEvent::Update(columns),
+            Statement::Update {
+                table, assignments, ..
+            },
+        ) => {
+            *table == trigger.table
+                && (columns.is_empty()
+                    || assignments.iter().any(|a| columns.contains(&a.column_name)))
+        }
+        _ => false,
+    }
+}
+
+/// The statements a trigger runs for one row, None when its WHEN clause isn't TRUE.
+pub fn instantiate(
+    trigger: &CreateTrigger,
+    row: &TriggerRow,
+    ctx: &dyn EvalContext,
+) -> Result<Option<Vec<Statement>>> {
+    if let Some(when) = &trigger.when {
+        let mut when = when.clone();
+        let mut result = Ok(());
+        when.walk_mut(&mut |e| bind_row(e, row, &mut result));
+        result?;
+        if !eval::is_true(&when, ctx)? {
+            return Ok(None);
+        }
+    }
+
+    let mut body = trigger.body.clone();
+    let mut result = Ok(());
+    for statement in &mut body {
+        statement.walk_exprs_mut(&mut |e| bind_row(e, row, &mut result));
+    }
+    result?;
+    Ok(Some(body))
+}
+
+// Replace NEW.column or OLD.column with the row's value, keeping the first error.
+fn bind_row(expr: &mut Expr, row: &TriggerRow, result: &mut Result<()>) {
+    let Expr::QualifiedColumn { table, column } = expr else {
+        return;
+    };
+    match row.value(table, column) {
+        Ok(Some(value)) => *expr = Expr::Literal(value),
+        Ok(None) => {}
+        Err(err) => {
+            if result.is_ok() {
+                *result = Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::parse;
+
+    struct NoRow;
+
+    impl EvalContext for NoRow {
+        fn column(&self, name: &str) -> Result<ColVal> {
+            bail!(SqlError::NoSuchColumn {
+                column: name.to_string()
+            })
+        }
+
+        fn subquery(&self, _select: &Statement) -> Result<Vec<Vec<ColVal>>> {
+            Ok(vec![])
+        }
+
+        fn exists(&self, _select: &Statement) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    fn trigger(sql: &str) -> CreateTrigger {
+        let Statement::CreateTrigger(trigger) = parse(sql).unwrap() else {
+            panic!("expected CREATE TRIGGER");
+        };
+        trigger
+    }
+
+    fn columns() -> Vec<String> {
+        vec!["id".to_string(), "balance".to_string()]
+    }
+
+    #[test]
+    fn update_of_fires_only_when_its_columns_are_set() {
+        let t = trigger(
+            "CREATE TRIGGER t AFTER UPDATE OF balance ON accounts BEGIN DELETE FROM x; END;",
+        );
+        assert!(fires_for(
+            &t,
+            &parse("UPDATE accounts SET balance = 0, id = 1;").unwrap()
+        ));
+        assert!(!fires_for(
+            &t,
+            &parse("UPDATE accounts SET id = 1;").unwrap()
+        ));
+        assert!(!fires_for(
+            &t,
+            &parse("UPDATE other SET balance = 1;").unwrap()
+        ));
+        assert!(!fires_for(&t, &parse("DELETE FROM accounts;").unwrap()));
+    }
+
+    #[test]
+    fn body_sees_the_row_through_new_and_old() {
+        let t = trigger(
+            "CREATE TRIGGER t AFTER UPDATE ON accounts WHEN NEW.balance < 0 BEGIN \
+             INSERT INTO overdrawn (account, was, now) VALUES (NEW.id, OLD.balance, NEW.balance); \
+             END;",
+        );
+        let columns = columns();
+        let old = [ColVal::Int(1), ColVal::Int(10)];
+
+        let new = [ColVal::Int(1), ColVal::Int(-5)];
+        let row = TriggerRow {
+            columns: &columns,
+            old: Some(&old),
+            new: Some(&new),
+        };
+        let body = instantiate(&t, &row, &NoRow).unwrap().unwrap();
+        assert_eq!(
+            body[0].to_string(),
+            "INSERT INTO overdrawn (account, was, now) VALUES (1, 10, -5)"
+        );
+
+        let new = [ColVal::Int(1), ColVal::Int(5)];
+        let row = TriggerRow {
+            columns: &columns,
+            old: Some(&old),
+            new: Some(&new),
+        };
+        assert_eq!(instantiate(&t, &row, &NoRow).unwrap(), None);
+    }
+
+    #[test]
+    fn delete_triggers_have_no_new_row() {
+        let t = trigger(
+            "CREATE TRIGGER t BEFORE DELETE ON accounts BEGIN \
+             DELETE FROM history WHERE account = OLD.id; \
+             DELETE FROM notes WHERE account = NEW.id; END;",
+        );
+        let columns = columns();
+        let old = [ColVal::Int(1), ColVal::Int(10)];
+        let row = TriggerRow {
+            columns: &columns,
+            old: Some(&old),
+            new: None,
+        };
+        assert_eq!(
+            instantiate(&t, &row, &NoRow).unwrap_err().to_string(),
+            "no such column: NEW.id"
+        );
+    }
+}
@@ -0,0 +1,214 @@
+/*
+    Rows that expire, for tables used as caches: a session table, memoised API responses,
+    rate limit counters. Rather than every application remembering to delete what has gone
+    stale, a table is given an expiry column and its rows prune themselves.
+
+        conn.set_expiry("sessions", Some("expires_at"))?;
+
+    A row has expired once its expiry column holds a time at or before now, in seconds
+    since the Unix epoch, as SQLite's unixepoch() gives it. A NULL never expires, and neither
+    does text, which sorts after every number just as it would in `expires_at <= now`.
+
+    Expired rows are swept on access. Before a statement reads or writes a table with an
+    expiry column, directly or through a view, the table's expired rows are deleted, so no
+    query ever sees one. Connection::sweep_expired sweeps every such table at once, for an
+    application to run on a timer so that tables nobody reads are pruned too; the engine
+    runs no threads of its own to do it.
+
+    A sweep is an ordinary DELETE: indexes are kept up to date, triggers fire and foreign
+    key actions run just as they would for one the application wrote, and inside a
+    transaction it is rolled back with everything else. The rows' space is only given back
+    to the table's B+tree, which VACUUM rebuilds with its nodes full again.
+
+    Like collations and functions, expiry columns belong to the connection rather than the
+    schema, so they are set again each time a database is opened.
+*/
+use crate::error::SqlError;
+use crate::planner::Catalog;
+use crate::schema::Schema;
+use crate::sql_parser::ast::{BinaryOp, ColVal, Expr, Statement};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The time now, in seconds since the Unix epoch.
+pub type Clock = Box<dyn Fn() -> i64 + Send>;
+
+pub struct Expiry {
+    // each table with an expiry column, and the column
+    columns: BTreeMap<String, String>,
+    clock: Clock,
+    // set while a sweep's DELETE runs, which mustn't sweep again
+    sweeping: bool,
+}
+
+impl Default for Expiry {
+    fn default() -> Self {
+        Expiry {
+            columns: BTreeMap::new(),
+            clock: Box::new(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs() as i64)
+            }),
+            sweeping: false,
+        }
+    }
+}
+
+impl fmt::Debug for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Expiry")
+            .field("columns", &self.columns)
+            .field("sweeping", &self.sweeping)
+            .finish()
+    }
+}
+
+impl Expiry {
+    /// Give `table` an expiry column, or with None stop its rows expiring.
+    pub fn set(&mut self, schema: &Schema, table: &str, column: Option<&str>) -> Result<()> {
+        if schema.table(table).is_none() {
+            bail!(SqlError::NoSuchTable {
+                table: table.to_string()
+            });
+        }
+        match column {
+            Some(column) => {
+                if !schema.table_columns(table)?.iter().any(|c| c == column) {
+                    bail!(SqlError::NoSuchColumn {
+                        column: column.to_string()
+                    });
+                }
+                self.columns.insert(table.to_string(), column.to_string());
+            }
+            None => {
+                self.columns.remove(table);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the clock expiry is measured against, as a test or a simulation would.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    /// The DELETE statements that sweep the tables a statement uses, looking through the
+    /// views it reads to their tables. None while a sweep is already running.
+    pub fn sweeps(&self, schema: &Schema, statement: &Statement) -> Vec<Statement> {
+        if self.sweeping || self.columns.is_empty() {
+            return vec![];
+        }
+        let mut tables = vec![];
+        expiring_tables(schema, statement, &mut tables);
+        tables.sort();
+        tables.dedup();
+        tables
+            .into_iter()
+            .filter_map(|table| self.sweep(&table))
+            .collect()
+    }
+
+    /// The DELETE statements that sweep every table with an expiry column.
+    pub fn sweep_all(&self) -> Vec<Statement> {
+        self.columns
+            .keys()
+            .filter_map(|table| self.sweep(table))
+            .collect()
+    }
+
+    fn sweep(&self, table: &str) -> Option<Statement> {
+        let column = self.columns.get(table)?;
+        Some(Statement::Delete {
+            from_table: table.to_string(),
+            where_clause: Some(Expr::Binary {
+                op: BinaryOp::LtEq,
+                left: Box::new(Expr::Column(column.clone())),
+                right: Box::new(Expr::Literal(ColVal::Int((self.clock)()))),
+            }),
+            order_by: vec![],
+            limit: None,
+        })
+    }
+
+    /// Note that a sweep's DELETE statements are running, or have finished.
+    pub fn set_sweeping(&mut self, sweeping: bool) {
+        self.sweeping = sweeping;
+    }
+}
+
+// The tables a statement reads or writes, with each view it reads replaced by its tables.
+fn expiring_tables(schema: &Schema, statement: &Statement, tables: &mut Vec<String>) {
+    for name in statement.tables() {
+        match schema.view(name) {
+            Some(view) => expiring_tables(schema, &view.select, tables),
+            None => tables.push(name.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::Executor;
+    use crate::sql_parser::ast::ColVal;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn expired_rows_are_swept_on_access() {
+        let mut db = Executor::default();
+        for sql in [
+            "CREATE TABLE sessions (token TEXT, expires_at INTEGER);",
+            "CREATE INDEX idx_expires ON sessions (expires_at);",
+            "CREATE VIEW live AS SELECT token FROM sessions;",
+            "CREATE TABLE swept (token TEXT);",
+            "CREATE TRIGGER log_sweep AFTER DELETE ON sessions BEGIN INSERT INTO swept (token) VALUES (OLD.token); END;",
+            "INSERT INTO sessions (token, expires_at) VALUES (\"a\", 100);",
+            "INSERT INTO sessions (token, expires_at) VALUES (\"b\", 200);",
+            "INSERT INTO sessions (token, expires_at) VALUES (\"c\", NULL);",
+        ] {
+            db.execute_sql(sql).unwrap();
+        }
+        let now = Arc::new(AtomicI64::new(50));
+        let clock = now.clone();
+        db.set_expiry_clock(Box::new(move || clock.load(Ordering::Relaxed)));
+        db.set_expiry("sessions", Some("expires_at")).unwrap();
+
+        let tokens = |db: &mut Executor, sql: &str| -> Vec<String> {
+            db.execute_sql(sql)
+                .unwrap()
+                .rows
+                .into_iter()
+                .map(|row| match &row[0] {
+                    ColVal::String(s) => s.clone(),
+                    other => panic!("{other:?}"),
+                })
+                .collect()
+        };
+        assert_eq!(tokens(&mut db, "SELECT token FROM live;"), ["a", "b", "c"]);
+
+        // a row expires at its time, and a NULL never does
+        now.store(100, Ordering::Relaxed);
+        assert_eq!(tokens(&mut db, "SELECT token FROM live;"), ["b", "c"]);
+        assert_eq!(tokens(&mut db, "SELECT token FROM swept;"), ["a"]);
+
+        // rows nobody reads are left until they are swept
+        now.store(1000, Ordering::Relaxed);
+        assert_eq!(tokens(&mut db, "SELECT token FROM swept;"), ["a"]);
+        db.sweep_expired().unwrap();
+        assert_eq!(tokens(&mut db, "SELECT token FROM swept;"), ["a", "b"]);
+
+        db.set_expiry("sessions", None).unwrap();
+        db.execute_sql("INSERT INTO sessions (token, expires_at) VALUES (\"d\", 1);")
+            .unwrap();
+        assert_eq!(tokens(&mut db, "SELECT token FROM sessions;"), ["c", "d"]);
+        assert_eq!(
+            db.set_expiry("sessions", Some("nope"))
+                .unwrap_err()
+                .to_string(),
+            "no such column: nope"
+        );
+    }
+}
@@ -0,0 +1,1697 @@
+/*
+    The virtual machine, which runs queries compiled to bytecode like SQLite's VDBE.
+
+    Rather than walk the plan tree, a query is compiled into a short program for a small
+    register machine and the program is run. The machine has numbered registers, each
+    holding one value, and numbered cursors, each positioned on a row of a table. Its
+    instructions load values into registers, move cursors along and jump about, much like a
+    CPU's:
+
+        addr  opcode     p1  p2  p3  p4
+        0     Init       0   1
+        1     OpenRead   0           users
+        2     Rewind     0   8
+        3     Expr       0   1       age > 25
+        4     IfNot      1   7
+        5     Column     0   1   0
+        6     ResultRow  0   1
+        7     Next       0   3
+        8     Halt
+
+    is `SELECT name FROM users WHERE age > 25`: open a cursor on users, and for each of its
+    rows work out the WHERE clause into register 1, skip the row unless that is true, and
+    otherwise load its second column into register 0 and hand that out as a result row.
+    EXPLAIN shows a statement's program in this form, and EXPLAIN QUERY PLAN how the plan
+    it was compiled from reads each table.
+
+    Compiling separates deciding how to run a query from running it: the planner and the
+    compiler make every decision up front, and the machine just follows instructions. An
+    IndexSearch becomes one SeekIndex loop per key it seeks, bounded by its range if it
+    has one, and an aggregate query steps each aggregate's accumulator with AggStep as the
+    rows go by and reads them out with AggFinal at the end. A result column that names
+    the rowid, as `rowid`, `oid` or `_rowid_`, is loaded with Rowid rather than Column.
+    A virtual table's cursor is pointed at its rows with VFilter, see vtab.rs, and read
+    as any other. LIMIT and OFFSET are counted down in registers, IfPos skipping the
+    rows OFFSET passes over and DecrJumpZero halting once LIMIT's are out, so that a
+    query with a LIMIT reads no further into its table than it has to.
+
+    A Next that moves a cursor on to one of every so many rows first looks whether the
+    statement has been interrupted, see interrupt.rs, so a long loop stops part way.
+
+    A Machine runs a program as sqlite3_step does, up to its next ResultRow, and hands
+    that row out before going on, so the rows of a query are made as they are read. Its
+    cursors walk the table's B+tree in place rather than copy its rows out first, which
+    leaves a scan, filtered or not, holding one row at a time however big the table.
+
+    A join is compiled to nested loops, its first table outermost, each table's columns
+    loaded into the joined row's registers for JoinExpr to work out the WHERE clause and
+    the result columns from. A table matched on keys, by a hash or a merge join alike, is
+    read once up front into a hash table with HashInsert, and HashSeek then points its
+    cursor at the rows filed under the outer row's key, which hands the pairs out in the
+    order join.rs does. A table without keys is read over again for each outer row. Only
+    the tables matched on keys are held in memory, the first table's rows and the joined
+    rows are made one at a time, and a LIMIT stops the outer loop once it is out.
+
+    Expressions are not compiled into instructions of their own yet. An Expr instruction
+    hands the expression to eval.rs together with the cursor's current row, which keeps
+    the rules for NULLs, collations and subqueries in one place.
+
+    Only queries that read a single table or join tables, with or without a WHERE clause,
+    aggregates and a LIMIT, are compiled so far. The compiler returns None for the rest,
+    semi-joins, views and CTEs among them, and the executor runs their plans as operator trees, as it does the
+    rows that UPDATE and DELETE are to write.
+*/
+use crate::aggregate::Accumulator;
+use crate::error::SqlError;
+use crate::eval;
+use crate::eval::Affinity;
+use crate::executor::RowSet;
+use crate::interrupt::CHECK_EVERY;
+use crate::planner::{self, Catalog, JoinTable, Plan};
+use crate::sql_parser::ast::{Aggregate, ColVal, Expr, Limit};
+use crate::sql_parser::parse_expr;
+use crate::storage::index::RowId;
+use anyhow::{anyhow, bail, Result};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+pub type Register = usize;
+pub type Address = usize;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Instruction {
+    // Jump to the program's first real instruction.
+    Init {
+        start: Address,
+    },
+    OpenRead {
+        cursor: usize,
+        table: String,
+    },
+    // Point the cursor at the table's first row, or jump if there are none.
+    Rewind {
+        cursor: usize,
+        if_empty: Address,
+    },
+    // Point the cursor at the first row whose leading index columns equal the `count`
+    // registers from `key` and whose next column is between the registers `lower` and
+    // `upper`, or jump if there are none.
+    SeekIndex {
+        cursor: usize,
+        index: String,
+        key: Register,
+        count: usize,
+        lower: Bound<Register>,
+        upper: Bound<Register>,
+        not_found: Address,
+    },
+    // Point a virtual table's cursor at the first of the rows its best_index `number`
+    // and the `count` registers from `args` pick out, or jump if there are none, see
+    // vtab.rs.
+    VFilter {
+        cursor: usize,
+        number: i64,
+        args: Register,
+        count: usize,
+        if_empty: Address,
+    },
+    // Move the cursor to its next row and jump, unless it was on its last.
+    Next {
+        cursor: usize,
+        target: Address,
+    },
+    Column {
+        cursor: usize,
+        column: usize,
+        target: Register,
+    },
+    // Load the rowid of the cursor's row.
+    Rowid {
+        cursor: usize,
+        target: Register,
+    },
+    Integer {
+        value: i64,
+        target: Register,
+    },
+    Real {
+        value: f64,
+        target: Register,
+    },
+    String8 {
+        value: String,
+        target: Register,
+    },
+    Blob {
+        value: Vec<u8>,
+        target: Register,
+    },
+    Boolean {
+        value: bool,
+        target: Register,
+    },
+    Null {
+        target: Register,
+    },
+    Copy {
+        source: Register,
+        target: Register,
+    },
+    // Evaluate an expression over the cursor's current row, or over no row.
+    Expr {
+        cursor: Option<usize>,
+        expr: Expr,
+        target: Register,
+    },
+    // Evaluate an expression over a join's row, whose columns are in the registers from
+    // `row`, named `name.column` as the program's joined columns are.
+    JoinExpr {
+        row: Register,
+        expr: Expr,
+        target: Register,
+    },
+    // Convert the register's value as the affinity would a column's, for a join's key.
+    Affinity {
+        register: Register,
+        affinity: Affinity,
+    },
+    // Add the cursor's row to its hash table under the key in the `count` registers from
+    // `key`, unless the key has a NULL in it, which matches nothing.
+    HashInsert {
+        cursor: usize,
+        key: Register,
+        count: usize,
+    },
+    // Point the cursor at the rows its hash table holds under the key in the `count`
+    // registers from `key`, in the order they were added, or jump if there are none.
+    HashSeek {
+        cursor: usize,
+        key: Register,
+        count: usize,
+        not_found: Address,
+    },
+    // Jump unless the register is true, so on false and on NULL.
+    IfNot {
+        condition: Register,
+        target: Address,
+    },
+    // Fail unless the register holds an integer, as LIMIT and OFFSET must.
+    MustBeInt {
+        register: Register,
+    },
+    // Take one off the register and jump, if it is positive.
+    IfPos {
+        register: Register,
+        target: Address,
+    },
+    // Take one off the register, if it is positive, and jump if that leaves it at 0.
+    DecrJumpZero {
+        register: Register,
+        target: Address,
+    },
+    // Add a row's value to an aggregate, or just count the row for COUNT(*).
+    AggStep {
+        aggregate: usize,
+        argument: Option<Register>,
+    },
+    AggFinal {
+        aggregate: usize,
+        target: Register,
+    },
+    ResultRow {
+        start: Register,
+        count: usize,
+    },
+    Halt,
+}
+
+impl Instruction {
+    fn opcode(&self) -> &'static str {
+        match self {
+            Instruction::Init { .. } => "Init",
+            Instruction::OpenRead { .. } => "OpenRead",
+            Instruction::Rewind { .. } => "Rewind",
+            Instruction::SeekIndex { .. } => "SeekIndex",
+            Instruction::VFilter { .. } => "VFilter",
+            Instruction::Next { .. } => "Next",
+            Instruction::Column { .. } => "Column",
+            Instruction::Rowid { .. } => "Rowid",
+            Instruction::Integer { .. } => "Integer",
+            Instruction::Real { .. } => "Real",
+            Instruction::String8 { .. } => "String8",
+            Instruction::Blob { .. } => "Blob",
+            Instruction::Boolean { .. } => "Boolean",
+            Instruction::Null { .. } => "Null",
+            Instruction::Copy { .. } => "Copy",
+            Instruction::Expr { .. } => "Expr",
+            Instruction::JoinExpr { .. } => "JoinExpr",
+            Instruction::Affinity { .. } => "Affinity",
+            Instruction::HashInsert { .. } => "HashInsert",
+            Instruction::HashSeek { .. } => "HashSeek",
+            Instruction::IfNot { .. } => "IfNot",
+            Instruction::MustBeInt { .. } => "MustBeInt",
+            Instruction::IfPos { .. } => "IfPos",
+            Instruction::DecrJumpZero { .. } => "DecrJumpZero",
+            Instruction::AggStep { .. } => "AggStep",
+            Instruction::AggFinal { .. } => "AggFinal",
+            Instruction::ResultRow { .. } => "ResultRow",
+            Instruction::Halt => "Halt",
+        }
+    }
+
+    // p1 to p4 as EXPLAIN lists them, registers and cursors and jumps in p1 to p3 and
+    // anything else in p4.
+    fn operands(&self) -> [ColVal; 4] {
+        let n = |n: usize| ColVal::Int(n as i64);
+        let text = |s: &str| ColVal::String(s.to_string());
+        let null = || ColVal::Null;
+        match self {
+            Instruction::Init { start } => [n(0), n(*start), null(), null()],
+            Instruction::OpenRead { cursor, table } => [n(*cursor), null(), null(), text(table)],
+            Instruction::Rewind { cursor, if_empty } => [n(*cursor), n(*if_empty), null(), null()],
+            Instruction::SeekIndex {
+                cursor,
+                index,
+                key,
+                count,
+                lower,
+                upper,
+                not_found,
+            } => {
+                // the bounds' registers after the key's, say `idx_age 0 >r1 <=r2`
+                let mut p4 = format!("{index} {count}");
+                match lower {
+                    Bound::Included(r) => p4.push_str(&format!(" >=r{r}")),
+                    Bound::Excluded(r) => p4.push_str(&format!(" >r{r}")),
+                    Bound::Unbounded => {}
+                }
+                match upper {
+                    Bound::Included(r) => p4.push_str(&format!(" <=r{r}")),
+                    Bound::Excluded(r) => p4.push_str(&format!(" <r{r}")),
+                    Bound::Unbounded => {}
+                }
+                [n(*cursor), n(*not_found), n(*key), text(&p4)]
+            }
+            Instruction::VFilter {
+                cursor,
+                number,
+                args,
+                count,
+                if_empty,
+            } => [
+                n(*cursor),
+                n(*if_empty),
+                n(*args),
+                text(&format!("{number} {count}")),
+            ],
+            Instruction::Next { cursor, target } => [n(*cursor), n(*target), null(), null()],
+            Instruction::Column {
+                cursor,
+                column,
+                target,
+            } => [n(*cursor), n(*column), n(*target), null()],
+            Instruction::Rowid { cursor, target } => [n(*cursor), n(*target), null(), null()],
+            Instruction::Integer { value, target } => {
+                [ColVal::Int(*value), n(*target), null(), null()]
+            }
+            Instruction::Real { value, target } => [n(0), n(*target), null(), ColVal::Real(*value)],
+            Instruction::String8 { value, target } => [n(0), n(*target), null(), text(value)],
+            Instruction::Blob { value, target } => [
+                n(value.len()),
+                n(*target),
+                null(),
+                ColVal::Blob(value.clone()),
+            ],
+            Instruction::Boolean { value, target } => {
+                [ColVal::Int(*value as i64), n(*target), null(), null()]
+            }
+            Instruction::Null { target } => [n(0), n(*target), null(), null()],
+            Instruction::Copy { source, target } => [n(*source), n(*target), null(), null()],
+            Instruction::Expr {
+                cursor,
+                expr,
+                target,
+            } => [
+                cursor.map_or(ColVal::Null, n),
+                n(*target),
+                null(),
+                text(&expr.to_string()),
+            ],
+            Instruction::JoinExpr { row, expr, target } => {
+                [n(*row), n(*target), null(), text(&expr.to_string())]
+            }
+            Instruction::Affinity { register, affinity } => [
+                n(*register),
+                null(),
+                null(),
+                text(&format!("{affinity:?}").to_uppercase()),
+            ],
+            Instruction::HashInsert { cursor, key, count } => {
+                [n(*cursor), null(), n(*key), n(*count)]
+            }
+            Instruction::HashSeek {
+                cursor,
+                key,
+                count,
+                not_found,
+            } => [n(*cursor), n(*not_found), n(*key), n(*count)],
+            Instruction::IfNot { condition, target } => [n(*condition), n(*target), null(), null()],
+            Instruction::MustBeInt { register } => [n(*register), null(), null(), null()],
+            Instruction::IfPos { register, target }
+            | Instruction::DecrJumpZero { register, target } => {
+                [n(*register), n(*target), null(), null()]
+            }
+            Instruction::AggStep {
+                aggregate,
+                argument,
+            } => [
+                n(*aggregate),
+                argument.map_or(ColVal::Null, n),
+                null(),
+                null(),
+            ],
+            Instruction::AggFinal { aggregate, target } => {
+                [n(*aggregate), n(*target), null(), null()]
+            }
+            Instruction::ResultRow { start, count } => [n(*start), n(*count), null(), null()],
+            Instruction::Halt => [null(), null(), null(), null()],
+        }
+    }
+
+    // Point a jump that was emitted before its target was known.
+    fn set_jump(&mut self, to: Address) {
+        match self {
+            Instruction::Init { start: target }
+            | Instruction::Rewind {
+                if_empty: target, ..
+            }
+            | Instruction::SeekIndex {
+                not_found: target, ..
+            }
+            | Instruction::VFilter {
+                if_empty: target, ..
+            }
+            | Instruction::HashSeek {
+                not_found: target, ..
+            }
+            | Instruction::Next { target, .. }
+            | Instruction::IfNot { target, .. }
+            | Instruction::IfPos { target, .. }
+            | Instruction::DecrJumpZero { target, .. } => *target = to,
+            other => unreachable!("{} doesn't jump", other.opcode()),
+        }
+    }
+}
+
+/// A row as a cursor sees it: its rowid, None for a WITHOUT ROWID table's, and its values.
+pub type CursorRow = (Option<RowId>, Vec<ColVal>);
+
+/// The rows a program reads and the expressions it can't evaluate itself, usually the
+/// Executor's.
+pub trait Database {
+    /// The rows of `table` in order, read as the cursor moves on.
+    fn table_rows(&self, table: &str) -> Result<Box<dyn Iterator<Item = CursorRow> + '_>>;
+
+    /// The rows of `table` whose leading columns of `index` equal `key` and whose next
+    /// column is between `lower` and `upper`.
+    fn index_rows(
+        &self,
+        table: &str,
+        index: &str,
+        key: &[ColVal],
+        lower: Bound<&ColVal>,
+        upper: Bound<&ColVal>,
+    ) -> Result<Box<dyn Iterator<Item = CursorRow> + '_>>;
+
+    /// The rows of virtual table `table` its cursor's filter picks out with `number` and
+    /// `values`, read off the cursor as it moves on, see vtab.rs.
+    fn virtual_rows(
+        &self,
+        table: &str,
+        number: i64,
+        values: &[ColVal],
+    ) -> Result<Box<dyn Iterator<Item = Result<CursorRow>> + '_>>;
+
+    /// Evaluate an expression over a row of `table`, or over no row at all.
+    fn eval(
+        &self,
+        expr: &Expr,
+        table: Option<&str>,
+        columns: &[String],
+        row: &[ColVal],
+        rowid: Option<RowId>,
+    ) -> Result<ColVal>;
+
+    /// Fail with "interrupted" if the statement has been interrupted, see interrupt.rs.
+    fn check_interrupt(&self) -> Result<()>;
+
+    /// Check for an interrupt when a cursor reaches its `row`th row, every so many rows.
+    fn check_interrupt_at(&self, row: usize) -> Result<()> {
+        if row % CHECK_EVERY == 0 {
+            self.check_interrupt()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct CursorTable {
+    table: String,
+    columns: Vec<String>,
+}
+
+/// A compiled query.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    /// The names of the result columns.
+    pub columns: Vec<String>,
+    registers: usize,
+    cursors: Vec<CursorTable>,
+    aggregates: Vec<Aggregate>,
+    // a join's columns, `name.column`, in the order its row's registers hold them
+    joined: Vec<String>,
+}
+
+// A cursor walks its table's B+tree, the rows an index search found or a virtual table's
+// own cursor, a row at a time as Next moves it on.
+struct Cursor<'a> {
+    rows: Box<dyn Iterator<Item = Result<CursorRow>> + 'a>,
+    // the row it is on, None once it has gone past the last
+    row: Option<CursorRow>,
+    // how many rows it has moved on, to check for an interrupt every so many
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(mut rows: Box<dyn Iterator<Item = Result<CursorRow>> + 'a>) -> Result<Self> {
+        Ok(Cursor {
+            row: rows.next().transpose()?,
+            rows,
+            position: 0,
+        })
+    }
+}
+
+impl Program {
+    /// The program as EXPLAIN shows it, one row per instruction.
+    pub fn explain(&self) -> RowSet {
+        RowSet {
+            columns: ["addr", "opcode", "p1", "p2", "p3", "p4"]
+                .map(str::to_string)
+                .to_vec(),
+            rows: self
+                .instructions
+                .iter()
+                .enumerate()
+                .map(|(addr, instruction)| {
+                    let mut row = vec![
+                        ColVal::Int(addr as i64),
+                        ColVal::String(instruction.opcode().to_string()),
+                    ];
+                    row.extend(instruction.operands());
+                    row
+                })
+                .collect(),
+            changes: None,
+        }
+    }
+
+    /// Run the program to the end, for all of its rows at once.
+    pub fn run(&self, db: &dyn Database) -> Result<Vec<Vec<ColVal>>> {
+        Machine::new(Cow::Borrowed(self), db).collect()
+    }
+}
+
+/// A program being run, which hands out its result rows one at a time as each is asked
+/// for, running on from where it stopped to the next ResultRow.
+pub struct Machine<'a> {
+    program: Cow<'a, Program>,
+    db: &'a dyn Database,
+    pc: Address,
+    registers: Vec<ColVal>,
+    cursors: Vec<Option<Cursor<'a>>>,
+    // the rows of each cursor's hash table by key, for a join
+    hashes: Vec<HashMap<Vec<ColVal>, Vec<CursorRow>>>,
+    accumulators: Vec<Accumulator>,
+    // set once it has halted or failed, after which it hands out no more rows
+    done: bool,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(program: Cow<'a, Program>, db: &'a dyn Database) -> Self {
+        Machine {
+            registers: vec![ColVal::Null; program.registers],
+            cursors: program.cursors.iter().map(|_| None).collect(),
+            hashes: program.cursors.iter().map(|_| HashMap::new()).collect(),
+            accumulators: program.aggregates.iter().map(Accumulator::new).collect(),
+            pc: 0,
+            program,
+            db,
+            done: false,
+        }
+    }
+
+    /// The names of the result columns.
+    pub fn columns(&self) -> &[String] {
+        &self.program.columns
+    }
+
+    // Run on to the next result row, or None at the Halt.
+    fn step(&mut self) -> Result<Option<Vec<ColVal>>> {
+        let db = self.db;
+        let program = &*self.program;
+        let registers = &mut self.registers;
+        let cursors = &mut self.cursors;
+        let hashes = &mut self.hashes;
+        let accumulators = &mut self.accumulators;
+        loop {
+            let instruction = &program.instructions[self.pc];
+            self.pc += 1;
+            match instruction {
+                Instruction::Init { start } => self.pc = *start,
+                Instruction::OpenRead { cursor, .. } => {
+                    cursors[*cursor] = Some(Cursor::new(Box::new(std::iter::empty()))?)
+                }
+                Instruction::Rewind { cursor, if_empty } => {
+                    let rows = db.table_rows(&program.cursors[*cursor].table)?;
+                    let c = Cursor::new(Box::new(rows.map(Ok)))?;
+                    if c.row.is_none() {
+                        self.pc = *if_empty;
+                    }
+                    cursors[*cursor] = Some(c);
+                }
+                Instruction::SeekIndex {
+                    cursor,
+                    index,
+                    key,
+                    count,
+                    lower,
+                    upper,
+                    not_found,
+                } => {
+                    let table = &program.cursors[*cursor].table;
+                    let rows = db.index_rows(
+                        table,
+                        index,
+                        &registers[*key..key + count],
+                        lower.map(|r| &registers[r]),
+                        upper.map(|r| &registers[r]),
+                    )?;
+                    let c = Cursor::new(Box::new(rows.map(Ok)))?;
+                    if c.row.is_none() {
+                        self.pc = *not_found;
+                    }
+                    cursors[*cursor] = Some(c);
+                }
+                Instruction::VFilter {
+                    cursor,
+                    number,
+                    args,
+                    count,
+                    if_empty,
+                } => {
+                    let table = &program.cursors[*cursor].table;
+                    let rows = db.virtual_rows(table, *number, &registers[*args..args + count])?;
+                    let c = Cursor::new(rows)?;
+                    if c.row.is_none() {
+                        self.pc = *if_empty;
+                    }
+                    cursors[*cursor] = Some(c);
+                }
+                Instruction::Next { cursor, target } => {
+                    let Some(c) = cursors[*cursor].as_mut() else {
+                        bail!("cursor {cursor} is not open");
+                    };
+                    c.position += 1;
+                    db.check_interrupt_at(c.position)?;
+                    c.row = c.rows.next().transpose()?;
+                    if c.row.is_some() {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::Column {
+                    cursor,
+                    column,
+                    target,
+                } => {
+                    registers[*target] = current(cursors, *cursor)
+                        .map_or(ColVal::Null, |(_, row)| row[*column].clone());
+                }
+                Instruction::Rowid { cursor, target } => {
+                    registers[*target] = match current(cursors, *cursor) {
+                        Some((Some(rowid), _)) => ColVal::Int(*rowid),
+                        _ => ColVal::Null,
+                    };
+                }
+                Instruction::Integer { value, target } => registers[*target] = ColVal::Int(*value),
+                Instruction::Real { value, target } => registers[*target] = ColVal::Real(*value),
+                Instruction::String8 { value, target } => {
+                    registers[*target] = ColVal::String(value.clone())
+                }
+                Instruction::Blob { value, target } => {
+                    registers[*target] = ColVal::Blob(value.clone())
+                }
+                Instruction::Boolean { value, target } => {
+                    registers[*target] = ColVal::Boolean(*value)
+                }
+                Instruction::Null { target } => registers[*target] = ColVal::Null,
+                Instruction::Copy { source, target } => {
+                    registers[*target] = registers[*source].clone()
+                }
+                Instruction::Expr {
+                    cursor,
+                    expr,
+                    target,
+                } => {
+                    registers[*target] = match cursor {
+                        Some(cursor) => {
+                            let table = &program.cursors[*cursor];
+                            let (rowid, row) = current(cursors, *cursor)
+                                .map_or((None, &[][..]), |(rowid, row)| (*rowid, &row[..]));
+                            db.eval(expr, Some(&table.table), &table.columns, row, rowid)?
+                        }
+                        None => db.eval(expr, None, &[], &[], None)?,
+                    };
+                }
+                Instruction::JoinExpr { row, expr, target } => {
+                    let values = &registers[*row..row + program.joined.len()];
+                    registers[*target] = db.eval(expr, None, &program.joined, values, None)?;
+                }
+                Instruction::Affinity { register, affinity } => {
+                    registers[*register] =
+                        affinity.apply(std::mem::replace(&mut registers[*register], ColVal::Null));
+                }
+                Instruction::HashInsert { cursor, key, count } => {
+                    let key = &registers[*key..key + count];
+                    if let (false, Some(row)) =
+                        (key.contains(&ColVal::Null), current(cursors, *cursor))
+                    {
+                        hashes[*cursor]
+                            .entry(key.to_vec())
+                            .or_default()
+                            .push(row.clone());
+                    }
+                }
+                Instruction::HashSeek {
+                    cursor,
+                    key,
+                    count,
+                    not_found,
+                } => {
+                    let rows = hashes[*cursor]
+                        .get(&registers[*key..key + count])
+                        .cloned()
+                        .unwrap_or_default();
+                    let c = Cursor::new(Box::new(rows.into_iter().map(Ok)))?;
+                    if c.row.is_none() {
+                        self.pc = *not_found;
+                    }
+                    cursors[*cursor] = Some(c);
+                }
+                Instruction::IfNot { condition, target } => {
+                    if eval::truth(&registers[*condition]) != Some(true) {
+                        self.pc = *target;
+                    }
+                }
+                Instruction::MustBeInt { register } => {
+                    registers[*register] = match &registers[*register] {
+                        ColVal::Int(n) => ColVal::Int(*n),
+                        ColVal::Boolean(b) => ColVal::Int(*b as i64),
+                        _ => bail!(SqlError::DatatypeMismatch),
+                    };
+                }
+                Instruction::IfPos { register, target } => {
+                    if let ColVal::Int(n) = &mut registers[*register] {
+                        if *n > 0 {
+                            *n -= 1;
+                            self.pc = *target;
+                        }
+                    }
+                }
+                Instruction::DecrJumpZero { register, target } => {
+                    if let ColVal::Int(n) = &mut registers[*register] {
+                        if *n > 0 {
+                            *n -= 1;
+                            if *n == 0 {
+                                self.pc = *target;
+                            }
+                        }
+                    }
+                }
+                Instruction::AggStep {
+                    aggregate,
+                    argument,
+                } => {
+                    let value = argument.map(|r| registers[r].clone());
+                    accumulators[*aggregate].step(value)?;
+                }
+                Instruction::AggFinal { aggregate, target } => {
+                    registers[*target] = accumulators[*aggregate].finish()
+                }
+                Instruction::ResultRow { start, count } => {
+                    return Ok(Some(registers[*start..start + count].to_vec()))
+                }
+                Instruction::Halt => return Ok(None),
+            }
+        }
+    }
+}
+
+// The row `cursor` is on, if it is open and hasn't gone past its last.
+fn current<'c>(cursors: &'c [Option<Cursor<'_>>], cursor: usize) -> Option<&'c CursorRow> {
+    cursors[cursor].as_ref()?.row.as_ref()
+}
+
+impl Iterator for Machine<'_> {
+    type Item = Result<Vec<ColVal>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let step = self.step().transpose();
+        self.done = !matches!(step, Some(Ok(_)));
+        step
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    instructions: Vec<Instruction>,
+    registers: usize,
+    // the registers counting down LIMIT's rows and OFFSET's, if the query has a LIMIT
+    limit: Option<(Register, Option<Register>)>,
+    // the jumps to the Halt, emitted before it was
+    halts: Vec<Address>,
+}
+
+impl Compiler {
+    fn emit(&mut self, instruction: Instruction) -> Address {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    fn here(&self) -> Address {
+        self.instructions.len()
+    }
+
+    fn registers(&mut self, count: usize) -> Register {
+        self.registers += count;
+        self.registers - count
+    }
+
+    fn jump_here(&mut self, from: Address) {
+        let here = self.here();
+        self.instructions[from].set_jump(here);
+    }
+
+    // Hand out the `count` registers from `start` as a result row once OFFSET's rows have
+    // been skipped, and halt once LIMIT's have been handed out. Returns the jump past the
+    // row taken while skipping, to be pointed at where the next row is read.
+    fn result_row(&mut self, start: Register, count: usize) -> Option<Address> {
+        let skip = match self.limit {
+            Some((_, Some(offset))) => Some(self.emit(Instruction::IfPos {
+                register: offset,
+                target: 0,
+            })),
+            _ => None,
+        };
+        self.emit(Instruction::ResultRow { start, count });
+        if let Some((limit, _)) = self.limit {
+            let done = self.emit(Instruction::DecrJumpZero {
+                register: limit,
+                target: 0,
+            });
+            self.halts.push(done);
+        }
+        skip
+    }
+
+    // Work out LIMIT's count and OFFSET's once before the first row. A LIMIT of 0 reads no
+    // rows at all, and a negative one never counts down to 0, so has no limit.
+    fn count_limit(&mut self, limit: &Limit) {
+        let count = self.integer(&limit.count);
+        let none = self.emit(Instruction::IfNot {
+            condition: count,
+            target: 0,
+        });
+        self.halts.push(none);
+        let offset = limit.offset.as_ref().map(|offset| self.integer(offset));
+        self.limit = Some((count, offset));
+    }
+
+    // Work out `predicate` over `cursor`'s row into `register`, returning the jump that
+    // skips the row unless it is true.
+    fn filter(&mut self, cursor: usize, predicate: &Expr, register: Register) -> Address {
+        self.emit(Instruction::Expr {
+            cursor: Some(cursor),
+            expr: predicate.clone(),
+            target: register,
+        });
+        self.emit(Instruction::IfNot {
+            condition: register,
+            target: 0,
+        })
+    }
+
+    // Give the register's value `affinity`, if there is one to give it.
+    fn affinity(&mut self, register: Register, affinity: Option<Affinity>) {
+        if let Some(affinity) = affinity {
+            self.emit(Instruction::Affinity { register, affinity });
+        }
+    }
+
+    // Step each aggregate with its argument, `load` giving the instruction that loads the
+    // argument's column into its register.
+    fn aggregate_step(
+        &mut self,
+        arguments: &[Option<(usize, Register)>],
+        load: impl Fn(usize, Register) -> Instruction,
+    ) {
+        for (aggregate, argument) in arguments.iter().enumerate() {
+            if let Some((column, register)) = argument {
+                self.emit(load(*column, *register));
+            }
+            self.emit(Instruction::AggStep {
+                aggregate,
+                argument: argument.map(|(_, register)| register),
+            });
+        }
+    }
+
+    // Past the last row, hand out the aggregates' results as the one result row if there
+    // are any, then halt, where every earlier jump to the Halt goes too.
+    fn finish(&mut self, body: &Body, aggregates: Option<&Vec<Aggregate>>, output: Register) {
+        if let Body::Aggregate { results, .. } = body {
+            let count = aggregates.map_or(0, |a| a.len());
+            let finals = self.registers(count);
+            for aggregate in 0..count {
+                self.emit(Instruction::AggFinal {
+                    aggregate,
+                    target: finals + aggregate,
+                });
+            }
+            // past the last row, an expression can only be worked out from no row
+            let load = |i, target| Instruction::Copy {
+                source: finals + i,
+                target,
+            };
+            emit_sources(self, results, output, load, None);
+            if let Some(skip) = self.result_row(output, results.len()) {
+                self.jump_here(skip);
+            }
+        }
+        for halt in std::mem::take(&mut self.halts) {
+            self.jump_here(halt);
+        }
+        self.emit(Instruction::Halt);
+    }
+
+    // An integer, for LIMIT or OFFSET, worked out into a register of its own.
+    fn integer(&mut self, expr: &Expr) -> Register {
+        let register = self.registers(1);
+        self.emit(Instruction::Expr {
+            cursor: None,
+            expr: expr.clone(),
+            target: register,
+        });
+        self.emit(Instruction::MustBeInt { register });
+        register
+    }
+
+    fn load(&mut self, value: &ColVal, target: Register) {
+        self.emit(match value {
+            ColVal::Null => Instruction::Null { target },
+            ColVal::Int(value) => Instruction::Integer {
+                value: *value,
+                target,
+            },
+            ColVal::Real(value) => Instruction::Real {
+                value: *value,
+                target,
+            },
+            ColVal::String(value) => Instruction::String8 {
+                value: value.clone(),
+                target,
+            },
+            ColVal::Blob(value) => Instruction::Blob {
+                value: value.clone(),
+                target,
+            },
+            ColVal::Boolean(value) => Instruction::Boolean {
+                value: *value,
+                target,
+            },
+        });
+    }
+}
+
+// Where a result column's value comes from: a column, of the row or of the aggregates'
+// results, the row's rowid, a constant, or an expression worked out from the row.
+enum Source {
+    Column(usize),
+    Rowid,
+    Constant(i64),
+    Expr(Expr),
+}
+
+// The sources of `names` among the `available` columns, and the rowid if `has_rowid`.
+fn sources(names: &[String], available: &[String], has_rowid: bool) -> Result<Vec<Source>> {
+    names
+        .iter()
+        .map(|name| match available.iter().position(|a| a == name) {
+            Some(i) => Ok(Source::Column(i)),
+            None if has_rowid && planner::is_rowid(name, available) => Ok(Source::Rowid),
+            None => match name.parse::<i64>() {
+                Ok(n) => Ok(Source::Constant(n)),
+                Err(_) => Ok(Source::Expr(parse_expr(name)?)),
+            },
+        })
+        .collect()
+}
+
+/// Compile a query's plan, None if it isn't a kind of query the machine can run yet.
+pub fn compile(plan: &Plan, catalog: &dyn Catalog) -> Result<Option<Program>> {
+    let Plan::Project { input, columns } = plan else {
+        return Ok(None);
+    };
+    let (limit, input) = match input.as_ref() {
+        Plan::Limit { input, limit } => (Some(limit), input.as_ref()),
+        input => (None, input),
+    };
+    let (aggregates, rows) = match input {
+        Plan::Aggregate {
+            input,
+            aggregates,
+            group_by,
+        } if group_by.is_empty() => (Some(aggregates), input.as_ref()),
+        rows => (None, rows),
+    };
+    let (source, predicate) = match rows {
+        Plan::Filter { input, predicate } => (input.as_ref(), Some(predicate)),
+        rows => (rows, None),
+    };
+    if let Plan::Join { tables } = source {
+        return compile_join(tables, columns, limit, aggregates, predicate, catalog);
+    }
+    let Some(table) = table_of(source) else {
+        return Ok(None);
+    };
+    let table_columns = catalog.table_columns(table)?;
+
+    let mut c = Compiler::default();
+    let init = c.emit(Instruction::Init { start: 0 });
+    c.jump_here(init);
+    c.emit(Instruction::OpenRead {
+        cursor: 0,
+        table: table.clone(),
+    });
+    if let Some(limit) = limit {
+        c.count_limit(limit);
+    }
+
+    // What each row passing the WHERE clause is turned into: a result row, or a step of
+    // every aggregate.
+    let output = c.registers(columns.len());
+    let body = match aggregates {
+        Some(aggregates) => aggregate_body(&mut c, aggregates, columns, &table_columns)?,
+        None => Body::Result(sources(columns, &table_columns, catalog.has_rowid(table))?),
+    };
+
+    let filter = predicate.map(|predicate| (predicate, c.registers(1)));
+    let load = |column, target| Instruction::Column {
+        cursor: 0,
+        column,
+        target,
+    };
+    emit_scan(&mut c, 0, source, &mut |c| {
+        let mut skips = vec![];
+        if let Some((predicate, register)) = filter {
+            skips.push(c.filter(0, predicate, register));
+        }
+        match &body {
+            Body::Result(sources) => {
+                emit_sources(c, sources, output, load, Some(0));
+                skips.extend(c.result_row(output, sources.len()));
+            }
+            Body::Aggregate { arguments, .. } => c.aggregate_step(arguments, load),
+        }
+        skips
+    });
+    c.finish(&body, aggregates, output);
+
+    Ok(Some(Program {
+        instructions: c.instructions,
+        columns: columns.clone(),
+        registers: c.registers,
+        cursors: vec![CursorTable {
+            table: table.clone(),
+            columns: table_columns,
+        }],
+        aggregates: aggregates.cloned().unwrap_or_default(),
+        joined: vec![],
+    }))
+}
+
+// The table a plan reads its rows from with a cursor of the machine's, None if it doesn't
+// read them from one.
+fn table_of(plan: &Plan) -> Option<&String> {
+    match plan {
+        Plan::Scan { table }
+        | Plan::IndexSearch { table, .. }
+        | Plan::VirtualScan { table, .. } => Some(table),
+        _ => None,
+    }
+}
+
+// A table of a join as it is compiled.
+struct Level<'p> {
+    name: &'p str,
+    // where its rows come from, and the terms of the WHERE clause on it alone
+    source: &'p Plan,
+    filter: Option<(&'p Expr, Register)>,
+    // where its columns are among the joined row's, and how many it has
+    start: usize,
+    width: usize,
+    // for each key, the outer column among the joined row's, the table's own column and
+    // the affinity they are compared with, and the registers the keys are loaded into
+    keys: Vec<(usize, usize, Option<Affinity>)>,
+    key: Register,
+}
+
+// Compile a join into a loop over the rows of each table nested in the loop over those of
+// the table joined before it, the first table's outermost. The table's columns are loaded
+// into the registers of the joined row as its cursor reaches each row, and the innermost
+// loop works out the rest of the WHERE clause and the result columns from them.
+fn compile_join(
+    tables: &[JoinTable],
+    columns: &[String],
+    limit: Option<&Limit>,
+    aggregates: Option<&Vec<Aggregate>>,
+    predicate: Option<&Expr>,
+    catalog: &dyn Catalog,
+) -> Result<Option<Program>> {
+    let position = |columns: &[String], name: &str| {
+        columns.iter().position(|c| c == name).ok_or_else(|| {
+            anyhow!(SqlError::NoSuchColumn {
+                column: name.to_string()
+            })
+        })
+    };
+    let mut c = Compiler::default();
+    let mut levels: Vec<Level> = vec![];
+    let mut cursors: Vec<CursorTable> = vec![];
+    let mut joined: Vec<String> = vec![];
+    for JoinTable {
+        name, rows, keys, ..
+    } in tables
+    {
+        let (source, filter) = match rows {
+            Plan::Filter { input, predicate } => (input.as_ref(), Some(predicate)),
+            rows => (rows, None),
+        };
+        let Some(table) = table_of(source) else {
+            return Ok(None);
+        };
+        let table_columns = catalog.table_columns(table)?;
+        let mut matched = vec![];
+        for key in keys {
+            // compared as `outer = inner` would compare them
+            let (outer_name, outer_column) = key.outer.split_once('.').expect("a qualified column");
+            let outer_table = levels
+                .iter()
+                .position(|level| level.name == outer_name)
+                .map(|cursor| cursors[cursor].table.as_str());
+            let affinity = outer_table.and_then(|outer| {
+                eval::comparison_affinity(
+                    catalog.column_affinity(outer, outer_column),
+                    catalog.column_affinity(table, &key.inner),
+                )
+            });
+            matched.push((
+                position(&joined, &key.outer)?,
+                position(&table_columns, &key.inner)?,
+                affinity,
+            ));
+        }
+        levels.push(Level {
+            name,
+            source,
+            filter: filter.map(|predicate| (predicate, c.registers(1))),
+            start: joined.len(),
+            width: table_columns.len(),
+            key: c.registers(matched.len()),
+            keys: matched,
+        });
+        joined.extend(
+            table_columns
+                .iter()
+                .map(|column| format!("{name}.{column}")),
+        );
+        cursors.push(CursorTable {
+            table: table.clone(),
+            columns: table_columns,
+        });
+    }
+
+    let init = c.emit(Instruction::Init { start: 0 });
+    c.jump_here(init);
+    for (cursor, table) in cursors.iter().enumerate() {
+        c.emit(Instruction::OpenRead {
+            cursor,
+            table: table.table.clone(),
+        });
+    }
+    if let Some(limit) = limit {
+        c.count_limit(limit);
+    }
+    let output = c.registers(columns.len());
+    let body = match aggregates {
+        Some(aggregates) => aggregate_body(&mut c, aggregates, columns, &joined)?,
+        None => Body::Result(sources(columns, &joined, false)?),
+    };
+    let row = c.registers(joined.len());
+    let filter = predicate.map(|predicate| (predicate, c.registers(1)));
+
+    // A table matched on keys is read up front into a hash table of its rows by key,
+    // which its cursor is pointed into for each row of the tables before it.
+    for (cursor, level) in levels.iter().enumerate() {
+        if level.keys.is_empty() {
+            continue;
+        }
+        emit_scan(&mut c, cursor, level.source, &mut |c| {
+            let mut skips = vec![];
+            if let Some((predicate, register)) = level.filter {
+                skips.push(c.filter(cursor, predicate, register));
+            }
+            for (i, (_, column, affinity)) in level.keys.iter().enumerate() {
+                c.emit(Instruction::Column {
+                    cursor,
+                    column: *column,
+                    target: level.key + i,
+                });
+                c.affinity(level.key + i, *affinity);
+            }
+            c.emit(Instruction::HashInsert {
+                cursor,
+                key: level.key,
+                count: level.keys.len(),
+            });
+            skips
+        });
+    }
+
+    let load = |column, target| Instruction::Copy {
+        source: row + column,
+        target,
+    };
+    emit_join_loops(&mut c, &levels, 0, row, &mut |c| {
+        let mut skips = vec![];
+        if let Some((predicate, register)) = filter {
+            c.emit(Instruction::JoinExpr {
+                row,
+                expr: predicate.clone(),
+                target: register,
+            });
+            skips.push(c.emit(Instruction::IfNot {
+                condition: register,
+                target: 0,
+            }));
+        }
+        match &body {
+            Body::Result(sources) => {
+                for (i, source) in sources.iter().enumerate() {
+                    let target = output + i;
+                    c.emit(match source {
+                        Source::Column(column) => load(*column, target),
+                        Source::Constant(value) => Instruction::Integer {
+                            value: *value,
+                            target,
+                        },
+                        Source::Expr(expr) => Instruction::JoinExpr {
+                            row,
+                            expr: expr.clone(),
+                            target,
+                        },
+                        Source::Rowid => unreachable!("a join's rows have no rowid"),
+                    });
+                }
+                skips.extend(c.result_row(output, sources.len()));
+            }
+            Body::Aggregate { arguments, .. } => c.aggregate_step(arguments, load),
+        }
+        skips
+    });
+    c.finish(&body, aggregates, output);
+
+    Ok(Some(Program {
+        instructions: c.instructions,
+        columns: columns.to_vec(),
+        registers: c.registers,
+        cursors,
+        aggregates: aggregates.cloned().unwrap_or_default(),
+        joined,
+    }))
+}
+
+// Emit the loop over the rows of the first of `levels`, on `cursor`, with the loops over
+// the rest nested inside it and `body` innermost. Each row's columns are loaded into the
+// joined row's registers from `row`. A table without keys is read again for each row of
+// those before it, one with keys looked up in its hash table by the keys of their row.
+fn emit_join_loops(
+    c: &mut Compiler,
+    levels: &[Level],
+    cursor: usize,
+    row: Register,
+    body: &mut dyn FnMut(&mut Compiler) -> Vec<Address>,
+) {
+    let (level, inner) = levels.split_first().expect("a table to join");
+    let mut each_row = |c: &mut Compiler| {
+        let mut skips = vec![];
+        if let (true, Some((predicate, register))) = (level.keys.is_empty(), level.filter) {
+            skips.push(c.filter(cursor, predicate, register));
+        }
+        for column in 0..level.width {
+            c.emit(Instruction::Column {
+                cursor,
+                column,
+                target: row + level.start + column,
+            });
+        }
+        match inner.is_empty() {
+            true => skips.extend(body(c)),
+            false => emit_join_loops(c, inner, cursor + 1, row, &mut *body),
+        }
+        skips
+    };
+    if level.keys.is_empty() {
+        emit_scan(c, cursor, level.source, &mut each_row);
+        return;
+    }
+    for (i, (outer, _, affinity)) in level.keys.iter().enumerate() {
+        c.emit(Instruction::Copy {
+            source: row + outer,
+            target: level.key + i,
+        });
+        c.affinity(level.key + i, *affinity);
+    }
+    let seek = c.emit(Instruction::HashSeek {
+        cursor,
+        key: level.key,
+        count: level.keys.len(),
+        not_found: 0,
+    });
+    for skip in each_row(c) {
+        c.jump_here(skip);
+    }
+    c.emit(Instruction::Next {
+        cursor,
+        target: seek + 1,
+    });
+    c.jump_here(seek);
+}
+
+// Emit the loop over the rows of `source` on `cursor`: what points the cursor at its first
+// row, once for each key an index search seeks, then what `body` emits for each row and
+// the Next back to it. `body` returns the jumps that skip a row, which go to the Next, and
+// a source without rows jumps past the loop.
+fn emit_scan(
+    c: &mut Compiler,
+    cursor: usize,
+    source: &Plan,
+    body: &mut dyn FnMut(&mut Compiler) -> Vec<Address>,
+) {
+    let mut emit_loop = |c: &mut Compiler, open: Address| {
+        for skip in body(c) {
+            c.jump_here(skip);
+        }
+        c.emit(Instruction::Next {
+            cursor,
+            target: open + 1,
+        });
+        c.jump_here(open);
+    };
+    match source {
+        Plan::IndexSearch {
+            index,
+            seeks,
+            range,
+            ..
+        } => {
+            for seek in seeks {
+                let key = c.registers(seek.len());
+                for (i, value) in seek.iter().enumerate() {
+                    c.load(value, key + i);
+                }
+                let mut bound = |bound: Option<&Bound<ColVal>>| match bound {
+                    Some(Bound::Included(value) | Bound::Excluded(value)) => {
+                        let register = c.registers(1);
+                        c.load(value, register);
+                        match bound {
+                            Some(Bound::Included(_)) => Bound::Included(register),
+                            _ => Bound::Excluded(register),
+                        }
+                    }
+                    _ => Bound::Unbounded,
+                };
+                let lower = bound(range.as_ref().map(|r| &r.lower));
+                let upper = bound(range.as_ref().map(|r| &r.upper));
+                let open = c.emit(Instruction::SeekIndex {
+                    cursor,
+                    index: index.clone(),
+                    key,
+                    count: seek.len(),
+                    lower,
+                    upper,
+                    not_found: 0,
+                });
+                emit_loop(c, open);
+            }
+        }
+        Plan::VirtualScan { number, values, .. } => {
+            let args = c.registers(values.len());
+            for (i, value) in values.iter().enumerate() {
+                c.emit(Instruction::Expr {
+                    cursor: None,
+                    expr: value.clone(),
+                    target: args + i,
+                });
+            }
+            let open = c.emit(Instruction::VFilter {
+                cursor,
+                number: *number,
+                args,
+                count: values.len(),
+                if_empty: 0,
+            });
+            emit_loop(c, open);
+        }
+        _ => {
+            let open = c.emit(Instruction::Rewind {
+                cursor,
+                if_empty: 0,
+            });
+            emit_loop(c, open);
+        }
+    }
+}
+
+// Each aggregate and the column of the `available` its argument is, with a register to
+// load it into, and where each of `columns` comes from among the aggregates' results.
+fn aggregate_body(
+    c: &mut Compiler,
+    aggregates: &[Aggregate],
+    columns: &[String],
+    available: &[String],
+) -> Result<Body> {
+    let names: Vec<String> = aggregates.iter().map(|a| a.to_string()).collect();
+    let results = sources(columns, &names, false)?;
+    let mut arguments = vec![];
+    for aggregate in aggregates {
+        arguments.push(match &aggregate.arg {
+            Some(arg) => match sources(std::slice::from_ref(arg), available, false)?[0] {
+                Source::Column(i) => Some((i, c.registers(1))),
+                Source::Rowid | Source::Constant(_) | Source::Expr(_) => {
+                    bail!(SqlError::NoSuchColumn {
+                        column: arg.to_string()
+                    })
+                }
+            },
+            None => None,
+        });
+    }
+    Ok(Body::Aggregate { arguments, results })
+}
+
+enum Body {
+    // hand out a row of these
+    Result(Vec<Source>),
+    // step each aggregate with the column given, then hand out one row at the end
+    Aggregate {
+        arguments: Vec<Option<(usize, Register)>>,
+        results: Vec<Source>,
+    },
+}
+
+// Load each source into the registers from `output`, `load` giving the instruction that
+// loads a column into a register, and an expression worked out from `cursor`'s row.
+fn emit_sources(
+    c: &mut Compiler,
+    sources: &[Source],
+    output: Register,
+    load: impl Fn(usize, Register) -> Instruction,
+    cursor: Option<usize>,
+) {
+    for (i, source) in sources.iter().enumerate() {
+        let target = output + i;
+        match source {
+            Source::Column(column) => {
+                c.emit(load(*column, target));
+            }
+            Source::Rowid => {
+                c.emit(Instruction::Rowid { cursor: 0, target });
+            }
+            Source::Constant(value) => {
+                c.emit(Instruction::Integer {
+                    value: *value,
+                    target,
+                });
+            }
+            Source::Expr(expr) => {
+                c.emit(Instruction::Expr {
+                    cursor,
+                    expr: expr.clone(),
+                    target,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::Executor;
+    use crate::planner;
+    use crate::sql_parser::parse;
+    use std::cell::Cell;
+
+    fn users() -> Executor {
+        let mut db = Executor::default();
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, age INTEGER);",
+            "CREATE INDEX idx_age ON users (age);",
+            "INSERT INTO users (name, age) VALUES (\"amy\", 30);",
+            "INSERT INTO users (name, age) VALUES (\"bob\", 21);",
+            "INSERT INTO users (name, age) VALUES (\"cat\", 30);",
+        ] {
+            db.execute_sql(sql).unwrap();
+        }
+        db
+    }
+
+    // The executor, counting the rows its cursors read from the tables.
+    struct Counting<'e> {
+        db: &'e Executor,
+        read: Cell<usize>,
+    }
+
+    impl Database for Counting<'_> {
+        fn table_rows(&self, table: &str) -> Result<Box<dyn Iterator<Item = CursorRow> + '_>> {
+            let rows = self.db.table_rows(table)?;
+            Ok(Box::new(
+                rows.inspect(move |_| self.read.set(self.read.get() + 1)),
+            ))
+        }
+
+        fn index_rows(
+            &self,
+            table: &str,
+            index: &str,
+            key: &[ColVal],
+            lower: Bound<&ColVal>,
+            upper: Bound<&ColVal>,
+        ) -> Result<Box<dyn Iterator<Item = CursorRow> + '_>> {
+            self.db.index_rows(table, index, key, lower, upper)
+        }
+
+        fn virtual_rows(
+            &self,
+            table: &str,
+            number: i64,
+            values: &[ColVal],
+        ) -> Result<Box<dyn Iterator<Item = Result<CursorRow>> + '_>> {
+            self.db.virtual_rows(table, number, values)
+        }
+
+        fn eval(
+            &self,
+            expr: &Expr,
+            table: Option<&str>,
+            columns: &[String],
+            row: &[ColVal],
+            rowid: Option<RowId>,
+        ) -> Result<ColVal> {
+            Database::eval(self.db, expr, table, columns, row, rowid)
+        }
+
+        fn check_interrupt(&self) -> Result<()> {
+            self.db.check_interrupt()
+        }
+    }
+
+    fn compiled(db: &Executor, sql: &str) -> Option<Program> {
+        let plan = planner::plan(&parse(sql).unwrap(), db.schema()).unwrap();
+        compile(&plan, db.schema()).unwrap()
+    }
+
+    #[test]
+    fn a_scan_compiles_to_a_loop_over_a_cursor() {
+        let db = users();
+        // NOT INDEXED, or the range would be searched for in idx_age
+        let program = compiled(&db, "SELECT name FROM users NOT INDEXED WHERE age > 25;").unwrap();
+        assert_eq!(
+            program.explain().to_string(),
+            "\
+0|Init|0|1||
+1|OpenRead|0|||users
+2|Rewind|0|8||
+3|Expr|0|1||age > 25
+4|IfNot|1|7||
+5|Column|0|1|0|
+6|ResultRow|0|1||
+7|Next|0|3||
+8|Halt||||"
+        );
+        let text = |s: &str| vec![ColVal::String(s.to_string())];
+        assert_eq!(program.run(&db).unwrap(), [text("amy"), text("cat")]);
+    }
+
+    #[test]
+    fn a_range_is_one_bounded_seek() {
+        let db = users();
+        let program =
+            compiled(&db, "SELECT name FROM users WHERE age >= 21 AND age < 30;").unwrap();
+        let text = |s: &str| ColVal::String(s.to_string());
+        let seeks: Vec<ColVal> = program
+            .explain()
+            .rows
+            .into_iter()
+            .filter(|row| row[1] == text("SeekIndex"))
+            .map(|row| row[5].clone())
+            .collect();
+        // the key is empty, the bounds are in registers 1 and 2
+        assert_eq!(seeks, [text("idx_age 0 >=r1 <r2")]);
+        assert_eq!(program.run(&db).unwrap(), [[text("bob")]]);
+    }
+
+    #[test]
+    fn index_seeks_and_aggregates_run() {
+        let db = users();
+        let program = compiled(
+            &db,
+            "SELECT COUNT(*), MAX(id) FROM users WHERE age IN (21, 30);",
+        )
+        .unwrap();
+        let seeks = program
+            .instructions
+            .iter()
+            .filter(|i| matches!(i, Instruction::SeekIndex { .. }))
+            .count();
+        assert_eq!(seeks, 2);
+        assert_eq!(
+            program.run(&db).unwrap(),
+            [[ColVal::Int(3), ColVal::Int(3)]]
+        );
+
+        // no rows still gives one row of aggregates
+        let program = compiled(&db, "SELECT COUNT(*), 7 FROM users WHERE age = 99;").unwrap();
+        assert_eq!(
+            program.run(&db).unwrap(),
+            [[ColVal::Int(0), ColVal::Int(7)]]
+        );
+
+        // views aren't compiled yet
+        let mut db = db;
+        db.execute_sql("CREATE VIEW names AS SELECT name FROM users;")
+            .unwrap();
+        assert!(compiled(&db, "SELECT * FROM names;").is_none());
+    }
+
+    #[test]
+    fn rows_are_read_from_the_table_as_they_are_asked_for() {
+        let mut db = Executor::default();
+        db.execute_sql("CREATE TABLE t (n INTEGER);").unwrap();
+        for n in 0..1000 {
+            db.execute_sql(&format!("INSERT INTO t (n) VALUES ({n});"))
+                .unwrap();
+        }
+        let program = compiled(&db, "SELECT n FROM t WHERE n >= 10;").unwrap();
+        let counting = Counting {
+            db: &db,
+            read: Cell::new(0),
+        };
+        let mut machine = Machine::new(Cow::Borrowed(&program), &counting);
+        assert_eq!(machine.next().unwrap().unwrap(), [ColVal::Int(10)]);
+        // the rows up to the first that passed, and no more
+        assert_eq!(counting.read.get(), 11);
+        assert_eq!(machine.count(), 989);
+        assert_eq!(counting.read.get(), 1000);
+    }
+
+    #[test]
+    fn a_join_streams_its_first_table_past_the_others_hash_tables() {
+        let mut db = Executor::default();
+        db.execute_sql("CREATE TABLE t (n INTEGER);").unwrap();
+        db.execute_sql("CREATE TABLE s (n TEXT, name TEXT);")
+            .unwrap();
+        for n in 0..1000 {
+            db.execute_sql(&format!("INSERT INTO t (n) VALUES ({n});"))
+                .unwrap();
+        }
+        for (n, name) in [(20, "b"), (10, "a"), (20, "c")] {
+            db.execute_sql(&format!("INSERT INTO s (n, name) VALUES ({n}, '{name}');"))
+                .unwrap();
+        }
+        db.execute_sql("INSERT INTO s (n, name) VALUES (NULL, 'd');")
+            .unwrap();
+        let program = compiled(&db, "SELECT t.n, s.name FROM t JOIN s ON t.n = s.n;").unwrap();
+        let opcodes: Vec<&str> = program.instructions.iter().map(|i| i.opcode()).collect();
+        assert!(opcodes.contains(&"HashInsert") && opcodes.contains(&"HashSeek"));
+
+        let counting = Counting {
+            db: &db,
+            read: Cell::new(0),
+        };
+        let mut machine = Machine::new(Cow::Borrowed(&program), &counting);
+        // s's rows are read into its hash table, t's up to the first that matches, and
+        // the text keys compared with the integers as numbers
+        let row = |n, name: &str| vec![ColVal::Int(n), ColVal::String(name.to_string())];
+        assert_eq!(machine.next().unwrap().unwrap(), row(10, "a"));
+        assert_eq!(counting.read.get(), 4 + 11);
+        // each of t's rows pairs with s's in the order they were inserted
+        assert_eq!(
+            machine.collect::<Result<Vec<_>>>().unwrap(),
+            [row(20, "b"), row(20, "c")]
+        );
+
+        // a table without keys is read again for each row before it
+        let program = compiled(
+            &db,
+            "SELECT t.n, s.name FROM t, s WHERE t.n < 2 AND s.name <> 'b' LIMIT 5;",
+        )
+        .unwrap();
+        assert_eq!(
+            program.run(&db).unwrap(),
+            [
+                row(0, "a"),
+                row(0, "c"),
+                row(0, "d"),
+                row(1, "a"),
+                row(1, "c")
+            ]
+        );
+    }
+}
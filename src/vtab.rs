@@ -0,0 +1,867 @@
+/*
+    Virtual tables, whose rows are made by Rust code rather than read from the database's
+    own storage, as with SQLite's sqlite3_module. A module makes tables and a query reads
+    a table's rows through a cursor:
+
+        CREATE VIRTUAL TABLE sales USING csv(filename = "sales.csv", header = yes);
+        SELECT region FROM sales WHERE amount = "100";
+
+    CREATE VIRTUAL TABLE hands the module the arguments after its name, as written, and
+    the module makes a VirtualTable of them or fails. The statement is kept in
+    sqlite_master like any other, so the table is made again whenever the database is
+    opened. Should its module not have been created yet, as when a program opens the
+    database before creating its modules, the table is there but can't be read until
+    the module is: reading it fails with "no such module".
+
+    The planner asks a table which terms of the WHERE clause it can make use of.
+    best_index is shown each term comparing one of the table's columns with a value,
+    `amount = 100` or `5 < value`, and answers with those it will take, in the order it
+    wants their values, and a number of its own choosing. A scan then opens a cursor,
+    calls filter with that number and the values, and reads rows with eof, column and
+    next as the query asks for them, until they run out or the query has all it needs,
+    as a LIMIT does. The terms also stay in the filter above the scan, so a table that
+    only narrows its rows down roughly, or not at all, still gives the right answer.
+    EXPLAIN QUERY PLAN shows the number as SQLite shows its idxNum:
+
+        SCAN generate_series VIRTUAL TABLE INDEX 3:
+
+    A table can also have hidden columns, its arguments, which `SELECT *` leaves out and
+    which let it be called like a function in FROM. `FROM generate_series(1, 10)` reads
+    generate_series with `start = 1 AND stop = 10` added to the WHERE clause, start and
+    stop being its first two arguments, see resolve.rs.
+
+    Virtual tables are read only, can't be indexed or have triggers, and can't be made
+    in an attached database yet.
+
+    Two come built in. generate_series(start, stop, step) needs no CREATE: its rows are
+    the integers from start up to stop, step apart, step being 1 unless given, and with
+    a negative step the same integers counting down. csv reads the CSV file given as
+    filename=..., whose first line names the columns with header=yes, or which has
+    columns c0, c1 and so on without. Every value is TEXT, as in SQLite's csv extension,
+    and the file is read again by every scan, so the table keeps up with changes to it.
+
+    An embedder adds modules of its own with Connection::create_module.
+*/
+use crate::eval::as_integer;
+use crate::interrupt::InterruptHandle;
+use crate::sql_parser::ast::{ColVal, CreateVirtualTable};
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// How a term of a WHERE clause compares a column with a value.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConstraintOp {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A term `column op value` that best_index may use, the column numbered as in
+/// VirtualCursor::column.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Constraint {
+    pub column: usize,
+    pub op: ConstraintOp,
+}
+
+/// What best_index chose: the constraints whose values filter is given, in that order,
+/// and a number for filter to tell the choice by.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct IndexPlan {
+    pub number: i64,
+    pub used: Vec<usize>,
+}
+
+/// A table whose rows are made by Rust code, see the module comment.
+pub trait VirtualTable: Send + Sync {
+    /// The names of the table's columns.
+    fn columns(&self) -> Vec<String>;
+
+    /// The names of its hidden columns, numbered after the others, which the arguments
+    /// of a call of the table in FROM are given to in order.
+    fn arguments(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Which of `constraints` a scan uses. None by default, and every scan reads every
+    /// row.
+    fn best_index(&self, _constraints: &[Constraint]) -> IndexPlan {
+        IndexPlan::default()
+    }
+
+    /// A cursor to read the table's rows with.
+    fn open(&self) -> Result<Box<dyn VirtualCursor + '_>>;
+}
+
+/// Reads the rows of a virtual table, once filter has said which.
+pub trait VirtualCursor {
+    /// Start at the first of the rows picked out by `number`, from best_index, and the
+    /// values of the constraints it chose.
+    fn filter(&mut self, number: i64, values: &[ColVal]) -> Result<()>;
+
+    /// Whether the cursor has gone past the last row.
+    fn eof(&self) -> bool;
+
+    fn next(&mut self) -> Result<()>;
+
+    /// The value of column `i` of the current row, the hidden columns numbered after the
+    /// others.
+    fn column(&self, i: usize) -> Result<ColVal>;
+}
+
+/// Makes a table of the arguments of CREATE VIRTUAL TABLE.
+pub type Module = dyn Fn(&[String]) -> Result<Box<dyn VirtualTable>> + Send + Sync;
+
+/// Every column of a table, the hidden ones last.
+pub fn all_columns(table: &dyn VirtualTable) -> Vec<String> {
+    let mut columns = table.columns();
+    columns.extend(table.arguments());
+    columns
+}
+
+/// The rows a scan of `table` reads, every column of each, read off its cursor as they
+/// are asked for, so that a scan stopped early, by a LIMIT say, makes no more rows than
+/// it was asked for. An interrupt fails the scan in place of its next row.
+pub fn scan<'t>(
+    table: &'t dyn VirtualTable,
+    number: i64,
+    values: &[ColVal],
+    interrupt: &'t InterruptHandle,
+) -> Result<VirtualRows<'t>> {
+    let mut cursor = table.open()?;
+    cursor.filter(number, values)?;
+    Ok(VirtualRows {
+        cursor,
+        width: all_columns(table).len(),
+        interrupt,
+        position: 0,
+        done: false,
+    })
+}
+
+/// The rows of a scan, see scan.
+pub struct VirtualRows<'t> {
+    cursor: Box<dyn VirtualCursor + 't>,
+    width: usize,
+    interrupt: &'t InterruptHandle,
+    // how many rows it has read, to check for an interrupt every so many
+    position: usize,
+    // set once it has failed, after which it reads no more rows
+    done: bool,
+}
+
+impl VirtualRows<'_> {
+    fn read(&mut self) -> Result<Vec<ColVal>> {
+        self.interrupt.check_row(self.position)?;
+        self.position += 1;
+        let row = (0..self.width)
+            .map(|i| self.cursor.column(i))
+            .collect::<Result<_>>()?;
+        self.cursor.next()?;
+        Ok(row)
+    }
+}
+
+impl Iterator for VirtualRows<'_> {
+    type Item = Result<Vec<ColVal>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.cursor.eof() {
+            return None;
+        }
+        let row = self.read();
+        self.done = row.is_err();
+        Some(row)
+    }
+}
+
+// A virtual table and the statement that made it, None for a built-in one. The table is
+// None until its module is created.
+struct Entry {
+    def: Option<CreateVirtualTable>,
+    table: Option<Arc<dyn VirtualTable>>,
+}
+
+/// The modules there are and the virtual tables made with them.
+pub struct VirtualTables {
+    // keyed by lowercased name, as module names aren't case sensitive
+    modules: BTreeMap<String, Arc<Module>>,
+    tables: BTreeMap<String, Entry>,
+}
+
+impl fmt::Debug for VirtualTables {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} modules, {} virtual tables",
+            self.modules.len(),
+            self.tables.len()
+        )
+    }
+}
+
+impl Default for VirtualTables {
+    fn default() -> Self {
+        let mut tables = VirtualTables {
+            modules: BTreeMap::new(),
+            tables: BTreeMap::new(),
+        };
+        tables.modules.insert("csv".to_string(), Arc::new(csv));
+        tables.tables.insert(
+            "generate_series".to_string(),
+            Entry {
+                def: None,
+                table: Some(Arc::new(Series)),
+            },
+        );
+        tables
+    }
+}
+
+impl VirtualTables {
+    /// Add a module, or replace the one of the same name, and make the tables loaded
+    /// before it was there.
+    pub fn create_module(&mut self, name: &str, module: Arc<Module>) -> Result<()> {
+        let name = name.to_lowercase();
+        for entry in self.tables.values_mut() {
+            match &entry.def {
+                Some(def) if entry.table.is_none() && def.module.to_lowercase() == name => {
+                    entry.table = Some(module(&def.args)?.into());
+                }
+                _ => {}
+            }
+        }
+        self.modules.insert(name, module);
+        Ok(())
+    }
+
+    /// Make the table `def` describes with its module.
+    pub fn create(&mut self, def: &CreateVirtualTable) -> Result<()> {
+        let Some(module) = self.modules.get(&def.module.to_lowercase()) else {
+            bail!("no such module: {}", def.module);
+        };
+        let table = module(&def.args)?.into();
+        self.insert(def, Some(table));
+        Ok(())
+    }
+
+    /// Like create, but a table whose module isn't there yet is made once it is.
+    pub fn load(&mut self, def: &CreateVirtualTable) -> Result<()> {
+        if self.modules.contains_key(&def.module.to_lowercase()) {
+            return self.create(def);
+        }
+        self.insert(def, None);
+        Ok(())
+    }
+
+    fn insert(&mut self, def: &CreateVirtualTable, table: Option<Arc<dyn VirtualTable>>) {
+        let entry = Entry {
+            def: Some(def.clone()),
+            table,
+        };
+        self.tables.insert(def.name.clone(), entry);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tables.contains_key(name)
+    }
+
+    /// Forget the table CREATE VIRTUAL TABLE made as `name`, returning whether there was
+    /// one.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.tables.remove(name).is_some()
+    }
+
+    /// Forget the tables main's catalog made, returning their names. The eponymous ones
+    /// and the modules stay.
+    pub fn drop_main(&mut self) -> Vec<String> {
+        let dropped: Vec<String> = self
+            .tables
+            .iter()
+            .filter(|(name, entry)| entry.def.is_some() && !name.contains('.'))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &dropped {
+            self.tables.remove(name);
+        }
+        dropped
+    }
+
+    /// The tables made by CREATE VIRTUAL TABLE, leaving out eponymous ones like
+    /// generate_series which no database has in its catalog.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tables
+            .iter()
+            .filter(|(_, entry)| entry.def.is_some())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The table called `name`, None if there is none or its module isn't there yet.
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn VirtualTable>> {
+        self.tables.get(name)?.table.as_ref()
+    }
+
+    /// Every column of the table called `name`, None if there is no such table.
+    pub fn columns(&self, name: &str) -> Option<Result<Vec<String>>> {
+        let entry = self.tables.get(name)?;
+        Some(match (&entry.table, &entry.def) {
+            (Some(table), _) => Ok(all_columns(table.as_ref())),
+            (None, Some(def)) => Err(anyhow::anyhow!("no such module: {}", def.module)),
+            (None, None) => unreachable!("built-in tables are made straight away"),
+        })
+    }
+}
+
+// The values of a module's `key = value` arguments, without any quotes around them.
+fn parameters(args: &[String]) -> Result<Vec<(String, String)>> {
+    args.iter()
+        .map(|arg| {
+            let Some((key, value)) = arg.split_once('=') else {
+                bail!("bad parameter or syntax error: {arg}");
+            };
+            let value = value.trim();
+            let unquoted = ['"', '\'']
+                .iter()
+                .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+                .unwrap_or(value);
+            Ok((key.trim().to_lowercase(), unquoted.to_string()))
+        })
+        .collect()
+}
+
+/* Built-in tables - generate_series */
+
+// Where a series without a stop stops, as in SQLite.
+const DEFAULT_STOP: i64 = u32::MAX as i64;
+
+struct Series;
+
+impl VirtualTable for Series {
+    fn columns(&self) -> Vec<String> {
+        vec!["value".to_string()]
+    }
+
+    fn arguments(&self) -> Vec<String> {
+        ["start", "stop", "step"].map(str::to_string).to_vec()
+    }
+
+    // The first `=` term on each of start, stop and step, the number having bit i set if
+    // argument i is given.
+    fn best_index(&self, constraints: &[Constraint]) -> IndexPlan {
+        let mut plan = IndexPlan::default();
+        for argument in 0..3 {
+            let given = constraints
+                .iter()
+                .position(|c| c.column == argument + 1 && c.op == ConstraintOp::Eq);
+            if let Some(i) = given {
+                plan.number |= 1 << argument;
+                plan.used.push(i);
+            }
+        }
+        plan
+    }
+
+    fn open(&self) -> Result<Box<dyn VirtualCursor + '_>> {
+        Ok(Box::new(SeriesCursor::default()))
+    }
+}
+
+// The arguments of a series as given and the value it is at, widened so that stepping
+// past either end of i64 can't overflow.
+#[derive(Default)]
+struct SeriesCursor {
+    start: i64,
+    stop: i64,
+    step: i64,
+    value: i128,
+    done: bool,
+}
+
+impl VirtualCursor for SeriesCursor {
+    fn filter(&mut self, number: i64, values: &[ColVal]) -> Result<()> {
+        if number & 1 == 0 {
+            bail!("first argument to \"generate_series()\" missing or unusable");
+        }
+        // a NULL argument makes an empty series
+        let mut values = values.iter();
+        let mut argument = |bit: i64, default: i64| match number & bit {
+            0 => Some(default),
+            _ => values.next().and_then(as_integer),
+        };
+        let (Some(start), Some(stop), Some(step)) =
+            (argument(1, 0), argument(2, DEFAULT_STOP), argument(4, 1))
+        else {
+            self.done = true;
+            return Ok(());
+        };
+        (self.start, self.stop, self.step) = (start, stop, step);
+        self.done = start > stop;
+        self.value = start as i128;
+        if step < 0 {
+            self.value += (stop as i128 - start as i128) / self.stride() * self.stride();
+        }
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        self.done
+    }
+
+    fn next(&mut self) -> Result<()> {
+        if self.step < 0 {
+            self.value -= self.stride();
+        } else {
+            self.value += self.stride();
+        }
+        self.done = self.value < self.start as i128 || self.value > self.stop as i128;
+        Ok(())
+    }
+
+    fn column(&self, i: usize) -> Result<ColVal> {
+        Ok(ColVal::Int(match i {
+            0 => self.value as i64,
+            1 => self.start,
+            2 => self.stop,
+            _ => self.step,
+        }))
+    }
+}
+
+impl SeriesCursor {
+    // How far apart the values are, a step of 0 counting as 1.
+    fn stride(&self) -> i128 {
+        (self.step as i128).abs().max(1)
+    }
+}
+
+/* Built-in tables - csv */
+
+struct Csv {
+    filename: PathBuf,
+    header: bool,
+    columns: Vec<String>,
+}
+
+// csv(filename = "data.csv" [, header = yes | no])
+fn csv(args: &[String]) -> Result<Box<dyn VirtualTable>> {
+    let mut filename = None;
+    let mut header = false;
+    for (key, value) in parameters(args)? {
+        match key.as_str() {
+            "filename" => filename = Some(PathBuf::from(value)),
+            "header" => {
+                header = match value.to_lowercase().as_str() {
+                    "yes" | "on" | "true" | "1" => true,
+                    "no" | "off" | "false" | "0" => false,
+                    _ => bail!("unrecognized argument to header: {value}"),
+                }
+            }
+            _ => bail!("bad parameter or syntax error: {key}"),
+        }
+    }
+    let Some(filename) = filename else {
+        bail!("csv needs a filename=");
+    };
+    let columns = match read_csv(&filename)?.first() {
+        Some(first) if header => first.clone(),
+        Some(first) => (0..first.len()).map(|i| format!("c{i}")).collect(),
+        None => bail!("no columns in \"{}\"", filename.display()),
+    };
+    Ok(Box::new(Csv {
+        filename,
+        header,
+        columns,
+    }))
+}
+
+impl VirtualTable for Csv {
+    fn columns(&self) -> Vec<String> {
+        self.columns.clone()
+    }
+
+    fn open(&self) -> Result<Box<dyn VirtualCursor + '_>> {
+        Ok(Box::new(CsvCursor {
+            table: self,
+            records: vec![],
+            at: 0,
+        }))
+    }
+}
+
+struct CsvCursor<'t> {
+    table: &'t Csv,
+    records: Vec<Vec<String>>,
+    at: usize,
+}
+
+impl VirtualCursor for CsvCursor<'_> {
+    fn filter(&mut self, _number: i64, _values: &[ColVal]) -> Result<()> {
+        self.records = read_csv(&self.table.filename)?;
+        self.at = self.table.header as usize;
+        Ok(())
+    }
+
+    fn eof(&self) -> bool {
+        self.at >= self.records.len()
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.at += 1;
+        Ok(())
+    }
+
+    // a record short of fields is NULL in the columns it lacks
+    fn column(&self, i: usize) -> Result<ColVal> {
+        Ok(self.records[self.at]
+            .get(i)
+            .map_or(ColVal::Null, |field| ColVal::String(field.clone())))
+    }
+}
+
+fn read_csv(filename: &PathBuf) -> Result<Vec<Vec<String>>> {
+    let text = std::fs::read_to_string(filename)
+        .with_context(|| format!("cannot open '{}' for reading", filename.display()))?;
+    Ok(parse_csv(&text))
+}
+
+// The records of a CSV file as RFC 4180 has them: fields separated by commas, records by
+// newlines, and a field in double quotes may hold either, and "" for a quote.
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.is_empty() => quoted = true,
+            ',' if !quoted => record.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::Connection;
+    use crate::executor::Executor;
+    use crate::row::Row;
+    use crate::storage::memdb::OpenTarget;
+
+    fn run(executor: &mut Executor, sql: &str) -> Vec<Vec<ColVal>> {
+        executor.execute_sql(sql).unwrap().rows
+    }
+
+    fn ints(values: &[i64]) -> Vec<Vec<ColVal>> {
+        values.iter().map(|v| vec![ColVal::Int(*v)]).collect()
+    }
+
+    fn text(s: &str) -> ColVal {
+        ColVal::String(s.to_string())
+    }
+
+    #[test]
+    fn generate_series_is_called_like_a_function() {
+        let mut executor = Executor::default();
+        for (sql, expected) in [
+            ("SELECT * FROM generate_series(1, 5);", &[1, 2, 3, 4, 5][..]),
+            ("SELECT value FROM generate_series(1, 10, 4);", &[1, 5, 9]),
+            ("SELECT value FROM generate_series(1, 10, -4);", &[9, 5, 1]),
+            (
+                "SELECT value FROM generate_series(0, 1 + 2) WHERE value > 1;",
+                &[2, 3],
+            ),
+            (
+                "SELECT value FROM generate_series WHERE stop = 4 AND start = 3;",
+                &[3, 4],
+            ),
+            ("SELECT value FROM generate_series(5, 1);", &[]),
+            ("SELECT value FROM generate_series(1, NULL);", &[]),
+            (
+                "SELECT value FROM generate_series(9223372036854775806, 9223372036854775807, 5);",
+                &[9223372036854775806],
+            ),
+            ("SELECT COUNT(*) FROM generate_series(1, 1000);", &[1000]),
+            // rows are read off the cursor as they are asked for, so these end
+            (
+                "SELECT * FROM generate_series(1, 9223372036854775807) LIMIT 3 OFFSET 2;",
+                &[3, 4, 5],
+            ),
+            (
+                "SELECT value FROM generate_series(1, 3) LIMIT -1;",
+                &[1, 2, 3],
+            ),
+            ("SELECT value FROM generate_series(1, 3) LIMIT 0;", &[]),
+        ] {
+            assert_eq!(run(&mut executor, sql), ints(expected), "{sql}");
+        }
+        assert_eq!(
+            run(
+                &mut executor,
+                "SELECT EXISTS (SELECT value FROM generate_series(1, 9223372036854775807));"
+            ),
+            [[ColVal::Boolean(true)]]
+        );
+        assert_eq!(
+            run(
+                &mut executor,
+                "SELECT start, stop, step FROM generate_series(1, 2);"
+            ),
+            [[1, 2, 1], [1, 2, 1]].map(|row| row.map(ColVal::Int))
+        );
+        assert_eq!(
+            run(
+                &mut executor,
+                "EXPLAIN QUERY PLAN SELECT value FROM generate_series(1, 10) WHERE value > 5;"
+            ),
+            [
+                [text("QUERY PLAN")],
+                [text("`--SCAN generate_series VIRTUAL TABLE INDEX 3:")]
+            ]
+        );
+
+        run(&mut executor, "CREATE TABLE t (a INTEGER);");
+        for (sql, err) in [
+            (
+                "SELECT value FROM generate_series;",
+                "first argument to \"generate_series()\" missing or unusable",
+            ),
+            (
+                "SELECT value FROM generate_series(1, 2, 3, 4);",
+                "too many arguments on generate_series() - max 3",
+            ),
+            ("SELECT a FROM t(1);", "'t' is not a function"),
+            (
+                "INSERT INTO generate_series (value) VALUES (1);",
+                "table generate_series may not be modified",
+            ),
+            (
+                "CREATE TABLE generate_series (a INTEGER);",
+                "table generate_series already exists",
+            ),
+        ] {
+            let got = executor.execute_sql(sql).unwrap_err();
+            assert_eq!(got.to_string(), err, "{sql}");
+        }
+    }
+
+    #[test]
+    fn csv_files_are_read_as_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = dir.path().join("sales.csv");
+        std::fs::write(
+            &csv,
+            "region,amount\r\nnorth,100\n\"south, east\",\"2\"\"5\"\nwest\n",
+        )
+        .unwrap();
+        let target = OpenTarget::parse(&dir.path().join("test.db").to_string_lossy()).unwrap();
+        let mut executor = Executor::open(target.clone()).unwrap();
+        let create = format!(
+            "CREATE VIRTUAL TABLE sales USING csv(filename = \"{}\", header = yes);",
+            csv.display()
+        );
+        run(&mut executor, &create);
+        assert_eq!(
+            run(&mut executor, "SELECT * FROM sales;"),
+            [
+                [text("north"), text("100")],
+                [text("south, east"), text("2\"5")],
+                [text("west"), ColVal::Null],
+            ]
+        );
+        assert_eq!(
+            run(
+                &mut executor,
+                "SELECT region FROM sales WHERE amount = \"100\";"
+            ),
+            [[text("north")]]
+        );
+
+        // every scan reads the file again, and the table is there when the database is
+        // opened again
+        std::fs::write(&csv, "region,amount\neast,7\n").unwrap();
+        drop(executor);
+        let mut executor = Executor::open(target).unwrap();
+        assert_eq!(
+            run(&mut executor, "SELECT region, amount FROM sales;"),
+            [[text("east"), text("7")]]
+        );
+
+        let missing = dir.path().join("none.csv");
+        for (sql, err) in [
+            (
+                "CREATE INDEX idx ON sales (region);".to_string(),
+                "virtual tables may not be indexed".to_string(),
+            ),
+            (
+                "DELETE FROM sales;".to_string(),
+                "table sales may not be modified".to_string(),
+            ),
+            (
+                format!(
+                    "CREATE VIRTUAL TABLE t USING csv(filename = \"{}\");",
+                    missing.display()
+                ),
+                format!("cannot open '{}' for reading", missing.display()),
+            ),
+            (
+                "CREATE VIRTUAL TABLE t USING csv(file = \"a.csv\");".to_string(),
+                "bad parameter or syntax error: file".to_string(),
+            ),
+            (
+                "CREATE VIRTUAL TABLE t USING nothing;".to_string(),
+                "no such module: nothing".to_string(),
+            ),
+        ] {
+            let got = executor.execute_sql(&sql).unwrap_err();
+            assert_eq!(got.to_string(), err, "{sql}");
+        }
+    }
+
+    // The squares of 1 to `count`, which finds the row of a given n without a scan.
+    struct Squares {
+        count: i64,
+    }
+
+    impl VirtualTable for Squares {
+        fn columns(&self) -> Vec<String> {
+            vec!["n".to_string(), "square".to_string()]
+        }
+
+        fn best_index(&self, constraints: &[Constraint]) -> IndexPlan {
+            match constraints
+                .iter()
+                .position(|c| c.column == 0 && c.op == ConstraintOp::Eq)
+            {
+                Some(i) => IndexPlan {
+                    number: 1,
+                    used: vec![i],
+                },
+                None => IndexPlan::default(),
+            }
+        }
+
+        fn open(&self) -> Result<Box<dyn VirtualCursor + '_>> {
+            Ok(Box::new(SquaresCursor {
+                n: 0,
+                last: self.count,
+            }))
+        }
+    }
+
+    struct SquaresCursor {
+        n: i64,
+        last: i64,
+    }
+
+    impl VirtualCursor for SquaresCursor {
+        fn filter(&mut self, number: i64, values: &[ColVal]) -> Result<()> {
+            (self.n, self.last) = match (number, values) {
+                (1, [ColVal::Int(n)]) => (*n, (*n).min(self.last)),
+                _ => (1, self.last),
+            };
+            Ok(())
+        }
+
+        fn eof(&self) -> bool {
+            self.n > self.last
+        }
+
+        fn next(&mut self) -> Result<()> {
+            self.n += 1;
+            Ok(())
+        }
+
+        fn column(&self, i: usize) -> Result<ColVal> {
+            Ok(ColVal::Int(if i == 0 { self.n } else { self.n * self.n }))
+        }
+    }
+
+    fn squares(args: &[String]) -> Result<Box<dyn VirtualTable>> {
+        let [count] = args else {
+            bail!("squares takes a count");
+        };
+        Ok(Box::new(Squares {
+            count: count.parse()?,
+        }))
+    }
+
+    #[test]
+    fn embedders_make_tables_of_their_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let mut conn = Connection::open(&path.to_string_lossy()).unwrap();
+        conn.create_module("squares", squares).unwrap();
+        conn.execute("CREATE VIRTUAL TABLE sq USING squares(4);", &[])
+            .unwrap();
+        let query = |conn: &mut Connection, sql: &str| -> Result<Vec<Vec<ColVal>>> {
+            conn.query(sql, &[])?
+                .map(|row| row.map(Row::into_values))
+                .collect()
+        };
+        assert_eq!(
+            query(&mut conn, "SELECT square FROM sq WHERE square > 4;").unwrap(),
+            ints(&[9, 16])
+        );
+        assert_eq!(
+            query(&mut conn, "SELECT square FROM sq WHERE n = ?;").unwrap(),
+            ints(&[])
+        );
+        assert_eq!(
+            conn.query("SELECT square FROM sq WHERE n = ?;", &[3.into()])
+                .unwrap()
+                .map(|row| row.unwrap().into_values())
+                .collect::<Vec<_>>(),
+            ints(&[9])
+        );
+        assert_eq!(
+            query(
+                &mut conn,
+                "EXPLAIN QUERY PLAN SELECT n FROM sq WHERE n = 2;"
+            )
+            .unwrap()[1],
+            [text("`--SCAN sq VIRTUAL TABLE INDEX 1:")]
+        );
+
+        // opened again before the module is there, the table can't be read until it is
+        drop(conn);
+        let mut conn = Connection::open(&path.to_string_lossy()).unwrap();
+        let err = query(&mut conn, "SELECT n FROM sq;").unwrap_err();
+        assert_eq!(err.to_string(), "no such module: squares");
+        conn.create_module("squares", squares).unwrap();
+        assert_eq!(
+            query(&mut conn, "SELECT n FROM sq;").unwrap(),
+            ints(&[1, 2, 3, 4])
+        );
+    }
+
+    #[test]
+    fn csv_is_split_into_records_and_fields() {
+        assert_eq!(
+            parse_csv("a,\"b\nc\",\"\"\r\n,\"d\"\"\"\nlast"),
+            [vec!["a", "b\nc", ""], vec!["", "d\""], vec!["last"]]
+        );
+        assert_eq!(parse_csv(""), Vec::<Vec<String>>::new());
+    }
+}
@@ -0,0 +1,333 @@
+/*
+    Window functions give every row of a query a value worked out from the rows around it,
+    as in `SELECT name, RANK() OVER (PARTITION BY dept ORDER BY pay DESC) FROM staff`.
+
+    The rows are sorted by the window's PARTITION BY and then its ORDER BY, and each
+    partition, the rows with the same PARTITION BY values, is taken on its own. Rows that
+    are equal in the ORDER BY are peers. The rules follow SQLite:
+
+        ROW_NUMBER()   the row's place in its partition, from 1
+        RANK()         the ROW_NUMBER() of the first of its peers, so ties share a rank
+                       and leave a gap after them
+        DENSE_RANK()   how many groups of peers come up to and including the row's, so
+                       ties share a rank without a gap
+        SUM(x) etc.    the aggregate, as in aggregate.rs, over the row's frame
+
+    The frame is the rows of the partition from its start bound to its end. ROWS counts
+    rows while RANGE, which can only be bounded by UNBOUNDED or CURRENT ROW here, takes
+    CURRENT ROW to mean all of the row's peers. The default frame is RANGE BETWEEN
+    UNBOUNDED PRECEDING AND CURRENT ROW, which without an ORDER BY, every row being a peer
+    of every other, is the whole partition. A frame that starts at UNBOUNDED PRECEDING
+    only ever grows from one row to the next, so its aggregate is kept running rather than
+    worked out again for each row.
+
+    Each window function adds its value to the end of every row. With several windows the
+    rows are sorted again for each, and come out in the order of the last.
+*/
+use crate::aggregate::Accumulator;
+use crate::collation::Collation;
+use crate::error::SqlError;
+use crate::sql_parser::ast::{Aggregate, ColVal, Frame, FrameBound, WindowFunc, WindowFunction};
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+
+// A column rows are sorted by, and the partitions told apart by.
+struct Key {
+    column: usize,
+    collation: Collation,
+    descending: bool,
+}
+
+fn compare(keys: &[Key], a: &[ColVal], b: &[ColVal]) -> Ordering {
+    keys.iter()
+        .map(|key| {
+            let ordering = key.collation.compare(&a[key.column], &b[key.column]);
+            if key.descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        })
+        .find(|o| *o != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+const DEFAULT_FRAME: Frame = Frame {
+    range: true,
+    start: FrameBound::UnboundedPreceding,
+    end: FrameBound::CurrentRow,
+};
+
+/// Add the value of each window function to the end of every row, the rows having the
+/// given column names and `collation` giving the collation each of them is compared under.
+/// Each row comes with a key of the caller's, such as its rowid, which stays with it.
+pub fn window<K>(
+    windows: &[WindowFunction],
+    columns: &[String],
+    collation: impl Fn(&str) -> Result<Collation>,
+    mut rows: Vec<(K, Vec<ColVal>)>,
+) -> Result<Vec<(K, Vec<ColVal>)>> {
+    let position = |column: &str| {
+        columns.iter().position(|c| c == column).ok_or_else(|| {
+            anyhow!(SqlError::NoSuchColumn {
+                column: column.to_string()
+            })
+        })
+    };
+    let key = |column: &str, descending: bool| -> Result<Key> {
+        Ok(Key {
+            column: position(column)?,
+            collation: collation(column)?,
+            descending,
+        })
+    };
+
+    for window in windows {
+        let partition_by = window
+            .partition_by
+            .iter()
+            .map(|column| key(column, false))
+            .collect::<Result<Vec<_>>>()?;
+        let order_by = window
+            .order_by
+            .iter()
+            .map(|(column, descending)| key(column, *descending))
+            .collect::<Result<Vec<_>>>()?;
+        let arg = match &window.func {
+            WindowFunc::Aggregate(Aggregate { arg: Some(arg), .. }) => Some(position(arg)?),
+            _ => None,
+        };
+
+        // a stable sort, so rows equal in both keep the order they came in
+        rows.sort_by(|(_, a), (_, b)| {
+            compare(&partition_by, a, b).then_with(|| compare(&order_by, a, b))
+        });
+        let mut start = 0;
+        while start < rows.len() {
+            let end = start
+                + rows[start..]
+                    .iter()
+                    .take_while(|(_, row)| {
+                        compare(&partition_by, row, &rows[start].1) == Ordering::Equal
+                    })
+                    .count();
+            let partition: Vec<&[ColVal]> = rows[start..end]
+                .iter()
+                .map(|(_, row)| row.as_slice())
+                .collect();
+            let values = partition_values(window, &order_by, arg, &partition)?;
+            for ((_, row), value) in rows[start..end].iter_mut().zip(values) {
+                row.push(value);
+            }
+            start = end;
+        }
+    }
+    Ok(rows)
+}
+
+// The window function's value for each row of one partition, sorted by its ORDER BY.
+fn partition_values(
+    window: &WindowFunction,
+    order_by: &[Key],
+    arg: Option<usize>,
+    rows: &[&[ColVal]],
+) -> Result<Vec<ColVal>> {
+    // where each row's group of peers starts, and where it ends
+    let mut peers = vec![(0, 0); rows.len()];
+    let mut first = 0;
+    for i in 1..=rows.len() {
+        if i == rows.len() || compare(order_by, rows[i], rows[first]) != Ordering::Equal {
+            peers[first..i].fill((first, i));
+            first = i;
+        }
+    }
+
+    let aggregate = match &window.func {
+        WindowFunc::RowNumber => {
+            return Ok((1..=rows.len()).map(|n| ColVal::Int(n as i64)).collect());
+        }
+        WindowFunc::Rank => {
+            return Ok(peers
+                .iter()
+                .map(|(first, _)| ColVal::Int(*first as i64 + 1))
+                .collect());
+        }
+        WindowFunc::DenseRank => {
+            let mut rank = 0;
+            return Ok(peers
+                .iter()
+                .enumerate()
+                .map(|(i, (first, _))| {
+                    if i == *first {
+                        rank += 1;
+                    }
+                    ColVal::Int(rank)
+                })
+                .collect());
+        }
+        WindowFunc::Aggregate(aggregate) => aggregate,
+    };
+
+    let frame = window.frame.unwrap_or(DEFAULT_FRAME);
+    let step = |accumulator: &mut Accumulator, row: &[ColVal]| {
+        accumulator.step(arg.map(|i| row[i].clone()))
+    };
+    let mut values = vec![];
+    let mut running = Accumulator::new(aggregate);
+    let mut taken = 0; // how many rows the running aggregate has had
+    for (i, peers) in peers.iter().enumerate() {
+        let (start, end) = frame_bounds(&frame, i, *peers, rows.len());
+        if frame.start == FrameBound::UnboundedPreceding {
+            for row in &rows[taken..end.max(taken)] {
+                step(&mut running, row)?;
+            }
+            taken = taken.max(end);
+            values.push(running.finish());
+        } else {
+            let mut accumulator = Accumulator::new(aggregate);
+            for row in rows.get(start..end).unwrap_or_default() {
+                step(&mut accumulator, row)?;
+            }
+            values.push(accumulator.finish());
+        }
+    }
+    Ok(values)
+}
+
+// The rows of the `i`th row's frame, from `start` up to but not including `end`, given its
+// group of peers and the number of rows in its partition. An empty frame can come out with
+// its end before its start.
+fn frame_bounds(frame: &Frame, i: usize, peers: (usize, usize), rows: usize) -> (usize, usize) {
+    let start = match frame.start {
+        FrameBound::UnboundedPreceding => 0,
+        FrameBound::Preceding(n) => i.saturating_sub(n as usize),
+        FrameBound::CurrentRow if frame.range => peers.0,
+        FrameBound::CurrentRow => i,
+        FrameBound::Following(n) => i.saturating_add(n as usize).min(rows),
+        FrameBound::UnboundedFollowing => rows,
+    };
+    let end = match frame.end {
+        FrameBound::UnboundedPreceding => 0,
+        FrameBound::Preceding(n) => (i + 1).saturating_sub(n as usize),
+        FrameBound::CurrentRow if frame.range => peers.1,
+        FrameBound::CurrentRow => i + 1,
+        FrameBound::Following(n) => i.saturating_add(n as usize).saturating_add(1).min(rows),
+        FrameBound::UnboundedFollowing => rows,
+    };
+    (start, end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_parser::parse_window_function;
+
+    // Each window's values for rows of (dept, pay), in the order they come out.
+    fn run(windows: &[&str], rows: &[(&str, i64)]) -> Vec<Vec<ColVal>> {
+        let windows: Vec<WindowFunction> = windows
+            .iter()
+            .map(|w| parse_window_function(w).unwrap())
+            .collect();
+        let columns = ["dept".to_string(), "pay".to_string()];
+        let rows = rows
+            .iter()
+            .map(|(dept, pay)| ((), vec![ColVal::from(*dept), ColVal::Int(*pay)]))
+            .collect();
+        window(&windows, &columns, |_| Ok(Collation::binary()), rows)
+            .unwrap()
+            .into_iter()
+            .map(|(_, row)| row)
+            .collect()
+    }
+
+    fn row(dept: &str, pay: i64, values: &[i64]) -> Vec<ColVal> {
+        [ColVal::from(dept), ColVal::Int(pay)]
+            .into_iter()
+            .chain(values.iter().map(|v| ColVal::Int(*v)))
+            .collect()
+    }
+
+    // Expected values are what SQLite 3.45 gives.
+    #[test]
+    fn ranking_within_partitions() {
+        assert_eq!(
+            run(
+                &[
+                    "ROW_NUMBER() OVER (PARTITION BY dept ORDER BY pay DESC)",
+                    "RANK() OVER (PARTITION BY dept ORDER BY pay DESC)",
+                    "DENSE_RANK() OVER (PARTITION BY dept ORDER BY pay DESC)",
+                ],
+                &[
+                    ("b", 10),
+                    ("a", 5),
+                    ("b", 30),
+                    ("b", 10),
+                    ("b", 5),
+                    ("a", 7)
+                ],
+            ),
+            [
+                row("a", 7, &[1, 1, 1]),
+                row("a", 5, &[2, 2, 2]),
+                row("b", 30, &[1, 1, 1]),
+                row("b", 10, &[2, 2, 2]),
+                row("b", 10, &[3, 2, 2]),
+                row("b", 5, &[4, 4, 3]),
+            ]
+        );
+    }
+
+    #[test]
+    fn aggregates_over_frames() {
+        assert_eq!(
+            run(
+                &[
+                    "SUM(pay) OVER (ORDER BY pay)",
+                    "SUM(pay) OVER (ORDER BY pay ROWS UNBOUNDED PRECEDING)",
+                    "SUM(pay) OVER ()",
+                    "COUNT(*) OVER (ORDER BY pay ROWS BETWEEN 1 PRECEDING AND 1 FOLLOWING)",
+                    "MAX(pay) OVER (ORDER BY pay ROWS BETWEEN 2 FOLLOWING AND UNBOUNDED FOLLOWING)",
+                ],
+                &[("a", 3), ("a", 1), ("a", 3), ("a", 2)],
+            ),
+            [
+                vec![
+                    ColVal::from("a"),
+                    ColVal::Int(1),
+                    ColVal::Int(1),
+                    ColVal::Int(1),
+                    ColVal::Int(9),
+                    ColVal::Int(2),
+                    ColVal::Int(3),
+                ],
+                vec![
+                    ColVal::from("a"),
+                    ColVal::Int(2),
+                    ColVal::Int(3),
+                    ColVal::Int(3),
+                    ColVal::Int(9),
+                    ColVal::Int(3),
+                    ColVal::Int(3),
+                ],
+                vec![
+                    ColVal::from("a"),
+                    ColVal::Int(3),
+                    ColVal::Int(9),
+                    ColVal::Int(6),
+                    ColVal::Int(9),
+                    ColVal::Int(3),
+                    ColVal::Null,
+                ],
+                vec![
+                    ColVal::from("a"),
+                    ColVal::Int(3),
+                    ColVal::Int(9),
+                    ColVal::Int(9),
+                    ColVal::Int(9),
+                    ColVal::Int(2),
+                    ColVal::Null,
+                ],
+            ]
+        );
+    }
+}
@@ -0,0 +1,33 @@
+-- Values take their column's affinity when stored, and comparisons give it to the
+-- values compared with a column.
+CREATE TABLE readings (id INTEGER PRIMARY KEY, label TEXT, value REAL, count INTEGER, raw);
+INSERT INTO readings (label, value, count, raw) VALUES (12, 5, "30", "7");
+INSERT INTO readings (label, value, count, raw) VALUES ("b", "2.5", 3, 8);
+INSERT INTO readings (label, value, count, raw) VALUES ("007", 1e2, "many", "x");
+SELECT * FROM readings;
+
+-- a column's affinity goes to a literal compared with it
+SELECT id FROM readings WHERE label = 12;
+SELECT id FROM readings WHERE label = 7;
+SELECT id FROM readings WHERE count = "30";
+SELECT id FROM readings WHERE count > "4";
+SELECT id FROM readings WHERE value IN ("2.5", "100");
+SELECT id FROM readings WHERE raw = 7;
+SELECT id FROM readings WHERE raw = "7";
+SELECT id FROM readings WHERE (label, count) = (12, "30");
+
+-- as does a CAST's, while two literals are compared as they are
+SELECT id FROM readings WHERE CAST(count AS TEXT) = 30;
+SELECT id FROM readings WHERE 12 = "12";
+SELECT id FROM readings WHERE id = "1";
+
+-- and an index is searched as the table is scanned
+CREATE INDEX readings_label ON readings (label);
+CREATE INDEX readings_count ON readings (count);
+SELECT id FROM readings WHERE label = 12;
+SELECT id FROM readings WHERE label IN (12, "12", "b");
+SELECT id FROM readings WHERE count >= "3" AND count < "31";
+
+-- CAST converts whatever is lost on the way
+SELECT id FROM readings WHERE CAST(label AS INTEGER) = 7;
+SELECT id FROM readings WHERE CAST(raw AS REAL) = 8;
@@ -0,0 +1,18 @@
+-- CHECK constraints, on a column or the table, refuse rows they come out FALSE for.
+CREATE TABLE stock (item TEXT, qty INTEGER CHECK (qty >= 0), price INTEGER, CHECK (qty * price < 100));
+INSERT INTO stock (item, qty, price) VALUES ("pen", 3, 2);
+INSERT INTO stock (item, qty, price) VALUES ("cap", -1, 2);
+INSERT INTO stock (item, qty, price) VALUES ("mug", 10, 10);
+INSERT INTO stock (item, price) VALUES ("ink", 5);
+UPDATE stock SET qty = qty - 4;
+UPDATE stock SET price = 20;
+UPDATE stock SET price = 30;
+SELECT * FROM stock;
+
+-- and can't read what isn't in the row
+CREATE TABLE bad (a CHECK (a > b));
+CREATE TABLE bad (a, CHECK (a IN (SELECT item FROM stock)));
+CREATE TABLE good (a CHECK (rowid > 0));
+INSERT INTO good (a) VALUES (1);
+SELECT * FROM good;
+SELECT sql FROM sqlite_master WHERE name = "stock";
@@ -0,0 +1,25 @@
+-- UNION, UNION ALL, INTERSECT and EXCEPT, and the ORDER BY and LIMIT of a compound.
+CREATE TABLE a (n INTEGER, t TEXT);
+CREATE TABLE b (n INTEGER, t TEXT);
+INSERT INTO a (n, t) VALUES (3, "three");
+INSERT INTO a (n, t) VALUES (1, "one");
+INSERT INTO a (n, t) VALUES (3, "three");
+INSERT INTO a (n, t) VALUES (NULL, NULL);
+INSERT INTO b (n, t) VALUES (2, "two");
+INSERT INTO b (n, t) VALUES (3, "three");
+INSERT INTO b (n, t) VALUES (NULL, NULL);
+SELECT n, t FROM a UNION SELECT n, t FROM b;
+SELECT n FROM a UNION ALL SELECT n FROM b;
+SELECT n, t FROM a INTERSECT SELECT n, t FROM b;
+SELECT n FROM a EXCEPT SELECT n FROM b;
+SELECT t FROM a WHERE n > 1 UNION SELECT t FROM b WHERE n < 3 UNION ALL SELECT t FROM a WHERE n = 1;
+SELECT n FROM a UNION SELECT n FROM b EXCEPT SELECT n FROM a WHERE n = 3;
+SELECT n, t FROM a UNION ALL SELECT n, t FROM b ORDER BY t DESC;
+SELECT n, t FROM a UNION SELECT n, t FROM b ORDER BY 2 LIMIT 2;
+SELECT n FROM a UNION ALL SELECT n FROM b ORDER BY n LIMIT 3 OFFSET 2;
+SELECT n FROM a UNION SELECT n, t FROM b;
+SELECT count(*) FROM a UNION SELECT n FROM b;
+SELECT count(*) FROM a UNION SELECT n, t FROM b;
+SELECT n, t FROM a UNION ALL SELECT count(*), max(t) FROM b;
+SELECT n FROM a UNION SELECT n FROM b ORDER BY 2;
+SELECT n FROM a UNION SELECT n FROM b ORDER BY t;
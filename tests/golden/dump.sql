@@ -0,0 +1,17 @@
+-- What a dump makes again.
+CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT, score REAL, joined);
+CREATE INDEX people_name ON people (name);
+CREATE TABLE log (name TEXT);
+CREATE TRIGGER log_people AFTER INSERT ON people BEGIN INSERT INTO log (name) VALUES (NEW.name); END;
+INSERT INTO people (name, score, joined) VALUES ('amy', 2.5, 2020);
+INSERT INTO people (name, score, joined) VALUES ('it''s bob', NULL, '2021');
+INSERT INTO people (id, name, score) VALUES (10, 'cat', 0.1);
+CREATE VIEW high AS SELECT name FROM people WHERE score > 1;
+.dump
+
+-- a dump puts an AUTOINCREMENT table's sequence back
+CREATE TABLE jobs (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT);
+INSERT INTO jobs (name) VALUES ('build');
+INSERT INTO jobs (name) VALUES ('test');
+DELETE FROM jobs WHERE name = 'test';
+.dump
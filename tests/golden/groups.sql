@@ -0,0 +1,18 @@
+-- ORDER BY, LIMIT and GROUP BY of a single SELECT.
+CREATE TABLE emp (name TEXT, dept TEXT, pay INTEGER);
+INSERT INTO emp (name, dept, pay) VALUES ('amy', 'ops', 10);
+INSERT INTO emp (name, dept, pay) VALUES ('bob', 'dev', 30);
+INSERT INTO emp (name, dept, pay) VALUES ('cat', 'dev', 20);
+INSERT INTO emp (name, dept, pay) VALUES ('dan', 'ops', 5);
+INSERT INTO emp (name, dept, pay) VALUES ('eve', 'hr', 7);
+SELECT name FROM emp ORDER BY pay DESC;
+SELECT name, pay FROM emp ORDER BY 2 LIMIT 2;
+SELECT name FROM emp ORDER BY name LIMIT 2 OFFSET 1;
+SELECT dept, COUNT(*), SUM(pay) FROM emp GROUP BY dept;
+SELECT dept, SUM(pay) FROM emp GROUP BY dept ORDER BY SUM(pay) DESC;
+SELECT dept, COUNT(*) FROM emp GROUP BY 1 ORDER BY 2, 1 LIMIT 2;
+SELECT dept FROM emp WHERE pay > 6 GROUP BY dept;
+SELECT COUNT(*) FROM emp WHERE pay > 100 GROUP BY dept;
+SELECT name FROM emp ORDER BY 3;
+SELECT dept FROM emp GROUP BY 0;
+SELECT name FROM emp ORDER BY name UNION SELECT dept FROM emp;
@@ -0,0 +1,65 @@
+-- Indexes, views and subqueries.
+CREATE TABLE users (id INTEGER PRIMARY KEY, name TEXT, email TEXT COLLATE NOCASE);
+CREATE UNIQUE INDEX users_email ON users (email);
+CREATE TABLE orders (id INTEGER PRIMARY KEY, user_id INTEGER, quantity INTEGER);
+CREATE INDEX orders_user ON orders (user_id);
+INSERT INTO users (name, email) VALUES ("amy", "amy@example.com");
+INSERT INTO users (name, email) VALUES ("bob", "bob@example.com");
+INSERT INTO users (name, email) VALUES ("cat", "cat@example.com");
+INSERT INTO users (name, email) VALUES ("dup", "AMY@example.com");
+INSERT INTO orders (user_id, quantity) VALUES (1, 3);
+INSERT INTO orders (user_id, quantity) VALUES (1, 1);
+INSERT INTO orders (user_id, quantity) VALUES (3, 10);
+SELECT name FROM users WHERE email = "BOB@EXAMPLE.COM";
+SELECT quantity FROM orders WHERE user_id = 1;
+SELECT u.name FROM users u WHERE EXISTS (SELECT 1 FROM orders o WHERE o.user_id = u.id);
+SELECT name FROM users WHERE id NOT IN (SELECT user_id FROM orders);
+SELECT name FROM users WHERE id IN (SELECT user_id FROM orders WHERE quantity > 5);
+CREATE VIEW big_orders AS SELECT user_id, quantity FROM orders WHERE quantity > 2;
+SELECT * FROM big_orders;
+SELECT user_id FROM big_orders WHERE quantity < 5;
+CREATE INDEX users_email ON users (name);
+CREATE VIEW big_orders AS SELECT id FROM orders;
+UPDATE users SET email = "Cat@Example.com" WHERE name = "amy";
+SELECT name FROM users WHERE email = "amy@example.com";
+
+-- ranges, read from an index in its order
+CREATE INDEX orders_quantity ON orders (quantity);
+INSERT INTO orders (user_id, quantity) VALUES (2, NULL);
+INSERT INTO orders (user_id, quantity) VALUES (2, 2.5);
+SELECT id, quantity FROM orders WHERE quantity > 1 AND quantity <= 10;
+SELECT id FROM orders WHERE quantity < 3;
+SELECT id FROM orders WHERE 3 <= quantity;
+SELECT id FROM orders WHERE quantity > 10;
+
+-- statistics
+ANALYZE;
+SELECT * FROM sqlite_stat1;
+ANALYZE orders_user;
+SELECT tbl, idx, stat FROM sqlite_stat1 WHERE tbl = "orders";
+ANALYZE nope;
+
+-- rebuilding indexes
+REINDEX;
+REINDEX orders;
+REINDEX users_email;
+REINDEX nocase;
+SELECT name FROM users WHERE email = "BOB@EXAMPLE.COM";
+SELECT id FROM orders WHERE quantity < 3;
+REINDEX nope;
+
+-- indexes on a WITHOUT ROWID table, whose entries end in the primary key
+CREATE TABLE tags (name TEXT PRIMARY KEY, colour TEXT, rank INTEGER) WITHOUT ROWID;
+INSERT INTO tags (name, colour, rank) VALUES ("red", "warm", 2);
+INSERT INTO tags (name, colour, rank) VALUES ("blue", "cool", 1);
+INSERT INTO tags (name, colour, rank) VALUES ("amber", "warm", 3);
+CREATE UNIQUE INDEX tags_rank ON tags (rank);
+CREATE INDEX tags_colour ON tags (colour);
+SELECT name FROM tags WHERE colour = "warm";
+INSERT INTO tags (name, colour, rank) VALUES ("green", "cool", 2);
+UPDATE tags SET colour = "cool" WHERE name = "red";
+SELECT name FROM tags WHERE colour = "cool";
+DELETE FROM tags WHERE name = "blue";
+SELECT name FROM tags WHERE colour = "cool";
+SELECT name FROM tags WHERE rank > 1;
+PRAGMA integrity_check;
@@ -0,0 +1,23 @@
+-- Scalar subqueries in expressions, correlated and not, and subqueries in FROM.
+CREATE TABLE emp (name TEXT, dept TEXT, salary INTEGER);
+INSERT INTO emp (name, dept, salary) VALUES ("ann", "eng", 120);
+INSERT INTO emp (name, dept, salary) VALUES ("bob", "ops", 80);
+INSERT INTO emp (name, dept, salary) VALUES ("cat", "eng", 100);
+INSERT INTO emp (name, dept, salary) VALUES ("dan", "ops", 95);
+INSERT INTO emp (name, dept, salary) VALUES ("eve", "eng", 90);
+CREATE TABLE depts (dept TEXT, floor INTEGER);
+INSERT INTO depts (dept, floor) VALUES ("eng", 3);
+INSERT INTO depts (dept, floor) VALUES ("ops", 1);
+SELECT name FROM emp WHERE salary > (SELECT AVG(salary) FROM emp);
+SELECT name FROM emp WHERE (SELECT floor FROM depts WHERE depts.dept = emp.dept) > 2;
+SELECT name FROM emp AS e WHERE salary = (SELECT MAX(salary) FROM emp AS e2 WHERE e2.dept = e.dept);
+SELECT name FROM emp WHERE salary > (SELECT salary FROM emp WHERE name = "nobody");
+SELECT dept FROM depts WHERE (SELECT COUNT(*) FROM emp WHERE emp.dept = depts.dept) + 1 > 3;
+SELECT * FROM (SELECT name, salary FROM emp WHERE dept = "eng") AS x WHERE salary > 95;
+SELECT name FROM (SELECT name, dept FROM emp) WHERE dept = "ops";
+SELECT x.name FROM (SELECT name, salary FROM emp) AS x WHERE x.salary < (SELECT AVG(salary) FROM emp);
+UPDATE emp SET salary = salary + 10 WHERE salary < (SELECT AVG(salary) FROM emp);
+SELECT name, salary FROM emp;
+DELETE FROM emp WHERE dept = (SELECT dept FROM depts WHERE floor = 1);
+SELECT name FROM emp;
+SELECT name FROM emp WHERE abs((SELECT dept, floor FROM depts)) > 0;
@@ -0,0 +1,51 @@
+-- Creating tables and changing their rows.
+CREATE TABLE people (id INTEGER PRIMARY KEY, name TEXT, email TEXT, age INTEGER);
+CREATE UNIQUE INDEX people_email ON people (email);
+INSERT INTO people (name, email, age) VALUES ("amy", "amy@example.com", 34);
+INSERT INTO people (name, email, age) VALUES ("bob", "bob@example.com", 27);
+INSERT INTO people (name, email, age) VALUES ("cat", NULL, 41);
+INSERT INTO people (id, name, email, age) VALUES (10, "dan", "dan@example.com", NULL);
+INSERT INTO people (name, email, age) VALUES ("eve", "eve@example.com", 30);
+SELECT * FROM people;
+SELECT name FROM people WHERE age > 29;
+SELECT name, age FROM people WHERE age >= 27 AND age < 35;
+SELECT id FROM people WHERE email = "bob@example.com";
+SELECT name FROM people WHERE age = NULL;
+UPDATE people SET age = age + 1 WHERE name = "amy";
+SELECT name, age FROM people WHERE id = 1;
+DELETE FROM people WHERE age < 30;
+SELECT id, name FROM people;
+
+-- constraints
+INSERT INTO people (name, email) VALUES ("fay", "amy@example.com");
+INSERT INTO people (name, email) VALUES ("gus", NULL);
+INSERT INTO people (id, name) VALUES (10, "hal");
+UPDATE people SET email = "eve@example.com" WHERE id = 1;
+UPDATE people SET id = 11 WHERE id = 1;
+SELECT count(*) FROM people;
+
+-- things that aren't there
+SELECT * FROM nobody;
+SELECT shoe_size FROM people;
+SELECT name FROM people WHERE shoe_size > 10;
+INSERT INTO nobody (a) VALUES (1);
+INSERT INTO people (shoe_size) VALUES (1);
+UPDATE nobody SET a = 1;
+DELETE FROM nobody;
+CREATE TABLE people (a INTEGER);
+CREATE INDEX people_email ON people (name);
+CREATE INDEX people_shoe_size ON people (shoe_size);
+
+-- values take their column's type
+CREATE TABLE readings (id INTEGER PRIMARY KEY, label TEXT, value REAL, count INTEGER, raw);
+INSERT INTO readings (label, value, count, raw) VALUES (12, 5, "30", "7");
+INSERT INTO readings (id, label, count) VALUES ("5", "b", "3.0");
+INSERT INTO readings (id, label) VALUES ("x", "c");
+INSERT INTO readings (label, count) VALUES ("d", "many");
+UPDATE readings SET value = "2.5" WHERE id = 5;
+SELECT * FROM readings;
+SELECT label FROM readings WHERE count = 30;
+SELECT label FROM readings WHERE raw = 7;
+
+-- the schema is kept in sqlite_master
+SELECT * FROM sqlite_master;
@@ -0,0 +1,22 @@
+-- Integers, reals, text and NULL, and how they mix.
+CREATE TABLE v (n INTEGER, r REAL, t TEXT, x);
+INSERT INTO v (n, r, t, x) VALUES (1, 1.5, "one", 1);
+INSERT INTO v (n, r, t, x) VALUES (2, 0.1, "2", 2.0);
+INSERT INTO v (n, r, t, x) VALUES (3, 1e20, "3.5", "three");
+INSERT INTO v (n, r, t, x) VALUES (NULL, NULL, NULL, NULL);
+INSERT INTO v (n, r, t, x) VALUES (9223372036854775807, -2.25, "", 7);
+SELECT * FROM v;
+SELECT n FROM v WHERE r > 1;
+SELECT n FROM v WHERE r + 0.2 > 0.3;
+SELECT t FROM v WHERE n / 2 = 1;
+SELECT t FROM v WHERE n * 2 < 0;
+SELECT n FROM v WHERE CAST(t AS INTEGER) = 3;
+SELECT n FROM v WHERE CAST(t AS REAL) = 3.5;
+SELECT n FROM v WHERE x = 2;
+SELECT n FROM v WHERE n / 0 = 1;
+SELECT count(*), count(n), sum(n), min(r), max(r) FROM v WHERE n < 10;
+SELECT avg(n), sum(r) FROM v WHERE n < 10;
+-- Strings in single quotes as well, a quote written twice to be part of one.
+INSERT INTO v (n, t) VALUES (4, 'it''s');
+INSERT INTO v (n, t) VALUES (5, "say ""hi""");
+select n, t from v where t = 'it''s' or t = 'say "hi"';
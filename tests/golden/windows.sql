@@ -0,0 +1,19 @@
+-- ROW_NUMBER, RANK, DENSE_RANK and aggregates over windows, with and without partitions,
+-- and frames of ROWS and RANGE.
+CREATE TABLE staff (name TEXT, dept TEXT, pay INTEGER);
+INSERT INTO staff (name, dept, pay) VALUES ("ann", "eng", 120);
+INSERT INTO staff (name, dept, pay) VALUES ("bob", "ops", 80);
+INSERT INTO staff (name, dept, pay) VALUES ("cat", "eng", 100);
+INSERT INTO staff (name, dept, pay) VALUES ("dan", "eng", 120);
+INSERT INTO staff (name, dept, pay) VALUES ("eve", "ops", 95);
+INSERT INTO staff (name, dept, pay) VALUES ("fay", "eng", 90);
+INSERT INTO staff (name, dept, pay) VALUES ("gus", "ops", NULL);
+SELECT name, ROW_NUMBER() OVER (ORDER BY name DESC) FROM staff;
+SELECT name, ROW_NUMBER() OVER (PARTITION BY dept ORDER BY name) FROM staff;
+SELECT dept, pay, RANK() OVER (PARTITION BY dept ORDER BY pay DESC), DENSE_RANK() OVER (PARTITION BY dept ORDER BY pay DESC) FROM staff;
+SELECT dept, pay, SUM(pay) OVER (PARTITION BY dept ORDER BY pay) FROM staff;
+SELECT name, SUM(pay) OVER (ORDER BY name ROWS BETWEEN 1 PRECEDING AND 1 FOLLOWING) FROM staff;
+SELECT name, COUNT(pay) OVER (ORDER BY name ROWS UNBOUNDED PRECEDING), AVG(pay) OVER (ORDER BY name ROWS UNBOUNDED PRECEDING) FROM staff;
+SELECT name, MAX(pay) OVER (PARTITION BY dept) FROM staff WHERE name > "b";
+SELECT name, COUNT(*) OVER (ORDER BY name RANGE BETWEEN CURRENT ROW AND UNBOUNDED FOLLOWING) FROM staff;
+SELECT ROW_NUMBER() OVER (ORDER BY nope) FROM staff;
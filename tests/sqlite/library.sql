@@ -0,0 +1,11 @@
+-- How library.db was made, with sqlite3: sqlite3 library.db < library.sql
+PRAGMA page_size = 512;
+CREATE TABLE authors (id INTEGER PRIMARY KEY, name TEXT);
+CREATE TABLE books (title TEXT, author_id INTEGER, price REAL, notes TEXT);
+CREATE INDEX books_by_author ON books (author_id);
+CREATE VIEW cheap AS SELECT title FROM books WHERE price < 10;
+INSERT INTO authors (id, name) VALUES (1, 'Austen'), (2, 'Borges'), (7, 'Calvino');
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 300)
+INSERT INTO books SELECT 'book ' || i, i % 3, i * 0.5, NULL FROM n;
+UPDATE books SET notes = replace(hex(zeroblob(1000)), '00', 'ab') WHERE title = 'book 42';
+UPDATE books SET author_id = 7 WHERE title = 'book 300';